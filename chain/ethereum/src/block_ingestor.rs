@@ -1,7 +1,12 @@
 use lazy_static;
-use std::collections::HashMap;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use graph::data::subgraph::schema::{
+    EthereumContractDataSourceEntity, NetworkReorgEntity, SubgraphDeploymentAssignmentEntity,
+    SubgraphManifestEntity, TypedEntity,
+};
 use graph::prelude::*;
 use web3::types::*;
 
@@ -10,6 +15,63 @@ lazy_static! {
         .ok()
         .map(|s| s.eq_ignore_ascii_case("true"))
         .unwrap_or(false);
+
+    /// Networks for which blocks are ingested even if no assigned
+    /// deployment uses them; `*` stands for all networks
+    static ref ALWAYS_INGEST_NETWORKS: HashSet<String> =
+        std::env::var("GRAPH_ALWAYS_INGEST_NETWORKS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+    /// How often to check whether any assigned deployment uses a network,
+    /// in seconds
+    static ref NETWORK_USAGE_CHECK_INTERVAL: Duration = Duration::from_secs(
+        std::env::var("GRAPH_NETWORK_USAGE_CHECK_INTERVAL")
+            .unwrap_or("60".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_NETWORK_USAGE_CHECK_INTERVAL")
+    );
+
+    /// How many of the most recent reorgs to remember for each network
+    static ref REORG_HISTORY: u32 = std::env::var("GRAPH_REORG_HISTORY")
+        .unwrap_or("100".into())
+        .parse::<u32>()
+        .expect("invalid GRAPH_REORG_HISTORY");
+}
+
+/// Whether any deployment that is assigned to a node indexes `network_name`.
+/// All data sources of a deployment are on the same network, so it is
+/// enough to look at the first data source of each deployment
+fn network_in_use(store: &impl Store, network_name: &str) -> Result<bool, Error> {
+    let data_source_ids: Vec<_> = store
+        .find(SubgraphDeploymentAssignmentEntity::query())?
+        .into_iter()
+        .map(|assignment| {
+            let id = SubgraphDeploymentId::new(assignment.id()?)
+                .map_err(|()| format_err!("Invalid subgraph hash in assignment entity"))?;
+            Ok(SubgraphManifestEntity::data_source_id(
+                &SubgraphManifestEntity::id(&id),
+                0,
+            ))
+        })
+        .collect::<Result<_, Error>>()?;
+    if data_source_ids.is_empty() {
+        return Ok(false);
+    }
+
+    let data_sources = store.find(
+        EthereumContractDataSourceEntity::query()
+            .filter(EntityFilter::And(vec![
+                EntityFilter::new_equal("network", network_name),
+                EntityFilter::new_in("id", data_source_ids),
+            ]))
+            .first(1),
+    )?;
+    Ok(!data_sources.is_empty())
 }
 
 pub struct BlockIngestorMetrics {
@@ -37,21 +99,65 @@ impl BlockIngestorMetrics {
     }
 }
 
+/// Reorgs that the block ingestors observed. The metrics are shared by the
+/// block ingestors of all networks
+pub struct ReorgMetrics {
+    reorg_count: Box<CounterVec>,
+    reorg_depth: Box<HistogramVec>,
+}
+
+impl ReorgMetrics {
+    pub fn new(registry: Arc<dyn MetricsRegistry>) -> Self {
+        Self {
+            reorg_count: registry
+                .new_counter_vec(
+                    String::from("ethereum_reorg_count"),
+                    String::from("Number of chain reorganizations seen by the block ingestor"),
+                    HashMap::new(),
+                    vec![String::from("network")],
+                )
+                .unwrap(),
+            reorg_depth: registry
+                .new_histogram_vec(
+                    String::from("ethereum_reorg_depth"),
+                    String::from("Number of blocks replaced by a chain reorganization"),
+                    HashMap::new(),
+                    vec![String::from("network")],
+                    vec![1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0, 100.0],
+                )
+                .unwrap(),
+        }
+    }
+
+    pub fn observe_reorg(&self, network_name: &str, depth: u64) {
+        self.reorg_count
+            .with_label_values(vec![network_name].as_slice())
+            .inc();
+        self.reorg_depth
+            .with_label_values(vec![network_name].as_slice())
+            .observe(depth as f64);
+    }
+}
+
 pub struct BlockIngestor<S>
 where
-    S: ChainStore,
+    S: ChainStore + Store,
 {
     chain_store: Arc<S>,
     eth_adapter: Arc<dyn EthereumAdapter>,
     ancestor_count: u64,
-    _network_name: String,
+    network_name: String,
     logger: Logger,
     polling_interval: Duration,
+    reorg_metrics: Arc<ReorgMetrics>,
+    /// When we last checked whether an assigned deployment uses the
+    /// network, and the outcome of that check
+    network_usage: Mutex<Option<(Instant, bool)>>,
 }
 
 impl<S> BlockIngestor<S>
 where
-    S: ChainStore,
+    S: ChainStore + Store,
 {
     pub fn new(
         chain_store: Arc<S>,
@@ -60,6 +166,7 @@ where
         network_name: String,
         logger_factory: &LoggerFactory,
         polling_interval: Duration,
+        reorg_metrics: Arc<ReorgMetrics>,
     ) -> Result<BlockIngestor<S>, Error> {
         let logger = logger_factory.component_logger(
             "BlockIngestor",
@@ -76,9 +183,11 @@ where
             chain_store,
             eth_adapter,
             ancestor_count,
-            _network_name: network_name,
+            network_name,
             logger,
             polling_interval,
+            reorg_metrics,
+            network_usage: Mutex::new(None),
         })
     }
 
@@ -90,6 +199,8 @@ where
         tokio::time::interval(static_self.polling_interval)
             .map(Ok)
             .compat()
+            // Skip polls while no assigned deployment uses the network
+            .filter(move |_| static_self.ingestion_needed())
             .for_each(move |_| {
                 // Attempt to poll
                 static_self
@@ -144,6 +255,49 @@ where
             })
     }
 
+    /// Whether to ingest blocks for the network: either the network is
+    /// listed in `GRAPH_ALWAYS_INGEST_NETWORKS`, or an assigned deployment
+    /// uses it. Usage is checked at most every
+    /// `GRAPH_NETWORK_USAGE_CHECK_INTERVAL`; if the check fails, we keep
+    /// ingesting
+    fn ingestion_needed(&self) -> bool {
+        if ALWAYS_INGEST_NETWORKS.contains("*")
+            || ALWAYS_INGEST_NETWORKS.contains(&self.network_name)
+        {
+            return true;
+        }
+
+        let mut usage = self.network_usage.lock().unwrap();
+        let was_in_use = match *usage {
+            Some((checked_at, in_use)) if checked_at.elapsed() < *NETWORK_USAGE_CHECK_INTERVAL => {
+                return in_use
+            }
+            Some((_, in_use)) => Some(in_use),
+            None => None,
+        };
+
+        let in_use = network_in_use(&*self.chain_store, &self.network_name).unwrap_or_else(|e| {
+            warn!(self.logger, "Failed to check whether any deployment uses the network";
+                  "error" => e.to_string());
+            true
+        });
+        if was_in_use != Some(in_use) {
+            if in_use {
+                info!(
+                    self.logger,
+                    "Ingesting blocks, the network is used by assigned subgraphs"
+                );
+            } else {
+                info!(
+                    self.logger,
+                    "Pausing block ingestion, no assigned subgraph uses the network"
+                );
+            }
+        }
+        *usage = Some((Instant::now(), in_use));
+        in_use
+    }
+
     fn do_poll(&'static self) -> impl Future<Item = (), Error = EthereumAdapterError> + 'static {
         trace!(self.logger, "BlockIngestor::do_poll");
 
@@ -238,9 +392,99 @@ where
                             })
                         )
                     })
+                    .map(move |()| {
+                        // Check whether moving the chain head replaced blocks
+                        if let Some(old_head_ptr) = head_block_ptr_opt {
+                            if let Err(e) = self.record_reorg(old_head_ptr) {
+                                warn!(self.logger, "Failed to check for a chain reorganization";
+                                      "error" => e.to_string());
+                            }
+                        }
+                    })
             })
     }
 
+    /// Compare the chain head with `old_head_ptr`, the chain head before the
+    /// last poll. If the old head is no longer on the chain, count the reorg,
+    /// observe its depth and remember it in the subgraph of subgraphs. The
+    /// block ingestors of all nodes for the network see the same reorgs, so
+    /// a reorg is identified by the first block it replaced, and only the
+    /// deepest observation of it is kept
+    fn record_reorg(&self, old_head_ptr: EthereumBlockPointer) -> Result<(), Error> {
+        let new_head_ptr = match self.chain_store.chain_head_ptr()? {
+            Some(ptr) => ptr,
+            None => return Ok(()),
+        };
+        let reorg = match find_reorg(
+            old_head_ptr,
+            new_head_ptr,
+            self.ancestor_count,
+            |ptr, offset| {
+                Ok(self
+                    .chain_store
+                    .ancestor_block(ptr, offset)?
+                    .map(EthereumBlockPointer::from))
+            },
+        )? {
+            Some(reorg) => reorg,
+            None => return Ok(()),
+        };
+
+        warn!(
+            self.logger,
+            "Chain reorganization detected";
+            "depth" => reorg.depth,
+            "old_head_number" => old_head_ptr.number,
+            "old_head_hash" => format!("{:x}", old_head_ptr.hash),
+            "new_head_number" => new_head_ptr.number,
+            "new_head_hash" => format!("{:x}", new_head_ptr.hash),
+            "common_ancestor" => reorg.common_ancestor.map(|ptr| ptr.number),
+        );
+        self.reorg_metrics
+            .observe_reorg(&self.network_name, reorg.depth);
+
+        let id = format!("{}-{:x}", self.network_name, reorg.first_replaced.hash);
+        let recorded_depth = self
+            .chain_store
+            .get(NetworkReorgEntity::key(id.clone()))?
+            .and_then(|recorded| recorded.get("depth").cloned())
+            .and_then(Value::as_int);
+        if recorded_depth.map_or(false, |depth| depth as u64 >= reorg.depth) {
+            return Ok(());
+        }
+
+        let detected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut ops = NetworkReorgEntity::new(
+            self.network_name.clone(),
+            reorg.depth,
+            old_head_ptr,
+            new_head_ptr,
+            reorg.common_ancestor,
+            detected_at,
+        )
+        .write_operations(&id);
+
+        // Forget the reorgs that fall out of the history
+        let expired = self.chain_store.find(
+            NetworkReorgEntity::query()
+                .filter(EntityFilter::And(vec![
+                    EntityFilter::new_equal("network", self.network_name.as_str()),
+                    EntityFilter::Not("id".to_owned(), Value::from(id.as_str())),
+                ]))
+                .order_by("detectedAt", ValueType::BigInt, EntityOrder::Descending)
+                .skip(REORG_HISTORY.saturating_sub(1)),
+        )?;
+        for reorg in expired {
+            ops.extend(NetworkReorgEntity::remove_operations(&reorg.id()?));
+        }
+        self.chain_store
+            .apply_metadata_operations(ops)
+            .map_err(Error::from)
+    }
+
     /// Put some blocks into the block store (if they are not there already), and try to update the
     /// head block pointer. If missing blocks prevent such an update, return a Vec with at least
     /// one of the missing blocks' hashes.
@@ -285,3 +529,261 @@ where
         Box::new(stream::futures_unordered(block_futures))
     }
 }
+
+/// A chain reorganization that replaced the blocks of the old chain from
+/// `first_replaced` up to the old chain head
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Reorg {
+    depth: u64,
+    first_replaced: EthereumBlockPointer,
+    /// `None` if the two chains did not meet within the blocks we have
+    common_ancestor: Option<EthereumBlockPointer>,
+}
+
+/// Find out whether moving the chain head from `old_head` to `new_head`
+/// replaced blocks by walking back along both chains, starting at the same
+/// block number, for at most `max_depth` blocks. `ancestor(ptr, offset)`
+/// returns the block `offset` blocks before `ptr`. Returns `None` if the
+/// old head is an ancestor of the new head, or if we do not have the blocks
+/// to tell
+fn find_reorg<F>(
+    old_head: EthereumBlockPointer,
+    new_head: EthereumBlockPointer,
+    max_depth: u64,
+    ancestor: F,
+) -> Result<Option<Reorg>, Error>
+where
+    F: Fn(EthereumBlockPointer, u64) -> Result<Option<EthereumBlockPointer>, Error>,
+{
+    if old_head == new_head {
+        return Ok(None);
+    }
+
+    let (mut old_offset, mut new_offset) = if new_head.number >= old_head.number {
+        (0, new_head.number - old_head.number)
+    } else {
+        (old_head.number - new_head.number, 0)
+    };
+    let mut common_ancestor = None;
+    let mut first_replaced = None;
+    for _ in 0..=max_depth {
+        let (old_ancestor, new_ancestor) = match (
+            ancestor(old_head, old_offset)?,
+            ancestor(new_head, new_offset)?,
+        ) {
+            (Some(old_ancestor), Some(new_ancestor)) => (old_ancestor, new_ancestor),
+            _ => break,
+        };
+        if old_ancestor == new_ancestor {
+            common_ancestor = Some(old_ancestor);
+            break;
+        }
+        first_replaced = Some(old_ancestor);
+        if old_ancestor.number == 0 {
+            break;
+        }
+        old_offset += 1;
+        new_offset += 1;
+    }
+
+    let first_replaced = match (first_replaced, common_ancestor) {
+        // The old head is an ancestor of the new head; the chain just grew
+        (_, Some(common)) if common == old_head => return Ok(None),
+        (Some(first_replaced), _) => first_replaced,
+        // The new head is an ancestor of the old head
+        (None, Some(common)) => match ancestor(old_head, old_head.number - common.number - 1)? {
+            Some(first_replaced) => first_replaced,
+            None => return Ok(None),
+        },
+        (None, None) => return Ok(None),
+    };
+    let depth = match common_ancestor {
+        Some(common) => old_head.number - common.number,
+        None => old_head.number - first_replaced.number + 1,
+    };
+    Ok(Some(Reorg {
+        depth,
+        first_replaced,
+        common_ancestor,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::mock::MockEthereumAdapter;
+    use mock::{MockMetricsRegistry, MockStore};
+
+    /// A store in which deployments `assigned` are assigned to nodes, and
+    /// that answers queries for their data sources with `data_sources`
+    fn network_store(assigned: Vec<&'static str>, data_sources: Vec<Entity>) -> MockStore {
+        let mut store = MockStore::new();
+        store
+            .expect_find()
+            .returning(move |query| match &query.collection {
+                EntityCollection::All(types)
+                    if types[0] == SubgraphDeploymentAssignmentEntity::TYPENAME =>
+                {
+                    Ok(assigned
+                        .iter()
+                        .map(|id| Entity::from(vec![("id", Value::from(*id))]))
+                        .collect())
+                }
+                _ => {
+                    let data_source_ids = assigned
+                        .iter()
+                        .map(|id| format!("{}-manifest-data-source-0", id))
+                        .collect::<Vec<_>>();
+                    assert_eq!(
+                        Some(EntityFilter::And(vec![
+                            EntityFilter::new_equal("network", "mainnet"),
+                            EntityFilter::new_in("id", data_source_ids),
+                        ])),
+                        query.filter
+                    );
+                    Ok(data_sources.clone())
+                }
+            });
+        store
+    }
+
+    #[test]
+    fn networks_are_in_use_when_an_assigned_deployment_uses_them() {
+        let data_source = Entity::from(vec![("network", Value::from("mainnet"))]);
+
+        // Without assignments, data sources are not even looked up
+        let mut store = MockStore::new();
+        store.expect_find().times(1).returning(|_| Ok(vec![]));
+        assert!(!network_in_use(&store, "mainnet").unwrap());
+
+        let store = network_store(vec!["QmA", "QmB"], vec![]);
+        assert!(!network_in_use(&store, "mainnet").unwrap());
+
+        let store = network_store(vec!["QmA", "QmB"], vec![data_source]);
+        assert!(network_in_use(&store, "mainnet").unwrap());
+    }
+
+    #[test]
+    fn ingestion_is_only_needed_for_networks_in_use() {
+        let ingestor = |store: MockStore| {
+            let registry = Arc::new(MockMetricsRegistry::new());
+            BlockIngestor::new(
+                Arc::new(store),
+                Arc::new(MockEthereumAdapter::new()),
+                50,
+                "mainnet".to_owned(),
+                &LoggerFactory::new(Logger::root(slog::Discard, o!()), None),
+                Duration::from_secs(1),
+                Arc::new(ReorgMetrics::new(registry)),
+            )
+            .unwrap()
+        };
+
+        // Whether the network is used is remembered for a while
+        let mut store = MockStore::new();
+        store.expect_find().times(1).returning(|_| Ok(vec![]));
+        let unused = ingestor(store);
+        assert!(!unused.ingestion_needed());
+        assert!(!unused.ingestion_needed());
+
+        let data_source = Entity::from(vec![("network", Value::from("mainnet"))]);
+        let used = ingestor(network_store(vec!["QmA"], vec![data_source]));
+        assert!(used.ingestion_needed());
+
+        // Blocks are ingested when we can not tell whether they are needed
+        let mut store = MockStore::new();
+        store
+            .expect_find()
+            .returning(|_| Err(QueryExecutionError::Timeout));
+        assert!(ingestor(store).ingestion_needed());
+    }
+
+    /// A block at `number` on the chain identified by `fork`
+    fn block(number: u64, fork: u64) -> EthereumBlockPointer {
+        EthereumBlockPointer {
+            hash: H256::from_low_u64_be(fork * 1000 + number),
+            number,
+        }
+    }
+
+    /// Two chains that share the blocks up to `fork_number` and then
+    /// continue on fork 1 and fork 2 respectively
+    fn ancestor(
+        fork_number: u64,
+    ) -> impl Fn(EthereumBlockPointer, u64) -> Result<Option<EthereumBlockPointer>, Error> {
+        move |ptr, offset| {
+            let number = match ptr.number.checked_sub(offset) {
+                Some(number) => number,
+                None => return Ok(None),
+            };
+            let fork = (ptr.hash.to_low_u64_be() - ptr.number) / 1000;
+            Ok(Some(if number <= fork_number {
+                block(number, 0)
+            } else {
+                block(number, fork)
+            }))
+        }
+    }
+
+    #[test]
+    fn finds_reorgs() {
+        let ancestor = ancestor(10);
+
+        // Growing the chain is not a reorg
+        assert_eq!(
+            None,
+            find_reorg(block(10, 0), block(12, 2), 50, &ancestor).unwrap()
+        );
+        assert_eq!(
+            None,
+            find_reorg(block(12, 1), block(12, 1), 50, &ancestor).unwrap()
+        );
+
+        // Moving to a longer, a shorter and an equally long fork
+        for new_head in vec![block(14, 2), block(11, 2), block(12, 2)] {
+            assert_eq!(
+                Some(Reorg {
+                    depth: 2,
+                    first_replaced: block(11, 1),
+                    common_ancestor: Some(block(10, 0)),
+                }),
+                find_reorg(block(12, 1), new_head, 50, &ancestor).unwrap()
+            );
+        }
+
+        // Moving back to an ancestor of the old head
+        assert_eq!(
+            Some(Reorg {
+                depth: 2,
+                first_replaced: block(11, 1),
+                common_ancestor: Some(block(10, 0)),
+            }),
+            find_reorg(block(12, 1), block(10, 0), 50, &ancestor).unwrap()
+        );
+
+        // The chains do not meet within `max_depth` blocks
+        assert_eq!(
+            Some(Reorg {
+                depth: 2,
+                first_replaced: block(11, 1),
+                common_ancestor: None,
+            }),
+            find_reorg(block(12, 1), block(12, 2), 1, &ancestor).unwrap()
+        );
+    }
+
+    #[test]
+    fn ingestors_agree_on_the_first_replaced_block() {
+        // Two ingestors that polled at different times saw different old
+        // heads on the replaced fork, but the same first replaced block
+        let ancestor = ancestor(10);
+        let early = find_reorg(block(11, 1), block(13, 2), 50, &ancestor)
+            .unwrap()
+            .unwrap();
+        let late = find_reorg(block(12, 1), block(13, 2), 50, &ancestor)
+            .unwrap()
+            .unwrap();
+        assert_eq!(early.first_replaced, late.first_replaced);
+        assert_eq!((1, 2), (early.depth, late.depth));
+    }
+}
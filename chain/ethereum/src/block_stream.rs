@@ -4,10 +4,13 @@ use std::mem;
 use std::sync::Mutex;
 use std::time::Duration;
 
-use graph::components::ethereum::{blocks_with_triggers, triggers_in_block};
+use graph::components::ethereum::{
+    blocks_with_triggers, pending_block_with_triggers, triggers_in_block,
+};
 use graph::data::subgraph::schema::{
     SubgraphDeploymentEntity, SubgraphEntity, SubgraphVersionEntity,
 };
+use graph::prelude::web3::types::H256;
 use graph::prelude::{
     BlockStream as BlockStreamTrait, BlockStreamBuilder as BlockStreamBuilderTrait, *,
 };
@@ -24,6 +27,20 @@ lazy_static! {
         .unwrap_or("1000".into())
         .parse::<u64>()
         .expect("invalid GRAPH_ETHEREUM_TARGET_TRIGGERS_PER_BLOCK_RANGE");
+
+    /// Whether deployments that have caught up with the chain head also
+    /// index the pending block on top of it
+    static ref INDEX_PENDING_BLOCKS: bool = std::env::var("GRAPH_INDEX_PENDING_BLOCKS")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+
+    /// How often to check the pending block for changes, in milliseconds
+    static ref PENDING_BLOCK_POLLING_INTERVAL: Duration = Duration::from_millis(
+        std::env::var("GRAPH_PENDING_BLOCK_POLLING_INTERVAL")
+            .unwrap_or("1000".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_PENDING_BLOCK_POLLING_INTERVAL")
+    );
 }
 
 enum BlockStreamState {
@@ -52,9 +69,17 @@ enum BlockStreamState {
     /// The BlockStream has reconciled the subgraph store and chain store states.
     /// No more work is needed until a chain head update.
     ///
-    /// Valid next states: Reconciliation
+    /// Valid next states: Reconciliation, LoadingPendingBlock
     Idle,
 
+    /// The BlockStream is idle and loads the pending block on top of the
+    /// chain head.
+    ///
+    /// Valid next states: Idle
+    LoadingPendingBlock(
+        Box<dyn Future<Item = Option<EthereumBlockWithTriggers>, Error = Error> + Send>,
+    ),
+
     /// Not a real state, only used when going from one state to another.
     Transition,
 }
@@ -100,9 +125,7 @@ struct BlockStreamContext<S, C> {
     node_id: NodeId,
     subgraph_id: SubgraphDeploymentId,
     reorg_threshold: u64,
-    log_filter: EthereumLogFilter,
-    call_filter: EthereumCallFilter,
-    block_filter: EthereumBlockFilter,
+    filter: EthereumTriggerFilter,
     start_blocks: Vec<u64>,
     templates_use_calls: bool,
     logger: Logger,
@@ -120,9 +143,7 @@ impl<S, C> Clone for BlockStreamContext<S, C> {
             node_id: self.node_id.clone(),
             subgraph_id: self.subgraph_id.clone(),
             reorg_threshold: self.reorg_threshold,
-            log_filter: self.log_filter.clone(),
-            call_filter: self.call_filter.clone(),
-            block_filter: self.block_filter.clone(),
+            filter: self.filter.clone(),
             start_blocks: self.start_blocks.clone(),
             templates_use_calls: self.templates_use_calls,
             logger: self.logger.clone(),
@@ -137,6 +158,12 @@ pub struct BlockStream<S, C> {
     state: Mutex<BlockStreamState>,
     consecutive_err_count: u32,
     chain_head_update_stream: ChainHeadUpdateStream,
+    /// When to look at the pending block next while idle, if pending
+    /// blocks are indexed
+    pending_block_delay: Option<Box<dyn Future<Item = (), Error = Error> + Send>>,
+    /// The number and transactions of the last pending block that was
+    /// streamed, to only stream a pending block again when it changed
+    last_pending_block: Option<(u64, Vec<H256>)>,
     ctx: BlockStreamContext<S, C>,
 }
 
@@ -158,9 +185,7 @@ where
         eth_adapter: Arc<dyn EthereumAdapter>,
         node_id: NodeId,
         subgraph_id: SubgraphDeploymentId,
-        log_filter: EthereumLogFilter,
-        call_filter: EthereumCallFilter,
-        block_filter: EthereumBlockFilter,
+        filter: EthereumTriggerFilter,
         start_blocks: Vec<u64>,
         templates_use_calls: bool,
         reorg_threshold: u64,
@@ -171,6 +196,8 @@ where
             state: Mutex::new(BlockStreamState::New),
             consecutive_err_count: 0,
             chain_head_update_stream: chain_store.chain_head_updates(),
+            pending_block_delay: None,
+            last_pending_block: None,
             ctx: BlockStreamContext {
                 subgraph_store,
                 chain_store,
@@ -179,9 +206,7 @@ where
                 subgraph_id,
                 reorg_threshold,
                 logger,
-                log_filter,
-                call_filter,
-                block_filter,
+                filter,
                 start_blocks,
                 templates_use_calls,
                 metrics,
@@ -203,8 +228,8 @@ where
     /// and populate them in the blocks
     fn include_calls_in_blocks(&self) -> bool {
         self.templates_use_calls
-            || !self.call_filter.is_empty()
-            || self.block_filter.contract_addresses.len() > 0
+            || !self.filter.call.is_empty()
+            || self.filter.block.contract_addresses.len() > 0
     }
 
     /// Perform reconciliation steps until there are blocks to yield or we are up-to-date.
@@ -245,12 +270,38 @@ where
         }))
     }
 
+    /// Load the pending block with its triggers if it builds on the block
+    /// the subgraph has processed last; `None` otherwise
+    fn pending_block(
+        &self,
+    ) -> Box<dyn Future<Item = Option<EthereumBlockWithTriggers>, Error = Error> + Send> {
+        let subgraph_ptr = match self.subgraph_store.block_ptr(self.subgraph_id.clone()) {
+            Ok(Some(ptr)) => ptr,
+            Ok(None) => return Box::new(future::ok(None)),
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        Box::new(
+            pending_block_with_triggers(
+                self.eth_adapter.clone(),
+                self.logger.clone(),
+                self.filter.log.clone(),
+                self.filter.block.clone(),
+            )
+            .map(move |block| {
+                block.filter(|block| {
+                    block.ethereum_block.light_block().parent_hash == subgraph_ptr.hash
+                })
+            }),
+        )
+    }
+
     /// Determine the next reconciliation step. Does not modify Store or ChainStore.
     fn get_next_step(&self) -> impl Future<Item = ReconciliationStep, Error = Error> + Send {
         let ctx = self.clone();
-        let log_filter = self.log_filter.clone();
-        let call_filter = self.call_filter.clone();
-        let block_filter = self.block_filter.clone();
+        let log_filter = self.filter.log.clone();
+        let call_filter = self.filter.call.clone();
+        let block_filter = self.filter.block.clone();
         let start_blocks = self.start_blocks.clone();
 
         // Get pointers from database for comparison
@@ -289,6 +340,21 @@ where
             self.metrics
                 .blocks_behind
                 .set((head_ptr.number - ptr.number) as f64);
+            // When either timestamp is unknown, report NaN rather than
+            // keep showing how far behind an earlier block was
+            let seconds_behind = ctx
+                .chain_store
+                .block_timestamps(vec![head_ptr.hash, ptr.hash])
+                .ok()
+                .and_then(|timestamps| {
+                    match (timestamps.get(&head_ptr.hash), timestamps.get(&ptr.hash)) {
+                        (Some(head), Some(latest)) => Some(head.saturating_sub(*latest) as f64),
+                        _ => None,
+                    }
+                });
+            self.metrics
+                .seconds_behind
+                .set(seconds_behind.unwrap_or(std::f64::NAN));
 
             if ptr.number >= head_ptr.number {
                 return Box::new(future::ok(ReconciliationStep::Done))
@@ -932,6 +998,10 @@ where
                     match self.chain_head_update_stream.poll() {
                         // Chain head was updated
                         Ok(Async::Ready(Some(()))) => {
+                            // The pending block will be a different one
+                            self.pending_block_delay = None;
+                            self.last_pending_block = None;
+
                             // Start reconciliation process
                             let next_blocks_future = self.ctx.next_blocks();
                             state = BlockStreamState::Reconciliation(next_blocks_future);
@@ -946,6 +1016,32 @@ where
                             return Err(format_err!("chain head update stream ended unexpectedly"));
                         }
 
+                        Ok(Async::NotReady) if *INDEX_PENDING_BLOCKS => {
+                            let delay = self.pending_block_delay.get_or_insert_with(|| {
+                                Box::new(
+                                    tokio::time::delay_for(*PENDING_BLOCK_POLLING_INTERVAL)
+                                        .map(Ok)
+                                        .compat(),
+                                )
+                            });
+                            match delay.poll() {
+                                Ok(Async::NotReady) => {
+                                    // Stay idle
+                                    state = BlockStreamState::Idle;
+                                    break Ok(Async::NotReady);
+                                }
+                                Ok(Async::Ready(())) | Err(_) => {
+                                    self.pending_block_delay = None;
+                                    state = BlockStreamState::LoadingPendingBlock(
+                                        self.ctx.pending_block(),
+                                    );
+
+                                    // Poll the pending_block() future
+                                    continue;
+                                }
+                            }
+                        }
+
                         Ok(Async::NotReady) => {
                             // Stay idle
                             state = BlockStreamState::Idle;
@@ -960,6 +1056,49 @@ where
                     }
                 }
 
+                // Waiting for the pending block; it is only streamed if it
+                // changed since it was last streamed
+                BlockStreamState::LoadingPendingBlock(mut pending_block_future) => {
+                    match pending_block_future.poll() {
+                        Ok(Async::NotReady) => {
+                            state = BlockStreamState::LoadingPendingBlock(pending_block_future);
+                            break Ok(Async::NotReady);
+                        }
+
+                        Ok(Async::Ready(Some(block))) => {
+                            let light_block = block.ethereum_block.light_block();
+                            let fingerprint = (
+                                light_block.number.map_or(0, |number| number.as_u64()),
+                                light_block.transactions.iter().map(|tx| tx.hash).collect(),
+                            );
+
+                            state = BlockStreamState::Idle;
+                            if self.last_pending_block.as_ref() != Some(&fingerprint) {
+                                self.last_pending_block = Some(fingerprint);
+                                break Ok(Async::Ready(Some(BlockStreamEvent::Pending(block))));
+                            }
+                            continue;
+                        }
+
+                        Ok(Async::Ready(None)) => {
+                            state = BlockStreamState::Idle;
+                            continue;
+                        }
+
+                        // Failing to load the pending block is not a
+                        // problem for the subgraph; try again later
+                        Err(e) => {
+                            debug!(
+                                self.ctx.logger,
+                                "Failed to load the pending block";
+                                "error" => e.to_string()
+                            );
+                            state = BlockStreamState::Idle;
+                            continue;
+                        }
+                    }
+                }
+
                 // This will only happen if this poll function fails to complete normally then is
                 // called again.
                 BlockStreamState::Transition => unreachable!(),
@@ -1033,9 +1172,7 @@ where
         deployment_id: SubgraphDeploymentId,
         network_name: String,
         start_blocks: Vec<u64>,
-        log_filter: EthereumLogFilter,
-        call_filter: EthereumCallFilter,
-        block_filter: EthereumBlockFilter,
+        filter: EthereumTriggerFilter,
         templates_use_calls: bool,
         metrics: Arc<BlockStreamMetrics>,
     ) -> Self::Stream {
@@ -1067,9 +1204,7 @@ where
             eth_adapter,
             self.node_id.clone(),
             deployment_id,
-            log_filter,
-            call_filter,
-            block_filter,
+            filter,
             start_blocks,
             templates_use_calls,
             self.reorg_threshold,
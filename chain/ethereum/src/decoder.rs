@@ -0,0 +1,646 @@
+//! Decoders for chains that are close enough to Ethereum to be indexed
+//! like it, but whose nodes return blocks and receipts that differ from
+//! what an Ethereum node returns, for example because they have
+//! transaction types without a gas price or nonce. A decoder is
+//! registered per network and rewrites the JSON of every block and
+//! receipt from that network into the shape the adapter expects before
+//! it is deserialized.
+//!
+//! Decoders are either native, i.e., compiled into graph-node and
+//! selected by name, or WASM modules installed by the operator.
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use wasmi::{
+    Error as WasmiError, ExternVal, Externals, FuncInstance, FuncRef, HostError, ImportsBuilder,
+    MemoryRef, Module, ModuleImportResolver, ModuleInstance, ModuleRef, RuntimeArgs, RuntimeValue,
+    Signature, Trap, TrapKind,
+};
+
+use graph::prelude::{format_err, serde_json, Error};
+
+/// The version of the interface between graph-node and WASM decoders. A
+/// WASM decoder must export an `abi_version` function that returns this
+/// version
+pub const WASM_DECODER_ABI_VERSION: i32 = 1;
+
+lazy_static! {
+    /// Which decoder to use for which network, as a comma-separated list
+    /// of `network=decoder` pairs. The decoder is either the name of a
+    /// native decoder or the path of a WASM decoder
+    static ref ETHEREUM_DECODERS: Option<String> = env::var("GRAPH_ETHEREUM_DECODERS").ok();
+
+    /// How many WASM instructions a WASM decoder may execute to decode one
+    /// block or receipt
+    static ref WASM_DECODER_FUEL: u64 = env::var("GRAPH_ETHEREUM_DECODER_FUEL")
+        .unwrap_or("1000000000".into())
+        .parse::<u64>()
+        .expect("invalid GRAPH_ETHEREUM_DECODER_FUEL");
+}
+
+/// Rewrites the blocks and receipts of a network before they are
+/// deserialized
+pub trait BlockDecoder: Send + Sync + 'static {
+    /// The name of the decoder, used in logs and errors
+    fn name(&self) -> &str;
+
+    /// The version of the decoder
+    fn version(&self) -> u32;
+
+    /// Decode a block as returned by `eth_getBlockByHash` or
+    /// `eth_getBlockByNumber` with full transactions
+    fn decode_block(&self, block: serde_json::Value) -> Result<serde_json::Value, Error>;
+
+    /// Decode a receipt as returned by `eth_getTransactionReceipt`
+    fn decode_receipt(&self, receipt: serde_json::Value) -> Result<serde_json::Value, Error>;
+}
+
+const ZERO: &str = "0x0";
+const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+
+fn empty_bloom() -> String {
+    format!("0x{}", "0".repeat(512))
+}
+
+/// Set the fields in `defaults` that `object` is missing or has as `null`
+fn fill_defaults(object: &mut serde_json::Value, defaults: &[(&str, serde_json::Value)]) {
+    if let Some(object) = object.as_object_mut() {
+        for (field, default) in defaults {
+            let value = object
+                .entry(field.to_string())
+                .or_insert(serde_json::Value::Null);
+            if value.is_null() {
+                *value = default.clone();
+            }
+        }
+    }
+}
+
+/// A native decoder for chains whose blocks, transactions and receipts
+/// leave out fields that Ethereum nodes always return, as happens for the
+/// system and deposit transactions of many rollups. Missing fields are
+/// filled with zero values
+pub struct LenientDecoder;
+
+impl BlockDecoder for LenientDecoder {
+    fn name(&self) -> &str {
+        "lenient"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn decode_block(&self, mut block: serde_json::Value) -> Result<serde_json::Value, Error> {
+        fill_defaults(
+            &mut block,
+            &[
+                ("difficulty", ZERO.into()),
+                ("totalDifficulty", ZERO.into()),
+                ("extraData", "0x".into()),
+                ("logsBloom", empty_bloom().into()),
+                ("sealFields", serde_json::Value::Array(vec![])),
+                ("uncles", serde_json::Value::Array(vec![])),
+            ],
+        );
+        if let Some(transactions) = block
+            .get_mut("transactions")
+            .and_then(|transactions| transactions.as_array_mut())
+        {
+            for transaction in transactions {
+                fill_defaults(
+                    transaction,
+                    &[
+                        ("from", ZERO_ADDRESS.into()),
+                        ("nonce", ZERO.into()),
+                        ("value", ZERO.into()),
+                        ("gas", ZERO.into()),
+                        ("gasPrice", ZERO.into()),
+                        ("input", "0x".into()),
+                    ],
+                );
+            }
+        }
+        Ok(block)
+    }
+
+    fn decode_receipt(&self, mut receipt: serde_json::Value) -> Result<serde_json::Value, Error> {
+        fill_defaults(
+            &mut receipt,
+            &[
+                ("cumulativeGasUsed", ZERO.into()),
+                ("logs", serde_json::Value::Array(vec![])),
+                ("logsBloom", empty_bloom().into()),
+            ],
+        );
+        Ok(receipt)
+    }
+}
+
+/// The native decoder with the given name
+fn native_decoder(name: &str) -> Option<Arc<dyn BlockDecoder>> {
+    match name {
+        "lenient" => Some(Arc::new(LenientDecoder)),
+        _ => None,
+    }
+}
+
+/// A decoder implemented as a WASM module. The module must export
+///
+/// - `memory`, the memory through which JSON is passed,
+/// - `abi_version() -> i32`, which must return `WASM_DECODER_ABI_VERSION`,
+/// - `version() -> i32`, the version of the decoder,
+/// - `alloc(len: i32) -> i32`, which reserves `len` bytes of memory,
+/// - `decode_block(ptr: i32, len: i32) -> i64`, which is passed the JSON
+///   of a block and returns the JSON of the decoded block, with its
+///   pointer in the upper and its length in the lower 32 bits,
+///
+/// and may export `decode_receipt(ptr: i32, len: i32) -> i64` to decode
+/// receipts in the same way. Receipts are passed through unchanged if it
+/// does not. The module can not import anything.
+///
+/// A fresh instance of the module is used for each block and receipt so
+/// that decoding one can not affect decoding another one. Each of them
+/// can execute at most `GRAPH_ETHEREUM_DECODER_FUEL` instructions
+pub struct WasmDecoder {
+    name: String,
+    version: u32,
+    module: Module,
+    decodes_receipts: bool,
+    /// How many instructions decoding a block or receipt may execute
+    fuel: u64,
+}
+
+/// The index of the host function that the metering code injected into
+/// WASM decoders calls
+const GAS_FUNC_INDEX: usize = 0;
+
+/// Provides the `gas` function that metering injects as an import
+struct GasResolver;
+
+impl ModuleImportResolver for GasResolver {
+    fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, WasmiError> {
+        match field_name {
+            "gas" => Ok(FuncInstance::alloc_host(signature.clone(), GAS_FUNC_INDEX)),
+            _ => Err(WasmiError::Instantiation(format!(
+                "WASM decoders can not import `{}`",
+                field_name
+            ))),
+        }
+    }
+}
+
+/// The limit on instructions that a decoder exceeded
+#[derive(Debug)]
+struct OutOfFuel(u64);
+
+impl fmt::Display for OutOfFuel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ran out of fuel after {} instructions, see GRAPH_ETHEREUM_DECODER_FUEL",
+            self.0
+        )
+    }
+}
+
+impl HostError for OutOfFuel {}
+
+/// The instructions a decoder may still execute while decoding one block
+/// or receipt, out of `limit`
+struct Fuel {
+    limit: u64,
+    remaining: u64,
+}
+
+impl Fuel {
+    fn new(limit: u64) -> Self {
+        Fuel {
+            limit,
+            remaining: limit,
+        }
+    }
+}
+
+impl Externals for Fuel {
+    fn invoke_index(
+        &mut self,
+        index: usize,
+        args: RuntimeArgs,
+    ) -> Result<Option<RuntimeValue>, Trap> {
+        assert_eq!(GAS_FUNC_INDEX, index);
+        let used = args.nth_checked::<u32>(0)? as u64;
+        match self.remaining.checked_sub(used) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(None)
+            }
+            None => Err(Trap::new(TrapKind::Host(Box::new(OutOfFuel(self.limit))))),
+        }
+    }
+}
+
+impl WasmDecoder {
+    /// Load and check the decoder in the WASM file at `path`
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string_lossy().into_owned());
+        let module = fs::read(path)
+            .map_err(|e| format_err!("failed to read WASM decoder {:?}: {}", path, e))?;
+        Self::from_bytes(name, &module)
+    }
+
+    fn from_bytes(name: String, module: &[u8]) -> Result<Self, Error> {
+        let invalid = |e: &dyn fmt::Display| format_err!("invalid WASM decoder `{}`: {}", name, e);
+        let module: parity_wasm::elements::Module =
+            parity_wasm::deserialize_buffer(module).map_err(|e| invalid(&e))?;
+        if module
+            .import_section()
+            .map_or(false, |section| !section.entries().is_empty())
+        {
+            return Err(invalid(&"the module can not import anything"));
+        }
+        // Inject metering calls, which are used for limiting how many
+        // instructions decoding can take
+        let module = pwasm_utils::inject_gas_counter(module, &Default::default())
+            .map_err(|_| invalid(&"failed to inject gas counter"))?;
+        let module = Module::from_parity_wasm_module(module).map_err(|e| invalid(&e))?;
+
+        let mut decoder = WasmDecoder {
+            name,
+            version: 0,
+            module,
+            decodes_receipts: false,
+            fuel: *WASM_DECODER_FUEL,
+        };
+
+        let instance = decoder.instantiate()?;
+        let abi_version = decoder.call_i32(&instance, "abi_version")?;
+        if abi_version != WASM_DECODER_ABI_VERSION {
+            return Err(format_err!(
+                "WASM decoder `{}` implements ABI version {}, but only version {} is supported",
+                decoder.name,
+                abi_version,
+                WASM_DECODER_ABI_VERSION
+            ));
+        }
+        decoder.version = decoder.call_i32(&instance, "version")? as u32;
+        decoder.memory(&instance)?;
+        for export in &["alloc", "decode_block"] {
+            if instance.export_by_name(export).is_none() {
+                return Err(format_err!(
+                    "WASM decoder `{}` does not export `{}`",
+                    decoder.name,
+                    export
+                ));
+            }
+        }
+        decoder.decodes_receipts = instance.export_by_name("decode_receipt").is_some();
+        Ok(decoder)
+    }
+
+    fn instantiate(&self) -> Result<ModuleRef, Error> {
+        let imports = ImportsBuilder::new().with_resolver("env", &GasResolver);
+        let instance = ModuleInstance::new(&self.module, &imports).map_err(|e| {
+            format_err!("failed to instantiate WASM decoder `{}`: {}", self.name, e)
+        })?;
+        if instance.has_start() {
+            return Err(format_err!(
+                "WASM decoder `{}` can not have a start function",
+                self.name
+            ));
+        }
+        Ok(instance.not_started_instance().clone())
+    }
+
+    fn memory(&self, instance: &ModuleRef) -> Result<MemoryRef, Error> {
+        match instance.export_by_name("memory") {
+            Some(ExternVal::Memory(memory)) => Ok(memory),
+            _ => Err(format_err!(
+                "WASM decoder `{}` does not export `memory`",
+                self.name
+            )),
+        }
+    }
+
+    fn invoke(
+        &self,
+        instance: &ModuleRef,
+        function: &str,
+        args: &[RuntimeValue],
+        fuel: &mut Fuel,
+    ) -> Result<Option<RuntimeValue>, Error> {
+        instance.invoke_export(function, args, fuel).map_err(|e| {
+            format_err!(
+                "call to `{}` of WASM decoder `{}` failed: {}",
+                function,
+                self.name,
+                e
+            )
+        })
+    }
+
+    fn call_i32(&self, instance: &ModuleRef, function: &str) -> Result<i32, Error> {
+        match self.invoke(instance, function, &[], &mut Fuel::new(self.fuel))? {
+            Some(RuntimeValue::I32(value)) => Ok(value),
+            _ => Err(format_err!(
+                "`{}` of WASM decoder `{}` must return an i32",
+                function,
+                self.name
+            )),
+        }
+    }
+
+    /// Pass `value` to the exported function `function` and return the
+    /// value it produces
+    fn decode(&self, function: &str, value: serde_json::Value) -> Result<serde_json::Value, Error> {
+        let instance = self.instantiate()?;
+        let memory = self.memory(&instance)?;
+        let input = serde_json::to_vec(&value)?;
+        let mut fuel = Fuel::new(self.fuel);
+
+        let ptr = match self.invoke(
+            &instance,
+            "alloc",
+            &[RuntimeValue::I32(input.len() as i32)],
+            &mut fuel,
+        )? {
+            Some(RuntimeValue::I32(ptr)) => ptr,
+            _ => {
+                return Err(format_err!(
+                    "`alloc` of WASM decoder `{}` must return an i32",
+                    self.name
+                ))
+            }
+        };
+        memory
+            .set(ptr as u32, &input)
+            .map_err(|e| format_err!("failed to write to WASM decoder `{}`: {}", self.name, e))?;
+
+        let packed = match self.invoke(
+            &instance,
+            function,
+            &[
+                RuntimeValue::I32(ptr),
+                RuntimeValue::I32(input.len() as i32),
+            ],
+            &mut fuel,
+        )? {
+            Some(RuntimeValue::I64(packed)) => packed as u64,
+            _ => {
+                return Err(format_err!(
+                    "`{}` of WASM decoder `{}` must return an i64",
+                    function,
+                    self.name
+                ))
+            }
+        };
+        let output = memory
+            .get((packed >> 32) as u32, (packed & 0xffff_ffff) as usize)
+            .map_err(|e| format_err!("failed to read from WASM decoder `{}`: {}", self.name, e))?;
+        serde_json::from_slice(&output).map_err(|e| {
+            format_err!(
+                "`{}` of WASM decoder `{}` returned invalid JSON: {}",
+                function,
+                self.name,
+                e
+            )
+        })
+    }
+}
+
+impl BlockDecoder for WasmDecoder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn decode_block(&self, block: serde_json::Value) -> Result<serde_json::Value, Error> {
+        self.decode("decode_block", block)
+    }
+
+    fn decode_receipt(&self, receipt: serde_json::Value) -> Result<serde_json::Value, Error> {
+        if self.decodes_receipts {
+            self.decode("decode_receipt", receipt)
+        } else {
+            Ok(receipt)
+        }
+    }
+}
+
+/// Parse a comma-separated list of `network=decoder` pairs into the
+/// decoder for each network
+fn parse_decoders(
+    spec: &str,
+    load_wasm: impl Fn(&Path) -> Result<Arc<dyn BlockDecoder>, Error>,
+) -> Result<HashMap<String, Arc<dyn BlockDecoder>>, Error> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let network = parts.next().unwrap_or_default().trim();
+            let decoder = parts.next().unwrap_or_default().trim();
+            if network.is_empty() || decoder.is_empty() {
+                return Err(format_err!(
+                    "invalid decoder `{}`, the format is `network=decoder`",
+                    entry
+                ));
+            }
+
+            let decoder = match native_decoder(decoder) {
+                Some(decoder) => decoder,
+                None if decoder.ends_with(".wasm") => load_wasm(Path::new(decoder))?,
+                None => {
+                    return Err(format_err!(
+                        "unknown decoder `{}` for network `{}`; use the name of a native \
+                         decoder or the path of a `.wasm` file",
+                        decoder,
+                        network
+                    ))
+                }
+            };
+            Ok((network.to_owned(), decoder))
+        })
+        .collect()
+}
+
+/// The decoders for each network as configured with
+/// `GRAPH_ETHEREUM_DECODERS`
+pub fn block_decoders_from_env() -> Result<HashMap<String, Arc<dyn BlockDecoder>>, Error> {
+    match ETHEREUM_DECODERS.as_ref() {
+        Some(spec) => parse_decoders(spec, |path| {
+            WasmDecoder::load(path).map(|decoder| Arc::new(decoder) as Arc<dyn BlockDecoder>)
+        }),
+        None => Ok(HashMap::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::prelude::serde_json::json;
+    use graph::prelude::web3::types::{Block, Transaction, TransactionReceipt};
+    use parity_wasm::builder;
+    use parity_wasm::elements::{BlockType, Instruction, Instructions, ValueType};
+
+    #[test]
+    fn lenient_decoder_fills_missing_fields() {
+        let block = json!({
+            "hash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+            "parentHash": "0x0000000000000000000000000000000000000000000000000000000000000000",
+            "sha3Uncles": "0x0000000000000000000000000000000000000000000000000000000000000000",
+            "miner": ZERO_ADDRESS,
+            "stateRoot": "0x0000000000000000000000000000000000000000000000000000000000000000",
+            "transactionsRoot": "0x0000000000000000000000000000000000000000000000000000000000000000",
+            "receiptsRoot": "0x0000000000000000000000000000000000000000000000000000000000000000",
+            "number": "0x1",
+            "gasUsed": "0x0",
+            "gasLimit": "0x0",
+            "timestamp": "0x0",
+            "difficulty": null,
+            "transactions": [{
+                "hash": "0x0000000000000000000000000000000000000000000000000000000000000002",
+                "blockHash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+                "blockNumber": "0x1",
+                "transactionIndex": "0x0",
+                "to": null,
+                "gas": "0x5208",
+                "input": "0x",
+            }],
+        });
+        let block = LenientDecoder.decode_block(block).unwrap();
+        assert_eq!(json!("0x0"), block["difficulty"]);
+        assert_eq!(json!("0x0"), block["transactions"][0]["gasPrice"]);
+        assert_eq!(json!("0x5208"), block["transactions"][0]["gas"]);
+        serde_json::from_value::<Block<Transaction>>(block).unwrap();
+
+        let receipt = json!({
+            "transactionHash": "0x0000000000000000000000000000000000000000000000000000000000000002",
+            "transactionIndex": "0x0",
+            "blockHash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+            "blockNumber": "0x1",
+            "gasUsed": "0x0",
+            "contractAddress": null,
+        });
+        let receipt = LenientDecoder.decode_receipt(receipt).unwrap();
+        serde_json::from_value::<TransactionReceipt>(receipt).unwrap();
+    }
+
+    #[test]
+    fn parse_decoders_per_network() {
+        let decoders = parse_decoders(" arbitrum=lenient, optimism = /opt/op.wasm ", |path| {
+            assert_eq!(Path::new("/opt/op.wasm"), path);
+            Ok(Arc::new(LenientDecoder))
+        })
+        .unwrap();
+        assert_eq!(2, decoders.len());
+        assert_eq!("lenient", decoders["arbitrum"].name());
+        assert!(decoders.contains_key("optimism"));
+
+        let no_wasm = |_: &Path| -> Result<Arc<dyn BlockDecoder>, Error> { unreachable!() };
+        assert!(parse_decoders("mainnet", no_wasm).is_err());
+        assert!(parse_decoders("mainnet=unknown", no_wasm).is_err());
+        assert!(parse_decoders("", no_wasm).unwrap().is_empty());
+    }
+
+    /// A WASM decoder whose `decode_block` runs `decode_block`
+    fn wasm_decoder(decode_block: Vec<Instruction>) -> Vec<u8> {
+        let function = |params: Vec<ValueType>, result: ValueType, body: Vec<Instruction>| {
+            builder::function()
+                .signature()
+                .with_params(params)
+                .with_return_type(Some(result))
+                .build()
+                .body()
+                .with_instructions(Instructions::new(body))
+                .build()
+                .build()
+        };
+        let export =
+            |name: &str, index: u32| builder::export().field(name).internal().func(index).build();
+
+        let module = builder::module()
+            .memory()
+            .with_min(1)
+            .build()
+            .export()
+            .field("memory")
+            .internal()
+            .memory(0)
+            .build()
+            .with_function(function(
+                vec![],
+                ValueType::I32,
+                vec![
+                    Instruction::I32Const(WASM_DECODER_ABI_VERSION),
+                    Instruction::End,
+                ],
+            ))
+            .with_function(function(
+                vec![],
+                ValueType::I32,
+                vec![Instruction::I32Const(7), Instruction::End],
+            ))
+            .with_function(function(
+                vec![ValueType::I32],
+                ValueType::I32,
+                vec![Instruction::I32Const(0), Instruction::End],
+            ))
+            .with_function(function(
+                vec![ValueType::I32, ValueType::I32],
+                ValueType::I64,
+                decode_block,
+            ))
+            .with_export(export("abi_version", 0))
+            .with_export(export("version", 1))
+            .with_export(export("alloc", 2))
+            .with_export(export("decode_block", 3))
+            .build();
+        parity_wasm::serialize(module).unwrap()
+    }
+
+    #[test]
+    fn wasm_decoders_are_metered() {
+        use Instruction::*;
+
+        // Return the input unchanged as `(ptr << 32) | len`
+        let identity = wasm_decoder(vec![
+            GetLocal(0),
+            I64ExtendUI32,
+            I64Const(32),
+            I64Shl,
+            GetLocal(1),
+            I64ExtendUI32,
+            I64Or,
+            End,
+        ]);
+        let decoder = WasmDecoder::from_bytes("identity".to_owned(), &identity).unwrap();
+        assert_eq!(7, decoder.version());
+        let block = json!({ "number": "0x1", "transactions": [] });
+        assert_eq!(block, decoder.decode_block(block.clone()).unwrap());
+        let receipt = json!({ "gasUsed": "0x0" });
+        assert_eq!(receipt, decoder.decode_receipt(receipt.clone()).unwrap());
+
+        let looping = wasm_decoder(vec![
+            Loop(BlockType::NoResult),
+            Br(0),
+            End,
+            I64Const(0),
+            End,
+        ]);
+        let mut decoder = WasmDecoder::from_bytes("looping".to_owned(), &looping).unwrap();
+        decoder.fuel = 10_000;
+        let e = decoder.decode_block(block).unwrap_err();
+        assert!(e.to_string().contains("OutOfFuel"));
+    }
+}
@@ -3,24 +3,108 @@ use futures::future;
 use futures::prelude::*;
 use lazy_static::lazy_static;
 use std::collections::HashSet;
+use std::fmt;
 use std::iter::FromIterator;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Instant;
 
 use ethabi::ParamType;
 use graph::components::ethereum::{EthereumAdapter as EthereumAdapterTrait, *};
 use graph::prelude::{
-    debug, err_msg, error, ethabi, format_err, hex, retry, stream, tiny_keccak, trace, warn, web3,
-    ChainStore, Error, EthereumCallCache, Logger, TimeoutError,
+    debug, err_msg, error, ethabi, format_err, hex, retry, serde_json, stream, tiny_keccak, trace,
+    warn, web3, ChainStore, Error, EthereumCallCache, Logger, TimeoutError,
 };
 use web3::api::Web3;
+use web3::helpers;
 use web3::transports::batch::Batch;
 use web3::types::{Filter, *};
 
+use crate::decoder::BlockDecoder;
+
+/// The Ethereum client software behind a provider. Clients differ in how
+/// they report reverted calls and in which methods they support. When the
+/// client is known, the adapter only applies the workarounds for that
+/// client; for `Generic`, it tries all of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EthereumClient {
+    Geth,
+    Erigon,
+    Parity,
+    Generic,
+}
+
+impl Default for EthereumClient {
+    fn default() -> Self {
+        EthereumClient::Generic
+    }
+}
+
+impl FromStr for EthereumClient {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "geth" => Ok(EthereumClient::Geth),
+            "erigon" => Ok(EthereumClient::Erigon),
+            "parity" => Ok(EthereumClient::Parity),
+            "generic" => Ok(EthereumClient::Generic),
+            _ => Err(format_err!(
+                "unknown Ethereum client `{}`, expected one of geth, erigon, parity, generic",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EthereumClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            EthereumClient::Geth => "geth",
+            EthereumClient::Erigon => "erigon",
+            EthereumClient::Parity => "parity",
+            EthereumClient::Generic => "generic",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl EthereumClient {
+    /// Geth and Erigon return the output of a reverted `eth_call`, which is
+    /// `0x` or an encoded `Error(string)`
+    fn reverts_in_output(&self) -> bool {
+        match self {
+            EthereumClient::Geth | EthereumClient::Erigon | EthereumClient::Generic => true,
+            EthereumClient::Parity => false,
+        }
+    }
+
+    /// Parity reports reverts as a VM execution error
+    fn reverts_as_parity_error(&self) -> bool {
+        match self {
+            EthereumClient::Parity | EthereumClient::Generic => true,
+            EthereumClient::Geth | EthereumClient::Erigon => false,
+        }
+    }
+
+    /// Clients we know nothing about might be Ganache
+    fn reverts_as_ganache_error(&self) -> bool {
+        *self == EthereumClient::Generic
+    }
+
+    fn supports_trace_filter(&self) -> bool {
+        *self != EthereumClient::Geth
+    }
+}
+
 #[derive(Clone)]
 pub struct EthereumAdapter<T: web3::Transport> {
     web3: Arc<Web3<T>>,
     metrics: Arc<ProviderEthRpcMetrics>,
+    client: EthereumClient,
+    /// Rewrites blocks and receipts for networks whose nodes do not
+    /// return them in the form Ethereum nodes do
+    decoder: Option<Arc<dyn BlockDecoder>>,
 }
 
 lazy_static! {
@@ -73,13 +157,25 @@ where
     T::Batch: Send,
     T::Out: Send,
 {
-    pub fn new(transport: T, provider_metrics: Arc<ProviderEthRpcMetrics>) -> Self {
+    pub fn new(
+        transport: T,
+        provider_metrics: Arc<ProviderEthRpcMetrics>,
+        client: EthereumClient,
+    ) -> Self {
         EthereumAdapter {
             web3: Arc::new(Web3::new(transport)),
             metrics: provider_metrics,
+            client,
+            decoder: None,
         }
     }
 
+    /// Pass all blocks and receipts from the node through `decoder`
+    pub fn with_decoder(mut self, decoder: Arc<dyn BlockDecoder>) -> Self {
+        self.decoder = Some(decoder);
+        self
+    }
+
     fn traces(
         &self,
         logger: &Logger,
@@ -88,10 +184,18 @@ where
         to: u64,
         addresses: Vec<H160>,
     ) -> impl Future<Item = Vec<Trace>, Error = Error> {
+        if !self.client.supports_trace_filter() {
+            return future::Either::A(future::err(format_err!(
+                "the Ethereum node is configured as {}, which does not support trace_filter; \
+                 call handlers and block handlers with a call filter need a parity or erigon node",
+                self.client
+            )));
+        }
+
         let eth = self.clone();
         let logger = logger.to_owned();
 
-        retry("trace_filter RPC call", &logger)
+        let traces = retry("trace_filter RPC call", &logger)
             .limit(*REQUEST_RETRIES)
             .timeout_secs(*JSON_RPC_TIMEOUT)
             .run(move || {
@@ -164,7 +268,8 @@ where
                         to
                     )
                 })
-            })
+            });
+        future::Either::B(traces)
     }
 
     fn logs_with_sigs(
@@ -358,6 +463,7 @@ where
     ) -> impl Future<Item = Bytes, Error = EthereumContractCallError> + Send {
         let web3 = self.web3.clone();
         let logger = logger.clone();
+        let client = self.client;
 
         // Outer retry used only for 0-byte responses,
         // where we can't guarantee the problem is temporary.
@@ -396,100 +502,9 @@ where
                             value: None,
                             data: Some(call_data.clone()),
                         };
-                        web3.eth().call(req, block_number_opt).then(|result| {
-                            // Try to check if the call was reverted. The JSON-RPC response for
-                            // reverts is not standardized, the current situation for the tested
-                            // clients is:
-                            //
-                            // - Parity/Alchemy returns a reliable RPC error response for reverts.
-                            // - Ganache also returns a reliable RPC error.
-                            // - Geth/Infura will either return `0x` on a revert with no reason
-                            //   string, or a Solidity encoded `Error(string)` call from `revert`
-                            //   and `require` calls with a reason string.
-
-                            // 0xfe is the "designated bad instruction" of the EVM, and Solidity
-                            // uses it for asserts.
-                            const PARITY_BAD_INSTRUCTION_FE: &str = "Bad instruction fe";
-
-                            // 0xfd is REVERT, but on some contracts, and only on older blocks,
-                            // this happens. Makes sense to consider it a revert as well.
-                            const PARITY_BAD_INSTRUCTION_FD: &str = "Bad instruction fd";
-
-                            const PARITY_BAD_JUMP_PREFIX: &str = "Bad jump";
-                            const GANACHE_VM_EXECUTION_ERROR: i64 = -32000;
-                            const GANACHE_REVERT_MESSAGE: &str =
-                                "VM Exception while processing transaction: revert";
-                            const PARITY_VM_EXECUTION_ERROR: i64 = -32015;
-                            const PARITY_REVERT_PREFIX: &str = "Reverted 0x";
-
-                            let as_solidity_revert_with_reason = |bytes: &[u8]| {
-                                let solidity_revert_function_selector =
-                                    &tiny_keccak::keccak256(b"Error(string)")[..4];
-
-                                match bytes.len() >= 4
-                                    && &bytes[..4] == solidity_revert_function_selector
-                                {
-                                    false => None,
-                                    true => ethabi::decode(&[ParamType::String], &bytes[4..])
-                                        .ok()
-                                        .and_then(|tokens| tokens[0].clone().to_string()),
-                                }
-                            };
-
-                            match result {
-                                // Check for Geth revert with reason.
-                                Ok(bytes) => match as_solidity_revert_with_reason(&bytes.0) {
-                                    None => Ok(bytes),
-                                    Some(reason) => Err(EthereumContractCallError::Revert(reason)),
-                                },
-
-                                // Check for Parity revert.
-                                Err(web3::Error::Rpc(ref rpc_error))
-                                    if rpc_error.code.code() == PARITY_VM_EXECUTION_ERROR =>
-                                {
-                                    match rpc_error.data.as_ref().and_then(|d| d.as_str()) {
-                                        Some(data)
-                                            if data.starts_with(PARITY_REVERT_PREFIX)
-                                                || data.starts_with(PARITY_BAD_JUMP_PREFIX)
-                                                || data == PARITY_BAD_INSTRUCTION_FE
-                                                || data == PARITY_BAD_INSTRUCTION_FD =>
-                                        {
-                                            let reason = if data == PARITY_BAD_INSTRUCTION_FE {
-                                                PARITY_BAD_INSTRUCTION_FE.to_owned()
-                                            } else {
-                                                let payload =
-                                                    data.trim_start_matches(PARITY_REVERT_PREFIX);
-                                                hex::decode(payload)
-                                                    .ok()
-                                                    .and_then(|payload| {
-                                                        as_solidity_revert_with_reason(&payload)
-                                                    })
-                                                    .unwrap_or("no reason".to_owned())
-                                            };
-                                            Err(EthereumContractCallError::Revert(reason))
-                                        }
-
-                                        // The VM execution error was not identified as a revert.
-                                        _ => Err(EthereumContractCallError::Web3Error(
-                                            web3::Error::Rpc(rpc_error.clone()),
-                                        )),
-                                    }
-                                }
-
-                                // Check for Ganache revert.
-                                Err(web3::Error::Rpc(ref rpc_error))
-                                    if rpc_error.code.code() == GANACHE_VM_EXECUTION_ERROR
-                                        && rpc_error.message == GANACHE_REVERT_MESSAGE =>
-                                {
-                                    Err(EthereumContractCallError::Revert(
-                                        rpc_error.message.clone(),
-                                    ))
-                                }
-
-                                // The error was not identified as a revert.
-                                Err(err) => Err(EthereumContractCallError::Web3Error(err)),
-                            }
-                        })
+                        web3.eth()
+                            .call(req, block_number_opt)
+                            .then(move |result| check_for_revert(client, result))
                     })
                     .map_err(|e| e.into_inner().unwrap_or(EthereumContractCallError::Timeout))
             })
@@ -502,16 +517,16 @@ where
         ids: Vec<H256>,
     ) -> impl Stream<Item = LightEthereumBlock, Error = Error> + Send {
         let web3 = self.web3.clone();
+        let decoder = self.decoder.clone();
 
         stream::iter_ok::<_, Error>(ids.into_iter().map(move |hash| {
             let web3 = web3.clone();
+            let decoder = decoder.clone();
             retry(format!("load block {}", hash), &logger)
                 .limit(*REQUEST_RETRIES)
                 .timeout_secs(*JSON_RPC_TIMEOUT)
                 .run(move || {
-                    web3.eth()
-                        .block_with_txs(BlockId::Hash(hash))
-                        .from_err::<Error>()
+                    block_with_txs(&web3, &decoder, BlockId::Hash(hash))
                         .map_err(|e| e.compat())
                         .and_then(move |block| {
                             block.ok_or_else(|| {
@@ -533,23 +548,26 @@ where
         block_nums: Vec<u64>,
     ) -> impl Stream<Item = EthereumBlockPointer, Error = Error> + Send {
         let web3 = self.web3.clone();
+        let decoder = self.decoder.clone();
 
         stream::iter_ok::<_, Error>(block_nums.into_iter().map(move |block_num| {
             let web3 = web3.clone();
+            let decoder = decoder.clone();
             retry(format!("load block ptr {}", block_num), &logger)
                 .no_limit()
                 .timeout_secs(*JSON_RPC_TIMEOUT)
                 .run(move || {
-                    web3.eth()
-                        .block(BlockId::Number(BlockNumber::Number(block_num.into())))
-                        .from_err::<Error>()
-                        .map_err(|e| e.compat())
-                        .and_then(move |block| {
-                            block.ok_or_else(|| {
-                                format_err!("Ethereum node did not find block {:?}", block_num)
-                                    .compat()
-                            })
+                    block_with_tx_hashes(
+                        &web3,
+                        &decoder,
+                        BlockId::Number(BlockNumber::Number(block_num.into())),
+                    )
+                    .map_err(|e| e.compat())
+                    .and_then(move |block| {
+                        block.ok_or_else(|| {
+                            format_err!("Ethereum node did not find block {:?}", block_num).compat()
                         })
+                    })
                 })
                 .from_err()
         }))
@@ -564,6 +582,14 @@ where
     T::Batch: Send,
     T::Out: Send,
 {
+    fn capabilities(&self) -> EthereumAdapterCapabilities {
+        EthereumAdapterCapabilities {
+            client: self.client.to_string(),
+            traces: self.client.supports_trace_filter(),
+            decoded_blocks: self.decoder.is_some(),
+        }
+    }
+
     fn net_identifiers(
         &self,
         logger: &Logger,
@@ -577,14 +603,13 @@ where
             .run(move || web3.net().version().from_err());
 
         let web3 = self.web3.clone();
+        let decoder = self.decoder.clone();
         let gen_block_hash_future = retry("eth_getBlockByNumber(0, false) RPC call", &logger)
             .no_limit()
             .timeout_secs(30)
             .run(move || {
-                web3.eth()
-                    .block(BlockNumber::Earliest.into())
-                    .from_err()
-                    .and_then(|gen_block_opt| {
+                block_with_tx_hashes(&web3, &decoder, BlockNumber::Earliest.into()).and_then(
+                    |gen_block_opt| {
                         future::result(
                             gen_block_opt
                                 .ok_or_else(|| {
@@ -592,7 +617,8 @@ where
                                 })
                                 .map(|gen_block| gen_block.hash.unwrap()),
                         )
-                    })
+                    },
+                )
             });
 
         Box::new(
@@ -617,14 +643,14 @@ where
         logger: &Logger,
     ) -> Box<dyn Future<Item = LightEthereumBlock, Error = EthereumAdapterError> + Send> {
         let web3 = self.web3.clone();
+        let decoder = self.decoder.clone();
 
         Box::new(
             retry("eth_getBlockByNumber(latest) RPC call", logger)
                 .no_limit()
                 .timeout_secs(*JSON_RPC_TIMEOUT)
                 .run(move || {
-                    web3.eth()
-                        .block_with_txs(BlockNumber::Latest.into())
+                    block_with_txs(&web3, &decoder, BlockNumber::Latest.into())
                         .map_err(|e| format_err!("could not get latest block from Ethereum: {}", e))
                         .from_err()
                         .and_then(|block_opt| {
@@ -665,17 +691,14 @@ where
         block_hash: H256,
     ) -> Box<dyn Future<Item = Option<LightEthereumBlock>, Error = Error> + Send> {
         let web3 = self.web3.clone();
+        let decoder = self.decoder.clone();
         let logger = logger.clone();
 
         Box::new(
             retry("eth_getBlockByHash RPC call", &logger)
                 .limit(*REQUEST_RETRIES)
                 .timeout_secs(*JSON_RPC_TIMEOUT)
-                .run(move || {
-                    web3.eth()
-                        .block_with_txs(BlockId::Hash(block_hash))
-                        .from_err()
-                })
+                .run(move || block_with_txs(&web3, &decoder, BlockId::Hash(block_hash)))
                 .map_err(move |e| {
                     e.into_inner().unwrap_or_else(move || {
                         format_err!("Ethereum node took too long to return block {}", block_hash)
@@ -690,17 +713,14 @@ where
         block_number: u64,
     ) -> Box<dyn Future<Item = Option<LightEthereumBlock>, Error = Error> + Send> {
         let web3 = self.web3.clone();
+        let decoder = self.decoder.clone();
         let logger = logger.clone();
 
         Box::new(
             retry("eth_getBlockByNumber RPC call", &logger)
                 .no_limit()
                 .timeout_secs(*JSON_RPC_TIMEOUT)
-                .run(move || {
-                    web3.eth()
-                        .block_with_txs(BlockId::Number(block_number.into()))
-                        .from_err()
-                })
+                .run(move || block_with_txs(&web3, &decoder, BlockId::Number(block_number.into())))
                 .map_err(move |e| {
                     e.into_inner().unwrap_or_else(move || {
                         format_err!(
@@ -730,6 +750,7 @@ where
             }));
         }
         let web3 = self.web3.clone();
+        let decoder = self.decoder.clone();
 
         // Retry, but eventually give up.
         // A receipt might be missing because the block was uncled, and the
@@ -750,10 +771,7 @@ where
                             let logger = logger.clone();
                             let tx_hash = tx.hash;
 
-                            batching_web3
-                                .eth()
-                                .transaction_receipt(tx_hash)
-                                .from_err()
+                            transaction_receipt(&batching_web3, &decoder, tx_hash)
                                 .map_err(EthereumAdapterError::Unknown)
                                 .and_then(move |receipt_opt| {
                                     receipt_opt.ok_or_else(move || {
@@ -867,6 +885,7 @@ where
         block_is_final: bool,
     ) -> Box<dyn Future<Item = Option<H256>, Error = Error> + Send> {
         let web3 = self.web3.clone();
+        let decoder = self.decoder.clone();
 
         let mut hashes = match chain_store.block_hashes_by_block_number(block_number) {
             Ok(hashes) => hashes,
@@ -905,9 +924,7 @@ where
                     .no_limit()
                     .timeout_secs(*JSON_RPC_TIMEOUT)
                     .run(move || {
-                        web3.eth()
-                            .block(BlockId::Number(block_number.into()))
-                            .from_err()
+                        block_with_tx_hashes(&web3, &decoder, BlockId::Number(block_number.into()))
                             .map(|block_opt| block_opt.map(|block| block.hash.unwrap()))
                     })
                     .inspect(confirm_block_hash)
@@ -934,22 +951,21 @@ where
         Box::new(
             futures::stream::futures_ordered((0..n).map(move |index| {
                 let web3 = self.web3.clone();
+                let decoder = self.decoder.clone();
 
                 retry("eth_getUncleByBlockHashAndIndex RPC call", &logger)
                     .no_limit()
                     .timeout_secs(60)
                     .run(move || {
-                        web3.eth()
-                            .uncle(block_hash.clone().into(), index.into())
-                            .map_err(move |e| {
-                                format_err!(
-                                    "could not get uncle {} for block {:?} ({} uncles): {}",
-                                    index,
-                                    block_hash,
-                                    n,
-                                    e
-                                )
-                            })
+                        uncle(&web3, &decoder, block_hash, index).map_err(move |e| {
+                            format_err!(
+                                "could not get uncle {} for block {:?} ({} uncles): {}",
+                                index,
+                                block_hash,
+                                n,
+                                e
+                            )
+                        })
                     })
                     .map_err(move |e| {
                         e.into_inner().unwrap_or_else(move || {
@@ -1082,6 +1098,54 @@ where
         )
     }
 
+    fn pending_block(
+        &self,
+        logger: &Logger,
+    ) -> Box<dyn Future<Item = Option<(LightEthereumBlock, Vec<Log>)>, Error = Error> + Send> {
+        let web3 = self.web3.clone();
+        let decoder = self.decoder.clone();
+
+        Box::new(
+            retry("eth_getBlockByNumber(pending) RPC call", logger)
+                .limit(*REQUEST_RETRIES)
+                .timeout_secs(*JSON_RPC_TIMEOUT)
+                .run(move || {
+                    let logs_filter = FilterBuilder::default()
+                        .from_block(BlockNumber::Pending)
+                        .to_block(BlockNumber::Pending)
+                        .build();
+
+                    block_with_txs(&web3, &decoder, BlockNumber::Pending.into())
+                        .join(web3.eth().logs(logs_filter).from_err())
+                        .map_err(|e| {
+                            format_err!("could not get pending block from Ethereum: {}", e)
+                        })
+                        .map(|(block, logs)| {
+                            block.map(|block| {
+                                // The pending block can change between the two
+                                // calls; only keep the logs of transactions that
+                                // are in the block we got
+                                let hashes: HashSet<_> =
+                                    block.transactions.iter().map(|tx| tx.hash).collect();
+                                let logs = logs
+                                    .into_iter()
+                                    .filter(|log| {
+                                        log.transaction_hash
+                                            .map_or(false, |hash| hashes.contains(&hash))
+                                    })
+                                    .collect();
+                                (block, logs)
+                            })
+                        })
+                })
+                .map_err(move |e| {
+                    e.into_inner().unwrap_or_else(move || {
+                        format_err!("Ethereum node took too long to return the pending block")
+                    })
+                }),
+        )
+    }
+
     fn contract_call(
         &self,
         logger: &Logger,
@@ -1105,14 +1169,27 @@ where
         // Encode the call parameters according to the ABI
         let call_data = call.function.encode_input(&call.args).unwrap();
 
+        // Calls for the pending block are made against the node's pending
+        // state and never cached, since all pending blocks share a hash
+        let pending = call.block_ptr.is_pending();
+        let block_number = if pending {
+            BlockNumber::Pending
+        } else {
+            call.block_ptr.number.into()
+        };
+
         // Check if we have it cached, if not do the call and cache.
-        Box::new(
-            match cache
+        let cached = if pending {
+            None
+        } else {
+            cache
                 .get_call(call.address, &call_data, call.block_ptr)
                 .map_err(|e| error!(logger, "call cache get error"; "error" => e.to_string()))
                 .ok()
                 .and_then(|x| x)
-            {
+        };
+        Box::new(
+            match cached {
                 Some(result) => {
                     Box::new(future::ok(result)) as Box<dyn Future<Item = _, Error = _> + Send>
                 }
@@ -1126,15 +1203,17 @@ where
                             &logger,
                             call.address,
                             Bytes(call_data.clone()),
-                            Some(call.block_ptr.number.into()),
+                            Some(block_number),
                         )
                         .map(move |result| {
-                            let _ = cache
-                                .set_call(call.address, &call_data, call.block_ptr, &result.0)
-                                .map_err(|e| {
-                                    error!(logger, "call cache set error";
-                                                   "error" => e.to_string())
-                                });
+                            if !pending {
+                                let _ = cache
+                                    .set_call(call.address, &call_data, call.block_ptr, &result.0)
+                                    .map_err(|e| {
+                                        error!(logger, "call cache set error";
+                                                       "error" => e.to_string())
+                                    });
+                            }
                             result.0
                         }),
                     )
@@ -1211,3 +1290,383 @@ where
         )
     }
 }
+
+/// Request a block or uncle with the JSON-RPC `method` as raw JSON and
+/// pass it through `decoder`. Blocks whose transactions are only hashes
+/// are decoded the same way; decoders leave such transactions alone
+fn decoded_block<T>(
+    web3: &Web3<T>,
+    decoder: Arc<dyn BlockDecoder>,
+    method: &'static str,
+    params: Vec<serde_json::Value>,
+) -> Box<dyn Future<Item = Option<serde_json::Value>, Error = Error> + Send>
+where
+    T: web3::Transport,
+    T::Out: Send + 'static,
+{
+    Box::new(
+        web3.transport()
+            .execute(method, params)
+            .from_err()
+            .and_then(move |block| {
+                if block.is_null() {
+                    return Ok(None);
+                }
+                decoder.decode_block(block).map(Some)
+            }),
+    )
+}
+
+fn invalid_decoded_block(decoder: &dyn BlockDecoder, e: serde_json::Error) -> Error {
+    format_err!(
+        "block decoded by `{}` version {} is invalid: {}",
+        decoder.name(),
+        decoder.version(),
+        e
+    )
+}
+
+fn block_params(id: BlockId, full_transactions: bool) -> (&'static str, Vec<serde_json::Value>) {
+    let (method, id) = match id {
+        BlockId::Hash(hash) => ("eth_getBlockByHash", helpers::serialize(&hash)),
+        BlockId::Number(number) => ("eth_getBlockByNumber", helpers::serialize(&number)),
+    };
+    (method, vec![id, helpers::serialize(&full_transactions)])
+}
+
+/// Request a block with its transactions. If there is a `decoder`, the
+/// block is requested as raw JSON and passed through the decoder before
+/// it is deserialized.
+fn block_with_txs<T>(
+    web3: &Web3<T>,
+    decoder: &Option<Arc<dyn BlockDecoder>>,
+    id: BlockId,
+) -> Box<dyn Future<Item = Option<LightEthereumBlock>, Error = Error> + Send>
+where
+    T: web3::Transport,
+    T::Out: Send + 'static,
+{
+    let decoder = match decoder {
+        Some(decoder) => decoder.clone(),
+        None => return Box::new(web3.eth().block_with_txs(id).from_err()),
+    };
+
+    let (method, params) = block_params(id, true);
+    Box::new(
+        decoded_block(web3, decoder.clone(), method, params).and_then(move |block| {
+            block
+                .map(|block| {
+                    serde_json::from_value(block)
+                        .map_err(|e| invalid_decoded_block(decoder.as_ref(), e))
+                })
+                .transpose()
+        }),
+    )
+}
+
+/// Request a block with the hashes of its transactions, passing it through
+/// the `decoder` if there is one
+fn block_with_tx_hashes<T>(
+    web3: &Web3<T>,
+    decoder: &Option<Arc<dyn BlockDecoder>>,
+    id: BlockId,
+) -> Box<dyn Future<Item = Option<Block<H256>>, Error = Error> + Send>
+where
+    T: web3::Transport,
+    T::Out: Send + 'static,
+{
+    let decoder = match decoder {
+        Some(decoder) => decoder.clone(),
+        None => return Box::new(web3.eth().block(id).from_err()),
+    };
+
+    let (method, params) = block_params(id, false);
+    Box::new(
+        decoded_block(web3, decoder.clone(), method, params).and_then(move |block| {
+            block
+                .map(|block| {
+                    serde_json::from_value(block)
+                        .map_err(|e| invalid_decoded_block(decoder.as_ref(), e))
+                })
+                .transpose()
+        }),
+    )
+}
+
+/// Request the uncle of block `block_hash` at `index`, passing it through
+/// the `decoder` if there is one
+fn uncle<T>(
+    web3: &Web3<T>,
+    decoder: &Option<Arc<dyn BlockDecoder>>,
+    block_hash: H256,
+    index: usize,
+) -> Box<dyn Future<Item = Option<Block<H256>>, Error = Error> + Send>
+where
+    T: web3::Transport,
+    T::Out: Send + 'static,
+{
+    let decoder = match decoder {
+        Some(decoder) => decoder.clone(),
+        None => return Box::new(web3.eth().uncle(block_hash.into(), index.into()).from_err()),
+    };
+
+    let params = vec![
+        helpers::serialize(&block_hash),
+        helpers::serialize(&Index::from(index)),
+    ];
+    Box::new(
+        decoded_block(
+            web3,
+            decoder.clone(),
+            "eth_getUncleByBlockHashAndIndex",
+            params,
+        )
+        .and_then(move |block| {
+            block
+                .map(|block| {
+                    serde_json::from_value(block)
+                        .map_err(|e| invalid_decoded_block(decoder.as_ref(), e))
+                })
+                .transpose()
+        }),
+    )
+}
+
+/// Request the receipt of a transaction, passing it through the `decoder`
+/// if there is one
+fn transaction_receipt<T>(
+    web3: &Web3<T>,
+    decoder: &Option<Arc<dyn BlockDecoder>>,
+    tx_hash: H256,
+) -> Box<dyn Future<Item = Option<TransactionReceipt>, Error = Error> + Send>
+where
+    T: web3::Transport,
+    T::Out: Send + 'static,
+{
+    let decoder = match decoder {
+        Some(decoder) => decoder.clone(),
+        None => return Box::new(web3.eth().transaction_receipt(tx_hash).from_err()),
+    };
+
+    Box::new(
+        web3.transport()
+            .execute(
+                "eth_getTransactionReceipt",
+                vec![helpers::serialize(&tx_hash)],
+            )
+            .from_err()
+            .and_then(move |receipt| {
+                if receipt.is_null() {
+                    return Ok(None);
+                }
+                let receipt = decoder.decode_receipt(receipt)?;
+                serde_json::from_value(receipt).map(Some).map_err(|e| {
+                    format_err!(
+                        "receipt decoded by `{}` version {} is invalid: {}",
+                        decoder.name(),
+                        decoder.version(),
+                        e
+                    )
+                })
+            }),
+    )
+}
+
+/// Turn the result of an `eth_call` into a revert if `client` reports
+/// reverts that way
+fn check_for_revert(
+    client: EthereumClient,
+    result: Result<Bytes, web3::Error>,
+) -> Result<Bytes, EthereumContractCallError> {
+    // Try to check if the call was reverted. The JSON-RPC response for
+    // reverts is not standardized, the current situation for the tested
+    // clients is:
+    //
+    // - Parity/Alchemy returns a reliable RPC error response for reverts.
+    // - Ganache also returns a reliable RPC error.
+    // - Geth/Infura will either return `0x` on a revert with no reason
+    //   string, or a Solidity encoded `Error(string)` call from `revert`
+    //   and `require` calls with a reason string.
+    //
+    // If the provider is configured with a specific client, only the
+    // checks for that client are applied.
+
+    // 0xfe is the "designated bad instruction" of the EVM, and Solidity
+    // uses it for asserts.
+    const PARITY_BAD_INSTRUCTION_FE: &str = "Bad instruction fe";
+
+    // 0xfd is REVERT, but on some contracts, and only on older blocks,
+    // this happens. Makes sense to consider it a revert as well.
+    const PARITY_BAD_INSTRUCTION_FD: &str = "Bad instruction fd";
+
+    const PARITY_BAD_JUMP_PREFIX: &str = "Bad jump";
+    const GANACHE_VM_EXECUTION_ERROR: i64 = -32000;
+    const GANACHE_REVERT_MESSAGE: &str = "VM Exception while processing transaction: revert";
+    const PARITY_VM_EXECUTION_ERROR: i64 = -32015;
+    const PARITY_REVERT_PREFIX: &str = "Reverted 0x";
+
+    let as_solidity_revert_with_reason = |bytes: &[u8]| {
+        let solidity_revert_function_selector = &tiny_keccak::keccak256(b"Error(string)")[..4];
+
+        match bytes.len() >= 4 && &bytes[..4] == solidity_revert_function_selector {
+            false => None,
+            true => ethabi::decode(&[ParamType::String], &bytes[4..])
+                .ok()
+                .and_then(|tokens| tokens[0].clone().to_string()),
+        }
+    };
+
+    match result {
+        // Check for Geth revert with reason.
+        Ok(bytes) if client.reverts_in_output() => match as_solidity_revert_with_reason(&bytes.0) {
+            None => Ok(bytes),
+            Some(reason) => Err(EthereumContractCallError::Revert(reason)),
+        },
+        Ok(bytes) => Ok(bytes),
+
+        // Check for Parity revert.
+        Err(web3::Error::Rpc(ref rpc_error))
+            if client.reverts_as_parity_error()
+                && rpc_error.code.code() == PARITY_VM_EXECUTION_ERROR =>
+        {
+            match rpc_error.data.as_ref().and_then(|d| d.as_str()) {
+                Some(data)
+                    if data.starts_with(PARITY_REVERT_PREFIX)
+                        || data.starts_with(PARITY_BAD_JUMP_PREFIX)
+                        || data == PARITY_BAD_INSTRUCTION_FE
+                        || data == PARITY_BAD_INSTRUCTION_FD =>
+                {
+                    let reason = if data == PARITY_BAD_INSTRUCTION_FE {
+                        PARITY_BAD_INSTRUCTION_FE.to_owned()
+                    } else {
+                        let payload = data.trim_start_matches(PARITY_REVERT_PREFIX);
+                        hex::decode(payload)
+                            .ok()
+                            .and_then(|payload| as_solidity_revert_with_reason(&payload))
+                            .unwrap_or("no reason".to_owned())
+                    };
+                    Err(EthereumContractCallError::Revert(reason))
+                }
+
+                // The VM execution error was not identified as a revert.
+                _ => Err(EthereumContractCallError::Web3Error(web3::Error::Rpc(
+                    rpc_error.clone(),
+                ))),
+            }
+        }
+
+        // Check for Ganache revert.
+        Err(web3::Error::Rpc(ref rpc_error))
+            if client.reverts_as_ganache_error()
+                && rpc_error.code.code() == GANACHE_VM_EXECUTION_ERROR
+                && rpc_error.message == GANACHE_REVERT_MESSAGE =>
+        {
+            Err(EthereumContractCallError::Revert(rpc_error.message.clone()))
+        }
+
+        // The error was not identified as a revert.
+        Err(err) => Err(EthereumContractCallError::Web3Error(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::error::Error as Web3Error;
+
+    /// What `check_for_revert` makes of `result`: the revert reason, `ok`
+    /// for a successful call, or `error` for any other error
+    fn outcome(client: EthereumClient, result: Result<Bytes, Web3Error>) -> String {
+        match check_for_revert(client, result) {
+            Ok(_) => "ok".to_owned(),
+            Err(EthereumContractCallError::Revert(reason)) => reason,
+            Err(_) => "error".to_owned(),
+        }
+    }
+
+    fn revert_output(reason: &str) -> Vec<u8> {
+        let mut output = tiny_keccak::keccak256(b"Error(string)")[..4].to_vec();
+        output.extend(ethabi::encode(&[Token::String(reason.to_owned())]));
+        output
+    }
+
+    fn rpc_error(code: i64, message: &str, data: Option<String>) -> Web3Error {
+        Web3Error::Rpc(jsonrpc_core::Error {
+            code: jsonrpc_core::ErrorCode::ServerError(code),
+            message: message.to_owned(),
+            data: data.map(serde_json::Value::String),
+        })
+    }
+
+    #[test]
+    fn reverts_are_only_detected_the_way_the_client_reports_them() {
+        let geth = || Ok(Bytes(revert_output("geth says no")));
+        let parity = || {
+            Err(rpc_error(
+                -32015,
+                "VM execution error.",
+                Some(format!(
+                    "Reverted 0x{}",
+                    hex::encode(revert_output("parity says no"))
+                )),
+            ))
+        };
+        let ganache = || {
+            Err(rpc_error(
+                -32000,
+                "VM Exception while processing transaction: revert",
+                None,
+            ))
+        };
+
+        for client in &[EthereumClient::Geth, EthereumClient::Erigon] {
+            assert_eq!("geth says no", outcome(*client, geth()));
+            assert_eq!("error", outcome(*client, parity()));
+            assert_eq!("error", outcome(*client, ganache()));
+        }
+
+        assert_eq!("ok", outcome(EthereumClient::Parity, geth()));
+        assert_eq!("parity says no", outcome(EthereumClient::Parity, parity()));
+        assert_eq!("error", outcome(EthereumClient::Parity, ganache()));
+
+        assert_eq!("geth says no", outcome(EthereumClient::Generic, geth()));
+        assert_eq!("parity says no", outcome(EthereumClient::Generic, parity()));
+        assert_eq!(
+            "VM Exception while processing transaction: revert",
+            outcome(EthereumClient::Generic, ganache())
+        );
+
+        // Output that is not an encoded `Error(string)` is the result of
+        // the call for every client
+        for client in &[
+            EthereumClient::Geth,
+            EthereumClient::Erigon,
+            EthereumClient::Parity,
+            EthereumClient::Generic,
+        ] {
+            assert_eq!("ok", outcome(*client, Ok(Bytes(vec![0; 32]))));
+        }
+    }
+
+    #[test]
+    fn only_geth_does_not_support_trace_filter() {
+        assert!(!EthereumClient::Geth.supports_trace_filter());
+        assert!(EthereumClient::Erigon.supports_trace_filter());
+        assert!(EthereumClient::Parity.supports_trace_filter());
+        assert!(EthereumClient::Generic.supports_trace_filter());
+    }
+
+    #[test]
+    fn clients_round_trip_through_their_names() {
+        for client in &[
+            EthereumClient::Geth,
+            EthereumClient::Erigon,
+            EthereumClient::Parity,
+            EthereumClient::Generic,
+        ] {
+            assert_eq!(*client, client.to_string().parse().unwrap());
+        }
+        assert!("ganache".parse::<EthereumClient>().is_err());
+        assert_eq!(EthereumClient::Generic, EthereumClient::default());
+    }
+}
@@ -3,11 +3,15 @@ extern crate lazy_static;
 
 mod block_ingestor;
 mod block_stream;
+mod decoder;
 mod ethereum_adapter;
 pub mod network_indexer;
 mod transport;
 
-pub use self::block_ingestor::{BlockIngestor, BlockIngestorMetrics};
+pub use self::block_ingestor::{BlockIngestor, BlockIngestorMetrics, ReorgMetrics};
 pub use self::block_stream::{BlockStream, BlockStreamBuilder};
-pub use self::ethereum_adapter::EthereumAdapter;
+pub use self::decoder::{
+    block_decoders_from_env, BlockDecoder, LenientDecoder, WasmDecoder, WASM_DECODER_ABI_VERSION,
+};
+pub use self::ethereum_adapter::{EthereumAdapter, EthereumClient};
 pub use self::transport::{EventLoopHandle, Transport};
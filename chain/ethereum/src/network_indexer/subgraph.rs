@@ -92,6 +92,8 @@ fn create_subgraph(
             .expect("valid Ethereum network subgraph schema"),
         data_sources: vec![],
         templates: vec![],
+        graft: None,
+        features: vec![],
     };
 
     // Create deployment entity
@@ -9,7 +9,7 @@ use ethabi::{Function, Param, ParamType, Token};
 use graph::components::ethereum::EthereumContractCall;
 use graph::prelude::EthereumAdapter as EthereumAdapterTrait;
 use graph::prelude::*;
-use graph_chain_ethereum::EthereumAdapter;
+use graph_chain_ethereum::{EthereumAdapter, EthereumClient};
 use mock::MockMetricsRegistry;
 use web3::helpers::*;
 use web3::types::*;
@@ -171,7 +171,7 @@ fn contract_call() {
 
     let provider_metrics = Arc::new(ProviderEthRpcMetrics::new(registry.clone()));
 
-    let adapter = EthereumAdapter::new(transport, provider_metrics);
+    let adapter = EthereumAdapter::new(transport, provider_metrics, EthereumClient::Generic);
     let balance_of = Function {
         name: "balanceOf".to_owned(),
         inputs: vec![Param {
@@ -200,3 +200,54 @@ fn contract_call() {
 
     assert_eq!(call_result[0], Token::Uint(U256::from(100000)));
 }
+
+#[test]
+fn trace_filter_is_only_requested_from_clients_that_support_it() {
+    let registry = Arc::new(MockMetricsRegistry::new());
+    let logger = Logger::root(slog::Discard, o!());
+    let provider_metrics = Arc::new(ProviderEthRpcMetrics::new(registry.clone()));
+    let subgraph_metrics = Arc::new(SubgraphEthRpcMetrics::new(
+        registry.clone(),
+        "traces".to_owned(),
+    ));
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // Asking Geth for the calls in a block fails without sending a request
+    let mut transport = TestTransport::default();
+    let adapter = EthereumAdapter::new(
+        transport.clone(),
+        provider_metrics.clone(),
+        EthereumClient::Geth,
+    );
+    assert!(!adapter.capabilities().traces);
+    let err = runtime
+        .block_on(
+            adapter
+                .calls_in_block(&logger, subgraph_metrics.clone(), 1, H256::zero())
+                .compat(),
+        )
+        .unwrap_err();
+    assert!(err.to_string().contains("does not support trace_filter"));
+    transport.assert_no_more_requests();
+
+    // Parity is asked for the traces of the block
+    let mut transport = TestTransport::default();
+    transport.set_response(jsonrpc_core::Value::Array(vec![]));
+    let adapter = EthereumAdapter::new(
+        transport.clone(),
+        provider_metrics.clone(),
+        EthereumClient::Parity,
+    );
+    assert!(adapter.capabilities().traces);
+    let err = runtime
+        .block_on(
+            adapter
+                .calls_in_block(&logger, subgraph_metrics.clone(), 1, H256::zero())
+                .compat(),
+        )
+        .unwrap_err();
+    assert!(err.to_string().contains("returned no traces"));
+    let requests = transport.requests.lock().unwrap();
+    assert_eq!(1, requests.len());
+    assert_eq!("trace_filter", requests[0].0);
+}
@@ -0,0 +1,449 @@
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::env;
+use std::mem::size_of_val;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use graph::data::subgraph::schema::SUBGRAPHS_ID;
+use graph::prelude::tiny_keccak::Keccak;
+use graph::prelude::web3::types::H256;
+use graph::prelude::*;
+use graph::util::lfu_cache::{CacheWeight, LfuCache};
+use graph_graphql::graphql_parser::{query as q, Style};
+use graph_graphql::schema::api::META_FIELD_NAME;
+
+lazy_static! {
+    /// About how many bytes the query results that are kept in memory may
+    /// take up, across all deployments. The cache is turned off if this is 0
+    static ref QUERY_CACHE_MAX_BYTES: u64 = env::var("GRAPH_QUERY_CACHE_MAX_BYTES")
+        .unwrap_or("0".into())
+        .parse::<u64>()
+        .expect("invalid GRAPH_QUERY_CACHE_MAX_BYTES");
+
+    /// Share cached query results between nodes through this Redis server
+    static ref QUERY_CACHE_REDIS_URL: Option<String> =
+        env::var("GRAPH_QUERY_CACHE_REDIS_URL").ok();
+
+    /// How long query results are kept in Redis, in seconds. Results for
+    /// blocks that deployments have moved past are never read again and
+    /// only need to stay around until they expire
+    static ref QUERY_CACHE_REDIS_TTL: Duration = Duration::from_secs(
+        env::var("GRAPH_QUERY_CACHE_REDIS_TTL")
+            .unwrap_or("60".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_QUERY_CACHE_REDIS_TTL")
+    );
+
+    /// How long connecting to Redis and each Redis command may take, in
+    /// milliseconds. Queries are executed without the shared results if
+    /// Redis does not answer in time
+    static ref QUERY_CACHE_REDIS_TIMEOUT: Duration = Duration::from_millis(
+        env::var("GRAPH_QUERY_CACHE_REDIS_TIMEOUT")
+            .unwrap_or("100".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_QUERY_CACHE_REDIS_TIMEOUT")
+    );
+}
+
+/// The number of idle Redis connections that are kept for later queries
+const MAX_IDLE_REDIS_CONNECTIONS: usize = 16;
+
+/// The hash that identifies a query, including its variables and the
+/// limits it is executed with since they decide whether it succeeds
+pub(crate) type QueryHash = [u8; 32];
+
+/// A query result in the cache, and the block it was computed at
+struct CachedResult {
+    block: EthereumBlockPointer,
+    data: q::Value,
+    weight: u64,
+}
+
+impl CachedResult {
+    fn new(block: EthereumBlockPointer, data: q::Value) -> Self {
+        let weight = value_weight(&data);
+        CachedResult {
+            block,
+            data,
+            weight,
+        }
+    }
+}
+
+impl Default for CachedResult {
+    fn default() -> Self {
+        CachedResult::new((H256::zero(), 0u64).into(), q::Value::Null)
+    }
+}
+
+impl CacheWeight for CachedResult {
+    fn weight(&self) -> u64 {
+        self.weight
+    }
+}
+
+/// About how many bytes `value` takes up in memory
+fn value_weight(value: &q::Value) -> u64 {
+    size_of_val(value) as u64
+        + match value {
+            q::Value::String(s) | q::Value::Enum(s) | q::Value::Variable(s) => s.len() as u64,
+            q::Value::List(values) => values.iter().map(value_weight).sum(),
+            q::Value::Object(map) => map
+                .iter()
+                .map(|(key, value)| {
+                    size_of_val(key) as u64 + key.len() as u64 + value_weight(value)
+                })
+                .sum(),
+            q::Value::Int(_) | q::Value::Float(_) | q::Value::Boolean(_) | q::Value::Null => 0,
+        }
+}
+
+/// Connections to the Redis server that query results are shared through.
+/// Connections are reused across queries, and connecting as well as every
+/// command time out after `GRAPH_QUERY_CACHE_REDIS_TIMEOUT`
+struct Redis {
+    client: redis::Client,
+    idle: Mutex<Vec<redis::Connection>>,
+}
+
+impl Redis {
+    fn connect(&self) -> redis::RedisResult<redis::Connection> {
+        if let Some(conn) = self.idle.lock().unwrap().pop() {
+            return Ok(conn);
+        }
+        let timeout = *QUERY_CACHE_REDIS_TIMEOUT;
+        let conn = self.client.get_connection_with_timeout(timeout)?;
+        conn.set_read_timeout(Some(timeout))?;
+        conn.set_write_timeout(Some(timeout))?;
+        Ok(conn)
+    }
+
+    fn query<T: redis::FromRedisValue>(&self, cmd: &redis::Cmd) -> redis::RedisResult<T> {
+        let mut conn = self.connect()?;
+        let result = cmd.query(&mut conn);
+        // A connection whose command failed or timed out may still receive
+        // the answer to it, and is therefore not reused
+        if result.is_ok() {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE_REDIS_CONNECTIONS {
+                idle.push(conn);
+            }
+        }
+        result
+    }
+}
+
+/// Caches the results of queries keyed by the deployment they query, a
+/// hash of the query, and the block they were executed at. Queries that
+/// use the cache are executed at the latest block of the deployment when
+/// they start, rather than at whatever block is latest while they run,
+/// and a result is only used while the deployment's latest block is still
+/// the block it was computed at. Results are therefore invalidated as soon
+/// as the deployment processes another block or reverts one. Results are
+/// kept in memory and, if `GRAPH_QUERY_CACHE_REDIS_URL` is set, in Redis
+/// so that nodes that answer queries for the same deployments can share
+/// them.
+///
+/// Only successful results are cached. Queries that read the pending block
+/// or the `_meta` field, and queries against deployments with annotation
+/// types, are never cached since their results can change without the
+/// deployment moving to another block.
+pub struct QueryCache {
+    logger: Logger,
+    memory: Mutex<LfuCache<(SubgraphDeploymentId, QueryHash), CachedResult>>,
+    redis: Option<Arc<Redis>>,
+}
+
+impl QueryCache {
+    /// Whether query results should be cached at all, which is the case if
+    /// `GRAPH_QUERY_CACHE_MAX_BYTES` is set
+    pub fn is_enabled() -> bool {
+        *QUERY_CACHE_MAX_BYTES > 0
+    }
+
+    pub fn new(logger: &Logger) -> Self {
+        let logger = logger.new(o!("component" => "QueryCache"));
+        let redis = QUERY_CACHE_REDIS_URL.as_ref().map(|url| {
+            info!(logger, "Sharing query results through Redis"; "url" => url);
+            Arc::new(Redis {
+                client: redis::Client::open(url.as_str())
+                    .expect("invalid GRAPH_QUERY_CACHE_REDIS_URL"),
+                idle: Mutex::new(Vec::new()),
+            })
+        });
+        QueryCache {
+            logger,
+            memory: Mutex::new(LfuCache::new()),
+            redis,
+        }
+    }
+
+    /// The hash that identifies `query` when it is executed with the given
+    /// limits, or `None` if the results of the query must not be cached
+    pub(crate) fn query_hash(
+        query: &Query,
+        max_complexity: Option<u64>,
+        max_depth: u8,
+        max_first: u32,
+    ) -> Option<QueryHash> {
+        if !Self::is_enabled()
+            || query.schema.id == *SUBGRAPHS_ID
+            || !query.schema.annotation_types().is_empty()
+            || !is_cacheable(&query.document)
+        {
+            return None;
+        }
+
+        let text = query.document.format(&Style::default().indent(0));
+        let variables = serde_json::to_string(&query.variables).ok()?;
+        let limits = format!("{:?}/{}/{}", max_complexity, max_depth, max_first);
+        let mut hasher = Keccak::new_keccak256();
+        for part in &[text.as_str(), variables.as_str(), limits.as_str()] {
+            hasher.update(&(part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        let mut hash = [0u8; 32];
+        hasher.finalize(&mut hash);
+        Some(hash)
+    }
+
+    /// The result of the query with `hash` against `id` at `block`, if it
+    /// is cached
+    pub(crate) fn get(
+        &self,
+        id: &SubgraphDeploymentId,
+        hash: &QueryHash,
+        block: &EthereumBlockPointer,
+    ) -> Option<q::Value> {
+        let key = (id.clone(), *hash);
+        if let Some(cached) = self.memory.lock().unwrap().get(&key) {
+            if &cached.block == block {
+                return Some(cached.data.clone());
+            }
+        }
+
+        let data = self.redis_get(id, hash, block)?;
+        self.remember(key, *block, data.clone());
+        Some(data)
+    }
+
+    /// Remember `data` as the result of the query with `hash` against `id`
+    /// at `block`
+    pub(crate) fn insert(
+        &self,
+        id: &SubgraphDeploymentId,
+        hash: &QueryHash,
+        block: &EthereumBlockPointer,
+        data: &q::Value,
+    ) {
+        self.remember((id.clone(), *hash), *block, data.clone());
+        self.redis_set(id, hash, block, data);
+    }
+
+    fn remember(
+        &self,
+        key: (SubgraphDeploymentId, QueryHash),
+        block: EthereumBlockPointer,
+        data: q::Value,
+    ) {
+        let mut memory = self.memory.lock().unwrap();
+        memory.insert(key, CachedResult::new(block, data));
+        memory.evict(*QUERY_CACHE_MAX_BYTES);
+    }
+
+    fn redis_key(
+        id: &SubgraphDeploymentId,
+        hash: &QueryHash,
+        block: &EthereumBlockPointer,
+    ) -> String {
+        format!(
+            "graph:query:{}:{}:{}",
+            id,
+            block.hash_hex(),
+            hex::encode(hash)
+        )
+    }
+
+    fn redis_get(
+        &self,
+        id: &SubgraphDeploymentId,
+        hash: &QueryHash,
+        block: &EthereumBlockPointer,
+    ) -> Option<q::Value> {
+        let pool = self.redis.as_ref()?;
+        let json = pool
+            .query::<Option<String>>(redis::cmd("GET").arg(Self::redis_key(id, hash, block)))
+            .map_err(|e| {
+                warn!(self.logger, "Failed to read query result from Redis"; "error" => e.to_string())
+            })
+            .ok()??;
+        serde_json::from_str(&json)
+            .map(json_to_value)
+            .map_err(
+                |e| warn!(self.logger, "Invalid query result in Redis"; "error" => e.to_string()),
+            )
+            .ok()
+    }
+
+    /// Write the result to Redis in the background, so that the query does
+    /// not have to wait for it
+    fn redis_set(
+        &self,
+        id: &SubgraphDeploymentId,
+        hash: &QueryHash,
+        block: &EthereumBlockPointer,
+        data: &q::Value,
+    ) {
+        let pool = match self.redis.as_ref() {
+            Some(pool) => pool.clone(),
+            None => return,
+        };
+        let json = match serde_json::to_string(&SerializableValue(data)) {
+            Ok(json) => json,
+            Err(_) => return,
+        };
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(Self::redis_key(id, hash, block))
+            .arg(json)
+            .arg("EX")
+            .arg(QUERY_CACHE_REDIS_TTL.as_secs());
+        let logger = self.logger.clone();
+
+        // Blocking due to Redis interactions
+        graph::spawn_blocking(async move {
+            if let Err(e) = pool.query::<()>(&cmd) {
+                warn!(logger, "Failed to write query result to Redis"; "error" => e.to_string());
+            }
+        });
+    }
+}
+
+/// Whether the results of queries in `document` only depend on the
+/// entities of the deployment at its latest block
+fn is_cacheable(document: &q::Document) -> bool {
+    fn reads_pending_block(field: &q::Field) -> bool {
+        field.arguments.iter().any(|(name, value)| match value {
+            q::Value::Object(map) if name == "block" => {
+                map.get("pending") == Some(&q::Value::Boolean(true))
+            }
+            _ => false,
+        })
+    }
+
+    fn selections_cacheable(selection_set: &q::SelectionSet, root: bool) -> bool {
+        selection_set.items.iter().all(|selection| match selection {
+            q::Selection::Field(field) => {
+                !(root && field.name == META_FIELD_NAME)
+                    && !reads_pending_block(field)
+                    && selections_cacheable(&field.selection_set, false)
+            }
+            q::Selection::InlineFragment(fragment) => {
+                selections_cacheable(&fragment.selection_set, root)
+            }
+            q::Selection::FragmentSpread(_) => true,
+        })
+    }
+
+    document
+        .definitions
+        .iter()
+        .all(|definition| match definition {
+            q::Definition::Operation(q::OperationDefinition::Query(query)) => {
+                selections_cacheable(&query.selection_set, true)
+            }
+            q::Definition::Operation(q::OperationDefinition::SelectionSet(selection_set)) => {
+                selections_cacheable(selection_set, true)
+            }
+            q::Definition::Operation(_) => false,
+            // Fragments can be spread into the root selection set
+            q::Definition::Fragment(fragment) => {
+                selections_cacheable(&fragment.selection_set, true)
+            }
+        })
+}
+
+/// Turn a query result that was serialized with `SerializableValue` back
+/// into a value that serializes to the same JSON
+fn json_to_value(json: serde_json::Value) -> q::Value {
+    match json {
+        serde_json::Value::Null => q::Value::Null,
+        serde_json::Value::Bool(b) => q::Value::Boolean(b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) if i >= std::i32::MIN as i64 && i <= std::i32::MAX as i64 => {
+                q::Value::Int(q::Number::from(i as i32))
+            }
+            _ => q::Value::Float(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => q::Value::String(s),
+        serde_json::Value::Array(values) => {
+            q::Value::List(values.into_iter().map(json_to_value).collect())
+        }
+        serde_json::Value::Object(map) => q::Value::Object(
+            map.into_iter()
+                .map(|(key, value)| (key, json_to_value(value)))
+                .collect::<BTreeMap<_, _>>(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph_graphql::graphql_parser::parse_query;
+
+    fn cacheable(query: &str) -> bool {
+        is_cacheable(&parse_query(query).unwrap())
+    }
+
+    #[test]
+    fn cacheable_queries() {
+        assert!(cacheable("{ things(first: 10) { id } }"));
+        assert!(cacheable(
+            "query { thing(id: \"1\", block: { number: 7 }) { id } }"
+        ));
+        assert!(cacheable("{ things { id owner { _meta } } }"));
+        assert!(!cacheable("{ things(block: { pending: true }) { id } }"));
+        assert!(!cacheable("{ _meta { block { number } } things { id } }"));
+        assert!(!cacheable(
+            "{ ...meta } fragment meta on Query { _meta { deployment } }"
+        ));
+        assert!(!cacheable("mutation { doSomething }"));
+    }
+
+    #[test]
+    fn results_are_bounded_by_weight() {
+        let id = SubgraphDeploymentId::new("QmQueryCache").unwrap();
+        let block = EthereumBlockPointer::from((H256::zero(), 7u64));
+        let small = q::Value::List(vec![q::Value::String("a".to_owned())]);
+        let large = q::Value::List(vec![q::Value::String("a".repeat(1000)); 10]);
+        assert!(value_weight(&large) > 10_000);
+
+        let mut memory = LfuCache::new();
+        memory.insert(
+            (id.clone(), [0u8; 32]),
+            CachedResult::new(block, small.clone()),
+        );
+        memory.get(&(id.clone(), [0u8; 32]));
+        memory.insert((id.clone(), [1u8; 32]), CachedResult::new(block, large));
+        memory.evict(1000);
+        assert_eq!(1, memory.len());
+        assert_eq!(
+            Some(&small),
+            memory.get(&(id, [0u8; 32])).map(|cached| &cached.data)
+        );
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = serde_json::json!({
+            "things": [{ "id": "1", "count": 3, "price": 1.5, "big": "1000000000000", "on": true }],
+            "thing": null,
+        });
+        let value = json_to_value(json.clone());
+        assert_eq!(
+            json,
+            serde_json::to_value(&SerializableValue(&value)).unwrap()
+        );
+    }
+}
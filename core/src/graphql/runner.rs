@@ -1,17 +1,26 @@
 use futures::future;
+use std::collections::HashSet;
 use std::env;
 use std::str::FromStr;
-use std::time::{Duration, Instant};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
 use graph::prelude::{GraphQlRunner as GraphQlRunnerTrait, *};
 use graph_graphql::prelude::*;
 
 use lazy_static::lazy_static;
 
+use super::cache::QueryCache;
+use crate::subgraph::{record_query as record_stored_query, QUERY_RECORD_INTERVAL};
+
 /// GraphQL runner implementation for The Graph.
 pub struct GraphQlRunner<S> {
     logger: Logger,
     store: Arc<S>,
+    activity: Option<Arc<DeploymentActivity>>,
+    cache: Option<Arc<QueryCache>>,
+    /// Deployments that are known to use relational storage
+    relational: Mutex<HashSet<SubgraphDeploymentId>>,
 }
 
 lazy_static! {
@@ -46,26 +55,129 @@ where
         GraphQlRunner {
             logger: logger.new(o!("component" => "GraphQlRunner")),
             store,
+            activity: None,
+            cache: None,
+            relational: Mutex::new(HashSet::new()),
         }
     }
-}
 
-impl<S> GraphQlRunnerTrait for GraphQlRunner<S>
-where
-    S: Store,
-{
-    fn run_query(&self, query: Query) -> QueryResultFuture {
+    /// Record every query in `activity` so that idle deployments can be
+    /// detected and woken up
+    pub fn with_activity(mut self, activity: Arc<DeploymentActivity>) -> Self {
+        self.activity = Some(activity);
+        self
+    }
+
+    /// Answer queries from `cache` while the deployment they query has
+    /// not moved to another block since they were last executed. Only
+    /// deployments that use relational storage can be cached, since their
+    /// queries are executed at the block they are cached for
+    pub fn with_cache(mut self, cache: Arc<QueryCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Record a query for deployment `id`. Every once in a while, the query
+    /// is also recorded in the store, since the node that indexes the
+    /// deployment usually does not answer queries for it
+    fn record_query(&self, id: &SubgraphDeploymentId) {
+        if let Some(activity) = &self.activity {
+            activity.record_query(id);
+            if activity.store_query(id, QUERY_RECORD_INTERVAL) {
+                let logger = self.logger.clone();
+                let store = self.store.clone();
+                let id = id.clone();
+
+                // Blocking due to store interactions. Won't be blocking after #905.
+                graph::spawn_blocking(async move {
+                    if let Err(e) = record_stored_query(&*store, &id, SystemTime::now()) {
+                        warn!(logger, "Failed to record query in the store";
+                              "subgraph_id" => id.to_string(),
+                              "error" => e.to_string());
+                    }
+                });
+            }
+        }
+    }
+
+    /// Whether deployment `id` uses relational storage. Deployments can be
+    /// migrated from JSONB storage, but never the other way around, and
+    /// only deployments that use relational storage are therefore
+    /// remembered
+    fn uses_relational_schema(&self, id: &SubgraphDeploymentId) -> bool {
+        if self.relational.lock().unwrap().contains(id) {
+            return true;
+        }
+        let relational = self.store.uses_relational_schema(id).unwrap_or(false);
+        if relational {
+            self.relational.lock().unwrap().insert(id.clone());
+        }
+        relational
+    }
+
+    fn execute(
+        &self,
+        query: Query,
+        max_complexity: Option<u64>,
+        max_depth: u8,
+        max_first: u32,
+    ) -> QueryResult {
+        self.record_query(&query.schema.id);
+
+        // Results can only be cached for a known block; if the latest block
+        // of the deployment can not be determined, the query is executed
+        // without the cache. Otherwise, it is executed at that block, even
+        // if the deployment moves on while the query runs, so that the
+        // result is exactly the one for the block it is cached for
+        let cached = self.cache.as_ref().and_then(|cache| {
+            let hash = QueryCache::query_hash(&query, max_complexity, max_depth, max_first)?;
+            if !self.uses_relational_schema(&query.schema.id) {
+                return None;
+            }
+            let block = self.store.block_ptr(query.schema.id.clone()).ok()??;
+            Some((cache, hash, block))
+        });
+        if let Some((cache, hash, block)) = &cached {
+            if let Some(data) = cache.get(&query.schema.id, hash, block) {
+                return QueryResult::new(Some(data));
+            }
+        }
+
+        let id = query.schema.id.clone();
         let result = execute_query(
             query,
             QueryExecutionOptions {
                 logger: self.logger.clone(),
                 resolver: StoreResolver::new(&self.logger, self.store.clone()),
                 deadline: GRAPHQL_QUERY_TIMEOUT.map(|t| Instant::now() + t),
-                max_complexity: *GRAPHQL_MAX_COMPLEXITY,
-                max_depth: *GRAPHQL_MAX_DEPTH,
-                max_first: *GRAPHQL_MAX_FIRST,
+                max_complexity,
+                max_depth,
+                max_first,
+                block: cached.as_ref().map_or(BLOCK_NUMBER_MAX, |(_, _, block)| {
+                    block.number as BlockNumber
+                }),
             },
         );
+        if let (Some((cache, hash, block)), None, Some(data)) =
+            (&cached, &result.errors, &result.data)
+        {
+            cache.insert(&id, hash, block, data);
+        }
+        result
+    }
+}
+
+impl<S> GraphQlRunnerTrait for GraphQlRunner<S>
+where
+    S: Store,
+{
+    fn run_query(&self, query: Query) -> QueryResultFuture {
+        let result = self.execute(
+            query,
+            *GRAPHQL_MAX_COMPLEXITY,
+            *GRAPHQL_MAX_DEPTH,
+            *GRAPHQL_MAX_FIRST,
+        );
         Box::new(future::ok(result))
     }
 
@@ -76,21 +188,32 @@ where
         max_depth: Option<u8>,
         max_first: Option<u32>,
     ) -> QueryResultFuture {
-        let result = execute_query(
+        let result = self.execute(
+            query,
+            max_complexity,
+            max_depth.unwrap_or(*GRAPHQL_MAX_DEPTH),
+            max_first.unwrap_or(*GRAPHQL_MAX_FIRST),
+        );
+        Box::new(future::ok(result))
+    }
+
+    fn check_query(&self, query: &Query) -> Result<(), Vec<QueryExecutionError>> {
+        check_query(
             query,
             QueryExecutionOptions {
                 logger: self.logger.clone(),
                 resolver: StoreResolver::new(&self.logger, self.store.clone()),
-                deadline: GRAPHQL_QUERY_TIMEOUT.map(|t| Instant::now() + t),
-                max_complexity: max_complexity,
-                max_depth: max_depth.unwrap_or(*GRAPHQL_MAX_DEPTH),
-                max_first: max_first.unwrap_or(*GRAPHQL_MAX_FIRST),
+                deadline: None,
+                max_complexity: *GRAPHQL_MAX_COMPLEXITY,
+                max_depth: *GRAPHQL_MAX_DEPTH,
+                max_first: *GRAPHQL_MAX_FIRST,
+                block: BLOCK_NUMBER_MAX,
             },
-        );
-        Box::new(future::ok(result))
+        )
     }
 
     fn run_subscription(&self, subscription: Subscription) -> SubscriptionResultFuture {
+        self.record_query(&subscription.query.schema.id);
         let result = execute_subscription(
             &subscription,
             SubscriptionExecutionOptions {
@@ -0,0 +1,237 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use graph::prelude::futures03::channel::oneshot;
+use graph::prelude::*;
+
+/// Requests are queued per deployment; requests that are not made on
+/// behalf of a deployment share one queue.
+type QueueKey = Option<SubgraphDeploymentId>;
+
+/// The requests waiting for a permit at one priority. Deployments take
+/// turns, so that a deployment with many requests can not starve the
+/// others.
+#[derive(Default)]
+struct Queue {
+    /// The deployments with waiting requests, in the order in which they
+    /// will be served
+    turns: VecDeque<QueueKey>,
+    waiting: HashMap<QueueKey, VecDeque<oneshot::Sender<Permit>>>,
+}
+
+impl Queue {
+    fn push(&mut self, key: QueueKey, sender: oneshot::Sender<Permit>) {
+        let waiting = self.waiting.entry(key.clone()).or_default();
+        if waiting.is_empty() {
+            self.turns.push_back(key);
+        }
+        waiting.push_back(sender);
+    }
+
+    fn pop(&mut self) -> Option<oneshot::Sender<Permit>> {
+        let key = self.turns.pop_front()?;
+        let waiting = self
+            .waiting
+            .get_mut(&key)
+            .expect("turn without waiting requests");
+        let sender = waiting.pop_front().expect("turn without waiting requests");
+        if waiting.is_empty() {
+            self.waiting.remove(&key);
+        } else {
+            self.turns.push_back(key);
+        }
+        Some(sender)
+    }
+}
+
+struct State {
+    /// The number of permits that have been handed out and not dropped yet
+    running: usize,
+    queues: BTreeMap<LinkResolverPriority, Queue>,
+    /// The earliest time at which the next permit may be used
+    next_start: Instant,
+}
+
+/// Limits the number of concurrent IPFS requests, and how many requests
+/// can be started per second. Requests that can not be started right away
+/// are queued; higher priority requests are served first, and requests of
+/// the same priority are served round-robin across deployments.
+pub(crate) struct IpfsScheduler {
+    max_concurrent: usize,
+    /// The minimum time between the start of two requests
+    interval: Duration,
+    state: Mutex<State>,
+}
+
+/// Permission to make one IPFS request. The permit is returned to the
+/// scheduler when it is dropped.
+pub(crate) struct Permit {
+    scheduler: Arc<IpfsScheduler>,
+    start_at: Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.scheduler.state.lock().unwrap().running -= 1;
+        self.scheduler.dispatch();
+    }
+}
+
+impl IpfsScheduler {
+    /// Create a scheduler that allows at most `max_concurrent` requests at
+    /// the same time and, if `max_per_second` is not `None`, starts at most
+    /// that many requests per second
+    pub fn new(max_concurrent: usize, max_per_second: Option<u64>) -> Arc<Self> {
+        assert!(max_concurrent > 0, "IPFS scheduler needs to allow requests");
+        Arc::new(IpfsScheduler {
+            max_concurrent,
+            interval: max_per_second
+                .filter(|rate| *rate > 0)
+                .map_or(Duration::from_secs(0), |rate| {
+                    Duration::from_secs(1) / rate as u32
+                }),
+            state: Mutex::new(State {
+                running: 0,
+                queues: BTreeMap::new(),
+                next_start: Instant::now(),
+            }),
+        })
+    }
+
+    /// Wait until a request with the given `priority` for `deployment` may
+    /// be made. The request must be made while holding on to the returned
+    /// permit.
+    pub async fn acquire(
+        self: Arc<Self>,
+        priority: LinkResolverPriority,
+        deployment: QueueKey,
+    ) -> Permit {
+        let (sender, receiver) = oneshot::channel();
+        self.state
+            .lock()
+            .unwrap()
+            .queues
+            .entry(priority)
+            .or_default()
+            .push(deployment, sender);
+        self.dispatch();
+
+        // The scheduler never drops a sender without sending a permit
+        let permit = receiver.await.expect("IPFS scheduler dropped a request");
+        let now = Instant::now();
+        if permit.start_at > now {
+            tokio::time::delay_for(permit.start_at - now).await;
+        }
+        permit
+    }
+
+    /// Hand out permits to waiting requests as long as we are below the
+    /// concurrency limit
+    fn dispatch(self: &Arc<Self>) {
+        let mut ready = vec![];
+        {
+            let mut state = self.state.lock().unwrap();
+            while state.running < self.max_concurrent {
+                let sender = match state
+                    .queues
+                    .values_mut()
+                    .rev()
+                    .find_map(|queue| queue.pop())
+                {
+                    Some(sender) => sender,
+                    None => break,
+                };
+                let start_at = state.next_start.max(Instant::now());
+                state.next_start = start_at + self.interval;
+                state.running += 1;
+                ready.push((sender, start_at));
+            }
+        }
+
+        // Send permits without holding the lock; if the request was
+        // abandoned, the permit we get back is dropped and returns its slot
+        for (sender, start_at) in ready {
+            let _ = sender.send(Permit {
+                scheduler: self.clone(),
+                start_at,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment(id: &str) -> QueueKey {
+        Some(SubgraphDeploymentId::new(id).unwrap())
+    }
+
+    fn is_ready(receiver: &mut oneshot::Receiver<Permit>) -> bool {
+        receiver.try_recv().unwrap().is_some()
+    }
+
+    fn enqueue(
+        scheduler: &Arc<IpfsScheduler>,
+        priority: LinkResolverPriority,
+        key: QueueKey,
+    ) -> oneshot::Receiver<Permit> {
+        let (sender, receiver) = oneshot::channel();
+        scheduler
+            .state
+            .lock()
+            .unwrap()
+            .queues
+            .entry(priority)
+            .or_default()
+            .push(key, sender);
+        receiver
+    }
+
+    #[test]
+    fn serves_by_priority_and_round_robin() {
+        let scheduler = IpfsScheduler::new(1, None);
+        let low = LinkResolverPriority::Low;
+        let high = LinkResolverPriority::High;
+
+        let mut a1 = enqueue(&scheduler, low, deployment("a"));
+        let mut a2 = enqueue(&scheduler, low, deployment("a"));
+        let mut b1 = enqueue(&scheduler, low, deployment("b"));
+        let mut h1 = enqueue(&scheduler, high, deployment("c"));
+
+        // The high priority request goes first
+        scheduler.dispatch();
+        assert!(is_ready(&mut h1));
+        assert_eq!(1, scheduler.state.lock().unwrap().running);
+
+        // The permit for `h1` was consumed by `is_ready` and dropped,
+        // which started the next request; deployments take turns
+        assert!(is_ready(&mut a1));
+        assert!(is_ready(&mut b1));
+        assert!(is_ready(&mut a2));
+        assert_eq!(0, scheduler.state.lock().unwrap().running);
+    }
+
+    #[test]
+    fn respects_concurrency_limit() {
+        let scheduler = IpfsScheduler::new(2, None);
+        let normal = LinkResolverPriority::Normal;
+
+        let mut receivers: Vec<_> = (0..3).map(|_| enqueue(&scheduler, normal, None)).collect();
+        scheduler.dispatch();
+
+        let permits: Vec<_> = receivers
+            .iter_mut()
+            .map(|receiver| receiver.try_recv().unwrap())
+            .collect();
+        assert!(permits[0].is_some());
+        assert!(permits[1].is_some());
+        assert!(permits[2].is_none());
+        assert_eq!(2, scheduler.state.lock().unwrap().running);
+
+        // Releasing a permit lets the waiting request go
+        drop(permits);
+        assert!(is_ready(&mut receivers[2]));
+    }
+}
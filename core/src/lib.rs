@@ -11,13 +11,16 @@ extern crate serde_yaml;
 extern crate uuid;
 
 mod graphql;
+mod ipfs_scheduler;
 mod link_resolver;
 mod metrics;
 mod subgraph;
 
-pub use crate::graphql::GraphQlRunner;
+pub use crate::graphql::{GraphQlRunner, QueryCache};
 pub use crate::link_resolver::LinkResolver;
 pub use crate::metrics::MetricsRegistry;
 pub use crate::subgraph::{
-    DataSourceLoader, SubgraphAssignmentProvider, SubgraphInstanceManager, SubgraphRegistrar,
+    DataSourceLoader, HandlerSimulator, IdleDeploymentMonitor, IndexingRulesReconciler,
+    ShutdownCoordinator, StandbyFollower, SubgraphAssignmentProvider, SubgraphInstanceManager,
+    SubgraphRegistrar, WebhookNotifier,
 };
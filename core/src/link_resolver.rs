@@ -3,14 +3,18 @@ use futures::{stream::poll_fn, try_ready};
 use ipfs_api;
 use lazy_static::lazy_static;
 use lru_time_cache::LruCache;
+use std::collections::HashMap;
 use std::env;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use graph::prelude::{LinkResolver as LinkResolverTrait, *};
 use serde_json::Value;
 
+use crate::ipfs_scheduler::IpfsScheduler;
+
 // Environment variable for limiting the `ipfs.map` file size limit.
 const MAX_IPFS_MAP_FILE_SIZE_VAR: &'static str = "GRAPH_MAX_IPFS_MAP_FILE_SIZE";
 
@@ -33,6 +37,16 @@ lazy_static! {
     static ref IPFS_TIMEOUT: Duration = Duration::from_secs(
         read_u64_from_env("GRAPH_IPFS_TIMEOUT").unwrap_or(60)
     );
+
+    // The maximum number of concurrent `ipfs.cat` requests across all
+    // deployments
+    static ref IPFS_MAX_CONCURRENT_REQUESTS: u64 =
+        read_u64_from_env("GRAPH_IPFS_MAX_CONCURRENT_REQUESTS").unwrap_or(64);
+
+    // The maximum number of `ipfs.cat` requests started per second; unlimited
+    // if not set
+    static ref IPFS_MAX_REQUESTS_PER_SECOND: Option<u64> =
+        read_u64_from_env("GRAPH_IPFS_MAX_REQUESTS_PER_SECOND");
 }
 
 fn read_u64_from_env(name: &str) -> Option<u64> {
@@ -89,7 +103,14 @@ pub struct LinkResolver {
     client: ipfs_api::IpfsClient,
     cache: Arc<Mutex<LruCache<String, Vec<u8>>>>,
     timeout: Duration,
-    retry: bool,
+    /// How many attempts to make for each request; unlimited if `None`
+    attempts: Option<usize>,
+    max_backoff: Duration,
+    scheduler: Arc<IpfsScheduler>,
+    priority: LinkResolverPriority,
+    deployment: Option<SubgraphDeploymentId>,
+    /// Counts retried requests by deployment
+    retries: Option<Arc<CounterVec>>,
 }
 
 impl From<ipfs_api::IpfsClient> for LinkResolver {
@@ -100,11 +121,36 @@ impl From<ipfs_api::IpfsClient> for LinkResolver {
                 *MAX_IPFS_CACHE_SIZE as usize,
             ))),
             timeout: *IPFS_TIMEOUT,
-            retry: false,
+            attempts: Some(1),
+            max_backoff: Duration::from_secs(30),
+            scheduler: IpfsScheduler::new(
+                *IPFS_MAX_CONCURRENT_REQUESTS as usize,
+                *IPFS_MAX_REQUESTS_PER_SECOND,
+            ),
+            priority: LinkResolverPriority::default(),
+            deployment: None,
+            retries: None,
         }
     }
 }
 
+impl LinkResolver {
+    /// Count the requests that are retried in the `ipfs_request_retries`
+    /// metric
+    pub fn with_metrics(mut self, registry: Arc<impl MetricsRegistry>) -> Self {
+        let retries = registry
+            .new_counter_vec(
+                String::from("ipfs_request_retries"),
+                String::from("Counts the IPFS requests that are retried after failing"),
+                HashMap::new(),
+                vec![String::from("deployment")],
+            )
+            .expect("failed to create `ipfs_request_retries` counter");
+        self.retries = Some(Arc::from(retries));
+        self
+    }
+}
+
 impl LinkResolverTrait for LinkResolver {
     fn with_timeout(mut self, timeout: Duration) -> Self {
         self.timeout = timeout;
@@ -112,7 +158,24 @@ impl LinkResolverTrait for LinkResolver {
     }
 
     fn with_retries(mut self) -> Self {
-        self.retry = true;
+        self.attempts = None;
+        self
+    }
+
+    fn with_retry_policy(mut self, policy: LinkResolverRetryPolicy) -> Self {
+        self.timeout = policy.timeout;
+        self.attempts = policy.attempts;
+        self.max_backoff = policy.max_backoff;
+        self
+    }
+
+    fn with_priority(mut self, priority: LinkResolverPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    fn for_deployment(mut self, deployment: SubgraphDeploymentId) -> Self {
+        self.deployment = Some(deployment);
         self
     }
 
@@ -124,7 +187,6 @@ impl LinkResolverTrait for LinkResolver {
     ) -> Box<dyn Future<Item = Vec<u8>, Error = failure::Error> + Send> {
         // Discard the `/ipfs/` prefix (if present) to get the hash.
         let path = link.link.trim_start_matches("/ipfs/").to_owned();
-        let path_for_error = path.clone();
 
         if let Some(data) = self.cache.lock().unwrap().get(&path) {
             trace!(logger, "IPFS cache hit"; "hash" => &path);
@@ -136,38 +198,75 @@ impl LinkResolverTrait for LinkResolver {
         let client_for_cat = self.client.clone();
         let client_for_file_size = self.client.clone();
         let cache_for_writing = self.cache.clone();
+        let scheduler = self.scheduler.clone();
+        let priority = self.priority;
+        let deployment = self.deployment.clone();
 
         let max_file_size: Option<u64> = read_u64_from_env(MAX_IPFS_FILE_SIZE_VAR);
-        let timeout_for_file_size = self.timeout.clone();
+        let timeout = self.timeout.clone();
 
-        let retry_fut = if self.retry {
-            retry("ipfs.cat", &logger).no_limit()
-        } else {
-            retry("ipfs.cat", &logger).limit(1)
+        let retries = self.retries.clone();
+        let logger = logger.clone();
+        let attempt = Arc::new(AtomicUsize::new(0));
+
+        let retry_fut = match self.attempts {
+            Some(attempts) => retry("ipfs.cat", &logger).limit(attempts),
+            None => retry("ipfs.cat", &logger).no_limit(),
         };
 
         Box::new(
             retry_fut
-                .timeout(self.timeout)
+                .max_backoff(self.max_backoff)
+                .no_timeout()
                 .run(move || {
                     let cache_for_writing = cache_for_writing.clone();
+                    let client_for_cat = client_for_cat.clone();
+                    let client_for_file_size = client_for_file_size.clone();
+                    let scheduler = scheduler.clone();
+                    let deployment = deployment.clone();
                     let path = path.clone();
 
-                    let cat = client_for_cat
-                        .cat(&path)
-                        .map_ok(|b| BytesMut::from_iter(b.into_iter()))
-                        .try_concat()
-                        .map_ok(|x| x.to_vec())
-                        .err_into();
-
-                    restrict_file_size(
-                        client_for_file_size.clone(),
-                        path.clone(),
-                        timeout_for_file_size,
-                        max_file_size,
-                        Box::new(cat.compat()),
-                    )
-                    .map(move |data| {
+                    let attempt = attempt.fetch_add(1, Ordering::SeqCst) + 1;
+                    if attempt > 1 {
+                        let deployment = deployment.as_ref().map_or("", |id| id.as_str());
+                        info!(logger, "Retrying IPFS request";
+                      "hash" => &path,
+                      "attempt" => attempt,
+                      "deployment" => deployment);
+                        if let Some(retries) = &retries {
+                            retries.with_label_values(&[deployment]).inc();
+                        }
+                    }
+
+                    Box::pin(async move {
+                        // Wait for our turn before making the request, and only
+                        // count the time the request itself takes towards the
+                        // timeout so that queued requests don't time out
+                        let _permit = scheduler.acquire(priority, deployment).await;
+
+                        let cat = client_for_cat
+                            .cat(&path)
+                            .map_ok(|b| BytesMut::from_iter(b.into_iter()))
+                            .try_concat()
+                            .map_ok(|x| x.to_vec())
+                            .err_into();
+
+                        let data = tokio::time::timeout(
+                            timeout,
+                            restrict_file_size(
+                                client_for_file_size,
+                                path.clone(),
+                                timeout,
+                                max_file_size,
+                                Box::new(cat.compat()),
+                            )
+                            .compat(),
+                        )
+                        .await
+                        .map_err(|_| {
+                            format_err!("ipfs.cat took too long or failed to load `{}`", path)
+                        })??;
+
                         // Only cache files if they are not too large
                         if data.len() <= *MAX_IPFS_CACHE_FILE_SIZE as usize {
                             let mut cache = cache_for_writing.lock().unwrap();
@@ -175,14 +274,9 @@ impl LinkResolverTrait for LinkResolver {
                                 cache.insert(path, data.clone());
                             }
                         }
-                        data
+                        Ok::<_, failure::Error>(data)
                     })
-                })
-                .map_err(move |e| {
-                    e.into_inner().unwrap_or(format_err!(
-                        "ipfs.cat took too long or failed to load `{}`",
-                        path_for_error,
-                    ))
+                    .compat()
                 }),
         )
     }
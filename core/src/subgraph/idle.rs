@@ -0,0 +1,872 @@
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use graph::data::subgraph::schema::{
+    SubgraphDeploymentAssignmentEntity, SubgraphDeploymentEntity, SubgraphDeploymentIdleEntity,
+    SubgraphDeploymentQueriedEntity, TypedEntity,
+};
+use graph::prelude::{SubgraphAssignmentProvider as SubgraphAssignmentProviderTrait, *};
+
+lazy_static! {
+    // How many days a deployment has to go without queries and triggers
+    // before it counts as idle; 0 turns idle detection off
+    static ref IDLE_DEPLOYMENT_DAYS: u64 = env::var("GRAPH_IDLE_DEPLOYMENT_DAYS")
+        .unwrap_or("0".into())
+        .parse::<u64>()
+        .expect("invalid GRAPH_IDLE_DEPLOYMENT_DAYS");
+
+    // How often to look for idle deployments, in seconds
+    static ref IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(
+        env::var("GRAPH_IDLE_CHECK_INTERVAL")
+            .unwrap_or("3600".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_IDLE_CHECK_INTERVAL")
+    );
+
+    // Whether idle deployments are stopped until they are used again
+    static ref IDLE_DEPLOYMENT_PAUSE: bool = env::var("GRAPH_IDLE_DEPLOYMENT_PAUSE")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+
+    // How often stopped idle deployments are started to catch up with the
+    // chain, in seconds
+    static ref IDLE_CATCH_UP_INTERVAL: Duration = Duration::from_secs(
+        env::var("GRAPH_IDLE_CATCH_UP_INTERVAL")
+            .unwrap_or("86400".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_IDLE_CATCH_UP_INTERVAL")
+    );
+
+    // How many days a deployment has to go without queries before it is
+    // hibernated, whether it is synced or not; 0 turns hibernation off
+    static ref HIBERNATE_DEPLOYMENT_DAYS: u64 = env::var("GRAPH_HIBERNATE_DEPLOYMENT_DAYS")
+        .unwrap_or("0".into())
+        .parse::<u64>()
+        .expect("invalid GRAPH_HIBERNATE_DEPLOYMENT_DAYS");
+}
+
+/// How often a node records in the store that it answered queries for a
+/// deployment
+pub(crate) const QUERY_RECORD_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Record in the store that deployment `id` was queried at `queried_at`. If
+/// the deployment is hibernated, its idle entity is removed, which wakes it
+/// up on the node that indexes it
+pub(crate) fn record_query<S: Store>(
+    store: &S,
+    id: &SubgraphDeploymentId,
+    queried_at: SystemTime,
+) -> Result<(), Error> {
+    let queried_at = queried_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut ops = SubgraphDeploymentQueriedEntity::new(queried_at).write_operations(id);
+    if deployment_hibernated(store, id)? {
+        ops.extend(SubgraphDeploymentIdleEntity::remove_operations(id));
+    }
+    store.apply_metadata_operations(ops)?;
+    Ok(())
+}
+
+/// Whether deployment `id` is hibernated
+pub(crate) fn deployment_hibernated<S: Store>(
+    store: &S,
+    id: &SubgraphDeploymentId,
+) -> Result<bool, Error> {
+    Ok(store
+        .get(SubgraphDeploymentIdleEntity::key(id.clone()))?
+        .and_then(|idle| idle.get("hibernated").cloned())
+        == Some(Value::Bool(true)))
+}
+
+fn days(days: u64) -> Option<Duration> {
+    match days {
+        0 => None,
+        days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+    }
+}
+
+/// When deployments count as idle and what happens to them
+#[derive(Clone, Debug)]
+struct IdlePolicy {
+    idle_after: Option<Duration>,
+    hibernate_after: Option<Duration>,
+    pause: bool,
+    catch_up_interval: Duration,
+    check_interval: Duration,
+}
+
+impl IdlePolicy {
+    fn from_env() -> Self {
+        IdlePolicy {
+            idle_after: days(*IDLE_DEPLOYMENT_DAYS),
+            hibernate_after: days(*HIBERNATE_DEPLOYMENT_DAYS),
+            pause: *IDLE_DEPLOYMENT_PAUSE,
+            catch_up_interval: *IDLE_CATCH_UP_INTERVAL,
+            check_interval: *IDLE_CHECK_INTERVAL,
+        }
+    }
+}
+
+/// What an idle deployment is doing, and since when
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum IdleState {
+    /// Running with smaller cache budgets
+    Running(Instant),
+    /// Stopped until it is used again or catches up
+    Stopped(Instant),
+    /// Started to catch up with the chain
+    CatchingUp(Instant),
+    /// Stopped until it is queried or woken up by an admin
+    Hibernated(Instant),
+}
+
+#[derive(Debug, PartialEq)]
+enum IdleAction {
+    /// Mark the deployment as idle
+    Downgrade,
+    /// Start the stopped deployment so that it catches up with the chain
+    CatchUp,
+    /// Stop the deployment
+    Stop,
+    /// Stop the deployment and do not let it catch up
+    Hibernate,
+}
+
+/// Decide what to do with a deployment given its idle `state`, whether it
+/// is synced, how long ago it was last queried or had triggers, and how
+/// long ago it was last queried
+fn idle_action(
+    policy: &IdlePolicy,
+    state: Option<IdleState>,
+    synced: bool,
+    inactive_for: Duration,
+    unqueried_for: Duration,
+    now: Instant,
+) -> Option<IdleAction> {
+    match (state, policy.hibernate_after) {
+        (Some(IdleState::Hibernated(_)), _) => return None,
+        (_, Some(hibernate_after)) if unqueried_for >= hibernate_after => {
+            return Some(IdleAction::Hibernate)
+        }
+        _ => (),
+    }
+
+    let idle = policy
+        .idle_after
+        .map_or(false, |idle_after| inactive_for >= idle_after);
+    match state {
+        None if synced && idle => Some(IdleAction::Downgrade),
+        None | Some(IdleState::Running(_)) => None,
+        Some(IdleState::Stopped(since)) if now - since >= policy.catch_up_interval => {
+            Some(IdleAction::CatchUp)
+        }
+        Some(IdleState::Stopped(_)) => None,
+        // A deployment catching up gets one check interval to do that
+        Some(IdleState::CatchingUp(since)) if now - since >= policy.check_interval => {
+            Some(IdleAction::Stop)
+        }
+        Some(IdleState::CatchingUp(_)) | Some(IdleState::Hibernated(_)) => None,
+    }
+}
+
+/// Looks for deployments on this node that have not been queried and have
+/// had no triggers for `GRAPH_IDLE_DEPLOYMENT_DAYS` and downgrades them.
+/// The instance manager keeps a smaller entity cache for idle deployments,
+/// and with `GRAPH_IDLE_DEPLOYMENT_PAUSE`, idle deployments are stopped,
+/// which releases their runtime hosts, block stream and caches altogether.
+/// Stopped deployments are started again every
+/// `GRAPH_IDLE_CATCH_UP_INTERVAL` to catch up with the chain, which is how
+/// they notice new triggers.
+///
+/// A query or a trigger for an idle deployment makes it a regular
+/// deployment again right away, starting it if it was stopped. Idle
+/// deployments are recorded in `SubgraphDeploymentIdle` entities, which
+/// the indexing status API reports.
+///
+/// Deployments that have not been queried for
+/// `GRAPH_HIBERNATE_DEPLOYMENT_DAYS` are hibernated, whether they are
+/// synced or not: they are stopped and stay stopped until they are queried
+/// or an admin request wakes them up by removing their
+/// `SubgraphDeploymentIdle` entity. Since queries usually go to other
+/// nodes, those record them in `SubgraphDeploymentQueried` entities, and
+/// remove the idle entity of a hibernated deployment they answer queries
+/// for. Unlike other idle deployments, hibernated deployments stay
+/// hibernated when the node restarts.
+pub struct IdleDeploymentMonitor<P, S> {
+    logger: Logger,
+    provider: Arc<P>,
+    store: Arc<S>,
+    activity: Arc<DeploymentActivity>,
+    node_id: NodeId,
+    policy: IdlePolicy,
+    states: Mutex<HashMap<SubgraphDeploymentId, IdleState>>,
+    started_at: SystemTime,
+    startup: Arc<StartupStatus>,
+}
+
+impl<P, S> IdleDeploymentMonitor<P, S>
+where
+    P: SubgraphAssignmentProviderTrait,
+    S: Store,
+{
+    pub fn new(
+        logger_factory: &LoggerFactory,
+        provider: Arc<P>,
+        store: Arc<S>,
+        activity: Arc<DeploymentActivity>,
+        node_id: NodeId,
+    ) -> Self {
+        IdleDeploymentMonitor {
+            logger: logger_factory.component_logger("IdleDeploymentMonitor", None),
+            provider,
+            store,
+            activity,
+            node_id,
+            policy: IdlePolicy::from_env(),
+            states: Mutex::new(HashMap::new()),
+            started_at: SystemTime::now(),
+            startup: Arc::new(StartupStatus::new()),
+        }
+    }
+
+    /// Leave deployments alone once `startup` says that the node is
+    /// shutting down
+    pub fn with_startup(mut self, startup: Arc<StartupStatus>) -> Self {
+        self.startup = startup;
+        self
+    }
+
+    /// Start checking for idle deployments, unless idle detection and
+    /// hibernation are turned off
+    pub fn start(self) {
+        use futures03::stream::StreamExt;
+
+        if self.policy.idle_after.is_none() && self.policy.hibernate_after.is_none() {
+            return;
+        }
+
+        // Activity is not persisted, so deployments that were idle before
+        // the node restarted start out as regular deployments; hibernated
+        // deployments are not started by the registrar and stay
+        // hibernated
+        if let Err(e) = self.clear_idle_entities() {
+            warn!(
+                self.logger,
+                "Failed to clear idle deployments";
+                "error" => e.to_string()
+            );
+        }
+        if let Err(e) = self
+            .assigned_deployments()
+            .and_then(|assigned| self.track_hibernated(&assigned))
+        {
+            warn!(
+                self.logger,
+                "Failed to load hibernated deployments";
+                "error" => e.to_string()
+            );
+        }
+        let wake_stream = self
+            .activity
+            .take_wake_stream()
+            .expect("the wake stream of deployment activity can only be taken once");
+        let store_events = self
+            .store
+            .subscribe(vec![SubgraphDeploymentIdleEntity::subgraph_entity_pair()]);
+        let monitor = Arc::new(self);
+
+        // Blocking due to store interactions. Won't be blocking after #905.
+        let checker = monitor.clone();
+        graph::spawn_blocking(
+            tokio::time::interval(checker.policy.check_interval).for_each(move |_| {
+                let monitor = checker.clone();
+                async move {
+                    if let Err(e) = monitor.check().await {
+                        warn!(
+                            monitor.logger,
+                            "Failed to check for idle deployments";
+                            "error" => e.to_string()
+                        );
+                    }
+                }
+            }),
+        );
+
+        let waker = monitor.clone();
+        graph::spawn_blocking(wake_stream.compat().for_each(move |id| {
+            let monitor = waker.clone();
+            async move {
+                if let Ok(id) = id {
+                    monitor.wake(id).await;
+                }
+            }
+        }));
+
+        // An admin wakes up a deployment by removing its idle entity
+        graph::spawn_blocking(store_events.compat().for_each(move |event| {
+            let monitor = monitor.clone();
+            async move {
+                let ids = event
+                    .map(|event| event.changes)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|change| change.operation == EntityChangeOperation::Removed)
+                    .filter_map(|change| SubgraphDeploymentId::new(change.entity_id).ok())
+                    .collect::<HashSet<_>>();
+                for id in ids {
+                    monitor.wake(id).await;
+                }
+            }
+        }));
+    }
+
+    /// The deployments assigned to this node
+    fn assigned_deployments(&self) -> Result<Vec<SubgraphDeploymentId>, Error> {
+        Ok(self
+            .store
+            .find(
+                SubgraphDeploymentAssignmentEntity::query()
+                    .filter(EntityFilter::new_equal("nodeId", self.node_id.to_string())),
+            )?
+            .into_iter()
+            .filter_map(|assignment| {
+                assignment
+                    .id()
+                    .ok()
+                    .and_then(|id| SubgraphDeploymentId::new(id).ok())
+            })
+            .collect())
+    }
+
+    /// The deployments that are hibernated, on any node
+    fn hibernated_deployments(&self) -> Result<HashSet<SubgraphDeploymentId>, Error> {
+        Ok(self
+            .store
+            .find(
+                SubgraphDeploymentIdleEntity::query()
+                    .filter(EntityFilter::new_equal("hibernated", true)),
+            )?
+            .into_iter()
+            .filter_map(|idle| idle.id().ok())
+            .filter_map(|id| SubgraphDeploymentId::new(id).ok())
+            .collect())
+    }
+
+    /// Remove the idle entities of the deployments on this node, except
+    /// for those of hibernated deployments
+    fn clear_idle_entities(&self) -> Result<(), Error> {
+        let hibernated = self.hibernated_deployments()?;
+        let ops = self
+            .assigned_deployments()?
+            .iter()
+            .filter(|id| !hibernated.contains(id))
+            .flat_map(SubgraphDeploymentIdleEntity::remove_operations)
+            .collect::<Vec<_>>();
+        self.store.apply_metadata_operations(ops)?;
+        Ok(())
+    }
+
+    /// When any node last recorded a query for deployment `id`
+    fn stored_query(&self, id: &SubgraphDeploymentId) -> Result<Option<SystemTime>, Error> {
+        Ok(self
+            .store
+            .get(SubgraphDeploymentQueriedEntity::key(id.clone()))?
+            .and_then(|queried| match queried.get("queriedAt") {
+                Some(Value::BigInt(secs)) => Some(UNIX_EPOCH + Duration::from_secs(secs.to_u64())),
+                _ => None,
+            }))
+    }
+
+    /// Remember that the hibernated deployments on this node are
+    /// hibernated. The registrar does not start deployments that were
+    /// hibernated before the node started or that were assigned to this
+    /// node while hibernated
+    fn track_hibernated(&self, assigned: &[SubgraphDeploymentId]) -> Result<(), Error> {
+        let hibernated = self.hibernated_deployments()?;
+        let mut states = self.states.lock().unwrap();
+        for id in assigned.iter().filter(|id| hibernated.contains(id)) {
+            if !states.contains_key(id) {
+                states.insert(id.clone(), IdleState::Hibernated(Instant::now()));
+                self.activity.set_idle(id, true);
+            }
+        }
+        Ok(())
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        if self.startup.is_shutting_down() {
+            return Ok(());
+        }
+        let assigned = self.assigned_deployments()?;
+        self.track_hibernated(&assigned)?;
+        for id in assigned {
+            let synced = self
+                .store
+                .get(SubgraphDeploymentEntity::key(id.clone()))?
+                .and_then(|deployment| deployment.get("synced").cloned())
+                == Some(Value::Bool(true));
+            let inactive_for = SystemTime::now()
+                .duration_since(self.activity.last_active(&id))
+                .unwrap_or_default();
+            let queried_at = self
+                .activity
+                .last_queried(&id)
+                .into_iter()
+                .chain(self.stored_query(&id)?)
+                .chain(Some(self.started_at))
+                .max()
+                .unwrap();
+            let unqueried_for = SystemTime::now()
+                .duration_since(queried_at)
+                .unwrap_or_default();
+            let state = self.states.lock().unwrap().get(&id).cloned();
+
+            let action = match idle_action(
+                &self.policy,
+                state,
+                synced,
+                inactive_for,
+                unqueried_for,
+                Instant::now(),
+            ) {
+                Some(action) => action,
+                None => continue,
+            };
+            if let Err(e) = self.apply(&id, action, inactive_for, unqueried_for).await {
+                warn!(
+                    self.logger,
+                    "Failed to downgrade idle deployment";
+                    "subgraph_id" => id.to_string(),
+                    "error" => e.to_string()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn apply(
+        &self,
+        id: &SubgraphDeploymentId,
+        action: IdleAction,
+        inactive_for: Duration,
+        unqueried_for: Duration,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        match action {
+            IdleAction::Downgrade => {
+                info!(
+                    self.logger,
+                    "Deployment is idle";
+                    "subgraph_id" => id.to_string(),
+                    "inactive_hours" => inactive_for.as_secs() / 3600,
+                    "pause" => self.policy.pause
+                );
+                self.activity.set_idle(id, true);
+                self.states
+                    .lock()
+                    .unwrap()
+                    .insert(id.clone(), IdleState::Running(now));
+                let since = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                self.store.apply_metadata_operations(
+                    SubgraphDeploymentIdleEntity::new(since, self.policy.pause, false)
+                        .write_operations(id),
+                )?;
+                if self.policy.pause {
+                    self.provider.stop(id.clone()).compat().await?;
+                    self.states
+                        .lock()
+                        .unwrap()
+                        .insert(id.clone(), IdleState::Stopped(now));
+                }
+            }
+            IdleAction::CatchUp => {
+                debug!(self.logger, "Start idle deployment to catch up";
+                       "subgraph_id" => id.to_string());
+                self.states
+                    .lock()
+                    .unwrap()
+                    .insert(id.clone(), IdleState::CatchingUp(now));
+                self.provider.start(id.clone()).compat().await?;
+            }
+            IdleAction::Hibernate => {
+                info!(
+                    self.logger,
+                    "Hibernate deployment";
+                    "subgraph_id" => id.to_string(),
+                    "unqueried_hours" => unqueried_for.as_secs() / 3600,
+                );
+
+                // Mark the deployment before stopping it so that a query
+                // that arrives in the meantime wakes it up
+                let previous = self
+                    .states
+                    .lock()
+                    .unwrap()
+                    .insert(id.clone(), IdleState::Hibernated(now));
+                self.activity.set_idle(id, true);
+                let running = match previous {
+                    Some(IdleState::Stopped(_)) => false,
+                    _ => true,
+                };
+                let stopped = if running {
+                    self.provider.stop(id.clone()).compat().await
+                } else {
+                    Ok(())
+                };
+
+                // If the deployment was woken up while it was being
+                // drained and stopped, the provider starts it again once the
+                // stop is over; it must then neither be restored nor
+                // marked as hibernated
+                let mut states = self.states.lock().unwrap();
+                if states.get(id) != Some(&IdleState::Hibernated(now)) {
+                    return Ok(());
+                }
+                if let Err(e) = stopped {
+                    match previous {
+                        Some(previous) => states.insert(id.clone(), previous),
+                        None => states.remove(id),
+                    };
+                    self.activity.set_idle(id, previous.is_some());
+                    return Err(e.into());
+                }
+
+                let since = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                self.store.apply_metadata_operations(
+                    SubgraphDeploymentIdleEntity::new(since, true, true).write_operations(id),
+                )?;
+            }
+            IdleAction::Stop => {
+                debug!(self.logger, "Stop idle deployment after catching up";
+                       "subgraph_id" => id.to_string());
+                self.provider.stop(id.clone()).compat().await?;
+                self.states
+                    .lock()
+                    .unwrap()
+                    .insert(id.clone(), IdleState::Stopped(now));
+            }
+        }
+        Ok(())
+    }
+
+    /// Make the idle deployment `id` a regular deployment again since it
+    /// was queried, had triggers, or was woken up by an admin
+    async fn wake(&self, id: SubgraphDeploymentId) {
+        if self.startup.is_shutting_down() {
+            return;
+        }
+        let state = match self.states.lock().unwrap().remove(&id) {
+            Some(state) => state,
+            None => return,
+        };
+        self.activity.set_idle(&id, false);
+
+        info!(self.logger, "Idle deployment is in use again";
+              "subgraph_id" => id.to_string());
+
+        let result = match state {
+            IdleState::Stopped(_) | IdleState::Hibernated(_) => self
+                .provider
+                .start(id.clone())
+                .compat()
+                .await
+                .map_err(Error::from),
+            IdleState::Running(_) | IdleState::CatchingUp(_) => Ok(()),
+        }
+        .and_then(|()| {
+            self.store
+                .apply_metadata_operations(SubgraphDeploymentIdleEntity::remove_operations(&id))
+                .map_err(Error::from)
+        });
+        if let Err(e) = result {
+            warn!(
+                self.logger,
+                "Failed to wake up idle deployment";
+                "subgraph_id" => id.to_string(),
+                "error" => e.to_string()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph_mock::MockStore;
+
+    /// Records the deployments it is asked to start
+    #[derive(Default)]
+    struct StartProvider {
+        started: Mutex<Vec<SubgraphDeploymentId>>,
+    }
+
+    impl EventProducer<SubgraphAssignmentProviderEvent> for StartProvider {
+        fn take_event_stream(
+            &mut self,
+        ) -> Option<Box<dyn Stream<Item = SubgraphAssignmentProviderEvent, Error = ()> + Send>>
+        {
+            None
+        }
+    }
+
+    impl SubgraphAssignmentProviderTrait for StartProvider {
+        fn start(
+            &self,
+            id: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            self.started.lock().unwrap().push(id);
+            Box::new(future::ok(()))
+        }
+
+        fn stop(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+
+        fn pause(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+
+        fn resume(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+
+        fn rewind(
+            &self,
+            _: SubgraphDeploymentId,
+            _: EthereumBlockPointer,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+
+        fn migrate_storage(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+    }
+
+    fn hibernated_idle_entity() -> Entity {
+        Entity::from(vec![("hibernated", Value::from(true))])
+    }
+
+    #[test]
+    fn idle_actions() {
+        let policy = IdlePolicy {
+            idle_after: Some(Duration::from_secs(100)),
+            hibernate_after: Some(Duration::from_secs(2000)),
+            pause: true,
+            catch_up_interval: Duration::from_secs(50),
+            check_interval: Duration::from_secs(10),
+        };
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        let action = |state, synced, inactive_for| {
+            idle_action(&policy, state, synced, inactive_for, inactive_for, now)
+        };
+
+        assert_eq!(None, action(None, true, secs(99)));
+        assert_eq!(None, action(None, false, secs(1000)));
+        assert_eq!(Some(IdleAction::Downgrade), action(None, true, secs(100)));
+        assert_eq!(
+            None,
+            action(Some(IdleState::Running(now - secs(1000))), true, secs(1000))
+        );
+        assert_eq!(
+            None,
+            action(Some(IdleState::Stopped(now - secs(49))), true, secs(1000))
+        );
+        assert_eq!(
+            Some(IdleAction::CatchUp),
+            action(Some(IdleState::Stopped(now - secs(50))), true, secs(1000))
+        );
+        assert_eq!(
+            None,
+            action(Some(IdleState::CatchingUp(now - secs(9))), true, secs(1000))
+        );
+        assert_eq!(
+            Some(IdleAction::Stop),
+            action(
+                Some(IdleState::CatchingUp(now - secs(10))),
+                true,
+                secs(1000)
+            )
+        );
+
+        // Hibernation does not care whether the deployment is synced or had
+        // triggers, only whether it was queried
+        assert_eq!(Some(IdleAction::Hibernate), action(None, false, secs(2000)));
+        assert_eq!(
+            Some(IdleAction::Hibernate),
+            action(Some(IdleState::Stopped(now - secs(10))), true, secs(2000))
+        );
+        assert_eq!(
+            None,
+            action(
+                Some(IdleState::Hibernated(now - secs(1000))),
+                true,
+                secs(5000)
+            )
+        );
+        assert_eq!(
+            Some(IdleAction::Hibernate),
+            idle_action(&policy, None, true, secs(10), secs(2000), now)
+        );
+
+        // Either can be turned off on its own
+        let hibernate_only = IdlePolicy {
+            idle_after: None,
+            ..policy.clone()
+        };
+        assert_eq!(
+            None,
+            idle_action(&hibernate_only, None, true, secs(1000), secs(1000), now)
+        );
+        let idle_only = IdlePolicy {
+            hibernate_after: None,
+            ..policy.clone()
+        };
+        assert_eq!(
+            Some(IdleAction::Downgrade),
+            idle_action(&idle_only, None, true, secs(5000), secs(5000), now)
+        );
+    }
+
+    #[test]
+    fn queries_wake_hibernated_deployments_through_the_store() {
+        let id = SubgraphDeploymentId::new("QmHibernated").unwrap();
+        let queried_at = UNIX_EPOCH + Duration::from_secs(1000);
+
+        let mut store = MockStore::new();
+        store
+            .expect_get()
+            .returning(|_| Ok(Some(hibernated_idle_entity())));
+        store
+            .expect_apply_metadata_operations()
+            .times(1)
+            .withf(|ops| match ops.as_slice() {
+                [MetadataOperation::Set { entity, data, .. }, MetadataOperation::Remove {
+                    entity: removed, ..
+                }] => {
+                    entity == SubgraphDeploymentQueriedEntity::TYPENAME
+                        && data.get("queriedAt") == Some(&Value::from(1000u64))
+                        && removed == SubgraphDeploymentIdleEntity::TYPENAME
+                }
+                _ => false,
+            })
+            .returning(|_| Ok(()));
+        record_query(&store, &id, queried_at).unwrap();
+
+        // Queries for deployments that are not hibernated are only recorded
+        let mut store = MockStore::new();
+        store.expect_get().returning(|_| Ok(None));
+        store
+            .expect_apply_metadata_operations()
+            .times(1)
+            .withf(|ops| match ops.as_slice() {
+                [MetadataOperation::Set { entity, .. }] => {
+                    entity == SubgraphDeploymentQueriedEntity::TYPENAME
+                }
+                _ => false,
+            })
+            .returning(|_| Ok(()));
+        record_query(&store, &id, queried_at).unwrap();
+    }
+
+    #[test]
+    fn hibernated_deployments_stay_hibernated_until_they_are_queried() {
+        let hibernated = SubgraphDeploymentId::new("QmHibernated").unwrap();
+        let running = SubgraphDeploymentId::new("QmRunning").unwrap();
+
+        let mut store = MockStore::new();
+        store
+            .expect_find()
+            .returning(|query| match &query.collection {
+                EntityCollection::All(types)
+                    if types[0] == SubgraphDeploymentAssignmentEntity::TYPENAME =>
+                {
+                    Ok(vec![
+                        Entity::from(vec![("id", Value::from("QmHibernated"))]),
+                        Entity::from(vec![("id", Value::from("QmRunning"))]),
+                    ])
+                }
+                _ => Ok(vec![Entity::from(vec![(
+                    "id",
+                    Value::from("QmHibernated"),
+                )])]),
+            });
+        // Restarting the node only clears the idle entities of deployments
+        // that are not hibernated, and waking up the hibernated deployment
+        // removes its idle entity
+        store
+            .expect_apply_metadata_operations()
+            .times(2)
+            .withf(|ops| match ops.as_slice() {
+                [MetadataOperation::Remove { entity, id }] => {
+                    entity == SubgraphDeploymentIdleEntity::TYPENAME
+                        && (id == "QmRunning" || id == "QmHibernated")
+                }
+                _ => false,
+            })
+            .returning(|_| Ok(()));
+
+        let provider = Arc::new(StartProvider::default());
+        let activity = Arc::new(DeploymentActivity::new());
+        let monitor = IdleDeploymentMonitor::new(
+            &LoggerFactory::new(Logger::root(slog::Discard, o!()), None),
+            provider.clone(),
+            Arc::new(store),
+            activity.clone(),
+            NodeId::new("here").unwrap(),
+        );
+        let wake_stream = activity.take_wake_stream().unwrap();
+
+        monitor.clear_idle_entities().unwrap();
+        let assigned = monitor.assigned_deployments().unwrap();
+        monitor.track_hibernated(&assigned).unwrap();
+        assert!(activity.is_idle(&hibernated));
+        assert!(!activity.is_idle(&running));
+        match monitor.states.lock().unwrap().get(&hibernated) {
+            Some(IdleState::Hibernated(_)) => (),
+            state => panic!("the deployment must be hibernated, not {:?}", state),
+        }
+
+        // A query for the hibernated deployment wakes it up, which starts it
+        activity.record_query(&running);
+        activity.record_query(&hibernated);
+        let (woken, _) = wake_stream.into_future().wait().ok().unwrap();
+        assert_eq!(Some(hibernated.clone()), woken);
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(monitor.wake(hibernated.clone()));
+        assert_eq!(vec![hibernated.clone()], *provider.started.lock().unwrap());
+        assert!(!activity.is_idle(&hibernated));
+        assert!(monitor.states.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,430 @@
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use graph::data::subgraph::schema::{
+    SubgraphDeploymentAssignmentEntity, SubgraphEntity, TypedEntity,
+};
+use graph::prelude::{SubgraphRegistrar as SubgraphRegistrarTrait, *};
+
+use super::rebalance::pinned_node;
+
+lazy_static! {
+    // How often the deployments on this node are reconciled with the
+    // indexing rules, in seconds
+    static ref INDEXING_RULES_INTERVAL: Duration = Duration::from_secs(
+        env::var("GRAPH_INDEXING_RULES_INTERVAL")
+            .unwrap_or("300".into())
+            .parse::<u64>()
+            .expect("invalid indexing rules interval")
+    );
+}
+
+/// The prefix of the names of the subgraphs that the indexing rules
+/// deploy. Only subgraphs with such a name are ever paused or removed
+/// because of the rules, so that deployments made by hand are left alone.
+const NAME_PREFIX: &str = "indexing-rules";
+
+/// The actor that changes made because of the indexing rules are recorded
+/// under in the audit log of admin operations
+const ACTOR: &str = "indexing-rules";
+
+/// The rules that decide which deployments this node indexes, read from
+/// a YAML file
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IndexingRules {
+    /// Deployments to index regardless of their signal
+    #[serde(default)]
+    deployments: Vec<SubgraphDeploymentId>,
+    /// Deployments to keep, but not index. Pausing a deployment takes
+    /// precedence over all other rules
+    #[serde(default)]
+    paused: Vec<SubgraphDeploymentId>,
+    registry: Option<SignalRegistry>,
+}
+
+/// An endpoint that lists deployments with their signal as a JSON array
+/// of `{ "deployment": "Qm...", "signal": 1234.5 }` objects
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignalRegistry {
+    url: String,
+    /// Index every deployment in the registry with at least this signal
+    min_signal: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeploymentSignal {
+    deployment: SubgraphDeploymentId,
+    signal: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum RuleAction {
+    Deploy(SubgraphDeploymentId),
+    Pause(SubgraphDeploymentId),
+    Resume(SubgraphDeploymentId),
+    Remove(SubgraphDeploymentId),
+}
+
+impl RuleAction {
+    /// The admin API method that has the same effect as the action, and
+    /// its parameters, for the audit log
+    fn operation(&self) -> (&'static str, serde_json::Value) {
+        match self {
+            RuleAction::Deploy(id) => (
+                "subgraph_deploy",
+                serde_json::json!({ "ipfs_hash": id.to_string() }),
+            ),
+            RuleAction::Pause(id) => (
+                "subgraph_pause",
+                serde_json::json!({ "ipfs_hash": id.to_string() }),
+            ),
+            RuleAction::Resume(id) => (
+                "subgraph_resume",
+                serde_json::json!({ "ipfs_hash": id.to_string() }),
+            ),
+            RuleAction::Remove(id) => (
+                "subgraph_remove",
+                serde_json::json!({ "ipfs_hash": id.to_string() }),
+            ),
+        }
+    }
+}
+
+/// A deployment that the indexing rules deployed
+#[derive(Clone, Debug, PartialEq)]
+struct ManagedDeployment {
+    /// Whether the deployment is assigned to this node. The rules of a
+    /// node never pause, resume or remove deployments on other nodes
+    local: bool,
+    paused: bool,
+    /// Pinned deployments are kept where an operator put them and are
+    /// never removed because of the rules
+    pinned: bool,
+}
+
+/// The subgraph name under which the indexing rules deploy `id`. Parts of
+/// subgraph names can be at most 32 characters long, which is shorter
+/// than IPFS hashes, so the hash is split across two parts
+fn subgraph_name(id: &SubgraphDeploymentId) -> Result<SubgraphName, Error> {
+    let (head, tail) = id.split_at(id.len().min(32));
+    let name = if tail.is_empty() {
+        format!("{}/{}", NAME_PREFIX, head)
+    } else {
+        format!("{}/{}/{}", NAME_PREFIX, head, tail)
+    };
+    SubgraphName::new(name.clone()).map_err(|()| format_err!("invalid subgraph name `{}`", name))
+}
+
+/// The deployment for a subgraph named by `subgraph_name`, or `None` if the
+/// subgraph was not deployed by the indexing rules
+fn deployment_id(name: &str) -> Option<SubgraphDeploymentId> {
+    let prefix = format!("{}/", NAME_PREFIX);
+    if !name.starts_with(&prefix) {
+        return None;
+    }
+    SubgraphDeploymentId::new(name[prefix.len()..].replace('/', "")).ok()
+}
+
+/// Determine what needs to change so that the deployments managed by the
+/// rules match the `desired` and `paused` deployments. `managed` contains
+/// every deployment that the rules of any node deployed.
+fn plan(
+    desired: &BTreeSet<SubgraphDeploymentId>,
+    paused: &BTreeSet<SubgraphDeploymentId>,
+    managed: &BTreeMap<SubgraphDeploymentId, ManagedDeployment>,
+) -> Vec<RuleAction> {
+    let mut actions = vec![];
+    for id in desired.difference(paused) {
+        match managed.get(id) {
+            None => actions.push(RuleAction::Deploy(id.clone())),
+            Some(deployment) if deployment.local && deployment.paused => {
+                actions.push(RuleAction::Resume(id.clone()))
+            }
+            Some(_) => (),
+        }
+    }
+    for (id, deployment) in managed.iter().filter(|(_, deployment)| deployment.local) {
+        if paused.contains(id) {
+            if !deployment.paused {
+                actions.push(RuleAction::Pause(id.clone()));
+            }
+        } else if !desired.contains(id) && !deployment.pinned {
+            actions.push(RuleAction::Remove(id.clone()));
+        }
+    }
+    actions
+}
+
+/// Periodically deploys, pauses and removes deployments on this node so
+/// that they match the indexing rules in a file. The file is read again
+/// on every run, so that changes to it take effect without a restart.
+/// Every change is recorded in the audit log of admin operations.
+pub struct IndexingRulesReconciler<R, S> {
+    logger: Logger,
+    path: PathBuf,
+    registrar: Arc<R>,
+    store: Arc<S>,
+    node_id: NodeId,
+    http: reqwest::Client,
+    startup: Arc<StartupStatus>,
+}
+
+impl<R, S> IndexingRulesReconciler<R, S>
+where
+    R: SubgraphRegistrarTrait,
+    S: Store,
+{
+    pub fn new(
+        logger_factory: &LoggerFactory,
+        path: PathBuf,
+        registrar: Arc<R>,
+        store: Arc<S>,
+        node_id: NodeId,
+    ) -> Self {
+        IndexingRulesReconciler {
+            logger: logger_factory.component_logger("IndexingRulesReconciler", None),
+            path,
+            registrar,
+            store,
+            node_id,
+            http: reqwest::Client::new(),
+            startup: Arc::new(StartupStatus::new()),
+        }
+    }
+
+    /// Stop applying the rules once `startup` says that the node is
+    /// shutting down
+    pub fn with_startup(mut self, startup: Arc<StartupStatus>) -> Self {
+        self.startup = startup;
+        self
+    }
+
+    pub fn start(self) {
+        use futures03::stream::StreamExt;
+
+        let reconciler = Arc::new(self);
+
+        // Blocking due to store interactions. Won't be blocking after #905.
+        graph::spawn_blocking(tokio::time::interval(*INDEXING_RULES_INTERVAL).for_each(
+            move |_| {
+                let reconciler = reconciler.clone();
+                async move {
+                    if let Err(e) = reconciler.reconcile().await {
+                        warn!(
+                            reconciler.logger,
+                            "Failed to apply indexing rules";
+                            "error" => e.to_string()
+                        );
+                    }
+                }
+            },
+        ));
+    }
+
+    async fn reconcile(&self) -> Result<(), Error> {
+        if self.startup.is_shutting_down() {
+            return Ok(());
+        }
+        let rules: IndexingRules = serde_yaml::from_str(&fs::read_to_string(&self.path)?)?;
+
+        // If the registry can not be reached, we do not know which
+        // deployments are desired and leave everything as it is, rather
+        // than removing all deployments that were chosen by their signal
+        let mut desired: BTreeSet<_> = rules.deployments.into_iter().collect();
+        if let Some(registry) = rules.registry {
+            desired.extend(self.signaled_deployments(&registry).await?);
+        }
+        let paused = rules.paused.into_iter().collect();
+
+        for action in plan(&desired, &paused, &self.managed_deployments()?) {
+            info!(self.logger, "Applying indexing rule"; "action" => format!("{:?}", action));
+            let result = self.apply(&action).await;
+            if let Err(e) = &result {
+                warn!(
+                    self.logger,
+                    "Failed to apply indexing rule";
+                    "action" => format!("{:?}", action),
+                    "error" => e.to_string()
+                );
+            }
+
+            let (operation, parameters) = action.operation();
+            if let Err(e) = self
+                .registrar
+                .record_admin_operation(
+                    operation.to_owned(),
+                    Some(ACTOR.to_owned()),
+                    parameters.to_string(),
+                    result.err().map(|e| e.to_string()),
+                )
+                .compat()
+                .await
+            {
+                error!(
+                    self.logger,
+                    "Failed to record indexing rule in the audit log";
+                    "action" => format!("{:?}", action),
+                    "error" => e.to_string()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn signaled_deployments(
+        &self,
+        registry: &SignalRegistry,
+    ) -> Result<Vec<SubgraphDeploymentId>, Error> {
+        let body = self
+            .http
+            .get(registry.url.as_str())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let signals: Vec<DeploymentSignal> = serde_json::from_slice(&body)?;
+        Ok(signals
+            .into_iter()
+            .filter(|signal| signal.signal >= registry.min_signal)
+            .map(|signal| signal.deployment)
+            .collect())
+    }
+
+    /// The deployments that the indexing rules deployed, and whether they
+    /// are assigned to this node, paused or pinned
+    fn managed_deployments(
+        &self,
+    ) -> Result<BTreeMap<SubgraphDeploymentId, ManagedDeployment>, Error> {
+        let subgraphs =
+            self.store
+                .find(SubgraphEntity::query().filter(EntityFilter::StartsWith(
+                    "name".to_owned(),
+                    Value::from(format!("{}/", NAME_PREFIX)),
+                )))?;
+
+        let mut managed = BTreeMap::new();
+        for subgraph in subgraphs {
+            let id = match subgraph
+                .get("name")
+                .and_then(|name| name.clone().as_string())
+                .and_then(|name| deployment_id(&name))
+            {
+                Some(id) => id,
+                None => continue,
+            };
+            let assignment = self
+                .store
+                .get(SubgraphDeploymentAssignmentEntity::key(id.clone()))?;
+            let node = assignment
+                .as_ref()
+                .and_then(|assignment| assignment.get("nodeId").cloned())
+                .and_then(Value::as_string);
+            let paused = assignment
+                .as_ref()
+                .and_then(|assignment| assignment.get("paused").cloned())
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+            let pinned = pinned_node(&*self.store, &id)?.is_some();
+            managed.insert(
+                id,
+                ManagedDeployment {
+                    local: node.as_ref() == Some(&self.node_id.to_string()),
+                    paused,
+                    pinned,
+                },
+            );
+        }
+        Ok(managed)
+    }
+
+    async fn apply(&self, action: &RuleAction) -> Result<(), Error> {
+        match action {
+            RuleAction::Deploy(id) => {
+                let name = subgraph_name(id)?;
+                match self.registrar.create_subgraph(name.clone()).compat().await {
+                    Ok(_) | Err(SubgraphRegistrarError::NameExists(_)) => (),
+                    Err(e) => return Err(e.into()),
+                }
+                // Deploying assigns the deployment; a pinned deployment
+                // stays on the node it is pinned to
+                let node_id =
+                    pinned_node(&*self.store, id)?.unwrap_or_else(|| self.node_id.clone());
+                self.registrar
+                    .create_subgraph_version(name, id.clone(), node_id, BTreeMap::new())
+                    .compat()
+                    .await?;
+            }
+            RuleAction::Pause(id) => self.registrar.pause_subgraph(id.clone()).compat().await?,
+            RuleAction::Resume(id) => self.registrar.resume_subgraph(id.clone()).compat().await?,
+            RuleAction::Remove(id) => {
+                self.registrar
+                    .remove_subgraph(subgraph_name(id)?)
+                    .compat()
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn indexing_rules() {
+    let id = |hash: &str| SubgraphDeploymentId::new(hash).unwrap();
+    let hash = "QmXoypizjW3WknFiJnKLwHCnL72vedxjQkDDP1mXWo6uco";
+
+    let name = subgraph_name(&id(hash)).unwrap();
+    assert_eq!(Some(id(hash)), deployment_id(&name.to_string()));
+    assert_eq!(None, deployment_id("other/QmA"));
+
+    let rules: IndexingRules = serde_yaml::from_str(
+        "
+deployments: [QmA, QmB]
+paused: [QmB]
+registry:
+  url: http://localhost/signals
+  minSignal: 10.5
+",
+    )
+    .unwrap();
+    assert_eq!(vec![id("QmB")], rules.paused);
+    assert_eq!(10.5, rules.registry.unwrap().min_signal);
+
+    let desired = vec![id("QmA"), id("QmB"), id("QmC"), id("QmD")]
+        .into_iter()
+        .collect();
+    let paused = vec![id("QmB"), id("QmE"), id("QmG")].into_iter().collect();
+    let deployment = |local, paused, pinned| ManagedDeployment {
+        local,
+        paused,
+        pinned,
+    };
+    let managed = vec![
+        (id("QmB"), deployment(true, false, false)),
+        (id("QmC"), deployment(true, true, false)),
+        (id("QmD"), deployment(true, false, false)),
+        (id("QmE"), deployment(true, true, false)),
+        (id("QmF"), deployment(true, false, false)),
+        // Deployments on other nodes and pinned deployments are left alone
+        (id("QmG"), deployment(false, false, false)),
+        (id("QmH"), deployment(false, false, false)),
+        (id("QmI"), deployment(true, false, true)),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(
+        vec![
+            RuleAction::Deploy(id("QmA")),
+            RuleAction::Resume(id("QmC")),
+            RuleAction::Pause(id("QmB")),
+            RuleAction::Remove(id("QmF")),
+        ],
+        plan(&desired, &paused, &managed)
+    );
+}
@@ -2,6 +2,7 @@ use futures::sync::mpsc::Sender;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::str::FromStr;
 
 use graph::prelude::{SubgraphInstance as SubgraphInstanceTrait, *};
@@ -12,6 +13,89 @@ lazy_static! {
         .ok()
         .map(|s| usize::from_str(&s)
             .unwrap_or_else(|_| panic!("failed to parse env var GRAPH_SUBGRAPH_MAX_DATA_SOURCES")));
+
+    /// Limits on data sources for individual deployments that take
+    /// precedence over `GRAPH_SUBGRAPH_MAX_DATA_SOURCES`
+    static ref MAX_DATA_SOURCES_OVERRIDES: HashMap<SubgraphDeploymentId, usize> =
+        deployment_overrides("GRAPH_SUBGRAPH_MAX_DATA_SOURCES_OVERRIDES");
+
+    /// The most entity operations the handlers of a deployment may make
+    /// for one block
+    static ref MAX_BLOCK_ENTITY_OPS: Option<usize> = env::var("GRAPH_SUBGRAPH_MAX_BLOCK_ENTITY_OPS")
+        .ok()
+        .map(|s| usize::from_str(&s)
+            .unwrap_or_else(|_| panic!("failed to parse env var GRAPH_SUBGRAPH_MAX_BLOCK_ENTITY_OPS")));
+
+    static ref MAX_BLOCK_ENTITY_OPS_OVERRIDES: HashMap<SubgraphDeploymentId, usize> =
+        deployment_overrides("GRAPH_SUBGRAPH_MAX_BLOCK_ENTITY_OPS_OVERRIDES");
+
+    /// The most gas the handlers of a deployment may use for one block
+    static ref MAX_BLOCK_GAS: Option<u64> = env::var("GRAPH_SUBGRAPH_MAX_BLOCK_GAS")
+        .ok()
+        .map(|s| u64::from_str(&s)
+            .unwrap_or_else(|_| panic!("failed to parse env var GRAPH_SUBGRAPH_MAX_BLOCK_GAS")));
+
+    static ref MAX_BLOCK_GAS_OVERRIDES: HashMap<SubgraphDeploymentId, u64> =
+        deployment_overrides("GRAPH_SUBGRAPH_MAX_BLOCK_GAS_OVERRIDES");
+}
+
+/// Read the per-deployment limits in env var `name`
+fn deployment_overrides<T>(name: &str) -> HashMap<SubgraphDeploymentId, T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    env::var(name)
+        .map(|s| {
+            parse_deployment_overrides(&s)
+                .unwrap_or_else(|e| panic!("failed to parse env var {}: {}", name, e))
+        })
+        .unwrap_or_default()
+}
+
+/// Parse limits for individual deployments of the form
+/// `<deployment>=<limit>`, separated by commas
+fn parse_deployment_overrides<T>(s: &str) -> Result<HashMap<SubgraphDeploymentId, T>, Error>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let id = parts.next().unwrap_or_default().trim();
+            let limit = parts
+                .next()
+                .ok_or_else(|| format_err!("missing limit for `{}`", id))?;
+            let id = SubgraphDeploymentId::new(id)
+                .map_err(|()| format_err!("invalid deployment ID `{}`", id))?;
+            let limit = T::from_str(limit.trim())
+                .map_err(|e| format_err!("invalid limit for `{}`: {}", id, e))?;
+            Ok((id, limit))
+        })
+        .collect()
+}
+
+/// The most data sources deployment `id` may have; no limit if `None`
+fn max_data_sources(id: &SubgraphDeploymentId) -> Option<usize> {
+    MAX_DATA_SOURCES_OVERRIDES
+        .get(id)
+        .cloned()
+        .or(*MAX_DATA_SOURCES)
+}
+
+/// The limits on the work the handlers of deployment `id` may do for one
+/// block
+fn block_budget(id: &SubgraphDeploymentId) -> BlockBudget {
+    BlockBudget {
+        max_entity_ops: MAX_BLOCK_ENTITY_OPS_OVERRIDES
+            .get(id)
+            .cloned()
+            .or(*MAX_BLOCK_ENTITY_OPS),
+        max_gas: MAX_BLOCK_GAS_OVERRIDES.get(id).cloned().or(*MAX_BLOCK_GAS),
+    }
 }
 
 pub struct SubgraphInstance<T: RuntimeHostBuilder> {
@@ -28,6 +112,12 @@ pub struct SubgraphInstance<T: RuntimeHostBuilder> {
 
     /// Maps a serialized module to a channel to the thread in which the module is instantiated.
     module_cache: HashMap<Vec<u8>, Sender<T::Req>>,
+
+    /// The most data sources the subgraph may have
+    max_data_sources: Option<usize>,
+
+    /// The limits on the work handlers may do for one block
+    block_budget: BlockBudget,
 }
 
 impl<T> SubgraphInstance<T>
@@ -46,6 +136,8 @@ where
 
         let mut this = SubgraphInstance {
             host_builder,
+            max_data_sources: max_data_sources(&subgraph_id),
+            block_budget: block_budget(&subgraph_id),
             subgraph_id,
             network,
             hosts: Vec::new(),
@@ -114,6 +206,60 @@ where
             host_metrics,
         )
     }
+
+    /// The runtime hosts of the subgraph, in the order in which they were
+    /// created
+    pub(crate) fn hosts(&self) -> &[Arc<T::Host>] {
+        &self.hosts
+    }
+
+    /// The limit on data sources for the subgraph given the data sources it
+    /// has now
+    pub(crate) fn data_source_limit(&self) -> Option<DataSourceLimit> {
+        self.max_data_sources.map(|max| DataSourceLimit {
+            max,
+            existing: self.hosts.len(),
+        })
+    }
+
+    /// The limits on the work handlers may do for one block
+    pub(crate) fn block_budget(&self) -> BlockBudget {
+        self.block_budget
+    }
+
+    /// Group the positions of `triggers` by the runtime host that handles
+    /// them, keeping the positions within each group in order. The groups
+    /// are in the order in which the hosts were created. Returns `None` if
+    /// any trigger is handled by more or less than exactly one host, since
+    /// such triggers can not be assigned to a single group.
+    pub(crate) fn triggers_by_host(&self, triggers: &[EthereumTrigger]) -> Option<Vec<Vec<usize>>> {
+        let mut groups: Vec<Vec<usize>> = vec![vec![]; self.hosts.len()];
+        for (position, trigger) in triggers.iter().enumerate() {
+            let mut matching = self
+                .hosts
+                .iter()
+                .enumerate()
+                .filter(|(_, host)| match trigger {
+                    EthereumTrigger::Log(log) => host.matches_log(log),
+                    EthereumTrigger::Call(call) => host.matches_call(call),
+                    EthereumTrigger::Block(ptr, trigger_type) => {
+                        host.matches_block(trigger_type.clone(), ptr.number)
+                    }
+                })
+                .map(|(index, _)| index);
+            match (matching.next(), matching.next()) {
+                (Some(index), None) => groups[index].push(position),
+                _ => return None,
+            }
+        }
+
+        Some(
+            groups
+                .into_iter()
+                .filter(|positions| !positions.is_empty())
+                .collect(),
+        )
+    }
 }
 
 impl<T> SubgraphInstanceTrait<T::Host> for SubgraphInstance<T>
@@ -148,71 +294,7 @@ where
         trigger: EthereumTrigger,
         state: BlockState,
     ) -> Box<dyn Future<Item = BlockState, Error = Error> + Send> {
-        let logger = logger.to_owned();
-        match trigger {
-            EthereumTrigger::Log(log) => {
-                let transaction = block
-                    .transaction_for_log(&log)
-                    .map(Arc::new)
-                    .ok_or_else(|| format_err!("Found no transaction for event"));
-                let matching_hosts: Vec<_> = hosts.filter(|host| host.matches_log(&log)).collect();
-                let log = Arc::new(log);
-
-                // Process the log in each host in the same order the corresponding data
-                // sources appear in the subgraph manifest
-                Box::new(future::result(transaction).and_then(|transaction| {
-                    stream::iter_ok(matching_hosts).fold(state, move |state, host| {
-                        host.process_log(
-                            logger.clone(),
-                            block.clone(),
-                            transaction.clone(),
-                            log.clone(),
-                            state,
-                        )
-                    })
-                }))
-            }
-            EthereumTrigger::Call(call) => {
-                let transaction = block
-                    .transaction_for_call(&call)
-                    .map(Arc::new)
-                    .ok_or_else(|| format_err!("Found no transaction for call"));
-                let matching_hosts: Vec<_> = hosts
-                    .into_iter()
-                    .filter(|host| host.matches_call(&call))
-                    .collect();
-                let call = Arc::new(call);
-
-                Box::new(future::result(transaction).and_then(|transaction| {
-                    stream::iter_ok(matching_hosts).fold(state, move |state, host| {
-                        host.process_call(
-                            logger.clone(),
-                            block.clone(),
-                            transaction.clone(),
-                            call.clone(),
-                            state,
-                        )
-                    })
-                }))
-            }
-            EthereumTrigger::Block(ptr, trigger_type) => {
-                let matching_hosts: Vec<_> = hosts
-                    .into_iter()
-                    .filter(|host| host.matches_block(trigger_type.clone(), ptr.number))
-                    .collect();
-
-                Box::new(
-                    stream::iter_ok(matching_hosts).fold(state, move |state, host| {
-                        host.process_block(
-                            logger.clone(),
-                            block.clone(),
-                            trigger_type.clone(),
-                            state,
-                        )
-                    }),
-                )
-            }
-        }
+        process_trigger_in_hosts(logger, hosts, block, trigger, state)
     }
 
     fn add_dynamic_data_source(
@@ -223,7 +305,7 @@ where
         metrics: Arc<HostMetrics>,
     ) -> Result<Arc<T::Host>, Error> {
         // Protect against creating more than the allowed maximum number of data sources
-        if let Some(max_data_sources) = *MAX_DATA_SOURCES {
+        if let Some(max_data_sources) = self.max_data_sources {
             if self.hosts.len() >= max_data_sources {
                 return Err(format_err!(
                     "Limit of {} data sources per subgraph exceeded",
@@ -242,3 +324,87 @@ where
         Ok(host)
     }
 }
+
+/// Process `trigger` in each of `hosts` that matches it, in order; this is how
+/// `SubgraphInstance` processes triggers
+pub(crate) fn process_trigger_in_hosts<H: RuntimeHost>(
+    logger: &Logger,
+    hosts: impl Iterator<Item = Arc<H>>,
+    block: Arc<LightEthereumBlock>,
+    trigger: EthereumTrigger,
+    state: BlockState,
+) -> Box<dyn Future<Item = BlockState, Error = Error> + Send> {
+    let logger = logger.to_owned();
+    match trigger {
+        EthereumTrigger::Log(log) => {
+            let transaction = block
+                .transaction_for_log(&log)
+                .map(Arc::new)
+                .ok_or_else(|| format_err!("Found no transaction for event"));
+            let matching_hosts: Vec<_> = hosts.filter(|host| host.matches_log(&log)).collect();
+            let log = Arc::new(log);
+
+            // Process the log in each host in the same order the corresponding data
+            // sources appear in the subgraph manifest
+            Box::new(future::result(transaction).and_then(|transaction| {
+                stream::iter_ok(matching_hosts).fold(state, move |state, host| {
+                    host.process_log(
+                        logger.clone(),
+                        block.clone(),
+                        transaction.clone(),
+                        log.clone(),
+                        state,
+                    )
+                })
+            }))
+        }
+        EthereumTrigger::Call(call) => {
+            let transaction = block
+                .transaction_for_call(&call)
+                .map(Arc::new)
+                .ok_or_else(|| format_err!("Found no transaction for call"));
+            let matching_hosts: Vec<_> = hosts
+                .into_iter()
+                .filter(|host| host.matches_call(&call))
+                .collect();
+            let call = Arc::new(call);
+
+            Box::new(future::result(transaction).and_then(|transaction| {
+                stream::iter_ok(matching_hosts).fold(state, move |state, host| {
+                    host.process_call(
+                        logger.clone(),
+                        block.clone(),
+                        transaction.clone(),
+                        call.clone(),
+                        state,
+                    )
+                })
+            }))
+        }
+        EthereumTrigger::Block(ptr, trigger_type) => {
+            let matching_hosts: Vec<_> = hosts
+                .into_iter()
+                .filter(|host| host.matches_block(trigger_type.clone(), ptr.number))
+                .collect();
+
+            Box::new(
+                stream::iter_ok(matching_hosts).fold(state, move |state, host| {
+                    host.process_block(logger.clone(), block.clone(), trigger_type.clone(), state)
+                }),
+            )
+        }
+    }
+}
+
+#[test]
+fn parse_overrides() {
+    let id = SubgraphDeploymentId::new("QmXYZ").unwrap();
+    let overrides = parse_deployment_overrides::<usize>(" QmXYZ=10, QmABC = 2,").unwrap();
+    assert_eq!(Some(&10), overrides.get(&id));
+    assert_eq!(2, overrides.len());
+
+    assert!(parse_deployment_overrides::<usize>("QmXYZ").is_err());
+    assert!(parse_deployment_overrides::<usize>("QmXYZ=many").is_err());
+    assert!(parse_deployment_overrides::<u64>("QmXYZ=-1").is_err());
+    assert!(parse_deployment_overrides::<usize>("not valid=1").is_err());
+}
@@ -1,19 +1,26 @@
 use futures::future::{loop_fn, Loop};
 use futures::sync::mpsc::{channel, Receiver, Sender};
 use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::sync::RwLock;
-use std::time::Instant;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 use graph::components::ethereum::triggers_in_block;
 use graph::components::store::ModificationsAndCache;
 use graph::data::subgraph::schema::{
     DynamicEthereumContractDataSourceEntity, SubgraphDeploymentEntity,
+    SubgraphDeploymentQuotaEntity, SubgraphErrorEntity, SubgraphHandlerExecutionEntity,
+    SubgraphSkippedBlockEntity, TypedEntity,
 };
+use graph::prelude::web3::types::H256;
 use graph::prelude::{SubgraphInstance as SubgraphInstanceTrait, *};
 use graph::util::lfu_cache::LfuCache;
 
+use super::instance::process_trigger_in_hosts;
+use super::provider::FAST_START;
+use super::supervisor::Supervisor;
 use super::SubgraphInstance;
 
 lazy_static! {
@@ -24,30 +31,180 @@ lazy_static! {
             .unwrap_or("10000".into())
             .parse::<u64>()
             .expect("invalid GRAPH_ENTITY_CACHE_SIZE");
+
+    /// Size limit of the entity LFU cache of idle deployments, in bytes
+    static ref IDLE_ENTITY_CACHE_SIZE: u64 = 1000
+        * std::env::var("GRAPH_IDLE_ENTITY_CACHE_SIZE")
+            .unwrap_or("100".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_IDLE_ENTITY_CACHE_SIZE");
+
+    /// Whether to run the handlers for triggers of different data sources in
+    /// a block concurrently; see `process_trigger_groups`
+    static ref PARALLEL_DATA_SOURCES: bool = std::env::var("GRAPH_EXPERIMENTAL_PARALLEL_DATA_SOURCES")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+
+    /// How often to measure the size of a deployment against its disk
+    /// quota, in seconds. A deployment that exceeds its hard limit is
+    /// checked again at the same interval
+    static ref DISK_QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(
+        std::env::var("GRAPH_DISK_QUOTA_CHECK_INTERVAL")
+            .unwrap_or("300".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_DISK_QUOTA_CHECK_INTERVAL")
+    );
+
+    /// For how many of the most recent blocks to keep the handler journal
+    /// of a deployment; 0 turns the journal off
+    static ref HANDLER_JOURNAL_BLOCKS: u64 = std::env::var("GRAPH_HANDLER_JOURNAL_BLOCKS")
+        .unwrap_or("0".into())
+        .parse::<u64>()
+        .expect("invalid GRAPH_HANDLER_JOURNAL_BLOCKS");
+
+    /// The hashes of the blocks whose triggers each deployment does not
+    /// process
+    static ref SKIPPED_BLOCKS: HashMap<SubgraphDeploymentId, HashSet<H256>> =
+        std::env::var("GRAPH_SKIP_BLOCKS")
+            .ok()
+            .map(|blocks| parse_skipped_blocks(&blocks).expect("invalid GRAPH_SKIP_BLOCKS"))
+            .unwrap_or_default();
+}
+
+/// The size limit of the entity cache of a deployment, which is smaller
+/// while the deployment is idle
+fn entity_cache_size<B, S>(inputs: &IndexingInputs<B, S>) -> u64 {
+    match &inputs.activity {
+        Some(activity) if activity.is_idle(&inputs.deployment_id) => *IDLE_ENTITY_CACHE_SIZE,
+        _ => *ENTITY_CACHE_SIZE,
+    }
+}
+
+/// Parse a list of blocks to skip of the form `deployment:hash,...`. Blocks
+/// are identified by their hash so that a block with the same number on a
+/// different fork is still processed
+fn parse_skipped_blocks(
+    blocks: &str,
+) -> Result<HashMap<SubgraphDeploymentId, HashSet<H256>>, Error> {
+    let mut skipped: HashMap<_, HashSet<_>> = HashMap::new();
+    for entry in blocks
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let mut parts = entry.splitn(2, ':');
+        let (deployment, hash) = match (parts.next(), parts.next()) {
+            (Some(deployment), Some(hash)) => (deployment, hash),
+            _ => {
+                return Err(format_err!(
+                    "block to skip `{}` must have the form `deployment:hash`",
+                    entry
+                ))
+            }
+        };
+        let deployment = SubgraphDeploymentId::new(deployment)
+            .map_err(|()| format_err!("invalid deployment ID `{}`", deployment))?;
+        let hash = H256::from_str(hash.trim_start_matches("0x"))
+            .map_err(|e| format_err!("invalid block hash `{}`: {}", hash, e))?;
+        skipped.entry(deployment).or_default().insert(hash);
+    }
+    Ok(skipped)
 }
 
 type SharedInstanceKeepAliveMap = Arc<RwLock<HashMap<SubgraphDeploymentId, CancelGuard>>>;
 
+/// The deployments whose dynamic data sources are being loaded before they
+/// are started; dropping a guard keeps its deployment from being started
+type SharedLoadingMap = Arc<Mutex<HashMap<SubgraphDeploymentId, CancelGuard>>>;
+
+/// The deployments whose block processing has been paused
+type SharedPausedSet = Arc<RwLock<HashSet<SubgraphDeploymentId>>>;
+
+/// Which deployments are processing a block, and which ones are being
+/// drained before they are stopped, together with the signals to notify
+/// once they have finished the block they are processing
+#[derive(Default)]
+struct DrainState {
+    processing: HashSet<SubgraphDeploymentId>,
+    draining: HashMap<SubgraphDeploymentId, Vec<DrainSignal>>,
+}
+
+impl DrainState {
+    /// Record that deployment `id` starts processing a block. Returns
+    /// `false` if the deployment is being drained and must not start
+    /// another block
+    fn start_block(&mut self, id: &SubgraphDeploymentId) -> bool {
+        if self.draining.contains_key(id) {
+            return false;
+        }
+        self.processing.insert(id.clone());
+        true
+    }
+
+    /// Record that deployment `id` has finished its block, which also
+    /// finishes draining it
+    fn finish_block(&mut self, id: &SubgraphDeploymentId) {
+        self.processing.remove(id);
+        if let Some(signals) = self.draining.get_mut(id) {
+            signals.drain(..).for_each(|signal| signal.notify());
+        }
+    }
+
+    /// Drain deployment `id` and notify `signal` once it is not processing
+    /// a block anymore
+    fn drain(&mut self, id: SubgraphDeploymentId, signal: DrainSignal) {
+        let processing = self.processing.contains(&id);
+        let signals = self.draining.entry(id).or_default();
+        if processing {
+            signals.push(signal);
+        } else {
+            signal.notify();
+        }
+    }
+
+    /// Forget that deployment `id` was being drained
+    fn stop(&mut self, id: &SubgraphDeploymentId) {
+        if let Some(signals) = self.draining.remove(id) {
+            signals.into_iter().for_each(|signal| signal.notify());
+        }
+    }
+}
+
+type SharedDrainState = Arc<Mutex<DrainState>>;
+
+/// How often a paused deployment checks whether it has been resumed
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 struct IndexingInputs<B, S> {
     deployment_id: SubgraphDeploymentId,
     network_name: String,
     start_blocks: Vec<u64>,
+    /// Whether the deployment opted into non-fatal errors
+    non_fatal_errors: bool,
     store: Arc<S>,
     eth_adapter: Arc<dyn EthereumAdapter>,
     stream_builder: B,
     templates_use_calls: bool,
     top_level_templates: Arc<Vec<DataSourceTemplate>>,
+    supervisor: Arc<Supervisor>,
+    activity: Option<Arc<DeploymentActivity>>,
 }
 
 struct IndexingState<T: RuntimeHostBuilder> {
     logger: Logger,
     instance: SubgraphInstance<T>,
     instances: SharedInstanceKeepAliveMap,
-    log_filter: EthereumLogFilter,
-    call_filter: EthereumCallFilter,
-    block_filter: EthereumBlockFilter,
+    paused: SharedPausedSet,
+    drains: SharedDrainState,
+    filter: EthereumTriggerFilter,
     restarts: u64,
     entity_lfu_cache: LfuCache<EntityKey, Option<Entity>>,
+    /// When the deployment's size was last checked against its disk quota
+    quota_checked_at: Option<Instant>,
+    handler_journal: HandlerJournal,
+    /// The dynamic data sources created by the block that is being
+    /// processed; they are handed to the supervisor once the block is written
+    new_data_sources: Vec<DataSource>,
 }
 
 struct IndexingContext<B, T: RuntimeHostBuilder, S> {
@@ -100,6 +257,14 @@ enum TriggerType {
 }
 
 impl TriggerType {
+    fn of(trigger: &EthereumTrigger) -> Self {
+        match trigger {
+            EthereumTrigger::Log(_) => TriggerType::Event,
+            EthereumTrigger::Call(_) => TriggerType::Call,
+            EthereumTrigger::Block(..) => TriggerType::Block,
+        }
+    }
+
     fn label_value(&self) -> &str {
         match self {
             TriggerType::Event => "event",
@@ -113,6 +278,7 @@ struct SubgraphInstanceMetrics {
     pub block_trigger_count: Box<Histogram>,
     pub block_processing_duration: Box<Histogram>,
     pub block_ops_transaction_duration: Box<Histogram>,
+    pub data_source_count: Box<Gauge>,
 
     trigger_processing_duration: Box<HistogramVec>,
 }
@@ -154,12 +320,20 @@ impl SubgraphInstanceMetrics {
                 vec![0.01, 0.05, 0.1, 0.3, 0.7, 2.0],
             )
             .expect("failed to create `subgraph_transact_block_operations_duration_{}");
+        let data_source_count = registry
+            .new_gauge(
+                format!("subgraph_data_source_count_{}", subgraph_hash),
+                String::from("Counts the static and dynamic data sources of a subgraph deployment"),
+                HashMap::new(),
+            )
+            .expect("failed to create `subgraph_data_source_count` gauge");
 
         Self {
             block_trigger_count,
             block_processing_duration,
             trigger_processing_duration,
             block_ops_transaction_duration,
+            data_source_count,
         }
     }
 
@@ -174,11 +348,16 @@ impl SubgraphInstanceMetrics {
         registry.unregister(self.block_trigger_count.clone());
         registry.unregister(self.trigger_processing_duration.clone());
         registry.unregister(self.block_ops_transaction_duration.clone());
+        registry.unregister(self.data_source_count.clone());
     }
 }
 
 impl SubgraphInstanceManager {
-    /// Creates a new runtime manager.
+    /// Creates a new runtime manager. With the fast start profile, the
+    /// `data_source_loader` loads the dynamic data sources of deployments
+    /// as they are started. Blocks with triggers are recorded in
+    /// `activity`, and deployments that it considers idle keep a smaller
+    /// entity cache. Whether deployments started is recorded in `startup`.
     pub fn new<B, S, M>(
         logger_factory: &LoggerFactory,
         stores: HashMap<String, Arc<S>>,
@@ -186,6 +365,9 @@ impl SubgraphInstanceManager {
         host_builder: impl RuntimeHostBuilder,
         block_stream_builder: B,
         metrics_registry: Arc<M>,
+        data_source_loader: Option<Arc<dyn DataSourceLoader + Send + Sync>>,
+        activity: Option<Arc<DeploymentActivity>>,
+        startup: Option<Arc<StartupStatus>>,
     ) -> Self
     where
         S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
@@ -207,6 +389,9 @@ impl SubgraphInstanceManager {
             host_builder,
             block_stream_builder,
             metrics_registry.clone(),
+            data_source_loader,
+            activity,
+            startup,
         );
 
         SubgraphInstanceManager {
@@ -224,6 +409,9 @@ impl SubgraphInstanceManager {
         host_builder: impl RuntimeHostBuilder,
         block_stream_builder: B,
         metrics_registry: Arc<M>,
+        data_source_loader: Option<Arc<dyn DataSourceLoader + Send + Sync>>,
+        activity: Option<Arc<DeploymentActivity>>,
+        startup: Option<Arc<StartupStatus>>,
     ) where
         S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
         B: BlockStreamBuilder,
@@ -231,10 +419,15 @@ impl SubgraphInstanceManager {
     {
         let metrics_registry_for_manager = metrics_registry.clone();
         let metrics_registry_for_subgraph = metrics_registry.clone();
-        let manager_metrics = SubgraphInstanceManagerMetrics::new(metrics_registry_for_manager);
+        let manager_metrics = Arc::new(SubgraphInstanceManagerMetrics::new(
+            metrics_registry_for_manager,
+        ));
 
         // Subgraph instance shutdown senders
         let instances: SharedInstanceKeepAliveMap = Default::default();
+        let paused: SharedPausedSet = Default::default();
+        let drains: SharedDrainState = Default::default();
+        let loading: SharedLoadingMap = Default::default();
 
         // Blocking due to store interactions. Won't be blocking after #905.
         graph::spawn_blocking(receiver.compat().try_for_each(move |event| {
@@ -249,49 +442,140 @@ impl SubgraphInstanceManager {
                         "data_sources" => manifest.data_sources.len()
                     );
                     let network = manifest.network_name();
-                    Self::start_subgraph(
-                        logger.clone(),
-                        instances.clone(),
-                        host_builder.clone(),
-                        block_stream_builder.clone(),
-                        stores
-                            .get(&network)
-                            .expect(&format!(
-                                "expected store that matches subgraph network: {}",
-                                &network
-                            ))
-                            .clone(),
-                        eth_adapters
-                            .get(&network)
-                            .expect(&format!(
-                                "expected eth adapter that matches subgraph network: {}",
-                                &network
-                            ))
-                            .clone(),
-                        manifest,
-                        metrics_registry_for_subgraph.clone(),
-                    )
-                    .map_err(|err| {
-                        error!(
-                            logger,
-                            "Failed to start subgraph";
-                            "error" => format!("{}", err),
-                            "code" => LogCode::SubgraphStartFailure
-                        )
-                    })
-                    .and_then(|_| {
-                        manager_metrics.subgraph_count.inc();
-                        Ok(())
-                    })
-                    .ok();
+                    let store = stores
+                        .get(&network)
+                        .expect(&format!(
+                            "expected store that matches subgraph network: {}",
+                            &network
+                        ))
+                        .clone();
+                    let eth_adapter = eth_adapters
+                        .get(&network)
+                        .expect(&format!(
+                            "expected eth adapter that matches subgraph network: {}",
+                            &network
+                        ))
+                        .clone();
+
+                    let start = {
+                        let logger = logger.clone();
+                        let instances = instances.clone();
+                        let paused = paused.clone();
+                        let drains = drains.clone();
+                        let activity = activity.clone();
+                        let host_builder = host_builder.clone();
+                        let block_stream_builder = block_stream_builder.clone();
+                        let registry = metrics_registry_for_subgraph.clone();
+                        let manager_metrics = manager_metrics.clone();
+                        let startup = startup.clone();
+                        move |manifest: SubgraphManifest| {
+                            let id = manifest.id.clone();
+                            let result = Self::start_subgraph(
+                                logger.clone(),
+                                instances,
+                                paused,
+                                drains,
+                                activity,
+                                host_builder,
+                                block_stream_builder,
+                                store,
+                                eth_adapter,
+                                manifest,
+                                registry,
+                                None,
+                                startup.clone(),
+                            );
+                            if let Some(startup) = &startup {
+                                let phase = match &result {
+                                    Ok(()) => StartupPhase::Started,
+                                    Err(e) => StartupPhase::Failed(e.to_string()),
+                                };
+                                startup.update_phase(&id, phase);
+                            }
+                            result
+                                .map_err(|err| {
+                                    error!(
+                                        logger,
+                                        "Failed to start subgraph";
+                                        "error" => format!("{}", err),
+                                        "code" => LogCode::SubgraphStartFailure
+                                    )
+                                })
+                                .and_then(|_| {
+                                    manager_metrics.subgraph_count.inc();
+                                    Ok(())
+                                })
+                                .ok();
+                        }
+                    };
+
+                    match &data_source_loader {
+                        // With the fast start profile, the provider hands us
+                        // deployments without their dynamic data sources. We
+                        // load them in the background so that they do not
+                        // hold up starting other deployments. The provider
+                        // considers the deployment running, so loading is
+                        // retried until it succeeds or the deployment is
+                        // stopped
+                        Some(loader) if *FAST_START => {
+                            let mut manifest = manifest;
+                            if let Some(startup) = &startup {
+                                startup.update_phase(
+                                    &manifest.id,
+                                    StartupPhase::LoadingDynamicDataSources,
+                                );
+                            }
+
+                            let id = manifest.id.clone();
+                            graph::spawn_blocking(
+                                load_dynamic_data_sources_and_start(
+                                    logger,
+                                    loader.clone(),
+                                    loading.clone(),
+                                    id,
+                                    move |data_sources| {
+                                        manifest.data_sources.extend(data_sources);
+                                        start(manifest);
+                                    },
+                                )
+                                .compat(),
+                            );
+                        }
+                        _ => start(manifest),
+                    }
                 }
                 SubgraphStop(id) => {
                     let logger = logger_factory.subgraph_logger(&id);
                     info!(logger, "Stop subgraph");
 
-                    Self::stop_subgraph(instances.clone(), id);
+                    // A deployment whose dynamic data sources are still
+                    // being loaded is not started once they are loaded
+                    let mut loading = loading.lock().unwrap();
+                    loading.remove(&id);
+                    Self::stop_subgraph(instances.clone(), id.clone());
+                    drop(loading);
+                    paused.write().unwrap().remove(&id);
+                    drains.lock().unwrap().stop(&id);
                     manager_metrics.subgraph_count.dec();
                 }
+                SubgraphPause(id) => {
+                    let logger = logger_factory.subgraph_logger(&id);
+                    info!(logger, "Pause subgraph");
+
+                    paused.write().unwrap().insert(id);
+                }
+                SubgraphResume(id) => {
+                    let logger = logger_factory.subgraph_logger(&id);
+                    info!(logger, "Resume subgraph");
+
+                    paused.write().unwrap().remove(&id);
+                }
+                SubgraphDrain(id, signal) => {
+                    let logger = logger_factory.subgraph_logger(&id);
+                    info!(logger, "Drain subgraph");
+
+                    drains.lock().unwrap().drain(id, signal);
+                }
             };
 
             futures03::future::ok(())
@@ -301,12 +585,17 @@ impl SubgraphInstanceManager {
     fn start_subgraph<B, S, M>(
         logger: Logger,
         instances: SharedInstanceKeepAliveMap,
+        paused: SharedPausedSet,
+        drains: SharedDrainState,
+        activity: Option<Arc<DeploymentActivity>>,
         host_builder: impl RuntimeHostBuilder,
         stream_builder: B,
         store: Arc<S>,
         eth_adapter: Arc<dyn EthereumAdapter>,
         manifest: SubgraphManifest,
         registry: Arc<M>,
+        supervisor: Option<Arc<Supervisor>>,
+        startup: Option<Arc<StartupStatus>>,
     ) -> Result<(), Error>
     where
         B: BlockStreamBuilder,
@@ -331,11 +620,10 @@ impl SubgraphInstanceManager {
         let deployment_id = manifest.id.clone();
         let network_name = manifest.network_name();
 
-        // Obtain filters from the manifest
-        let log_filter = EthereumLogFilter::from_data_sources(&manifest.data_sources);
-        let call_filter = EthereumCallFilter::from_data_sources(&manifest.data_sources);
-        let block_filter = EthereumBlockFilter::from_data_sources(&manifest.data_sources);
+        // Obtain the trigger filter from the manifest
+        let filter = EthereumTriggerFilter::from_data_sources(&manifest.data_sources);
         let start_blocks = manifest.start_blocks();
+        let non_fatal_errors = manifest.non_fatal_errors();
 
         // Identify whether there are templates with call handlers or
         // block handlers with call filters; in this case, we need to
@@ -346,6 +634,31 @@ impl SubgraphInstanceManager {
         });
 
         let top_level_templates = Arc::new(manifest.templates.clone());
+        let handler_journal =
+            HandlerJournal::load(&*store, &deployment_id, *HANDLER_JOURNAL_BLOCKS)?;
+
+        // Restarts after a transient failure keep the supervisor of the
+        // first run of the deployment
+        let supervisor =
+            supervisor.unwrap_or_else(|| Arc::new(Supervisor::new(manifest.data_sources.clone())));
+        let restart = Restart {
+            logger: logger.clone(),
+            instances: instances.clone(),
+            paused: paused.clone(),
+            drains: drains.clone(),
+            activity: activity.clone(),
+            host_builder: host_builder.clone(),
+            stream_builder: stream_builder.clone(),
+            store: store.clone(),
+            eth_adapter: eth_adapter.clone(),
+            manifest: SubgraphManifest {
+                data_sources: vec![],
+                ..manifest.clone()
+            },
+            registry: registry.clone(),
+            supervisor: supervisor.clone(),
+            startup,
+        };
 
         // Create a subgraph instance from the manifest; this moves
         // ownership of the manifest and host builder into the new instance
@@ -373,6 +686,9 @@ impl SubgraphInstanceManager {
         ));
         let instance =
             SubgraphInstance::from_manifest(&logger, manifest, host_builder, host_metrics.clone())?;
+        subgraph_metrics
+            .data_source_count
+            .set(instance.hosts().len() as f64);
 
         // The subgraph state tracks the state of the subgraph instance over time
         let ctx = IndexingContext {
@@ -380,21 +696,27 @@ impl SubgraphInstanceManager {
                 deployment_id: deployment_id.clone(),
                 network_name,
                 start_blocks,
+                non_fatal_errors,
                 store,
                 eth_adapter,
                 stream_builder,
                 templates_use_calls,
                 top_level_templates,
+                supervisor,
+                activity,
             },
             state: IndexingState {
                 logger,
                 instance,
                 instances,
-                log_filter,
-                call_filter,
-                block_filter,
+                paused,
+                drains,
+                filter,
                 restarts: 0,
                 entity_lfu_cache: LfuCache::new(),
+                quota_checked_at: None,
+                handler_journal,
+                new_data_sources: vec![],
             },
             subgraph_metrics,
             host_metrics,
@@ -411,7 +733,12 @@ impl SubgraphInstanceManager {
         // This task has many calls to the store, so mark it as `blocking`.
         let subgraph_runner = loop_fn(ctx, move |ctx| run_subgraph(ctx)).then(move |res| {
             subgraph_metrics_unregister.unregister(registry);
-            future::result(res)
+            match res {
+                Ok(()) | Err(RunEnd::Stopped) => {
+                    Box::new(future::ok(())) as Box<dyn Future<Item = _, Error = _> + Send>
+                }
+                Err(RunEnd::Restart(delay)) => Box::new(restart.after(delay)),
+            }
         });
         graph::spawn_blocking(subgraph_runner.compat());
 
@@ -437,9 +764,113 @@ impl EventConsumer<SubgraphAssignmentProviderEvent> for SubgraphInstanceManager
     }
 }
 
+/// Why a deployment stopped running
+enum RunEnd {
+    /// The deployment was stopped, or it failed and has been marked as failed
+    Stopped,
+    /// The deployment failed because of a transient error and is restarted
+    /// after the delay
+    Restart(Duration),
+}
+
+/// Everything needed to start a deployment again after it failed because
+/// of a transient error
+struct Restart<B, H, S, M> {
+    logger: Logger,
+    instances: SharedInstanceKeepAliveMap,
+    paused: SharedPausedSet,
+    drains: SharedDrainState,
+    activity: Option<Arc<DeploymentActivity>>,
+    host_builder: H,
+    stream_builder: B,
+    store: Arc<S>,
+    eth_adapter: Arc<dyn EthereumAdapter>,
+    /// The manifest of the deployment without its data sources, which are
+    /// taken from the supervisor
+    manifest: SubgraphManifest,
+    registry: Arc<M>,
+    supervisor: Arc<Supervisor>,
+    /// Deployments are not restarted once the node shuts down
+    startup: Option<Arc<StartupStatus>>,
+}
+
+impl<B, H, S, M> Restart<B, H, S, M>
+where
+    B: BlockStreamBuilder,
+    H: RuntimeHostBuilder,
+    S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
+    M: MetricsRegistry,
+{
+    /// Start the deployment again after `delay`. Stopping the deployment
+    /// while it waits drops the cancel guard and ends the wait early
+    fn after(self, delay: Duration) -> impl Future<Item = (), Error = ()> {
+        let canceler = CancelGuard::new();
+        let wait = tokio::time::delay_for(delay)
+            .unit_error()
+            .compat()
+            .cancelable(&canceler, || ());
+        {
+            // The deployment may have been stopped after it failed, in which
+            // case its block stream guard is gone and it stays stopped
+            let mut instances = self.instances.write().unwrap();
+            if !instances.contains_key(&self.manifest.id) {
+                return future::Either::A(future::ok(()));
+            }
+            instances.insert(self.manifest.id.clone(), canceler);
+        }
+
+        future::Either::B(wait.then(move |result| {
+            if result.is_err() {
+                debug!(self.logger, "Subgraph stopped before it was restarted");
+                return Ok(());
+            }
+            if let Some(startup) = &self.startup {
+                if startup.is_shutting_down() {
+                    debug!(
+                        self.logger,
+                        "Not restarting subgraph, the node is shutting down"
+                    );
+                    return Ok(());
+                }
+            }
+
+            let logger = self.logger.clone();
+            let manifest = SubgraphManifest {
+                data_sources: self.supervisor.data_sources(),
+                ..self.manifest
+            };
+            SubgraphInstanceManager::start_subgraph(
+                self.logger,
+                self.instances,
+                self.paused,
+                self.drains,
+                self.activity,
+                self.host_builder,
+                self.stream_builder,
+                self.store,
+                self.eth_adapter,
+                manifest,
+                self.registry,
+                Some(self.supervisor),
+                self.startup,
+            )
+            .map_err(|e| {
+                error!(
+                    logger,
+                    "Failed to restart subgraph";
+                    "error" => format!("{}", e),
+                    "code" => LogCode::SubgraphStartFailure
+                )
+            })
+            .ok();
+            Ok(())
+        }))
+    }
+}
+
 fn run_subgraph<B, T, S>(
     ctx: IndexingContext<B, T, S>,
-) -> impl Future<Item = Loop<(), IndexingContext<B, T, S>>, Error = ()>
+) -> impl Future<Item = Loop<(), IndexingContext<B, T, S>>, Error = RunEnd>
 where
     B: BlockStreamBuilder,
     T: RuntimeHostBuilder,
@@ -452,6 +883,7 @@ where
     // Clone a few things for different parts of the async processing
     let id_for_err = ctx.inputs.deployment_id.clone();
     let store_for_err = ctx.inputs.store.clone();
+    let supervisor_for_err = ctx.inputs.supervisor.clone();
     let logger_for_err = logger.clone();
     let logger_for_block_stream_errors = logger.clone();
 
@@ -465,9 +897,7 @@ where
             ctx.inputs.deployment_id.clone(),
             ctx.inputs.network_name.clone(),
             ctx.inputs.start_blocks.clone(),
-            ctx.state.log_filter.clone(),
-            ctx.state.call_filter.clone(),
-            ctx.state.block_filter.clone(),
+            ctx.state.filter.clone(),
             ctx.inputs.templates_use_calls,
             ctx.block_stream_metrics.clone(),
         )
@@ -489,8 +919,93 @@ where
     enum StreamEnd<B: BlockStreamBuilder, T: RuntimeHostBuilder, S> {
         Error(CancelableError<Error>),
         NeedsRestart(IndexingContext<B, T, S>),
+        Paused(IndexingContext<B, T, S>),
+        Drained,
     }
 
+    let process_event = move |mut ctx: IndexingContext<B, T, S>,
+                              event: BlockStreamEvent|
+          -> Box<dyn Future<Item = _, Error = StreamEnd<B, T, S>> + Send> {
+        // A paused deployment stops before processing the next block;
+        // the block is streamed again once the deployment is resumed
+        if ctx
+            .state
+            .paused
+            .read()
+            .unwrap()
+            .contains(&ctx.inputs.deployment_id)
+        {
+            return Box::new(future::err(StreamEnd::Paused(ctx)));
+        }
+
+        let block = match event {
+            BlockStreamEvent::Revert => {
+                // On revert, clear the entity cache.
+                ctx.state.entity_lfu_cache = LfuCache::new();
+
+                // The handlers that ran for the reverted block did not run
+                // on the main chain
+                let head = ctx.inputs.store.block_ptr(ctx.inputs.deployment_id.clone());
+                if let Err(e) = head.and_then(|head| {
+                    let ops = ctx.state.handler_journal.revert(head);
+                    if ops.is_empty() {
+                        return Ok(());
+                    }
+                    ctx.inputs
+                        .store
+                        .apply_metadata_operations(ops)
+                        .map_err(Error::from)
+                }) {
+                    warn!(
+                        logger,
+                        "Failed to remove reverted handler journal entries: {}", e
+                    );
+                }
+                return Box::new(future::ok(ctx));
+            }
+            BlockStreamEvent::Pending(block) => {
+                return Box::new(
+                    process_pending_block(logger.clone(), ctx, block).map_err(StreamEnd::Error),
+                );
+            }
+            BlockStreamEvent::Block(block) => block,
+        };
+        let subgraph_metrics = ctx.subgraph_metrics.clone();
+        let start = Instant::now();
+        if block.triggers.len() > 0 {
+            subgraph_metrics
+                .block_trigger_count
+                .observe(block.triggers.len() as f64);
+            if let Some(activity) = &ctx.inputs.activity {
+                activity.record_triggers(&ctx.inputs.deployment_id);
+            }
+        }
+        let logger = logger.clone();
+        let block_stream_cancel_handle = block_stream_cancel_handle.clone();
+        Box::new(
+            enforce_disk_quota(ctx, block_stream_cancel_handle.clone())
+                .and_then(move |ctx| {
+                    process_block(
+                        logger,
+                        ctx.inputs.eth_adapter.clone(),
+                        ctx,
+                        block_stream_cancel_handle,
+                        block,
+                    )
+                })
+                .map_err(|e| StreamEnd::Error(e))
+                .and_then(|(ctx, needs_restart)| match needs_restart {
+                    false => Ok(ctx),
+                    true => Err(StreamEnd::NeedsRestart(ctx)),
+                })
+                .then(move |res| {
+                    let elapsed = start.elapsed().as_secs_f64();
+                    subgraph_metrics.block_processing_duration.observe(elapsed);
+                    res
+                }),
+        )
+    };
+
     block_stream
         // Log and drop the errors from the block_stream
         // The block stream will continue attempting to produce blocks
@@ -509,94 +1024,270 @@ where
         // Process events from the stream as long as no restart is needed
         .fold(
             ctx,
-            move |mut ctx, event| -> Box<dyn Future<Item = _, Error = _> + Send> {
-                let block = match event {
-                    BlockStreamEvent::Revert => {
-                        // On revert, clear the entity cache.
-                        ctx.state.entity_lfu_cache = LfuCache::new();
-                        return Box::new(future::ok(ctx));
-                    }
-                    BlockStreamEvent::Block(block) => block,
-                };
-                let subgraph_metrics = ctx.subgraph_metrics.clone();
-                let start = Instant::now();
-                if block.triggers.len() > 0 {
-                    subgraph_metrics
-                        .block_trigger_count
-                        .observe(block.triggers.len() as f64);
+            move |ctx, event| -> Box<dyn Future<Item = _, Error = _> + Send> {
+                // A deployment that is being drained does not start another
+                // block, so that stopping it does not cut a block short
+                let drains = ctx.state.drains.clone();
+                let id = ctx.inputs.deployment_id.clone();
+                if !drains.lock().unwrap().start_block(&id) {
+                    return Box::new(future::err(StreamEnd::Drained));
                 }
-                Box::new(
-                    process_block(
-                        logger.clone(),
-                        ctx.inputs.eth_adapter.clone(),
-                        ctx,
-                        block_stream_cancel_handle.clone(),
-                        block,
-                    )
-                    .map_err(|e| StreamEnd::Error(e))
-                    .and_then(|(ctx, needs_restart)| match needs_restart {
-                        false => Ok(ctx),
-                        true => Err(StreamEnd::NeedsRestart(ctx)),
-                    })
-                    .then(move |res| {
-                        let elapsed = start.elapsed().as_secs_f64();
-                        subgraph_metrics.block_processing_duration.observe(elapsed);
-                        res
-                    }),
-                )
+                Box::new(process_event(ctx, event).then(move |res| {
+                    drains.lock().unwrap().finish_block(&id);
+                    res
+                }))
             },
         )
-        .then(move |res| match res {
-            Ok(_) => unreachable!("block stream finished without error"),
-            Err(StreamEnd::NeedsRestart(mut ctx)) => {
-                // Increase the restart counter
-                ctx.state.restarts += 1;
-
-                // Cancel the stream for real
-                ctx.state
-                    .instances
-                    .write()
-                    .unwrap()
-                    .remove(&ctx.inputs.deployment_id);
-
-                // And restart the subgraph
-                Ok(Loop::Continue(ctx))
-            }
+        .then(move |res| -> Box<dyn Future<Item = _, Error = _> + Send> {
+            match res {
+                Ok(_) => unreachable!("block stream finished without error"),
+                Err(StreamEnd::NeedsRestart(mut ctx)) => {
+                    // Increase the restart counter
+                    ctx.state.restarts += 1;
+
+                    // Cancel the stream for real
+                    ctx.state
+                        .instances
+                        .write()
+                        .unwrap()
+                        .remove(&ctx.inputs.deployment_id);
+
+                    // And restart the subgraph
+                    Box::new(future::ok(Loop::Continue(ctx)))
+                }
 
-            Err(StreamEnd::Error(CancelableError::Cancel)) => {
-                debug!(
-                    logger_for_err,
-                    "Subgraph block stream shut down cleanly";
-                    "id" => id_for_err.to_string(),
-                );
-                Err(())
-            }
+                // Restart the subgraph once it is resumed
+                Err(StreamEnd::Paused(ctx)) => Box::new(
+                    wait_for_resume(ctx)
+                        .map(Loop::Continue)
+                        .map_err(|()| RunEnd::Stopped),
+                ),
 
-            // Handle unexpected stream errors by marking the subgraph as failed.
-            Err(StreamEnd::Error(CancelableError::Error(e))) => {
-                error!(
-                    logger_for_err,
-                    "Subgraph instance failed to run: {}", e;
-                    "id" => id_for_err.to_string(),
-                    "code" => LogCode::SubgraphSyncingFailure
-                );
+                Err(StreamEnd::Drained) => {
+                    info!(
+                        logger_for_err,
+                        "Subgraph drained; stopped processing blocks";
+                        "id" => id_for_err.to_string(),
+                    );
+                    Box::new(future::err(RunEnd::Stopped))
+                }
+
+                Err(StreamEnd::Error(CancelableError::Cancel)) => {
+                    debug!(
+                        logger_for_err,
+                        "Subgraph block stream shut down cleanly";
+                        "id" => id_for_err.to_string(),
+                    );
+                    Box::new(future::err(RunEnd::Stopped))
+                }
+
+                // Handle unexpected stream errors by marking the subgraph as failed.
+                Err(StreamEnd::Error(CancelableError::Error(e))) => {
+                    // Errors caused by outages of the Ethereum node, IPFS or
+                    // the database go away on their own; restart the subgraph
+                    // with an increasing delay until the restarts are used up
+                    if TransientError::is_cause_of(&e) {
+                        if let Some((attempt, delay)) = supervisor_for_err.next_restart() {
+                            warn!(
+                                logger_for_err,
+                                "Subgraph instance failed because of a transient error, \
+                                 restarting: {}", e;
+                                "id" => id_for_err.to_string(),
+                                "attempt" => attempt,
+                                "delay_secs" => delay.as_secs(),
+                            );
+                            return Box::new(future::err(RunEnd::Restart(delay)));
+                        }
+                    }
 
-                // Set subgraph status to Failed
-                let status_ops =
-                    SubgraphDeploymentEntity::update_failed_operations(&id_for_err, true);
-                if let Err(e) = store_for_err.apply_metadata_operations(status_ops) {
                     error!(
                         logger_for_err,
-                        "Failed to set subgraph status to Failed: {}", e;
+                        "Subgraph instance failed to run: {}", e;
                         "id" => id_for_err.to_string(),
-                        "code" => LogCode::SubgraphSyncingFailureNotRecorded
+                        "code" => LogCode::SubgraphSyncingFailure
                     );
+
+                    // Set subgraph status to Failed
+                    let mut status_ops =
+                        SubgraphDeploymentEntity::update_failed_operations(&id_for_err, true);
+
+                    // The failed block is never written, so the handler that
+                    // failed has to be added to the handler journal separately
+                    if let Some(failure) = HandlerExecutionError::find(&e) {
+                        if *HANDLER_JOURNAL_BLOCKS > 0 {
+                            let entry = SubgraphHandlerExecutionEntity::new(
+                                id_for_err.clone(),
+                                failure.index,
+                                failure.execution.clone(),
+                            );
+                            let id = entry.id();
+                            status_ops.extend(entry.write_operations(&id));
+                        }
+                    }
+
+                    if let Err(e) = store_for_err.apply_metadata_operations(status_ops) {
+                        error!(
+                            logger_for_err,
+                            "Failed to set subgraph status to Failed: {}", e;
+                            "id" => id_for_err.to_string(),
+                            "code" => LogCode::SubgraphSyncingFailureNotRecorded
+                        );
+                    }
+                    Box::new(future::err(RunEnd::Stopped))
                 }
-                Err(())
             }
         })
 }
 
+/// Wait until the paused deployment is resumed. The deployment has no block
+/// stream while it waits, but keeps the rest of its state; the wait ends
+/// early with an error if the deployment is stopped
+fn wait_for_resume<B, T: RuntimeHostBuilder, S>(
+    ctx: IndexingContext<B, T, S>,
+) -> impl Future<Item = IndexingContext<B, T, S>, Error = ()>
+where
+    B: BlockStreamBuilder,
+    S: ChainStore + Store + EthereumCallCache + SubgraphDeploymentStore,
+{
+    info!(
+        ctx.state.logger,
+        "Subgraph paused; stopped processing blocks"
+    );
+
+    wait_until_resumed(
+        ctx.state.paused.clone(),
+        ctx.state.instances.clone(),
+        ctx.inputs.deployment_id.clone(),
+    )
+    .map(move |()| {
+        info!(ctx.state.logger, "Subgraph resumed");
+        ctx
+    })
+}
+
+/// Wait until the deployment `id` is not in `paused` anymore. Stopping the
+/// deployment drops the guard that this puts into `instances` in place of
+/// the one for the block stream, which ends the wait with an error
+fn wait_until_resumed(
+    paused: SharedPausedSet,
+    instances: SharedInstanceKeepAliveMap,
+    id: SubgraphDeploymentId,
+) -> impl Future<Item = (), Error = ()> {
+    let canceler = CancelGuard::new();
+    let id_for_wait = id.clone();
+    let wait = loop_fn(
+        (),
+        move |()| -> Box<dyn Future<Item = _, Error = _> + Send> {
+            if paused.read().unwrap().contains(&id_for_wait) {
+                Box::new(
+                    tokio::time::delay_for(PAUSE_CHECK_INTERVAL)
+                        .unit_error()
+                        .compat()
+                        .then(|_| Ok(Loop::Continue(()))),
+                )
+            } else {
+                Box::new(future::ok(Loop::Break(())))
+            }
+        },
+    )
+    .cancelable(&canceler, || ());
+    instances.write().unwrap().insert(id, canceler);
+    wait
+}
+
+/// Check the deployment against its disk quota if the last check was more
+/// than `DISK_QUOTA_CHECK_INTERVAL` ago. While the deployment exceeds its
+/// hard limit, keep waiting and checking again; this pauses indexing, but
+/// not queries, until an operator raises the limit or frees up space.
+/// Failing to check the quota is not fatal
+fn enforce_disk_quota<B, T: RuntimeHostBuilder, S>(
+    ctx: IndexingContext<B, T, S>,
+    block_stream_cancel_handle: CancelHandle,
+) -> impl Future<Item = IndexingContext<B, T, S>, Error = CancelableError<Error>>
+where
+    B: BlockStreamBuilder,
+    S: ChainStore + Store + EthereumCallCache + SubgraphDeploymentStore,
+{
+    loop_fn(
+        ctx,
+        move |mut ctx| -> Box<dyn Future<Item = _, Error = _> + Send> {
+            if block_stream_cancel_handle.is_canceled() {
+                return Box::new(future::err(CancelableError::Cancel));
+            }
+            if let Some(checked_at) = ctx.state.quota_checked_at {
+                if checked_at.elapsed() < *DISK_QUOTA_CHECK_INTERVAL {
+                    return Box::new(future::ok(Loop::Break(ctx)));
+                }
+            }
+
+            ctx.state.quota_checked_at = Some(Instant::now());
+            match check_disk_quota(
+                &ctx.state.logger,
+                &*ctx.inputs.store,
+                &ctx.inputs.deployment_id,
+            ) {
+                Ok(true) => Box::new(
+                    tokio::time::delay_for(*DISK_QUOTA_CHECK_INTERVAL)
+                        .unit_error()
+                        .compat()
+                        .then(move |_| Ok(Loop::Continue(ctx))),
+                ),
+                Ok(false) => Box::new(future::ok(Loop::Break(ctx))),
+                Err(e) => {
+                    warn!(ctx.state.logger, "Failed to check disk quota";
+                          "error" => e.to_string());
+                    Box::new(future::ok(Loop::Break(ctx)))
+                }
+            }
+        },
+    )
+}
+
+/// Measure the size of the deployment, compare it to the deployment's
+/// disk quota and record the outcome in the quota entity. Returns whether
+/// the deployment exceeds its hard limit
+fn check_disk_quota<S>(logger: &Logger, store: &S, id: &SubgraphDeploymentId) -> Result<bool, Error>
+where
+    S: Store + SubgraphDeploymentStore,
+{
+    let quota = match store.get(SubgraphDeploymentQuotaEntity::key(id.clone()))? {
+        Some(quota) => quota,
+        None => return Ok(false),
+    };
+    let limit = |name: &str| {
+        quota
+            .get(name)
+            .cloned()
+            .and_then(Value::as_bigint)
+            .map(|limit| limit.to_u64())
+    };
+    let soft_limit = limit("softLimit");
+    let hard_limit = limit("hardLimit");
+
+    let size = store.deployment_size(id)?;
+    let soft_limit_exceeded = SubgraphDeploymentQuotaEntity::exceeds(size, soft_limit);
+    let hard_limit_exceeded = SubgraphDeploymentQuotaEntity::exceeds(size, hard_limit);
+
+    if hard_limit_exceeded {
+        warn!(logger, "Deployment exceeds its hard disk quota, pausing indexing";
+              "size" => size,
+              "hard_limit" => hard_limit);
+    } else if soft_limit_exceeded {
+        warn!(logger, "Deployment exceeds its soft disk quota";
+              "size" => size,
+              "soft_limit" => soft_limit);
+    }
+
+    store.apply_metadata_operations(SubgraphDeploymentQuotaEntity::update_usage_operations(
+        id,
+        size,
+        soft_limit_exceeded,
+        hard_limit_exceeded,
+    ))?;
+
+    Ok(hard_limit_exceeded)
+}
+
 /// Processes a block and returns the updated context and a boolean flag indicating
 /// whether new dynamic data sources have been added to the subgraph.
 fn process_block<B, T: RuntimeHostBuilder, S>(
@@ -620,6 +1311,26 @@ where
     ));
     let logger1 = logger.clone();
 
+    // Operators can make a deployment skip blocks that it can not process,
+    // e.g., because a provider returns corrupt data for them. The block
+    // pointer still advances, and the skipped block is recorded
+    let (triggers, skipped_block) = match SKIPPED_BLOCKS.get(&ctx.inputs.deployment_id) {
+        Some(blocks) if blocks.contains(&block_ptr.hash) => {
+            warn!(
+                logger,
+                "Skipping block as configured in GRAPH_SKIP_BLOCKS";
+                "skipped_triggers" => triggers.len()
+            );
+            let skipped_block = SubgraphSkippedBlockEntity::new(
+                ctx.inputs.deployment_id.clone(),
+                block_ptr,
+                triggers.len(),
+            );
+            (vec![], Some(skipped_block))
+        }
+        _ => (triggers, None),
+    };
+
     if triggers.len() == 1 {
         info!(logger, "1 trigger found in this block for this subgraph");
     } else if triggers.len() > 1 {
@@ -641,7 +1352,9 @@ where
     // collected previously to every new event being processed
     process_triggers(
         logger.clone(),
-        BlockState::with_cache(std::mem::take(&mut ctx.state.entity_lfu_cache)),
+        BlockState::with_cache(std::mem::take(&mut ctx.state.entity_lfu_cache))
+            .with_data_source_limit(ctx.state.instance.data_source_limit())
+            .with_budget(ctx.state.instance.block_budget()),
         ctx,
         light_block.clone(),
         triggers,
@@ -673,10 +1386,12 @@ where
                     &mut ctx,
                     host_metrics.clone(),
                     block_state.created_data_sources.drain(..),
+                    &block_ptr_for_new_data_sources,
                 ) {
                     Ok(ok) => ok,
                     Err(err) => return Box::new(future::err(err.into())),
                 };
+                block_state.data_source_limit = ctx.state.instance.data_source_limit();
 
                 // Reprocess the triggers from this block that match the new data sources
                 let logger = logger.clone();
@@ -745,12 +1460,39 @@ where
         .from_err()
     })
     // Apply entity operations and advance the stream
-    .and_then(move |(mut ctx, block_state, needs_restart)| {
+    .and_then(move |(mut ctx, mut block_state, needs_restart)| {
         // Avoid writing to store if block stream has been canceled
         if block_stream_cancel_handle.is_canceled() {
             return Err(CancelableError::Cancel);
         }
 
+        let executions = std::mem::take(&mut block_state.handler_executions);
+        let journal_ops = ctx.state.handler_journal.record(
+            &ctx.inputs.deployment_id,
+            &block_ptr_after,
+            executions,
+        );
+        block_state.entity_cache.append(journal_ops);
+        if let Some(message) = block_state.non_fatal_error.take() {
+            let error = SubgraphErrorEntity::new(
+                ctx.inputs.deployment_id.clone(),
+                block_ptr_after,
+                message,
+            );
+            let id = error.id();
+            block_state
+                .entity_cache
+                .append(error.write_entity_operations(&id));
+        }
+        if let Some(skipped_block) = skipped_block {
+            let id = skipped_block.id();
+            block_state
+                .entity_cache
+                .append(skipped_block.write_entity_operations(&id));
+        }
+
+        let metric_increments = std::mem::take(&mut block_state.metric_increments);
+
         let section = ctx.host_metrics.stopwatch.start_section("as_modifications");
         let ModificationsAndCache {
             modifications: mods,
@@ -759,10 +1501,8 @@ where
             .entity_cache
             .as_modifications(ctx.inputs.store.as_ref())
             .map_err(|e| {
-                CancelableError::from(format_err!(
-                    "Error while processing block stream for a subgraph: {}",
-                    e
-                ))
+                let message = format!("Error while processing block stream for a subgraph: {}", e);
+                CancelableError::from(Error::from(e.context(message)))
             })?;
         section.end();
 
@@ -770,7 +1510,7 @@ where
             .host_metrics
             .stopwatch
             .start_section("entity_cache_evict");
-        cache.evict(*ENTITY_CACHE_SIZE);
+        cache.evict(entity_cache_size(&ctx.inputs));
         section.end();
 
         // Put the cache back in the ctx, asserting that the placeholder cache was not used.
@@ -793,6 +1533,10 @@ where
             .map(|should_migrate| {
                 let elapsed = start.elapsed().as_secs_f64();
                 metrics.block_ops_transaction_duration.observe(elapsed);
+                ctx.inputs
+                    .supervisor
+                    .block_written(std::mem::take(&mut ctx.state.new_data_sources));
+                apply_metric_increments(&logger1, &ctx.host_metrics, metric_increments);
                 if should_migrate {
                     ctx.inputs.store.migrate_subgraph_deployment(
                         &logger1,
@@ -803,57 +1547,693 @@ where
                 (ctx, needs_restart)
             })
             .map_err(|e| {
-                format_err!("Error while processing block stream for a subgraph: {}", e).into()
+                let message = format!("Error while processing block stream for a subgraph: {}", e);
+                Error::from(e.context(message)).into()
             })
     })
 }
 
+/// Count the increments of custom metrics that the handlers for a block
+/// made, now that the block has been written
+/// Load the dynamic data sources of deployment `id` with `loader` and hand
+/// them to `start`. Loading is retried until it succeeds or the deployment
+/// is stopped by removing it from `loading`; `start` is not called for a
+/// deployment that was stopped meanwhile
+fn load_dynamic_data_sources_and_start(
+    logger: Logger,
+    loader: Arc<dyn DataSourceLoader + Send + Sync>,
+    loading: SharedLoadingMap,
+    id: SubgraphDeploymentId,
+    start: impl FnOnce(Vec<DataSource>) + Send + 'static,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let guard = CancelGuard::new();
+    let handle = guard.handle();
+    loading.lock().unwrap().insert(id.clone(), guard);
+
+    let logger_for_load = logger.clone();
+    let id_for_load = id.clone();
+    retry("load dynamic data sources", &logger)
+        .no_limit()
+        .no_timeout()
+        .run(move || {
+            loader
+                .clone()
+                .load_dynamic_data_sources(&id_for_load, None, logger_for_load.clone())
+        })
+        .cancelable(&handle, || format_err!("subgraph stopped"))
+        .then(move |result| {
+            // Holding the lock keeps a stop from slipping in between
+            // checking that the deployment is still wanted and starting it
+            let mut loading = loading.lock().unwrap();
+            if handle.is_canceled() {
+                info!(
+                    logger,
+                    "Subgraph stopped while loading dynamic data sources"
+                );
+                return Ok(());
+            }
+            loading.remove(&id);
+            if let Ok(data_sources) = result {
+                start(data_sources);
+            }
+            Ok(())
+        })
+}
+
+fn apply_metric_increments(
+    logger: &Logger,
+    host_metrics: &HostMetrics,
+    increments: Vec<(String, f64)>,
+) {
+    for (name, value) in increments {
+        match host_metrics.increment_custom_metric(&name, value) {
+            CustomMetricUpdate::Counted | CustomMetricUpdate::Dropped { first: false } => (),
+            CustomMetricUpdate::Dropped { first: true } => warn!(
+                logger,
+                "Dropping values for metric, the subgraph has too many metrics";
+                "metric" => &name
+            ),
+        }
+    }
+}
+
+/// Process the pending block on top of the deployment's latest block and
+/// replace the deployment's pending changes with its changes. The pending
+/// block leaves no trace in the indexing state: data sources it creates
+/// are ignored, and a failure only means that there are no pending
+/// changes until the next pending block
+fn process_pending_block<B, T: RuntimeHostBuilder, S>(
+    logger: Logger,
+    ctx: IndexingContext<B, T, S>,
+    block: EthereumBlockWithTriggers,
+) -> impl Future<Item = IndexingContext<B, T, S>, Error = CancelableError<Error>>
+where
+    B: BlockStreamBuilder,
+    S: ChainStore + Store + EthereumCallCache + SubgraphDeploymentStore,
+{
+    // Pending blocks always have a number, and `PENDING_BLOCK_HASH` as
+    // their hash
+    let triggers = block.triggers;
+    let block_ptr = EthereumBlockPointer::from(&block.ethereum_block);
+    let light_block = Arc::new(block.ethereum_block.light_block());
+    let logger = logger.new(o!("pending_block_number" => block_ptr.number));
+
+    let hosts = ctx.state.instance.hosts().to_vec();
+    let logger1 = logger.clone();
+    stream::iter_ok(triggers)
+        // Entities are loaded from the store instead of the entity cache
+        // so that the pending block does not have to copy the cache
+        .fold(
+            BlockState::with_cache(LfuCache::new()),
+            move |block_state, trigger| {
+                SubgraphInstance::<T>::process_trigger_in_runtime_hosts(
+                    &logger1,
+                    hosts.iter().cloned(),
+                    light_block.clone(),
+                    trigger,
+                    block_state,
+                )
+            },
+        )
+        .then(move |result| {
+            let id = &ctx.inputs.deployment_id;
+            let result = result
+                .and_then(|block_state| {
+                    block_state
+                        .entity_cache
+                        .as_modifications(ctx.inputs.store.as_ref())
+                        .map_err(|e| format_err!("{}", e))
+                })
+                .and_then(|ModificationsAndCache { modifications, .. }| {
+                    // Only the deployment's own entities are pending;
+                    // metadata such as dynamic data sources is not
+                    let mods = modifications
+                        .into_iter()
+                        .filter(|modification| modification.entity_key().subgraph_id == *id)
+                        .collect::<Vec<_>>();
+                    debug!(
+                        logger,
+                        "Applying {} pending entity operation(s)",
+                        mods.len()
+                    );
+                    ctx.inputs
+                        .store
+                        .transact_pending_block_operations(id, block_ptr, mods)
+                        .map_err(Error::from)
+                });
+            if let Err(e) = result {
+                warn!(logger, "Failed to process the pending block: {}", e);
+
+                // Changes of an earlier pending block are outdated now
+                if let Err(e) =
+                    ctx.inputs
+                        .store
+                        .transact_pending_block_operations(id, block_ptr, vec![])
+                {
+                    warn!(logger, "Failed to discard pending changes: {}", e);
+                }
+            }
+            Ok(ctx)
+        })
+}
+
+/// The handler journal of a deployment: the IDs of the journal entries that
+/// the store has for it, by block number and oldest first
+struct HandlerJournal {
+    /// For how many of the most recent blocks entries are kept; nothing is
+    /// recorded if this is 0
+    blocks: u64,
+    entries: VecDeque<(u64, Vec<String>)>,
+}
+
+impl HandlerJournal {
+    /// Load the IDs of the journal entries that the store has for a
+    /// deployment, so that they are removed once they are too old just like
+    /// the entries that are recorded from now on
+    fn load<S: Store>(store: &S, id: &SubgraphDeploymentId, blocks: u64) -> Result<Self, Error> {
+        let mut entries: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        let found = store.find(
+            SubgraphHandlerExecutionEntity::query()
+                .filter(EntityFilter::new_equal("deployment", id.to_string())),
+        )?;
+        for entry in found {
+            let number = entry
+                .get("blockNumber")
+                .cloned()
+                .and_then(Value::as_bigint)
+                .map(|number| number.to_u64())
+                .ok_or_else(|| format_err!("handler journal entry without block number"))?;
+            entries.entry(number).or_default().push(entry.id()?);
+        }
+        Ok(HandlerJournal {
+            blocks,
+            entries: entries.into_iter().collect(),
+        })
+    }
+
+    /// The operations that add the handlers that ran for a block to the
+    /// journal, and remove the entries of blocks that are now too old to be
+    /// kept. They are written together with the block
+    fn record(
+        &mut self,
+        deployment: &SubgraphDeploymentId,
+        block_ptr: &EthereumBlockPointer,
+        executions: Vec<HandlerExecution>,
+    ) -> Vec<EntityOperation> {
+        let mut ops = vec![];
+        if self.blocks > 0 && !executions.is_empty() {
+            let mut ids = vec![];
+            for (index, execution) in executions.into_iter().enumerate() {
+                let entry =
+                    SubgraphHandlerExecutionEntity::new(deployment.clone(), index, execution);
+                let id = entry.id();
+                ops.extend(entry.write_entity_operations(&id));
+                ids.push(id);
+            }
+            self.entries.push_back((block_ptr.number, ids));
+        }
+
+        while let Some((number, _)) = self.entries.front() {
+            if number + self.blocks > block_ptr.number {
+                break;
+            }
+            let (_, ids) = self.entries.pop_front().unwrap();
+            ops.extend(ids.into_iter().map(|id| EntityOperation::Remove {
+                key: SubgraphHandlerExecutionEntity::key(id),
+            }));
+        }
+        ops
+    }
+
+    /// The operations that remove the entries of the blocks after `head`,
+    /// which were reverted, from the store. `head` is `None` if the
+    /// deployment has no blocks left
+    fn revert(&mut self, head: Option<EthereumBlockPointer>) -> Vec<MetadataOperation> {
+        let mut ops = vec![];
+        while let Some((number, _)) = self.entries.back() {
+            if head.map_or(false, |head| *number <= head.number) {
+                break;
+            }
+            let (_, ids) = self.entries.pop_back().unwrap();
+            for id in ids {
+                ops.extend(SubgraphHandlerExecutionEntity::remove_operations(&id));
+            }
+        }
+        ops
+    }
+}
+
 fn process_triggers<B, T: RuntimeHostBuilder, S>(
     logger: Logger,
     block_state: BlockState,
     ctx: IndexingContext<B, T, S>,
     block: Arc<LightEthereumBlock>,
     triggers: Vec<EthereumTrigger>,
+) -> Box<
+    dyn Future<Item = (IndexingContext<B, T, S>, BlockState), Error = CancelableError<Error>>
+        + Send,
+>
+where
+    B: BlockStreamBuilder,
+    S: Send + Sync + 'static,
+{
+    if *PARALLEL_DATA_SOURCES {
+        match ctx.state.instance.triggers_by_host(&triggers) {
+            Some(groups) if groups.len() > 1 => {
+                return process_triggers_in_parallel(
+                    logger,
+                    block_state,
+                    ctx,
+                    block,
+                    triggers,
+                    groups,
+                )
+            }
+            _ => (),
+        }
+    }
+
+    Box::new(process_triggers_serially(
+        logger,
+        block_state,
+        ctx,
+        block,
+        triggers,
+    ))
+}
+
+/// Process the triggers of each data source concurrently with those of the
+/// other data sources; see `process_trigger_groups`
+fn process_triggers_in_parallel<B, T: RuntimeHostBuilder, S>(
+    logger: Logger,
+    block_state: BlockState,
+    ctx: IndexingContext<B, T, S>,
+    block: Arc<LightEthereumBlock>,
+    triggers: Vec<EthereumTrigger>,
+    groups: Vec<Vec<usize>>,
+) -> Box<
+    dyn Future<Item = (IndexingContext<B, T, S>, BlockState), Error = CancelableError<Error>>
+        + Send,
+>
+where
+    B: BlockStreamBuilder,
+    S: Send + Sync + 'static,
+{
+    let hosts = ctx.state.instance.hosts().to_vec();
+    let triggers = Arc::new(triggers);
+
+    Box::new(
+        process_trigger_groups(
+            logger.clone(),
+            hosts,
+            ctx.subgraph_metrics.clone(),
+            block.clone(),
+            triggers.clone(),
+            groups,
+            block_state,
+        )
+        .then(
+            move |result| -> Box<dyn Future<Item = _, Error = _> + Send> {
+                match result {
+                    Ok(ParallelOutcome::Processed(block_state)) => {
+                        Box::new(future::ok((ctx, block_state)))
+                    }
+                    Ok(ParallelOutcome::Failed(e, cache)) => Box::new(future::result(
+                        handle_trigger_error(&logger, &ctx, e, cache)
+                            .map(|block_state| (ctx, block_state))
+                            .map_err(CancelableError::from),
+                    )),
+                    Ok(ParallelOutcome::Serial(block_state)) => {
+                        let triggers = Arc::try_unwrap(triggers)
+                            .unwrap_or_else(|triggers| triggers.as_ref().clone());
+                        Box::new(process_triggers_serially(
+                            logger,
+                            block_state,
+                            ctx,
+                            block,
+                            triggers,
+                        ))
+                    }
+                    Err(()) => unreachable!("processing trigger groups does not fail"),
+                }
+            },
+        ),
+    )
+}
+
+/// The result of processing the triggers of a block in parallel
+enum ParallelOutcome {
+    /// The block state is the same as if the triggers had been processed
+    /// one after the other
+    Processed(BlockState),
+    /// Processing the triggers one after the other would have failed with
+    /// the error; the entities read from the store are kept alongside it
+    Failed(Error, LfuCache<EntityKey, Option<Entity>>),
+    /// The triggers have to be processed one after the other, starting
+    /// from the block state, which is the one processing started with
+    Serial(BlockState),
+}
+
+/// What processing one trigger of a block added to the block state
+struct TriggerOutcome {
+    /// The position of the trigger among the triggers of the block
+    position: usize,
+    handler_executions: Vec<HandlerExecution>,
+    entity_ops: usize,
+    gas_used: u64,
+}
+
+/// The state after processing a group of triggers one after the other,
+/// with what processing each of them added to it
+struct TriggerGroup {
+    state: BlockState,
+    outcomes: Vec<TriggerOutcome>,
+}
+
+/// Process each of the `groups` of positions in `triggers` one after the
+/// other, and the groups concurrently. Each group is processed in a fork of
+/// `block_state` that shares the entities read from the store with the
+/// other groups and tracks what the handlers read.
+///
+/// The state of a group is merged into the block state as is if the group
+/// does not touch any entity that another group touches and creates no
+/// data sources, since processing the triggers of the block one after the
+/// other would lead to the same changes. The triggers of the remaining
+/// groups are then processed again one after the other, and merged the
+/// same way. Only if that now touches entities that the merged groups
+/// touched does the whole block have to be processed serially.
+fn process_trigger_groups<H: RuntimeHost>(
+    logger: Logger,
+    hosts: Vec<Arc<H>>,
+    subgraph_metrics: Arc<SubgraphInstanceMetrics>,
+    block: Arc<LightEthereumBlock>,
+    triggers: Arc<Vec<EthereumTrigger>>,
+    groups: Vec<Vec<usize>>,
+    mut block_state: BlockState,
+) -> impl Future<Item = ParallelOutcome, Error = ()> {
+    let hosts = Arc::new(hosts);
+    let group_futures: Vec<_> = groups
+        .iter()
+        .map(|positions| {
+            process_trigger_group(
+                logger.clone(),
+                hosts.clone(),
+                subgraph_metrics.clone(),
+                block.clone(),
+                positions
+                    .iter()
+                    .map(|position| (*position, triggers[*position].clone()))
+                    .collect(),
+                block_state.fork(),
+            )
+            .then(|result| Ok::<_, ()>(result))
+        })
+        .collect();
+
+    future::join_all(group_futures).and_then(
+        move |results| -> Box<dyn Future<Item = _, Error = _> + Send> {
+            // What a failed group touched is not known, but its triggers are
+            // processed again and checked against the merged groups then
+            let mergeable: Vec<_> = results
+                .iter()
+                .enumerate()
+                .map(|(i, result)| match result {
+                    Ok(group) => {
+                        group.state.created_data_sources.is_empty()
+                            && results.iter().enumerate().all(|(j, other)| match other {
+                                Ok(other) if i != j => !group
+                                    .state
+                                    .entity_cache
+                                    .conflicts_with(&other.state.entity_cache),
+                                _ => true,
+                            })
+                    }
+                    Err(_) => false,
+                })
+                .collect();
+
+            let mut merged = vec![];
+            let mut remaining = vec![];
+            for ((result, positions), mergeable) in results.into_iter().zip(groups).zip(mergeable)
+            {
+                match result {
+                    Ok(group) if mergeable => merged.push(group),
+                    _ => remaining.extend(positions),
+                }
+            }
+            if remaining.is_empty() {
+                return Box::new(future::ok(merge_trigger_groups(
+                    block_state,
+                    block.as_ref(),
+                    triggers.as_ref(),
+                    merged,
+                )));
+            }
+
+            debug!(
+                logger,
+                "Processing the triggers of data sources that can not be processed in parallel again";
+                "merged_data_sources" => merged.len(),
+                "triggers" => remaining.len(),
+            );
+            remaining.sort();
+            let remaining = process_trigger_group(
+                logger.clone(),
+                hosts,
+                subgraph_metrics,
+                block.clone(),
+                remaining
+                    .into_iter()
+                    .map(|position| (position, triggers[position].clone()))
+                    .collect(),
+                block_state.fork(),
+            );
+            Box::new(remaining.then(move |result| match result {
+                Ok(group) => {
+                    if merged.iter().any(|other| {
+                        group
+                            .state
+                            .entity_cache
+                            .conflicts_with(&other.state.entity_cache)
+                    }) {
+                        // Processing the triggers again changed which
+                        // entities their handlers touch
+                        debug!(
+                            logger,
+                            "Data sources can not be processed in parallel, processing block serially"
+                        );
+                        drop(merged);
+                        drop(group);
+                        block_state.entity_cache.unshare();
+                        return Ok(ParallelOutcome::Serial(block_state));
+                    }
+                    merged.push(group);
+                    Ok(merge_trigger_groups(
+                        block_state,
+                        block.as_ref(),
+                        triggers.as_ref(),
+                        merged,
+                    ))
+                }
+                Err(e) => {
+                    drop(merged);
+                    block_state.entity_cache.unshare();
+                    Ok(ParallelOutcome::Failed(
+                        e,
+                        block_state.entity_cache.discard_changes(),
+                    ))
+                }
+            }))
+        },
+    )
+}
+
+/// Process `triggers`, which are given with their position in the block,
+/// one after the other in the same way as `SubgraphInstance::process_trigger`
+/// does with `hosts`
+fn process_trigger_group<H: RuntimeHost>(
+    logger: Logger,
+    hosts: Arc<Vec<Arc<H>>>,
+    subgraph_metrics: Arc<SubgraphInstanceMetrics>,
+    block: Arc<LightEthereumBlock>,
+    triggers: Vec<(usize, EthereumTrigger)>,
+    state: BlockState,
+) -> impl Future<Item = TriggerGroup, Error = Error> {
+    let group = TriggerGroup {
+        state,
+        outcomes: vec![],
+    };
+    stream::iter_ok(triggers).fold(group, move |group, (position, trigger)| {
+        let TriggerGroup {
+            state,
+            mut outcomes,
+        } = group;
+        let block_ptr = EthereumBlockPointer::from(block.as_ref());
+        let transaction_id = trigger_transaction(&trigger);
+        let trigger_type = TriggerType::of(&trigger);
+        let subgraph_metrics = subgraph_metrics.clone();
+        let executions = state.handler_executions.len();
+        let entity_ops = state.entity_ops;
+        let gas_used = state.gas_used;
+        let start = Instant::now();
+        process_trigger_in_hosts(
+            &logger,
+            hosts.iter().cloned(),
+            block.clone(),
+            trigger,
+            state,
+        )
+        .map(move |state| {
+            let elapsed = start.elapsed().as_secs_f64();
+            subgraph_metrics.observe_trigger_processing_duration(elapsed, trigger_type);
+            outcomes.push(TriggerOutcome {
+                position,
+                handler_executions: state.handler_executions[executions..].to_vec(),
+                entity_ops: state.entity_ops - entity_ops,
+                gas_used: state.gas_used - gas_used,
+            });
+            TriggerGroup { state, outcomes }
+        })
+        .map_err(move |e| trigger_error(e, block_ptr, transaction_id))
+    })
+}
+
+/// Merge the states of `groups`, which do not touch the same entities,
+/// into `block_state`. The handler executions are put in the order of
+/// their triggers in the block, and the handlers fail at the same trigger
+/// as they would have when processing the triggers one after the other if
+/// together they did more work than the budget allows
+fn merge_trigger_groups(
+    mut block_state: BlockState,
+    block: &LightEthereumBlock,
+    triggers: &[EthereumTrigger],
+    groups: Vec<TriggerGroup>,
+) -> ParallelOutcome {
+    let mut outcomes = vec![];
+    for group in groups {
+        block_state.entity_cache.extend(group.state.entity_cache);
+        block_state
+            .created_data_sources
+            .extend(group.state.created_data_sources);
+        block_state
+            .metric_increments
+            .extend(group.state.metric_increments);
+        outcomes.extend(group.outcomes);
+    }
+    block_state.entity_cache.unshare();
+
+    outcomes.sort_by_key(|outcome| outcome.position);
+    for outcome in outcomes {
+        block_state
+            .handler_executions
+            .extend(outcome.handler_executions);
+        block_state.entity_ops += outcome.entity_ops;
+        block_state.gas_used += outcome.gas_used;
+        if let Err(e) = block_state.check_budget() {
+            let e = trigger_error(
+                e,
+                EthereumBlockPointer::from(block),
+                trigger_transaction(&triggers[outcome.position]),
+            );
+            return ParallelOutcome::Failed(e, block_state.entity_cache.discard_changes());
+        }
+    }
+    ParallelOutcome::Processed(block_state)
+}
+
+fn process_triggers_serially<B, T: RuntimeHostBuilder, S>(
+    logger: Logger,
+    block_state: BlockState,
+    ctx: IndexingContext<B, T, S>,
+    block: Arc<LightEthereumBlock>,
+    triggers: Vec<EthereumTrigger>,
 ) -> impl Future<Item = (IndexingContext<B, T, S>, BlockState), Error = CancelableError<Error>>
 where
     B: BlockStreamBuilder,
+    S: Send + Sync + 'static,
 {
     stream::iter_ok::<_, CancelableError<Error>>(triggers)
         // Process events from the block stream
-        .fold((ctx, block_state), move |(ctx, block_state), trigger| {
-            let logger = logger.clone();
-            let block = block.clone();
-            let block_ptr = EthereumBlockPointer::from(block.as_ref());
-            let subgraph_metrics = ctx.subgraph_metrics.clone();
-            let trigger_type = match trigger {
-                EthereumTrigger::Log(_) => TriggerType::Event,
-                EthereumTrigger::Call(_) => TriggerType::Call,
-                EthereumTrigger::Block(..) => TriggerType::Block,
-            };
-            let transaction_id = match &trigger {
-                EthereumTrigger::Log(log) => log.transaction_hash,
-                EthereumTrigger::Call(call) => call.transaction_hash,
-                EthereumTrigger::Block(..) => None,
-            };
-            let start = Instant::now();
-            ctx.state
-                .instance
-                .process_trigger(&logger, block, trigger, block_state)
-                .map(move |block_state| {
-                    let elapsed = start.elapsed().as_secs_f64();
-                    subgraph_metrics.observe_trigger_processing_duration(elapsed, trigger_type);
-                    (ctx, block_state)
-                })
-                .map_err(move |e| match transaction_id {
-                    Some(tx_hash) => format_err!(
-                        "Failed to process trigger in block {}, transaction {:x}: {}",
-                        block_ptr,
-                        tx_hash,
-                        e
-                    ),
-                    None => format_err!("Failed to process trigger: {}", e),
-                })
-        })
+        .fold(
+            (ctx, block_state),
+            move |(ctx, block_state), trigger| -> Box<dyn Future<Item = _, Error = Error> + Send> {
+                // After a non-fatal error, the rest of the block is not processed
+                if block_state.non_fatal_error.is_some() {
+                    return Box::new(future::ok((ctx, block_state)));
+                }
+
+                let logger = logger.clone();
+                let block = block.clone();
+                let block_ptr = EthereumBlockPointer::from(block.as_ref());
+                let subgraph_metrics = ctx.subgraph_metrics.clone();
+                let trigger_type = TriggerType::of(&trigger);
+                let transaction_id = trigger_transaction(&trigger);
+                let start = Instant::now();
+                let processed =
+                    ctx.state
+                        .instance
+                        .process_trigger(&logger, block, trigger, block_state);
+                Box::new(processed.then(move |result| match result {
+                    Ok(block_state) => {
+                        let elapsed = start.elapsed().as_secs_f64();
+                        subgraph_metrics.observe_trigger_processing_duration(elapsed, trigger_type);
+                        Ok((ctx, block_state))
+                    }
+                    Err(e) => {
+                        let e = trigger_error(e, block_ptr, transaction_id);
+                        let cache = HandlerFailure::take_cache(&e).unwrap_or_else(LfuCache::new);
+                        let block_state = handle_trigger_error(&logger, &ctx, e, cache)?;
+                        Ok((ctx, block_state))
+                    }
+                }))
+            },
+        )
+}
+
+/// Errors that would happen again if the block was processed again are
+/// deterministic. Deployments that opted into non-fatal errors discard the
+/// changes of the block and record the error instead of failing. They keep
+/// the entities in `cache` that were read from the store
+fn handle_trigger_error<B, T: RuntimeHostBuilder, S>(
+    logger: &Logger,
+    ctx: &IndexingContext<B, T, S>,
+    e: Error,
+    cache: LfuCache<EntityKey, Option<Entity>>,
+) -> Result<BlockState, Error> {
+    if !ctx.inputs.non_fatal_errors || TransientError::is_cause_of(&e) {
+        return Err(e);
+    }
+    warn!(
+        logger,
+        "Discarding the changes of the block because of a non-fatal error";
+        "error" => e.to_string(),
+    );
+    let mut block_state = BlockState::with_cache(cache);
+    block_state.non_fatal_error = Some(e.to_string());
+    Ok(block_state)
+}
+
+/// The transaction that caused `trigger`, if there is one
+fn trigger_transaction(trigger: &EthereumTrigger) -> Option<H256> {
+    match trigger {
+        EthereumTrigger::Log(log) => log.transaction_hash,
+        EthereumTrigger::Call(call) => call.transaction_hash,
+        EthereumTrigger::Block(..) => None,
+    }
+}
+
+/// Add the block and transaction of the trigger that failed with `e` to the
+/// error. The error keeps `e` as its cause so that transient errors can be
+/// recognized
+fn trigger_error(e: Error, block_ptr: EthereumBlockPointer, transaction_id: Option<H256>) -> Error {
+    let message = match transaction_id {
+        Some(tx_hash) => format!(
+            "Failed to process trigger in block {}, transaction {:x}: {}",
+            block_ptr, tx_hash, e
+        ),
+        None => format!("Failed to process trigger: {}", e),
+    };
+    Error::from(e.context(message))
 }
 
 fn create_dynamic_data_sources<B, T: RuntimeHostBuilder, S>(
@@ -861,6 +2241,7 @@ fn create_dynamic_data_sources<B, T: RuntimeHostBuilder, S>(
     ctx: &mut IndexingContext<B, T, S>,
     host_metrics: Arc<HostMetrics>,
     created_data_sources: impl Iterator<Item = DataSourceTemplateInfo>,
+    block_ptr: &EthereumBlockPointer,
 ) -> Result<(Vec<DataSource>, Vec<Arc<T::Host>>), Error>
 where
     B: BlockStreamBuilder,
@@ -871,7 +2252,8 @@ where
 
     for info in created_data_sources {
         // Try to instantiate a data source from the template
-        let data_source = DataSource::try_from_template(info.template, &info.params)?;
+        let data_source =
+            DataSource::try_from_template(info.template, &info.params, block_ptr.number)?;
         let host_metrics = host_metrics.clone();
 
         // Try to create a runtime host for the data source
@@ -885,6 +2267,9 @@ where
         data_sources.push(data_source);
         runtime_hosts.push(host);
     }
+    ctx.subgraph_metrics
+        .data_source_count
+        .set(ctx.state.instance.hosts().len() as f64);
 
     Ok((data_sources, runtime_hosts))
 }
@@ -920,18 +2305,530 @@ fn persist_dynamic_data_sources<B, T: RuntimeHostBuilder, S>(
         entity_cache.append(operations);
     }
 
-    // Merge log filters from data sources into the block stream builder
+    // Merge the filters of the new data sources into the block stream filter
     ctx.state
-        .log_filter
-        .extend(EthereumLogFilter::from_data_sources(&data_sources));
+        .filter
+        .extend(EthereumTriggerFilter::from_data_sources(&data_sources));
+    ctx.state.new_data_sources.extend(data_sources);
+}
 
-    // Merge call filters from data sources into the block stream builder
-    ctx.state
-        .call_filter
-        .extend(EthereumCallFilter::from_data_sources(&data_sources));
+#[test]
+fn skipped_blocks() {
+    assert!(parse_skipped_blocks("QmDeployment").is_err());
+    assert!(parse_skipped_blocks("QmDeployment:latest").is_err());
+    assert!(parse_skipped_blocks("QmDeployment:1234").is_err());
+
+    let hash1 = "0x0000000000000000000000000000000000000000000000000000000000000001";
+    let hash7 = "0000000000000000000000000000000000000000000000000000000000000007";
+    let hash2 = "0x0000000000000000000000000000000000000000000000000000000000000002";
+    assert!(parse_skipped_blocks(&format!("Qm-Deployment:{}", hash1)).is_err());
+
+    let skipped = parse_skipped_blocks(&format!(
+        "QmDeployment:{}, QmDeployment:{},QmOther:{},",
+        hash1, hash7, hash2
+    ))
+    .unwrap();
+    let deployment = SubgraphDeploymentId::new("QmDeployment").unwrap();
+    assert_eq!(2, skipped.len());
+    assert!(skipped[&deployment].contains(&H256::from_low_u64_be(1)));
+    assert!(skipped[&deployment].contains(&H256::from_low_u64_be(7)));
+    assert!(!skipped[&deployment].contains(&H256::from_low_u64_be(2)));
+}
 
-    // Merge block filters from data sources into the block stream builder
-    ctx.state
-        .block_filter
-        .extend(EthereumBlockFilter::from_data_sources(&data_sources));
+#[test]
+fn drain_waits_for_block() {
+    let id = SubgraphDeploymentId::new("QmDeployment").unwrap();
+    let mut drains = DrainState::default();
+
+    // Draining a deployment that is not processing a block is immediate
+    let (signal, mut drained) = DrainSignal::new();
+    drains.drain(id.clone(), signal);
+    assert_eq!(Ok(Async::Ready(())), drained.poll());
+    assert!(!drains.start_block(&id));
+    drains.stop(&id);
+
+    // Otherwise, draining waits for the block to finish
+    assert!(drains.start_block(&id));
+    let (signal, mut drained) = DrainSignal::new();
+    drains.drain(id.clone(), signal);
+    assert_eq!(Ok(Async::NotReady), drained.poll());
+    drains.finish_block(&id);
+    assert_eq!(Ok(Async::Ready(())), drained.poll());
+    assert!(!drains.start_block(&id));
+}
+
+/// Fails to load dynamic data sources `failures` times and then loads
+/// none, or never finishes loading if `hang` is set
+#[cfg(test)]
+struct FlakyLoader {
+    failures: std::sync::atomic::AtomicUsize,
+    hang: bool,
+}
+
+#[cfg(test)]
+impl DataSourceLoader for FlakyLoader {
+    fn load_dynamic_data_sources(
+        self: Arc<Self>,
+        _: &SubgraphDeploymentId,
+        _: Option<BlockNumber>,
+        _: Logger,
+    ) -> Box<dyn Future<Item = Vec<DataSource>, Error = Error> + Send> {
+        use std::sync::atomic::Ordering;
+
+        if self.hang {
+            return Box::new(future::empty());
+        }
+        let failures = self.failures.load(Ordering::SeqCst);
+        if failures > 0 {
+            self.failures.store(failures - 1, Ordering::SeqCst);
+            Box::new(future::err(format_err!("store unavailable")))
+        } else {
+            Box::new(future::ok(vec![]))
+        }
+    }
+}
+
+#[test]
+fn dynamic_data_sources_are_loaded_until_that_succeeds() {
+    let id = SubgraphDeploymentId::new("QmFastStart").unwrap();
+    let loader = Arc::new(FlakyLoader {
+        failures: 3.into(),
+        hang: false,
+    });
+    let loading = SharedLoadingMap::default();
+    let started = Arc::new(Mutex::new(false));
+
+    let load = {
+        let started = started.clone();
+        load_dynamic_data_sources_and_start(
+            Logger::root(slog::Discard, o!()),
+            loader.clone(),
+            loading.clone(),
+            id.clone(),
+            move |data_sources| {
+                assert!(data_sources.is_empty());
+                *started.lock().unwrap() = true;
+            },
+        )
+    };
+    assert!(loading.lock().unwrap().contains_key(&id));
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(load.compat()).unwrap();
+    assert!(*started.lock().unwrap());
+    assert!(loading.lock().unwrap().is_empty());
+    assert_eq!(0, loader.failures.load(std::sync::atomic::Ordering::SeqCst));
+}
+
+#[test]
+fn deployments_stopped_while_loading_are_not_started() {
+    let id = SubgraphDeploymentId::new("QmFastStart").unwrap();
+    let loader = Arc::new(FlakyLoader {
+        failures: 0.into(),
+        hang: true,
+    });
+    let loading = SharedLoadingMap::default();
+
+    let load = load_dynamic_data_sources_and_start(
+        Logger::root(slog::Discard, o!()),
+        loader,
+        loading.clone(),
+        id.clone(),
+        |_| panic!("a deployment that was stopped must not be started"),
+    );
+
+    // Stopping the deployment drops its guard, which ends loading
+    loading.lock().unwrap().remove(&id);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(load.compat()).unwrap();
+    assert!(loading.lock().unwrap().is_empty());
+}
+
+#[test]
+fn paused_deployments_wait_until_they_are_resumed_or_stopped() {
+    use std::thread;
+
+    let id = SubgraphDeploymentId::new("QmPaused").unwrap();
+    let paused = SharedPausedSet::default();
+    let instances = SharedInstanceKeepAliveMap::default();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // Resuming ends the wait, after which the deployment builds a new block
+    // stream that starts after the last block it processed
+    paused.write().unwrap().insert(id.clone());
+    let wait = wait_until_resumed(paused.clone(), instances.clone(), id.clone());
+    assert!(instances.read().unwrap().contains_key(&id));
+    let resume = {
+        let paused = paused.clone();
+        let id = id.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            paused.write().unwrap().remove(&id);
+        })
+    };
+    assert_eq!(Ok(()), runtime.block_on(wait.compat()));
+    resume.join().unwrap();
+
+    // Stopping the deployment drops its guard, which ends the wait too
+    paused.write().unwrap().insert(id.clone());
+    let wait = wait_until_resumed(paused.clone(), instances.clone(), id.clone());
+    let stop = {
+        let instances = instances.clone();
+        let id = id.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            instances.write().unwrap().remove(&id);
+        })
+    };
+    assert_eq!(Err(()), runtime.block_on(wait.compat()));
+    stop.join().unwrap();
+    assert!(paused.read().unwrap().contains(&id));
+}
+
+#[test]
+fn handler_journal() {
+    let deployment = SubgraphDeploymentId::new("QmJournal").unwrap();
+    let ptr = |number: u64| EthereumBlockPointer::from((H256::from_low_u64_be(number), number));
+    let execution = |number: u64, handler: &str| HandlerExecution {
+        block: ptr(number),
+        data_source: "Token".to_owned(),
+        handler: handler.to_owned(),
+        duration: Duration::from_millis(1),
+        error: None,
+    };
+    let sets = |ops: &[EntityOperation]| {
+        ops.iter()
+            .filter(|op| match op {
+                EntityOperation::Set { .. } => true,
+                EntityOperation::Remove { .. } => false,
+            })
+            .count()
+    };
+    let removed = |ops: &[EntityOperation]| -> Vec<String> {
+        ops.iter()
+            .filter_map(|op| match op {
+                EntityOperation::Remove { key } => Some(key.entity_id.clone()),
+                EntityOperation::Set { .. } => None,
+            })
+            .collect()
+    };
+
+    // A journal of 0 blocks records nothing
+    let mut off = HandlerJournal {
+        blocks: 0,
+        entries: VecDeque::new(),
+    };
+    assert!(off
+        .record(&deployment, &ptr(1), vec![execution(1, "handleTransfer")])
+        .is_empty());
+    assert!(off.entries.is_empty());
+
+    let mut journal = HandlerJournal {
+        blocks: 2,
+        entries: VecDeque::new(),
+    };
+    let ops = journal.record(
+        &deployment,
+        &ptr(1),
+        vec![
+            execution(1, "handleTransfer"),
+            execution(1, "handleApproval"),
+        ],
+    );
+    assert_eq!(2, sets(&ops));
+    assert!(removed(&ops).is_empty());
+    let first: Vec<String> = journal.entries[0].1.clone();
+    assert_eq!(2, first.len());
+
+    // Blocks without handler executions have no entries, but still make
+    // older entries expire
+    assert!(journal.record(&deployment, &ptr(2), vec![]).is_empty());
+    let ops = journal.record(&deployment, &ptr(3), vec![execution(3, "handleTransfer")]);
+    assert_eq!(1, sets(&ops));
+    assert_eq!(first, removed(&ops));
+    assert_eq!(
+        vec![3],
+        journal.entries.iter().map(|(n, _)| *n).collect::<Vec<_>>()
+    );
+
+    journal.record(&deployment, &ptr(4), vec![execution(4, "handleTransfer")]);
+
+    // Reverting block 4 removes its entries from the journal and the store
+    let ops = journal.revert(Some(ptr(3)));
+    assert_eq!(1, ops.len());
+    match &ops[0] {
+        MetadataOperation::Remove { entity, id } => {
+            assert_eq!(SubgraphHandlerExecutionEntity::TYPENAME, entity);
+            assert!(id.contains(&ptr(4).hash_hex()));
+        }
+        op => panic!("unexpected operation {:?}", op),
+    }
+    assert_eq!(
+        vec![3],
+        journal.entries.iter().map(|(n, _)| *n).collect::<Vec<_>>()
+    );
+    assert!(journal.revert(Some(ptr(3))).is_empty());
+
+    // The entries of the block that replaces the reverted one expire as usual
+    journal.record(&deployment, &ptr(4), vec![execution(4, "handleApproval")]);
+    let ops = journal.record(&deployment, &ptr(5), vec![]);
+    assert_eq!(1, removed(&ops).len());
+
+    // Reverting all blocks removes all entries
+    assert_eq!(1, journal.revert(None).len());
+    assert!(journal.entries.is_empty());
+}
+
+#[test]
+fn parallel_trigger_groups() {
+    use graph::prelude::web3::types::{Address, Log, Transaction};
+    use graph_mock::{MockMetricsRegistry, MockStore};
+    use std::fmt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Reads and changes entities through the block state
+    type Handler = fn(&MockStore, &mut BlockState);
+
+    /// A host for the block triggers with calls to `address`
+    struct TestHost {
+        name: &'static str,
+        address: Address,
+        store: Arc<MockStore>,
+        handler: Handler,
+        calls: AtomicUsize,
+    }
+
+    impl fmt::Debug for TestHost {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "TestHost({})", self.name)
+        }
+    }
+
+    impl RuntimeHost for TestHost {
+        fn matches_log(&self, _: &Log) -> bool {
+            false
+        }
+
+        fn matches_call(&self, _: &EthereumCall) -> bool {
+            false
+        }
+
+        fn matches_block(&self, trigger_type: EthereumBlockTriggerType, _: u64) -> bool {
+            trigger_type == EthereumBlockTriggerType::WithCallTo(self.address)
+        }
+
+        fn process_log(
+            &self,
+            _: Logger,
+            _: Arc<LightEthereumBlock>,
+            _: Arc<Transaction>,
+            _: Arc<Log>,
+            _: BlockState,
+        ) -> Box<dyn Future<Item = BlockState, Error = Error> + Send> {
+            unimplemented!()
+        }
+
+        fn process_call(
+            &self,
+            _: Logger,
+            _: Arc<LightEthereumBlock>,
+            _: Arc<Transaction>,
+            _: Arc<EthereumCall>,
+            _: BlockState,
+        ) -> Box<dyn Future<Item = BlockState, Error = Error> + Send> {
+            unimplemented!()
+        }
+
+        fn process_block(
+            &self,
+            _: Logger,
+            block: Arc<LightEthereumBlock>,
+            _: EthereumBlockTriggerType,
+            mut state: BlockState,
+        ) -> Box<dyn Future<Item = BlockState, Error = Error> + Send> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            (self.handler)(&self.store, &mut state);
+            state.handler_executions.push(HandlerExecution {
+                block: EthereumBlockPointer::from(block.as_ref()),
+                data_source: self.name.to_owned(),
+                handler: "handleBlock".to_owned(),
+                duration: Duration::from_secs(0),
+                error: None,
+            });
+            Box::new(future::ok(state))
+        }
+    }
+
+    fn key(id: &str) -> EntityKey {
+        EntityKey {
+            subgraph_id: SubgraphDeploymentId::new("parallel").unwrap(),
+            entity_type: "Thing".to_owned(),
+            entity_id: id.to_owned(),
+        }
+    }
+
+    fn thing(id: &str, count: i32) -> Entity {
+        Entity::from(vec![("id", Value::from(id)), ("count", Value::Int(count))])
+    }
+
+    fn count(store: &MockStore, state: &mut BlockState, id: &str) -> Option<i32> {
+        state
+            .entity_cache
+            .get(store, &key(id))
+            .unwrap()
+            .map(|thing| match thing.get("count") {
+                Some(Value::Int(count)) => *count,
+                _ => panic!("thing without count"),
+            })
+    }
+
+    /// Process the block with a trigger for each of `triggers`, which are
+    /// indexes into `handlers`; the handler of each host is called
+    /// `a`, `b`, ... in the order of `handlers`
+    fn process(
+        handlers: Vec<Handler>,
+        triggers: Vec<usize>,
+    ) -> (Arc<MockStore>, Vec<Arc<TestHost>>, ParallelOutcome) {
+        let mut store = MockStore::new();
+        store.expect_get().returning(|_| Ok(None));
+        let store = Arc::new(store);
+        let names = ["a", "b", "c"];
+        let hosts: Vec<_> = handlers
+            .into_iter()
+            .enumerate()
+            .map(|(i, handler)| {
+                Arc::new(TestHost {
+                    name: names[i],
+                    address: Address::from_low_u64_be(i as u64 + 1),
+                    store: store.clone(),
+                    handler,
+                    calls: AtomicUsize::new(0),
+                })
+            })
+            .collect();
+
+        let block = Arc::new(LightEthereumBlock {
+            hash: Some(H256::from_low_u64_be(1)),
+            number: Some(1.into()),
+            ..Default::default()
+        });
+        let ptr = EthereumBlockPointer::from(block.as_ref());
+        let triggers: Vec<_> = triggers
+            .into_iter()
+            .map(|i| {
+                EthereumTrigger::Block(ptr, EthereumBlockTriggerType::WithCallTo(hosts[i].address))
+            })
+            .collect();
+        let mut groups: Vec<Vec<usize>> = vec![vec![]; hosts.len()];
+        for (position, trigger) in triggers.iter().enumerate() {
+            let host = hosts
+                .iter()
+                .position(|host| match trigger {
+                    EthereumTrigger::Block(ptr, trigger_type) => {
+                        host.matches_block(trigger_type.clone(), ptr.number)
+                    }
+                    _ => false,
+                })
+                .unwrap();
+            groups[host].push(position);
+        }
+
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics = Arc::new(SubgraphInstanceMetrics::new(
+            Arc::new(MockMetricsRegistry::new()),
+            "parallel".to_owned(),
+        ));
+        let outcome = process_trigger_groups(
+            logger,
+            hosts.clone(),
+            metrics,
+            block,
+            Arc::new(triggers),
+            groups,
+            BlockState::with_cache(LfuCache::new()),
+        )
+        .wait()
+        .unwrap();
+        (store, hosts, outcome)
+    }
+
+    fn calls(hosts: &[Arc<TestHost>]) -> Vec<usize> {
+        hosts
+            .iter()
+            .map(|host| host.calls.load(Ordering::SeqCst))
+            .collect()
+    }
+
+    fn set_a(_: &MockStore, state: &mut BlockState) {
+        state.entity_cache.set(key("a"), thing("a", 1));
+    }
+
+    fn set_b(_: &MockStore, state: &mut BlockState) {
+        state.entity_cache.set(key("b"), thing("b", 1));
+    }
+
+    fn increment_a(store: &MockStore, state: &mut BlockState) {
+        let count = count(store, state, "a").unwrap_or(0);
+        state.entity_cache.set(key("a"), thing("a", count + 1));
+    }
+
+    fn copy_a_to_b(store: &MockStore, state: &mut BlockState) {
+        let count = count(store, state, "a").unwrap_or(0);
+        state.entity_cache.set(key("b"), thing("b", count));
+    }
+
+    fn set_b_if_a(store: &MockStore, state: &mut BlockState) {
+        if count(store, state, "a").is_some() {
+            set_b(store, state);
+        }
+    }
+
+    fn set_c(_: &MockStore, state: &mut BlockState) {
+        state.entity_cache.set(key("c"), thing("c", 1));
+    }
+
+    fn copy_b_to_c(store: &MockStore, state: &mut BlockState) {
+        let count = count(store, state, "b").unwrap_or(0);
+        state.entity_cache.set(key("c"), thing("c", count));
+    }
+
+    // Data sources that touch different entities are processed in
+    // parallel, and each handler runs once
+    let (store, hosts, outcome) = process(vec![set_a, set_b], vec![0, 1, 0]);
+    let mut state = match outcome {
+        ParallelOutcome::Processed(state) => state,
+        _ => panic!("independent data sources were not processed in parallel"),
+    };
+    assert_eq!(vec![2, 1], calls(&hosts));
+    assert_eq!(Some(1), count(&store, &mut state, "a"));
+    assert_eq!(Some(1), count(&store, &mut state, "b"));
+    let order: Vec<_> = state
+        .handler_executions
+        .iter()
+        .map(|execution| execution.data_source.as_str())
+        .collect();
+    assert_eq!(vec!["a", "b", "a"], order);
+
+    // When `b` reads what `a` writes, only their triggers are processed
+    // again, one after the other, and `c` is merged as is
+    let (store, hosts, outcome) = process(vec![increment_a, copy_a_to_b, set_c], vec![0, 1, 2]);
+    let mut state = match outcome {
+        ParallelOutcome::Processed(state) => state,
+        _ => panic!("conflicting data sources were not processed again"),
+    };
+    assert_eq!(vec![2, 2, 1], calls(&hosts));
+    assert_eq!(Some(1), count(&store, &mut state, "a"));
+    assert_eq!(Some(1), count(&store, &mut state, "b"));
+    assert_eq!(Some(1), count(&store, &mut state, "c"));
+
+    // If processing the triggers of `a` and `b` again makes `b` write what
+    // `c` read, the block has to be processed serially
+    let (store, hosts, outcome) = process(vec![set_a, set_b_if_a, copy_b_to_c], vec![0, 1, 2]);
+    let mut state = match outcome {
+        ParallelOutcome::Serial(state) => state,
+        _ => panic!("block was not processed serially"),
+    };
+    assert_eq!(vec![2, 2, 1], calls(&hosts));
+    assert_eq!(None, count(&store, &mut state, "a"));
+    assert!(state.handler_executions.is_empty());
 }
@@ -0,0 +1,187 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::time::Duration;
+
+use graph::prelude::*;
+
+lazy_static! {
+    // The timeout for IPFS requests in seconds
+    static ref IPFS_SUBGRAPH_LOADING_TIMEOUT: u64 =
+        env::var("GRAPH_IPFS_SUBGRAPH_LOADING_TIMEOUT")
+            .unwrap_or("60".into())
+            .parse::<u64>()
+            .expect("invalid IPFS subgraph loading timeout");
+
+    // How many attempts to make for each IPFS request; 0 means that
+    // requests are retried until they succeed
+    static ref IPFS_SUBGRAPH_LOADING_ATTEMPTS: usize =
+        env::var("GRAPH_IPFS_SUBGRAPH_LOADING_ATTEMPTS")
+            .unwrap_or("0".into())
+            .parse::<usize>()
+            .expect("invalid IPFS subgraph loading attempts");
+
+    // The longest time to wait between attempts for an IPFS request, in
+    // seconds
+    static ref IPFS_SUBGRAPH_LOADING_MAX_BACKOFF: u64 =
+        env::var("GRAPH_IPFS_SUBGRAPH_LOADING_MAX_BACKOFF")
+            .unwrap_or("30".into())
+            .parse::<u64>()
+            .expect("invalid IPFS subgraph loading max backoff");
+
+    /// The retry policies for loading subgraphs from IPFS, from the
+    /// environment and the file that `GRAPH_IPFS_RETRY_POLICY` points to
+    pub(crate) static ref IPFS_RETRY_POLICIES: IpfsRetryPolicies = {
+        let node = PolicySettings {
+            timeout: Some(*IPFS_SUBGRAPH_LOADING_TIMEOUT),
+            attempts: Some(*IPFS_SUBGRAPH_LOADING_ATTEMPTS),
+            max_backoff: Some(*IPFS_SUBGRAPH_LOADING_MAX_BACKOFF),
+        }
+        .policy(None);
+
+        match env::var_os("GRAPH_IPFS_RETRY_POLICY") {
+            Some(path) => {
+                let file = fs::read_to_string(&path).unwrap_or_else(|e| {
+                    panic!("failed to read IPFS retry policy file {:?}: {}", path, e)
+                });
+                IpfsRetryPolicies::parse(node, &file).unwrap_or_else(|e| {
+                    panic!("invalid IPFS retry policy file {:?}: {}", path, e)
+                })
+            }
+            None => IpfsRetryPolicies {
+                node,
+                deployments: HashMap::new(),
+            },
+        }
+    };
+}
+
+/// Settings for retrying IPFS requests; settings that are not given are
+/// taken from the node's policy
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PolicySettings {
+    /// How long a single attempt may take, in seconds
+    timeout: Option<u64>,
+    /// How many attempts to make; 0 means no limit
+    attempts: Option<usize>,
+    /// The longest time to wait between attempts, in seconds
+    max_backoff: Option<u64>,
+}
+
+impl PolicySettings {
+    fn policy(&self, base: Option<&LinkResolverRetryPolicy>) -> LinkResolverRetryPolicy {
+        let timeout = self
+            .timeout
+            .map(Duration::from_secs)
+            .or(base.map(|base| base.timeout))
+            .unwrap_or_default();
+        let attempts = match self.attempts {
+            Some(0) => None,
+            Some(attempts) => Some(attempts),
+            None => base.and_then(|base| base.attempts),
+        };
+        let max_backoff = self
+            .max_backoff
+            .map(Duration::from_secs)
+            .or(base.map(|base| base.max_backoff))
+            .unwrap_or_default();
+        LinkResolverRetryPolicy {
+            timeout,
+            attempts,
+            max_backoff,
+        }
+    }
+}
+
+/// The format of the IPFS retry policy file. Settings at the top level
+/// apply to all deployments, those under `deployments` only to one
+/// deployment
+#[derive(Debug, Deserialize)]
+struct PolicyFile {
+    #[serde(flatten)]
+    node: PolicySettings,
+    #[serde(default)]
+    deployments: HashMap<SubgraphDeploymentId, PolicySettings>,
+}
+
+/// How this node retries IPFS requests when loading subgraphs
+pub(crate) struct IpfsRetryPolicies {
+    node: LinkResolverRetryPolicy,
+    deployments: HashMap<SubgraphDeploymentId, LinkResolverRetryPolicy>,
+}
+
+impl IpfsRetryPolicies {
+    /// Apply the settings in the policy file `file` on top of `node`
+    fn parse(node: LinkResolverRetryPolicy, file: &str) -> Result<Self, Error> {
+        let file: PolicyFile = serde_yaml::from_str(file)?;
+        let node = file.node.policy(Some(&node));
+        let deployments = file
+            .deployments
+            .into_iter()
+            .map(|(id, settings)| {
+                let policy = settings.policy(Some(&node));
+                (id, policy)
+            })
+            .collect();
+        Ok(IpfsRetryPolicies { node, deployments })
+    }
+
+    /// The policy for requests that are not made for a particular
+    /// deployment
+    pub fn node(&self) -> LinkResolverRetryPolicy {
+        self.node.clone()
+    }
+
+    /// The policy for requests made for `deployment`
+    pub fn for_deployment(&self, deployment: &SubgraphDeploymentId) -> LinkResolverRetryPolicy {
+        self.deployments
+            .get(deployment)
+            .unwrap_or(&self.node)
+            .clone()
+    }
+}
+
+#[test]
+fn ipfs_retry_policies() {
+    let id = |hash: &str| SubgraphDeploymentId::new(hash).unwrap();
+    let node = LinkResolverRetryPolicy {
+        timeout: Duration::from_secs(60),
+        attempts: None,
+        max_backoff: Duration::from_secs(30),
+    };
+
+    let policies = IpfsRetryPolicies::parse(
+        node,
+        "
+attempts: 5
+deployments:
+  QmA:
+    timeout: 600
+    maxBackoff: 120
+  QmB:
+    attempts: 0
+",
+    )
+    .unwrap();
+
+    assert_eq!(
+        LinkResolverRetryPolicy {
+            timeout: Duration::from_secs(60),
+            attempts: Some(5),
+            max_backoff: Duration::from_secs(30),
+        },
+        policies.for_deployment(&id("QmC"))
+    );
+    assert_eq!(
+        LinkResolverRetryPolicy {
+            timeout: Duration::from_secs(600),
+            attempts: Some(5),
+            max_backoff: Duration::from_secs(120),
+        },
+        policies.for_deployment(&id("QmA"))
+    );
+    assert_eq!(None, policies.for_deployment(&id("QmB")).attempts);
+    assert_eq!(policies.node(), policies.for_deployment(&id("QmC")));
+}
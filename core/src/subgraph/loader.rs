@@ -7,6 +7,16 @@ use graph::data::subgraph::UnresolvedDataSource;
 use graph::prelude::{DataSourceLoader as DataSourceLoaderTrait, GraphQlRunner, *};
 use graph_graphql::graphql_parser::{parse_query, query as q};
 
+/// How many dynamic data sources are loaded with one query
+const PAGE_SIZE: i32 = 100;
+
+/// How often loading a page of dynamic data sources is retried before
+/// loading the data sources fails
+const PAGE_RETRIES: usize = 10;
+
+/// Log progress every this many pages of dynamic data sources
+const PAGES_PER_PROGRESS_LOG: i32 = 10;
+
 pub struct DataSourceLoader<L, Q, S> {
     store: Arc<S>,
     link_resolver: Arc<L>,
@@ -30,7 +40,8 @@ where
     fn dynamic_data_sources_query(
         &self,
         deployment: &SubgraphDeploymentId,
-        skip: i32,
+        until: BlockNumber,
+        after: &str,
     ) -> Result<Query, Error> {
         // Obtain the "subgraphs" schema
         let schema = self.store.api_schema(&SUBGRAPHS_ID)?;
@@ -41,9 +52,14 @@ where
             schema,
             document: parse_query(
                 r#"
-                query deployment($id: ID!, $skip: Int!) {
+                query deployment($id: ID!, $until: BigInt!, $first: Int!, $after: ID!) {
                   subgraphDeployment(id: $id) {
-                    dynamicDataSources(orderBy: id, skip: $skip) {
+                    dynamicDataSources(
+                      orderBy: id,
+                      first: $first,
+                      where: { ethereumBlockNumber_lte: $until, id_gt: $after }
+                    ) {
+                      id
                       kind
                       network
                       name
@@ -82,13 +98,7 @@ where
                 "#,
             )
             .expect("invalid query for dynamic data sources"),
-            variables: Some(QueryVariables::new(HashMap::from_iter(
-                vec![
-                    (String::from("id"), q::Value::String(deployment.to_string())),
-                    (String::from("skip"), q::Value::Int(skip.into())),
-                ]
-                .into_iter(),
-            ))),
+            variables: Some(page_variables(deployment, until, after)),
         })
     }
 
@@ -207,6 +217,81 @@ where
                 })
         })
     }
+
+    /// Load and resolve the page of dynamic data sources whose IDs come
+    /// after `after`. Returns the data sources together with the ID of the
+    /// last one, or `None` if this was the last page
+    fn load_page(
+        self: Arc<Self>,
+        deployment_id: SubgraphDeploymentId,
+        until: BlockNumber,
+        after: String,
+        logger: Logger,
+    ) -> impl Future<Item = (Vec<DataSource>, Option<String>), Error = Error> + Send {
+        let self1 = self.clone();
+        let self2 = self.clone();
+        let self3 = self.clone();
+        let deployment_id1 = deployment_id.clone();
+
+        future::result(self.dynamic_data_sources_query(&deployment_id, until, &after))
+            .and_then(move |query| self1.query_dynamic_data_sources(deployment_id1, query))
+            .and_then(move |query_result| {
+                let next = next_page_after(&query_result);
+                self2
+                    .parse_data_sources(deployment_id, query_result)
+                    .map(|typed_entities| (typed_entities, next))
+            })
+            .and_then(move |(typed_entities, next)| {
+                let unresolved_data_sources =
+                    self3.convert_to_unresolved_data_sources(typed_entities);
+                self3
+                    .resolve_data_sources(unresolved_data_sources, logger)
+                    .map(|data_sources| (data_sources, next))
+            })
+    }
+}
+
+/// The variables for the query of the page of dynamic data sources of
+/// `deployment` whose IDs come after `after`
+fn page_variables(
+    deployment: &SubgraphDeploymentId,
+    until: BlockNumber,
+    after: &str,
+) -> QueryVariables {
+    QueryVariables::new(HashMap::from_iter(
+        vec![
+            (String::from("id"), q::Value::String(deployment.to_string())),
+            (String::from("until"), q::Value::String(until.to_string())),
+            (String::from("first"), q::Value::Int(PAGE_SIZE.into())),
+            (String::from("after"), q::Value::String(after.to_owned())),
+        ]
+        .into_iter(),
+    ))
+}
+
+/// The ID of the last dynamic data source in the page `query_result`, or
+/// `None` if the page is not full and therefore the last one
+fn next_page_after(query_result: &q::Value) -> Option<String> {
+    let data_sources = match query_result {
+        q::Value::Object(data) => match data.get("subgraphDeployment") {
+            Some(q::Value::Object(deployment)) => match deployment.get("dynamicDataSources") {
+                Some(q::Value::List(data_sources)) => data_sources,
+                _ => return None,
+            },
+            _ => return None,
+        },
+        _ => return None,
+    };
+    if data_sources.len() < PAGE_SIZE as usize {
+        return None;
+    }
+    match data_sources.last() {
+        Some(q::Value::Object(data_source)) => match data_source.get("id") {
+            Some(q::Value::String(id)) => Some(id.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
 }
 
 impl<L, Q, S> DataSourceLoaderTrait for DataSourceLoader<L, Q, S>
@@ -218,63 +303,78 @@ where
     fn load_dynamic_data_sources(
         self: Arc<Self>,
         deployment_id: &SubgraphDeploymentId,
+        until: Option<BlockNumber>,
         logger: Logger,
     ) -> Box<dyn Future<Item = Vec<DataSource>, Error = Error> + Send> {
+        // Pages are loaded by ID, each starting after the last data source
+        // of the previous page, so that loading a page does not get slower
+        // the more pages have been loaded already
         struct LoopState {
             data_sources: Vec<DataSource>,
-            skip: i32,
+            after: String,
+            pages: i32,
         }
 
         let start_time = Instant::now();
         let initial_state = LoopState {
             data_sources: vec![],
-            skip: 0,
+            after: String::new(),
+            pages: 0,
         };
 
         // Clones for async looping
         let self1 = self.clone();
         let deployment_id = deployment_id.clone();
         let timing_logger = logger.clone();
+        let until = until.unwrap_or(BLOCK_NUMBER_MAX);
 
         Box::new(
             future::loop_fn(initial_state, move |mut state| {
                 let logger = logger.clone();
-
-                let deployment_id1 = deployment_id.clone();
-                let deployment_id2 = deployment_id.clone();
-                let deployment_id3 = deployment_id.clone();
-
+                let progress_logger = logger.clone();
                 let self2 = self1.clone();
-                let self3 = self1.clone();
-                let self4 = self1.clone();
-                let self5 = self1.clone();
-                let self6 = self1.clone();
-
-                future::result(self2.dynamic_data_sources_query(&deployment_id1, state.skip))
-                    .and_then(move |query| self3.query_dynamic_data_sources(deployment_id2, query))
-                    .and_then(move |query_result| {
-                        self4.parse_data_sources(deployment_id3, query_result)
-                    })
-                    .and_then(move |typed_entities| {
-                        future::ok(self5.convert_to_unresolved_data_sources(typed_entities))
-                    })
-                    .and_then(move |unresolved_data_sources| {
-                        self6.resolve_data_sources(unresolved_data_sources, logger)
+                let deployment_id = deployment_id.clone();
+                let after = state.after.clone();
+
+                // Pages are retried individually so that a temporary store
+                // or IPFS problem does not throw away the pages that were
+                // already loaded
+                retry("load dynamic data sources", &logger)
+                    .limit(PAGE_RETRIES)
+                    .no_timeout()
+                    .run(move || {
+                        self2.clone().load_page(
+                            deployment_id.clone(),
+                            until,
+                            after.clone(),
+                            logger.clone(),
+                        )
                     })
-                    .map(move |data_sources| {
-                        if data_sources.is_empty() {
-                            future::Loop::Break(state)
-                        } else {
-                            state.skip += data_sources.len() as i32;
-                            state.data_sources.extend(data_sources);
-                            future::Loop::Continue(state)
+                    .map(move |(data_sources, next)| {
+                        state.pages += 1;
+                        state.data_sources.extend(data_sources);
+                        let after = match next {
+                            Some(after) => after,
+                            None => return future::Loop::Break(state),
+                        };
+
+                        state.after = after;
+                        if state.pages % PAGES_PER_PROGRESS_LOG == 0 {
+                            info!(
+                                progress_logger,
+                                "Loading dynamic data sources";
+                                "loaded" => state.data_sources.len(),
+                                "ms" => start_time.elapsed().as_millis()
+                            );
                         }
+                        future::Loop::Continue(state)
                     })
             })
             .map(move |state| {
                 trace!(
                     timing_logger,
                     "Loaded dynamic data sources";
+                    "count" => state.data_sources.len(),
                     "ms" => start_time.elapsed().as_millis()
                 );
                 state.data_sources
@@ -282,3 +382,46 @@ where
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph_graphql::prelude::object_value;
+
+    fn page(ids: Vec<String>) -> q::Value {
+        let data_sources = ids
+            .into_iter()
+            .map(|id| object_value(vec![("id", q::Value::String(id))]))
+            .collect();
+        object_value(vec![(
+            "subgraphDeployment",
+            object_value(vec![("dynamicDataSources", q::Value::List(data_sources))]),
+        )])
+    }
+
+    #[test]
+    fn pages_continue_after_the_last_id() {
+        let full: Vec<_> = (0..PAGE_SIZE).map(|i| format!("ds-{:03}", i)).collect();
+        let last = full.last().unwrap().clone();
+        assert_eq!(Some(last), next_page_after(&page(full)));
+
+        // A page that is not full is the last one
+        assert_eq!(None, next_page_after(&page(vec!["ds-000".to_owned()])));
+        assert_eq!(None, next_page_after(&page(vec![])));
+    }
+
+    #[test]
+    fn page_variables_select_by_id() {
+        let deployment = SubgraphDeploymentId::new("QmLoader").unwrap();
+        let variables = page_variables(&deployment, 7, "ds-099");
+        assert_eq!(
+            Some(&q::Value::String("ds-099".to_owned())),
+            variables.get("after")
+        );
+        assert_eq!(
+            Some(&q::Value::Int(PAGE_SIZE.into())),
+            variables.get("first")
+        );
+        assert_eq!(None, variables.get("skip"));
+    }
+}
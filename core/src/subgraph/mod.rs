@@ -1,11 +1,27 @@
+mod idle;
+mod indexing_rules;
 mod instance;
 mod instance_manager;
+mod ipfs_retry_policy;
 mod loader;
 mod provider;
+mod rebalance;
 mod registrar;
+mod shutdown;
+mod simulator;
+mod standby;
+mod supervisor;
+mod webhook;
 
+pub use self::idle::IdleDeploymentMonitor;
+pub(crate) use self::idle::{record_query, QUERY_RECORD_INTERVAL};
+pub use self::indexing_rules::IndexingRulesReconciler;
 pub use self::instance::SubgraphInstance;
 pub use self::instance_manager::SubgraphInstanceManager;
 pub use self::loader::DataSourceLoader;
 pub use self::provider::SubgraphAssignmentProvider;
 pub use self::registrar::SubgraphRegistrar;
+pub use self::shutdown::ShutdownCoordinator;
+pub use self::simulator::HandlerSimulator;
+pub use self::standby::StandbyFollower;
+pub use self::webhook::WebhookNotifier;
@@ -1,25 +1,230 @@
+use futures::future::{loop_fn, Loop};
 use futures::sync::mpsc::{channel, Receiver, Sender};
-use std::collections::HashSet;
+use futures::sync::oneshot;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 
-use graph::data::subgraph::schema::attribute_index_definitions;
+use graph::data::subgraph::schema::{
+    attribute_index_definitions, DynamicEthereumContractDataSourceEntity,
+    SubgraphDeploymentIndexFailureEntity, TypedEntity,
+};
 use graph::prelude::{
     DataSourceLoader as _, GraphQlRunner,
     SubgraphAssignmentProvider as SubgraphAssignmentProviderTrait, *,
 };
 
-use crate::subgraph::registrar::IPFS_SUBGRAPH_LOADING_TIMEOUT;
+use crate::subgraph::ipfs_retry_policy::IPFS_RETRY_POLICIES;
+use crate::subgraph::registrar::{deployment_block_reason, deployment_paused, pending_graft};
 use crate::DataSourceLoader;
 
+lazy_static! {
+    /// With the `fast` start profile, starting a deployment that has run
+    /// before does not check that its attribute indexes exist, and its
+    /// dynamic data sources are loaded by the instance manager in the
+    /// background instead of before the deployment is handed to it. This
+    /// gets hundreds of deployments going much sooner after a restart
+    pub(crate) static ref FAST_START: bool =
+        match std::env::var("GRAPH_START_PROFILE").as_ref().map(String::as_str) {
+            Ok("fast") => true,
+            Ok("full") | Err(_) => false,
+            Ok(profile) => panic!("invalid GRAPH_START_PROFILE `{}`", profile),
+        };
+
+    /// How long to wait for the instance manager to make room for an event
+    /// when the event queue is full, in seconds
+    static ref EVENT_SEND_TIMEOUT: Duration = Duration::from_secs(
+        std::env::var("GRAPH_PROVIDER_EVENT_SEND_TIMEOUT")
+            .unwrap_or("120".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_PROVIDER_EVENT_SEND_TIMEOUT")
+    );
+
+    /// How long to wait for a deployment that is being stopped to finish
+    /// the block it is processing, in seconds. The deployment is stopped
+    /// anyway when it takes longer than that
+    static ref DRAIN_TIMEOUT: Duration = Duration::from_secs(
+        std::env::var("GRAPH_SUBGRAPH_DRAIN_TIMEOUT")
+            .unwrap_or("120".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_SUBGRAPH_DRAIN_TIMEOUT")
+    );
+
+    /// How many tables of the graft base of a deployment are copied at the
+    /// same time
+    static ref GRAFT_COPY_CONCURRENCY: usize = std::env::var("GRAPH_GRAFT_COPY_CONCURRENCY")
+        .unwrap_or("4".into())
+        .parse::<usize>()
+        .expect("invalid GRAPH_GRAFT_COPY_CONCURRENCY");
+
+    /// How many entity versions of the graft base of a deployment are
+    /// copied in one transaction. Copying resumes after the last batch that
+    /// was copied when it is interrupted
+    static ref GRAFT_COPY_BATCH_SIZE: i64 = std::env::var("GRAPH_GRAFT_COPY_BATCH_SIZE")
+        .unwrap_or("10000".into())
+        .parse::<i64>()
+        .expect("invalid GRAPH_GRAFT_COPY_BATCH_SIZE");
+
+    /// How many entities are copied in one transaction when a deployment
+    /// is migrated from JSONB storage to relational storage
+    static ref STORAGE_MIGRATION_BATCH_SIZE: i64 =
+        std::env::var("GRAPH_STORAGE_MIGRATION_BATCH_SIZE")
+            .unwrap_or("1000".into())
+            .parse::<i64>()
+            .expect("invalid GRAPH_STORAGE_MIGRATION_BATCH_SIZE");
+
+    /// How long to wait between two batches of a migration, in
+    /// milliseconds, so that migrating does not starve queries
+    static ref STORAGE_MIGRATION_BATCH_DELAY: Duration = Duration::from_millis(
+        std::env::var("GRAPH_STORAGE_MIGRATION_BATCH_DELAY")
+            .unwrap_or("100".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_STORAGE_MIGRATION_BATCH_DELAY")
+    );
+}
+
+/// How many events can wait for the instance manager before sending more
+/// events waits for it to catch up
+const EVENT_QUEUE_CAPACITY: usize = 100;
+
+/// How often to check whether a full event queue has room again
+const EVENT_QUEUE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+type ProviderFuture = Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send>;
+
+/// What the provider is doing with a deployment. Deployments the provider
+/// has no state for are not running on this node. Starting or stopping a
+/// deployment that is still being stopped, rewound or migrated waits for
+/// that to finish first
+enum DeploymentState {
+    /// The deployment is being prepared before it is handed to the
+    /// instance manager. Dropping the guard cancels starting it; the
+    /// receiver learns whether the deployment was handed over anyway
+    Starting(CancelGuard, oneshot::Receiver<bool>),
+    /// The deployment has been handed to the instance manager
+    Running,
+    /// The deployment is being stopped; the waiters are answered once
+    /// that is over
+    Stopping(Vec<Waiter>),
+    /// The deployment is being rewound or its storage is being migrated
+    /// while it is not running; the waiters are answered once that is over
+    Maintaining(Vec<Waiter>),
+}
+
+/// A request for a deployment that waits for the deployment to be stopped,
+/// rewound or migrated. Waiters are answered in the order in which they
+/// were made, so that the last start or stop decides whether the
+/// deployment runs afterwards
+enum Waiter {
+    /// A start, which is handed the future that starts the deployment
+    /// unless a stop or another start was requested after it
+    Start(oneshot::Sender<ProviderFuture>),
+    /// A stop, which learns whether the deployment is not running anymore
+    Stop(oneshot::Sender<bool>),
+    /// A rewind or migration, which is tried again
+    Maintain(oneshot::Sender<()>),
+}
+
+/// Work on a deployment that can only be done while it is not running
+#[derive(Clone, Copy, Debug)]
+enum Maintenance {
+    /// Revert the entities of the deployment to the block
+    Rewind(EthereumBlockPointer),
+    /// Migrate the deployment from JSONB storage to relational storage
+    MigrateStorage,
+}
+
+impl Maintenance {
+    fn error(&self, e: Error) -> SubgraphAssignmentProviderError {
+        match self {
+            Maintenance::Rewind(_) => SubgraphAssignmentProviderError::RewindError(e),
+            Maintenance::MigrateStorage => {
+                SubgraphAssignmentProviderError::StorageMigrationError(e)
+            }
+        }
+    }
+}
+
+struct SubgraphAssignmentProviderMetrics {
+    start_phase_duration: Box<HistogramVec>,
+    event_queue_full: Box<Counter>,
+    events_dropped: Box<Counter>,
+}
+
+impl SubgraphAssignmentProviderMetrics {
+    pub fn new(registry: Arc<impl MetricsRegistry>) -> Self {
+        let start_phase_duration = registry
+            .new_histogram_vec(
+                String::from("subgraph_start_phase_duration"),
+                String::from(
+                    "Measures the duration of each phase of starting a subgraph deployment",
+                ),
+                HashMap::new(),
+                vec![String::from("deployment"), String::from("phase")],
+                vec![0.05, 0.2, 0.7, 1.5, 4.0, 10.0, 60.0, 120.0, 240.0],
+            )
+            .expect("failed to create `subgraph_start_phase_duration` histogram");
+        let event_queue_full = registry
+            .new_counter(
+                String::from("subgraph_provider_event_queue_full"),
+                String::from(
+                    "Counts events that had to wait because the instance manager was not keeping up",
+                ),
+                HashMap::new(),
+            )
+            .expect("failed to create `subgraph_provider_event_queue_full` counter");
+        let events_dropped = registry
+            .new_counter(
+                String::from("subgraph_provider_events_dropped"),
+                String::from("Counts events that could not be forwarded to the instance manager"),
+                HashMap::new(),
+            )
+            .expect("failed to create `subgraph_provider_events_dropped` counter");
+        Self {
+            start_phase_duration,
+            event_queue_full,
+            events_dropped,
+        }
+    }
+
+    /// Record how long `phase` of starting `deployment` took, given the
+    /// time at which the phase started
+    fn observe_phase(
+        &self,
+        logger: &Logger,
+        deployment: &SubgraphDeploymentId,
+        phase: &str,
+        started: Instant,
+    ) {
+        let elapsed = started.elapsed();
+        self.start_phase_duration
+            .with_label_values(&[deployment.as_str(), phase])
+            .observe(elapsed.as_secs_f64());
+        trace!(
+            logger,
+            "Finished subgraph start phase";
+            "phase" => phase,
+            "ms" => elapsed.as_millis()
+        );
+    }
+}
+
 pub struct SubgraphAssignmentProvider<L, Q, S> {
     logger: Logger,
     logger_factory: LoggerFactory,
     event_stream: Option<Receiver<SubgraphAssignmentProviderEvent>>,
     event_sink: Sender<SubgraphAssignmentProviderEvent>,
+    event_queue: Arc<EventQueue>,
     resolver: Arc<L>,
-    subgraphs_running: Arc<Mutex<HashSet<SubgraphDeploymentId>>>,
+    deployments: Arc<Mutex<HashMap<SubgraphDeploymentId, DeploymentState>>>,
+    subgraphs_paused: Arc<Mutex<HashSet<SubgraphDeploymentId>>>,
     store: Arc<S>,
     graphql_runner: Arc<Q>,
+    metrics: Arc<SubgraphAssignmentProviderMetrics>,
+    startup: Arc<StartupStatus>,
 }
 
 impl<L, Q, S> SubgraphAssignmentProvider<L, Q, S>
@@ -33,8 +238,9 @@ where
         resolver: Arc<L>,
         store: Arc<S>,
         graphql_runner: Arc<Q>,
+        metrics_registry: Arc<impl MetricsRegistry>,
     ) -> Self {
-        let (event_sink, event_stream) = channel(100);
+        let (event_sink, event_stream) = channel(EVENT_QUEUE_CAPACITY);
 
         let logger = logger_factory.component_logger("SubgraphAssignmentProvider", None);
         let logger_factory = logger_factory.with_parent(logger.clone());
@@ -45,31 +251,220 @@ where
             logger_factory,
             event_stream: Some(event_stream),
             event_sink,
+            event_queue: Arc::new(EventQueue::new(EVENT_QUEUE_CAPACITY)),
             resolver: Arc::new(
                 resolver
                     .as_ref()
                     .clone()
-                    .with_timeout(*IPFS_SUBGRAPH_LOADING_TIMEOUT)
-                    .with_retries(),
+                    .with_retry_policy(IPFS_RETRY_POLICIES.node())
+                    .with_priority(LinkResolverPriority::Low),
             ),
-            subgraphs_running: Arc::new(Mutex::new(HashSet::new())),
+            deployments: Arc::new(Mutex::new(HashMap::new())),
+            subgraphs_paused: Arc::new(Mutex::new(HashSet::new())),
             store,
             graphql_runner,
+            metrics: Arc::new(SubgraphAssignmentProviderMetrics::new(metrics_registry)),
+            startup: Arc::new(StartupStatus::new()),
         }
     }
 
+    /// Record how far starting each deployment has come in `startup`
+    pub fn with_startup(mut self, startup: Arc<StartupStatus>) -> Self {
+        self.startup = startup;
+        self
+    }
+
     /// Clones but forcing receivers to `None`.
     fn clone(&self) -> Self {
         SubgraphAssignmentProvider {
             logger: self.logger.clone(),
             event_stream: None,
             event_sink: self.event_sink.clone(),
+            event_queue: self.event_queue.clone(),
             resolver: self.resolver.clone(),
-            subgraphs_running: self.subgraphs_running.clone(),
+            deployments: self.deployments.clone(),
+            subgraphs_paused: self.subgraphs_paused.clone(),
             store: self.store.clone(),
             graphql_runner: self.graphql_runner.clone(),
             logger_factory: self.logger_factory.clone(),
+            metrics: self.metrics.clone(),
+            startup: self.startup.clone(),
+        }
+    }
+
+    /// Forward `event` for deployment `id` to the instance manager. While
+    /// the instance manager has `EVENT_QUEUE_CAPACITY` events it has not
+    /// taken yet, wait for it to catch up, and give up after
+    /// `GRAPH_PROVIDER_EVENT_SEND_TIMEOUT`
+    fn send_event(
+        &self,
+        id: SubgraphDeploymentId,
+        event: SubgraphAssignmentProviderEvent,
+    ) -> impl Future<Item = (), Error = SubgraphAssignmentProviderError> {
+        self.forward_event(id, event, None)
+    }
+
+    /// Forward `event` like `send_event`, but keep waiting for the instance
+    /// manager to catch up until `handle` is canceled. Deployments that are
+    /// assigned to this node must not be left unstarted because the
+    /// instance manager was busy for a while
+    fn send_start_event(
+        &self,
+        id: SubgraphDeploymentId,
+        event: SubgraphAssignmentProviderEvent,
+        handle: CancelHandle,
+    ) -> impl Future<Item = (), Error = SubgraphAssignmentProviderError> {
+        self.forward_event(id, event, Some(handle))
+    }
+
+    /// Hand the deployment `subgraph` to the instance manager. A deployment
+    /// that was paused is paused before it starts, so that it does not
+    /// process any blocks until it is resumed
+    fn send_start_events(
+        &self,
+        subgraph: SubgraphManifest,
+        paused: bool,
+        handle: CancelHandle,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send> {
+        let id = subgraph.id.clone();
+        if !paused {
+            return Box::new(self.send_start_event(
+                id,
+                SubgraphAssignmentProviderEvent::SubgraphStart(subgraph),
+                handle,
+            ));
+        }
+
+        self.subgraphs_paused.lock().unwrap().insert(id.clone());
+        let self_for_start = self.clone();
+        let handle_for_start = handle.clone();
+        Box::new(
+            self.send_start_event(
+                id.clone(),
+                SubgraphAssignmentProviderEvent::SubgraphPause(id.clone()),
+                handle,
+            )
+            .and_then(move |()| {
+                self_for_start.send_start_event(
+                    id,
+                    SubgraphAssignmentProviderEvent::SubgraphStart(subgraph),
+                    handle_for_start,
+                )
+            }),
+        )
+    }
+
+    fn forward_event(
+        &self,
+        id: SubgraphDeploymentId,
+        event: SubgraphAssignmentProviderEvent,
+        handle: Option<CancelHandle>,
+    ) -> impl Future<Item = (), Error = SubgraphAssignmentProviderError> {
+        let queue = self.event_queue.clone();
+        let sink = self.event_sink.clone();
+        let metrics = self.metrics.clone();
+
+        if queue.is_full() {
+            metrics.event_queue_full.inc();
+            warn!(
+                self.logger,
+                "Instance manager is not keeping up, waiting to forward subgraph event";
+                "subgraph_id" => id.to_string(),
+                "queued" => queue.len()
+            );
+        }
+
+        // Reserve a place in the queue before sending the event so that
+        // concurrent senders can not take the same place
+        let waiting_since = Instant::now();
+        let queue_for_room = queue.clone();
+        let id_for_room = id.clone();
+        let metrics_for_room = metrics.clone();
+        let room = loop_fn(
+            (),
+            move |()| -> Box<dyn Future<Item = _, Error = _> + Send> {
+                if queue_for_room.try_reserve() {
+                    return Box::new(future::ok(Loop::Break(())));
+                }
+                match &handle {
+                    None if waiting_since.elapsed() >= *EVENT_SEND_TIMEOUT => {
+                        metrics_for_room.events_dropped.inc();
+                        return Box::new(future::err(
+                            SubgraphAssignmentProviderError::EventQueueFull(id_for_room.clone()),
+                        ));
+                    }
+                    Some(handle) if handle.is_canceled() => {
+                        return Box::new(future::err(SubgraphAssignmentProviderError::Canceled(
+                            id_for_room.clone(),
+                        )));
+                    }
+                    _ => (),
+                }
+                Box::new(
+                    tokio::time::delay_for(EVENT_QUEUE_CHECK_INTERVAL)
+                        .unit_error()
+                        .compat()
+                        .map(|()| Loop::Continue(()))
+                        .map_err(|()| unreachable!()),
+                )
+            },
+        );
+
+        room.and_then(move |()| {
+            sink.send(event).map(|_| ()).map_err(move |_| {
+                queue.release();
+                metrics.events_dropped.inc();
+                SubgraphAssignmentProviderError::EventQueueClosed(id)
+            })
+        })
+    }
+}
+
+/// Counts the events the instance manager has not taken yet. The channel
+/// itself does not hold back senders since every clone of the sender may
+/// add an event, no matter how full the channel is
+struct EventQueue {
+    capacity: usize,
+    queued: AtomicUsize,
+}
+
+impl EventQueue {
+    fn new(capacity: usize) -> Self {
+        EventQueue {
+            capacity,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    /// Take a place in the queue if there is one
+    fn try_reserve(&self) -> bool {
+        let mut queued = self.queued.load(Ordering::SeqCst);
+        while queued < self.capacity {
+            match self.queued.compare_exchange(
+                queued,
+                queued + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(current) => queued = current,
+            }
         }
+        false
+    }
+
+    /// Give up a place in the queue, either because the event in it was
+    /// taken or because it could not be sent
+    fn release(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
@@ -83,14 +478,106 @@ where
         &self,
         id: SubgraphDeploymentId,
     ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static> {
+        // Deployments that are stopped while the node shuts down, and
+        // those that get assigned in the meantime, stay stopped
+        if self.startup.is_shutting_down() {
+            return Box::new(future::err(SubgraphAssignmentProviderError::ShuttingDown(
+                id,
+            )));
+        }
+
+        // Refuse to start deployments on the blocklist
+        match deployment_block_reason(&*self.store, &id) {
+            Ok(None) => (),
+            Ok(Some(reason)) => {
+                warn!(
+                    self.logger_factory.subgraph_logger(&id),
+                    "Refusing to start blocked subgraph deployment";
+                    "reason" => &reason
+                );
+                self.startup
+                    .set_phase(&id, StartupPhase::Skipped(reason.clone()));
+                return Box::new(future::err(SubgraphAssignmentProviderError::Blocked(
+                    id, reason,
+                )));
+            }
+            Err(e) => {
+                return Box::new(future::err(SubgraphAssignmentProviderError::Unknown(
+                    e.into(),
+                )))
+            }
+        }
+
+        // Deployments that were paused stay paused until they are resumed
+        let paused = match deployment_paused(&*self.store, &id) {
+            Ok(paused) => paused,
+            Err(e) => {
+                return Box::new(future::err(SubgraphAssignmentProviderError::Unknown(
+                    e.into(),
+                )))
+            }
+        };
+
+        // Starting a deployment that is being started or running already
+        // does nothing, and one that is being stopped, rewound or migrated
+        // is started once that is over
+        let (handle, started) = {
+            let mut deployments = self.deployments.lock().unwrap();
+            match deployments.get_mut(&id) {
+                Some(DeploymentState::Starting(..)) | Some(DeploymentState::Running) => {
+                    return Box::new(future::err(
+                        SubgraphAssignmentProviderError::AlreadyRunning(id),
+                    ));
+                }
+                Some(DeploymentState::Stopping(waiters))
+                | Some(DeploymentState::Maintaining(waiters)) => {
+                    let (sender, start) = oneshot::channel();
+                    waiters.push(Waiter::Start(sender));
+                    return Box::new(start.then(move |start| -> ProviderFuture {
+                        match start {
+                            Ok(start) => start,
+                            Err(_) => {
+                                Box::new(future::err(SubgraphAssignmentProviderError::Canceled(id)))
+                            }
+                        }
+                    }));
+                }
+                None => {
+                    let guard = CancelGuard::new();
+                    let handle = guard.handle();
+                    let (started, receiver) = oneshot::channel();
+                    deployments.insert(id.clone(), DeploymentState::Starting(guard, receiver));
+                    (handle, started)
+                }
+            }
+        };
+
         let self_clone = self.clone();
         let store = self.store.clone();
         let subgraph_id = id.clone();
+        let subgraph_id_for_cancel = id.clone();
+        let subgraph_id_for_state = id.clone();
+        let handle_for_send = handle.clone();
+        let deployments = self.deployments.clone();
         let subgraph_id_for_data_sources = id.clone();
+        let resolver = Arc::new(
+            self.resolver
+                .as_ref()
+                .clone()
+                .for_deployment(id.clone())
+                .with_retry_policy(IPFS_RETRY_POLICIES.for_deployment(&id)),
+        );
+        let metrics = self.metrics.clone();
+        let metrics_for_data_sources = self.metrics.clone();
+        let startup = self.startup.clone();
+        let startup_for_data_sources = self.startup.clone();
+        let startup_for_err = self.startup.clone();
+
+        startup.set_phase(&id, StartupPhase::ResolvingManifest);
 
         let loader = Arc::new(DataSourceLoader::new(
             store.clone(),
-            self.resolver.clone(),
+            resolver.clone(),
             self.graphql_runner.clone(),
         ));
 
@@ -103,81 +590,182 @@ where
 
         info!(logger, "Resolve subgraph files using IPFS");
 
+        let logger_for_graft = logger.clone();
+        let store_for_graft = store.clone();
+        let loader_for_graft = loader.clone();
+        let metrics_for_graft = self.metrics.clone();
+
+        let resolve_started = Instant::now();
         Box::new(
-            SubgraphManifest::resolve(Link { link }, self.resolver.clone(), logger_for_resolve)
+            SubgraphManifest::resolve(Link { link }, resolver, logger_for_resolve)
                 .map_err(SubgraphAssignmentProviderError::ResolveError)
                 .and_then(move |manifest| {
-                    (
-                        future::ok(manifest),
-                        loader
-                            .load_dynamic_data_sources(
-                                &subgraph_id_for_data_sources,
-                                logger_for_data_sources,
-                            )
-                            .map_err(SubgraphAssignmentProviderError::DynamicDataSourcesError),
+                    metrics.observe_phase(
+                        &logger_for_graft,
+                        &manifest.id,
+                        "resolve",
+                        resolve_started,
+                    );
+
+                    // A grafted deployment needs the data of its base
+                    // before it can load its dynamic data sources
+                    copy_graft_base(
+                        logger_for_graft,
+                        store_for_graft,
+                        loader_for_graft,
+                        metrics_for_graft,
+                        manifest,
                     )
                 })
-                .and_then(
-                    move |(mut subgraph, data_sources)| -> Box<dyn Future<Item = _, Error = _> + Send> {
+                .and_then(move |manifest| {
+                    if *FAST_START {
+                        return future::Either::A(future::ok((manifest, vec![])));
+                    }
+
+                    startup_for_data_sources.update_phase(
+                        &subgraph_id_for_data_sources,
+                        StartupPhase::LoadingDynamicDataSources,
+                    );
+                    let load_started = Instant::now();
+                    future::Either::B(
+                        (
+                            future::ok(manifest),
+                            loader
+                                .load_dynamic_data_sources(
+                                    &subgraph_id_for_data_sources,
+                                    None,
+                                    logger_for_data_sources.clone(),
+                                )
+                                .map_err(SubgraphAssignmentProviderError::DynamicDataSourcesError)
+                                .map(move |data_sources| {
+                                    metrics_for_data_sources.observe_phase(
+                                        &logger_for_data_sources,
+                                        &subgraph_id_for_data_sources,
+                                        "load_dynamic_data_sources",
+                                        load_started,
+                                    );
+                                    data_sources
+                                }),
+                        )
+                            .into_future(),
+                    )
+                })
+                .map({
+                    let self_clone = self_clone.clone();
+                    let logger = logger.clone();
+                    move |(mut subgraph, data_sources)| {
                         info!(logger, "Successfully resolved subgraph files using IPFS");
 
                         // Add dynamic data sources to the subgraph
                         subgraph.data_sources.extend(data_sources);
 
-                        // If subgraph ID already in set
-                        if !self_clone
-                            .subgraphs_running
-                            .lock()
-                            .unwrap()
-                            .insert(subgraph.id.clone())
-                        {
-                            info!(logger, "Subgraph deployment is already running");
-
-                            return Box::new(future::err(
-                                SubgraphAssignmentProviderError::AlreadyRunning(subgraph.id),
-                            ));
-                        }
-
-                        info!(logger, "Create attribute indexes for subgraph entities");
-
-                        // Build indexes for each entity attribute in the Subgraph
-                        let index_definitions = attribute_index_definitions(
-                            subgraph.id.clone(),
-                            subgraph.schema.document.clone(),
-                        );
-                        self_clone
+                        // Deployments that have processed blocks before have
+                        // had their indexes created when they first started
+                        let started_before = self_clone
                             .store
-                            .clone()
-                            .build_entity_attribute_indexes(&subgraph.id, index_definitions)
-                            .map(|_| {
-                                info!(
-                                    logger,
-                                    "Successfully created attribute indexes for subgraph entities"
-                                )
-                            })
+                            .block_ptr(subgraph.id.clone())
+                            .map(|ptr| ptr.is_some())
+                            .unwrap_or(false);
+                        if *FAST_START && started_before {
+                            info!(logger, "Skip checking attribute indexes for fast start");
+                        } else {
+                            info!(logger, "Create attribute indexes for subgraph entities");
+
+                            // Build indexes for each entity attribute in the Subgraph;
+                            // the deployment runs without them if that fails
+                            let index_started = Instant::now();
+                            create_attribute_indexes(
+                                &logger,
+                                &*self_clone.store,
+                                &subgraph.id,
+                                &subgraph.schema,
+                            )
                             .ok();
+                            self_clone.metrics.observe_phase(
+                                &logger,
+                                &subgraph.id,
+                                "create_indexes",
+                                index_started,
+                            );
+                        }
 
-                        // Send events to trigger subgraph processing
-                        Box::new(
-                            self_clone
-                                .event_sink
-                                .clone()
-                                .send(SubgraphAssignmentProviderEvent::SubgraphStart(subgraph))
-                                .map_err(|e| panic!("failed to forward subgraph: {}", e))
-                                .map(|_| ()),
-                        )
-                    },
-                )
+                        subgraph
+                    }
+                })
+                // Once the deployment is being handed to the instance
+                // manager, stopping it waits for that to finish
+                .cancelable(&handle, move || {
+                    SubgraphAssignmentProviderError::Canceled(subgraph_id_for_cancel.clone())
+                })
+                .and_then(move |subgraph| {
+                    // Send events to trigger subgraph processing. While the
+                    // instance manager is busy, this waits until the
+                    // deployment is stopped rather than dropping it
+                    let send_started = Instant::now();
+                    let subgraph_id = subgraph.id.clone();
+                    startup.update_phase(&subgraph_id, StartupPhase::StartingBlockStream);
+                    let metrics = self_clone.metrics.clone();
+                    self_clone
+                        .send_start_events(subgraph, paused, handle_for_send)
+                        .map(move |_| {
+                            metrics.observe_phase(
+                                &logger,
+                                &subgraph_id,
+                                "send_start_event",
+                                send_started,
+                            )
+                        })
+                })
+                .then(move |result| {
+                    // A stop that canceled starting the deployment needs to
+                    // know whether the deployment was handed over anyway;
+                    // otherwise, the deployment is now running, or can be
+                    // started again if starting it failed
+                    let mut deployments = deployments.lock().unwrap();
+                    if handle.is_canceled() {
+                        let _ = started.send(result.is_ok());
+                    } else if result.is_ok() {
+                        deployments.insert(subgraph_id_for_state, DeploymentState::Running);
+                    } else {
+                        deployments.remove(&subgraph_id_for_state);
+                    }
+                    result
+                })
                 .map_err(move |e| {
-                    error!(
-                        logger_for_err,
-                        "Failed to resolve subgraph files using IPFS";
-                        "error" => format!("{}", e)
-                    );
+                    match e {
+                        SubgraphAssignmentProviderError::Canceled(_) => (),
+                        _ => startup_for_err
+                            .update_phase(&subgraph_id, StartupPhase::Failed(e.to_string())),
+                    }
+                    match e {
+                        SubgraphAssignmentProviderError::Canceled(_) => {
+                            info!(logger_for_err, "Stopped starting subgraph deployment");
+                        }
+                        // The deployment itself is fine; it just could not
+                        // be handed to the instance manager
+                        SubgraphAssignmentProviderError::EventQueueFull(_)
+                        | SubgraphAssignmentProviderError::EventQueueClosed(_) => {
+                            error!(
+                                logger_for_err,
+                                "Failed to forward subgraph to the instance manager";
+                                "error" => format!("{}", e)
+                            );
+                        }
+                        _ => {
+                            error!(
+                                logger_for_err,
+                                "Failed to resolve subgraph files using IPFS";
+                                "error" => format!("{}", e)
+                            );
 
-                    let _ = store.apply_metadata_operations(
-                        SubgraphDeploymentEntity::update_failed_operations(&subgraph_id, true),
-                    );
+                            let _ = store.apply_metadata_operations(
+                                SubgraphDeploymentEntity::update_failed_operations(
+                                    &subgraph_id,
+                                    true,
+                                ),
+                            );
+                        }
+                    }
                     e
                 }),
         )
@@ -187,18 +775,362 @@ where
         &self,
         id: SubgraphDeploymentId,
     ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static> {
-        // If subgraph ID was in set
-        if self.subgraphs_running.lock().unwrap().remove(&id) {
-            // Shut down subgraph processing
-            Box::new(
-                self.event_sink
-                    .clone()
-                    .send(SubgraphAssignmentProviderEvent::SubgraphStop(id))
-                    .map_err(|e| panic!("failed to forward subgraph shut down event: {}", e))
-                    .map(|_| ()),
+        // Stopping a deployment that is being started cancels starting it,
+        // and stopping one that is being stopped, rewound or migrated waits
+        // for that to finish
+        let handed_over: Box<
+            dyn Future<Item = bool, Error = SubgraphAssignmentProviderError> + Send,
+        > = {
+            let mut deployments = self.deployments.lock().unwrap();
+            match deployments.get_mut(&id) {
+                Some(DeploymentState::Stopping(waiters))
+                | Some(DeploymentState::Maintaining(waiters)) => {
+                    let (sender, stopped) = oneshot::channel();
+                    waiters.push(Waiter::Stop(sender));
+                    let self_clone = self.clone();
+                    return Box::new(stopped.then(move |stopped| -> ProviderFuture {
+                        match stopped {
+                            Ok(true) => Box::new(future::ok(())),
+                            // Stopping the deployment failed, so it is
+                            // still running
+                            _ => Box::new(self_clone.stop(id).then(|result| match result {
+                                Err(SubgraphAssignmentProviderError::NotRunning(_)) => Ok(()),
+                                result => result,
+                            })),
+                        }
+                    }));
+                }
+                _ => (),
+            }
+            match deployments.remove(&id) {
+                None
+                | Some(DeploymentState::Stopping(_))
+                | Some(DeploymentState::Maintaining(_)) => {
+                    return Box::new(future::err(SubgraphAssignmentProviderError::NotRunning(id)))
+                }
+                Some(DeploymentState::Starting(guard, started)) => {
+                    // If starting the deployment was abandoned, it may have
+                    // been handed over already; stopping a deployment the
+                    // instance manager does not know is harmless
+                    drop(guard);
+                    deployments.insert(id.clone(), DeploymentState::Stopping(vec![]));
+                    Box::new(started.then(|started| Ok(started.unwrap_or(true))))
+                }
+                Some(DeploymentState::Running) => {
+                    deployments.insert(id.clone(), DeploymentState::Stopping(vec![]));
+                    Box::new(future::ok(true))
+                }
+            }
+        };
+        self.subgraphs_paused.lock().unwrap().remove(&id);
+        self.startup.remove(&id);
+
+        // Let the deployment finish writing the block it is processing
+        // before shutting down subgraph processing, so that stopping it,
+        // e.g. because it moved to another node, does not cut a block
+        // short; if an event is not forwarded, the deployment is still
+        // running
+        let logger = self.logger_factory.subgraph_logger(&id);
+        let self_clone = self.clone();
+        let self_for_waiters = self.clone();
+        let deployments = self.deployments.clone();
+        let id_for_stop = id.clone();
+        Box::new(
+            handed_over
+                .and_then(move |handed_over| -> Box<dyn Future<Item = _, Error = _> + Send> {
+                    if !handed_over {
+                        return Box::new(future::ok(()));
+                    }
+
+                    let (drain_signal, drained) = DrainSignal::new();
+                    let self_for_stop = self_clone.clone();
+                    Box::new(
+                        self_clone
+                            .send_event(
+                                id_for_stop.clone(),
+                                SubgraphAssignmentProviderEvent::SubgraphDrain(
+                                    id_for_stop.clone(),
+                                    drain_signal,
+                                ),
+                            )
+                            .and_then(move |()| {
+                                drained.timeout(*DRAIN_TIMEOUT).compat().then(move |result| {
+                                    if result.is_err() {
+                                        warn!(
+                                            logger,
+                                            "Subgraph did not finish its block in time, stopping it anyway";
+                                            "timeout_secs" => DRAIN_TIMEOUT.as_secs()
+                                        );
+                                    }
+                                    self_for_stop.send_event(
+                                        id_for_stop.clone(),
+                                        SubgraphAssignmentProviderEvent::SubgraphStop(id_for_stop),
+                                    )
+                                })
+                            }),
+                    )
+                })
+                .then(move |result| {
+                    let waiters = {
+                        let mut deployments = deployments.lock().unwrap();
+                        let waiters = match deployments.remove(&id) {
+                            Some(DeploymentState::Stopping(waiters)) => waiters,
+                            _ => vec![],
+                        };
+                        if result.is_err() {
+                            deployments.insert(id.clone(), DeploymentState::Running);
+                        }
+                        waiters
+                    };
+                    self_for_waiters.answer_waiters(id, waiters, result.is_ok());
+                    result
+                }),
+        )
+    }
+
+    fn pause(
+        &self,
+        id: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static> {
+        match self.deployments.lock().unwrap().get(&id) {
+            Some(DeploymentState::Running) => (),
+            _ => return Box::new(future::err(SubgraphAssignmentProviderError::NotRunning(id))),
+        }
+        if !self.subgraphs_paused.lock().unwrap().insert(id.clone()) {
+            return Box::new(future::err(SubgraphAssignmentProviderError::AlreadyPaused(
+                id,
+            )));
+        }
+
+        let subgraphs_paused = self.subgraphs_paused.clone();
+        Box::new(
+            self.send_event(
+                id.clone(),
+                SubgraphAssignmentProviderEvent::SubgraphPause(id.clone()),
             )
-        } else {
-            Box::new(future::err(SubgraphAssignmentProviderError::NotRunning(id)))
+            .map_err(move |e| {
+                subgraphs_paused.lock().unwrap().remove(&id);
+                e
+            }),
+        )
+    }
+
+    fn resume(
+        &self,
+        id: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static> {
+        if !self.subgraphs_paused.lock().unwrap().remove(&id) {
+            return Box::new(future::err(SubgraphAssignmentProviderError::NotPaused(id)));
+        }
+
+        let subgraphs_paused = self.subgraphs_paused.clone();
+        Box::new(
+            self.send_event(
+                id.clone(),
+                SubgraphAssignmentProviderEvent::SubgraphResume(id.clone()),
+            )
+            .map_err(move |e| {
+                subgraphs_paused.lock().unwrap().insert(id);
+                e
+            }),
+        )
+    }
+
+    fn rewind(
+        &self,
+        id: SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static> {
+        info!(self.logger, "Rewind subgraph";
+              "subgraph_id" => id.to_string(),
+              "block_number" => block.number,
+              "block_hash" => format!("{:?}", block.hash));
+
+        self.maintain(id, Maintenance::Rewind(block))
+    }
+
+    fn migrate_storage(
+        &self,
+        id: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static> {
+        info!(self.logger, "Migrate subgraph storage";
+              "subgraph_id" => id.to_string());
+
+        self.maintain(id, Maintenance::MigrateStorage)
+    }
+}
+
+impl<L, Q, S> SubgraphAssignmentProvider<L, Q, S>
+where
+    L: LinkResolver + Clone,
+    Q: GraphQlRunner,
+    S: Store + SubgraphDeploymentStore,
+{
+    /// Perform `maintenance` on the deployment `id` while it is not
+    /// running. A deployment that is running is stopped first, and started
+    /// again afterwards, even if the maintenance failed, since it is still
+    /// assigned to this node
+    fn maintain(
+        &self,
+        id: SubgraphDeploymentId,
+        maintenance: Maintenance,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static> {
+        self.maintain_and_restart(id, maintenance, false)
+    }
+
+    /// Perform `maintenance` like `maintain`. With `restart`, the
+    /// deployment was running before and is started again afterwards,
+    /// unless it was stopped while it was being maintained
+    fn maintain_and_restart(
+        &self,
+        id: SubgraphDeploymentId,
+        maintenance: Maintenance,
+        restart: bool,
+    ) -> ProviderFuture {
+        let restarted =
+            {
+                let mut deployments = self.deployments.lock().unwrap();
+                match deployments.get_mut(&id) {
+                    None => {
+                        let (waiters, restarted) = if restart {
+                            let (sender, restarted) = oneshot::channel();
+                            (vec![Waiter::Start(sender)], Some(restarted))
+                        } else {
+                            (vec![], None)
+                        };
+                        deployments.insert(id.clone(), DeploymentState::Maintaining(waiters));
+                        restarted
+                    }
+                    Some(DeploymentState::Maintaining(_)) => {
+                        return Box::new(future::err(maintenance.error(format_err!(
+                            "subgraph `{}` is being rewound or migrated already",
+                            id
+                        ))));
+                    }
+                    Some(DeploymentState::Stopping(waiters)) => {
+                        let (sender, stopped) = oneshot::channel();
+                        waiters.push(Waiter::Maintain(sender));
+                        let self_clone = self.clone();
+                        return Box::new(stopped.then(move |_| {
+                            self_clone.maintain_and_restart(id, maintenance, restart)
+                        }));
+                    }
+                    Some(DeploymentState::Starting(..)) | Some(DeploymentState::Running) => {
+                        let self_clone = self.clone();
+                        return Box::new(
+                            self.stop(id.clone())
+                                .then(|result| match result {
+                                    Err(SubgraphAssignmentProviderError::NotRunning(_)) => Ok(()),
+                                    result => result,
+                                })
+                                .and_then(move |()| {
+                                    self_clone.maintain_and_restart(id, maintenance, true)
+                                }),
+                        );
+                    }
+                }
+            };
+
+        let logger = self.logger_factory.subgraph_logger(&id);
+        let store = self.store.clone();
+        let deployments = self.deployments.clone();
+        let self_clone = self.clone();
+        let maintained: Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send> =
+            match maintenance {
+                Maintenance::Rewind(block) => {
+                    let id = id.clone();
+                    Box::new(future::lazy(move || {
+                        store
+                            .rewind_deployment(&id, block)
+                            .map_err(|e| maintenance.error(e.into()))
+                    }))
+                }
+                Maintenance::MigrateStorage => {
+                    let id = id.clone();
+                    // Blocking due to store interactions. Won't be blocking after #905.
+                    Box::new(
+                        graph::spawn_blocking(async move {
+                            let started = Instant::now();
+                            let mut batches = 1;
+                            while !store.migrate_storage_batch(
+                                &logger,
+                                &id,
+                                *STORAGE_MIGRATION_BATCH_SIZE,
+                            )? {
+                                batches += 1;
+                                // Give queries against the database some room
+                                tokio::time::delay_for(*STORAGE_MIGRATION_BATCH_DELAY).await;
+                            }
+                            info!(logger, "Migrated subgraph storage";
+                              "batches" => batches,
+                              "ms" => started.elapsed().as_millis());
+                            Ok::<_, StoreError>(())
+                        })
+                        .compat()
+                        .then(move |result| match result {
+                            Ok(Ok(())) => Ok(()),
+                            Ok(Err(e)) => Err(maintenance.error(e.into())),
+                            Err(e) => Err(maintenance
+                                .error(format_err!("migrating the storage was cancelled: {}", e))),
+                        }),
+                    )
+                }
+            };
+        Box::new(maintained.then(move |maintained| -> ProviderFuture {
+            let waiters = match deployments.lock().unwrap().remove(&id) {
+                Some(DeploymentState::Maintaining(waiters)) => waiters,
+                _ => vec![],
+            };
+            self_clone.answer_waiters(id, waiters, true);
+
+            // Restarting the deployment is a start that waited for the
+            // maintenance; it does not happen if a stop came after it
+            match restarted {
+                None => Box::new(future::result(maintained)),
+                Some(restarted) => Box::new(restarted.then(move |start| -> ProviderFuture {
+                    match start {
+                        Ok(start) => Box::new(start.then(move |started| {
+                            let started = match started {
+                                Err(SubgraphAssignmentProviderError::AlreadyRunning(_))
+                                | Err(SubgraphAssignmentProviderError::Canceled(_)) => Ok(()),
+                                started => started,
+                            };
+                            maintained.and(started)
+                        })),
+                        Err(_) => Box::new(future::result(maintained)),
+                    }
+                })),
+            }
+        }))
+    }
+
+    /// Answer the requests that waited for deployment `id` to be stopped,
+    /// rewound or migrated, now that that is over; `stopped` says whether
+    /// the deployment is not running anymore. The deployment is only
+    /// started if no stop was requested after the last start. Starting it
+    /// right here, rather than leaving that to the start that waited,
+    /// keeps a stop that arrives later from finding the deployment not
+    /// running yet and the start going ahead anyway
+    fn answer_waiters(&self, id: SubgraphDeploymentId, waiters: Vec<Waiter>, stopped: bool) {
+        let mut start = None;
+        let mut maintenance = vec![];
+        for waiter in waiters {
+            match waiter {
+                // A start whose caller went away was not waited for
+                Waiter::Start(sender) if sender.is_canceled() => (),
+                Waiter::Start(sender) => start = Some(sender),
+                Waiter::Stop(sender) => {
+                    start = None;
+                    let _ = sender.send(stopped);
+                }
+                Waiter::Maintain(sender) => maintenance.push(sender),
+            }
+        }
+        if let Some(sender) = start {
+            let _ = sender.send(self.start(id));
+        }
+        for sender in maintenance {
+            let _ = sender.send(());
         }
     }
 }
@@ -209,9 +1141,678 @@ impl<L, Q, S> EventProducer<SubgraphAssignmentProviderEvent>
     fn take_event_stream(
         &mut self,
     ) -> Option<Box<dyn Stream<Item = SubgraphAssignmentProviderEvent, Error = ()> + Send>> {
+        let event_queue = self.event_queue.clone();
         self.event_stream.take().map(|s| {
-            Box::new(s)
+            Box::new(s.inspect(move |_| {
+                event_queue.release();
+            }))
                 as Box<dyn Stream<Item = SubgraphAssignmentProviderEvent, Error = ()> + Send>
         })
     }
 }
+
+/// Create the attribute indexes of deployment `id` for the entity types in
+/// `schema`. Failures are recorded in a `SubgraphDeploymentIndexFailure`
+/// so that they show up in the indexing status of the deployment until
+/// creating the indexes is retried successfully
+pub(crate) fn create_attribute_indexes<S>(
+    logger: &Logger,
+    store: &S,
+    id: &SubgraphDeploymentId,
+    schema: &Schema,
+) -> Result<(), SubgraphAssignmentProviderError>
+where
+    S: Store,
+{
+    let index_definitions = attribute_index_definitions(id.clone(), schema.document.clone());
+    let result = store.build_entity_attribute_indexes(id, index_definitions);
+    let ops = match &result {
+        Ok(()) => {
+            info!(
+                logger,
+                "Successfully created attribute indexes for subgraph entities"
+            );
+            match store.get(SubgraphDeploymentIndexFailureEntity::key(id.clone())) {
+                Ok(None) => return result,
+                _ => SubgraphDeploymentIndexFailureEntity::remove_operations(id),
+            }
+        }
+        Err(e) => {
+            warn!(
+                logger,
+                "Failed to create attribute indexes for subgraph entities, \
+                 queries against the subgraph may be slow";
+                "error" => e.to_string()
+            );
+            let failed_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            SubgraphDeploymentIndexFailureEntity::new(e.to_string(), failed_at).write_operations(id)
+        }
+    };
+    if let Err(e) = store.apply_metadata_operations(ops) {
+        warn!(
+            logger,
+            "Failed to record the outcome of creating attribute indexes";
+            "error" => e.to_string()
+        );
+    }
+    result
+}
+
+/// Copy the entities and dynamic data sources that the base of a grafted
+/// deployment had at the graft block into the deployment, unless the
+/// deployment is not grafted or has copied them before. Tables are copied
+/// concurrently and in batches; a copy that was interrupted continues
+/// after the last batch it copied. The copied dynamic data sources are
+/// recorded as created at the graft block
+fn copy_graft_base<L, Q, S>(
+    logger: Logger,
+    store: Arc<S>,
+    loader: Arc<DataSourceLoader<L, Q, S>>,
+    metrics: Arc<SubgraphAssignmentProviderMetrics>,
+    manifest: SubgraphManifest,
+) -> Box<dyn Future<Item = SubgraphManifest, Error = SubgraphAssignmentProviderError> + Send>
+where
+    L: LinkResolver,
+    Q: GraphQlRunner,
+    S: Store + SubgraphDeploymentStore,
+{
+    let (base, block) = match pending_graft(&*store, &manifest.id) {
+        Ok(Some(graft)) => graft,
+        Ok(None) => return Box::new(future::ok(manifest)),
+        Err(e) => return Box::new(future::err(SubgraphAssignmentProviderError::Unknown(e))),
+    };
+
+    info!(logger, "Copy data from graft base";
+          "base" => base.to_string(),
+          "block_number" => block.number);
+
+    let started = Instant::now();
+    let entity_types = match store.start_copy_deployment(&base, &manifest.id, block) {
+        Ok(entity_types) => entity_types,
+        Err(e) => {
+            return Box::new(future::err(SubgraphAssignmentProviderError::GraftError(
+                e.into(),
+            )))
+        }
+    };
+    let copy_tables = copy_graft_tables(
+        logger.clone(),
+        store.clone(),
+        base.clone(),
+        manifest.id.clone(),
+        block,
+        entity_types,
+    );
+
+    Box::new(
+        copy_tables
+            .and_then(move |()| {
+                loader
+                    .load_dynamic_data_sources(
+                        &base,
+                        Some(block.number as BlockNumber),
+                        logger.clone(),
+                    )
+                    .map_err(SubgraphAssignmentProviderError::GraftError)
+                    .map(move |data_sources| (base, logger, data_sources))
+            })
+            .and_then(move |(base, logger, data_sources)| {
+                let ops = data_sources
+                    .iter()
+                    .flat_map(|data_source| {
+                        let id = format!("{}-dynamic", Uuid::new_v4().to_simple());
+                        DynamicEthereumContractDataSourceEntity::from((
+                            &manifest.id,
+                            data_source,
+                            &block,
+                        ))
+                        .write_operations(&id)
+                    })
+                    .collect();
+                store
+                    .finish_copy_deployment(&base, &manifest.id, block, ops)
+                    .map_err(|e| SubgraphAssignmentProviderError::GraftError(e.into()))?;
+                metrics.observe_phase(&logger, &manifest.id, "graft", started);
+                info!(logger, "Copied data from graft base";
+                      "base" => base.to_string(),
+                      "dynamic_data_sources" => data_sources.len());
+                Ok(manifest)
+            }),
+    )
+}
+
+/// Copy the tables for `entity_types` from the graft base `base` into the
+/// deployment `id`, `GRAFT_COPY_CONCURRENCY` tables at a time. Every table
+/// is copied in batches of `GRAFT_COPY_BATCH_SIZE` versions, each of them
+/// in its own transaction
+fn copy_graft_tables<S>(
+    logger: Logger,
+    store: Arc<S>,
+    base: SubgraphDeploymentId,
+    id: SubgraphDeploymentId,
+    block: EthereumBlockPointer,
+    entity_types: Vec<String>,
+) -> impl Future<Item = (), Error = SubgraphAssignmentProviderError> + Send
+where
+    S: SubgraphDeploymentStore,
+{
+    stream::iter_ok(entity_types)
+        .map(move |entity_type| {
+            let logger = logger.clone();
+            let store = store.clone();
+            let base = base.clone();
+            let id = id.clone();
+            graph::spawn_blocking(async move {
+                let started = Instant::now();
+                let mut batches = 1;
+                while !store.copy_deployment_batch(
+                    &base,
+                    &id,
+                    &entity_type,
+                    block,
+                    *GRAFT_COPY_BATCH_SIZE,
+                )? {
+                    batches += 1;
+                }
+                debug!(logger, "Copied table from graft base";
+                       "entity_type" => &entity_type,
+                       "batches" => batches,
+                       "ms" => started.elapsed().as_millis());
+                Ok::<_, StoreError>(())
+            })
+            .compat()
+            .then(|result| match result {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(SubgraphAssignmentProviderError::GraftError(e.into())),
+                Err(e) => Err(SubgraphAssignmentProviderError::GraftError(format_err!(
+                    "copying a table from the graft base was cancelled: {}",
+                    e
+                ))),
+            })
+        })
+        .buffer_unordered(*GRAFT_COPY_CONCURRENCY)
+        .for_each(|()| Ok(()))
+}
+
+#[test]
+fn event_queue_is_never_overfilled() {
+    use std::thread;
+
+    let queue = Arc::new(EventQueue::new(10));
+    let reserved = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let queue = queue.clone();
+            let reserved = reserved.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    if queue.try_reserve() {
+                        reserved.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(10, reserved.load(Ordering::SeqCst));
+    assert!(queue.is_full());
+
+    // Taking an event makes room for exactly one more
+    queue.release();
+    assert!(!queue.is_full());
+    assert!(queue.try_reserve());
+    assert!(!queue.try_reserve());
+}
+
+#[test]
+fn rewinding_is_serialized_with_stopping() {
+    use graph_mock::{MockMetricsRegistry, MockStore};
+
+    let id = SubgraphDeploymentId::new("rewound").unwrap();
+    let block: EthereumBlockPointer = (web3::types::H256::zero(), 1u64).into();
+    let mut store = MockStore::new();
+    store
+        .expect_rewind_deployment()
+        .times(1)
+        .returning(|_, _| Ok(()));
+    let store = Arc::new(store);
+    let logger = Logger::root(slog::Discard, o!());
+    let provider = SubgraphAssignmentProvider::new(
+        &LoggerFactory::new(logger.clone(), None),
+        Arc::new(crate::LinkResolver::from(ipfs_api::IpfsClient::default())),
+        store.clone(),
+        Arc::new(crate::GraphQlRunner::new(&logger, store)),
+        Arc::new(MockMetricsRegistry::new()),
+    );
+
+    // Rewinding a deployment that is being rewound already is refused
+    provider
+        .deployments
+        .lock()
+        .unwrap()
+        .insert(id.clone(), DeploymentState::Maintaining(vec![]));
+    assert!(provider.rewind(id.clone(), block).wait().is_err());
+
+    // Rewinding a deployment that is being stopped waits for that to finish
+    provider
+        .deployments
+        .lock()
+        .unwrap()
+        .insert(id.clone(), DeploymentState::Stopping(vec![]));
+    let rewound = provider.rewind(id.clone(), block);
+    let waiters = match provider.deployments.lock().unwrap().remove(&id) {
+        Some(DeploymentState::Stopping(waiters)) => waiters,
+        _ => panic!("rewinding must not touch a deployment that is being stopped"),
+    };
+    assert_eq!(1, waiters.len());
+    for waiter in waiters {
+        match waiter {
+            Waiter::Maintain(waiter) => waiter.send(()).unwrap(),
+            _ => panic!("rewinding must wait as a maintenance"),
+        }
+    }
+    rewound.wait().expect("rewinding failed");
+
+    // Once rewinding is over, the provider has no state for the deployment
+    assert!(provider.deployments.lock().unwrap().get(&id).is_none());
+}
+
+#[test]
+fn starting_waits_for_the_deployment_to_be_drained() {
+    use graph_mock::{MockMetricsRegistry, MockStore};
+
+    let id = SubgraphDeploymentId::new("drained").unwrap();
+    // Whether the deployment is blocked or paused is checked when the
+    // start is requested, and again when it goes ahead
+    let mut store = MockStore::new();
+    store.expect_get().times(4).returning(|_| Ok(None));
+    let store = Arc::new(store);
+    let logger = Logger::root(slog::Discard, o!());
+    let mut provider = SubgraphAssignmentProvider::new(
+        &LoggerFactory::new(logger.clone(), None),
+        Arc::new(crate::LinkResolver::from(ipfs_api::IpfsClient::default())),
+        store.clone(),
+        Arc::new(crate::GraphQlRunner::new(&logger, store)),
+        Arc::new(MockMetricsRegistry::new()),
+    );
+
+    // The instance manager finishes its block right away
+    let events = provider.take_event_stream().unwrap();
+    let manager = std::thread::spawn(move || {
+        events
+            .take(2)
+            .map(|event| {
+                if let SubgraphAssignmentProviderEvent::SubgraphDrain(_, signal) = &event {
+                    signal.notify();
+                }
+                event
+            })
+            .collect()
+            .wait()
+    });
+
+    // A start that arrives while the deployment is being drained waits
+    provider
+        .deployments
+        .lock()
+        .unwrap()
+        .insert(id.clone(), DeploymentState::Running);
+    let stopped = provider.stop(id.clone());
+    let started = provider.start(id.clone());
+    match provider.deployments.lock().unwrap().get(&id) {
+        Some(DeploymentState::Stopping(waiters)) => assert_eq!(1, waiters.len()),
+        _ => panic!("starting must wait for the deployment to be stopped"),
+    }
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(stopped.compat()).expect("stopping failed");
+    match manager.join().unwrap().unwrap().as_slice() {
+        [SubgraphAssignmentProviderEvent::SubgraphDrain(..), SubgraphAssignmentProviderEvent::SubgraphStop(stopped)] =>
+        {
+            assert_eq!(&id, stopped)
+        }
+        _ => panic!("the deployment must be drained before it is stopped"),
+    }
+
+    // Once the deployment is stopped, the start goes ahead
+    match provider.deployments.lock().unwrap().get(&id) {
+        Some(DeploymentState::Starting(..)) => (),
+        _ => panic!("the deployment must be started once it is stopped"),
+    }
+    drop(started);
+}
+
+#[test]
+fn stopping_cancels_starting() {
+    use graph_mock::{MockMetricsRegistry, MockStore};
+
+    let id = SubgraphDeploymentId::new("canceled").unwrap();
+    let store = Arc::new(MockStore::new());
+    let logger = Logger::root(slog::Discard, o!());
+    let mut provider = SubgraphAssignmentProvider::new(
+        &LoggerFactory::new(logger.clone(), None),
+        Arc::new(crate::LinkResolver::from(ipfs_api::IpfsClient::default())),
+        store.clone(),
+        Arc::new(crate::GraphQlRunner::new(&logger, store)),
+        Arc::new(MockMetricsRegistry::new()),
+    );
+    let events = provider.take_event_stream().unwrap();
+
+    let guard = CancelGuard::new();
+    let handle = guard.handle();
+    let (started, receiver) = oneshot::channel();
+    provider
+        .deployments
+        .lock()
+        .unwrap()
+        .insert(id.clone(), DeploymentState::Starting(guard, receiver));
+    let stopped = provider.stop(id.clone());
+    assert!(handle.is_canceled());
+    match provider.deployments.lock().unwrap().get(&id) {
+        Some(DeploymentState::Stopping(_)) => (),
+        _ => panic!("the deployment must be stopping"),
+    }
+
+    // A deployment that was not handed to the instance manager before
+    // starting it was canceled has nothing else to stop
+    started.send(false).unwrap();
+    stopped.wait().expect("stopping failed");
+    assert!(provider.deployments.lock().unwrap().get(&id).is_none());
+    drop(provider);
+    assert!(events.collect().wait().unwrap().is_empty());
+}
+
+#[test]
+fn paused_deployments_are_paused_before_they_start() {
+    use graph_mock::{MockMetricsRegistry, MockStore};
+
+    let id = SubgraphDeploymentId::new("paused").unwrap();
+    let store = Arc::new(MockStore::new());
+    let logger = Logger::root(slog::Discard, o!());
+    let mut provider = SubgraphAssignmentProvider::new(
+        &LoggerFactory::new(logger.clone(), None),
+        Arc::new(crate::LinkResolver::from(ipfs_api::IpfsClient::default())),
+        store.clone(),
+        Arc::new(crate::GraphQlRunner::new(&logger, store)),
+        Arc::new(MockMetricsRegistry::new()),
+    );
+    let events = provider.take_event_stream().unwrap();
+    let manifest = SubgraphManifest {
+        id: id.clone(),
+        location: "/ipfs/paused".to_owned(),
+        spec_version: "0.0.2".to_owned(),
+        description: None,
+        repository: None,
+        schema: Schema::parse("type Thing @entity { id: ID! }", id.clone()).unwrap(),
+        data_sources: vec![],
+        templates: vec![],
+        graft: None,
+        features: vec![],
+    };
+
+    // A deployment that was paused before the node restarted does not
+    // process any blocks until it is resumed
+    let guard = CancelGuard::new();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime
+        .block_on(
+            provider
+                .send_start_events(manifest, true, guard.handle())
+                .compat(),
+        )
+        .expect("starting failed");
+    runtime
+        .block_on(provider.resume(id.clone()).compat())
+        .expect("resuming failed");
+    drop(provider);
+
+    match events.collect().wait().unwrap().as_slice() {
+        [SubgraphAssignmentProviderEvent::SubgraphPause(paused), SubgraphAssignmentProviderEvent::SubgraphStart(started), SubgraphAssignmentProviderEvent::SubgraphResume(resumed)] =>
+        {
+            assert_eq!(&id, paused);
+            assert_eq!(&id, &started.id);
+            assert_eq!(&id, resumed);
+        }
+        _ => panic!("a paused deployment must be paused before it starts"),
+    }
+}
+
+#[test]
+fn the_last_request_decides_whether_a_stopped_deployment_runs() {
+    use graph_mock::{MockMetricsRegistry, MockStore};
+
+    let id = SubgraphDeploymentId::new("orphaned").unwrap();
+    let mut store = MockStore::new();
+    store.expect_get().times(2).returning(|_| Ok(None));
+    let store = Arc::new(store);
+    let logger = Logger::root(slog::Discard, o!());
+    let provider = SubgraphAssignmentProvider::new(
+        &LoggerFactory::new(logger.clone(), None),
+        Arc::new(crate::LinkResolver::from(ipfs_api::IpfsClient::default())),
+        store.clone(),
+        Arc::new(crate::GraphQlRunner::new(&logger, store)),
+        Arc::new(MockMetricsRegistry::new()),
+    );
+
+    // The deployment is unassigned, assigned and unassigned again while it
+    // is being stopped; it must not be left running
+    provider
+        .deployments
+        .lock()
+        .unwrap()
+        .insert(id.clone(), DeploymentState::Stopping(vec![]));
+    let started = provider.start(id.clone());
+    let stopped = provider.stop(id.clone());
+    let waiters = match provider.deployments.lock().unwrap().remove(&id) {
+        Some(DeploymentState::Stopping(waiters)) => waiters,
+        _ => panic!("requests must wait for the deployment to be stopped"),
+    };
+    provider.answer_waiters(id.clone(), waiters, true);
+
+    match started.wait() {
+        Err(SubgraphAssignmentProviderError::Canceled(canceled)) => assert_eq!(id, canceled),
+        _ => panic!("a start that was followed by a stop must be canceled"),
+    }
+    stopped.wait().expect("stopping failed");
+    assert!(provider.deployments.lock().unwrap().get(&id).is_none());
+}
+
+#[test]
+fn storage_is_migrated_in_batches_while_not_running() {
+    use graph_mock::{MockMetricsRegistry, MockStore};
+
+    let id = SubgraphDeploymentId::new("migrated").unwrap();
+    let batches = Arc::new(AtomicUsize::new(0));
+    let mut store = MockStore::new();
+    {
+        let batches = batches.clone();
+        store
+            .expect_migrate_storage_batch()
+            .times(3)
+            .returning(move |_, _, _| Ok(batches.fetch_add(1, Ordering::SeqCst) == 2));
+    }
+    let store = Arc::new(store);
+    let logger = Logger::root(slog::Discard, o!());
+    let provider = SubgraphAssignmentProvider::new(
+        &LoggerFactory::new(logger.clone(), None),
+        Arc::new(crate::LinkResolver::from(ipfs_api::IpfsClient::default())),
+        store.clone(),
+        Arc::new(crate::GraphQlRunner::new(&logger, store)),
+        Arc::new(MockMetricsRegistry::new()),
+    );
+
+    // Migrating a deployment that is being rewound is refused
+    provider
+        .deployments
+        .lock()
+        .unwrap()
+        .insert(id.clone(), DeploymentState::Maintaining(vec![]));
+    match provider.migrate_storage(id.clone()).wait() {
+        Err(SubgraphAssignmentProviderError::StorageMigrationError(_)) => (),
+        _ => panic!("a deployment can only be migrated when it is not maintained already"),
+    }
+    provider.deployments.lock().unwrap().remove(&id);
+
+    // Batches are migrated until the store reports that the deployment
+    // was switched over
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime
+        .block_on(provider.migrate_storage(id.clone()).compat())
+        .expect("migrating failed");
+    assert_eq!(3, batches.load(Ordering::SeqCst));
+    assert!(provider.deployments.lock().unwrap().get(&id).is_none());
+}
+
+#[test]
+fn deployments_are_not_started_during_shutdown() {
+    use graph_mock::{MockMetricsRegistry, MockStore};
+
+    // The mock store has no expectations, so checking anything in the
+    // store before refusing the start would panic
+    let store = Arc::new(MockStore::new());
+    let logger = Logger::root(slog::Discard, o!());
+    let startup = Arc::new(StartupStatus::new());
+    let provider = SubgraphAssignmentProvider::new(
+        &LoggerFactory::new(logger.clone(), None),
+        Arc::new(crate::LinkResolver::from(ipfs_api::IpfsClient::default())),
+        store.clone(),
+        Arc::new(crate::GraphQlRunner::new(&logger, store)),
+        Arc::new(MockMetricsRegistry::new()),
+    )
+    .with_startup(startup.clone());
+
+    startup.set_shutting_down();
+    let id = SubgraphDeploymentId::new("stopped").unwrap();
+    match provider.start(id.clone()).wait() {
+        Err(SubgraphAssignmentProviderError::ShuttingDown(refused)) => assert_eq!(id, refused),
+        _ => panic!("deployments must not start while the node shuts down"),
+    }
+    assert!(provider.deployments.lock().unwrap().get(&id).is_none());
+}
+
+#[test]
+fn index_creation_failures_are_recorded_until_indexes_exist() {
+    use graph_mock::MockStore;
+
+    fn recorded(ops: &[MetadataOperation], set: bool) -> bool {
+        match ops {
+            [MetadataOperation::Set { entity, .. }] if set => {
+                entity == SubgraphDeploymentIndexFailureEntity::TYPENAME
+            }
+            [MetadataOperation::Remove { entity, .. }] if !set => {
+                entity == SubgraphDeploymentIndexFailureEntity::TYPENAME
+            }
+            _ => false,
+        }
+    }
+
+    let id = SubgraphDeploymentId::new("indexed").unwrap();
+    let schema = Schema::parse("type Thing @entity { id: ID!, name: String }", id.clone()).unwrap();
+    let logger = Logger::root(slog::Discard, o!());
+
+    // A failure is recorded
+    let mut store = MockStore::new();
+    store
+        .expect_build_entity_attribute_indexes()
+        .times(1)
+        .returning(|_, _| {
+            Err(SubgraphAssignmentProviderError::Unknown(format_err!(
+                "out of disk space"
+            )))
+        });
+    store
+        .expect_apply_metadata_operations()
+        .withf(|ops| recorded(ops, true))
+        .times(1)
+        .returning(|_| Ok(()));
+    assert!(create_attribute_indexes(&logger, &store, &id, &schema).is_err());
+
+    // Creating the indexes successfully removes the failure
+    let mut store = MockStore::new();
+    store
+        .expect_build_entity_attribute_indexes()
+        .times(1)
+        .returning(|_, _| Ok(()));
+    store
+        .expect_get()
+        .times(1)
+        .returning(|_| Ok(Some(Entity::new())));
+    store
+        .expect_apply_metadata_operations()
+        .withf(|ops| recorded(ops, false))
+        .times(1)
+        .returning(|_| Ok(()));
+    assert!(create_attribute_indexes(&logger, &store, &id, &schema).is_ok());
+
+    // Without a failure, nothing is written
+    let mut store = MockStore::new();
+    store
+        .expect_build_entity_attribute_indexes()
+        .times(1)
+        .returning(|_, _| Ok(()));
+    store.expect_get().times(1).returning(|_| Ok(None));
+    store.expect_apply_metadata_operations().never();
+    assert!(create_attribute_indexes(&logger, &store, &id, &schema).is_ok());
+}
+
+#[test]
+fn start_phases_are_timed_per_deployment_and_phase() {
+    use graph::prelude::Registry;
+
+    let logger = Logger::root(slog::Discard, o!());
+    let registry = Arc::new(Registry::new());
+    let metrics = SubgraphAssignmentProviderMetrics::new(Arc::new(crate::MetricsRegistry::new(
+        logger.clone(),
+        registry.clone(),
+    )));
+
+    let first = SubgraphDeploymentId::new("first").unwrap();
+    let second = SubgraphDeploymentId::new("second").unwrap();
+    let started = Instant::now() - Duration::from_secs(3);
+    metrics.observe_phase(&logger, &first, "resolve", started);
+    metrics.observe_phase(&logger, &first, "resolve", started);
+    metrics.observe_phase(&logger, &first, "create_indexes", started);
+    metrics.observe_phase(&logger, &second, "resolve", started);
+
+    let family = registry
+        .gather()
+        .into_iter()
+        .find(|family| family.get_name() == "subgraph_start_phase_duration")
+        .expect("the start phases are not timed");
+    let mut observed = family
+        .get_metric()
+        .iter()
+        .map(|metric| {
+            let label = |name: &str| {
+                metric
+                    .get_label()
+                    .iter()
+                    .find(|label| label.get_name() == name)
+                    .map(|label| label.get_value().to_owned())
+                    .unwrap()
+            };
+            let histogram = metric.get_histogram();
+            // Every phase took at least the 3 seconds since `started`
+            assert!(histogram.get_sample_sum() >= 3.0 * histogram.get_sample_count() as f64);
+            (
+                label("deployment"),
+                label("phase"),
+                histogram.get_sample_count(),
+            )
+        })
+        .collect::<Vec<_>>();
+    observed.sort();
+    assert_eq!(
+        vec![
+            ("first".to_owned(), "create_indexes".to_owned(), 1),
+            ("first".to_owned(), "resolve".to_owned(), 2),
+            ("second".to_owned(), "resolve".to_owned(), 1),
+        ],
+        observed
+    );
+}
@@ -0,0 +1,501 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use graph::data::subgraph::schema::{
+    SubgraphDeploymentAssignmentEntity, SubgraphDeploymentCostEntity, SubgraphDeploymentEntity,
+    SubgraphDeploymentPinEntity, SubgraphRebalanceLeaseEntity, TypedEntity,
+};
+use graph::prelude::*;
+
+lazy_static! {
+    // The index nodes between which assignments are rebalanced; rebalancing
+    // is off if there are none. Whichever of them holds the rebalance
+    // lease measures the cost of deployments and rebalances assignments
+    // periodically
+    pub(crate) static ref REBALANCE_NODES: Vec<NodeId> = env::var("GRAPH_REBALANCE_NODES")
+        .map(|s| parse_node_ids(&s).expect("invalid GRAPH_REBALANCE_NODES"))
+        .unwrap_or_default();
+
+    // How often the cost of deployments is measured and assignments are
+    // rebalanced, in seconds
+    pub(crate) static ref REBALANCE_INTERVAL: Duration = Duration::from_secs(
+        env::var("GRAPH_REBALANCE_INTERVAL")
+            .unwrap_or("900".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_REBALANCE_INTERVAL")
+    );
+
+    // The most deployments that are moved by one rebalance
+    pub(crate) static ref REBALANCE_MAX_MOVES: usize = env::var("GRAPH_REBALANCE_MAX_MOVES")
+        .unwrap_or("10".into())
+        .parse::<usize>()
+        .expect("invalid GRAPH_REBALANCE_MAX_MOVES");
+}
+
+/// Parse a comma-separated list of node ids
+fn parse_node_ids(s: &str) -> Result<Vec<NodeId>, Error> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|node_id| !node_id.is_empty())
+        .map(|node_id| {
+            NodeId::new(node_id).map_err(|()| format_err!("invalid node id `{}`", node_id))
+        })
+        .collect()
+}
+
+/// Seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The cost of a deployment that processes `blocks_per_minute` blocks and
+/// writes `entity_writes_per_minute` entities. Every deployment costs at
+/// least 1 so that deployments that are not doing anything right now are
+/// still spread across nodes
+fn deployment_cost(blocks_per_minute: u64, entity_writes_per_minute: u64) -> u64 {
+    (blocks_per_minute + entity_writes_per_minute).max(1)
+}
+
+/// The progress of a deployment at some point in time
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CostSample {
+    block: u64,
+    entity_writes: u64,
+    /// Seconds since the epoch
+    at: u64,
+}
+
+impl CostSample {
+    /// The sample kept in a `SubgraphDeploymentCost` entity
+    fn from_cost(cost: &Entity) -> Option<Self> {
+        let number = |name: &str| {
+            cost.get(name)
+                .cloned()
+                .and_then(Value::as_bigint)
+                .map(|number| number.to_u64())
+        };
+        Some(CostSample {
+            block: number("blockNumber")?,
+            entity_writes: number("entityWriteCount")?,
+            at: number("updatedAt")?,
+        })
+    }
+
+    /// Blocks processed and entities written per minute between `self`
+    /// and the later sample `other`
+    fn rates(&self, other: &CostSample) -> (u64, u64) {
+        if other.at <= self.at {
+            return (0, 0);
+        }
+        let minutes = (other.at - self.at) as f64 / 60.0;
+        let per_minute = |delta: u64| (delta as f64 / minutes).round() as u64;
+        (
+            per_minute(other.block.saturating_sub(self.block)),
+            per_minute(other.entity_writes.saturating_sub(self.entity_writes)),
+        )
+    }
+}
+
+/// Take or renew the lease that lets `node_id` measure costs and rebalance
+/// assignments until `duration` from now. Returns `false` without changing
+/// anything if another node holds a lease that has not expired, or took
+/// the lease at the same time
+fn acquire_lease(store: &impl Store, node_id: &NodeId, duration: Duration) -> Result<bool, Error> {
+    let id = SubgraphRebalanceLeaseEntity::ID.to_owned();
+    let now = now();
+    let lease = store.get(SubgraphRebalanceLeaseEntity::key(id.clone()))?;
+
+    let holder = lease
+        .as_ref()
+        .and_then(|lease| lease.get("nodeId").cloned())
+        .and_then(Value::as_string);
+    let expires_at = lease
+        .as_ref()
+        .and_then(|lease| lease.get("expiresAt").cloned())
+        .and_then(Value::as_bigint)
+        .map(|expires_at| expires_at.to_u64());
+    match (&holder, expires_at) {
+        (Some(holder), Some(expires_at)) if holder != &node_id.to_string() && expires_at > now => {
+            return Ok(false)
+        }
+        _ => (),
+    }
+
+    // Only take the lease if nobody else changed it since we looked at it
+    let unchanged = match (holder, expires_at) {
+        (Some(holder), Some(expires_at)) => EntityFilter::And(vec![
+            EntityFilter::new_equal("id", id.clone()),
+            EntityFilter::new_equal("nodeId", holder),
+            EntityFilter::new_equal("expiresAt", expires_at),
+        ]),
+        _ => EntityFilter::new_equal("id", id.clone()),
+    };
+    let mut ops = vec![MetadataOperation::AbortUnless {
+        description: "Rebalance lease is unchanged".to_owned(),
+        query: SubgraphRebalanceLeaseEntity::query().filter(unchanged),
+        entity_ids: lease.map(|_| vec![id.clone()]).unwrap_or_default(),
+    }];
+    ops.extend(
+        SubgraphRebalanceLeaseEntity::new(node_id.clone(), now + duration.as_secs())
+            .write_operations(),
+    );
+    match store.apply_metadata_operations(ops) {
+        Ok(()) => Ok(true),
+        Err(StoreError::Aborted(_)) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Measure how many blocks each assigned deployment processed and how many
+/// entities it wrote since the sample kept in its `SubgraphDeploymentCost`
+/// entity, and record the resulting cost together with a new sample.
+/// Deployments that were not measured before only get a sample, and keep
+/// costing 1 until the next measurement
+fn measure_costs(store: &impl Store) -> Result<(), Error> {
+    let now = now();
+
+    let mut ops = vec![];
+    for assignment in store.find(SubgraphDeploymentAssignmentEntity::query())? {
+        let id = SubgraphDeploymentId::new(assignment.id()?)
+            .map_err(|()| format_err!("Invalid subgraph hash in assignment entity"))?;
+        let deployment = match store.get(SubgraphDeploymentEntity::key(id.clone()))? {
+            Some(deployment) => deployment,
+            None => continue,
+        };
+        let number = |name: &str| {
+            deployment
+                .get(name)
+                .cloned()
+                .and_then(Value::as_bigint)
+                .map_or(0, |number| number.to_u64())
+        };
+        let sample = CostSample {
+            block: number("latestEthereumBlockNumber"),
+            entity_writes: number("entityWriteCount"),
+            at: now,
+        };
+
+        let previous = store
+            .get(SubgraphDeploymentCostEntity::key(id.clone()))?
+            .and_then(|cost| CostSample::from_cost(&cost));
+        let (blocks, entity_writes) = previous.map_or((0, 0), |previous| previous.rates(&sample));
+        ops.extend(
+            SubgraphDeploymentCostEntity::new(
+                blocks,
+                entity_writes,
+                deployment_cost(blocks, entity_writes),
+                now,
+                sample.block,
+                sample.entity_writes,
+            )
+            .write_operations(&id),
+        );
+    }
+
+    store.apply_metadata_operations(ops)?;
+    Ok(())
+}
+
+/// What the rebalancer needs to know about an assigned deployment
+#[derive(Clone, Debug)]
+struct DeploymentLoad {
+    id: SubgraphDeploymentId,
+    node_id: NodeId,
+    cost: u64,
+    pinned: bool,
+}
+
+/// Decide which deployments to move between `nodes` to even out the total
+/// cost of the deployments on each node. Each step moves a deployment from
+/// the node with the highest cost to the one with the lowest cost, picking
+/// the deployment that brings the two closest together. A deployment is
+/// only moved if that narrows the gap between the two nodes, and at most
+/// once. Deployments on other nodes and pinned deployments stay where they
+/// are, though pinned deployments count towards the cost of their node
+fn plan_rebalance(
+    nodes: &[NodeId],
+    deployments: &[DeploymentLoad],
+    max_moves: usize,
+) -> Vec<AssignmentMove> {
+    let mut loads: HashMap<&NodeId, u64> = nodes.iter().map(|node_id| (node_id, 0)).collect();
+    for deployment in deployments {
+        if let Some(load) = loads.get_mut(&deployment.node_id) {
+            *load += deployment.cost;
+        }
+    }
+
+    let mut movable: Vec<&DeploymentLoad> = deployments
+        .iter()
+        .filter(|deployment| !deployment.pinned && loads.contains_key(&deployment.node_id))
+        .collect();
+    let mut moves = vec![];
+    while moves.len() < max_moves {
+        // Ties are broken by the order of `nodes` so that the plan does not
+        // depend on the iteration order of `loads`
+        let busiest = nodes
+            .iter()
+            .rev()
+            .max_by_key(|node_id| loads[node_id])
+            .expect("there are nodes to rebalance");
+        let idlest = nodes
+            .iter()
+            .min_by_key(|node_id| loads[node_id])
+            .expect("there are nodes to rebalance");
+        let gap = loads[busiest] - loads[idlest];
+
+        let candidate = movable
+            .iter()
+            .enumerate()
+            .filter(|(_, deployment)| &deployment.node_id == busiest && deployment.cost < gap)
+            .min_by_key(|(_, deployment)| {
+                let remaining = gap as i64 - 2 * deployment.cost as i64;
+                (remaining.abs(), deployment.id.to_string())
+            })
+            .map(|(i, _)| i);
+        let deployment = match candidate {
+            Some(i) => movable.remove(i),
+            None => break,
+        };
+
+        *loads.get_mut(busiest).unwrap() -= deployment.cost;
+        *loads.get_mut(idlest).unwrap() += deployment.cost;
+        moves.push(AssignmentMove {
+            deployment: deployment.id.clone(),
+            from: busiest.clone(),
+            to: idlest.clone(),
+        });
+    }
+    moves
+}
+
+/// The node that the deployment `hash` is pinned to, if any
+pub(crate) fn pinned_node(
+    store: &impl Store,
+    hash: &SubgraphDeploymentId,
+) -> Result<Option<NodeId>, Error> {
+    Ok(store
+        .get(SubgraphDeploymentPinEntity::key(hash.clone()))?
+        .and_then(|pin| pin.get("nodeId").cloned())
+        .and_then(Value::as_string)
+        .and_then(|node_id| NodeId::new(node_id).ok()))
+}
+
+/// Operations that move the deployment `hash` from `from` to `to`, keeping
+/// its standby node unless that is `to`
+pub(crate) fn move_operations(
+    hash: &SubgraphDeploymentId,
+    from: &NodeId,
+    to: NodeId,
+    standby: Option<NodeId>,
+) -> Vec<MetadataOperation> {
+    let standby = standby.filter(|standby| standby != &to);
+    let mut ops = vec![MetadataOperation::AbortUnless {
+        description: "Deployment assignment is unchanged".to_owned(),
+        query: SubgraphDeploymentAssignmentEntity::query().filter(EntityFilter::And(vec![
+            EntityFilter::new_equal("nodeId", from.to_string()),
+            EntityFilter::new_equal("id", hash.to_string()),
+        ])),
+        entity_ids: vec![hash.to_string()],
+    }];
+    ops.extend(
+        SubgraphDeploymentAssignmentEntity::new(to)
+            .with_standby(standby)
+            .write_operations(hash),
+    );
+    ops
+}
+
+/// Move deployments between `nodes` according to `plan_rebalance`, using
+/// the costs recorded by `measure_costs`. Deployments that were not
+/// measured yet cost 1
+pub(crate) fn rebalance(
+    logger: &Logger,
+    store: &impl Store,
+    nodes: &[NodeId],
+    max_moves: usize,
+) -> Result<Vec<AssignmentMove>, SubgraphRegistrarError> {
+    if nodes.is_empty() {
+        return Err(SubgraphRegistrarError::RebalancingDisabled);
+    }
+
+    let node_id = |entity: &Entity, attr: &str| {
+        entity
+            .get(attr)
+            .cloned()
+            .and_then(Value::as_string)
+            .and_then(|node_id| NodeId::new(node_id).ok())
+    };
+
+    let mut deployments = vec![];
+    let mut standbys = HashMap::new();
+    for assignment in store.find(SubgraphDeploymentAssignmentEntity::query())? {
+        let id = SubgraphDeploymentId::new(assignment.id()?)
+            .map_err(|()| format_err!("Invalid subgraph hash in assignment entity"))?;
+        let assigned = match node_id(&assignment, "nodeId") {
+            Some(assigned) if nodes.contains(&assigned) => assigned,
+            _ => continue,
+        };
+        let cost = store
+            .get(SubgraphDeploymentCostEntity::key(id.clone()))?
+            .and_then(|cost| cost.get("cost").cloned())
+            .and_then(Value::as_bigint)
+            .map_or(1, |cost| cost.to_u64());
+        let pinned = pinned_node(store, &id)?.is_some();
+
+        standbys.insert(id.clone(), node_id(&assignment, "standbyNodeId"));
+        deployments.push(DeploymentLoad {
+            id,
+            node_id: assigned,
+            cost,
+            pinned,
+        });
+    }
+
+    let moves = plan_rebalance(nodes, &deployments, max_moves);
+    for mv in &moves {
+        info!(
+            logger,
+            "Moving deployment to rebalance assignments";
+            "subgraph_id" => mv.deployment.to_string(),
+            "from" => mv.from.to_string(),
+            "to" => mv.to.to_string(),
+        );
+        let standby = standbys.remove(&mv.deployment).unwrap_or(None);
+        store.apply_metadata_operations(move_operations(
+            &mv.deployment,
+            &mv.from,
+            mv.to.clone(),
+            standby,
+        ))?;
+    }
+    Ok(moves)
+}
+
+/// Measure costs and rebalance assignments across `REBALANCE_NODES` if
+/// `node_id` holds the rebalance lease or can take it. The lease lasts
+/// for two intervals, so that another node takes over if the node that
+/// holds it goes away
+pub(crate) fn run_with_lease(
+    logger: &Logger,
+    store: &impl Store,
+    node_id: &NodeId,
+) -> Result<(), SubgraphRegistrarError> {
+    if !acquire_lease(store, node_id, *REBALANCE_INTERVAL * 2)? {
+        return Ok(());
+    }
+    measure_costs(store)?;
+    rebalance(logger, store, &REBALANCE_NODES, *REBALANCE_MAX_MOVES).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str) -> NodeId {
+        NodeId::new(name).unwrap()
+    }
+
+    fn load(id: &str, node_id: &str, cost: u64, pinned: bool) -> DeploymentLoad {
+        DeploymentLoad {
+            id: SubgraphDeploymentId::new(id).unwrap(),
+            node_id: node(node_id),
+            cost,
+            pinned,
+        }
+    }
+
+    fn moved(moves: &[AssignmentMove]) -> Vec<(String, String)> {
+        moves
+            .iter()
+            .map(|mv| (mv.deployment.to_string(), mv.to.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_node_ids() {
+        assert_eq!(
+            vec![node("index_0"), node("index_1")],
+            parse_node_ids(" index_0, index_1,").unwrap()
+        );
+        assert!(parse_node_ids("index 0").is_err());
+    }
+
+    #[test]
+    fn rates_per_minute() {
+        let before = CostSample {
+            block: 100,
+            entity_writes: 500,
+            at: 1000,
+        };
+        let after = CostSample {
+            block: 110,
+            entity_writes: 700,
+            at: 1120,
+        };
+        assert_eq!((5, 100), before.rates(&after));
+        assert_eq!((0, 0), before.rates(&before));
+        assert_eq!((0, 0), after.rates(&before));
+
+        let mut cost = Entity::new();
+        cost.set("updatedAt", 1000u64);
+        cost.set("blockNumber", 100u64);
+        assert_eq!(None, CostSample::from_cost(&cost));
+        cost.set("entityWriteCount", 500u64);
+        assert_eq!(Some(before), CostSample::from_cost(&cost));
+    }
+
+    #[test]
+    fn moves_deployments_to_idle_nodes() {
+        let nodes = vec![node("a"), node("b")];
+        let deployments = vec![
+            load("Qm1", "a", 6, false),
+            load("Qm2", "a", 3, false),
+            load("Qm3", "a", 1, false),
+            load("Qm4", "b", 2, false),
+        ];
+        // The gap of 8 is narrowed most by moving the deployment costing 3,
+        // and the remaining gap of 2 is closed by the one costing 1
+        assert_eq!(
+            vec![
+                ("Qm2".to_owned(), "b".to_owned()),
+                ("Qm3".to_owned(), "b".to_owned())
+            ],
+            moved(&plan_rebalance(&nodes, &deployments, 10))
+        );
+    }
+
+    #[test]
+    fn keeps_pinned_and_foreign_deployments() {
+        let nodes = vec![node("a"), node("b")];
+        let deployments = vec![
+            load("Qm1", "a", 5, true),
+            load("Qm2", "a", 5, false),
+            load("Qm3", "c", 1, false),
+        ];
+        assert_eq!(
+            vec![("Qm2".to_owned(), "b".to_owned())],
+            moved(&plan_rebalance(&nodes, &deployments, 10))
+        );
+
+        let deployments = vec![load("Qm1", "a", 5, true), load("Qm2", "a", 5, true)];
+        assert!(plan_rebalance(&nodes, &deployments, 10).is_empty());
+    }
+
+    #[test]
+    fn limits_moves() {
+        let nodes = vec![node("a"), node("b"), node("c")];
+        let deployments = vec![
+            load("Qm1", "a", 1, false),
+            load("Qm2", "a", 1, false),
+            load("Qm3", "a", 1, false),
+        ];
+        assert_eq!(2, plan_rebalance(&nodes, &deployments, 10).len());
+        assert_eq!(1, plan_rebalance(&nodes, &deployments, 1).len());
+        assert!(plan_rebalance(&nodes, &deployments[..1], 10).is_empty());
+    }
+}
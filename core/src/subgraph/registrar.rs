@@ -1,27 +1,88 @@
 use lazy_static::lazy_static;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::sync::Mutex;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{env, iter};
 
 lazy_static! {
-    // The timeout for IPFS requests in seconds
-    pub static ref IPFS_SUBGRAPH_LOADING_TIMEOUT: Duration = Duration::from_secs(
-        env::var("GRAPH_IPFS_SUBGRAPH_LOADING_TIMEOUT")
+    // How often a node checks the progress of deployments for which it is
+    // the standby node, in seconds
+    pub(crate) static ref STANDBY_CHECK_INTERVAL: Duration = Duration::from_secs(
+        env::var("GRAPH_STANDBY_CHECK_INTERVAL")
+            .unwrap_or("30".into())
+            .parse::<u64>()
+            .expect("invalid standby check interval")
+    );
+
+    // How long a deployment may go without making progress before its
+    // standby node takes over, in seconds
+    static ref STANDBY_TAKEOVER_TIMEOUT: Duration = Duration::from_secs(
+        env::var("GRAPH_STANDBY_TAKEOVER_TIMEOUT")
+            .unwrap_or("600".into())
+            .parse::<u64>()
+            .expect("invalid standby takeover timeout")
+    );
+
+    // How often a node checks whether deployments assigned to it are due
+    // for their scheduled removal, in seconds
+    static ref SCHEDULED_REMOVAL_CHECK_INTERVAL: Duration = Duration::from_secs(
+        env::var("GRAPH_SCHEDULED_REMOVAL_CHECK_INTERVAL")
+            .unwrap_or("60".into())
+            .parse::<u64>()
+            .expect("invalid scheduled removal check interval")
+    );
+
+    // How long after its removal became due a deployment is actually
+    // removed, in seconds. Until then, every check logs a warning
+    static ref SCHEDULED_REMOVAL_GRACE_PERIOD: u64 =
+        env::var("GRAPH_SCHEDULED_REMOVAL_GRACE_PERIOD")
+            .unwrap_or("3600".into())
+            .parse::<u64>()
+            .expect("invalid scheduled removal grace period");
+
+    // How often a node checks whether the storage of deployments assigned
+    // to it should be migrated to relational storage, in seconds
+    static ref STORAGE_MIGRATION_CHECK_INTERVAL: Duration = Duration::from_secs(
+        env::var("GRAPH_STORAGE_MIGRATION_CHECK_INTERVAL")
             .unwrap_or("60".into())
             .parse::<u64>()
-            .expect("invalid IPFS subgraph loading timeout")
+            .expect("invalid storage migration check interval")
     );
+
+    // How many of the deployments assigned to a node are started at the
+    // same time when the node starts. Starting a deployment resolves its
+    // manifest and dynamic data sources from IPFS
+    static ref STARTUP_CONCURRENCY: usize = env::var("GRAPH_STARTUP_CONCURRENCY")
+        .unwrap_or("20".into())
+        .parse::<usize>()
+        .expect("invalid startup concurrency");
 }
 
+use graph::data::graphql::ext::DocumentExt;
+use graph::data::schema::PartitionSpec;
 use graph::data::subgraph::schema::{
-    generate_entity_id, SubgraphDeploymentAssignmentEntity, SubgraphDeploymentEntity,
+    generate_entity_id, SubgraphAdminOperationEntity, SubgraphDeploymentAssignmentEntity,
+    SubgraphDeploymentBlockEntity, SubgraphDeploymentEntity, SubgraphDeploymentIdleEntity,
+    SubgraphDeploymentPinEntity, SubgraphDeploymentQuotaEntity, SubgraphDeploymentRemovalEntity,
     SubgraphEntity, SubgraphVersionEntity, TypedEntity,
 };
+use graph::prelude::web3::types::H256;
 use graph::prelude::{
     CreateSubgraphResult, SubgraphAssignmentProvider as SubgraphAssignmentProviderTrait,
     SubgraphRegistrar as SubgraphRegistrarTrait, *,
 };
 
+use graph_graphql::graphql_parser::query as q;
+use graph_graphql::prelude::validate_entity;
+
+use crate::subgraph::idle::deployment_hibernated;
+use crate::subgraph::ipfs_retry_policy::IPFS_RETRY_POLICIES;
+use crate::subgraph::provider::create_attribute_indexes;
+use crate::subgraph::rebalance::{
+    self, move_operations, pinned_node, REBALANCE_INTERVAL, REBALANCE_MAX_MOVES, REBALANCE_NODES,
+};
+
 pub struct SubgraphRegistrar<L, P, S, CS> {
     logger: Logger,
     logger_factory: LoggerFactory,
@@ -33,6 +94,11 @@ pub struct SubgraphRegistrar<L, P, S, CS> {
     node_id: NodeId,
     version_switching_mode: SubgraphVersionSwitchingMode,
     assignment_event_stream_cancel_guard: CancelGuard, // cancels on drop
+    startup: Arc<StartupStatus>,
+    /// Deployments whose attribute indexes are being created again
+    index_retries: Arc<Mutex<HashSet<SubgraphDeploymentId>>>,
+    /// Deployments whose storage is being migrated
+    storage_migrations: Arc<Mutex<HashSet<SubgraphDeploymentId>>>,
 }
 
 impl<L, P, S, CS> SubgraphRegistrar<L, P, S, CS>
@@ -62,8 +128,8 @@ where
                 resolver
                     .as_ref()
                     .clone()
-                    .with_timeout(*IPFS_SUBGRAPH_LOADING_TIMEOUT)
-                    .with_retries(),
+                    .with_retry_policy(IPFS_RETRY_POLICIES.node())
+                    .with_priority(LinkResolverPriority::High),
             ),
             provider,
             store,
@@ -72,13 +138,24 @@ where
             node_id,
             version_switching_mode,
             assignment_event_stream_cancel_guard: CancelGuard::new(),
+            startup: Arc::new(StartupStatus::new()),
+            index_retries: Arc::new(Mutex::new(HashSet::new())),
+            storage_migrations: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
+    /// Record in `startup` when all deployments assigned to this node at
+    /// startup have been started
+    pub fn with_startup(mut self, startup: Arc<StartupStatus>) -> Self {
+        self.startup = startup;
+        self
+    }
+
     pub fn start(&self) -> impl Future<Item = (), Error = Error> {
         let logger_clone1 = self.logger.clone();
         let logger_clone2 = self.logger.clone();
         let provider = self.provider.clone();
+        let store = self.store.clone();
         let node_id = self.node_id.clone();
         let assignment_event_stream_cancel_handle =
             self.assignment_event_stream_cancel_guard.handle();
@@ -108,6 +185,19 @@ where
         // Start event stream
         let assignment_event_stream = self.assignment_events();
 
+        // Watch the deployments for which this node is the standby
+        self.start_standby_watcher();
+
+        // Remove deployments whose scheduled removal is due
+        self.start_removal_watcher();
+
+        // Migrate deployments whose migration to relational storage was
+        // requested
+        self.start_storage_migration_watcher();
+
+        // Rebalance assignments across index nodes
+        self.start_rebalancer();
+
         // Deploy named subgraphs found in store
         self.start_assigned_subgraphs().and_then(move |()| {
             // Spawn a task to handle assignment events.
@@ -121,7 +211,12 @@ where
                     })
                     .for_each(move |assignment_event| {
                         assert_eq!(assignment_event.node_id(), &node_id);
-                        handle_assignment_event(assignment_event, provider.clone(), &logger_clone1)
+                        handle_assignment_event(
+                            assignment_event,
+                            provider.clone(),
+                            store.clone(),
+                            &logger_clone1,
+                        )
                     })
                     .map_err(move |e| match e {
                         CancelableError::Cancel => panic!("assignment event stream canceled"),
@@ -145,17 +240,17 @@ where
         store
             .subscribe(vec![
                 SubgraphDeploymentAssignmentEntity::subgraph_entity_pair(),
+                SubgraphDeploymentBlockEntity::subgraph_entity_pair(),
             ])
             .map_err(|()| format_err!("Entity change stream failed"))
             .map(|event| {
-                // We're only interested in the SubgraphDeploymentAssignment change; we
-                // know that there is at least one, as that is what we subscribed to
-                stream::iter_ok(
-                    event
-                        .changes
-                        .into_iter()
-                        .filter(|change| change.entity_type == "SubgraphDeploymentAssignment"),
-                )
+                // We're only interested in the SubgraphDeploymentAssignment and
+                // SubgraphDeploymentBlock changes; we know that there is at least
+                // one, as that is what we subscribed to
+                stream::iter_ok(event.changes.into_iter().filter(|change| {
+                    change.entity_type == SubgraphDeploymentAssignmentEntity::TYPENAME
+                        || change.entity_type == SubgraphDeploymentBlockEntity::TYPENAME
+                }))
             })
             .flatten()
             .and_then(
@@ -170,6 +265,15 @@ where
                             )
                         })?;
 
+                    if entity_change.entity_type == SubgraphDeploymentBlockEntity::TYPENAME {
+                        return Ok(Box::new(stream::iter_ok(block_change_event(
+                            &*store,
+                            &node_id,
+                            subgraph_hash,
+                            entity_change.operation,
+                        )?)));
+                    }
+
                     match entity_change.operation {
                         EntityChangeOperation::Set => {
                             store
@@ -222,9 +326,105 @@ where
             .flatten()
     }
 
+    /// Periodically check the deployments for which this node is the
+    /// standby node, and take over any deployment that has not made
+    /// progress for `GRAPH_STANDBY_TAKEOVER_TIMEOUT`
+    fn start_standby_watcher(&self) {
+        use futures03::stream::StreamExt;
+
+        let logger = self.logger.clone();
+        let store = self.store.clone();
+        let node_id = self.node_id.clone();
+        let mut progress = HashMap::new();
+
+        // Blocking due to store interactions. Won't be blocking after #905.
+        graph::spawn_blocking(
+            tokio::time::interval(*STANDBY_CHECK_INTERVAL).for_each(move |_| {
+                if let Err(e) = check_standby_deployments(&logger, &*store, &node_id, &mut progress)
+                {
+                    warn!(logger, "Failed to check standby deployments"; "error" => e.to_string());
+                }
+                futures03::future::ready(())
+            }),
+        );
+    }
+
+    /// Periodically measure the cost of all assigned deployments and
+    /// rebalance assignments across `GRAPH_REBALANCE_NODES`. Every one of
+    /// these nodes tries to, but only the one that holds the rebalance
+    /// lease does, so that nodes do not move deployments back and forth
+    /// between them
+    fn start_rebalancer(&self) {
+        use futures03::stream::StreamExt;
+
+        if !REBALANCE_NODES.contains(&self.node_id) {
+            return;
+        }
+
+        let logger = self.logger.clone();
+        let store = self.store.clone();
+        let node_id = self.node_id.clone();
+
+        // Blocking due to store interactions. Won't be blocking after #905.
+        graph::spawn_blocking(
+            tokio::time::interval(*REBALANCE_INTERVAL).for_each(move |_| {
+                if let Err(e) = rebalance::run_with_lease(&logger, &*store, &node_id) {
+                    warn!(logger, "Failed to rebalance assignments"; "error" => e.to_string());
+                }
+                futures03::future::ready(())
+            }),
+        );
+    }
+
+    fn start_removal_watcher(&self) {
+        use futures03::stream::StreamExt;
+
+        let logger = self.logger.clone();
+        let store = self.store.clone();
+        let node_id = self.node_id.clone();
+
+        // Blocking due to store interactions. Won't be blocking after #905.
+        graph::spawn_blocking(
+            tokio::time::interval(*SCHEDULED_REMOVAL_CHECK_INTERVAL).for_each(move |_| {
+                if let Err(e) = check_scheduled_removals(&logger, store.clone(), &node_id) {
+                    warn!(logger, "Failed to check scheduled removals"; "error" => e.to_string());
+                }
+                futures03::future::ready(())
+            }),
+        );
+    }
+
+    fn start_storage_migration_watcher(&self) {
+        use futures03::stream::StreamExt;
+
+        let logger = self.logger.clone();
+        let store = self.store.clone();
+        let provider = self.provider.clone();
+        let node_id = self.node_id.clone();
+        let running = self.storage_migrations.clone();
+
+        // Blocking due to store interactions. Won't be blocking after #905.
+        graph::spawn_blocking(
+            tokio::time::interval(*STORAGE_MIGRATION_CHECK_INTERVAL).for_each(move |_| {
+                if let Err(e) = check_storage_migrations(
+                    &logger,
+                    &*store,
+                    provider.clone(),
+                    &node_id,
+                    running.clone(),
+                ) {
+                    warn!(logger, "Failed to check storage migrations"; "error" => e.to_string());
+                }
+                futures03::future::ready(())
+            }),
+        );
+    }
+
     fn start_assigned_subgraphs(&self) -> impl Future<Item = (), Error = Error> {
         let provider = self.provider.clone();
+        let store = self.store.clone();
         let logger = self.logger.clone();
+        let startup = self.startup.clone();
 
         // Create a query to find all assignments with this node ID
         let assignment_query = SubgraphDeploymentAssignmentEntity::query()
@@ -246,27 +446,32 @@ where
                     .collect::<Result<HashSet<SubgraphDeploymentId>, _>>()
             })
             .and_then(move |subgraph_ids| {
+                // Hibernated deployments stay stopped until they are woken up
+                let (hibernated, subgraph_ids): (HashSet<_>, HashSet<_>) = subgraph_ids
+                    .into_iter()
+                    .partition(|id| deployment_hibernated(&*store, id).unwrap_or(false));
+                let count = subgraph_ids.len();
+                info!(logger, "Starting assigned subgraphs";
+                      "count" => count,
+                      "hibernated" => hibernated.len(),
+                      "concurrency" => *STARTUP_CONCURRENCY);
+
                 // This operation should finish only after all subgraphs are
-                // started. We wait for the spawned tasks to complete by giving
-                // each a `sender` and waiting for all of them to be dropped, so
-                // the receiver terminates without receiving anything.
-                let (sender, receiver) = futures::sync::mpsc::channel::<()>(1);
-                for id in subgraph_ids {
-                    let sender = sender.clone();
-                    let provider = provider.clone();
-                    let logger = logger.clone();
-
-                    // Blocking due to store interactions. Won't be blocking after #905.
-                    graph::spawn_blocking(
-                        start_subgraph(id, &*provider, logger)
-                            .map(move |()| drop(sender))
-                            .map_err(|()| unreachable!())
-                            .compat(),
-                    );
+                // started. Each deployment is started in its own task, which
+                // signals when it is done; at most `STARTUP_CONCURRENCY` of
+                // them run at once, and each deployment is handed to the
+                // instance manager as soon as its own start completes
+                for id in &subgraph_ids {
+                    startup.set_phase(id, StartupPhase::Queued);
                 }
-                drop(sender);
-                receiver.collect().then(move |_| {
-                    info!(logger, "Started all subgraphs");
+
+                let logger_for_done = logger.clone();
+                start_concurrently(subgraph_ids, *STARTUP_CONCURRENCY, move |id| {
+                    start_subgraph(id, &*provider, logger.clone())
+                })
+                .then(move |_| {
+                    info!(logger_for_done, "Started all subgraphs"; "count" => count);
+                    startup.set_assignments_loaded();
                     future::ok(())
                 })
             })
@@ -275,7 +480,7 @@ where
 
 impl<L, P, S, CS> SubgraphRegistrarTrait for SubgraphRegistrar<L, P, S, CS>
 where
-    L: LinkResolver,
+    L: LinkResolver + Clone,
     P: SubgraphAssignmentProviderTrait,
     S: Store + SubgraphDeploymentStore,
     CS: ChainStore,
@@ -297,7 +502,23 @@ where
         name: SubgraphName,
         hash: SubgraphDeploymentId,
         node_id: NodeId,
-    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        partitions: BTreeMap<String, PartitionSpec>,
+    ) -> Box<
+        dyn Future<Item = Vec<SubgraphManifestValidationWarning>, Error = SubgraphRegistrarError>
+            + Send
+            + 'static,
+    > {
+        match deployment_block_reason(&*self.store, &hash) {
+            Ok(None) => (),
+            Ok(Some(reason)) => {
+                return Box::new(future::err(SubgraphRegistrarError::DeploymentBlocked(
+                    hash.to_string(),
+                    reason,
+                )))
+            }
+            Err(e) => return Box::new(future::err(e.into())),
+        }
+
         let store_for_validation = self.store.clone();
         let store_for_subgraph_version = self.store.clone();
         let chain_stores = self.chain_stores.clone();
@@ -312,7 +533,13 @@ where
         Box::new(
             UnvalidatedSubgraphManifest::resolve(
                 hash.to_ipfs_link(),
-                self.resolver.clone(),
+                Arc::new(
+                    self.resolver
+                        .as_ref()
+                        .clone()
+                        .for_deployment(hash.clone())
+                        .with_retry_policy(IPFS_RETRY_POLICIES.for_deployment(&hash)),
+                ),
                 logger,
             )
             .map_err(SubgraphRegistrarError::ResolveError)
@@ -323,6 +550,17 @@ where
                     },
                 )
             })
+            .and_then(move |(mut manifest, validation_warnings)| {
+                manifest
+                    .schema
+                    .set_partitioning(&partitions)
+                    .map(|()| (manifest, validation_warnings))
+                    .map_err(|e| {
+                        SubgraphRegistrarError::ManifestValidationError(vec![
+                            SubgraphManifestValidationError::SchemaValidationError(vec![e]),
+                        ])
+                    })
+            })
             .and_then(move |(manifest, validation_warnings)| {
                 let network_name = manifest.network_name();
                 chain_stores
@@ -371,7 +609,115 @@ where
                     "subgraph_hash" => manifest_id.to_string(),
                     "validation_warnings" => format!("{:?}", validation_warnings),
                 );
-                Ok(())
+                Ok(validation_warnings)
+            }),
+        )
+    }
+
+    fn validate_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = SubgraphValidation, Error = SubgraphRegistrarError> + Send + 'static>
+    {
+        let mut validation = SubgraphValidation::default();
+        match deployment_block_reason(&*self.store, &hash) {
+            Ok(None) => (),
+            Ok(Some(reason)) => validation.errors.push(
+                SubgraphRegistrarError::DeploymentBlocked(hash.to_string(), reason).to_string(),
+            ),
+            Err(e) => return Box::new(future::err(e.into())),
+        }
+
+        let store = self.store.clone();
+        let chain_stores = self.chain_stores.clone();
+        let ethereum_adapters = self.ethereum_adapters.clone();
+        let logger = self.logger_factory.subgraph_logger(&hash);
+
+        Box::new(
+            UnvalidatedSubgraphManifest::resolve(
+                hash.to_ipfs_link(),
+                Arc::new(
+                    self.resolver
+                        .as_ref()
+                        .clone()
+                        .for_deployment(hash.clone())
+                        .with_retry_policy(IPFS_RETRY_POLICIES.for_deployment(&hash)),
+                ),
+                logger.clone(),
+            )
+            .then(move |unvalidated| {
+                // Problems with the manifest are part of the validation
+                // result rather than errors of the request
+                let unvalidated = match unvalidated {
+                    Ok(unvalidated) => unvalidated,
+                    Err(e) => {
+                        validation
+                            .errors
+                            .push(SubgraphRegistrarError::ResolveError(e).to_string());
+                        return Box::new(future::ok(validation))
+                            as Box<dyn Future<Item = _, Error = SubgraphRegistrarError> + Send>;
+                    }
+                };
+                let manifest = match unvalidated.validate(store) {
+                    Ok((manifest, warnings)) => {
+                        validation
+                            .warnings
+                            .extend(warnings.into_iter().map(|warning| warning.to_string()));
+                        manifest
+                    }
+                    Err(errors) => {
+                        validation
+                            .errors
+                            .extend(errors.into_iter().map(|error| error.to_string()));
+                        return Box::new(future::ok(validation));
+                    }
+                };
+
+                let network_name = manifest.network_name();
+                validation.network = Some(network_name.clone());
+                let (chain_store, ethereum_adapter) = match (
+                    chain_stores.get(&network_name),
+                    ethereum_adapters.get(&network_name),
+                ) {
+                    (Some(chain_store), Some(ethereum_adapter)) => {
+                        (chain_store.clone(), ethereum_adapter.clone())
+                    }
+                    _ => {
+                        validation.errors.push(
+                            SubgraphRegistrarError::NetworkNotSupported(network_name).to_string(),
+                        );
+                        return Box::new(future::ok(validation));
+                    }
+                };
+
+                // Deploying the subgraph also needs the blocks at which it
+                // starts and at which it is grafted to exist on the network
+                let graft_block = resolve_graft_block(
+                    &manifest,
+                    chain_store.clone(),
+                    ethereum_adapter.clone(),
+                    &logger,
+                )
+                .then(Ok::<_, SubgraphRegistrarError>);
+                Box::new(
+                    resolve_subgraph_chain_blocks(manifest, chain_store, ethereum_adapter, &logger)
+                        .then(Ok::<_, SubgraphRegistrarError>)
+                        .join(graft_block)
+                        .map(move |(start_blocks, graft_block)| {
+                            for result in vec![start_blocks.map(|_| ()), graft_block.map(|_| ())] {
+                                match result {
+                                    Ok(()) => (),
+                                    Err(SubgraphRegistrarError::ManifestValidationError(
+                                        errors,
+                                    )) => validation
+                                        .errors
+                                        .extend(errors.into_iter().map(|error| error.to_string())),
+                                    Err(e) => validation.errors.push(e.to_string()),
+                                }
+                            }
+                            validation
+                        }),
+                )
             }),
         )
     }
@@ -398,725 +744,2703 @@ where
             node_id,
         )))
     }
-}
-
-fn handle_assignment_event<P>(
-    event: AssignmentEvent,
-    provider: Arc<P>,
-    logger: &Logger,
-) -> Box<dyn Future<Item = (), Error = CancelableError<SubgraphAssignmentProviderError>> + Send>
-where
-    P: SubgraphAssignmentProviderTrait,
-{
-    let logger = logger.to_owned();
 
-    debug!(logger, "Received assignment event: {:?}", event);
+    fn rebalance_assignments(
+        &self,
+        node_ids: Option<Vec<NodeId>>,
+    ) -> Box<dyn Future<Item = Vec<AssignmentMove>, Error = SubgraphRegistrarError> + Send + 'static>
+    {
+        let node_ids = node_ids.unwrap_or_else(|| REBALANCE_NODES.clone());
+        Box::new(future::result(rebalance::rebalance(
+            &self.logger,
+            &*self.store,
+            &node_ids,
+            *REBALANCE_MAX_MOVES,
+        )))
+    }
 
-    match event {
-        AssignmentEvent::Add {
-            subgraph_id,
-            node_id: _,
-        } => Box::new(start_subgraph(subgraph_id, &*provider, logger).map_err(|()| unreachable!())),
-        AssignmentEvent::Remove {
-            subgraph_id,
-            node_id: _,
-        } => Box::new(
-            provider
-                .stop(subgraph_id)
-                .then(|result| match result {
-                    Ok(()) => Ok(()),
-                    Err(SubgraphAssignmentProviderError::NotRunning(_)) => Ok(()),
-                    Err(e) => Err(e),
-                })
-                .map_err(CancelableError::Error),
-        ),
+    fn pin_deployment(
+        &self,
+        hash: SubgraphDeploymentId,
+        node_id: Option<NodeId>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(pin_deployment(&*self.store, hash, node_id)))
     }
-}
 
-// Never errors.
-fn start_subgraph<P: SubgraphAssignmentProviderTrait>(
-    subgraph_id: SubgraphDeploymentId,
-    provider: &P,
-    logger: Logger,
-) -> impl Future<Item = (), Error = ()> + 'static {
-    trace!(
-        logger,
-        "Start subgraph";
-        "subgraph_id" => subgraph_id.to_string()
-    );
+    fn assign_standby(
+        &self,
+        hash: SubgraphDeploymentId,
+        node_id: Option<NodeId>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(assign_standby(
+            self.store.clone(),
+            hash,
+            node_id,
+        )))
+    }
 
-    let start_time = Instant::now();
-    provider
-        .start(subgraph_id.clone())
-        .then(move |result| -> Result<(), _> {
-            debug!(
-                logger,
-                "Subgraph started";
-                "subgraph_id" => subgraph_id.to_string(),
-                "start_ms" => start_time.elapsed().as_millis()
-            );
+    fn promote_standby(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(promote_standby(&*self.store, hash)))
+    }
 
-            match result {
-                Ok(()) => Ok(()),
-                Err(SubgraphAssignmentProviderError::AlreadyRunning(_)) => Ok(()),
-                Err(e) => {
-                    // Errors here are likely an issue with the subgraph.
-                    error!(
-                        logger,
-                        "Subgraph instance failed to start";
-                        "error" => e.to_string(),
-                        "subgraph_id" => subgraph_id.to_string()
-                    );
-                    Ok(())
-                }
-            }
-        })
-}
+    fn block_deployment(
+        &self,
+        hash: SubgraphDeploymentId,
+        reason: String,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(block_deployment(
+            &self.logger,
+            &*self.store,
+            hash,
+            reason,
+        )))
+    }
 
-fn create_subgraph(
-    logger: &Logger,
-    store: Arc<impl Store>,
-    name: SubgraphName,
-) -> Result<CreateSubgraphResult, SubgraphRegistrarError> {
-    let mut ops = vec![];
+    fn unblock_deployment(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(unblock_deployment(
+            &self.logger,
+            &*self.store,
+            hash,
+        )))
+    }
 
-    // Check if this subgraph already exists
-    let subgraph_entity_opt = store.find_one(
-        SubgraphEntity::query().filter(EntityFilter::new_equal("name", name.to_string())),
-    )?;
-    if subgraph_entity_opt.is_some() {
-        debug!(
-            logger,
-            "Subgraph name already exists: {:?}",
-            name.to_string()
-        );
-        return Err(SubgraphRegistrarError::NameExists(name.to_string()));
+    fn pause_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        let store = self.store.clone();
+        Box::new(
+            self.provider
+                .pause(hash.clone())
+                .map_err(SubgraphRegistrarError::AssignmentProviderError)
+                .and_then(move |()| {
+                    store
+                        .apply_metadata_operations(
+                            SubgraphDeploymentAssignmentEntity::paused_operations(&hash, true),
+                        )
+                        .map_err(SubgraphRegistrarError::from)
+                }),
+        )
     }
 
-    ops.push(MetadataOperation::AbortUnless {
-        description: "Subgraph entity should not exist".to_owned(),
-        query: SubgraphEntity::query().filter(EntityFilter::new_equal("name", name.to_string())),
-        entity_ids: vec![],
-    });
+    fn resume_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        let store = self.store.clone();
+        Box::new(
+            self.provider
+                .resume(hash.clone())
+                .map_err(SubgraphRegistrarError::AssignmentProviderError)
+                .and_then(move |()| {
+                    store
+                        .apply_metadata_operations(
+                            SubgraphDeploymentAssignmentEntity::paused_operations(&hash, false),
+                        )
+                        .map_err(SubgraphRegistrarError::from)
+                }),
+        )
+    }
 
-    let created_at = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+    fn wake_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(wake_subgraph(
+            &self.logger,
+            &*self.store,
+            hash,
+        )))
+    }
+
+    fn rewind_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        if let Err(e) = check_rewind_assignment(&*self.store, &self.node_id, &hash) {
+            return Box::new(future::err(e));
+        }
+
+        Box::new(
+            self.provider
+                .rewind(hash, block)
+                .map_err(SubgraphRegistrarError::AssignmentProviderError),
+        )
+    }
+
+    fn copy_subgraph(
+        &self,
+        source: SubgraphDeploymentId,
+        target: SubgraphDeploymentId,
+        node_id: NodeId,
+        tablespace: Option<String>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        match deployment_block_reason(&*self.store, &target) {
+            Ok(None) => (),
+            Ok(Some(reason)) => {
+                return Box::new(future::err(SubgraphRegistrarError::DeploymentBlocked(
+                    target.to_string(),
+                    reason,
+                )))
+            }
+            Err(e) => return Box::new(future::err(e.into())),
+        }
+
+        let store = self.store.clone();
+        let store_for_validation = self.store.clone();
+        let logger = self.logger_factory.subgraph_logger(&target);
+        let logger_for_copy = logger.clone();
+
+        Box::new(
+            UnvalidatedSubgraphManifest::resolve(
+                target.to_ipfs_link(),
+                Arc::new(
+                    self.resolver
+                        .as_ref()
+                        .clone()
+                        .for_deployment(target.clone())
+                        .with_retry_policy(IPFS_RETRY_POLICIES.for_deployment(&target)),
+                ),
+                logger,
+            )
+            .map_err(SubgraphRegistrarError::ResolveError)
+            .and_then(move |unvalidated| {
+                unvalidated
+                    .validate(store_for_validation)
+                    .map_err(SubgraphRegistrarError::ManifestValidationError)
+            })
+            .and_then(move |(manifest, _)| {
+                copy_subgraph(
+                    &logger_for_copy,
+                    &*store,
+                    source,
+                    manifest,
+                    node_id,
+                    tablespace,
+                )
+            }),
+        )
+    }
+
+    fn annotate(
+        &self,
+        hash: SubgraphDeploymentId,
+        entity_type: String,
+        entity_id: String,
+        data: Option<HashMap<String, serde_json::Value>>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(annotate(
+            &self.logger,
+            &*self.store,
+            hash,
+            entity_type,
+            entity_id,
+            data,
+        )))
+    }
+
+    fn create_missing_indexes(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = Vec<IndexCreation>, Error = SubgraphRegistrarError> + Send + 'static>
+    {
+        Box::new(future::result(create_missing_indexes(
+            &self.logger,
+            &*self.store,
+            hash,
+        )))
+    }
+
+    fn oversized_entity_ids(
+        &self,
+        hash: SubgraphDeploymentId,
+        max_length: usize,
+    ) -> Box<
+        dyn Future<Item = Vec<OversizedEntityIds>, Error = SubgraphRegistrarError> + Send + 'static,
+    > {
+        let store = self.store.clone();
+
+        // The report scans every entity table of the deployment, which can
+        // take a long time
+        Box::new(
+            graph::spawn_blocking_allow_panic(async move {
+                oversized_entity_ids(&*store, hash, max_length)
+            })
+            .compat()
+            .map_err(|e| SubgraphRegistrarError::Unknown(e.into()))
+            .and_then(future::result),
+        )
+    }
+
+    fn retry_index_creation(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(retry_index_creation(
+            &self.logger,
+            self.store.clone(),
+            self.index_retries.clone(),
+            hash,
+        )))
+    }
+
+    fn migrate_storage(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(migrate_storage(
+            &self.logger,
+            &*self.store,
+            hash,
+        )))
+    }
+
+    fn set_deployment_quota(
+        &self,
+        hash: SubgraphDeploymentId,
+        soft_limit: Option<u64>,
+        hard_limit: Option<u64>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(set_deployment_quota(
+            &self.logger,
+            &*self.store,
+            hash,
+            soft_limit,
+            hard_limit,
+        )))
+    }
+
+    fn schedule_deployment_removal(
+        &self,
+        hash: SubgraphDeploymentId,
+        at_block: Option<u64>,
+        at_time: Option<u64>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(schedule_deployment_removal(
+            &self.logger,
+            &*self.store,
+            hash,
+            at_block,
+            at_time,
+        )))
+    }
+
+    fn record_admin_operation(
+        &self,
+        operation: String,
+        actor: Option<String>,
+        parameters: String,
+        error: Option<String>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static> {
+        Box::new(future::result(record_admin_operation(
+            &*self.store,
+            operation,
+            actor,
+            parameters,
+            error,
+        )))
+    }
+}
+
+/// Check the progress of all deployments for which `node_id` is the standby
+/// node. `progress` remembers the latest block of each deployment and when
+/// we first saw the deployment at that block
+fn check_standby_deployments(
+    logger: &Logger,
+    store: &impl Store,
+    node_id: &NodeId,
+    progress: &mut HashMap<SubgraphDeploymentId, (Option<Value>, Instant)>,
+) -> Result<(), Error> {
+    let assignments = store.find(SubgraphDeploymentAssignmentEntity::query().filter(
+        EntityFilter::new_equal("standbyNodeId", node_id.to_string()),
+    ))?;
+
+    let mut watched = HashSet::new();
+    for assignment in assignments {
+        let id = SubgraphDeploymentId::new(assignment.id()?)
+            .map_err(|()| format_err!("Invalid subgraph hash in assignment entity"))?;
+        let deployment = match store.get(SubgraphDeploymentEntity::key(id.clone()))? {
+            Some(deployment) => deployment,
+            None => continue,
+        };
+
+        // A deployment that failed will fail in the same way on this node;
+        // taking it over does not help. A deployment that is pinned to
+        // another node can not be taken over
+        if deployment.get("failed") == Some(&Value::Bool(true)) {
+            continue;
+        }
+        match pinned_node(store, &id)? {
+            Some(pinned) if &pinned != node_id => continue,
+            _ => (),
+        }
+        watched.insert(id.clone());
+
+        let latest = deployment.get("latestEthereumBlockNumber").cloned();
+        let stalled = match progress.get(&id) {
+            Some((block, since)) if block == &latest => {
+                since.elapsed() >= *STANDBY_TAKEOVER_TIMEOUT
+            }
+            _ => {
+                progress.insert(id.clone(), (latest, Instant::now()));
+                false
+            }
+        };
+
+        if stalled {
+            warn!(
+                logger,
+                "Deployment has not made progress, taking over as its standby node";
+                "subgraph_id" => id.to_string(),
+                "assigned_node_id" => assignment
+                    .get("nodeId")
+                    .map(|node| node.to_string())
+                    .unwrap_or_default(),
+                "timeout_s" => STANDBY_TAKEOVER_TIMEOUT.as_secs(),
+            );
+            progress.remove(&id);
+            if let Err(e) = promote_standby(store, id.clone()) {
+                error!(
+                    logger,
+                    "Failed to take over deployment";
+                    "subgraph_id" => id.to_string(),
+                    "error" => e.to_string(),
+                );
+            }
+        }
+    }
+    progress.retain(|id, _| watched.contains(id));
+    Ok(())
+}
+
+/// Start migrating the storage of a deployment assigned to `node_id` for
+/// which a migration to relational storage was requested. Only one
+/// deployment is migrated at a time so that migrations do not slow down
+/// indexing on the node too much
+fn check_storage_migrations<P>(
+    logger: &Logger,
+    store: &(impl Store + SubgraphDeploymentStore),
+    provider: Arc<P>,
+    node_id: &NodeId,
+    running: Arc<Mutex<HashSet<SubgraphDeploymentId>>>,
+) -> Result<(), Error>
+where
+    P: SubgraphAssignmentProviderTrait,
+{
+    if !running.lock().unwrap().is_empty() {
+        return Ok(());
+    }
+
+    for id in store.storage_migrations_requested()? {
+        let assigned_node = store
+            .get(SubgraphDeploymentAssignmentEntity::key(id.clone()))?
+            .and_then(|assignment| assignment.get("nodeId").cloned());
+        if assigned_node != Some(Value::String(node_id.to_string())) {
+            continue;
+        }
+
+        let logger = logger.new(o!("subgraph_id" => id.to_string()));
+        info!(logger, "Start migrating subgraph storage");
+        running.lock().unwrap().insert(id.clone());
+
+        // Blocking due to store interactions. Won't be blocking after #905.
+        graph::spawn_blocking(
+            provider
+                .migrate_storage(id.clone())
+                .then(move |result| {
+                    match result {
+                        Ok(()) => info!(logger, "Finished migrating subgraph storage"),
+                        Err(e) => warn!(logger, "Failed to migrate subgraph storage";
+                                        "error" => e.to_string()),
+                    }
+                    running.lock().unwrap().remove(&id);
+                    Ok::<_, ()>(())
+                })
+                .compat(),
+        );
+        break;
+    }
+    Ok(())
+}
+
+/// Remove the deployments assigned to `node_id` whose scheduled removal has
+/// been due for at least `SCHEDULED_REMOVAL_GRACE_PERIOD`. Until then, warn
+/// on every check that the deployment is about to be removed
+fn check_scheduled_removals(
+    logger: &Logger,
+    store: Arc<impl Store>,
+    node_id: &NodeId,
+) -> Result<(), Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    for removal in store.find(SubgraphDeploymentRemovalEntity::query())? {
+        let id = SubgraphDeploymentId::new(removal.id()?)
+            .map_err(|()| format_err!("Invalid subgraph hash in removal entity"))?;
+        let assigned_node = store
+            .get(SubgraphDeploymentAssignmentEntity::key(id.clone()))?
+            .and_then(|assignment| assignment.get("nodeId").cloned());
+        if assigned_node != Some(Value::String(node_id.to_string())) {
+            continue;
+        }
+
+        let number = |entity: &Entity, name: &str| {
+            entity
+                .get(name)
+                .cloned()
+                .and_then(Value::as_bigint)
+                .map(|number| number.to_u64())
+        };
+        let latest_block = store
+            .get(SubgraphDeploymentEntity::key(id.clone()))?
+            .and_then(|deployment| number(&deployment, "latestEthereumBlockNumber"));
+        let block_reached = match (number(&removal, "atBlock"), latest_block) {
+            (Some(at_block), Some(latest_block)) => latest_block >= at_block,
+            _ => false,
+        };
+        let time_passed = number(&removal, "atTime").map_or(false, |at_time| now >= at_time);
+        if !block_reached && !time_passed {
+            continue;
+        }
+
+        let due_since = match number(&removal, "dueSince") {
+            Some(due_since) => due_since,
+            None => {
+                store.apply_metadata_operations(
+                    SubgraphDeploymentRemovalEntity::update_due_operations(&id, now),
+                )?;
+                now
+            }
+        };
+        let remaining = (due_since + *SCHEDULED_REMOVAL_GRACE_PERIOD).saturating_sub(now);
+        if remaining > 0 {
+            warn!(
+                logger,
+                "Deployment is scheduled for removal";
+                "subgraph_id" => id.to_string(),
+                "remaining_s" => remaining,
+            );
+            continue;
+        }
+
+        warn!(logger, "Removing deployment as scheduled"; "subgraph_id" => id.to_string());
+        if let Err(e) = remove_deployment(logger, store.clone(), &id) {
+            error!(
+                logger,
+                "Failed to remove deployment";
+                "subgraph_id" => id.to_string(),
+                "error" => e.to_string(),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Remove the subgraphs whose versions all use the deployment `id` and
+/// unassign the deployment, which stops indexing it. Subgraphs that also
+/// have versions with other deployments are kept. All of that happens in
+/// one set of metadata operations, so that a failure does not leave the
+/// deployment assigned without the subgraphs that used it
+fn remove_deployment(
+    logger: &Logger,
+    store: Arc<impl Store>,
+    id: &SubgraphDeploymentId,
+) -> Result<(), SubgraphRegistrarError> {
+    let versions = store.find(
+        SubgraphVersionEntity::query()
+            .filter(EntityFilter::new_equal("deployment", id.to_string())),
+    )?;
+    let subgraph_ids = versions
+        .iter()
+        .filter_map(|version| version.get("subgraph").cloned())
+        .filter_map(Value::as_string)
+        .collect::<HashSet<_>>();
+
+    let mut ops = vec![];
+    let mut removed_versions = vec![];
+    let mut removed_subgraphs = vec![];
+    for subgraph_id in subgraph_ids {
+        let other_versions = store.find(SubgraphVersionEntity::query().filter(
+            EntityFilter::And(vec![
+                EntityFilter::new_equal("subgraph", subgraph_id.clone()),
+                EntityFilter::Not("deployment".to_owned(), Value::from(id.to_string())),
+            ]),
+        ))?;
+        let name = store
+            .get(SubgraphEntity::key(subgraph_id))?
+            .and_then(|subgraph| subgraph.get("name").cloned())
+            .and_then(Value::as_string);
+        match name {
+            Some(name) if other_versions.is_empty() => {
+                let name = SubgraphName::new(name.clone())
+                    .map_err(|()| format_err!("Invalid subgraph name `{}`", name))?;
+                let (subgraph, versions, checks) = find_subgraph_for_removal(&*store, &name)?;
+                ops.extend(checks);
+                removed_versions.extend(versions);
+                removed_subgraphs.push((subgraph.id()?, name));
+            }
+            Some(name) => warn!(
+                logger,
+                "Keeping subgraph that has versions with other deployments";
+                "subgraph_name" => name,
+                "subgraph_id" => id.to_string(),
+            ),
+            None => (),
+        }
+    }
+
+    // Removing the versions of all subgraphs together removes the
+    // deployment and its assignment if no other subgraph uses it
+    if !removed_versions.is_empty() {
+        ops.extend(remove_subgraph_versions(
+            logger,
+            store.clone(),
+            removed_versions,
+        )?);
+    }
+    ops.extend(
+        removed_subgraphs
+            .iter()
+            .map(|(subgraph_id, _)| MetadataOperation::Remove {
+                entity: SubgraphEntity::TYPENAME.to_owned(),
+                id: subgraph_id.clone(),
+            }),
+    );
+
+    let assignment_removed = ops.iter().any(|op| match op {
+        MetadataOperation::Remove {
+            entity,
+            id: removed,
+        } => entity == SubgraphDeploymentAssignmentEntity::TYPENAME && removed == &id.to_string(),
+        _ => false,
+    });
+    if !assignment_removed {
+        ops.push(MetadataOperation::Remove {
+            entity: SubgraphDeploymentAssignmentEntity::TYPENAME.to_owned(),
+            id: id.to_string(),
+        });
+    }
+    ops.push(MetadataOperation::Remove {
+        entity: SubgraphDeploymentRemovalEntity::TYPENAME.to_owned(),
+        id: id.to_string(),
+    });
+    store.apply_metadata_operations(ops)?;
+
+    for (_, name) in removed_subgraphs {
+        debug!(logger, "Removed subgraph"; "subgraph_name" => name.to_string());
+    }
+    Ok(())
+}
+
+/// The assignment event for a change to the blocklist entry of the
+/// deployment `hash`: a deployment that this node runs is stopped when it
+/// is blocked and started again when it is unblocked. Changes for
+/// deployments assigned to other nodes are ignored
+fn block_change_event(
+    store: &impl Store,
+    node_id: &NodeId,
+    hash: SubgraphDeploymentId,
+    operation: EntityChangeOperation,
+) -> Result<Option<AssignmentEvent>, Error> {
+    let assigned_here = store
+        .get(SubgraphDeploymentAssignmentEntity::key(hash.clone()))
+        .map_err(|e| format_err!("Failed to get subgraph assignment entity: {}", e))?
+        .map_or(false, |assignment| {
+            assignment.get("nodeId") == Some(&node_id.to_string().into())
+        });
+    if !assigned_here {
+        return Ok(None);
+    }
+
+    let node_id = node_id.clone();
+    Ok(Some(match operation {
+        EntityChangeOperation::Set => AssignmentEvent::Remove {
+            subgraph_id: hash,
+            node_id,
+        },
+        EntityChangeOperation::Removed => AssignmentEvent::Add {
+            subgraph_id: hash,
+            node_id,
+        },
+    }))
+}
+
+fn handle_assignment_event<P, S>(
+    event: AssignmentEvent,
+    provider: Arc<P>,
+    store: Arc<S>,
+    logger: &Logger,
+) -> Box<dyn Future<Item = (), Error = CancelableError<SubgraphAssignmentProviderError>> + Send>
+where
+    P: SubgraphAssignmentProviderTrait,
+    S: Store,
+{
+    let logger = logger.to_owned();
+
+    debug!(logger, "Received assignment event: {:?}", event);
+
+    match event {
+        AssignmentEvent::Add {
+            subgraph_id,
+            node_id: _,
+        } => {
+            // Hibernated deployments stay stopped until they are woken up
+            if deployment_hibernated(&*store, &subgraph_id).unwrap_or(false) {
+                info!(logger, "Not starting hibernated subgraph";
+                      "subgraph_id" => subgraph_id.to_string());
+                return Box::new(future::ok(()));
+            }
+            if let Ok(Some(reason)) = deployment_block_reason(&*store, &subgraph_id) {
+                info!(logger, "Not starting blocked subgraph";
+                      "subgraph_id" => subgraph_id.to_string(),
+                      "reason" => reason);
+                return Box::new(future::ok(()));
+            }
+            Box::new(start_subgraph(subgraph_id, &*provider, logger).map_err(|()| unreachable!()))
+        }
+        AssignmentEvent::Remove {
+            subgraph_id,
+            node_id: _,
+        } => Box::new(
+            provider
+                .stop(subgraph_id)
+                .then(|result| match result {
+                    Ok(()) => Ok(()),
+                    Err(SubgraphAssignmentProviderError::NotRunning(_)) => Ok(()),
+                    Err(e) => Err(e),
+                })
+                .map_err(CancelableError::Error),
+        ),
+    }
+}
+
+/// Start each of the deployments `ids` with `start` in its own task, at
+/// most `concurrency` of them at once. The returned future completes once
+/// all of them have started
+fn start_concurrently<F, R>(
+    ids: impl IntoIterator<Item = SubgraphDeploymentId>,
+    concurrency: usize,
+    start: F,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Fn(SubgraphDeploymentId) -> R,
+    R: Future<Item = (), Error = ()> + Send + 'static,
+{
+    stream::iter_ok::<_, ()>(ids)
+        .map(move |id| {
+            let (done, started) = futures::sync::oneshot::channel::<()>();
+
+            // Blocking due to store interactions. Won't be blocking after #905.
+            graph::spawn_blocking(
+                start(id)
+                    .map(move |()| drop(done))
+                    .map_err(|()| unreachable!())
+                    .compat(),
+            );
+            started.then(|_| Ok(()))
+        })
+        .buffer_unordered(concurrency.max(1))
+        .for_each(|()| Ok(()))
+}
+
+// Never errors.
+fn start_subgraph<P: SubgraphAssignmentProviderTrait>(
+    subgraph_id: SubgraphDeploymentId,
+    provider: &P,
+    logger: Logger,
+) -> impl Future<Item = (), Error = ()> + 'static {
+    trace!(
+        logger,
+        "Start subgraph";
+        "subgraph_id" => subgraph_id.to_string()
+    );
+
+    let start_time = Instant::now();
+    provider
+        .start(subgraph_id.clone())
+        .then(move |result| -> Result<(), _> {
+            debug!(
+                logger,
+                "Subgraph started";
+                "subgraph_id" => subgraph_id.to_string(),
+                "start_ms" => start_time.elapsed().as_millis()
+            );
+
+            match result {
+                Ok(()) => Ok(()),
+                Err(SubgraphAssignmentProviderError::AlreadyRunning(_))
+                | Err(SubgraphAssignmentProviderError::Canceled(_)) => Ok(()),
+                Err(e) => {
+                    // Errors here are likely an issue with the subgraph.
+                    error!(
+                        logger,
+                        "Subgraph instance failed to start";
+                        "error" => e.to_string(),
+                        "subgraph_id" => subgraph_id.to_string()
+                    );
+                    Ok(())
+                }
+            }
+        })
+}
+
+fn create_subgraph(
+    logger: &Logger,
+    store: Arc<impl Store>,
+    name: SubgraphName,
+) -> Result<CreateSubgraphResult, SubgraphRegistrarError> {
+    let mut ops = vec![];
+
+    // Check if this subgraph already exists
+    let subgraph_entity_opt = store.find_one(
+        SubgraphEntity::query().filter(EntityFilter::new_equal("name", name.to_string())),
+    )?;
+    if subgraph_entity_opt.is_some() {
+        debug!(
+            logger,
+            "Subgraph name already exists: {:?}",
+            name.to_string()
+        );
+        return Err(SubgraphRegistrarError::NameExists(name.to_string()));
+    }
+
+    ops.push(MetadataOperation::AbortUnless {
+        description: "Subgraph entity should not exist".to_owned(),
+        query: SubgraphEntity::query().filter(EntityFilter::new_equal("name", name.to_string())),
+        entity_ids: vec![],
+    });
+
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
     let entity = SubgraphEntity::new(name.clone(), None, None, created_at);
     let entity_id = generate_entity_id();
     ops.extend(
-        entity
-            .write_operations(&entity_id)
+        entity
+            .write_operations(&entity_id)
+            .into_iter()
+            .map(|op| op.into()),
+    );
+
+    store.apply_metadata_operations(ops)?;
+
+    debug!(logger, "Created subgraph"; "subgraph_name" => name.to_string());
+
+    Ok(CreateSubgraphResult { id: entity_id })
+}
+
+/// Resolves the chain head block and the subgraph's earliest block
+fn resolve_subgraph_chain_blocks(
+    manifest: SubgraphManifest,
+    chain_store: Arc<impl ChainStore>,
+    ethereum_adapter: Arc<dyn EthereumAdapter>,
+    logger: &Logger,
+) -> Box<
+    dyn Future<
+            Item = (Option<EthereumBlockPointer>, Option<EthereumBlockPointer>),
+            Error = SubgraphRegistrarError,
+        > + Send,
+> {
+    Box::new(
+        // If the minimum start block is 0 (i.e. the genesis block),
+        // return `None` to start indexing from the genesis block. Otherwise
+        // return a block pointer for the block with number `min_start_block - 1`.
+        match manifest
+            .start_blocks()
+            .into_iter()
+            .min()
+            .expect("cannot identify minimum start block because there are no data sources")
+        {
+            0 => Box::new(future::ok(None)) as Box<dyn Future<Item = _, Error = _> + Send>,
+            min_start_block => Box::new(
+                ethereum_adapter
+                    .block_pointer_from_number(logger, chain_store.clone(), min_start_block - 1)
+                    .map(Some)
+                    .map_err(move |_| {
+                        SubgraphRegistrarError::ManifestValidationError(vec![
+                            SubgraphManifestValidationError::BlockNotFound(
+                                min_start_block.to_string(),
+                            ),
+                        ])
+                    }),
+            ) as Box<dyn Future<Item = _, Error = _> + Send>,
+        }
+        .and_then(move |start_block_ptr| {
+            chain_store
+                .chain_head_ptr()
+                .map(|chain_head_block_ptr| (chain_head_block_ptr, start_block_ptr))
+                .map_err(SubgraphRegistrarError::Unknown)
+        }),
+    )
+}
+
+/// Resolve the block at which the subgraph is grafted onto its base to a
+/// block pointer. Returns `None` if the subgraph is not grafted
+fn resolve_graft_block(
+    manifest: &SubgraphManifest,
+    chain_store: Arc<impl ChainStore>,
+    ethereum_adapter: Arc<dyn EthereumAdapter>,
+    logger: &Logger,
+) -> Box<
+    dyn Future<
+            Item = Option<(SubgraphDeploymentId, EthereumBlockPointer)>,
+            Error = SubgraphRegistrarError,
+        > + Send,
+> {
+    let graft = match &manifest.graft {
+        Some(graft) => graft.clone(),
+        None => return Box::new(future::ok(None)),
+    };
+    Box::new(
+        ethereum_adapter
+            .block_pointer_from_number(logger, chain_store, graft.block)
+            .map(move |block| Some((graft.base, block)))
+            .map_err(move |_| {
+                SubgraphRegistrarError::ManifestValidationError(vec![
+                    SubgraphManifestValidationError::BlockNotFound(graft.block.to_string()),
+                ])
+            }),
+    )
+}
+
+struct SubraphVersionUpdatingMetadata {
+    subgraph_entity_id: String,
+    version_entity_id: String,
+    current_is_synced: bool,
+    current_version_id_opt: Option<String>,
+    pending_version_id_opt: Option<String>,
+    version_summaries_before: Vec<SubgraphVersionSummary>,
+    version_summaries_after: Vec<SubgraphVersionSummary>,
+    read_summaries_ops: Vec<MetadataOperation>,
+}
+
+fn get_version_ids_and_summaries(
+    logger: Logger,
+    store: Arc<impl Store>,
+    name: String,
+    manifest_id: SubgraphDeploymentId,
+    version_switching_mode: SubgraphVersionSwitchingMode,
+) -> Result<SubraphVersionUpdatingMetadata, SubgraphRegistrarError> {
+    let name = name.clone();
+    // Look up subgraph entity by name
+    let subgraph_entity_opt = store
+        .find_one(SubgraphEntity::query().filter(EntityFilter::new_equal("name", name.clone())))?;
+    let subgraph_entity = subgraph_entity_opt.ok_or_else(|| {
+        debug!(
+            logger,
+            "Subgraph not found, could not create_subgraph_version";
+            "subgraph_name" => name.to_string()
+        );
+        SubgraphRegistrarError::NameNotFound(name)
+    })?;
+    let subgraph_entity_id = subgraph_entity.id()?;
+    let current_version_id_opt = match subgraph_entity.get("currentVersion") {
+        Some(Value::String(current_version_id)) => Some(current_version_id.to_owned()),
+        Some(Value::Null) => None,
+        None => None,
+        Some(_) => panic!("subgraph entity has invalid type in currentVersion field"),
+    };
+    let pending_version_id_opt = match subgraph_entity.get("pendingVersion") {
+        Some(Value::String(pending_version_id)) => Some(pending_version_id.to_owned()),
+        Some(Value::Null) => None,
+        None => None,
+        Some(_) => panic!("subgraph entity has invalid type in pendingVersion field"),
+    };
+
+    let store = store.clone();
+    // Look up current version's deployment hash
+    let current_version_hash_opt = match current_version_id_opt {
+        Some(ref current_version_id) => Some(get_subgraph_version_deployment_id(
+            store.clone(),
+            current_version_id.clone(),
+        )?),
+        None => None,
+    };
+
+    // Look up pending version's deployment hash
+    let pending_version_hash_opt = match pending_version_id_opt {
+        Some(ref pending_version_id) => Some(get_subgraph_version_deployment_id(
+            store.clone(),
+            pending_version_id.clone(),
+        )?),
+        None => None,
+    };
+
+    // See if current version is fully synced
+    let current_is_synced = match &current_version_hash_opt {
+        Some(hash) => store.is_deployment_synced(hash.to_owned())?,
+        None => false,
+    };
+
+    // Find all subgraph version entities that point to this hash or a hash
+    let (version_summaries_before, read_summaries_ops) = store.read_subgraph_version_summaries(
+        iter::once(manifest_id.clone())
+            .chain(current_version_hash_opt)
+            .chain(pending_version_hash_opt)
+            .collect(),
+    )?;
+
+    let version_entity_id = generate_entity_id();
+
+    // Simulate the creation of the new version and updating of Subgraph.pending/current
+    let version_summaries_after = match version_switching_mode {
+        SubgraphVersionSwitchingMode::Instant => {
+            // Previously pending or current versions will no longer be pending/current
+            let mut version_summaries_after = version_summaries_before
+                .clone()
+                .into_iter()
+                .map(|mut version_summary| {
+                    if Some(&version_summary.id) == pending_version_id_opt.as_ref() {
+                        version_summary.pending = false;
+                    }
+
+                    if Some(&version_summary.id) == current_version_id_opt.as_ref() {
+                        version_summary.current = false;
+                    }
+
+                    version_summary
+                })
+                .collect::<Vec<_>>();
+
+            // Add new version, which will immediately be current
+            version_summaries_after.push(SubgraphVersionSummary {
+                id: version_entity_id.clone(),
+                subgraph_id: subgraph_entity_id.clone(),
+                deployment_id: manifest_id.clone(),
+                pending: false,
+                current: true,
+            });
+
+            version_summaries_after
+        }
+        SubgraphVersionSwitchingMode::Synced => {
+            // There is a current version. Depending on whether it is synced
+            // or not, make the new version the pending or the current version
+            if current_version_id_opt.is_some() {
+                // Previous pending version (if there was one) is no longer pending
+                // Previous current version if it's not fully synced is no
+                // longer the current version
+                let mut version_summaries_after = version_summaries_before
+                    .clone()
+                    .into_iter()
+                    .map(|mut version_summary| {
+                        if Some(&version_summary.id) == pending_version_id_opt.as_ref() {
+                            version_summary.pending = false;
+                        }
+                        if !current_is_synced
+                            && Some(&version_summary.id) == current_version_id_opt.as_ref()
+                        {
+                            // We will make the new version the current version
+                            version_summary.current = false;
+                        }
+
+                        version_summary
+                    })
+                    .collect::<Vec<_>>();
+
+                // Determine if this new version should be the pending or
+                // current version. When we add a version to a subgraph that
+                // already has a current version, the new version becomes the
+                // pending version if the current version is synced,
+                // and replaces the current version if the current version is still syncing
+                let (pending, current) = if current_is_synced {
+                    (true, false)
+                } else {
+                    (false, true)
+                };
+
+                // Add new version, setting pending and current appropriately
+                version_summaries_after.push(SubgraphVersionSummary {
+                    id: version_entity_id.clone(),
+                    subgraph_id: subgraph_entity_id.clone(),
+                    deployment_id: manifest_id.clone(),
+                    pending,
+                    current,
+                });
+
+                version_summaries_after
+            } else {
+                // No need to process list, as there is no current version
+                let mut version_summaries_after = version_summaries_before.clone();
+
+                // Add new version, which will immediately be current
+                version_summaries_after.push(SubgraphVersionSummary {
+                    id: version_entity_id.clone(),
+                    subgraph_id: subgraph_entity_id.clone(),
+                    deployment_id: manifest_id.clone(),
+                    pending: false,
+                    current: true,
+                });
+
+                version_summaries_after
+            }
+        }
+    };
+    Ok(SubraphVersionUpdatingMetadata {
+        subgraph_entity_id,
+        version_entity_id,
+        current_is_synced,
+        current_version_id_opt,
+        pending_version_id_opt,
+        version_summaries_before,
+        version_summaries_after,
+        read_summaries_ops,
+    })
+}
+
+fn create_subgraph_version(
+    logger: &Logger,
+    store: Arc<impl Store>,
+    chain_store: Arc<impl ChainStore>,
+    ethereum_adapter: Arc<dyn EthereumAdapter>,
+    name: SubgraphName,
+    manifest: SubgraphManifest,
+    node_id: NodeId,
+    version_switching_mode: SubgraphVersionSwitchingMode,
+) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send> {
+    let logger = logger.clone();
+    let manifest = manifest.clone();
+    let manifest_id = manifest.id.clone();
+    let store = store.clone();
+    let deployment_store = store.clone();
+
+    Box::new(
+        future::result(get_version_ids_and_summaries(
+            logger.clone(),
+            store.clone(),
+            name.to_string(),
+            manifest_id.clone(),
+            version_switching_mode,
+        ))
+        .and_then(move |subgraph_version_data| {
+            let mut ops = vec![];
+            ops.push(MetadataOperation::AbortUnless {
+                description:
+                    "Subgraph entity must still exist, have same name/currentVersion/pendingVersion"
+                        .to_owned(),
+                query: SubgraphEntity::query().filter(EntityFilter::And(vec![
+                    EntityFilter::new_equal("name", name.to_string()),
+                    EntityFilter::new_equal(
+                        "currentVersion",
+                        subgraph_version_data.current_version_id_opt.clone(),
+                    ),
+                    EntityFilter::new_equal(
+                        "pendingVersion",
+                        subgraph_version_data.pending_version_id_opt.clone(),
+                    ),
+                ])),
+                entity_ids: vec![subgraph_version_data.subgraph_entity_id.clone()],
+            });
+
+            // Create the subgraph version entity
+            let created_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            ops.extend(subgraph_version_data.read_summaries_ops);
+            ops.extend(
+                SubgraphVersionEntity::new(
+                    subgraph_version_data.subgraph_entity_id.clone(),
+                    manifest_id.clone(),
+                    created_at,
+                )
+                .write_operations(&subgraph_version_data.version_entity_id)
+                .into_iter()
+                .map(|op| op.into()),
+            );
+
+            // Possibly add assignment for new deployment hash, and possibly remove
+            // assignments for old current/pending
+            ops.extend(
+                store
+                    .reconcile_assignments(
+                        &logger.clone(),
+                        subgraph_version_data.version_summaries_before,
+                        subgraph_version_data.version_summaries_after,
+                        Some(node_id),
+                    )
+                    .into_iter()
+                    .map(|op| op.into()),
+            );
+
+            // Update current/pending versions in Subgraph entity
+            match version_switching_mode {
+                SubgraphVersionSwitchingMode::Instant => {
+                    ops.extend(SubgraphEntity::update_pending_version_operations(
+                        &subgraph_version_data.subgraph_entity_id,
+                        None,
+                    ));
+                    ops.extend(SubgraphEntity::update_current_version_operations(
+                        &subgraph_version_data.subgraph_entity_id,
+                        Some(subgraph_version_data.version_entity_id),
+                    ));
+                }
+                SubgraphVersionSwitchingMode::Synced => {
+                    // Set the new version as pending, unless there is no fully synced
+                    // current versions
+                    if subgraph_version_data.current_is_synced {
+                        ops.extend(SubgraphEntity::update_pending_version_operations(
+                            &subgraph_version_data.subgraph_entity_id,
+                            Some(subgraph_version_data.version_entity_id),
+                        ));
+                    } else {
+                        ops.extend(SubgraphEntity::update_pending_version_operations(
+                            &subgraph_version_data.subgraph_entity_id,
+                            None,
+                        ));
+                        ops.extend(SubgraphEntity::update_current_version_operations(
+                            &subgraph_version_data.subgraph_entity_id,
+                            Some(subgraph_version_data.version_entity_id),
+                        ));
+                    }
+                }
+            }
+
+            // Check if deployment exists
+            store
+                .get(SubgraphDeploymentEntity::key(manifest_id.clone()))
+                .map_err(|e| SubgraphRegistrarError::QueryExecutionError(e))
+                .map(|a| (logger, ops, a.is_some()))
+        })
+        .and_then(move |(logger, mut ops, deployment_exists)| {
+            ops.push(MetadataOperation::AbortUnless {
+                description: "Subgraph deployment entity must continue to exist/not exist"
+                    .to_owned(),
+                query: SubgraphDeploymentEntity::query()
+                    .filter(EntityFilter::new_equal("id", manifest.id.to_string())),
+                entity_ids: if deployment_exists {
+                    vec![manifest.id.to_string()]
+                } else {
+                    vec![]
+                },
+            });
+
+            let logger_for_graft = logger.clone();
+            let manifest_for_graft = manifest.clone();
+            resolve_subgraph_chain_blocks(
+                manifest.clone(),
+                chain_store.clone(),
+                ethereum_adapter.clone(),
+                &logger.clone(),
+            )
+            .and_then(move |(chain_head_block, start_block)| {
+                resolve_graft_block(
+                    &manifest_for_graft,
+                    chain_store,
+                    ethereum_adapter,
+                    &logger_for_graft,
+                )
+                .map(move |graft| (chain_head_block, start_block, graft))
+            })
+            .and_then(move |(chain_head_block, start_block, graft)| {
+                info!(
+                    logger,
+                    "Set subgraph start block";
+                    "block_number" => format!("{:?}", start_block.map(|block| block.number)),
+                    "block_hash" => format!("{:?}", start_block.map(|block| block.hash)),
+                );
+                if let Some((base, block)) = &graft {
+                    info!(
+                        logger,
+                        "Graft subgraph onto base deployment";
+                        "base" => base.to_string(),
+                        "block_number" => block.number,
+                        "block_hash" => format!("{:?}", block.hash),
+                    );
+                }
+
+                // Apply the subgraph versioning and deployment operations,
+                // creating a new subgraph deployment if one doesn't exist.
+                if deployment_exists {
+                    deployment_store
+                        .apply_metadata_operations(ops)
+                        .map_err(|e| SubgraphRegistrarError::SubgraphDeploymentError(e))
+                } else {
+                    let mut deployment = SubgraphDeploymentEntity::new(
+                        &manifest,
+                        false,
+                        false,
+                        start_block,
+                        chain_head_block,
+                    );
+                    if let Some((base, block)) = graft {
+                        deployment = deployment.graft(base, block);
+                    }
+                    ops.extend(deployment.create_operations(&manifest.id));
+                    deployment_store
+                        .create_subgraph_deployment(&manifest.schema, ops)
+                        .map_err(|e| SubgraphRegistrarError::SubgraphDeploymentError(e))
+                }
+            })
+        }),
+    )
+}
+
+fn get_subgraph_version_deployment_id(
+    store: Arc<impl Store>,
+    version_id: String,
+) -> Result<SubgraphDeploymentId, SubgraphRegistrarError> {
+    let version_entity = store
+        .get(SubgraphVersionEntity::key(version_id))?
+        .ok_or_else(|| TransactionAbortError::Other(format!("Subgraph version entity missing")))
+        .map_err(StoreError::from)?;
+
+    Ok(SubgraphDeploymentId::new(
+        version_entity
+            .get("deployment")
+            .unwrap()
+            .to_owned()
+            .as_string()
+            .unwrap(),
+    )
+    .unwrap())
+}
+
+fn remove_subgraph(
+    logger: &Logger,
+    store: Arc<impl Store>,
+    name: SubgraphName,
+) -> Result<(), SubgraphRegistrarError> {
+    let (subgraph_entity, subgraph_version_entities, mut ops) =
+        find_subgraph_for_removal(&*store, &name)?;
+
+    // Remove subgraph version entities, and their deployment/assignment when applicable
+    ops.extend(
+        remove_subgraph_versions(logger, store.clone(), subgraph_version_entities)?
+            .into_iter()
+            .map(|op| op.into()),
+    );
+
+    // Remove the subgraph entity
+    ops.push(MetadataOperation::Remove {
+        entity: SubgraphEntity::TYPENAME.to_owned(),
+        id: subgraph_entity.id()?,
+    });
+
+    store.apply_metadata_operations(ops)?;
+
+    debug!(logger, "Removed subgraph"; "subgraph_name" => name.to_string());
+
+    Ok(())
+}
+
+/// Find the subgraph entity of subgraph `name` and its versions, together
+/// with the operations that abort removing them if either changed in the
+/// meantime
+fn find_subgraph_for_removal(
+    store: &impl Store,
+    name: &SubgraphName,
+) -> Result<(Entity, Vec<Entity>, Vec<MetadataOperation>), SubgraphRegistrarError> {
+    let mut ops = vec![];
+
+    // Find the subgraph entity
+    let subgraph_entity_opt = store
+        .find_one(SubgraphEntity::query().filter(EntityFilter::new_equal("name", name.to_string())))
+        .map_err(|e| format_err!("query execution error: {}", e))?;
+    let subgraph_entity = subgraph_entity_opt
+        .ok_or_else(|| SubgraphRegistrarError::NameNotFound(name.to_string()))?;
+
+    ops.push(MetadataOperation::AbortUnless {
+        description: "Subgraph entity must still exist".to_owned(),
+        query: SubgraphEntity::query().filter(EntityFilter::new_equal("name", name.to_string())),
+        entity_ids: vec![subgraph_entity.id().unwrap()],
+    });
+
+    // Find subgraph version entities
+    let subgraph_version_entities = store.find(SubgraphVersionEntity::query().filter(
+        EntityFilter::new_equal("subgraph", subgraph_entity.id().unwrap()),
+    ))?;
+
+    ops.push(MetadataOperation::AbortUnless {
+        description: "Subgraph must have same set of versions".to_owned(),
+        query: SubgraphVersionEntity::query().filter(EntityFilter::new_equal(
+            "subgraph",
+            subgraph_entity.id().unwrap(),
+        )),
+        entity_ids: subgraph_version_entities
+            .iter()
+            .map(|entity| entity.id().unwrap())
+            .collect(),
+    });
+
+    Ok((subgraph_entity, subgraph_version_entities, ops))
+}
+
+/// Remove a set of subgraph versions atomically.
+///
+/// It may seem like it would be easier to generate the EntityOperations for subgraph versions
+/// removal one at a time, but that approach is significantly complicated by the fact that the
+/// store does not reflect the EntityOperations that have been accumulated so far. Earlier subgraph
+/// version creations/removals can affect later ones by affecting whether or not a subgraph deployment
+/// or assignment needs to be created/removed.
+fn remove_subgraph_versions(
+    logger: &Logger,
+    store: Arc<impl Store>,
+    version_entities_to_delete: Vec<Entity>,
+) -> Result<Vec<MetadataOperation>, SubgraphRegistrarError> {
+    let mut ops = vec![];
+
+    let version_entity_ids_to_delete = version_entities_to_delete
+        .iter()
+        .map(|version_entity| version_entity.id().unwrap())
+        .collect::<HashSet<_>>();
+
+    // Get hashes that are referenced by versions that will be deleted.
+    // These are candidates for clean up.
+    let referenced_subgraph_hashes = version_entities_to_delete
+        .iter()
+        .map(|version_entity| {
+            SubgraphDeploymentId::new(
+                version_entity
+                    .get("deployment")
+                    .unwrap()
+                    .to_owned()
+                    .as_string()
+                    .unwrap(),
+            )
+            .unwrap()
+        })
+        .collect::<HashSet<_>>();
+
+    // Find all subgraph version entities that point to these subgraph deployments
+    let (version_summaries, read_summaries_ops) =
+        store.read_subgraph_version_summaries(referenced_subgraph_hashes.into_iter().collect())?;
+    ops.extend(read_summaries_ops);
+
+    // Simulate the planned removal of SubgraphVersion entities
+    let version_summaries_after_delete = version_summaries
+        .clone()
+        .into_iter()
+        .filter(|version_summary| !version_entity_ids_to_delete.contains(&version_summary.id))
+        .collect::<Vec<_>>();
+
+    // Create/remove assignments based on the subgraph version changes.
+    // We are only deleting versions here, so no assignments will be created,
+    // and we can safely pass None for the node ID.
+    ops.extend(
+        store
+            .reconcile_assignments(
+                logger,
+                version_summaries,
+                version_summaries_after_delete,
+                None,
+            )
+            .into_iter()
+            .map(|op| op.into()),
+    );
+
+    // Actually remove the subgraph version entities.
+    // Note: we do this last because earlier AbortUnless ops depend on these entities still
+    // existing.
+    ops.extend(
+        version_entities_to_delete
+            .iter()
+            .map(|version_entity| MetadataOperation::Remove {
+                entity: SubgraphVersionEntity::TYPENAME.to_owned(),
+                id: version_entity.id().unwrap(),
+            }),
+    );
+
+    Ok(ops)
+}
+
+/// Reassign a subgraph deployment to a different node.
+///
+/// Reassigning to a nodeId that does not match any reachable graph-nodes will effectively pause the
+/// subgraph syncing process.
+fn reassign_subgraph(
+    store: Arc<impl Store>,
+    hash: SubgraphDeploymentId,
+    node_id: NodeId,
+) -> Result<(), SubgraphRegistrarError> {
+    let mut ops = vec![];
+
+    let current_deployment = store.find(
+        SubgraphDeploymentAssignmentEntity::query()
+            .filter(EntityFilter::new_equal("id", hash.clone().to_string())),
+    )?;
+
+    let current_node_id = current_deployment
+        .first()
+        .and_then(|d| d.get("nodeId"))
+        .ok_or_else(|| SubgraphRegistrarError::DeploymentNotFound(hash.clone().to_string()))?;
+
+    if current_node_id.to_string() == node_id.to_string() {
+        return Err(SubgraphRegistrarError::DeploymentAssignmentUnchanged(
+            hash.clone().to_string(),
+        ));
+    }
+
+    // Pinned deployments only move when they are pinned to another node
+    if let Some(pinned) = pinned_node(&*store, &hash)? {
+        if pinned != node_id {
+            return Err(SubgraphRegistrarError::DeploymentPinned(
+                hash.to_string(),
+                pinned.to_string(),
+            ));
+        }
+    }
+
+    // Keep the standby node, unless the deployment is reassigned to it
+    let standby_node_id = current_deployment
+        .first()
+        .and_then(|d| d.get("standbyNodeId"))
+        .and_then(|standby| standby.clone().as_string())
+        .filter(|standby| standby != &node_id.to_string())
+        .and_then(|standby| NodeId::new(standby).ok());
+
+    ops.push(MetadataOperation::AbortUnless {
+        description: "Deployment assignment is unchanged".to_owned(),
+        query: SubgraphDeploymentAssignmentEntity::query().filter(EntityFilter::And(vec![
+            EntityFilter::new_equal("nodeId", current_node_id.to_string()),
+            EntityFilter::new_equal("id", hash.clone().to_string()),
+        ])),
+        entity_ids: vec![hash.clone().to_string()],
+    });
+
+    // Create the assignment update operations.
+    // Note: This will also generate a remove operation for the existing subgraph assignment.
+    ops.extend(
+        SubgraphDeploymentAssignmentEntity::new(node_id)
+            .with_standby(standby_node_id)
+            .write_operations(&hash.clone())
             .into_iter()
             .map(|op| op.into()),
     );
 
     store.apply_metadata_operations(ops)?;
 
-    debug!(logger, "Created subgraph"; "subgraph_name" => name.to_string());
+    Ok(())
+}
+
+/// Return the node and the standby node that the deployment `hash` is
+/// currently assigned to
+fn current_assignment(
+    store: &impl Store,
+    hash: &SubgraphDeploymentId,
+) -> Result<(NodeId, Option<NodeId>), SubgraphRegistrarError> {
+    let assignment = store
+        .get(SubgraphDeploymentAssignmentEntity::key(hash.clone()))?
+        .ok_or_else(|| SubgraphRegistrarError::DeploymentNotFound(hash.to_string()))?;
+    let node_id = |attr: &str| -> Result<Option<NodeId>, SubgraphRegistrarError> {
+        match assignment.get(attr) {
+            Some(Value::String(node_id)) => NodeId::new(node_id.as_str())
+                .map(Some)
+                .map_err(|()| format_err!("Invalid node id in assignment entity").into()),
+            _ => Ok(None),
+        }
+    };
+    let current = node_id("nodeId")?
+        .ok_or_else(|| format_err!("Assignment entity without node id: {}", hash))?;
+    Ok((current, node_id("standbyNodeId")?))
+}
+
+/// Make `standby` the standby node for the deployment `hash`; passing
+/// `None` removes the standby node.
+fn assign_standby(
+    store: Arc<impl Store>,
+    hash: SubgraphDeploymentId,
+    standby: Option<NodeId>,
+) -> Result<(), SubgraphRegistrarError> {
+    let (node_id, current_standby) = current_assignment(&*store, &hash)?;
+
+    if standby == current_standby || standby.as_ref() == Some(&node_id) {
+        return Err(SubgraphRegistrarError::DeploymentAssignmentUnchanged(
+            hash.to_string(),
+        ));
+    }
+
+    let mut ops = vec![MetadataOperation::AbortUnless {
+        description: "Deployment assignment is unchanged".to_owned(),
+        query: SubgraphDeploymentAssignmentEntity::query().filter(EntityFilter::And(vec![
+            EntityFilter::new_equal("nodeId", node_id.to_string()),
+            EntityFilter::new_equal("id", hash.to_string()),
+        ])),
+        entity_ids: vec![hash.to_string()],
+    }];
+    ops.extend(
+        SubgraphDeploymentAssignmentEntity::new(node_id)
+            .with_standby(standby)
+            .write_operations(&hash),
+    );
+
+    store.apply_metadata_operations(ops)?;
+
+    Ok(())
+}
+
+/// Swap the node and the standby node for the deployment `hash`. The
+/// standby node starts indexing the deployment, and the node that
+/// indexed it so far becomes the new standby node.
+fn promote_standby(
+    store: &impl Store,
+    hash: SubgraphDeploymentId,
+) -> Result<(), SubgraphRegistrarError> {
+    let (node_id, standby) = current_assignment(store, &hash)?;
+    let standby = standby.ok_or_else(|| SubgraphRegistrarError::NoStandbyNode(hash.to_string()))?;
+
+    // A pinned deployment stays on the node it is pinned to
+    if let Some(pinned) = pinned_node(store, &hash)? {
+        if pinned != standby {
+            return Err(SubgraphRegistrarError::DeploymentPinned(
+                hash.to_string(),
+                pinned.to_string(),
+            ));
+        }
+    }
+
+    let mut ops = vec![MetadataOperation::AbortUnless {
+        description: "Deployment assignment is unchanged".to_owned(),
+        query: SubgraphDeploymentAssignmentEntity::query().filter(EntityFilter::And(vec![
+            EntityFilter::new_equal("nodeId", node_id.to_string()),
+            EntityFilter::new_equal("standbyNodeId", standby.to_string()),
+            EntityFilter::new_equal("id", hash.to_string()),
+        ])),
+        entity_ids: vec![hash.to_string()],
+    }];
+    ops.extend(
+        SubgraphDeploymentAssignmentEntity::new(standby)
+            .with_standby(Some(node_id))
+            .write_operations(&hash),
+    );
+
+    store.apply_metadata_operations(ops)?;
+
+    Ok(())
+}
+
+/// Assign the deployment `hash` to `node_id` and record that it stays
+/// there when assignments are rebalanced; passing `None` removes the pin
+/// and leaves the deployment where it is
+fn pin_deployment(
+    store: &impl Store,
+    hash: SubgraphDeploymentId,
+    node_id: Option<NodeId>,
+) -> Result<(), SubgraphRegistrarError> {
+    let (current, standby) = current_assignment(store, &hash)?;
+
+    let ops = match node_id {
+        Some(node_id) if node_id == current => {
+            SubgraphDeploymentPinEntity::new(node_id).write_operations(&hash)
+        }
+        Some(node_id) => {
+            let mut ops = move_operations(&hash, &current, node_id.clone(), standby);
+            ops.extend(SubgraphDeploymentPinEntity::new(node_id).write_operations(&hash));
+            ops
+        }
+        None => SubgraphDeploymentPinEntity::remove_operations(&hash),
+    };
+    store.apply_metadata_operations(ops)?;
+
+    Ok(())
+}
 
-    Ok(CreateSubgraphResult { id: entity_id })
+/// Whether block processing for `hash` was paused and should stay paused
+/// when the deployment is started
+pub(crate) fn deployment_paused(
+    store: &impl Store,
+    hash: &SubgraphDeploymentId,
+) -> Result<bool, QueryExecutionError> {
+    Ok(store
+        .get(SubgraphDeploymentAssignmentEntity::key(hash.clone()))?
+        .and_then(|assignment| assignment.get("paused").cloned())
+        .and_then(|paused| paused.as_bool())
+        .unwrap_or(false))
 }
 
-/// Resolves the chain head block and the subgraph's earliest block
-fn resolve_subgraph_chain_blocks(
-    manifest: SubgraphManifest,
-    chain_store: Arc<impl ChainStore>,
-    ethereum_adapter: Arc<dyn EthereumAdapter>,
-    logger: &Logger,
-) -> Box<
-    dyn Future<
-            Item = (Option<EthereumBlockPointer>, Option<EthereumBlockPointer>),
-            Error = SubgraphRegistrarError,
-        > + Send,
-> {
-    Box::new(
-        // If the minimum start block is 0 (i.e. the genesis block),
-        // return `None` to start indexing from the genesis block. Otherwise
-        // return a block pointer for the block with number `min_start_block - 1`.
-        match manifest
-            .start_blocks()
-            .into_iter()
-            .min()
-            .expect("cannot identify minimum start block because there are no data sources")
-        {
-            0 => Box::new(future::ok(None)) as Box<dyn Future<Item = _, Error = _> + Send>,
-            min_start_block => Box::new(
-                ethereum_adapter
-                    .block_pointer_from_number(logger, chain_store.clone(), min_start_block - 1)
-                    .map(Some)
-                    .map_err(move |_| {
-                        SubgraphRegistrarError::ManifestValidationError(vec![
-                            SubgraphManifestValidationError::BlockNotFound(
-                                min_start_block.to_string(),
-                            ),
-                        ])
-                    }),
-            ) as Box<dyn Future<Item = _, Error = _> + Send>,
-        }
-        .and_then(move |start_block_ptr| {
-            chain_store
-                .chain_head_ptr()
-                .map(|chain_head_block_ptr| (chain_head_block_ptr, start_block_ptr))
-                .map_err(SubgraphRegistrarError::Unknown)
-        }),
-    )
+/// Return the reason why the deployment `hash` is on the blocklist, or
+/// `None` if it is not blocked
+pub(crate) fn deployment_block_reason(
+    store: &impl Store,
+    hash: &SubgraphDeploymentId,
+) -> Result<Option<String>, QueryExecutionError> {
+    Ok(store
+        .get(SubgraphDeploymentBlockEntity::key(hash.clone()))?
+        .and_then(|block| block.get("reason").cloned())
+        .and_then(|reason| reason.as_string()))
 }
 
-struct SubraphVersionUpdatingMetadata {
-    subgraph_entity_id: String,
-    version_entity_id: String,
-    current_is_synced: bool,
-    current_version_id_opt: Option<String>,
-    pending_version_id_opt: Option<String>,
-    version_summaries_before: Vec<SubgraphVersionSummary>,
-    version_summaries_after: Vec<SubgraphVersionSummary>,
-    read_summaries_ops: Vec<MetadataOperation>,
+/// Create the deployment for `manifest` as a copy of the synced deployment
+/// `source` at the latest block that `source` processed, and assign it to
+/// `node_id`. The copy is grafted onto `source` so that it copies the data
+/// of `source` when it starts, the same way that grafted deployments do
+fn copy_subgraph(
+    logger: &Logger,
+    store: &(impl Store + SubgraphDeploymentStore),
+    source: SubgraphDeploymentId,
+    manifest: SubgraphManifest,
+    node_id: NodeId,
+    tablespace: Option<String>,
+) -> Result<(), SubgraphRegistrarError> {
+    let deployment = store
+        .get(SubgraphDeploymentEntity::key(source.clone()))?
+        .ok_or_else(|| SubgraphRegistrarError::DeploymentNotFound(source.to_string()))?;
+    if deployment.get("synced") != Some(&Value::Bool(true)) {
+        return Err(SubgraphRegistrarError::DeploymentNotSynced(
+            source.to_string(),
+        ));
+    }
+    if store
+        .get(SubgraphDeploymentEntity::key(manifest.id.clone()))?
+        .is_some()
+    {
+        return Err(SubgraphRegistrarError::DeploymentExists(
+            manifest.id.to_string(),
+        ));
+    }
+    let block = store
+        .block_ptr(source.clone())?
+        .ok_or_else(|| SubgraphRegistrarError::DeploymentNotSynced(source.to_string()))?;
+
+    info!(logger, "Copy subgraph deployment";
+          "source" => source.to_string(),
+          "target" => manifest.id.to_string(),
+          "block_number" => block.number,
+          "node_id" => node_id.to_string(),
+          "tablespace" => tablespace.as_ref().map(String::as_str).unwrap_or("default"));
+
+    let mut ops = vec![MetadataOperation::AbortUnless {
+        description: "Subgraph deployment entity must not exist".to_owned(),
+        query: SubgraphDeploymentEntity::query()
+            .filter(EntityFilter::new_equal("id", manifest.id.to_string())),
+        entity_ids: vec![],
+    }];
+    ops.extend(
+        SubgraphDeploymentEntity::new(&manifest, false, false, None, None)
+            .graft(source, block)
+            .create_operations(&manifest.id),
+    );
+    ops.extend(SubgraphDeploymentAssignmentEntity::new(node_id).write_operations(&manifest.id));
+    match tablespace {
+        Some(tablespace) => {
+            store.create_subgraph_deployment_in_tablespace(&manifest.schema, ops, tablespace)
+        }
+        None => store.create_subgraph_deployment(&manifest.schema, ops),
+    }
+    .map_err(SubgraphRegistrarError::SubgraphDeploymentError)
 }
 
-fn get_version_ids_and_summaries(
-    logger: Logger,
-    store: Arc<impl Store>,
-    name: String,
-    manifest_id: SubgraphDeploymentId,
-    version_switching_mode: SubgraphVersionSwitchingMode,
-) -> Result<SubraphVersionUpdatingMetadata, SubgraphRegistrarError> {
-    let name = name.clone();
-    // Look up subgraph entity by name
-    let subgraph_entity_opt = store
-        .find_one(SubgraphEntity::query().filter(EntityFilter::new_equal("name", name.clone())))?;
-    let subgraph_entity = subgraph_entity_opt.ok_or_else(|| {
-        debug!(
-            logger,
-            "Subgraph not found, could not create_subgraph_version";
-            "subgraph_name" => name.to_string()
-        );
-        SubgraphRegistrarError::NameNotFound(name)
-    })?;
-    let subgraph_entity_id = subgraph_entity.id()?;
-    let current_version_id_opt = match subgraph_entity.get("currentVersion") {
-        Some(Value::String(current_version_id)) => Some(current_version_id.to_owned()),
-        Some(Value::Null) => None,
-        None => None,
-        Some(_) => panic!("subgraph entity has invalid type in currentVersion field"),
-    };
-    let pending_version_id_opt = match subgraph_entity.get("pendingVersion") {
-        Some(Value::String(pending_version_id)) => Some(pending_version_id.to_owned()),
-        Some(Value::Null) => None,
-        None => None,
-        Some(_) => panic!("subgraph entity has invalid type in pendingVersion field"),
+/// The deployment that `hash` is grafted onto and the block it is grafted
+/// at, as long as `hash` has not copied the data of its base yet. Returns
+/// `None` if the deployment is not grafted or has already copied that data
+pub(crate) fn pending_graft(
+    store: &impl Store,
+    hash: &SubgraphDeploymentId,
+) -> Result<Option<(SubgraphDeploymentId, EthereumBlockPointer)>, Error> {
+    let deployment = match store.get(SubgraphDeploymentEntity::key(hash.clone()))? {
+        Some(deployment) => deployment,
+        None => return Ok(None),
     };
+    let value = |name: &str| deployment.get(name).cloned().unwrap_or(Value::Null);
 
-    let store = store.clone();
-    // Look up current version's deployment hash
-    let current_version_hash_opt = match current_version_id_opt {
-        Some(ref current_version_id) => Some(get_subgraph_version_deployment_id(
-            store.clone(),
-            current_version_id.clone(),
-        )?),
-        None => None,
+    let latest_block: Option<BigInt> = value("latestEthereumBlockNumber").try_into()?;
+    if latest_block.is_some() {
+        return Ok(None);
+    }
+    let base = match value("graftBase") {
+        Value::String(base) => SubgraphDeploymentId::new(base.clone())
+            .map_err(|()| format_err!("invalid graft base `{}` for {}", base, hash))?,
+        _ => return Ok(None),
     };
+    let block_hash: Option<H256> = value("graftBlockHash").try_into()?;
+    let block_number: Option<BigInt> = value("graftBlockNumber").try_into()?;
+    match (block_hash, block_number) {
+        (Some(block_hash), Some(block_number)) => Ok(Some((
+            base,
+            EthereumBlockPointer {
+                hash: block_hash,
+                number: block_number.to_u64(),
+            },
+        ))),
+        _ => Err(format_err!("the graft block of {} is missing", hash)),
+    }
+}
 
-    // Look up pending version's deployment hash
-    let pending_version_hash_opt = match pending_version_id_opt {
-        Some(ref pending_version_id) => Some(get_subgraph_version_deployment_id(
-            store.clone(),
-            pending_version_id.clone(),
-        )?),
-        None => None,
-    };
+/// Put the deployment `hash` on the blocklist. Blocked deployments can not
+/// be deployed, and nodes refuse to start them. The node that a blocked
+/// deployment is assigned to stops it when it sees the blocklist entry
+fn block_deployment(
+    logger: &Logger,
+    store: &impl Store,
+    hash: SubgraphDeploymentId,
+    reason: String,
+) -> Result<(), SubgraphRegistrarError> {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
 
-    // See if current version is fully synced
-    let current_is_synced = match &current_version_hash_opt {
-        Some(hash) => store.is_deployment_synced(hash.to_owned())?,
-        None => false,
-    };
+    info!(logger, "Block subgraph deployment";
+          "subgraph_hash" => hash.to_string(),
+          "reason" => &reason);
 
-    // Find all subgraph version entities that point to this hash or a hash
-    let (version_summaries_before, read_summaries_ops) = store.read_subgraph_version_summaries(
-        iter::once(manifest_id.clone())
-            .chain(current_version_hash_opt)
-            .chain(pending_version_hash_opt)
-            .collect(),
+    store.apply_metadata_operations(
+        SubgraphDeploymentBlockEntity::new(reason, created_at).write_operations(&hash),
     )?;
 
-    let version_entity_id = generate_entity_id();
+    Ok(())
+}
 
-    // Simulate the creation of the new version and updating of Subgraph.pending/current
-    let version_summaries_after = match version_switching_mode {
-        SubgraphVersionSwitchingMode::Instant => {
-            // Previously pending or current versions will no longer be pending/current
-            let mut version_summaries_after = version_summaries_before
-                .clone()
-                .into_iter()
-                .map(|mut version_summary| {
-                    if Some(&version_summary.id) == pending_version_id_opt.as_ref() {
-                        version_summary.pending = false;
-                    }
+/// Remove the deployment `hash` from the blocklist. The node that it is
+/// assigned to starts it again
+fn unblock_deployment(
+    logger: &Logger,
+    store: &impl Store,
+    hash: SubgraphDeploymentId,
+) -> Result<(), SubgraphRegistrarError> {
+    if deployment_block_reason(store, &hash)?.is_none() {
+        return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string()));
+    }
 
-                    if Some(&version_summary.id) == current_version_id_opt.as_ref() {
-                        version_summary.current = false;
-                    }
+    info!(logger, "Unblock subgraph deployment"; "subgraph_hash" => hash.to_string());
 
-                    version_summary
-                })
-                .collect::<Vec<_>>();
+    store.apply_metadata_operations(vec![MetadataOperation::Remove {
+        entity: SubgraphDeploymentBlockEntity::TYPENAME.to_owned(),
+        id: hash.to_string(),
+    }])?;
 
-            // Add new version, which will immediately be current
-            version_summaries_after.push(SubgraphVersionSummary {
-                id: version_entity_id.clone(),
-                subgraph_id: subgraph_entity_id.clone(),
-                deployment_id: manifest_id.clone(),
-                pending: false,
-                current: true,
-            });
+    Ok(())
+}
 
-            version_summaries_after
+fn wake_subgraph(
+    logger: &Logger,
+    store: &impl Store,
+    hash: SubgraphDeploymentId,
+) -> Result<(), SubgraphRegistrarError> {
+    if store
+        .get(SubgraphDeploymentEntity::key(hash.clone()))?
+        .is_none()
+    {
+        return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string()));
+    }
+    if store
+        .get(SubgraphDeploymentIdleEntity::key(hash.clone()))?
+        .is_none()
+    {
+        return Ok(());
+    }
+
+    info!(logger, "Wake up subgraph deployment"; "subgraph_hash" => hash.to_string());
+
+    // The node that the deployment is assigned to starts it once the
+    // idle entity is gone
+    store.apply_metadata_operations(SubgraphDeploymentIdleEntity::remove_operations(&hash))?;
+
+    Ok(())
+}
+
+/// Check that the deployment `hash` exists and is not assigned to a node
+/// other than `node_id`
+fn check_rewind_assignment(
+    store: &impl Store,
+    node_id: &NodeId,
+    hash: &SubgraphDeploymentId,
+) -> Result<(), SubgraphRegistrarError> {
+    if store
+        .get(SubgraphDeploymentEntity::key(hash.clone()))?
+        .is_none()
+    {
+        return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string()));
+    }
+
+    let assigned_to = store
+        .get(SubgraphDeploymentAssignmentEntity::key(hash.clone()))?
+        .and_then(|assignment| assignment.get("nodeId").cloned());
+    match assigned_to {
+        Some(Value::String(node)) if node != node_id.to_string() => Err(
+            SubgraphRegistrarError::DeploymentAssignedElsewhere(hash.to_string(), node),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Create the indexes that the deployment `hash` is missing
+fn create_missing_indexes(
+    logger: &Logger,
+    store: &(impl Store + SubgraphDeploymentStore),
+    hash: SubgraphDeploymentId,
+) -> Result<Vec<IndexCreation>, SubgraphRegistrarError> {
+    if store
+        .get(SubgraphDeploymentEntity::key(hash.clone()))?
+        .is_none()
+    {
+        return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string()));
+    }
+
+    info!(logger, "Create missing indexes"; "subgraph_hash" => hash.to_string());
+
+    let created = store.create_missing_indexes(&hash)?;
+    for index in &created {
+        match &index.error {
+            None => info!(logger, "Created missing index";
+                          "subgraph_hash" => hash.to_string(),
+                          "index" => &index.name),
+            Some(error) => warn!(logger, "Failed to create missing index";
+                                 "subgraph_hash" => hash.to_string(),
+                                 "index" => &index.name,
+                                 "error" => error),
         }
-        SubgraphVersionSwitchingMode::Synced => {
-            // There is a current version. Depending on whether it is synced
-            // or not, make the new version the pending or the current version
-            if current_version_id_opt.is_some() {
-                // Previous pending version (if there was one) is no longer pending
-                // Previous current version if it's not fully synced is no
-                // longer the current version
-                let mut version_summaries_after = version_summaries_before
-                    .clone()
-                    .into_iter()
-                    .map(|mut version_summary| {
-                        if Some(&version_summary.id) == pending_version_id_opt.as_ref() {
-                            version_summary.pending = false;
-                        }
-                        if !current_is_synced
-                            && Some(&version_summary.id) == current_version_id_opt.as_ref()
-                        {
-                            // We will make the new version the current version
-                            version_summary.current = false;
-                        }
+    }
+    Ok(created)
+}
 
-                        version_summary
-                    })
-                    .collect::<Vec<_>>();
+/// Report the entity types of the deployment `hash` that have entities
+/// with an ID longer than `max_length` bytes
+fn oversized_entity_ids(
+    store: &(impl Store + SubgraphDeploymentStore),
+    hash: SubgraphDeploymentId,
+    max_length: usize,
+) -> Result<Vec<OversizedEntityIds>, SubgraphRegistrarError> {
+    if store
+        .get(SubgraphDeploymentEntity::key(hash.clone()))?
+        .is_none()
+    {
+        return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string()));
+    }
+
+    Ok(store.oversized_entity_ids(&hash, max_length)?)
+}
+
+/// Create the attribute indexes of the deployment `hash` again. Creating
+/// indexes can take a long time, and therefore happens in the background;
+/// its outcome shows up in the indexing status of the deployment. Retries
+/// for a deployment whose indexes are being created already are ignored,
+/// and `running` holds the deployments for which that is the case
+fn retry_index_creation<S>(
+    logger: &Logger,
+    store: Arc<S>,
+    running: Arc<Mutex<HashSet<SubgraphDeploymentId>>>,
+    hash: SubgraphDeploymentId,
+) -> Result<(), SubgraphRegistrarError>
+where
+    S: Store + SubgraphDeploymentStore,
+{
+    if store
+        .get(SubgraphDeploymentEntity::key(hash.clone()))?
+        .is_none()
+    {
+        return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string()));
+    }
+
+    let logger = logger.new(o!("subgraph_hash" => hash.to_string()));
+    if !running.lock().unwrap().insert(hash.clone()) {
+        info!(logger, "Attribute indexes are being created already");
+        return Ok(());
+    }
+    info!(logger, "Retry creating attribute indexes");
+
+    // Blocking due to store interactions. Won't be blocking after #905.
+    graph::spawn_blocking(async move {
+        match store.input_schema(&hash) {
+            // Failures are logged and recorded by `create_attribute_indexes`
+            Ok(schema) => {
+                let _ = create_attribute_indexes(&logger, &*store, &hash, &schema);
+            }
+            Err(e) => warn!(logger, "Failed to retry creating attribute indexes";
+                            "error" => e.to_string()),
+        }
+        running.lock().unwrap().remove(&hash);
+    });
+    Ok(())
+}
+
+/// Ask for the deployment `hash` to be migrated to relational storage
+fn migrate_storage(
+    logger: &Logger,
+    store: &(impl Store + SubgraphDeploymentStore),
+    hash: SubgraphDeploymentId,
+) -> Result<(), SubgraphRegistrarError> {
+    if store
+        .get(SubgraphDeploymentEntity::key(hash.clone()))?
+        .is_none()
+    {
+        return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string()));
+    }
 
-                // Determine if this new version should be the pending or
-                // current version. When we add a version to a subgraph that
-                // already has a current version, the new version becomes the
-                // pending version if the current version is synced,
-                // and replaces the current version if the current version is still syncing
-                let (pending, current) = if current_is_synced {
-                    (true, false)
-                } else {
-                    (false, true)
-                };
+    info!(logger, "Request migrating subgraph storage"; "subgraph_hash" => hash.to_string());
+    store.request_storage_migration(&hash)?;
+    Ok(())
+}
 
-                // Add new version, setting pending and current appropriately
-                version_summaries_after.push(SubgraphVersionSummary {
-                    id: version_entity_id.clone(),
-                    subgraph_id: subgraph_entity_id.clone(),
-                    deployment_id: manifest_id.clone(),
-                    pending,
-                    current,
-                });
+/// Set or remove the disk quota of the deployment `hash`
+fn set_deployment_quota(
+    logger: &Logger,
+    store: &impl Store,
+    hash: SubgraphDeploymentId,
+    soft_limit: Option<u64>,
+    hard_limit: Option<u64>,
+) -> Result<(), SubgraphRegistrarError> {
+    if store
+        .get(SubgraphDeploymentEntity::key(hash.clone()))?
+        .is_none()
+    {
+        return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string()));
+    }
+    if let (Some(soft_limit), Some(hard_limit)) = (soft_limit, hard_limit) {
+        if soft_limit > hard_limit {
+            return Err(SubgraphRegistrarError::InvalidQuota(format!(
+                "soft limit {} exceeds hard limit {}",
+                soft_limit, hard_limit
+            )));
+        }
+    }
 
-                version_summaries_after
-            } else {
-                // No need to process list, as there is no current version
-                let mut version_summaries_after = version_summaries_before.clone();
+    let ops = match (soft_limit, hard_limit) {
+        (None, None) => {
+            info!(logger, "Remove subgraph deployment quota"; "subgraph_hash" => hash.to_string());
+            vec![MetadataOperation::Remove {
+                entity: SubgraphDeploymentQuotaEntity::TYPENAME.to_owned(),
+                id: hash.to_string(),
+            }]
+        }
+        (soft_limit, hard_limit) => {
+            info!(logger, "Set subgraph deployment quota";
+                  "subgraph_hash" => hash.to_string(),
+                  "soft_limit" => soft_limit,
+                  "hard_limit" => hard_limit);
+            // Keep the size that was measured for the old quota, so that
+            // the new limits are checked against it until the deployment is
+            // measured again
+            let size = store
+                .get(SubgraphDeploymentQuotaEntity::key(hash.clone()))?
+                .and_then(|quota| quota.get("size").cloned())
+                .and_then(Value::as_bigint)
+                .map(|size| size.to_u64());
+            SubgraphDeploymentQuotaEntity::new(soft_limit, hard_limit).write_operations(&hash, size)
+        }
+    };
+    store.apply_metadata_operations(ops)?;
 
-                // Add new version, which will immediately be current
-                version_summaries_after.push(SubgraphVersionSummary {
-                    id: version_entity_id.clone(),
-                    subgraph_id: subgraph_entity_id.clone(),
-                    deployment_id: manifest_id.clone(),
-                    pending: false,
-                    current: true,
-                });
+    Ok(())
+}
 
-                version_summaries_after
-            }
+/// Schedule or cancel the removal of the deployment `hash`
+fn schedule_deployment_removal(
+    logger: &Logger,
+    store: &impl Store,
+    hash: SubgraphDeploymentId,
+    at_block: Option<u64>,
+    at_time: Option<u64>,
+) -> Result<(), SubgraphRegistrarError> {
+    if store
+        .get(SubgraphDeploymentEntity::key(hash.clone()))?
+        .is_none()
+    {
+        return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string()));
+    }
+
+    let ops = match (at_block, at_time) {
+        (None, None) => {
+            info!(logger, "Cancel scheduled removal of subgraph deployment";
+                  "subgraph_hash" => hash.to_string());
+            vec![MetadataOperation::Remove {
+                entity: SubgraphDeploymentRemovalEntity::TYPENAME.to_owned(),
+                id: hash.to_string(),
+            }]
+        }
+        (at_block, at_time) => {
+            info!(logger, "Schedule removal of subgraph deployment";
+                  "subgraph_hash" => hash.to_string(),
+                  "at_block" => at_block,
+                  "at_time" => at_time);
+            SubgraphDeploymentRemovalEntity::new(at_block, at_time).write_operations(&hash)
         }
     };
-    Ok(SubraphVersionUpdatingMetadata {
-        subgraph_entity_id,
-        version_entity_id,
-        current_is_synced,
-        current_version_id_opt,
-        pending_version_id_opt,
-        version_summaries_before,
-        version_summaries_after,
-        read_summaries_ops,
-    })
+    store.apply_metadata_operations(ops)?;
+
+    Ok(())
 }
 
-fn create_subgraph_version(
+/// Write or remove an annotation of the deployment `hash`
+fn annotate(
     logger: &Logger,
-    store: Arc<impl Store>,
-    chain_store: Arc<impl ChainStore>,
-    ethereum_adapter: Arc<dyn EthereumAdapter>,
-    name: SubgraphName,
-    manifest: SubgraphManifest,
-    node_id: NodeId,
-    version_switching_mode: SubgraphVersionSwitchingMode,
-) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send> {
-    let logger = logger.clone();
-    let manifest = manifest.clone();
-    let manifest_id = manifest.id.clone();
-    let store = store.clone();
-    let deployment_store = store.clone();
+    store: &(impl Store + SubgraphDeploymentStore),
+    hash: SubgraphDeploymentId,
+    entity_type: String,
+    entity_id: String,
+    data: Option<HashMap<String, serde_json::Value>>,
+) -> Result<(), SubgraphRegistrarError> {
+    if store
+        .get(SubgraphDeploymentEntity::key(hash.clone()))?
+        .is_none()
+    {
+        return Err(SubgraphRegistrarError::DeploymentNotFound(hash.to_string()));
+    }
 
-    Box::new(
-        future::result(get_version_ids_and_summaries(
-            logger.clone(),
-            store.clone(),
-            name.to_string(),
-            manifest_id.clone(),
-            version_switching_mode,
-        ))
-        .and_then(move |subgraph_version_data| {
-            let mut ops = vec![];
-            ops.push(MetadataOperation::AbortUnless {
-                description:
-                    "Subgraph entity must still exist, have same name/currentVersion/pendingVersion"
-                        .to_owned(),
-                query: SubgraphEntity::query().filter(EntityFilter::And(vec![
-                    EntityFilter::new_equal("name", name.to_string()),
-                    EntityFilter::new_equal(
-                        "currentVersion",
-                        subgraph_version_data.current_version_id_opt.clone(),
-                    ),
-                    EntityFilter::new_equal(
-                        "pendingVersion",
-                        subgraph_version_data.pending_version_id_opt.clone(),
-                    ),
-                ])),
-                entity_ids: vec![subgraph_version_data.subgraph_entity_id.clone()],
-            });
+    let schema = store.input_schema(&hash)?;
+    if !schema.is_annotation_type(&entity_type) {
+        return Err(SubgraphRegistrarError::InvalidAnnotation(format!(
+            "type {} is not declared with @annotation",
+            entity_type
+        )));
+    }
 
-            // Create the subgraph version entity
-            let created_at = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            ops.extend(subgraph_version_data.read_summaries_ops);
-            ops.extend(
-                SubgraphVersionEntity::new(
-                    subgraph_version_data.subgraph_entity_id.clone(),
-                    manifest_id.clone(),
-                    created_at,
-                )
-                .write_operations(&subgraph_version_data.version_entity_id)
-                .into_iter()
-                .map(|op| op.into()),
-            );
+    let key = EntityKey {
+        subgraph_id: hash.clone(),
+        entity_type,
+        entity_id,
+    };
+    let modification = match data {
+        None => {
+            info!(logger, "Remove annotation";
+                  "subgraph_hash" => hash.to_string(),
+                  "entity_type" => &key.entity_type,
+                  "entity_id" => &key.entity_id);
+            EntityModification::Remove { key }
+        }
+        Some(data) => {
+            let entity = annotation_entity(&schema, &key, data)
+                .map_err(|e| SubgraphRegistrarError::InvalidAnnotation(e.to_string()))?;
+            info!(logger, "Write annotation";
+                  "subgraph_hash" => hash.to_string(),
+                  "entity_type" => &key.entity_type,
+                  "entity_id" => &key.entity_id);
+            EntityModification::Overwrite { key, data: entity }
+        }
+    };
+    store.write_annotations(&hash, vec![modification])?;
 
-            // Possibly add assignment for new deployment hash, and possibly remove
-            // assignments for old current/pending
-            ops.extend(
-                store
-                    .reconcile_assignments(
-                        &logger.clone(),
-                        subgraph_version_data.version_summaries_before,
-                        subgraph_version_data.version_summaries_after,
-                        Some(node_id),
-                    )
+    Ok(())
+}
+
+/// Convert the JSON `data` for the annotation `key` into an entity, using
+/// the types that the schema declares for its fields
+fn annotation_entity(
+    schema: &Schema,
+    key: &EntityKey,
+    data: HashMap<String, serde_json::Value>,
+) -> Result<Entity, Error> {
+    fn query_value(value: serde_json::Value) -> Result<q::Value, Error> {
+        use serde_json::Value as J;
+
+        Ok(match value {
+            J::Null => q::Value::Null,
+            J::Bool(b) => q::Value::Boolean(b),
+            J::Number(n) => match n.as_i64().and_then(|n| i32::try_from(n).ok()) {
+                Some(n) => q::Value::Int(n.into()),
+                None => return Err(format_err!("{} is not an Int", n)),
+            },
+            J::String(s) => q::Value::String(s),
+            J::Array(values) => q::Value::List(
+                values
                     .into_iter()
-                    .map(|op| op.into()),
-            );
+                    .map(query_value)
+                    .collect::<Result<_, _>>()?,
+            ),
+            J::Object(_) => return Err(format_err!("objects are not supported")),
+        })
+    }
 
-            // Update current/pending versions in Subgraph entity
-            match version_switching_mode {
-                SubgraphVersionSwitchingMode::Instant => {
-                    ops.extend(SubgraphEntity::update_pending_version_operations(
-                        &subgraph_version_data.subgraph_entity_id,
-                        None,
-                    ));
-                    ops.extend(SubgraphEntity::update_current_version_operations(
-                        &subgraph_version_data.subgraph_entity_id,
-                        Some(subgraph_version_data.version_entity_id),
-                    ));
-                }
-                SubgraphVersionSwitchingMode::Synced => {
-                    // Set the new version as pending, unless there is no fully synced
-                    // current versions
-                    if subgraph_version_data.current_is_synced {
-                        ops.extend(SubgraphEntity::update_pending_version_operations(
-                            &subgraph_version_data.subgraph_entity_id,
-                            Some(subgraph_version_data.version_entity_id),
-                        ));
-                    } else {
-                        ops.extend(SubgraphEntity::update_pending_version_operations(
-                            &subgraph_version_data.subgraph_entity_id,
-                            None,
-                        ));
-                        ops.extend(SubgraphEntity::update_current_version_operations(
-                            &subgraph_version_data.subgraph_entity_id,
-                            Some(subgraph_version_data.version_entity_id),
-                        ));
-                    }
-                }
-            }
+    let object_type = schema
+        .document
+        .get_object_type_definitions()
+        .into_iter()
+        .find(|object_type| object_type.name == key.entity_type)
+        .ok_or_else(|| format_err!("unknown type {}", key.entity_type))?;
 
-            // Check if deployment exists
-            store
-                .get(SubgraphDeploymentEntity::key(manifest_id.clone()))
-                .map_err(|e| SubgraphRegistrarError::QueryExecutionError(e))
-                .map(|a| (logger, ops, a.is_some()))
-        })
-        .and_then(move |(logger, mut ops, deployment_exists)| {
-            ops.push(MetadataOperation::AbortUnless {
-                description: "Subgraph deployment entity must continue to exist/not exist"
-                    .to_owned(),
-                query: SubgraphDeploymentEntity::query()
-                    .filter(EntityFilter::new_equal("id", manifest.id.to_string())),
-                entity_ids: if deployment_exists {
-                    vec![manifest.id.to_string()]
-                } else {
-                    vec![]
-                },
+    let mut entity = Entity::new();
+    for (name, value) in data {
+        let field = object_type
+            .fields
+            .iter()
+            .find(|field| field.name == name)
+            .ok_or_else(|| format_err!("type {} has no field {}", key.entity_type, name))?;
+        let value = query_value(value)
+            .and_then(|value| Ok(Value::from_query_value(&value, &field.field_type)?))
+            .map_err(|e| format_err!("invalid value for field {}: {}", name, e))?;
+        entity.set(name, value);
+    }
+    entity.set("id", key.entity_id.clone());
+    validate_entity(&schema.document, key, &entity)?;
+    Ok(entity)
+}
+
+/// Add an entry to the audit log of admin operations
+fn record_admin_operation(
+    store: &impl Store,
+    operation: String,
+    actor: Option<String>,
+    parameters: String,
+    error: Option<String>,
+) -> Result<(), SubgraphRegistrarError> {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    store.apply_metadata_operations(
+        SubgraphAdminOperationEntity::new(operation, actor, parameters, error, created_at)
+            .write_operations(&generate_entity_id()),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph_mock::MockStore;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn index_creation_is_retried_in_the_background() {
+        let id = SubgraphDeploymentId::new("QmRetried").unwrap();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+
+        let mut store = MockStore::new();
+        // The deployment exists, and creating its indexes had not failed
+        store.expect_get().times(2).returning(|key| {
+            if key.entity_type == SubgraphDeploymentEntity::TYPENAME {
+                Ok(Some(Entity::new()))
+            } else {
+                Ok(None)
+            }
+        });
+        store.expect_input_schema().times(1).returning(|id| {
+            Ok(Arc::new(
+                Schema::parse("type Thing @entity { id: ID!, name: String }", id.clone()).unwrap(),
+            ))
+        });
+        store
+            .expect_build_entity_attribute_indexes()
+            .times(1)
+            .returning(move |_, _| {
+                released.lock().unwrap().recv().unwrap();
+                Ok(())
             });
+        store.expect_apply_metadata_operations().never();
+
+        let store = Arc::new(store);
+        let running = Arc::new(Mutex::new(HashSet::new()));
+        let logger = Logger::root(slog::Discard, o!());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.enter(|| {
+            // The retry returns while the indexes are still being created,
+            // and retrying again in the meantime does nothing
+            retry_index_creation(&logger, store.clone(), running.clone(), id.clone()).unwrap();
+            assert!(running.lock().unwrap().contains(&id));
+            retry_index_creation(&logger, store.clone(), running.clone(), id.clone()).unwrap();
+        });
+
+        release.send(()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !running.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "creating indexes did not finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
 
-            resolve_subgraph_chain_blocks(
-                manifest.clone(),
-                chain_store.clone(),
-                ethereum_adapter.clone(),
-                &logger.clone(),
-            )
-            .and_then(move |(chain_head_block, start_block)| {
-                info!(
-                    logger,
-                    "Set subgraph start block";
-                    "block_number" => format!("{:?}", start_block.map(|block| block.number)),
-                    "block_hash" => format!("{:?}", start_block.map(|block| block.hash)),
-                );
+    #[test]
+    fn index_creation_is_not_retried_for_unknown_deployments() {
+        let mut store = MockStore::new();
+        store.expect_get().times(1).returning(|_| Ok(None));
+        store.expect_input_schema().never();
+        store.expect_build_entity_attribute_indexes().never();
+
+        let running = Arc::new(Mutex::new(HashSet::new()));
+        match retry_index_creation(
+            &Logger::root(slog::Discard, o!()),
+            Arc::new(store),
+            running.clone(),
+            SubgraphDeploymentId::new("QmUnknown").unwrap(),
+        ) {
+            Err(SubgraphRegistrarError::DeploymentNotFound(_)) => (),
+            _ => panic!("retrying index creation must fail for unknown deployments"),
+        }
+        assert!(running.lock().unwrap().is_empty());
+    }
 
-                // Apply the subgraph versioning and deployment operations,
-                // creating a new subgraph deployment if one doesn't exist.
-                if deployment_exists {
-                    deployment_store
-                        .apply_metadata_operations(ops)
-                        .map_err(|e| SubgraphRegistrarError::SubgraphDeploymentError(e))
-                } else {
-                    ops.extend(
-                        SubgraphDeploymentEntity::new(
-                            &manifest,
-                            false,
-                            false,
-                            start_block,
-                            chain_head_block,
-                        )
-                        .create_operations(&manifest.id),
-                    );
-                    deployment_store
-                        .create_subgraph_deployment(&manifest.schema, ops)
-                        .map_err(|e| SubgraphRegistrarError::SubgraphDeploymentError(e))
+    #[test]
+    fn deployment_quotas_are_checked_against_the_measured_size() {
+        let id = SubgraphDeploymentId::new("QmQuota").unwrap();
+        let logger = Logger::root(slog::Discard, o!());
+
+        let mut store = MockStore::new();
+        store.expect_get().returning(|key| {
+            if key.entity_type == SubgraphDeploymentEntity::TYPENAME {
+                Ok(Some(Entity::new()))
+            } else {
+                Ok(Some(Entity::from(vec![
+                    ("softLimitExceeded", Value::from(false)),
+                    ("hardLimitExceeded", Value::from(false)),
+                    ("size", Value::from(150u64)),
+                ])))
+            }
+        });
+        store
+            .expect_apply_metadata_operations()
+            .times(1)
+            .withf(|ops| match ops.as_slice() {
+                [MetadataOperation::Set { data, .. }] => {
+                    data.get("size").is_none()
+                        && data.get("softLimitExceeded") == Some(&Value::from(true))
+                        && data.get("hardLimitExceeded") == Some(&Value::from(false))
+                }
+                _ => false,
+            })
+            .returning(|_| Ok(()));
+        set_deployment_quota(&logger, &store, id.clone(), Some(100), Some(200)).unwrap();
+
+        let mut store = MockStore::new();
+        store.expect_get().returning(|_| Ok(Some(Entity::new())));
+        store
+            .expect_apply_metadata_operations()
+            .times(1)
+            .withf(|ops| match ops.as_slice() {
+                [MetadataOperation::Remove { entity, .. }] => {
+                    entity == SubgraphDeploymentQuotaEntity::TYPENAME
                 }
+                _ => false,
             })
-        }),
-    )
-}
+            .returning(|_| Ok(()));
+        set_deployment_quota(&logger, &store, id.clone(), None, None).unwrap();
+
+        let mut store = MockStore::new();
+        store.expect_get().returning(|_| Ok(Some(Entity::new())));
+        store.expect_apply_metadata_operations().never();
+        match set_deployment_quota(&logger, &store, id.clone(), Some(300), Some(200)) {
+            Err(SubgraphRegistrarError::InvalidQuota(_)) => (),
+            _ => panic!("the soft limit must not exceed the hard limit"),
+        }
 
-fn get_subgraph_version_deployment_id(
-    store: Arc<impl Store>,
-    version_id: String,
-) -> Result<SubgraphDeploymentId, SubgraphRegistrarError> {
-    let version_entity = store
-        .get(SubgraphVersionEntity::key(version_id))?
-        .ok_or_else(|| TransactionAbortError::Other(format!("Subgraph version entity missing")))
-        .map_err(StoreError::from)?;
+        let mut store = MockStore::new();
+        store.expect_get().returning(|_| Ok(None));
+        store.expect_apply_metadata_operations().never();
+        match set_deployment_quota(&logger, &store, id, Some(100), Some(200)) {
+            Err(SubgraphRegistrarError::DeploymentNotFound(_)) => (),
+            _ => panic!("quotas can only be set for existing deployments"),
+        }
+    }
 
-    Ok(SubgraphDeploymentId::new(
-        version_entity
-            .get("deployment")
-            .unwrap()
-            .to_owned()
-            .as_string()
-            .unwrap(),
-    )
-    .unwrap())
-}
+    /// Check the scheduled removal with `fields` of deployment `QmRemoved`,
+    /// which is assigned to `assigned_to` and has indexed up to
+    /// `latest_block`, once on node `default`, and return the metadata
+    /// operations that the check applied
+    fn check_removal(
+        fields: Vec<(&'static str, Value)>,
+        assigned_to: &'static str,
+        latest_block: u64,
+    ) -> Vec<Vec<MetadataOperation>> {
+        let mut removal = Entity::new();
+        removal.set("id", "QmRemoved");
+        for (name, value) in fields {
+            removal.set(name, value);
+        }
 
-fn remove_subgraph(
-    logger: &Logger,
-    store: Arc<impl Store>,
-    name: SubgraphName,
-) -> Result<(), SubgraphRegistrarError> {
-    let mut ops = vec![];
+        let mut store = MockStore::new();
+        store
+            .expect_find()
+            .returning(move |query| match &query.collection {
+                EntityCollection::All(types)
+                    if types[0] == SubgraphDeploymentRemovalEntity::TYPENAME =>
+                {
+                    Ok(vec![removal.clone()])
+                }
+                // No subgraph uses the deployment
+                _ => Ok(vec![]),
+            });
+        store.expect_get().returning(move |key| {
+            if key.entity_type == SubgraphDeploymentAssignmentEntity::TYPENAME {
+                Ok(Some(Entity::from(vec![(
+                    "nodeId",
+                    Value::from(assigned_to),
+                )])))
+            } else {
+                Ok(Some(Entity::from(vec![(
+                    "latestEthereumBlockNumber",
+                    Value::from(latest_block),
+                )])))
+            }
+        });
+        let applied = Arc::new(Mutex::new(vec![]));
+        let recorded = applied.clone();
+        store
+            .expect_apply_metadata_operations()
+            .returning(move |ops| {
+                recorded.lock().unwrap().push(ops);
+                Ok(())
+            });
 
-    // Find the subgraph entity
-    let subgraph_entity_opt = store
-        .find_one(SubgraphEntity::query().filter(EntityFilter::new_equal("name", name.to_string())))
-        .map_err(|e| format_err!("query execution error: {}", e))?;
-    let subgraph_entity = subgraph_entity_opt
-        .ok_or_else(|| SubgraphRegistrarError::NameNotFound(name.to_string()))?;
+        check_scheduled_removals(
+            &Logger::root(slog::Discard, o!()),
+            Arc::new(store),
+            &NodeId::new("default").unwrap(),
+        )
+        .unwrap();
+        let applied = applied.lock().unwrap().drain(..).collect();
+        applied
+    }
 
-    ops.push(MetadataOperation::AbortUnless {
-        description: "Subgraph entity must still exist".to_owned(),
-        query: SubgraphEntity::query().filter(EntityFilter::new_equal("name", name.to_string())),
-        entity_ids: vec![subgraph_entity.id().unwrap()],
-    });
+    fn removes_deployment(applied: &[Vec<MetadataOperation>]) -> bool {
+        let removes = |ops: &Vec<MetadataOperation>, typename: &str| {
+            ops.iter().any(|op| match op {
+                MetadataOperation::Remove { entity, id } => entity == typename && id == "QmRemoved",
+                _ => false,
+            })
+        };
+        match applied {
+            [ops] => {
+                removes(ops, SubgraphDeploymentAssignmentEntity::TYPENAME)
+                    && removes(ops, SubgraphDeploymentRemovalEntity::TYPENAME)
+            }
+            _ => false,
+        }
+    }
 
-    // Find subgraph version entities
-    let subgraph_version_entities = store.find(SubgraphVersionEntity::query().filter(
-        EntityFilter::new_equal("subgraph", subgraph_entity.id().unwrap()),
-    ))?;
+    #[test]
+    fn scheduled_removals_wait_for_their_block_or_time() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let long_due = ("dueSince", Value::from(0u64));
+
+        // Removal at a block, once the deployment has indexed it
+        let at_block = ("atBlock", Value::from(100u64));
+        assert!(removes_deployment(&check_removal(
+            vec![at_block.clone(), long_due.clone()],
+            "default",
+            100
+        )));
+        assert!(check_removal(vec![at_block.clone()], "default", 99).is_empty());
+
+        // Removal at a time, once it has passed
+        assert!(removes_deployment(&check_removal(
+            vec![("atTime", Value::from(now - 10)), long_due.clone()],
+            "default",
+            0
+        )));
+        assert!(check_removal(vec![("atTime", Value::from(now + 3600))], "default", 0).is_empty());
+
+        // Deployments on other nodes are left to those nodes
+        assert!(check_removal(vec![at_block, long_due], "other", 100).is_empty());
+    }
 
-    ops.push(MetadataOperation::AbortUnless {
-        description: "Subgraph must have same set of versions".to_owned(),
-        query: SubgraphVersionEntity::query().filter(EntityFilter::new_equal(
-            "subgraph",
-            subgraph_entity.id().unwrap(),
-        )),
-        entity_ids: subgraph_version_entities
-            .iter()
-            .map(|entity| entity.id().unwrap())
-            .collect(),
-    });
+    #[test]
+    fn scheduled_removals_wait_for_the_grace_period() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let at_block = ("atBlock", Value::from(100u64));
+
+        // The first check that finds the removal due only records that
+        let applied = check_removal(vec![at_block.clone()], "default", 100);
+        match applied.as_slice() {
+            [ops] => match ops.as_slice() {
+                [MetadataOperation::Update { entity, data, .. }] => {
+                    assert_eq!(SubgraphDeploymentRemovalEntity::TYPENAME, entity);
+                    let due_since = data
+                        .get("dueSince")
+                        .cloned()
+                        .and_then(Value::as_bigint)
+                        .unwrap()
+                        .to_u64();
+                    assert!(due_since >= now);
+                }
+                _ => panic!("only the due time must be recorded, got {:?}", ops),
+            },
+            _ => panic!("expected one set of operations, got {:?}", applied),
+        }
 
-    // Remove subgraph version entities, and their deployment/assignment when applicable
-    ops.extend(
-        remove_subgraph_versions(logger, store.clone(), subgraph_version_entities)?
-            .into_iter()
-            .map(|op| op.into()),
-    );
+        // The deployment stays until the grace period is over
+        let recently_due = ("dueSince", Value::from(now - 10));
+        assert!(check_removal(vec![at_block.clone(), recently_due], "default", 100).is_empty());
+        let due_after_grace = (
+            "dueSince",
+            Value::from(now - *SCHEDULED_REMOVAL_GRACE_PERIOD),
+        );
+        assert!(removes_deployment(&check_removal(
+            vec![at_block, due_after_grace],
+            "default",
+            100
+        )));
+    }
 
-    // Remove the subgraph entity
-    ops.push(MetadataOperation::Remove {
-        entity: SubgraphEntity::TYPENAME.to_owned(),
-        id: subgraph_entity.id()?,
-    });
+    #[test]
+    fn startup_concurrency_bounds_the_starts() {
+        // How many starts are running, and the most that ever ran at once
+        let running = Arc::new(Mutex::new((0, 0)));
+        let started = Arc::new(Mutex::new(HashSet::new()));
+        let ids = (0..20)
+            .map(|i| SubgraphDeploymentId::new(format!("QmStart{}", i)).unwrap())
+            .collect::<Vec<_>>();
+
+        let start = {
+            let running = running.clone();
+            let started = started.clone();
+            move |id: SubgraphDeploymentId| {
+                let running = running.clone();
+                let started = started.clone();
+                future::lazy(move || {
+                    {
+                        let (now, most) = &mut *running.lock().unwrap();
+                        *now += 1;
+                        *most = (*most).max(*now);
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                    running.lock().unwrap().0 -= 1;
+                    started.lock().unwrap().insert(id);
+                    Ok(())
+                })
+            }
+        };
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(start_concurrently(ids.clone(), 3, start).compat())
+            .unwrap();
+
+        let (now, most) = *running.lock().unwrap();
+        assert_eq!(0, now);
+        assert!(most <= 3, "{} starts ran at once", most);
+        assert_eq!(
+            ids.into_iter().collect::<HashSet<_>>(),
+            *started.lock().unwrap()
+        );
+    }
 
-    store.apply_metadata_operations(ops)?;
+    /// Records the deployments it is asked to start and stop
+    #[derive(Default)]
+    struct RecordingProvider {
+        started: Mutex<Vec<SubgraphDeploymentId>>,
+        stopped: Mutex<Vec<SubgraphDeploymentId>>,
+    }
 
-    debug!(logger, "Removed subgraph"; "subgraph_name" => name.to_string());
+    impl EventProducer<SubgraphAssignmentProviderEvent> for RecordingProvider {
+        fn take_event_stream(
+            &mut self,
+        ) -> Option<Box<dyn Stream<Item = SubgraphAssignmentProviderEvent, Error = ()> + Send>>
+        {
+            None
+        }
+    }
 
-    Ok(())
-}
+    impl SubgraphAssignmentProviderTrait for RecordingProvider {
+        fn start(
+            &self,
+            id: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            self.started.lock().unwrap().push(id);
+            Box::new(future::ok(()))
+        }
 
-/// Remove a set of subgraph versions atomically.
-///
-/// It may seem like it would be easier to generate the EntityOperations for subgraph versions
-/// removal one at a time, but that approach is significantly complicated by the fact that the
-/// store does not reflect the EntityOperations that have been accumulated so far. Earlier subgraph
-/// version creations/removals can affect later ones by affecting whether or not a subgraph deployment
-/// or assignment needs to be created/removed.
-fn remove_subgraph_versions(
-    logger: &Logger,
-    store: Arc<impl Store>,
-    version_entities_to_delete: Vec<Entity>,
-) -> Result<Vec<MetadataOperation>, SubgraphRegistrarError> {
-    let mut ops = vec![];
+        fn stop(
+            &self,
+            id: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            self.stopped.lock().unwrap().push(id);
+            Box::new(future::ok(()))
+        }
 
-    let version_entity_ids_to_delete = version_entities_to_delete
-        .iter()
-        .map(|version_entity| version_entity.id().unwrap())
-        .collect::<HashSet<_>>();
+        fn pause(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
 
-    // Get hashes that are referenced by versions that will be deleted.
-    // These are candidates for clean up.
-    let referenced_subgraph_hashes = version_entities_to_delete
-        .iter()
-        .map(|version_entity| {
-            SubgraphDeploymentId::new(
-                version_entity
-                    .get("deployment")
-                    .unwrap()
-                    .to_owned()
-                    .as_string()
-                    .unwrap(),
-            )
-            .unwrap()
-        })
-        .collect::<HashSet<_>>();
+        fn resume(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
 
-    // Find all subgraph version entities that point to these subgraph deployments
-    let (version_summaries, read_summaries_ops) =
-        store.read_subgraph_version_summaries(referenced_subgraph_hashes.into_iter().collect())?;
-    ops.extend(read_summaries_ops);
+        fn rewind(
+            &self,
+            _: SubgraphDeploymentId,
+            _: EthereumBlockPointer,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
 
-    // Simulate the planned removal of SubgraphVersion entities
-    let version_summaries_after_delete = version_summaries
-        .clone()
-        .into_iter()
-        .filter(|version_summary| !version_entity_ids_to_delete.contains(&version_summary.id))
-        .collect::<Vec<_>>();
+        fn migrate_storage(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+    }
 
-    // Create/remove assignments based on the subgraph version changes.
-    // We are only deleting versions here, so no assignments will be created,
-    // and we can safely pass None for the node ID.
-    ops.extend(
+    #[test]
+    fn blocking_writes_the_blocklist_entry() {
+        let mut store = MockStore::new();
         store
-            .reconcile_assignments(
-                logger,
-                version_summaries,
-                version_summaries_after_delete,
-                None,
-            )
-            .into_iter()
-            .map(|op| op.into()),
-    );
-
-    // Actually remove the subgraph version entities.
-    // Note: we do this last because earlier AbortUnless ops depend on these entities still
-    // existing.
-    ops.extend(
-        version_entities_to_delete
-            .iter()
-            .map(|version_entity| MetadataOperation::Remove {
-                entity: SubgraphVersionEntity::TYPENAME.to_owned(),
-                id: version_entity.id().unwrap(),
-            }),
-    );
+            .expect_apply_metadata_operations()
+            .times(1)
+            .withf(|ops| match ops.as_slice() {
+                [MetadataOperation::Set { entity, id, data }] => {
+                    entity == SubgraphDeploymentBlockEntity::TYPENAME
+                        && id == "QmBlocked"
+                        && data.get("reason") == Some(&Value::from("spam"))
+                }
+                _ => false,
+            })
+            .returning(|_| Ok(()));
 
-    Ok(ops)
-}
+        block_deployment(
+            &Logger::root(slog::Discard, o!()),
+            &store,
+            SubgraphDeploymentId::new("QmBlocked").unwrap(),
+            "spam".to_owned(),
+        )
+        .unwrap();
+    }
 
-/// Reassign a subgraph deployment to a different node.
-///
-/// Reassigning to a nodeId that does not match any reachable graph-nodes will effectively pause the
-/// subgraph syncing process.
-fn reassign_subgraph(
-    store: Arc<impl Store>,
-    hash: SubgraphDeploymentId,
-    node_id: NodeId,
-) -> Result<(), SubgraphRegistrarError> {
-    let mut ops = vec![];
+    #[test]
+    fn unblocking_removes_the_blocklist_entry() {
+        let logger = Logger::root(slog::Discard, o!());
+        let id = SubgraphDeploymentId::new("QmBlocked").unwrap();
 
-    let current_deployment = store.find(
-        SubgraphDeploymentAssignmentEntity::query()
-            .filter(EntityFilter::new_equal("id", hash.clone().to_string())),
-    )?;
+        let mut store = MockStore::new();
+        store
+            .expect_get()
+            .returning(|_| Ok(Some(Entity::from(vec![("reason", Value::from("spam"))]))));
+        store
+            .expect_apply_metadata_operations()
+            .times(1)
+            .withf(|ops| match ops.as_slice() {
+                [MetadataOperation::Remove { entity, id }] => {
+                    entity == SubgraphDeploymentBlockEntity::TYPENAME && id == "QmBlocked"
+                }
+                _ => false,
+            })
+            .returning(|_| Ok(()));
+        unblock_deployment(&logger, &store, id.clone()).unwrap();
+
+        // Deployments that are not blocked can not be unblocked
+        let mut store = MockStore::new();
+        store.expect_get().returning(|_| Ok(None));
+        store.expect_apply_metadata_operations().never();
+        match unblock_deployment(&logger, &store, id) {
+            Err(SubgraphRegistrarError::DeploymentNotFound(_)) => (),
+            _ => panic!("unblocking must fail for deployments that are not blocked"),
+        }
+    }
 
-    let current_node_id = current_deployment
-        .first()
-        .and_then(|d| d.get("nodeId"))
-        .ok_or_else(|| SubgraphRegistrarError::DeploymentNotFound(hash.clone().to_string()))?;
+    #[test]
+    fn blocklist_changes_stop_and_start_deployments_assigned_here() {
+        let id = SubgraphDeploymentId::new("QmBlocked").unwrap();
+        let here = NodeId::new("here").unwrap();
+        let elsewhere = NodeId::new("elsewhere").unwrap();
+
+        let mut store = MockStore::new();
+        store.expect_get().returning(|key| {
+            assert_eq!(
+                SubgraphDeploymentAssignmentEntity::TYPENAME,
+                key.entity_type
+            );
+            Ok(Some(Entity::from(vec![("nodeId", Value::from("here"))])))
+        });
 
-    if current_node_id.to_string() == node_id.to_string() {
-        return Err(SubgraphRegistrarError::DeploymentAssignmentUnchanged(
-            hash.clone().to_string(),
-        ));
+        assert_eq!(
+            Some(AssignmentEvent::Remove {
+                subgraph_id: id.clone(),
+                node_id: here.clone(),
+            }),
+            block_change_event(&store, &here, id.clone(), EntityChangeOperation::Set).unwrap()
+        );
+        assert_eq!(
+            Some(AssignmentEvent::Add {
+                subgraph_id: id.clone(),
+                node_id: here.clone(),
+            }),
+            block_change_event(&store, &here, id.clone(), EntityChangeOperation::Removed).unwrap()
+        );
+        assert_eq!(
+            None,
+            block_change_event(&store, &elsewhere, id, EntityChangeOperation::Set).unwrap()
+        );
     }
 
-    ops.push(MetadataOperation::AbortUnless {
-        description: "Deployment assignment is unchanged".to_owned(),
-        query: SubgraphDeploymentAssignmentEntity::query().filter(EntityFilter::And(vec![
-            EntityFilter::new_equal("nodeId", current_node_id.to_string()),
-            EntityFilter::new_equal("id", hash.clone().to_string()),
-        ])),
-        entity_ids: vec![hash.clone().to_string()],
-    });
+    #[test]
+    fn blocked_deployments_are_stopped_and_not_started() {
+        let id = SubgraphDeploymentId::new("QmBlocked").unwrap();
+        let node_id = NodeId::new("here").unwrap();
+        let logger = Logger::root(slog::Discard, o!());
 
-    // Create the assignment update operations.
-    // Note: This will also generate a remove operation for the existing subgraph assignment.
-    ops.extend(
-        SubgraphDeploymentAssignmentEntity::new(node_id)
-            .write_operations(&hash.clone())
-            .into_iter()
-            .map(|op| op.into()),
-    );
+        let mut store = MockStore::new();
+        store.expect_get().returning(|key| {
+            if key.entity_type == SubgraphDeploymentBlockEntity::TYPENAME {
+                Ok(Some(Entity::from(vec![("reason", Value::from("spam"))])))
+            } else {
+                Ok(None)
+            }
+        });
+        let store = Arc::new(store);
+        let provider = Arc::new(RecordingProvider::default());
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        for event in vec![
+            AssignmentEvent::Remove {
+                subgraph_id: id.clone(),
+                node_id: node_id.clone(),
+            },
+            AssignmentEvent::Add {
+                subgraph_id: id.clone(),
+                node_id,
+            },
+        ] {
+            runtime
+                .block_on(
+                    handle_assignment_event(event, provider.clone(), store.clone(), &logger)
+                        .compat(),
+                )
+                .unwrap();
+        }
 
-    store.apply_metadata_operations(ops)?;
+        assert_eq!(vec![id], *provider.stopped.lock().unwrap());
+        assert!(provider.started.lock().unwrap().is_empty());
+    }
 
-    Ok(())
+    #[test]
+    fn hibernated_deployments_are_not_started() {
+        let id = SubgraphDeploymentId::new("QmHibernated").unwrap();
+
+        let mut store = MockStore::new();
+        store.expect_get().returning(|key| {
+            if key.entity_type == SubgraphDeploymentIdleEntity::TYPENAME {
+                Ok(Some(Entity::from(vec![("hibernated", Value::from(true))])))
+            } else {
+                Ok(None)
+            }
+        });
+        let provider = Arc::new(RecordingProvider::default());
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(
+                handle_assignment_event(
+                    AssignmentEvent::Add {
+                        subgraph_id: id,
+                        node_id: NodeId::new("here").unwrap(),
+                    },
+                    provider.clone(),
+                    Arc::new(store),
+                    &Logger::root(slog::Discard, o!()),
+                )
+                .compat(),
+            )
+            .unwrap();
+        assert!(provider.started.lock().unwrap().is_empty());
+    }
 }
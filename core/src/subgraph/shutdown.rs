@@ -0,0 +1,356 @@
+use lazy_static::lazy_static;
+use std::env;
+use std::time::{Duration, Instant};
+
+use graph::data::subgraph::schema::{SubgraphDeploymentAssignmentEntity, TypedEntity};
+use graph::prelude::{SubgraphAssignmentProvider as SubgraphAssignmentProviderTrait, *};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+use super::idle::record_query;
+
+lazy_static! {
+    // How long a node that is asked to shut down waits for its deployments
+    // to finish the blocks they are processing, in seconds
+    static ref SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(
+        env::var("GRAPH_SHUTDOWN_TIMEOUT")
+            .unwrap_or("150".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_SHUTDOWN_TIMEOUT")
+    );
+}
+
+/// What happened to the deployments on this node while it shut down
+#[derive(Debug, Default)]
+struct ShutdownReport {
+    /// Deployments that finished their block and were stopped
+    stopped: Vec<SubgraphDeploymentId>,
+    /// Deployments that are assigned to this node but were not running
+    not_running: Vec<SubgraphDeploymentId>,
+    /// Deployments that could not be stopped
+    failed: Vec<(SubgraphDeploymentId, String)>,
+    /// Deployments that were still being stopped when the shutdown
+    /// timeout expired
+    timed_out: Vec<SubgraphDeploymentId>,
+    /// How many deployments had queries that were recorded in the store
+    queries_recorded: usize,
+}
+
+/// Shuts the node down when it receives SIGTERM or SIGINT. Instead of
+/// dropping deployments in the middle of a block, every deployment on this
+/// node is drained, so that the block it is processing is committed, and
+/// then stopped before the process exits. Deployments that take longer than
+/// `GRAPH_SHUTDOWN_TIMEOUT` are abandoned; their partial block is reverted
+/// when they start again. A second signal exits right away.
+///
+/// While the node shuts down, deployments are not started anymore, and
+/// queries that were only tracked in memory are recorded in the store.
+pub struct ShutdownCoordinator<P, S> {
+    logger: Logger,
+    provider: Arc<P>,
+    store: Arc<S>,
+    node_id: NodeId,
+    startup: Arc<StartupStatus>,
+    activity: Option<Arc<DeploymentActivity>>,
+}
+
+impl<P, S> ShutdownCoordinator<P, S>
+where
+    P: SubgraphAssignmentProviderTrait,
+    S: Store,
+{
+    pub fn new(
+        logger_factory: &LoggerFactory,
+        provider: Arc<P>,
+        store: Arc<S>,
+        node_id: NodeId,
+        startup: Arc<StartupStatus>,
+    ) -> Self {
+        ShutdownCoordinator {
+            logger: logger_factory.component_logger("ShutdownCoordinator", None),
+            provider,
+            store,
+            node_id,
+            startup,
+            activity: None,
+        }
+    }
+
+    /// Record the queries that `activity` has not recorded in the store yet
+    /// before the node exits
+    pub fn with_activity(mut self, activity: Arc<DeploymentActivity>) -> Self {
+        self.activity = Some(activity);
+        self
+    }
+
+    pub fn start(self) {
+        // Blocking due to store interactions. Won't be blocking after #905.
+        graph::spawn_blocking(async move {
+            let mut signals = Signals::new();
+            let signal = signals.next().await;
+            info!(
+                self.logger,
+                "Shutting down";
+                "signal" => signal,
+                "timeout_secs" => SHUTDOWN_TIMEOUT.as_secs()
+            );
+
+            // Load balancers should stop sending queries to this node, and
+            // deployments must not be started again
+            self.startup.set_shutting_down();
+
+            let logger = self.logger.clone();
+            graph::spawn(async move {
+                let signal = signals.next().await;
+                warn!(
+                    logger,
+                    "Exiting without waiting for deployments";
+                    "signal" => signal
+                );
+                std::process::exit(1);
+            });
+
+            let started = Instant::now();
+            let report = self.shutdown(*SHUTDOWN_TIMEOUT).await;
+            info!(
+                self.logger,
+                "Shut down";
+                "stopped" => report.stopped.len(),
+                "not_running" => report.not_running.len(),
+                "failed" => report.failed.len(),
+                "timed_out" => format!("{:?}", report.timed_out),
+                "queries_recorded" => report.queries_recorded,
+                "ms" => started.elapsed().as_millis()
+            );
+            for (id, error) in &report.failed {
+                warn!(
+                    self.logger,
+                    "Failed to stop subgraph during shutdown";
+                    "subgraph_id" => id.to_string(),
+                    "error" => error
+                );
+            }
+
+            std::process::exit(0);
+        });
+    }
+
+    /// Stop all deployments assigned to this node, each of them after it
+    /// finished the block it is processing, and then record the queries
+    /// that were only tracked in memory
+    async fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        let mut report = ShutdownReport::default();
+        match self.assigned_deployments() {
+            Ok(ids) => {
+                let stops = ids.into_iter().map(|id| {
+                    let stop = self.provider.stop(id.clone()).compat();
+                    async move { (id, tokio::time::timeout(timeout, stop).await) }
+                });
+                for (id, result) in futures03::future::join_all(stops).await {
+                    match result {
+                        Ok(Ok(())) => report.stopped.push(id),
+                        Ok(Err(SubgraphAssignmentProviderError::NotRunning(_))) => {
+                            report.not_running.push(id)
+                        }
+                        Ok(Err(e)) => report.failed.push((id, e.to_string())),
+                        Err(_) => report.timed_out.push(id),
+                    }
+                }
+            }
+            Err(e) => error!(
+                self.logger,
+                "Failed to load assignments, not waiting for deployments";
+                "error" => e.to_string()
+            ),
+        }
+
+        let queries = self
+            .activity
+            .as_ref()
+            .map(|activity| activity.unstored_queries())
+            .unwrap_or_default();
+        for (id, queried_at) in queries {
+            match record_query(&*self.store, &id, queried_at) {
+                Ok(()) => report.queries_recorded += 1,
+                Err(e) => warn!(
+                    self.logger,
+                    "Failed to record queries during shutdown";
+                    "subgraph_id" => id.to_string(),
+                    "error" => e.to_string()
+                ),
+            }
+        }
+        report
+    }
+
+    fn assigned_deployments(&self) -> Result<Vec<SubgraphDeploymentId>, Error> {
+        let query = SubgraphDeploymentAssignmentEntity::query()
+            .filter(EntityFilter::new_equal("nodeId", self.node_id.to_string()));
+        self.store
+            .find(query)
+            .map_err(|e| format_err!("Error querying subgraph assignments: {}", e))?
+            .into_iter()
+            .map(|assignment| {
+                assignment.id().and_then(|id| {
+                    SubgraphDeploymentId::new(id)
+                        .map_err(|()| format_err!("Invalid subgraph hash in assignment entity"))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Listens for the signals that ask the process to stop. The listeners
+/// are kept for the lifetime of the process so that no signal is missed
+/// between waiting for the first and the second one
+struct Signals {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl Signals {
+    fn new() -> Self {
+        Signals {
+            terminate: signal(SignalKind::terminate()).expect("failed to listen for SIGTERM"),
+            interrupt: signal(SignalKind::interrupt()).expect("failed to listen for SIGINT"),
+        }
+    }
+
+    /// Wait until the process is asked to stop and return the name of the
+    /// signal that asked for it
+    async fn next(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph_mock::MockStore;
+
+    /// Stops deployments depending on their ID
+    struct StopProvider;
+
+    impl EventProducer<SubgraphAssignmentProviderEvent> for StopProvider {
+        fn take_event_stream(
+            &mut self,
+        ) -> Option<Box<dyn Stream<Item = SubgraphAssignmentProviderEvent, Error = ()> + Send>>
+        {
+            None
+        }
+    }
+
+    impl SubgraphAssignmentProviderTrait for StopProvider {
+        fn start(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+
+        fn stop(
+            &self,
+            id: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            match id.as_str() {
+                "QmStopped" => Box::new(future::ok(())),
+                "QmNotRunning" => {
+                    Box::new(future::err(SubgraphAssignmentProviderError::NotRunning(id)))
+                }
+                "QmSlow" => Box::new(future::empty()),
+                _ => Box::new(future::err(SubgraphAssignmentProviderError::Unknown(
+                    format_err!("database is gone"),
+                ))),
+            }
+        }
+
+        fn pause(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+
+        fn resume(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+
+        fn rewind(
+            &self,
+            _: SubgraphDeploymentId,
+            _: EthereumBlockPointer,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+
+        fn migrate_storage(
+            &self,
+            _: SubgraphDeploymentId,
+        ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>
+        {
+            unimplemented!()
+        }
+    }
+
+    fn id(hash: &str) -> SubgraphDeploymentId {
+        SubgraphDeploymentId::new(hash).unwrap()
+    }
+
+    #[test]
+    fn shutdown_stops_deployments_and_records_queries() {
+        let mut store = MockStore::new();
+        store.expect_find().times(1).returning(|_| {
+            Ok(["QmStopped", "QmNotRunning", "QmSlow", "QmFailed"]
+                .iter()
+                .map(|id| {
+                    Entity::from(vec![
+                        ("id", Value::from(*id)),
+                        ("nodeId", Value::from("index_node_0")),
+                    ])
+                })
+                .collect())
+        });
+        // The queried deployment is not hibernated
+        store.expect_get().times(1).returning(|_| Ok(None));
+        store
+            .expect_apply_metadata_operations()
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let activity = Arc::new(DeploymentActivity::new());
+        activity.record_query(&id("QmQueried"));
+        let coordinator = ShutdownCoordinator::new(
+            &LoggerFactory::new(Logger::root(slog::Discard, o!()), None),
+            Arc::new(StopProvider),
+            Arc::new(store),
+            NodeId::new("index_node_0").unwrap(),
+            Arc::new(StartupStatus::new()),
+        )
+        .with_activity(activity.clone());
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(coordinator.shutdown(Duration::from_millis(100)));
+        assert_eq!(vec![id("QmStopped")], report.stopped);
+        assert_eq!(vec![id("QmNotRunning")], report.not_running);
+        assert_eq!(vec![id("QmSlow")], report.timed_out);
+        assert_eq!(
+            vec![id("QmFailed")],
+            report
+                .failed
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, report.queries_recorded);
+    }
+}
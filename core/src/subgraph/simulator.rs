@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+
+use graph::components::ethereum::blocks_with_triggers;
+use graph::log::collect::CollectingDrain;
+use graph::prelude::web3::types::Log;
+use graph::prelude::{
+    HandlerSimulator as HandlerSimulatorTrait, SubgraphInstance as SubgraphInstanceTrait, *,
+};
+use graph::util::lfu_cache::LfuCache;
+
+use crate::subgraph::ipfs_retry_policy::IPFS_RETRY_POLICIES;
+use crate::subgraph::SubgraphInstance;
+use crate::MetricsRegistry;
+
+/// Runs a single handler of a deployment for the triggers of a block, the
+/// way the instance manager would, but discards the block state instead of
+/// writing it to the store
+pub struct HandlerSimulator<L, H, S> {
+    logger_factory: LoggerFactory,
+    resolver: Arc<L>,
+    host_builder: H,
+    stores: HashMap<String, Arc<S>>,
+    eth_adapters: HashMap<String, Arc<dyn EthereumAdapter>>,
+    data_source_loader: Arc<dyn DataSourceLoader + Send + Sync>,
+}
+
+impl<L, H, S> HandlerSimulator<L, H, S>
+where
+    L: LinkResolver + Clone,
+    H: RuntimeHostBuilder,
+    S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
+{
+    pub fn new(
+        logger_factory: &LoggerFactory,
+        resolver: Arc<L>,
+        host_builder: H,
+        stores: HashMap<String, Arc<S>>,
+        eth_adapters: HashMap<String, Arc<dyn EthereumAdapter>>,
+        data_source_loader: Arc<dyn DataSourceLoader + Send + Sync>,
+    ) -> Self {
+        let logger = logger_factory.component_logger("HandlerSimulator", None);
+        HandlerSimulator {
+            logger_factory: logger_factory.with_parent(logger),
+            resolver,
+            host_builder,
+            stores,
+            eth_adapters,
+            data_source_loader,
+        }
+    }
+}
+
+impl<L, H, S> HandlerSimulatorTrait for HandlerSimulator<L, H, S>
+where
+    L: LinkResolver + Clone,
+    H: RuntimeHostBuilder,
+    S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
+{
+    fn simulate(
+        &self,
+        simulation: HandlerSimulation,
+    ) -> Box<dyn Future<Item = SimulationResult, Error = Error> + Send> {
+        let id = simulation.deployment.clone();
+        let logger = self.logger_factory.subgraph_logger(&id);
+        let resolver = Arc::new(
+            self.resolver
+                .as_ref()
+                .clone()
+                .for_deployment(id.clone())
+                .with_retry_policy(IPFS_RETRY_POLICIES.for_deployment(&id)),
+        );
+        let host_builder = self.host_builder.clone();
+        let stores = self.stores.clone();
+        let eth_adapters = self.eth_adapters.clone();
+        let loader = self.data_source_loader.clone();
+
+        info!(logger, "Simulate handler";
+              "handler" => &simulation.handler,
+              "block_number" => simulation.block_number);
+
+        let link = Link {
+            link: format!("/ipfs/{}", id),
+        };
+        let logger_for_resolve = logger.clone();
+        let logger_for_data_sources = logger.clone();
+        Box::new(
+            SubgraphManifest::resolve(link, resolver, logger_for_resolve)
+                .map_err(Error::from)
+                .join(
+                    // Only data sources that existed at the block can have
+                    // triggers in it
+                    loader.load_dynamic_data_sources(
+                        &id,
+                        Some(simulation.block_number as BlockNumber),
+                        logger_for_data_sources,
+                    ),
+                )
+                .and_then(move |(mut manifest, data_sources)| {
+                    manifest.data_sources.extend(data_sources);
+                    manifest.data_sources = handler_data_sources(
+                        manifest.data_sources,
+                        &simulation.handler,
+                        simulation.data_source.as_ref().map(String::as_str),
+                    );
+                    if manifest.data_sources.is_empty() {
+                        return Err(format_err!(
+                            "deployment `{}` has no handler `{}`",
+                            id,
+                            simulation.handler
+                        ));
+                    }
+
+                    let network = manifest.network_name();
+                    let store = stores
+                        .get(&network)
+                        .cloned()
+                        .ok_or_else(|| format_err!("no store for network `{}`", network))?;
+                    let eth_adapter = eth_adapters.get(&network).cloned().ok_or_else(|| {
+                        format_err!("no Ethereum adapter for network `{}`", network)
+                    })?;
+                    Ok((manifest, store, eth_adapter, simulation))
+                })
+                .and_then(move |(manifest, store, eth_adapter, simulation)| {
+                    simulate_handler(
+                        logger,
+                        host_builder,
+                        store,
+                        eth_adapter,
+                        manifest,
+                        simulation,
+                    )
+                }),
+        )
+    }
+}
+
+/// The data sources that have a handler named `handler`, with all their
+/// other handlers removed. With `data_source`, only the data source of that
+/// name is considered
+fn handler_data_sources(
+    data_sources: Vec<DataSource>,
+    handler: &str,
+    data_source: Option<&str>,
+) -> Vec<DataSource> {
+    data_sources
+        .into_iter()
+        .filter(|ds| data_source.map_or(true, |name| ds.name == name))
+        .filter_map(|mut ds| {
+            let mapping = &mut ds.mapping;
+            mapping.event_handlers.retain(|h| h.handler == handler);
+            mapping.call_handlers.retain(|h| h.handler == handler);
+            mapping.block_handlers.retain(|h| h.handler == handler);
+            if mapping.event_handlers.is_empty()
+                && mapping.call_handlers.is_empty()
+                && mapping.block_handlers.is_empty()
+            {
+                None
+            } else {
+                Some(ds)
+            }
+        })
+        .collect()
+}
+
+/// Whether `trigger` happened in the transaction `hash`; block triggers do
+/// not belong to any transaction
+fn in_transaction(trigger: &EthereumTrigger, hash: &H256) -> bool {
+    match trigger {
+        EthereumTrigger::Log(log) => log.transaction_hash.as_ref() == Some(hash),
+        EthereumTrigger::Call(call) => call.transaction_hash.as_ref() == Some(hash),
+        EthereumTrigger::Block(..) => false,
+    }
+}
+
+fn simulate_handler<H, S>(
+    logger: Logger,
+    host_builder: H,
+    store: Arc<S>,
+    eth_adapter: Arc<dyn EthereumAdapter>,
+    manifest: SubgraphManifest,
+    simulation: HandlerSimulation,
+) -> impl Future<Item = SimulationResult, Error = Error> + Send
+where
+    H: RuntimeHostBuilder,
+    S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
+{
+    let id = manifest.id.clone();
+    // A supplied event replaces the triggers of the block, which therefore
+    // don't need to be looked for
+    let filter = match simulation.log {
+        Some(_) => EthereumTriggerFilter::default(),
+        None => EthereumTriggerFilter::from_data_sources(&manifest.data_sources),
+    };
+    let entity_cache = entity_cache_before(store.as_ref(), &id, simulation.block_number);
+
+    // The metrics of a simulation go to a registry of their own so that
+    // they don't clash with those of the running deployment
+    let registry = Arc::new(MetricsRegistry::new(
+        logger.clone(),
+        Arc::new(Registry::new()),
+    ));
+    let stopwatch = StopwatchMetrics::new(logger.clone(), id.clone(), registry.clone());
+    let host_metrics = Arc::new(HostMetrics::new(
+        registry.clone(),
+        id.to_string(),
+        stopwatch,
+    ));
+    let ethrpc_metrics = Arc::new(SubgraphEthRpcMetrics::new(registry, id.to_string()));
+
+    // The mappings log to a logger of their own so that their messages can
+    // be returned with the result
+    let drain = CollectingDrain::new();
+    let mapping_logger = Logger::root(drain.clone(), o!("subgraph_id" => id.to_string()));
+
+    future::result(SubgraphInstance::from_manifest(
+        &mapping_logger,
+        manifest,
+        host_builder,
+        host_metrics,
+    ))
+    .join3(
+        future::result(entity_cache),
+        blocks_with_triggers(
+            eth_adapter,
+            logger.clone(),
+            store.clone(),
+            ethrpc_metrics,
+            simulation.block_number,
+            simulation.block_number,
+            filter.log,
+            filter.call,
+            filter.block,
+        )
+        .and_then(move |blocks| {
+            blocks
+                .into_iter()
+                .next()
+                .ok_or_else(|| format_err!("block #{} not found", simulation.block_number))
+                .map(|block| (block, simulation))
+        }),
+    )
+    .and_then(move |(instance, entity_cache, (block, simulation))| {
+        let light_block = Arc::new(block.ethereum_block.light_block());
+        let transaction_hash = simulation.transaction_hash;
+        let triggers: Vec<_> = match simulation.log {
+            Some(log) => vec![EthereumTrigger::Log(supplied_log(
+                log,
+                &light_block,
+                transaction_hash,
+            )?)],
+            None => block
+                .triggers
+                .into_iter()
+                .filter(|trigger| {
+                    transaction_hash
+                        .as_ref()
+                        .map_or(true, |hash| in_transaction(trigger, hash))
+                })
+                .collect(),
+        };
+        let trigger_count = triggers.len();
+        let instance = Arc::new(instance);
+
+        let mut state = BlockState::with_cache(LfuCache::new());
+        state.entity_cache = entity_cache;
+        Ok(stream::iter_ok::<_, Error>(triggers)
+            .fold(state, move |state, trigger| {
+                instance.process_trigger(&mapping_logger, light_block.clone(), trigger, state)
+            })
+            .then(move |result| {
+                let mut simulated = SimulationResult {
+                    triggers: trigger_count,
+                    ..SimulationResult::default()
+                };
+                match result.and_then(|state| {
+                    let data_sources = state
+                        .created_data_sources
+                        .iter()
+                        .map(|info| format!("{}({})", info.template.name, info.params.join(", ")))
+                        .collect();
+                    let mods = state.entity_cache.as_modifications(store.as_ref())?;
+                    Ok((mods.modifications, data_sources))
+                }) {
+                    Ok((mods, data_sources)) => {
+                        simulated.changes = mods.into_iter().map(simulated_change).collect();
+                        simulated.data_sources = data_sources;
+                    }
+                    Err(e) => simulated.error = Some(e.to_string()),
+                }
+                simulated.logs = drain.messages();
+
+                info!(logger, "Simulated handler";
+                      "triggers" => simulated.triggers,
+                      "changes" => simulated.changes.len(),
+                      "error" => simulated.error.as_ref().map(String::as_str).unwrap_or(""));
+                Ok::<_, Error>(simulated)
+            }))
+    })
+    .flatten()
+}
+
+/// A cache that reads the entities of the deployment `id` as they were
+/// before the block `number`. Only relational storage can read entities as
+/// of a block; with JSONB storage, the latest entities are those before
+/// the block after the deployment's latest block, and no other block can
+/// be simulated
+fn entity_cache_before<S: Store + SubgraphDeploymentStore>(
+    store: &S,
+    id: &SubgraphDeploymentId,
+    number: u64,
+) -> Result<EntityCache, Error> {
+    if store.uses_relational_schema(id)? {
+        return Ok(EntityCache::new().at_block(number as BlockNumber - 1));
+    }
+    match store.block_ptr(id.clone())? {
+        Some(ptr) if ptr.number + 1 != number => Err(format_err!(
+            "deployment `{}` stores its entities as JSONB and can only simulate block #{}",
+            id,
+            ptr.number + 1
+        )),
+        _ => Ok(EntityCache::new()),
+    }
+}
+
+/// The supplied event `log` as if it was emitted in `block` by its
+/// transaction, or by the transaction `transaction_hash` if it names none
+fn supplied_log(
+    mut log: Log,
+    block: &LightEthereumBlock,
+    transaction_hash: Option<H256>,
+) -> Result<Log, Error> {
+    log.block_hash = block.hash;
+    log.block_number = block.number;
+    log.transaction_hash = log.transaction_hash.or(transaction_hash);
+    let transaction = block.transaction_for_log(&log).ok_or_else(|| {
+        format_err!(
+            "the transaction of the event is not in block #{}",
+            block.number()
+        )
+    })?;
+    log.transaction_index = transaction.transaction_index;
+    Ok(log)
+}
+
+fn simulated_change(modification: EntityModification) -> SimulatedChange {
+    match modification {
+        EntityModification::Insert { key, data } | EntityModification::Overwrite { key, data } => {
+            SimulatedChange::Set {
+                entity: key.entity_type,
+                id: key.entity_id,
+                data,
+            }
+        }
+        EntityModification::Remove { key } => SimulatedChange::Remove {
+            entity: key.entity_type,
+            id: key.entity_id,
+        },
+    }
+}
+
+#[cfg(test)]
+fn test_data_source(name: &str, event_handlers: &[&str], block_handlers: &[&str]) -> DataSource {
+    DataSource {
+        kind: "ethereum/contract".to_owned(),
+        network: Some("mainnet".to_owned()),
+        name: name.to_owned(),
+        source: graph::data::subgraph::Source {
+            address: None,
+            abi: name.to_owned(),
+            start_block: 0,
+        },
+        mapping: graph::data::subgraph::Mapping {
+            kind: "ethereum/events".to_owned(),
+            api_version: "0.0.4".to_owned(),
+            language: "wasm/assemblyscript".to_owned(),
+            entities: vec![],
+            abis: vec![],
+            block_handlers: block_handlers
+                .iter()
+                .map(|handler| MappingBlockHandler {
+                    handler: handler.to_string(),
+                    filter: None,
+                })
+                .collect(),
+            call_handlers: vec![],
+            event_handlers: event_handlers
+                .iter()
+                .map(|handler| MappingEventHandler {
+                    event: "Transfer(address,address,uint256)".to_owned(),
+                    topic0: None,
+                    handler: handler.to_string(),
+                })
+                .collect(),
+            runtime: Arc::new(Default::default()),
+            link: Link {
+                link: "/ipfs/mapping".to_owned(),
+            },
+        },
+        templates: vec![],
+    }
+}
+
+#[test]
+fn handler_data_sources_keep_only_the_handler() {
+    let data_sources = || {
+        vec![
+            test_data_source("Token", &["handleTransfer", "handleApproval"], &[]),
+            test_data_source("Pool", &["handleTransfer"], &["handleBlock"]),
+            test_data_source("Registry", &["handleRegistration"], &[]),
+        ]
+    };
+    let names = |data_sources: &[DataSource]| -> Vec<String> {
+        data_sources.iter().map(|ds| ds.name.clone()).collect()
+    };
+
+    let transfers = handler_data_sources(data_sources(), "handleTransfer", None);
+    assert_eq!(vec!["Token", "Pool"], names(&transfers));
+    for ds in &transfers {
+        let handlers: Vec<_> = ds
+            .mapping
+            .event_handlers
+            .iter()
+            .map(|h| &h.handler)
+            .collect();
+        assert_eq!(vec!["handleTransfer"], handlers);
+        assert!(ds.mapping.block_handlers.is_empty());
+    }
+
+    let blocks = handler_data_sources(data_sources(), "handleBlock", None);
+    assert_eq!(vec!["Pool"], names(&blocks));
+    assert!(blocks[0].mapping.event_handlers.is_empty());
+    assert_eq!(1, blocks[0].mapping.block_handlers.len());
+
+    let pool = handler_data_sources(data_sources(), "handleTransfer", Some("Pool"));
+    assert_eq!(vec!["Pool"], names(&pool));
+
+    assert!(handler_data_sources(data_sources(), "handleApproval", Some("Pool")).is_empty());
+    assert!(handler_data_sources(data_sources(), "handleSwap", None).is_empty());
+}
+
+#[test]
+fn in_transaction_matches_logs_and_calls() {
+    let hash = H256::from_low_u64_be(1);
+    let other = H256::from_low_u64_be(2);
+
+    let log = |transaction_hash: Option<H256>| -> EthereumTrigger {
+        let log: Log = serde_json::from_value(serde_json::json!({
+            "address": "0x0000000000000000000000000000000000000001",
+            "topics": [],
+            "data": "0x",
+            "transactionHash": transaction_hash,
+        }))
+        .unwrap();
+        EthereumTrigger::Log(log)
+    };
+    let call = |transaction_hash: Option<H256>| {
+        EthereumTrigger::Call(EthereumCall {
+            transaction_hash,
+            ..EthereumCall::default()
+        })
+    };
+
+    assert!(in_transaction(&log(Some(hash)), &hash));
+    assert!(!in_transaction(&log(Some(other)), &hash));
+    assert!(!in_transaction(&log(None), &hash));
+    assert!(in_transaction(&call(Some(hash)), &hash));
+    assert!(!in_transaction(&call(Some(other)), &hash));
+    assert!(!in_transaction(&call(None), &hash));
+
+    let block = EthereumTrigger::Block(
+        EthereumBlockPointer::from((hash, 1u64)),
+        EthereumBlockTriggerType::Every,
+    );
+    assert!(!in_transaction(&block, &hash));
+}
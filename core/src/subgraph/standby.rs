@@ -0,0 +1,646 @@
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::sync::Mutex;
+
+use graph::components::ethereum::{blocks_with_triggers, triggers_in_block};
+use graph::data::subgraph::schema::SubgraphDeploymentAssignmentEntity;
+use graph::prelude::{SubgraphInstance as SubgraphInstanceTrait, *};
+use graph::util::lfu_cache::LfuCache;
+
+use crate::subgraph::ipfs_retry_policy::IPFS_RETRY_POLICIES;
+use crate::subgraph::registrar::STANDBY_CHECK_INTERVAL;
+use crate::subgraph::SubgraphInstance;
+use crate::MetricsRegistry;
+
+lazy_static! {
+    // How many blocks a standby node verifies for each deployment it
+    // follows every time it checks them
+    static ref STANDBY_VERIFY_BLOCKS: u64 = env::var("GRAPH_STANDBY_VERIFY_BLOCKS")
+        .unwrap_or("1000".into())
+        .parse::<u64>()
+        .expect("invalid GRAPH_STANDBY_VERIFY_BLOCKS");
+}
+
+/// How the changes a standby node computed for a block compare to the
+/// proof of indexing that the assigned node stored for it
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    /// Both nodes made the same changes
+    Agree,
+    /// The nodes made different changes; `expected` is the digest of the
+    /// assigned node and `actual` the one of the standby node
+    Disagree { expected: H256, actual: H256 },
+    /// The standby node made changes in a block that the assigned node did
+    /// not process
+    Unprocessed,
+    /// The assigned node processed a different block with that number
+    Reorg,
+}
+
+/// Compare the changes `mods` that this node computed for `block` with the
+/// proof `primary` of the assigned node for the same block, given the
+/// proof `previous` of the assigned node for the block it processed before
+fn verify_block(
+    previous: Option<H256>,
+    block: &EthereumBlockPointer,
+    primary: Option<&ProofOfIndexing>,
+    mods: &[EntityModification],
+) -> Result<Verdict, StoreError> {
+    let primary = match primary {
+        Some(primary) => primary,
+        None if mods.is_empty() => return Ok(Verdict::Agree),
+        None => return Ok(Verdict::Unprocessed),
+    };
+    if &primary.block != block {
+        return Ok(Verdict::Reorg);
+    }
+    let actual = ProofOfIndexing::digest(previous, block, mods)?;
+    if actual == primary.digest {
+        Ok(Verdict::Agree)
+    } else {
+        Ok(Verdict::Disagree {
+            expected: primary.digest,
+            actual,
+        })
+    }
+}
+
+/// A deployment that this node follows as its standby node
+struct Followed<H: RuntimeHostBuilder, S> {
+    id: SubgraphDeploymentId,
+    logger: Logger,
+    store: Arc<S>,
+    eth_adapter: Arc<dyn EthereumAdapter>,
+    instance: SubgraphInstance<H>,
+    data_sources: Vec<DataSource>,
+    top_level_templates: Arc<Vec<DataSourceTemplate>>,
+    non_fatal_errors: bool,
+    host_metrics: Arc<HostMetrics>,
+    ethrpc_metrics: Arc<SubgraphEthRpcMetrics>,
+    /// The proof of the assigned node for the last block it processed
+    /// that we verified
+    verified: ProofOfIndexing,
+    /// The first block that we have not verified yet
+    next_block: u64,
+}
+
+/// Keeps the deployments for which this node is the standby node loaded
+/// and runs their handlers for every block that the assigned node
+/// processes, without writing anything to the store. The changes the
+/// handlers make are checked against the proof of indexing of the assigned
+/// node, so that a standby node that disagrees with it is noticed before
+/// it is promoted. Handlers read entities as of the block before the one
+/// they run for, which only deployments with relational storage support
+pub struct StandbyFollower<L, H: RuntimeHostBuilder, S> {
+    logger: Logger,
+    logger_factory: LoggerFactory,
+    resolver: Arc<L>,
+    host_builder: H,
+    store: Arc<S>,
+    stores: HashMap<String, Arc<S>>,
+    eth_adapters: HashMap<String, Arc<dyn EthereumAdapter>>,
+    data_source_loader: Arc<dyn DataSourceLoader + Send + Sync>,
+    node_id: NodeId,
+    followed: Mutex<HashMap<SubgraphDeploymentId, Followed<H, S>>>,
+}
+
+impl<L, H, S> StandbyFollower<L, H, S>
+where
+    L: LinkResolver + Clone,
+    H: RuntimeHostBuilder,
+    S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
+{
+    pub fn new(
+        logger_factory: &LoggerFactory,
+        resolver: Arc<L>,
+        host_builder: H,
+        store: Arc<S>,
+        stores: HashMap<String, Arc<S>>,
+        eth_adapters: HashMap<String, Arc<dyn EthereumAdapter>>,
+        data_source_loader: Arc<dyn DataSourceLoader + Send + Sync>,
+        node_id: NodeId,
+    ) -> Self {
+        let logger = logger_factory.component_logger("StandbyFollower", None);
+        StandbyFollower {
+            logger_factory: logger_factory.with_parent(logger.clone()),
+            logger,
+            resolver,
+            host_builder,
+            store,
+            stores,
+            eth_adapters,
+            data_source_loader,
+            node_id,
+            followed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn start(self) {
+        use futures03::stream::StreamExt;
+
+        let follower = Arc::new(self);
+
+        // Blocking due to store interactions. Won't be blocking after #905.
+        graph::spawn_blocking(
+            tokio::time::interval(*STANDBY_CHECK_INTERVAL).for_each(move |_| {
+                let follower = follower.clone();
+                async move {
+                    if let Err(e) = follower.follow().await {
+                        warn!(
+                            follower.logger,
+                            "Failed to follow standby deployments";
+                            "error" => e.to_string()
+                        );
+                    }
+                }
+            }),
+        );
+    }
+
+    /// Verify the blocks that the assigned nodes processed since the last
+    /// check for all deployments for which this node is the standby node
+    async fn follow(&self) -> Result<(), Error> {
+        let ids = self
+            .store
+            .find(
+                SubgraphDeploymentAssignmentEntity::query().filter(EntityFilter::new_equal(
+                    "standbyNodeId",
+                    self.node_id.to_string(),
+                )),
+            )?
+            .into_iter()
+            .map(|assignment| {
+                SubgraphDeploymentId::new(assignment.id()?)
+                    .map_err(|()| format_err!("Invalid subgraph hash in assignment entity"))
+            })
+            .collect::<Result<HashSet<_>, Error>>()?;
+
+        // Deployments for which we are not the standby node anymore, e.g.,
+        // because we were promoted, are dropped
+        let mut followed = std::mem::take(&mut *self.followed.lock().unwrap());
+        followed.retain(|id, _| ids.contains(id));
+
+        for id in ids {
+            let deployment = match followed.remove(&id) {
+                Some(deployment) => Ok(Some(deployment)),
+                None => self.load(&id).await,
+            };
+            let deployment = match deployment {
+                Ok(Some(deployment)) => deployment,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(
+                        self.logger_factory.subgraph_logger(&id),
+                        "Failed to load standby deployment";
+                        "error" => e.to_string()
+                    );
+                    continue;
+                }
+            };
+            let logger = deployment.logger.clone();
+            match verify_blocks(deployment).await {
+                Ok(Some(deployment)) => {
+                    followed.insert(id, deployment);
+                }
+                // The deployment is loaded again the next time around
+                Ok(None) => (),
+                Err(e) => warn!(
+                    logger,
+                    "Failed to verify blocks of standby deployment";
+                    "error" => e.to_string()
+                ),
+            }
+        }
+
+        *self.followed.lock().unwrap() = followed;
+        Ok(())
+    }
+
+    /// Load the deployment `id` with the data sources it had at the latest
+    /// block that its assigned node processed. Returns `None` if that node
+    /// has not processed any blocks yet
+    async fn load(&self, id: &SubgraphDeploymentId) -> Result<Option<Followed<H, S>>, Error> {
+        let logger = self.logger_factory.subgraph_logger(id);
+        let resolver = Arc::new(
+            self.resolver
+                .as_ref()
+                .clone()
+                .for_deployment(id.clone())
+                .with_retry_policy(IPFS_RETRY_POLICIES.for_deployment(id)),
+        );
+        let link = Link {
+            link: format!("/ipfs/{}", id),
+        };
+        let mut manifest = SubgraphManifest::resolve(link, resolver, logger.clone())
+            .compat()
+            .await?;
+
+        let network = manifest.network_name();
+        let store = self
+            .stores
+            .get(&network)
+            .cloned()
+            .ok_or_else(|| format_err!("no store for network `{}`", network))?;
+        let eth_adapter = self
+            .eth_adapters
+            .get(&network)
+            .cloned()
+            .ok_or_else(|| format_err!("no Ethereum adapter for network `{}`", network))?;
+
+        let block_ptr = match store.block_ptr(id.clone())? {
+            Some(block_ptr) => block_ptr,
+            None => return Ok(None),
+        };
+        let verified = match store.proof_of_indexing(id, block_ptr.number as BlockNumber)? {
+            Some(proof) => proof,
+            None => return Ok(None),
+        };
+
+        let data_sources = self
+            .data_source_loader
+            .clone()
+            .load_dynamic_data_sources(id, Some(block_ptr.number as BlockNumber), logger.clone())
+            .compat()
+            .await?;
+        manifest.data_sources.extend(data_sources);
+
+        // The metrics of a standby deployment go to a registry of their
+        // own so that they don't clash with those of a running deployment
+        let registry = Arc::new(MetricsRegistry::new(
+            logger.clone(),
+            Arc::new(Registry::new()),
+        ));
+        let stopwatch = StopwatchMetrics::new(logger.clone(), id.clone(), registry.clone());
+        let host_metrics = Arc::new(HostMetrics::new(
+            registry.clone(),
+            id.to_string(),
+            stopwatch,
+        ));
+        let ethrpc_metrics = Arc::new(SubgraphEthRpcMetrics::new(registry, id.to_string()));
+
+        let data_sources = manifest.data_sources.clone();
+        let top_level_templates = Arc::new(manifest.templates.clone());
+        let non_fatal_errors = manifest.non_fatal_errors();
+        let instance = SubgraphInstance::from_manifest(
+            &logger,
+            manifest,
+            self.host_builder.clone(),
+            host_metrics.clone(),
+        )?;
+
+        info!(logger, "Following deployment as its standby node";
+              "block_number" => block_ptr.number);
+        Ok(Some(Followed {
+            id: id.clone(),
+            logger,
+            store,
+            eth_adapter,
+            instance,
+            data_sources,
+            top_level_templates,
+            non_fatal_errors,
+            host_metrics,
+            ethrpc_metrics,
+            verified,
+            next_block: block_ptr.number + 1,
+        }))
+    }
+}
+
+/// Run the handlers of `deployment` for the blocks that its assigned node
+/// processed since we last verified it, up to `GRAPH_STANDBY_VERIFY_BLOCKS`
+/// of them, and check the changes against the proofs of the assigned node.
+/// Returns `None` if the deployment needs to be loaded again because the
+/// chain was reorganized
+async fn verify_blocks<H, S>(
+    mut deployment: Followed<H, S>,
+) -> Result<Option<Followed<H, S>>, Error>
+where
+    H: RuntimeHostBuilder,
+    S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
+{
+    let id = deployment.id.clone();
+    let latest = match deployment.store.block_ptr(id.clone())? {
+        Some(latest) => latest,
+        None => return Ok(None),
+    };
+    if latest.number < deployment.verified.block.number {
+        // The assigned node reverted blocks we already verified
+        return Ok(None);
+    }
+    if latest.number < deployment.next_block {
+        return Ok(Some(deployment));
+    }
+
+    let from = deployment.next_block;
+    let to = latest
+        .number
+        .min(deployment.next_block + *STANDBY_VERIFY_BLOCKS - 1);
+    let proofs =
+        deployment
+            .store
+            .proofs_of_indexing(&id, from as BlockNumber, to as BlockNumber)?;
+    let filter = EthereumTriggerFilter::from_data_sources(&deployment.data_sources);
+    let found = blocks_with_triggers(
+        deployment.eth_adapter.clone(),
+        deployment.logger.clone(),
+        deployment.store.clone(),
+        deployment.ethrpc_metrics.clone(),
+        from,
+        to,
+        filter.log,
+        filter.call,
+        filter.block,
+    )
+    .compat()
+    .await?;
+
+    // The blocks that either node processed; the assigned node processes
+    // the blocks in which it finds triggers, just like we do
+    let mut blocks: BTreeMap<u64, (Option<ProofOfIndexing>, Option<EthereumBlockWithTriggers>)> =
+        BTreeMap::new();
+    for proof in proofs {
+        blocks.entry(proof.block.number).or_default().0 = Some(proof);
+    }
+    for block in found {
+        if !block.triggers.is_empty() {
+            let number = EthereumBlockPointer::from(&block.ethereum_block).number;
+            blocks.entry(number).or_default().1 = Some(block);
+        }
+    }
+
+    for (number, (proof, block)) in blocks {
+        let block_ptr = match (&proof, &block) {
+            (_, Some(block)) => EthereumBlockPointer::from(&block.ethereum_block),
+            (Some(proof), None) => proof.block.clone(),
+            (None, None) => continue,
+        };
+        let (mods, created_data_sources) = match block {
+            Some(block) => process_block(&mut deployment, block).await?,
+            None => (vec![], false),
+        };
+
+        let logger = deployment.logger.new(o!(
+            "block_number" => number,
+            "block_hash" => format!("{:x}", block_ptr.hash)
+        ));
+        match verify_block(
+            Some(deployment.verified.digest),
+            &block_ptr,
+            proof.as_ref(),
+            &mods,
+        )? {
+            Verdict::Agree => (),
+            Verdict::Disagree { expected, actual } => error!(
+                logger,
+                "Standby node disagrees with the proof of indexing of the assigned node";
+                "expected" => format!("{:x}", expected),
+                "actual" => format!("{:x}", actual),
+            ),
+            Verdict::Unprocessed => error!(
+                logger,
+                "Standby node changed entities in a block that the assigned node did not process";
+                "changes" => mods.len(),
+            ),
+            Verdict::Reorg => {
+                info!(
+                    logger,
+                    "Chain was reorganized, following standby deployment again"
+                );
+                return Ok(None);
+            }
+        }
+
+        if let Some(proof) = proof {
+            deployment.verified = proof;
+        }
+        deployment.next_block = number + 1;
+
+        // The blocks after this one were looked for without the triggers
+        // of the new data sources
+        if created_data_sources {
+            return Ok(Some(deployment));
+        }
+    }
+    deployment.next_block = to + 1;
+    Ok(Some(deployment))
+}
+
+/// Run the handlers of `deployment` for the triggers in `block` and return
+/// the changes they make, and whether they created data sources. Handlers
+/// see the entities as they were at the end of the previous block
+async fn process_block<H, S>(
+    deployment: &mut Followed<H, S>,
+    block: EthereumBlockWithTriggers,
+) -> Result<(Vec<EntityModification>, bool), Error>
+where
+    H: RuntimeHostBuilder,
+    S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
+{
+    let EthereumBlockWithTriggers {
+        ethereum_block,
+        triggers,
+    } = block;
+    let block_ptr = EthereumBlockPointer::from(&ethereum_block);
+    let light_block = Arc::new(ethereum_block.light_block());
+    let logger = deployment
+        .logger
+        .new(o!("block_number" => block_ptr.number));
+
+    let mut state = BlockState::with_cache(LfuCache::new())
+        .with_data_source_limit(deployment.instance.data_source_limit())
+        .with_budget(deployment.instance.block_budget());
+    state.entity_cache = EntityCache::new().at_block(block_ptr.number as BlockNumber - 1);
+
+    let mut created_data_sources = false;
+    let result: Result<BlockState, Error> = async {
+        for trigger in triggers {
+            state = deployment
+                .instance
+                .process_trigger(&logger, light_block.clone(), trigger, state)
+                .compat()
+                .await?;
+        }
+
+        // Run the handlers of data sources created in this block for the
+        // triggers in it, like the assigned node does
+        while !state.created_data_sources.is_empty() {
+            created_data_sources = true;
+            let mut data_sources = vec![];
+            let mut hosts = vec![];
+            for info in state.created_data_sources.drain(..) {
+                let data_source =
+                    DataSource::try_from_template(info.template, &info.params, block_ptr.number)?;
+                hosts.push(deployment.instance.add_dynamic_data_source(
+                    &logger,
+                    data_source.clone(),
+                    deployment.top_level_templates.clone(),
+                    deployment.host_metrics.clone(),
+                )?);
+                data_sources.push(data_source);
+            }
+            state.data_source_limit = deployment.instance.data_source_limit();
+
+            let block = triggers_in_block(
+                deployment.eth_adapter.clone(),
+                logger.clone(),
+                deployment.store.clone(),
+                deployment.ethrpc_metrics.clone(),
+                EthereumLogFilter::from_data_sources(data_sources.iter()),
+                EthereumCallFilter::from_data_sources(data_sources.iter()),
+                EthereumBlockFilter::from_data_sources(data_sources.iter()),
+                ethereum_block.clone(),
+            )
+            .compat()
+            .await?;
+            deployment.data_sources.extend(data_sources);
+
+            for trigger in block.triggers {
+                state = SubgraphInstance::<H>::process_trigger_in_runtime_hosts(
+                    &logger,
+                    hosts.iter().cloned(),
+                    light_block.clone(),
+                    trigger,
+                    state,
+                )
+                .compat()
+                .await?;
+            }
+        }
+        Ok(state)
+    }
+    .await;
+
+    let mods = match result {
+        Ok(state) => {
+            state
+                .entity_cache
+                .as_modifications(deployment.store.as_ref())?
+                .modifications
+        }
+        // The assigned node discards the changes of a block in which a
+        // handler fails if the deployment opted into non-fatal errors
+        Err(e) if deployment.non_fatal_errors => {
+            debug!(logger, "Handler failed on standby node"; "error" => e.to_string());
+            vec![]
+        }
+        Err(e) => return Err(e),
+    };
+    Ok((mods, created_data_sources))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::data::subgraph::schema::SUBGRAPHS_ID;
+
+    fn block(number: u64, hash: u64) -> EthereumBlockPointer {
+        EthereumBlockPointer {
+            hash: H256::from_low_u64_be(hash),
+            number,
+        }
+    }
+
+    fn set(subgraph_id: &SubgraphDeploymentId, id: &str, name: &str) -> EntityModification {
+        EntityModification::Insert {
+            key: EntityKey {
+                subgraph_id: subgraph_id.clone(),
+                entity_type: "Band".to_owned(),
+                entity_id: id.to_owned(),
+            },
+            data: Entity::from(vec![("id", Value::from(id)), ("name", Value::from(name))]),
+        }
+    }
+
+    fn proof(
+        previous: H256,
+        block: &EthereumBlockPointer,
+        mods: &[EntityModification],
+    ) -> ProofOfIndexing {
+        ProofOfIndexing {
+            block: block.clone(),
+            digest: ProofOfIndexing::digest(Some(previous), block, mods).unwrap(),
+        }
+    }
+
+    #[test]
+    fn agrees_with_same_changes() {
+        let id = SubgraphDeploymentId::new("standby").unwrap();
+        let previous = H256::from_low_u64_be(7);
+        let ptr = block(10, 10);
+        let mods = vec![set(&id, "1", "Mogwai"), set(&id, "2", "Sigur Ros")];
+        let primary = proof(previous, &ptr, &mods);
+
+        // The order of changes does not matter
+        let reversed: Vec<_> = mods.iter().rev().cloned().collect();
+        assert_eq!(
+            verify_block(Some(previous), &ptr, Some(&primary), &reversed).unwrap(),
+            Verdict::Agree
+        );
+
+        // Neither do changes to metadata
+        let mut with_metadata = mods.clone();
+        with_metadata.push(set(&*SUBGRAPHS_ID, "standby", "metadata"));
+        assert_eq!(
+            verify_block(Some(previous), &ptr, Some(&primary), &with_metadata).unwrap(),
+            Verdict::Agree
+        );
+    }
+
+    #[test]
+    fn disagrees_with_different_changes() {
+        let id = SubgraphDeploymentId::new("standby").unwrap();
+        let previous = H256::from_low_u64_be(7);
+        let ptr = block(10, 10);
+        let primary = proof(previous, &ptr, &[set(&id, "1", "Mogwai")]);
+
+        match verify_block(
+            Some(previous),
+            &ptr,
+            Some(&primary),
+            &[set(&id, "1", "Low")],
+        )
+        .unwrap()
+        {
+            Verdict::Disagree { expected, .. } => assert_eq!(expected, primary.digest),
+            verdict => panic!("unexpected verdict {:?}", verdict),
+        }
+
+        // The proof of the previous block is part of the digest
+        match verify_block(
+            Some(H256::from_low_u64_be(8)),
+            &ptr,
+            Some(&primary),
+            &[set(&id, "1", "Mogwai")],
+        )
+        .unwrap()
+        {
+            Verdict::Disagree { .. } => (),
+            verdict => panic!("unexpected verdict {:?}", verdict),
+        }
+    }
+
+    #[test]
+    fn blocks_the_assigned_node_did_not_process() {
+        let id = SubgraphDeploymentId::new("standby").unwrap();
+        let ptr = block(10, 10);
+
+        assert_eq!(verify_block(None, &ptr, None, &[]).unwrap(), Verdict::Agree);
+        assert_eq!(
+            verify_block(None, &ptr, None, &[set(&id, "1", "Mogwai")]).unwrap(),
+            Verdict::Unprocessed
+        );
+    }
+
+    #[test]
+    fn detects_reorgs() {
+        let previous = H256::from_low_u64_be(7);
+        let primary = proof(previous, &block(10, 10), &[]);
+
+        assert_eq!(
+            verify_block(Some(previous), &block(10, 11), Some(&primary), &[]).unwrap(),
+            Verdict::Reorg
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use graph::prelude::*;
+
+lazy_static! {
+    /// How often a deployment that failed because of a transient error is
+    /// restarted before it is marked as failed; 0 turns restarts off
+    static ref RESTART_MAX_ATTEMPTS: u32 = std::env::var("GRAPH_SUBGRAPH_RESTART_MAX_ATTEMPTS")
+        .unwrap_or("10".into())
+        .parse::<u32>()
+        .expect("invalid GRAPH_SUBGRAPH_RESTART_MAX_ATTEMPTS");
+
+    /// How long to wait before the first restart, in seconds. The delay
+    /// doubles with every further attempt
+    static ref RESTART_BASE_DELAY: Duration = Duration::from_secs(
+        std::env::var("GRAPH_SUBGRAPH_RESTART_BASE_DELAY")
+            .unwrap_or("10".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_SUBGRAPH_RESTART_BASE_DELAY")
+    );
+
+    /// The longest delay between two restarts, in seconds
+    static ref RESTART_MAX_DELAY: Duration = Duration::from_secs(
+        std::env::var("GRAPH_SUBGRAPH_RESTART_MAX_DELAY")
+            .unwrap_or("1800".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_SUBGRAPH_RESTART_MAX_DELAY")
+    );
+}
+
+/// The delay before restart number `attempt`, counting from 0
+fn restart_delay(attempt: u32, base: Duration, max: Duration) -> Duration {
+    base.checked_mul(2u32.saturating_pow(attempt))
+        .map(|delay| delay.min(max))
+        .unwrap_or(max)
+}
+
+/// Keeps what is needed to restart a deployment after it failed with a
+/// transient error. It is shared by all runs of the deployment, so that
+/// the restart counter survives restarts and the restarted deployment
+/// includes the dynamic data sources created in the meantime
+pub(crate) struct Supervisor {
+    /// The data sources of the deployment, including the dynamic data
+    /// sources of all blocks that have been written
+    data_sources: Mutex<Vec<DataSource>>,
+    /// How often the deployment has been restarted since it last wrote
+    /// a block
+    attempts: Mutex<u32>,
+}
+
+impl Supervisor {
+    pub fn new(data_sources: Vec<DataSource>) -> Self {
+        Supervisor {
+            data_sources: Mutex::new(data_sources),
+            attempts: Mutex::new(0),
+        }
+    }
+
+    /// Record that a block has been written together with the dynamic data
+    /// sources it created. A deployment that makes progress starts over
+    /// with the shortest delay when it fails again
+    pub fn block_written(&self, data_sources: Vec<DataSource>) {
+        self.data_sources.lock().unwrap().extend(data_sources);
+        *self.attempts.lock().unwrap() = 0;
+    }
+
+    /// The data sources to restart the deployment with
+    pub fn data_sources(&self) -> Vec<DataSource> {
+        self.data_sources.lock().unwrap().clone()
+    }
+
+    /// How long to wait before the next restart, and the number of that
+    /// restart. Returns `None` once the deployment has been restarted
+    /// `GRAPH_SUBGRAPH_RESTART_MAX_ATTEMPTS` times without progress
+    pub fn next_restart(&self) -> Option<(u32, Duration)> {
+        let mut attempts = self.attempts.lock().unwrap();
+        if *attempts >= *RESTART_MAX_ATTEMPTS {
+            return None;
+        }
+        let delay = restart_delay(*attempts, *RESTART_BASE_DELAY, *RESTART_MAX_DELAY);
+        *attempts += 1;
+        Some((*attempts, delay))
+    }
+}
+
+#[test]
+fn restart_delays() {
+    let base = Duration::from_secs(10);
+    let max = Duration::from_secs(60);
+    assert_eq!(Duration::from_secs(10), restart_delay(0, base, max));
+    assert_eq!(Duration::from_secs(20), restart_delay(1, base, max));
+    assert_eq!(Duration::from_secs(40), restart_delay(2, base, max));
+    assert_eq!(max, restart_delay(3, base, max));
+    assert_eq!(max, restart_delay(100, base, max));
+}
+
+#[test]
+fn transient_errors() {
+    let failed = |e: Error| Error::from(e.context("Failed to process trigger"));
+
+    assert!(TransientError::is_cause_of(&failed(Error::from(
+        TransientError(
+            "Failed to call function \"balanceOf\": ethereum node took too long".to_owned()
+        )
+    ))));
+    assert!(TransientError::is_cause_of(&failed(Error::from(
+        std::io::Error::new(std::io::ErrorKind::Other, "oops")
+    ))));
+    assert!(TransientError::is_cause_of(&Error::from(
+        QueryExecutionError::StoreError(Error::from(StoreError::Unknown(Error::from(
+            std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection reset")
+        ))))
+    )));
+    // Only the type of an error counts, not its message
+    assert!(!TransientError::is_cause_of(&failed(format_err!(
+        "Mapping aborted at mapping.ts, line 12, with message: timed out"
+    ))));
+    assert!(!TransientError::is_cause_of(&failed(format_err!(
+        "Failed to call function `balanceOf`: call reverted"
+    ))));
+}
@@ -0,0 +1,525 @@
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use graph::data::subgraph::schema::{
+    SubgraphDeploymentAssignmentEntity, SubgraphDeploymentEntity, TypedEntity,
+};
+use graph::prelude::*;
+
+lazy_static! {
+    // How long a single webhook request may take, in seconds
+    static ref WEBHOOK_TIMEOUT: Duration = Duration::from_secs(
+        env::var("GRAPH_WEBHOOK_TIMEOUT")
+            .unwrap_or("10".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_WEBHOOK_TIMEOUT")
+    );
+
+    // How many attempts to make for each webhook request
+    static ref WEBHOOK_ATTEMPTS: usize = env::var("GRAPH_WEBHOOK_ATTEMPTS")
+        .unwrap_or("3".into())
+        .parse::<usize>()
+        .expect("invalid GRAPH_WEBHOOK_ATTEMPTS");
+}
+
+/// How many webhook events can wait to be sent; further events are
+/// dropped
+const WEBHOOK_QUEUE_SIZE: usize = 1000;
+
+/// The parts of a deployment's metadata that webhook events are derived
+/// from
+#[derive(Clone, Debug, Default, PartialEq)]
+struct DeploymentStatus {
+    node: Option<String>,
+    synced: bool,
+    failed: bool,
+    block: Option<u64>,
+    /// Whether the deployment is reverting blocks because of a reorg
+    reverting: bool,
+}
+
+/// A lifecycle event of a deployment, as it is posted to the webhook
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+enum WebhookEvent {
+    DeploymentCreated {
+        deployment: String,
+        node: String,
+    },
+    DeploymentSynced {
+        deployment: String,
+        node: String,
+        block: Option<u64>,
+    },
+    DeploymentFailed {
+        deployment: String,
+        node: String,
+        block: Option<u64>,
+    },
+    DeploymentReassigned {
+        deployment: String,
+        from: String,
+        to: Option<String>,
+    },
+    ReorgDetected {
+        deployment: String,
+        node: String,
+        from: u64,
+        to: u64,
+    },
+}
+
+/// The events that the change of deployment `id` from `before` to `after`
+/// causes on node `node_id`. Each event is only reported by one node: the
+/// node a deployment is assigned to reports its events, and the node a
+/// deployment is taken from reports the reassignment
+fn webhook_events(
+    id: &SubgraphDeploymentId,
+    node_id: &NodeId,
+    before: Option<&DeploymentStatus>,
+    after: &DeploymentStatus,
+) -> Vec<WebhookEvent> {
+    let this_node = Some(node_id.to_string());
+    let deployment = id.to_string();
+    let node = node_id.to_string();
+    let mut events = vec![];
+
+    // The deployment and its assignment may be written separately, so a
+    // deployment counts as created when it is first assigned to a node
+    let before = match before {
+        Some(before) if before.node.is_some() => before,
+        _ => {
+            if after.node == this_node {
+                events.push(WebhookEvent::DeploymentCreated { deployment, node });
+            }
+            return events;
+        }
+    };
+
+    if before.node == this_node && after.node != before.node {
+        events.push(WebhookEvent::DeploymentReassigned {
+            deployment: deployment.clone(),
+            from: node.clone(),
+            to: after.node.clone(),
+        });
+    }
+    if after.node != this_node {
+        return events;
+    }
+
+    if !before.synced && after.synced {
+        events.push(WebhookEvent::DeploymentSynced {
+            deployment: deployment.clone(),
+            node: node.clone(),
+            block: after.block,
+        });
+    }
+    if !before.failed && after.failed {
+        events.push(WebhookEvent::DeploymentFailed {
+            deployment: deployment.clone(),
+            node: node.clone(),
+            block: after.block,
+        });
+    }
+    // A reorg reverts one block after the other; only report the first
+    if let (Some(from), Some(to), false) = (before.block, after.block, before.reverting) {
+        if to < from {
+            events.push(WebhookEvent::ReorgDetected {
+                deployment,
+                node,
+                from,
+                to,
+            });
+        }
+    }
+    events
+}
+
+/// Posts a JSON object to a webhook when deployments on this node are
+/// created, synced, fail, are reassigned or revert blocks because of a
+/// reorg, so that operators can build alerting without polling the
+/// indexing status API.
+///
+/// Events are derived from changes to the deployment metadata in the
+/// store; changes that happen while the node is not running are not
+/// reported. Only the metadata of deployments assigned to this node is
+/// loaded. Events are posted one after the other, and events are dropped
+/// with a warning if the webhook falls too far behind
+pub struct WebhookNotifier<S> {
+    logger: Logger,
+    url: String,
+    store: Arc<S>,
+    node_id: NodeId,
+    http: reqwest::Client,
+}
+
+/// What the notifier knows about deployments
+#[derive(Default)]
+struct Deployments {
+    /// The node every deployment is assigned to
+    nodes: HashMap<SubgraphDeploymentId, String>,
+    /// The status of the deployments assigned to this node
+    statuses: HashMap<SubgraphDeploymentId, DeploymentStatus>,
+}
+
+impl<S> WebhookNotifier<S>
+where
+    S: Store,
+{
+    pub fn new(
+        logger_factory: &LoggerFactory,
+        url: String,
+        store: Arc<S>,
+        node_id: NodeId,
+    ) -> Self {
+        WebhookNotifier {
+            logger: logger_factory.component_logger("WebhookNotifier", None),
+            url,
+            store,
+            node_id,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn start(self) {
+        use futures03::stream::StreamExt;
+
+        let store_events = self.store.subscribe(vec![
+            SubgraphDeploymentEntity::subgraph_entity_pair(),
+            SubgraphDeploymentAssignmentEntity::subgraph_entity_pair(),
+        ]);
+        let mut deployments = match self.deployments() {
+            Ok(deployments) => deployments,
+            Err(e) => {
+                error!(
+                    self.logger,
+                    "Failed to load deployments, not sending webhook events";
+                    "error" => e.to_string()
+                );
+                return;
+            }
+        };
+
+        let (mut sender, receiver) = futures03::channel::mpsc::channel(WEBHOOK_QUEUE_SIZE);
+        graph::spawn(deliver(
+            self.logger.clone(),
+            self.http.clone(),
+            self.url.clone(),
+            receiver,
+        ));
+
+        let notifier = Arc::new(self);
+
+        // Blocking due to store interactions. Won't be blocking after #905.
+        graph::spawn_blocking(store_events.compat().for_each(move |event| {
+            if let Ok(event) = event {
+                let mut assigned = HashSet::new();
+                let mut changed = HashSet::new();
+                for change in event.changes {
+                    let id = match SubgraphDeploymentId::new(change.entity_id) {
+                        Ok(id) => id,
+                        Err(()) => continue,
+                    };
+                    if change.entity_type == SubgraphDeploymentAssignmentEntity::TYPENAME {
+                        assigned.insert(id);
+                    } else if deployments.statuses.contains_key(&id) {
+                        // Only deployments on this node are of interest
+                        changed.insert(id);
+                    }
+                }
+                let changed: Vec<_> = changed
+                    .into_iter()
+                    .filter(|id| !assigned.contains(id))
+                    .map(|id| (id, false))
+                    .collect();
+
+                for (id, assignment_changed) in
+                    assigned.into_iter().map(|id| (id, true)).chain(changed)
+                {
+                    match notifier.update(&mut deployments, id.clone(), assignment_changed) {
+                        Ok(events) => {
+                            for event in events {
+                                notifier.send(&mut sender, event);
+                            }
+                        }
+                        Err(e) => warn!(
+                            notifier.logger,
+                            "Failed to check deployment for webhook events";
+                            "subgraph_id" => id.to_string(),
+                            "error" => e.to_string()
+                        ),
+                    }
+                }
+            }
+            futures03::future::ready(())
+        }));
+    }
+
+    /// The node of all deployments and the status of the deployments on
+    /// this node, so that existing deployments are not reported as created
+    fn deployments(&self) -> Result<Deployments, Error> {
+        let nodes: HashMap<_, _> = self
+            .store
+            .find(SubgraphDeploymentAssignmentEntity::query())?
+            .into_iter()
+            .filter_map(|assignment| {
+                let id = SubgraphDeploymentId::new(assignment.id().ok()?).ok()?;
+                Some((id, assignment.get("nodeId")?.clone().as_string()?))
+            })
+            .collect();
+        let this_node = self.node_id.to_string();
+        let ours: Vec<_> = nodes
+            .iter()
+            .filter(|(_, node)| **node == this_node)
+            .map(|(id, _)| Value::from(id.to_string()))
+            .collect();
+
+        let statuses = if ours.is_empty() {
+            HashMap::new()
+        } else {
+            self.store
+                .find(
+                    SubgraphDeploymentEntity::query()
+                        .filter(EntityFilter::In("id".to_owned(), ours))
+                        .first(u32::max_value()),
+                )?
+                .into_iter()
+                .filter_map(|deployment| {
+                    let id = SubgraphDeploymentId::new(deployment.id().ok()?).ok()?;
+                    let status = Self::status(Some(self.node_id.to_string()), &deployment);
+                    Some((id, status))
+                })
+                .collect()
+        };
+        Ok(Deployments { nodes, statuses })
+    }
+
+    fn status(node: Option<String>, deployment: &Entity) -> DeploymentStatus {
+        DeploymentStatus {
+            node,
+            synced: deployment.get("synced") == Some(&Value::Bool(true)),
+            failed: deployment.get("failed") == Some(&Value::Bool(true)),
+            block: match deployment.get("latestEthereumBlockNumber") {
+                Some(Value::BigInt(n)) => Some(n.to_u64()),
+                _ => None,
+            },
+            reverting: false,
+        }
+    }
+
+    /// The events caused by a change to deployment `id`, or to its
+    /// assignment if `assignment_changed`
+    fn update(
+        &self,
+        deployments: &mut Deployments,
+        id: SubgraphDeploymentId,
+        assignment_changed: bool,
+    ) -> Result<Vec<WebhookEvent>, Error> {
+        let this_node = self.node_id.to_string();
+        let previous_node = deployments.nodes.get(&id).cloned();
+        // The assignment is only looked up when it was changed
+        let node = if assignment_changed {
+            self.store
+                .get(SubgraphDeploymentAssignmentEntity::key(id.clone()))?
+                .and_then(|assignment| assignment.get("nodeId").cloned())
+                .and_then(|node| node.as_string())
+        } else {
+            previous_node.clone()
+        };
+        match &node {
+            Some(node) => deployments.nodes.insert(id.clone(), node.clone()),
+            None => deployments.nodes.remove(&id),
+        };
+
+        // Deployments that neither were nor are on this node do not cause
+        // events here
+        let tracked = deployments.statuses.contains_key(&id);
+        if !tracked && node.as_ref() != Some(&this_node) {
+            return Ok(vec![]);
+        }
+
+        let deployment = match self.store.get(SubgraphDeploymentEntity::key(id.clone()))? {
+            Some(deployment) => deployment,
+            None => {
+                deployments.statuses.remove(&id);
+                return Ok(vec![]);
+            }
+        };
+        let mut after = Self::status(node, &deployment);
+
+        // A deployment that moves here from another node is not new
+        let moved_here = match &previous_node {
+            Some(previous) if !tracked => Some(DeploymentStatus {
+                node: Some(previous.clone()),
+                ..after.clone()
+            }),
+            _ => None,
+        };
+        let before = deployments.statuses.get(&id).or(moved_here.as_ref());
+        after.reverting = match (before.and_then(|b| b.block), after.block) {
+            (Some(from), Some(to)) if to < from => true,
+            (Some(from), Some(to)) if to == from => before.map_or(false, |b| b.reverting),
+            _ => false,
+        };
+
+        let events = webhook_events(&id, &self.node_id, before, &after);
+        if after.node.as_ref() == Some(&this_node) {
+            deployments.statuses.insert(id, after);
+        } else {
+            deployments.statuses.remove(&id);
+        }
+        Ok(events)
+    }
+
+    /// Queue `event` to be posted to the webhook
+    fn send(
+        &self,
+        sender: &mut futures03::channel::mpsc::Sender<serde_json::Value>,
+        event: WebhookEvent,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut body = serde_json::to_value(&event).expect("webhook events serialize to JSON");
+        body["timestamp"] = timestamp.into();
+
+        debug!(self.logger, "Send webhook event"; "event" => body.to_string());
+        if let Err(e) = sender.try_send(body) {
+            warn!(
+                self.logger,
+                "Dropped webhook event because too many events are waiting to be sent";
+                "event" => e.into_inner().to_string()
+            );
+        }
+    }
+}
+
+/// Post the events from `events` to the webhook at `url`, one after the
+/// other
+async fn deliver(
+    logger: Logger,
+    http: reqwest::Client,
+    url: String,
+    mut events: futures03::channel::mpsc::Receiver<serde_json::Value>,
+) {
+    use futures03::stream::StreamExt;
+
+    while let Some(body) = events.next().await {
+        let mut last_error = None;
+        for attempt in 0..(*WEBHOOK_ATTEMPTS).max(1) {
+            if attempt > 0 {
+                tokio::time::delay_for(Duration::from_secs(1 << attempt.min(6))).await;
+            }
+            let result = http
+                .post(url.as_str())
+                .timeout(*WEBHOOK_TIMEOUT)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => {
+                    last_error = None;
+                    break;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        if let Some(e) = last_error {
+            warn!(
+                logger,
+                "Failed to send webhook event";
+                "event" => body.to_string(),
+                "error" => e.to_string()
+            );
+        }
+    }
+}
+
+#[test]
+fn webhook_events_for_changes() {
+    let id = SubgraphDeploymentId::new("QmXYZ").unwrap();
+    let this = NodeId::new("this").unwrap();
+    let status = |node: &str, synced, failed, block| DeploymentStatus {
+        node: Some(node.to_owned()),
+        synced,
+        failed,
+        block,
+        reverting: false,
+    };
+    let events = |before: Option<&DeploymentStatus>, after: &DeploymentStatus| {
+        webhook_events(&id, &this, before, after)
+    };
+
+    // New deployments are only reported by the node they are assigned to
+    assert_eq!(
+        vec![WebhookEvent::DeploymentCreated {
+            deployment: "QmXYZ".to_owned(),
+            node: "this".to_owned()
+        }],
+        events(None, &status("this", false, false, None))
+    );
+    assert!(events(None, &status("other", false, false, None)).is_empty());
+    let unassigned = DeploymentStatus {
+        node: None,
+        ..status("this", false, false, None)
+    };
+    assert_eq!(
+        1,
+        events(Some(&unassigned), &status("this", false, false, None)).len()
+    );
+
+    // Syncing, failing and reverting blocks
+    let running = status("this", false, false, Some(10));
+    assert!(events(Some(&running), &status("this", false, false, Some(11))).is_empty());
+    assert_eq!(
+        vec![WebhookEvent::DeploymentSynced {
+            deployment: "QmXYZ".to_owned(),
+            node: "this".to_owned(),
+            block: Some(11)
+        }],
+        events(Some(&running), &status("this", true, false, Some(11)))
+    );
+    assert_eq!(
+        vec![WebhookEvent::DeploymentFailed {
+            deployment: "QmXYZ".to_owned(),
+            node: "this".to_owned(),
+            block: Some(10)
+        }],
+        events(Some(&running), &status("this", false, true, Some(10)))
+    );
+    assert_eq!(
+        vec![WebhookEvent::ReorgDetected {
+            deployment: "QmXYZ".to_owned(),
+            node: "this".to_owned(),
+            from: 10,
+            to: 9
+        }],
+        events(Some(&running), &status("this", false, false, Some(9)))
+    );
+    let reverting = DeploymentStatus {
+        reverting: true,
+        ..status("this", false, false, Some(9))
+    };
+    assert!(events(Some(&reverting), &status("this", false, false, Some(8))).is_empty());
+
+    // Reassignments are reported by the node that loses the deployment
+    assert_eq!(
+        vec![WebhookEvent::DeploymentReassigned {
+            deployment: "QmXYZ".to_owned(),
+            from: "this".to_owned(),
+            to: Some("other".to_owned())
+        }],
+        events(Some(&running), &status("other", false, false, Some(10)))
+    );
+    assert!(events(
+        Some(&status("other", false, false, Some(10))),
+        &status("this", false, false, Some(10))
+    )
+    .is_empty());
+}
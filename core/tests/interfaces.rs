@@ -1,4 +1,4 @@
-// Tests for graphql interfaces.
+// Tests for graphql interfaces and unions.
 
 use graph::prelude::*;
 use graph_graphql::prelude::{execute_query, QueryExecutionOptions, StoreResolver};
@@ -42,6 +42,7 @@ fn insert_and_query(
         max_complexity: None,
         max_depth: 100,
         max_first: std::u32::MAX,
+        block: BLOCK_NUMBER_MAX,
     };
     let document = graphql_parser::parse_query(query).unwrap();
     let query = Query {
@@ -570,3 +571,85 @@ fn invalid_fragment() {
         e => panic!("error {} is not the expected one", e),
     }
 }
+
+#[test]
+fn union_interleaved_ordering() {
+    let subgraph_id = "UnionInterleavedOrdering";
+    let schema = "type Swap @entity { id: ID!, timestamp: Int!, amountIn: Int! }
+                  type Mint @entity { id: ID!, timestamp: Int!, liquidity: Int! }
+                  union Activity = Swap | Mint";
+
+    let activity = |id: &str, timestamp: i32, attr: &str, value: i32, entity_type| {
+        (
+            Entity::from(vec![
+                ("id", Value::from(id)),
+                ("timestamp", Value::from(timestamp)),
+                (attr, Value::from(value)),
+            ]),
+            entity_type,
+        )
+    };
+    // Entities of different types can have the same id; they are then
+    // ordered by their type
+    let entities = vec![
+        activity("1", 20, "amountIn", 7, "Swap"),
+        activity("1", 20, "liquidity", 8, "Mint"),
+        activity("2", 10, "liquidity", 9, "Mint"),
+        activity("3", 30, "amountIn", 6, "Swap"),
+    ];
+
+    let query = "query { activities(orderBy: timestamp, first: 3) {
+                   __typename
+                   ... on Swap { amountIn }
+                   ... on Mint { liquidity }
+                 } }";
+    let res = insert_and_query(subgraph_id, schema, entities, query).unwrap();
+    assert!(res.errors.is_none());
+    assert_eq!(
+        format!("{:?}", res.data.unwrap()),
+        "Object({\
+         \"activities\": List([\
+         Object({\"__typename\": String(\"Mint\"), \"liquidity\": Int(Number(9))}), \
+         Object({\"__typename\": String(\"Mint\"), \"liquidity\": Int(Number(8))}), \
+         Object({\"__typename\": String(\"Swap\"), \"amountIn\": Int(Number(7))})\
+         ])\
+         })"
+    );
+
+    // Paging continues where the previous page stopped
+    let query = "query { activities(orderBy: timestamp, skip: 2, first: 2, \
+                                     where: { timestamp_gte: 20 }) {
+                   __typename
+                   ... on Swap { amountIn }
+                 } }";
+    let res = insert_and_query(subgraph_id, schema, vec![], query).unwrap();
+    assert!(res.errors.is_none());
+    assert_eq!(
+        format!("{:?}", res.data.unwrap()),
+        "Object({\
+         \"activities\": List([\
+         Object({\"__typename\": String(\"Swap\"), \"amountIn\": Int(Number(6))})\
+         ])\
+         })"
+    );
+}
+
+#[test]
+fn union_fields_need_fragment() {
+    let subgraph_id = "UnionFieldsNeedFragment";
+    let schema = "type Swap @entity { id: ID!, timestamp: Int! }
+                  type Mint @entity { id: ID!, timestamp: Int! }
+                  union Activity = Swap | Mint";
+
+    let query = "query { activities { timestamp } }";
+
+    let res = insert_and_query(subgraph_id, schema, vec![], query).unwrap();
+
+    match &res.errors.unwrap()[0] {
+        QueryError::ExecutionError(QueryExecutionError::UnknownField(_, type_name, field_name)) => {
+            assert_eq!(type_name, "Activity");
+            assert_eq!(field_name, "timestamp");
+        }
+        e => panic!("error {} is not the expected one", e),
+    }
+}
@@ -4,7 +4,7 @@
 use ipfs_api::IpfsClient;
 use walkdir::WalkDir;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::read_to_string;
 use std::io::Cursor;
 use std::time::Duration;
@@ -178,6 +178,9 @@ fn multiple_data_sources_per_subgraph() {
                 host_builder.clone(),
                 block_stream_builder.clone(),
                 metrics_registry,
+                None,
+                None,
+                None,
             );
 
             // Load a subgraph with two data sources
@@ -262,8 +265,20 @@ async fn subgraph_provider_events() {
         resolver.clone(),
         store.clone(),
         graphql_runner.clone(),
+        Arc::new(MockMetricsRegistry::new()),
     );
-    let provider_events = provider.take_event_stream().unwrap();
+    // Stopping a deployment waits for it to be drained; there is no
+    // instance manager here to do that
+    let provider_events = provider
+        .take_event_stream()
+        .unwrap()
+        .filter(|event| match event {
+            SubgraphAssignmentProviderEvent::SubgraphDrain(_, signal) => {
+                signal.notify();
+                false
+            }
+            _ => true,
+        });
     let node_id = NodeId::new("test").unwrap();
 
     let registrar = graph_core::SubgraphRegistrar::new(
@@ -315,6 +330,7 @@ async fn subgraph_provider_events() {
                     subgraph_name_clone1.clone(),
                     subgraph1_id_clone1.clone(),
                     node_id_clone1.clone(),
+                    BTreeMap::new(),
                 )
                 .then(move |result| {
                     assert!(result.is_err());
@@ -328,9 +344,10 @@ async fn subgraph_provider_events() {
                         subgraph_name_clone2.clone(),
                         subgraph1_id_clone1.clone(),
                         node_id_clone1.clone(),
+                        BTreeMap::new(),
                     )
                 })
-                .and_then(move |()| {
+                .and_then(move |_| {
                     // Give some time for event to be picked up.
                     tokio::time::delay_for(Duration::from_secs(2))
                         .never_error()
@@ -343,9 +360,10 @@ async fn subgraph_provider_events() {
                         subgraph_name_clone3,
                         subgraph2_id_clone1,
                         node_id_clone2,
+                        BTreeMap::new(),
                     )
                 })
-                .and_then(move |()| {
+                .and_then(move |_| {
                     // Give some time for event to be picked up.
                     tokio::time::delay_for(Duration::from_secs(2))
                         .never_error()
@@ -12,6 +12,7 @@ use web3::types::*;
 
 use super::types::*;
 use crate::components::metrics::{CounterVec, GaugeVec, HistogramVec};
+use crate::components::trigger_filter::TriggerFilter;
 use crate::prelude::*;
 
 pub type EventSignature = H256;
@@ -271,7 +272,7 @@ impl EthereumLogFilter {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct EthereumCallFilter {
     // Each call filter has a map of filters keyed by address, each containing a tuple with
     // start_block and the set of function signatures
@@ -483,6 +484,59 @@ impl EthereumBlockFilter {
     }
 }
 
+/// The triggers on Ethereum that a subgraph is interested in, combining
+/// the filters for logs, calls and blocks.
+#[derive(Clone, Debug, Default)]
+pub struct EthereumTriggerFilter {
+    pub log: EthereumLogFilter,
+    pub call: EthereumCallFilter,
+    pub block: EthereumBlockFilter,
+}
+
+impl EthereumTriggerFilter {
+    pub fn from_data_sources<'a>(iter: impl IntoIterator<Item = &'a DataSource> + Clone) -> Self {
+        EthereumTriggerFilter {
+            log: EthereumLogFilter::from_data_sources(iter.clone()),
+            call: EthereumCallFilter::from_data_sources(iter.clone()),
+            block: EthereumBlockFilter::from_data_sources(iter),
+        }
+    }
+}
+
+impl TriggerFilter for EthereumTriggerFilter {
+    type Trigger = EthereumTrigger;
+
+    fn matches(&self, trigger: &EthereumTrigger) -> bool {
+        match trigger {
+            EthereumTrigger::Log(log) => self.log.matches(log),
+            EthereumTrigger::Call(call) => self.call.matches(call),
+            EthereumTrigger::Block(_, EthereumBlockTriggerType::Every) => {
+                self.block.trigger_every_block
+            }
+            EthereumTrigger::Block(ptr, EthereumBlockTriggerType::WithCallTo(address)) => self
+                .block
+                .contract_addresses
+                .iter()
+                .any(|(start_block, contract)| contract == address && *start_block <= ptr.number),
+        }
+    }
+
+    fn extend(&mut self, other: Self) {
+        // Destructure to make sure we're extending all fields.
+        let EthereumTriggerFilter { log, call, block } = other;
+        self.log.extend(log);
+        self.call.extend(call);
+        self.block.extend(block);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.log.is_empty()
+            && self.call.is_empty()
+            && !self.block.trigger_every_block
+            && self.block.contract_addresses.is_empty()
+    }
+}
+
 #[derive(Clone)]
 pub struct ProviderEthRpcMetrics {
     request_duration: Box<HistogramVec>,
@@ -570,6 +624,7 @@ impl SubgraphEthRpcMetrics {
 pub struct BlockStreamMetrics {
     pub ethrpc_metrics: Arc<SubgraphEthRpcMetrics>,
     pub blocks_behind: Box<Gauge>,
+    pub seconds_behind: Box<Gauge>,
     pub reverted_blocks: Box<Gauge>,
     pub stopwatch: StopwatchMetrics,
 }
@@ -590,6 +645,16 @@ impl BlockStreamMetrics {
                 HashMap::new(),
             )
             .expect("failed to create `subgraph_blocks_behind` gauge");
+        let seconds_behind = registry
+            .new_gauge(
+                format!("subgraph_seconds_behind_{}", deployment_id.to_string()),
+                String::from(
+                    "Track how many seconds the latest block of a subgraph deployment \
+                     is older than the HEAD block",
+                ),
+                HashMap::new(),
+            )
+            .expect("failed to create `subgraph_seconds_behind` gauge");
         let reverted_blocks = registry
             .new_gauge(
                 format!("subgraph_reverted_blocks_{}", deployment_id.to_string()),
@@ -600,18 +665,35 @@ impl BlockStreamMetrics {
         Self {
             ethrpc_metrics,
             blocks_behind,
+            seconds_behind,
             reverted_blocks,
             stopwatch,
         }
     }
 }
 
+/// What the Ethereum node behind an adapter supports
+#[derive(Clone, Debug, PartialEq)]
+pub struct EthereumAdapterCapabilities {
+    /// The kind of client, e.g. `geth` or `parity`
+    pub client: String,
+    /// Whether the node supports `trace_filter`, which call handlers and
+    /// block handlers with a call filter depend on
+    pub traces: bool,
+    /// Whether blocks and receipts from the node are rewritten by a block
+    /// decoder before they are used
+    pub decoded_blocks: bool,
+}
+
 /// Common trait for components that watch and manage access to Ethereum.
 ///
 /// Implementations may be implemented against an in-process Ethereum node
 /// or a remote node over RPC.
 #[automock]
 pub trait EthereumAdapter: Send + Sync + 'static {
+    /// What the Ethereum node behind this adapter supports
+    fn capabilities(&self) -> EthereumAdapterCapabilities;
+
     /// Ask the Ethereum node for some identifying information about the Ethereum network it is
     /// connected to.
     fn net_identifiers(
@@ -749,6 +831,15 @@ pub trait EthereumAdapter: Send + Sync + 'static {
         call_filter: EthereumCallFilter,
     ) -> Box<dyn Stream<Item = EthereumCall, Error = Error> + Send>;
 
+    /// Load the pending block that the Ethereum node builds on top of its
+    /// latest block, together with the logs its transactions emitted so
+    /// far. Pending blocks have no hash yet. Returns `None` if the node
+    /// does not report a pending block
+    fn pending_block(
+        &self,
+        logger: &Logger,
+    ) -> Box<dyn Future<Item = Option<(LightEthereumBlock, Vec<Log>)>, Error = Error> + Send>;
+
     /// Call the function of a smart contract.
     fn contract_call(
         &self,
@@ -858,6 +949,43 @@ pub fn triggers_in_block(
     })
 }
 
+/// Returns the pending block of the Ethereum node with the triggers that
+/// match the filters, or `None` if there is no pending block. Since the
+/// pending block has no hash yet, it is given the zero hash. Call triggers
+/// need traces, which are not available for pending blocks, and are
+/// therefore never included
+pub fn pending_block_with_triggers(
+    adapter: Arc<dyn EthereumAdapter>,
+    logger: Logger,
+    log_filter: EthereumLogFilter,
+    block_filter: EthereumBlockFilter,
+) -> Box<dyn Future<Item = Option<EthereumBlockWithTriggers>, Error = Error> + Send> {
+    Box::new(adapter.pending_block(&logger).map(move |pending| {
+        // Some nodes do not number pending blocks; those can not be
+        // processed, since entity changes are recorded by block number
+        pending
+            .filter(|(block, _)| block.number.is_some())
+            .map(|(mut block, logs)| {
+                block.hash = Some(PENDING_BLOCK_HASH);
+
+                let mut triggers = logs
+                    .into_iter()
+                    .filter(|log| log_filter.matches(log))
+                    .map(EthereumTrigger::Log)
+                    .collect::<Vec<_>>();
+                let full_block = EthereumBlockWithCalls {
+                    ethereum_block: EthereumBlock {
+                        block,
+                        transaction_receipts: vec![],
+                    },
+                    calls: None,
+                };
+                triggers.append(&mut parse_block_triggers(block_filter, &full_block));
+                EthereumBlockWithTriggers::new(triggers, BlockFinality::NonFinal(full_block))
+            })
+    }))
+}
+
 /// Returns blocks with triggers, corresponding to the specified range and filters.
 /// If a block contains no triggers, there may be no corresponding item in the stream.
 /// However the `to` block will always be present, even if triggers are empty.
@@ -982,7 +1110,13 @@ pub fn blocks_with_triggers(
 
 #[cfg(test)]
 mod tests {
-    use super::EthereumCallFilter;
+    use super::{EthereumBlockFilter, EthereumCallFilter, EthereumTriggerFilter};
+    use crate::components::ethereum::{
+        EthereumBlockPointer, EthereumBlockTriggerType, EthereumTrigger,
+    };
+    use crate::components::trigger_filter::TriggerFilter;
+
+    use web3::types::H256;
 
     use web3::types::Address;
 
@@ -1033,4 +1167,46 @@ mod tests {
             Some(&(1, HashSet::from_iter(vec![[1u8; 4]])))
         );
     }
+
+    #[test]
+    fn extended_ethereum_trigger_filter_matches_both_filters() {
+        let block = |number: u64| EthereumBlockPointer::from((H256::zero(), number));
+        let call_to = |number: u64, address: u64| {
+            EthereumTrigger::Block(
+                block(number),
+                EthereumBlockTriggerType::WithCallTo(Address::from_low_u64_be(address)),
+            )
+        };
+
+        assert!(EthereumTriggerFilter::default().is_empty());
+
+        let mut filter = EthereumTriggerFilter {
+            block: EthereumBlockFilter {
+                contract_addresses: HashSet::from_iter(vec![(10, Address::from_low_u64_be(1))]),
+                trigger_every_block: false,
+            },
+            ..Default::default()
+        };
+        assert!(!filter.is_empty());
+        assert!(filter.matches(&call_to(10, 1)));
+        assert!(!filter.matches(&call_to(9, 1)));
+        assert!(!filter.matches(&call_to(10, 2)));
+        assert!(!filter.matches(&EthereumTrigger::Block(
+            block(10),
+            EthereumBlockTriggerType::Every
+        )));
+
+        filter.extend(EthereumTriggerFilter {
+            block: EthereumBlockFilter {
+                contract_addresses: HashSet::new(),
+                trigger_every_block: true,
+            },
+            ..Default::default()
+        });
+        assert!(filter.matches(&call_to(10, 1)));
+        assert!(filter.matches(&EthereumTrigger::Block(
+            block(10),
+            EthereumBlockTriggerType::Every
+        )));
+    }
 }
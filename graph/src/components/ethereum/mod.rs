@@ -4,17 +4,19 @@ mod stream;
 mod types;
 
 pub use self::adapter::{
-    blocks_with_triggers, triggers_in_block, BlockStreamMetrics, EthGetLogsFilter, EthereumAdapter,
-    EthereumAdapterError, EthereumBlockFilter, EthereumCallFilter, EthereumContractCall,
-    EthereumContractCallError, EthereumContractState, EthereumContractStateError,
-    EthereumContractStateRequest, EthereumLogFilter, EthereumNetworkIdentifier,
-    MockEthereumAdapter, ProviderEthRpcMetrics, SubgraphEthRpcMetrics,
+    blocks_with_triggers, pending_block_with_triggers, triggers_in_block, BlockStreamMetrics,
+    EthGetLogsFilter, EthereumAdapter, EthereumAdapterCapabilities, EthereumAdapterError,
+    EthereumBlockFilter, EthereumCallFilter, EthereumContractCall, EthereumContractCallError,
+    EthereumContractState, EthereumContractStateError, EthereumContractStateRequest,
+    EthereumLogFilter, EthereumNetworkIdentifier, EthereumTriggerFilter, MockEthereumAdapter,
+    ProviderEthRpcMetrics, SubgraphEthRpcMetrics,
 };
 pub use self::listener::{ChainHeadUpdate, ChainHeadUpdateListener, ChainHeadUpdateStream};
 pub use self::stream::{BlockStream, BlockStreamBuilder, BlockStreamEvent};
 pub use self::types::{
-    BlockFinality, EthereumBlock, EthereumBlockData, EthereumBlockPointer,
-    EthereumBlockTriggerType, EthereumBlockWithCalls, EthereumBlockWithTriggers, EthereumCall,
-    EthereumCallData, EthereumEventData, EthereumTransactionData, EthereumTrigger,
-    LightEthereumBlock, LightEthereumBlockExt,
+    block_trigger_id, log_trigger_id, trigger_id, BlockFinality, EthereumBlock, EthereumBlockData,
+    EthereumBlockPointer, EthereumBlockTriggerType, EthereumBlockWithCalls,
+    EthereumBlockWithTriggers, EthereumCall, EthereumCallData, EthereumEventData,
+    EthereumTransactionData, EthereumTrigger, LightEthereumBlock, LightEthereumBlockExt,
+    TriggerKind,
 };
@@ -8,6 +8,11 @@ pub enum BlockStreamEvent {
 
     /// Signals that a revert happened and was processed.
     Revert,
+
+    /// The pending block on top of the chain head, for deployments that
+    /// have caught up with the chain head. Only streamed when indexing
+    /// pending blocks is turned on with `GRAPH_INDEX_PENDING_BLOCKS`
+    Pending(EthereumBlockWithTriggers),
 }
 
 pub trait BlockStream: Stream<Item = BlockStreamEvent, Error = Error> {}
@@ -15,15 +20,15 @@ pub trait BlockStream: Stream<Item = BlockStreamEvent, Error = Error> {}
 pub trait BlockStreamBuilder: Clone + Send + Sync + 'static {
     type Stream: BlockStream + Send + 'static;
 
+    /// Build a stream of the blocks that contain triggers matching
+    /// `filter`
     fn build(
         &self,
         logger: Logger,
         deployment_id: SubgraphDeploymentId,
         network_name: String,
         start_blocks: Vec<u64>,
-        log_filter: EthereumLogFilter,
-        call_filter: EthereumCallFilter,
-        block_filter: EthereumBlockFilter,
+        filter: EthereumTriggerFilter,
         templates_use_calls: bool,
         ethrpc_metrics: Arc<BlockStreamMetrics>,
     ) -> Self::Stream;
@@ -124,6 +124,7 @@ pub struct EthereumCall {
     pub block_hash: H256,
     pub transaction_hash: Option<H256>,
     transaction_index: u64,
+    trace_address: Vec<usize>,
 }
 
 impl EthereumCall {
@@ -160,8 +161,81 @@ impl EthereumCall {
             block_hash: trace.block_hash,
             transaction_hash: trace.transaction_hash,
             transaction_index,
+            trace_address: trace.trace_address.clone(),
         })
     }
+
+    /// The unique id of the trigger for this call; see `trigger_id`
+    pub fn trigger_id(&self) -> String {
+        let mut position = vec![self.transaction_index];
+        position.extend(self.trace_address.iter().map(|pos| *pos as u64));
+        trigger_id(self.block_number, TriggerKind::Call, &position)
+    }
+}
+
+/// The kinds of triggers that can be identified with `trigger_id`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerKind {
+    Block = 0,
+    Log = 1,
+    Call = 2,
+}
+
+/// Generate an id for a trigger that is unique across all triggers on a
+/// chain and does not depend on the subgraph that processes the trigger.
+/// The id encodes the position of the trigger in the chain: `block_number`
+/// as 8 bytes, the `kind` of trigger as one byte, and each element of
+/// `position` as 4 bytes, all in big-endian order and as a `0x`-prefixed
+/// hex string. For logs, `position` is the transaction index and the log
+/// index, for calls it is the transaction index followed by the trace
+/// address of the call, and for blocks it is empty; see `block_trigger_id`
+/// for how block handlers are told apart.
+///
+/// Ids for triggers from earlier blocks sort before those from later
+/// blocks
+pub fn trigger_id(block_number: u64, kind: TriggerKind, position: &[u64]) -> String {
+    let mut id = format!("0x{:016x}{:02x}", block_number, kind as u8);
+    for pos in position {
+        id.push_str(&format!("{:08x}", pos));
+    }
+    id
+}
+
+/// The unique id of the trigger for the block handler `handler` of the
+/// data source with address `address`. Since any number of block handlers
+/// can run for the same block, the id of the block is followed by the
+/// address and the hex-encoded name of the handler
+pub fn block_trigger_id(block_number: u64, address: &H160, handler: &str) -> String {
+    let mut id = trigger_id(block_number, TriggerKind::Block, &[]);
+    for byte in address.as_ref().iter().chain(handler.as_bytes()) {
+        id.push_str(&format!("{:02x}", byte));
+    }
+    id
+}
+
+impl EthereumTrigger {
+    /// The unique id of this trigger; see `trigger_id`. For block triggers,
+    /// `address` and `handler` identify the block handler that runs for
+    /// the trigger; they are ignored for other triggers
+    pub fn trigger_id(&self, address: &H160, handler: &str) -> String {
+        match self {
+            EthereumTrigger::Block(ptr, _) => block_trigger_id(ptr.number, address, handler),
+            EthereumTrigger::Call(call) => call.trigger_id(),
+            EthereumTrigger::Log(log) => log_trigger_id(log),
+        }
+    }
+}
+
+/// The unique id of the trigger for `log`; see `trigger_id`
+pub fn log_trigger_id(log: &Log) -> String {
+    trigger_id(
+        log.block_number.unwrap_or_default().as_u64(),
+        TriggerKind::Log,
+        &[
+            log.transaction_index.unwrap_or_default().as_u64(),
+            log.log_index.unwrap_or_default().as_u64(),
+        ],
+    )
 }
 
 #[derive(Clone, Debug)]
@@ -400,6 +474,10 @@ impl Clone for EthereumCallData {
     }
 }
 
+/// The hash that pending blocks are given. They do not have a hash until
+/// they are mined, and no mined block has this one
+pub const PENDING_BLOCK_HASH: H256 = H256([0u8; 32]);
+
 /// A block hash and block number from a specific Ethereum block.
 ///
 /// Maximum block number supported: 2^63 - 1
@@ -410,6 +488,11 @@ pub struct EthereumBlockPointer {
 }
 
 impl EthereumBlockPointer {
+    /// Whether this points to a pending block rather than a mined one
+    pub fn is_pending(&self) -> bool {
+        self.hash == PENDING_BLOCK_HASH
+    }
+
     /// Encodes the block hash into a hexadecimal string **without** a "0x" prefix.
     /// Hashes are stored in the database in this format.
     ///
@@ -527,9 +610,69 @@ impl ToEntityKey for EthereumBlockPointer {
 
 #[cfg(test)]
 mod test {
-    use super::{EthereumBlockPointer, EthereumBlockTriggerType, EthereumCall, EthereumTrigger};
+    use super::{
+        trigger_id, EthereumBlockPointer, EthereumBlockTriggerType, EthereumCall, EthereumTrigger,
+        TriggerKind,
+    };
     use web3::types::*;
 
+    #[test]
+    fn test_trigger_id() {
+        assert_eq!(
+            "0x000000000000000a00",
+            trigger_id(10, TriggerKind::Block, &[])
+        );
+        assert_eq!(
+            "0x000000000098968001000000030000002a",
+            trigger_id(10_000_000, TriggerKind::Log, &[3, 42])
+        );
+
+        let mut call = EthereumCall::default();
+        call.block_number = 7;
+        call.transaction_index = 1;
+        call.trace_address = vec![0, 2];
+        assert_eq!(
+            "0x00000000000000070200000001000000000000000002",
+            EthereumTrigger::Call(call).trigger_id(&H160::zero(), "handleCall")
+        );
+
+        // Block handlers in the same block get different ids
+        let block = EthereumTrigger::Block(
+            EthereumBlockPointer {
+                hash: H256::zero(),
+                number: 10,
+            },
+            EthereumBlockTriggerType::Every,
+        );
+        assert_eq!(
+            "0x000000000000000a0000000000000000000000000000000000000000016869",
+            block.trigger_id(&H160::from_low_u64_be(1), "hi")
+        );
+        assert_ne!(
+            block.trigger_id(&H160::from_low_u64_be(1), "handleBlock"),
+            block.trigger_id(&H160::from_low_u64_be(1), "handleOtherBlock")
+        );
+        assert_ne!(
+            block.trigger_id(&H160::from_low_u64_be(1), "handleBlock"),
+            block.trigger_id(&H160::from_low_u64_be(2), "handleBlock")
+        );
+
+        let mut block_ids = vec![
+            trigger_id(256, TriggerKind::Log, &[0, 0]),
+            trigger_id(255, TriggerKind::Call, &[7]),
+            trigger_id(1, TriggerKind::Block, &[]),
+        ];
+        block_ids.sort();
+        assert_eq!(
+            vec![
+                trigger_id(1, TriggerKind::Block, &[]),
+                trigger_id(255, TriggerKind::Call, &[7]),
+                trigger_id(256, TriggerKind::Log, &[0, 0]),
+            ],
+            block_ids
+        );
+    }
+
     #[test]
     fn test_trigger_ordering() {
         let block1 = EthereumTrigger::Block(
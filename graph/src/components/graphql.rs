@@ -1,6 +1,6 @@
 use futures::prelude::*;
 
-use crate::data::query::{Query, QueryError, QueryResult};
+use crate::data::query::{Query, QueryError, QueryExecutionError, QueryResult};
 use crate::data::subscription::{Subscription, SubscriptionError, SubscriptionResult};
 
 /// Future for query results.
@@ -24,6 +24,10 @@ pub trait GraphQlRunner: Send + Sync + 'static {
         max_first: Option<u32>,
     ) -> QueryResultFuture;
 
+    /// Checks that a GraphQL query stays within the complexity and depth
+    /// limits that `run_query` enforces, without running it.
+    fn check_query(&self, query: &Query) -> Result<(), Vec<QueryExecutionError>>;
+
     /// Runs a GraphQL subscription and returns a stream of results.
     fn run_subscription(&self, subscription: Subscription) -> SubscriptionResultFuture;
 }
@@ -4,7 +4,7 @@ use serde_json::Value;
 use slog::Logger;
 use std::time::Duration;
 
-use crate::data::subgraph::Link;
+use crate::data::subgraph::{Link, SubgraphDeploymentId};
 
 /// The values that `json_stream` returns. The struct contains the deserialized
 /// JSON value from the input stream, together with the line number from which
@@ -17,6 +17,37 @@ pub struct JsonStreamValue {
 pub type JsonValueStream =
     Box<dyn Stream<Item = JsonStreamValue, Error = failure::Error> + Send + 'static>;
 
+/// How urgently the requests of a link resolver need to be served. When
+/// the resolver has to queue requests, those with a higher priority are
+/// served first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LinkResolverPriority {
+    /// Requests made when (re)starting deployments
+    Low,
+    /// Requests from mappings and everything else
+    Normal,
+    /// Requests made for deployments triggered by users
+    High,
+}
+
+impl Default for LinkResolverPriority {
+    fn default() -> Self {
+        LinkResolverPriority::Normal
+    }
+}
+
+/// How a link resolver retries requests that fail or time out
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkResolverRetryPolicy {
+    /// How long a single attempt may take
+    pub timeout: Duration,
+    /// How many attempts to make in total; unlimited if `None`
+    pub attempts: Option<usize>,
+    /// The longest time to wait between attempts. The wait grows
+    /// exponentially with each attempt until it reaches this limit
+    pub max_backoff: Duration,
+}
+
 /// Resolves links to subgraph manifests and resources referenced by them.
 pub trait LinkResolver: Send + Sync + 'static {
     /// Updates the timeout used by the resolver.
@@ -29,6 +60,23 @@ pub trait LinkResolver: Send + Sync + 'static {
     where
         Self: Sized;
 
+    /// Sets the timeout, the number of attempts and the backoff between
+    /// attempts for requests made through the resolver.
+    fn with_retry_policy(self, policy: LinkResolverRetryPolicy) -> Self
+    where
+        Self: Sized;
+
+    /// Sets the priority of requests made through the resolver.
+    fn with_priority(self, priority: LinkResolverPriority) -> Self
+    where
+        Self: Sized;
+
+    /// Attributes requests made through the resolver to `deployment`, so
+    /// that queued requests can be served fairly across deployments.
+    fn for_deployment(self, deployment: SubgraphDeploymentId) -> Self
+    where
+        Self: Sized;
+
     /// Fetches the link contents as bytes.
     fn cat(
         &self,
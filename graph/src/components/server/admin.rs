@@ -1,8 +1,62 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::env;
 use std::io;
 use std::sync::Arc;
 
-use crate::prelude::Logger;
-use crate::prelude::NodeId;
+use crate::prelude::{format_err, Error, HandlerSimulator, Logger, NodeId};
+
+lazy_static! {
+    /// The tokens that grant access to the admin API, mapped to the name of
+    /// their holder. Set with `GRAPH_ADMIN_TOKENS=name1:token1,name2:token2`;
+    /// if it is not set, the JSON-RPC admin API does not require a token
+    static ref ADMIN_TOKENS: Option<HashMap<String, String>> = env::var("GRAPH_ADMIN_TOKENS")
+        .ok()
+        .map(|tokens| parse_admin_tokens(&tokens).expect("invalid GRAPH_ADMIN_TOKENS"));
+}
+
+fn parse_admin_tokens(tokens: &str) -> Result<HashMap<String, String>, Error> {
+    tokens
+        .split(',')
+        .map(|entry| {
+            let mut parts = entry.trim().splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(token)) if !name.is_empty() && !token.is_empty() => {
+                    Ok((token.to_owned(), name.to_owned()))
+                }
+                _ => Err(format_err!(
+                    "admin token `{}` must have the form `name:token`",
+                    entry
+                )),
+            }
+        })
+        .collect()
+}
+
+fn actor_for(
+    tokens: Option<&HashMap<String, String>>,
+    authorization: Option<&str>,
+) -> Result<Option<String>, Error> {
+    let tokens = match tokens {
+        Some(tokens) => tokens,
+        None => return Ok(None),
+    };
+    authorization
+        .map(str::trim)
+        .filter(|authorization| authorization.starts_with("Bearer "))
+        .and_then(|authorization| tokens.get(authorization["Bearer ".len()..].trim()))
+        .map(|name| Some(name.clone()))
+        .ok_or_else(|| format_err!("a valid admin token is required"))
+}
+
+/// Identify who makes an admin request from the value of its
+/// `Authorization` header, which must have the form `Bearer <token>`.
+/// Returns the name of the holder of the token, or `None` if no admin
+/// tokens are configured. Fails if tokens are configured and the request
+/// does not carry one of them.
+pub fn admin_actor(authorization: Option<&str>) -> Result<Option<String>, Error> {
+    actor_for(ADMIN_TOKENS.as_ref(), authorization)
+}
 
 /// Common trait for JSON-RPC admin server implementations.
 pub trait JsonRpcServer<P> {
@@ -13,7 +67,26 @@ pub trait JsonRpcServer<P> {
         http_port: u16,
         ws_port: u16,
         provider: Arc<P>,
+        simulator: Arc<dyn HandlerSimulator>,
         node_id: NodeId,
         logger: Logger,
     ) -> Result<Self::Server, io::Error>;
 }
+
+#[test]
+fn admin_tokens() {
+    assert!(parse_admin_tokens("alice").is_err());
+    assert!(parse_admin_tokens("alice:").is_err());
+
+    let tokens = parse_admin_tokens("alice:secret1, bob:secret:2").unwrap();
+    assert_eq!(Some(&"bob".to_owned()), tokens.get("secret:2"));
+
+    assert_eq!(None, actor_for(None, None).unwrap());
+    assert_eq!(
+        Some("alice".to_owned()),
+        actor_for(Some(&tokens), Some("Bearer secret1")).unwrap()
+    );
+    assert!(actor_for(Some(&tokens), None).is_err());
+    assert!(actor_for(Some(&tokens), Some("secret1")).is_err());
+    assert!(actor_for(Some(&tokens), Some("Bearer alice")).is_err());
+}
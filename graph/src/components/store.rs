@@ -5,18 +5,20 @@ use lazy_static::lazy_static;
 use mockall::predicate::*;
 use mockall::*;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fmt;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use web3::types::H256;
 
 use crate::data::store::*;
 use crate::data::subgraph::schema::*;
 use crate::prelude::*;
+use crate::util::compression;
 use crate::util::lfu_cache::LfuCache;
 
 lazy_static! {
@@ -48,6 +50,8 @@ pub struct EntityKey {
 pub enum EntityFilter {
     And(Vec<EntityFilter>),
     Or(Vec<EntityFilter>),
+    /// The entity does not pass the filter
+    Negation(Box<EntityFilter>),
     Equal(Attribute, Value),
     Not(Attribute, Value),
     GreaterThan(Attribute, Value),
@@ -58,10 +62,35 @@ pub enum EntityFilter {
     NotIn(Attribute, Vec<Value>),
     Contains(Attribute, Value),
     NotContains(Attribute, Value),
+    /// The list attribute contains at least one of the values in the list
+    ContainsAny(Attribute, Value),
     StartsWith(Attribute, Value),
     NotStartsWith(Attribute, Value),
     EndsWith(Attribute, Value),
     NotEndsWith(Attribute, Value),
+    /// The filter only applies to entities of the given type; entities of
+    /// any other type pass it. This is used to filter queries for
+    /// interfaces by attributes that only some implementers have
+    TypeCondition(String, Box<EntityFilter>),
+    /// At least one of the entities that the entity is linked to through
+    /// an attribute passes the filter
+    Child(ChildFilter),
+}
+
+/// A filter on the entities that an entity is linked to, like the tokens
+/// whose owner has a balance above some amount
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChildFilter {
+    /// The attribute of the parent that holds the ids of its children or,
+    /// if the link is `derived`, the attribute of the children that holds
+    /// the id of their parent
+    pub attribute: Attribute,
+    /// The entity types that children can have
+    pub entity_types: Vec<String>,
+    pub derived: bool,
+    /// Whether `attribute` holds a list of ids
+    pub list: bool,
+    pub filter: Box<EntityFilter>,
 }
 
 // Define some convenience methods
@@ -104,6 +133,105 @@ impl EntityFilter {
             None => self,
         }
     }
+
+    /// Whether `entity` passes this filter. This evaluates the filter in
+    /// memory the same way the store evaluates it in the database, for
+    /// entities that are not stored yet. Missing attributes are `null`, and
+    /// like in SQL, comparing `null` with anything but `null` fails, even
+    /// if the comparison is negated. Filters on the entities an entity is
+    /// linked to can not be evaluated in memory
+    pub fn matches(&self, entity: &Entity) -> Result<bool, QueryExecutionError> {
+        use std::cmp::Ordering::*;
+        use EntityFilter as f;
+
+        let get = |attr: &Attribute| entity.get(attr).unwrap_or(&Value::Null);
+        let is_null = |attr: &Attribute| *get(attr) == Value::Null;
+        let compare = |attr: &Attribute, value: &Value| match get(attr) {
+            Value::Null => None,
+            actual => actual.partial_cmp(value),
+        };
+        let contains = |attr: &Attribute, value: &Value| match (get(attr), value) {
+            (Value::String(s), Value::String(part)) => s.contains(part.as_str()),
+            (Value::Bytes(b), Value::Bytes(part)) => {
+                let (b, part) = (b.as_slice(), part.as_slice());
+                part.is_empty() || b.windows(part.len()).any(|window| window == part)
+            }
+            (Value::List(list), Value::List(values)) => {
+                values.iter().all(|value| list.contains(value))
+            }
+            _ => false,
+        };
+        let string = |attr: &Attribute| match get(attr) {
+            Value::String(s) => Some(s.as_str()),
+            _ => None,
+        };
+        let prefix = |value: &Value| match value {
+            Value::String(s) => s.clone(),
+            _ => value.to_string(),
+        };
+
+        Ok(match self {
+            f::And(filters) => {
+                for filter in filters {
+                    if !filter.matches(entity)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            f::Or(filters) => {
+                for filter in filters {
+                    if filter.matches(entity)? {
+                        return Ok(true);
+                    }
+                }
+                false
+            }
+            f::Negation(filter) => !filter.matches(entity)?,
+            f::Equal(attr, value) => get(attr) == value,
+            f::Not(attr, Value::Null) => !is_null(attr),
+            f::Not(attr, value) => !is_null(attr) && get(attr) != value,
+            f::GreaterThan(attr, value) => compare(attr, value) == Some(Greater),
+            f::LessThan(attr, value) => compare(attr, value) == Some(Less),
+            f::GreaterOrEqual(attr, value) => compare(attr, value).map_or(false, |ord| ord != Less),
+            f::LessOrEqual(attr, value) => compare(attr, value).map_or(false, |ord| ord != Greater),
+            f::In(attr, values) => values.contains(get(attr)),
+            // The store checks `attr is not null or attr not in (..)` if
+            // `values` contains `null`
+            f::NotIn(attr, values) => {
+                !is_null(attr) && (values.contains(&Value::Null) || !values.contains(get(attr)))
+            }
+            f::Contains(attr, value) => contains(attr, value),
+            f::NotContains(attr, value) => !is_null(attr) && !contains(attr, value),
+            f::ContainsAny(attr, value) => match (get(attr), value) {
+                (Value::List(list), Value::List(values)) => {
+                    values.iter().any(|value| list.contains(value))
+                }
+                _ => false,
+            },
+            f::StartsWith(attr, value) => {
+                string(attr).map_or(false, |s| s.starts_with(&prefix(value)))
+            }
+            f::NotStartsWith(attr, value) => {
+                string(attr).map_or(false, |s| !s.starts_with(&prefix(value)))
+            }
+            f::EndsWith(attr, value) => string(attr).map_or(false, |s| s.ends_with(&prefix(value))),
+            f::NotEndsWith(attr, value) => {
+                string(attr).map_or(false, |s| !s.ends_with(&prefix(value)))
+            }
+            f::TypeCondition(entity_type, filter) => match entity.get("__typename") {
+                Some(Value::String(typename)) if typename == entity_type => {
+                    filter.matches(entity)?
+                }
+                _ => true,
+            },
+            f::Child(_) => {
+                return Err(QueryExecutionError::NotSupported(
+                    "filters on linked entities can not be evaluated in memory".to_owned(),
+                ))
+            }
+        })
+    }
 }
 
 /// The order in which entities should be restored from a store.
@@ -121,6 +249,37 @@ impl EntityOrder {
             EntityOrder::Descending => "desc",
         }
     }
+
+    pub fn reverse(&self) -> Self {
+        match self {
+            EntityOrder::Ascending => EntityOrder::Descending,
+            EntityOrder::Descending => EntityOrder::Ascending,
+        }
+    }
+}
+
+/// Where entities whose sort key is null go when entities are ordered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NullsOrder {
+    First,
+    Last,
+}
+
+impl NullsOrder {
+    /// Return `"nulls first"` or `"nulls last"` as is used in SQL
+    pub fn to_sql(&self) -> &'static str {
+        match self {
+            NullsOrder::First => "nulls first",
+            NullsOrder::Last => "nulls last",
+        }
+    }
+
+    pub fn reverse(&self) -> Self {
+        match self {
+            NullsOrder::First => NullsOrder::Last,
+            NullsOrder::Last => NullsOrder::First,
+        }
+    }
 }
 
 /// How many entities to return, how many to skip etc.
@@ -131,6 +290,11 @@ pub struct EntityRange {
 
     /// How many entities to skip.
     pub skip: u32,
+
+    /// Count `first` and `skip` from the end of the result rather than
+    /// from its start, so that the query returns the last entities. The
+    /// entities are still returned in the order of the query
+    pub from_end: bool,
 }
 
 impl EntityRange {
@@ -139,10 +303,94 @@ impl EntityRange {
         Self {
             first: Some(n),
             skip: 0,
+            from_end: false,
         }
     }
 }
 
+/// The position of an entity in the order of a query. Queries are ordered
+/// by an attribute, then by `id`, and then by entity type since entities
+/// of different types can have the same id. The cursor holds the entity's
+/// values for all three, which lets queries continue after or stop before
+/// the entity without counting the entities in front of it. Cursors are
+/// handed to clients as opaque strings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityCursor {
+    /// The value of the attribute the query is ordered by; `Null` if the
+    /// entity does not have one or the query is only ordered by `id`. For
+    /// fulltext searches, whose matches are ranked when the query runs,
+    /// this is the position of the entity in the matches instead
+    pub value: Value,
+    pub id: String,
+    pub entity_type: String,
+}
+
+impl EntityCursor {
+    /// The cursor of `entity` in a query that is ordered by `order_by`
+    pub fn new(entity: &Entity, order_by: Option<&str>) -> Result<Self, QueryExecutionError> {
+        let value = order_by
+            .and_then(|attribute| entity.get(attribute))
+            .cloned()
+            .unwrap_or(Value::Null);
+        Self::with_value(entity, value)
+    }
+
+    /// The cursor of `entity` at `position` in the matches of a fulltext
+    /// search
+    pub fn at_position(entity: &Entity, position: u32) -> Result<Self, QueryExecutionError> {
+        Self::with_value(entity, Value::Int(position as i32))
+    }
+
+    fn with_value(entity: &Entity, value: Value) -> Result<Self, QueryExecutionError> {
+        let id = entity
+            .id()
+            .map_err(|e| QueryExecutionError::EntityParseError(e.to_string()))?;
+        let entity_type = match entity.get("__typename") {
+            Some(Value::String(entity_type)) => entity_type.clone(),
+            _ => {
+                return Err(QueryExecutionError::EntityParseError(format!(
+                    "entity `{}` has no type",
+                    id
+                )))
+            }
+        };
+        Ok(EntityCursor {
+            value,
+            id,
+            entity_type,
+        })
+    }
+
+    /// The position in the matches of a fulltext search that this cursor
+    /// was made for with `at_position`
+    pub fn position(&self) -> Result<u32, QueryExecutionError> {
+        match &self.value {
+            Value::Int(position) if *position >= 0 => Some(*position as u32),
+            _ => None,
+        }
+        .ok_or_else(|| QueryExecutionError::InvalidCursor(self.encode()))
+    }
+
+    pub fn encode(&self) -> String {
+        hex::encode(
+            serde_json::to_vec(&(&self.value, &self.id, &self.entity_type))
+                .expect("cursors can be serialized"),
+        )
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, QueryExecutionError> {
+        let (value, id, entity_type) = hex::decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<(Value, String, String)>(&bytes).ok())
+            .ok_or_else(|| QueryExecutionError::InvalidCursor(cursor.to_owned()))?;
+        Ok(EntityCursor {
+            value,
+            id,
+            entity_type,
+        })
+    }
+}
+
 /// The attribute we want to window by in an `EntityWindow`. We have to
 /// distinguish between scalar and list attributes since we need to use
 /// different queries for them, and the JSONB storage scheme can not
@@ -227,6 +475,11 @@ pub type BlockNumber = i32;
 
 pub const BLOCK_NUMBER_MAX: BlockNumber = std::i32::MAX;
 
+/// Queries at this block see the latest block of a deployment together
+/// with the changes of the pending block the deployment indexed on top of
+/// it, if there is one. It is never the number of a real block
+pub const BLOCK_NUMBER_PENDING: BlockNumber = BLOCK_NUMBER_MAX - 1;
+
 /// A query for entities in a store.
 ///
 /// Details of how query generation for `EntityQuery` works can be found
@@ -256,9 +509,22 @@ pub struct EntityQuery {
     /// The direction to order entities in.
     pub order_direction: Option<EntityOrder>,
 
+    /// Where to put entities whose sort key is null. Entities with the
+    /// same sort key are always ordered by their id.
+    pub order_nulls: Option<NullsOrder>,
+
     /// A range to limit the size of the result.
     pub range: EntityRange,
 
+    /// Only return entities that come after this cursor in the order of
+    /// the query. Unlike `range.skip`, this does not get slower the
+    /// further into the result the cursor is
+    pub after: Option<EntityCursor>,
+
+    /// Only return entities that come before this cursor in the order of
+    /// the query
+    pub before: Option<EntityCursor>,
+
     /// Optional logger for anything related to this query
     pub logger: Option<Logger>,
 
@@ -278,7 +544,10 @@ impl EntityQuery {
             filter: None,
             order_by: None,
             order_direction: None,
+            order_nulls: None,
             range: EntityRange::first(100),
+            after: None,
+            before: None,
             logger: None,
             _force_use_of_new: (),
         }
@@ -294,6 +563,11 @@ impl EntityQuery {
         self
     }
 
+    pub fn order_nulls(mut self, nulls: NullsOrder) -> Self {
+        self.order_nulls = Some(nulls);
+        self
+    }
+
     pub fn order_by_attribute(mut self, by: (String, ValueType)) -> Self {
         self.order_by = Some(by);
         self
@@ -325,6 +599,16 @@ impl EntityQuery {
         self
     }
 
+    pub fn after(mut self, cursor: EntityCursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    pub fn before(mut self, cursor: EntityCursor) -> Self {
+        self.before = Some(cursor);
+        self
+    }
+
     pub fn simplify(mut self) -> Self {
         // If there is one window, with one id, in a direct relation to the
         // entities, we can simplify the query by changing the filter and
@@ -353,6 +637,76 @@ impl EntityQuery {
     }
 }
 
+/// The functions that aggregate the values of numeric attributes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregateFunction {
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl AggregateFunction {
+    pub const ALL: [AggregateFunction; 4] = [
+        AggregateFunction::Sum,
+        AggregateFunction::Min,
+        AggregateFunction::Max,
+        AggregateFunction::Avg,
+    ];
+
+    /// The name of the SQL function that computes the aggregate
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AggregateFunction::Sum => "sum",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+            AggregateFunction::Avg => "avg",
+        }
+    }
+
+    /// The attribute that holds this aggregate of `attribute`, for
+    /// example, `amount_sum`
+    pub fn attribute(&self, attribute: &str) -> String {
+        format!("{}_{}", attribute, self.as_str())
+    }
+}
+
+/// The attribute of an aggregation that holds the number of entities in
+/// each group
+pub const AGGREGATE_COUNT_ATTRIBUTE: &str = "count";
+
+/// A query for aggregations over the entities of one type that match a
+/// filter. Entities are grouped by the values of the `group_by` attributes,
+/// or form a single group if there are none. The result has one entity per
+/// group; it holds the number of entities in the group under
+/// `AGGREGATE_COUNT_ATTRIBUTE`, the values of the `group_by` attributes,
+/// and every aggregate in `AggregateFunction::ALL` of every numeric
+/// attribute. Groups are ordered by their `group_by` values
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityAggregation {
+    /// ID of the subgraph.
+    pub subgraph_id: SubgraphDeploymentId,
+
+    /// The block height at which to aggregate entities
+    pub block: BlockNumber,
+
+    /// The entity type whose entities are aggregated
+    pub entity_type: String,
+
+    /// Only aggregate entities that match this filter
+    pub filter: Option<EntityFilter>,
+
+    /// The attributes whose values form the groups
+    pub group_by: Vec<String>,
+
+    /// The range of groups to return
+    pub range: EntityRange,
+
+    /// The most entities that the aggregation may look at; aggregating
+    /// fails if more entities match the filter
+    pub max_entities: u32,
+}
+
 /// Operation types that lead to entity changes.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
@@ -408,6 +762,175 @@ impl From<MetadataOperation> for Option<EntityChange> {
     }
 }
 
+/// How an entity was modified by a block or a range of blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockEntityChangeKind {
+    /// The entity did not exist before the changes
+    Created,
+    /// A new version of an existing entity was written
+    Updated,
+    /// The entity was removed
+    Deleted,
+}
+
+/// A change that a block or a range of blocks made to an entity, as
+/// reconstructed from the versions of the entity that the store keeps
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockEntityChange {
+    /// Entity type name of the changed entity.
+    pub entity_type: String,
+    /// ID of the changed entity.
+    pub entity_id: String,
+    /// What happened to the entity.
+    pub kind: BlockEntityChangeKind,
+    /// The latest version of the entity, or for deletions the version
+    /// that was current before the changes.
+    pub data: Entity,
+}
+
+/// A digest of all the changes a deployment made to its entities up to
+/// and including a block. Indexers that compute the same digest for a
+/// block agree on the data of the deployment at that block
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProofOfIndexing {
+    pub block: EthereumBlockPointer,
+    pub digest: H256,
+}
+
+impl ProofOfIndexing {
+    /// The digest for `block` given the digest of the previous block the
+    /// deployment processed, or `None` if this is its first block, and the
+    /// changes `mods` that the block made
+    pub fn digest(
+        previous: Option<H256>,
+        block: &EthereumBlockPointer,
+        mods: &[EntityModification],
+    ) -> Result<H256, StoreError> {
+        /// Add `bytes` to `hasher`, prefixed with their length so that
+        /// different sequences of parts never hash the same
+        fn update(hasher: &mut tiny_keccak::Keccak, bytes: &[u8]) {
+            hasher.update(&(bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+
+        /// Compressed bytes are hashed by their uncompressed payload since
+        /// the compressed bytes depend on the version of the compression
+        /// library that produced them
+        fn uncompressed(value: &Value) -> Cow<Value> {
+            match value {
+                Value::Bytes(bytes) => match compression::uncompressed(bytes.as_slice()) {
+                    Some(payload) => Cow::Owned(Value::Bytes(payload.as_slice().into())),
+                    None => Cow::Borrowed(value),
+                },
+                Value::List(values) => {
+                    let values: Vec<_> = values.iter().map(uncompressed).collect();
+                    if values.iter().all(|value| match value {
+                        Cow::Borrowed(_) => true,
+                        Cow::Owned(_) => false,
+                    }) {
+                        Cow::Borrowed(value)
+                    } else {
+                        Cow::Owned(Value::List(
+                            values.into_iter().map(Cow::into_owned).collect(),
+                        ))
+                    }
+                }
+                _ => Cow::Borrowed(value),
+            }
+        }
+
+        let mut mods: Vec<_> = mods
+            .iter()
+            .filter(|modification| modification.entity_key().subgraph_id != *SUBGRAPHS_ID)
+            .collect();
+        mods.sort_by(|a, b| {
+            let (a, b) = (a.entity_key(), b.entity_key());
+            (&a.entity_type, &a.entity_id).cmp(&(&b.entity_type, &b.entity_id))
+        });
+
+        let mut hasher = tiny_keccak::Keccak::new_keccak256();
+        update(&mut hasher, previous.unwrap_or_else(H256::zero).as_ref());
+        update(&mut hasher, block.hash.as_ref());
+        for modification in mods {
+            use EntityModification::*;
+
+            let key = modification.entity_key();
+            update(&mut hasher, key.entity_type.as_bytes());
+            update(&mut hasher, key.entity_id.as_bytes());
+            match modification {
+                Insert { data, .. } | Overwrite { data, .. } => {
+                    // Attributes are hashed in a fixed order
+                    let data: BTreeMap<_, _> = data
+                        .iter()
+                        .map(|(attr, value)| (attr, uncompressed(value)))
+                        .collect();
+                    update(&mut hasher, &serde_json::to_vec(&data)?);
+                }
+                Remove { .. } => update(&mut hasher, &[]),
+            }
+        }
+
+        let mut digest = [0u8; 32];
+        hasher.finalize(&mut digest);
+        Ok(H256::from(digest))
+    }
+}
+
+/// How an entity differs between the states of a subgraph at two blocks
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityDiff {
+    pub entity_id: String,
+    pub kind: BlockEntityChangeKind,
+    /// The attributes whose values differ, sorted by name. Only updated
+    /// entities have changed fields
+    pub changed_fields: Vec<String>,
+}
+
+impl EntityDiff {
+    /// Compare the versions of the entity with id `entity_id` at two
+    /// blocks. Returns `None` if the entity is the same at both blocks
+    pub fn between(
+        entity_id: String,
+        previous: Option<&Entity>,
+        current: Option<&Entity>,
+    ) -> Option<EntityDiff> {
+        let (kind, changed_fields) = match (previous, current) {
+            (None, None) => return None,
+            (None, Some(_)) => (BlockEntityChangeKind::Created, vec![]),
+            (Some(_), None) => (BlockEntityChangeKind::Deleted, vec![]),
+            (Some(previous), Some(current)) => {
+                let mut fields: Vec<_> = previous
+                    .keys()
+                    .chain(current.keys().filter(|key| !previous.contains_key(*key)))
+                    .filter(|key| previous.get(*key) != current.get(*key))
+                    .cloned()
+                    .collect();
+                if fields.is_empty() {
+                    return None;
+                }
+                fields.sort();
+                (BlockEntityChangeKind::Updated, fields)
+            }
+        };
+        Some(EntityDiff {
+            entity_id,
+            kind,
+            changed_fields,
+        })
+    }
+
+    /// The difference in the opposite direction, from the later block to
+    /// the earlier one
+    pub fn inverse(self) -> EntityDiff {
+        let kind = match self.kind {
+            BlockEntityChangeKind::Created => BlockEntityChangeKind::Deleted,
+            BlockEntityChangeKind::Updated => BlockEntityChangeKind::Updated,
+            BlockEntityChangeKind::Deleted => BlockEntityChangeKind::Created,
+        };
+        EntityDiff { kind, ..self }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 /// The store emits `StoreEvents` to indicate that some entities have changed.
 /// For block-related data, at most one `StoreEvent` is emitted for each block
@@ -713,6 +1236,24 @@ pub struct AttributeIndexDefinition {
     pub entity_name: String,
 }
 
+/// The outcome of creating an index that a subgraph was missing
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexCreation {
+    pub name: String,
+    /// Why the index could not be created, if it could not
+    pub error: Option<String>,
+}
+
+/// The entities of one type whose IDs are longer than a limit
+#[derive(Clone, Debug, PartialEq)]
+pub struct OversizedEntityIds {
+    pub entity_type: String,
+    /// How many entities have an ID that is too long
+    pub count: u64,
+    /// The length of the longest ID in bytes
+    pub longest: u64,
+}
+
 #[derive(Fail, Debug)]
 pub enum StoreError {
     #[fail(display = "store transaction failed, need to retry: {}", _0)]
@@ -808,10 +1349,23 @@ pub trait Store: Send + Sync + 'static {
     /// Queries the store for a single entity matching the store query.
     fn find_one(&self, query: EntityQuery) -> Result<Option<Entity>, QueryExecutionError>;
 
+    /// Aggregates the entities that `query` selects.
+    fn aggregate(&self, query: EntityAggregation) -> Result<Vec<Entity>, QueryExecutionError>;
+
     /// Find the reverse of keccak256 for `hash` through looking it up in the
     /// rainbow table.
     fn find_ens_name(&self, _hash: &str) -> Result<Option<String>, QueryExecutionError>;
 
+    /// For each entity type of the deployment `subgraph_id`, the latest
+    /// block up to and including `block` in which entities of the type
+    /// changed, ordered by entity type. Without `block`, the latest such
+    /// block, which does not go backwards when blocks are reverted
+    fn entity_type_updates(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block: Option<BlockNumber>,
+    ) -> Result<Vec<(String, BlockNumber)>, StoreError>;
+
     /// Transact the entity changes from a single block atomically into the store, and update the
     /// subgraph block pointer to `block_ptr_to`.
     ///
@@ -1144,6 +1698,16 @@ pub trait Store: Send + Sync + 'static {
         ops: Vec<MetadataOperation>,
     ) -> Result<(), StoreError>;
 
+    /// Create a new subgraph deployment like `create_subgraph_deployment`,
+    /// but keep its tables and indexes in the database tablespace
+    /// `tablespace`, which must exist already
+    fn create_subgraph_deployment_in_tablespace(
+        &self,
+        schema: &Schema,
+        ops: Vec<MetadataOperation>,
+        tablespace: String,
+    ) -> Result<(), StoreError>;
+
     /// Start an existing subgraph deployment. This will reset the state of
     /// the subgraph to a known good state. `ops` needs to contain all the
     /// operations on the subgraph of subgraphs to reset the metadata of the
@@ -1191,6 +1755,199 @@ pub trait SubgraphDeploymentStore: Send + Sync + 'static {
     /// store internals that should really be hidden and should be used
     /// sparingly and only when absolutely needed
     fn uses_relational_schema(&self, subgraph_id: &SubgraphDeploymentId) -> Result<bool, Error>;
+
+    /// Return the changes that the block with number `block` made to the
+    /// entities of the subgraph, ordered by entity type and id. This is only
+    /// supported for subgraphs that use relational storage
+    fn entity_changes_in_block(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<Vec<BlockEntityChange>, StoreError>;
+
+    /// Return the changes that the blocks after `since` up to and including
+    /// `to` made to the entities of the subgraph, ordered by entity type and
+    /// id. Applying them to a snapshot of the entities as of `since` turns
+    /// it into a snapshot as of `to`. This is only supported for subgraphs
+    /// that use relational storage
+    fn entity_changes_since(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        since: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<BlockEntityChange>, StoreError>;
+
+    /// Return how the entities of type `entity_type` differ between the
+    /// states of the subgraph at blocks `from` and `to`, ordered by entity
+    /// id. `from` may be later than `to`. This is only supported for
+    /// subgraphs that use relational storage, and only for blocks whose
+    /// entity versions have not been removed by a revert
+    fn entity_diff(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        entity_type: &str,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<EntityDiff>, StoreError>;
+
+    /// Create the indexes on the entities of the subgraph that it should
+    /// have, but does not, e.g., because it was deployed by an older
+    /// version of graph-node. Failing to create an index does not stop the
+    /// others from being created; the outcome for each missing index is
+    /// returned
+    fn create_missing_indexes(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<IndexCreation>, StoreError>;
+
+    /// Find the entities of the subgraph whose IDs are longer than
+    /// `max_length` bytes, e.g., because they were stored before IDs were
+    /// limited, and report them for each entity type that has any
+    fn oversized_entity_ids(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        max_length: usize,
+    ) -> Result<Vec<OversizedEntityIds>, StoreError>;
+
+    /// Return the numbers of the blocks after `since` up to and including
+    /// `to` that created, updated or deleted entities of the subgraph, in
+    /// ascending order and at most `limit` of them. This is only supported
+    /// for subgraphs that use relational storage
+    fn blocks_with_entity_changes(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        since: BlockNumber,
+        to: BlockNumber,
+        limit: usize,
+    ) -> Result<Vec<BlockNumber>, StoreError>;
+
+    /// Return the disk space used by the tables and indexes of the
+    /// subgraph, in bytes
+    fn deployment_size(&self, subgraph_id: &SubgraphDeploymentId) -> Result<u64, StoreError>;
+
+    /// Ask for the deployment `subgraph_id`, which must use JSONB storage,
+    /// to be migrated to relational storage. The node the deployment is
+    /// assigned to performs the migration in the background with
+    /// `migrate_storage_batch`. Queries keep being answered from the old
+    /// tables until the deployment is switched over
+    fn request_storage_migration(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError>;
+
+    /// The deployments whose migration to relational storage was requested
+    /// and has not finished yet
+    fn storage_migrations_requested(&self) -> Result<Vec<SubgraphDeploymentId>, StoreError>;
+
+    /// Perform the next step of migrating the deployment `subgraph_id` to
+    /// relational storage, copying at most `batch_size` entities, and
+    /// return `true` once the deployment has been switched over. The
+    /// deployment must not process blocks while it is migrated. A
+    /// migration that was interrupted continues where it left off, unless
+    /// the deployment processed blocks in the meantime. The history of the
+    /// deployment is only kept for its most recent blocks; it can not be
+    /// queried or reverted before those any more
+    fn migrate_storage_batch(
+        &self,
+        logger: &Logger,
+        subgraph_id: &SubgraphDeploymentId,
+        batch_size: i64,
+    ) -> Result<bool, StoreError>;
+
+    /// Start copying the entities that the deployment `base` had at
+    /// `block` into the deployment `subgraph_id`, which must not have
+    /// processed any blocks yet, or resume a copy that was interrupted.
+    /// Returns the entity types whose tables still need to be copied with
+    /// `copy_deployment_batch`. This is only supported if both deployments
+    /// use relational storage
+    fn start_copy_deployment(
+        &self,
+        base: &SubgraphDeploymentId,
+        subgraph_id: &SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+    ) -> Result<Vec<String>, StoreError>;
+
+    /// Copy the next batch of at most `batch_size` versions of
+    /// `entity_type` from `base` into `subgraph_id` and record how far
+    /// copying the table has come, in one transaction. Returns `true` once
+    /// the table has been copied completely. Batches of different tables
+    /// can be copied concurrently
+    fn copy_deployment_batch(
+        &self,
+        base: &SubgraphDeploymentId,
+        subgraph_id: &SubgraphDeploymentId,
+        entity_type: &str,
+        block: EthereumBlockPointer,
+        batch_size: i64,
+    ) -> Result<bool, StoreError>;
+
+    /// Finish copying the entities of `base` into `subgraph_id` once all
+    /// tables have been copied: copy the proofs of indexing, apply `ops`
+    /// and move the block pointer of `subgraph_id` to `block` in one
+    /// transaction
+    fn finish_copy_deployment(
+        &self,
+        base: &SubgraphDeploymentId,
+        subgraph_id: &SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+        ops: Vec<MetadataOperation>,
+    ) -> Result<(), StoreError>;
+
+    /// Revert all changes that the blocks after `block` made to the
+    /// deployment `subgraph_id`, remove the dynamic data sources they
+    /// created and move its block pointer back to `block`, in one
+    /// transaction. `block` must be an ancestor of the deployment's current
+    /// block in the block cache. This is only supported for deployments
+    /// that use relational storage
+    fn rewind_deployment(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+    ) -> Result<(), StoreError>;
+
+    /// Replace the pending changes of the deployment `subgraph_id` with
+    /// `mods`, the changes of the pending block `block` on top of the
+    /// deployment's latest block. Pending changes are kept apart from the
+    /// deployment's entities, are only visible to queries at
+    /// `BLOCK_NUMBER_PENDING`, and are discarded when the next block is
+    /// written or reverted. This is only supported for deployments that
+    /// use relational storage
+    fn transact_pending_block_operations(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+        mods: Vec<EntityModification>,
+    ) -> Result<(), StoreError>;
+
+    /// Write the changes `mods` to annotation entities of the deployment
+    /// `subgraph_id`. Annotations are written outside of block processing
+    /// and are not versioned: every change replaces the entity at all
+    /// blocks, and reverting blocks does not affect them. This is only
+    /// supported for deployments that use relational storage
+    fn write_annotations(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        mods: Vec<EntityModification>,
+    ) -> Result<(), StoreError>;
+
+    /// Return the proof of indexing of the deployment `subgraph_id` for
+    /// block `block`, or `None` if the deployment did not process that
+    /// block
+    fn proof_of_indexing(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<Option<ProofOfIndexing>, StoreError>;
+
+    /// Return the proofs of indexing of the deployment `subgraph_id` for
+    /// the blocks from `from` to `to`, inclusive, that it processed,
+    /// ordered by block number
+    fn proofs_of_indexing(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<ProofOfIndexing>, StoreError>;
 }
 
 /// Common trait for blockchain store implementations.
@@ -1245,6 +2002,12 @@ pub trait ChainStore: Send + Sync + 'static {
     /// Returns the blocks present in the store.
     fn blocks(&self, hashes: Vec<H256>) -> Result<Vec<LightEthereumBlock>, Error>;
 
+    /// The timestamps of the blocks with `hashes` in seconds since the
+    /// epoch. Blocks that are not present in the store are left out. Blocks
+    /// are looked up by hash alone, so that this works for blocks of any
+    /// network
+    fn block_timestamps(&self, hashes: Vec<H256>) -> Result<HashMap<H256, u64>, Error>;
+
     /// Get the `offset`th ancestor of `block_hash`, where offset=0 means the block matching
     /// `block_hash` and offset=1 means its parent. Returns None if unable to complete due to
     /// missing blocks in the chain store.
@@ -1339,6 +2102,22 @@ pub struct EntityCache {
     /// The accumulated changes to an entity. An entry of `None`
     /// means that the entity should be deleted
     updates: HashMap<EntityKey, Option<Entity>>,
+    /// The keys of all entities read with `get`; only tracked after
+    /// `track_reads` has been called
+    reads: Option<HashSet<EntityKey>>,
+    /// The keys of the entities changed with `set`, in the order in which
+    /// they were set after not having been changed or after having been
+    /// removed. A key can appear more than once
+    set_order: Vec<EntityKey>,
+    /// The entity types for which `created_entity_ids` was called; any
+    /// change to an entity of these types could change what it returned
+    listed_types: HashSet<(SubgraphDeploymentId, String)>,
+    /// The block as of which entities are read from the store; the latest
+    /// block if `None`
+    block: Option<BlockNumber>,
+    /// Entities read from the store that this cache shares with the caches
+    /// created with `fork`; see there
+    shared: Option<Arc<Mutex<LfuCache<EntityKey, Option<Entity>>>>>,
 }
 
 pub struct ModificationsAndCache {
@@ -1355,17 +2134,134 @@ impl EntityCache {
         EntityCache {
             current,
             updates: HashMap::new(),
+            reads: None,
+            set_order: Vec::new(),
+            listed_types: HashSet::new(),
+            block: None,
+            shared: None,
         }
     }
 
+    /// The entities that were read from the store, without the changes
+    /// that were made to them
+    pub fn discard_changes(self) -> LfuCache<EntityKey, Option<Entity>> {
+        self.current
+    }
+
+    /// Read entities from the store as of `block` rather than the latest
+    /// block, which is only possible for deployments that use relational
+    /// storage
+    pub fn at_block(mut self, block: BlockNumber) -> Self {
+        self.block = Some(block);
+        self
+    }
+
+    /// Create a cache without changes that reads entities the same way as
+    /// `self` and tracks its reads. The entities `self` read from the store
+    /// so far, and those that any of its forks read from now on, are shared
+    /// between them so that caches that are used concurrently do not read
+    /// the same entity from the store more than once. Call `unshare` once
+    /// the forks are not needed anymore
+    pub fn fork(&mut self) -> EntityCache {
+        let current = &mut self.current;
+        let shared = self
+            .shared
+            .get_or_insert_with(|| Arc::new(Mutex::new(std::mem::take(current))))
+            .clone();
+        let mut fork = EntityCache::new();
+        fork.block = self.block;
+        fork.shared = Some(shared);
+        fork.track_reads();
+        fork
+    }
+
+    /// Take the entities that were shared with forks back into `self`.
+    /// Forks that still exist keep reading from the store, but their
+    /// entities are not shared with `self` anymore
+    pub fn unshare(&mut self) {
+        if let Some(shared) = self.shared.take() {
+            let mut current = match Arc::try_unwrap(shared) {
+                Ok(shared) => shared.into_inner().unwrap(),
+                Err(shared) => shared.lock().unwrap().clone(),
+            };
+            current.extend(std::mem::take(&mut self.current));
+            self.current = current;
+        }
+    }
+
+    /// Remember the keys of all entities that are read from now on, so
+    /// that `conflicts_with` can take them into account
+    pub fn track_reads(&mut self) {
+        self.reads.get_or_insert_with(HashSet::new);
+    }
+
+    /// Return `true` if `self` changes an entity that `other` reads or
+    /// changes, or the other way around. Without conflicts, the changes in
+    /// both caches can be combined in any order with the same result.
+    /// Reads are only considered if they were tracked with `track_reads`.
+    pub fn conflicts_with(&self, other: &EntityCache) -> bool {
+        fn touches(cache: &EntityCache, key: &EntityKey) -> bool {
+            cache.updates.contains_key(key)
+                || cache
+                    .reads
+                    .as_ref()
+                    .map_or(false, |reads| reads.contains(key))
+                || cache
+                    .listed_types
+                    .contains(&(key.subgraph_id.clone(), key.entity_type.clone()))
+        }
+
+        self.updates.keys().any(|key| touches(other, key))
+            || other.updates.keys().any(|key| touches(self, key))
+    }
+
+    /// The key of an entity that `self` and `other` change differently, if
+    /// there is one. The smallest such key is returned so that the result
+    /// does not depend on the order of the changes
+    pub fn different_update(&self, other: &EntityCache) -> Option<EntityKey> {
+        self.updates
+            .keys()
+            .chain(other.updates.keys())
+            .filter(|key| self.updates.get(key) != other.updates.get(key))
+            .min_by_key(|key| (&key.entity_type, &key.entity_id))
+            .cloned()
+    }
+
     pub fn get(
         &mut self,
         store: &(impl Store + ?Sized),
         key: &EntityKey,
     ) -> Result<Option<Entity>, QueryExecutionError> {
+        if let Some(reads) = self.reads.as_mut() {
+            reads.insert(key.clone());
+        }
         let current = match self.current.get(&key) {
             None => {
-                let entity = store.get(key.clone())?;
+                let shared = self
+                    .shared
+                    .as_ref()
+                    .and_then(|shared| shared.lock().unwrap().get(key).cloned());
+                let entity = match (shared, self.block) {
+                    (Some(entity), _) => entity,
+                    (None, block) => {
+                        let entity = match block {
+                            None => store.get(key.clone())?,
+                            Some(_) => {
+                                let mut ids_for_type = BTreeMap::new();
+                                ids_for_type
+                                    .insert(key.entity_type.as_str(), vec![key.entity_id.as_str()]);
+                                load_many(store, self.block, &key.subgraph_id, ids_for_type)?
+                                    .into_iter()
+                                    .flat_map(|(_, entities)| entities)
+                                    .next()
+                            }
+                        };
+                        if let Some(shared) = &self.shared {
+                            shared.lock().unwrap().insert(key.clone(), entity.clone());
+                        }
+                        entity
+                    }
+                };
                 self.current.insert(key.clone(), entity.clone());
                 entity
             }
@@ -1392,6 +2288,10 @@ impl EntityCache {
     }
 
     pub fn set(&mut self, key: EntityKey, entity: Entity) {
+        match self.updates.get(&key) {
+            Some(Some(_)) => (),
+            _ => self.set_order.push(key.clone()),
+        }
         let update = self.updates.entry(key).or_insert(None);
 
         match update {
@@ -1419,8 +2319,20 @@ impl EntityCache {
         }
     }
 
-    pub fn extend(&mut self, other: EntityCache) {
+    pub fn extend(&mut self, mut other: EntityCache) {
         self.current.extend(other.current);
+        self.listed_types.extend(other.listed_types);
+
+        // Set entities in the order in which `other` set them, so that
+        // `created_entity_ids` returns them in that order
+        for key in other.set_order {
+            if let Some(update) = other.updates.remove(&key) {
+                match update {
+                    Some(update) => self.set(key, update),
+                    None => self.remove(key),
+                }
+            }
+        }
         for (key, update) in other.updates {
             match update {
                 Some(update) => self.set(key, update),
@@ -1429,6 +2341,78 @@ impl EntityCache {
         }
     }
 
+    /// Return the IDs of the entities of type `entity_type` that were
+    /// created through this cache, in the order in which they were set.
+    /// Entities that already existed in the store, or that have been
+    /// removed again, are not included. Since the cache for a block starts
+    /// out empty, these are the entities created in the block so far
+    pub fn created_entity_ids(
+        &mut self,
+        store: &(impl Store + ?Sized),
+        subgraph_id: &SubgraphDeploymentId,
+        entity_type: &str,
+    ) -> Result<Vec<String>, QueryExecutionError> {
+        self.listed_types
+            .insert((subgraph_id.clone(), entity_type.to_owned()));
+
+        let mut seen = HashSet::new();
+        let updates = &self.updates;
+        let candidates: Vec<EntityKey> = self
+            .set_order
+            .iter()
+            .filter(|key| &key.subgraph_id == subgraph_id && key.entity_type == entity_type)
+            .filter(|key| match updates.get(key) {
+                Some(Some(_)) => true,
+                _ => false,
+            })
+            .filter(|key| seen.insert(*key))
+            .cloned()
+            .collect();
+
+        // Find out which of the entities were in the store before
+        let missing: Vec<&str> = candidates
+            .iter()
+            .filter(|key| !self.current.contains_key(key))
+            .map(|key| key.entity_id.as_str())
+            .collect();
+        if !missing.is_empty() {
+            let mut existing = HashSet::new();
+            let mut ids_for_type = BTreeMap::new();
+            ids_for_type.insert(entity_type, missing.clone());
+            for entity in load_many(store, self.block, subgraph_id, ids_for_type)?
+                .into_iter()
+                .flat_map(|(_, entities)| entities)
+            {
+                let key = EntityKey {
+                    subgraph_id: subgraph_id.clone(),
+                    entity_type: entity_type.to_owned(),
+                    entity_id: entity.id().unwrap(),
+                };
+                existing.insert(key.entity_id.clone());
+                self.current.insert(key, Some(entity));
+            }
+            for id in missing {
+                if !existing.contains(id) {
+                    let key = EntityKey {
+                        subgraph_id: subgraph_id.clone(),
+                        entity_type: entity_type.to_owned(),
+                        entity_id: id.to_owned(),
+                    };
+                    self.current.insert(key, None);
+                }
+            }
+        }
+
+        Ok(candidates
+            .into_iter()
+            .filter(|key| match self.current.get(key) {
+                Some(Some(_)) => false,
+                _ => true,
+            })
+            .map(|key| key.entity_id)
+            .collect())
+    }
+
     /// Return the changes that have been made via `set` and `remove` as
     /// `EntityModification`, making sure to only produce one when a change
     /// to the current state is actually needed.
@@ -1456,7 +2440,7 @@ impl EntityCache {
         }
 
         for (subgraph_id, keys) in missing_by_subgraph {
-            for (entity_type, entities) in store.get_many(subgraph_id, keys)? {
+            for (entity_type, entities) in load_many(store, self.block, subgraph_id, keys)? {
                 for entity in entities {
                     let key = EntityKey {
                         subgraph_id: subgraph_id.clone(),
@@ -1510,3 +2494,33 @@ impl EntityCache {
         })
     }
 }
+
+/// Load the entities with the given ids from `store`, as of `block` or the
+/// latest block if `block` is `None`
+fn load_many(
+    store: &(impl Store + ?Sized),
+    block: Option<BlockNumber>,
+    subgraph_id: &SubgraphDeploymentId,
+    ids_for_type: BTreeMap<&str, Vec<&str>>,
+) -> Result<BTreeMap<String, Vec<Entity>>, QueryExecutionError> {
+    let block = match block {
+        None => return Ok(store.get_many(subgraph_id, ids_for_type)?),
+        Some(block) => block,
+    };
+
+    let mut entities = BTreeMap::new();
+    for (entity_type, ids) in ids_for_type {
+        let query = EntityQuery::new(
+            subgraph_id.clone(),
+            block,
+            EntityCollection::All(vec![entity_type.to_owned()]),
+        )
+        .filter(EntityFilter::In(
+            "id".to_owned(),
+            ids.iter().map(|id| Value::from(*id)).collect(),
+        ))
+        .first(ids.len() as u32);
+        entities.insert(entity_type.to_owned(), store.find(query)?);
+    }
+    Ok(entities)
+}
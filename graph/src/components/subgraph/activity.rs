@@ -0,0 +1,185 @@
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::prelude::SubgraphDeploymentId;
+
+/// Keeps track of when the deployments on this node were last queried and
+/// last had triggers, and which of them are idle. Queries and triggers for
+/// an idle deployment are announced on the wake stream so that the
+/// deployment can be brought back to full speed.
+///
+/// Activity is only tracked in memory; after a restart, deployments count
+/// as active as of the time the node started. Queries usually go to other
+/// nodes than the one that indexes a deployment, so the node that answers
+/// them also records them in the store every once in a while.
+pub struct DeploymentActivity {
+    started_at: SystemTime,
+    queried: RwLock<HashMap<SubgraphDeploymentId, SystemTime>>,
+    /// When queries were last recorded in the store
+    stored: Mutex<HashMap<SubgraphDeploymentId, SystemTime>>,
+    triggered: RwLock<HashMap<SubgraphDeploymentId, SystemTime>>,
+    idle: RwLock<HashSet<SubgraphDeploymentId>>,
+    wake_sink: UnboundedSender<SubgraphDeploymentId>,
+    wake_stream: Mutex<Option<UnboundedReceiver<SubgraphDeploymentId>>>,
+}
+
+impl DeploymentActivity {
+    pub fn new() -> Self {
+        let (wake_sink, wake_stream) = unbounded();
+        DeploymentActivity {
+            started_at: SystemTime::now(),
+            queried: RwLock::new(HashMap::new()),
+            stored: Mutex::new(HashMap::new()),
+            triggered: RwLock::new(HashMap::new()),
+            idle: RwLock::new(HashSet::new()),
+            wake_sink,
+            wake_stream: Mutex::new(Some(wake_stream)),
+        }
+    }
+
+    /// Record that deployment `id` was queried just now
+    pub fn record_query(&self, id: &SubgraphDeploymentId) {
+        self.queried
+            .write()
+            .unwrap()
+            .insert(id.clone(), SystemTime::now());
+        self.wake_if_idle(id);
+    }
+
+    /// Whether a query for deployment `id` should be recorded in the store
+    /// now because that was last done more than `interval` ago. Returns
+    /// `true` at most once per `interval`
+    pub fn store_query(&self, id: &SubgraphDeploymentId, interval: Duration) -> bool {
+        let now = SystemTime::now();
+        let mut stored = self.stored.lock().unwrap();
+        match stored.get(id) {
+            Some(stored_at) if now.duration_since(*stored_at).unwrap_or_default() < interval => {
+                false
+            }
+            _ => {
+                stored.insert(id.clone(), now);
+                true
+            }
+        }
+    }
+
+    /// The deployments that were queried after their queries were last
+    /// recorded in the store, together with the time of their last query
+    pub fn unstored_queries(&self) -> Vec<(SubgraphDeploymentId, SystemTime)> {
+        let stored = self.stored.lock().unwrap();
+        self.queried
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(id, queried_at)| {
+                stored
+                    .get(*id)
+                    .map_or(true, |stored_at| *queried_at > stored_at)
+            })
+            .map(|(id, queried_at)| (id.clone(), *queried_at))
+            .collect()
+    }
+
+    /// Record that deployment `id` processed a block with triggers just now
+    pub fn record_triggers(&self, id: &SubgraphDeploymentId) {
+        self.triggered
+            .write()
+            .unwrap()
+            .insert(id.clone(), SystemTime::now());
+        self.wake_if_idle(id);
+    }
+
+    /// The most recent time at which deployment `id` was queried or had
+    /// triggers
+    pub fn last_active(&self, id: &SubgraphDeploymentId) -> SystemTime {
+        let queried = self.queried.read().unwrap().get(id).cloned();
+        let triggered = self.triggered.read().unwrap().get(id).cloned();
+        queried
+            .into_iter()
+            .chain(triggered)
+            .chain(Some(self.started_at))
+            .max()
+            .unwrap()
+    }
+
+    /// The most recent time at which deployment `id` was queried, or
+    /// `None` if it was not queried since the node started
+    pub fn last_queried(&self, id: &SubgraphDeploymentId) -> Option<SystemTime> {
+        self.queried.read().unwrap().get(id).cloned()
+    }
+
+    pub fn is_idle(&self, id: &SubgraphDeploymentId) -> bool {
+        self.idle.read().unwrap().contains(id)
+    }
+
+    pub fn set_idle(&self, id: &SubgraphDeploymentId, idle: bool) {
+        let mut ids = self.idle.write().unwrap();
+        if idle {
+            ids.insert(id.clone());
+        } else {
+            ids.remove(id);
+        }
+    }
+
+    /// The deployments that were idle and have been queried or had
+    /// triggers since. The stream can only be taken once
+    pub fn take_wake_stream(&self) -> Option<UnboundedReceiver<SubgraphDeploymentId>> {
+        self.wake_stream.lock().unwrap().take()
+    }
+
+    fn wake_if_idle(&self, id: &SubgraphDeploymentId) {
+        if self.is_idle(id) {
+            let _ = self.wake_sink.unbounded_send(id.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+
+    #[test]
+    fn queries_are_stored_at_most_once_per_interval() {
+        let activity = DeploymentActivity::new();
+        let id = SubgraphDeploymentId::new("QmQueried").unwrap();
+        let interval = Duration::from_secs(600);
+
+        assert!(activity.unstored_queries().is_empty());
+        activity.record_query(&id);
+        assert_eq!(
+            vec![id.clone()],
+            activity
+                .unstored_queries()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        );
+
+        assert!(activity.store_query(&id, interval));
+        assert!(activity.unstored_queries().is_empty());
+        assert!(!activity.store_query(&id, interval));
+        assert!(activity.store_query(&id, Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn using_idle_deployments_wakes_them() {
+        let activity = DeploymentActivity::new();
+        let id = SubgraphDeploymentId::new("QmIdle").unwrap();
+        let wake_stream = activity.take_wake_stream().unwrap();
+        assert!(activity.take_wake_stream().is_none());
+
+        // Only deployments that are idle are woken up
+        activity.record_query(&id);
+        activity.set_idle(&id, true);
+        activity.record_query(&id);
+        activity.record_triggers(&id);
+        activity.set_idle(&id, false);
+        activity.record_query(&id);
+        drop(activity);
+
+        assert_eq!(vec![id.clone(), id], wake_stream.collect().wait().unwrap());
+    }
+}
@@ -1,11 +1,13 @@
 use failure::Error;
 use futures::prelude::*;
 use futures::sync::mpsc;
-use std::collections::HashMap;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fmt;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use crate::components::metrics::HistogramVec;
+use crate::components::metrics::{CounterVec, HistogramVec};
 use crate::prelude::*;
 use web3::types::{Log, Transaction};
 
@@ -50,10 +52,39 @@ pub trait RuntimeHost: Send + Sync + Debug + 'static {
     ) -> Box<dyn Future<Item = BlockState, Error = Error> + Send>;
 }
 
+lazy_static! {
+    /// The maximum number of distinct metrics that the mappings of one
+    /// deployment can create with `metrics.increment`
+    static ref MAX_CUSTOM_METRICS: usize = env::var("GRAPH_MAPPING_MAX_CUSTOM_METRICS")
+        .unwrap_or("20".into())
+        .parse::<usize>()
+        .expect("invalid GRAPH_MAPPING_MAX_CUSTOM_METRICS");
+}
+
+/// The longest name a mapping can give a custom metric
+const MAX_CUSTOM_METRIC_NAME_LENGTH: usize = 64;
+
+/// What happened to a value passed to `HostMetrics::increment_custom_metric`
+#[derive(Debug, PartialEq)]
+pub enum CustomMetricUpdate {
+    /// The metric was incremented
+    Counted,
+    /// The metric would have exceeded the limit on custom metrics for the
+    /// deployment and the value was dropped. `first` is true the first time
+    /// this metric was dropped
+    Dropped { first: bool },
+}
+
 pub struct HostMetrics {
     handler_execution_time: Box<HistogramVec>,
     host_fn_execution_time: Box<HistogramVec>,
     pub stopwatch: StopwatchMetrics,
+    /// Counters that mappings increment with `metrics.increment`, one for
+    /// each distinct `name` label
+    custom_metrics: Box<CounterVec>,
+    /// The names of the custom metrics that were created, and of those that
+    /// were dropped because there were too many
+    custom_metric_names: Mutex<(HashSet<String>, HashSet<String>)>,
 }
 
 impl fmt::Debug for HostMetrics {
@@ -87,11 +118,64 @@ impl HostMetrics {
                 vec![0.025, 0.05, 0.2, 2.0, 8.0, 20.0],
             )
             .expect("failed to create `subgraph_host_fn_execution_time` histogram");
+        let custom_metrics = registry
+            .new_counter_vec(
+                format!("subgraph_custom_{}", subgraph_hash),
+                String::from("Counters incremented by the subgraph's mappings"),
+                HashMap::new(),
+                vec![String::from("name")],
+            )
+            .expect("failed to create `subgraph_custom` counter");
         Self {
             handler_execution_time,
             host_fn_execution_time,
             stopwatch,
+            custom_metrics,
+            custom_metric_names: Mutex::new((HashSet::new(), HashSet::new())),
+        }
+    }
+
+    /// Check that a mapping may add `value` to the custom metric `name`.
+    /// Names must be identifiers that Prometheus accepts as label values
+    /// without escaping, and counters can only go up.
+    pub fn check_custom_metric(name: &str, value: f64) -> Result<(), String> {
+        let valid_name = !name.is_empty()
+            && name.len() <= MAX_CUSTOM_METRIC_NAME_LENGTH
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(format!(
+                "invalid metric name `{}`: names must consist of at most {} letters, \
+                 digits and underscores, and must not start with a digit",
+                name, MAX_CUSTOM_METRIC_NAME_LENGTH
+            ));
+        }
+        if !value.is_finite() || value < 0.0 {
+            return Err(format!(
+                "invalid increment {} for metric `{}`: metrics can only be incremented \
+                 by a finite, non-negative amount",
+                value, name
+            ));
+        }
+        Ok(())
+    }
+
+    /// Add `value`, which has passed `check_custom_metric`, to the custom
+    /// metric `name`. Once a deployment has created as many metrics as it
+    /// may, values for new names are dropped so that a mapping can not
+    /// flood the metrics registry.
+    pub fn increment_custom_metric(&self, name: &str, value: f64) -> CustomMetricUpdate {
+        let mut names = self.custom_metric_names.lock().unwrap();
+        let (created, dropped) = &mut *names;
+        if !created.contains(name) {
+            if created.len() >= *MAX_CUSTOM_METRICS {
+                let first = dropped.len() < *MAX_CUSTOM_METRICS && dropped.insert(name.to_owned());
+                return CustomMetricUpdate::Dropped { first };
+            }
+            created.insert(name.to_owned());
         }
+        self.custom_metrics.with_label_values(&[name]).inc_by(value);
+        CustomMetricUpdate::Counted
     }
 
     pub fn observe_handler_execution_time(&self, duration: f64, handler: String) {
@@ -1,3 +1,7 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
 use crate::prelude::*;
 use crate::util::lfu_cache::LfuCache;
 use web3::types::Log;
@@ -9,10 +13,218 @@ pub struct DataSourceTemplateInfo {
     pub params: Vec<String>,
 }
 
-#[derive(Debug, Default)]
+/// A handler that ran while processing a block, as recorded in the
+/// handler journal of the deployment
+#[derive(Clone, Debug)]
+pub struct HandlerExecution {
+    pub block: EthereumBlockPointer,
+    pub data_source: String,
+    pub handler: String,
+    pub duration: Duration,
+    /// The error the handler failed with, `None` if it succeeded
+    pub error: Option<String>,
+}
+
+/// How many data sources a deployment may have
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DataSourceLimit {
+    /// The most data sources, static and dynamic, the deployment may have
+    pub max: usize,
+    /// How many data sources the deployment had when the block state was
+    /// created, not counting `BlockState::created_data_sources`
+    pub existing: usize,
+}
+
+impl DataSourceLimit {
+    /// Whether creating another data source on top of `created` ones would
+    /// exceed the limit
+    pub fn exceeded_by(&self, created: usize) -> bool {
+        self.existing + created >= self.max
+    }
+}
+
+/// An error while processing a block that was caused by an outage of the
+/// Ethereum node, IPFS or the database rather than by the subgraph, so
+/// that processing the block again is likely to succeed
+#[derive(Fail, Debug)]
+#[fail(display = "{}", _0)]
+pub struct TransientError(pub String);
+
+impl TransientError {
+    /// Whether `error` or one of its causes is known to be transient: a
+    /// `TransientError`, a timeout, an I/O error, a conflicting
+    /// transaction, or a failure to get or use a database connection.
+    /// Everything else is assumed to be deterministic, so that a deployment
+    /// with a broken handler is not restarted over and over
+    pub fn is_cause_of(error: &Error) -> bool {
+        use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+        error.iter_chain().any(|cause| {
+            // These wrap errors without making them their cause
+            match cause.downcast_ref::<QueryExecutionError>() {
+                Some(QueryExecutionError::StoreError(e)) => return Self::is_cause_of(e),
+                _ => (),
+            }
+            match cause.downcast_ref::<StoreError>() {
+                Some(StoreError::Unknown(e)) => return Self::is_cause_of(e),
+                Some(StoreError::Aborted(_)) => return true,
+                _ => (),
+            }
+            match cause.downcast_ref::<DieselError>() {
+                Some(DieselError::DatabaseError(DatabaseErrorKind::UnableToSendCommand, _)) => {
+                    return true
+                }
+                _ => (),
+            }
+            cause.downcast_ref::<TransientError>().is_some()
+                || cause.downcast_ref::<tokio::time::Elapsed>().is_some()
+                || cause.downcast_ref::<std::io::Error>().is_some()
+                || cause.downcast_ref::<diesel::r2d2::PoolError>().is_some()
+                || cause.downcast_ref::<diesel::ConnectionError>().is_some()
+        })
+    }
+}
+
+/// A handler error that keeps the entities that the block state had read
+/// from the store before the handler failed. Deployments that continue
+/// after a non-fatal error use them instead of reading them again
+#[derive(Debug)]
+pub struct HandlerFailure {
+    error: Error,
+    cache: Mutex<Option<LfuCache<EntityKey, Option<Entity>>>>,
+}
+
+impl HandlerFailure {
+    pub fn new(error: Error, state: BlockState) -> Self {
+        HandlerFailure {
+            error,
+            cache: Mutex::new(Some(state.entity_cache.discard_changes())),
+        }
+    }
+
+    /// Take the entities read from the store out of the `HandlerFailure`
+    /// that is `error` or one of its causes, if there is one and its
+    /// entities were not taken yet
+    pub fn take_cache(error: &Error) -> Option<LfuCache<EntityKey, Option<Entity>>> {
+        error
+            .iter_chain()
+            .filter_map(|cause| cause.downcast_ref::<HandlerFailure>())
+            .find_map(|failure| failure.cache.lock().unwrap().take())
+    }
+}
+
+impl fmt::Display for HandlerFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Fail for HandlerFailure {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(self.error.as_fail())
+    }
+}
+
+/// The error of a handler, with the execution of the handler that failed.
+/// A failed handler fails its block, which is never written, so the
+/// failure is added to the handler journal from the error
+#[derive(Debug)]
+pub struct HandlerExecutionError {
+    error: Error,
+    /// The position of the execution among the handler executions of its
+    /// block
+    pub index: usize,
+    pub execution: HandlerExecution,
+}
+
+impl HandlerExecutionError {
+    pub fn new(error: Error, index: usize, execution: HandlerExecution) -> Self {
+        HandlerExecutionError {
+            error,
+            index,
+            execution,
+        }
+    }
+
+    /// The failed handler execution of the `HandlerExecutionError` that is
+    /// `error` or one of its causes, if there is one
+    pub fn find(error: &Error) -> Option<&Self> {
+        error
+            .iter_chain()
+            .find_map(|cause| cause.downcast_ref::<HandlerExecutionError>())
+    }
+}
+
+impl fmt::Display for HandlerExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Fail for HandlerExecutionError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(self.error.as_fail())
+    }
+}
+
+/// Limits on the work the handlers of a deployment may do for one block,
+/// so that a single deployment can not starve the others on the node
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BlockBudget {
+    /// The most `store.set` and `store.remove` calls for the block
+    pub max_entity_ops: Option<usize>,
+    /// The most gas, i.e. WASM instructions, all handlers for the block may
+    /// use together. Unlike the time handlers take, the gas they use is the
+    /// same every time the block is processed
+    pub max_gas: Option<u64>,
+}
+
+impl BlockBudget {
+    /// Fail if `entity_ops` operations and `gas` used exceed the budget.
+    /// The error only mentions the limit that was exceeded so that it is
+    /// the same every time the block is processed
+    pub fn check(&self, entity_ops: usize, gas: u64) -> Result<(), Error> {
+        if let Some(max) = self.max_entity_ops {
+            if entity_ops > max {
+                return Err(format_err!(
+                    "Handlers exceeded the budget of {} entity operations per block",
+                    max
+                ));
+            }
+        }
+        if let Some(max) = self.max_gas {
+            if gas > max {
+                return Err(format_err!(
+                    "Handlers exceeded the budget of {} gas per block",
+                    max
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct BlockState {
     pub entity_cache: EntityCache,
     pub created_data_sources: Vec<DataSourceTemplateInfo>,
+    /// The handlers that ran for the block so far, in order
+    pub handler_executions: Vec<HandlerExecution>,
+    /// The limit on data sources for the deployment; no limit if `None`
+    pub data_source_limit: Option<DataSourceLimit>,
+    /// The handler error because of which the changes of the block were
+    /// discarded, for deployments that opt into non-fatal errors
+    pub non_fatal_error: Option<String>,
+    /// The limits on the work handlers may do for the block
+    pub budget: BlockBudget,
+    /// How many entity operations handlers made for the block so far
+    pub entity_ops: usize,
+    /// How much gas handlers used for the block so far
+    pub gas_used: u64,
+    /// The increments of custom metrics that handlers made for the block;
+    /// they are applied once the block has been written, so that blocks
+    /// that fail or are processed again are not counted
+    pub metric_increments: Vec<(String, f64)>,
 }
 
 impl BlockState {
@@ -20,8 +232,62 @@ impl BlockState {
         BlockState {
             entity_cache: EntityCache::with_current(lfu_cache),
             created_data_sources: Vec::new(),
+            handler_executions: Vec::new(),
+            data_source_limit: None,
+            non_fatal_error: None,
+            budget: BlockBudget::default(),
+            entity_ops: 0,
+            gas_used: 0,
+            metric_increments: Vec::new(),
+        }
+    }
+
+    pub fn with_data_source_limit(mut self, limit: Option<DataSourceLimit>) -> Self {
+        self.data_source_limit = limit;
+        self
+    }
+
+    pub fn with_budget(mut self, budget: BlockBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// A state without changes for processing some of the triggers of the
+    /// block concurrently with others. It has the same limits as `self`
+    /// and shares the entities read from the store with it; see
+    /// `EntityCache::fork`
+    pub fn fork(&mut self) -> BlockState {
+        BlockState {
+            entity_cache: self.entity_cache.fork(),
+            data_source_limit: self.data_source_limit,
+            budget: self.budget,
+            ..BlockState::default()
         }
     }
+
+    /// Count `gas` used by a handler against the budget, failing if that
+    /// exceeds it. This is called for every metered block of WASM code, so
+    /// the budget is only checked when there is a limit on gas
+    pub fn count_gas(&mut self, gas: u64) -> Result<(), Error> {
+        self.gas_used += gas;
+        match self.budget.max_gas {
+            Some(max) if self.gas_used > max => self.check_budget(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count an entity operation against the budget, failing if that
+    /// exceeds it
+    pub fn count_entity_op(&mut self) -> Result<(), Error> {
+        self.entity_ops += 1;
+        self.check_budget()
+    }
+
+    /// Fail if the handlers for the block did more work than the budget
+    /// allows
+    pub fn check_budget(&self) -> Result<(), Error> {
+        self.budget.check(self.entity_ops, self.gas_used)
+    }
 }
 
 /// Represents a loaded instance of a subgraph.
@@ -56,3 +322,79 @@ pub trait SubgraphInstance<H: RuntimeHost> {
         metrics: Arc<HostMetrics>,
     ) -> Result<Arc<H>, Error>;
 }
+
+#[test]
+fn handler_failures_keep_the_entities_read_from_the_store() {
+    let key = EntityKey {
+        subgraph_id: SubgraphDeploymentId::new("QmFailure").unwrap(),
+        entity_type: "Thing".to_owned(),
+        entity_id: "1".to_owned(),
+    };
+    let mut cache = LfuCache::new();
+    cache.insert(key.clone(), None);
+    let mut state = BlockState::with_cache(cache);
+    state
+        .entity_cache
+        .set(key.clone(), Entity::from(vec![("id", Value::from("1"))]));
+
+    let failure = HandlerFailure::new(TransientError("outage".to_owned()).into(), state);
+    let e = Error::from(Error::from(failure).context("Failed to process trigger"));
+    assert!(TransientError::is_cause_of(&e));
+
+    // The entities are only taken once, and the change is discarded
+    let mut cache = HandlerFailure::take_cache(&e).unwrap();
+    assert_eq!(Some(&None), cache.get(&key));
+    assert!(HandlerFailure::take_cache(&e).is_none());
+}
+
+#[test]
+fn handler_execution_errors_carry_the_failed_execution() {
+    use web3::types::H256;
+
+    let execution = HandlerExecution {
+        block: EthereumBlockPointer::from((H256::from_low_u64_be(3), 3u64)),
+        data_source: "Token".to_owned(),
+        handler: "handleTransfer".to_owned(),
+        duration: Duration::from_millis(5),
+        error: Some("division by zero".to_owned()),
+    };
+    let failure = HandlerExecutionError::new(format_err!("division by zero"), 2, execution);
+    let e = Error::from(Error::from(failure).context("Failed to process trigger"));
+
+    let failure = HandlerExecutionError::find(&e).unwrap();
+    assert_eq!(2, failure.index);
+    assert_eq!("handleTransfer", failure.execution.handler);
+    assert!(HandlerExecutionError::find(&format_err!("division by zero")).is_none());
+}
+
+#[test]
+fn block_budget() {
+    let unlimited = BlockState::with_cache(LfuCache::new());
+    assert!(unlimited
+        .budget
+        .check(usize::max_value(), u64::max_value())
+        .is_ok());
+
+    let budget = BlockBudget {
+        max_entity_ops: Some(2),
+        max_gas: Some(100),
+    };
+    let mut state = BlockState::with_cache(LfuCache::new()).with_budget(budget);
+    assert!(state.count_entity_op().is_ok());
+    assert!(state.count_entity_op().is_ok());
+    assert!(state.count_gas(100).is_ok());
+
+    let e = state.count_entity_op().unwrap_err();
+    assert!(e.to_string().contains("2 entity operations"));
+    assert!(budget
+        .check(2, 101)
+        .unwrap_err()
+        .to_string()
+        .contains("100 gas"));
+
+    // The error does not depend on how far the budget was exceeded
+    assert_eq!(
+        budget.check(3, 0).unwrap_err().to_string(),
+        budget.check(30, 0).unwrap_err().to_string()
+    );
+}
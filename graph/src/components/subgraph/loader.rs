@@ -1,9 +1,12 @@
 use crate::prelude::*;
 
 pub trait DataSourceLoader {
+    /// Load the dynamic data sources of deployment `id`. With `until`, only
+    /// the ones that were created at or before that block are loaded
     fn load_dynamic_data_sources(
         self: Arc<Self>,
         id: &SubgraphDeploymentId,
+        until: Option<BlockNumber>,
         logger: Logger,
     ) -> Box<dyn Future<Item = Vec<DataSource>, Error = Error> + Send>;
 }
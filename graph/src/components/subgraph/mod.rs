@@ -1,15 +1,27 @@
+mod activity;
 mod host;
 mod instance;
 mod instance_manager;
 mod loader;
 mod provider;
 mod registrar;
+mod simulator;
+mod startup;
 
 pub use crate::prelude::Entity;
 
-pub use self::host::{HostMetrics, RuntimeHost, RuntimeHostBuilder};
-pub use self::instance::{BlockState, DataSourceTemplateInfo, SubgraphInstance};
+pub use self::activity::DeploymentActivity;
+pub use self::host::{CustomMetricUpdate, HostMetrics, RuntimeHost, RuntimeHostBuilder};
+pub use self::instance::{
+    BlockBudget, BlockState, DataSourceLimit, DataSourceTemplateInfo, HandlerExecution,
+    HandlerExecutionError, HandlerFailure, SubgraphInstance, TransientError,
+};
 pub use self::instance_manager::SubgraphInstanceManager;
 pub use self::loader::DataSourceLoader;
 pub use self::provider::SubgraphAssignmentProvider;
-pub use self::registrar::{SubgraphRegistrar, SubgraphVersionSwitchingMode};
+pub use self::registrar::{
+    AssignmentMove, MockSubgraphRegistrar, SubgraphRegistrar, SubgraphValidation,
+    SubgraphVersionSwitchingMode,
+};
+pub use self::simulator::{HandlerSimulation, HandlerSimulator, SimulatedChange, SimulationResult};
+pub use self::startup::{StartupPhase, StartupStatus};
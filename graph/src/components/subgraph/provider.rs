@@ -13,4 +13,36 @@ pub trait SubgraphAssignmentProvider:
         &self,
         id: SubgraphDeploymentId,
     ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>;
+
+    /// Stop processing blocks for the running deployment `id` without
+    /// stopping it, so that it keeps its state and its assignment
+    fn pause(
+        &self,
+        id: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>;
+
+    /// Continue processing blocks for the paused deployment `id` from the
+    /// last block it processed
+    fn resume(
+        &self,
+        id: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>;
+
+    /// Revert the deployment `id` to `block`. A deployment that is running
+    /// is stopped for that and started again afterwards, so that it indexes
+    /// the blocks after `block` again
+    fn rewind(
+        &self,
+        id: SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>;
+
+    /// Migrate the deployment `id` from JSONB storage to relational storage
+    /// after a migration was requested for it. A deployment that is running
+    /// is stopped for that and started again afterwards, so that it does
+    /// not process blocks while it is migrated
+    fn migrate_storage(
+        &self,
+        id: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphAssignmentProviderError> + Send + 'static>;
 }
@@ -1,4 +1,8 @@
 use crate::prelude::*;
+use mockall::automock;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::data::schema::PartitionSpec;
 
 #[derive(Clone, Copy, Debug)]
 pub enum SubgraphVersionSwitchingMode {
@@ -16,19 +20,65 @@ impl SubgraphVersionSwitchingMode {
     }
 }
 
+/// The outcome of checking a subgraph manifest without deploying it
+#[derive(Debug, Default)]
+pub struct SubgraphValidation {
+    /// The network the subgraph indexes, if the manifest could be resolved
+    /// and names one
+    pub network: Option<String>,
+    /// Problems that would make deploying the subgraph fail
+    pub errors: Vec<String>,
+    /// Problems that would not prevent deploying the subgraph
+    pub warnings: Vec<String>,
+}
+
+impl SubgraphValidation {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// A deployment that was moved to another node to balance the cost of
+/// indexing across nodes
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssignmentMove {
+    pub deployment: SubgraphDeploymentId,
+    pub from: NodeId,
+    pub to: NodeId,
+}
+
 /// Common trait for named subgraph providers.
+#[automock]
 pub trait SubgraphRegistrar: Send + Sync + 'static {
     fn create_subgraph(
         &self,
         name: SubgraphName,
     ) -> Box<dyn Future<Item = CreateSubgraphResult, Error = SubgraphRegistrarError> + Send + 'static>;
 
+    /// Deploy `hash` as a new version of the subgraph `name`. Problems with
+    /// the subgraph that do not prevent deploying it are returned as
+    /// warnings. The tables of the entity types in `partitions` are
+    /// partitioned as if their types had the corresponding `@partition`
+    /// directive; that only has an effect if the deployment does not exist
+    /// yet
     fn create_subgraph_version(
         &self,
         name: SubgraphName,
         hash: SubgraphDeploymentId,
         assignment_node_id: NodeId,
-    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+        partitions: BTreeMap<String, PartitionSpec>,
+    ) -> Box<
+        dyn Future<Item = Vec<SubgraphManifestValidationWarning>, Error = SubgraphRegistrarError>
+            + Send
+            + 'static,
+    >;
+
+    /// Resolve and validate the manifest of `hash`, and check that this
+    /// node could index it, without deploying it
+    fn validate_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = SubgraphValidation, Error = SubgraphRegistrarError> + Send + 'static>;
 
     fn remove_subgraph(
         &self,
@@ -40,4 +90,180 @@ pub trait SubgraphRegistrar: Send + Sync + 'static {
         hash: SubgraphDeploymentId,
         node_id: NodeId,
     ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Make `node_id` the standby node for the deployment `hash`, or remove
+    /// the standby if `node_id` is `None`
+    fn assign_standby(
+        &self,
+        hash: SubgraphDeploymentId,
+        node_id: Option<NodeId>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Make the standby node for the deployment `hash` the node that
+    /// indexes it; the previously assigned node becomes the standby
+    fn promote_standby(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Move deployments between `node_ids` so that the cost of indexing
+    /// them is spread evenly, and return the moves that were made. Without
+    /// `node_ids`, the nodes configured with `GRAPH_REBALANCE_NODES` are
+    /// used. Pinned deployments and deployments assigned to other nodes are
+    /// not moved
+    fn rebalance_assignments(
+        &self,
+        node_ids: Option<Vec<NodeId>>,
+    ) -> Box<dyn Future<Item = Vec<AssignmentMove>, Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Assign the deployment `hash` to `node_id` and keep it there when
+    /// assignments are rebalanced, or let it be rebalanced again if
+    /// `node_id` is `None`
+    fn pin_deployment(
+        &self,
+        hash: SubgraphDeploymentId,
+        node_id: Option<NodeId>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Put the deployment `hash` on the blocklist; `reason` is returned to
+    /// anybody who tries to deploy it
+    fn block_deployment(
+        &self,
+        hash: SubgraphDeploymentId,
+        reason: String,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Remove the deployment `hash` from the blocklist
+    fn unblock_deployment(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Stop processing blocks for the deployment `hash`, which must be
+    /// running on this node, until it is resumed. The deployment stays
+    /// assigned to this node
+    fn pause_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Continue processing blocks for the deployment `hash` that was paused
+    /// on this node
+    fn resume_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Wake up the deployment `hash` if it is idle or hibernated, which
+    /// starts it again on the node it is assigned to
+    fn wake_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Revert the entities and the block pointer of the deployment `hash`
+    /// to `block` and index it again from there. The deployment must not
+    /// be assigned to a different node, which would keep writing blocks
+    fn rewind_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Create the deployment `target` as a copy of the synced deployment
+    /// `source` and assign it to `node_id`. The copy gets the entities and
+    /// dynamic data sources of `source` at the latest block that `source`
+    /// had processed when the copy was requested, and continues indexing
+    /// from there; later changes to `source` are not copied. The manifest
+    /// of `target` must have the same schema as that of `source`. If
+    /// `tablespace` is given, the data of `target` is stored in that
+    /// database tablespace
+    fn copy_subgraph(
+        &self,
+        source: SubgraphDeploymentId,
+        target: SubgraphDeploymentId,
+        node_id: NodeId,
+        tablespace: Option<String>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Write the annotation with id `entity_id` of the type `entity_type`,
+    /// which must be declared with `@annotation`, for the deployment
+    /// `hash`, or remove it if `data` is `None`. The values in `data` are
+    /// converted according to the types of the fields in the schema;
+    /// `BigInt`, `BigDecimal` and `Bytes` values are passed as strings
+    fn annotate(
+        &self,
+        hash: SubgraphDeploymentId,
+        entity_type: String,
+        entity_id: String,
+        data: Option<HashMap<String, serde_json::Value>>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Create the indexes that the deployment `hash` is missing, e.g.,
+    /// because it was deployed by an older version of graph-node, and
+    /// report for each of them whether it could be created
+    fn create_missing_indexes(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = Vec<IndexCreation>, Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Report the entity types of the deployment `hash` that have entities
+    /// with an ID longer than `max_length` bytes
+    fn oversized_entity_ids(
+        &self,
+        hash: SubgraphDeploymentId,
+        max_length: usize,
+    ) -> Box<
+        dyn Future<Item = Vec<OversizedEntityIds>, Error = SubgraphRegistrarError> + Send + 'static,
+    >;
+
+    /// Create the attribute indexes of the deployment `hash` again after
+    /// creating them failed when the deployment was started. The indexes
+    /// are created in the background; on success, the failure is no longer
+    /// reported in the deployment's indexing status
+    fn retry_index_creation(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Migrate the deployment `hash` from the legacy JSONB storage to
+    /// relational storage. The migration happens in the background on the
+    /// node that indexes the deployment
+    fn migrate_storage(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Set the disk budget of the deployment `hash` in bytes. Exceeding
+    /// `soft_limit` produces warnings; while the deployment exceeds
+    /// `hard_limit`, it is not indexed any further. Passing `None` for both
+    /// limits removes the quota
+    fn set_deployment_quota(
+        &self,
+        hash: SubgraphDeploymentId,
+        soft_limit: Option<u64>,
+        hard_limit: Option<u64>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Schedule the removal of the deployment `hash` once it has processed
+    /// block `at_block` or once `at_time`, in seconds since the epoch, has
+    /// passed, whichever happens first. Passing `None` for both cancels a
+    /// scheduled removal
+    fn schedule_deployment_removal(
+        &self,
+        hash: SubgraphDeploymentId,
+        at_block: Option<u64>,
+        at_time: Option<u64>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
+
+    /// Add an entry to the audit log of admin operations. `actor` is the
+    /// holder of the admin token used for the operation, `parameters` are
+    /// its parameters as JSON, and `error` is `None` if it succeeded
+    fn record_admin_operation(
+        &self,
+        operation: String,
+        actor: Option<String>,
+        parameters: String,
+        error: Option<String>,
+    ) -> Box<dyn Future<Item = (), Error = SubgraphRegistrarError> + Send + 'static>;
 }
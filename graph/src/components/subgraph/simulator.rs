@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use web3::types::Log;
+
+use crate::prelude::*;
+
+/// A request to run one handler of a deployment for the triggers of a
+/// block, without writing anything to the store
+#[derive(Clone, Debug, Deserialize)]
+pub struct HandlerSimulation {
+    pub deployment: SubgraphDeploymentId,
+    /// The name of the mapping function to run, e.g. `handleTransfer`
+    pub handler: String,
+    /// Only run the handler of this data source; all data sources with a
+    /// handler of that name if `None`
+    pub data_source: Option<String>,
+    pub block_number: u64,
+    /// Only run the handler for triggers of this transaction
+    pub transaction_hash: Option<H256>,
+    /// Run the handler for this event instead of the triggers of the
+    /// block. The event is taken to be emitted in the block, by its
+    /// transaction `transactionHash`, or by `transaction_hash` if it has
+    /// none; that transaction must be in the block
+    #[serde(default)]
+    pub log: Option<Log>,
+}
+
+/// An entity change the simulated handler would have made
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "operation", rename_all = "lowercase")]
+pub enum SimulatedChange {
+    Set {
+        entity: String,
+        id: String,
+        data: Entity,
+    },
+    Remove {
+        entity: String,
+        id: String,
+    },
+}
+
+/// What a simulated handler did
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulationResult {
+    /// How many triggers of the block the handler ran for
+    pub triggers: usize,
+    pub changes: Vec<SimulatedChange>,
+    /// The templates the handler instantiated, with their parameters
+    pub data_sources: Vec<String>,
+    /// The messages the mapping logged
+    pub logs: Vec<String>,
+    /// The error the handler failed with; `changes` is empty if it did
+    pub error: Option<String>,
+}
+
+/// Runs mapping handlers of deployments for debugging
+pub trait HandlerSimulator: Send + Sync + 'static {
+    /// Run the handler for the triggers that `simulation` describes and
+    /// report what it would have done. The handler sees the entities of
+    /// the deployment as they were before the block
+    fn simulate(
+        &self,
+        simulation: HandlerSimulation,
+    ) -> Box<dyn Future<Item = SimulationResult, Error = Error> + Send>;
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use crate::prelude::SubgraphDeploymentId;
+
+/// How far starting a deployment on this node has come
+#[derive(Clone, Debug, PartialEq)]
+pub enum StartupPhase {
+    /// Waiting for other deployments to start first
+    Queued,
+    ResolvingManifest,
+    LoadingDynamicDataSources,
+    StartingBlockStream,
+    Started,
+    /// The deployment was deliberately not started, for example because it
+    /// is on the blocklist
+    Skipped(String),
+    Failed(String),
+}
+
+impl StartupPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StartupPhase::Queued => "queued",
+            StartupPhase::ResolvingManifest => "resolving_manifest",
+            StartupPhase::LoadingDynamicDataSources => "loading_dynamic_data_sources",
+            StartupPhase::StartingBlockStream => "starting_block_stream",
+            StartupPhase::Started => "started",
+            StartupPhase::Skipped(_) => "skipped",
+            StartupPhase::Failed(_) => "failed",
+        }
+    }
+
+    /// Why the deployment was skipped or failed to start
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            StartupPhase::Skipped(reason) | StartupPhase::Failed(reason) => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// Whether starting the deployment is over, no matter how it went
+    pub fn is_settled(&self) -> bool {
+        match self {
+            StartupPhase::Started | StartupPhase::Skipped(_) | StartupPhase::Failed(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Keeps track of starting the deployments assigned to this node. The node
+/// is ready once the assignments have been loaded and every deployment has
+/// either been started, skipped, or failed to start; after that, it stays
+/// ready even while deployments that get assigned later are starting, until
+/// it starts shutting down.
+pub struct StartupStatus {
+    assignments_loaded: AtomicBool,
+    ready: AtomicBool,
+    shutting_down: AtomicBool,
+    deployments: RwLock<BTreeMap<SubgraphDeploymentId, StartupPhase>>,
+}
+
+impl StartupStatus {
+    pub fn new() -> Self {
+        StartupStatus {
+            assignments_loaded: AtomicBool::new(false),
+            ready: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+            deployments: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record that starting deployment `id` has reached `phase`
+    pub fn set_phase(&self, id: &SubgraphDeploymentId, phase: StartupPhase) {
+        self.deployments.write().unwrap().insert(id.clone(), phase);
+    }
+
+    /// Record that starting deployment `id` has reached `phase` if it is
+    /// still being tracked. Starting a deployment can go on after it was
+    /// stopped and removed, and must not bring it back
+    pub fn update_phase(&self, id: &SubgraphDeploymentId, phase: StartupPhase) {
+        if let Some(current) = self.deployments.write().unwrap().get_mut(id) {
+            *current = phase;
+        }
+    }
+
+    /// Forget about deployment `id`, for example because it was unassigned
+    pub fn remove(&self, id: &SubgraphDeploymentId) {
+        self.deployments.write().unwrap().remove(id);
+    }
+
+    /// Record that the deployments assigned to this node when it started
+    /// have all been handed to the assignment provider
+    pub fn set_assignments_loaded(&self) {
+        self.assignments_loaded.store(true, Ordering::SeqCst);
+    }
+
+    /// Record that the node is shutting down; it is not ready from now on,
+    /// and deployments are not started anymore
+    pub fn set_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        if self.is_shutting_down() {
+            return false;
+        }
+        if self.ready.load(Ordering::SeqCst) {
+            return true;
+        }
+        let ready = self.assignments_loaded.load(Ordering::SeqCst)
+            && self
+                .deployments
+                .read()
+                .unwrap()
+                .values()
+                .all(StartupPhase::is_settled);
+        if ready {
+            self.ready.store(true, Ordering::SeqCst);
+        }
+        ready
+    }
+
+    /// The startup phase of every deployment this node knows about
+    pub fn deployments(&self) -> Vec<(SubgraphDeploymentId, StartupPhase)> {
+        self.deployments
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, phase)| (id.clone(), phase.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readiness() {
+        let id = |hash: &str| SubgraphDeploymentId::new(hash).unwrap();
+        let startup = StartupStatus::new();
+
+        // Not ready until the assignments are loaded
+        assert!(!startup.is_ready());
+        startup.set_phase(&id("QmA"), StartupPhase::Queued);
+        startup.set_phase(&id("QmB"), StartupPhase::Queued);
+        startup.set_assignments_loaded();
+        assert!(!startup.is_ready());
+
+        // Ready once every deployment settled, however that went
+        startup.update_phase(&id("QmA"), StartupPhase::Started);
+        assert!(!startup.is_ready());
+        startup.update_phase(&id("QmB"), StartupPhase::Failed("no manifest".to_owned()));
+        assert!(startup.is_ready());
+
+        // Deployments assigned later do not make the node unready
+        startup.set_phase(&id("QmC"), StartupPhase::ResolvingManifest);
+        assert!(startup.is_ready());
+
+        startup.set_shutting_down();
+        assert!(!startup.is_ready());
+    }
+
+    #[test]
+    fn removed_deployments_stay_removed() {
+        let id = SubgraphDeploymentId::new("QmA").unwrap();
+        let startup = StartupStatus::new();
+
+        startup.set_phase(&id, StartupPhase::ResolvingManifest);
+        startup.remove(&id);
+        startup.update_phase(&id, StartupPhase::Started);
+        assert!(startup.deployments().is_empty());
+
+        // Stopped deployments do not hold up readiness
+        startup.set_phase(&id, StartupPhase::Queued);
+        startup.set_assignments_loaded();
+        assert!(!startup.is_ready());
+        startup.remove(&id);
+        assert!(startup.is_ready());
+    }
+}
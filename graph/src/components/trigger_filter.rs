@@ -0,0 +1,17 @@
+/// Describes which triggers of a chain a subgraph is interested in,
+/// independently of how the blocks of the chain are obtained. Block
+/// streams use the filter to decide which blocks matter.
+pub trait TriggerFilter: Clone + Default + Send + Sync + 'static {
+    /// The triggers that the filter selects
+    type Trigger;
+
+    /// Return `true` if the subgraph is interested in `trigger`
+    fn matches(&self, trigger: &Self::Trigger) -> bool;
+
+    /// Extend this filter so that it also matches everything that `other`
+    /// matches
+    fn extend(&mut self, other: Self);
+
+    /// An empty filter is one that never matches.
+    fn is_empty(&self) -> bool;
+}
@@ -3,7 +3,7 @@ use graphql_parser::{query as q, Pos};
 use hex::FromHexError;
 use num_bigint;
 use serde::ser::*;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
 use std::string::FromUtf8Error;
@@ -54,7 +54,15 @@ pub enum QueryExecutionError {
     ScalarCoercionError(Pos, String, q::Value, String),
     TooComplex(u64, u64), // (complexity, max_complexity)
     TooDeep(u8),          // max_depth
+    FilterTooDeep(u8),    // max_depth
     UndefinedFragment(String),
+    DuplicateVariableDefinition(Pos, String),
+    DuplicateFragmentDefinition(Pos, String),
+    UnusedFragment(Pos, String),
+    BlockNotAvailable(SubgraphDeploymentId, u64, u64), // (subgraph, block, earliest block)
+    Unauthorized(String),
+    InvalidCursor(String),
+    TooManyEntitiesToAggregate(String, u32), // (entity type, max entities)
     // Using slow and prefetch query resolution yield different results
     IncorrectPrefetchResult { slow: q::Value, prefetch: q::Value },
 }
@@ -196,7 +204,23 @@ impl fmt::Display for QueryExecutionError {
                            return smaller collections", complexity, max_complexity)
             }
             TooDeep(max_depth) => write!(f, "query has a depth that exceeds the limit of `{}`", max_depth),
+            FilterTooDeep(max_depth) => write!(f, "filter has a depth that exceeds the limit of `{}`", max_depth),
             UndefinedFragment(frag_name) => write!(f, "fragment `{}` is not defined", frag_name),
+            DuplicateVariableDefinition(_, name) => write!(f, "variable `${}` is defined more than once", name),
+            DuplicateFragmentDefinition(_, name) => write!(f, "fragment `{}` is defined more than once", name),
+            UnusedFragment(_, name) => write!(f, "fragment `{}` is never used", name),
+            BlockNotAvailable(subgraph, block, earliest) => {
+                write!(f, "subgraph {} only has data starting at block number {} \
+                           and data for block number {} is therefore not available",
+                           subgraph, earliest, block)
+            }
+            Unauthorized(reason) => write!(f, "unauthorized: {}", reason),
+            InvalidCursor(cursor) => write!(f, "Invalid cursor `{}`", cursor),
+            TooManyEntitiesToAggregate(entity_type, max_entities) => {
+                write!(f, "more than {} `{}` entities would have to be aggregated; \
+                           use `where` to aggregate fewer entities",
+                           max_entities, entity_type)
+            }
             IncorrectPrefetchResult{ .. } => write!(f, "Running query with prefetch \
                            and slow query resolution yielded different results. \
                            This is a bug. Please open an issue at \
@@ -331,7 +355,7 @@ impl Serialize for QueryError {
                     .unwrap();
 
                 // Generate the list of locations
-                let mut location = HashMap::new();
+                let mut location = BTreeMap::new();
                 location.insert("line", line);
                 location.insert("column", column);
                 map.serialize_entry("locations", &vec![location])?;
@@ -350,8 +374,11 @@ impl Serialize for QueryError {
             | QueryError::ExecutionError(AmbiguousDerivedFromResult(pos, _, _, _))
             | QueryError::ExecutionError(EnumCoercionError(pos, _, _, _, _))
             | QueryError::ExecutionError(ScalarCoercionError(pos, _, _, _))
-            | QueryError::ExecutionError(UnknownField(pos, _, _)) => {
-                let mut location = HashMap::new();
+            | QueryError::ExecutionError(UnknownField(pos, _, _))
+            | QueryError::ExecutionError(DuplicateVariableDefinition(pos, _))
+            | QueryError::ExecutionError(DuplicateFragmentDefinition(pos, _))
+            | QueryError::ExecutionError(UnusedFragment(pos, _)) => {
+                let mut location = BTreeMap::new();
                 location.insert("line", pos.line);
                 location.insert("column", pos.column);
                 map.serialize_entry("locations", &vec![location])?;
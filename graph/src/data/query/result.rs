@@ -21,11 +21,22 @@ pub struct QueryResult {
     pub data: Option<q::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub errors: Option<Vec<QueryError>>,
+    /// Information about the response that is not part of the data, like
+    /// whether the deployment that answered the query has failed
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_data"
+    )]
+    pub extensions: Option<q::Value>,
 }
 
 impl QueryResult {
     pub fn new(data: Option<q::Value>) -> Self {
-        QueryResult { data, errors: None }
+        QueryResult {
+            data,
+            errors: None,
+            extensions: None,
+        }
     }
 }
 
@@ -42,6 +53,7 @@ impl From<Vec<QueryExecutionError>> for QueryResult {
         QueryResult {
             data: None,
             errors: Some(e.into_iter().map(QueryError::from).collect()),
+            extensions: None,
         }
     }
 }
@@ -11,6 +11,7 @@ use graphql_parser::{
     schema::{self, InterfaceType, ObjectType, TypeDefinition, *},
     Pos,
 };
+use inflector::Inflector;
 use serde::{Deserialize, Serialize};
 
 use std::collections::{BTreeMap, HashMap, HashSet};
@@ -22,6 +23,49 @@ use std::sync::Arc;
 
 pub const SCHEMA_TYPE_NAME: &str = "_Schema_";
 
+/// The directive that marks entity types whose entities are written through
+/// the `subgraph_annotate` JSON-RPC method instead of by mappings
+pub const ANNOTATION_DIRECTIVE: &str = "annotation";
+
+/// The directive with which entity types ask for their table to be
+/// partitioned
+pub const PARTITION_DIRECTIVE: &str = "partition";
+
+/// How an operator asks for the table of an entity type to be partitioned
+/// when deploying a subgraph. Deploying with a `PartitionSpec` for a type
+/// has the same effect as a `@partition` directive on the type
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "by", rename_all = "lowercase")]
+pub enum PartitionSpec {
+    /// `@partition(by: "block", size: <size>)`
+    Block { size: i64 },
+    /// `@partition(by: "hash", field: "id", count: <count>)`
+    Hash { field: String, count: i64 },
+}
+
+impl PartitionSpec {
+    fn to_directive(&self) -> schema::Directive {
+        let arg = |name: &str, value: schema::Value| (schema::Name::from(name), value);
+        let int = |n: i64| schema::Value::Int(schema::Number::from(n as i32));
+        let arguments = match self {
+            PartitionSpec::Block { size } => vec![
+                arg("by", schema::Value::String("block".to_owned())),
+                arg("size", int(*size)),
+            ],
+            PartitionSpec::Hash { field, count } => vec![
+                arg("by", schema::Value::String("hash".to_owned())),
+                arg("field", schema::Value::String(field.clone())),
+                arg("count", int(*count)),
+            ],
+        };
+        schema::Directive {
+            name: PARTITION_DIRECTIVE.to_owned(),
+            position: Pos::default(),
+            arguments,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Strings(Vec<String>);
 
@@ -57,7 +101,7 @@ pub enum SchemaValidationError {
     ImportedSubgraphNameInvalid(String),
     #[fail(display = "Imported subgraph id `{}` is invalid", _0)]
     ImportedSubgraphIdInvalid(String),
-    #[fail(display = "The _Schema_ type only allows @import directives")]
+    #[fail(display = "The _Schema_ type only allows @import and @fulltext directives")]
     InvalidSchemaTypeDirectives,
     #[fail(display = r#"@import directives must have the form \
 @import(types: ["A", {{ name: "B", as: "C"}}], from: {{ name: "org/subgraph"}}) or \
@@ -73,6 +117,48 @@ pub enum SchemaValidationError {
         _0, _1
     )]
     ImportedTypeUndefined(String, String), // (type_name, schema)
+    #[fail(display = "Member `{}` of union `{}` is not an entity type", _1, _0)]
+    UnionMemberInvalid(String, String), // (union, member)
+    #[fail(
+        display = "Field `{}` in type `{}` has union type `{}`; unions can only be queried \
+                   from the root `Query` type",
+        _1, _0, _2
+    )]
+    UnionFieldUnsupported(String, String, String), // (type, field, union)
+    #[fail(display = "@fulltext directive `{}` is invalid: {}", _0, _1)]
+    FulltextDirectiveInvalid(String, String), // (name, reason)
+    #[fail(display = "Cannot partition `{}`: it is not an entity type", _0)]
+    PartitionedTypeUndefined(String),
+    #[fail(display = "Cannot partition `{}`: {} is out of range", _0, _1)]
+    PartitionSpecInvalid(String, i64), // (type, size or count)
+}
+
+/// Entity types with more fields than this are reported as too wide by
+/// the schema linter
+const MAX_ENTITY_FIELDS: usize = 50;
+
+/// Patterns in a schema that are valid, but make a subgraph slow or wasteful
+/// to index or query
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum SchemaLintWarning {
+    #[fail(
+        display = "Field `{}` in type `{}` stores a list of `{}` references that can grow \
+                   without bound; consider deriving it with @derivedFrom instead",
+        _1, _0, _2
+    )]
+    UnboundedEntityList(String, String, String), // (type, field, referenced type)
+    #[fail(
+        display = "Type `{}` has {} fields, more than the recommended {}; wide entities \
+                   are expensive to write since every change stores all fields",
+        _0, _1, _2
+    )]
+    WideEntity(String, usize, usize), // (type, fields, max fields)
+    #[fail(
+        display = "Field `{}` in type `{}` looks like it holds an address but has type `{}`; \
+                   addresses take half the space when stored as `Bytes`",
+        _1, _0, _2
+    )]
+    AddressAsString(String, String, String), // (type, field, field type)
 }
 
 #[derive(Debug, Fail, PartialEq, Eq, Clone)]
@@ -157,6 +243,253 @@ impl SchemaReference {
     }
 }
 
+/// The text search configuration that is used to turn the fields of a
+/// fulltext search into a `tsvector`. Postgres applies the stemming and
+/// stop words of the configuration's language; `Simple` does neither.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FulltextLanguage {
+    Simple,
+    Danish,
+    Dutch,
+    English,
+    Finnish,
+    French,
+    German,
+    Hungarian,
+    Italian,
+    Norwegian,
+    Portuguese,
+    Romanian,
+    Russian,
+    Spanish,
+    Swedish,
+    Turkish,
+}
+
+impl Default for FulltextLanguage {
+    fn default() -> Self {
+        FulltextLanguage::English
+    }
+}
+
+impl TryFrom<&str> for FulltextLanguage {
+    type Error = String;
+
+    /// Languages are given by their ISO 639-1 code in the schema
+    fn try_from(language: &str) -> Result<Self, Self::Error> {
+        use FulltextLanguage::*;
+
+        match language {
+            "simple" => Ok(Simple),
+            "da" => Ok(Danish),
+            "nl" => Ok(Dutch),
+            "en" => Ok(English),
+            "fi" => Ok(Finnish),
+            "fr" => Ok(French),
+            "de" => Ok(German),
+            "hu" => Ok(Hungarian),
+            "it" => Ok(Italian),
+            "no" => Ok(Norwegian),
+            "pt" => Ok(Portuguese),
+            "ro" => Ok(Romanian),
+            "ru" => Ok(Russian),
+            "es" => Ok(Spanish),
+            "sv" => Ok(Swedish),
+            "tr" => Ok(Turkish),
+            invalid => Err(format!(
+                "Provided language for fulltext search is invalid: {}",
+                invalid
+            )),
+        }
+    }
+}
+
+impl FulltextLanguage {
+    /// The name of the Postgres text search configuration for this language
+    pub fn as_sql(&self) -> &'static str {
+        use FulltextLanguage::*;
+
+        match self {
+            Simple => "simple",
+            Danish => "danish",
+            Dutch => "dutch",
+            English => "english",
+            Finnish => "finnish",
+            French => "french",
+            German => "german",
+            Hungarian => "hungarian",
+            Italian => "italian",
+            Norwegian => "norwegian",
+            Portuguese => "portuguese",
+            Romanian => "romanian",
+            Russian => "russian",
+            Spanish => "spanish",
+            Swedish => "swedish",
+            Turkish => "turkish",
+        }
+    }
+}
+
+/// How the matches of a fulltext search are ranked
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FulltextAlgorithm {
+    /// Rank by how often the search terms occur (`ts_rank`)
+    Rank,
+    /// Rank by how often and how close to each other the search terms
+    /// occur (`ts_rank_cd`)
+    ProximityRank,
+}
+
+impl Default for FulltextAlgorithm {
+    fn default() -> Self {
+        FulltextAlgorithm::Rank
+    }
+}
+
+impl TryFrom<&str> for FulltextAlgorithm {
+    type Error = String;
+
+    fn try_from(algorithm: &str) -> Result<Self, Self::Error> {
+        match algorithm {
+            "rank" => Ok(FulltextAlgorithm::Rank),
+            "proximityRank" => Ok(FulltextAlgorithm::ProximityRank),
+            invalid => Err(format!(
+                "The provided fulltext search algorithm {} is invalid. It must be one of: rank, proximityRank",
+                invalid,
+            )),
+        }
+    }
+}
+
+impl FulltextAlgorithm {
+    /// The name of the Postgres function that ranks matches
+    pub fn rank_function(&self) -> &'static str {
+        match self {
+            FulltextAlgorithm::Rank => "ts_rank",
+            FulltextAlgorithm::ProximityRank => "ts_rank_cd",
+        }
+    }
+}
+
+/// The directive on `_Schema_` that defines a fulltext search
+pub const FULLTEXT_DIRECTIVE: &str = "fulltext";
+
+/// A fulltext search over `String` fields of an entity type. Searches are
+/// defined with directives on `_Schema_` of the form
+///
+/// ```graphql
+/// type _Schema_
+///   @fulltext(
+///     name: "bandSearch"
+///     language: en
+///     algorithm: rank
+///     include: [{ entity: "Band", fields: [{ name: "name" }, { name: "bio" }] }]
+///   )
+/// ```
+///
+/// where `language` and `algorithm` are optional. The search is queried
+/// through a field of the `Query` type with the given `name`
+#[derive(Clone, Debug, PartialEq)]
+pub struct FulltextDefinition {
+    pub name: String,
+    pub language: FulltextLanguage,
+    pub algorithm: FulltextAlgorithm,
+    pub entity: String,
+    pub fields: Vec<String>,
+}
+
+impl FulltextDefinition {
+    fn from_directive(directive: &Directive) -> Result<Self, SchemaValidationError> {
+        let arg = |name: &str| {
+            directive
+                .arguments
+                .iter()
+                .find(|(arg, _)| arg == name)
+                .map(|(_, value)| value)
+        };
+
+        let name = match arg("name") {
+            Some(Value::String(name)) => name.clone(),
+            _ => {
+                return Err(SchemaValidationError::FulltextDirectiveInvalid(
+                    String::new(),
+                    "`name` must be a string".to_owned(),
+                ))
+            }
+        };
+        let invalid =
+            |reason: String| SchemaValidationError::FulltextDirectiveInvalid(name.clone(), reason);
+
+        let language = match arg("language") {
+            None => FulltextLanguage::default(),
+            Some(Value::Enum(language)) | Some(Value::String(language)) => {
+                FulltextLanguage::try_from(language.as_str()).map_err(invalid)?
+            }
+            Some(_) => return Err(invalid("`language` must be a language code".to_owned())),
+        };
+        let algorithm = match arg("algorithm") {
+            None => FulltextAlgorithm::default(),
+            Some(Value::Enum(algorithm)) | Some(Value::String(algorithm)) => {
+                FulltextAlgorithm::try_from(algorithm.as_str()).map_err(invalid)?
+            }
+            Some(_) => {
+                return Err(invalid(
+                    "`algorithm` must be `rank` or `proximityRank`".to_owned(),
+                ))
+            }
+        };
+
+        let include = match arg("include") {
+            Some(Value::List(include)) => match include.as_slice() {
+                [Value::Object(include)] => include,
+                _ => {
+                    return Err(invalid(
+                        "`include` must list exactly one entity type".to_owned(),
+                    ))
+                }
+            },
+            _ => {
+                return Err(invalid(
+                    "`include` must list exactly one entity type".to_owned(),
+                ))
+            }
+        };
+        let entity = match include.get("entity") {
+            Some(Value::String(entity)) => entity.clone(),
+            _ => {
+                return Err(invalid(
+                    "`entity` must be the name of an entity type".to_owned(),
+                ))
+            }
+        };
+        let fields = match include.get("fields") {
+            Some(Value::List(fields)) if !fields.is_empty() => fields
+                .iter()
+                .map(|field| match field {
+                    Value::Object(field) => match field.get("name") {
+                        Some(Value::String(name)) => Ok(name.clone()),
+                        _ => Err(invalid(
+                            "fields must have the form `{ name: \"field\" }`".to_owned(),
+                        )),
+                    },
+                    _ => Err(invalid(
+                        "fields must have the form `{ name: \"field\" }`".to_owned(),
+                    )),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err(invalid("`fields` must list at least one field".to_owned())),
+        };
+
+        Ok(FulltextDefinition {
+            name,
+            language,
+            algorithm,
+            entity,
+            fields,
+        })
+    }
+}
+
 /// A validated and preprocessed GraphQL schema for a subgraph.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Schema {
@@ -166,8 +499,14 @@ pub struct Schema {
     // Maps type name to implemented interfaces.
     pub interfaces_for_type: BTreeMap<Name, Vec<InterfaceType>>,
 
-    // Maps an interface name to the list of entities that implement it.
+    // Maps an interface name to the list of entities that implement it,
+    // and a union name to the list of its members.
     pub types_for_interface: BTreeMap<Name, Vec<ObjectType>>,
+
+    // Maps a union name to an interface with the fields that all members
+    // of the union have in common. Queries for a union are executed as if
+    // they were for that interface.
+    pub union_interfaces: BTreeMap<Name, InterfaceType>,
 }
 
 impl Schema {
@@ -180,6 +519,7 @@ impl Schema {
             document,
             interfaces_for_type: BTreeMap::new(),
             types_for_interface: BTreeMap::new(),
+            union_interfaces: BTreeMap::new(),
         }
     }
 
@@ -280,22 +620,110 @@ impl Schema {
         return Ok((interfaces_for_type, types_for_interface));
     }
 
+    /// Add the members of each union in `document` to `types_for_interface`
+    /// and return the interface with the shared fields of each union
+    pub fn collect_unions(
+        document: &schema::Document,
+        types_for_interface: &mut BTreeMap<Name, Vec<ObjectType>>,
+    ) -> Result<BTreeMap<Name, InterfaceType>, SchemaValidationError> {
+        let mut union_interfaces = BTreeMap::new();
+        for definition in &document.definitions {
+            if let schema::Definition::TypeDefinition(TypeDefinition::Union(union_type)) =
+                definition
+            {
+                let members = union_type
+                    .types
+                    .iter()
+                    .map(|member| {
+                        document
+                            .get_object_type_definitions()
+                            .into_iter()
+                            .find(|object_type| {
+                                &object_type.name == member && object_type.name != SCHEMA_TYPE_NAME
+                            })
+                            .cloned()
+                            .ok_or_else(|| {
+                                SchemaValidationError::UnionMemberInvalid(
+                                    union_type.name.clone(),
+                                    member.clone(),
+                                )
+                            })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                // A field is shared if every member has a field with the
+                // same name and type
+                let fields = members
+                    .first()
+                    .map(|first| {
+                        first
+                            .fields
+                            .iter()
+                            .filter(|field| {
+                                members.iter().all(|member| {
+                                    member.fields.iter().any(|other| {
+                                        other.name == field.name
+                                            && other.field_type == field.field_type
+                                    })
+                                })
+                            })
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                union_interfaces.insert(
+                    union_type.name.clone(),
+                    InterfaceType {
+                        position: union_type.position,
+                        description: union_type.description.clone(),
+                        name: union_type.name.clone(),
+                        directives: vec![],
+                        fields,
+                    },
+                );
+                types_for_interface.insert(union_type.name.clone(), members);
+            }
+        }
+        Ok(union_interfaces)
+    }
+
     pub fn parse(raw: &str, id: SubgraphDeploymentId) -> Result<Self, Error> {
         let document = graphql_parser::parse_schema(&raw)?;
 
-        let (interfaces_for_type, types_for_interface) = Self::collect_interfaces(&document)?;
+        let (interfaces_for_type, mut types_for_interface) = Self::collect_interfaces(&document)?;
+        let union_interfaces = Self::collect_unions(&document, &mut types_for_interface)?;
 
         let mut schema = Schema {
             id: id.clone(),
             document,
             interfaces_for_type,
             types_for_interface,
+            union_interfaces,
         };
         schema.add_subgraph_id_directives(id);
 
         Ok(schema)
     }
 
+    /// The fulltext searches that the `@fulltext` directives on the
+    /// `_Schema_` type of `document` define. Invalid directives are
+    /// skipped here; validating the schema reports them
+    pub fn fulltext_definitions(document: &schema::Document) -> Vec<FulltextDefinition> {
+        document
+            .get_object_type_definitions()
+            .into_iter()
+            .find(|object_type| object_type.name.eq(SCHEMA_TYPE_NAME))
+            .map_or(vec![], |object_type| {
+                object_type
+                    .directives
+                    .iter()
+                    .filter(|directive| directive.name.eq(FULLTEXT_DIRECTIVE))
+                    .filter_map(|directive| FulltextDefinition::from_directive(directive).ok())
+                    .collect()
+            })
+    }
+
     pub fn imported_types(&self) -> HashMap<ImportedType, SchemaReference> {
         self.subgraph_schema_object_type()
             .map_or(HashMap::new(), |object| {
@@ -396,7 +824,8 @@ impl Schema {
         }
     }
 
-    /// Returned map has one an entry for each interface in the schema.
+    /// Returned map has one an entry for each interface and each union in
+    /// the schema.
     pub fn types_for_interface(&self) -> &BTreeMap<Name, Vec<ObjectType>> {
         &self.types_for_interface
     }
@@ -406,6 +835,75 @@ impl Schema {
         self.interfaces_for_type.get(type_name)
     }
 
+    /// Whether `entity_type` is declared with the `@annotation` directive
+    pub fn is_annotation_type(&self, entity_type: &str) -> bool {
+        self.document
+            .get_object_type_definitions()
+            .into_iter()
+            .find(|object_type| object_type.name == entity_type)
+            .map_or(false, |object_type| {
+                object_type
+                    .find_directive(ANNOTATION_DIRECTIVE.to_owned())
+                    .is_some()
+            })
+    }
+
+    /// The names of all entity types declared with the `@annotation`
+    /// directive
+    pub fn annotation_types(&self) -> HashSet<String> {
+        self.document
+            .get_object_type_definitions()
+            .into_iter()
+            .filter(|object_type| {
+                object_type
+                    .find_directive(ANNOTATION_DIRECTIVE.to_owned())
+                    .is_some()
+            })
+            .map(|object_type| object_type.name.clone())
+            .collect()
+    }
+
+    /// Put a `@partition` directive that corresponds to its spec in
+    /// `partitions` on each entity type, replacing the one the type might
+    /// already have
+    pub fn set_partitioning(
+        &mut self,
+        partitions: &BTreeMap<String, PartitionSpec>,
+    ) -> Result<(), SchemaValidationError> {
+        for (type_name, spec) in partitions {
+            let n = match spec {
+                PartitionSpec::Block { size } => *size,
+                PartitionSpec::Hash { count, .. } => *count,
+            };
+            if n <= 0 || n > std::i32::MAX as i64 {
+                return Err(SchemaValidationError::PartitionSpecInvalid(
+                    type_name.clone(),
+                    n,
+                ));
+            }
+            let object_type = self
+                .document
+                .definitions
+                .iter_mut()
+                .filter_map(|definition| match definition {
+                    schema::Definition::TypeDefinition(TypeDefinition::Object(object_type)) => {
+                        Some(object_type)
+                    }
+                    _ => None,
+                })
+                .find(|object_type| &object_type.name == type_name)
+                .filter(|object_type| object_type.find_directive("entity".to_owned()).is_some())
+                .ok_or_else(|| {
+                    SchemaValidationError::PartitionedTypeUndefined(type_name.clone())
+                })?;
+            object_type
+                .directives
+                .retain(|directive| directive.name != PARTITION_DIRECTIVE);
+            object_type.directives.push(spec.to_directive());
+        }
+        Ok(())
+    }
+
     // Adds a @subgraphId(id: ...) directive to object/interface/enum types in the schema.
     pub fn add_subgraph_id_directives(&mut self, id: SubgraphDeploymentId) {
         for definition in self.document.definitions.iter_mut() {
@@ -452,6 +950,28 @@ impl Schema {
         }
     }
 
+    /// Render the schema as SDL the way clients see it, i.e., without the
+    /// `@subgraphId` directives that we add for internal bookkeeping
+    pub fn to_sdl(&self) -> String {
+        let mut document = self.document.clone();
+        for definition in document.definitions.iter_mut() {
+            if let schema::Definition::TypeDefinition(ref mut type_definition) = definition {
+                let directives = match type_definition {
+                    TypeDefinition::Object(object_type) => &mut object_type.directives,
+                    TypeDefinition::Interface(interface_type) => &mut interface_type.directives,
+                    TypeDefinition::Enum(enum_type) => &mut enum_type.directives,
+                    TypeDefinition::Scalar(scalar_type) => &mut scalar_type.directives,
+                    TypeDefinition::InputObject(input_object_type) => {
+                        &mut input_object_type.directives
+                    }
+                    TypeDefinition::Union(union_type) => &mut union_type.directives,
+                };
+                directives.retain(|directive| !directive.name.eq("subgraphId"));
+            }
+        }
+        document.to_string()
+    }
+
     pub fn validate(
         &self,
         schemas: &HashMap<SchemaReference, Arc<Schema>>,
@@ -463,9 +983,10 @@ impl Schema {
             .unwrap_or_else(|err| errors.push(err));
         self.validate_schema_type_has_no_fields()
             .unwrap_or_else(|err| errors.push(err));
-        self.validate_only_import_directives_on_schema_type()
+        self.validate_directives_on_schema_type()
             .unwrap_or_else(|err| errors.push(err));
         errors.append(&mut self.validate_fields());
+        errors.append(&mut self.validate_fulltext_directives());
         errors.append(&mut self.validate_import_directives());
         errors.append(&mut self.validate_imported_types(schemas));
         if errors.is_empty() {
@@ -475,6 +996,58 @@ impl Schema {
         }
     }
 
+    /// Check the schema for patterns that are valid but known to cause
+    /// problems. Unlike validation errors, these do not prevent deploying
+    /// the subgraph
+    pub fn lint(&self) -> Vec<SchemaLintWarning> {
+        fn is_list(field_type: &Type) -> bool {
+            match field_type {
+                Type::NamedType(_) => false,
+                Type::ListType(_) => true,
+                Type::NonNullType(inner) => is_list(inner),
+            }
+        }
+
+        let entity_types = self.document.get_object_and_interface_type_fields();
+        let mut warnings = vec![];
+        for object_type in self
+            .document
+            .get_object_type_definitions()
+            .into_iter()
+            .filter(|object_type| !object_type.name.eq(SCHEMA_TYPE_NAME))
+        {
+            if object_type.fields.len() > MAX_ENTITY_FIELDS {
+                warnings.push(SchemaLintWarning::WideEntity(
+                    object_type.name.to_owned(),
+                    object_type.fields.len(),
+                    MAX_ENTITY_FIELDS,
+                ));
+            }
+
+            for field in &object_type.fields {
+                let base = field.field_type.get_base_type();
+                let derived = field.find_directive(String::from("derivedFrom")).is_some();
+                if is_list(&field.field_type) && entity_types.contains_key(base) && !derived {
+                    warnings.push(SchemaLintWarning::UnboundedEntityList(
+                        object_type.name.to_owned(),
+                        field.name.to_owned(),
+                        base.to_owned(),
+                    ));
+                }
+                if (base == "String" || base == "ID")
+                    && field.name.to_lowercase().ends_with("address")
+                {
+                    warnings.push(SchemaLintWarning::AddressAsString(
+                        object_type.name.to_owned(),
+                        field.name.to_owned(),
+                        base.to_owned(),
+                    ));
+                }
+            }
+        }
+        warnings
+    }
+
     fn validate_schema_type_has_no_fields(&self) -> Result<(), SchemaValidationError> {
         match self
             .subgraph_schema_object_type()
@@ -490,14 +1063,16 @@ impl Schema {
         }
     }
 
-    fn validate_only_import_directives_on_schema_type(&self) -> Result<(), SchemaValidationError> {
+    fn validate_directives_on_schema_type(&self) -> Result<(), SchemaValidationError> {
         match self
             .subgraph_schema_object_type()
             .and_then(|subgraph_schema_type| {
                 if !subgraph_schema_type
                     .directives
                     .iter()
-                    .filter(|directive| !directive.name.eq("import"))
+                    .filter(|directive| {
+                        !directive.name.eq("import") && !directive.name.eq(FULLTEXT_DIRECTIVE)
+                    })
                     .collect::<Vec<&Directive>>()
                     .is_empty()
                 {
@@ -511,6 +1086,115 @@ impl Schema {
         }
     }
 
+    /// Check that every fulltext search has a unique name that the `Query`
+    /// type does not already use, and searches `String` fields of an
+    /// entity type
+    fn validate_fulltext_directives(&self) -> Vec<SchemaValidationError> {
+        fn is_string(field_type: &Type) -> bool {
+            match field_type {
+                Type::NamedType(name) => name == "String",
+                Type::NonNullType(inner) => is_string(inner),
+                Type::ListType(_) => false,
+            }
+        }
+
+        let directives = self
+            .subgraph_schema_object_type()
+            .map_or(vec![], |object_type| {
+                object_type
+                    .directives
+                    .iter()
+                    .filter(|directive| directive.name.eq(FULLTEXT_DIRECTIVE))
+                    .collect()
+            });
+
+        // The fields that the API schema generates on the `Query` type for
+        // each entity type and interface, and the aggregations of each
+        // entity type
+        let query_fields = self
+            .document
+            .get_object_and_interface_type_fields()
+            .keys()
+            .filter(|name| !name.as_str().eq(SCHEMA_TYPE_NAME))
+            .flat_map(|name| vec![name.to_camel_case(), name.to_plural().to_camel_case()])
+            .chain(
+                self.document
+                    .get_object_type_definitions()
+                    .into_iter()
+                    .filter(|object_type| !object_type.name.eq(SCHEMA_TYPE_NAME))
+                    .map(|object_type| format!("{}Aggregates", object_type.name.to_camel_case())),
+            )
+            .collect::<HashSet<_>>();
+
+        let mut names = HashSet::new();
+        let mut errors = vec![];
+        for directive in directives {
+            let definition = match FulltextDefinition::from_directive(directive) {
+                Ok(definition) => definition,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+            let invalid = |reason: String| {
+                SchemaValidationError::FulltextDirectiveInvalid(definition.name.clone(), reason)
+            };
+
+            if !names.insert(definition.name.clone()) {
+                errors.push(invalid(
+                    "another fulltext search has the same name".to_owned(),
+                ));
+            }
+            // Names starting with `_` are reserved for fields like `_meta`
+            if definition.name.starts_with('_') || query_fields.contains(&definition.name) {
+                errors.push(invalid(
+                    "the `Query` type already has a field with that name".to_owned(),
+                ));
+            }
+
+            let object_type = match self
+                .document
+                .get_object_type_definitions()
+                .into_iter()
+                .find(|object_type| {
+                    object_type.name == definition.entity && !object_type.name.eq(SCHEMA_TYPE_NAME)
+                }) {
+                Some(object_type) => object_type,
+                None => {
+                    errors.push(invalid(format!(
+                        "`{}` is not an entity type",
+                        definition.entity
+                    )));
+                    continue;
+                }
+            };
+            if object_type
+                .fields
+                .iter()
+                .any(|field| field.name == definition.name)
+            {
+                errors.push(invalid(format!(
+                    "`{}` already has a field with that name",
+                    definition.entity
+                )));
+            }
+            for name in &definition.fields {
+                match object_type.fields.iter().find(|field| &field.name == name) {
+                    Some(field) if is_string(&field.field_type) => {}
+                    Some(_) => errors.push(invalid(format!(
+                        "field `{}` of `{}` is not a `String`",
+                        name, definition.entity
+                    ))),
+                    None => errors.push(invalid(format!(
+                        "`{}` has no field `{}`",
+                        definition.entity, name
+                    ))),
+                }
+            }
+        }
+        errors
+    }
+
     fn validate_import_type(typ: &Value) -> Result<(), ()> {
         match typ {
             Value::String(_) => Ok(()),
@@ -691,6 +1375,14 @@ impl Schema {
                     if local_types.contains_key(base) {
                         return errors;
                     }
+                    if self.union_interfaces.contains_key(base) {
+                        errors.push(SchemaValidationError::UnionFieldUnsupported(
+                            type_name.to_string(),
+                            field.name.to_string(),
+                            base.to_string(),
+                        ));
+                        return errors;
+                    }
                     if imported_types
                         .iter()
                         .any(|(imported_type, _)| match imported_type {
@@ -949,6 +1641,71 @@ fn invalid_interface_implementation() {
     );
 }
 
+#[test]
+fn union_shared_fields() {
+    let schema = "
+        type Swap @entity { id: ID!, timestamp: Int!, amountIn: BigInt! }
+        type Mint @entity { id: ID!, timestamp: Int!, liquidity: BigInt! }
+        type Burn @entity { id: ID!, timestamp: String! }
+        union Activity = Swap | Mint
+        union Event = Swap | Mint | Burn
+    ";
+    let schema = Schema::parse(schema, SubgraphDeploymentId::new("dummy").unwrap()).unwrap();
+
+    let field_names = |name: &str| {
+        schema.union_interfaces[name]
+            .fields
+            .iter()
+            .map(|field| field.name.as_str())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(vec!["id", "timestamp"], field_names("Activity"));
+    // `timestamp` has a different type in `Burn`
+    assert_eq!(vec!["id"], field_names("Event"));
+
+    let members = schema.types_for_interface["Event"]
+        .iter()
+        .map(|object_type| object_type.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(vec!["Swap", "Mint", "Burn"], members);
+    assert!(schema.interfaces_for_type(&"Swap".to_owned()).is_none());
+}
+
+#[test]
+fn invalid_union_member() {
+    let schema = "
+        type Swap @entity { id: ID! }
+        enum Kind { A, B }
+        union Activity = Swap | Kind
+    ";
+    let res = Schema::parse(schema, SubgraphDeploymentId::new("dummy").unwrap());
+    assert_eq!(
+        res.unwrap_err()
+            .downcast::<SchemaValidationError>()
+            .unwrap(),
+        SchemaValidationError::UnionMemberInvalid("Activity".to_owned(), "Kind".to_owned())
+    );
+}
+
+#[test]
+fn union_field_unsupported() {
+    let schema = "
+        type Swap @entity { id: ID! }
+        type Mint @entity { id: ID! }
+        union Activity = Swap | Mint
+        type User @entity { id: ID!, activities: [Activity!]! }
+    ";
+    let schema = Schema::parse(schema, SubgraphDeploymentId::new("dummy").unwrap()).unwrap();
+    assert_eq!(
+        schema.validate(&HashMap::new()),
+        Err(vec![SchemaValidationError::UnionFieldUnsupported(
+            "User".to_owned(),
+            "activities".to_owned(),
+            "Activity".to_owned()
+        )])
+    );
+}
+
 #[test]
 fn test_derived_from_validation() {
     const OTHER_TYPES: &str = "
@@ -1038,11 +1795,9 @@ type _Schema_ @illegal";
     let document = graphql_parser::parse_schema(ROOT_SCHEMA).expect("Failed to parse root schema");
     let schema = Schema::new(SubgraphDeploymentId::new("id").unwrap(), document);
     assert_eq!(
-        schema
-            .validate_only_import_directives_on_schema_type()
-            .expect_err(
-                "Expected validation to fail due to extra imports defined on the reserved type"
-            ),
+        schema.validate_directives_on_schema_type().expect_err(
+            "Expected validation to fail due to extra imports defined on the reserved type"
+        ),
         SchemaValidationError::InvalidSchemaTypeDirectives
     )
 }
@@ -1164,3 +1919,169 @@ type T @entity { id: ID! }
         _ => (),
     }
 }
+
+#[test]
+fn test_fulltext_options() {
+    assert_eq!(
+        FulltextLanguage::try_from("pt").map(|language| language.as_sql()),
+        Ok("portuguese")
+    );
+    assert_eq!(
+        FulltextLanguage::try_from("simple"),
+        Ok(FulltextLanguage::Simple)
+    );
+    assert!(FulltextLanguage::try_from("english").is_err());
+    assert_eq!(
+        FulltextAlgorithm::try_from("proximityRank").map(|algorithm| algorithm.rank_function()),
+        Ok("ts_rank_cd")
+    );
+    assert!(FulltextAlgorithm::try_from("bm25").is_err());
+}
+
+#[test]
+fn test_to_sdl_omits_subgraph_id_directives() {
+    let schema = Schema::parse(
+        "type Thing @entity { id: ID!, name: String }",
+        SubgraphDeploymentId::new("id").unwrap(),
+    )
+    .unwrap();
+    let sdl = schema.to_sdl();
+    assert!(sdl.contains("type Thing @entity"));
+    assert!(!sdl.contains("subgraphId"));
+}
+
+#[test]
+fn test_lint() {
+    let many_fields = (0..MAX_ENTITY_FIELDS)
+        .map(|i| format!("f{}: Int", i))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let raw = format!(
+        "type Token @entity {{ id: ID!, owners: [Account!]!, contractAddress: String! }}
+         type Account @entity {{ id: ID!, tokens: [Token!]! @derivedFrom(field: \"owners\") }}
+         type Wide @entity {{ id: ID!\n{} }}",
+        many_fields
+    );
+    let schema = Schema::parse(&raw, SubgraphDeploymentId::new("id").unwrap()).unwrap();
+    assert_eq!(
+        schema.lint(),
+        vec![
+            SchemaLintWarning::UnboundedEntityList(
+                "Token".to_owned(),
+                "owners".to_owned(),
+                "Account".to_owned()
+            ),
+            SchemaLintWarning::AddressAsString(
+                "Token".to_owned(),
+                "contractAddress".to_owned(),
+                "String".to_owned()
+            ),
+            SchemaLintWarning::WideEntity(
+                "Wide".to_owned(),
+                MAX_ENTITY_FIELDS + 1,
+                MAX_ENTITY_FIELDS
+            ),
+        ]
+    );
+}
+
+#[test]
+fn annotation_types() {
+    let schema = Schema::parse(
+        "type Token @entity { id: ID!, label: Label }
+         type Label @entity @annotation { id: ID!, text: String! }",
+        SubgraphDeploymentId::new("id").unwrap(),
+    )
+    .unwrap();
+    assert!(schema.is_annotation_type("Label"));
+    assert!(!schema.is_annotation_type("Token"));
+    assert!(!schema.is_annotation_type("Missing"));
+    assert_eq!(
+        vec!["Label".to_owned()].into_iter().collect::<HashSet<_>>(),
+        schema.annotation_types()
+    );
+}
+
+#[test]
+fn test_fulltext_directives() {
+    const SCHEMA: &str = r#"
+type _Schema_
+  @fulltext(
+    name: "bandSearch"
+    language: en
+    algorithm: proximityRank
+    include: [{ entity: "Band", fields: [{ name: "name" }, { name: "bio" }] }]
+  )
+
+type Band @entity {
+  id: ID!
+  name: String!
+  bio: String
+  members: [String!]!
+}"#;
+
+    let schema = Schema::parse(SCHEMA, SubgraphDeploymentId::new("id").unwrap()).unwrap();
+    assert_eq!(Ok(()), schema.validate(&HashMap::new()));
+    assert_eq!(
+        vec![FulltextDefinition {
+            name: "bandSearch".to_owned(),
+            language: FulltextLanguage::English,
+            algorithm: FulltextAlgorithm::ProximityRank,
+            entity: "Band".to_owned(),
+            fields: vec!["name".to_owned(), "bio".to_owned()],
+        }],
+        Schema::fulltext_definitions(&schema.document)
+    );
+
+    let invalid = |include: &str| {
+        let schema = Schema::parse(
+            &SCHEMA.replace(
+                r#"{ entity: "Band", fields: [{ name: "name" }, { name: "bio" }] }"#,
+                include,
+            ),
+            SubgraphDeploymentId::new("id").unwrap(),
+        )
+        .unwrap();
+        schema.validate_fulltext_directives()
+    };
+    assert_eq!(
+        vec![SchemaValidationError::FulltextDirectiveInvalid(
+            "bandSearch".to_owned(),
+            "field `members` of `Band` is not a `String`".to_owned()
+        )],
+        invalid(r#"{ entity: "Band", fields: [{ name: "members" }] }"#)
+    );
+    assert_eq!(
+        vec![SchemaValidationError::FulltextDirectiveInvalid(
+            "bandSearch".to_owned(),
+            "`Album` is not an entity type".to_owned()
+        )],
+        invalid(r#"{ entity: "Album", fields: [{ name: "name" }] }"#)
+    );
+    assert_eq!(
+        vec![SchemaValidationError::FulltextDirectiveInvalid(
+            "bandSearch".to_owned(),
+            "`fields` must list at least one field".to_owned()
+        )],
+        invalid(r#"{ entity: "Band", fields: [] }"#)
+    );
+
+    // The search can not take the name of a generated `Query` field
+    let renamed = |name: &str| {
+        let schema = Schema::parse(
+            &SCHEMA.replace("bandSearch", name),
+            SubgraphDeploymentId::new("id").unwrap(),
+        )
+        .unwrap();
+        schema.validate_fulltext_directives()
+    };
+    for name in &["bands", "band", "bandAggregates", "_meta"] {
+        assert_eq!(
+            vec![SchemaValidationError::FulltextDirectiveInvalid(
+                name.to_string(),
+                "the `Query` type already has a field with that name".to_owned()
+            )],
+            renamed(name)
+        );
+    }
+}
@@ -1,10 +1,12 @@
 use failure::Error;
 use graphql_parser::query;
 use graphql_parser::schema;
+use lazy_static::lazy_static;
 use serde::de;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
+use std::env;
 use std::fmt;
 use std::iter::FromIterator;
 use std::ops::{Deref, DerefMut};
@@ -14,6 +16,119 @@ use crate::data::subgraph::SubgraphDeploymentId;
 use crate::prelude::{format_err, EntityKey, QueryExecutionError};
 use crate::util::lfu_cache::CacheWeight;
 
+lazy_static! {
+    /// Whether values in query results are formatted canonically, so that
+    /// the same data always serializes to the same bytes
+    static ref CANONICAL_RESPONSES: bool = env::var("GRAPH_CANONICAL_RESPONSES")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+
+    /// The longest ID, in bytes, that mappings of deployments created on
+    /// this node may give entities. IDs can be of any length if this is
+    /// not set
+    pub static ref MAX_ENTITY_ID_LENGTH: Option<usize> = env::var("GRAPH_MAX_ENTITY_ID_LENGTH")
+        .ok()
+        .map(|s| s.parse::<usize>().expect("invalid GRAPH_MAX_ENTITY_ID_LENGTH"));
+
+    /// What the IDs that mappings of deployments created on this node give
+    /// entities must look like. IDs can be any string if this is not set
+    static ref ENTITY_ID_FORMAT: Option<EntityIdFormat> = env::var("GRAPH_ENTITY_ID_FORMAT")
+        .ok()
+        .map(|s| s.parse::<EntityIdFormat>().expect("invalid GRAPH_ENTITY_ID_FORMAT"));
+}
+
+/// The formats that operators can require entity IDs to have
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntityIdFormat {
+    /// Hex digits, optionally prefixed with `0x`
+    Hex,
+    /// Any string without control characters
+    Printable,
+}
+
+impl FromStr for EntityIdFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "hex" => Ok(EntityIdFormat::Hex),
+            "printable" => Ok(EntityIdFormat::Printable),
+            _ => Err(format_err!(
+                "unknown entity ID format `{}`, expected `hex` or `printable`",
+                s
+            )),
+        }
+    }
+}
+
+impl EntityIdFormat {
+    pub fn matches(&self, id: &str) -> bool {
+        match self {
+            EntityIdFormat::Hex => {
+                let digits = if id.starts_with("0x") { &id[2..] } else { id };
+                !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit())
+            }
+            EntityIdFormat::Printable => !id.chars().any(char::is_control),
+        }
+    }
+
+    /// The name of the format, as it is given in `GRAPH_ENTITY_ID_FORMAT`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntityIdFormat::Hex => "hex",
+            EntityIdFormat::Printable => "printable",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            EntityIdFormat::Hex => "a hex string",
+            EntityIdFormat::Printable => "printable",
+        }
+    }
+}
+
+/// The limits on the IDs that the mappings of a deployment may give
+/// entities. They are recorded when the deployment is created so that all
+/// nodes that index it agree on whether a handler fails
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EntityIdLimits {
+    /// The longest ID in bytes
+    pub max_length: Option<usize>,
+    pub format: Option<EntityIdFormat>,
+}
+
+impl EntityIdLimits {
+    /// The limits that `GRAPH_MAX_ENTITY_ID_LENGTH` and
+    /// `GRAPH_ENTITY_ID_FORMAT` set for new deployments
+    pub fn configured() -> Self {
+        EntityIdLimits {
+            max_length: *MAX_ENTITY_ID_LENGTH,
+            format: *ENTITY_ID_FORMAT,
+        }
+    }
+
+    /// Check that `id` is no longer than `max_length` bytes and has
+    /// `format`. The error does not contain `id`, which might be very long
+    pub fn check(&self, id: &str) -> Result<(), String> {
+        if let Some(max_length) = self.max_length {
+            if id.len() > max_length {
+                return Err(format!(
+                    "the ID is {} bytes long, but IDs can be at most {} bytes long",
+                    id.len(),
+                    max_length
+                ));
+            }
+        }
+        if let Some(format) = self.format {
+            if !format.matches(id) {
+                return Err(format!("the ID is not {}", format.description()));
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Custom scalars in GraphQL.
 pub mod scalar;
 
@@ -102,6 +217,12 @@ pub const BYTES_SCALAR: &str = "Bytes";
 pub const BIG_INT_SCALAR: &str = "BigInt";
 pub const BIG_DECIMAL_SCALAR: &str = "BigDecimal";
 
+/// The most digits before the decimal point that a Postgres `numeric` can hold
+pub const NUMERIC_MAX_INTEGER_DIGITS: u64 = 131072;
+
+/// The most digits after the decimal point that a Postgres `numeric` can hold
+pub const NUMERIC_MAX_FRACTION_DIGITS: u64 = 16383;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum ValueType {
     Boolean,
@@ -221,6 +342,59 @@ impl Value {
         }
     }
 
+    /// Check that `BigInt` and `BigDecimal` values, including those nested
+    /// in lists, fit into a Postgres `numeric`, and describe the problem if
+    /// they don't
+    pub fn check_numeric_range(&self) -> Result<(), String> {
+        match self {
+            Value::BigInt(n) => {
+                // Only count digits exactly when `n` might be too big; the
+                // number of bits gives us an upper bound
+                if (n.bits() as f64 * std::f64::consts::LOG10_2) as u64 + 1
+                    <= NUMERIC_MAX_INTEGER_DIGITS
+                {
+                    return Ok(());
+                }
+                let digits = n.to_string().trim_start_matches('-').len() as u64;
+                if digits > NUMERIC_MAX_INTEGER_DIGITS {
+                    return Err(format!(
+                        "BigInt has {} digits, but at most {} are supported",
+                        digits, NUMERIC_MAX_INTEGER_DIGITS
+                    ));
+                }
+                Ok(())
+            }
+            Value::BigDecimal(d) => {
+                let (_, scale) = d.as_bigint_and_exponent();
+                let digits = d.digits() as i64;
+                let integer_digits = (digits - scale).max(0) as u64;
+                let fraction_digits = scale.max(0) as u64;
+                if integer_digits > NUMERIC_MAX_INTEGER_DIGITS {
+                    return Err(format!(
+                        "BigDecimal has {} digits before the decimal point, \
+                         but at most {} are supported",
+                        integer_digits, NUMERIC_MAX_INTEGER_DIGITS
+                    ));
+                }
+                if fraction_digits > NUMERIC_MAX_FRACTION_DIGITS {
+                    return Err(format!(
+                        "BigDecimal has {} digits after the decimal point, \
+                         but at most {} are supported",
+                        fraction_digits, NUMERIC_MAX_FRACTION_DIGITS
+                    ));
+                }
+                Ok(())
+            }
+            Value::List(values) => values
+                .iter()
+                .map(|value| value.check_numeric_range())
+                .collect(),
+            Value::String(_) | Value::Int(_) | Value::Bool(_) | Value::Null | Value::Bytes(_) => {
+                Ok(())
+            }
+        }
+    }
+
     pub fn as_int(self) -> Option<i32> {
         if let Value::Int(i) = self {
             Some(i)
@@ -316,11 +490,48 @@ impl fmt::Display for Value {
     }
 }
 
+/// Values of the same type are ordered the way the store orders them;
+/// values of different types can not be compared
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (Value::String(a), Value::String(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::BigDecimal(a), Value::BigDecimal(b)) => a.partial_cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
+            (Value::List(a), Value::List(b)) => a.partial_cmp(b),
+            (Value::Null, Value::Null) => Some(std::cmp::Ordering::Equal),
+            (Value::Bytes(a), Value::Bytes(b)) => a.as_slice().partial_cmp(b.as_slice()),
+            (Value::BigInt(a), Value::BigInt(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+/// Format `d` without trailing zeros after the decimal point, so that
+/// numbers that are equal are formatted the same no matter how many digits
+/// they were stored with
+fn canonical_big_decimal(d: &scalar::BigDecimal) -> String {
+    let s = d.to_string();
+    if !s.contains('.') {
+        return s;
+    }
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" {
+        "0".to_owned()
+    } else {
+        s.to_owned()
+    }
+}
+
 impl From<Value> for query::Value {
     fn from(value: Value) -> Self {
         match value {
             Value::String(s) => query::Value::String(s.to_string()),
             Value::Int(i) => query::Value::Int(query::Number::from(i)),
+            Value::BigDecimal(d) if *CANONICAL_RESPONSES => {
+                query::Value::String(canonical_big_decimal(&d))
+            }
             Value::BigDecimal(d) => query::Value::String(d.to_string()),
             Value::Bool(b) => query::Value::Boolean(b),
             Value::Null => query::Value::Null,
@@ -575,3 +786,68 @@ fn value_bigint() {
     );
     assert_eq!(query::Value::from(from_query), graphql_value);
 }
+
+#[test]
+fn value_numeric_range() {
+    use scalar::BigDecimal;
+
+    let ok = BigDecimal::from_str("123456789.123456789").unwrap();
+    assert_eq!(Ok(()), Value::BigDecimal(ok).check_numeric_range());
+
+    let too_precise = BigDecimal::from_str("1e-16384").unwrap();
+    assert!(Value::BigDecimal(too_precise.clone())
+        .check_numeric_range()
+        .is_err());
+    assert!(
+        Value::List(vec![Value::Null, Value::BigDecimal(too_precise)])
+            .check_numeric_range()
+            .is_err()
+    );
+
+    let too_big = BigDecimal::from_str("1e131072").unwrap();
+    assert!(Value::BigDecimal(too_big).check_numeric_range().is_err());
+    let big_enough = BigDecimal::from_str("1e131071").unwrap();
+    assert_eq!(Ok(()), Value::BigDecimal(big_enough).check_numeric_range());
+
+    let too_big: scalar::BigInt = FromStr::from_str(&format!("-1{}", "0".repeat(131072))).unwrap();
+    assert!(Value::BigInt(too_big).check_numeric_range().is_err());
+    let big_enough: scalar::BigInt = FromStr::from_str(&"9".repeat(131072)).unwrap();
+    assert_eq!(Ok(()), Value::BigInt(big_enough).check_numeric_range());
+}
+
+#[test]
+fn canonical_big_decimals() {
+    use scalar::BigDecimal;
+
+    let canonical = |s: &str| canonical_big_decimal(&BigDecimal::from_str(s).unwrap());
+    assert_eq!("1.5", canonical("1.500"));
+    assert_eq!("15", canonical("15.0"));
+    assert_eq!("1500", canonical("1500"));
+    assert_eq!("0", canonical("-0.00"));
+    assert_eq!("-0.001", canonical("-0.0010"));
+}
+
+#[test]
+fn entity_id_rules() {
+    use EntityIdFormat::{Hex, Printable};
+
+    let check_entity_id = |id, max_length, format| EntityIdLimits { max_length, format }.check(id);
+
+    assert_eq!(Ok(()), check_entity_id("0xab12", Some(6), Some(Hex)));
+    assert_eq!(Ok(()), check_entity_id("ab12", None, Some(Hex)));
+    assert!(check_entity_id("0xab12", Some(5), None).is_err());
+    assert!(check_entity_id("0x", None, Some(Hex)).is_err());
+    assert!(check_entity_id("0x0xab", None, Some(Hex)).is_err());
+    assert!(check_entity_id("0xab-1", None, Some(Hex)).is_err());
+
+    assert_eq!(Ok(()), check_entity_id("tökén 1", None, Some(Printable)));
+    assert!(check_entity_id("token\n1", None, Some(Printable)).is_err());
+    assert_eq!(Ok(()), EntityIdLimits::default().check("token\n1"));
+
+    assert_eq!(Hex, "hex".parse::<EntityIdFormat>().unwrap());
+    assert_eq!(
+        Ok(Printable),
+        Printable.as_str().parse::<EntityIdFormat>().map_err(|_| ())
+    );
+    assert!("base58".parse::<EntityIdFormat>().is_err());
+}
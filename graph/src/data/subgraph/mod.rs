@@ -3,8 +3,9 @@ use failure;
 use failure::{Error, SyncFailure};
 use futures::prelude::*;
 use futures::stream;
+use futures::sync::oneshot;
 use parity_wasm;
-use parity_wasm::elements::Module;
+use parity_wasm::elements::{External, Internal, Module};
 use serde::de;
 use serde::ser;
 use serde_yaml;
@@ -13,8 +14,11 @@ use web3::types::{Address, H256};
 
 use crate::components::link_resolver::LinkResolver;
 use crate::components::store::{Store, StoreError, SubgraphDeploymentStore};
+use crate::data::graphql::ext::DocumentExt;
 use crate::data::query::QueryExecutionError;
-use crate::data::schema::{Schema, SchemaImportError, SchemaValidationError};
+use crate::data::schema::{
+    Schema, SchemaImportError, SchemaLintWarning, SchemaValidationError, SCHEMA_TYPE_NAME,
+};
 use crate::data::subgraph::schema::{
     EthereumBlockHandlerEntity, EthereumCallHandlerEntity, EthereumContractAbiEntity,
     EthereumContractDataSourceEntity, EthereumContractDataSourceTemplateEntity,
@@ -22,12 +26,16 @@ use crate::data::subgraph::schema::{
     EthereumContractMappingEntity, EthereumContractSourceEntity, SUBGRAPHS_ID,
 };
 use crate::prelude::{format_err, Deserialize, Fail, Serialize};
-use crate::util::ethereum::string_to_h256;
+use crate::util::ethereum::{
+    contract_event_with_signature, contract_function_with_signature, event_signature_mismatch,
+    function_signature_mismatch, string_to_h256,
+};
 
+use std::collections::BTreeSet;
 use std::fmt;
 use std::ops::Deref;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 /// Rust representation of the GraphQL schema for a `SubgraphManifest`.
 pub mod schema;
@@ -219,6 +227,425 @@ fn test_subgraph_name_validation() {
     assert!(SubgraphName::new("this-component-is-longer-than-the-length-limit").is_err());
 }
 
+#[test]
+fn supported_versions_are_ordered() {
+    for versions in &[SPEC_VERSIONS, API_VERSIONS] {
+        let parsed: Vec<_> = versions
+            .iter()
+            .map(|version| semver::Version::parse(version).unwrap())
+            .collect();
+        assert!(parsed.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
+
+#[cfg(test)]
+fn test_mapping(api_version: &str, module: Module) -> Mapping {
+    Mapping {
+        kind: "ethereum/events".to_owned(),
+        api_version: api_version.to_owned(),
+        language: "wasm/assemblyscript".to_owned(),
+        entities: vec![],
+        abis: vec![],
+        block_handlers: vec![],
+        call_handlers: vec![],
+        event_handlers: vec![],
+        runtime: Arc::new(module),
+        link: Link {
+            link: "/ipfs/mapping".to_owned(),
+        },
+    }
+}
+
+#[test]
+fn test_mapping_string_constants() {
+    use parity_wasm::builder;
+    use parity_wasm::elements::Instruction;
+
+    fn string_constant(length: u32, text: &str) -> Vec<u8> {
+        let mut bytes = length.to_le_bytes().to_vec();
+        bytes.extend(text.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()));
+        bytes
+    }
+
+    // Older compilers give the length in characters, newer ones in bytes
+    let mut data = string_constant(5, "Token");
+    data.extend(string_constant(10, "Owner"));
+    data.extend(string_constant(3, "Pool")[4..].iter());
+
+    let module = builder::module()
+        .data()
+        .offset(Instruction::I32Const(0))
+        .value(data)
+        .build()
+        .build();
+    let mapping = test_mapping("0.0.4", module);
+
+    assert!(mapping.has_string_constant("Token"));
+    assert!(mapping.has_string_constant("Owner"));
+    assert!(!mapping.has_string_constant("Tok"));
+    assert!(!mapping.has_string_constant("Pool"));
+}
+
+#[test]
+fn test_mapping_exports_function() {
+    use parity_wasm::builder;
+
+    let module = builder::module()
+        .function()
+        .signature()
+        .build()
+        .body()
+        .build()
+        .build()
+        .export()
+        .field("handleTransfer")
+        .internal()
+        .func(0)
+        .build()
+        .export()
+        .field("memory")
+        .internal()
+        .memory(0)
+        .build()
+        .build();
+    let mapping = test_mapping("0.0.4", module);
+
+    assert!(mapping.exports_function("handleTransfer"));
+    assert!(!mapping.exports_function("handleApproval"));
+    assert!(!mapping.exports_function("memory"));
+}
+
+#[test]
+fn test_mapping_api_version_supported() {
+    let mut mapping = test_mapping("0.0.1", parity_wasm::builder::module().build());
+    for api_version in API_VERSIONS {
+        mapping.api_version = api_version.to_string();
+        assert!(mapping.api_version_supported());
+    }
+    for api_version in &["0.0.5", "0.1.0", "latest"] {
+        mapping.api_version = api_version.to_string();
+        assert!(!mapping.api_version_supported());
+    }
+}
+
+#[test]
+fn test_mapping_imports_function() {
+    use parity_wasm::builder;
+
+    let module = builder::module()
+        .import()
+        .module("index")
+        .field("ipfs.cat")
+        .external()
+        .func(0)
+        .build()
+        .import()
+        .module("env")
+        .field("memory")
+        .external()
+        .memory(1, None)
+        .build()
+        .build();
+    let mapping = test_mapping("0.0.4", module);
+
+    assert!(mapping.imports_function("ipfs.cat"));
+    assert!(!mapping.imports_function("ipfs.map"));
+    assert!(!mapping.imports_function("memory"));
+}
+
+#[test]
+fn test_used_features() {
+    use parity_wasm::builder;
+
+    let id = SubgraphDeploymentId::new("features").unwrap();
+    let data_source = |mapping: Mapping| DataSource {
+        kind: "ethereum/contract".to_owned(),
+        network: Some("mainnet".to_owned()),
+        name: "Band".to_owned(),
+        source: Source {
+            address: None,
+            abi: "Band".to_owned(),
+            start_block: 0,
+        },
+        mapping,
+        templates: vec![],
+    };
+    let manifest = |schema: &str, mapping: Mapping| SubgraphManifest {
+        id: id.clone(),
+        location: "/ipfs/features".to_owned(),
+        spec_version: "0.0.2".to_owned(),
+        description: None,
+        repository: None,
+        schema: Schema::parse(schema, id.clone()).unwrap(),
+        data_sources: vec![data_source(mapping)],
+        templates: vec![],
+        graft: None,
+        features: vec![],
+    };
+    let schema = "type Band @entity { id: ID!, name: String! }";
+
+    let plain = manifest(schema, test_mapping("0.0.4", builder::module().build()));
+    assert!(plain.used_features().is_empty());
+
+    let module = builder::module()
+        .import()
+        .module("index")
+        .field("ipfs.map")
+        .external()
+        .func(0)
+        .build()
+        .build();
+    let mut mapping = test_mapping("0.0.4", module);
+    mapping.block_handlers.push(MappingBlockHandler {
+        handler: "handleBlock".to_owned(),
+        filter: None,
+    });
+    mapping.call_handlers.push(MappingCallHandler {
+        function: "join(address)".to_owned(),
+        handler: "handleJoin".to_owned(),
+    });
+    let fulltext = r#"
+type _Schema_
+  @fulltext(
+    name: "bandSearch"
+    language: en
+    algorithm: rank
+    include: [{ entity: "Band", fields: [{ name: "name" }] }]
+  )
+"#;
+    let mut full = manifest(&format!("{}{}", fulltext, schema), mapping.clone());
+    full.graft = Some(Graft {
+        base: SubgraphDeploymentId::new("base").unwrap(),
+        block: 7,
+    });
+    full.templates.push(DataSourceTemplate {
+        kind: "ethereum/contract".to_owned(),
+        network: Some("mainnet".to_owned()),
+        name: "Member".to_owned(),
+        source: TemplateSource {
+            abi: "Member".to_owned(),
+        },
+        mapping,
+    });
+    assert_eq!(
+        vec![
+            "blockHandlers",
+            "callHandlers",
+            "fullTextSearch",
+            "grafting",
+            "ipfsOnEthereumContracts",
+            "templates",
+        ],
+        full.used_features().into_iter().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_handler_signature_errors() {
+    use parity_wasm::builder;
+
+    let abi = r#"[
+        {"type": "event", "name": "Filled", "anonymous": false, "inputs": [
+            {"name": "maker", "type": "address", "indexed": true},
+            {"name": "order", "type": "tuple", "indexed": false, "components": [
+                {"name": "amount", "type": "uint256"},
+                {"name": "taker", "type": "address"}]}]},
+        {"type": "function", "name": "fill", "constant": false, "outputs": [],
+         "inputs": [{"name": "order", "type": "tuple", "components": [
+             {"name": "amount", "type": "uint256"},
+             {"name": "taker", "type": "address"}]}]}
+    ]"#;
+    let module = builder::module()
+        .function()
+        .signature()
+        .build()
+        .body()
+        .build()
+        .build()
+        .export()
+        .field("handleFilled")
+        .internal()
+        .func(0)
+        .build()
+        .build();
+    let mapping = |event: &str, function: &str| {
+        let mut mapping = test_mapping("0.0.4", module.clone());
+        mapping.abis.push(MappingABI {
+            name: "Exchange".to_owned(),
+            contract: Contract::load(abi.as_bytes()).unwrap(),
+            link: Link {
+                link: "/ipfs/abi".to_owned(),
+            },
+        });
+        mapping.event_handlers.push(MappingEventHandler {
+            event: event.to_owned(),
+            topic0: None,
+            handler: "handleFilled".to_owned(),
+        });
+        mapping.call_handlers.push(MappingCallHandler {
+            function: function.to_owned(),
+            handler: "handleFilled".to_owned(),
+        });
+        mapping
+    };
+    let id = SubgraphDeploymentId::new("signatures").unwrap();
+    let manifest = |abi: &str, mapping: Mapping| SubgraphManifest {
+        id: id.clone(),
+        location: "/ipfs/signatures".to_owned(),
+        spec_version: "0.0.2".to_owned(),
+        description: None,
+        repository: None,
+        schema: Schema::parse("type Fill @entity { id: ID! }", id.clone()).unwrap(),
+        data_sources: vec![DataSource {
+            kind: "ethereum/contract".to_owned(),
+            network: Some("mainnet".to_owned()),
+            name: "Exchange".to_owned(),
+            source: Source {
+                address: None,
+                abi: abi.to_owned(),
+                start_block: 0,
+            },
+            mapping,
+            templates: vec![],
+        }],
+        templates: vec![],
+        graft: None,
+        features: vec![],
+    };
+    let errors = |manifest: SubgraphManifest| {
+        manifest
+            .handler_signature_errors()
+            .into_iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let valid = mapping(
+        "Filled(indexed address,(uint256,address))",
+        "fill((uint256,address))",
+    );
+    assert!(errors(manifest("Exchange", valid.clone())).is_empty());
+
+    // Tuple components are compared one by one, not just their number
+    let swapped = mapping(
+        "Filled(indexed address,(address,uint256))",
+        "fill((address,uint256))",
+    );
+    assert_eq!(
+        vec![
+            "event `Filled(indexed address,(address,uint256))` of data source `Exchange` \
+             is not in ABI `Exchange`: the ABI declares \
+             `Filled(indexed address,(uint256,address))`",
+            "function `fill((address,uint256))` of data source `Exchange` \
+             is not in ABI `Exchange`: the ABI declares `fill((uint256,address))`",
+        ],
+        errors(manifest("Exchange", swapped))
+    );
+
+    // Indexed-ness has to match once the manifest states it
+    let indexed = mapping(
+        "Filled(address,indexed (uint256,address))",
+        "fill((uint256,address))",
+    );
+    assert_eq!(1, errors(manifest("Exchange", indexed)).len());
+
+    let mut unexported = valid.clone();
+    unexported.block_handlers.push(MappingBlockHandler {
+        handler: "handleBlock".to_owned(),
+        filter: None,
+    });
+    let unexported = errors(manifest("Exchange", unexported));
+    assert_eq!(1, unexported.len());
+    assert!(unexported[0].contains("handleBlock"));
+
+    let missing_abi = errors(manifest("Token", valid));
+    assert_eq!(1, missing_abi.len());
+    assert!(missing_abi[0].contains("`Token`"));
+}
+
+#[test]
+fn test_entity_usage_warnings() {
+    use parity_wasm::builder;
+    use parity_wasm::elements::Instruction;
+
+    fn mapping_writing(entity_type: &str, entities: Vec<&str>) -> Mapping {
+        let mut data = (entity_type.len() as u32).to_le_bytes().to_vec();
+        data.extend(
+            entity_type
+                .encode_utf16()
+                .flat_map(|c| c.to_le_bytes().to_vec()),
+        );
+        let module = builder::module()
+            .data()
+            .offset(Instruction::I32Const(0))
+            .value(data)
+            .build()
+            .build();
+        let mut mapping = test_mapping("0.0.4", module);
+        mapping.entities = entities.into_iter().map(str::to_owned).collect();
+        mapping
+    }
+
+    let id = SubgraphDeploymentId::new("usage").unwrap();
+    let schema = r#"
+type _Schema_
+  @fulltext(
+    name: "bandSearch"
+    language: en
+    algorithm: rank
+    include: [{ entity: "Band", fields: [{ name: "name" }] }]
+  )
+type Band @entity { id: ID!, name: String! }
+type Member @entity { id: ID! }
+type Token @entity { id: ID! }
+"#;
+    let manifest = SubgraphManifest {
+        id: id.clone(),
+        location: "/ipfs/usage".to_owned(),
+        spec_version: "0.0.2".to_owned(),
+        description: None,
+        repository: None,
+        schema: Schema::parse(schema, id.clone()).unwrap(),
+        data_sources: vec![DataSource {
+            kind: "ethereum/contract".to_owned(),
+            network: Some("mainnet".to_owned()),
+            name: "Band".to_owned(),
+            source: Source {
+                address: None,
+                abi: "Band".to_owned(),
+                start_block: 0,
+            },
+            mapping: mapping_writing("Band", vec!["Band", "Album"]),
+            templates: vec![],
+        }],
+        // Entity types that only templates write are used, too
+        templates: vec![DataSourceTemplate {
+            kind: "ethereum/contract".to_owned(),
+            network: Some("mainnet".to_owned()),
+            name: "Member".to_owned(),
+            source: TemplateSource {
+                abi: "Member".to_owned(),
+            },
+            mapping: mapping_writing("Member", vec!["Member"]),
+        }],
+        graft: None,
+        features: vec![],
+    };
+
+    assert_eq!(
+        vec![
+            "entity type `Token` is declared in the schema, but no mapping appears to write it",
+            "entity type `Album` is listed in the mappings of the manifest, \
+             but not declared in the schema",
+        ],
+        manifest
+            .entity_usage_warnings()
+            .into_iter()
+            .map(|warning| warning.to_string())
+            .collect::<Vec<_>>()
+    );
+}
+
 /// Result of a creating a subgraph in the registar.
 #[derive(Serialize)]
 pub struct CreateSubgraphResult {
@@ -238,8 +665,28 @@ pub enum SubgraphRegistrarError {
     NetworkNotSupported(String),
     #[fail(display = "deployment not found: {}", _0)]
     DeploymentNotFound(String),
+    #[fail(display = "deployment already exists: {}", _0)]
+    DeploymentExists(String),
+    #[fail(display = "deployment is not synced: {}", _0)]
+    DeploymentNotSynced(String),
     #[fail(display = "deployment assignment unchanged: {}", _0)]
     DeploymentAssignmentUnchanged(String),
+    #[fail(display = "deployment has no standby node: {}", _0)]
+    NoStandbyNode(String),
+    #[fail(display = "deployment {} is blocked: {}", _0, _1)]
+    DeploymentBlocked(String, String),
+    #[fail(display = "invalid deployment quota: {}", _0)]
+    InvalidQuota(String),
+    #[fail(display = "deployment {} is assigned to node {}", _0, _1)]
+    DeploymentAssignedElsewhere(String, String),
+    #[fail(display = "deployment {} is pinned to node {}", _0, _1)]
+    DeploymentPinned(String, String),
+    #[fail(display = "assignment rebalancing is not enabled")]
+    RebalancingDisabled,
+    #[fail(display = "invalid annotation: {}", _0)]
+    InvalidAnnotation(String),
+    #[fail(display = "{}", _0)]
+    AssignmentProviderError(SubgraphAssignmentProviderError),
     #[fail(display = "subgraph registrar internal query error: {}", _0)]
     QueryExecutionError(QueryExecutionError),
     #[fail(display = "subgraph registrar error with store: {}", _0)]
@@ -282,11 +729,24 @@ pub enum SubgraphAssignmentProviderError {
     ResolveError(SubgraphManifestResolveError),
     #[fail(display = "Failed to load dynamic data sources: {}", _0)]
     DynamicDataSourcesError(failure::Error),
+    #[fail(display = "Failed to copy data from graft base: {}", _0)]
+    GraftError(failure::Error),
+    #[fail(display = "Failed to rewind subgraph: {}", _0)]
+    RewindError(failure::Error),
+    #[fail(display = "Failed to migrate subgraph storage: {}", _0)]
+    StorageMigrationError(failure::Error),
     /// Occurs when attempting to remove a subgraph that's not hosted.
     #[fail(display = "Subgraph with ID {} already running", _0)]
     AlreadyRunning(SubgraphDeploymentId),
     #[fail(display = "Subgraph with ID {} is not running", _0)]
     NotRunning(SubgraphDeploymentId),
+    /// Occurs when the subgraph is stopped while it is being started.
+    #[fail(display = "Starting subgraph with ID {} was canceled", _0)]
+    Canceled(SubgraphDeploymentId),
+    #[fail(display = "Subgraph with ID {} is already paused", _0)]
+    AlreadyPaused(SubgraphDeploymentId),
+    #[fail(display = "Subgraph with ID {} is not paused", _0)]
+    NotPaused(SubgraphDeploymentId),
     /// Occurs when a subgraph's GraphQL schema is invalid.
     #[fail(display = "GraphQL schema error: {}", _0)]
     SchemaValidationError(failure::Error),
@@ -295,6 +755,27 @@ pub enum SubgraphAssignmentProviderError {
         _0, _1, _2
     )]
     BuildIndexesError(String, String, String),
+    /// Occurs when an operator put the subgraph on the deployment blocklist.
+    #[fail(display = "Subgraph with ID {} is blocked: {}", _0, _1)]
+    Blocked(SubgraphDeploymentId, String),
+    /// Occurs when the instance manager does not take events for a while.
+    #[fail(
+        display = "Timed out forwarding event for subgraph with ID {}; the instance manager is not keeping up",
+        _0
+    )]
+    EventQueueFull(SubgraphDeploymentId),
+    /// Occurs when the instance manager has shut down.
+    #[fail(
+        display = "Failed to forward event for subgraph with ID {}; the instance manager has shut down",
+        _0
+    )]
+    EventQueueClosed(SubgraphDeploymentId),
+    /// Occurs when a subgraph is started while the node shuts down.
+    #[fail(
+        display = "Subgraph with ID {} was not started because the node is shutting down",
+        _0
+    )]
+    ShuttingDown(SubgraphDeploymentId),
     #[fail(display = "Subgraph provider error: {}", _0)]
     Unknown(failure::Error),
 }
@@ -318,12 +799,71 @@ pub enum SubgraphAssignmentProviderEvent {
     SubgraphStart(SubgraphManifest),
     /// The subgraph with the given ID should stop processing.
     SubgraphStop(SubgraphDeploymentId),
+    /// The subgraph with the given ID should stop processing blocks for
+    /// now, but keep its state so that it can be resumed.
+    SubgraphPause(SubgraphDeploymentId),
+    /// The paused subgraph with the given ID should continue processing
+    /// blocks where it left off.
+    SubgraphResume(SubgraphDeploymentId),
+    /// The subgraph with the given ID is about to be stopped and should
+    /// not start processing another block. The signal is notified once
+    /// the block it is processing, if any, has been written.
+    SubgraphDrain(SubgraphDeploymentId, DrainSignal),
+}
+
+/// Tells whoever asked for a subgraph to be drained that the subgraph has
+/// finished the block it was processing. Dropping every copy of the signal
+/// without notifying it counts as the subgraph being drained, too.
+#[derive(Clone)]
+pub struct DrainSignal(Arc<Mutex<Option<oneshot::Sender<()>>>>);
+
+impl DrainSignal {
+    /// Create a signal and the receiver that resolves once it is notified
+    pub fn new() -> (Self, oneshot::Receiver<()>) {
+        let (sender, receiver) = oneshot::channel();
+        (DrainSignal(Arc::new(Mutex::new(Some(sender)))), receiver)
+    }
+
+    pub fn notify(&self) {
+        if let Some(sender) = self.0.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+impl fmt::Debug for DrainSignal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DrainSignal")
+    }
+}
+
+impl PartialEq for DrainSignal {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
 #[derive(Fail, Debug)]
 pub enum SubgraphManifestValidationWarning {
     #[fail(display = "schema validation produced warnings: {:?}", _0)]
     SchemaValidationWarning(SchemaImportError),
+    #[fail(display = "{}", _0)]
+    SchemaLintWarning(SchemaLintWarning),
+    #[fail(
+        display = "entity type `{}` is declared in the schema, but no mapping appears to write it",
+        _0
+    )]
+    UnusedEntity(String),
+    #[fail(
+        display = "entity type `{}` is listed in the mappings of the manifest, but not declared in the schema",
+        _0
+    )]
+    UndeclaredEntity(String),
+    #[fail(
+        display = "subgraph uses feature `{}`, but does not list it under `features`",
+        _0
+    )]
+    UndeclaredFeature(String),
 }
 
 #[derive(Fail, Debug)]
@@ -344,6 +884,38 @@ pub enum SubgraphManifestValidationError {
     SchemaImportError(Vec<SchemaImportError>),
     #[fail(display = "schema validation failed: {:?}", _0)]
     SchemaValidationError(Vec<SchemaValidationError>),
+    #[fail(
+        display = "data source `{}` uses ABI `{}`, which is not listed in its mapping",
+        _0, _1
+    )]
+    SourceAbiNotFound(String, String),
+    #[fail(
+        display = "event `{}` of data source `{}` is not in ABI `{}`: {}",
+        _1, _0, _2, _3
+    )]
+    EventNotInAbi(String, String, String, String),
+    #[fail(
+        display = "function `{}` of data source `{}` is not in ABI `{}`: {}",
+        _1, _0, _2, _3
+    )]
+    FunctionNotInAbi(String, String, String, String),
+    #[fail(display = "subgraph can not be grafted onto `{}`: {}", _0, _1)]
+    GraftBaseInvalid(String, String),
+    #[fail(
+        display = "handler `{}` of data source `{}` is not exported by its mapping",
+        _1, _0
+    )]
+    HandlerNotExported(String, String),
+    #[fail(
+        display = "feature `{}` is not supported by this node, it supports {}",
+        _0, _1
+    )]
+    FeatureNotSupported(String, String),
+    #[fail(
+        display = "mapping API version `{}` is not supported by this node, it supports up to {}",
+        _0, _1
+    )]
+    ApiVersionNotSupported(String, String),
 }
 
 #[derive(Fail, Debug)]
@@ -568,6 +1140,75 @@ pub struct Mapping {
     pub link: Link,
 }
 
+impl Mapping {
+    /// Whether the mapping's WASM module contains `text` as a string
+    /// constant. AssemblyScript stores string constants in data segments
+    /// as UTF-16, right after their length, which older compilers give in
+    /// characters and newer ones in bytes
+    pub fn has_string_constant(&self, text: &str) -> bool {
+        let chars: Vec<u8> = text
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes().to_vec())
+            .collect();
+        let lengths = [
+            (chars.len() as u32 / 2).to_le_bytes(),
+            (chars.len() as u32).to_le_bytes(),
+        ];
+
+        self.runtime.data_section().map_or(false, |section| {
+            section.entries().iter().any(|segment| {
+                segment.value().windows(chars.len() + 4).any(|window| {
+                    window.ends_with(&chars)
+                        && lengths.iter().any(|length| window.starts_with(length))
+                })
+            })
+        })
+    }
+
+    /// Whether this node can run mappings with the mapping's API version
+    pub fn api_version_supported(&self) -> bool {
+        let max_api_version = semver::Version::parse(API_VERSIONS.last().unwrap()).unwrap();
+        semver::Version::parse(&self.api_version)
+            .map_or(false, |version| version <= max_api_version)
+    }
+
+    /// Whether the mapping's WASM module exports a function called `name`
+    pub fn exports_function(&self, name: &str) -> bool {
+        self.runtime.export_section().map_or(false, |section| {
+            section.entries().iter().any(|entry| {
+                entry.field() == name
+                    && match entry.internal() {
+                        Internal::Function(_) => true,
+                        _ => false,
+                    }
+            })
+        })
+    }
+
+    /// Whether the mapping's WASM module imports a host function called
+    /// `name`, like `ipfs.cat`
+    pub fn imports_function(&self, name: &str) -> bool {
+        self.runtime.import_section().map_or(false, |section| {
+            section.entries().iter().any(|entry| {
+                entry.field() == name
+                    && match entry.external() {
+                        External::Function(_) => true,
+                        _ => false,
+                    }
+            })
+        })
+    }
+
+    /// The names of the handlers that the mapping calls
+    pub fn handler_names(&self) -> impl Iterator<Item = &String> {
+        self.block_handlers
+            .iter()
+            .map(|handler| &handler.handler)
+            .chain(self.call_handlers.iter().map(|handler| &handler.handler))
+            .chain(self.event_handlers.iter().map(|handler| &handler.handler))
+    }
+}
+
 impl UnresolvedMapping {
     pub fn resolve(
         self,
@@ -682,9 +1323,12 @@ impl UnresolvedDataSource {
 }
 
 impl DataSource {
+    /// Create a data source from `template` for the parameters `params`
+    /// that starts at `start_block`, the block in which it was created
     pub fn try_from_template(
         template: DataSourceTemplate,
         params: &Vec<String>,
+        start_block: u64,
     ) -> Result<Self, failure::Error> {
         // Obtain the address from the parameters
         let string = params
@@ -712,7 +1356,7 @@ impl DataSource {
             source: Source {
                 address: Some(address),
                 abi: template.source.abi,
-                start_block: 0,
+                start_block,
             },
             mapping: template.mapping,
             templates: Vec::new(),
@@ -802,6 +1446,40 @@ impl DataSourceTemplate {
     }
 }
 
+/// The manifest spec versions this node supports, oldest first. Spec
+/// version 0.0.3 does not exist, but subgraphs that used it by mistake
+/// before spec versions were checked are still accepted
+pub const SPEC_VERSIONS: &[&str] = &["0.0.1", "0.0.2", "0.0.3"];
+
+/// The mapping API versions this node supports, oldest first
+pub const API_VERSIONS: &[&str] = &["0.0.1", "0.0.2", "0.0.3", "0.0.4"];
+
+/// The optional subgraph features this node supports
+pub const FEATURES: &[&str] = &[
+    "blockHandlers",
+    "callHandlers",
+    "fullTextSearch",
+    "grafting",
+    "ipfsOnEthereumContracts",
+    "nonFatalErrors",
+    "templates",
+];
+
+/// The feature that makes handler errors that are not transient non-fatal:
+/// the changes of the block that caused them are discarded, the error is
+/// recorded, and the deployment continues with the next block
+pub const NON_FATAL_ERRORS: &str = "nonFatalErrors";
+
+/// An existing deployment that a new deployment starts from. Instead of
+/// indexing from its start block, the new deployment copies the entities
+/// and dynamic data sources of `base` as of `block` and continues indexing
+/// with the block after that
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Graft {
+    pub base: SubgraphDeploymentId,
+    pub block: u64,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BaseSubgraphManifest<S, D, T> {
@@ -814,6 +1492,10 @@ pub struct BaseSubgraphManifest<S, D, T> {
     pub data_sources: Vec<D>,
     #[serde(default)]
     pub templates: Vec<T>,
+    pub graft: Option<Graft>,
+    /// The optional features from `FEATURES` that the subgraph opts into
+    #[serde(default)]
+    pub features: Vec<String>,
 }
 
 /// Consider two subgraphs to be equal if they come from the same IPLD link.
@@ -852,11 +1534,20 @@ impl UnvalidatedSubgraphManifest {
         (SubgraphManifest, Vec<SubgraphManifestValidationWarning>),
         Vec<SubgraphManifestValidationError>,
     > {
-        let (schemas, import_errors) = self.0.schema.resolve_schema_references(store);
-        let validation_warnings = import_errors
+        let (schemas, import_errors) = self.0.schema.resolve_schema_references(store.clone());
+        let mut validation_warnings: Vec<_> = import_errors
             .into_iter()
             .map(|err| SubgraphManifestValidationWarning::SchemaValidationWarning(err))
             .collect();
+        validation_warnings.extend(
+            self.0
+                .schema
+                .lint()
+                .into_iter()
+                .map(SubgraphManifestValidationWarning::SchemaLintWarning),
+        );
+
+        validation_warnings.extend(self.0.entity_usage_warnings());
 
         let mut errors: Vec<SubgraphManifestValidationError> = vec![];
 
@@ -919,6 +1610,62 @@ impl UnvalidatedSubgraphManifest {
             _ => errors.push(SubgraphManifestValidationError::MultipleEthereumNetworks),
         }
 
+        errors.extend(self.0.handler_signature_errors());
+
+        // The runtime refuses these mappings when the subgraph starts
+        let unsupported_api_versions: BTreeSet<_> = self
+            .0
+            .mappings()
+            .filter(|mapping| !mapping.api_version_supported())
+            .map(|mapping| mapping.api_version.clone())
+            .collect();
+        errors.extend(unsupported_api_versions.into_iter().map(|api_version| {
+            SubgraphManifestValidationError::ApiVersionNotSupported(
+                api_version,
+                API_VERSIONS.last().unwrap().to_string(),
+            )
+        }));
+
+        // Refuse features this node does not have instead of failing
+        // once a handler needs them
+        errors.extend(
+            self.0
+                .features
+                .iter()
+                .filter(|feature| !FEATURES.contains(&feature.as_str()))
+                .map(|feature| {
+                    SubgraphManifestValidationError::FeatureNotSupported(
+                        feature.clone(),
+                        FEATURES.join(", "),
+                    )
+                }),
+        );
+        validation_warnings.extend(
+            self.0
+                .used_features()
+                .into_iter()
+                .filter(|feature| !self.0.features.iter().any(|declared| declared == feature))
+                .map(|feature| {
+                    SubgraphManifestValidationWarning::UndeclaredFeature(feature.to_owned())
+                }),
+        );
+
+        // Validate that the graft base exists and has indexed the graft block
+        if let Some(graft) = &self.0.graft {
+            let invalid = |reason: String| {
+                SubgraphManifestValidationError::GraftBaseInvalid(graft.base.to_string(), reason)
+            };
+            match store.block_ptr(graft.base.clone()) {
+                Err(e) => errors.push(invalid(e.to_string())),
+                Ok(None) => errors.push(invalid("it has not indexed any blocks yet".to_owned())),
+                Ok(Some(ptr)) if ptr.number < graft.block => errors.push(invalid(format!(
+                    "it has only indexed up to block {}, not block {}",
+                    ptr.number, graft.block
+                ))),
+                Ok(Some(_)) => (),
+            }
+        }
+
         self.0
             .schema
             .validate(&schemas)
@@ -938,6 +1685,159 @@ impl UnvalidatedSubgraphManifest {
 }
 
 impl SubgraphManifest {
+    /// The mappings of all data sources and templates
+    fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.data_sources
+            .iter()
+            .flat_map(|data_source| {
+                std::iter::once(&data_source.mapping).chain(
+                    data_source
+                        .templates
+                        .iter()
+                        .map(|template| &template.mapping),
+                )
+            })
+            .chain(self.templates.iter().map(|template| &template.mapping))
+    }
+
+    /// The features from `FEATURES` that the manifest and its mappings
+    /// use, whether they are listed under `features` or not
+    fn used_features(&self) -> BTreeSet<&'static str> {
+        let mut features = BTreeSet::new();
+        if self
+            .mappings()
+            .any(|mapping| !mapping.block_handlers.is_empty())
+        {
+            features.insert("blockHandlers");
+        }
+        if self
+            .mappings()
+            .any(|mapping| !mapping.call_handlers.is_empty())
+        {
+            features.insert("callHandlers");
+        }
+        if !Schema::fulltext_definitions(&self.schema.document).is_empty() {
+            features.insert("fullTextSearch");
+        }
+        if self.graft.is_some() {
+            features.insert("grafting");
+        }
+        if self.mappings().any(|mapping| {
+            mapping.imports_function("ipfs.cat") || mapping.imports_function("ipfs.map")
+        }) {
+            features.insert("ipfsOnEthereumContracts");
+        }
+        if !self.templates.is_empty()
+            || self
+                .data_sources
+                .iter()
+                .any(|data_source| !data_source.templates.is_empty())
+        {
+            features.insert("templates");
+        }
+        features
+    }
+
+    /// Check every event and call handler against the ABI of its data
+    /// source or template. Handlers whose signature is not in the ABI
+    /// would never be triggered, so this reports them together with the
+    /// signatures that the ABI declares under the same name
+    fn handler_signature_errors<'a>(&'a self) -> Vec<SubgraphManifestValidationError> {
+        let template_source = |template: &'a DataSourceTemplate| {
+            (&template.name, &template.source.abi, &template.mapping)
+        };
+        let sources = self
+            .data_sources
+            .iter()
+            .flat_map(|data_source| {
+                let source = (
+                    &data_source.name,
+                    &data_source.source.abi,
+                    &data_source.mapping,
+                );
+                std::iter::once(source).chain(data_source.templates.iter().map(template_source))
+            })
+            .chain(self.templates.iter().map(template_source));
+
+        let mut errors = vec![];
+        for (name, abi_name, mapping) in sources {
+            for handler in mapping.handler_names() {
+                if !mapping.exports_function(handler) {
+                    errors.push(SubgraphManifestValidationError::HandlerNotExported(
+                        name.clone(),
+                        handler.clone(),
+                    ));
+                }
+            }
+
+            let contract = match mapping.abis.iter().find(|abi| &abi.name == abi_name) {
+                Some(abi) => &abi.contract,
+                None => {
+                    errors.push(SubgraphManifestValidationError::SourceAbiNotFound(
+                        name.clone(),
+                        abi_name.clone(),
+                    ));
+                    continue;
+                }
+            };
+
+            for handler in &mapping.event_handlers {
+                if contract_event_with_signature(contract, &handler.event).is_none() {
+                    errors.push(SubgraphManifestValidationError::EventNotInAbi(
+                        name.clone(),
+                        handler.event.clone(),
+                        abi_name.clone(),
+                        event_signature_mismatch(contract, &handler.event),
+                    ));
+                }
+            }
+            for handler in &mapping.call_handlers {
+                if contract_function_with_signature(contract, &handler.function).is_none() {
+                    errors.push(SubgraphManifestValidationError::FunctionNotInAbi(
+                        name.clone(),
+                        handler.function.clone(),
+                        abi_name.clone(),
+                        function_signature_mismatch(contract, &handler.function),
+                    ));
+                }
+            }
+        }
+        errors
+    }
+
+    /// Compare the entity types that the schema declares with the ones
+    /// that the mappings use. An entity type is considered written if its
+    /// name is a string constant in one of the mappings, which is how
+    /// AssemblyScript passes it to `store.set`; this can miss names that
+    /// mappings build at runtime, and so only produces warnings
+    fn entity_usage_warnings(&self) -> Vec<SubgraphManifestValidationWarning> {
+        let declared: BTreeSet<_> = self
+            .schema
+            .document
+            .get_object_type_definitions()
+            .into_iter()
+            .filter(|object_type| !object_type.name.eq(SCHEMA_TYPE_NAME))
+            .map(|object_type| object_type.name.as_str())
+            .collect();
+        let listed: BTreeSet<_> = self
+            .mappings()
+            .flat_map(|mapping| mapping.entities.iter().map(String::as_str))
+            .collect();
+
+        let unused = declared
+            .iter()
+            .filter(|name| {
+                !self
+                    .mappings()
+                    .any(|mapping| mapping.has_string_constant(name))
+            })
+            .map(|name| SubgraphManifestValidationWarning::UnusedEntity(name.to_string()));
+        let undeclared = listed
+            .difference(&declared)
+            .map(|name| SubgraphManifestValidationWarning::UndeclaredEntity(name.to_string()));
+        unused.chain(undeclared).collect()
+    }
+
     /// Entry point for resolving a subgraph definition.
     /// Right now the only supported links are of the form:
     /// `/ipfs/QmUmg7BZC1YP1ca66rRtWKxpXp77WgVHrnv263JtDuvs2k`
@@ -1002,6 +1902,13 @@ impl SubgraphManifest {
             .map(|data_source| data_source.source.start_block)
             .collect()
     }
+
+    /// Whether the subgraph opts into the `nonFatalErrors` feature
+    pub fn non_fatal_errors(&self) -> bool {
+        self.features
+            .iter()
+            .any(|feature| feature == NON_FATAL_ERRORS)
+    }
 }
 
 impl UnresolvedSubgraphManifest {
@@ -1019,19 +1926,23 @@ impl UnresolvedSubgraphManifest {
             schema,
             data_sources,
             templates,
+            graft,
+            features,
         } = self;
 
+        // Before this check was introduced, there were already subgraphs in
+        // the wild with spec version 0.0.3, due to confusion with the api
+        // version. To avoid breaking those, we accept 0.0.3 though it
+        // doesn't exist. In the future we should not use 0.0.3 as version
+        // and skip to 0.0.4 to avoid ambiguity.
+        let max_spec_version = SPEC_VERSIONS.last().unwrap();
         match semver::Version::parse(&spec_version) {
-            // Before this check was introduced, there were already subgraphs in
-            // the wild with spec version 0.0.3, due to confusion with the api
-            // version. To avoid breaking those, we accept 0.0.3 though it
-            // doesn't exist. In the future we should not use 0.0.3 as version
-            // and skip to 0.0.4 to avoid ambiguity.
-            Ok(ref ver) if *ver <= semver::Version::new(0, 0, 3) => {}
+            Ok(ref ver) if *ver <= semver::Version::parse(max_spec_version).unwrap() => {}
             _ => {
                 return Box::new(futures::future::err(format_err!(
-                    "This Graph Node only supports manifest spec versions <= 0.0.2,
-                    but subgraph `{}` uses `{}`",
+                    "This Graph Node only supports manifest spec versions <= {}, \
+                     but subgraph `{}` uses `{}`",
+                    max_spec_version,
                     id,
                     spec_version
                 ))) as Box<dyn Future<Item = _, Error = _> + Send>;
@@ -1066,6 +1977,8 @@ impl UnresolvedSubgraphManifest {
                     schema,
                     data_sources,
                     templates,
+                    graft,
+                    features,
                 }),
         )
     }
@@ -28,7 +28,9 @@ use crate::components::store::{
     EntityQuery, EntityRange, MetadataOperation,
 };
 use crate::data::graphql::{TryFromValue, ValueMap};
-use crate::data::store::{Entity, NodeId, SubgraphEntityPair, Value, ValueType};
+use crate::data::store::{
+    Entity, EntityIdFormat, EntityIdLimits, NodeId, SubgraphEntityPair, Value, ValueType,
+};
 use crate::data::subgraph::{SubgraphManifest, SubgraphName};
 use crate::prelude::*;
 
@@ -47,6 +49,7 @@ pub trait TypedEntity {
         let range = EntityRange {
             first: None,
             skip: 0,
+            from_end: false,
         };
         EntityQuery::new(
             SUBGRAPHS_ID.clone(),
@@ -219,6 +222,10 @@ pub struct SubgraphDeploymentEntity {
     ethereum_head_block_hash: Option<H256>,
     ethereum_head_block_number: Option<u64>,
     total_ethereum_blocks_count: u64,
+    graft_base: Option<SubgraphDeploymentId>,
+    graft_block_hash: Option<H256>,
+    graft_block_number: Option<u64>,
+    entity_id_limits: EntityIdLimits,
 }
 
 impl TypedEntity for SubgraphDeploymentEntity {
@@ -245,9 +252,43 @@ impl SubgraphDeploymentEntity {
             ethereum_head_block_hash: chain_head_block.map(Into::into),
             ethereum_head_block_number: chain_head_block.map(Into::into),
             total_ethereum_blocks_count: chain_head_block.map_or(0, |block| block.number + 1),
+            graft_base: None,
+            graft_block_hash: None,
+            graft_block_number: None,
+            entity_id_limits: EntityIdLimits::configured(),
         }
     }
 
+    /// The limits on entity IDs that were recorded in the `deployment`
+    /// entity when it was created. Deployments created before IDs could be
+    /// limited have no limits
+    pub fn entity_id_limits(deployment: &Entity) -> Result<EntityIdLimits, Error> {
+        let max_length = match deployment.get("maxEntityIdLength") {
+            Some(Value::BigInt(n)) => Some(n.to_string().parse::<usize>()?),
+            Some(Value::Null) | None => None,
+            Some(v) => return Err(format_err!("invalid maxEntityIdLength: {}", v)),
+        };
+        let format = match deployment.get("entityIdFormat") {
+            Some(Value::String(s)) => Some(s.parse::<EntityIdFormat>()?),
+            Some(Value::Null) | None => None,
+            Some(v) => return Err(format_err!("invalid entityIdFormat: {}", v)),
+        };
+        Ok(EntityIdLimits { max_length, format })
+    }
+
+    /// Start the deployment from the data that `base` had at `block`. The
+    /// deployment has no block pointer until that data has been copied
+    pub fn graft(mut self, base: SubgraphDeploymentId, block: EthereumBlockPointer) -> Self {
+        self.earliest_ethereum_block_hash = None;
+        self.earliest_ethereum_block_number = None;
+        self.latest_ethereum_block_hash = None;
+        self.latest_ethereum_block_number = None;
+        self.graft_base = Some(base);
+        self.graft_block_hash = Some(block.hash);
+        self.graft_block_number = Some(block.number);
+        self
+    }
+
     // Overwrite entity if it exists. Only in debug builds so it's not used outside tests.
     #[cfg(debug_assertions)]
     pub fn create_operations_replace(self, id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
@@ -305,6 +346,19 @@ impl SubgraphDeploymentEntity {
         );
         entity.set("totalEthereumBlocksCount", self.total_ethereum_blocks_count);
         entity.set("entityCount", 0 as u64);
+        entity.set("graftBase", self.graft_base.map(|base| base.to_string()));
+        entity.set("graftBlockHash", Value::from(self.graft_block_hash));
+        entity.set("graftBlockNumber", Value::from(self.graft_block_number));
+        entity.set(
+            "maxEntityIdLength",
+            Value::from(self.entity_id_limits.max_length.map(|n| n as u64)),
+        );
+        entity.set(
+            "entityIdFormat",
+            self.entity_id_limits
+                .format
+                .map(|format| format.as_str().to_owned()),
+        );
         ops.push(set_metadata_operation(
             Self::TYPENAME,
             id.to_string(),
@@ -377,6 +431,7 @@ impl SubgraphDeploymentEntity {
 #[derive(Debug)]
 pub struct SubgraphDeploymentAssignmentEntity {
     node_id: NodeId,
+    standby_node_id: Option<NodeId>,
     cost: u64,
 }
 
@@ -387,18 +442,700 @@ impl TypedEntity for SubgraphDeploymentAssignmentEntity {
 
 impl SubgraphDeploymentAssignmentEntity {
     pub fn new(node_id: NodeId) -> Self {
-        Self { node_id, cost: 1 }
+        Self {
+            node_id,
+            standby_node_id: None,
+            cost: 1,
+        }
+    }
+
+    /// Set the node that follows the deployment in standby mode and takes
+    /// over indexing it when the assigned node stops making progress
+    pub fn with_standby(mut self, standby_node_id: Option<NodeId>) -> Self {
+        self.standby_node_id = standby_node_id;
+        self
+    }
+
+    pub fn write_operations(self, id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("id", id.to_string());
+        entity.set("nodeId", self.node_id.to_string());
+        entity.set(
+            "standbyNodeId",
+            self.standby_node_id
+                .map_or(Value::Null, |node_id| node_id.to_string().into()),
+        );
+        entity.set("cost", self.cost);
+        vec![set_metadata_operation(Self::TYPENAME, id.as_str(), entity)]
+    }
+
+    /// Record whether block processing for deployment `id` is paused, so
+    /// that it stays paused when the deployment is started again
+    pub fn paused_operations(id: &SubgraphDeploymentId, paused: bool) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("paused", paused);
+        vec![update_metadata_operation(
+            Self::TYPENAME,
+            id.as_str(),
+            entity,
+        )]
+    }
+}
+
+/// An entry in the blocklist of deployments that must neither be deployed
+/// nor started
+#[derive(Debug)]
+pub struct SubgraphDeploymentBlockEntity {
+    reason: String,
+    created_at: u64,
+}
+
+impl TypedEntity for SubgraphDeploymentBlockEntity {
+    const TYPENAME: &'static str = "SubgraphDeploymentBlock";
+    type IdType = SubgraphDeploymentId;
+}
+
+impl SubgraphDeploymentBlockEntity {
+    pub fn new(reason: String, created_at: u64) -> Self {
+        Self { reason, created_at }
+    }
+
+    pub fn write_operations(self, id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("id", id.to_string());
+        entity.set("reason", self.reason);
+        entity.set("createdAt", self.created_at);
+        vec![set_metadata_operation(Self::TYPENAME, id.as_str(), entity)]
+    }
+}
+
+/// A scheduled removal of a deployment, once its subgraph has reached a
+/// block or a point in time has passed, whichever comes first
+#[derive(Debug)]
+pub struct SubgraphDeploymentRemovalEntity {
+    at_block: Option<u64>,
+    at_time: Option<u64>,
+}
+
+impl TypedEntity for SubgraphDeploymentRemovalEntity {
+    const TYPENAME: &'static str = "SubgraphDeploymentRemoval";
+    type IdType = SubgraphDeploymentId;
+}
+
+impl SubgraphDeploymentRemovalEntity {
+    /// `at_time` is in seconds since the epoch
+    pub fn new(at_block: Option<u64>, at_time: Option<u64>) -> Self {
+        Self { at_block, at_time }
+    }
+
+    pub fn write_operations(self, id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("id", id.to_string());
+        entity.set("atBlock", self.at_block.map_or(Value::Null, Value::from));
+        entity.set("atTime", self.at_time.map_or(Value::Null, Value::from));
+        entity.set("dueSince", Value::Null);
+        vec![set_metadata_operation(Self::TYPENAME, id.as_str(), entity)]
+    }
+
+    /// Record when the removal first became due, in seconds since the epoch
+    pub fn update_due_operations(
+        id: &SubgraphDeploymentId,
+        due_since: u64,
+    ) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("dueSince", due_since);
+
+        vec![update_metadata_operation(
+            Self::TYPENAME,
+            id.to_string(),
+            entity,
+        )]
+    }
+}
+
+/// The disk budget of a deployment, and whether the deployment currently
+/// exceeds it. Indexing pauses while the hard limit is exceeded
+#[derive(Debug)]
+pub struct SubgraphDeploymentQuotaEntity {
+    soft_limit: Option<u64>,
+    hard_limit: Option<u64>,
+}
+
+impl TypedEntity for SubgraphDeploymentQuotaEntity {
+    const TYPENAME: &'static str = "SubgraphDeploymentQuota";
+    type IdType = SubgraphDeploymentId;
+}
+
+impl SubgraphDeploymentQuotaEntity {
+    /// Limits are in bytes
+    pub fn new(soft_limit: Option<u64>, hard_limit: Option<u64>) -> Self {
+        Self {
+            soft_limit,
+            hard_limit,
+        }
+    }
+
+    /// Whether a deployment of `size` bytes exceeds `limit`
+    pub fn exceeds(size: u64, limit: Option<u64>) -> bool {
+        limit.map_or(false, |limit| size > limit)
+    }
+
+    /// Set the limits of the quota. `size` is the size of the deployment
+    /// as last measured, if it was measured; it is kept, and whether the
+    /// new limits are exceeded is derived from it
+    pub fn write_operations(
+        self,
+        id: &SubgraphDeploymentId,
+        size: Option<u64>,
+    ) -> Vec<MetadataOperation> {
+        let exceeded = |limit| size.map_or(false, |size| Self::exceeds(size, limit));
+        let mut entity = Entity::new();
+        entity.set("id", id.to_string());
+        entity.set(
+            "softLimit",
+            self.soft_limit.map_or(Value::Null, Value::from),
+        );
+        entity.set(
+            "hardLimit",
+            self.hard_limit.map_or(Value::Null, Value::from),
+        );
+        entity.set("softLimitExceeded", exceeded(self.soft_limit));
+        entity.set("hardLimitExceeded", exceeded(self.hard_limit));
+        vec![set_metadata_operation(Self::TYPENAME, id.as_str(), entity)]
+    }
+
+    /// Record the size of the deployment as last measured, and which of
+    /// its limits it exceeds
+    pub fn update_usage_operations(
+        id: &SubgraphDeploymentId,
+        size: u64,
+        soft_limit_exceeded: bool,
+        hard_limit_exceeded: bool,
+    ) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("size", size);
+        entity.set("softLimitExceeded", soft_limit_exceeded);
+        entity.set("hardLimitExceeded", hard_limit_exceeded);
+
+        vec![update_metadata_operation(
+            Self::TYPENAME,
+            id.to_string(),
+            entity,
+        )]
+    }
+}
+
+/// A deployment that has not been queried and has had no triggers for a
+/// while, and has been downgraded because of that, or that has not been
+/// queried for so long that it was hibernated
+#[derive(Debug)]
+pub struct SubgraphDeploymentIdleEntity {
+    since: u64,
+    paused: bool,
+    hibernated: bool,
+}
+
+impl TypedEntity for SubgraphDeploymentIdleEntity {
+    const TYPENAME: &'static str = "SubgraphDeploymentIdle";
+    type IdType = SubgraphDeploymentId;
+}
+
+impl SubgraphDeploymentIdleEntity {
+    /// `since` is in seconds since the epoch
+    pub fn new(since: u64, paused: bool, hibernated: bool) -> Self {
+        Self {
+            since,
+            paused,
+            hibernated,
+        }
+    }
+
+    pub fn write_operations(self, id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("id", id.to_string());
+        entity.set("since", self.since);
+        entity.set("paused", self.paused);
+        entity.set("hibernated", self.hibernated);
+        vec![set_metadata_operation(Self::TYPENAME, id.as_str(), entity)]
+    }
+
+    /// Record that deployment `id` is in use again
+    pub fn remove_operations(id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
+        vec![MetadataOperation::Remove {
+            entity: Self::TYPENAME.to_owned(),
+            id: id.to_string(),
+        }]
+    }
+}
+
+/// When any query node last recorded a query for a deployment. Query nodes
+/// only record queries once in a while, so this can be behind by that much
+#[derive(Debug)]
+pub struct SubgraphDeploymentQueriedEntity {
+    queried_at: u64,
+}
+
+impl TypedEntity for SubgraphDeploymentQueriedEntity {
+    const TYPENAME: &'static str = "SubgraphDeploymentQueried";
+    type IdType = SubgraphDeploymentId;
+}
+
+impl SubgraphDeploymentQueriedEntity {
+    /// `queried_at` is in seconds since the epoch
+    pub fn new(queried_at: u64) -> Self {
+        Self { queried_at }
+    }
+
+    pub fn write_operations(self, id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("id", id.to_string());
+        entity.set("queriedAt", self.queried_at);
+        vec![set_metadata_operation(Self::TYPENAME, id.as_str(), entity)]
+    }
+}
+
+/// Creating the attribute indexes of a deployment failed when it was
+/// started; the deployment runs without them, and queries that need them
+/// are slow, until creating them is retried successfully
+#[derive(Debug)]
+pub struct SubgraphDeploymentIndexFailureEntity {
+    error: String,
+    failed_at: u64,
+}
+
+impl TypedEntity for SubgraphDeploymentIndexFailureEntity {
+    const TYPENAME: &'static str = "SubgraphDeploymentIndexFailure";
+    type IdType = SubgraphDeploymentId;
+}
+
+impl SubgraphDeploymentIndexFailureEntity {
+    /// `failed_at` is in seconds since the epoch
+    pub fn new(error: String, failed_at: u64) -> Self {
+        Self { error, failed_at }
+    }
+
+    pub fn write_operations(self, id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("id", id.to_string());
+        entity.set("error", self.error);
+        entity.set("failedAt", self.failed_at);
+        vec![set_metadata_operation(Self::TYPENAME, id.as_str(), entity)]
+    }
+
+    /// Record that the attribute indexes of deployment `id` exist
+    pub fn remove_operations(id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
+        vec![MetadataOperation::Remove {
+            entity: Self::TYPENAME.to_owned(),
+            id: id.to_string(),
+        }]
+    }
+}
+
+/// A deployment that stays on a node when assignments are rebalanced
+#[derive(Debug)]
+pub struct SubgraphDeploymentPinEntity {
+    node_id: NodeId,
+}
+
+impl TypedEntity for SubgraphDeploymentPinEntity {
+    const TYPENAME: &'static str = "SubgraphDeploymentPin";
+    type IdType = SubgraphDeploymentId;
+}
+
+impl SubgraphDeploymentPinEntity {
+    pub fn new(node_id: NodeId) -> Self {
+        Self { node_id }
     }
 
     pub fn write_operations(self, id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
         let mut entity = Entity::new();
         entity.set("id", id.to_string());
         entity.set("nodeId", self.node_id.to_string());
+        vec![set_metadata_operation(Self::TYPENAME, id.as_str(), entity)]
+    }
+
+    /// Let assignments of deployment `id` be rebalanced again
+    pub fn remove_operations(id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
+        vec![MetadataOperation::Remove {
+            entity: Self::TYPENAME.to_owned(),
+            id: id.to_string(),
+        }]
+    }
+}
+
+/// How much work indexing a deployment is, as measured by the node that
+/// rebalances assignments. The block number and the number of entity
+/// writes of the deployment at `updated_at` are kept so that the next
+/// measurement can be made by any node
+#[derive(Debug)]
+pub struct SubgraphDeploymentCostEntity {
+    blocks_per_minute: u64,
+    entity_writes_per_minute: u64,
+    cost: u64,
+    updated_at: u64,
+    block_number: u64,
+    entity_write_count: u64,
+}
+
+impl TypedEntity for SubgraphDeploymentCostEntity {
+    const TYPENAME: &'static str = "SubgraphDeploymentCost";
+    type IdType = SubgraphDeploymentId;
+}
+
+impl SubgraphDeploymentCostEntity {
+    /// `updated_at` is in seconds since the epoch
+    pub fn new(
+        blocks_per_minute: u64,
+        entity_writes_per_minute: u64,
+        cost: u64,
+        updated_at: u64,
+        block_number: u64,
+        entity_write_count: u64,
+    ) -> Self {
+        Self {
+            blocks_per_minute,
+            entity_writes_per_minute,
+            cost,
+            updated_at,
+            block_number,
+            entity_write_count,
+        }
+    }
+
+    pub fn write_operations(self, id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("id", id.to_string());
+        entity.set("blocksPerMinute", self.blocks_per_minute);
+        entity.set("entityWritesPerMinute", self.entity_writes_per_minute);
         entity.set("cost", self.cost);
+        entity.set("updatedAt", self.updated_at);
+        entity.set("blockNumber", self.block_number);
+        entity.set("entityWriteCount", self.entity_write_count);
         vec![set_metadata_operation(Self::TYPENAME, id.as_str(), entity)]
     }
 }
 
+/// The node that currently measures costs and rebalances assignments, and
+/// until when it may do so without renewing the lease. There is only one
+/// such entity, with id `rebalance`
+#[derive(Debug)]
+pub struct SubgraphRebalanceLeaseEntity {
+    node_id: NodeId,
+    expires_at: u64,
+}
+
+impl TypedEntity for SubgraphRebalanceLeaseEntity {
+    const TYPENAME: &'static str = "SubgraphRebalanceLease";
+    type IdType = String;
+}
+
+impl SubgraphRebalanceLeaseEntity {
+    pub const ID: &'static str = "rebalance";
+
+    /// `expires_at` is in seconds since the epoch
+    pub fn new(node_id: NodeId, expires_at: u64) -> Self {
+        Self {
+            node_id,
+            expires_at,
+        }
+    }
+
+    pub fn write_operations(self) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("id", Self::ID);
+        entity.set("nodeId", self.node_id.to_string());
+        entity.set("expiresAt", self.expires_at);
+        vec![set_metadata_operation(Self::TYPENAME, Self::ID, entity)]
+    }
+}
+
+/// An entry in the audit log of operations performed through the admin
+/// API
+#[derive(Debug)]
+pub struct SubgraphAdminOperationEntity {
+    operation: String,
+    actor: Option<String>,
+    parameters: String,
+    error: Option<String>,
+    created_at: u64,
+}
+
+impl TypedEntity for SubgraphAdminOperationEntity {
+    const TYPENAME: &'static str = "SubgraphAdminOperation";
+    type IdType = String;
+}
+
+impl SubgraphAdminOperationEntity {
+    /// Record that `actor` performed `operation` with the given
+    /// `parameters`, which are serialized as JSON. `error` is `None` if
+    /// the operation succeeded
+    pub fn new(
+        operation: String,
+        actor: Option<String>,
+        parameters: String,
+        error: Option<String>,
+        created_at: u64,
+    ) -> Self {
+        Self {
+            operation,
+            actor,
+            parameters,
+            error,
+            created_at,
+        }
+    }
+
+    pub fn write_operations(self, id: &str) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("id", id);
+        entity.set("operation", self.operation);
+        entity.set("actor", self.actor.map_or(Value::Null, Value::from));
+        entity.set("parameters", self.parameters);
+        entity.set("error", self.error.map_or(Value::Null, Value::from));
+        entity.set("createdAt", self.created_at);
+        vec![set_metadata_operation(Self::TYPENAME, id, entity)]
+    }
+}
+
+/// A chain reorganization that the block ingestor of a network observed
+/// when it moved the chain head from `old_head` to `new_head`
+#[derive(Debug)]
+pub struct NetworkReorgEntity {
+    network: String,
+    depth: u64,
+    old_head: EthereumBlockPointer,
+    new_head: EthereumBlockPointer,
+    common_ancestor: Option<EthereumBlockPointer>,
+    detected_at: u64,
+}
+
+impl TypedEntity for NetworkReorgEntity {
+    const TYPENAME: &'static str = "NetworkReorg";
+    type IdType = String;
+}
+
+impl NetworkReorgEntity {
+    /// `common_ancestor` is `None` if the two chains did not meet within
+    /// the blocks that the block ingestor keeps
+    pub fn new(
+        network: String,
+        depth: u64,
+        old_head: EthereumBlockPointer,
+        new_head: EthereumBlockPointer,
+        common_ancestor: Option<EthereumBlockPointer>,
+        detected_at: u64,
+    ) -> Self {
+        Self {
+            network,
+            depth,
+            old_head,
+            new_head,
+            common_ancestor,
+            detected_at,
+        }
+    }
+
+    pub fn write_operations(self, id: &str) -> Vec<MetadataOperation> {
+        let mut entity = Entity::new();
+        entity.set("id", id);
+        entity.set("network", self.network);
+        entity.set("depth", self.depth as i32);
+        entity.set("oldHeadNumber", self.old_head.number);
+        entity.set("oldHeadHash", self.old_head.hash);
+        entity.set("newHeadNumber", self.new_head.number);
+        entity.set("newHeadHash", self.new_head.hash);
+        entity.set(
+            "commonAncestorNumber",
+            self.common_ancestor
+                .map_or(Value::Null, |ptr| Value::from(ptr.number)),
+        );
+        entity.set(
+            "commonAncestorHash",
+            self.common_ancestor
+                .map_or(Value::Null, |ptr| Value::from(ptr.hash)),
+        );
+        entity.set("detectedAt", self.detected_at);
+        vec![set_metadata_operation(Self::TYPENAME, id, entity)]
+    }
+
+    pub fn remove_operations(id: &str) -> Vec<MetadataOperation> {
+        vec![MetadataOperation::Remove {
+            entity: Self::TYPENAME.to_owned(),
+            id: id.to_owned(),
+        }]
+    }
+}
+
+/// An entry in the handler journal of a deployment, which records the
+/// handlers that ran for recent blocks
+#[derive(Debug)]
+pub struct SubgraphHandlerExecutionEntity {
+    deployment: SubgraphDeploymentId,
+    index: usize,
+    execution: HandlerExecution,
+}
+
+impl TypedEntity for SubgraphHandlerExecutionEntity {
+    const TYPENAME: &'static str = "SubgraphHandlerExecution";
+    type IdType = String;
+}
+
+impl SubgraphHandlerExecutionEntity {
+    /// `index` is the position of the execution among the handler
+    /// executions of its block
+    pub fn new(
+        deployment: SubgraphDeploymentId,
+        index: usize,
+        execution: HandlerExecution,
+    ) -> Self {
+        Self {
+            deployment,
+            index,
+            execution,
+        }
+    }
+
+    pub fn id(&self) -> String {
+        format!(
+            "{}-{}-{}",
+            self.deployment,
+            self.execution.block.hash_hex(),
+            self.index
+        )
+    }
+
+    pub fn write_operations(self, id: &str) -> Vec<MetadataOperation> {
+        WriteOperations::write_operations(self, id)
+    }
+
+    pub fn write_entity_operations(self, id: &str) -> Vec<EntityOperation> {
+        WriteOperations::write_entity_operations(self, id)
+    }
+
+    pub fn remove_operations(id: &str) -> Vec<MetadataOperation> {
+        vec![MetadataOperation::Remove {
+            entity: Self::TYPENAME.to_owned(),
+            id: id.to_owned(),
+        }]
+    }
+}
+
+impl WriteOperations for SubgraphHandlerExecutionEntity {
+    fn generate(self, id: &str, ops: &mut dyn OperationList) {
+        let mut entity = Entity::new();
+        entity.set("id", id);
+        entity.set("deployment", self.deployment.to_string());
+        entity.set("blockNumber", self.execution.block.number);
+        entity.set("blockHash", self.execution.block.hash);
+        entity.set("index", self.index as i32);
+        entity.set("dataSource", self.execution.data_source);
+        entity.set("handler", self.execution.handler);
+        entity.set("durationMs", self.execution.duration.as_millis() as u64);
+        entity.set(
+            "error",
+            self.execution.error.map_or(Value::Null, Value::from),
+        );
+        ops.add(Self::TYPENAME, id.to_owned(), entity);
+    }
+}
+
+/// A block that a deployment did not process the triggers of because an
+/// operator told it to skip the block
+#[derive(Debug)]
+pub struct SubgraphSkippedBlockEntity {
+    deployment: SubgraphDeploymentId,
+    block: EthereumBlockPointer,
+    skipped_triggers: usize,
+}
+
+impl TypedEntity for SubgraphSkippedBlockEntity {
+    const TYPENAME: &'static str = "SubgraphSkippedBlock";
+    type IdType = String;
+}
+
+impl SubgraphSkippedBlockEntity {
+    pub fn new(
+        deployment: SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+        skipped_triggers: usize,
+    ) -> Self {
+        Self {
+            deployment,
+            block,
+            skipped_triggers,
+        }
+    }
+
+    pub fn id(&self) -> String {
+        format!("{}-{}", self.deployment, self.block.hash_hex())
+    }
+
+    pub fn write_entity_operations(self, id: &str) -> Vec<EntityOperation> {
+        WriteOperations::write_entity_operations(self, id)
+    }
+}
+
+impl WriteOperations for SubgraphSkippedBlockEntity {
+    fn generate(self, id: &str, ops: &mut dyn OperationList) {
+        let mut entity = Entity::new();
+        entity.set("id", id);
+        entity.set("deployment", self.deployment.to_string());
+        entity.set("blockNumber", self.block.number);
+        entity.set("blockHash", self.block.hash);
+        entity.set("skippedTriggers", self.skipped_triggers as i32);
+        ops.add(Self::TYPENAME, id.to_owned(), entity);
+    }
+}
+
+/// A handler error that a deployment that opted into non-fatal errors
+/// continued indexing after. The changes of the block in which the error
+/// happened were discarded
+#[derive(Debug)]
+pub struct SubgraphErrorEntity {
+    deployment: SubgraphDeploymentId,
+    block: EthereumBlockPointer,
+    message: String,
+}
+
+impl TypedEntity for SubgraphErrorEntity {
+    const TYPENAME: &'static str = "SubgraphError";
+    type IdType = String;
+}
+
+impl SubgraphErrorEntity {
+    pub fn new(
+        deployment: SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+        message: String,
+    ) -> Self {
+        Self {
+            deployment,
+            block,
+            message,
+        }
+    }
+
+    pub fn id(&self) -> String {
+        format!("{}-{}", self.deployment, self.block.hash_hex())
+    }
+
+    pub fn write_entity_operations(self, id: &str) -> Vec<EntityOperation> {
+        WriteOperations::write_entity_operations(self, id)
+    }
+}
+
+impl WriteOperations for SubgraphErrorEntity {
+    fn generate(self, id: &str, ops: &mut dyn OperationList) {
+        let mut entity = Entity::new();
+        entity.set("id", id);
+        entity.set("deployment", self.deployment.to_string());
+        entity.set("blockNumber", self.block.number);
+        entity.set("blockHash", self.block.hash);
+        entity.set("message", self.message);
+        ops.add(Self::TYPENAME, id.to_owned(), entity);
+    }
+}
+
 #[derive(Debug)]
 pub struct SubgraphManifestEntity {
     spec_version: String,
@@ -419,12 +1156,18 @@ impl SubgraphManifestEntity {
         format!("{}-manifest", subgraph_id)
     }
 
+    /// The ID of the data source at `index` in the manifest with ID
+    /// `manifest_id`
+    pub fn data_source_id(manifest_id: &str, index: usize) -> String {
+        format!("{}-data-source-{}", manifest_id, index)
+    }
+
     fn write_operations(self, id: &str) -> Vec<MetadataOperation> {
         let mut ops = vec![];
 
         let mut data_source_ids: Vec<Value> = vec![];
         for (i, data_source) in self.data_sources.into_iter().enumerate() {
-            let data_source_id = format!("{}-data-source-{}", id, i);
+            let data_source_id = Self::data_source_id(id, i);
             ops.extend(data_source.write_operations(&data_source_id));
             data_source_ids.push(data_source_id.into());
         }
@@ -575,6 +1318,10 @@ pub struct DynamicEthereumContractDataSourceEntity {
 }
 
 impl DynamicEthereumContractDataSourceEntity {
+    pub fn write_operations(self, id: &str) -> Vec<MetadataOperation> {
+        WriteOperations::write_operations(self, id)
+    }
+
     pub fn write_entity_operations(self, id: &str) -> Vec<EntityOperation> {
         WriteOperations::write_entity_operations(self, id)
     }
@@ -17,6 +17,7 @@ pub mod log;
 pub mod mock {
     pub use crate::components::ethereum::MockEthereumAdapter;
     pub use crate::components::store::MockStore;
+    pub use crate::components::subgraph::MockSubgraphRegistrar;
 }
 
 /// Wrapper for spawning tasks that abort on panic, which is our default.
@@ -55,17 +56,21 @@ pub mod prelude {
     pub use crate::components::ethereum::{
         BlockFinality, BlockStream, BlockStreamBuilder, BlockStreamEvent, BlockStreamMetrics,
         ChainHeadUpdate, ChainHeadUpdateListener, ChainHeadUpdateStream, EthereumAdapter,
-        EthereumAdapterError, EthereumBlock, EthereumBlockData, EthereumBlockFilter,
-        EthereumBlockPointer, EthereumBlockTriggerType, EthereumBlockWithCalls,
-        EthereumBlockWithTriggers, EthereumCall, EthereumCallData, EthereumCallFilter,
-        EthereumContractCall, EthereumContractCallError, EthereumEventData, EthereumLogFilter,
-        EthereumNetworkIdentifier, EthereumTransactionData, EthereumTrigger, LightEthereumBlock,
-        LightEthereumBlockExt, ProviderEthRpcMetrics, SubgraphEthRpcMetrics,
+        EthereumAdapterCapabilities, EthereumAdapterError, EthereumBlock, EthereumBlockData,
+        EthereumBlockFilter, EthereumBlockPointer, EthereumBlockTriggerType,
+        EthereumBlockWithCalls, EthereumBlockWithTriggers, EthereumCall, EthereumCallData,
+        EthereumCallFilter, EthereumContractCall, EthereumContractCallError, EthereumEventData,
+        EthereumLogFilter, EthereumNetworkIdentifier, EthereumTransactionData, EthereumTrigger,
+        EthereumTriggerFilter, LightEthereumBlock, LightEthereumBlockExt, ProviderEthRpcMetrics,
+        SubgraphEthRpcMetrics,
     };
     pub use crate::components::graphql::{
         GraphQlRunner, QueryResultFuture, SubscriptionResultFuture,
     };
-    pub use crate::components::link_resolver::{JsonStreamValue, JsonValueStream, LinkResolver};
+    pub use crate::components::link_resolver::{
+        JsonStreamValue, JsonValueStream, LinkResolver, LinkResolverPriority,
+        LinkResolverRetryPolicy,
+    };
     pub use crate::components::metrics::{
         aggregate::Aggregate, stopwatch::StopwatchMetrics, Collector, Counter, CounterVec, Gauge,
         GaugeVec, Histogram, HistogramOpts, HistogramVec, MetricsRegistry, Opts, PrometheusError,
@@ -77,18 +82,25 @@ pub mod prelude {
     pub use crate::components::server::query::GraphQLServer;
     pub use crate::components::server::subscription::SubscriptionServer;
     pub use crate::components::store::{
-        AttributeIndexDefinition, BlockNumber, ChainStore, EntityCache, EntityChange,
-        EntityChangeOperation, EntityCollection, EntityFilter, EntityKey, EntityLink,
-        EntityModification, EntityOperation, EntityOrder, EntityQuery, EntityRange, EntityWindow,
-        EthereumCallCache, MetadataOperation, ParentLink, Store, StoreError, StoreEvent,
+        AggregateFunction, AttributeIndexDefinition, BlockEntityChange, BlockEntityChangeKind,
+        BlockNumber, ChainStore, ChildFilter, EntityAggregation, EntityCache, EntityChange,
+        EntityChangeOperation, EntityCollection, EntityCursor, EntityDiff, EntityFilter, EntityKey,
+        EntityLink, EntityModification, EntityOperation, EntityOrder, EntityQuery, EntityRange,
+        EntityWindow, EthereumCallCache, IndexCreation, MetadataOperation, NullsOrder,
+        OversizedEntityIds, ParentLink, ProofOfIndexing, Store, StoreError, StoreEvent,
         StoreEventStream, StoreEventStreamBox, SubgraphDeploymentStore, TransactionAbortError,
-        WindowAttribute, BLOCK_NUMBER_MAX, SUBSCRIPTION_THROTTLE_INTERVAL,
+        WindowAttribute, AGGREGATE_COUNT_ATTRIBUTE, BLOCK_NUMBER_MAX, BLOCK_NUMBER_PENDING,
+        SUBSCRIPTION_THROTTLE_INTERVAL,
     };
     pub use crate::components::subgraph::{
-        BlockState, DataSourceLoader, DataSourceTemplateInfo, HostMetrics, RuntimeHost,
-        RuntimeHostBuilder, SubgraphAssignmentProvider, SubgraphInstance, SubgraphInstanceManager,
-        SubgraphRegistrar, SubgraphVersionSwitchingMode,
+        AssignmentMove, BlockBudget, BlockState, CustomMetricUpdate, DataSourceLimit,
+        DataSourceLoader, DataSourceTemplateInfo, DeploymentActivity, HandlerExecution,
+        HandlerExecutionError, HandlerFailure, HandlerSimulation, HandlerSimulator, HostMetrics,
+        RuntimeHost, RuntimeHostBuilder, SimulatedChange, SimulationResult, StartupPhase,
+        StartupStatus, SubgraphAssignmentProvider, SubgraphInstance, SubgraphInstanceManager,
+        SubgraphRegistrar, SubgraphValidation, SubgraphVersionSwitchingMode, TransientError,
     };
+    pub use crate::components::trigger_filter::TriggerFilter;
     pub use crate::components::{EventConsumer, EventProducer};
 
     pub use crate::data::graphql::{SerializableValue, TryFromValue, ValueMap};
@@ -104,11 +116,12 @@ pub mod prelude {
     };
     pub use crate::data::subgraph::schema::{SubgraphDeploymentEntity, TypedEntity};
     pub use crate::data::subgraph::{
-        BlockHandlerFilter, CreateSubgraphResult, DataSource, DataSourceTemplate, Link, MappingABI,
-        MappingBlockHandler, MappingCallHandler, MappingEventHandler,
+        BlockHandlerFilter, CreateSubgraphResult, DataSource, DataSourceTemplate, DrainSignal,
+        Link, MappingABI, MappingBlockHandler, MappingCallHandler, MappingEventHandler,
         SubgraphAssignmentProviderError, SubgraphAssignmentProviderEvent, SubgraphDeploymentId,
         SubgraphManifest, SubgraphManifestResolveError, SubgraphManifestValidationError,
-        SubgraphName, SubgraphRegistrarError, UnvalidatedSubgraphManifest,
+        SubgraphManifestValidationWarning, SubgraphName, SubgraphRegistrarError,
+        UnvalidatedSubgraphManifest,
     };
     pub use crate::data::subscription::{
         QueryResultStream, Subscription, SubscriptionError, SubscriptionResult,
@@ -0,0 +1,33 @@
+use std::sync::{Arc, Mutex};
+
+use slog::*;
+
+/// An slog `Drain` that keeps the messages logged to it in memory.
+#[derive(Clone, Default)]
+pub struct CollectingDrain {
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+impl CollectingDrain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The messages logged so far, prefixed with their level
+    pub fn messages(&self) -> Vec<String> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+impl Drain for CollectingDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _: &OwnedKVList) -> std::result::Result<(), Never> {
+        self.messages
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", record.level().as_str(), record.msg()));
+        Ok(())
+    }
+}
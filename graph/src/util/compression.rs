@@ -0,0 +1,120 @@
+//! The compression formats that mappings can use. Compressed output depends
+//! on the version of the library that produced it, so anything that must be
+//! the same on every node, like the proof of indexing, only ever looks at
+//! the uncompressed payload.
+use failure::{format_err, Error};
+use std::fmt;
+use std::io::{Read, Write};
+
+/// The most bytes that decompressing may produce, and that compressing
+/// accepts. This is a constant rather than a setting since handlers must
+/// succeed or fail the same way on every node
+pub const MAX_DECOMPRESSED_BYTES: u64 = 16 * 1024 * 1024;
+
+const GZIP_LEVEL: u32 = 6;
+const ZSTD_LEVEL: i32 = 3;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    pub fn parse(codec: &str) -> Result<Self, Error> {
+        match codec {
+            "gzip" => Ok(Codec::Gzip),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(format_err!(
+                "Unknown compression codec `{}`, must be either `gzip` or `zstd`",
+                codec
+            )),
+        }
+    }
+
+    /// The codec that `bytes` look like they were compressed with, judging
+    /// by the magic number they start with
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(GZIP_MAGIC) {
+            Some(Codec::Gzip)
+        } else if bytes.starts_with(ZSTD_MAGIC) {
+            Some(Codec::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Decompress `bytes`. Payloads that are malformed or decompress to
+    /// more than `MAX_DECOMPRESSED_BYTES` are an error
+    pub fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let reader: Box<dyn Read + '_> = match self {
+            Codec::Gzip => Box::new(flate2::read::GzDecoder::new(bytes)),
+            Codec::Zstd => Box::new(zstd::stream::read::Decoder::new(bytes)?),
+        };
+
+        // Read one byte more than allowed so that we can tell when the
+        // output is too large without decompressing all of it
+        let mut output = Vec::new();
+        reader
+            .take(MAX_DECOMPRESSED_BYTES + 1)
+            .read_to_end(&mut output)
+            .map_err(|e| {
+                format_err!(
+                    "Failed to decompress {} bytes with {}: {}",
+                    bytes.len(),
+                    self,
+                    e
+                )
+            })?;
+        if output.len() as u64 > MAX_DECOMPRESSED_BYTES {
+            return Err(format_err!(
+                "Decompressing {} bytes with {} produces more than the maximum of {} bytes",
+                bytes.len(),
+                self,
+                MAX_DECOMPRESSED_BYTES
+            ));
+        }
+        Ok(output)
+    }
+
+    /// Compress `bytes` with a fixed compression level, so that the output
+    /// only depends on the input and the version of the compression library
+    pub fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        if bytes.len() as u64 > MAX_DECOMPRESSED_BYTES {
+            return Err(format_err!(
+                "Can not compress {} bytes, the maximum is {} bytes",
+                bytes.len(),
+                MAX_DECOMPRESSED_BYTES
+            ));
+        }
+
+        let result = match self {
+            Codec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(GZIP_LEVEL));
+                encoder.write_all(bytes).and_then(|()| encoder.finish())
+            }
+            Codec::Zstd => zstd::stream::encode_all(bytes, ZSTD_LEVEL),
+        };
+        result.map_err(|e| format_err!("Failed to compress bytes: {}", e))
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::Gzip => write!(f, "gzip"),
+            Codec::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// The uncompressed payload of `bytes` if they were compressed with one of
+/// the codecs, and `None` if they do not look compressed or do not
+/// decompress
+pub fn uncompressed(bytes: &[u8]) -> Option<Vec<u8>> {
+    Codec::detect(bytes).and_then(|codec| codec.decompress(bytes).ok())
+}
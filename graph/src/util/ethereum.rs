@@ -118,23 +118,125 @@ pub fn contract_event_with_signature<'a>(
         })
 }
 
+/// Returns an `operation(address,uint256,bool)` signature for a function.
+fn function_signature(function: &Function) -> String {
+    format!(
+        "{}({})",
+        function.name,
+        function
+            .inputs
+            .iter()
+            .map(|input| event_param_type_signature(&input.kind))
+            .collect::<Vec<String>>()
+            .join(",")
+    )
+}
+
 pub fn contract_function_with_signature<'a>(
     contract: &'a Contract,
     target_signature: &str,
 ) -> Option<&'a Function> {
-    contract.functions().find(|function| {
-        // Construct the argument function signature:
-        // `address,uint256,bool`
-        let mut arguments = function
-            .inputs
-            .iter()
-            .map(|input| format!("{}", input.kind))
-            .collect::<Vec<String>>()
-            .join(",");
-        // `address,uint256,bool)
-        arguments.push_str(")");
-        // `operation(address,uint256,bool)`
-        let actual_signature = vec![function.name.clone(), arguments].join("(");
-        !function.constant && target_signature == actual_signature
-    })
+    contract
+        .functions()
+        .find(|function| !function.constant && target_signature == function_signature(function))
+}
+
+/// Returns the name in an `Event(uint256)` or `operation(address)` signature.
+fn signature_name(signature: &str) -> &str {
+    signature.split('(').next().unwrap_or("").trim()
+}
+
+/// Describes the candidates for a signature that did not match, e.g.
+/// "the ABI declares `Transfer(indexed address,uint256)`".
+fn mismatch_hint(kind: &str, name: &str, candidates: Vec<String>) -> String {
+    if candidates.is_empty() {
+        format!("the ABI has no {} named `{}`", kind, name)
+    } else {
+        format!(
+            "the ABI declares {}",
+            candidates
+                .iter()
+                .map(|candidate| format!("`{}`", candidate))
+                .collect::<Vec<_>>()
+                .join(" and ")
+        )
+    }
+}
+
+/// Explains why `signature` does not match an event of the contract by
+/// listing the full signatures of the events with the same name.
+pub fn event_signature_mismatch(contract: &Contract, signature: &str) -> String {
+    let name = signature_name(signature);
+    let candidates = contract
+        .events()
+        .filter(|event| event.name == name)
+        .map(event_signature)
+        .collect();
+    mismatch_hint("event", name, candidates)
+}
+
+/// Explains why `signature` does not match a function of the contract that
+/// call handlers can be attached to, i.e., a non-constant function.
+pub fn function_signature_mismatch(contract: &Contract, signature: &str) -> String {
+    let name = signature_name(signature);
+    let candidates = contract
+        .functions()
+        .filter(|function| function.name == name && !function.constant)
+        .map(function_signature)
+        .collect();
+    mismatch_hint("non-constant function", name, candidates)
+}
+
+#[test]
+fn signature_mismatch_hints() {
+    let abi = r#"[
+        {"type": "event", "name": "Transfer", "anonymous": false, "inputs": [
+            {"name": "from", "type": "address", "indexed": true},
+            {"name": "value", "type": "uint256", "indexed": false}]},
+        {"type": "function", "name": "transfer", "constant": false, "outputs": [],
+         "inputs": [{"name": "to", "type": "address"}]},
+        {"type": "event", "name": "Filled", "anonymous": false, "inputs": [
+            {"name": "order", "type": "tuple", "indexed": false, "components": [
+                {"name": "amount", "type": "uint256"},
+                {"name": "maker", "type": "address"}]}]},
+        {"type": "function", "name": "fill", "constant": false, "outputs": [],
+         "inputs": [{"name": "order", "type": "tuple", "components": [
+             {"name": "amount", "type": "uint256"},
+             {"name": "maker", "type": "address"}]}]}
+    ]"#;
+    let contract = Contract::load(abi.as_bytes()).unwrap();
+
+    assert!(
+        contract_event_with_signature(&contract, "Transfer(indexed address,uint256)").is_some()
+    );
+    assert!(contract_event_with_signature(&contract, "Transfer(address,uint256)").is_some());
+    assert!(contract_event_with_signature(&contract, "Transfer(address,address)").is_none());
+    assert_eq!(
+        "the ABI declares `Transfer(indexed address,uint256)`",
+        event_signature_mismatch(&contract, "Transfer(address,address)")
+    );
+    assert_eq!(
+        "the ABI has no event named `Approval`",
+        event_signature_mismatch(&contract, "Approval(address)")
+    );
+
+    assert!(contract_function_with_signature(&contract, "transfer(address)").is_some());
+    assert_eq!(
+        "the ABI declares `transfer(address)`",
+        function_signature_mismatch(&contract, "transfer(address,uint256)")
+    );
+
+    // Tuples only match if their components do
+    assert!(contract_event_with_signature(&contract, "Filled((uint256,address))").is_some());
+    assert!(contract_event_with_signature(&contract, "Filled((address,uint256))").is_none());
+    assert_eq!(
+        "the ABI declares `Filled((uint256,address))`",
+        event_signature_mismatch(&contract, "Filled((address,uint256))")
+    );
+    assert!(contract_function_with_signature(&contract, "fill((uint256,address))").is_some());
+    assert!(contract_function_with_signature(&contract, "fill((uint256))").is_none());
+    assert_eq!(
+        "the ABI declares `fill((uint256,address))`",
+        function_signature_mismatch(&contract, "fill((uint256))")
+    );
 }
@@ -9,13 +9,16 @@ use std::time::Duration;
 use tokio_retry::strategy::{jitter, ExponentialBackoff};
 use tokio_retry::Retry;
 
+/// The longest time `retry` waits between attempts unless told otherwise
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
 /// Generic helper function for retrying async operations with built-in logging.
 ///
 /// To use this helper, do the following:
 ///
 /// 1. Call this function with an operation name (used for logging) and a `Logger`.
 /// 2. Optional: Chain a call to `.when(...)` to set a custom retry condition.
-/// 3. Optional: call `.log_after(...)` or `.no_logging()`.
+/// 3. Optional: call `.log_after(...)` or `.no_logging()`, and `.max_backoff(...)`.
 /// 4. Call either `.limit(...)` or `.no_limit()`.
 /// 5. Call one of `.timeout_secs(...)`, `.timeout_millis(...)`, `.timeout(...)`, and
 ///    `.no_timeout()`.
@@ -51,6 +54,7 @@ pub fn retry<I, E>(operation_name: impl ToString, logger: &Logger) -> RetryConfi
         logger: logger.to_owned(),
         condition: RetryIf::Error,
         log_after: 1,
+        max_backoff: DEFAULT_MAX_BACKOFF,
         limit: RetryConfigProperty::Unknown,
         phantom_item: PhantomData,
         phantom_error: PhantomData,
@@ -62,6 +66,7 @@ pub struct RetryConfig<I, E> {
     logger: Logger,
     condition: RetryIf<I, E>,
     log_after: u64,
+    max_backoff: Duration,
     limit: RetryConfigProperty<usize>,
     phantom_item: PhantomData<I>,
     phantom_error: PhantomData<E>,
@@ -97,6 +102,13 @@ where
         self
     }
 
+    /// Wait at most `max_backoff` between attempts. The default is 30
+    /// seconds.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
     /// Set a limit on how many retry attempts to make.
     pub fn limit(mut self, limit: usize) -> Self {
         self.limit.set(limit);
@@ -157,6 +169,7 @@ where
         let logger = self.inner.logger.clone();
         let condition = self.inner.condition;
         let log_after = self.inner.log_after;
+        let max_backoff = self.inner.max_backoff;
         let limit_opt = self.inner.limit.unwrap(&operation_name, "limit");
         let timeout = self.timeout;
 
@@ -167,6 +180,7 @@ where
             logger,
             condition,
             log_after,
+            max_backoff,
             limit_opt,
             move || {
                 try_it()
@@ -196,6 +210,7 @@ impl<I, E> RetryConfigNoTimeout<I, E> {
         let logger = self.inner.logger.clone();
         let condition = self.inner.condition;
         let log_after = self.inner.log_after;
+        let max_backoff = self.inner.max_backoff;
         let limit_opt = self.inner.limit.unwrap(&operation_name, "limit");
 
         trace!(logger, "Run with retry: {}", operation_name);
@@ -205,6 +220,7 @@ impl<I, E> RetryConfigNoTimeout<I, E> {
             logger,
             condition,
             log_after,
+            max_backoff,
             limit_opt,
             // No timeout, so all errors are inner errors
             move || try_it().map_err(TimeoutError::Inner),
@@ -245,6 +261,7 @@ fn run_retry<I, E, F, R>(
     logger: Logger,
     condition: RetryIf<I, E>,
     log_after: u64,
+    max_backoff: Duration,
     limit_opt: Option<usize>,
     try_it_with_timeout: F,
 ) -> impl Future<Item = I, Error = TimeoutError<E>> + Send
@@ -257,7 +274,7 @@ where
     let condition = Arc::new(condition);
 
     let mut attempt_count = 0;
-    Retry::spawn(retry_strategy(limit_opt), move || {
+    Retry::spawn(retry_strategy(limit_opt, max_backoff), move || {
         let operation_name = operation_name.clone();
         let logger = logger.clone();
         let condition = condition.clone();
@@ -320,11 +337,13 @@ where
     })
 }
 
-fn retry_strategy(limit_opt: Option<usize>) -> Box<dyn Iterator<Item = Duration> + Send> {
+fn retry_strategy(
+    limit_opt: Option<usize>,
+    max_backoff: Duration,
+) -> Box<dyn Iterator<Item = Duration> + Send> {
     // Exponential backoff, but with a maximum
-    let max_delay_ms = 30_000;
     let backoff = ExponentialBackoff::from_millis(2)
-        .max_delay(Duration::from_millis(max_delay_ms))
+        .max_delay(max_backoff)
         .map(jitter);
 
     // Apply limit (maximum retry count)
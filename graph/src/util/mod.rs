@@ -8,3 +8,6 @@ pub mod ethereum;
 pub mod security;
 
 pub mod lfu_cache;
+
+/// Compressing and decompressing bytes.
+pub mod compression;
@@ -199,3 +199,163 @@ fn consecutive_modifications() {
         },])
     );
 }
+
+#[test]
+fn conflicting_caches() {
+    let mut store = MockStore::new();
+    store.expect_get().returning(|_| Ok(None));
+
+    let (mogwai_key, mogwai_data) = make_band(
+        "mogwai",
+        vec![("id", "mogwai".into()), ("name", "Mogwai".into())],
+    );
+    let (sigurros_key, sigurros_data) = make_band(
+        "sigurros",
+        vec![("id", "sigurros".into()), ("name", "Sigur Ros".into())],
+    );
+
+    // Caches that change different entities don't conflict
+    let mut mogwai_cache = EntityCache::new();
+    mogwai_cache.track_reads();
+    mogwai_cache.set(mogwai_key.clone(), mogwai_data.clone());
+    let mut sigurros_cache = EntityCache::new();
+    sigurros_cache.track_reads();
+    sigurros_cache.set(sigurros_key.clone(), sigurros_data.clone());
+    assert!(!mogwai_cache.conflicts_with(&sigurros_cache));
+
+    // Reading an entity that the other cache changes is a conflict
+    sigurros_cache.get(&store, &mogwai_key).unwrap();
+    assert!(mogwai_cache.conflicts_with(&sigurros_cache));
+    assert!(sigurros_cache.conflicts_with(&mogwai_cache));
+
+    // Without read tracking, only changes can conflict
+    let mut reader = EntityCache::new();
+    reader.get(&store, &mogwai_key).unwrap();
+    assert!(!mogwai_cache.conflicts_with(&reader));
+
+    // Changing the same entity is a conflict
+    let mut other_mogwai_cache = EntityCache::new();
+    other_mogwai_cache.remove(mogwai_key);
+    assert!(mogwai_cache.conflicts_with(&other_mogwai_cache));
+}
+
+#[test]
+fn created_entity_ids() {
+    let mut store = MockStore::new();
+
+    // Only `sigurros` exists in the store
+    let (sigurros_key, sigurros_data) = make_band(
+        "sigurros",
+        vec![("id", "sigurros".into()), ("name", "Sigur Ros".into())],
+    );
+    let existing = sigurros_data.clone();
+    store.expect_get_many().returning(move |_, ids_for_type| {
+        let mut result = BTreeMap::new();
+        if ids_for_type["Band"].contains(&"sigurros") {
+            result.insert("Band".to_owned(), vec![existing.clone()]);
+        }
+        Ok(result)
+    });
+
+    let (mogwai_key, mogwai_data) = make_band(
+        "mogwai",
+        vec![("id", "mogwai".into()), ("name", "Mogwai".into())],
+    );
+    let (slint_key, slint_data) = make_band("slint", vec![("id", "slint".into())]);
+    let (tortoise_key, tortoise_data) = make_band("tortoise", vec![("id", "tortoise".into())]);
+    let subgraph_id = mogwai_key.subgraph_id.clone();
+
+    let mut cache = EntityCache::new();
+    cache.set(slint_key.clone(), slint_data);
+    cache.set(sigurros_key, sigurros_data);
+    cache.set(mogwai_key, mogwai_data);
+    cache.set(tortoise_key.clone(), tortoise_data);
+    cache.set(slint_key, Entity::from(vec![("name", "Slint".into())]));
+    cache.remove(tortoise_key);
+
+    // Entities are listed in the order in which they were created, without
+    // those that existed before or were removed
+    assert_eq!(
+        vec!["slint".to_owned(), "mogwai".to_owned()],
+        cache
+            .created_entity_ids(&store, &subgraph_id, "Band")
+            .unwrap()
+    );
+    assert!(cache
+        .created_entity_ids(&store, &subgraph_id, "Album")
+        .unwrap()
+        .is_empty());
+
+    // Listing entities conflicts with changes to any entity of that type
+    let (explosions_key, explosions_data) =
+        make_band("explosions", vec![("id", "explosions".into())]);
+    let mut other_cache = EntityCache::new();
+    other_cache.set(explosions_key, explosions_data);
+    assert!(cache.conflicts_with(&other_cache));
+}
+
+#[test]
+fn read_at_block() {
+    let mut store = MockStore::new();
+
+    // Reads at a block go through `find` so that they see the entities as
+    // they were at that block
+    let (sigurros_key, sigurros_data) = make_band(
+        "sigurros",
+        vec![("id", "sigurros".into()), ("name", "Sigur Ros".into())],
+    );
+    let existing = sigurros_data.clone();
+    store.expect_find().returning(move |query| {
+        assert_eq!(query.block, 7);
+        Ok(vec![existing.clone()])
+    });
+    store.expect_get().never();
+    store.expect_get_many().never();
+
+    let mut cache = EntityCache::new().at_block(7);
+    assert_eq!(
+        Some(sigurros_data),
+        cache.get(&store, &sigurros_key).unwrap()
+    );
+
+    cache.set(
+        sigurros_key.clone(),
+        Entity::from(vec![("name", "Jónsi".into())]),
+    );
+    let result = cache.as_modifications(&store);
+    assert_eq!(
+        result.unwrap().modifications,
+        vec![EntityModification::Overwrite {
+            key: sigurros_key,
+            data: Entity::from(vec![("id", "sigurros".into()), ("name", "Jónsi".into())]),
+        }]
+    );
+}
+
+#[test]
+fn forks_share_reads() {
+    let mut store = MockStore::new();
+    store.expect_get().times(1).returning(|_| Ok(None));
+    let (mogwai_key, mogwai_data) = make_band(
+        "mogwai",
+        vec![("id", "mogwai".into()), ("name", "Mogwai".into())],
+    );
+
+    // An entity that one fork read from the store is not read again by
+    // the other fork or by the cache they were forked from
+    let mut cache = EntityCache::new();
+    let mut fork = cache.fork();
+    let mut other_fork = cache.fork();
+    assert_eq!(None, fork.get(&store, &mogwai_key).unwrap());
+    other_fork.set(mogwai_key.clone(), mogwai_data.clone());
+    assert_eq!(
+        Some(mogwai_data),
+        other_fork.get(&store, &mogwai_key).unwrap()
+    );
+    assert!(fork.conflicts_with(&other_fork));
+
+    drop(fork);
+    drop(other_fork);
+    cache.unshare();
+    assert_eq!(None, cache.get(&store, &mogwai_key).unwrap());
+}
@@ -0,0 +1,77 @@
+use graph::prelude::{ChildFilter, Entity, EntityFilter, Value};
+
+fn band(name: Option<&str>) -> Entity {
+    let mut data = vec![("id", Value::from("mogwai"))];
+    if let Some(name) = name {
+        data.push(("name", Value::from(name)));
+    }
+    Entity::from(data)
+}
+
+fn matches(filter: EntityFilter, entity: &Entity) -> bool {
+    filter.matches(entity).unwrap()
+}
+
+#[test]
+fn negated_filters_do_not_match_null() {
+    let named = band(Some("Mogwai"));
+    let unnamed = band(None);
+    let name = || "name".to_owned();
+
+    assert!(matches(
+        EntityFilter::Not(name(), "Sigur Ros".into()),
+        &named
+    ));
+    assert!(!matches(
+        EntityFilter::Not(name(), "Sigur Ros".into()),
+        &unnamed
+    ));
+    assert!(matches(EntityFilter::Not(name(), Value::Null), &named));
+    assert!(!matches(EntityFilter::Not(name(), Value::Null), &unnamed));
+
+    assert!(!matches(
+        EntityFilter::NotIn(name(), vec!["Sigur Ros".into()]),
+        &unnamed
+    ));
+    assert!(matches(
+        EntityFilter::NotIn(name(), vec![Value::Null, "Mogwai".into()]),
+        &named
+    ));
+    assert!(!matches(
+        EntityFilter::NotContains(name(), "Ros".into()),
+        &unnamed
+    ));
+    assert!(!matches(
+        EntityFilter::NotStartsWith(name(), "Sig".into()),
+        &unnamed
+    ));
+    assert!(!matches(
+        EntityFilter::NotEndsWith(name(), "Ros".into()),
+        &unnamed
+    ));
+
+    // A filter that fails because of a null passes when it is negated
+    assert!(matches(
+        EntityFilter::Negation(Box::new(EntityFilter::Equal(name(), "Mogwai".into()))),
+        &unnamed
+    ));
+}
+
+#[test]
+fn child_filters_can_not_be_evaluated() {
+    let filter = EntityFilter::Child(ChildFilter {
+        attribute: "members".to_owned(),
+        entity_types: vec!["Musician".to_owned()],
+        derived: false,
+        list: true,
+        filter: Box::new(EntityFilter::Equal("name".to_owned(), "John".into())),
+    });
+    assert!(filter.matches(&band(Some("Mogwai"))).is_err());
+
+    // Short-circuiting does not hide the child filter when it matters
+    let filter = EntityFilter::Or(vec![
+        EntityFilter::Equal("name".to_owned(), "Sigur Ros".into()),
+        filter,
+    ]);
+    assert!(filter.matches(&band(Some("Mogwai"))).is_err());
+}
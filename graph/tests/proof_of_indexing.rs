@@ -0,0 +1,97 @@
+use graph::data::subgraph::schema::SUBGRAPHS_ID;
+use graph::prelude::web3::types::H256;
+use graph::prelude::{
+    Entity, EntityKey, EntityModification, EthereumBlockPointer, ProofOfIndexing,
+    SubgraphDeploymentId, Value,
+};
+use graph::util::compression::Codec;
+
+fn block(number: u64) -> EthereumBlockPointer {
+    EthereumBlockPointer {
+        hash: H256::from_low_u64_be(number),
+        number,
+    }
+}
+
+fn key(subgraph_id: &SubgraphDeploymentId, id: &str) -> EntityKey {
+    EntityKey {
+        subgraph_id: subgraph_id.clone(),
+        entity_type: "Band".to_owned(),
+        entity_id: id.to_owned(),
+    }
+}
+
+fn insert(id: &str, name: &str) -> EntityModification {
+    let subgraph_id = SubgraphDeploymentId::new("proofOfIndexing").unwrap();
+    EntityModification::Insert {
+        key: key(&subgraph_id, id),
+        data: Entity::from(vec![("id", Value::from(id)), ("name", Value::from(name))]),
+    }
+}
+
+fn digest(previous: Option<H256>, number: u64, mods: &[EntityModification]) -> H256 {
+    ProofOfIndexing::digest(previous, &block(number), mods).unwrap()
+}
+
+#[test]
+fn digest_does_not_depend_on_order_or_metadata() {
+    let mods = vec![insert("1", "Mogwai"), insert("2", "Sigur Ros")];
+    let reversed = vec![insert("2", "Sigur Ros"), insert("1", "Mogwai")];
+    assert_eq!(digest(None, 1, &mods), digest(None, 1, &reversed));
+
+    let mut with_metadata = mods.clone();
+    with_metadata.push(EntityModification::Insert {
+        key: key(&*SUBGRAPHS_ID, "meta"),
+        data: Entity::from(vec![("id", Value::from("meta"))]),
+    });
+    assert_eq!(digest(None, 1, &mods), digest(None, 1, &with_metadata));
+}
+
+#[test]
+fn digest_covers_data_block_and_previous_digest() {
+    let mods = vec![insert("1", "Mogwai")];
+    let base = digest(None, 1, &mods);
+
+    assert_ne!(base, digest(None, 1, &[insert("1", "Sigur Ros")]));
+    assert_ne!(base, digest(None, 1, &[insert("2", "Mogwai")]));
+    assert_ne!(base, digest(None, 1, &[]));
+    assert_ne!(base, digest(None, 2, &mods));
+    assert_ne!(base, digest(Some(H256::from_low_u64_be(7)), 1, &mods));
+
+    let removed = vec![EntityModification::Remove {
+        key: key(&SubgraphDeploymentId::new("proofOfIndexing").unwrap(), "1"),
+    }];
+    assert_ne!(digest(None, 1, &[]), digest(None, 1, &removed));
+
+    // The digest of a block depends on all earlier blocks
+    let second = digest(Some(base), 2, &[]);
+    assert_eq!(second, digest(Some(base), 2, &[]));
+    assert_ne!(second, digest(Some(digest(None, 1, &[])), 2, &[]));
+}
+
+#[test]
+fn digest_hashes_uncompressed_payload() {
+    let subgraph_id = SubgraphDeploymentId::new("proofOfIndexing").unwrap();
+    let insert = |value: Value| EntityModification::Insert {
+        key: key(&subgraph_id, "1"),
+        data: Entity::from(vec![("id", Value::from("1")), ("logo", value)]),
+    };
+    let payload = b"Mogwai Young Team".repeat(10);
+    let bytes = |bytes: Vec<u8>| Value::Bytes(bytes.as_slice().into());
+    let gzip = Codec::Gzip.compress(&payload).unwrap();
+    let zstd = Codec::Zstd.compress(&payload).unwrap();
+    assert_ne!(gzip, zstd);
+
+    let base = digest(None, 1, &[insert(bytes(payload.clone()))]);
+    assert_eq!(base, digest(None, 1, &[insert(bytes(gzip.clone()))]));
+    assert_eq!(base, digest(None, 1, &[insert(bytes(zstd.clone()))]));
+    assert_eq!(
+        digest(None, 1, &[insert(Value::List(vec![bytes(gzip)]))]),
+        digest(None, 1, &[insert(Value::List(vec![bytes(zstd)]))])
+    );
+
+    // Bytes that only look compressed are hashed as they are
+    let mut truncated = Codec::Gzip.compress(&payload).unwrap();
+    truncated.truncate(8);
+    assert_ne!(base, digest(None, 1, &[insert(bytes(truncated))]));
+}
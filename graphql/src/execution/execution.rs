@@ -14,11 +14,19 @@ use crate::introspection::INTROSPECTION_DOCUMENT;
 use crate::prelude::*;
 use crate::query::ast as qast;
 use crate::query::ext::FieldExt as _;
+use crate::schema::api::aggregated_entity_type;
 use crate::schema::ast as sast;
 use crate::values::coercion;
 
 lazy_static! {
     static ref NO_PREFETCH: bool = std::env::var_os("GRAPH_GRAPHQL_NO_PREFETCH").is_some();
+
+    /// The most entities one `<entity>Aggregates` field may aggregate
+    pub(crate) static ref MAX_AGGREGATE_ENTITIES: u32 =
+        std::env::var("GRAPH_GRAPHQL_MAX_AGGREGATE_ENTITIES")
+            .unwrap_or("10000".into())
+            .parse::<u32>()
+            .expect("invalid GRAPH_GRAPHQL_MAX_AGGREGATE_ENTITIES");
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -178,7 +186,8 @@ where
 
                             // `Scalar` and `Enum` cannot have selection sets.
                             // `InputObject` can't appear in a selection.
-                            // `Union` is not yet supported.
+                            // `Union` has no fields other than `__typename`;
+                            // the fields of its members are selected in fragments.
                             s::TypeDefinition::Scalar(_)
                             | s::TypeDefinition::Enum(_)
                             | s::TypeDefinition::InputObject(_)
@@ -186,14 +195,27 @@ where
                         }
                         .ok_or(Invalid)?;
 
+                        let field_type = get_named_type(schema, s_field.field_type.get_base_type())
+                            .ok_or(Invalid)?;
                         let field_complexity = self.query_complexity(
-                            &get_named_type(schema, s_field.field_type.get_base_type())
-                                .ok_or(Invalid)?,
+                            &field_type,
                             &field.selection_set,
                             max_depth,
                             depth + 1,
                         )?;
 
+                        // Aggregations look at up to `MAX_AGGREGATE_ENTITIES`
+                        // entities, however few groups they return
+                        let aggregated = match &field_type {
+                            s::TypeDefinition::Object(t) => aggregated_entity_type(t).is_some(),
+                            _ => false,
+                        };
+                        let aggregated = if aggregated {
+                            *MAX_AGGREGATE_ENTITIES as u64
+                        } else {
+                            0
+                        };
+
                         // Non-collection queries pass through.
                         if !sast::is_list_or_non_null_list_field(&s_field) {
                             return Ok(total_complexity + field_complexity);
@@ -206,10 +228,19 @@ where
                                 _ => None,
                             })
                             .unwrap_or(100);
+
+                        // Each filter on linked entities looks at the linked
+                        // entities of every entity, like selecting them would
+                        let nested_filters = qast::get_argument_value(&field.arguments, "where")
+                            .map_or(0, |filter| self.nested_filters(filter));
+                        let field_complexity = field_complexity
+                            .checked_add(nested_filters)
+                            .ok_or(Overflow)?;
                         max_entities
                             .checked_add(
                                 max_entities.checked_mul(field_complexity).ok_or(Overflow)?,
                             )
+                            .and_then(|complexity| complexity.checked_add(aggregated))
                             .ok_or(Overflow)
                     }
                     q::Selection::FragmentSpread(fragment) => {
@@ -233,6 +264,43 @@ where
             })
     }
 
+    /// The number of filters on linked entities, like `owner_: { .. }`, and
+    /// of `and`, `or` and `not` combinators in the filter `value`
+    fn nested_filters(&self, value: &q::Value) -> u64 {
+        match value {
+            q::Value::Variable(name) => self
+                .variable_values
+                .get(name)
+                .map_or(0, |value| self.nested_filters(value)),
+            q::Value::Object(object) => object
+                .iter()
+                .map(|(key, value)| {
+                    let nested = self.nested_filters(value);
+                    let combinator = key == sast::AND_FILTER
+                        || key == sast::OR_FILTER
+                        || key == sast::NOT_FILTER;
+                    let is_nested = match value {
+                        q::Value::Object(_) | q::Value::Variable(_) => {
+                            key.ends_with('_') || combinator
+                        }
+                        q::Value::List(_) => combinator,
+                        _ => false,
+                    };
+                    if is_nested {
+                        nested.saturating_add(1)
+                    } else {
+                        nested
+                    }
+                })
+                .fold(0, u64::saturating_add),
+            q::Value::List(values) => values
+                .iter()
+                .map(|value| self.nested_filters(value))
+                .fold(0, u64::saturating_add),
+            _ => 0,
+        }
+    }
+
     // Checks for invalid selections.
     pub(crate) fn validate_fields(
         &self,
@@ -252,13 +320,20 @@ where
                             s::TypeDefinition::Object(t) => get_field(t, &field.name),
                             s::TypeDefinition::Interface(t) => get_field(t, &field.name),
 
+                            // `Union` has no fields other than `__typename`;
+                            // the fields of its members are selected in fragments.
+                            s::TypeDefinition::Union(t) => self
+                                .schema
+                                .union_interfaces
+                                .get(&t.name)
+                                .filter(|_| field.name == "__typename")
+                                .and_then(|i| get_field(i, &field.name)),
+
                             // `Scalar` and `Enum` cannot have selection sets.
                             // `InputObject` can't appear in a selection.
-                            // `Union` is not yet supported.
                             s::TypeDefinition::Scalar(_)
                             | s::TypeDefinition::Enum(_)
-                            | s::TypeDefinition::InputObject(_)
-                            | s::TypeDefinition::Union(_) => None,
+                            | s::TypeDefinition::InputObject(_) => None,
                         };
 
                         match s_field {
@@ -754,7 +829,19 @@ where
             ctx.block,
         ),
 
-        s::TypeDefinition::Union(_) => Err(QueryExecutionError::Unimplemented("unions".to_owned())),
+        // Unions are resolved like an interface with the fields all their
+        // members share
+        s::TypeDefinition::Union(t) => union_interface(ctx, t).and_then(|union_interface| {
+            ctx.resolver.resolve_object(
+                object_value,
+                field,
+                field_definition,
+                union_interface.into(),
+                argument_values,
+                ctx.schema.types_for_interface(),
+                ctx.block,
+            )
+        }),
 
         s::TypeDefinition::InputObject(_) => unreachable!("input objects are never resolved"),
     }
@@ -790,6 +877,19 @@ where
                 .ok_or_else(|| QueryExecutionError::NamedTypeError(type_name.to_string()))?;
 
             match named_type {
+                // Aggregations are root fields; without a prefetched root
+                // value, the resolver has to compute them here
+                s::TypeDefinition::Object(t)
+                    if object_value.is_none() && aggregated_entity_type(t).is_some() =>
+                {
+                    ctx.resolver.resolve_aggregates(
+                        ctx,
+                        object_type,
+                        field,
+                        aggregated_entity_type(t).unwrap(),
+                    )
+                }
+
                 // Let the resolver decide how the list field (with the given item object type)
                 // is resolved into a entities based on the (potential) parent object
                 s::TypeDefinition::Object(t) => ctx
@@ -840,9 +940,20 @@ where
                     )
                     .map_err(|e| vec![e]),
 
-                s::TypeDefinition::Union(_) => Err(vec![QueryExecutionError::Unimplemented(
-                    "unions".to_owned(),
-                )]),
+                s::TypeDefinition::Union(t) => union_interface(ctx, t)
+                    .and_then(|union_interface| {
+                        ctx.resolver.resolve_objects(
+                            object_value,
+                            field,
+                            field_definition,
+                            union_interface.into(),
+                            argument_values,
+                            ctx.schema.types_for_interface(),
+                            ctx.block,
+                            ctx.max_first,
+                        )
+                    })
+                    .map_err(|e| vec![e]),
 
                 s::TypeDefinition::InputObject(_) => {
                     unreachable!("input objects are never resolved")
@@ -857,6 +968,20 @@ where
     }
 }
 
+/// The interface with the fields that all members of `union_type` share
+fn union_interface<'a, R>(
+    ctx: &'a ExecutionContext<'_, R>,
+    union_type: &s::UnionType,
+) -> Result<&'a s::InterfaceType, QueryExecutionError>
+where
+    R: Resolver,
+{
+    ctx.schema
+        .union_interfaces
+        .get(&union_type.name)
+        .ok_or_else(|| QueryExecutionError::NamedTypeError(union_type.name.clone()))
+}
+
 /// Ensures that a value matches the expected return type.
 fn complete_value<'a, R>(
     ctx: &ExecutionContext<'a, R>,
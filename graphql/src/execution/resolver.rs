@@ -103,6 +103,21 @@ pub trait Resolver: Clone + Send + Sync {
         block: BlockNumber,
     ) -> Result<q::Value, QueryExecutionError>;
 
+    /// Resolves the aggregations that the root field `field` asks for when
+    /// they were not prefetched. `entity_type` is the type it aggregates
+    fn resolve_aggregates<'a>(
+        &self,
+        _ctx: &ExecutionContext<'a, Self>,
+        _query_type: &s::ObjectType,
+        field: &q::Field,
+        _entity_type: &str,
+    ) -> Result<q::Value, Vec<QueryExecutionError>> {
+        Err(vec![QueryExecutionError::NotSupported(format!(
+            "Resolving `{}` is not supported by this resolver",
+            field.name
+        ))])
+    }
+
     /// Resolves an enum value for a given enum type.
     fn resolve_enum_value(
         &self,
@@ -291,12 +291,7 @@ fn union_type_object(schema: &Schema, union_type: &s::UnionType) -> q::Value {
             q::Value::List(
                 sast::get_object_type_definitions(&schema.document)
                     .iter()
-                    .filter(|object_type| {
-                        object_type
-                            .implements_interfaces
-                            .iter()
-                            .any(|implemented_name| implemented_name == &union_type.name)
-                    })
+                    .filter(|object_type| union_type.types.contains(&object_type.name))
                     .map(|object_type| q::Value::String(object_type.name.to_owned()))
                     .collect(),
             ),
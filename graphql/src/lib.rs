@@ -28,9 +28,12 @@ pub mod prelude {
     pub use super::execution::{ExecutionContext, ObjectOrInterface, Resolver};
     pub use super::introspection::{introspection_schema, IntrospectionResolver};
     pub use super::query::{
-        execute_query, ext::BlockConstraint, ext::BlockLocator, QueryExecutionOptions,
+        check_query, execute_query, ext::BlockConstraint, ext::BlockLocator,
+        validation::validate_strict, QueryExecutionOptions,
+    };
+    pub use super::schema::{
+        api_schema, api_schema_without_aggregations, ast::validate_entity, APISchemaError,
     };
-    pub use super::schema::{api_schema, ast::validate_entity, APISchemaError};
     pub use super::store::{build_query, StoreResolver};
     pub use super::subscription::{execute_subscription, SubscriptionExecutionOptions};
     pub use super::values::{object_value, MaybeCoercible};
@@ -38,6 +38,8 @@ impl ValueExt for q::Value {
 pub enum BlockLocator {
     Hash(H256),
     Number(BlockNumber),
+    /// The pending block on top of the subgraph's latest block
+    Pending,
 }
 
 pub struct BlockConstraint {
@@ -79,10 +81,21 @@ impl FieldExt for q::Field {
             if let q::Value::Object(map) = value {
                 let hash = map.get("hash");
                 let number = map.get("number");
-                if map.len() != 1 || (hash.is_none() && number.is_none()) {
+                let pending = map.get("pending");
+                if map.len() != 1 || (hash.is_none() && number.is_none() && pending.is_none()) {
                     return Err(invalid_argument("block", self, value));
                 }
                 let subgraph = parse_subgraph_id(object_type)?;
+                if let Some(pending_value) = pending {
+                    return match pending_value {
+                        q::Value::Boolean(true) => Ok(Some(BlockConstraint {
+                            subgraph,
+                            block: BlockLocator::Pending,
+                        })),
+                        q::Value::Boolean(false) => Ok(None),
+                        _ => Err(invalid_argument("block.pending", self, pending_value)),
+                    };
+                }
                 match (hash, number) {
                     (Some(hash), _) => TryFromValue::try_from_value(hash)
                         .map_err(|_| invalid_argument("block.hash", self, value))
@@ -13,6 +13,9 @@ pub mod ast;
 /// Extension traits
 pub mod ext;
 
+/// Strict validation of queries against the GraphQL spec
+pub mod validation;
+
 /// Options available for query execution.
 pub struct QueryExecutionOptions<R>
 where
@@ -35,6 +38,10 @@ where
 
     /// Maximum value for the `first` argument.
     pub max_first: u32,
+
+    /// The block at which fields without a `block` argument are resolved.
+    /// Use `BLOCK_NUMBER_MAX` to resolve them at the latest block.
+    pub block: BlockNumber,
 }
 
 /// Executes a query and returns a result.
@@ -81,7 +88,7 @@ where
         variable_values: Arc::new(coerced_variable_values),
         deadline: options.deadline,
         max_first: options.max_first,
-        block: BLOCK_NUMBER_MAX,
+        block: options.block,
         mode,
     };
 
@@ -127,3 +134,51 @@ where
         Err(e) => QueryResult::from(e),
     }
 }
+
+/// Checks that `query` stays within the complexity and depth limits in
+/// `options` without executing it. Errors that only executing the query
+/// would uncover are left to `execute_query`
+pub fn check_query<R>(
+    query: &Query,
+    options: QueryExecutionOptions<R>,
+) -> Result<(), Vec<QueryExecutionError>>
+where
+    R: Resolver,
+{
+    let operation = qast::get_operation(&query.document, None).map_err(|e| vec![e])?;
+    let coerced_variable_values =
+        coerce_variable_values(&query.schema, operation, &query.variables)?;
+
+    let ctx = ExecutionContext {
+        logger: options.logger,
+        resolver: Arc::new(options.resolver),
+        schema: query.schema.clone(),
+        document: &query.document,
+        fields: vec![],
+        variable_values: Arc::new(coerced_variable_values),
+        deadline: options.deadline,
+        max_first: options.max_first,
+        block: options.block,
+        mode: ExecutionMode::Prefetch,
+    };
+
+    match operation {
+        q::OperationDefinition::Query(q::Query { selection_set, .. })
+        | q::OperationDefinition::SelectionSet(selection_set) => {
+            let root_type = sast::get_root_query_type_def(&ctx.schema.document).unwrap();
+            let complexity = ctx
+                .root_query_complexity(root_type, selection_set, options.max_depth)
+                .map_err(|e| vec![e])?;
+            match options.max_complexity {
+                Some(max_complexity) if complexity > max_complexity => {
+                    Err(vec![QueryExecutionError::TooComplex(
+                        complexity,
+                        max_complexity,
+                    )])
+                }
+                _ => Ok(()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,144 @@
+use graphql_parser::query::*;
+use std::collections::{HashMap, HashSet};
+
+use graph::prelude::QueryExecutionError;
+
+/// Checks `document` for the violations of the GraphQL spec that query
+/// execution tolerates: variables that are defined more than once in an
+/// operation, fragments that are defined more than once, and fragments
+/// that no operation uses. Returns an empty list if there are none.
+pub fn validate_strict(document: &Document) -> Vec<QueryExecutionError> {
+    let mut errors = vec![];
+
+    let mut fragments = HashMap::new();
+    for definition in &document.definitions {
+        match definition {
+            Definition::Operation(operation) => {
+                let variable_definitions = match operation {
+                    OperationDefinition::Query(query) => &query.variable_definitions,
+                    OperationDefinition::Mutation(mutation) => &mutation.variable_definitions,
+                    OperationDefinition::Subscription(subscription) => {
+                        &subscription.variable_definitions
+                    }
+                    OperationDefinition::SelectionSet(_) => continue,
+                };
+                let mut names = HashSet::new();
+                for variable in variable_definitions {
+                    if !names.insert(&variable.name) {
+                        errors.push(QueryExecutionError::DuplicateVariableDefinition(
+                            variable.position,
+                            variable.name.clone(),
+                        ));
+                    }
+                }
+            }
+            Definition::Fragment(fragment) => {
+                if fragments.insert(&fragment.name, fragment).is_some() {
+                    errors.push(QueryExecutionError::DuplicateFragmentDefinition(
+                        fragment.position,
+                        fragment.name.clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    // Follow fragment spreads from the operations to find all fragments
+    // that are used, directly or through other fragments
+    let mut used = HashSet::new();
+    let mut pending: Vec<&SelectionSet> = document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Operation(OperationDefinition::Query(query)) => Some(&query.selection_set),
+            Definition::Operation(OperationDefinition::Mutation(mutation)) => {
+                Some(&mutation.selection_set)
+            }
+            Definition::Operation(OperationDefinition::Subscription(subscription)) => {
+                Some(&subscription.selection_set)
+            }
+            Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                Some(selection_set)
+            }
+            Definition::Fragment(_) => None,
+        })
+        .collect();
+    while let Some(selection_set) = pending.pop() {
+        for selection in &selection_set.items {
+            match selection {
+                Selection::Field(field) => pending.push(&field.selection_set),
+                Selection::InlineFragment(fragment) => pending.push(&fragment.selection_set),
+                Selection::FragmentSpread(spread) => {
+                    if used.insert(&spread.fragment_name) {
+                        if let Some(fragment) = fragments.get(&spread.fragment_name) {
+                            pending.push(&fragment.selection_set);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for definition in &document.definitions {
+        if let Definition::Fragment(fragment) = definition {
+            if !used.contains(&fragment.name) {
+                errors.push(QueryExecutionError::UnusedFragment(
+                    fragment.position,
+                    fragment.name.clone(),
+                ));
+            }
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use graphql_parser::parse_query;
+
+    use super::*;
+
+    fn violations(query: &str) -> Vec<String> {
+        validate_strict(&parse_query(query).unwrap())
+            .iter()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn accepts_valid_queries() {
+        assert!(violations(
+            "query($id: ID!) { user(id: $id) { ...Name } } \
+             fragment Name on User { name ...Friends } \
+             fragment Friends on User { friends { id } }"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn rejects_duplicate_variables() {
+        assert_eq!(
+            vec!["variable `$id` is defined more than once"],
+            violations("query($id: ID!, $id: ID!) { user(id: $id) { id } }")
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_and_unused_fragments() {
+        assert_eq!(
+            vec![
+                "fragment `Name` is defined more than once",
+                "fragment `Unused` is never used",
+                "fragment `OnlyUsedByUnused` is never used",
+            ],
+            violations(
+                "{ user { ...Name } } \
+                 fragment Name on User { name } \
+                 fragment Name on User { id } \
+                 fragment Unused on User { ...OnlyUsedByUnused } \
+                 fragment OnlyUsedByUnused on User { id }"
+            )
+        );
+    }
+}
@@ -1,4 +1,8 @@
+use std::collections::BTreeMap;
+
 use crate::schema::ast;
+use graph::data::graphql::ext::TypeExt;
+use graph::data::schema::{FulltextDefinition, SchemaValidationError, SCHEMA_TYPE_NAME};
 use graph::prelude::*;
 use graphql_parser::schema::{Value, *};
 use graphql_parser::Pos;
@@ -10,10 +14,85 @@ pub enum APISchemaError {
     TypeExists(String),
     #[fail(display = "Type {} not found", _0)]
     TypeNotFound(String),
+    #[fail(display = "{}", _0)]
+    InvalidUnion(SchemaValidationError),
 }
 
 const BLOCK_HEIGHT: &str = "Block_height";
 
+/// The name of the `Query` field that returns information about the
+/// deployment rather than entities, and the name of its type
+pub const META_FIELD_NAME: &str = "_meta";
+pub const META_FIELD_TYPE: &str = "_Meta_";
+
+/// The types that the `_meta` field returns. They are not entity types and
+/// are resolved from the deployment's metadata
+pub const META_TYPES: &[&str] = &[META_FIELD_TYPE, "_Block_", "_EntityType_"];
+
+/// The suffix of the names of the types that the `<entity>Aggregates`
+/// fields of the `Query` type return
+pub const AGGREGATE_TYPE_SUFFIX: &str = "_aggregate";
+
+/// If `object_type` is the type that the `<entity>Aggregates` field of the
+/// `Query` type returns, the name of the entity type it aggregates. Entity
+/// types themselves can have names that end in `AGGREGATE_TYPE_SUFFIX`,
+/// but unlike the generated types, they have an `@entity` directive
+pub fn aggregated_entity_type(object_type: &ObjectType) -> Option<&str> {
+    if object_type
+        .directives
+        .iter()
+        .any(|directive| directive.name == "entity")
+    {
+        return None;
+    }
+    if object_type.name.ends_with(AGGREGATE_TYPE_SUFFIX) {
+        Some(&object_type.name[..object_type.name.len() - AGGREGATE_TYPE_SUFFIX.len()])
+    } else {
+        None
+    }
+}
+
+/// The field that entity types get for the position of an entity in the
+/// collection it was queried from. Its value can be passed as the `after`
+/// or `before` argument of the collection to page through it
+pub const CURSOR_FIELD_NAME: &str = "cursor";
+
+/// The description of the generated `cursor` field, which tells it apart
+/// from `cursor` fields that entity types declare themselves
+const CURSOR_FIELD_DESCRIPTION: &str = "The position of this entity in the collection it was \
+                                        queried from, for the `after` and `before` arguments \
+                                        of the collection";
+
+/// Whether the entity type with `fields` has the generated `cursor` field
+/// in the API schema, which it has unless it declares a field with that
+/// name itself. `fields` can come from the input or the API schema
+pub fn has_generated_cursor(fields: &[Field]) -> bool {
+    fields
+        .iter()
+        .find(|field| field.name == CURSOR_FIELD_NAME)
+        .map_or(true, |field| {
+            field.description.as_ref().map(String::as_str) == Some(CURSOR_FIELD_DESCRIPTION)
+        })
+}
+
+const META_SCHEMA: &str = "
+type _Meta_ {
+  deployment: String!
+  block: _Block_
+  entityTypes: [_EntityType_!]!
+}
+
+type _Block_ {
+  number: Int!
+  hash: Bytes
+}
+
+type _EntityType_ {
+  name: String!
+  lastUpdatedBlock: Int!
+}
+";
+
 /// Derives a full-fledged GraphQL API schema from an input schema.
 ///
 /// The input schema should only have type/enum/interface/union definitions
@@ -21,21 +100,66 @@ const BLOCK_HEIGHT: &str = "Block_height";
 /// with all its fields and their input arguments, based on the existing
 /// types.
 pub fn api_schema(input_schema: &Document) -> Result<Document, APISchemaError> {
+    derive_api_schema(input_schema, true)
+}
+
+/// Derives the API schema like `api_schema`, but without the
+/// `<entity>Aggregates` fields, for deployments whose storage can not
+/// aggregate entities
+pub fn api_schema_without_aggregations(
+    input_schema: &Document,
+) -> Result<Document, APISchemaError> {
+    derive_api_schema(input_schema, false)
+}
+
+fn derive_api_schema(
+    input_schema: &Document,
+    aggregations: bool,
+) -> Result<Document, APISchemaError> {
     // Refactor: Take `input_schema` by value.
     let object_types = ast::get_object_type_definitions(input_schema);
+    let aggregated_types = if aggregations {
+        aggregatable_types(&object_types)
+    } else {
+        vec![]
+    };
     let interface_types = ast::get_interface_type_definitions(input_schema);
 
+    // Unions are queried like interfaces with the fields all their members
+    // have in common
+    let mut union_members = BTreeMap::new();
+    let union_interfaces = Schema::collect_unions(input_schema, &mut union_members)
+        .map_err(APISchemaError::InvalidUnion)?;
+    let union_types = union_interfaces.values().collect::<Vec<_>>();
+    let abstract_types = interface_types
+        .iter()
+        .chain(union_types.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+
     // Refactor: Don't clone the schema.
     let mut schema = input_schema.clone();
     add_directives(&mut schema);
     add_builtin_scalar_types(&mut schema)?;
     add_order_direction_enum(&mut schema);
+    add_order_nulls_enum(&mut schema);
     add_block_height_type(&mut schema);
+    add_meta_field_types(&mut schema)?;
     add_types_for_object_types(&mut schema, &object_types)?;
-    add_types_for_interface_types(&mut schema, &interface_types)?;
+    add_aggregate_types(&mut schema, &aggregated_types)?;
+    add_types_for_interface_types(&mut schema, &interface_types, &object_types)?;
+    add_types_for_union_types(&mut schema, &union_types, &union_members)?;
+    add_child_filter_fields(&mut schema, &object_types, &abstract_types);
+    add_cursor_fields(&mut schema, &object_types, &interface_types);
     add_field_arguments(&mut schema, &input_schema)?;
-    add_query_type(&mut schema, &object_types, &interface_types)?;
-    add_subscription_type(&mut schema, &object_types, &interface_types)?;
+    add_query_type(
+        &mut schema,
+        &object_types,
+        &abstract_types,
+        &aggregated_types,
+        &Schema::fulltext_definitions(input_schema),
+    )?;
+    add_subscription_type(&mut schema, &object_types, &abstract_types)?;
     Ok(schema)
 }
 
@@ -109,9 +233,31 @@ fn add_directives(schema: &mut Document) {
         locations: vec![DirectiveLocation::Object],
     });
 
+    let input_value = |name: &str, type_name: &str| InputValue {
+        position: Pos::default(),
+        description: None,
+        name: name.to_owned(),
+        value_type: Type::NamedType(type_name.to_owned()),
+        default_value: None,
+        directives: vec![],
+    };
+    let partition = Definition::DirectiveDefinition(DirectiveDefinition {
+        position: Pos::default(),
+        description: None,
+        name: "partition".to_owned(),
+        arguments: vec![
+            input_value("by", "String"),
+            input_value("size", "Int"),
+            input_value("field", "String"),
+            input_value("count", "Int"),
+        ],
+        locations: vec![DirectiveLocation::Object],
+    });
+
     schema.definitions.push(entity);
     schema.definitions.push(derived_from);
     schema.definitions.push(subgraph_id);
+    schema.definitions.push(partition);
 }
 
 /// Adds a global `OrderDirection` type to the schema.
@@ -135,6 +281,28 @@ fn add_order_direction_enum(schema: &mut Document) {
     schema.definitions.push(def);
 }
 
+/// Adds a global `OrderNulls` type to the schema. It controls whether
+/// entities whose `orderBy` attribute is null come first or last
+fn add_order_nulls_enum(schema: &mut Document) {
+    let typedef = TypeDefinition::Enum(EnumType {
+        position: Pos::default(),
+        description: None,
+        name: "OrderNulls".to_string(),
+        directives: vec![],
+        values: ["first", "last"]
+            .iter()
+            .map(|name| EnumValue {
+                position: Pos::default(),
+                description: None,
+                name: name.to_string(),
+                directives: vec![],
+            })
+            .collect(),
+    });
+    let def = Definition::TypeDefinition(typedef);
+    schema.definitions.push(def);
+}
+
 /// Adds a global `Block_height` type to the schema. The `block` argument
 /// accepts values of this type
 fn add_block_height_type(schema: &mut Document) {
@@ -160,19 +328,174 @@ fn add_block_height_type(schema: &mut Document) {
                 default_value: None,
                 directives: vec![],
             },
+            InputValue {
+                position: Pos::default(),
+                description: None,
+                name: "pending".to_owned(),
+                value_type: Type::NamedType("Boolean".to_owned()),
+                default_value: None,
+                directives: vec![],
+            },
         ],
     });
     let def = Definition::TypeDefinition(typedef);
     schema.definitions.push(def);
 }
 
+/// Adds the types that the `_meta` field of the `Query` type returns
+fn add_meta_field_types(schema: &mut Document) -> Result<(), APISchemaError> {
+    let meta_schema = parse_schema(META_SCHEMA).expect("the schema of `_meta` is invalid");
+    for definition in meta_schema.definitions {
+        if let Definition::TypeDefinition(TypeDefinition::Object(ref object_type)) = definition {
+            if ast::get_named_type(schema, &object_type.name).is_some() {
+                return Err(APISchemaError::TypeExists(object_type.name.clone()));
+            }
+        }
+        schema.definitions.push(definition);
+    }
+    Ok(())
+}
+
 fn add_types_for_object_types(
     schema: &mut Document,
     object_types: &Vec<&ObjectType>,
 ) -> Result<(), APISchemaError> {
     for object_type in object_types {
         add_order_by_type(schema, &object_type.name, &object_type.fields)?;
-        add_filter_type(schema, &object_type.name, &object_type.fields)?;
+        add_filter_type(schema, &object_type.name, &object_type.fields, &[])?;
+    }
+    Ok(())
+}
+
+/// The object types whose entities can be aggregated
+fn aggregatable_types<'a>(object_types: &[&'a ObjectType]) -> Vec<&'a ObjectType> {
+    object_types
+        .iter()
+        .filter(|object_type| object_type.name != SCHEMA_TYPE_NAME)
+        .cloned()
+        .collect()
+}
+
+/// Adds a `<type_name>_groupBy` enum and a `<type_name>_aggregate` type for
+/// each of the aggregatable `object_types` to the schema. The aggregate type has a `count` field,
+/// a field for each field that entities can be grouped by, and fields for
+/// the sum, minimum, maximum, and average of each numeric field. Entities
+/// can be grouped by fields that are neither lists nor derived, other than
+/// their `id`; references are grouped by the `ID` of the entity they
+/// reference.
+fn add_aggregate_types(
+    schema: &mut Document,
+    object_types: &[&ObjectType],
+) -> Result<(), APISchemaError> {
+    for object_type in object_types {
+        let numeric = object_type
+            .fields
+            .iter()
+            .filter(|field| {
+                !ast::is_list_or_non_null_list_field(field)
+                    && ["Int", "BigInt", "BigDecimal"]
+                        .contains(&ast::get_field_name(&field.field_type).as_str())
+            })
+            .collect::<Vec<_>>();
+        let group_by = object_type
+            .fields
+            .iter()
+            .filter(|field| {
+                field.name != "id"
+                    && field.name != AGGREGATE_COUNT_ATTRIBUTE
+                    && !ast::is_list_or_non_null_list_field(field)
+                    && ast::get_derived_from_directive(field).is_none()
+                    && !numeric.iter().any(|numeric| {
+                        AggregateFunction::ALL
+                            .iter()
+                            .any(|function| function.attribute(&numeric.name) == field.name)
+                    })
+            })
+            .map(|field| {
+                let type_name = ast::get_field_name(&field.field_type);
+                let field_type = match ast::get_named_type(schema, &type_name) {
+                    Some(TypeDefinition::Object(_))
+                    | Some(TypeDefinition::Interface(_))
+                    | Some(TypeDefinition::Union(_)) => "ID".to_owned(),
+                    _ => type_name,
+                };
+                (field.name.clone(), field_type)
+            })
+            .collect::<Vec<_>>();
+
+        let group_by_name = format!("{}_groupBy", object_type.name);
+        if !group_by.is_empty() {
+            if ast::get_named_type(schema, &group_by_name).is_some() {
+                return Err(APISchemaError::TypeExists(group_by_name));
+            }
+            schema
+                .definitions
+                .push(Definition::TypeDefinition(TypeDefinition::Enum(EnumType {
+                    position: Pos::default(),
+                    description: None,
+                    name: group_by_name,
+                    directives: vec![],
+                    values: group_by
+                        .iter()
+                        .map(|(name, _)| EnumValue {
+                            position: Pos::default(),
+                            description: None,
+                            name: name.clone(),
+                            directives: vec![],
+                        })
+                        .collect(),
+                })));
+        }
+
+        let aggregate_name = format!("{}{}", object_type.name, AGGREGATE_TYPE_SUFFIX);
+        if ast::get_named_type(schema, &aggregate_name).is_some() {
+            return Err(APISchemaError::TypeExists(aggregate_name));
+        }
+        let field = |name: String, field_type: Type| Field {
+            position: Pos::default(),
+            description: None,
+            name,
+            arguments: vec![],
+            field_type,
+            directives: vec![],
+        };
+        let mut fields = vec![field(
+            AGGREGATE_COUNT_ATTRIBUTE.to_owned(),
+            Type::NonNullType(Box::new(Type::NamedType("Int".to_owned()))),
+        )];
+        for (name, field_type) in group_by {
+            fields.push(field(name, Type::NamedType(field_type)));
+        }
+        for numeric in numeric {
+            let type_name = ast::get_field_name(&numeric.field_type);
+            for function in AggregateFunction::ALL.iter() {
+                let field_type = match (function, type_name.as_str()) {
+                    (AggregateFunction::Avg, _) => "BigDecimal",
+                    (AggregateFunction::Sum, "Int") => "BigInt",
+                    (_, type_name) => type_name,
+                };
+                fields.push(field(
+                    function.attribute(&numeric.name),
+                    Type::NamedType(field_type.to_owned()),
+                ));
+            }
+        }
+        schema
+            .definitions
+            .push(Definition::TypeDefinition(TypeDefinition::Object(
+                ObjectType {
+                    position: Pos::default(),
+                    description: Some(format!(
+                        "The number of `{}` entities in a group and aggregations \
+                         of their numeric fields",
+                        object_type.name
+                    )),
+                    name: aggregate_name,
+                    implements_interfaces: vec![],
+                    directives: vec![],
+                    fields,
+                },
+            )));
     }
     Ok(())
 }
@@ -181,14 +504,80 @@ fn add_types_for_object_types(
 fn add_types_for_interface_types(
     schema: &mut Document,
     interface_types: &[&InterfaceType],
+    object_types: &[&ObjectType],
 ) -> Result<(), APISchemaError> {
     for interface_type in interface_types {
+        let implementers = object_types
+            .iter()
+            .filter(|object_type| {
+                object_type
+                    .implements_interfaces
+                    .contains(&interface_type.name)
+            })
+            .map(|object_type| *object_type)
+            .collect::<Vec<_>>();
         add_order_by_type(schema, &interface_type.name, &interface_type.fields)?;
-        add_filter_type(schema, &interface_type.name, &interface_type.fields)?;
+        add_filter_type(
+            schema,
+            &interface_type.name,
+            &interface_type.fields,
+            &implementers,
+        )?;
+    }
+    Ok(())
+}
+
+/// Adds `*_orderBy` and `*_filter` types for the given unions to the schema.
+/// The `union_types` are the interfaces with the fields that the members of
+/// each union share, and `union_members` maps each union to its members.
+fn add_types_for_union_types(
+    schema: &mut Document,
+    union_types: &[&InterfaceType],
+    union_members: &BTreeMap<Name, Vec<ObjectType>>,
+) -> Result<(), APISchemaError> {
+    for union_type in union_types {
+        let members = union_members[&union_type.name].iter().collect::<Vec<_>>();
+        add_order_by_type(schema, &union_type.name, &union_type.fields)?;
+        add_filter_type(schema, &union_type.name, &union_type.fields, &members)?;
     }
     Ok(())
 }
 
+/// Adds the `cursor` field to the given object and interface types unless
+/// they have a field with that name already
+fn add_cursor_fields(
+    schema: &mut Document,
+    object_types: &[&ObjectType],
+    interface_types: &[&InterfaceType],
+) {
+    let cursor_field = || Field {
+        position: Pos::default(),
+        description: Some(CURSOR_FIELD_DESCRIPTION.to_owned()),
+        name: CURSOR_FIELD_NAME.to_owned(),
+        arguments: vec![],
+        field_type: Type::NonNullType(Box::new(Type::NamedType("String".to_owned()))),
+        directives: vec![],
+    };
+    for object_type in object_types {
+        if object_type.name == SCHEMA_TYPE_NAME || !has_generated_cursor(&object_type.fields) {
+            continue;
+        }
+        ast::get_object_type_mut(schema, &object_type.name)
+            .expect("object type from input schema is missing in API schema")
+            .fields
+            .push(cursor_field());
+    }
+    for interface_type in interface_types {
+        if !has_generated_cursor(&interface_type.fields) {
+            continue;
+        }
+        ast::get_interface_type_mut(schema, &interface_type.name)
+            .expect("interface type from input schema is missing in API schema")
+            .fields
+            .push(cursor_field());
+    }
+}
+
 /// Adds a `<type_name>_orderBy` enum type for the given fields to the schema.
 fn add_order_by_type(
     schema: &mut Document,
@@ -224,15 +613,20 @@ fn add_order_by_type(
 }
 
 /// Adds a `<type_name>_filter` enum type for the given fields to the schema.
+/// For interfaces, `implementers` are the object types that implement the
+/// interface; the filter gets a field `_on_<Object>` for each of them that
+/// restricts entities of that type with the `<Object>_filter`.
 fn add_filter_type(
     schema: &mut Document,
     type_name: &Name,
     fields: &[Field],
+    implementers: &[&ObjectType],
 ) -> Result<(), APISchemaError> {
     let filter_type_name = format!("{}_filter", type_name).to_string();
     match ast::get_named_type(schema, &filter_type_name) {
         None => {
-            let input_values = field_input_values(schema, fields)?;
+            let mut input_values = field_input_values(schema, fields)?;
+            input_values.extend(type_condition_input_values(schema, implementers));
 
             // Don't generate an input object with no fields, this makes the JS
             // graphql library, which graphiql uses, very confused and graphiql
@@ -241,12 +635,13 @@ fn add_filter_type(
             if input_values.is_empty() {
                 return Ok(());
             }
+            input_values.extend(logical_input_values(&filter_type_name, fields));
             let typedef = TypeDefinition::InputObject(InputObjectType {
                 position: Pos::default(),
                 description: None,
                 name: filter_type_name,
                 directives: vec![],
-                fields: field_input_values(schema, fields)?,
+                fields: input_values,
             });
             let def = Definition::TypeDefinition(typedef);
             schema.definitions.push(def);
@@ -257,6 +652,78 @@ fn add_filter_type(
     Ok(())
 }
 
+/// Generates the `and`, `or` and `not` input values for the filter
+/// `filter_type_name`, which combine filters of that type. Combinators whose
+/// name is also the name of one of the `fields` are left out
+fn logical_input_values(filter_type_name: &Name, fields: &[Field]) -> Vec<InputValue> {
+    let filter_type = || Type::NonNullType(Box::new(Type::NamedType(filter_type_name.clone())));
+    vec![
+        (ast::AND_FILTER, Type::ListType(Box::new(filter_type()))),
+        (ast::OR_FILTER, Type::ListType(Box::new(filter_type()))),
+        (ast::NOT_FILTER, Type::NamedType(filter_type_name.clone())),
+    ]
+    .into_iter()
+    .filter(|(name, _)| fields.iter().all(|field| &field.name != name))
+    .map(|(name, value_type)| input_value(&name.to_owned(), "", value_type))
+    .collect()
+}
+
+/// Adds a `<field>_` input value to the filters of the given types for
+/// each of their fields that link to other entities, so that entities can
+/// be filtered by the entities they link to, as in
+/// `where: { owner_: { balance_gt: 100 } }`. The filters of different
+/// types refer to each other, and this therefore has to run after all
+/// filter types have been added.
+fn add_child_filter_fields(
+    schema: &mut Document,
+    object_types: &[&ObjectType],
+    interface_types: &[&InterfaceType],
+) {
+    let types = object_types
+        .iter()
+        .filter(|object_type| object_type.name != SCHEMA_TYPE_NAME)
+        .map(|object_type| (&object_type.name, &object_type.fields))
+        .chain(
+            interface_types
+                .iter()
+                .map(|interface_type| (&interface_type.name, &interface_type.fields)),
+        );
+    for (type_name, fields) in types {
+        let input_values = fields
+            .iter()
+            .filter_map(|field| {
+                // A field called `<field>_` already has a filter of that name
+                let name = format!("{}_", field.name);
+                if fields.iter().any(|other| other.name == name) {
+                    return None;
+                }
+                let child_type = field.field_type.get_base_type();
+                match ast::get_named_type(schema, child_type) {
+                    Some(TypeDefinition::Object(_))
+                    | Some(TypeDefinition::Interface(_))
+                    | Some(TypeDefinition::Union(_)) => (),
+                    _ => return None,
+                }
+                let child_filter_type_name = format!("{}_filter", child_type);
+                ast::get_named_type(schema, &child_filter_type_name).map(|_| InputValue {
+                    position: Pos::default(),
+                    description: None,
+                    name,
+                    value_type: Type::NamedType(child_filter_type_name),
+                    default_value: None,
+                    directives: vec![],
+                })
+            })
+            .collect::<Vec<_>>();
+        let filter_type_name = format!("{}_filter", type_name);
+        if let Some(TypeDefinition::InputObject(filter_type)) =
+            ast::get_named_type_definition_mut(schema, &filter_type_name)
+        {
+            filter_type.fields.extend(input_values);
+        }
+    }
+}
+
 /// Generates `*_filter` input values for the given set of fields.
 fn field_input_values(
     schema: &Document,
@@ -273,6 +740,26 @@ fn field_input_values(
     Ok(input_values)
 }
 
+/// Generates the `_on_<Object>` input values for the filter of an interface
+/// that the object types `implementers` implement. Object types that have
+/// no filter type, because none of their fields can be filtered, are skipped.
+fn type_condition_input_values(schema: &Document, implementers: &[&ObjectType]) -> Vec<InputValue> {
+    implementers
+        .iter()
+        .filter_map(|object_type| {
+            let filter_type_name = format!("{}_filter", object_type.name);
+            ast::get_named_type(schema, &filter_type_name).map(|_| InputValue {
+                position: Pos::default(),
+                description: None,
+                name: format!("{}{}", ast::TYPE_CONDITION_PREFIX, object_type.name),
+                value_type: Type::NamedType(filter_type_name),
+                default_value: None,
+                directives: vec![],
+            })
+        })
+        .collect()
+}
+
 /// Generates `*_filter` input values for the given field.
 fn field_filter_input_values(
     schema: &Document,
@@ -363,21 +850,19 @@ fn field_enum_filter_input_values(
     field: &Field,
     field_type: &EnumType,
 ) -> Vec<InputValue> {
-    vec![
-        Some(input_value(
-            &field.name,
-            "",
-            Type::NamedType(field_type.name.to_owned()),
-        )),
-        Some(input_value(
-            &field.name,
-            "not",
-            Type::NamedType(field_type.name.to_owned()),
-        )),
-    ]
-    .into_iter()
-    .filter_map(|value_opt| value_opt)
-    .collect()
+    vec!["", "not", "in", "not_in"]
+        .into_iter()
+        .map(|filter_type| {
+            let field_type = Type::NamedType(field_type.name.to_owned());
+            let value_type = match filter_type {
+                "in" | "not_in" => {
+                    Type::ListType(Box::new(Type::NonNullType(Box::new(field_type))))
+                }
+                _ => field_type,
+            };
+            input_value(&field.name, filter_type, value_type)
+        })
+        .collect()
 }
 
 /// Generates `*_filter` input values for the given list field.
@@ -405,7 +890,7 @@ fn field_list_filter_input_values(
         };
 
         Some(
-            vec!["", "not", "contains", "not_contains"]
+            vec!["", "not", "contains", "contains_any", "not_contains"]
                 .into_iter()
                 .map(|filter_type| {
                     input_value(
@@ -437,11 +922,14 @@ fn input_value(name: &Name, suffix: &'static str, value_type: Type) -> InputValu
     }
 }
 
-/// Adds a root `Query` object type to the schema.
+/// Adds a root `Query` object type to the schema. The `interface_types`
+/// include the interfaces that unions are queried as.
 fn add_query_type(
     schema: &mut Document,
     object_types: &[&ObjectType],
     interface_types: &[&InterfaceType],
+    aggregated_types: &[&ObjectType],
+    fulltext: &[FulltextDefinition],
 ) -> Result<(), APISchemaError> {
     let type_name = String::from("Query");
 
@@ -460,6 +948,17 @@ fn add_query_type(
             .map(|t| &t.name)
             .chain(interface_types.iter().map(|t| &t.name))
             .flat_map(|name| query_fields_for_type(schema, name))
+            .chain(
+                fulltext
+                    .iter()
+                    .map(|definition| fulltext_query_field(schema, definition)),
+            )
+            .chain(
+                aggregated_types
+                    .iter()
+                    .map(|object_type| aggregate_query_field(schema, &object_type.name)),
+            )
+            .chain(Some(meta_field()))
             .collect(),
     });
     let def = Definition::TypeDefinition(typedef);
@@ -467,7 +966,119 @@ fn add_query_type(
     Ok(())
 }
 
-/// Adds a root `Subscription` object type to the schema.
+/// The `Query` field for the fulltext search `definition`. It returns the
+/// matching entities ordered by how well they match, and therefore does
+/// not take any of the `order*` arguments
+fn fulltext_query_field(schema: &Document, definition: &FulltextDefinition) -> Field {
+    let input_objects = ast::get_input_object_definitions(schema);
+    let mut arguments = vec![InputValue {
+        position: Pos::default(),
+        description: Some(
+            "The text to search for. Matches contain all of its words, \
+             ignoring punctuation and common words like `the`"
+                .to_owned(),
+        ),
+        name: "text".to_owned(),
+        value_type: Type::NonNullType(Box::new(Type::NamedType("String".to_owned()))),
+        default_value: None,
+        directives: vec![],
+    }];
+    arguments.extend(
+        collection_arguments_for_named_type(&input_objects, &definition.entity)
+            .into_iter()
+            .filter(|argument| !argument.name.starts_with("order")),
+    );
+    arguments.push(block_argument());
+
+    Field {
+        position: Pos::default(),
+        description: Some(format!(
+            "Fulltext search for `{}` entities by {}",
+            definition.entity,
+            definition
+                .fields
+                .iter()
+                .map(|field| format!("`{}`", field))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+        name: definition.name.clone(),
+        arguments,
+        field_type: Type::NonNullType(Box::new(Type::ListType(Box::new(Type::NonNullType(
+            Box::new(Type::NamedType(definition.entity.clone())),
+        ))))),
+        directives: vec![],
+    }
+}
+
+/// The `<entity>Aggregates` field of the `Query` type for `type_name`. It
+/// groups the entities that match `where` by the `groupBy` fields and
+/// returns one `<type_name>_aggregate` per group, ordered by the values of
+/// the `groupBy` fields
+fn aggregate_query_field(schema: &Document, type_name: &Name) -> Field {
+    let input_objects = ast::get_input_object_definitions(schema);
+    // Groups are always ordered by their `groupBy` values, and can not be
+    // paged through with cursors
+    let mut arguments = collection_arguments_for_named_type(&input_objects, type_name)
+        .into_iter()
+        .filter(|argument| {
+            !argument.name.starts_with("order")
+                && argument.name != "after"
+                && argument.name != "before"
+        })
+        .collect::<Vec<_>>();
+    let group_by_name = format!("{}_groupBy", type_name);
+    if ast::get_named_type(schema, &group_by_name).is_some() {
+        arguments.push(input_value(
+            &"groupBy".to_owned(),
+            "",
+            Type::ListType(Box::new(Type::NonNullType(Box::new(Type::NamedType(
+                group_by_name,
+            ))))),
+        ));
+    }
+    arguments.push(block_argument());
+
+    Field {
+        position: Pos::default(),
+        description: Some(format!(
+            "Count `{}` entities and aggregate their numeric fields, for all \
+             entities or for each group of entities with the same values for \
+             the `groupBy` fields",
+            type_name
+        )),
+        name: format!("{}Aggregates", type_name.as_str().to_camel_case()),
+        arguments,
+        field_type: Type::NonNullType(Box::new(Type::ListType(Box::new(Type::NonNullType(
+            Box::new(Type::NamedType(format!(
+                "{}{}",
+                type_name, AGGREGATE_TYPE_SUFFIX
+            ))),
+        ))))),
+        directives: vec![],
+    }
+}
+
+/// The `_meta` field of the `Query` type
+fn meta_field() -> Field {
+    Field {
+        position: Pos::default(),
+        description: Some(
+            "Information about the deployment, like the block it has indexed \
+             and when entities of each type last changed. With a `block` \
+             argument, the block is the one that the other fields of the query \
+             are executed at"
+                .to_owned(),
+        ),
+        name: META_FIELD_NAME.to_owned(),
+        arguments: vec![block_argument()],
+        field_type: Type::NamedType(META_FIELD_TYPE.to_owned()),
+        directives: vec![],
+    }
+}
+
+/// Adds a root `Subscription` object type to the schema. The
+/// `interface_types` include the interfaces that unions are queried as.
 fn add_subscription_type(
     schema: &mut Document,
     object_types: &[&ObjectType],
@@ -579,6 +1190,21 @@ fn collection_arguments_for_named_type(
             "",
             Type::NamedType("OrderDirection".to_string()),
         ),
+        input_value(
+            &"orderNulls".to_string(),
+            "",
+            Type::NamedType("OrderNulls".to_string()),
+        ),
+        input_value(
+            &"after".to_string(),
+            "",
+            Type::NamedType("String".to_string()),
+        ),
+        input_value(
+            &"before".to_string(),
+            "",
+            Type::NamedType("String".to_string()),
+        ),
     ];
 
     // Not all types have filter types, see comment in `add_filter_type`.
@@ -677,7 +1303,7 @@ fn add_field_arguments(
 mod tests {
     use graphql_parser::schema::*;
 
-    use super::api_schema;
+    use super::{api_schema, META_FIELD_NAME, META_FIELD_TYPE, META_TYPES};
     use crate::schema::ast;
 
     #[test]
@@ -715,6 +1341,24 @@ mod tests {
         assert_eq!(values, [&"asc".to_string(), &"desc".to_string()]);
     }
 
+    #[test]
+    fn api_schema_contains_order_nulls_enum() {
+        let input_schema = parse_schema("type User { id: ID!, name: String! }")
+            .expect("Failed to parse input schema");
+        let schema = api_schema(&input_schema).expect("Failed to derived API schema");
+
+        let order_nulls = ast::get_named_type(&schema, &"OrderNulls".to_string())
+            .expect("OrderNulls type is missing in derived API schema");
+        let enum_type = match order_nulls {
+            TypeDefinition::Enum(t) => Some(t),
+            _ => None,
+        }
+        .expect("OrderNulls type is not an enum");
+
+        let values: Vec<&Name> = enum_type.values.iter().map(|value| &value.name).collect();
+        assert_eq!(values, [&"first".to_string(), &"last".to_string()]);
+    }
+
     #[test]
     fn api_schema_contains_query_type() {
         let input_schema =
@@ -724,6 +1368,38 @@ mod tests {
             .expect("Root Query type is missing in API schema");
     }
 
+    #[test]
+    fn api_schema_contains_meta_field() {
+        let input_schema =
+            parse_schema("type User { id: ID! }").expect("Failed to parse input schema");
+        let schema = api_schema(&input_schema).expect("Failed to derive API schema");
+
+        let query_type = ast::get_named_type(&schema, &"Query".to_string())
+            .expect("Query type is missing in derived API schema");
+        let meta_field = match query_type {
+            TypeDefinition::Object(t) => ast::get_field(t, &META_FIELD_NAME.to_string()),
+            _ => None,
+        }
+        .expect("\"_meta\" field is missing on Query type");
+        assert_eq!(
+            meta_field.field_type,
+            Type::NamedType(META_FIELD_TYPE.to_string())
+        );
+        assert_eq!(
+            meta_field
+                .arguments
+                .iter()
+                .map(|input_value| input_value.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["block"]
+        );
+
+        for name in META_TYPES.iter() {
+            ast::get_named_type(&schema, &name.to_string())
+                .expect("meta type is missing in derived API schema");
+        }
+    }
+
     #[test]
     fn api_schema_contains_field_order_by_enum() {
         let input_schema = parse_schema("type User { id: ID!, name: String! }")
@@ -809,10 +1485,12 @@ mod tests {
                 "favoritePetNames",
                 "favoritePetNames_not",
                 "favoritePetNames_contains",
+                "favoritePetNames_contains_any",
                 "favoritePetNames_not_contains",
                 "pets",
                 "pets_not",
                 "pets_contains",
+                "pets_contains_any",
                 "pets_not_contains",
                 "favoritePet",
                 "favoritePet_not",
@@ -828,11 +1506,85 @@ mod tests {
                 "favoritePet_not_starts_with",
                 "favoritePet_ends_with",
                 "favoritePet_not_ends_with",
+                "and",
+                "or",
+                "not",
+                "pets_",
+                "favoritePet_",
+                "leastFavoritePet_",
+                "mostFavoritePets_",
+            ]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>()
+        );
+    }
+
+    #[test]
+    fn api_schema_contains_enum_and_type_condition_filters() {
+        let input_schema = parse_schema(
+            r#"
+              enum Color { brown, white }
+
+              interface Pet {
+                  id: ID!
+                  color: Color!
+              }
+
+              type Dog implements Pet {
+                  id: ID!
+                  color: Color!
+                  barks: Boolean!
+              }
+            "#,
+        )
+        .expect("Failed to parse input schema");
+        let schema = api_schema(&input_schema).expect("Failed to derived API schema");
+
+        let pet_filter = ast::get_named_type(&schema, &"Pet_filter".to_string())
+            .expect("Pet_filter type is missing in derived API schema");
+
+        let filter_type = match pet_filter {
+            TypeDefinition::InputObject(t) => Some(t),
+            _ => None,
+        }
+        .expect("Pet_filter type is not an input object");
+
+        assert_eq!(
+            filter_type
+                .fields
+                .iter()
+                .map(|field| field.name.to_owned())
+                .collect::<Vec<String>>(),
+            [
+                "id",
+                "id_not",
+                "id_gt",
+                "id_lt",
+                "id_gte",
+                "id_lte",
+                "id_in",
+                "id_not_in",
+                "color",
+                "color_not",
+                "color_in",
+                "color_not_in",
+                "_on_Dog",
+                "and",
+                "or",
+                "not",
             ]
             .iter()
             .map(|name| name.to_string())
             .collect::<Vec<String>>()
         );
+
+        let on_dog = filter_type
+            .fields
+            .iter()
+            .find(|field| field.name == "_on_Dog")
+            .unwrap();
+        assert_eq!(on_dog.value_type, Type::NamedType("Dog_filter".to_owned()));
     }
 
     #[test]
@@ -890,6 +1642,9 @@ mod tests {
                 "first",
                 "orderBy",
                 "orderDirection",
+                "orderNulls",
+                "after",
+                "before",
                 "where",
                 "block"
             ]
@@ -981,6 +1736,9 @@ mod tests {
                 "first",
                 "orderBy",
                 "orderDirection",
+                "orderNulls",
+                "after",
+                "before",
                 "where",
                 "block"
             ]
@@ -989,4 +1747,209 @@ mod tests {
             .collect::<Vec<String>>()
         );
     }
+
+    #[test]
+    fn api_schema_contains_union_fields_on_query_type() {
+        let input_schema = parse_schema(
+            "
+            type Swap { id: ID!, timestamp: Int!, amountIn: BigInt! }
+            type Mint { id: ID!, timestamp: Int!, liquidity: BigInt! }
+            union Activity = Swap | Mint
+            ",
+        )
+        .expect("Failed to parse input schema");
+        let schema = api_schema(&input_schema).expect("Failed to derive API schema");
+
+        let query_type = ast::get_named_type(&schema, &"Query".to_string())
+            .expect("Query type is missing in derived API schema");
+
+        let plural_field = match query_type {
+            TypeDefinition::Object(ref t) => ast::get_field(t, &"activities".to_string()),
+            _ => None,
+        }
+        .expect("\"activities\" field is missing on Query type");
+
+        assert_eq!(
+            plural_field.field_type,
+            Type::NonNullType(Box::new(Type::ListType(Box::new(Type::NonNullType(
+                Box::new(Type::NamedType("Activity".to_string()))
+            )))))
+        );
+
+        // Unions can be ordered by the fields all their members share
+        let order_by = match ast::get_named_type(&schema, &"Activity_orderBy".to_string()) {
+            Some(TypeDefinition::Enum(t)) => Some(t),
+            _ => None,
+        }
+        .expect("Activity_orderBy type is missing in derived API schema");
+        assert_eq!(
+            order_by
+                .values
+                .iter()
+                .map(|value| value.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["id", "timestamp"]
+        );
+
+        // Unions can be filtered by the shared fields, and by the fields of
+        // each member
+        let filter = match ast::get_named_type(&schema, &"Activity_filter".to_string()) {
+            Some(TypeDefinition::InputObject(t)) => Some(t),
+            _ => None,
+        }
+        .expect("Activity_filter type is missing in derived API schema");
+        let filter_fields = filter
+            .fields
+            .iter()
+            .map(|field| field.name.as_str())
+            .collect::<Vec<_>>();
+        assert!(filter_fields.contains(&"timestamp_gt"));
+        assert!(filter_fields.contains(&"_on_Swap"));
+        assert!(filter_fields.contains(&"_on_Mint"));
+        assert!(!filter_fields.contains(&"amountIn"));
+    }
+
+    #[test]
+    fn api_schema_contains_fulltext_fields_on_query_type() {
+        let input_schema = parse_schema(
+            r#"
+            type _Schema_ @fulltext(
+                name: "bandSearch",
+                language: en,
+                include: [{ entity: "Band", fields: [{ name: "name" }] }]
+            )
+            type Band { id: ID!, name: String! }
+            "#,
+        )
+        .expect("Failed to parse input schema");
+        let schema = api_schema(&input_schema).expect("Failed to derive API schema");
+
+        let query_type = ast::get_named_type(&schema, &"Query".to_string())
+            .expect("Query type is missing in derived API schema");
+
+        let search_field = match query_type {
+            TypeDefinition::Object(ref t) => ast::get_field(t, &"bandSearch".to_string()),
+            _ => None,
+        }
+        .expect("\"bandSearch\" field is missing on Query type");
+
+        assert_eq!(
+            search_field.field_type,
+            Type::NonNullType(Box::new(Type::ListType(Box::new(Type::NonNullType(
+                Box::new(Type::NamedType("Band".to_string()))
+            )))))
+        );
+        assert_eq!(
+            search_field
+                .arguments
+                .iter()
+                .map(|argument| argument.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["text", "skip", "first", "after", "before", "where", "block"]
+        );
+    }
+
+    #[test]
+    fn api_schema_contains_aggregate_fields_on_query_type() {
+        let input_schema = parse_schema(
+            r#"
+            type Band { id: ID!, name: String!, fans: Int!, members: [String!]! }
+            type Song { id: ID!, band: Band!, plays: BigInt }
+            "#,
+        )
+        .expect("Failed to parse input schema");
+        let schema = api_schema(&input_schema).expect("Failed to derive API schema");
+
+        let query_type = ast::get_named_type(&schema, &"Query".to_string())
+            .expect("Query type is missing in derived API schema");
+        let aggregates_field = match query_type {
+            TypeDefinition::Object(ref t) => ast::get_field(t, &"songAggregates".to_string()),
+            _ => None,
+        }
+        .expect("\"songAggregates\" field is missing on Query type");
+        assert_eq!(
+            aggregates_field.field_type,
+            Type::NonNullType(Box::new(Type::ListType(Box::new(Type::NonNullType(
+                Box::new(Type::NamedType("Song_aggregate".to_string()))
+            )))))
+        );
+        assert_eq!(
+            aggregates_field
+                .arguments
+                .iter()
+                .map(|argument| argument.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["skip", "first", "where", "groupBy", "block"]
+        );
+
+        let group_by = match ast::get_named_type(&schema, &"Band_groupBy".to_string()) {
+            Some(TypeDefinition::Enum(t)) => t,
+            _ => panic!("Band_groupBy enum is missing in derived API schema"),
+        };
+        assert_eq!(
+            group_by
+                .values
+                .iter()
+                .map(|value| value.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["name", "fans"]
+        );
+
+        let aggregate_type = match ast::get_named_type(&schema, &"Song_aggregate".to_string()) {
+            Some(TypeDefinition::Object(t)) => t,
+            _ => panic!("Song_aggregate type is missing in derived API schema"),
+        };
+        assert_eq!(
+            aggregate_type
+                .fields
+                .iter()
+                .map(|field| (field.name.as_str(), ast::get_field_name(&field.field_type)))
+                .collect::<Vec<_>>(),
+            vec![
+                ("count", "Int".to_string()),
+                ("band", "ID".to_string()),
+                ("plays", "BigInt".to_string()),
+                ("plays_sum", "BigInt".to_string()),
+                ("plays_min", "BigInt".to_string()),
+                ("plays_max", "BigInt".to_string()),
+                ("plays_avg", "BigDecimal".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn api_schema_adds_cursor_fields_to_entity_types() {
+        let input_schema = parse_schema(
+            r#"
+            interface Named { id: ID!, name: String! }
+            type Band implements Named { id: ID!, name: String! }
+            type Page { id: ID!, cursor: Int! }
+            "#,
+        )
+        .expect("Failed to parse input schema");
+        let schema = api_schema(&input_schema).expect("Failed to derive API schema");
+
+        let cursor_type = |type_name: &str| {
+            let fields = match ast::get_named_type(&schema, &type_name.to_string()) {
+                Some(TypeDefinition::Object(t)) => &t.fields,
+                Some(TypeDefinition::Interface(t)) => &t.fields,
+                _ => panic!("{} is missing in derived API schema", type_name),
+            };
+            assert!(has_generated_cursor(fields) == (type_name != "Page"));
+            fields
+                .iter()
+                .find(|field| field.name == CURSOR_FIELD_NAME)
+                .map(|field| field.field_type.clone())
+        };
+        let string = Type::NonNullType(Box::new(Type::NamedType("String".to_string())));
+        assert_eq!(Some(string.clone()), cursor_type("Band"));
+        assert_eq!(Some(string), cursor_type("Named"));
+        // Types keep their own `cursor` field
+        assert_eq!(
+            Some(Type::NonNullType(Box::new(Type::NamedType(
+                "Int".to_string()
+            )))),
+            cursor_type("Page")
+        );
+    }
 }
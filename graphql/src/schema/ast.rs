@@ -9,6 +9,16 @@ use crate::query::ast as qast;
 use graph::data::store;
 use graph::prelude::*;
 
+/// The prefix of the names of filter fields on interfaces that restrict
+/// the entities of one implementing type, like `_on_Dog: Dog_filter`
+pub(crate) const TYPE_CONDITION_PREFIX: &str = "_on_";
+
+/// The names of the filter fields that combine filters of the same type,
+/// like `or: [Token_filter!]`
+pub(crate) const AND_FILTER: &str = "and";
+pub(crate) const OR_FILTER: &str = "or";
+pub(crate) const NOT_FILTER: &str = "not";
+
 pub(crate) enum FilterOp {
     Not,
     GreaterThan,
@@ -19,6 +29,7 @@ pub(crate) enum FilterOp {
     NotIn,
     Contains,
     NotContains,
+    ContainsAny,
     StartsWith,
     NotStartsWith,
     EndsWith,
@@ -36,6 +47,7 @@ pub(crate) fn parse_field_as_filter(key: &Name) -> (Name, FilterOp) {
         k if k.ends_with("_lte") => ("_lte", FilterOp::LessOrEqual),
         k if k.ends_with("_not_in") => ("_not_in", FilterOp::NotIn),
         k if k.ends_with("_in") => ("_in", FilterOp::In),
+        k if k.ends_with("_contains_any") => ("_contains_any", FilterOp::ContainsAny),
         k if k.ends_with("_not_contains") => ("_not_contains", FilterOp::NotContains),
         k if k.ends_with("_contains") => ("_contains", FilterOp::Contains),
         k if k.ends_with("_not_starts_with") => ("_not_starts_with", FilterOp::NotStartsWith),
@@ -12,14 +12,16 @@ use std::time::Instant;
 
 use graph::data::graphql::ext::ObjectTypeExt;
 use graph::prelude::{
-    BlockNumber, Entity, EntityCollection, EntityFilter, EntityLink, EntityWindow, Logger,
-    ParentLink, QueryExecutionError, Schema, Store, Value as StoreValue, WindowAttribute,
+    BlockNumber, Entity, EntityAggregation, EntityCollection, EntityFilter, EntityLink,
+    EntityWindow, Logger, ParentLink, QueryExecutionError, Schema, Store, Value as StoreValue,
+    WindowAttribute,
 };
 
-use crate::execution::{ExecutionContext, ObjectOrInterface, Resolver};
+use crate::execution::{ExecutionContext, ObjectOrInterface, Resolver, MAX_AGGREGATE_ENTITIES};
 use crate::query::ast as qast;
+use crate::schema::api::{aggregated_entity_type, META_FIELD_NAME};
 use crate::schema::ast as sast;
-use crate::store::build_query;
+use crate::store::{add_cursors, add_fulltext_search, build_query};
 
 lazy_static! {
     static ref ARG_FIRST: String = String::from("first");
@@ -63,7 +65,7 @@ impl TypeCondition {
     /// Return the type that matches this condition; for `Any`, use `object_type`
     fn matching_type<'a>(
         &self,
-        schema: &'a s::Document,
+        schema: &'a Schema,
         object_type: &'a ObjectOrInterface<'a>,
     ) -> Option<ObjectOrInterface<'a>> {
         use TypeCondition::*;
@@ -501,7 +503,12 @@ where
         items: Vec::new(),
     };
 
-    for (_, type_fields) in collect_fields(ctx, &query_type.into(), selection_set, None) {
+    // The `<entity>Aggregates` fields, with their response key and the
+    // entity type they aggregate
+    let mut aggregates = Vec::new();
+
+    for (response_key, type_fields) in collect_fields(ctx, &query_type.into(), selection_set, None)
+    {
         let fields = match type_fields.get(&TypeCondition::Any) {
             None => return Ok(vec![]),
             Some(fields) => fields,
@@ -513,18 +520,115 @@ where
             .map(|f| q::Selection::Field((*f).clone()));
         // See if this is an introspection or data field. We don't worry about
         // nonexistant fields; those will cause an error later when we execute
-        // the query in `execution::execute_root_selection_set`
-        if sast::get_field(query_type, &name).is_some() {
-            data_set.items.extend(selections)
+        // the query in `execution::execute_root_selection_set`. The `_meta`
+        // field does not return entities and is resolved without prefetching
+        if name == META_FIELD_NAME {
+            continue;
+        }
+        if let Some(field) = sast::get_field(query_type, &name) {
+            match object_or_interface_from_type(&ctx.schema, &field.field_type) {
+                Some(ObjectOrInterface::Object(object_type)) => {
+                    match aggregated_entity_type(object_type) {
+                        Some(entity_type) => {
+                            aggregates.push((response_key, fields[0], entity_type))
+                        }
+                        None => data_set.items.extend(selections),
+                    }
+                }
+                _ => data_set.items.extend(selections),
+            }
         }
     }
 
     // Execute the root selection set against the root query type
-    execute_selection_set(&ctx, store, make_root_node(), &data_set, &query_type.into())
+    let mut root =
+        execute_selection_set(&ctx, store, make_root_node(), &data_set, &query_type.into())?;
+
+    for (response_key, field, entity_type) in aggregates {
+        let entities = aggregate(ctx, store, query_type, field, entity_type)?;
+        root[0].children.insert(
+            response_key.to_owned(),
+            entities
+                .into_iter()
+                .map(|entity| Rc::new(Node::from(entity)))
+                .collect(),
+        );
+    }
+    Ok(root)
+}
+
+/// Compute the aggregations that the root field `field` asks for. Since
+/// every aggregation produces a fixed set of scalars, the result is
+/// complete without looking at the selection set of `field`
+pub(crate) fn aggregate<R, S>(
+    ctx: &ExecutionContext<'_, R>,
+    store: &S,
+    query_type: &s::ObjectType,
+    field: &q::Field,
+    entity_type: &str,
+) -> Result<Vec<Entity>, Vec<QueryExecutionError>>
+where
+    R: Resolver,
+    S: Store,
+{
+    let entity_type = object_or_interface_by_name(&ctx.schema, &entity_type.to_owned())
+        .ok_or_else(|| vec![QueryExecutionError::NamedTypeError(entity_type.to_owned())])?;
+    let mut arguments = crate::execution::coerce_argument_values(ctx, query_type, field)?;
+    if !arguments.contains_key(&*ARG_FIRST) {
+        arguments.insert(&*ARG_FIRST, q::Value::Null);
+    }
+    if !arguments.contains_key(&*ARG_SKIP) {
+        arguments.insert(&*ARG_SKIP, q::Value::Null);
+    }
+
+    // Reuse how entity queries turn the arguments into a filter and range
+    let query = build_query(
+        entity_type,
+        ctx.block,
+        &arguments,
+        ctx.schema.types_for_interface(),
+        Some(ctx.schema.as_ref()),
+        ctx.max_first,
+    )
+    .map_err(|e| vec![e])?;
+    let group_by = match arguments.get(&"groupBy".to_owned()) {
+        None | Some(q::Value::Null) => vec![],
+        Some(q::Value::List(values)) => values
+            .iter()
+            .map(|value| match value {
+                q::Value::Enum(name) => Ok(name.clone()),
+                _ => Err(QueryExecutionError::InvalidArgumentError(
+                    field.position,
+                    "groupBy".to_owned(),
+                    value.clone(),
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| vec![e])?,
+        Some(value) => {
+            return Err(vec![QueryExecutionError::InvalidArgumentError(
+                field.position,
+                "groupBy".to_owned(),
+                value.clone(),
+            )])
+        }
+    };
+
+    store
+        .aggregate(EntityAggregation {
+            subgraph_id: query.subgraph_id,
+            block: ctx.block,
+            entity_type: entity_type.name().to_owned(),
+            filter: query.filter,
+            group_by,
+            range: query.range,
+            max_entities: *MAX_AGGREGATE_ENTITIES,
+        })
+        .map_err(|e| vec![e])
 }
 
-fn object_or_interface_from_type<'a>(
-    schema: &'a s::Document,
+pub(crate) fn object_or_interface_from_type<'a>(
+    schema: &'a Schema,
     field_type: &'a s::Type,
 ) -> Option<ObjectOrInterface<'a>> {
     match field_type {
@@ -534,13 +638,16 @@ fn object_or_interface_from_type<'a>(
     }
 }
 
+/// The object or interface type called `name`. Unions are represented by
+/// the interface with the fields that all their members share
 fn object_or_interface_by_name<'a>(
-    schema: &'a s::Document,
+    schema: &'a Schema,
     name: &s::Name,
 ) -> Option<ObjectOrInterface<'a>> {
-    match sast::get_named_type(schema, name) {
+    match sast::get_named_type(&schema.document, name) {
         Some(s::TypeDefinition::Object(t)) => Some(t.into()),
         Some(s::TypeDefinition::Interface(t)) => Some(t.into()),
+        Some(s::TypeDefinition::Union(t)) => schema.union_interfaces.get(&t.name).map(Into::into),
         _ => None,
     }
 }
@@ -577,14 +684,14 @@ where
             }
 
             let concrete_type = type_cond
-                .matching_type(&ctx.schema.document, object_type)
+                .matching_type(&ctx.schema, object_type)
                 .expect("collect_fields does not create type conditions for nonexistent types");
 
             if let Some(ref field) = concrete_type.field(&fields[0].name) {
                 match ctx.for_field(&fields[0], concrete_type.clone()) {
                     Ok(ctx) => {
                         let child_type =
-                            object_or_interface_from_type(&ctx.schema.document, &field.field_type)
+                            object_or_interface_from_type(&ctx.schema, &field.field_type)
                                 .expect("we only collect fields that are objects or interfaces");
 
                         let join = Join::new(
@@ -606,11 +713,9 @@ where
                             Ok(children) => {
                                 let child_selection_set =
                                     crate::execution::merge_selection_sets(fields);
-                                let child_object_type = object_or_interface_from_type(
-                                    &ctx.schema.document,
-                                    &field.field_type,
-                                )
-                                .expect("type of child field is object or interface");
+                                let child_object_type =
+                                    object_or_interface_from_type(&ctx.schema, &field.field_type)
+                                        .expect("type of child field is object or interface");
                                 match execute_selection_set(
                                     &ctx,
                                     store,
@@ -686,7 +791,9 @@ where
             .map(|field_def| sast::get_type_definition_from_field(schema, field_def))
             .unwrap_or(None)
             .map(|type_def| match type_def {
-                s::TypeDefinition::Interface(_) | s::TypeDefinition::Object(_) => true,
+                s::TypeDefinition::Interface(_)
+                | s::TypeDefinition::Object(_)
+                | s::TypeDefinition::Union(_) => true,
                 _ => false,
             })
             .unwrap_or(false)
@@ -750,7 +857,7 @@ where
                 let fragment_cond = TypeCondition::from(fragment.type_condition.clone());
                 // Fields for this fragment need to be looked up in the type
                 // mentioned in the condition
-                let fragment_type = fragment_cond.matching_type(&ctx.schema.document, object_type);
+                let fragment_type = fragment_cond.matching_type(&ctx.schema, object_type);
 
                 // The `None` case here indicates an error where the type condition
                 // mentions a nonexistent type; the overall query execution logic will catch
@@ -844,8 +951,9 @@ where
         store,
         &parents,
         &join,
+        field_definition,
         &argument_values,
-        ctx.schema.types_for_interface(),
+        &ctx.schema,
         ctx.block,
         ctx.max_first,
     )
@@ -859,18 +967,22 @@ fn fetch<S: Store>(
     store: &S,
     parents: &Vec<Node>,
     join: &Join<'_>,
+    field_definition: &s::Field,
     arguments: &HashMap<&q::Name, q::Value>,
-    types_for_interface: &BTreeMap<s::Name, Vec<s::ObjectType>>,
+    schema: &Schema,
     block: BlockNumber,
     max_first: u32,
 ) -> Result<Vec<Node>, QueryExecutionError> {
+    let types_for_interface = schema.types_for_interface();
     let mut query = build_query(
         join.child_type,
         block,
         arguments,
         types_for_interface,
+        Some(schema),
         max_first,
     )?;
+    let start = add_fulltext_search(&mut query, field_definition, arguments)?;
 
     query.logger = Some(logger);
     if let Some(q::Value::String(id)) = arguments.get(&*ARG_ID) {
@@ -890,7 +1002,17 @@ fn fetch<S: Store>(
         query.collection = EntityCollection::Window(windows);
     }
 
-    store
-        .find(query)
-        .map(|entities| entities.into_iter().map(|entity| entity.into()).collect())
+    let order_by = query
+        .order_by
+        .as_ref()
+        .map(|(attribute, _)| attribute.clone());
+    let mut entities = store.find(query)?;
+    add_cursors(
+        join.child_type,
+        types_for_interface,
+        order_by.as_ref().map(String::as_str),
+        start,
+        &mut entities,
+    )?;
+    Ok(entities.into_iter().map(|entity| entity.into()).collect())
 }
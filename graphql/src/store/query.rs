@@ -1,13 +1,28 @@
 use graphql_parser::{query as q, query::Name, schema as s, schema::ObjectType};
+use lazy_static::lazy_static;
 use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::mem::discriminant;
 
 use graph::prelude::*;
 
 use crate::execution::ObjectOrInterface;
+use crate::schema::api::{has_generated_cursor, CURSOR_FIELD_NAME};
 use crate::schema::ast as sast;
+use crate::store::prefetch::object_or_interface_from_type;
 
-/// Builds a EntityQuery from GraphQL arguments.
+lazy_static! {
+    /// How deeply filters on linked entities and `and`, `or` and `not`
+    /// combinators may be nested in the `where` argument of a query
+    static ref MAX_FILTER_DEPTH: u8 = std::env::var("GRAPH_GRAPHQL_MAX_FILTER_DEPTH")
+        .unwrap_or("5".into())
+        .parse::<u8>()
+        .expect("invalid GRAPH_GRAPHQL_MAX_FILTER_DEPTH");
+}
+
+/// Builds a EntityQuery from GraphQL arguments. Filters on the entities
+/// that `entity` links to can only be built if the `schema` is given.
+/// Fails if they and `and`, `or` and `not` combinators are nested more than
+/// `GRAPH_GRAPHQL_MAX_FILTER_DEPTH` levels deep.
 ///
 /// Panics if `entity` is not present in `schema`.
 pub fn build_query<'a>(
@@ -15,6 +30,7 @@ pub fn build_query<'a>(
     block: BlockNumber,
     arguments: &HashMap<&q::Name, q::Value>,
     types_for_interface: &BTreeMap<Name, Vec<ObjectType>>,
+    schema: Option<&Schema>,
     max_first: u32,
 ) -> Result<EntityQuery, QueryExecutionError> {
     let entity = entity.into();
@@ -27,7 +43,7 @@ pub fn build_query<'a>(
     });
     let mut query = EntityQuery::new(parse_subgraph_id(entity)?, block, entity_types)
         .range(build_range(arguments, max_first)?);
-    if let Some(filter) = build_filter(entity, arguments)? {
+    if let Some(filter) = build_filter(entity, arguments, types_for_interface, schema)? {
         query = query.filter(filter);
     }
     if let Some(order_by) = build_order_by(entity, arguments)? {
@@ -36,9 +52,143 @@ pub fn build_query<'a>(
     if let Some(direction) = build_order_direction(arguments)? {
         query = query.order_direction(direction);
     }
+    if let Some(nulls) = build_order_nulls(arguments)? {
+        query = query.order_nulls(nulls);
+    }
+    if let Some(cursor) = build_cursor(arguments, "after")? {
+        query = query.after(cursor);
+    }
+    if let Some(cursor) = build_cursor(arguments, "before")? {
+        query = query.before(cursor);
+    }
     Ok(query)
 }
 
+/// Set the `cursor` field of `entities` to their position in the order
+/// `order_by` of the query they were found with, for the entities whose
+/// type has the generated `cursor` field. For fulltext searches, `start`
+/// is the position of the first of `entities` in the matches of the search
+pub fn add_cursors<'a>(
+    entity: impl Into<ObjectOrInterface<'a>>,
+    types_for_interface: &'a BTreeMap<Name, Vec<ObjectType>>,
+    order_by: Option<&str>,
+    start: Option<u32>,
+    entities: &mut Vec<Entity>,
+) -> Result<(), QueryExecutionError> {
+    let with_cursor = match entity.into() {
+        ObjectOrInterface::Object(object) => vec![object],
+        ObjectOrInterface::Interface(interface) => types_for_interface
+            .get(&interface.name)
+            .map_or(vec![], |object_types| object_types.iter().collect()),
+    }
+    .into_iter()
+    .filter(|object_type| has_generated_cursor(&object_type.fields))
+    .map(|object_type| object_type.name.as_str())
+    .collect::<Vec<_>>();
+
+    for (pos, entity) in entities.iter_mut().enumerate() {
+        let has_cursor = match entity.get("__typename") {
+            Some(Value::String(typename)) => with_cursor.contains(&typename.as_str()),
+            _ => false,
+        };
+        if has_cursor {
+            let cursor = match start {
+                Some(start) => EntityCursor::at_position(entity, start + pos as u32)?,
+                None => EntityCursor::new(entity, order_by)?,
+            }
+            .encode();
+            entity.insert(CURSOR_FIELD_NAME.to_owned(), Value::String(cursor));
+        }
+    }
+    Ok(())
+}
+
+/// If `field_definition` is the `Query` field of a fulltext search, restrict
+/// `query` to the entities that match the `text` argument and order them
+/// by how well they match, best matches first. The store matches and ranks
+/// entities by the column of the search, which has the name of the field.
+/// Since matches are always ordered by rank, the `order*` arguments are
+/// rejected rather than ignored.
+///
+/// Ranks depend on the search text and are only computed when the query
+/// runs, so the store can not continue a search after the rank of a match.
+/// Cursors of matches hold their position instead, and the `after` and
+/// `before` cursors of `query` are turned into its range. For fulltext
+/// searches, this returns the position of the first match the query finds
+pub fn add_fulltext_search(
+    query: &mut EntityQuery,
+    field_definition: &s::Field,
+    arguments: &HashMap<&q::Name, q::Value>,
+) -> Result<Option<u32>, QueryExecutionError> {
+    if !field_definition
+        .arguments
+        .iter()
+        .any(|argument| argument.name == "text")
+    {
+        return Ok(None);
+    }
+
+    for name in &["orderBy", "orderDirection"] {
+        if let Some(value) = arguments.get(&name.to_string()) {
+            return Err(QueryExecutionError::InvalidArgumentError(
+                field_definition.position,
+                name.to_string(),
+                value.clone(),
+            ));
+        }
+    }
+
+    let text = match arguments.get(&"text".to_string()) {
+        Some(q::Value::String(text)) => text.to_owned(),
+        _ => {
+            return Err(QueryExecutionError::MissingArgumentError(
+                field_definition.position,
+                "text".to_owned(),
+            ))
+        }
+    };
+    query.filter = Some(
+        EntityFilter::Equal(field_definition.name.clone(), Value::String(text))
+            .and_maybe(query.filter.take()),
+    );
+    query.order_by = Some((field_definition.name.clone(), ValueType::String));
+    query.order_direction = Some(EntityOrder::Descending);
+
+    // Like for other queries, `skip` counts from the `before` cursor if
+    // there is one, and `first` takes the matches right in front of it
+    let after = query
+        .after
+        .take()
+        .map(|cursor| cursor.position())
+        .transpose()?;
+    let before = query
+        .before
+        .take()
+        .map(|cursor| cursor.position())
+        .transpose()?;
+    let lower = after.map_or(0, |after| after + 1);
+    let range = &query.range;
+    let (start, end) = match before {
+        None => {
+            let start = lower + range.skip;
+            (start, range.first.map(|first| start + first))
+        }
+        Some(before) => {
+            let end = before.saturating_sub(range.skip).max(lower);
+            let start = range
+                .first
+                .map_or(lower, |first| end.saturating_sub(first).max(lower));
+            (start, Some(end))
+        }
+    };
+    query.range = EntityRange {
+        first: end.map(|end| end - start),
+        skip: start,
+        from_end: false,
+    };
+    Ok(Some(start))
+}
+
 /// Parses GraphQL arguments into a EntityRange, if present.
 fn build_range(
     arguments: &HashMap<&q::Name, q::Value>,
@@ -74,6 +224,7 @@ fn build_range(
         (Ok(first), Ok(skip)) => Ok(EntityRange {
             first: Some(first),
             skip,
+            from_end: false,
         }),
         _ => {
             let errors: Vec<_> = vec![first, skip]
@@ -86,22 +237,41 @@ fn build_range(
     }
 }
 
+/// Parses the cursor in the `after` or `before` argument, if present.
+fn build_cursor(
+    arguments: &HashMap<&q::Name, q::Value>,
+    name: &str,
+) -> Result<Option<EntityCursor>, QueryExecutionError> {
+    match arguments.get(&name.to_string()) {
+        Some(q::Value::String(cursor)) => EntityCursor::decode(cursor).map(Some),
+        _ => Ok(None),
+    }
+}
+
 /// Parses GraphQL arguments into a EntityFilter, if present.
 fn build_filter(
     entity: ObjectOrInterface,
     arguments: &HashMap<&q::Name, q::Value>,
+    types_for_interface: &BTreeMap<Name, Vec<ObjectType>>,
+    schema: Option<&Schema>,
 ) -> Result<Option<EntityFilter>, QueryExecutionError> {
     match arguments.get(&"where".to_string()) {
-        Some(q::Value::Object(object)) => build_filter_from_object(entity, object),
+        Some(q::Value::Object(object)) => {
+            build_filter_from_object(entity, object, types_for_interface, schema, 0)
+        }
         None | Some(q::Value::Null) => Ok(None),
         _ => Err(QueryExecutionError::InvalidFilterError),
     }
 }
 
-/// Parses a GraphQL input object into a EntityFilter, if present.
+/// Parses a GraphQL input object into a EntityFilter, if present. `depth`
+/// is how deeply the object is nested in filters on linked entities
 fn build_filter_from_object(
     entity: ObjectOrInterface,
     object: &BTreeMap<q::Name, q::Value>,
+    types_for_interface: &BTreeMap<Name, Vec<ObjectType>>,
+    schema: Option<&Schema>,
+    depth: u8,
 ) -> Result<Option<EntityFilter>, QueryExecutionError> {
     Ok(Some(EntityFilter::And({
         object
@@ -109,6 +279,51 @@ fn build_filter_from_object(
             .map(|(key, value)| {
                 use self::sast::FilterOp::*;
 
+                if key.starts_with(sast::TYPE_CONDITION_PREFIX) {
+                    return build_type_condition(
+                        entity,
+                        key,
+                        value,
+                        types_for_interface,
+                        schema,
+                        depth,
+                    );
+                }
+
+                // Entities can have fields called `and`, `or` or `not`, in
+                // which case the filter has no combinator of that name
+                if sast::get_field(entity, key).is_none() {
+                    match key.as_str() {
+                        sast::AND_FILTER | sast::OR_FILTER | sast::NOT_FILTER => {
+                            return build_logical_filter(
+                                entity,
+                                key,
+                                value,
+                                types_for_interface,
+                                schema,
+                                depth,
+                            );
+                        }
+                        _ => (),
+                    }
+                }
+
+                // `<field>_` filters by the entities that `field` links to,
+                // unless the entity has a field called `<field>_`
+                if key.ends_with('_') && sast::get_field(entity, key).is_none() {
+                    let field_name = key[..key.len() - 1].to_owned();
+                    if let Some(field) = sast::get_field(entity, &field_name) {
+                        return build_child_filter(
+                            entity,
+                            field,
+                            value,
+                            types_for_interface,
+                            schema,
+                            depth,
+                        );
+                    }
+                }
+
                 let (field_name, op) = sast::parse_field_as_filter(key);
 
                 let field = sast::get_field(entity, &field_name).ok_or_else(|| {
@@ -131,6 +346,7 @@ fn build_filter_from_object(
                     NotIn => EntityFilter::NotIn(field_name, list_values(store_value, "_not_in")?),
                     Contains => EntityFilter::Contains(field_name, store_value),
                     NotContains => EntityFilter::NotContains(field_name, store_value),
+                    ContainsAny => EntityFilter::ContainsAny(field_name, store_value),
                     StartsWith => EntityFilter::StartsWith(field_name, store_value),
                     NotStartsWith => EntityFilter::NotStartsWith(field_name, store_value),
                     EndsWith => EntityFilter::EndsWith(field_name, store_value),
@@ -142,6 +358,142 @@ fn build_filter_from_object(
     })))
 }
 
+/// Parses a `_on_<Object>` filter on an interface into a filter that only
+/// applies to entities of type `<Object>`
+fn build_type_condition(
+    entity: ObjectOrInterface,
+    key: &Name,
+    value: &q::Value,
+    types_for_interface: &BTreeMap<Name, Vec<ObjectType>>,
+    schema: Option<&Schema>,
+    depth: u8,
+) -> Result<EntityFilter, QueryExecutionError> {
+    let type_name = key.trim_start_matches(sast::TYPE_CONDITION_PREFIX);
+    let object_type = match entity {
+        ObjectOrInterface::Interface(interface) => types_for_interface
+            .get(&interface.name)
+            .and_then(|types| types.iter().find(|o| o.name == type_name)),
+        ObjectOrInterface::Object(_) => None,
+    }
+    .ok_or_else(|| {
+        QueryExecutionError::EntityFieldError(entity.name().to_owned(), key.to_owned())
+    })?;
+
+    match value {
+        q::Value::Object(object) => {
+            let filter = build_filter_from_object(
+                object_type.into(),
+                object,
+                types_for_interface,
+                schema,
+                depth,
+            )?
+            .unwrap_or_else(|| EntityFilter::And(vec![]));
+            Ok(EntityFilter::TypeCondition(
+                object_type.name.to_owned(),
+                Box::new(filter),
+            ))
+        }
+        _ => Err(QueryExecutionError::InvalidFilterError),
+    }
+}
+
+/// Parses an `and`, `or` or `not` filter, which combines filters of the
+/// same type as the filter it is part of
+fn build_logical_filter(
+    entity: ObjectOrInterface,
+    key: &str,
+    value: &q::Value,
+    types_for_interface: &BTreeMap<Name, Vec<ObjectType>>,
+    schema: Option<&Schema>,
+    depth: u8,
+) -> Result<EntityFilter, QueryExecutionError> {
+    if depth >= *MAX_FILTER_DEPTH {
+        return Err(QueryExecutionError::FilterTooDeep(*MAX_FILTER_DEPTH));
+    }
+    let build = |value: &q::Value| match value {
+        q::Value::Object(object) => {
+            build_filter_from_object(entity, object, types_for_interface, schema, depth + 1)
+                .map(|filter| filter.unwrap_or_else(|| EntityFilter::And(vec![])))
+        }
+        _ => Err(QueryExecutionError::InvalidFilterError),
+    };
+    let build_all = |value: &q::Value| match value {
+        q::Value::List(values) => values.iter().map(&build).collect::<Result<Vec<_>, _>>(),
+        _ => Err(QueryExecutionError::InvalidFilterError),
+    };
+
+    match key {
+        sast::AND_FILTER => Ok(EntityFilter::And(build_all(value)?)),
+        sast::OR_FILTER => Ok(EntityFilter::Or(build_all(value)?)),
+        _ => Ok(EntityFilter::Negation(Box::new(build(value)?))),
+    }
+}
+
+/// Parses a `<field>_` filter into a filter on the entities that `field`
+/// links to, either directly or, for derived fields, through the field of
+/// the children that `field` is derived from
+fn build_child_filter(
+    entity: ObjectOrInterface,
+    field: &s::Field,
+    value: &q::Value,
+    types_for_interface: &BTreeMap<Name, Vec<ObjectType>>,
+    schema: Option<&Schema>,
+    depth: u8,
+) -> Result<EntityFilter, QueryExecutionError> {
+    let key = format!("{}_", field.name);
+    if depth >= *MAX_FILTER_DEPTH {
+        return Err(QueryExecutionError::FilterTooDeep(*MAX_FILTER_DEPTH));
+    }
+    let schema = schema.ok_or_else(|| {
+        QueryExecutionError::NotSupported(format!(
+            "the nested filter `{}` needs the schema of the subgraph",
+            key
+        ))
+    })?;
+    let child_type = object_or_interface_from_type(schema, &field.field_type).ok_or_else(|| {
+        QueryExecutionError::EntityFieldError(entity.name().to_owned(), key.clone())
+    })?;
+    let object = match value {
+        q::Value::Object(object) => object,
+        _ => return Err(QueryExecutionError::InvalidFilterError),
+    };
+
+    let (link, derived) = match sast::get_derived_from_directive(field) {
+        Some(_) => {
+            let derived_from =
+                sast::get_derived_from_field(child_type, field).ok_or_else(|| {
+                    QueryExecutionError::EntityFieldError(child_type.name().to_owned(), key)
+                })?;
+            (derived_from, true)
+        }
+        None => (field, false),
+    };
+    let entity_types = match child_type {
+        ObjectOrInterface::Object(object_type) => vec![object_type.name.clone()],
+        ObjectOrInterface::Interface(interface) => types_for_interface
+            .get(&interface.name)
+            .map_or(vec![], |types| {
+                types.iter().map(|o| o.name.clone()).collect()
+            }),
+    };
+    let filter = build_filter_from_object(
+        child_type,
+        object,
+        types_for_interface,
+        Some(schema),
+        depth + 1,
+    )?
+    .unwrap_or_else(|| EntityFilter::And(vec![]));
+    Ok(EntityFilter::Child(ChildFilter {
+        attribute: link.name.clone(),
+        entity_types,
+        derived,
+        list: sast::is_list_or_non_null_list_field(link),
+        filter: Box::new(filter),
+    }))
+}
+
 /// Parses a list of GraphQL values into a vector of entity field values.
 fn list_values(value: Value, filter_type: &str) -> Result<Vec<Value>, QueryExecutionError> {
     match value {
@@ -208,6 +560,19 @@ fn build_order_direction(
         }))
 }
 
+/// Parses GraphQL arguments into a NullsOrder, if present.
+fn build_order_nulls(
+    arguments: &HashMap<&q::Name, q::Value>,
+) -> Result<Option<NullsOrder>, QueryExecutionError> {
+    Ok(arguments
+        .get(&"orderNulls".to_string())
+        .and_then(|value| match value {
+            q::Value::Enum(name) if name == "first" => Some(NullsOrder::First),
+            q::Value::Enum(name) if name == "last" => Some(NullsOrder::Last),
+            _ => None,
+        }))
+}
+
 /// Parses the subgraph ID from the ObjectType directives.
 pub fn parse_subgraph_id<'a>(
     entity: impl Into<ObjectOrInterface<'a>>,
@@ -285,14 +650,16 @@ pub fn collect_entities_from_query_field(
 mod tests {
     use graphql_parser::{
         query as q, schema as s,
-        schema::{Directive, Field, InputValue, ObjectType, Type, Value as SchemaValue},
+        schema::{
+            Directive, Field, InputValue, InterfaceType, ObjectType, Type, Value as SchemaValue,
+        },
         Pos,
     };
     use std::collections::{BTreeMap, HashMap};
 
     use graph::prelude::*;
 
-    use super::build_query;
+    use super::{add_fulltext_search, build_query};
 
     fn default_object() -> ObjectType {
         let subgraph_id_argument = (
@@ -374,6 +741,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &default_arguments(),
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -386,6 +754,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &default_arguments(),
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -402,6 +771,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &default_arguments(),
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -414,6 +784,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &default_arguments(),
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -433,6 +804,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -448,6 +820,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -467,6 +840,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -482,6 +856,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -501,6 +876,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -516,6 +892,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -531,6 +908,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -539,6 +917,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn build_query_parses_order_nulls_from_enum_values() {
+        let order_nulls = "orderNulls".to_string();
+        let query = |value: &str| {
+            let mut args = default_arguments();
+            args.insert(&order_nulls, q::Value::Enum(value.to_string()));
+            build_query(
+                &default_object(),
+                BLOCK_NUMBER_MAX,
+                &args,
+                &BTreeMap::new(),
+                None,
+                std::u32::MAX,
+            )
+            .unwrap()
+            .order_nulls
+        };
+        assert_eq!(query("first"), Some(NullsOrder::First));
+        assert_eq!(query("last"), Some(NullsOrder::Last));
+        assert_eq!(query("middle"), None);
+    }
+
     #[test]
     fn build_query_ignores_order_direction_from_non_enum_values() {
         let order_direction = "orderDirection".to_string();
@@ -550,6 +950,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -565,6 +966,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -581,6 +983,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &default_arguments(),
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -600,6 +1003,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX
             )
             .unwrap()
@@ -607,6 +1011,7 @@ mod tests {
             EntityRange {
                 first: Some(100),
                 skip: 50,
+                from_end: false,
             },
         );
     }
@@ -631,6 +1036,7 @@ mod tests {
                 BLOCK_NUMBER_MAX,
                 &args,
                 &BTreeMap::new(),
+                None,
                 std::u32::MAX,
             )
             .unwrap()
@@ -641,4 +1047,252 @@ mod tests {
             )]))
         )
     }
+
+    #[test]
+    fn fields_ending_in_an_underscore_are_not_linked_entity_filters() {
+        let whre = "where".to_string();
+        let mut args = default_arguments();
+        args.insert(
+            &whre,
+            q::Value::Object(BTreeMap::from_iter(vec![(
+                "name_".to_string(),
+                q::Value::String("ello".to_string()),
+            )])),
+        );
+        assert_eq!(
+            build_query(
+                &ObjectType {
+                    fields: vec![
+                        field("name", Type::NamedType("String".to_owned())),
+                        field("name_", Type::NamedType("String".to_owned())),
+                    ],
+                    ..default_object()
+                },
+                BLOCK_NUMBER_MAX,
+                &args,
+                &BTreeMap::new(),
+                None,
+                std::u32::MAX,
+            )
+            .unwrap()
+            .filter,
+            Some(EntityFilter::And(vec![EntityFilter::Equal(
+                "name_".to_string(),
+                Value::String("ello".to_string()),
+            )]))
+        )
+    }
+
+    #[test]
+    fn build_query_yields_logical_filters() {
+        let name = |value: &str| {
+            q::Value::Object(BTreeMap::from_iter(vec![(
+                "name".to_string(),
+                q::Value::String(value.to_string()),
+            )]))
+        };
+        let whre = "where".to_string();
+        let mut args = default_arguments();
+        args.insert(
+            &whre,
+            q::Value::Object(BTreeMap::from_iter(vec![
+                ("or".to_string(), q::Value::List(vec![name("a"), name("b")])),
+                ("not".to_string(), name("c")),
+            ])),
+        );
+        let equal = |value: &str| {
+            EntityFilter::And(vec![EntityFilter::Equal(
+                "name".to_string(),
+                Value::String(value.to_string()),
+            )])
+        };
+        assert_eq!(
+            build_query(
+                &ObjectType {
+                    fields: vec![field("name", Type::NamedType("string".to_owned()))],
+                    ..default_object()
+                },
+                BLOCK_NUMBER_MAX,
+                &args,
+                &BTreeMap::new(),
+                None,
+                std::u32::MAX,
+            )
+            .unwrap()
+            .filter,
+            Some(EntityFilter::And(vec![
+                EntityFilter::Negation(Box::new(equal("c"))),
+                EntityFilter::Or(vec![equal("a"), equal("b")]),
+            ]))
+        )
+    }
+
+    #[test]
+    fn build_query_yields_type_conditions() {
+        let interface = InterfaceType {
+            position: Pos::default(),
+            description: None,
+            name: "Animal".to_owned(),
+            directives: default_object().directives,
+            fields: vec![field("name", Type::NamedType("String".to_owned()))],
+        };
+        let dog = ObjectType {
+            implements_interfaces: vec!["Animal".to_owned()],
+            fields: vec![field("barks", Type::NamedType("Boolean".to_owned()))],
+            ..object("Dog")
+        };
+        let types_for_interface = BTreeMap::from_iter(vec![("Animal".to_owned(), vec![dog])]);
+
+        let whre = "where".to_string();
+        let mut args = default_arguments();
+        args.insert(
+            &whre,
+            q::Value::Object(BTreeMap::from_iter(vec![(
+                "_on_Dog".to_string(),
+                q::Value::Object(BTreeMap::from_iter(vec![(
+                    "barks".to_string(),
+                    q::Value::Boolean(true),
+                )])),
+            )])),
+        );
+        assert_eq!(
+            build_query(
+                &interface,
+                BLOCK_NUMBER_MAX,
+                &args,
+                &types_for_interface,
+                None,
+                std::u32::MAX,
+            )
+            .unwrap()
+            .filter,
+            Some(EntityFilter::And(vec![EntityFilter::TypeCondition(
+                "Dog".to_owned(),
+                Box::new(EntityFilter::And(vec![EntityFilter::Equal(
+                    "barks".to_string(),
+                    Value::Bool(true),
+                )])),
+            )]))
+        );
+
+        // Type conditions are only allowed for types that implement the
+        // interface
+        let mut args = default_arguments();
+        args.insert(
+            &whre,
+            q::Value::Object(BTreeMap::from_iter(vec![(
+                "_on_Cat".to_string(),
+                q::Value::Object(BTreeMap::new()),
+            )])),
+        );
+        assert!(build_query(
+            &interface,
+            BLOCK_NUMBER_MAX,
+            &args,
+            &types_for_interface,
+            None,
+            std::u32::MAX,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn fulltext_search_ranks_matches() {
+        let mut search = field("bandSearch", Type::NamedType("Band".to_owned()));
+        search.arguments.push(InputValue {
+            position: Pos::default(),
+            description: None,
+            name: "text".to_owned(),
+            value_type: Type::NamedType("String".to_owned()),
+            default_value: None,
+            directives: vec![],
+        });
+        let text = "text".to_owned();
+        let order_by = "orderBy".to_owned();
+
+        let mut args = default_arguments();
+        args.insert(&text, q::Value::String("sigur ros".to_owned()));
+        let mut query = build_query(
+            &object("Band"),
+            BLOCK_NUMBER_MAX,
+            &args,
+            &BTreeMap::new(),
+            None,
+            std::u32::MAX,
+        )
+        .unwrap();
+        add_fulltext_search(&mut query, &search, &args).unwrap();
+        assert_eq!(
+            query.filter,
+            Some(EntityFilter::Equal(
+                "bandSearch".to_owned(),
+                Value::String("sigur ros".to_owned())
+            ))
+        );
+        assert_eq!(
+            query.order_by,
+            Some(("bandSearch".to_owned(), ValueType::String))
+        );
+
+        // Matches are always ordered by rank
+        args.insert(&order_by, q::Value::Enum("name".to_owned()));
+        assert!(add_fulltext_search(&mut query, &search, &args).is_err());
+    }
+
+    #[test]
+    fn fulltext_search_cursors_hold_positions() {
+        let mut search = field("bandSearch", Type::NamedType("Band".to_owned()));
+        search.arguments.push(InputValue {
+            position: Pos::default(),
+            description: None,
+            name: "text".to_owned(),
+            value_type: Type::NamedType("String".to_owned()),
+            default_value: None,
+            directives: vec![],
+        });
+        let text = "text".to_owned();
+        let (first_arg, skip_arg) = ("first".to_owned(), "skip".to_owned());
+        let (after_arg, before_arg) = ("after".to_owned(), "before".to_owned());
+        let cursor = |position| {
+            let mut band = Entity::new();
+            band.set("__typename", "Band");
+            band.set("id", "b");
+            q::Value::String(EntityCursor::at_position(&band, position).unwrap().encode())
+        };
+
+        // The position of the first match, and the range of the query, for
+        // `first`, `skip`, `after` and `before`
+        let search_range = |first: i32, skip: i32, after_pos, before_pos| {
+            let mut args = default_arguments();
+            args.insert(&text, q::Value::String("sigur ros".to_owned()));
+            args.insert(&first_arg, q::Value::Int(first.into()));
+            args.insert(&skip_arg, q::Value::Int(skip.into()));
+            if let Some(position) = after_pos {
+                args.insert(&after_arg, cursor(position));
+            }
+            if let Some(position) = before_pos {
+                args.insert(&before_arg, cursor(position));
+            }
+            let mut query = build_query(
+                &object("Band"),
+                BLOCK_NUMBER_MAX,
+                &args,
+                &BTreeMap::new(),
+                None,
+                std::u32::MAX,
+            )
+            .unwrap();
+            let start = add_fulltext_search(&mut query, &search, &args).unwrap();
+            assert!(query.after.is_none() && query.before.is_none());
+            (start, query.range.skip, query.range.first)
+        };
+
+        assert_eq!((Some(2), 2, Some(10)), search_range(10, 2, None, None));
+        assert_eq!((Some(8), 8, Some(10)), search_range(10, 2, Some(5), None));
+        // `before` takes the matches right in front of it
+        assert_eq!((Some(2), 2, Some(3)), search_range(3, 0, None, Some(5)));
+        assert_eq!((Some(1), 1, Some(3)), search_range(3, 1, None, Some(5)));
+        assert_eq!((Some(4), 4, Some(1)), search_range(3, 0, Some(3), Some(5)));
+        assert_eq!((Some(0), 0, Some(2)), search_range(3, 0, None, Some(2)));
+    }
 }
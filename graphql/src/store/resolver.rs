@@ -1,5 +1,6 @@
 use graphql_parser::{query as q, schema as s};
 use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
 use std::result;
 use std::sync::Arc;
 
@@ -8,10 +9,13 @@ use graph::prelude::*;
 
 use crate::prelude::*;
 use crate::query::ast as qast;
-use crate::query::ext::BlockConstraint;
+use crate::query::ext::{BlockConstraint, FieldExt};
+use crate::schema::api::{META_FIELD_TYPE, META_TYPES};
 use crate::schema::ast as sast;
 
-use crate::store::query::{collect_entities_from_query_field, parse_subgraph_id};
+use crate::store::query::{
+    add_cursors, add_fulltext_search, collect_entities_from_query_field, parse_subgraph_id,
+};
 
 /// A resolver that fetches entities from a `Store`.
 pub struct StoreResolver<S> {
@@ -178,6 +182,90 @@ where
         }
     }
 
+    /// Whether `object_type` is one of the types that the `_meta` field
+    /// returns
+    fn is_meta_type(object_type: ObjectOrInterface<'_>) -> bool {
+        META_TYPES.contains(&object_type.name().as_str())
+    }
+
+    /// Resolve a field whose type is one of the `_meta` types. The value of
+    /// `_meta` comes from the metadata of the deployment and contains the
+    /// values of all fields below it
+    fn resolve_meta(
+        &self,
+        parent: &Option<q::Value>,
+        field: &q::Field,
+        object_type: ObjectOrInterface<'_>,
+        block: BlockNumber,
+    ) -> Result<q::Value, QueryExecutionError> {
+        if object_type.name() != META_FIELD_TYPE {
+            return Ok(match parent {
+                Some(q::Value::Object(map)) => {
+                    map.get(&field.name).cloned().unwrap_or(q::Value::Null)
+                }
+                _ => q::Value::Null,
+            });
+        }
+
+        let subgraph_id = parse_subgraph_id(object_type)?;
+        let latest = self
+            .store
+            .block_ptr(subgraph_id.clone())
+            .map_err(StoreError::from)?;
+        // Report the block the query is executed at; we only know its hash
+        // if it is the latest block or was given in the `block` argument
+        // Entity types were last updated at or before the block the query
+        // is executed at
+        let updated_until = if block == BLOCK_NUMBER_MAX || block == BLOCK_NUMBER_PENDING {
+            None
+        } else {
+            Some(block)
+        };
+        let block = match latest {
+            None => q::Value::Null,
+            Some(ptr)
+                if block == BLOCK_NUMBER_MAX
+                    || block == BLOCK_NUMBER_PENDING
+                    || ptr.number == block as u64 =>
+            {
+                object_value(vec![
+                    ("number", q::Value::Int(q::Number::from(ptr.number as i32))),
+                    ("hash", q::Value::String(ptr.hash_hex())),
+                ])
+            }
+            Some(_) => {
+                let hash = match field.block_constraint(object_type)? {
+                    Some(BlockConstraint {
+                        block: BlockLocator::Hash(hash),
+                        ..
+                    }) => q::Value::String(format!("{:x}", hash)),
+                    _ => q::Value::Null,
+                };
+                object_value(vec![
+                    ("number", q::Value::Int(q::Number::from(block))),
+                    ("hash", hash),
+                ])
+            }
+        };
+        let entity_types = self
+            .store
+            .entity_type_updates(&subgraph_id, updated_until)?
+            .into_iter()
+            .map(|(entity_type, number)| {
+                object_value(vec![
+                    ("name", q::Value::String(entity_type)),
+                    ("lastUpdatedBlock", q::Value::Int(q::Number::from(number))),
+                ])
+            })
+            .collect();
+
+        Ok(object_value(vec![
+            ("deployment", q::Value::String(subgraph_id.to_string())),
+            ("block", block),
+            ("entityTypes", q::Value::List(entity_types)),
+        ]))
+    }
+
     fn resolve_objects_prefetch(
         &self,
         parent: &Option<q::Value>,
@@ -233,6 +321,41 @@ where
             )));
         }
     }
+
+    /// The number of the latest block the subgraph has indexed, and the
+    /// earliest block for which it retains history. History before that
+    /// block was discarded, e.g., when the deployment was migrated from
+    /// JSONB storage; all of the history is available if it is `None`.
+    /// Blocks before the start block of the deployment are available, and
+    /// queries for them simply find no entities
+    fn indexed_blocks(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+    ) -> Result<(u64, Option<u64>), QueryExecutionError> {
+        // The deployment can be removed after the query was checked
+        let deployment = self
+            .store
+            .get(SubgraphDeploymentEntity::key(subgraph.clone()))?
+            .ok_or_else(|| {
+                QueryExecutionError::StoreError(format_err!("subgraph {} does not exist", subgraph))
+            })?;
+        let number = |attr: &str| -> Result<Option<u64>, QueryExecutionError> {
+            match deployment.get(attr) {
+                None | Some(Value::Null) => Ok(None),
+                Some(value) => {
+                    let number: BigInt = value
+                        .clone()
+                        .try_into()
+                        .map_err(QueryExecutionError::StoreError)?;
+                    Ok(Some(number.to_u64()))
+                }
+            }
+        };
+        Ok((
+            number("latestEthereumBlockNumber")?.unwrap_or(0),
+            number("earliestRetainedBlockNumber")?,
+        ))
+    }
 }
 
 impl<S> Resolver for StoreResolver<S>
@@ -247,27 +370,44 @@ where
         super::prefetch::run(ctx, selection_set, self.store.clone()).map(|value| Some(value))
     }
 
+    fn resolve_aggregates<'r>(
+        &self,
+        ctx: &ExecutionContext<'r, Self>,
+        query_type: &s::ObjectType,
+        field: &q::Field,
+        entity_type: &str,
+    ) -> Result<q::Value, Vec<QueryExecutionError>> {
+        super::prefetch::aggregate(ctx, self.store.as_ref(), query_type, field, entity_type).map(
+            |entities| q::Value::List(entities.into_iter().map(|entity| entity.into()).collect()),
+        )
+    }
+
     fn locate_block(&self, bc: &BlockConstraint) -> Result<BlockNumber, QueryExecutionError> {
         match bc.block {
-            BlockLocator::Number(number) => self
-                .store
-                .block_ptr(bc.subgraph.clone())
-                .map_err(|e| StoreError::from(e).into())
-                .and_then(|ptr| {
-                    let ptr = ptr.expect("we should have already checked that the subgraph exists");
-                    if ptr.number < number as u64 {
-                        Err(QueryExecutionError::ValueParseError(
-                            "block.number".to_owned(),
-                            format!(
-                                "subgraph {} has only indexed up to block number {} \
-                                 and data for block number {} is therefore not yet available",
-                                &bc.subgraph, ptr.number, number
-                            ),
-                        ))
-                    } else {
-                        Ok(number)
+            BlockLocator::Number(number) => {
+                let (latest, earliest) = self.indexed_blocks(&bc.subgraph)?;
+                if latest < number as u64 {
+                    Err(QueryExecutionError::ValueParseError(
+                        "block.number".to_owned(),
+                        format!(
+                            "subgraph {} has only indexed up to block number {} \
+                             and data for block number {} is therefore not yet available",
+                            &bc.subgraph, latest, number
+                        ),
+                    ))
+                } else {
+                    match earliest {
+                        Some(earliest) if (number as u64) < earliest => {
+                            Err(QueryExecutionError::BlockNotAvailable(
+                                bc.subgraph.clone(),
+                                number as u64,
+                                earliest,
+                            ))
+                        }
+                        _ => Ok(number),
                     }
-                }),
+                }
+            }
             BlockLocator::Hash(hash) => self
                 .store
                 .block_number(&bc.subgraph, hash)
@@ -279,7 +419,16 @@ where
                             "no block with that hash found".to_owned(),
                         )
                     })
+                })
+                .and_then(|number| {
+                    // Blocks after the subgraph's latest block are not
+                    // indexed yet, no matter how they are identified
+                    self.locate_block(&BlockConstraint {
+                        subgraph: bc.subgraph.clone(),
+                        block: BlockLocator::Number(number),
+                    })
                 }),
+            BlockLocator::Pending => Ok(BLOCK_NUMBER_PENDING),
         }
     }
 
@@ -294,18 +443,27 @@ where
         block: BlockNumber,
         max_first: u32,
     ) -> Result<q::Value, QueryExecutionError> {
+        if Self::is_meta_type(object_type) {
+            return self.resolve_meta(parent, field, object_type, block);
+        }
         if Self::was_prefetched(parent) {
             return self.resolve_objects_prefetch(parent, field, object_type);
         }
 
         let object_type = object_type.into();
+        let schema = self
+            .store
+            .api_schema(&parse_subgraph_id(object_type)?)
+            .map_err(QueryExecutionError::StoreError)?;
         let mut query = build_query(
             object_type,
             block,
             arguments,
             types_for_interface,
+            Some(&schema),
             max_first,
         )?;
+        let start = add_fulltext_search(&mut query, field_definition, arguments)?;
 
         // Add matching filter for derived fields
         let derived_from_field = sast::get_derived_from_field(object_type, field_definition);
@@ -329,11 +487,21 @@ where
             Self::add_filter_for_reference_field(&mut query, parent, field_definition, object_type);
         }
 
-        let mut entity_values = Vec::new();
-        for entity in self.store.find(query)? {
-            entity_values.push(entity.into())
-        }
-        Ok(q::Value::List(entity_values))
+        let order_by = query
+            .order_by
+            .as_ref()
+            .map(|(attribute, _)| attribute.clone());
+        let mut entities = self.store.find(query)?;
+        add_cursors(
+            object_type,
+            types_for_interface,
+            order_by.as_ref().map(String::as_str),
+            start,
+            &mut entities,
+        )?;
+        Ok(q::Value::List(
+            entities.into_iter().map(|entity| entity.into()).collect(),
+        ))
     }
 
     fn resolve_object(
@@ -346,6 +514,9 @@ where
         types_for_interface: &BTreeMap<Name, Vec<ObjectType>>,
         block: BlockNumber,
     ) -> Result<q::Value, QueryExecutionError> {
+        if Self::is_meta_type(object_type) {
+            return self.resolve_meta(parent, field, object_type, block);
+        }
         if Self::was_prefetched(parent) {
             return self.resolve_object_prefetch(parent, field, field_definition, object_type);
         }
@@ -396,8 +567,18 @@ where
 
                 let skip_arg_name = q::Name::from("skip");
                 arguments.insert(&skip_arg_name, q::Value::Int(q::Number::from(0)));
-                let mut query =
-                    build_query(object_type, block, &arguments, types_for_interface, 2)?;
+                let schema = self
+                    .store
+                    .api_schema(&subgraph_id)
+                    .map_err(QueryExecutionError::StoreError)?;
+                let mut query = build_query(
+                    object_type,
+                    block,
+                    &arguments,
+                    types_for_interface,
+                    Some(&schema),
+                    2,
+                )?;
                 Self::add_filter_for_derived_field(&mut query, parent, derived_from_field);
 
                 // Find the entity or entities that reference the parent entity
@@ -424,7 +605,12 @@ where
             }
         };
 
-        Ok(entity.map_or(q::Value::Null, Into::into))
+        let mut entities = entity.into_iter().collect();
+        add_cursors(object_type, types_for_interface, None, None, &mut entities)?;
+        Ok(entities
+            .into_iter()
+            .next()
+            .map_or(q::Value::Null, Into::into))
     }
 
     fn resolve_field_stream<'a, 'b>(
@@ -455,3 +641,70 @@ where
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use graph::mock::MockStore;
+
+    use super::*;
+
+    fn resolver(latest: u64, earliest_retained: Option<u64>) -> StoreResolver<MockStore> {
+        let mut store = MockStore::new();
+        store.expect_get().returning(move |_| {
+            let mut deployment = Entity::new();
+            deployment.set("earliestEthereumBlockNumber", 5u64);
+            deployment.set("latestEthereumBlockNumber", latest);
+            if let Some(earliest) = earliest_retained {
+                deployment.set("earliestRetainedBlockNumber", earliest);
+            }
+            Ok(Some(deployment))
+        });
+        store.expect_block_ptr().never();
+        StoreResolver::new(&Logger::root(slog::Discard, o!()), Arc::new(store))
+    }
+
+    fn locate(
+        resolver: &StoreResolver<MockStore>,
+        number: BlockNumber,
+    ) -> Result<BlockNumber, String> {
+        resolver
+            .locate_block(&BlockConstraint {
+                subgraph: SubgraphDeploymentId::new("resolver").unwrap(),
+                block: BlockLocator::Number(number),
+            })
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn blocks_before_start_block_are_available() {
+        let resolver = resolver(10, None);
+        assert_eq!(Ok(0), locate(&resolver, 0));
+        assert_eq!(Ok(7), locate(&resolver, 7));
+        assert!(locate(&resolver, 11)
+            .unwrap_err()
+            .contains("is therefore not yet available"));
+    }
+
+    #[test]
+    fn blocks_before_retained_history_are_rejected() {
+        let resolver = resolver(10, Some(8));
+        assert_eq!(
+            Err(
+                "subgraph resolver only has data starting at block number 8 \
+                 and data for block number 7 is therefore not available"
+                    .to_owned()
+            ),
+            locate(&resolver, 7)
+        );
+        assert_eq!(Ok(8), locate(&resolver, 8));
+        assert_eq!(Ok(10), locate(&resolver, 10));
+    }
+
+    #[test]
+    fn blocks_of_removed_deployments_are_not_available() {
+        let mut store = MockStore::new();
+        store.expect_get().returning(|_| Ok(None));
+        let resolver = StoreResolver::new(&Logger::root(slog::Discard, o!()), Arc::new(store));
+        assert!(locate(&resolver, 7).unwrap_err().contains("does not exist"));
+    }
+}
@@ -567,6 +567,7 @@ fn introspection_query(schema: Schema, query: &str) -> QueryResult {
             max_complexity: None,
             max_depth: 100,
             max_first: std::u32::MAX,
+            block: BLOCK_NUMBER_MAX,
         },
     )
 }
@@ -82,6 +82,8 @@ fn insert_test_entities(store: &impl Store, id: SubgraphDeploymentId) {
         schema: schema.clone(),
         data_sources: vec![],
         templates: vec![],
+        graft: None,
+        features: vec![],
     };
 
     let ops = SubgraphDeploymentEntity::new(&manifest, false, false, None, None)
@@ -234,6 +236,7 @@ fn execute_query_document_with_variables(
         max_complexity: None,
         max_depth: 100,
         max_first: std::u32::MAX,
+        block: BLOCK_NUMBER_MAX,
     };
 
     execute_query(query, options)
@@ -728,6 +731,7 @@ fn query_complexity() {
         max_complexity,
         max_depth: 100,
         max_first: std::u32::MAX,
+        block: BLOCK_NUMBER_MAX,
     };
 
     // This query is exactly at the maximum complexity.
@@ -765,6 +769,7 @@ fn query_complexity() {
         max_complexity,
         max_depth: 100,
         max_first: std::u32::MAX,
+        block: BLOCK_NUMBER_MAX,
     };
 
     // The extra introspection causes the complexity to go over.
@@ -775,6 +780,71 @@ fn query_complexity() {
     };
 }
 
+#[test]
+fn aggregate_query_complexity() {
+    let logger = Logger::root(slog::Discard, o!());
+    let store_resolver = StoreResolver::new(&logger, STORE.clone());
+    let execute = |query: &str| {
+        let query = Query {
+            schema: Arc::new(api_test_schema()),
+            document: graphql_parser::parse_query(query).unwrap(),
+            variables: None,
+        };
+        let options = QueryExecutionOptions {
+            logger: logger.clone(),
+            resolver: store_resolver.clone(),
+            deadline: None,
+            max_complexity: Some(10_100),
+            max_depth: 100,
+            max_first: std::u32::MAX,
+            block: BLOCK_NUMBER_MAX,
+        };
+        execute_query(query, options)
+    };
+
+    // An aggregation counts as many entities as it may aggregate, on top
+    // of the groups it returns
+    let result = execute("query { songStatAggregates { count } }");
+    assert!(result.errors.is_none());
+
+    let result = execute("query { songStatAggregates { count } musicians { name } }");
+    match result.errors.unwrap()[0] {
+        QueryError::ExecutionError(QueryExecutionError::TooComplex(10_200, _)) => (),
+        _ => panic!("did not catch complexity"),
+    }
+}
+
+#[test]
+fn aggregates_resolve_without_prefetching() {
+    // `@verify` resolves the query with and without prefetching, and
+    // fails if the results differ
+    let query = graphql_parser::parse_query(
+        "
+        query @verify {
+          songStatAggregates {
+            count
+            played_sum
+          }
+        }
+    ",
+    )
+    .expect("invalid test query");
+
+    let result = execute_query_document(query);
+
+    assert!(result.errors.is_none(), "{:?}", result.errors);
+    assert_eq!(
+        result.data,
+        Some(object_value(vec![(
+            "songStatAggregates",
+            q::Value::List(vec![object_value(vec![
+                ("count", q::Value::Int(q::Number::from(2))),
+                ("played_sum", q::Value::String(String::from("25"))),
+            ])])
+        )]))
+    );
+}
+
 #[tokio::test]
 async fn query_complexity_subscriptions() {
     let logger = Logger::root(slog::Discard, o!());
@@ -872,6 +942,7 @@ fn instant_timeout() {
         max_complexity: None,
         max_depth: 100,
         max_first: std::u32::MAX,
+        block: BLOCK_NUMBER_MAX,
     };
 
     match execute_query(query, options).errors.unwrap()[0] {
@@ -1063,6 +1134,115 @@ fn ambiguous_derived_from_result() {
     }
 }
 
+#[test]
+fn order_nulls_and_ties() {
+    let result = execute_query_document(
+        graphql_parser::parse_query(
+            "
+        query {
+            nullsLast: musicians(orderBy: mainBand) { id }
+            nullsFirst: musicians(orderBy: mainBand, orderNulls: first) { id }
+            descending: musicians(orderBy: mainBand, orderDirection: desc) { id }
+        }
+        ",
+        )
+        .expect("invalid test query"),
+    );
+
+    assert!(
+        result.errors.is_none(),
+        format!("Unexpected errors return for query: {:#?}", result.errors)
+    );
+
+    // `m4` has no `mainBand`, and `m1` and `m2` share one; entities with
+    // the same sort key are always ordered by their id
+    let ids = |ids: Vec<&str>| {
+        q::Value::List(
+            ids.into_iter()
+                .map(|id| object_value(vec![("id", q::Value::String(id.to_owned()))]))
+                .collect(),
+        )
+    };
+    assert_eq!(
+        result.data,
+        Some(object_value(vec![
+            ("nullsLast", ids(vec!["m1", "m2", "m3", "m4"])),
+            ("nullsFirst", ids(vec!["m4", "m1", "m2", "m3"])),
+            ("descending", ids(vec!["m3", "m1", "m2", "m4"])),
+        ]))
+    );
+}
+
+#[test]
+fn paginate_with_cursors() {
+    let query = |arguments: &str| {
+        let result = execute_query_document(
+            graphql_parser::parse_query(&format!(
+                "query {{ musicians(orderBy: mainBand, {}) {{ id cursor }} }}",
+                arguments
+            ))
+            .expect("invalid test query"),
+        );
+        assert!(
+            result.errors.is_none(),
+            format!("Unexpected errors return for query: {:#?}", result.errors)
+        );
+        let musicians = match result.data {
+            Some(q::Value::Object(mut data)) => data.remove("musicians"),
+            _ => None,
+        };
+        match musicians {
+            Some(q::Value::List(musicians)) => musicians
+                .into_iter()
+                .map(|musician| match musician {
+                    q::Value::Object(musician) => match (&musician["id"], &musician["cursor"]) {
+                        (q::Value::String(id), q::Value::String(cursor)) => {
+                            (id.clone(), cursor.clone())
+                        }
+                        _ => panic!("musicians must have an id and a cursor"),
+                    },
+                    _ => panic!("musicians must be objects"),
+                })
+                .collect::<Vec<_>>(),
+            _ => panic!("the result must contain musicians"),
+        }
+    };
+    let ids = |musicians: Vec<(String, String)>| {
+        musicians.into_iter().map(|(id, _)| id).collect::<Vec<_>>()
+    };
+
+    // The order is m1, m2, m3, m4 since `m4` has no `mainBand`
+    let page = query("first: 2");
+    assert_eq!(vec!["m1", "m2"], ids(page.clone()));
+    let after = &page[1].1;
+    assert_eq!(
+        vec!["m3", "m4"],
+        ids(query(&format!("after: \"{}\"", after)))
+    );
+    let before = query("first: 1, skip: 3").remove(0).1;
+    assert_eq!(
+        vec!["m1", "m2", "m3"],
+        ids(query(&format!("before: \"{}\"", before)))
+    );
+    // `first` takes the musicians right in front of the cursor, and `skip`
+    // counts back from it
+    assert_eq!(
+        vec!["m2", "m3"],
+        ids(query(&format!("first: 2, before: \"{}\"", before)))
+    );
+    assert_eq!(
+        vec!["m1", "m2"],
+        ids(query(&format!("first: 2, skip: 1, before: \"{}\"", before)))
+    );
+    assert_eq!(
+        vec!["m3"],
+        ids(query(&format!(
+            "after: \"{}\", before: \"{}\"",
+            after, before
+        )))
+    );
+}
+
 #[test]
 fn can_filter_by_relationship_fields() {
     let result = execute_query_document(
@@ -1152,6 +1332,189 @@ fn cannot_filter_by_derved_relationship_fields() {
     };
 }
 
+#[test]
+fn can_filter_by_linked_entities() {
+    let result = execute_query_document(
+        graphql_parser::parse_query(
+            "
+        query {
+            musicians(orderBy: id, where: { mainBand_: { name: \"The Amateurs\" } }) { id }
+            writers: musicians(orderBy: id, where: { writtenSongs_: { title_starts_with: \"Rock\" } }) { id }
+            bands(orderBy: id, where: { members_: { name: \"Lisa\" } }) { id }
+        }
+        ",
+        )
+        .expect("invalid test query"),
+    );
+
+    assert!(
+        result.errors.is_none(),
+        format!("Unexpected errors return for query: {:#?}", result.errors)
+    );
+    let only = |id: &str| {
+        q::Value::List(vec![object_value(vec![(
+            "id",
+            q::Value::String(String::from(id)),
+        )])])
+    };
+    assert_eq!(
+        result.data,
+        Some(object_value(vec![
+            ("musicians", only("m3")),
+            ("writers", only("m2")),
+            ("bands", only("b1")),
+        ]))
+    );
+
+    // `@verify` resolves the query with and without prefetching, and fails
+    // if the results differ
+    let result = execute_query_document(
+        graphql_parser::parse_query(
+            "
+        query @verify {
+            musicians(orderBy: id, where: { mainBand_: { name: \"The Amateurs\" } }) { id }
+            bands(orderBy: id, where: { members_: { name: \"Lisa\" } }) { id }
+        }
+        ",
+        )
+        .expect("invalid test query"),
+    );
+    assert!(
+        result.errors.is_none(),
+        format!("Unexpected errors return for query: {:#?}", result.errors)
+    );
+}
+
+#[test]
+fn filters_by_linked_entities_are_bounded() {
+    let logger = Logger::root(slog::Discard, o!());
+    let store_resolver = StoreResolver::new(&logger, STORE.clone());
+    let execute = |query: &str| {
+        let query = Query {
+            schema: Arc::new(api_test_schema()),
+            document: graphql_parser::parse_query(query).unwrap(),
+            variables: None,
+        };
+        let options = QueryExecutionOptions {
+            logger: logger.clone(),
+            resolver: store_resolver.clone(),
+            deadline: None,
+            max_complexity: Some(15),
+            max_depth: 100,
+            max_first: std::u32::MAX,
+            block: BLOCK_NUMBER_MAX,
+        };
+        execute_query(query, options)
+    };
+
+    // Each filter on linked entities counts like selecting the linked
+    // entities of every entity
+    let result = execute("query { musicians(first: 10) { id } }");
+    assert!(result.errors.is_none());
+    let result = execute(
+        "query { musicians(first: 10, where: { mainBand_: { name: \"The Amateurs\" } }) { id } }",
+    );
+    match result.errors.unwrap()[0] {
+        QueryError::ExecutionError(QueryExecutionError::TooComplex(20, _)) => (),
+        _ => panic!("did not catch complexity"),
+    }
+
+    // Filters can not be nested arbitrarily deep
+    let result = execute(
+        "query { musicians(first: 1, where: { mainBand_: { members_: { mainBand_: { \
+         members_: { mainBand_: { members_: { name: \"Lisa\" } } } } } } }) { id } }",
+    );
+    match result.errors.unwrap()[0] {
+        QueryError::ExecutionError(QueryExecutionError::FilterTooDeep(5)) => (),
+        _ => panic!("did not catch filter depth"),
+    }
+}
+
+#[test]
+fn can_combine_filters() {
+    let result = execute_query_document(
+        graphql_parser::parse_query(
+            "
+        query {
+            musicians(orderBy: id, where: { or: [{ name: \"John\" }, { mainBand: \"b2\" }], not: { name: \"Tom\" } }) { id }
+            others: musicians(orderBy: id, where: { not: { mainBand: \"b1\" } }) { id }
+        }
+        ",
+        )
+        .expect("invalid test query"),
+    );
+
+    assert!(
+        result.errors.is_none(),
+        format!("Unexpected errors return for query: {:#?}", result.errors)
+    );
+    let ids = |ids: Vec<&str>| {
+        q::Value::List(
+            ids.into_iter()
+                .map(|id| object_value(vec![("id", q::Value::String(String::from(id)))]))
+                .collect(),
+        )
+    };
+    // Musicians without a main band are not in `b1`
+    assert_eq!(
+        result.data,
+        Some(object_value(vec![
+            ("musicians", ids(vec!["m1"])),
+            ("others", ids(vec!["m3", "m4"])),
+        ]))
+    );
+}
+
+#[test]
+fn combined_filters_are_bounded() {
+    let logger = Logger::root(slog::Discard, o!());
+    let store_resolver = StoreResolver::new(&logger, STORE.clone());
+    let execute = |query: &str| {
+        let query = Query {
+            schema: Arc::new(api_test_schema()),
+            document: graphql_parser::parse_query(query).unwrap(),
+            variables: None,
+        };
+        let options = QueryExecutionOptions {
+            logger: logger.clone(),
+            resolver: store_resolver.clone(),
+            deadline: None,
+            max_complexity: Some(15),
+            max_depth: 100,
+            max_first: std::u32::MAX,
+            block: BLOCK_NUMBER_MAX,
+        };
+        execute_query(query, options)
+    };
+
+    // Each `and`, `or` and `not` counts like a filter on linked entities
+    let result =
+        execute("query { musicians(first: 10, where: { not: { name: \"Tom\" } }) { id } }");
+    match result.errors.unwrap()[0] {
+        QueryError::ExecutionError(QueryExecutionError::TooComplex(20, _)) => (),
+        _ => panic!("did not catch complexity"),
+    }
+    let result = execute(
+        "query { musicians(first: 1, where: { or: [{ name: \"Tom\" }, { and: [{ name: \"John\" }] }] }) { id } }",
+    );
+    assert!(result.errors.is_none());
+
+    // Combinators can not be nested arbitrarily deep
+    let result = execute(
+        "query { musicians(first: 1, where: { not: { not: { not: { not: { not: \
+         { name: \"Tom\" } } } } } }) { id } }",
+    );
+    assert!(result.errors.is_none());
+    let result = execute(
+        "query { musicians(first: 1, where: { not: { not: { not: { not: { not: \
+         { not: { name: \"Tom\" } } } } } } }) { id } }",
+    );
+    match result.errors.unwrap()[0] {
+        QueryError::ExecutionError(QueryExecutionError::FilterTooDeep(5)) => (),
+        _ => panic!("did not catch filter depth"),
+    }
+}
+
 #[tokio::test]
 async fn subscription_gets_result_even_without_events() {
     let logger = Logger::root(slog::Discard, o!());
@@ -1324,6 +1687,8 @@ fn query_at_block() {
 
     const BLOCK_NOT_INDEXED: &str = "subgraph graphqlTestsQuery has only indexed \
          up to block number 1 and data for block number 7000 is therefore not yet available";
+    const BLOCK_HASH_NOT_INDEXED: &str = "subgraph graphqlTestsQuery has only indexed \
+         up to block number 1 and data for block number 2 is therefore not yet available";
     const BLOCK_HASH_NOT_FOUND: &str = "no block with that hash found";
 
     musicians_at("number: 7000", Err(BLOCK_NOT_INDEXED), "n7000");
@@ -1332,6 +1697,105 @@ fn query_at_block() {
 
     musicians_at(&hash(&*GENESIS_BLOCK), Ok(vec!["m1", "m2"]), "h0");
     musicians_at(&hash(&*BLOCK_ONE), Ok(vec!["m1", "m2", "m3", "m4"]), "h1");
-    musicians_at(&hash(&*BLOCK_TWO), Ok(vec!["m1", "m2", "m3", "m4"]), "h2");
+    musicians_at(&hash(&*BLOCK_TWO), Err(BLOCK_HASH_NOT_INDEXED), "h2");
     musicians_at(&hash(&*BLOCK_THREE), Err(BLOCK_HASH_NOT_FOUND), "h3");
 }
+
+#[test]
+fn meta_at_block() {
+    use test_store::block_store::GENESIS_BLOCK;
+
+    fn meta_block_at(block: &str) -> q::Value {
+        let query = format!(
+            "query {{ _meta(block: {{ {} }}) {{ block {{ number hash }} }} }}",
+            block
+        );
+        let query = graphql_parser::parse_query(&query).expect("invalid test query");
+        let result = execute_query_document(query);
+        assert!(result.errors.is_none(), "{:?}", result.errors);
+        result.data.unwrap()
+    }
+
+    if !STORE.uses_relational_schema(&*TEST_SUBGRAPH_ID).unwrap() {
+        return;
+    }
+
+    // The block is the one the query is executed at, with its hash if
+    // the query identified the block by its hash
+    assert_eq!(
+        meta_block_at("number: 0"),
+        object_value(vec![(
+            "_meta",
+            object_value(vec![(
+                "block",
+                object_value(vec![
+                    ("hash", q::Value::Null),
+                    ("number", q::Value::Int(q::Number::from(0))),
+                ])
+            )])
+        )])
+    );
+    assert_eq!(
+        meta_block_at(&format!("hash: \"0x{}\"", GENESIS_BLOCK.hash)),
+        object_value(vec![(
+            "_meta",
+            object_value(vec![(
+                "block",
+                object_value(vec![
+                    ("hash", q::Value::String(GENESIS_BLOCK.hash.clone())),
+                    ("number", q::Value::Int(q::Number::from(0))),
+                ])
+            )])
+        )])
+    );
+}
+
+#[test]
+fn meta_entity_types_at_block() {
+    fn musicians_updated_at(block: &str) -> q::Value {
+        let query = format!(
+            "query {{ _meta{} {{ entityTypes {{ name lastUpdatedBlock }} }} }}",
+            block
+        );
+        let query = graphql_parser::parse_query(&query).expect("invalid test query");
+        let result = execute_query_document(query);
+        assert!(result.errors.is_none(), "{:?}", result.errors);
+        let entity_types = match result.data.unwrap() {
+            q::Value::Object(mut data) => match data.remove("_meta") {
+                Some(q::Value::Object(mut meta)) => meta.remove("entityTypes"),
+                _ => None,
+            },
+            _ => None,
+        };
+        match entity_types {
+            Some(q::Value::List(entity_types)) => entity_types
+                .into_iter()
+                .find_map(|entity_type| match entity_type {
+                    q::Value::Object(mut entity_type)
+                        if entity_type.get("name")
+                            == Some(&q::Value::String("Musician".to_owned())) =>
+                    {
+                        entity_type.remove("lastUpdatedBlock")
+                    }
+                    _ => None,
+                })
+                .expect("musicians were updated"),
+            _ => panic!("_meta has no entity types"),
+        }
+    }
+
+    if !STORE.uses_relational_schema(&*TEST_SUBGRAPH_ID).unwrap() {
+        return;
+    }
+
+    // Musicians were added in blocks 0 and 1
+    assert_eq!(q::Value::Int(q::Number::from(1)), musicians_updated_at(""));
+    assert_eq!(
+        q::Value::Int(q::Number::from(1)),
+        musicians_updated_at("(block: { number: 1 })")
+    );
+    assert_eq!(
+        q::Value::Int(q::Number::from(0)),
+        musicians_updated_at("(block: { number: 0 })")
+    );
+}
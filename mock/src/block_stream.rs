@@ -53,9 +53,7 @@ impl BlockStreamBuilder for MockBlockStreamBuilder {
         _deployment_id: SubgraphDeploymentId,
         _network_name: String,
         _start_blocks: Vec<u64>,
-        _: EthereumLogFilter,
-        _: EthereumCallFilter,
-        _: EthereumBlockFilter,
+        _: EthereumTriggerFilter,
         _: bool,
         _: Arc<BlockStreamMetrics>,
     ) -> Self::Stream {
@@ -12,4 +12,4 @@ mod store;
 
 pub use self::block_stream::{MockBlockStream, MockBlockStreamBuilder};
 pub use self::metrics_registry::MockMetricsRegistry;
-pub use self::store::{mock_store_with_users_subgraph, MockStore};
+pub use self::store::{mock_store_with_users_subgraph, mock_users_subgraph_store, MockStore};
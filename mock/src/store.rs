@@ -1,6 +1,6 @@
 use mockall::predicate::*;
 use mockall::*;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
 use graph::components::store::*;
 use graph::data::subgraph::schema::*;
@@ -29,8 +29,16 @@ mock! {
 
         fn find_one(&self, query: EntityQuery) -> Result<Option<Entity>, QueryExecutionError>;
 
+        fn aggregate(&self, query: EntityAggregation) -> Result<Vec<Entity>, QueryExecutionError>;
+
         fn find_ens_name(&self, _hash: &str) -> Result<Option<String>, QueryExecutionError>;
 
+        fn entity_type_updates(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            block: Option<BlockNumber>,
+        ) -> Result<Vec<(String, BlockNumber)>, StoreError>;
+
         fn transact_block_operations(
             &self,
             subgraph_id: SubgraphDeploymentId,
@@ -65,6 +73,13 @@ mock! {
             ops: Vec<MetadataOperation>,
         ) -> Result<(), StoreError>;
 
+        fn create_subgraph_deployment_in_tablespace(
+            &self,
+            schema: &Schema,
+            ops: Vec<MetadataOperation>,
+            tablespace: String,
+        ) -> Result<(), StoreError>;
+
         fn start_subgraph_deployment(
             &self,
             subgraph_id: &SubgraphDeploymentId,
@@ -91,6 +106,118 @@ mock! {
         fn api_schema(&self, subgraph_id: &SubgraphDeploymentId) -> Result<Arc<Schema>, Error>;
 
         fn uses_relational_schema(&self, subgraph_id: &SubgraphDeploymentId) -> Result<bool, Error>;
+
+        fn entity_changes_in_block(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            block: BlockNumber,
+        ) -> Result<Vec<BlockEntityChange>, StoreError>;
+
+        fn entity_changes_since(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            since: BlockNumber,
+            to: BlockNumber,
+        ) -> Result<Vec<BlockEntityChange>, StoreError>;
+
+        fn entity_diff(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            entity_type: &str,
+            from: BlockNumber,
+            to: BlockNumber,
+        ) -> Result<Vec<EntityDiff>, StoreError>;
+
+        fn create_missing_indexes(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+        ) -> Result<Vec<IndexCreation>, StoreError>;
+
+        fn oversized_entity_ids(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            max_length: usize,
+        ) -> Result<Vec<OversizedEntityIds>, StoreError>;
+
+        fn blocks_with_entity_changes(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            since: BlockNumber,
+            to: BlockNumber,
+            limit: usize,
+        ) -> Result<Vec<BlockNumber>, StoreError>;
+
+        fn deployment_size(&self, subgraph_id: &SubgraphDeploymentId) -> Result<u64, StoreError>;
+
+        fn request_storage_migration(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+        ) -> Result<(), StoreError>;
+
+        fn storage_migrations_requested(&self) -> Result<Vec<SubgraphDeploymentId>, StoreError>;
+
+        fn migrate_storage_batch(
+            &self,
+            logger: &Logger,
+            subgraph_id: &SubgraphDeploymentId,
+            batch_size: i64,
+        ) -> Result<bool, StoreError>;
+
+        fn start_copy_deployment(
+            &self,
+            base: &SubgraphDeploymentId,
+            subgraph_id: &SubgraphDeploymentId,
+            block: EthereumBlockPointer,
+        ) -> Result<Vec<String>, StoreError>;
+
+        fn copy_deployment_batch(
+            &self,
+            base: &SubgraphDeploymentId,
+            subgraph_id: &SubgraphDeploymentId,
+            entity_type: &str,
+            block: EthereumBlockPointer,
+            batch_size: i64,
+        ) -> Result<bool, StoreError>;
+
+        fn finish_copy_deployment(
+            &self,
+            base: &SubgraphDeploymentId,
+            subgraph_id: &SubgraphDeploymentId,
+            block: EthereumBlockPointer,
+            ops: Vec<MetadataOperation>,
+        ) -> Result<(), StoreError>;
+
+        fn rewind_deployment(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            block: EthereumBlockPointer,
+        ) -> Result<(), StoreError>;
+
+        fn transact_pending_block_operations(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            block: EthereumBlockPointer,
+            mods: Vec<EntityModification>,
+        ) -> Result<(), StoreError>;
+
+        fn write_annotations(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            mods: Vec<EntityModification>,
+        ) -> Result<(), StoreError>;
+
+        fn proof_of_indexing(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            block: BlockNumber,
+        ) -> Result<Option<ProofOfIndexing>, StoreError>;
+
+        fn proofs_of_indexing(
+            &self,
+            subgraph_id: &SubgraphDeploymentId,
+            from: BlockNumber,
+            to: BlockNumber,
+        ) -> Result<Vec<ProofOfIndexing>, StoreError>;
     }
 
     trait ChainStore: Send + Sync + 'static {
@@ -112,6 +239,8 @@ mock! {
 
         fn blocks(&self, hashes: Vec<H256>) -> Result<Vec<LightEthereumBlock>, Error>;
 
+        fn block_timestamps(&self, hashes: Vec<H256>) -> Result<HashMap<H256, u64>, Error>;
+
         fn ancestor_block(
             &self,
             block_ptr: EthereumBlockPointer,
@@ -127,6 +256,13 @@ mock! {
 }
 
 pub fn mock_store_with_users_subgraph() -> (Arc<MockStore>, SubgraphDeploymentId) {
+    let (store, subgraph_id) = mock_users_subgraph_store();
+    (Arc::new(store), subgraph_id)
+}
+
+/// Like `mock_store_with_users_subgraph`, but the store can be given
+/// further expectations
+pub fn mock_users_subgraph_store() -> (MockStore, SubgraphDeploymentId) {
     let mut store = MockStore::new();
 
     let subgraph_id = SubgraphDeploymentId::new("users").unwrap();
@@ -168,5 +304,5 @@ pub fn mock_store_with_users_subgraph() -> (Arc<MockStore>, SubgraphDeploymentId
             Ok(Arc::new(schema))
         });
 
-    (Arc::new(store), subgraph_id)
+    (store, subgraph_id)
 }
@@ -3,7 +3,7 @@ use git_testament::{git_testament, render_testament};
 use ipfs_api::IpfsClient;
 use lazy_static::lazy_static;
 use prometheus::Registry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::str::FromStr;
 use std::time::Duration;
@@ -15,10 +15,15 @@ use graph::prelude::{
     EthereumAdapter as EthereumAdapterTrait, IndexNodeServer as _, JsonRpcServer as _, *,
 };
 use graph::util::security::SafeDisplay;
-use graph_chain_ethereum::{network_indexer, BlockIngestor, BlockStreamBuilder, Transport};
+use graph_chain_ethereum::{
+    network_indexer, BlockDecoder, BlockIngestor, BlockStreamBuilder, EthereumClient, ReorgMetrics,
+    Transport,
+};
 use graph_core::{
-    LinkResolver, MetricsRegistry, SubgraphAssignmentProvider as IpfsSubgraphAssignmentProvider,
-    SubgraphInstanceManager, SubgraphRegistrar as IpfsSubgraphRegistrar,
+    HandlerSimulator, IdleDeploymentMonitor, IndexingRulesReconciler, LinkResolver,
+    MetricsRegistry, ShutdownCoordinator, StandbyFollower,
+    SubgraphAssignmentProvider as IpfsSubgraphAssignmentProvider, SubgraphInstanceManager,
+    SubgraphRegistrar as IpfsSubgraphRegistrar, WebhookNotifier,
 };
 use graph_runtime_wasm::RuntimeHostBuilder as WASMRuntimeHostBuilder;
 use graph_server_http::GraphQLServer as GraphQLQueryServer;
@@ -27,7 +32,7 @@ use graph_server_json_rpc::JsonRpcServer;
 use graph_server_metrics::PrometheusMetricsServer;
 use graph_server_websocket::SubscriptionServer as GraphQLSubscriptionServer;
 use graph_store_postgres::connection_pool::create_connection_pool;
-use graph_store_postgres::{Store as DieselStore, StoreConfig};
+use graph_store_postgres::{Store as DieselStore, StoreConfig, TableMaintenance};
 
 lazy_static! {
     // Default to an Ethereum reorg threshold to 50 blocks
@@ -86,10 +91,12 @@ async fn main() {
                 .required_unless_one(&["ethereum-ws", "ethereum-ipc"])
                 .conflicts_with_all(&["ethereum-ws", "ethereum-ipc"])
                 .long("ethereum-rpc")
-                .value_name("NETWORK_NAME:URL")
+                .value_name("NETWORK_NAME:[CLIENT:]URL")
                 .help(
                     "Ethereum network name (e.g. 'mainnet') and \
-                     Ethereum RPC URL, separated by a ':'",
+                     Ethereum RPC URL, separated by a ':'. The URL can be \
+                     prefixed with the client the node runs (one of geth, \
+                     erigon, parity, generic), e.g. 'mainnet:geth:URL'",
                 ),
         )
         .arg(
@@ -100,10 +107,12 @@ async fn main() {
                 .required_unless_one(&["ethereum-rpc", "ethereum-ipc"])
                 .conflicts_with_all(&["ethereum-rpc", "ethereum-ipc"])
                 .long("ethereum-ws")
-                .value_name("NETWORK_NAME:URL")
+                .value_name("NETWORK_NAME:[CLIENT:]URL")
                 .help(
                     "Ethereum network name (e.g. 'mainnet') and \
-                     Ethereum WebSocket URL, separated by a ':'",
+                     Ethereum WebSocket URL, separated by a ':'. The URL \
+                     can be prefixed with the client the node runs, \
+                     like for --ethereum-rpc",
                 ),
         )
         .arg(
@@ -114,10 +123,12 @@ async fn main() {
                 .required_unless_one(&["ethereum-rpc", "ethereum-ws"])
                 .conflicts_with_all(&["ethereum-rpc", "ethereum-ws"])
                 .long("ethereum-ipc")
-                .value_name("NETWORK_NAME:FILE")
+                .value_name("NETWORK_NAME:[CLIENT:]FILE")
                 .help(
                     "Ethereum network name (e.g. 'mainnet') and \
-                     Ethereum IPC pipe, separated by a ':'",
+                     Ethereum IPC pipe, separated by a ':'. The pipe \
+                     can be prefixed with the client the node runs, \
+                     like for --ethereum-rpc",
                 ),
         )
         .arg(
@@ -395,9 +406,6 @@ async fn main() {
             .await
     });
 
-    // Convert the client into a link resolver
-    let link_resolver = Arc::new(LinkResolver::from(ipfs_client));
-
     // Set up Prometheus registry
     let prometheus_registry = Arc::new(Registry::new());
     let metrics_registry = Arc::new(MetricsRegistry::new(
@@ -407,8 +415,14 @@ async fn main() {
     let mut metrics_server =
         PrometheusMetricsServer::new(&logger_factory, prometheus_registry.clone());
 
+    // Convert the client into a link resolver
+    let link_resolver =
+        Arc::new(LinkResolver::from(ipfs_client).with_metrics(metrics_registry.clone()));
+
     // Ethereum clients
-    let eth_adapters = [
+    let decoders = graph_chain_ethereum::block_decoders_from_env()
+        .unwrap_or_else(|e| panic!("Failed to load block decoders: {}", e));
+    let eth_adapters: HashMap<_, _> = [
         (ConnectionType::RPC, ethereum_rpc),
         (ConnectionType::IPC, ethereum_ipc),
         (ConnectionType::WS, ethereum_ws),
@@ -422,6 +436,7 @@ async fn main() {
             values.unwrap(),
             connection_type,
             metrics_registry.clone(),
+            &decoders,
         ) {
             Ok(adapter) => adapters.into_iter().chain(adapter).collect(),
             Err(e) => {
@@ -433,6 +448,20 @@ async fn main() {
         }
     });
 
+    // A decoder for a network without an Ethereum node is most likely a
+    // typo in the network name, which would leave the network undecoded
+    let undecoded: Vec<_> = decoders
+        .keys()
+        .filter(|network| !eth_adapters.contains_key(*network))
+        .map(String::as_str)
+        .collect();
+    if !undecoded.is_empty() {
+        panic!(
+            "GRAPH_ETHEREUM_DECODERS has decoders for networks without an Ethereum node: {}",
+            undecoded.join(", ")
+        );
+    }
+
     // Set up Store
     info!(
         logger,
@@ -456,6 +485,9 @@ async fn main() {
         connection_pool_registry,
     );
 
+    // Tune autovacuum for entity tables according to their churn
+    TableMaintenance::new(&logger, postgres_conn_pool.clone()).start();
+
     graph::spawn(
         futures::stream::FuturesOrdered::from_iter(stores_eth_adapters.into_iter().map(
             |(network_name, eth_adapter)| {
@@ -500,10 +532,20 @@ async fn main() {
         .and_then(move |stores| {
             let generic_store = stores.values().next().expect("error creating stores");
 
-            let graphql_runner = Arc::new(graph_core::GraphQlRunner::new(
-                &logger,
-                generic_store.clone(),
-            ));
+            // Queries and triggers per deployment, to detect idle deployments
+            let activity = Arc::new(DeploymentActivity::new());
+
+            // How far starting the assigned deployments has come; the index
+            // node server reports the node as ready once they all started
+            let startup = Arc::new(StartupStatus::new());
+
+            let mut graphql_runner = graph_core::GraphQlRunner::new(&logger, generic_store.clone())
+                .with_activity(activity.clone());
+            if graph_core::QueryCache::is_enabled() {
+                graphql_runner =
+                    graphql_runner.with_cache(Arc::new(graph_core::QueryCache::new(&logger)));
+            }
+            let graphql_runner = Arc::new(graphql_runner);
             let mut graphql_server = GraphQLQueryServer::new(
                 &logger_factory,
                 graphql_metrics_registry,
@@ -521,7 +563,11 @@ async fn main() {
                 &logger_factory,
                 graphql_runner.clone(),
                 generic_store.clone(),
+                link_resolver.clone(),
+                metrics_registry.clone(),
                 node_id.clone(),
+                eth_adapters.clone(),
+                startup.clone(),
             );
 
             // Spawn Ethereum network indexers for all networks that are to be indexed
@@ -568,6 +614,8 @@ async fn main() {
 
                 info!(logger, "Starting block ingestors");
 
+                let reorg_metrics = Arc::new(ReorgMetrics::new(metrics_registry.clone()));
+
                 // Create Ethereum block ingestors and spawn a thread to run each
                 eth_adapters.iter().for_each(|(network_name, eth_adapter)| {
                     info!(
@@ -583,6 +631,7 @@ async fn main() {
                         network_name.to_string(),
                         &logger_factory,
                         block_polling_interval,
+                        reorg_metrics.clone(),
                     )
                     .expect("failed to create Ethereum block ingestor");
 
@@ -605,6 +654,39 @@ async fn main() {
                 stores.clone(),
             );
 
+            // Run single handlers of deployments for debugging, without
+            // writing their changes to the store
+            let handler_simulator = Arc::new(HandlerSimulator::new(
+                &logger_factory,
+                link_resolver.clone(),
+                runtime_host_builder.clone(),
+                stores.clone(),
+                eth_adapters.clone(),
+                Arc::new(graph_core::DataSourceLoader::new(
+                    generic_store.clone(),
+                    link_resolver.clone(),
+                    graphql_runner.clone(),
+                )),
+            ));
+
+            // Follow the deployments for which this node is the standby
+            // node and check their proofs of indexing
+            StandbyFollower::new(
+                &logger_factory,
+                link_resolver.clone(),
+                runtime_host_builder.clone(),
+                generic_store.clone(),
+                stores.clone(),
+                eth_adapters.clone(),
+                Arc::new(graph_core::DataSourceLoader::new(
+                    generic_store.clone(),
+                    link_resolver.clone(),
+                    graphql_runner.clone(),
+                )),
+                node_id.clone(),
+            )
+            .start();
+
             let subgraph_instance_manager = SubgraphInstanceManager::new(
                 &logger_factory,
                 stores.clone(),
@@ -612,6 +694,13 @@ async fn main() {
                 runtime_host_builder,
                 block_stream_builder,
                 metrics_registry.clone(),
+                Some(Arc::new(graph_core::DataSourceLoader::new(
+                    generic_store.clone(),
+                    link_resolver.clone(),
+                    graphql_runner.clone(),
+                ))),
+                Some(activity.clone()),
+                Some(startup.clone()),
             );
 
             // Create IPFS-based subgraph provider
@@ -620,7 +709,9 @@ async fn main() {
                 link_resolver.clone(),
                 generic_store.clone(),
                 graphql_runner.clone(),
-            );
+                metrics_registry.clone(),
+            )
+            .with_startup(startup.clone());
 
             // Forward subgraph events from the subgraph provider to the subgraph instance manager
             graph::spawn(
@@ -628,6 +719,7 @@ async fn main() {
                     .unwrap()
                     .compat(),
             );
+            let subgraph_provider = Arc::new(subgraph_provider);
 
             // Check version switching mode environment variable
             let version_switching_mode = SubgraphVersionSwitchingMode::parse(
@@ -638,16 +730,19 @@ async fn main() {
             );
 
             // Create named subgraph provider for resolving subgraph name->ID mappings
-            let subgraph_registrar = Arc::new(IpfsSubgraphRegistrar::new(
-                &logger_factory,
-                link_resolver,
-                Arc::new(subgraph_provider),
-                generic_store.clone(),
-                stores,
-                eth_adapters.clone(),
-                node_id.clone(),
-                version_switching_mode,
-            ));
+            let subgraph_registrar = Arc::new(
+                IpfsSubgraphRegistrar::new(
+                    &logger_factory,
+                    link_resolver,
+                    subgraph_provider.clone(),
+                    generic_store.clone(),
+                    stores,
+                    eth_adapters.clone(),
+                    node_id.clone(),
+                    version_switching_mode,
+                )
+                .with_startup(startup.clone()),
+            );
             graph::spawn(
                 subgraph_registrar
                     .start()
@@ -655,12 +750,56 @@ async fn main() {
                     .compat(),
             );
 
+            // Deploy, pause and remove deployments according to the
+            // indexing rules, if there are any
+            if let Some(path) = env::var_os("GRAPH_INDEXING_RULES") {
+                IndexingRulesReconciler::new(
+                    &logger_factory,
+                    path.into(),
+                    subgraph_registrar.clone(),
+                    generic_store.clone(),
+                    node_id.clone(),
+                )
+                .with_startup(startup.clone())
+                .start();
+            }
+
+            // Post lifecycle events of deployments on this node to a webhook
+            if let Ok(url) = env::var("GRAPH_WEBHOOK_URL") {
+                WebhookNotifier::new(&logger_factory, url, generic_store.clone(), node_id.clone())
+                    .start();
+            }
+
+            // Let deployments finish their block before the node exits
+            ShutdownCoordinator::new(
+                &logger_factory,
+                subgraph_provider.clone(),
+                generic_store.clone(),
+                node_id.clone(),
+                startup.clone(),
+            )
+            .with_activity(activity.clone())
+            .start();
+
+            // Downgrade deployments that nobody uses; this is off unless
+            // GRAPH_IDLE_DEPLOYMENT_DAYS is set
+            IdleDeploymentMonitor::new(
+                &logger_factory,
+                subgraph_provider,
+                generic_store.clone(),
+                activity,
+                node_id.clone(),
+            )
+            .with_startup(startup)
+            .start();
+
             // Start admin JSON-RPC server.
             let json_rpc_server = JsonRpcServer::serve(
                 json_rpc_port,
                 http_port,
                 ws_port,
                 subgraph_registrar.clone(),
+                handler_simulator,
                 node_id.clone(),
                 logger.clone(),
             )
@@ -687,8 +826,14 @@ async fn main() {
                     subgraph_registrar
                         .create_subgraph(name.clone())
                         .and_then(move |_| {
-                            subgraph_registrar.create_subgraph_version(name, subgraph_id, node_id)
+                            subgraph_registrar.create_subgraph_version(
+                                name,
+                                subgraph_id,
+                                node_id,
+                                BTreeMap::new(),
+                            )
                         })
+                        .map(|_| ())
                         .map_err(|e| {
                             panic!("Failed to deploy subgraph from `--subgraph` flag: {}", e)
                         })
@@ -768,65 +913,144 @@ fn parse_ethereum_networks_and_nodes(
     networks: clap::Values,
     connection_type: ConnectionType,
     registry: Arc<MetricsRegistry>,
+    decoders: &HashMap<String, Arc<dyn BlockDecoder>>,
 ) -> Result<HashMap<String, Arc<dyn EthereumAdapterTrait>>, Error> {
     let eth_rpc_metrics = Arc::new(ProviderEthRpcMetrics::new(registry));
     networks
         .map(|network| {
-            if network.starts_with("wss://")
-                || network.starts_with("http://")
-                || network.starts_with("https://")
-            {
-                return Err(format_err!(
-                    "Is your Ethereum node string missing a network name? \
-                     Try 'mainnet:' + the Ethereum node URL."
-                ));
-            } else {
-                // Parse string (format is "NETWORK_NAME:URL")
-                let split_at = network.find(':').ok_or_else(|| {
-                    return format_err!(
-                        "A network name must be provided alongside the \
-                         Ethereum node location. Try e.g. 'mainnet:URL'."
-                    );
-                })?;
+            let (name, client, loc) = parse_ethereum_node(network)?;
 
-                let (name, loc_with_delim) = network.split_at(split_at);
-                let loc = &loc_with_delim[1..];
+            info!(
+                logger,
+                "Creating transport";
+                "network" => &name,
+                "url" => &loc,
+                "client" => client.to_string(),
+            );
 
-                if name.is_empty() {
-                    return Err(format_err!(
-                        "Ethereum network name cannot be an empty string"
-                    ));
-                }
+            let (transport_event_loop, transport) = match connection_type {
+                ConnectionType::RPC => Transport::new_rpc(loc),
+                ConnectionType::IPC => Transport::new_ipc(loc),
+                ConnectionType::WS => Transport::new_ws(loc),
+            };
 
-                if loc.is_empty() {
-                    return Err(format_err!("Ethereum node URL cannot be an empty string"));
-                }
+            // If we drop the event loop the transport will stop working.
+            // For now it's fine to just leak it.
+            std::mem::forget(transport_event_loop);
 
+            let mut adapter = graph_chain_ethereum::EthereumAdapter::new(
+                transport,
+                eth_rpc_metrics.clone(),
+                client,
+            );
+            if let Some(decoder) = decoders.get(name) {
                 info!(
                     logger,
-                    "Creating transport";
+                    "Decoding blocks with `{}` version {}",
+                    decoder.name(),
+                    decoder.version();
                     "network" => &name,
-                    "url" => &loc,
                 );
-
-                let (transport_event_loop, transport) = match connection_type {
-                    ConnectionType::RPC => Transport::new_rpc(loc),
-                    ConnectionType::IPC => Transport::new_ipc(loc),
-                    ConnectionType::WS => Transport::new_ws(loc),
-                };
-
-                // If we drop the event loop the transport will stop working.
-                // For now it's fine to just leak it.
-                std::mem::forget(transport_event_loop);
-
-                Ok((
-                    name.to_string(),
-                    Arc::new(graph_chain_ethereum::EthereumAdapter::new(
-                        transport,
-                        eth_rpc_metrics.clone(),
-                    )) as Arc<dyn EthereumAdapter>,
-                ))
+                adapter = adapter.with_decoder(decoder.clone());
             }
+
+            Ok((
+                name.to_string(),
+                Arc::new(adapter) as Arc<dyn EthereumAdapter>,
+            ))
         })
         .collect()
 }
+
+/// Split an Ethereum node string of the form `NETWORK_NAME:[CLIENT:]URL`
+/// into the network name, the client and the URL
+fn parse_ethereum_node(network: &str) -> Result<(&str, EthereumClient, &str), Error> {
+    if network.starts_with("wss://")
+        || network.starts_with("http://")
+        || network.starts_with("https://")
+    {
+        return Err(format_err!(
+            "Is your Ethereum node string missing a network name? \
+             Try 'mainnet:' + the Ethereum node URL."
+        ));
+    }
+
+    // Parse string (format is "NETWORK_NAME:URL")
+    let split_at = network.find(':').ok_or_else(|| {
+        format_err!(
+            "A network name must be provided alongside the \
+             Ethereum node location. Try e.g. 'mainnet:URL'."
+        )
+    })?;
+
+    let (name, loc_with_delim) = network.split_at(split_at);
+    let loc = &loc_with_delim[1..];
+
+    if name.is_empty() {
+        return Err(format_err!(
+            "Ethereum network name cannot be an empty string"
+        ));
+    }
+
+    // An optional client hint precedes the location
+    let (client, loc) = match loc.find(':') {
+        Some(split_at) => match loc[..split_at].parse::<EthereumClient>() {
+            Ok(client) => (client, &loc[split_at + 1..]),
+            Err(_) => (EthereumClient::default(), loc),
+        },
+        None => (EthereumClient::default(), loc),
+    };
+
+    if loc.is_empty() {
+        return Err(format_err!("Ethereum node URL cannot be an empty string"));
+    }
+
+    Ok((name, client, loc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ethereum_nodes_name_their_network_and_optionally_their_client() {
+        assert_eq!(
+            ("mainnet", EthereumClient::Generic, "http://localhost:8545"),
+            parse_ethereum_node("mainnet:http://localhost:8545").unwrap()
+        );
+        assert_eq!(
+            ("mainnet", EthereumClient::Parity, "http://localhost:8545"),
+            parse_ethereum_node("mainnet:parity:http://localhost:8545").unwrap()
+        );
+        assert_eq!(
+            ("ropsten", EthereumClient::Geth, "/var/run/geth.ipc"),
+            parse_ethereum_node("ropsten:geth:/var/run/geth.ipc").unwrap()
+        );
+        assert_eq!(
+            ("xdai", EthereumClient::Erigon, "wss://xdai.example.com"),
+            parse_ethereum_node("xdai:erigon:wss://xdai.example.com").unwrap()
+        );
+
+        // Anything before the first colon of the URL that is not a client
+        // is part of the URL
+        assert_eq!(
+            ("mainnet", EthereumClient::Generic, "localhost:8545"),
+            parse_ethereum_node("mainnet:localhost:8545").unwrap()
+        );
+    }
+
+    #[test]
+    fn malformed_ethereum_nodes_are_rejected() {
+        for node in &[
+            "http://localhost:8545",
+            "https://localhost:8545",
+            "wss://localhost:8545",
+            "mainnet",
+            ":http://localhost:8545",
+            "mainnet:",
+            "mainnet:parity:",
+        ] {
+            assert!(parse_ethereum_node(node).is_err(), "{} is invalid", node);
+        }
+    }
+}
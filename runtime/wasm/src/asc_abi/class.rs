@@ -497,6 +497,15 @@ pub(crate) struct AscTypedMap<K, V> {
 pub(crate) type AscEntity = AscTypedMap<AscString, AscEnum<StoreValueKind>>;
 pub(crate) type AscJson = AscTypedMap<AscString, AscEnum<JsonValueKind>>;
 
+/// The outcome of a host function that can fail without failing the
+/// handler. Exactly one of `value` and `error` is not null.
+#[repr(C)]
+#[derive(AscType)]
+pub(crate) struct AscResult<V, E> {
+    pub value: AscPtr<V>,
+    pub error: AscPtr<E>,
+}
+
 #[repr(C)]
 #[derive(AscType)]
 pub(crate) struct AscUnresolvedContractCall {
@@ -12,7 +12,8 @@ use crate::mapping::{MappingContext, MappingRequest, MappingTrigger};
 use ethabi::{LogParam, RawLog};
 use graph::components::ethereum::*;
 use graph::components::store::Store;
-use graph::data::subgraph::{Mapping, Source};
+use graph::data::store::EntityIdLimits;
+use graph::data::subgraph::{Mapping, Source, API_VERSIONS};
 use graph::prelude::{
     RuntimeHost as RuntimeHostTrait, RuntimeHostBuilder as RuntimeHostBuilderTrait, *,
 };
@@ -161,9 +162,14 @@ impl RuntimeHost {
         metrics: Arc<HostMetrics>,
     ) -> Result<Self, Error> {
         let api_version = Version::parse(&config.mapping.api_version)?;
-        if !VersionReq::parse("<= 0.0.4").unwrap().matches(&api_version) {
+        let max_api_version = API_VERSIONS.last().unwrap();
+        if !VersionReq::parse(&format!("<= {}", max_api_version))
+            .unwrap()
+            .matches(&api_version)
+        {
             return Err(format_err!(
-                "This Graph Node only supports mapping API versions <= 0.0.4, but subgraph `{}` uses `{}`",
+                "This Graph Node only supports mapping API versions <= {}, but subgraph `{}` uses `{}`",
+                max_api_version,
                 config.subgraph_id,
                 api_version
             ));
@@ -184,6 +190,12 @@ impl RuntimeHost {
             .clone();
 
         let data_source_name = config.data_source_name;
+        let annotation_types = store.input_schema(&config.subgraph_id)?.annotation_types();
+        let entity_id_limits =
+            match store.get(SubgraphDeploymentEntity::key(config.subgraph_id.clone()))? {
+                Some(deployment) => SubgraphDeploymentEntity::entity_id_limits(&deployment)?,
+                None => EntityIdLimits::default(),
+            };
 
         // Create new instance of externally hosted functions invoker. The `Arc` is simply to avoid
         // implementing `Clone` for `HostExports`.
@@ -203,6 +215,8 @@ impl RuntimeHost {
                 .ok()
                 .and_then(|s| u64::from_str(&s).ok())
                 .map(Duration::from_secs),
+            annotation_types,
+            entity_id_limits,
         ));
 
         Ok(RuntimeHost {
@@ -489,6 +503,7 @@ impl RuntimeHostTrait for RuntimeHost {
         let (result_sender, result_receiver) = oneshot::channel();
         let start_time = Instant::now();
         let metrics = self.metrics.clone();
+        let journal_entry = JournalEntry::new(&block, &self.data_source_name, &state);
         Box::new(
             self.mapping_request_sender
                 .clone()
@@ -526,7 +541,7 @@ impl RuntimeHostTrait for RuntimeHost {
                         "handler" => &call_handler.handler,
                         "ms" => elapsed.as_millis(),
                     );
-                    result
+                    journal_entry.record(&call_handler.handler, elapsed, result)
                 }),
         )
     }
@@ -555,6 +570,7 @@ impl RuntimeHostTrait for RuntimeHost {
         let (result_sender, result_receiver) = oneshot::channel();
         let start_time = Instant::now();
         let metrics = self.metrics.clone();
+        let journal_entry = JournalEntry::new(&block, &self.data_source_name, &state);
         Box::new(
             self.mapping_request_sender
                 .clone()
@@ -591,7 +607,7 @@ impl RuntimeHostTrait for RuntimeHost {
                         "handler" => &block_handler.handler,
                         "ms" => elapsed.as_millis(),
                     );
-                    result
+                    journal_entry.record(&block_handler.handler, elapsed, result)
                 }),
         )
     }
@@ -730,6 +746,7 @@ impl RuntimeHostTrait for RuntimeHost {
         let event_signature = event_handler.event.clone();
         let start_time = Instant::now();
         let metrics = self.metrics.clone();
+        let journal_entry = JournalEntry::new(&block, &data_source_name, &state);
         Box::new(
             self.mapping_request_sender
                 .clone()
@@ -786,8 +803,58 @@ impl RuntimeHostTrait for RuntimeHost {
                             .as_millis(),
                     );
 
-                    result
+                    journal_entry.record(&event_handler.handler, elapsed, result)
                 }),
         )
     }
 }
+
+/// What the handler journal needs to know about a handler execution
+/// before the handler runs
+struct JournalEntry {
+    block: EthereumBlockPointer,
+    data_source: String,
+    /// The position of the execution among those of the block
+    index: usize,
+}
+
+impl JournalEntry {
+    fn new(block: &LightEthereumBlock, data_source: &str, state: &BlockState) -> Self {
+        JournalEntry {
+            block: EthereumBlockPointer::from(block),
+            data_source: data_source.to_owned(),
+            index: state.handler_executions.len(),
+        }
+    }
+
+    /// Add the execution of `handler` to the block state if it succeeded,
+    /// or to its error if it did not. Fails if the handlers for the block
+    /// now did more work than the budget allows
+    fn record(
+        self,
+        handler: &str,
+        duration: Duration,
+        result: Result<BlockState, Error>,
+    ) -> Result<BlockState, Error> {
+        let mut execution = HandlerExecution {
+            block: self.block,
+            data_source: self.data_source,
+            handler: handler.to_owned(),
+            duration,
+            error: None,
+        };
+        match result {
+            Ok(mut state) => {
+                state.handler_executions.push(execution);
+                match state.check_budget() {
+                    Ok(()) => Ok(state),
+                    Err(e) => Err(HandlerFailure::new(e, state).into()),
+                }
+            }
+            Err(e) => {
+                execution.error = Some(e.to_string());
+                Err(HandlerExecutionError::new(e, self.index, execution).into())
+            }
+        }
+    }
+}
@@ -3,13 +3,16 @@ use ethabi::{Address, Token};
 use graph::components::ethereum::*;
 use graph::components::store::EntityKey;
 use graph::data::store;
+use graph::data::store::EntityIdLimits;
 use graph::prelude::serde_json;
 use graph::prelude::{slog::b, slog::record_static, *};
+use graph::util::compression::Codec;
 use semver::Version;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::str::FromStr;
 use std::time::{Duration, Instant};
+use wasmi::Trap;
 use web3::types::H160;
 
 use graph_graphql::prelude::validate_entity;
@@ -36,6 +39,28 @@ impl From<graph::prelude::Error> for HostExportError<String> {
     }
 }
 
+/// The message of an error raised in a host function that was caused by an
+/// outage of the Ethereum node, IPFS or the database rather than by the
+/// mapping. Handlers that fail with it fail with a `TransientError`
+#[derive(Debug)]
+pub(crate) struct Transient(pub(crate) String);
+
+impl fmt::Display for Transient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The error for a host function that failed with `message` because of
+/// `error`, which is transient if `error` is
+fn host_error(message: String, error: &Error) -> Trap {
+    if TransientError::is_cause_of(error) {
+        HostExportError(Transient(message)).into()
+    } else {
+        HostExportError(message).into()
+    }
+}
+
 pub(crate) struct HostExports {
     subgraph_id: SubgraphDeploymentId,
     pub(crate) api_version: Version,
@@ -49,6 +74,12 @@ pub(crate) struct HostExports {
     call_cache: Arc<dyn EthereumCallCache>,
     store: Arc<dyn crate::RuntimeStore>,
     handler_timeout: Option<Duration>,
+    /// The entity types of the deployment that are declared with
+    /// `@annotation`
+    annotation_types: HashSet<String>,
+    /// The limits on entity IDs that were recorded when the deployment
+    /// was created
+    entity_id_limits: EntityIdLimits,
 }
 
 // Not meant to be useful, only to allow deriving.
@@ -72,6 +103,8 @@ impl HostExports {
         store: Arc<dyn crate::RuntimeStore>,
         call_cache: Arc<dyn EthereumCallCache>,
         handler_timeout: Option<Duration>,
+        annotation_types: HashSet<String>,
+        entity_id_limits: EntityIdLimits,
     ) -> Self {
         Self {
             subgraph_id,
@@ -86,6 +119,8 @@ impl HostExports {
             call_cache,
             store,
             handler_timeout,
+            annotation_types,
+            entity_id_limits,
         }
     }
 
@@ -136,13 +171,42 @@ impl HostExports {
             _ => (),
         }
 
+        // Reject IDs that the deployment does not allow, e.g., because they
+        // are so long that they bloat the indexes on the entity tables
+        self.entity_id_limits.check(&entity_id).map_err(|e| {
+            HostExportError(format!(
+                "Invalid ID for {} entity passed to `store.set()`: {}",
+                entity_type, e
+            ))
+        })?;
+
+        // Reject numbers that Postgres can not store now, rather than
+        // failing with an obscure error when the block is written. Report
+        // the first bad field by name so that the error is deterministic
+        if let Some((field, e)) = data
+            .iter()
+            .filter_map(|(field, value)| value.check_numeric_range().err().map(|e| (field, e)))
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+        {
+            return Err(HostExportError(format!(
+                "Value of field `{}` of {} entity with ID `{}` is out of range: {}",
+                field, entity_type, entity_id, e
+            )));
+        }
+
+        state
+            .count_entity_op()
+            .map_err(|e| HostExportError(e.to_string()))?;
+
+        self.check_not_annotation(&entity_type, "set")?;
+
+        let schema = self.store.input_schema(&self.subgraph_id)?;
         let key = EntityKey {
             subgraph_id: self.subgraph_id.clone(),
             entity_type,
             entity_id,
         };
         let entity = Entity::from(data);
-        let schema = self.store.input_schema(&self.subgraph_id)?;
         let is_valid = validate_entity(&schema.document, &key, &entity).is_ok();
         state.entity_cache.set(key.clone(), entity);
 
@@ -164,13 +228,53 @@ impl HostExports {
         state: &mut BlockState,
         entity_type: String,
         entity_id: String,
-    ) {
+    ) -> Result<(), HostExportError<impl ExportError>> {
+        state
+            .count_entity_op()
+            .map_err(|e| HostExportError(e.to_string()))?;
+
+        self.check_not_annotation(&entity_type, "remove")?;
+
         let key = EntityKey {
             subgraph_id: self.subgraph_id.clone(),
             entity_type,
             entity_id,
         };
         state.entity_cache.remove(key);
+        Ok(())
+    }
+
+    /// Annotation entities are only written through the `subgraph_annotate`
+    /// JSON-RPC method, and mappings can not access them at all: they are
+    /// not versioned, so handlers that read them would not produce the same
+    /// result on every node. This also keeps them out of the entity cache
+    /// that is kept between blocks, which annotation writes do not update
+    fn check_not_annotation(
+        &self,
+        entity_type: &str,
+        operation: &str,
+    ) -> Result<(), HostExportError<String>> {
+        if self.annotation_types.contains(entity_type) {
+            return Err(HostExportError(format!(
+                "`store.{}()` can not access entities of type {} since it is \
+                 declared with @annotation",
+                operation, entity_type
+            )));
+        }
+        Ok(())
+    }
+
+    /// The IDs of the entities of type `entity_type` that were created in
+    /// the current block, in the order in which they were created
+    pub(crate) fn store_created_in_block(
+        &self,
+        state: &mut BlockState,
+        entity_type: String,
+    ) -> Result<Vec<String>, HostExportError<impl ExportError>> {
+        state
+            .entity_cache
+            .created_entity_ids(self.store.as_ref(), &self.subgraph_id, &entity_type)
+            .map_err(HostExportError)
     }
 
     pub(crate) fn store_get(
@@ -179,7 +283,9 @@ impl HostExports {
         state: &mut BlockState,
         entity_type: String,
         entity_id: String,
-    ) -> Result<Option<Entity>, HostExportError<impl ExportError>> {
+    ) -> Result<Option<Entity>, Trap> {
+        self.check_not_annotation(&entity_type, "get")?;
+
         let start_time = Instant::now();
         let store_key = EntityKey {
             subgraph_id: self.subgraph_id.clone(),
@@ -190,7 +296,10 @@ impl HostExports {
         let result = state
             .entity_cache
             .get(self.store.as_ref(), &store_key)
-            .map_err(HostExportError)
+            .map_err(|e| {
+                let message = e.to_string();
+                host_error(message, &Error::from(e))
+            })
             .map(|ok| ok.to_owned());
 
         debug!(logger, "Store get finished";
@@ -206,7 +315,7 @@ impl HostExports {
         logger: &Logger,
         block: &LightEthereumBlock,
         unresolved_call: UnresolvedContractCall,
-    ) -> Result<Option<Vec<Token>>, HostExportError<impl ExportError>> {
+    ) -> Result<Option<Vec<Token>>, Trap> {
         let start_time = Instant::now();
 
         // Obtain the path to the contract ABI
@@ -280,10 +389,21 @@ impl HostExports {
                 info!(logger, "Contract call reverted"; "reason" => reason);
                 Ok(None)
             }
-            Err(e) => Err(HostExportError(format!(
-                "Failed to call function \"{}\" of contract \"{}\": {}",
-                unresolved_call.function_name, unresolved_call.contract_name, e
-            ))),
+            Err(e) => {
+                let message = format!(
+                    "Failed to call function \"{}\" of contract \"{}\": {}",
+                    unresolved_call.function_name, unresolved_call.contract_name, e
+                );
+                // Failing to reach the Ethereum node says nothing about the
+                // mapping, unlike calls that the ABI does not fit
+                Err(match e {
+                    EthereumContractCallError::Web3Error(_)
+                    | EthereumContractCallError::Timeout => {
+                        HostExportError(Transient(message)).into()
+                    }
+                    _ => HostExportError(message).into(),
+                })
+            }
         };
 
         debug!(logger, "Contract call finished";
@@ -374,6 +494,30 @@ impl HostExports {
         })
     }
 
+    /// Like `json_from_bytes`, but malformed JSON is returned as an error
+    /// that the mapping can handle instead of failing the handler.
+    pub(crate) fn json_try_from_bytes(&self, bytes: Vec<u8>) -> Result<serde_json::Value, String> {
+        serde_json::from_reader(&*bytes).map_err(|e| format!("Failed to parse JSON: {}", e))
+    }
+
+    /// Parses `bytes` as JSON and checks that the result has the shape
+    /// declared by `schema`. Malformed JSON and JSON of the wrong shape are
+    /// returned as an error for the mapping to handle; an invalid `schema`
+    /// is a bug in the mapping and fails the handler.
+    pub(crate) fn json_try_from_bytes_with_schema(
+        &self,
+        bytes: Vec<u8>,
+        schema: String,
+    ) -> Result<Result<serde_json::Value, String>, HostExportError<impl ExportError>> {
+        let shape = serde_json::from_str(&schema)
+            .map_err(|e| e.to_string())
+            .and_then(|schema| JsonShape::parse(&schema))
+            .map_err(|e| HostExportError(format!("Invalid JSON schema `{}`: {}", schema, e)))?;
+        Ok(self
+            .json_try_from_bytes(bytes)
+            .and_then(|value| shape.check(&value, "$").map(|()| value)))
+    }
+
     pub(crate) fn ipfs_cat(
         &self,
         logger: &Logger,
@@ -400,10 +544,10 @@ impl HostExports {
         callback: &str,
         user_data: store::Value,
         flags: Vec<String>,
-    ) -> Result<Vec<BlockState>, HostExportError<impl ExportError>> {
+    ) -> Result<Vec<BlockState>, Trap> {
         const JSON_FLAG: &str = "json";
         if !flags.contains(&JSON_FLAG.to_string()) {
-            return Err(HostExportError(format!("Flags must contain 'json'")));
+            return Err(HostExportError(format!("Flags must contain 'json'")).into());
         }
 
         let host_metrics = module.host_metrics.clone();
@@ -446,7 +590,7 @@ impl HostExports {
                         })
                         .collect()
                 })
-                .map_err(move |e| HostExportError(format!("{}: {}", errmsg, e.to_string()))),
+                .map_err(move |e| host_error(format!("{}: {}", errmsg, e), &e)),
         )
     }
 
@@ -491,6 +635,32 @@ impl HostExports {
         tiny_keccak::keccak256(&input)
     }
 
+    /// Decompresses `bytes` that were compressed with `codec`, which is
+    /// either `gzip` or `zstd`. Payloads that are malformed or decompress
+    /// to more than `MAX_DECOMPRESSED_BYTES` fail the handler
+    pub(crate) fn compression_decompress(
+        &self,
+        bytes: Vec<u8>,
+        codec: String,
+    ) -> Result<Vec<u8>, HostExportError<impl ExportError>> {
+        Codec::parse(&codec)
+            .and_then(|codec| codec.decompress(&bytes))
+            .map_err(|e| HostExportError(e.to_string()))
+    }
+
+    /// Compresses `bytes` with `codec`, which is either `gzip` or `zstd`.
+    /// The compression level is fixed so that the output only depends on
+    /// the input
+    pub(crate) fn compression_compress(
+        &self,
+        bytes: Vec<u8>,
+        codec: String,
+    ) -> Result<Vec<u8>, HostExportError<impl ExportError>> {
+        Codec::parse(&codec)
+            .and_then(|codec| codec.compress(&bytes))
+            .map_err(|e| HostExportError(e.to_string()))
+    }
+
     pub(crate) fn big_int_plus(&self, x: BigInt, y: BigInt) -> BigInt {
         x + y
     }
@@ -622,6 +792,18 @@ impl HostExports {
             })?
             .clone();
 
+        // Fail the handler, rather than the block, when the deployment would
+        // have too many data sources
+        if let Some(limit) = &state.data_source_limit {
+            if limit.exceeded_by(state.created_data_sources.len()) {
+                return Err(HostExportError(format!(
+                    "Failed to create data source from template `{}`: \
+                     the limit of {} data sources for this subgraph was reached",
+                    name, limit.max
+                )));
+            }
+        }
+
         // Remember that we need to create this data source
         state.created_data_sources.push(DataSourceTemplateInfo {
             data_source: self.data_source_name.clone(),
@@ -669,6 +851,165 @@ pub(crate) fn string_to_h160(string: &str) -> Result<H160, HostExportError<impl
         .map_err(|e| HostExportError(format!("Failed to convert string to Address/H160: {}", e)))
 }
 
+/// The shape a JSON value must have, declared with a subset of JSON
+/// Schema: `type` (a single type name), `required`, `properties` and
+/// `items`. Any other keyword is rejected, so that a mapping can not
+/// believe it validated something it didn't.
+struct JsonShape {
+    kind: Option<String>,
+    required: Vec<String>,
+    properties: BTreeMap<String, JsonShape>,
+    items: Option<Box<JsonShape>>,
+}
+
+const JSON_KINDS: &[&str] = &[
+    "null", "boolean", "integer", "number", "string", "array", "object",
+];
+
+fn json_kind(value: &serde_json::Value) -> &'static str {
+    use serde_json::Value;
+
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+impl JsonShape {
+    fn parse(schema: &serde_json::Value) -> Result<Self, String> {
+        use serde_json::Value;
+
+        let schema = schema
+            .as_object()
+            .ok_or_else(|| format!("schema `{}` is not an object", schema))?;
+        let mut shape = JsonShape {
+            kind: None,
+            required: vec![],
+            properties: BTreeMap::new(),
+            items: None,
+        };
+        for (keyword, value) in schema {
+            match (keyword.as_str(), value) {
+                ("type", Value::String(kind)) if JSON_KINDS.contains(&kind.as_str()) => {
+                    shape.kind = Some(kind.clone())
+                }
+                ("required", Value::Array(names)) => {
+                    shape.required = names
+                        .iter()
+                        .map(|name| {
+                            name.as_str()
+                                .map(str::to_owned)
+                                .ok_or_else(|| format!("required field `{}` is not a string", name))
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                ("properties", Value::Object(properties)) => {
+                    shape.properties = properties
+                        .iter()
+                        .map(|(name, schema)| Ok((name.clone(), JsonShape::parse(schema)?)))
+                        .collect::<Result<_, String>>()?
+                }
+                ("items", schema) => shape.items = Some(Box::new(JsonShape::parse(schema)?)),
+                _ => return Err(format!("unsupported keyword `{}: {}`", keyword, value)),
+            }
+        }
+        Ok(shape)
+    }
+
+    /// Checks that `value`, found at `path` in the document, has this
+    /// shape. Reports the first mismatch, looking at fields in a fixed
+    /// order so that the error is the same on every node.
+    fn check(&self, value: &serde_json::Value, path: &str) -> Result<(), String> {
+        use serde_json::Value;
+
+        if let Some(kind) = &self.kind {
+            let matches = match (kind.as_str(), value) {
+                ("integer", Value::Number(number)) => !number
+                    .to_string()
+                    .contains(|c| c == '.' || c == 'e' || c == 'E'),
+                (kind, value) => kind == json_kind(value),
+            };
+            if !matches {
+                return Err(format!(
+                    "`{}` must be of type {} but is of type {}",
+                    path,
+                    kind,
+                    json_kind(value)
+                ));
+            }
+        }
+
+        match value {
+            Value::Object(object) => {
+                if let Some(name) = self
+                    .required
+                    .iter()
+                    .find(|name| !object.contains_key(*name))
+                {
+                    return Err(format!("`{}` is missing required field `{}`", path, name));
+                }
+                for (name, shape) in &self.properties {
+                    if let Some(value) = object.get(name) {
+                        shape.check(value, &format!("{}.{}", path, name))?;
+                    }
+                }
+            }
+            Value::Array(items) => {
+                if let Some(shape) = &self.items {
+                    for (i, item) in items.iter().enumerate() {
+                        shape.check(item, &format!("{}[{}]", path, i))?;
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn json_shape() {
+    let shape = JsonShape::parse(&serde_json::json!({
+        "type": "object",
+        "required": ["name", "tags"],
+        "properties": {
+            "name": { "type": "string" },
+            "size": { "type": "integer" },
+            "tags": { "type": "array", "items": { "type": "string" } }
+        }
+    }))
+    .unwrap();
+
+    let check = |json: serde_json::Value| shape.check(&json, "$");
+    assert_eq!(
+        Ok(()),
+        check(serde_json::json!({ "name": "a", "size": 1, "tags": [], "extra": null }))
+    );
+    assert_eq!(
+        Err("`$` must be of type object but is of type array".to_owned()),
+        check(serde_json::json!([]))
+    );
+    assert_eq!(
+        Err("`$` is missing required field `tags`".to_owned()),
+        check(serde_json::json!({ "name": "a" }))
+    );
+    assert_eq!(
+        Err("`$.size` must be of type integer but is of type number".to_owned()),
+        check(serde_json::json!({ "name": "a", "size": 1.5, "tags": [] }))
+    );
+    assert_eq!(
+        Err("`$.tags[1]` must be of type string but is of type number".to_owned()),
+        check(serde_json::json!({ "name": "a", "tags": ["x", 2] }))
+    );
+
+    assert!(JsonShape::parse(&serde_json::json!({ "type": "date" })).is_err());
+    assert!(JsonShape::parse(&serde_json::json!({ "minLength": 1 })).is_err());
+}
+
 #[test]
 fn test_string_to_h160_with_0x() {
     assert_eq!(
@@ -1,13 +1,25 @@
-use crate::module::WasmiModule;
+use crate::module::{host_function_index, stub_unknown_import, WasmiModule};
 use ethabi::LogParam;
 use futures::sync::mpsc;
 use futures::sync::oneshot;
 use graph::components::ethereum::*;
+use graph::data::subgraph::API_VERSIONS;
 use graph::prelude::*;
+use lazy_static::lazy_static;
+use parity_wasm::elements::{External, Type};
+use std::env;
 use std::thread;
 use std::time::Instant;
 use web3::types::{Log, Transaction};
 
+lazy_static! {
+    /// Whether to run every handler twice and fail it if the two runs give
+    /// different results
+    static ref PARANOID_HANDLERS: bool = env::var("GRAPH_PARANOID_HANDLERS")
+        .map(|s| s == "true")
+        .unwrap_or(false);
+}
+
 /// Spawn a wasm module in its own thread.
 pub fn spawn_module(
     parsed_module: parity_wasm::elements::Module,
@@ -17,6 +29,13 @@ pub fn spawn_module(
     runtime: tokio::runtime::Handle,
 ) -> Result<mpsc::Sender<MappingRequest>, Error> {
     let valid_module = Arc::new(ValidModule::new(parsed_module)?);
+    if !valid_module.stubbed_imports.is_empty() {
+        warn!(
+            logger,
+            "Replacing host functions that this node does not provide with stubs that do nothing";
+            "functions" => valid_module.stubbed_imports.join(", ")
+        );
+    }
 
     // Create channel for event handling requests
     let (mapping_request_sender, mapping_request_receiver) = mpsc::channel(100);
@@ -42,46 +61,11 @@ pub fn spawn_module(
                         result_sender,
                     } = request;
 
-                    // Start the WASMI module runtime.
-                    let section = host_metrics.stopwatch.start_section("module_init");
-                    let module = WasmiModule::from_valid_module_with_ctx(
-                        valid_module.clone(),
-                        ctx,
-                        host_metrics.clone(),
-                    )?;
-                    section.end();
-
-                    let section = host_metrics.stopwatch.start_section("run_handler");
-                    let result = match trigger {
-                        MappingTrigger::Log {
-                            transaction,
-                            log,
-                            params,
-                            handler,
-                        } => module.handle_ethereum_log(
-                            handler.handler.as_str(),
-                            transaction,
-                            log,
-                            params,
-                        ),
-                        MappingTrigger::Call {
-                            transaction,
-                            call,
-                            inputs,
-                            outputs,
-                            handler,
-                        } => module.handle_ethereum_call(
-                            handler.handler.as_str(),
-                            transaction,
-                            call,
-                            inputs,
-                            outputs,
-                        ),
-                        MappingTrigger::Block { handler } => {
-                            module.handle_ethereum_block(handler.handler.as_str())
-                        }
+                    let result = if *PARANOID_HANDLERS {
+                        run_handler_twice(&valid_module, ctx, trigger, &host_metrics)?
+                    } else {
+                        run_handler(&valid_module, ctx, trigger, &host_metrics)?
                     };
-                    section.end();
 
                     result_sender
                         .send((result, future::ok(Instant::now())))
@@ -101,7 +85,133 @@ pub fn spawn_module(
     Ok(mapping_request_sender)
 }
 
-#[derive(Debug)]
+/// Instantiate the module with `ctx` and run the handler for `trigger`.
+/// The outer error means that the module could not be instantiated, the
+/// inner one that the handler failed
+fn run_handler(
+    valid_module: &Arc<ValidModule>,
+    ctx: MappingContext,
+    trigger: MappingTrigger,
+    host_metrics: &Arc<HostMetrics>,
+) -> Result<Result<BlockState, Error>, Error> {
+    // Start the WASMI module runtime.
+    let section = host_metrics.stopwatch.start_section("module_init");
+    let module =
+        WasmiModule::from_valid_module_with_ctx(valid_module.clone(), ctx, host_metrics.clone())?;
+    section.end();
+
+    let section = host_metrics.stopwatch.start_section("run_handler");
+    let result = match trigger {
+        MappingTrigger::Log {
+            transaction,
+            log,
+            params,
+            handler,
+        } => module.handle_ethereum_log(handler.handler.as_str(), transaction, log, params),
+        MappingTrigger::Call {
+            transaction,
+            call,
+            inputs,
+            outputs,
+            handler,
+        } => module.handle_ethereum_call(
+            handler.handler.as_str(),
+            transaction,
+            call,
+            inputs,
+            outputs,
+        ),
+        MappingTrigger::Block { handler } => module.handle_ethereum_block(handler.handler.as_str()),
+    };
+    section.end();
+    Ok(result)
+}
+
+/// Run the handler for `trigger` twice, the second time against a copy of
+/// the block state from before the first run, and fail it if the runs
+/// differ. This finds handlers that are not deterministic, e.g., because
+/// they iterate over an unordered map, before their results diverge from
+/// those of other indexers
+fn run_handler_twice(
+    valid_module: &Arc<ValidModule>,
+    ctx: MappingContext,
+    trigger: MappingTrigger,
+    host_metrics: &Arc<HostMetrics>,
+) -> Result<Result<BlockState, Error>, Error> {
+    let logger = ctx.logger.clone();
+    let block = EthereumBlockPointer::from(ctx.block.as_ref());
+    let handler = trigger.handler_name().to_owned();
+    let copy = MappingContext {
+        state: ctx.state.clone(),
+        ..ctx.clone()
+    };
+
+    let first = run_handler(valid_module, ctx, trigger.clone(), host_metrics)?;
+    let second = run_handler(valid_module, copy, trigger, host_metrics)?;
+    Ok(match divergence(&first, &second) {
+        None => first,
+        Some(difference) => {
+            error!(
+                logger,
+                "Handler is not deterministic";
+                "handler" => &handler,
+                "block_number" => block.number,
+                "block_hash" => block.hash_hex(),
+                "difference" => &difference,
+            );
+            Err(format_err!(
+                "Handler `{}` is not deterministic: running it twice for block {} \
+                 gave different results: {}",
+                handler,
+                block,
+                difference
+            ))
+        }
+    })
+}
+
+/// How the results of two runs of the same handler differ, if they do
+fn divergence(
+    first: &Result<BlockState, Error>,
+    second: &Result<BlockState, Error>,
+) -> Option<String> {
+    match (first, second) {
+        (Ok(first), Ok(second)) => {
+            if let Some(key) = first.entity_cache.different_update(&second.entity_cache) {
+                return Some(format!(
+                    "the runs changed entity {}[{}] differently",
+                    key.entity_type, key.entity_id
+                ));
+            }
+            let created = |state: &BlockState| {
+                state
+                    .created_data_sources
+                    .iter()
+                    .map(|info| (info.template.name.clone(), info.params.clone()))
+                    .collect::<Vec<_>>()
+            };
+            if created(first) != created(second) {
+                return Some("the runs created different data sources".to_owned());
+            }
+            None
+        }
+        (Err(first), Err(second)) if first.to_string() == second.to_string() => None,
+        (Err(first), Err(second)) => Some(format!(
+            "the runs failed with different errors: `{}` and `{}`",
+            first, second
+        )),
+        (Err(e), Ok(_)) => Some(format!(
+            "the first run failed with `{}`, the second one succeeded",
+            e
+        )),
+        (Ok(_), Err(e)) => Some(format!(
+            "the first run succeeded, the second one failed with `{}`",
+            e
+        )),
+    }
+}
+
+#[derive(Clone, Debug)]
 pub(crate) enum MappingTrigger {
     Log {
         transaction: Arc<Transaction>,
@@ -121,6 +231,16 @@ pub(crate) enum MappingTrigger {
     },
 }
 
+impl MappingTrigger {
+    fn handler_name(&self) -> &str {
+        match self {
+            MappingTrigger::Log { handler, .. } => &handler.handler,
+            MappingTrigger::Call { handler, .. } => &handler.handler,
+            MappingTrigger::Block { handler } => &handler.handler,
+        }
+    }
+}
+
 type MappingResponse = (Result<BlockState, Error>, futures::Finished<Instant, Error>);
 
 #[derive(Debug)]
@@ -156,6 +276,9 @@ impl Clone for MappingContext {
 pub(crate) struct ValidModule {
     pub(super) module: wasmi::Module,
     pub(super) host_module_names: Vec<String>,
+    /// Imported host functions that this node does not provide and that
+    /// are replaced with stubs that do nothing
+    pub(super) stubbed_imports: Vec<String>,
 }
 
 impl ValidModule {
@@ -176,12 +299,75 @@ impl ValidModule {
             .collect();
         host_module_names.dedup();
 
+        // Check that we provide all the host functions that the module
+        // imports, so that a mapping compiled against a newer graph-ts
+        // fails with a list of what is missing rather than when it is
+        // instantiated
+        let types = parsed_module
+            .type_section()
+            .map(|section| section.types())
+            .unwrap_or(&[]);
+        let mut stubbed_imports = vec![];
+        let mut unknown_imports = vec![];
+        for import in import_section.entries() {
+            let type_ref = match import.external() {
+                External::Function(type_ref) => *type_ref as usize,
+                _ => continue,
+            };
+            if host_function_index(import.module(), import.field()).is_some() {
+                continue;
+            }
+            let return_type = match types.get(type_ref) {
+                Some(Type::Function(function_type)) => function_type.return_type(),
+                None => None,
+            };
+            if stub_unknown_import(return_type) {
+                stubbed_imports.push(import.field().to_owned());
+            } else {
+                unknown_imports.push(import.field().to_owned());
+            }
+        }
+        if !unknown_imports.is_empty() {
+            return Err(format_err!(
+                "the mapping imports host functions that this Graph Node does not provide: {}; \
+                 it was probably compiled with a newer version of graph-ts than this node \
+                 supports, which only provides mapping API versions up to {}",
+                unknown_imports.join(", "),
+                API_VERSIONS.last().unwrap()
+            ));
+        }
+
         let module = wasmi::Module::from_parity_wasm_module(parsed_module)
             .map_err(|e| format_err!("Invalid WASM module: {}", e))?;
 
         Ok(ValidModule {
             module,
             host_module_names,
+            stubbed_imports,
         })
     }
 }
+
+#[test]
+fn divergent_handler_results() {
+    let key = |id: &str| EntityKey {
+        subgraph_id: SubgraphDeploymentId::new("QmXYZ").unwrap(),
+        entity_type: "Token".to_owned(),
+        entity_id: id.to_owned(),
+    };
+    let changed = |id: &str| {
+        let mut state = BlockState::default();
+        state.entity_cache.set(key(id), Entity::new());
+        Ok(state)
+    };
+    let failed = |msg: &'static str| Err(err_msg(msg));
+
+    assert_eq!(None, divergence(&changed("1"), &changed("1")));
+    assert_eq!(None, divergence(&failed("boom"), &failed("boom")));
+    assert_eq!(
+        Some("the runs changed entity Token[1] differently".to_owned()),
+        divergence(&changed("2"), &changed("1"))
+    );
+    assert!(divergence(&changed("1"), &failed("boom")).is_some());
+    assert!(divergence(&failed("boom"), &failed("bang")).is_some());
+}
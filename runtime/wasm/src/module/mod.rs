@@ -3,6 +3,7 @@ use std::fmt;
 use std::ops::Deref;
 use std::time::Instant;
 
+use lazy_static::lazy_static;
 use semver::Version;
 use wasmi::{
     nan_preserving_float::F64, Error, Externals, FuncInstance, FuncRef, HostError, ImportsBuilder,
@@ -10,7 +11,7 @@ use wasmi::{
     Signature, Trap,
 };
 
-use crate::host_exports::{self, HostExportError};
+use crate::host_exports::{self, HostExportError, Transient};
 use crate::mapping::MappingContext;
 use ethabi::LogParam;
 use graph::components::ethereum::*;
@@ -69,6 +70,14 @@ const LOG_LOG: usize = 37;
 const BIG_INT_POW: usize = 38;
 const DATA_SOURCE_ADDRESS: usize = 39;
 const DATA_SOURCE_NETWORK: usize = 40;
+const TRIGGER_ID: usize = 41;
+const STORE_CREATED_IN_BLOCK_FUNC_INDEX: usize = 42;
+const JSON_TRY_FROM_BYTES_FUNC_INDEX: usize = 43;
+const JSON_TRY_FROM_BYTES_WITH_SCHEMA_FUNC_INDEX: usize = 44;
+const METRICS_INCREMENT_FUNC_INDEX: usize = 45;
+const STUB_FUNC_INDEX: usize = 46;
+const COMPRESSION_DECOMPRESS_FUNC_INDEX: usize = 47;
+const COMPRESSION_COMPRESS_FUNC_INDEX: usize = 48;
 
 /// Transform function index into the function name string
 fn fn_index_to_metrics_string(index: usize) -> Option<String> {
@@ -92,6 +101,27 @@ fn format_wasmi_error(e: Error) -> String {
     }
 }
 
+/// The error for a handler that failed with `e`, described by `what`. If a
+/// host function failed because of an outage of the Ethereum node, IPFS or
+/// the database, it is a `TransientError`
+fn handler_error(e: Error, what: String) -> FailureError {
+    let transient = match &e {
+        Error::Trap(trap) => match trap.kind() {
+            wasmi::TrapKind::Host(host_error) => host_error
+                .downcast_ref::<HostExportError<Transient>>()
+                .is_some(),
+            _ => false,
+        },
+        _ => false,
+    };
+    let message = format!("{}: {}", what, format_wasmi_error(e));
+    if transient {
+        TransientError(message).into()
+    } else {
+        err_msg(message)
+    }
+}
+
 /// A WASM module based on wasmi that powers a subgraph runtime.
 pub(crate) struct WasmiModule {
     pub module: ModuleRef,
@@ -116,6 +146,10 @@ pub(crate) struct WasmiModule {
 
     // How many times we've passed a timeout checkpoint during execution.
     timeout_checkpoint_count: u64,
+
+    // The unique id of the trigger the current handler is processing; this
+    // is `None` for callbacks that are not invoked for a trigger.
+    trigger_id: Option<String>,
 }
 
 impl WasmiModule {
@@ -162,6 +196,7 @@ impl WasmiModule {
             arena_free_size: 0,
             arena_start_ptr: 0,
             timeout_checkpoint_count: 0,
+            trigger_id: None,
         };
 
         this.module = module
@@ -182,6 +217,7 @@ impl WasmiModule {
         self.start_time = Instant::now();
 
         let block = self.ctx.block.clone();
+        self.trigger_id = Some(log_trigger_id(&log));
 
         // Prepare an EthereumEvent for the WASM runtime
         // Decide on the destination type using the mapping
@@ -221,13 +257,13 @@ impl WasmiModule {
             .invoke_export(handler_name, &[event], &mut self);
 
         // Return either the output state (collected entity operations etc.) or an error
-        result.map(|_| self.ctx.state).map_err(|e| {
-            format_err!(
-                "Failed to handle Ethereum event with handler \"{}\": {}",
-                handler_name,
-                format_wasmi_error(e)
-            )
-        })
+        self.handler_result(
+            result,
+            format!(
+                "Failed to handle Ethereum event with handler \"{}\"",
+                handler_name
+            ),
+        )
     }
 
     pub(crate) fn handle_json_callback(
@@ -246,13 +282,13 @@ impl WasmiModule {
                 .invoke_export(handler_name, &[value, user_data], &mut self);
 
         // Return either the collected entity operations or an error
-        result.map(|_| self.ctx.state).map_err(|e| {
-            format_err!(
-                "Failed to handle callback with handler \"{}\": {}",
-                handler_name,
-                format_wasmi_error(e),
-            )
-        })
+        self.handler_result(
+            result,
+            format!(
+                "Failed to handle callback with handler \"{}\"",
+                handler_name
+            ),
+        )
     }
 
     pub(crate) fn handle_ethereum_call(
@@ -264,6 +300,7 @@ impl WasmiModule {
         outputs: Vec<LogParam>,
     ) -> Result<BlockState, FailureError> {
         self.start_time = Instant::now();
+        self.trigger_id = Some(call.trigger_id());
 
         let call = EthereumCallData {
             to: call.to,
@@ -284,13 +321,13 @@ impl WasmiModule {
             .clone()
             .invoke_export(handler_name, &[arg], &mut self);
 
-        result.map(|_| self.ctx.state).map_err(|err| {
-            format_err!(
-                "Failed to handle Ethereum call with handler \"{}\": {}",
-                handler_name,
-                format_wasmi_error(err),
-            )
-        })
+        self.handler_result(
+            result,
+            format!(
+                "Failed to handle Ethereum call with handler \"{}\"",
+                handler_name
+            ),
+        )
     }
 
     pub(crate) fn handle_ethereum_block(
@@ -298,6 +335,11 @@ impl WasmiModule {
         handler_name: &str,
     ) -> Result<BlockState, FailureError> {
         self.start_time = Instant::now();
+        self.trigger_id = Some(block_trigger_id(
+            self.ctx.block.number(),
+            &self.ctx.host_exports.data_source_address(),
+            handler_name,
+        ));
 
         // Prepare an EthereumBlock for the WASM runtime
         let arg = EthereumBlockData::from(self.ctx.block.as_ref());
@@ -308,13 +350,28 @@ impl WasmiModule {
             &mut self,
         );
 
-        result.map(|_| self.ctx.state).map_err(|err| {
-            format_err!(
-                "Failed to handle Ethereum block with handler \"{}\": {}",
-                handler_name,
-                format_wasmi_error(err)
-            )
-        })
+        self.handler_result(
+            result,
+            format!(
+                "Failed to handle Ethereum block with handler \"{}\"",
+                handler_name
+            ),
+        )
+    }
+}
+
+impl WasmiModule {
+    /// The state the handler produced if it succeeded. If it failed, the
+    /// error keeps the entities the state had read from the store
+    fn handler_result(
+        self,
+        result: Result<Option<RuntimeValue>, Error>,
+        what: String,
+    ) -> Result<BlockState, FailureError> {
+        match result {
+            Ok(_) => Ok(self.ctx.state),
+            Err(e) => Err(HandlerFailure::new(handler_error(e, what), self.ctx.state).into()),
+        }
     }
 }
 
@@ -359,7 +416,15 @@ impl<E> HostError for HostExportError<E> where E: fmt::Debug + fmt::Display + Se
 
 // Implementation of externals.
 impl WasmiModule {
-    fn gas(&mut self) -> Result<Option<RuntimeValue>, Trap> {
+    /// function gas(amount: i32): void
+    /// `amount` is the number of instructions in the block of code that is
+    /// about to run, which counts against the gas budget of the block
+    fn gas(&mut self, amount: i32) -> Result<Option<RuntimeValue>, Trap> {
+        self.ctx
+            .state
+            .count_gas(amount as u64)
+            .map_err(|e| HostExportError(e.to_string()))?;
+
         // This function is called so often that the overhead of calling `Instant::now()` every
         // time would be significant, so we spread out the checks.
         if self.timeout_checkpoint_count % 100 == 0 {
@@ -434,7 +499,7 @@ impl WasmiModule {
         let id = self.asc_get(id_ptr);
         self.ctx
             .host_exports
-            .store_remove(&mut self.ctx.state, entity, id);
+            .store_remove(&mut self.ctx.state, entity, id)?;
         Ok(None)
     }
 
@@ -465,6 +530,20 @@ impl WasmiModule {
         }))
     }
 
+    /// function store.createdInBlock(entity: string): Array<string>
+    fn store_created_in_block(
+        &mut self,
+        entity_ptr: AscPtr<AscString>,
+    ) -> Result<Option<RuntimeValue>, Trap> {
+        let entity = self.asc_get(entity_ptr);
+        let ids = self
+            .ctx
+            .host_exports
+            .store_created_in_block(&mut self.ctx.state, entity)?;
+        let ids_ptr: AscPtr<Array<AscPtr<AscString>>> = self.asc_new(ids.as_slice());
+        Ok(Some(RuntimeValue::from(ids_ptr)))
+    }
+
     /// function ethereum.call(call: SmartContractCall): Array<Token> | null
     fn ethereum_call(
         &mut self,
@@ -556,6 +635,36 @@ impl WasmiModule {
         Ok(Some(RuntimeValue::from(self.asc_new(&result))))
     }
 
+    /// function json.try_fromBytes(bytes: Bytes): Result<JSONValue, string>
+    fn json_try_from_bytes(
+        &mut self,
+        bytes_ptr: AscPtr<Uint8Array>,
+    ) -> Result<Option<RuntimeValue>, Trap> {
+        let result = self
+            .ctx
+            .host_exports
+            .json_try_from_bytes(self.asc_get(bytes_ptr));
+        let result_ptr: AscPtr<AscResult<AscEnum<JsonValueKind>, AscString>> =
+            self.asc_new(&result);
+        Ok(Some(RuntimeValue::from(result_ptr)))
+    }
+
+    /// function json.try_fromBytesWithSchema(bytes: Bytes, schema: string):
+    ///     Result<JSONValue, string>
+    fn json_try_from_bytes_with_schema(
+        &mut self,
+        bytes_ptr: AscPtr<Uint8Array>,
+        schema_ptr: AscPtr<AscString>,
+    ) -> Result<Option<RuntimeValue>, Trap> {
+        let result = self
+            .ctx
+            .host_exports
+            .json_try_from_bytes_with_schema(self.asc_get(bytes_ptr), self.asc_get(schema_ptr))?;
+        let result_ptr: AscPtr<AscResult<AscEnum<JsonValueKind>, AscString>> =
+            self.asc_new(&result);
+        Ok(Some(RuntimeValue::from(result_ptr)))
+    }
+
     /// function ipfs.cat(link: String): Bytes
     fn ipfs_cat(&mut self, link_ptr: AscPtr<AscString>) -> Result<Option<RuntimeValue>, Trap> {
         let link = self.asc_get(link_ptr);
@@ -614,6 +723,10 @@ impl WasmiModule {
                             .state
                             .created_data_sources
                             .extend(output_state.created_data_sources);
+                        self.ctx
+                            .state
+                            .metric_increments
+                            .extend(output_state.metric_increments);
                     }
                     Ok(None)
                 }
@@ -675,6 +788,34 @@ impl WasmiModule {
         Ok(Some(RuntimeValue::from(hash_ptr)))
     }
 
+    /// function compression.decompress(bytes: Bytes, codec: string): Bytes
+    fn compression_decompress(
+        &mut self,
+        bytes_ptr: AscPtr<Uint8Array>,
+        codec_ptr: AscPtr<AscString>,
+    ) -> Result<Option<RuntimeValue>, Trap> {
+        let output = self
+            .ctx
+            .host_exports
+            .compression_decompress(self.asc_get(bytes_ptr), self.asc_get(codec_ptr))?;
+        let output_ptr: AscPtr<Uint8Array> = self.asc_new(&*output);
+        Ok(Some(RuntimeValue::from(output_ptr)))
+    }
+
+    /// function compression.compress(bytes: Bytes, codec: string): Bytes
+    fn compression_compress(
+        &mut self,
+        bytes_ptr: AscPtr<Uint8Array>,
+        codec_ptr: AscPtr<AscString>,
+    ) -> Result<Option<RuntimeValue>, Trap> {
+        let output = self
+            .ctx
+            .host_exports
+            .compression_compress(self.asc_get(bytes_ptr), self.asc_get(codec_ptr))?;
+        let output_ptr: AscPtr<Uint8Array> = self.asc_new(&*output);
+        Ok(Some(RuntimeValue::from(output_ptr)))
+    }
+
     /// function bigInt.plus(x: BigInt, y: BigInt): BigInt
     fn big_int_plus(
         &mut self,
@@ -903,6 +1044,16 @@ impl WasmiModule {
         )))
     }
 
+    /// function trigger.id(): string | null
+    fn trigger_id(&mut self) -> Result<Option<RuntimeValue>, Trap> {
+        // map `None` to `null`, and `Some(s)` to a runtime string
+        Ok(self
+            .trigger_id
+            .clone()
+            .map(|id| RuntimeValue::from(self.asc_new(&*id)))
+            .or(Some(RuntimeValue::from(0))))
+    }
+
     fn ens_name_by_hash(
         &mut self,
         hash_ptr: AscPtr<AscString>,
@@ -925,6 +1076,19 @@ impl WasmiModule {
         self.ctx.host_exports.log_log(&self.ctx.logger, level, msg);
         Ok(None)
     }
+
+    /// function metrics.increment(name: string, value: f64): void
+    fn metrics_increment(
+        &mut self,
+        name_ptr: AscPtr<AscString>,
+        value: F64,
+    ) -> Result<Option<RuntimeValue>, Trap> {
+        let name: String = self.asc_get(name_ptr);
+        let value = value.to_float();
+        HostMetrics::check_custom_metric(&name, value).map_err(HostExportError)?;
+        self.ctx.state.metric_increments.push((name, value));
+        Ok(None)
+    }
 }
 
 impl Externals for WasmiModule {
@@ -935,7 +1099,7 @@ impl Externals for WasmiModule {
     ) -> Result<Option<RuntimeValue>, Trap> {
         // This function is hot, so avoid the cost of registering metrics.
         if index == GAS_FUNC_INDEX {
-            return self.gas();
+            return self.gas(args.nth_checked(0)?);
         }
 
         // Start a catch-all section for exports that don't have their own section.
@@ -964,6 +1128,7 @@ impl Externals for WasmiModule {
             STORE_REMOVE_FUNC_INDEX => {
                 self.store_remove(args.nth_checked(0)?, args.nth_checked(1)?)
             }
+            STORE_CREATED_IN_BLOCK_FUNC_INDEX => self.store_created_in_block(args.nth_checked(0)?),
             ETHEREUM_CALL_FUNC_INDEX => {
                 let _section = stopwatch.start_section("host_export_ethereum_call");
 
@@ -990,6 +1155,10 @@ impl Externals for WasmiModule {
             TYPE_CONVERSION_I32_TO_BIG_INT_FUNC_INDEX => self.i32_to_big_int(args.nth_checked(0)?),
             TYPE_CONVERSION_BIG_INT_TO_I32_FUNC_INDEX => self.big_int_to_i32(args.nth_checked(0)?),
             JSON_FROM_BYTES_FUNC_INDEX => self.json_from_bytes(args.nth_checked(0)?),
+            JSON_TRY_FROM_BYTES_FUNC_INDEX => self.json_try_from_bytes(args.nth_checked(0)?),
+            JSON_TRY_FROM_BYTES_WITH_SCHEMA_FUNC_INDEX => {
+                self.json_try_from_bytes_with_schema(args.nth_checked(0)?, args.nth_checked(1)?)
+            }
             JSON_TO_I64_FUNC_INDEX => self.json_to_i64(args.nth_checked(0)?),
             JSON_TO_U64_FUNC_INDEX => self.json_to_u64(args.nth_checked(0)?),
             JSON_TO_F64_FUNC_INDEX => self.json_to_f64(args.nth_checked(0)?),
@@ -999,6 +1168,12 @@ impl Externals for WasmiModule {
                 self.ipfs_cat(args.nth_checked(0)?)
             }
             CRYPTO_KECCAK_256_INDEX => self.crypto_keccak_256(args.nth_checked(0)?),
+            COMPRESSION_DECOMPRESS_FUNC_INDEX => {
+                self.compression_decompress(args.nth_checked(0)?, args.nth_checked(1)?)
+            }
+            COMPRESSION_COMPRESS_FUNC_INDEX => {
+                self.compression_compress(args.nth_checked(0)?, args.nth_checked(1)?)
+            }
             BIG_INT_PLUS => self.big_int_plus(args.nth_checked(0)?, args.nth_checked(1)?),
             BIG_INT_MINUS => self.big_int_minus(args.nth_checked(0)?, args.nth_checked(1)?),
             BIG_INT_TIMES => self.big_int_times(args.nth_checked(0)?, args.nth_checked(1)?),
@@ -1038,6 +1213,11 @@ impl Externals for WasmiModule {
             LOG_LOG => self.log_log(args.nth_checked(0)?, args.nth_checked(1)?),
             DATA_SOURCE_ADDRESS => self.data_source_address(),
             DATA_SOURCE_NETWORK => self.data_source_network(),
+            TRIGGER_ID => self.trigger_id(),
+            METRICS_INCREMENT_FUNC_INDEX => {
+                self.metrics_increment(args.nth_checked(0)?, args.nth_checked(1)?)
+            }
+            STUB_FUNC_INDEX => Ok(None),
             _ => panic!("Unimplemented function at {}", index),
         };
         // Record execution time
@@ -1049,116 +1229,164 @@ impl Externals for WasmiModule {
     }
 }
 
+/// How to handle imports of host functions that this node does not
+/// provide, usually because the mapping was compiled against a newer
+/// version of graph-ts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum UnknownImports {
+    /// Refuse to run the mapping
+    Fail,
+    /// Replace unknown functions that do not return anything, like logging
+    /// functions, with functions that do nothing. Mappings that import
+    /// unknown functions that return a value still fail
+    Stub,
+}
+
+lazy_static! {
+    pub(crate) static ref UNKNOWN_IMPORTS: UnknownImports =
+        match std::env::var("GRAPH_WASM_UNKNOWN_IMPORTS")
+            .unwrap_or("fail".into())
+            .as_str()
+        {
+            "fail" => UnknownImports::Fail,
+            "stub" => UnknownImports::Stub,
+            behavior => panic!(
+                "invalid GRAPH_WASM_UNKNOWN_IMPORTS `{}`, must be either `fail` or `stub`",
+                behavior
+            ),
+        };
+}
+
+/// The index of the host function that implements the import `field_name`
+/// from the host module `module_name`, or `None` if there is no such host
+/// function
+pub(crate) fn host_function_index(module_name: &str, field_name: &str) -> Option<usize> {
+    if module_name == "env" {
+        return match field_name {
+            "gas" => Some(GAS_FUNC_INDEX),
+            "abort" => Some(ABORT_FUNC_INDEX),
+            _ => None,
+        };
+    }
+
+    Some(match field_name {
+        // store
+        "store.set" => STORE_SET_FUNC_INDEX,
+        "store.remove" => STORE_REMOVE_FUNC_INDEX,
+        "store.get" => STORE_GET_FUNC_INDEX,
+        "store.createdInBlock" => STORE_CREATED_IN_BLOCK_FUNC_INDEX,
+
+        // ethereum
+        "ethereum.call" => ETHEREUM_CALL_FUNC_INDEX,
+
+        // typeConversion
+        "typeConversion.bytesToString" => TYPE_CONVERSION_BYTES_TO_STRING_FUNC_INDEX,
+        "typeConversion.bytesToHex" => TYPE_CONVERSION_BYTES_TO_HEX_FUNC_INDEX,
+        "typeConversion.bigIntToString" => TYPE_CONVERSION_BIG_INT_TO_STRING_FUNC_INDEX,
+        "typeConversion.bigIntToHex" => TYPE_CONVERSION_BIG_INT_TO_HEX_FUNC_INDEX,
+        "typeConversion.stringToH160" => TYPE_CONVERSION_STRING_TO_H160_FUNC_INDEX,
+        "typeConversion.i32ToBigInt" => TYPE_CONVERSION_I32_TO_BIG_INT_FUNC_INDEX,
+        "typeConversion.bigIntToI32" => TYPE_CONVERSION_BIG_INT_TO_I32_FUNC_INDEX,
+        "typeConversion.bytesToBase58" => TYPE_CONVERSION_BYTES_TO_BASE_58_INDEX,
+
+        // json
+        "json.fromBytes" => JSON_FROM_BYTES_FUNC_INDEX,
+        "json.try_fromBytes" => JSON_TRY_FROM_BYTES_FUNC_INDEX,
+        "json.try_fromBytesWithSchema" => JSON_TRY_FROM_BYTES_WITH_SCHEMA_FUNC_INDEX,
+        "json.toI64" => JSON_TO_I64_FUNC_INDEX,
+        "json.toU64" => JSON_TO_U64_FUNC_INDEX,
+        "json.toF64" => JSON_TO_F64_FUNC_INDEX,
+        "json.toBigInt" => JSON_TO_BIG_INT_FUNC_INDEX,
+
+        // ipfs
+        "ipfs.cat" => IPFS_CAT_FUNC_INDEX,
+        "ipfs.map" => IPFS_MAP_FUNC_INDEX,
+
+        // crypto
+        "crypto.keccak256" => CRYPTO_KECCAK_256_INDEX,
+
+        // compression
+        "compression.decompress" => COMPRESSION_DECOMPRESS_FUNC_INDEX,
+        "compression.compress" => COMPRESSION_COMPRESS_FUNC_INDEX,
+
+        // bigInt
+        "bigInt.plus" => BIG_INT_PLUS,
+        "bigInt.minus" => BIG_INT_MINUS,
+        "bigInt.times" => BIG_INT_TIMES,
+        "bigInt.dividedBy" => BIG_INT_DIVIDED_BY,
+        "bigInt.dividedByDecimal" => BIG_INT_DIVIDED_BY_DECIMAL,
+        "bigInt.mod" => BIG_INT_MOD,
+        "bigInt.pow" => BIG_INT_POW,
+
+        // bigDecimal
+        "bigDecimal.plus" => BIG_DECIMAL_PLUS,
+        "bigDecimal.minus" => BIG_DECIMAL_MINUS,
+        "bigDecimal.times" => BIG_DECIMAL_TIMES,
+        "bigDecimal.dividedBy" => BIG_DECIMAL_DIVIDED_BY,
+        "bigDecimal.equals" => BIG_DECIMAL_EQUALS,
+        "bigDecimal.toString" => BIG_DECIMAL_TO_STRING,
+        "bigDecimal.fromString" => BIG_DECIMAL_FROM_STRING,
+
+        // dataSource
+        "dataSource.create" => DATA_SOURCE_CREATE_INDEX,
+        "dataSource.address" => DATA_SOURCE_ADDRESS,
+        "dataSource.network" => DATA_SOURCE_NETWORK,
+
+        // trigger
+        "trigger.id" => TRIGGER_ID,
+
+        // ens.nameByHash
+        "ens.nameByHash" => ENS_NAME_BY_HASH,
+
+        // log.log
+        "log.log" => LOG_LOG,
+
+        // metrics
+        "metrics.increment" => METRICS_INCREMENT_FUNC_INDEX,
+
+        // Unknown export
+        _ => return None,
+    })
+}
+
+/// Whether an unknown import with the given return type is replaced with
+/// a stub that does nothing
+pub(crate) fn stub_unknown_import<T>(return_type: Option<T>) -> bool {
+    *UNKNOWN_IMPORTS == UnknownImports::Stub && return_type.is_none()
+}
+
+fn resolve_host_function(
+    module_name: &str,
+    field_name: &str,
+    signature: &Signature,
+) -> Result<FuncRef, Error> {
+    match host_function_index(module_name, field_name) {
+        Some(index) => Ok(FuncInstance::alloc_host(signature.clone(), index)),
+        None if stub_unknown_import(signature.return_type()) => {
+            Ok(FuncInstance::alloc_host(signature.clone(), STUB_FUNC_INDEX))
+        }
+        None => Err(Error::Instantiation(format!(
+            "Export '{}' not found",
+            field_name
+        ))),
+    }
+}
+
 /// Env module resolver
 pub struct EnvModuleResolver;
 
 impl ModuleImportResolver for EnvModuleResolver {
     fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
-        Ok(match field_name {
-            "gas" => FuncInstance::alloc_host(signature.clone(), GAS_FUNC_INDEX),
-            "abort" => FuncInstance::alloc_host(signature.clone(), ABORT_FUNC_INDEX),
-            _ => {
-                return Err(Error::Instantiation(format!(
-                    "Export '{}' not found",
-                    field_name
-                )));
-            }
-        })
+        resolve_host_function("env", field_name, signature)
     }
 }
 
+/// Resolver for all host modules other than `env`; the host functions
+/// are identified by their name alone
 pub struct ModuleResolver;
 
 impl ModuleImportResolver for ModuleResolver {
     fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
-        let signature = signature.clone();
-        Ok(match field_name {
-            // store
-            "store.set" => FuncInstance::alloc_host(signature, STORE_SET_FUNC_INDEX),
-            "store.remove" => FuncInstance::alloc_host(signature, STORE_REMOVE_FUNC_INDEX),
-            "store.get" => FuncInstance::alloc_host(signature, STORE_GET_FUNC_INDEX),
-
-            // ethereum
-            "ethereum.call" => FuncInstance::alloc_host(signature, ETHEREUM_CALL_FUNC_INDEX),
-
-            // typeConversion
-            "typeConversion.bytesToString" => {
-                FuncInstance::alloc_host(signature, TYPE_CONVERSION_BYTES_TO_STRING_FUNC_INDEX)
-            }
-            "typeConversion.bytesToHex" => {
-                FuncInstance::alloc_host(signature, TYPE_CONVERSION_BYTES_TO_HEX_FUNC_INDEX)
-            }
-            "typeConversion.bigIntToString" => {
-                FuncInstance::alloc_host(signature, TYPE_CONVERSION_BIG_INT_TO_STRING_FUNC_INDEX)
-            }
-            "typeConversion.bigIntToHex" => {
-                FuncInstance::alloc_host(signature, TYPE_CONVERSION_BIG_INT_TO_HEX_FUNC_INDEX)
-            }
-            "typeConversion.stringToH160" => {
-                FuncInstance::alloc_host(signature, TYPE_CONVERSION_STRING_TO_H160_FUNC_INDEX)
-            }
-            "typeConversion.i32ToBigInt" => {
-                FuncInstance::alloc_host(signature, TYPE_CONVERSION_I32_TO_BIG_INT_FUNC_INDEX)
-            }
-            "typeConversion.bigIntToI32" => {
-                FuncInstance::alloc_host(signature, TYPE_CONVERSION_BIG_INT_TO_I32_FUNC_INDEX)
-            }
-            "typeConversion.bytesToBase58" => {
-                FuncInstance::alloc_host(signature, TYPE_CONVERSION_BYTES_TO_BASE_58_INDEX)
-            }
-
-            // json
-            "json.fromBytes" => FuncInstance::alloc_host(signature, JSON_FROM_BYTES_FUNC_INDEX),
-            "json.toI64" => FuncInstance::alloc_host(signature, JSON_TO_I64_FUNC_INDEX),
-            "json.toU64" => FuncInstance::alloc_host(signature, JSON_TO_U64_FUNC_INDEX),
-            "json.toF64" => FuncInstance::alloc_host(signature, JSON_TO_F64_FUNC_INDEX),
-            "json.toBigInt" => FuncInstance::alloc_host(signature, JSON_TO_BIG_INT_FUNC_INDEX),
-
-            // ipfs
-            "ipfs.cat" => FuncInstance::alloc_host(signature, IPFS_CAT_FUNC_INDEX),
-            "ipfs.map" => FuncInstance::alloc_host(signature, IPFS_MAP_FUNC_INDEX),
-
-            // crypto
-            "crypto.keccak256" => FuncInstance::alloc_host(signature, CRYPTO_KECCAK_256_INDEX),
-
-            // bigInt
-            "bigInt.plus" => FuncInstance::alloc_host(signature, BIG_INT_PLUS),
-            "bigInt.minus" => FuncInstance::alloc_host(signature, BIG_INT_MINUS),
-            "bigInt.times" => FuncInstance::alloc_host(signature, BIG_INT_TIMES),
-            "bigInt.dividedBy" => FuncInstance::alloc_host(signature, BIG_INT_DIVIDED_BY),
-            "bigInt.dividedByDecimal" => {
-                FuncInstance::alloc_host(signature, BIG_INT_DIVIDED_BY_DECIMAL)
-            }
-            "bigInt.mod" => FuncInstance::alloc_host(signature, BIG_INT_MOD),
-            "bigInt.pow" => FuncInstance::alloc_host(signature, BIG_INT_POW),
-
-            // bigDecimal
-            "bigDecimal.plus" => FuncInstance::alloc_host(signature, BIG_DECIMAL_PLUS),
-            "bigDecimal.minus" => FuncInstance::alloc_host(signature, BIG_DECIMAL_MINUS),
-            "bigDecimal.times" => FuncInstance::alloc_host(signature, BIG_DECIMAL_TIMES),
-            "bigDecimal.dividedBy" => FuncInstance::alloc_host(signature, BIG_DECIMAL_DIVIDED_BY),
-            "bigDecimal.equals" => FuncInstance::alloc_host(signature, BIG_DECIMAL_EQUALS),
-            "bigDecimal.toString" => FuncInstance::alloc_host(signature, BIG_DECIMAL_TO_STRING),
-            "bigDecimal.fromString" => FuncInstance::alloc_host(signature, BIG_DECIMAL_FROM_STRING),
-
-            // dataSource
-            "dataSource.create" => FuncInstance::alloc_host(signature, DATA_SOURCE_CREATE_INDEX),
-            "dataSource.address" => FuncInstance::alloc_host(signature, DATA_SOURCE_ADDRESS),
-            "dataSource.network" => FuncInstance::alloc_host(signature, DATA_SOURCE_NETWORK),
-
-            // ens.nameByHash
-            "ens.nameByHash" => FuncInstance::alloc_host(signature, ENS_NAME_BY_HASH),
-
-            // log.log
-            "log.log" => FuncInstance::alloc_host(signature, LOG_LOG),
-
-            // Unknown export
-            _ => {
-                return Err(Error::Instantiation(format!(
-                    "Export '{}' not found",
-                    field_name
-                )));
-            }
-        })
+        resolve_host_function("index", field_name, signature)
     }
 }
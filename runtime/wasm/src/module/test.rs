@@ -127,6 +127,7 @@ fn mock_host_exports(
     store: Arc<impl Store + SubgraphDeploymentStore + EthereumCallCache>,
 ) -> HostExports {
     let mock_ethereum_adapter = Arc::new(MockEthereumAdapter::default());
+    let annotation_types = store.input_schema(&subgraph_id).unwrap().annotation_types();
     HostExports::new(
         subgraph_id,
         Version::parse(&data_source.mapping.api_version).unwrap(),
@@ -145,6 +146,8 @@ fn mock_host_exports(
             .ok()
             .and_then(|s| u64::from_str(&s).ok())
             .map(std::time::Duration::from_secs),
+        annotation_types,
+        Default::default(),
     )
 }
 
@@ -431,6 +434,41 @@ fn crypto_keccak256() {
     );
 }
 
+#[test]
+fn compression() {
+    use std::io::Write;
+
+    let module = test_module("compression", mock_data_source("wasm_test/crypto.wasm"));
+    let host_exports = &module.ctx.host_exports;
+    let input = b"compressed payloads in calldata".repeat(10);
+
+    for codec in &["gzip", "zstd"] {
+        let compressed = host_exports
+            .compression_compress(input.clone(), codec.to_string())
+            .unwrap_or_else(|_| panic!("failed to compress with {}", codec));
+        assert!(compressed.len() < input.len());
+        let decompressed = host_exports
+            .compression_decompress(compressed, codec.to_string())
+            .unwrap_or_else(|_| panic!("failed to decompress with {}", codec));
+        assert_eq!(input, decompressed);
+    }
+
+    assert!(host_exports
+        .compression_decompress(input.clone(), "gzip".to_owned())
+        .is_err());
+    assert!(host_exports
+        .compression_compress(input, "brotli".to_owned())
+        .is_err());
+
+    // A small payload that decompresses to more than the limit
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&vec![0; 17 * 1024 * 1024]).unwrap();
+    let bomb = encoder.finish().unwrap();
+    assert!(host_exports
+        .compression_decompress(bomb, "gzip".to_owned())
+        .is_err());
+}
+
 #[test]
 fn token_numeric_conversion() {
     let mut module = test_module(
@@ -871,3 +909,138 @@ fn entity_store() {
         _ => assert!(false, "expected Insert modification"),
     }
 }
+
+#[test]
+fn annotations_are_not_accessible_to_mappings() {
+    test_store::create_test_subgraph(
+        "annotationStore",
+        "type User @entity {
+            id: ID!,
+            name: String,
+        }
+
+        type Label @entity @annotation {
+            id: ID!,
+            text: String,
+        }",
+    );
+    let subgraph_id = SubgraphDeploymentId::new("annotationStore").unwrap();
+    let host_exports = mock_host_exports(
+        subgraph_id,
+        mock_data_source("wasm_test/store.wasm"),
+        STORE.clone(),
+    );
+    let logger = test_store::LOGGER.clone();
+    let mut state = BlockState::default();
+    let data = || {
+        vec![("text".to_owned(), Value::from("spam"))]
+            .into_iter()
+            .collect::<HashMap<_, _>>()
+    };
+
+    assert!(host_exports
+        .store_get(&logger, &mut state, "Label".to_owned(), "1".to_owned())
+        .is_err());
+    assert!(host_exports
+        .store_set(&mut state, "Label".to_owned(), "1".to_owned(), data())
+        .is_err());
+    assert!(host_exports
+        .store_remove(&mut state, "Label".to_owned(), "1".to_owned())
+        .is_err());
+    assert!(host_exports
+        .store_get(&logger, &mut state, "User".to_owned(), "1".to_owned())
+        .is_ok());
+}
+
+#[test]
+fn known_host_functions() {
+    assert_eq!(Some(GAS_FUNC_INDEX), host_function_index("env", "gas"));
+    assert_eq!(None, host_function_index("env", "store.set"));
+    assert_eq!(
+        Some(STORE_SET_FUNC_INDEX),
+        host_function_index("index", "store.set")
+    );
+    assert_eq!(None, host_function_index("index", "store.setMany"));
+    assert_eq!(
+        Some(COMPRESSION_DECOMPRESS_FUNC_INDEX),
+        host_function_index("index", "compression.decompress")
+    );
+}
+
+#[test]
+fn custom_metrics_are_checked() {
+    assert!(HostMetrics::check_custom_metric("trades_total", 1.5).is_ok());
+    assert!(HostMetrics::check_custom_metric("_private2", 0.0).is_ok());
+
+    for name in &["", "1st_trade", "trades-total", "trades total", "tradés"] {
+        assert!(
+            HostMetrics::check_custom_metric(name, 1.0).is_err(),
+            "metric name `{}` must be rejected",
+            name
+        );
+    }
+    assert!(HostMetrics::check_custom_metric(&"a".repeat(64), 1.0).is_ok());
+    assert!(HostMetrics::check_custom_metric(&"a".repeat(65), 1.0).is_err());
+
+    for value in &[-1.0, std::f64::NAN, std::f64::INFINITY] {
+        assert!(
+            HostMetrics::check_custom_metric("trades_total", *value).is_err(),
+            "increment {} must be rejected",
+            value
+        );
+    }
+}
+
+#[test]
+fn custom_metrics_are_limited() {
+    let max_metrics = env::var("GRAPH_MAPPING_MAX_CUSTOM_METRICS")
+        .map(|max| max.parse::<usize>().unwrap())
+        .unwrap_or(20);
+    let deployment_id = SubgraphDeploymentId::new("customMetrics").unwrap();
+    let metrics_registry = Arc::new(MockMetricsRegistry::new());
+    let host_metrics = HostMetrics::new(
+        metrics_registry.clone(),
+        deployment_id.to_string(),
+        StopwatchMetrics::new(
+            Logger::root(slog::Discard, o!()),
+            deployment_id,
+            metrics_registry,
+        ),
+    );
+
+    for i in 0..max_metrics {
+        let name = format!("metric_{}", i);
+        assert_eq!(
+            CustomMetricUpdate::Counted,
+            host_metrics.increment_custom_metric(&name, 1.0)
+        );
+    }
+
+    // Metrics that exist keep counting, but new ones are dropped, and
+    // only the first drop of each is reported as such
+    assert_eq!(
+        CustomMetricUpdate::Counted,
+        host_metrics.increment_custom_metric("metric_0", 2.0)
+    );
+    assert_eq!(
+        CustomMetricUpdate::Dropped { first: true },
+        host_metrics.increment_custom_metric("one_too_many", 1.0)
+    );
+    assert_eq!(
+        CustomMetricUpdate::Dropped { first: false },
+        host_metrics.increment_custom_metric("one_too_many", 1.0)
+    );
+
+    // The names of dropped metrics are only remembered up to the limit
+    for i in 1..max_metrics {
+        let name = format!("dropped_{}", i);
+        assert_eq!(
+            CustomMetricUpdate::Dropped { first: true },
+            host_metrics.increment_custom_metric(&name, 1.0)
+        );
+    }
+    assert_eq!(
+        CustomMetricUpdate::Dropped { first: false },
+        host_metrics.increment_custom_metric("forgotten", 1.0)
+    );
+}
@@ -88,6 +88,23 @@ impl<C: AscType, T: FromAscObj<C>> FromAscObj<Array<AscPtr<C>>> for Vec<T> {
     }
 }
 
+impl<V: AscType, E: AscType, T: ToAscObj<V>, U: ToAscObj<E>> ToAscObj<AscResult<V, E>>
+    for Result<T, U>
+{
+    fn to_asc_obj<H: AscHeap>(&self, heap: &mut H) -> AscResult<V, E> {
+        match self {
+            Ok(value) => AscResult {
+                value: heap.asc_new(value),
+                error: AscPtr::null(),
+            },
+            Err(error) => AscResult {
+                value: AscPtr::null(),
+                error: heap.asc_new(error),
+            },
+        }
+    }
+}
+
 impl<K: AscType, V: AscType, T: FromAscObj<K>, U: FromAscObj<V>> FromAscObj<AscTypedMapEntry<K, V>>
     for (T, U)
 {
@@ -0,0 +1,301 @@
+//! Queries that read from several deployments on this node at once. The
+//! `subgraphs` field of a composite query maps namespaces to subgraph
+//! names or deployment ids, and every field in the top-level selection set
+//! of the query must be one of these namespaces. The selection set of each
+//! namespace field is run as a query of its own against the deployment
+//! for the namespace, and its result is returned under the field's name
+//! or alias. The `extensions` of the response say which deployment and
+//! block each namespace was answered from.
+use graphql_parser::query as q;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::env;
+
+use graph::components::server::query::GraphQLServerError;
+use graph::data::query::QueryResult;
+use graph::prelude::{
+    serde_json, EthereumBlockPointer, QueryError, QueryVariables, SubgraphDeploymentId,
+};
+
+lazy_static! {
+    /// How many namespaces a composite query can select from
+    static ref MAX_NAMESPACES: usize = env::var("GRAPH_COMPOSITE_QUERY_MAX_NAMESPACES")
+        .unwrap_or("10".into())
+        .parse::<usize>()
+        .expect("invalid GRAPH_COMPOSITE_QUERY_MAX_NAMESPACES");
+}
+
+/// A composite query as sent by the client
+pub struct CompositeRequest {
+    pub document: q::Document,
+    pub variables: Option<QueryVariables>,
+    /// The deployment for each namespace, as a subgraph name or a
+    /// deployment id
+    pub subgraphs: BTreeMap<String, String>,
+}
+
+/// The part of a composite query that is run against one deployment
+pub struct NamespacedQuery {
+    /// The name under which the result appears in the response
+    pub response_key: String,
+    /// The subgraph name or deployment id the query runs against
+    pub subgraph: String,
+    pub document: q::Document,
+}
+
+/// The result of a `NamespacedQuery`
+pub struct NamespacedResult {
+    pub response_key: String,
+    pub deployment: SubgraphDeploymentId,
+    /// The latest block of the deployment when the query was run
+    pub block: Option<EthereumBlockPointer>,
+    pub failed: bool,
+    /// Whether the deployment continued indexing after non-fatal errors
+    pub indexing_errors: bool,
+    pub result: QueryResult,
+}
+
+fn client_error(message: impl Into<String>) -> GraphQLServerError {
+    GraphQLServerError::ClientError(message.into())
+}
+
+/// Parses the body of a composite query request, which is a JSON object
+/// with `query`, `subgraphs` and optionally `variables`
+pub fn parse_request(body: &[u8]) -> Result<CompositeRequest, GraphQLServerError> {
+    let json: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| client_error(e.to_string()))?;
+    let obj = json
+        .as_object()
+        .ok_or_else(|| client_error("Request data is not an object"))?;
+
+    let query = obj
+        .get("query")
+        .and_then(|query| query.as_str())
+        .ok_or_else(|| client_error("The \"query\" field is missing or not a string"))?;
+    let document = graphql_parser::parse_query(query)
+        .map_err(|e| GraphQLServerError::from(QueryError::from(e)))?;
+
+    let variables = match obj.get("variables") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(variables @ serde_json::Value::Object(_)) => Some(
+            serde_json::from_value(variables.clone()).map_err(|e| client_error(e.to_string()))?,
+        ),
+        _ => return Err(client_error("Invalid query variables provided")),
+    };
+
+    let subgraphs = obj
+        .get("subgraphs")
+        .ok_or_else(|| client_error("The \"subgraphs\" field is missing"))
+        .and_then(|subgraphs| {
+            serde_json::from_value::<BTreeMap<String, String>>(subgraphs.clone()).map_err(|_| {
+                client_error(
+                    "The \"subgraphs\" field must map namespaces to subgraph names \
+                     or deployment ids",
+                )
+            })
+        })?;
+
+    Ok(CompositeRequest {
+        document,
+        variables,
+        subgraphs,
+    })
+}
+
+/// Splits a composite query into one query for each field in its
+/// top-level selection set. Each of these queries keeps the variable
+/// definitions and fragments of the composite query
+pub fn split(request: &CompositeRequest) -> Result<Vec<NamespacedQuery>, GraphQLServerError> {
+    split_at_most(request, *MAX_NAMESPACES)
+}
+
+fn split_at_most(
+    request: &CompositeRequest,
+    max_namespaces: usize,
+) -> Result<Vec<NamespacedQuery>, GraphQLServerError> {
+    let mut operations = request
+        .document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            q::Definition::Operation(operation) => Some(operation),
+            q::Definition::Fragment(_) => None,
+        });
+    let selection_set = match (operations.next(), operations.next()) {
+        (Some(q::OperationDefinition::Query(query)), None) => &query.selection_set,
+        (Some(q::OperationDefinition::SelectionSet(selection_set)), None) => selection_set,
+        _ => {
+            return Err(client_error(
+                "A composite query must consist of exactly one query operation",
+            ))
+        }
+    };
+
+    if selection_set.items.len() > max_namespaces {
+        return Err(client_error(format!(
+            "A composite query can select from at most {} namespaces",
+            max_namespaces
+        )));
+    }
+
+    selection_set
+        .items
+        .iter()
+        .map(|selection| {
+            let field = match selection {
+                q::Selection::Field(field) => field,
+                _ => {
+                    return Err(client_error(
+                        "The top-level selection set of a composite query can only \
+                         contain namespace fields",
+                    ))
+                }
+            };
+            let subgraph = request.subgraphs.get(&field.name).ok_or_else(|| {
+                client_error(format!(
+                    "`{}` is not one of the namespaces in \"subgraphs\"",
+                    field.name
+                ))
+            })?;
+            if !field.arguments.is_empty() || !field.directives.is_empty() {
+                return Err(client_error(format!(
+                    "The namespace field `{}` can not have arguments or directives",
+                    field.name
+                )));
+            }
+
+            let mut document = request.document.clone();
+            for definition in document.definitions.iter_mut() {
+                match definition {
+                    q::Definition::Operation(q::OperationDefinition::Query(query)) => {
+                        query.selection_set = field.selection_set.clone()
+                    }
+                    q::Definition::Operation(q::OperationDefinition::SelectionSet(
+                        selection_set,
+                    )) => *selection_set = field.selection_set.clone(),
+                    _ => (),
+                }
+            }
+
+            Ok(NamespacedQuery {
+                response_key: field.alias.clone().unwrap_or_else(|| field.name.clone()),
+                subgraph: subgraph.clone(),
+                document,
+            })
+        })
+        .collect()
+}
+
+/// Combines the results of the namespaced queries into the result of the
+/// composite query
+pub fn combine(results: Vec<NamespacedResult>) -> QueryResult {
+    let mut data = BTreeMap::new();
+    let mut errors = vec![];
+    let mut deployments = BTreeMap::new();
+
+    for result in results {
+        data.insert(
+            result.response_key.clone(),
+            result.result.data.unwrap_or(q::Value::Null),
+        );
+        errors.extend(result.result.errors.unwrap_or_default());
+
+        let mut block = BTreeMap::new();
+        block.insert(
+            "number".to_owned(),
+            result.block.map_or(q::Value::Null, |ptr| {
+                q::Value::Int(q::Number::from(ptr.number as i32))
+            }),
+        );
+        block.insert(
+            "hash".to_owned(),
+            result
+                .block
+                .map_or(q::Value::Null, |ptr| q::Value::String(ptr.hash_hex())),
+        );
+
+        let mut meta = BTreeMap::new();
+        meta.insert(
+            "deployment".to_owned(),
+            q::Value::String(result.deployment.to_string()),
+        );
+        meta.insert("block".to_owned(), q::Value::Object(block));
+        meta.insert("failed".to_owned(), q::Value::Boolean(result.failed));
+        if result.indexing_errors {
+            meta.insert("hasIndexingErrors".to_owned(), q::Value::Boolean(true));
+        }
+        deployments.insert(result.response_key, q::Value::Object(meta));
+    }
+
+    let mut meta = BTreeMap::new();
+    meta.insert("deployments".to_owned(), q::Value::Object(deployments));
+    let mut extensions = BTreeMap::new();
+    extensions.insert("_meta".to_owned(), q::Value::Object(meta));
+
+    let mut result = QueryResult::new(Some(q::Value::Object(data)));
+    if !errors.is_empty() {
+        result.errors = Some(errors);
+    }
+    result.extensions = Some(q::Value::Object(extensions));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(query: &str) -> CompositeRequest {
+        let body = serde_json::json!({
+            "query": query,
+            "subgraphs": { "tokens": "org/tokens", "pools": "QmPools" },
+        });
+        parse_request(body.to_string().as_bytes()).unwrap()
+    }
+
+    fn format(document: &q::Document) -> String {
+        document
+            .to_string()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn splits_namespace_fields() {
+        let request = request(
+            "query q($n: Int) { tokens { tokens(first: $n) { ...T } } \
+             big: pools { pools { id } } } \
+             fragment T on Token { id }",
+        );
+        let queries = split(&request).unwrap();
+
+        assert_eq!(2, queries.len());
+        assert_eq!("tokens", queries[0].response_key);
+        assert_eq!("org/tokens", queries[0].subgraph);
+        assert_eq!(
+            "query q($n: Int) { tokens(first: $n) { ...T } } fragment T on Token { id }",
+            format(&queries[0].document)
+        );
+        assert_eq!("big", queries[1].response_key);
+        assert_eq!("QmPools", queries[1].subgraph);
+        assert_eq!(
+            "query q($n: Int) { pools { id } } fragment T on Token { id }",
+            format(&queries[1].document)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_namespaces() {
+        assert!(split(&request("{ tokens { id } other { id } }")).is_err());
+        assert!(split(&request("{ tokens(first: 1) { id } }")).is_err());
+        assert!(split(&request("{ ... on Query { tokens { id } } }")).is_err());
+        assert!(split(&request("mutation { tokens { id } }")).is_err());
+    }
+
+    #[test]
+    fn limits_namespaces() {
+        let request = request("{ tokens { id } pools { id } more: pools { id } }");
+        assert_eq!(3, split_at_most(&request, 3).unwrap().len());
+        assert!(split_at_most(&request, 2).is_err());
+    }
+}
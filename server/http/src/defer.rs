@@ -0,0 +1,350 @@
+//! Incremental delivery of query results with `@defer`. Fragments in the
+//! top-level selection set of a query that carry `@defer` are split off
+//! into queries of their own. The rest of the query is answered right
+//! away, and the results of the deferred fragments follow as further parts
+//! of a `multipart/mixed` response once they have been computed. All parts
+//! are pinned to the same block so that the response is consistent.
+use graphql_parser::query as q;
+use hyper::body::Bytes;
+use std::collections::{BTreeMap, HashSet};
+
+use graph::data::query::QueryResult;
+use graph::prelude::{serde_json, EthereumBlockPointer, Query, QueryVariables};
+
+/// The value of the `Content-Type` header of multipart responses
+pub const MULTIPART_CONTENT_TYPE: &str = "multipart/mixed; boundary=\"-\"";
+
+/// A fragment of a query whose result is delivered after the result for
+/// the rest of the query
+pub struct DeferredQuery {
+    /// The `label` argument of the `@defer` directive
+    pub label: Option<String>,
+    pub query: Query,
+}
+
+/// Returns true if a client that sent the given `Accept` header can handle
+/// multipart responses
+pub fn accepts_multipart(accept: Option<&str>) -> bool {
+    accept.map_or(false, |accept| {
+        accept
+            .split(',')
+            .any(|media_type| media_type.trim().starts_with("multipart/mixed"))
+    })
+}
+
+/// Returns the selection set of the only operation in `document` if that
+/// operation is a query
+fn query_selection_set(document: &mut q::Document) -> Option<&mut q::SelectionSet> {
+    let mut operations =
+        document
+            .definitions
+            .iter_mut()
+            .filter_map(|definition| match definition {
+                q::Definition::Operation(operation) => Some(operation),
+                q::Definition::Fragment(_) => None,
+            });
+    match (operations.next(), operations.next()) {
+        (Some(q::OperationDefinition::Query(query)), None) => Some(&mut query.selection_set),
+        (Some(q::OperationDefinition::SelectionSet(selection_set)), None) => Some(selection_set),
+        _ => None,
+    }
+}
+
+fn is_enabled(directive: &q::Directive, variables: Option<&QueryVariables>) -> bool {
+    let condition = directive
+        .arguments
+        .iter()
+        .find(|(name, _)| name == "if")
+        .map(|(_, value)| value);
+    match condition {
+        Some(q::Value::Boolean(enabled)) => *enabled,
+        Some(q::Value::Variable(name)) => match variables.and_then(|vars| vars.get(name)) {
+            Some(q::Value::Boolean(enabled)) => *enabled,
+            _ => true,
+        },
+        _ => true,
+    }
+}
+
+/// Removes an enabled `@defer` directive from a fragment and returns it.
+/// Fields can not be deferred and are left alone.
+fn take_defer(
+    selection: &mut q::Selection,
+    variables: Option<&QueryVariables>,
+) -> Option<q::Directive> {
+    let directives = match selection {
+        q::Selection::Field(_) => return None,
+        q::Selection::FragmentSpread(spread) => &mut spread.directives,
+        q::Selection::InlineFragment(fragment) => &mut fragment.directives,
+    };
+    let index = directives
+        .iter()
+        .position(|directive| directive.name == "defer" && is_enabled(directive, variables))?;
+    Some(directives.remove(index))
+}
+
+fn label(directive: &q::Directive) -> Option<String> {
+    directive
+        .arguments
+        .iter()
+        .find_map(|(name, value)| match value {
+            q::Value::String(label) if name == "label" => Some(label.clone()),
+            _ => None,
+        })
+}
+
+/// Splits the deferred fragments off `query`. Returns the query without
+/// them, and one query for each deferred fragment. Only fragments in the
+/// top-level selection set are deferred; `@defer` anywhere else is
+/// ignored, and the fragment is part of the same result as its parent.
+pub fn split_deferred(mut query: Query) -> (Query, Vec<DeferredQuery>) {
+    let variables = query.variables.clone();
+    let selection_set = match query_selection_set(&mut query.document) {
+        Some(selection_set) => selection_set,
+        None => return (query, vec![]),
+    };
+
+    let mut deferred = vec![];
+    let items = std::mem::replace(&mut selection_set.items, vec![]);
+    for mut selection in items {
+        match take_defer(&mut selection, variables.as_ref()) {
+            Some(directive) => deferred.push((label(&directive), selection)),
+            None => selection_set.items.push(selection),
+        }
+    }
+
+    let deferred = deferred
+        .into_iter()
+        .map(|(label, selection)| {
+            let mut document = query.document.clone();
+            query_selection_set(&mut document)
+                .expect("a query we already split has a selection set")
+                .items = vec![selection];
+            DeferredQuery {
+                label,
+                query: Query {
+                    schema: query.schema.clone(),
+                    document,
+                    variables: variables.clone(),
+                },
+            }
+        })
+        .collect();
+    (query, deferred)
+}
+
+/// Resolves the top-level fields of `query` that do not ask for a block of
+/// their own at `block`, including the fields of top-level fragments
+pub fn pin_to_block(query: &mut Query, block: &EthereumBlockPointer) {
+    let mut constraint = BTreeMap::new();
+    constraint.insert("hash".to_owned(), q::Value::String(block.hash_hex()));
+    let constraint = q::Value::Object(constraint);
+
+    let mut spreads = vec![];
+    if let Some(selection_set) = query_selection_set(&mut query.document) {
+        pin_selection_set(selection_set, &constraint, &mut spreads);
+    }
+
+    // Fragments on the query type can only be spread at the top level
+    let mut pinned = HashSet::new();
+    while let Some(name) = spreads.pop() {
+        if !pinned.insert(name.clone()) {
+            continue;
+        }
+        for definition in query.document.definitions.iter_mut() {
+            match definition {
+                q::Definition::Fragment(fragment) if fragment.name == name => {
+                    pin_selection_set(&mut fragment.selection_set, &constraint, &mut spreads)
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+fn pin_selection_set(
+    selection_set: &mut q::SelectionSet,
+    constraint: &q::Value,
+    spreads: &mut Vec<String>,
+) {
+    for selection in selection_set.items.iter_mut() {
+        match selection {
+            q::Selection::Field(field) => {
+                if !field.name.starts_with("__")
+                    && !field.arguments.iter().any(|(name, _)| name == "block")
+                {
+                    field
+                        .arguments
+                        .push(("block".to_owned(), constraint.clone()));
+                }
+            }
+            q::Selection::InlineFragment(fragment) => {
+                pin_selection_set(&mut fragment.selection_set, constraint, spreads)
+            }
+            q::Selection::FragmentSpread(spread) => spreads.push(spread.fragment_name.clone()),
+        }
+    }
+}
+
+fn part(mut payload: serde_json::Value, has_next: bool) -> Bytes {
+    payload
+        .as_object_mut()
+        .expect("query results serialize to an object")
+        .insert("hasNext".to_owned(), serde_json::Value::Bool(has_next));
+    let mut part = format!(
+        "\r\n---\r\nContent-Type: application/json; charset=utf-8\r\n\r\n{}",
+        payload
+    );
+    if !has_next {
+        part.push_str("\r\n-----\r\n");
+    }
+    Bytes::from(part)
+}
+
+/// The first part of a multipart response, holding the result for
+/// everything that was not deferred
+pub fn initial_part(result: &QueryResult) -> Bytes {
+    let payload = serde_json::to_value(result).expect("Failed to serialize query result");
+    part(payload, true)
+}
+
+/// A part of a multipart response holding the result for a deferred
+/// fragment. Since only top-level fragments are deferred, its `path` is
+/// always the root of the response
+pub fn deferred_part(result: &QueryResult, label: Option<&str>, has_next: bool) -> Bytes {
+    let mut payload = serde_json::to_value(result).expect("Failed to serialize query result");
+    let object = payload
+        .as_object_mut()
+        .expect("query results serialize to an object");
+    object.insert("path".to_owned(), serde_json::Value::Array(vec![]));
+    if let Some(label) = label {
+        object.insert(
+            "label".to_owned(),
+            serde_json::Value::String(label.to_owned()),
+        );
+    }
+    part(payload, has_next)
+}
+
+#[cfg(test)]
+mod tests {
+    use graphql_parser;
+    use std::collections::{BTreeMap, HashMap};
+
+    use graph::prelude::web3::types::H256;
+    use graph::prelude::*;
+
+    use super::*;
+
+    const EXAMPLE_SCHEMA: &'static str = "type Query @entity { users: [User!] }";
+
+    fn query(text: &str, variables: Option<HashMap<String, q::Value>>) -> Query {
+        let schema =
+            Schema::parse(EXAMPLE_SCHEMA, SubgraphDeploymentId::new("test").unwrap()).unwrap();
+        Query {
+            schema: Arc::new(schema),
+            document: graphql_parser::parse_query(text).unwrap(),
+            variables: variables.map(QueryVariables::new),
+        }
+    }
+
+    fn format(query: &Query) -> String {
+        query
+            .document
+            .to_string()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn splits_top_level_fragments() {
+        let (initial, deferred) = split_deferred(query(
+            "query($slow: Boolean) { \
+               users { id } \
+               ... @defer(label: \"names\") { users { name } } \
+               ... Friends @defer(if: $slow) \
+               ... @defer(if: false) { users { age } } \
+             } \
+             fragment Friends on Query { users { friends { id } } }",
+            Some(
+                vec![("slow".to_owned(), q::Value::Boolean(true))]
+                    .into_iter()
+                    .collect(),
+            ),
+        ));
+
+        assert_eq!(2, deferred.len());
+        assert_eq!(Some("names".to_owned()), deferred[0].label);
+        assert_eq!(None, deferred[1].label);
+        assert!(format(&initial).contains("users { id }"));
+        assert!(format(&initial).contains("... @defer(if: false) { users { age } }"));
+        assert!(!format(&initial).contains("name"));
+        assert!(!format(&initial).contains("... Friends"));
+        assert!(format(&deferred[0].query).contains("{ ... { users { name } } }"));
+        assert!(format(&deferred[1].query).contains("{ ... Friends }"));
+        assert!(format(&deferred[1].query).contains("fragment Friends on Query"));
+    }
+
+    #[test]
+    fn ignores_defer_below_the_top_level() {
+        let (initial, deferred) = split_deferred(query("{ users { ... @defer { name } } }", None));
+        assert!(deferred.is_empty());
+        assert!(format(&initial).contains("@defer"));
+    }
+
+    #[test]
+    fn pins_top_level_fields_to_a_block() {
+        let block = EthereumBlockPointer {
+            hash: H256::from_low_u64_be(7),
+            number: 7,
+        };
+        let (mut initial, mut deferred) = split_deferred(query(
+            "{ \
+               users { id } \
+               old: users(block: { number: 1 }) { id } \
+               __typename \
+               ... @defer { users { name } } \
+               ... Friends @defer \
+             } \
+             fragment Friends on Query { users { friends { id } } }",
+            None,
+        ));
+        pin_to_block(&mut initial, &block);
+        for deferred in deferred.iter_mut() {
+            pin_to_block(&mut deferred.query, &block);
+        }
+
+        let pinned = format!("(block: {{hash: \"{}\"}})", block.hash_hex());
+        assert!(format(&initial).contains(&format!("users{} {{ id }}", pinned)));
+        assert!(format(&initial).contains("old: users(block: {number: 1})"));
+        assert!(format(&initial).contains("__typename }"));
+        assert!(format(&deferred[0].query).contains(&format!("users{} {{ name }}", pinned)));
+        assert!(format(&deferred[1].query)
+            .contains(&format!("fragment Friends on Query {{ users{} {{", pinned)));
+    }
+
+    #[test]
+    fn formats_parts() {
+        let data = q::Value::Object(BTreeMap::new());
+        let result = QueryResult::new(Some(data));
+
+        let initial = initial_part(&result);
+        assert!(initial.starts_with(b"\r\n---\r\n"));
+        assert!(initial.ends_with(b"{\"data\":{},\"hasNext\":true}"));
+
+        let last = deferred_part(&result, Some("names"), false);
+        assert!(last.ends_with(
+            b"{\"data\":{},\"hasNext\":false,\"label\":\"names\",\"path\":[]}\r\n-----\r\n"
+        ));
+    }
+
+    #[test]
+    fn detects_multipart_clients() {
+        assert!(!accepts_multipart(None));
+        assert!(!accepts_multipart(Some("application/json")));
+        assert!(accepts_multipart(Some(
+            "application/json, multipart/mixed; deferSpec=20220824"
+        )));
+    }
+}
@@ -0,0 +1,423 @@
+//! Tell clients when the deployment they query has failed. A failed
+//! deployment keeps serving the data it had indexed when it failed, which
+//! looks just like current data to a client. Responses for such a
+//! deployment therefore carry a `_meta` extension that says so and names
+//! the block the data is frozen at. Operators can also reject queries to
+//! failed deployments altogether.
+//!
+//! Deployments that opted into non-fatal errors keep indexing after a
+//! handler error, but lack the changes of the blocks in which errors
+//! happened. Responses for them say so with `hasIndexingErrors` in the
+//! `_meta` extension.
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use graph::components::server::query::GraphQLServerError;
+use graph::data::subgraph::schema::{SubgraphDeploymentEntity, SubgraphErrorEntity};
+use graph::prelude::*;
+use graphql_parser::query as q;
+
+lazy_static! {
+    static ref REJECT_QUERIES_TO_FAILED_DEPLOYMENTS: bool =
+        env::var("GRAPH_REJECT_QUERIES_TO_FAILED_DEPLOYMENTS")
+            .map(|s| s == "true")
+            .unwrap_or(false);
+
+    /// How long whether a deployment has failed is remembered, in seconds
+    static ref FAILED_DEPLOYMENTS_TTL: Duration = Duration::from_secs(
+        env::var("GRAPH_FAILED_DEPLOYMENTS_TTL")
+            .unwrap_or("10".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_FAILED_DEPLOYMENTS_TTL")
+    );
+
+    /// How long whether a deployment has indexing errors is remembered, in
+    /// seconds
+    static ref INDEXING_ERRORS_TTL: Duration = Duration::from_secs(
+        env::var("GRAPH_INDEXING_ERRORS_TTL")
+            .unwrap_or("10".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_INDEXING_ERRORS_TTL")
+    );
+}
+
+/// A deployment that has failed, and the block its data is frozen at
+#[derive(Clone, Debug)]
+pub struct FailedDeployment {
+    id: SubgraphDeploymentId,
+    block_number: Option<Value>,
+    block_hash: Option<Value>,
+}
+
+impl FailedDeployment {
+    /// Look up whether deployment `id` has failed. Returns `None` if it is
+    /// healthy. Queries should go through `FailedDeployments`, which
+    /// remembers what it found
+    fn load(store: &impl Store, id: &SubgraphDeploymentId) -> Result<Option<Self>, Error> {
+        let deployment = match store.get(SubgraphDeploymentEntity::key(id.clone()))? {
+            Some(deployment) => deployment,
+            None => return Ok(None),
+        };
+        if deployment.get("failed") != Some(&Value::Bool(true)) {
+            return Ok(None);
+        }
+        Ok(Some(FailedDeployment {
+            id: id.clone(),
+            block_number: deployment.get("latestEthereumBlockNumber").cloned(),
+            block_hash: deployment.get("latestEthereumBlockHash").cloned(),
+        }))
+    }
+
+    /// The error clients get when queries to failed deployments are rejected
+    fn rejection(&self) -> String {
+        match &self.block_number {
+            Some(number) => format!(
+                "Subgraph deployment {} has failed at block {} and does not serve queries",
+                self.id, number
+            ),
+            None => format!(
+                "Subgraph deployment {} has failed and does not serve queries",
+                self.id
+            ),
+        }
+    }
+
+    /// The extensions to add to responses from the failed deployment
+    pub fn extensions(&self) -> q::Value {
+        let value =
+            |value: &Option<Value>| value.clone().map(q::Value::from).unwrap_or(q::Value::Null);
+
+        let mut block = BTreeMap::new();
+        block.insert("number".to_owned(), value(&self.block_number));
+        block.insert("hash".to_owned(), value(&self.block_hash));
+
+        let mut meta = BTreeMap::new();
+        meta.insert(
+            "deployment".to_owned(),
+            q::Value::String(self.id.to_string()),
+        );
+        meta.insert("failed".to_owned(), q::Value::Boolean(true));
+        meta.insert("frozenAtBlock".to_owned(), q::Value::Object(block));
+
+        let mut extensions = BTreeMap::new();
+        extensions.insert("_meta".to_owned(), q::Value::Object(meta));
+        q::Value::Object(extensions)
+    }
+}
+
+/// What was last found out about deployments, and when
+#[derive(Debug)]
+struct Checked<T> {
+    checked: Mutex<HashMap<SubgraphDeploymentId, (Instant, T)>>,
+}
+
+impl<T> Default for Checked<T> {
+    fn default() -> Self {
+        Checked {
+            checked: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> Checked<T> {
+    /// Return what was found for deployment `id` if that is younger than
+    /// `ttl`, and find it out with `check` otherwise
+    fn get_or_check(
+        &self,
+        id: &SubgraphDeploymentId,
+        now: Instant,
+        ttl: Duration,
+        check: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        if let Some((checked, value)) = self.checked.lock().unwrap().get(id) {
+            if now.duration_since(*checked) < ttl {
+                return Ok(value.clone());
+            }
+        }
+
+        let value = check()?;
+        self.checked
+            .lock()
+            .unwrap()
+            .insert(id.clone(), (now, value.clone()));
+        Ok(value)
+    }
+}
+
+/// Which deployments have failed. What was found for a deployment is
+/// remembered for `GRAPH_FAILED_DEPLOYMENTS_TTL` so that not every query
+/// has to look it up
+#[derive(Debug)]
+pub struct FailedDeployments {
+    checked: Checked<Option<FailedDeployment>>,
+    reject_queries: bool,
+}
+
+impl Default for FailedDeployments {
+    fn default() -> Self {
+        Self::new(*REJECT_QUERIES_TO_FAILED_DEPLOYMENTS)
+    }
+}
+
+impl FailedDeployments {
+    /// Create a check that rejects queries to failed deployments if
+    /// `reject_queries` is set, instead of answering them from the data the
+    /// deployment had when it failed
+    pub fn new(reject_queries: bool) -> Self {
+        FailedDeployments {
+            checked: Checked::default(),
+            reject_queries,
+        }
+    }
+
+    /// Whether a query to deployment `id` can be answered, and whether the
+    /// deployment has failed. Returns `None` if it is healthy
+    pub fn check(
+        &self,
+        store: &impl Store,
+        id: &SubgraphDeploymentId,
+    ) -> Result<Option<FailedDeployment>, GraphQLServerError> {
+        self.check_at(store, id, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        store: &impl Store,
+        id: &SubgraphDeploymentId,
+        now: Instant,
+    ) -> Result<Option<FailedDeployment>, GraphQLServerError> {
+        let failed = self
+            .checked
+            .get_or_check(id, now, *FAILED_DEPLOYMENTS_TTL, || {
+                FailedDeployment::load(store, id)
+            })
+            .map_err(|e| GraphQLServerError::InternalError(e.to_string()))?;
+        match failed {
+            Some(failed) if self.reject_queries => {
+                Err(GraphQLServerError::ClientError(failed.rejection()))
+            }
+            failed => Ok(failed),
+        }
+    }
+}
+
+/// Whether deployments continued indexing after non-fatal errors. What
+/// was found for a deployment is remembered for `GRAPH_INDEXING_ERRORS_TTL`
+/// so that not every query has to look it up
+#[derive(Debug, Default)]
+pub struct IndexingErrors {
+    checked: Checked<bool>,
+}
+
+impl IndexingErrors {
+    /// Whether deployment `id` continued indexing after non-fatal errors
+    pub fn check(&self, store: &impl Store, id: &SubgraphDeploymentId) -> Result<bool, Error> {
+        self.check_at(store, id, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        store: &impl Store,
+        id: &SubgraphDeploymentId,
+        now: Instant,
+    ) -> Result<bool, Error> {
+        self.checked
+            .get_or_check(id, now, *INDEXING_ERRORS_TTL, || {
+                let errors = store.find(
+                    SubgraphErrorEntity::query()
+                        .filter(EntityFilter::new_equal("deployment", id.to_string()))
+                        .first(1),
+                )?;
+                Ok(!errors.is_empty())
+            })
+    }
+}
+
+/// Add `hasIndexingErrors` to the `_meta` extension of `extensions`, or
+/// create the extension for deployment `id` if there is none yet
+pub fn with_indexing_errors(extensions: Option<q::Value>, id: &SubgraphDeploymentId) -> q::Value {
+    let mut extensions = match extensions {
+        Some(q::Value::Object(extensions)) => extensions,
+        _ => BTreeMap::new(),
+    };
+    let mut meta = match extensions.remove("_meta") {
+        Some(q::Value::Object(meta)) => meta,
+        _ => {
+            let mut meta = BTreeMap::new();
+            meta.insert("deployment".to_owned(), q::Value::String(id.to_string()));
+            meta
+        }
+    };
+    meta.insert("hasIndexingErrors".to_owned(), q::Value::Boolean(true));
+    extensions.insert("_meta".to_owned(), q::Value::Object(meta));
+    q::Value::Object(extensions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph_mock::MockStore;
+
+    /// A store with deployment `QmFailed`, which failed at block 17
+    fn failed_store(times: usize) -> MockStore {
+        let mut store = MockStore::new();
+        store.expect_get().times(times).returning(|_| {
+            let mut deployment = Entity::new();
+            deployment.set("failed", true);
+            deployment.set("latestEthereumBlockNumber", 17u64);
+            deployment.set("latestEthereumBlockHash", H256::from_low_u64_be(17));
+            Ok(Some(deployment))
+        });
+        store
+    }
+
+    fn failed_id() -> SubgraphDeploymentId {
+        SubgraphDeploymentId::new("QmFailed").unwrap()
+    }
+
+    #[test]
+    fn failed_deployments_are_remembered() {
+        let store = failed_store(2);
+        let failed = FailedDeployments::new(false);
+        let start = Instant::now();
+        assert!(failed
+            .check_at(&store, &failed_id(), start)
+            .unwrap()
+            .is_some());
+        assert!(failed
+            .check_at(&store, &failed_id(), start + Duration::from_secs(1))
+            .unwrap()
+            .is_some());
+
+        // Expired results are looked up again
+        let later = start + *FAILED_DEPLOYMENTS_TTL;
+        assert!(failed
+            .check_at(&store, &failed_id(), later)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn healthy_deployments_are_not_flagged() {
+        let mut store = MockStore::new();
+        store.expect_get().returning(|key| {
+            if key.entity_id == "QmMissing" {
+                return Ok(None);
+            }
+            let mut deployment = Entity::new();
+            deployment.set("failed", false);
+            Ok(Some(deployment))
+        });
+
+        let failed = FailedDeployments::new(true);
+        for id in &["QmHealthy", "QmMissing"] {
+            let id = SubgraphDeploymentId::new(*id).unwrap();
+            assert!(failed.check(&store, &id).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn queries_to_failed_deployments_can_be_rejected() {
+        let store = failed_store(1);
+        match FailedDeployments::new(true).check(&store, &failed_id()) {
+            Err(GraphQLServerError::ClientError(message)) => assert_eq!(
+                "Subgraph deployment QmFailed has failed at block 17 and does not serve queries",
+                message
+            ),
+            other => panic!("queries must be rejected, got {:?}", other),
+        }
+
+        let store = failed_store(1);
+        assert!(FailedDeployments::new(false)
+            .check(&store, &failed_id())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn failed_deployments_have_meta_extension() {
+        let store = failed_store(1);
+        let failed = FailedDeployments::new(false)
+            .check(&store, &failed_id())
+            .unwrap()
+            .unwrap();
+
+        let mut block = BTreeMap::new();
+        block.insert("number".to_owned(), q::Value::String("17".to_owned()));
+        block.insert(
+            "hash".to_owned(),
+            q::Value::String(format!("0x{:064x}", 17)),
+        );
+        let mut meta = BTreeMap::new();
+        meta.insert(
+            "deployment".to_owned(),
+            q::Value::String("QmFailed".to_owned()),
+        );
+        meta.insert("failed".to_owned(), q::Value::Boolean(true));
+        meta.insert("frozenAtBlock".to_owned(), q::Value::Object(block));
+        let mut extensions = BTreeMap::new();
+        extensions.insert("_meta".to_owned(), q::Value::Object(meta));
+
+        assert_eq!(q::Value::Object(extensions), failed.extensions());
+    }
+
+    #[test]
+    fn indexing_errors_are_remembered() {
+        let id = SubgraphDeploymentId::new("QmErrors").unwrap();
+        let mut store = MockStore::new();
+        store
+            .expect_find()
+            .times(2)
+            .returning(|_| Ok(vec![Entity::new()]));
+
+        let errors = IndexingErrors::default();
+        let start = Instant::now();
+        assert!(errors.check_at(&store, &id, start).unwrap());
+        assert!(errors
+            .check_at(&store, &id, start + Duration::from_secs(1))
+            .unwrap());
+
+        // Expired results are looked up again
+        let later = start + *INDEXING_ERRORS_TTL;
+        assert!(errors.check_at(&store, &id, later).unwrap());
+    }
+
+    #[test]
+    fn indexing_errors_are_added_to_meta() {
+        let id = SubgraphDeploymentId::new("QmErrors").unwrap();
+        let meta = |extensions: q::Value| match extensions {
+            q::Value::Object(mut extensions) => extensions.remove("_meta").unwrap(),
+            _ => panic!("extensions must be an object"),
+        };
+        let field = |meta: &q::Value, name: &str| match meta {
+            q::Value::Object(meta) => meta.get(name).cloned(),
+            _ => panic!("_meta must be an object"),
+        };
+
+        let created = meta(with_indexing_errors(None, &id));
+        assert_eq!(
+            Some(q::Value::Boolean(true)),
+            field(&created, "hasIndexingErrors")
+        );
+        assert_eq!(
+            Some(q::Value::String(id.to_string())),
+            field(&created, "deployment")
+        );
+
+        // The `_meta` of a failed deployment is extended
+        let failed = FailedDeployment {
+            id: id.clone(),
+            block_number: None,
+            block_hash: None,
+        };
+        let extended = meta(with_indexing_errors(Some(failed.extensions()), &id));
+        assert_eq!(Some(q::Value::Boolean(true)), field(&extended, "failed"));
+        assert_eq!(
+            Some(q::Value::Boolean(true)),
+            field(&extended, "hasIndexingErrors")
+        );
+    }
+}
@@ -8,11 +8,16 @@ extern crate http;
 extern crate hyper;
 extern crate serde;
 
+mod composite;
+mod defer;
+mod failed;
 mod request;
 mod response;
 mod server;
 mod service;
+mod validation;
 
+pub use self::failed::{FailedDeployment, FailedDeployments};
 pub use self::request::GraphQLRequest;
 pub use self::response::GraphQLResponse;
 pub use self::server::GraphQLServer;
@@ -4,6 +4,7 @@ use hyper;
 use hyper::service::make_service_fn;
 use hyper::Server;
 
+use crate::failed::{FailedDeployments, IndexingErrors};
 use crate::service::{GraphQLService, GraphQLServiceMetrics};
 use graph::prelude::{GraphQLServer as GraphQLServerTrait, *};
 
@@ -85,15 +86,20 @@ where
         let metrics = self.metrics.clone();
         let store = self.store.clone();
         let node_id = self.node_id.clone();
+        let failed_deployments = Arc::new(FailedDeployments::default());
+        let indexing_errors = Arc::new(IndexingErrors::default());
         let new_service = make_service_fn(move |_| {
-            futures03::future::ok::<_, Error>(GraphQLService::new(
-                logger_for_service.clone(),
-                metrics.clone(),
-                graphql_runner.clone(),
-                store.clone(),
-                ws_port,
-                node_id.clone(),
-            ))
+            futures03::future::ok::<_, Error>(
+                GraphQLService::new(
+                    logger_for_service.clone(),
+                    metrics.clone(),
+                    graphql_runner.clone(),
+                    store.clone(),
+                    ws_port,
+                    node_id.clone(),
+                )
+                .with_deployment_checks(failed_deployments.clone(), indexing_errors.clone()),
+            )
         });
 
         // Create a task to run the server and handle HTTP requests
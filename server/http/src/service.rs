@@ -9,17 +9,28 @@ use std::time::Instant;
 
 use graph::components::server::query::GraphQLServerError;
 use graph::data::subgraph::schema::{SubgraphEntity, SUBGRAPHS_ID};
+use graph::prelude::futures03::future::Either;
 use graph::prelude::*;
+use graph_graphql::prelude::validate_strict;
 use http::header;
 use hyper::service::Service;
 use hyper::{Body, Method, Request, Response, StatusCode};
 
+use crate::composite::{self, NamespacedQuery, NamespacedResult};
+use crate::defer::{self, DeferredQuery};
+use crate::failed::{with_indexing_errors, FailedDeployment, FailedDeployments, IndexingErrors};
 use crate::request::GraphQLRequest;
 use crate::response::GraphQLResponse;
+use crate::validation::{validation_mode, ValidationMode};
+
+/// How often a namespace of a composite query is run before its result is
+/// returned even though the deployment's block changed while it ran
+const COMPOSITE_QUERY_ATTEMPTS: usize = 3;
 
 pub struct GraphQLServiceMetrics {
     query_execution_time: Box<HistogramVec>,
     failed_query_execution_time: Box<HistogramVec>,
+    validation_violations: Box<CounterVec>,
 }
 
 impl fmt::Debug for GraphQLServiceMetrics {
@@ -50,9 +61,22 @@ impl GraphQLServiceMetrics {
             )
             .expect("failed to create `subgraph_failed_query_execution_time` histogram");
 
+        let validation_violations = registry
+            .new_counter_vec(
+                format!("subgraph_query_validation_violations"),
+                String::from(
+                    "Queries that violate the GraphQL spec, by whether they were rejected \
+                     (strict) or run anyway (legacy)",
+                ),
+                HashMap::new(),
+                vec![String::from("subgraph_deployment"), String::from("mode")],
+            )
+            .expect("failed to create `subgraph_query_validation_violations` counter");
+
         Self {
             query_execution_time,
             failed_query_execution_time,
+            validation_violations,
         }
     }
 
@@ -62,6 +86,12 @@ impl GraphQLServiceMetrics {
             .observe(duration.clone());
     }
 
+    pub fn observe_validation_violation(&self, deployment_id: &str, mode: ValidationMode) {
+        self.validation_violations
+            .with_label_values(vec![deployment_id, mode.as_str()].as_slice())
+            .inc();
+    }
+
     pub fn observe_failed_query_execution_time(&self, duration: f64, deployment_id: String) {
         self.failed_query_execution_time
             .with_label_values(vec![deployment_id.as_ref()].as_slice())
@@ -83,6 +113,8 @@ pub struct GraphQLService<Q, S> {
     store: Arc<S>,
     ws_port: u16,
     node_id: NodeId,
+    failed_deployments: Arc<FailedDeployments>,
+    indexing_errors: Arc<IndexingErrors>,
 }
 
 impl<Q, S> Clone for GraphQLService<Q, S> {
@@ -94,6 +126,8 @@ impl<Q, S> Clone for GraphQLService<Q, S> {
             store: self.store.clone(),
             ws_port: self.ws_port,
             node_id: self.node_id.clone(),
+            failed_deployments: self.failed_deployments.clone(),
+            indexing_errors: self.indexing_errors.clone(),
         }
     }
 }
@@ -119,9 +153,24 @@ where
             store,
             ws_port,
             node_id,
+            failed_deployments: Arc::new(FailedDeployments::default()),
+            indexing_errors: Arc::new(IndexingErrors::default()),
         }
     }
 
+    /// Share what was found out about failed deployments and indexing
+    /// errors with the other services of the server instead of looking it
+    /// up again for each connection
+    pub fn with_deployment_checks(
+        mut self,
+        failed_deployments: Arc<FailedDeployments>,
+        indexing_errors: Arc<IndexingErrors>,
+    ) -> Self {
+        self.failed_deployments = failed_deployments;
+        self.indexing_errors = indexing_errors;
+        self
+    }
+
     fn graphiql_html(&self) -> String {
         include_str!("../assets/index.html")
             .replace("__WS_PORT__", format!("{}", self.ws_port).as_str())
@@ -215,7 +264,7 @@ where
                 ))
             })?;
 
-        self.handle_graphql_query(subgraph_id, request.into_body())
+        self.handle_graphql_query(Some(subgraph_name), subgraph_id, request)
             .await
     }
 
@@ -226,14 +275,15 @@ where
     ) -> GraphQLServiceResponse {
         match SubgraphDeploymentId::new(id) {
             Err(()) => self.handle_not_found(),
-            Ok(id) => self.handle_graphql_query(id, request.into_body()).boxed(),
+            Ok(id) => self.handle_graphql_query(None, id, request).boxed(),
         }
     }
 
     async fn handle_graphql_query(
         self,
+        subgraph_name: Option<String>,
         id: SubgraphDeploymentId,
-        request_body: Body,
+        request: Request<Body>,
     ) -> GraphQLServiceResult {
         let service = self.clone();
         let logger = self.logger.clone();
@@ -260,21 +310,109 @@ where
             }
         };
 
+        // A failed deployment still answers queries from the data it had
+        // when it failed, unless that is turned off, but we let the client
+        // know that the data is not current
+        let failed = self.failed_deployments.check(self.store.as_ref(), &id)?;
+        let indexing_errors = match self.indexing_errors.check(self.store.as_ref(), &id) {
+            Ok(indexing_errors) => indexing_errors,
+            Err(e) => {
+                return Err(GraphQLServerError::InternalError(e.to_string()));
+            }
+        };
+
+        // Deferred fragments are only split off for clients that can
+        // receive the multipart response we send for them
+        let multipart = defer::accepts_multipart(
+            request
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok()),
+        );
+
+        let mode = validation_mode(subgraph_name.as_ref().map(String::as_str), &id);
+        let validation_logger = self.logger.clone();
+        let validation_metrics = self.metrics.clone();
+        let validation_id = id.clone();
+        let meta_id = id.clone();
+        let block_id = id.clone();
+
         let start = Instant::now();
-        hyper::body::to_bytes(request_body)
+        let result = hyper::body::to_bytes(request.into_body())
             .map_err(|_| GraphQLServerError::from("Failed to read request body"))
             .and_then(move |body| GraphQLRequest::new(body, schema).compat())
             .and_then(move |query| {
+                let violations = validate_strict(&query.document);
+                if !violations.is_empty() {
+                    validation_metrics.observe_validation_violation(&validation_id, mode);
+                    debug!(
+                        validation_logger,
+                        "Query violates the GraphQL spec";
+                        "subgraph_deployment" => validation_id.deref(),
+                        "mode" => mode.as_str(),
+                        "violations" => violations
+                            .iter()
+                            .map(|e| e.to_string())
+                            .collect::<Vec<_>>()
+                            .join("; "),
+                    );
+                    if mode == ValidationMode::Strict {
+                        let result = QueryResult::from(violations);
+                        return Either::Left(futures03::future::ok::<_, GraphQLServerError>((
+                            result,
+                            vec![],
+                        )));
+                    }
+                }
+
+                let (query, deferred) = if multipart {
+                    // The limits apply to the query as a whole, not to each
+                    // of the parts it is split into
+                    if let Err(errors) = service.graphql_runner.check_query(&query) {
+                        return Either::Left(futures03::future::ok((
+                            QueryResult::from(errors),
+                            vec![],
+                        )));
+                    }
+                    let (mut query, mut deferred) = defer::split_deferred(query);
+
+                    // All parts of the response reflect the block the
+                    // deployment is at now, even if it moves on before the
+                    // deferred parts are run
+                    if !deferred.is_empty() {
+                        match service.store.block_ptr(block_id) {
+                            Ok(Some(block)) => {
+                                defer::pin_to_block(&mut query, &block);
+                                for deferred in deferred.iter_mut() {
+                                    defer::pin_to_block(&mut deferred.query, &block);
+                                }
+                            }
+                            Ok(None) => (),
+                            Err(e) => {
+                                return Either::Left(futures03::future::err(
+                                    GraphQLServerError::InternalError(e.to_string()),
+                                ))
+                            }
+                        }
+                    }
+                    (query, deferred)
+                } else {
+                    (query, vec![])
+                };
+
                 // Run the query using the query runner
-                tokio::task::block_in_place(|| {
-                    service
-                        .graphql_runner
-                        .run_query(query)
-                        .map_err(|e| GraphQLServerError::from(e))
-                        .compat()
-                })
+                Either::Right(
+                    tokio::task::block_in_place(|| {
+                        service
+                            .graphql_runner
+                            .run_query(query)
+                            .map_err(|e| GraphQLServerError::from(e))
+                            .compat()
+                    })
+                    .map_ok(move |result| (result, deferred)),
+                )
             })
-            .then(move |result| {
+            .map(move |result| {
                 service_metrics.observe_query_execution_time(
                     start.elapsed().as_secs_f64(),
                     sd_id.deref().to_string(),
@@ -297,11 +435,314 @@ where
                         "code" => LogCode::GraphQlQueryFailure,
                     ),
                 }
-                GraphQLResponse::new(result).compat()
+                result
+            })
+            .map_ok(move |(mut result, deferred)| {
+                result.extensions = failed.as_ref().map(FailedDeployment::extensions);
+                if indexing_errors {
+                    result.extensions = Some(with_indexing_errors(result.extensions, &meta_id));
+                }
+                (result, deferred)
+            })
+            .await;
+
+        match result {
+            Ok((result, deferred)) if !deferred.is_empty() => {
+                Ok(self.send_deferred(id, result, deferred))
+            }
+            result => {
+                GraphQLResponse::new(result.map(|(result, _)| result))
+                    .compat()
+                    .await
+            }
+        }
+    }
+
+    /// Responds with a multipart response whose first part is the
+    /// `initial` result. The deferred queries are run one after the other
+    /// in the background, and their results are sent as further parts as
+    /// soon as they are available. They have been pinned to the block of
+    /// the initial result already.
+    fn send_deferred(
+        self,
+        id: SubgraphDeploymentId,
+        initial: QueryResult,
+        deferred: Vec<DeferredQuery>,
+    ) -> Response<Body> {
+        let (mut sender, body) = Body::channel();
+
+        graph::spawn(async move {
+            let start = Instant::now();
+            let count = deferred.len();
+            if sender
+                .send_data(defer::initial_part(&initial))
+                .await
+                .is_err()
+            {
+                return;
+            }
+
+            for (i, DeferredQuery { label, query }) in deferred.into_iter().enumerate() {
+                let result =
+                    tokio::task::block_in_place(|| self.graphql_runner.run_query(query).compat())
+                        .await
+                        .unwrap_or_else(|e| QueryResult {
+                            data: None,
+                            errors: Some(vec![e]),
+                            extensions: None,
+                        });
+
+                let part = defer::deferred_part(
+                    &result,
+                    label.as_ref().map(String::as_str),
+                    i + 1 < count,
+                );
+                if sender.send_data(part).await.is_err() {
+                    // The client went away, don't bother running the
+                    // remaining queries
+                    debug!(
+                        self.logger,
+                        "Client disconnected before receiving deferred results";
+                        "subgraph_deployment" => id.deref(),
+                    );
+                    return;
+                }
+            }
+
+            info!(
+                self.logger,
+                "Deferred GraphQL results served";
+                "subgraph_deployment" => id.deref(),
+                "fragments" => count,
+                "query_time_ms" => start.elapsed().as_millis(),
+            );
+        });
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Headers", "Content-Type")
+            .header("Access-Control-Allow-Methods", "GET, OPTIONS, POST")
+            .header(header::CONTENT_TYPE, defer::MULTIPART_CONTENT_TYPE)
+            .body(body)
+            .unwrap()
+    }
+
+    /// The deployment that `subgraph` refers to, which can be a subgraph
+    /// name or a deployment id, together with the subgraph name if it was
+    /// one
+    fn resolve_deployment(
+        &self,
+        subgraph: &str,
+    ) -> Result<(Option<String>, SubgraphDeploymentId), GraphQLServerError> {
+        if let Ok(name) = SubgraphName::new(subgraph) {
+            if let Some(id) = self.store.resolve_subgraph_name_to_id(name).map_err(|e| {
+                GraphQLServerError::InternalError(format!("Error resolving subgraph name: {}", e))
+            })? {
+                return Ok((Some(subgraph.to_owned()), id));
+            }
+        }
+
+        let id = SubgraphDeploymentId::new(subgraph).map_err(|()| {
+            GraphQLServerError::ClientError(format!("Subgraph {} not found", subgraph))
+        })?;
+        match self.store.is_deployed(&id) {
+            Err(e) => Err(GraphQLServerError::InternalError(e.to_string())),
+            Ok(false) => Err(GraphQLServerError::ClientError(format!(
+                "No data found for subgraph {}",
+                id
+            ))),
+            Ok(true) => Ok((None, id)),
+        }
+    }
+
+    /// Runs `query` against deployment `id` and returns its result together
+    /// with the block the deployment was at. If the deployment processed or
+    /// reverted blocks while the query ran, the query is run again so that
+    /// the block matches the data, up to `COMPOSITE_QUERY_ATTEMPTS` times
+    async fn run_at_latest_block(
+        &self,
+        id: &SubgraphDeploymentId,
+        query: Query,
+    ) -> Result<(QueryResult, Option<EthereumBlockPointer>), GraphQLServerError> {
+        let block_ptr = || {
+            self.store
+                .block_ptr(id.clone())
+                .map_err(|e| GraphQLServerError::InternalError(e.to_string()))
+        };
+
+        let mut attempt = 1;
+        loop {
+            let before = block_ptr()?;
+            let result = tokio::task::block_in_place(|| {
+                self.graphql_runner
+                    .run_query(query.clone())
+                    .map_err(|e| GraphQLServerError::from(e))
+                    .compat()
             })
+            .await?;
+            let after = block_ptr()?;
+            if before == after || attempt >= COMPOSITE_QUERY_ATTEMPTS {
+                return Ok((result, after));
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Runs a query that reads from several deployments, see the
+    /// `composite` module
+    async fn handle_composite_query(self, request: Request<Body>) -> GraphQLServiceResult {
+        let start = Instant::now();
+        let body = hyper::body::to_bytes(request.into_body())
+            .await
+            .map_err(|_| GraphQLServerError::from("Failed to read request body"))?;
+        let request = composite::parse_request(&body)?;
+        let queries = composite::split(&request)?;
+        let mut deployments = vec![];
+        for query in queries {
+            let (name, id) = self.resolve_deployment(&query.subgraph)?;
+            deployments.push((query, name, id));
+        }
+
+        // The composite query is validated as a whole, since the fragments
+        // it defines are shared by its namespaces. It is rejected if any
+        // of the endpoints it reads from validates strictly
+        let violations = validate_strict(&request.document);
+        if !violations.is_empty() {
+            let mut strict = false;
+            for (_, name, id) in &deployments {
+                let mode = validation_mode(name.as_ref().map(String::as_str), id);
+                self.metrics.observe_validation_violation(id, mode);
+                strict = strict || mode == ValidationMode::Strict;
+            }
+            debug!(
+                self.logger,
+                "Composite query violates the GraphQL spec";
+                "strict" => strict,
+                "violations" => violations
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            );
+            if strict {
+                return GraphQLResponse::new(Ok(QueryResult::from(violations)))
+                    .compat()
+                    .await;
+            }
+        }
+
+        let mut results = vec![];
+        for (query, _, id) in deployments {
+            let NamespacedQuery {
+                response_key,
+                document,
+                ..
+            } = query;
+            let schema = self
+                .store
+                .api_schema(&id)
+                .map_err(|e| GraphQLServerError::InternalError(e.to_string()))?;
+            let failed = self.failed_deployments.check(self.store.as_ref(), &id)?;
+            let indexing_errors = self
+                .indexing_errors
+                .check(self.store.as_ref(), &id)
+                .map_err(|e| GraphQLServerError::InternalError(e.to_string()))?;
+
+            let query = Query {
+                schema,
+                document,
+                variables: request.variables.clone(),
+            };
+            let query_start = Instant::now();
+            let (result, block) = match self.run_at_latest_block(&id, query).await {
+                Ok(result) => {
+                    self.metrics.observe_query_execution_time(
+                        query_start.elapsed().as_secs_f64(),
+                        id.to_string(),
+                    );
+                    result
+                }
+                Err(e) => {
+                    self.metrics.observe_failed_query_execution_time(
+                        query_start.elapsed().as_secs_f64(),
+                        id.to_string(),
+                    );
+                    error!(
+                        self.logger,
+                        "Composite GraphQL query failed";
+                        "subgraph_deployment" => id.deref(),
+                        "error" => e.to_string(),
+                        "query_time_ms" => start.elapsed().as_millis(),
+                        "code" => LogCode::GraphQlQueryFailure,
+                    );
+                    return Err(e);
+                }
+            };
+
+            results.push(NamespacedResult {
+                response_key,
+                deployment: id,
+                block,
+                failed: failed.is_some(),
+                indexing_errors,
+                result,
+            });
+        }
+
+        let deployments = results
+            .iter()
+            .map(|result| result.deployment.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        info!(
+            self.logger,
+            "Composite GraphQL query served";
+            "subgraph_deployments" => deployments,
+            "query_time_ms" => start.elapsed().as_millis(),
+            "code" => LogCode::GraphQlQuerySuccess,
+        );
+
+        GraphQLResponse::new(Ok(composite::combine(results)))
+            .compat()
             .await
     }
 
+    /// Serves the API schema of a deployment as SDL, including everything
+    /// that is generated from the subgraph's schema, like filters and
+    /// ordering
+    async fn handle_graphql_schema(self, id: String) -> GraphQLServiceResult {
+        let id = match SubgraphDeploymentId::new(id) {
+            Err(()) => return self.handle_not_found().await,
+            Ok(id) => id,
+        };
+
+        match self.store.is_deployed(&id) {
+            Err(e) => {
+                return Err(GraphQLServerError::InternalError(e.to_string()));
+            }
+            Ok(false) => {
+                return Err(GraphQLServerError::ClientError(format!(
+                    "No data found for subgraph {}",
+                    id
+                )));
+            }
+            Ok(true) => (),
+        }
+
+        let schema = self
+            .store
+            .api_schema(&id)
+            .map_err(|e| GraphQLServerError::InternalError(e.to_string()))?;
+
+        Ok(Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Content-Type", "application/graphql; charset=utf-8")
+            .body(Body::from(schema.to_sdl()))
+            .unwrap())
+    }
+
     // Handles OPTIONS requests
     fn handle_graphql_options(&self, _request: Request<Body>) -> GraphQLServiceResponse {
         async {
@@ -368,6 +809,10 @@ where
             | (Method::GET, &["subgraphs", "network", _, _, "graphql"])
             | (Method::GET, &["subgraphs", "graphql"]) => self.handle_graphiql(),
 
+            (Method::GET, &["subgraphs", "id", subgraph_id, "graphql-schema"]) => {
+                self.handle_graphql_schema(subgraph_id.to_owned()).boxed()
+            }
+
             (Method::GET, path @ ["subgraphs", "id", _])
             | (Method::GET, path @ ["subgraphs", "name", _])
             | (Method::GET, path @ ["subgraphs", "name", _, _])
@@ -400,6 +845,9 @@ where
             | (Method::OPTIONS, ["subgraphs", "name", _, _])
             | (Method::OPTIONS, ["subgraphs", "network", _, _]) => self.handle_graphql_options(req),
 
+            (Method::POST, &["subgraphs", "composite"]) => self.handle_composite_query(req).boxed(),
+            (Method::OPTIONS, ["subgraphs", "composite"]) => self.handle_graphql_options(req),
+
             // `/subgraphs` acts as an alias to `/subgraphs/id/SUBGRAPHS_ID`
             (Method::POST, &["subgraphs"]) => {
                 self.handle_graphql_query_by_id(SUBGRAPHS_ID.to_string(), req)
@@ -477,19 +925,26 @@ where
 
 #[cfg(test)]
 mod tests {
+    use http::header;
     use http::status::StatusCode;
     use hyper::service::Service;
     use hyper::{Body, Method, Request};
     use std::collections::BTreeMap;
+    use std::sync::Mutex;
 
+    use graph::prelude::web3::types::H256;
     use graph::prelude::*;
-    use graph_mock::{mock_store_with_users_subgraph, MockMetricsRegistry};
+    use graph_mock::{
+        mock_store_with_users_subgraph, mock_users_subgraph_store, MockMetricsRegistry,
+    };
     use graphql_parser::query as q;
 
+    use crate::defer;
     use crate::test_utils;
 
     use super::GraphQLService;
     use super::GraphQLServiceMetrics;
+    use super::GraphQLServiceResponse;
 
     /// A simple stupid query runner for testing.
     pub struct TestGraphQlRunner;
@@ -517,6 +972,10 @@ mod tests {
             )))))
         }
 
+        fn check_query(&self, _query: &Query) -> Result<(), Vec<QueryExecutionError>> {
+            Ok(())
+        }
+
         fn run_subscription(&self, _subscription: Subscription) -> SubscriptionResultFuture {
             unreachable!();
         }
@@ -597,4 +1056,169 @@ mod tests {
             .expect("Query result field \"name\" is not a string");
         assert_eq!(name, "Jordi".to_string());
     }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn getting_graphql_schema_yields_sdl() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry));
+        let (store, subgraph_id) = mock_store_with_users_subgraph();
+        let graphql_runner = Arc::new(TestGraphQlRunner);
+
+        let node_id = NodeId::new("test").unwrap();
+        let mut service =
+            GraphQLService::new(logger, metrics, graphql_runner, store, 8001, node_id);
+
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(format!(
+                "http://localhost:8000/subgraphs/id/{}/graphql-schema",
+                subgraph_id
+            ))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = tokio::spawn(service.call(request))
+            .await
+            .unwrap()
+            .expect("Should return a response");
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let sdl = String::from_utf8(body.to_vec()).unwrap();
+
+        // The SDL is the API schema, not the input schema
+        assert!(sdl.contains("type User @entity"));
+        assert!(sdl.contains("input User_filter"));
+        assert!(!sdl.contains("subgraphId"));
+    }
+
+    /// A query runner that records the queries it runs and that finds all
+    /// queries too complex if `too_complex` is set
+    struct RecordingGraphQlRunner {
+        queries: Mutex<Vec<String>>,
+        too_complex: bool,
+    }
+
+    impl RecordingGraphQlRunner {
+        fn new(too_complex: bool) -> Self {
+            RecordingGraphQlRunner {
+                queries: Mutex::new(vec![]),
+                too_complex,
+            }
+        }
+    }
+
+    impl GraphQlRunner for RecordingGraphQlRunner {
+        fn run_query_with_complexity(
+            &self,
+            _query: Query,
+            _complexity: Option<u64>,
+            _max_depth: Option<u8>,
+            _max_first: Option<u32>,
+        ) -> QueryResultFuture {
+            unimplemented!();
+        }
+
+        fn run_query(&self, query: Query) -> QueryResultFuture {
+            self.queries
+                .lock()
+                .unwrap()
+                .push(query.document.to_string());
+            Box::new(future::ok(QueryResult::new(Some(q::Value::Object(
+                BTreeMap::new(),
+            )))))
+        }
+
+        fn check_query(&self, _query: &Query) -> Result<(), Vec<QueryExecutionError>> {
+            if self.too_complex {
+                Err(vec![QueryExecutionError::TooComplex(20, 10)])
+            } else {
+                Ok(())
+            }
+        }
+
+        fn run_subscription(&self, _subscription: Subscription) -> SubscriptionResultFuture {
+            unreachable!();
+        }
+    }
+
+    fn deferred_query(
+        graphql_runner: Arc<RecordingGraphQlRunner>,
+        block: Option<EthereumBlockPointer>,
+    ) -> GraphQLServiceResponse {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry));
+        let (mut store, subgraph_id) = mock_users_subgraph_store();
+        store.expect_find().returning(|_| Ok(vec![]));
+        if let Some(block) = block {
+            store.expect_block_ptr().returning(move |_| Ok(Some(block)));
+        }
+
+        let node_id = NodeId::new("test").unwrap();
+        let mut service = GraphQLService::new(
+            logger,
+            metrics,
+            graphql_runner,
+            Arc::new(store),
+            8001,
+            node_id,
+        );
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!(
+                "http://localhost:8000/subgraphs/id/{}",
+                subgraph_id
+            ))
+            .header(header::ACCEPT, "multipart/mixed")
+            .body(Body::from(
+                "{\"query\": \"{ users { id } ... @defer { users { name } } }\"}",
+            ))
+            .unwrap();
+        service.call(request)
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn deferred_parts_are_pinned_to_one_block() {
+        let graphql_runner = Arc::new(RecordingGraphQlRunner::new(false));
+        let block = EthereumBlockPointer {
+            hash: H256::from_low_u64_be(3),
+            number: 3,
+        };
+
+        let response = tokio::spawn(deferred_query(graphql_runner.clone(), Some(block)))
+            .await
+            .unwrap()
+            .expect("Should return a response");
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            defer::MULTIPART_CONTENT_TYPE
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.ends_with(b"\r\n-----\r\n"));
+
+        // The initial query and the deferred fragment both read the block
+        // the deployment was at when the request came in
+        let queries = graphql_runner.queries.lock().unwrap();
+        assert_eq!(2, queries.len());
+        let pinned = format!("users(block: {{hash: \"{}\"}})", block.hash_hex());
+        for query in queries.iter() {
+            assert!(query.contains(&pinned), "query is not pinned: {}", query);
+        }
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn deferred_queries_are_limited_as_a_whole() {
+        let graphql_runner = Arc::new(RecordingGraphQlRunner::new(true));
+
+        let response = tokio::spawn(deferred_query(graphql_runner.clone(), None))
+            .await
+            .unwrap()
+            .expect("Should return a response");
+        let errors = test_utils::assert_error_response(response, StatusCode::OK);
+        assert_eq!(1, errors.len());
+        assert!(graphql_runner.queries.lock().unwrap().is_empty());
+    }
 }
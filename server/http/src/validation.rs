@@ -0,0 +1,104 @@
+//! Choose how strictly queries are validated for each endpoint. Query
+//! execution tolerates some violations of the GraphQL spec, like variables
+//! that are defined twice or fragments that are never used, and clients
+//! have come to rely on that. Operators can therefore reject such queries
+//! for most endpoints while keeping the lenient behavior for some.
+use lazy_static::lazy_static;
+use std::env;
+
+use graph::prelude::SubgraphDeploymentId;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValidationMode {
+    /// Reject queries that violate the spec
+    Strict,
+    /// Run queries that violate the spec, but count them
+    Legacy,
+}
+
+impl ValidationMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValidationMode::Strict => "strict",
+            ValidationMode::Legacy => "legacy",
+        }
+    }
+}
+
+lazy_static! {
+    static ref VALIDATION_MODE: ValidationMode =
+        match env::var("GRAPH_GRAPHQL_VALIDATION").as_ref().map(String::as_str) {
+            Ok("strict") => ValidationMode::Strict,
+            Ok("legacy") | Err(_) => ValidationMode::Legacy,
+            Ok(mode) => panic!("invalid GRAPH_GRAPHQL_VALIDATION `{}`", mode),
+        };
+
+    /// Subgraph names and deployment ids whose endpoints keep validating
+    /// queries leniently when `GRAPH_GRAPHQL_VALIDATION` is `strict`
+    static ref LEGACY_VALIDATION: Vec<String> = env::var("GRAPH_GRAPHQL_LEGACY_VALIDATION")
+        .map(|endpoints| {
+            endpoints
+                .split(',')
+                .map(|endpoint| endpoint.trim().to_owned())
+                .filter(|endpoint| !endpoint.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+}
+
+fn mode_for(
+    default: ValidationMode,
+    legacy: &[String],
+    name: Option<&str>,
+    id: &SubgraphDeploymentId,
+) -> ValidationMode {
+    let is_legacy = legacy
+        .iter()
+        .any(|endpoint| Some(endpoint.as_str()) == name || endpoint == id.as_str());
+    if is_legacy {
+        ValidationMode::Legacy
+    } else {
+        default
+    }
+}
+
+/// The validation mode for queries against the endpoint for the subgraph
+/// `name`, or the endpoint for deployment `id` if the query did not use a
+/// subgraph name
+pub fn validation_mode(name: Option<&str>, id: &SubgraphDeploymentId) -> ValidationMode {
+    mode_for(*VALIDATION_MODE, &LEGACY_VALIDATION, name, id)
+}
+
+#[test]
+fn validation_mode_per_endpoint() {
+    let id = SubgraphDeploymentId::new("QmOld").unwrap();
+    let other = SubgraphDeploymentId::new("QmNew").unwrap();
+    let legacy = vec!["old/subgraph".to_owned(), "QmOld".to_owned()];
+
+    assert_eq!(
+        ValidationMode::Legacy,
+        mode_for(
+            ValidationMode::Strict,
+            &legacy,
+            Some("old/subgraph"),
+            &other
+        )
+    );
+    assert_eq!(
+        ValidationMode::Legacy,
+        mode_for(ValidationMode::Strict, &legacy, None, &id)
+    );
+    assert_eq!(
+        ValidationMode::Strict,
+        mode_for(
+            ValidationMode::Strict,
+            &legacy,
+            Some("new/subgraph"),
+            &other
+        )
+    );
+    assert_eq!(
+        ValidationMode::Legacy,
+        mode_for(ValidationMode::Legacy, &[], None, &other)
+    );
+}
@@ -57,6 +57,10 @@ impl GraphQlRunner for TestGraphQlRunner {
         )))))
     }
 
+    fn check_query(&self, _query: &Query) -> Result<(), Vec<QueryExecutionError>> {
+        Ok(())
+    }
+
     fn run_subscription(&self, _subscription: Subscription) -> SubscriptionResultFuture {
         unreachable!();
     }
@@ -0,0 +1,275 @@
+//! Check whether this node can fetch the files of a deployment from IPFS.
+//! Gateways use this to decide whether to send deployments to a node. A
+//! check resolves the deployment's manifest together with every file it
+//! references, and counts the files as unavailable if that does not finish
+//! in time. Checks run in the background, and their results are reused for
+//! a while so that gateways can poll without putting load on IPFS.
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use graph::prelude::*;
+
+lazy_static! {
+    /// How long resolving all files of a deployment may take, in seconds
+    static ref FILES_CHECK_TIMEOUT: Duration = Duration::from_secs(
+        env::var("GRAPH_DEPLOYMENT_FILES_CHECK_TIMEOUT")
+            .unwrap_or("30".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_DEPLOYMENT_FILES_CHECK_TIMEOUT")
+    );
+
+    /// How long the result of a check is reused, in seconds
+    static ref FILES_CHECK_TTL: Duration = Duration::from_secs(
+        env::var("GRAPH_DEPLOYMENT_FILES_CHECK_TTL")
+            .unwrap_or("300".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_DEPLOYMENT_FILES_CHECK_TTL")
+    );
+}
+
+/// The number of deployments for which check results are kept
+const MAX_CHECKS: usize = 1000;
+
+/// The outcome of checking the files of a deployment
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilesAvailability {
+    pub available: bool,
+    /// Why the files are not available
+    pub error: Option<String>,
+    /// When the check was made, in seconds since the epoch, or `None` if
+    /// the files have not been checked yet
+    pub checked_at: Option<u64>,
+    /// Whether the files are being checked again right now
+    pub checking: bool,
+}
+
+impl FilesAvailability {
+    fn unchecked() -> Self {
+        FilesAvailability {
+            available: false,
+            error: Some("the files of the deployment have not been checked yet".to_owned()),
+            checked_at: None,
+            checking: true,
+        }
+    }
+}
+
+/// The results of recent checks, and the deployments that are being
+/// checked. At most `limit` results are kept
+struct Checks {
+    limit: usize,
+    results: HashMap<SubgraphDeploymentId, (Instant, FilesAvailability)>,
+    running: HashSet<SubgraphDeploymentId>,
+}
+
+impl Checks {
+    fn new(limit: usize) -> Self {
+        Checks {
+            limit,
+            results: HashMap::new(),
+            running: HashSet::new(),
+        }
+    }
+
+    /// Remember the result of checking `id`. If there are too many results
+    /// already, expired ones are forgotten, or the oldest one if none have
+    /// expired
+    fn insert(&mut self, id: SubgraphDeploymentId, availability: FilesAvailability, now: Instant) {
+        self.running.remove(&id);
+        if !self.results.contains_key(&id) && self.results.len() >= self.limit {
+            self.results
+                .retain(|_, (checked, _)| now.duration_since(*checked) < *FILES_CHECK_TTL);
+            if self.results.len() >= self.limit {
+                let oldest = self
+                    .results
+                    .iter()
+                    .min_by_key(|(_, (checked, _))| *checked)
+                    .map(|(id, _)| id.clone());
+                if let Some(oldest) = oldest {
+                    self.results.remove(&oldest);
+                }
+            }
+        }
+        self.results.insert(id, (now, availability));
+    }
+
+    /// How many of the deployments with a result had files that were not
+    /// available
+    fn unavailable(&self) -> usize {
+        self.results
+            .values()
+            .filter(|(_, availability)| !availability.available)
+            .count()
+    }
+}
+
+/// Checks the availability of the files of deployments. The number of
+/// recently checked deployments whose files were not available is kept in
+/// the `deployment_files_unavailable` gauge
+pub struct DeploymentFiles<L> {
+    logger: Logger,
+    link_resolver: Arc<L>,
+    unavailable: Arc<Box<Gauge>>,
+    checks: Arc<Mutex<Checks>>,
+}
+
+impl<L> fmt::Debug for DeploymentFiles<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DeploymentFiles {{ }}")
+    }
+}
+
+impl<L> DeploymentFiles<L>
+where
+    L: LinkResolver + Clone,
+{
+    pub fn new(
+        logger: &Logger,
+        link_resolver: Arc<L>,
+        registry: Arc<impl MetricsRegistry>,
+    ) -> Self {
+        let unavailable = registry
+            .new_gauge(
+                String::from("deployment_files_unavailable"),
+                String::from(
+                    "How many recently checked deployments had files the node could not fetch",
+                ),
+                HashMap::new(),
+            )
+            .expect("failed to create `deployment_files_unavailable` gauge");
+        DeploymentFiles {
+            logger: logger.new(o!("component" => "DeploymentFiles")),
+            link_resolver,
+            unavailable: Arc::new(unavailable),
+            checks: Arc::new(Mutex::new(Checks::new(MAX_CHECKS))),
+        }
+    }
+
+    /// Whether the manifest of deployment `id` and all files it references
+    /// could be fetched when they were last checked. If that result is not
+    /// recent, the files are checked again in the background, and the
+    /// previous result is returned in the meantime
+    pub fn check(&self, id: &SubgraphDeploymentId) -> FilesAvailability {
+        let mut checks = self.checks.lock().unwrap();
+        let latest = checks.results.get(id).cloned();
+        if let Some((checked, availability)) = &latest {
+            if checked.elapsed() < *FILES_CHECK_TTL {
+                return availability.clone();
+            }
+        }
+
+        if checks.running.insert(id.clone()) {
+            self.start_check(id.clone());
+        }
+        match latest {
+            Some((_, availability)) => FilesAvailability {
+                checking: true,
+                ..availability
+            },
+            None => FilesAvailability::unchecked(),
+        }
+    }
+
+    fn start_check(&self, id: SubgraphDeploymentId) {
+        let logger = self.logger.new(o!("subgraph_id" => id.to_string()));
+        let resolver = Arc::new(
+            self.link_resolver
+                .as_ref()
+                .clone()
+                .with_priority(LinkResolverPriority::High)
+                .for_deployment(id.clone()),
+        );
+        let resolve = SubgraphManifest::resolve(id.to_ipfs_link(), resolver, logger.clone());
+        let timeout = *FILES_CHECK_TIMEOUT;
+        let checks = self.checks.clone();
+        let unavailable = self.unavailable.clone();
+
+        graph::spawn(async move {
+            let error = match tokio::time::timeout(timeout, resolve.compat()).await {
+                Ok(Ok(_)) => None,
+                Ok(Err(e)) => Some(e.to_string()),
+                Err(_) => Some(format!(
+                    "resolving the files took longer than {}s",
+                    timeout.as_secs()
+                )),
+            };
+            if let Some(error) = &error {
+                info!(logger, "Deployment files are not available"; "error" => error);
+            }
+
+            let availability = FilesAvailability {
+                available: error.is_none(),
+                error,
+                checked_at: Some(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|since| since.as_secs())
+                        .unwrap_or(0),
+                ),
+                checking: false,
+            };
+            let mut checks = checks.lock().unwrap();
+            checks.insert(id, availability, Instant::now());
+            unavailable.set(checks.unavailable() as f64);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn availability(available: bool) -> FilesAvailability {
+        FilesAvailability {
+            available,
+            error: None,
+            checked_at: Some(0),
+            checking: false,
+        }
+    }
+
+    #[test]
+    fn checks_are_bounded() {
+        let id = |hash: &str| SubgraphDeploymentId::new(hash).unwrap();
+        let start = Instant::now();
+        let mut checks = Checks::new(2);
+
+        checks.running.insert(id("QmA"));
+        checks.insert(id("QmA"), availability(false), start);
+        checks.insert(
+            id("QmB"),
+            availability(true),
+            start + Duration::from_secs(1),
+        );
+        assert!(checks.running.is_empty());
+        assert_eq!(1, checks.unavailable());
+
+        // Updating a deployment that has a result does not evict anything
+        checks.insert(
+            id("QmA"),
+            availability(false),
+            start + Duration::from_secs(2),
+        );
+        assert_eq!(2, checks.results.len());
+
+        // Without expired results, the oldest result is forgotten
+        checks.insert(
+            id("QmC"),
+            availability(false),
+            start + Duration::from_secs(3),
+        );
+        assert_eq!(2, checks.results.len());
+        assert!(!checks.results.contains_key(&id("QmB")));
+        assert_eq!(2, checks.unavailable());
+
+        // Expired results are forgotten first
+        let later = start + *FILES_CHECK_TTL + Duration::from_secs(3);
+        checks.insert(id("QmD"), availability(true), later);
+        assert_eq!(1, checks.results.len());
+        assert!(checks.results.contains_key(&id("QmD")));
+    }
+}
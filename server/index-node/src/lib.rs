@@ -1,3 +1,4 @@
+mod files;
 mod request;
 mod resolver;
 mod response;
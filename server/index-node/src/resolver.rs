@@ -1,19 +1,31 @@
 use graphql_parser::{query as q, query::Name, schema as s, schema::ObjectType};
 use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
 
 use graph::data::graphql::{TryFromValue, ValueList, ValueMap};
-use graph::data::subgraph::schema::SUBGRAPHS_ID;
+use graph::data::subgraph::schema::{SubgraphAdminOperationEntity, TypedEntity, SUBGRAPHS_ID};
+use graph::data::subgraph::{API_VERSIONS, FEATURES, SPEC_VERSIONS};
 use graph::prelude::*;
 use graph_graphql::prelude::{
     object_value, BlockConstraint, ExecutionContext, ObjectOrInterface, Resolver,
 };
 use web3::types::H256;
 
+use crate::files::{DeploymentFiles, FilesAvailability};
+
 /// Resolver for the index node GraphQL API.
-pub struct IndexNodeResolver<R, S> {
+pub struct IndexNodeResolver<R, S, L> {
     logger: Logger,
     graphql_runner: Arc<R>,
     store: Arc<S>,
+    deployment_files: Arc<DeploymentFiles<L>>,
+    /// The Ethereum networks this node is configured for
+    networks: Arc<BTreeMap<String, EthereumAdapterCapabilities>>,
+    /// Whether the request carried a valid admin token, and why not if it
+    /// did not
+    admin_access: Result<(), String>,
 }
 
 /// The ID of a subgraph deployment assignment.
@@ -23,6 +35,8 @@ struct DeploymentAssignment {
     subgraph: String,
     /// ID of the Graph Node that indexes the subgraph.
     node: String,
+    /// Whether block processing for the subgraph is paused.
+    paused: bool,
 }
 
 impl TryFromValue for DeploymentAssignment {
@@ -30,6 +44,7 @@ impl TryFromValue for DeploymentAssignment {
         Ok(Self {
             subgraph: value.get_required("id")?,
             node: value.get_required("nodeId")?,
+            paused: value.get_optional("paused")?.unwrap_or(false),
         })
     }
 }
@@ -58,8 +73,13 @@ struct EthereumIndexingStatus {
     chain_head_block: Option<EthereumBlock>,
     /// The earliest block available for this subgraph.
     earliest_block: Option<EthereumBlock>,
+    /// The earliest block that queries can still be run against, if older
+    /// entity versions have been pruned.
+    earliest_retained_block_number: Option<u64>,
     /// The latest block that the subgraph has synced to.
     latest_block: Option<EthereumBlock>,
+    /// How many seconds the latest block is older than the chain head block.
+    seconds_behind_chain_head: Option<u64>,
 }
 
 /// Indexing status information for different chains (only Ethereum right now).
@@ -88,10 +108,26 @@ impl From<ChainIndexingStatus> for q::Value {
                     "earliestBlock",
                     inner.earliest_block.map_or(q::Value::Null, q::Value::from),
                 ),
+                (
+                    "earliestRetainedBlockNumber",
+                    inner
+                        .earliest_retained_block_number
+                        .map_or(q::Value::Null, |number| {
+                            q::Value::String(format!("{}", number))
+                        }),
+                ),
                 (
                     "latestBlock",
                     inner.latest_block.map_or(q::Value::Null, q::Value::from),
                 ),
+                (
+                    "secondsBehindChainHead",
+                    inner
+                        .seconds_behind_chain_head
+                        .map_or(q::Value::Null, |seconds| {
+                            q::Value::Int((seconds.min(std::i32::MAX as u64) as i32).into())
+                        }),
+                ),
             ]),
         }
     }
@@ -124,11 +160,38 @@ struct IndexingStatus {
     chains: Vec<ChainIndexingStatus>,
     /// ID of the Graph Node that the subgraph is indexed by.
     node: String,
+    /// Whether or not the subgraph has been downgraded for lack of use.
+    idle: bool,
+    /// Whether or not the subgraph has been stopped until it is used again.
+    hibernated: bool,
+    /// Whether or not block processing for the subgraph has been paused.
+    paused: bool,
+    /// Why creating the attribute indexes of the subgraph failed, if it did.
+    index_creation_error: Option<String>,
+    /// Which limits of its disk quota the subgraph exceeded when it was last
+    /// measured.
+    disk_quota: DiskQuotaStatus,
+}
+
+/// Whether a subgraph exceeds the soft and the hard limit of its disk quota.
+#[derive(Clone, Copy, Debug, Default)]
+struct DiskQuotaStatus {
+    soft_limit_exceeded: bool,
+    hard_limit_exceeded: bool,
 }
 
 impl IndexingStatusWithoutNode {
-    /// Adds a Graph Node ID to the indexing status.
-    fn with_node(self, node: String) -> IndexingStatus {
+    /// Adds a Graph Node ID, idleness, whether the subgraph is paused, index
+    /// creation failures and the disk quota status to the indexing status.
+    fn with_node(
+        self,
+        node: String,
+        idle: bool,
+        hibernated: bool,
+        paused: bool,
+        index_creation_error: Option<String>,
+        disk_quota: DiskQuotaStatus,
+    ) -> IndexingStatus {
         IndexingStatus {
             subgraph: self.subgraph,
             synced: self.synced,
@@ -136,6 +199,11 @@ impl IndexingStatusWithoutNode {
             error: self.error,
             chains: self.chains,
             node: node,
+            idle,
+            hibernated,
+            paused,
+            index_creation_error,
+            disk_quota,
         }
     }
 
@@ -179,7 +247,11 @@ impl TryFromValue for IndexingStatusWithoutNode {
                     .get_required("network")?,
                 chain_head_block: Self::block_from_value(value, "ethereumHeadBlock")?,
                 earliest_block: Self::block_from_value(value, "earliestEthereumBlock")?,
+                earliest_retained_block_number: value
+                    .get_optional::<BigInt>("earliestRetainedBlockNumber")?
+                    .map(|n| n.to_u64()),
                 latest_block: Self::block_from_value(value, "latestEthereumBlock")?,
+                seconds_behind_chain_head: None,
             })],
         })
     }
@@ -204,37 +276,158 @@ impl From<IndexingStatus> for q::Value {
                 q::Value::List(status.chains.into_iter().map(q::Value::from).collect()),
             ),
             ("node", q::Value::String(status.node)),
+            ("idle", q::Value::Boolean(status.idle)),
+            ("hibernated", q::Value::Boolean(status.hibernated)),
+            ("paused", q::Value::Boolean(status.paused)),
+            (
+                "indexCreationError",
+                status
+                    .index_creation_error
+                    .map_or(q::Value::Null, q::Value::String),
+            ),
+            (
+                "softDiskQuotaExceeded",
+                q::Value::Boolean(status.disk_quota.soft_limit_exceeded),
+            ),
+            (
+                "hardDiskQuotaExceeded",
+                q::Value::Boolean(status.disk_quota.hard_limit_exceeded),
+            ),
         ])
     }
 }
 
 struct IndexingStatuses(Vec<IndexingStatus>);
 
-impl From<q::Value> for IndexingStatuses {
-    fn from(data: q::Value) -> Self {
+impl TryFrom<q::Value> for IndexingStatuses {
+    type Error = Error;
+
+    fn try_from(data: q::Value) -> Result<Self, Error> {
         // Extract deployment assignment IDs from the query result
         let assignments = data
-            .get_required::<q::Value>("subgraphDeploymentAssignments")
-            .expect("no subgraph deployment assignments in the result")
-            .get_values::<DeploymentAssignment>()
-            .expect("failed to parse subgraph deployment assignments");
+            .get_required::<q::Value>("subgraphDeploymentAssignments")?
+            .get_values::<DeploymentAssignment>()?;
+
+        // Extract the IDs of idle deployments, and whether they are
+        // hibernated
+        let idle = data
+            .get_required::<q::Value>("subgraphDeploymentIdles")?
+            .get_values::<q::Value>()?
+            .iter()
+            .map(|idle| {
+                Ok((
+                    idle.get_required::<String>("id")?,
+                    idle.get_optional::<bool>("hibernated")?.unwrap_or(false),
+                ))
+            })
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+
+        // Extract why creating the attribute indexes of deployments failed
+        let index_failures = data
+            .get_required::<q::Value>("subgraphDeploymentIndexFailures")?
+            .get_values::<q::Value>()?
+            .iter()
+            .map(|failure| {
+                Ok((
+                    failure.get_required::<String>("id")?,
+                    failure.get_required::<String>("error")?,
+                ))
+            })
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+
+        // Extract which limits of their disk quotas deployments exceed
+        let disk_quotas = data
+            .get_required::<q::Value>("subgraphDeploymentQuotas")?
+            .get_values::<q::Value>()?
+            .iter()
+            .map(|quota| {
+                Ok((
+                    quota.get_required::<String>("id")?,
+                    DiskQuotaStatus {
+                        soft_limit_exceeded: quota.get_required("softLimitExceeded")?,
+                        hard_limit_exceeded: quota.get_required("hardLimitExceeded")?,
+                    },
+                ))
+            })
+            .collect::<Result<HashMap<_, _>, Error>>()?;
 
-        IndexingStatuses(
+        Ok(IndexingStatuses(
             // Parse indexing statuses from deployments
-            data.get_required::<q::Value>("subgraphDeployments")
-                .expect("no subgraph deployments in the result")
-                .get_values()
-                .expect("failed to parse subgraph deployments")
+            data.get_required::<q::Value>("subgraphDeployments")?
+                .get_values()?
                 .into_iter()
                 // Filter out those deployments for which there is no active assignment
                 .filter_map(|status: IndexingStatusWithoutNode| {
                     assignments
                         .iter()
                         .find(|assignment| assignment.subgraph == status.subgraph)
-                        .map(|assignment| status.with_node(assignment.node.clone()))
+                        .map(|assignment| {
+                            let hibernated = idle.get(&status.subgraph).cloned();
+                            let index_creation_error =
+                                index_failures.get(&status.subgraph).cloned();
+                            status.with_node(
+                                assignment.node.clone(),
+                                hibernated.is_some(),
+                                hibernated.unwrap_or(false),
+                                assignment.paused,
+                                index_creation_error,
+                                disk_quotas
+                                    .get(&status.subgraph)
+                                    .cloned()
+                                    .unwrap_or_default(),
+                            )
+                        })
                 })
                 .collect(),
-        )
+        ))
+    }
+}
+
+impl IndexingStatuses {
+    /// The hashes of the chain head blocks and latest blocks of all
+    /// deployments
+    fn block_hashes(&self) -> Vec<H256> {
+        let mut hashes = vec![];
+        for status in &self.0 {
+            for chain in &status.chains {
+                match chain {
+                    ChainIndexingStatus::Ethereum(inner) => {
+                        for block in inner.chain_head_block.iter().chain(&inner.latest_block) {
+                            hashes.push(block.0.hash);
+                        }
+                    }
+                }
+            }
+        }
+        hashes
+    }
+
+    /// Fill in how many seconds every deployment is behind the chain head
+    /// from `timestamps`, the timestamps of blocks by their hash.
+    /// Deployments whose blocks have no timestamp are left alone
+    fn with_seconds_behind(mut self, timestamps: &HashMap<H256, u64>) -> Self {
+        let timestamp = |block: &Option<EthereumBlock>| {
+            block
+                .as_ref()
+                .and_then(|block| timestamps.get(&block.0.hash))
+                .cloned()
+        };
+        for status in self.0.iter_mut() {
+            for chain in status.chains.iter_mut() {
+                match chain {
+                    ChainIndexingStatus::Ethereum(inner) => {
+                        inner.seconds_behind_chain_head = match (
+                            timestamp(&inner.chain_head_block),
+                            timestamp(&inner.latest_block),
+                        ) {
+                            (Some(head), Some(latest)) => Some(head.saturating_sub(latest)),
+                            _ => None,
+                        };
+                    }
+                }
+            }
+        }
+        self
     }
 }
 
@@ -244,20 +437,536 @@ impl From<IndexingStatuses> for q::Value {
     }
 }
 
-impl<R, S> IndexNodeResolver<R, S>
+/// Light wrapper around `BlockEntityChange` that is compatible with GraphQL values.
+struct EntityChangeInBlock(BlockEntityChange);
+
+impl From<EntityChangeInBlock> for q::Value {
+    fn from(change: EntityChangeInBlock) -> Self {
+        let change = change.0;
+        let kind = match change.kind {
+            BlockEntityChangeKind::Created => "CREATED",
+            BlockEntityChangeKind::Updated => "UPDATED",
+            BlockEntityChangeKind::Deleted => "DELETED",
+        };
+        object_value(vec![
+            ("__typename", q::Value::String(String::from("EntityChange"))),
+            ("entityType", q::Value::String(change.entity_type)),
+            ("entityId", q::Value::String(change.entity_id)),
+            ("kind", q::Value::Enum(String::from(kind))),
+            ("data", q::Value::Object(change.data.into())),
+        ])
+    }
+}
+
+/// How the entities of one type differ between two blocks, with the ids
+/// of the entities that were created, updated and deleted.
+struct EntityDiffs(Vec<EntityDiff>);
+
+impl From<EntityDiffs> for q::Value {
+    fn from(diffs: EntityDiffs) -> Self {
+        let (mut created, mut updated, mut deleted) = (vec![], vec![], vec![]);
+        for diff in diffs.0 {
+            match diff.kind {
+                BlockEntityChangeKind::Created => {
+                    created.push(q::Value::String(diff.entity_id));
+                }
+                BlockEntityChangeKind::Updated => updated.push(object_value(vec![
+                    ("__typename", q::Value::String(String::from("EntityUpdate"))),
+                    ("entityId", q::Value::String(diff.entity_id)),
+                    (
+                        "changedFields",
+                        q::Value::List(
+                            diff.changed_fields
+                                .into_iter()
+                                .map(q::Value::String)
+                                .collect(),
+                        ),
+                    ),
+                ])),
+                BlockEntityChangeKind::Deleted => {
+                    deleted.push(q::Value::String(diff.entity_id));
+                }
+            }
+        }
+        object_value(vec![
+            ("__typename", q::Value::String(String::from("EntityDiff"))),
+            ("created", q::Value::List(created)),
+            ("updated", q::Value::List(updated)),
+            ("deleted", q::Value::List(deleted)),
+        ])
+    }
+}
+
+impl From<FilesAvailability> for q::Value {
+    fn from(availability: FilesAvailability) -> Self {
+        object_value(vec![
+            (
+                "__typename",
+                q::Value::String(String::from("DeploymentFilesAvailability")),
+            ),
+            ("available", q::Value::Boolean(availability.available)),
+            (
+                "error",
+                availability.error.map_or(q::Value::Null, q::Value::String),
+            ),
+            (
+                "checkedAt",
+                availability
+                    .checked_at
+                    .map_or(q::Value::Null, |at| q::Value::String(at.to_string())),
+            ),
+            ("checking", q::Value::Boolean(availability.checking)),
+        ])
+    }
+}
+
+/// The changes to the entities of a deployment since some block, up to
+/// the deployment's latest block, which is `None` if the deployment has
+/// not processed any blocks yet.
+struct EntityChangesSince {
+    block: Option<EthereumBlockPointer>,
+    changes: Vec<BlockEntityChange>,
+}
+
+impl From<EntityChangesSince> for q::Value {
+    fn from(changes: EntityChangesSince) -> Self {
+        object_value(vec![
+            (
+                "__typename",
+                q::Value::String(String::from("EntityChangesSince")),
+            ),
+            (
+                "block",
+                changes
+                    .block
+                    .map_or(q::Value::Null, |block| q::Value::from(EthereumBlock(block))),
+            ),
+            (
+                "changes",
+                q::Value::List(
+                    changes
+                        .changes
+                        .into_iter()
+                        .map(|change| q::Value::from(EntityChangeInBlock(change)))
+                        .collect(),
+                ),
+            ),
+        ])
+    }
+}
+
+/// The most blocks a page of the `blockStream` can hold
+const BLOCK_STREAM_MAX_FIRST: u64 = 100;
+
+/// How many entities of one type a block created, updated and deleted
+#[derive(Default)]
+struct EntityChangeSummary {
+    entity_type: String,
+    created: i32,
+    updated: i32,
+    deleted: i32,
+}
+
+/// A position in the `blockStream`: the number of the last block a
+/// consumer has seen together with its hash. The hash is missing for the
+/// position before the first block, and for blocks that are no longer in
+/// the block cache, which are final
+#[derive(Clone, Debug, PartialEq)]
+struct BlockCursor {
+    number: BlockNumber,
+    hash: Option<H256>,
+}
+
+impl BlockCursor {
+    fn parse(cursor: &str) -> Result<Self, QueryExecutionError> {
+        let invalid = || {
+            QueryExecutionError::ValueParseError(
+                "after".to_owned(),
+                format!("invalid cursor `{}`", cursor),
+            )
+        };
+        let mut parts = cursor.splitn(2, ':');
+        let number = parts
+            .next()
+            .and_then(|number| number.parse::<BlockNumber>().ok())
+            .ok_or_else(invalid)?;
+        let hash = parts
+            .next()
+            .map(|hash| H256::from_str(hash.trim_start_matches("0x")).map_err(|_| invalid()))
+            .transpose()?;
+        Ok(BlockCursor { number, hash })
+    }
+}
+
+impl fmt::Display for BlockCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.hash {
+            Some(hash) => write!(f, "{}:{:x}", self.number, hash),
+            None => write!(f, "{}", self.number),
+        }
+    }
+}
+
+/// A block that changed entities of a deployment, summarized by entity
+/// type. The changes are ordered by entity type, and so are the summaries
+struct StreamedBlock {
+    cursor: BlockCursor,
+    changes: Vec<BlockEntityChange>,
+}
+
+impl From<StreamedBlock> for q::Value {
+    fn from(block: StreamedBlock) -> Self {
+        let mut summaries: Vec<EntityChangeSummary> = vec![];
+        for change in block.changes {
+            if summaries.last().map(|summary| &summary.entity_type) != Some(&change.entity_type) {
+                summaries.push(EntityChangeSummary {
+                    entity_type: change.entity_type.clone(),
+                    ..Default::default()
+                });
+            }
+            let summary = summaries.last_mut().unwrap();
+            match change.kind {
+                BlockEntityChangeKind::Created => summary.created += 1,
+                BlockEntityChangeKind::Updated => summary.updated += 1,
+                BlockEntityChangeKind::Deleted => summary.deleted += 1,
+            }
+        }
+
+        object_value(vec![
+            (
+                "__typename",
+                q::Value::String(String::from("StreamedBlock")),
+            ),
+            ("number", q::Value::String(block.cursor.number.to_string())),
+            ("cursor", q::Value::String(block.cursor.to_string())),
+            (
+                "changes",
+                q::Value::List(
+                    summaries
+                        .into_iter()
+                        .map(|summary| {
+                            object_value(vec![
+                                (
+                                    "__typename",
+                                    q::Value::String(String::from("EntityChangeSummary")),
+                                ),
+                                ("entityType", q::Value::String(summary.entity_type)),
+                                ("created", q::Value::Int(summary.created.into())),
+                                ("updated", q::Value::Int(summary.updated.into())),
+                                ("deleted", q::Value::Int(summary.deleted.into())),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+        ])
+    }
+}
+
+/// A page of the `blockStream`. Its cursor covers all blocks up to the
+/// last block of the page, or up to the deployment's `head` if the page
+/// is not full.
+struct BlockStreamPage {
+    head: Option<EthereumBlockPointer>,
+    blocks: Vec<StreamedBlock>,
+    cursor: BlockCursor,
+}
+
+impl From<BlockStreamPage> for q::Value {
+    fn from(page: BlockStreamPage) -> Self {
+        object_value(vec![
+            (
+                "__typename",
+                q::Value::String(String::from("BlockStreamPage")),
+            ),
+            (
+                "head",
+                page.head
+                    .map_or(q::Value::Null, |block| q::Value::from(EthereumBlock(block))),
+            ),
+            (
+                "blocks",
+                q::Value::List(page.blocks.into_iter().map(q::Value::from).collect()),
+            ),
+            ("cursor", q::Value::String(page.cursor.to_string())),
+        ])
+    }
+}
+
+/// A deployed version of a subgraph, as listed in the subgraph registry.
+struct SubgraphRegistryEntry {
+    /// The subgraph name.
+    name: String,
+    /// The ID of the deployment of this version.
+    deployment: String,
+    /// When the version was deployed.
+    created_at: BigInt,
+    /// Whether this is the current version of the subgraph.
+    current: bool,
+    /// Whether this is the pending version of the subgraph.
+    pending: bool,
+    /// The `description` from the subgraph manifest.
+    description: Option<String>,
+    /// The `repository` from the subgraph manifest.
+    repository: Option<String>,
+    /// The network the subgraph's first data source is on.
+    network: Option<String>,
+    /// Whether or not the deployment has synced all the way to the current chain head.
+    synced: bool,
+    /// Whether or not the deployment has failed syncing.
+    failed: bool,
+    /// ID of the Graph Node that the deployment is indexed by, if it is assigned.
+    node: Option<String>,
+}
+
+impl SubgraphRegistryEntry {
+    /// Parses the registry entries for all versions of a `Subgraph` object
+    /// value from the subgraph of subgraphs.
+    fn from_subgraph(
+        subgraph: &q::Value,
+        assignments: &[DeploymentAssignment],
+    ) -> Result<Vec<Self>, Error> {
+        let name: String = subgraph.get_required("name")?;
+        let version_id = |field: &str| -> Result<Option<String>, Error> {
+            subgraph
+                .get_optional::<q::Value>(field)?
+                .map(|version| version.get_required("id"))
+                .transpose()
+        };
+        let current_version = version_id("currentVersion")?;
+        let pending_version = version_id("pendingVersion")?;
+
+        subgraph
+            .get_required::<q::Value>("versions")?
+            .get_values::<q::Value>()?
+            .into_iter()
+            .map(|version| {
+                let id: String = version.get_required("id")?;
+                let deployment = version.get_required::<q::Value>("deployment")?;
+                let manifest = deployment.get_required::<q::Value>("manifest")?;
+                let deployment_id: String = deployment.get_required("id")?;
+                let network = manifest
+                    .get_required::<q::Value>("dataSources")?
+                    .get_values::<q::Value>()?
+                    .first()
+                    .map(|data_source| data_source.get_optional::<String>("network"))
+                    .transpose()?
+                    .and_then(|network| network);
+
+                Ok(SubgraphRegistryEntry {
+                    name: name.clone(),
+                    created_at: version.get_required("createdAt")?,
+                    current: current_version.as_ref() == Some(&id),
+                    pending: pending_version.as_ref() == Some(&id),
+                    description: manifest.get_optional("description")?,
+                    repository: manifest.get_optional("repository")?,
+                    network,
+                    synced: deployment.get_required("synced")?,
+                    failed: deployment.get_required("failed")?,
+                    node: assignments
+                        .iter()
+                        .find(|assignment| assignment.subgraph == deployment_id)
+                        .map(|assignment| assignment.node.clone()),
+                    deployment: deployment_id,
+                })
+            })
+            .collect()
+    }
+}
+
+impl From<SubgraphRegistryEntry> for q::Value {
+    fn from(entry: SubgraphRegistryEntry) -> Self {
+        object_value(vec![
+            (
+                "__typename",
+                q::Value::String(String::from("SubgraphRegistryEntry")),
+            ),
+            ("name", q::Value::String(entry.name)),
+            ("deployment", q::Value::String(entry.deployment)),
+            ("createdAt", q::Value::String(entry.created_at.to_string())),
+            ("current", q::Value::Boolean(entry.current)),
+            ("pending", q::Value::Boolean(entry.pending)),
+            (
+                "description",
+                entry.description.map_or(q::Value::Null, q::Value::String),
+            ),
+            (
+                "repository",
+                entry.repository.map_or(q::Value::Null, q::Value::String),
+            ),
+            (
+                "network",
+                entry.network.map_or(q::Value::Null, q::Value::String),
+            ),
+            ("synced", q::Value::Boolean(entry.synced)),
+            ("failed", q::Value::Boolean(entry.failed)),
+            ("node", entry.node.map_or(q::Value::Null, q::Value::String)),
+        ])
+    }
+}
+
+/// A handler that ran for a block, from the handler journal of a deployment.
+struct HandlerJournalEntry {
+    block: EthereumBlockPointer,
+    /// The position of the execution among those of the block.
+    index: u64,
+    data_source: String,
+    handler: String,
+    duration_ms: BigInt,
+    /// The error the handler failed with, if it failed.
+    error: Option<String>,
+}
+
+impl TryFromValue for HandlerJournalEntry {
+    fn try_from_value(value: &q::Value) -> Result<Self, Error> {
+        Ok(Self {
+            block: EthereumBlockPointer {
+                hash: value.get_required("blockHash")?,
+                number: value.get_required::<BigInt>("blockNumber")?.to_u64(),
+            },
+            index: value.get_required("index")?,
+            data_source: value.get_required("dataSource")?,
+            handler: value.get_required("handler")?,
+            duration_ms: value.get_required("durationMs")?,
+            error: value.get_optional("error")?,
+        })
+    }
+}
+
+impl From<HandlerJournalEntry> for q::Value {
+    fn from(execution: HandlerJournalEntry) -> Self {
+        let outcome = match execution.error {
+            Some(_) => "FAILED",
+            None => "SUCCEEDED",
+        };
+        object_value(vec![
+            (
+                "__typename",
+                q::Value::String(String::from("HandlerExecution")),
+            ),
+            ("block", q::Value::from(EthereumBlock(execution.block))),
+            ("dataSource", q::Value::String(execution.data_source)),
+            ("handler", q::Value::String(execution.handler)),
+            (
+                "durationMs",
+                q::Value::String(execution.duration_ms.to_string()),
+            ),
+            ("outcome", q::Value::Enum(String::from(outcome))),
+            (
+                "error",
+                execution.error.map_or(q::Value::Null, q::Value::String),
+            ),
+        ])
+    }
+}
+
+/// A chain reorganization that the block ingestor of a network observed.
+struct NetworkReorg {
+    id: String,
+    network: String,
+    depth: i32,
+    old_head: EthereumBlockPointer,
+    new_head: EthereumBlockPointer,
+    /// The block where the old and the new chain meet, if it is known.
+    common_ancestor: Option<EthereumBlockPointer>,
+    detected_at: BigInt,
+}
+
+impl TryFromValue for NetworkReorg {
+    fn try_from_value(value: &q::Value) -> Result<Self, Error> {
+        let common_ancestor = match (
+            value.get_optional("commonAncestorHash")?,
+            value.get_optional::<BigInt>("commonAncestorNumber")?,
+        ) {
+            (Some(hash), Some(number)) => Some(EthereumBlockPointer {
+                hash,
+                number: number.to_u64(),
+            }),
+            _ => None,
+        };
+        Ok(Self {
+            id: value.get_required("id")?,
+            network: value.get_required("network")?,
+            depth: value.get_required("depth")?,
+            old_head: EthereumBlockPointer {
+                hash: value.get_required("oldHeadHash")?,
+                number: value.get_required::<BigInt>("oldHeadNumber")?.to_u64(),
+            },
+            new_head: EthereumBlockPointer {
+                hash: value.get_required("newHeadHash")?,
+                number: value.get_required::<BigInt>("newHeadNumber")?.to_u64(),
+            },
+            common_ancestor,
+            detected_at: value.get_required("detectedAt")?,
+        })
+    }
+}
+
+impl From<NetworkReorg> for q::Value {
+    fn from(reorg: NetworkReorg) -> Self {
+        object_value(vec![
+            ("__typename", q::Value::String(String::from("Reorg"))),
+            ("id", q::Value::String(reorg.id)),
+            ("network", q::Value::String(reorg.network)),
+            ("depth", q::Value::Int(q::Number::from(reorg.depth))),
+            ("oldHead", q::Value::from(EthereumBlock(reorg.old_head))),
+            ("newHead", q::Value::from(EthereumBlock(reorg.new_head))),
+            (
+                "commonAncestor",
+                reorg
+                    .common_ancestor
+                    .map_or(q::Value::Null, |ptr| q::Value::from(EthereumBlock(ptr))),
+            ),
+            (
+                "detectedAt",
+                q::Value::String(reorg.detected_at.to_string()),
+            ),
+        ])
+    }
+}
+
+impl<R, S, L> IndexNodeResolver<R, S, L>
 where
     R: GraphQlRunner,
-    S: Store + SubgraphDeploymentStore,
+    S: Store + SubgraphDeploymentStore + ChainStore,
+    L: LinkResolver + Clone,
 {
-    pub fn new(logger: &Logger, graphql_runner: Arc<R>, store: Arc<S>) -> Self {
+    pub fn new(
+        logger: &Logger,
+        graphql_runner: Arc<R>,
+        store: Arc<S>,
+        deployment_files: Arc<DeploymentFiles<L>>,
+        networks: Arc<BTreeMap<String, EthereumAdapterCapabilities>>,
+        admin_access: Result<(), String>,
+    ) -> Self {
         let logger = logger.new(o!("component" => "IndexNodeResolver"));
         Self {
             logger,
             graphql_runner,
             store,
+            deployment_files,
+            networks,
+            admin_access,
         }
     }
 
+    /// Add how many seconds every deployment in `statuses` is behind the
+    /// chain head, as far as the chain store has the timestamps for that
+    fn add_seconds_behind(&self, statuses: IndexingStatuses) -> IndexingStatuses {
+        let timestamps = self
+            .store
+            .block_timestamps(statuses.block_hashes())
+            .unwrap_or_else(|e| {
+                warn!(
+                    self.logger,
+                    "Failed to load block timestamps";
+                    "error" => e.to_string()
+                );
+                HashMap::new()
+            });
+        statuses.with_seconds_behind(&timestamps)
+    }
+
     fn resolve_indexing_statuses(
         &self,
         arguments: &HashMap<&q::Name, q::Value>,
@@ -300,6 +1009,7 @@ where
                     ethereumHeadBlockHash
                     earliestEthereumBlockHash
                     earliestEthereumBlockNumber
+                    earliestRetainedBlockNumber
                     latestEthereumBlockHash
                     latestEthereumBlockNumber
                     manifest {
@@ -311,6 +1021,20 @@ where
                   subgraphDeploymentAssignments(where: $whereAssignments, first: 1000000) {
                     id
                     nodeId
+                    paused
+                  }
+                  subgraphDeploymentIdles(first: 1000000) {
+                    id
+                    hibernated
+                  }
+                  subgraphDeploymentIndexFailures(first: 1000000) {
+                    id
+                    error
+                  }
+                  subgraphDeploymentQuotas(first: 1000000) {
+                    id
+                    softLimitExceeded
+                    hardLimitExceeded
                   }
                 }
                 "#,
@@ -348,7 +1072,9 @@ where
             }
         };
 
-        Ok(IndexingStatuses::from(data).into())
+        IndexingStatuses::try_from(data)
+            .map(|statuses| self.add_seconds_behind(statuses).into())
+            .map_err(QueryExecutionError::StoreError)
     }
 
     fn resolve_indexing_statuses_for_subgraph_name(
@@ -393,6 +1119,7 @@ where
                         ethereumHeadBlockHash
                         earliestEthereumBlockHash
                         earliestEthereumBlockNumber
+                        earliestRetainedBlockNumber
                         latestEthereumBlockHash
                         latestEthereumBlockNumber
                         manifest {
@@ -406,6 +1133,20 @@ where
                   subgraphDeploymentAssignments(first: 1000000) {
                     id
                     nodeId
+                    paused
+                  }
+                  subgraphDeploymentIdles(first: 1000000) {
+                    id
+                    hibernated
+                  }
+                  subgraphDeploymentIndexFailures(first: 1000000) {
+                    id
+                    error
+                  }
+                  subgraphDeploymentQuotas(first: 1000000) {
+                    id
+                    softLimitExceeded
+                    hardLimitExceeded
                   }
                 }
                 "#,
@@ -477,42 +1218,761 @@ where
                 data.get_required::<q::Value>("subgraphDeploymentAssignments")
                     .expect("missing deployment assignments"),
             ),
+            (
+                "subgraphDeploymentIdles",
+                data.get_required::<q::Value>("subgraphDeploymentIdles")
+                    .expect("missing idle deployments"),
+            ),
+            (
+                "subgraphDeploymentIndexFailures",
+                data.get_required::<q::Value>("subgraphDeploymentIndexFailures")
+                    .expect("missing deployment index failures"),
+            ),
+            (
+                "subgraphDeploymentQuotas",
+                data.get_required::<q::Value>("subgraphDeploymentQuotas")
+                    .expect("missing deployment quotas"),
+            ),
         ]);
 
-        Ok(IndexingStatuses::from(transformed_data).into())
-    }
-}
-
-impl<R, S> Clone for IndexNodeResolver<R, S>
-where
-    R: GraphQlRunner,
-    S: Store + SubgraphDeploymentStore,
-{
-    fn clone(&self) -> Self {
-        Self {
-            logger: self.logger.clone(),
-            graphql_runner: self.graphql_runner.clone(),
-            store: self.store.clone(),
-        }
+        IndexingStatuses::try_from(transformed_data)
+            .map(|statuses| self.add_seconds_behind(statuses).into())
+            .map_err(QueryExecutionError::StoreError)
     }
-}
 
-impl<R, S> Resolver for IndexNodeResolver<R, S>
-where
-    R: GraphQlRunner,
-    S: Store + SubgraphDeploymentStore,
-{
-    fn prefetch<'r>(
+    fn resolve_subgraph_registry(
         &self,
-        _: &ExecutionContext<'r, Self>,
-        _: &q::SelectionSet,
-    ) -> Result<Option<q::Value>, Vec<QueryExecutionError>> {
-        Ok(None)
-    }
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        // Extract the optional "subgraphName" argument
+        let subgraph_name = arguments
+            .get_optional::<String>("subgraphName")
+            .expect("invalid subgraphName");
 
-    fn locate_block(&self, _: &BlockConstraint) -> Result<BlockNumber, QueryExecutionError> {
-        Ok(BLOCK_NUMBER_MAX)
-    }
+        // Build a `where` filter that the subgraphs have to match
+        let where_filter = object_value(match subgraph_name {
+            Some(ref name) => vec![("name", q::Value::String(name.clone()))],
+            None => vec![],
+        });
+
+        let query = Query {
+            // The query is against the subgraph of subgraphs
+            schema: self
+                .store
+                .api_schema(&SUBGRAPHS_ID)
+                .map_err(QueryExecutionError::StoreError)?,
+
+            document: q::parse_query(
+                r#"
+                query subgraphs($where: Subgraph_filter!) {
+                  subgraphs(where: $where, orderBy: name, first: 1000000) {
+                    name
+                    currentVersion {
+                      id
+                    }
+                    pendingVersion {
+                      id
+                    }
+                    versions(orderBy: createdAt, orderDirection: asc, first: 1000000) {
+                      id
+                      createdAt
+                      deployment {
+                        id
+                        synced
+                        failed
+                        manifest {
+                          description
+                          repository
+                          dataSources(first: 1) {
+                            network
+                          }
+                        }
+                      }
+                    }
+                  }
+                  subgraphDeploymentAssignments(first: 1000000) {
+                    id
+                    nodeId
+                  }
+                }
+                "#,
+            )
+            .unwrap(),
+
+            variables: Some(QueryVariables::new(HashMap::from_iter(
+                vec![("where".into(), where_filter)].into_iter(),
+            ))),
+        };
+
+        // Execute the query
+        let result = self
+            .graphql_runner
+            .run_query_with_complexity(query, None, None, Some(std::u32::MAX))
+            .wait()
+            .expect("error querying subgraphs");
+
+        let data = match result.data {
+            Some(data) => data,
+            None => {
+                error!(
+                    self.logger,
+                    "Failed to query subgraph registry";
+                    "subgraph" => format!("{:?}", subgraph_name),
+                    "errors" => format!("{:?}", result.errors)
+                );
+                return Ok(q::Value::List(vec![]));
+            }
+        };
+
+        // The subgraph of subgraphs might hold data we can not parse; that
+        // fails the query, not the node
+        let parse_error = |e: Error| QueryExecutionError::EntityParseError(e.to_string());
+
+        let assignments = data
+            .get_required::<q::Value>("subgraphDeploymentAssignments")
+            .and_then(|assignments| assignments.get_values::<DeploymentAssignment>())
+            .map_err(parse_error)?;
+
+        let subgraphs = data
+            .get_required::<q::Value>("subgraphs")
+            .and_then(|subgraphs| subgraphs.get_values::<q::Value>())
+            .map_err(parse_error)?;
+
+        let mut entries = vec![];
+        for subgraph in subgraphs.iter() {
+            entries.extend(
+                SubgraphRegistryEntry::from_subgraph(subgraph, &assignments)
+                    .map_err(parse_error)?
+                    .into_iter()
+                    .map(q::Value::from),
+            );
+        }
+        Ok(q::Value::List(entries))
+    }
+
+    fn resolve_admin_operations(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        // The audit log reveals who did what; only holders of an admin
+        // token may read it
+        self.admin_access
+            .clone()
+            .map_err(QueryExecutionError::Unauthorized)?;
+
+        let operation = arguments
+            .get_optional::<String>("operation")
+            .expect("invalid operation");
+        let first = arguments
+            .get_optional::<u64>("first")
+            .expect("invalid first")
+            .unwrap_or(100);
+        let skip = arguments
+            .get_optional::<u64>("skip")
+            .expect("invalid skip")
+            .unwrap_or(0);
+
+        // The audit log is not part of the public API of the subgraph of
+        // subgraphs, so read it from the store directly
+        let mut query = SubgraphAdminOperationEntity::query()
+            .order_by("createdAt", ValueType::BigInt, EntityOrder::Descending)
+            .first(first as u32)
+            .skip(skip as u32);
+        if let Some(operation) = operation {
+            query = query.filter(EntityFilter::Equal(
+                "operation".to_owned(),
+                Value::String(operation),
+            ));
+        }
+
+        let operations = self
+            .store
+            .find(query)?
+            .into_iter()
+            .map(|entity| {
+                let mut map: BTreeMap<String, q::Value> = entity
+                    .iter()
+                    .map(|(key, value)| (key.clone(), q::Value::from(value.clone())))
+                    .collect();
+                map.insert(
+                    "__typename".to_owned(),
+                    q::Value::String(String::from("AdminOperation")),
+                );
+                q::Value::Object(map)
+            })
+            .collect();
+        Ok(q::Value::List(operations))
+    }
+
+    fn resolve_handler_executions(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        let subgraph_id = arguments
+            .get_required::<String>("subgraphId")
+            .expect("subgraphId not provided");
+        let block_number = arguments
+            .get_optional::<u64>("blockNumber")
+            .expect("invalid blockNumber");
+
+        // Build a `where` filter that the journal entries have to match
+        let mut filter = vec![("deployment", q::Value::String(subgraph_id.clone()))];
+        if let Some(block_number) = block_number {
+            filter.push(("blockNumber", q::Value::String(block_number.to_string())));
+        }
+
+        let query = Query {
+            // The query is against the subgraph of subgraphs
+            schema: self
+                .store
+                .api_schema(&SUBGRAPHS_ID)
+                .map_err(QueryExecutionError::StoreError)?,
+
+            document: q::parse_query(
+                r#"
+                query handlerExecutions($where: SubgraphHandlerExecution_filter!) {
+                  subgraphHandlerExecutions(
+                    where: $where,
+                    orderBy: blockNumber,
+                    orderDirection: desc,
+                    first: 1000
+                  ) {
+                    blockNumber
+                    blockHash
+                    index
+                    dataSource
+                    handler
+                    durationMs
+                    error
+                  }
+                }
+                "#,
+            )
+            .unwrap(),
+
+            variables: Some(QueryVariables::new(HashMap::from_iter(
+                vec![("where".into(), object_value(filter))].into_iter(),
+            ))),
+        };
+
+        // Execute the query
+        let result = self
+            .graphql_runner
+            .run_query_with_complexity(query, None, None, Some(std::u32::MAX))
+            .wait()
+            .expect("error querying handler executions");
+
+        let data = match result.data {
+            Some(data) => data,
+            None => {
+                error!(
+                    self.logger,
+                    "Failed to query handler executions";
+                    "subgraph" => &subgraph_id,
+                    "errors" => format!("{:?}", result.errors)
+                );
+                return Ok(q::Value::List(vec![]));
+            }
+        };
+
+        let mut executions = data
+            .get_required::<q::Value>("subgraphHandlerExecutions")
+            .expect("no handler executions in the result")
+            .get_values::<HandlerJournalEntry>()
+            .expect("failed to parse handler executions");
+
+        // Most recent blocks first, and the handlers of a block in the
+        // order in which they ran
+        executions.sort_by(|a, b| {
+            b.block
+                .number
+                .cmp(&a.block.number)
+                .then_with(|| a.block.hash.cmp(&b.block.hash))
+                .then_with(|| a.index.cmp(&b.index))
+        });
+        Ok(q::Value::List(
+            executions.into_iter().map(q::Value::from).collect(),
+        ))
+    }
+
+    fn resolve_reorgs(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        let network = arguments
+            .get_required::<String>("network")
+            .expect("network not provided");
+        let first = arguments
+            .get_optional::<u64>("first")
+            .expect("invalid first")
+            .unwrap_or(100);
+
+        let query = Query {
+            // The query is against the subgraph of subgraphs
+            schema: self
+                .store
+                .api_schema(&SUBGRAPHS_ID)
+                .map_err(QueryExecutionError::StoreError)?,
+
+            document: q::parse_query(
+                r#"
+                query reorgs($where: NetworkReorg_filter!, $first: Int!) {
+                  networkReorgs(
+                    where: $where,
+                    orderBy: detectedAt,
+                    orderDirection: desc,
+                    first: $first
+                  ) {
+                    id
+                    network
+                    depth
+                    oldHeadNumber
+                    oldHeadHash
+                    newHeadNumber
+                    newHeadHash
+                    commonAncestorNumber
+                    commonAncestorHash
+                    detectedAt
+                  }
+                }
+                "#,
+            )
+            .unwrap(),
+
+            variables: Some(QueryVariables::new(HashMap::from_iter(
+                vec![
+                    (
+                        "where".into(),
+                        object_value(vec![("network", q::Value::String(network.clone()))]),
+                    ),
+                    ("first".into(), q::Value::Int(q::Number::from(first as i32))),
+                ]
+                .into_iter(),
+            ))),
+        };
+
+        // Execute the query
+        let result = self
+            .graphql_runner
+            .run_query_with_complexity(query, None, None, Some(std::u32::MAX))
+            .wait()
+            .expect("error querying reorgs");
+
+        let data = match result.data {
+            Some(data) => data,
+            None => {
+                error!(
+                    self.logger,
+                    "Failed to query reorgs";
+                    "network" => &network,
+                    "errors" => format!("{:?}", result.errors)
+                );
+                return Ok(q::Value::List(vec![]));
+            }
+        };
+
+        let reorgs = data
+            .get_required::<q::Value>("networkReorgs")
+            .expect("no reorgs in the result")
+            .get_values::<NetworkReorg>()
+            .expect("failed to parse reorgs");
+        Ok(q::Value::List(
+            reorgs.into_iter().map(q::Value::from).collect(),
+        ))
+    }
+
+    fn resolve_entity_changes_in_block(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        // Both arguments are non-null and have already been validated
+        let subgraph_id = arguments
+            .get_required::<String>("subgraphId")
+            .expect("subgraphId not provided");
+        let block_number = arguments
+            .get_required::<u64>("blockNumber")
+            .expect("blockNumber not provided");
+
+        let subgraph_id = SubgraphDeploymentId::new(subgraph_id.clone())
+            .map_err(|()| QueryExecutionError::SubgraphDeploymentIdError(subgraph_id))?;
+        let block = BlockNumber::try_from(block_number).map_err(|_| {
+            QueryExecutionError::ValueParseError(
+                "blockNumber".to_owned(),
+                format!("block number {} is out of range", block_number),
+            )
+        })?;
+
+        debug!(
+            self.logger,
+            "Resolve entity changes in block";
+            "subgraph" => subgraph_id.to_string(),
+            "block" => block
+        );
+
+        let changes = self.store.entity_changes_in_block(&subgraph_id, block)?;
+        Ok(q::Value::List(
+            changes
+                .into_iter()
+                .map(|change| q::Value::from(EntityChangeInBlock(change)))
+                .collect(),
+        ))
+    }
+
+    fn resolve_entity_changes_since(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        // Both arguments are non-null and have already been validated
+        let subgraph_id = arguments
+            .get_required::<String>("subgraphId")
+            .expect("subgraphId not provided");
+        let since_block = arguments
+            .get_required::<u64>("sinceBlock")
+            .expect("sinceBlock not provided");
+
+        let subgraph_id = SubgraphDeploymentId::new(subgraph_id.clone())
+            .map_err(|()| QueryExecutionError::SubgraphDeploymentIdError(subgraph_id))?;
+        let since = BlockNumber::try_from(since_block).map_err(|_| {
+            QueryExecutionError::ValueParseError(
+                "sinceBlock".to_owned(),
+                format!("block number {} is out of range", since_block),
+            )
+        })?;
+
+        debug!(
+            self.logger,
+            "Resolve entity changes since block";
+            "subgraph" => subgraph_id.to_string(),
+            "since" => since
+        );
+
+        // Changes are collected up to the block the deployment has
+        // processed when we start, so that they match a block pointer even
+        // if the deployment keeps indexing in the meantime
+        let block = match self
+            .store
+            .block_ptr(subgraph_id.clone())
+            .map_err(QueryExecutionError::StoreError)?
+        {
+            Some(block) => block,
+            None => {
+                return Ok(q::Value::from(EntityChangesSince {
+                    block: None,
+                    changes: vec![],
+                }))
+            }
+        };
+        if since_block > block.number {
+            // The snapshot contains blocks that the deployment no longer
+            // has, e.g., because they were reverted
+            return Err(QueryExecutionError::ValueParseError(
+                "sinceBlock".to_owned(),
+                format!(
+                    "block {} is after the latest block {} of the subgraph",
+                    since_block, block.number
+                ),
+            ));
+        }
+
+        let to = block.number as BlockNumber;
+        let changes = self.store.entity_changes_since(&subgraph_id, since, to)?;
+        Ok(q::Value::from(EntityChangesSince {
+            block: Some(block),
+            changes,
+        }))
+    }
+
+    fn resolve_block_stream(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        // The deployment id is non-null and has already been validated
+        let subgraph_id = arguments
+            .get_required::<String>("subgraphId")
+            .expect("subgraphId not provided");
+        let after = arguments
+            .get_optional::<String>("after")
+            .expect("invalid after");
+        let first = arguments
+            .get_optional::<u64>("first")
+            .expect("invalid first")
+            .unwrap_or(10);
+
+        let subgraph_id = SubgraphDeploymentId::new(subgraph_id.clone())
+            .map_err(|()| QueryExecutionError::SubgraphDeploymentIdError(subgraph_id))?;
+        if first == 0 || first > BLOCK_STREAM_MAX_FIRST {
+            return Err(QueryExecutionError::ValueParseError(
+                "first".to_owned(),
+                format!(
+                    "must be between 1 and {}, but is {}",
+                    BLOCK_STREAM_MAX_FIRST, first
+                ),
+            ));
+        }
+        // Without a cursor, the stream starts with the first block
+        let after = match after {
+            Some(cursor) => BlockCursor::parse(&cursor)?,
+            None => BlockCursor {
+                number: -1,
+                hash: None,
+            },
+        };
+
+        let head = self
+            .store
+            .block_ptr(subgraph_id.clone())
+            .map_err(QueryExecutionError::StoreError)?;
+        let head_number = head.as_ref().map_or(-1, |head| head.number as BlockNumber);
+        // The consumer saw blocks that the deployment no longer has
+        // because they were reverted, and needs to rewind
+        let reverted = |reason: String| {
+            QueryExecutionError::ValueParseError(
+                "after".to_owned(),
+                format!("the cursor `{}` {}", after, reason),
+            )
+        };
+        if after.number > head_number {
+            return Err(reverted(format!(
+                "is after the latest block {} of the subgraph",
+                head_number
+            )));
+        }
+        if let (Some(head), Some(hash)) = (&head, &after.hash) {
+            match self.block_hash_at(head, after.number)? {
+                Some(current) if &current != hash => {
+                    return Err(reverted(format!(
+                        "is on a chain the subgraph no longer follows; \
+                         its block {} is now {:x}",
+                        after.number, current
+                    )))
+                }
+                _ => (),
+            }
+        }
+        if after.number == head_number {
+            // Consumers that follow the deployment poll at its latest
+            // block; there can not be anything new for them
+            return Ok(q::Value::from(BlockStreamPage {
+                head,
+                blocks: vec![],
+                cursor: after,
+            }));
+        }
+
+        debug!(
+            self.logger,
+            "Resolve block stream";
+            "subgraph" => subgraph_id.to_string(),
+            "after" => after.to_string(),
+            "head" => head_number
+        );
+
+        // The head exists since it is after the cursor
+        let head_ptr = head.as_ref().unwrap();
+        let cursor_at = |number: BlockNumber| -> Result<_, QueryExecutionError> {
+            Ok(BlockCursor {
+                number,
+                hash: self.block_hash_at(head_ptr, number)?,
+            })
+        };
+        let numbers = self.store.blocks_with_entity_changes(
+            &subgraph_id,
+            after.number,
+            head_number,
+            first as usize,
+        )?;
+        let blocks = numbers
+            .iter()
+            .map(|number| {
+                Ok(StreamedBlock {
+                    cursor: cursor_at(*number)?,
+                    changes: self.store.entity_changes_in_block(&subgraph_id, *number)?,
+                })
+            })
+            .collect::<Result<Vec<_>, QueryExecutionError>>()?;
+        let cursor = match blocks.last() {
+            Some(block) if blocks.len() as u64 == first => block.cursor.clone(),
+            _ => cursor_at(head_number)?,
+        };
+
+        Ok(q::Value::from(BlockStreamPage {
+            head,
+            blocks,
+            cursor,
+        }))
+    }
+
+    /// The hash of the block with number `number` on the chain that ends
+    /// in `head`, if the block cache still has it
+    fn block_hash_at(
+        &self,
+        head: &EthereumBlockPointer,
+        number: BlockNumber,
+    ) -> Result<Option<H256>, QueryExecutionError> {
+        let offset = head.number - number as u64;
+        if offset == 0 {
+            return Ok(Some(head.hash));
+        }
+        Ok(self
+            .store
+            .ancestor_block(head.clone(), offset)
+            .map_err(|e| QueryExecutionError::StoreError(e.into()))?
+            .and_then(|block| block.block.hash))
+    }
+
+    fn resolve_deployment_files_available(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        // Every check makes the node fetch files from IPFS; only holders of
+        // an admin token may trigger that
+        self.admin_access
+            .clone()
+            .map_err(QueryExecutionError::Unauthorized)?;
+
+        // The argument is non-null and has already been validated
+        let subgraph_id = arguments
+            .get_required::<String>("subgraphId")
+            .expect("subgraphId not provided");
+        let subgraph_id = SubgraphDeploymentId::new(subgraph_id.clone())
+            .map_err(|()| QueryExecutionError::SubgraphDeploymentIdError(subgraph_id))?;
+
+        debug!(
+            self.logger,
+            "Resolve deployment files availability";
+            "subgraph" => subgraph_id.to_string()
+        );
+
+        Ok(q::Value::from(self.deployment_files.check(&subgraph_id)))
+    }
+
+    fn resolve_proof_of_indexing(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        // Both arguments are non-null and have already been validated
+        let subgraph_id = arguments
+            .get_required::<String>("subgraphId")
+            .expect("subgraphId not provided");
+        let block_number = arguments
+            .get_required::<u64>("blockNumber")
+            .expect("blockNumber not provided");
+
+        let subgraph_id = SubgraphDeploymentId::new(subgraph_id.clone())
+            .map_err(|()| QueryExecutionError::SubgraphDeploymentIdError(subgraph_id))?;
+        let block = BlockNumber::try_from(block_number).map_err(|_| {
+            QueryExecutionError::ValueParseError(
+                "blockNumber".to_owned(),
+                format!("block number {} is out of range", block_number),
+            )
+        })?;
+
+        debug!(
+            self.logger,
+            "Resolve proof of indexing";
+            "subgraph" => subgraph_id.to_string(),
+            "block" => block
+        );
+
+        Ok(self
+            .store
+            .proof_of_indexing(&subgraph_id, block)?
+            .map(|proof| {
+                object_value(vec![
+                    (
+                        "__typename",
+                        q::Value::String(String::from("ProofOfIndexing")),
+                    ),
+                    ("block", q::Value::from(EthereumBlock(proof.block))),
+                    ("digest", q::Value::String(format!("{:x}", proof.digest))),
+                ])
+            })
+            .unwrap_or(q::Value::Null))
+    }
+
+    fn resolve_node_capabilities(&self) -> Result<q::Value, QueryExecutionError> {
+        Ok(node_capabilities(&self.networks))
+    }
+
+    fn resolve_entity_diff(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        // All arguments are non-null and have already been validated
+        let subgraph_id = arguments
+            .get_required::<String>("subgraphId")
+            .expect("subgraphId not provided");
+        let entity_type = arguments
+            .get_required::<String>("entityType")
+            .expect("entityType not provided");
+        let block_number = |name: &str| {
+            let number = arguments
+                .get_required::<u64>(name)
+                .expect("block number not provided");
+            BlockNumber::try_from(number).map_err(|_| {
+                QueryExecutionError::ValueParseError(
+                    name.to_owned(),
+                    format!("block number {} is out of range", number),
+                )
+            })
+        };
+        let from = block_number("fromBlock")?;
+        let to = block_number("toBlock")?;
+
+        let subgraph_id = SubgraphDeploymentId::new(subgraph_id.clone())
+            .map_err(|()| QueryExecutionError::SubgraphDeploymentIdError(subgraph_id))?;
+
+        debug!(
+            self.logger,
+            "Resolve entity diff";
+            "subgraph" => subgraph_id.to_string(),
+            "entity_type" => &entity_type,
+            "from" => from,
+            "to" => to
+        );
+
+        let diffs = self
+            .store
+            .entity_diff(&subgraph_id, &entity_type, from, to)?;
+        Ok(q::Value::from(EntityDiffs(diffs)))
+    }
+}
+
+impl<R, S, L> Clone for IndexNodeResolver<R, S, L>
+where
+    R: GraphQlRunner,
+    S: Store + SubgraphDeploymentStore + ChainStore,
+    L: LinkResolver + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            logger: self.logger.clone(),
+            graphql_runner: self.graphql_runner.clone(),
+            store: self.store.clone(),
+            deployment_files: self.deployment_files.clone(),
+            networks: self.networks.clone(),
+            admin_access: self.admin_access.clone(),
+        }
+    }
+}
+
+impl<R, S, L> Resolver for IndexNodeResolver<R, S, L>
+where
+    R: GraphQlRunner,
+    S: Store + SubgraphDeploymentStore + ChainStore,
+    L: LinkResolver + Clone,
+{
+    fn prefetch<'r>(
+        &self,
+        _: &ExecutionContext<'r, Self>,
+        _: &q::SelectionSet,
+    ) -> Result<Option<q::Value>, Vec<QueryExecutionError>> {
+        Ok(None)
+    }
+
+    fn locate_block(&self, _: &BlockConstraint) -> Result<BlockNumber, QueryExecutionError> {
+        Ok(BLOCK_NUMBER_MAX)
+    }
 
     fn resolve_objects(
         &self,
@@ -545,6 +2005,72 @@ where
                 self.resolve_indexing_statuses_for_subgraph_name(arguments)
             }
 
+            // The top-level `entityChangesInBlock` field
+            (None, "EntityChange", "entityChangesInBlock") => {
+                self.resolve_entity_changes_in_block(arguments)
+            }
+
+            // The `changes` field of `EntityChangesSince` values
+            (Some(changes), "EntityChange", "changes") => match changes {
+                q::Value::Object(map) => Ok(map
+                    .get("changes")
+                    .expect("entity changes without `changes`")
+                    .clone()),
+                _ => unreachable!(),
+            },
+
+            // The `updated` field of `EntityDiff` values
+            (Some(diff), "EntityUpdate", "updated") => match diff {
+                q::Value::Object(map) => Ok(map
+                    .get("updated")
+                    .expect("entity diff without `updated`")
+                    .clone()),
+                _ => unreachable!(),
+            },
+
+            // The `blocks` field of `BlockStreamPage` values
+            (Some(page), "StreamedBlock", "blocks") => match page {
+                q::Value::Object(map) => Ok(map
+                    .get("blocks")
+                    .expect("block stream page without `blocks`")
+                    .clone()),
+                _ => unreachable!(),
+            },
+
+            // The `changes` field of `StreamedBlock` values
+            (Some(block), "EntityChangeSummary", "changes") => match block {
+                q::Value::Object(map) => Ok(map
+                    .get("changes")
+                    .expect("streamed block without `changes`")
+                    .clone()),
+                _ => unreachable!(),
+            },
+
+            // The top-level `subgraphRegistry` field
+            (None, "SubgraphRegistryEntry", "subgraphRegistry") => {
+                self.resolve_subgraph_registry(arguments)
+            }
+
+            // The top-level `adminOperations` field
+            (None, "AdminOperation", "adminOperations") => self.resolve_admin_operations(arguments),
+
+            // The top-level `handlerExecutions` field
+            (None, "HandlerExecution", "handlerExecutions") => {
+                self.resolve_handler_executions(arguments)
+            }
+
+            // The top-level `reorgs` field
+            (None, "Reorg", "reorgs") => self.resolve_reorgs(arguments),
+
+            // The `networks` field of `NodeCapabilities` values
+            (Some(capabilities), "NetworkCapabilities", "networks") => match capabilities {
+                q::Value::Object(map) => Ok(map
+                    .get("networks")
+                    .expect("node capabilities without `networks`")
+                    .clone()),
+                _ => unreachable!(),
+            },
+
             // Unknown fields on the `Query` type
             (None, _, name) => Err(QueryExecutionError::UnknownField(
                 field_definition.position.clone(),
@@ -567,11 +2093,35 @@ where
         field: &q::Field,
         field_definition: &s::Field,
         object_type: ObjectOrInterface<'_>,
-        _arguments: &HashMap<&q::Name, q::Value>,
+        arguments: &HashMap<&q::Name, q::Value>,
         _types_for_interface: &BTreeMap<Name, Vec<ObjectType>>,
         _block: BlockNumber,
     ) -> Result<q::Value, QueryExecutionError> {
         match (parent, object_type.name(), field.name.as_str()) {
+            // The top-level `entityChangesSince` field
+            (None, "EntityChangesSince", "entityChangesSince") => {
+                self.resolve_entity_changes_since(arguments)
+            }
+
+            // The top-level `entityDiff` field
+            (None, "EntityDiff", "entityDiff") => self.resolve_entity_diff(arguments),
+
+            // The top-level `blockStream` field
+            (None, "BlockStreamPage", "blockStream") => self.resolve_block_stream(arguments),
+
+            // The top-level `deploymentFilesAvailable` field
+            (None, "DeploymentFilesAvailability", "deploymentFilesAvailable") => {
+                self.resolve_deployment_files_available(arguments)
+            }
+
+            // The top-level `nodeCapabilities` field
+            (None, "NodeCapabilities", "nodeCapabilities") => self.resolve_node_capabilities(),
+
+            // The top-level `proofOfIndexing` field
+            (None, "ProofOfIndexing", "proofOfIndexing") => {
+                self.resolve_proof_of_indexing(arguments)
+            }
+
             (Some(status), "EthereumBlock", "chainHeadBlock") => Ok(status
                 .get_optional("chainHeadBlock")
                 .map_err(|e| QueryExecutionError::StoreError(e))?
@@ -585,6 +2135,19 @@ where
                 .map_err(|e| QueryExecutionError::StoreError(e))?
                 .unwrap_or(q::Value::Null)),
 
+            // The `head` field of `BlockStreamPage` values
+            (Some(page), "EthereumBlock", "head") => Ok(page
+                .get_optional("head")
+                .map_err(|e| QueryExecutionError::StoreError(e))?
+                .unwrap_or(q::Value::Null)),
+
+            // The `block` field of `EntityChangesSince` and `HandlerExecution`
+            // values
+            (Some(parent), "EthereumBlock", "block") => Ok(parent
+                .get_optional("block")
+                .map_err(|e| QueryExecutionError::StoreError(e))?
+                .unwrap_or(q::Value::Null)),
+
             // Unknown fields on other types
             (_, type_name, name) => Err(QueryExecutionError::UnknownField(
                 field_definition.position.clone(),
@@ -594,3 +2157,759 @@ where
         }
     }
 }
+
+/// The manifest spec versions, mapping API versions and features this node
+/// supports, and what the Ethereum nodes behind `networks` support
+fn node_capabilities(networks: &BTreeMap<String, EthereumAdapterCapabilities>) -> q::Value {
+    let strings = |values: &[&str]| {
+        q::Value::List(
+            values
+                .iter()
+                .map(|value| q::Value::String(value.to_string()))
+                .collect(),
+        )
+    };
+    let networks = networks
+        .iter()
+        .map(|(network, capabilities)| {
+            object_value(vec![
+                ("network", q::Value::String(network.clone())),
+                ("client", q::Value::String(capabilities.client.clone())),
+                ("traces", q::Value::Boolean(capabilities.traces)),
+                (
+                    "decodedBlocks",
+                    q::Value::Boolean(capabilities.decoded_blocks),
+                ),
+            ])
+        })
+        .collect();
+
+    object_value(vec![
+        ("specVersions", strings(SPEC_VERSIONS)),
+        ("apiVersions", strings(API_VERSIONS)),
+        ("features", strings(FEATURES)),
+        ("networks", q::Value::List(networks)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph_graphql::prelude::{execute_query, QueryExecutionOptions};
+    use graph_mock::{MockMetricsRegistry, MockStore};
+
+    use crate::schema::SCHEMA;
+
+    struct TestGraphQlRunner;
+
+    impl GraphQlRunner for TestGraphQlRunner {
+        fn run_query(&self, _query: Query) -> QueryResultFuture {
+            unimplemented!();
+        }
+
+        fn run_query_with_complexity(
+            &self,
+            _query: Query,
+            _complexity: Option<u64>,
+            _max_depth: Option<u8>,
+            _max_first: Option<u32>,
+        ) -> QueryResultFuture {
+            unimplemented!();
+        }
+
+        fn check_query(&self, _query: &Query) -> Result<(), Vec<QueryExecutionError>> {
+            unimplemented!();
+        }
+
+        fn run_subscription(&self, _subscription: Subscription) -> SubscriptionResultFuture {
+            unimplemented!();
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestLinkResolver;
+
+    impl LinkResolver for TestLinkResolver {
+        fn with_timeout(self, _timeout: std::time::Duration) -> Self {
+            self
+        }
+
+        fn with_retries(self) -> Self {
+            self
+        }
+
+        fn with_retry_policy(self, _policy: LinkResolverRetryPolicy) -> Self {
+            self
+        }
+
+        fn with_priority(self, _priority: LinkResolverPriority) -> Self {
+            self
+        }
+
+        fn for_deployment(self, _deployment: SubgraphDeploymentId) -> Self {
+            self
+        }
+
+        fn cat(
+            &self,
+            _logger: &Logger,
+            _link: &Link,
+        ) -> Box<dyn Future<Item = Vec<u8>, Error = failure::Error> + Send> {
+            unimplemented!();
+        }
+
+        fn json_stream(
+            &self,
+            _link: &Link,
+        ) -> Box<dyn Future<Item = JsonValueStream, Error = failure::Error> + Send + 'static>
+        {
+            unimplemented!();
+        }
+    }
+
+    /// A GraphQL runner that answers every query with the same data
+    struct StaticGraphQlRunner(q::Value);
+
+    impl GraphQlRunner for StaticGraphQlRunner {
+        fn run_query(&self, _query: Query) -> QueryResultFuture {
+            Box::new(future::ok(QueryResult::new(Some(self.0.clone()))))
+        }
+
+        fn run_query_with_complexity(
+            &self,
+            query: Query,
+            _complexity: Option<u64>,
+            _max_depth: Option<u8>,
+            _max_first: Option<u32>,
+        ) -> QueryResultFuture {
+            self.run_query(query)
+        }
+
+        fn check_query(&self, _query: &Query) -> Result<(), Vec<QueryExecutionError>> {
+            Ok(())
+        }
+
+        fn run_subscription(&self, _subscription: Subscription) -> SubscriptionResultFuture {
+            unimplemented!();
+        }
+    }
+
+    /// Run `query` against the index node API with the data in `store`
+    fn run_query(store: MockStore, query: &str) -> QueryResult {
+        run_query_with_runner(store, TestGraphQlRunner, query)
+    }
+
+    /// Run `query` against the index node API with the data in `store`,
+    /// answering the queries the resolver makes with `graphql_runner`
+    fn run_query_with_runner<R: GraphQlRunner>(
+        store: MockStore,
+        graphql_runner: R,
+        query: &str,
+    ) -> QueryResult {
+        let logger = Logger::root(slog::Discard, o!());
+        let deployment_files = Arc::new(DeploymentFiles::new(
+            &logger,
+            Arc::new(TestLinkResolver),
+            Arc::new(MockMetricsRegistry::new()),
+        ));
+        let query = Query {
+            schema: SCHEMA.clone(),
+            document: graphql_parser::parse_query(query).unwrap(),
+            variables: None,
+        };
+        execute_query(
+            query,
+            QueryExecutionOptions {
+                logger: logger.clone(),
+                resolver: IndexNodeResolver::new(
+                    &logger,
+                    Arc::new(graphql_runner),
+                    Arc::new(store),
+                    deployment_files,
+                    Arc::new(BTreeMap::new()),
+                    Ok(()),
+                ),
+                deadline: None,
+                max_complexity: None,
+                max_depth: 100,
+                max_first: std::u32::MAX,
+                block: BLOCK_NUMBER_MAX,
+            },
+        )
+    }
+
+    /// The value at `path` in the data of `result`, which must not have
+    /// errors
+    fn result_value(result: QueryResult, path: &[&str]) -> q::Value {
+        assert!(result.errors.is_none(), "{:?}", result.errors);
+        let mut value = result.data.expect("query without data");
+        for name in path {
+            value = match value {
+                q::Value::Object(mut map) => map
+                    .remove(*name)
+                    .unwrap_or_else(|| panic!("no field `{}`", name)),
+                _ => panic!("`{}` is not a field of an object", name),
+            };
+        }
+        value
+    }
+
+    /// A store that only knows the API schema of the subgraph of subgraphs;
+    /// the registry queries it through the GraphQL runner
+    fn registry_store() -> MockStore {
+        let mut store = MockStore::new();
+        store.expect_api_schema().returning(|_| {
+            Ok(Arc::new(
+                Schema::parse("type Subgraph @entity { id: ID! }", SUBGRAPHS_ID.clone()).unwrap(),
+            ))
+        });
+        store
+    }
+
+    /// A version of a subgraph in the subgraph of subgraphs
+    fn registry_version(id: &str, deployment: &str, created_at: &str) -> q::Value {
+        object_value(vec![
+            ("id", q::Value::String(id.to_owned())),
+            ("createdAt", q::Value::String(created_at.to_owned())),
+            (
+                "deployment",
+                object_value(vec![
+                    ("id", q::Value::String(deployment.to_owned())),
+                    ("synced", q::Value::Boolean(true)),
+                    ("failed", q::Value::Boolean(false)),
+                    (
+                        "manifest",
+                        object_value(vec![
+                            ("description", q::Value::String("A subgraph".to_owned())),
+                            ("repository", q::Value::Null),
+                            (
+                                "dataSources",
+                                q::Value::List(vec![object_value(vec![(
+                                    "network",
+                                    q::Value::String("mainnet".to_owned()),
+                                )])]),
+                            ),
+                        ]),
+                    ),
+                ]),
+            ),
+        ])
+    }
+
+    const REGISTRY_QUERY: &str = "{ subgraphRegistry { \
+        name deployment createdAt current pending description repository \
+        network synced failed node } }";
+
+    #[test]
+    fn subgraph_registry_lists_every_version() {
+        let data = object_value(vec![
+            (
+                "subgraphs",
+                q::Value::List(vec![object_value(vec![
+                    ("name", q::Value::String("team/tokens".to_owned())),
+                    (
+                        "currentVersion",
+                        object_value(vec![("id", q::Value::String("v1".to_owned()))]),
+                    ),
+                    (
+                        "pendingVersion",
+                        object_value(vec![("id", q::Value::String("v2".to_owned()))]),
+                    ),
+                    (
+                        "versions",
+                        q::Value::List(vec![
+                            registry_version("v1", "QmOld", "100"),
+                            registry_version("v2", "QmNew", "200"),
+                        ]),
+                    ),
+                ])]),
+            ),
+            (
+                "subgraphDeploymentAssignments",
+                q::Value::List(vec![object_value(vec![
+                    ("id", q::Value::String("QmNew".to_owned())),
+                    ("nodeId", q::Value::String("index_node_0".to_owned())),
+                ])]),
+            ),
+        ]);
+
+        let result =
+            run_query_with_runner(registry_store(), StaticGraphQlRunner(data), REGISTRY_QUERY);
+        let entry = |deployment: &str, created_at: &str, current, node: Option<&str>| {
+            object_value(vec![
+                ("name", q::Value::String("team/tokens".to_owned())),
+                ("deployment", q::Value::String(deployment.to_owned())),
+                ("createdAt", q::Value::String(created_at.to_owned())),
+                ("current", q::Value::Boolean(current)),
+                ("pending", q::Value::Boolean(!current)),
+                ("description", q::Value::String("A subgraph".to_owned())),
+                ("repository", q::Value::Null),
+                ("network", q::Value::String("mainnet".to_owned())),
+                ("synced", q::Value::Boolean(true)),
+                ("failed", q::Value::Boolean(false)),
+                (
+                    "node",
+                    node.map_or(q::Value::Null, |node| q::Value::String(node.to_owned())),
+                ),
+            ])
+        };
+        assert_eq!(
+            q::Value::List(vec![
+                entry("QmOld", "100", true, None),
+                entry("QmNew", "200", false, Some("index_node_0")),
+            ]),
+            result_value(result, &["subgraphRegistry"])
+        );
+    }
+
+    #[test]
+    fn malformed_subgraph_registry_data_fails_the_query() {
+        let assignments = || ("subgraphDeploymentAssignments", q::Value::List(vec![]));
+        let subgraph = |versions: Vec<q::Value>| {
+            object_value(vec![
+                ("name", q::Value::String("team/tokens".to_owned())),
+                ("currentVersion", q::Value::Null),
+                ("pendingVersion", q::Value::Null),
+                ("versions", q::Value::List(versions)),
+            ])
+        };
+
+        let malformed = vec![
+            // No subgraphs at all
+            object_value(vec![assignments()]),
+            // Assignments without a node
+            object_value(vec![
+                ("subgraphs", q::Value::List(vec![])),
+                (
+                    "subgraphDeploymentAssignments",
+                    q::Value::List(vec![object_value(vec![(
+                        "id",
+                        q::Value::String("QmNew".to_owned()),
+                    )])]),
+                ),
+            ]),
+            // A creation time that is not a number
+            object_value(vec![
+                (
+                    "subgraphs",
+                    q::Value::List(vec![subgraph(vec![registry_version(
+                        "v1",
+                        "QmOld",
+                        "yesterday",
+                    )])]),
+                ),
+                assignments(),
+            ]),
+            // A version without a deployment
+            object_value(vec![
+                (
+                    "subgraphs",
+                    q::Value::List(vec![subgraph(vec![object_value(vec![
+                        ("id", q::Value::String("v1".to_owned())),
+                        ("createdAt", q::Value::String("100".to_owned())),
+                    ])])]),
+                ),
+                assignments(),
+            ]),
+        ];
+
+        for data in malformed {
+            let result = run_query_with_runner(
+                registry_store(),
+                StaticGraphQlRunner(data.clone()),
+                REGISTRY_QUERY,
+            );
+            assert!(result.errors.is_some(), "{:?} is malformed", data);
+        }
+    }
+
+    #[test]
+    fn entity_changes_in_block() {
+        let id = SubgraphDeploymentId::new("QmChanges").unwrap();
+
+        let mut store = MockStore::new();
+        store
+            .expect_entity_changes_in_block()
+            .withf(|subgraph_id, block| subgraph_id.to_string() == "QmChanges" && *block == 7)
+            .returning(|_, _| {
+                Ok(vec![
+                    BlockEntityChange {
+                        entity_type: "User".to_owned(),
+                        entity_id: "1".to_owned(),
+                        kind: BlockEntityChangeKind::Created,
+                        data: Entity::from(vec![("id", Value::from("1"))]),
+                    },
+                    BlockEntityChange {
+                        entity_type: "User".to_owned(),
+                        entity_id: "2".to_owned(),
+                        kind: BlockEntityChangeKind::Deleted,
+                        data: Entity::from(vec![("id", Value::from("2"))]),
+                    },
+                ])
+            });
+
+        let result = run_query(
+            store,
+            &format!(
+                "{{ entityChangesInBlock(subgraphId: \"{}\", blockNumber: 7) {{ \
+                 entityType entityId kind data }} }}",
+                id
+            ),
+        );
+        let change = |id: &str, kind: &str| {
+            let mut data = BTreeMap::new();
+            data.insert("id".to_owned(), q::Value::String(id.to_owned()));
+            object_value(vec![
+                ("entityType", q::Value::String("User".to_owned())),
+                ("entityId", q::Value::String(id.to_owned())),
+                ("kind", q::Value::Enum(kind.to_owned())),
+                ("data", q::Value::Object(data)),
+            ])
+        };
+        assert_eq!(
+            q::Value::List(vec![change("1", "CREATED"), change("2", "DELETED")]),
+            result_value(result, &["entityChangesInBlock"])
+        );
+
+        // An invalid deployment id is an error and does not touch the store
+        let result = run_query(
+            MockStore::new(),
+            "{ entityChangesInBlock(subgraphId: \"not a valid id\", blockNumber: 7) { entityId } }",
+        );
+        assert!(result.errors.is_some());
+    }
+
+    #[test]
+    fn entity_changes_since_block() {
+        let id = SubgraphDeploymentId::new("QmChanges").unwrap();
+        let head = EthereumBlockPointer::from((H256::from_low_u64_be(12), 12u64));
+
+        let mut store = MockStore::new();
+        store.expect_block_ptr().returning(move |_| Ok(Some(head)));
+        store
+            .expect_entity_changes_since()
+            .withf(|_, since, to| *since == 10 && *to == 12)
+            .returning(|_, _, _| {
+                Ok(vec![BlockEntityChange {
+                    entity_type: "User".to_owned(),
+                    entity_id: "1".to_owned(),
+                    kind: BlockEntityChangeKind::Updated,
+                    data: Entity::from(vec![("id", Value::from("1"))]),
+                }])
+            });
+
+        let result = run_query(
+            store,
+            &format!(
+                "{{ entityChangesSince(subgraphId: \"{}\", sinceBlock: 10) {{ \
+                 block {{ number hash }} changes {{ entityType entityId kind }} }} }}",
+                id
+            ),
+        );
+        let changes = result_value(result, &["entityChangesSince"]);
+        assert_eq!(
+            object_value(vec![
+                (
+                    "block",
+                    object_value(vec![
+                        ("number", q::Value::String("12".to_owned())),
+                        ("hash", q::Value::String(head.hash_hex())),
+                    ])
+                ),
+                (
+                    "changes",
+                    q::Value::List(vec![object_value(vec![
+                        ("entityType", q::Value::String("User".to_owned())),
+                        ("entityId", q::Value::String("1".to_owned())),
+                        ("kind", q::Value::Enum("UPDATED".to_owned())),
+                    ])])
+                ),
+            ]),
+            changes
+        );
+
+        // A deployment without blocks has no latest block and no changes
+        let mut store = MockStore::new();
+        store.expect_block_ptr().returning(|_| Ok(None));
+        let result = run_query(
+            store,
+            &format!(
+                "{{ entityChangesSince(subgraphId: \"{}\", sinceBlock: 0) {{ \
+                 block {{ number }} changes {{ entityId }} }} }}",
+                id
+            ),
+        );
+        assert_eq!(
+            object_value(vec![
+                ("block", q::Value::Null),
+                ("changes", q::Value::List(vec![])),
+            ]),
+            result_value(result, &["entityChangesSince"])
+        );
+    }
+
+    #[test]
+    fn node_capabilities_list_networks() {
+        let mut networks = BTreeMap::new();
+        networks.insert(
+            "mainnet".to_owned(),
+            EthereumAdapterCapabilities {
+                client: "geth".to_owned(),
+                traces: false,
+                decoded_blocks: false,
+            },
+        );
+        networks.insert(
+            "sidechain".to_owned(),
+            EthereumAdapterCapabilities {
+                client: "parity".to_owned(),
+                traces: true,
+                decoded_blocks: true,
+            },
+        );
+
+        let capabilities = match node_capabilities(&networks) {
+            q::Value::Object(map) => map,
+            _ => panic!("node capabilities must be an object"),
+        };
+        assert_eq!(
+            Some(&q::Value::String(SPEC_VERSIONS.last().unwrap().to_string())),
+            match &capabilities["specVersions"] {
+                q::Value::List(versions) => versions.last(),
+                _ => None,
+            }
+        );
+        let networks = match &capabilities["networks"] {
+            q::Value::List(networks) => networks.clone(),
+            _ => panic!("networks must be a list"),
+        };
+        assert_eq!(
+            vec![
+                object_value(vec![
+                    ("network", q::Value::String("mainnet".to_owned())),
+                    ("client", q::Value::String("geth".to_owned())),
+                    ("traces", q::Value::Boolean(false)),
+                    ("decodedBlocks", q::Value::Boolean(false)),
+                ]),
+                object_value(vec![
+                    ("network", q::Value::String("sidechain".to_owned())),
+                    ("client", q::Value::String("parity".to_owned())),
+                    ("traces", q::Value::Boolean(true)),
+                    ("decodedBlocks", q::Value::Boolean(true)),
+                ]),
+            ],
+            networks
+        );
+    }
+
+    #[test]
+    fn block_cursors() {
+        let hash = H256::from_low_u64_be(7);
+        let cursor = BlockCursor {
+            number: 12,
+            hash: Some(hash),
+        };
+        assert_eq!(cursor, BlockCursor::parse(&cursor.to_string()).unwrap());
+        assert_eq!(
+            cursor,
+            BlockCursor::parse(&format!("12:0x{:x}", hash)).unwrap()
+        );
+        assert_eq!(
+            BlockCursor {
+                number: 12,
+                hash: None
+            },
+            BlockCursor::parse("12").unwrap()
+        );
+        assert!(BlockCursor::parse("twelve").is_err());
+        assert!(BlockCursor::parse("12:xyz").is_err());
+    }
+
+    #[test]
+    fn indexing_statuses_report_disk_quotas() {
+        let string = |s: &str| q::Value::String(s.to_owned());
+        let deployment = |id: &str| {
+            object_value(vec![
+                ("id", string(id)),
+                ("synced", q::Value::Boolean(true)),
+                ("failed", q::Value::Boolean(false)),
+                (
+                    "manifest",
+                    object_value(vec![(
+                        "dataSources",
+                        q::Value::List(vec![object_value(vec![("network", string("mainnet"))])]),
+                    )]),
+                ),
+            ])
+        };
+        let assignment = |id: &str| {
+            object_value(vec![
+                ("id", string(id)),
+                ("nodeId", string("default")),
+                ("paused", q::Value::Boolean(false)),
+            ])
+        };
+        let data = object_value(vec![
+            (
+                "subgraphDeployments",
+                q::Value::List(vec![deployment("QmLimited"), deployment("QmUnlimited")]),
+            ),
+            (
+                "subgraphDeploymentAssignments",
+                q::Value::List(vec![assignment("QmLimited"), assignment("QmUnlimited")]),
+            ),
+            ("subgraphDeploymentIdles", q::Value::List(vec![])),
+            ("subgraphDeploymentIndexFailures", q::Value::List(vec![])),
+            (
+                "subgraphDeploymentQuotas",
+                q::Value::List(vec![object_value(vec![
+                    ("id", string("QmLimited")),
+                    ("softLimitExceeded", q::Value::Boolean(true)),
+                    ("hardLimitExceeded", q::Value::Boolean(false)),
+                ])]),
+            ),
+        ]);
+
+        let statuses = IndexingStatuses::try_from(data).unwrap();
+        let exceeded = statuses
+            .0
+            .into_iter()
+            .map(|status| match q::Value::from(status) {
+                q::Value::Object(map) => (
+                    map["softDiskQuotaExceeded"].clone(),
+                    map["hardDiskQuotaExceeded"].clone(),
+                ),
+                _ => panic!("indexing status must be an object"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (q::Value::Boolean(true), q::Value::Boolean(false)),
+                (q::Value::Boolean(false), q::Value::Boolean(false)),
+            ],
+            exceeded
+        );
+    }
+
+    #[test]
+    fn indexing_statuses_report_earliest_retained_block() {
+        let string = |s: &str| q::Value::String(s.to_owned());
+        let deployment = |id: &str, retained: Option<&str>| {
+            let mut fields = vec![
+                ("id", string(id)),
+                ("synced", q::Value::Boolean(true)),
+                ("failed", q::Value::Boolean(false)),
+                (
+                    "manifest",
+                    object_value(vec![(
+                        "dataSources",
+                        q::Value::List(vec![object_value(vec![("network", string("mainnet"))])]),
+                    )]),
+                ),
+            ];
+            if let Some(retained) = retained {
+                fields.push(("earliestRetainedBlockNumber", string(retained)));
+            }
+            object_value(fields)
+        };
+        let data = object_value(vec![
+            (
+                "subgraphDeployments",
+                q::Value::List(vec![
+                    deployment("QmPruned", Some("1200")),
+                    deployment("QmComplete", None),
+                ]),
+            ),
+            (
+                "subgraphDeploymentAssignments",
+                q::Value::List(vec![
+                    object_value(vec![
+                        ("id", string("QmPruned")),
+                        ("nodeId", string("default")),
+                    ]),
+                    object_value(vec![
+                        ("id", string("QmComplete")),
+                        ("nodeId", string("default")),
+                    ]),
+                ]),
+            ),
+            ("subgraphDeploymentIdles", q::Value::List(vec![])),
+            ("subgraphDeploymentIndexFailures", q::Value::List(vec![])),
+            ("subgraphDeploymentQuotas", q::Value::List(vec![])),
+        ]);
+
+        let statuses = IndexingStatuses::try_from(data).unwrap();
+        let retained = statuses
+            .0
+            .into_iter()
+            .map(|status| match q::Value::from(status) {
+                q::Value::Object(map) => match &map["chains"] {
+                    q::Value::List(chains) => match &chains[0] {
+                        q::Value::Object(chain) => chain["earliestRetainedBlockNumber"].clone(),
+                        _ => panic!("chain status must be an object"),
+                    },
+                    _ => panic!("chains must be a list"),
+                },
+                _ => panic!("indexing status must be an object"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![string("1200"), q::Value::Null], retained);
+    }
+
+    #[test]
+    fn seconds_behind_chain_head() {
+        let block = |number: u64| {
+            EthereumBlock(EthereumBlockPointer {
+                hash: H256::from_low_u64_be(number),
+                number,
+            })
+        };
+        let status = |subgraph: &str, latest: Option<u64>| IndexingStatus {
+            subgraph: subgraph.to_owned(),
+            synced: false,
+            failed: false,
+            error: None,
+            chains: vec![ChainIndexingStatus::Ethereum(EthereumIndexingStatus {
+                network: "mainnet".to_owned(),
+                chain_head_block: Some(block(10)),
+                earliest_block: Some(block(0)),
+                earliest_retained_block_number: None,
+                latest_block: latest.map(block),
+                seconds_behind_chain_head: None,
+            })],
+            node: "default".to_owned(),
+            idle: false,
+            hibernated: false,
+            paused: false,
+            index_creation_error: None,
+            disk_quota: DiskQuotaStatus::default(),
+        };
+        let statuses = IndexingStatuses(vec![
+            status("behind", Some(7)),
+            status("missing", Some(3)),
+            status("new", None),
+        ]);
+        assert_eq!(
+            vec![10, 7, 10, 3, 10],
+            statuses
+                .block_hashes()
+                .into_iter()
+                .map(|hash| hash.to_low_u64_be())
+                .collect::<Vec<_>>()
+        );
+
+        // Block 3 is not in the chain store
+        let mut timestamps = HashMap::new();
+        timestamps.insert(H256::from_low_u64_be(10), 1_000);
+        timestamps.insert(H256::from_low_u64_be(7), 955);
+        let seconds_behind = statuses
+            .with_seconds_behind(&timestamps)
+            .0
+            .into_iter()
+            .map(|status| match &status.chains[0] {
+                ChainIndexingStatus::Ethereum(inner) => inner.seconds_behind_chain_head,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![Some(45), None, None], seconds_behind);
+    }
+}
@@ -1,10 +1,12 @@
 use hyper;
 use hyper::service::make_service_fn;
 use hyper::Server;
+use std::collections::{BTreeMap, HashMap};
 use std::net::{Ipv4Addr, SocketAddrV4};
 
 use graph::prelude::{IndexNodeServer as IndexNodeServerTrait, *};
 
+use crate::files::DeploymentFiles;
 use crate::service::IndexNodeService;
 
 /// Errors that may occur when starting the server.
@@ -21,20 +23,30 @@ impl From<hyper::Error> for IndexNodeServeError {
 }
 
 /// A GraphQL server based on Hyper.
-pub struct IndexNodeServer<Q, S> {
+pub struct IndexNodeServer<Q, S, L> {
     logger: Logger,
     graphql_runner: Arc<Q>,
     store: Arc<S>,
+    deployment_files: Arc<DeploymentFiles<L>>,
     node_id: NodeId,
+    networks: Arc<BTreeMap<String, EthereumAdapterCapabilities>>,
+    startup: Arc<StartupStatus>,
 }
 
-impl<Q, S> IndexNodeServer<Q, S> {
+impl<Q, S, L> IndexNodeServer<Q, S, L>
+where
+    L: LinkResolver + Clone,
+{
     /// Creates a new GraphQL server.
     pub fn new(
         logger_factory: &LoggerFactory,
         graphql_runner: Arc<Q>,
         store: Arc<S>,
+        link_resolver: Arc<L>,
+        metrics_registry: Arc<impl MetricsRegistry>,
         node_id: NodeId,
+        eth_adapters: HashMap<String, Arc<dyn EthereumAdapter>>,
+        startup: Arc<StartupStatus>,
     ) -> Self {
         let logger = logger_factory.component_logger(
             "IndexNodeServer",
@@ -45,19 +57,34 @@ impl<Q, S> IndexNodeServer<Q, S> {
             }),
         );
 
+        let deployment_files = Arc::new(DeploymentFiles::new(
+            &logger,
+            link_resolver,
+            metrics_registry,
+        ));
+
         IndexNodeServer {
             logger,
             graphql_runner,
             store,
+            deployment_files,
             node_id,
+            networks: Arc::new(
+                eth_adapters
+                    .into_iter()
+                    .map(|(network, adapter)| (network, adapter.capabilities()))
+                    .collect(),
+            ),
+            startup,
         }
     }
 }
 
-impl<Q, S> IndexNodeServerTrait for IndexNodeServer<Q, S>
+impl<Q, S, L> IndexNodeServerTrait for IndexNodeServer<Q, S, L>
 where
     Q: GraphQlRunner,
-    S: SubgraphDeploymentStore + Store,
+    S: SubgraphDeploymentStore + Store + ChainStore,
+    L: LinkResolver + Clone,
 {
     type ServeError = IndexNodeServeError;
 
@@ -79,13 +106,19 @@ where
         let logger_for_service = self.logger.clone();
         let graphql_runner = self.graphql_runner.clone();
         let store = self.store.clone();
+        let deployment_files = self.deployment_files.clone();
         let node_id = self.node_id.clone();
+        let networks = self.networks.clone();
+        let startup = self.startup.clone();
         let new_service = make_service_fn(move |_| {
             futures03::future::ok::<_, Error>(IndexNodeService::new(
                 logger_for_service.clone(),
                 graphql_runner.clone(),
                 store.clone(),
+                deployment_files.clone(),
                 node_id.clone(),
+                networks.clone(),
+                startup.clone(),
             ))
         });
 
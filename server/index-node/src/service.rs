@@ -1,15 +1,18 @@
 use http::header;
 use hyper::service::Service;
 use hyper::{Body, Method, Request, Response, StatusCode};
+use std::collections::BTreeMap;
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
 use std::time::Instant;
 
+use graph::components::server::admin::admin_actor;
 use graph::components::server::query::GraphQLServerError;
 use graph::prelude::*;
 use graph_graphql::prelude::{execute_query, QueryExecutionOptions};
 
+use crate::files::DeploymentFiles;
 use crate::request::IndexNodeRequest;
 use crate::resolver::IndexNodeResolver;
 use crate::response::IndexNodeResponse;
@@ -21,36 +24,54 @@ pub type IndexNodeServiceResponse =
 
 /// A Hyper Service that serves GraphQL over a POST / endpoint.
 #[derive(Debug)]
-pub struct IndexNodeService<Q, S> {
+pub struct IndexNodeService<Q, S, L> {
     logger: Logger,
     graphql_runner: Arc<Q>,
     store: Arc<S>,
+    deployment_files: Arc<DeploymentFiles<L>>,
     node_id: NodeId,
+    networks: Arc<BTreeMap<String, EthereumAdapterCapabilities>>,
+    startup: Arc<StartupStatus>,
 }
 
-impl<Q, S> Clone for IndexNodeService<Q, S> {
+impl<Q, S, L> Clone for IndexNodeService<Q, S, L> {
     fn clone(&self) -> Self {
         Self {
             logger: self.logger.clone(),
             graphql_runner: self.graphql_runner.clone(),
             store: self.store.clone(),
+            deployment_files: self.deployment_files.clone(),
             node_id: self.node_id.clone(),
+            networks: self.networks.clone(),
+            startup: self.startup.clone(),
         }
     }
 }
 
-impl<Q, S> IndexNodeService<Q, S>
+impl<Q, S, L> IndexNodeService<Q, S, L>
 where
     Q: GraphQlRunner,
-    S: SubgraphDeploymentStore + Store,
+    S: SubgraphDeploymentStore + Store + ChainStore,
+    L: LinkResolver + Clone,
 {
     /// Creates a new GraphQL service.
-    pub fn new(logger: Logger, graphql_runner: Arc<Q>, store: Arc<S>, node_id: NodeId) -> Self {
+    pub fn new(
+        logger: Logger,
+        graphql_runner: Arc<Q>,
+        store: Arc<S>,
+        deployment_files: Arc<DeploymentFiles<L>>,
+        node_id: NodeId,
+        networks: Arc<BTreeMap<String, EthereumAdapterCapabilities>>,
+        startup: Arc<StartupStatus>,
+    ) -> Self {
         IndexNodeService {
             logger,
             graphql_runner,
             store,
+            deployment_files,
             node_id,
+            networks,
+            startup,
         }
     }
 
@@ -89,22 +110,72 @@ where
         })
     }
 
+    /// Responds with 200 once the deployments assigned to this node have
+    /// all been started, skipped, or failed to start, and with 503 until
+    /// then. Either way, the body lists the startup phase of every
+    /// deployment on this node
+    fn handle_ready(&self) -> IndexNodeServiceResponse {
+        let ready = self.startup.is_ready();
+        let deployments: Vec<_> = self
+            .startup
+            .deployments()
+            .into_iter()
+            .map(|(id, phase)| {
+                serde_json::json!({
+                    "deployment": id.as_str(),
+                    "phase": phase.as_str(),
+                    "reason": phase.reason(),
+                })
+            })
+            .collect();
+        let body = serde_json::json!({
+            "ready": ready,
+            "deployments": deployments,
+        });
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        Box::pin(futures03::future::ok(
+            Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        ))
+    }
+
     fn handle_graphiql(&self) -> IndexNodeServiceResponse {
         self.serve_dynamic_file(self.graphiql_html())
     }
 
-    fn handle_graphql_query(&self, request_body: Body) -> IndexNodeServiceResponse {
+    fn handle_graphql_query(&self, request: Request<Body>) -> IndexNodeServiceResponse {
         let logger = self.logger.clone();
         let store = self.store.clone();
+        let deployment_files = self.deployment_files.clone();
+        let networks = self.networks.clone();
         let result_logger = self.logger.clone();
         let graphql_runner = self.graphql_runner.clone();
 
         // Obtain the schema for the index node GraphQL API
         let schema = SCHEMA.clone();
 
+        // Admin fields are only available to holders of an admin token
+        let authorization = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        let admin_access = match admin_actor(authorization) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err("no admin tokens are configured".to_owned()),
+            Err(e) => Err(e.to_string()),
+        };
+
         let start = Instant::now();
 
-        hyper::body::to_bytes(request_body)
+        hyper::body::to_bytes(request.into_body())
             .map_err(|_| GraphQLServerError::from("Failed to read request body"))
             .and_then(move |body| IndexNodeRequest::new(body, schema).compat())
             .and_then(move |query| {
@@ -117,11 +188,19 @@ where
                         query,
                         QueryExecutionOptions {
                             logger: logger.clone(),
-                            resolver: IndexNodeResolver::new(&logger, graphql_runner, store),
+                            resolver: IndexNodeResolver::new(
+                                &logger,
+                                graphql_runner,
+                                store,
+                                deployment_files,
+                                networks,
+                                admin_access,
+                            ),
                             deadline: None,
                             max_complexity: None,
                             max_depth: 100,
                             max_first: std::u32::MAX,
+                            block: BLOCK_NUMBER_MAX,
                         },
                     ))
                 })
@@ -154,7 +233,10 @@ where
             Ok(Response::builder()
                 .status(200)
                 .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Headers", "Content-Type")
+                .header(
+                    "Access-Control-Allow-Headers",
+                    "Content-Type, Authorization",
+                )
                 .header("Access-Control-Allow-Methods", "GET, OPTIONS, POST")
                 .body(Body::from(""))
                 .unwrap())
@@ -201,6 +283,7 @@ where
 
         match (method, path_segments.as_slice()) {
             (Method::GET, [""]) => self.index(),
+            (Method::GET, ["ready"]) => self.handle_ready(),
             (Method::GET, ["graphiql.css"]) => {
                 self.serve_file(include_str!("../assets/graphiql.css"))
             }
@@ -214,7 +297,7 @@ where
             }
             (Method::GET, ["graphql", "playground"]) => self.handle_graphiql(),
 
-            (Method::POST, ["graphql"]) => self.handle_graphql_query(req.into_body()),
+            (Method::POST, ["graphql"]) => self.handle_graphql_query(req),
             (Method::OPTIONS, ["graphql"]) => self.handle_graphql_options(req),
 
             _ => self.handle_not_found(),
@@ -222,10 +305,11 @@ where
     }
 }
 
-impl<Q, S> Service<Request<Body>> for IndexNodeService<Q, S>
+impl<Q, S, L> Service<Request<Body>> for IndexNodeService<Q, S, L>
 where
     Q: GraphQlRunner,
-    S: SubgraphDeploymentStore + Store,
+    S: SubgraphDeploymentStore + Store + ChainStore,
+    L: LinkResolver + Clone,
 {
     type Response = Response<Body>;
     type Error = GraphQLServerError;
@@ -3,17 +3,21 @@ extern crate jsonrpc_http_server;
 extern crate lazy_static;
 extern crate serde;
 
+use graph::components::server::admin::admin_actor;
+use graph::data::schema::PartitionSpec;
+use graph::data::store::MAX_ENTITY_ID_LENGTH;
 use graph::prelude::futures03::channel::{mpsc, oneshot};
 use graph::prelude::futures03::SinkExt;
 use graph::prelude::serde_json;
 use graph::prelude::{JsonRpcServer as JsonRpcServerTrait, *};
 use jsonrpc_http_server::{
-    jsonrpc_core::{self, Compatibility, IoHandler, Params, Value},
+    hyper,
+    jsonrpc_core::{self, Compatibility, MetaIoHandler, Metadata, Params, Value},
     RestApi, Server, ServerBuilder,
 };
 use lazy_static::lazy_static;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddrV4};
@@ -29,6 +33,53 @@ const JSON_RPC_DEPLOY_ERROR: i64 = 0;
 const JSON_RPC_REMOVE_ERROR: i64 = 1;
 const JSON_RPC_CREATE_ERROR: i64 = 2;
 const JSON_RPC_REASSIGN_ERROR: i64 = 3;
+const JSON_RPC_STANDBY_ERROR: i64 = 4;
+const JSON_RPC_BLOCK_ERROR: i64 = 5;
+const JSON_RPC_UNAUTHORIZED_ERROR: i64 = 6;
+const JSON_RPC_QUOTA_ERROR: i64 = 7;
+const JSON_RPC_SCHEDULE_REMOVAL_ERROR: i64 = 8;
+const JSON_RPC_PAUSE_ERROR: i64 = 9;
+const JSON_RPC_INDEX_ERROR: i64 = 10;
+const JSON_RPC_REWIND_ERROR: i64 = 11;
+const JSON_RPC_VALIDATE_ERROR: i64 = 12;
+const JSON_RPC_COPY_ERROR: i64 = 13;
+const JSON_RPC_ANNOTATE_ERROR: i64 = 14;
+const JSON_RPC_SIMULATE_ERROR: i64 = 15;
+const JSON_RPC_REBALANCE_ERROR: i64 = 16;
+const JSON_RPC_PIN_ERROR: i64 = 17;
+const JSON_RPC_WAKE_ERROR: i64 = 18;
+
+/// Admin requests that only make sense for a running deployment, and that
+/// therefore wake up the deployment they are for if it is idle
+const WAKING_METHODS: &[&str] = &["subgraph_resume", "subgraph_unblock"];
+const JSON_RPC_ENTITY_ID_ERROR: i64 = 19;
+
+/// Who made an admin request, as determined from the admin token in its
+/// `Authorization` header
+#[derive(Clone, Debug, Default)]
+struct AdminMeta {
+    /// The holder of the admin token; `None` if no admin tokens are
+    /// configured
+    actor: Option<String>,
+    /// Why the request is not allowed, if it isn't
+    denied: Option<String>,
+}
+
+impl Metadata for AdminMeta {}
+
+/// Determine who makes an admin request from its `Authorization` header
+fn admin_meta(authorization: Option<&str>) -> AdminMeta {
+    match admin_actor(authorization) {
+        Ok(actor) => AdminMeta {
+            actor,
+            denied: None,
+        },
+        Err(e) => AdminMeta {
+            actor: None,
+            denied: Some(e.to_string()),
+        },
+    }
+}
 
 #[derive(Debug, Deserialize)]
 struct SubgraphCreateParams {
@@ -40,6 +91,20 @@ struct SubgraphDeployParams {
     name: SubgraphName,
     ipfs_hash: SubgraphDeploymentId,
     node_id: Option<NodeId>,
+    /// Remove the deployment once it has processed this block
+    remove_at_block: Option<u64>,
+    /// Remove the deployment once this time, in seconds since the epoch,
+    /// has passed
+    remove_at_time: Option<u64>,
+    /// Partition the tables of these entity types, as if the types had a
+    /// `@partition` directive
+    #[serde(default)]
+    partitions: BTreeMap<String, PartitionSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphValidateParams {
+    ipfs_hash: SubgraphDeploymentId,
 }
 
 #[derive(Debug, Deserialize)]
@@ -47,101 +112,866 @@ struct SubgraphRemoveParams {
     name: SubgraphName,
 }
 
-#[derive(Debug, Deserialize)]
-struct SubgraphReassignParams {
-    ipfs_hash: SubgraphDeploymentId,
-    node_id: NodeId,
-}
+#[derive(Debug, Deserialize)]
+struct SubgraphReassignParams {
+    ipfs_hash: SubgraphDeploymentId,
+    node_id: NodeId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphAssignStandbyParams {
+    ipfs_hash: SubgraphDeploymentId,
+    node_id: Option<NodeId>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphPromoteStandbyParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphBlockParams {
+    ipfs_hash: SubgraphDeploymentId,
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphUnblockParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphPauseParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphResumeParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphWakeParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphCreateMissingIndexesParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphRetryIndexCreationParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphOversizedEntityIdsParams {
+    ipfs_hash: SubgraphDeploymentId,
+    /// Defaults to `GRAPH_MAX_ENTITY_ID_LENGTH`
+    max_length: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphMigrateStorageParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphRewindParams {
+    ipfs_hash: SubgraphDeploymentId,
+    block_hash: web3::types::H256,
+    block_number: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphSetQuotaParams {
+    ipfs_hash: SubgraphDeploymentId,
+    soft_limit: Option<u64>,
+    hard_limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphScheduleRemovalParams {
+    ipfs_hash: SubgraphDeploymentId,
+    at_block: Option<u64>,
+    at_time: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphCopyParams {
+    source: SubgraphDeploymentId,
+    target: SubgraphDeploymentId,
+    node_id: Option<NodeId>,
+    /// The database tablespace for the data of the copy
+    tablespace: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphAnnotateParams {
+    ipfs_hash: SubgraphDeploymentId,
+    entity_type: String,
+    id: String,
+    /// The fields of the annotation; `null` removes the annotation
+    data: Option<HashMap<String, serde_json::Value>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphRebalanceParams {
+    /// The nodes to rebalance between; `GRAPH_REBALANCE_NODES` if missing
+    node_ids: Option<Vec<NodeId>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphPinParams {
+    ipfs_hash: SubgraphDeploymentId,
+    /// The node to pin the deployment to; `null` removes the pin
+    node_id: Option<NodeId>,
+}
+
+pub struct JsonRpcServer<R> {
+    registrar: Arc<R>,
+    simulator: Arc<dyn HandlerSimulator>,
+    http_port: u16,
+    ws_port: u16,
+    node_id: NodeId,
+    logger: Logger,
+}
+
+impl<R> JsonRpcServer<R>
+where
+    R: SubgraphRegistrar,
+{
+    /// Run `handler` for an admin request if its caller is allowed to make
+    /// it, and record the request and its outcome in the audit log
+    fn audited<F, T>(
+        &self,
+        method: &'static str,
+        params: Params,
+        meta: AdminMeta,
+        handler: F,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send>
+    where
+        F: FnOnce(Params) -> T,
+        T: Future<Item = Value, Error = jsonrpc_core::Error> + Send + 'static,
+    {
+        let registrar = self.registrar.clone();
+        let logger = self.logger.clone();
+        let parameters = serde_json::to_string(&params).expect("invalid JSON-RPC params");
+
+        // Rejected requests are only logged; anybody can send them, and
+        // persisting them would let unauthenticated callers fill the audit
+        // log
+        if let Some(e) = self.rejection(method, &parameters, &meta) {
+            return Box::new(future::err(e));
+        }
+        let actor = meta.actor;
+
+        let result = self
+            .wake_deployment(method, &params)
+            .then(move |_| handler(params));
+
+        Box::new(result.then(move |result| {
+            let error = result.as_ref().err().map(|e| e.message.clone());
+            registrar
+                .record_admin_operation(method.to_owned(), actor, parameters, error)
+                .then(move |recorded| {
+                    if let Err(e) = recorded {
+                        error!(logger, "Failed to record {} in the audit log", method;
+                               "error" => e.to_string());
+                    }
+                    result
+                })
+        }))
+    }
+
+    /// Run `handler` for an admin request that does not change anything if
+    /// its caller is allowed to make it. Such requests are not recorded in
+    /// the audit log and do not wake up deployments
+    fn authorized<F, T>(
+        &self,
+        method: &'static str,
+        params: Params,
+        meta: AdminMeta,
+        handler: F,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send>
+    where
+        F: FnOnce(Params) -> T,
+        T: Future<Item = Value, Error = jsonrpc_core::Error> + Send + 'static,
+    {
+        let parameters = serde_json::to_string(&params).expect("invalid JSON-RPC params");
+        match self.rejection(method, &parameters, &meta) {
+            Some(e) => Box::new(future::err(e)),
+            None => Box::new(handler(params)),
+        }
+    }
+
+    /// The error for a request whose caller is not allowed to make it
+    fn rejection(
+        &self,
+        method: &'static str,
+        parameters: &str,
+        meta: &AdminMeta,
+    ) -> Option<jsonrpc_core::Error> {
+        meta.denied.as_ref().map(|reason| {
+            warn!(self.logger, "Rejected {} request", method;
+                  "error" => reason,
+                  "actor" => &meta.actor,
+                  "params" => parameters);
+            json_rpc_error(JSON_RPC_UNAUTHORIZED_ERROR, reason.clone())
+        })
+    }
+
+    /// Wake up the deployment that an admin request is for, if the request
+    /// needs it to run and the deployment is idle or hibernated
+    fn wake_deployment(
+        &self,
+        method: &'static str,
+        params: &Params,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if !WAKING_METHODS.contains(&method) {
+            return Box::new(future::ok(()));
+        }
+        let logger = self.logger.clone();
+        match params.clone().parse::<SubgraphWakeParams>() {
+            Ok(params) => Box::new(self.registrar.wake_subgraph(params.ipfs_hash).map_err(
+                move |e| {
+                    debug!(logger, "Did not wake up deployment for {} request", method;
+                           "error" => e.to_string());
+                },
+            )),
+            Err(_) => Box::new(future::ok(())),
+        }
+    }
+
+    /// Handler for the `subgraph_create` endpoint.
+    fn create_handler(
+        &self,
+        params: SubgraphCreateParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_create request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .create_subgraph(params.name.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_create failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_CREATE_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_CREATE_ERROR, e.to_string())
+                    }
+                })
+                .map(move |result| {
+                    serde_json::to_value(result).expect("invalid subgraph creation result")
+                }),
+        )
+    }
+
+    /// Handler for the `subgraph_deploy` endpoint.
+    fn deploy_handler(
+        &self,
+        params: SubgraphDeployParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_deploy request"; "params" => format!("{:?}", params));
+
+        let node_id = params.node_id.clone().unwrap_or(self.node_id.clone());
+        let routes = subgraph_routes(&params.name, self.http_port, self.ws_port);
+        let registrar = self.registrar.clone();
+        let (hash, remove_at_block, remove_at_time) = (
+            params.ipfs_hash.clone(),
+            params.remove_at_block,
+            params.remove_at_time,
+        );
+
+        Box::new(
+            self.registrar
+                .create_subgraph_version(
+                    params.name.clone(),
+                    params.ipfs_hash.clone(),
+                    node_id,
+                    params.partitions.clone(),
+                )
+                .and_then(
+                    move |warnings| -> Box<dyn Future<Item = _, Error = _> + Send> {
+                        if remove_at_block.is_none() && remove_at_time.is_none() {
+                            return Box::new(future::ok(warnings));
+                        }
+                        Box::new(
+                            registrar
+                                .schedule_deployment_removal(hash, remove_at_block, remove_at_time)
+                                .map(move |()| warnings),
+                        )
+                    },
+                )
+                .map_err(move |e| {
+                    error!(logger, "subgraph_deploy failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_DEPLOY_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_DEPLOY_ERROR, e.to_string())
+                    }
+                })
+                .map(move |warnings| {
+                    let mut response = routes;
+                    response["warnings"] = warnings
+                        .into_iter()
+                        .map(|warning| Value::String(warning.to_string()))
+                        .collect();
+                    response
+                }),
+        )
+    }
+
+    /// Handler for the `subgraph_validate` endpoint.
+    fn validate_handler(
+        &self,
+        params: SubgraphValidateParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_validate request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .validate_subgraph(params.ipfs_hash.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_validate failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_VALIDATE_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_VALIDATE_ERROR, e.to_string())
+                    }
+                })
+                .map(subgraph_validation),
+        )
+    }
+
+    /// Handler for the `subgraph_remove` endpoint.
+    fn remove_handler(
+        &self,
+        params: SubgraphRemoveParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_remove request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .remove_subgraph(params.name.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_remove failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_REMOVE_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_REMOVE_ERROR, e.to_string())
+                    }
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_assign` endpoint.
+    fn reassign_handler(
+        &self,
+        params: SubgraphReassignParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_reassignment request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .reassign_subgraph(params.ipfs_hash.clone(), params.node_id.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_reassign failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_REASSIGN_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_REASSIGN_ERROR, e.to_string())
+                    }
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_assign_standby` endpoint.
+    fn assign_standby_handler(
+        &self,
+        params: SubgraphAssignStandbyParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_assign_standby request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .assign_standby(params.ipfs_hash.clone(), params.node_id.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_assign_standby failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_STANDBY_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_STANDBY_ERROR, e.to_string())
+                    }
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_promote_standby` endpoint.
+    fn promote_standby_handler(
+        &self,
+        params: SubgraphPromoteStandbyParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_promote_standby request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .promote_standby(params.ipfs_hash.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_promote_standby failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_STANDBY_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_STANDBY_ERROR, e.to_string())
+                    }
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_block` endpoint.
+    fn block_handler(
+        &self,
+        params: SubgraphBlockParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_block request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .block_deployment(params.ipfs_hash.clone(), params.reason.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_block failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_BLOCK_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_BLOCK_ERROR, e.to_string())
+                    }
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_unblock` endpoint.
+    fn unblock_handler(
+        &self,
+        params: SubgraphUnblockParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_unblock request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .unblock_deployment(params.ipfs_hash.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_unblock failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_BLOCK_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_BLOCK_ERROR, e.to_string())
+                    }
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_pause` endpoint.
+    fn pause_handler(
+        &self,
+        params: SubgraphPauseParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_pause request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .pause_subgraph(params.ipfs_hash.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_pause failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    json_rpc_error(JSON_RPC_PAUSE_ERROR, e.to_string())
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_resume` endpoint.
+    fn resume_handler(
+        &self,
+        params: SubgraphResumeParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_resume request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .resume_subgraph(params.ipfs_hash.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_resume failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    json_rpc_error(JSON_RPC_PAUSE_ERROR, e.to_string())
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_wake` endpoint.
+    fn wake_handler(
+        &self,
+        params: SubgraphWakeParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_wake request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .wake_subgraph(params.ipfs_hash.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_wake failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    json_rpc_error(JSON_RPC_WAKE_ERROR, e.to_string())
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_create_missing_indexes` endpoint.
+    fn create_missing_indexes_handler(
+        &self,
+        params: SubgraphCreateMissingIndexesParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_create_missing_indexes request";
+              "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .create_missing_indexes(params.ipfs_hash.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_create_missing_indexes failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_INDEX_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_INDEX_ERROR, e.to_string())
+                    }
+                })
+                .map(|created| Ok(index_creations(created)))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_retry_index_creation` endpoint.
+    fn retry_index_creation_handler(
+        &self,
+        params: SubgraphRetryIndexCreationParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_retry_index_creation request";
+              "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .retry_index_creation(params.ipfs_hash.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_retry_index_creation failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    json_rpc_error(JSON_RPC_INDEX_ERROR, e.to_string())
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_oversized_entity_ids` endpoint.
+    fn oversized_entity_ids_handler(
+        &self,
+        params: SubgraphOversizedEntityIdsParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_oversized_entity_ids request";
+              "params" => format!("{:?}", params));
+
+        let max_length = match params.max_length.or(*MAX_ENTITY_ID_LENGTH) {
+            Some(max_length) => max_length,
+            None => {
+                return Box::new(future::err(json_rpc_error(
+                    JSON_RPC_ENTITY_ID_ERROR,
+                    "max_length is required since GRAPH_MAX_ENTITY_ID_LENGTH is not set".to_owned(),
+                )))
+            }
+        };
+
+        Box::new(
+            self.registrar
+                .oversized_entity_ids(params.ipfs_hash.clone(), max_length)
+                .map_err(move |e| {
+                    error!(logger, "subgraph_oversized_entity_ids failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_ENTITY_ID_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_ENTITY_ID_ERROR, e.to_string())
+                    }
+                })
+                .map(move |oversized| Ok(oversized_entity_ids(max_length, oversized)))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_migrate_storage` endpoint.
+    fn migrate_storage_handler(
+        &self,
+        params: SubgraphMigrateStorageParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_migrate_storage request";
+              "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .migrate_storage(params.ipfs_hash.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_migrate_storage failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    json_rpc_error(JSON_RPC_INDEX_ERROR, e.to_string())
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_rewind` endpoint.
+    fn rewind_handler(
+        &self,
+        params: SubgraphRewindParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_rewind request"; "params" => format!("{:?}", params));
+
+        let block = EthereumBlockPointer {
+            hash: params.block_hash,
+            number: params.block_number,
+        };
+        Box::new(
+            self.registrar
+                .rewind_subgraph(params.ipfs_hash.clone(), block)
+                .map_err(move |e| {
+                    error!(logger, "subgraph_rewind failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    json_rpc_error(JSON_RPC_REWIND_ERROR, e.to_string())
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_copy` endpoint.
+    fn copy_handler(
+        &self,
+        params: SubgraphCopyParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_copy request"; "params" => format!("{:?}", params));
 
-pub struct JsonRpcServer<R> {
-    registrar: Arc<R>,
-    http_port: u16,
-    ws_port: u16,
-    node_id: NodeId,
-    logger: Logger,
-}
+        let node_id = params.node_id.clone().unwrap_or(self.node_id.clone());
 
-impl<R> JsonRpcServer<R>
-where
-    R: SubgraphRegistrar,
-{
-    /// Handler for the `subgraph_create` endpoint.
-    fn create_handler(
+        Box::new(
+            self.registrar
+                .copy_subgraph(
+                    params.source.clone(),
+                    params.target.clone(),
+                    node_id,
+                    params.tablespace.clone(),
+                )
+                .map_err(move |e| {
+                    error!(logger, "subgraph_copy failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_COPY_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_COPY_ERROR, e.to_string())
+                    }
+                })
+                .map(|_| Ok(Value::Null))
+                .flatten(),
+        )
+    }
+
+    /// Handler for the `subgraph_annotate` endpoint.
+    fn annotate_handler(
         &self,
-        params: SubgraphCreateParams,
+        params: SubgraphAnnotateParams,
     ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
         let logger = self.logger.clone();
 
-        info!(logger, "Received subgraph_create request"; "params" => format!("{:?}", params));
+        info!(logger, "Received subgraph_annotate request"; "params" => format!("{:?}", params));
 
         Box::new(
             self.registrar
-                .create_subgraph(params.name.clone())
+                .annotate(
+                    params.ipfs_hash.clone(),
+                    params.entity_type.clone(),
+                    params.id.clone(),
+                    params.data.clone(),
+                )
                 .map_err(move |e| {
-                    error!(logger, "subgraph_create failed";
+                    error!(logger, "subgraph_annotate failed";
                            "error" => format!("{:?}", e),
                            "params" => format!("{:?}", params));
                     if let SubgraphRegistrarError::Unknown(_) = e {
-                        json_rpc_error(JSON_RPC_CREATE_ERROR, "internal error".to_owned())
+                        json_rpc_error(JSON_RPC_ANNOTATE_ERROR, "internal error".to_owned())
                     } else {
-                        json_rpc_error(JSON_RPC_CREATE_ERROR, e.to_string())
+                        json_rpc_error(JSON_RPC_ANNOTATE_ERROR, e.to_string())
                     }
                 })
-                .map(move |result| {
-                    serde_json::to_value(result).expect("invalid subgraph creation result")
-                }),
+                .map(|_| Ok(Value::Null))
+                .flatten(),
         )
     }
 
-    /// Handler for the `subgraph_deploy` endpoint.
-    fn deploy_handler(
+    /// Handler for the `subgraph_simulate` endpoint.
+    fn simulate_handler(
         &self,
-        params: SubgraphDeployParams,
+        params: HandlerSimulation,
     ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
         let logger = self.logger.clone();
 
-        info!(logger, "Received subgraph_deploy request"; "params" => format!("{:?}", params));
+        info!(logger, "Received subgraph_simulate request"; "params" => format!("{:?}", params));
 
-        let node_id = params.node_id.clone().unwrap_or(self.node_id.clone());
-        let routes = subgraph_routes(&params.name, self.http_port, self.ws_port);
+        Box::new(
+            self.simulator
+                .simulate(params.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_simulate failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    json_rpc_error(JSON_RPC_SIMULATE_ERROR, e.to_string())
+                })
+                .map(|result| serde_json::to_value(result).expect("invalid simulation result")),
+        )
+    }
+
+    /// Handler for the `subgraph_schedule_removal` endpoint.
+    fn schedule_removal_handler(
+        &self,
+        params: SubgraphScheduleRemovalParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_schedule_removal request"; "params" => format!("{:?}", params));
 
         Box::new(
             self.registrar
-                .create_subgraph_version(params.name.clone(), params.ipfs_hash.clone(), node_id)
+                .schedule_deployment_removal(
+                    params.ipfs_hash.clone(),
+                    params.at_block,
+                    params.at_time,
+                )
                 .map_err(move |e| {
-                    error!(logger, "subgraph_deploy failed";
+                    error!(logger, "subgraph_schedule_removal failed";
                            "error" => format!("{:?}", e),
                            "params" => format!("{:?}", params));
                     if let SubgraphRegistrarError::Unknown(_) = e {
-                        json_rpc_error(JSON_RPC_DEPLOY_ERROR, "internal error".to_owned())
+                        json_rpc_error(JSON_RPC_SCHEDULE_REMOVAL_ERROR, "internal error".to_owned())
                     } else {
-                        json_rpc_error(JSON_RPC_DEPLOY_ERROR, e.to_string())
+                        json_rpc_error(JSON_RPC_SCHEDULE_REMOVAL_ERROR, e.to_string())
                     }
                 })
-                .map(move |_| routes),
+                .map(|_| Ok(Value::Null))
+                .flatten(),
         )
     }
 
-    /// Handler for the `subgraph_remove` endpoint.
-    fn remove_handler(
+    /// Handler for the `subgraph_set_quota` endpoint.
+    fn set_quota_handler(
         &self,
-        params: SubgraphRemoveParams,
+        params: SubgraphSetQuotaParams,
     ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
         let logger = self.logger.clone();
 
-        info!(logger, "Received subgraph_remove request"; "params" => format!("{:?}", params));
+        info!(logger, "Received subgraph_set_quota request"; "params" => format!("{:?}", params));
 
         Box::new(
             self.registrar
-                .remove_subgraph(params.name.clone())
+                .set_deployment_quota(
+                    params.ipfs_hash.clone(),
+                    params.soft_limit,
+                    params.hard_limit,
+                )
                 .map_err(move |e| {
-                    error!(logger, "subgraph_remove failed";
+                    error!(logger, "subgraph_set_quota failed";
                            "error" => format!("{:?}", e),
                            "params" => format!("{:?}", params));
                     if let SubgraphRegistrarError::Unknown(_) = e {
-                        json_rpc_error(JSON_RPC_REMOVE_ERROR, "internal error".to_owned())
+                        json_rpc_error(JSON_RPC_QUOTA_ERROR, "internal error".to_owned())
                     } else {
-                        json_rpc_error(JSON_RPC_REMOVE_ERROR, e.to_string())
+                        json_rpc_error(JSON_RPC_QUOTA_ERROR, e.to_string())
                     }
                 })
                 .map(|_| Ok(Value::Null))
@@ -149,26 +979,52 @@ where
         )
     }
 
-    /// Handler for the `subgraph_assign` endpoint.
-    fn reassign_handler(
+    /// Handler for the `subgraph_rebalance` endpoint.
+    fn rebalance_handler(
         &self,
-        params: SubgraphReassignParams,
+        params: SubgraphRebalanceParams,
     ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
         let logger = self.logger.clone();
 
-        info!(logger, "Received subgraph_reassignment request"; "params" => format!("{:?}", params));
+        info!(logger, "Received subgraph_rebalance request"; "params" => format!("{:?}", params));
 
         Box::new(
             self.registrar
-                .reassign_subgraph(params.ipfs_hash.clone(), params.node_id.clone())
+                .rebalance_assignments(params.node_ids.clone())
                 .map_err(move |e| {
-                    error!(logger, "subgraph_reassign failed";
+                    error!(logger, "subgraph_rebalance failed";
                            "error" => format!("{:?}", e),
                            "params" => format!("{:?}", params));
                     if let SubgraphRegistrarError::Unknown(_) = e {
-                        json_rpc_error(JSON_RPC_REASSIGN_ERROR, "internal error".to_owned())
+                        json_rpc_error(JSON_RPC_REBALANCE_ERROR, "internal error".to_owned())
                     } else {
-                        json_rpc_error(JSON_RPC_REASSIGN_ERROR, e.to_string())
+                        json_rpc_error(JSON_RPC_REBALANCE_ERROR, e.to_string())
+                    }
+                })
+                .map(assignment_moves),
+        )
+    }
+
+    /// Handler for the `subgraph_pin` endpoint.
+    fn pin_handler(
+        &self,
+        params: SubgraphPinParams,
+    ) -> Box<dyn Future<Item = Value, Error = jsonrpc_core::Error> + Send> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_pin request"; "params" => format!("{:?}", params));
+
+        Box::new(
+            self.registrar
+                .pin_deployment(params.ipfs_hash.clone(), params.node_id.clone())
+                .map_err(move |e| {
+                    error!(logger, "subgraph_pin failed";
+                           "error" => format!("{:?}", e),
+                           "params" => format!("{:?}", params));
+                    if let SubgraphRegistrarError::Unknown(_) = e {
+                        json_rpc_error(JSON_RPC_PIN_ERROR, "internal error".to_owned())
+                    } else {
+                        json_rpc_error(JSON_RPC_PIN_ERROR, e.to_string())
                     }
                 })
                 .map(|_| Ok(Value::Null))
@@ -188,6 +1044,7 @@ where
         http_port: u16,
         ws_port: u16,
         registrar: Arc<R>,
+        simulator: Arc<dyn HandlerSimulator>,
         node_id: NodeId,
         logger: Logger,
     ) -> Result<Self::Server, io::Error> {
@@ -200,10 +1057,12 @@ where
 
         let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
 
-        let mut handler = IoHandler::with_compatibility(Compatibility::Both);
+        let mut handler: MetaIoHandler<AdminMeta> =
+            MetaIoHandler::with_compatibility(Compatibility::Both);
 
         let arc_self = Arc::new(JsonRpcServer {
             registrar,
+            simulator,
             http_port,
             ws_port,
             node_id,
@@ -241,14 +1100,272 @@ where
 
         let me = arc_self.clone();
         let sender = task_sender.clone();
-        handler.add_method("subgraph_create", move |params: Params| {
+        handler.add_method_with_meta("subgraph_create", move |params: Params, meta: AdminMeta| {
+            let me = me.clone();
+            Box::pin(tokio02_spawn(
+                sender.clone(),
+                me.clone()
+                    .audited("subgraph_create", params, meta, move |params| {
+                        params
+                            .parse()
+                            .into_future()
+                            .and_then(move |params| me.create_handler(params))
+                    })
+                    .compat(),
+            ))
+            .compat()
+        });
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta("subgraph_deploy", move |params: Params, meta: AdminMeta| {
+            let me = me.clone();
+            Box::pin(tokio02_spawn(
+                sender.clone(),
+                me.clone()
+                    .audited("subgraph_deploy", params, meta, move |params| {
+                        params
+                            .parse()
+                            .into_future()
+                            .and_then(move |params| me.deploy_handler(params))
+                    })
+                    .compat(),
+            ))
+            .compat()
+        });
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_validate",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .authorized("subgraph_validate", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.validate_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta("subgraph_remove", move |params: Params, meta: AdminMeta| {
+            let me = me.clone();
+            Box::pin(tokio02_spawn(
+                sender.clone(),
+                me.clone()
+                    .audited("subgraph_remove", params, meta, move |params| {
+                        params
+                            .parse()
+                            .into_future()
+                            .and_then(move |params| me.remove_handler(params))
+                    })
+                    .compat(),
+            ))
+            .compat()
+        });
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_reassign",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited("subgraph_reassign", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.reassign_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_assign_standby",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited("subgraph_assign_standby", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.assign_standby_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_promote_standby",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited("subgraph_promote_standby", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.promote_standby_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta("subgraph_block", move |params: Params, meta: AdminMeta| {
+            let me = me.clone();
+            Box::pin(tokio02_spawn(
+                sender.clone(),
+                me.clone()
+                    .audited("subgraph_block", params, meta, move |params| {
+                        params
+                            .parse()
+                            .into_future()
+                            .and_then(move |params| me.block_handler(params))
+                    })
+                    .compat(),
+            ))
+            .compat()
+        });
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_unblock",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited("subgraph_unblock", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.unblock_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_set_quota",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited("subgraph_set_quota", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.set_quota_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_schedule_removal",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited("subgraph_schedule_removal", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.schedule_removal_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta("subgraph_pause", move |params: Params, meta: AdminMeta| {
+            let me = me.clone();
+            Box::pin(tokio02_spawn(
+                sender.clone(),
+                me.clone()
+                    .audited("subgraph_pause", params, meta, move |params| {
+                        params
+                            .parse()
+                            .into_future()
+                            .and_then(move |params| me.pause_handler(params))
+                    })
+                    .compat(),
+            ))
+            .compat()
+        });
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta("subgraph_resume", move |params: Params, meta: AdminMeta| {
+            let me = me.clone();
+            Box::pin(tokio02_spawn(
+                sender.clone(),
+                me.clone()
+                    .audited("subgraph_resume", params, meta, move |params| {
+                        params
+                            .parse()
+                            .into_future()
+                            .and_then(move |params| me.resume_handler(params))
+                    })
+                    .compat(),
+            ))
+            .compat()
+        });
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta("subgraph_wake", move |params: Params, meta: AdminMeta| {
             let me = me.clone();
             Box::pin(tokio02_spawn(
                 sender.clone(),
-                params
-                    .parse()
-                    .into_future()
-                    .and_then(move |params| me.create_handler(params))
+                me.clone()
+                    .audited("subgraph_wake", params, meta, move |params| {
+                        params
+                            .parse()
+                            .into_future()
+                            .and_then(move |params| me.wake_handler(params))
+                    })
                     .compat(),
             ))
             .compat()
@@ -256,14 +1373,115 @@ where
 
         let me = arc_self.clone();
         let sender = task_sender.clone();
-        handler.add_method("subgraph_deploy", move |params: Params| {
+        handler.add_method_with_meta(
+            "subgraph_create_missing_indexes",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited(
+                            "subgraph_create_missing_indexes",
+                            params,
+                            meta,
+                            move |params| {
+                                params.parse().into_future().and_then(move |params| {
+                                    me.create_missing_indexes_handler(params)
+                                })
+                            },
+                        )
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_retry_index_creation",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited(
+                            "subgraph_retry_index_creation",
+                            params,
+                            meta,
+                            move |params| {
+                                params
+                                    .parse()
+                                    .into_future()
+                                    .and_then(move |params| me.retry_index_creation_handler(params))
+                            },
+                        )
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_oversized_entity_ids",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited(
+                            "subgraph_oversized_entity_ids",
+                            params,
+                            meta,
+                            move |params| {
+                                params
+                                    .parse()
+                                    .into_future()
+                                    .and_then(move |params| me.oversized_entity_ids_handler(params))
+                            },
+                        )
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_migrate_storage",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited("subgraph_migrate_storage", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.migrate_storage_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta("subgraph_rewind", move |params: Params, meta: AdminMeta| {
             let me = me.clone();
             Box::pin(tokio02_spawn(
                 sender.clone(),
-                params
-                    .parse()
-                    .into_future()
-                    .and_then(move |params| me.deploy_handler(params))
+                me.clone()
+                    .audited("subgraph_rewind", params, meta, move |params| {
+                        params
+                            .parse()
+                            .into_future()
+                            .and_then(move |params| me.rewind_handler(params))
+                    })
                     .compat(),
             ))
             .compat()
@@ -271,14 +1489,17 @@ where
 
         let me = arc_self.clone();
         let sender = task_sender.clone();
-        handler.add_method("subgraph_remove", move |params: Params| {
+        handler.add_method_with_meta("subgraph_copy", move |params: Params, meta: AdminMeta| {
             let me = me.clone();
             Box::pin(tokio02_spawn(
                 sender.clone(),
-                params
-                    .parse()
-                    .into_future()
-                    .and_then(move |params| me.remove_handler(params))
+                me.clone()
+                    .audited("subgraph_copy", params, meta, move |params| {
+                        params
+                            .parse()
+                            .into_future()
+                            .and_then(move |params| me.copy_handler(params))
+                    })
                     .compat(),
             ))
             .compat()
@@ -286,24 +1507,97 @@ where
 
         let me = arc_self.clone();
         let sender = task_sender.clone();
-        handler.add_method("subgraph_reassign", move |params: Params| {
+        handler.add_method_with_meta(
+            "subgraph_annotate",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited("subgraph_annotate", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.annotate_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_simulate",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited("subgraph_simulate", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.simulate_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_rebalance",
+            move |params: Params, meta: AdminMeta| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    me.clone()
+                        .audited("subgraph_rebalance", params, meta, move |params| {
+                            params
+                                .parse()
+                                .into_future()
+                                .and_then(move |params| me.rebalance_handler(params))
+                        })
+                        .compat(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta("subgraph_pin", move |params: Params, meta: AdminMeta| {
             let me = me.clone();
             Box::pin(tokio02_spawn(
                 sender.clone(),
-                params
-                    .parse()
-                    .into_future()
-                    .and_then(move |params| me.reassign_handler(params))
+                me.clone()
+                    .audited("subgraph_pin", params, meta, move |params| {
+                        params
+                            .parse()
+                            .into_future()
+                            .and_then(move |params| me.pin_handler(params))
+                    })
                     .compat(),
             ))
             .compat()
         });
 
-        ServerBuilder::new(handler)
-            // Enable REST API:
-            // POST /<method>/<param1>/<param2>
-            .rest_api(RestApi::Secure)
-            .start_http(&addr.into())
+        ServerBuilder::with_meta_extractor(handler, |request: &hyper::Request<hyper::Body>| {
+            admin_meta(
+                request
+                    .headers()
+                    .get(hyper::header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok()),
+            )
+        })
+        // Enable REST API:
+        // POST /<method>/<param1>/<param2>
+        .rest_api(RestApi::Secure)
+        .start_http(&addr.into())
     }
 }
 
@@ -350,3 +1644,201 @@ fn subgraph_routes(name: &SubgraphName, http_port: u16, ws_port: u16) -> Value {
     );
     jsonrpc_core::to_value(map).unwrap()
 }
+
+/// Whether a subgraph can be deployed, and the problems that validating it
+/// found
+fn subgraph_validation(validation: SubgraphValidation) -> Value {
+    let mut map = BTreeMap::new();
+    map.insert("valid", Value::Bool(validation.is_valid()));
+    map.insert(
+        "network",
+        validation.network.map_or(Value::Null, Value::String),
+    );
+    map.insert("errors", jsonrpc_core::to_value(validation.errors).unwrap());
+    map.insert(
+        "warnings",
+        jsonrpc_core::to_value(validation.warnings).unwrap(),
+    );
+    jsonrpc_core::to_value(map).unwrap()
+}
+
+/// The indexes that were created, and those that could not be created
+/// together with the reason
+fn index_creations(indexes: Vec<IndexCreation>) -> Value {
+    let (created, failed): (Vec<_>, Vec<_>) =
+        indexes.into_iter().partition(|index| index.error.is_none());
+    let failed: Vec<_> = failed
+        .into_iter()
+        .map(|index| {
+            let mut map = BTreeMap::new();
+            map.insert("name", index.name);
+            map.insert("error", index.error.unwrap_or_default());
+            map
+        })
+        .collect();
+
+    let mut map = BTreeMap::new();
+    map.insert(
+        "created",
+        jsonrpc_core::to_value(
+            created
+                .into_iter()
+                .map(|index| index.name)
+                .collect::<Vec<_>>(),
+        )
+        .unwrap(),
+    );
+    map.insert("failed", jsonrpc_core::to_value(failed).unwrap());
+    jsonrpc_core::to_value(map).unwrap()
+}
+
+/// The entity types with IDs longer than `max_length`, and how many such
+/// IDs each of them has
+fn oversized_entity_ids(max_length: usize, oversized: Vec<OversizedEntityIds>) -> Value {
+    let entity_types: Vec<_> = oversized
+        .into_iter()
+        .map(|oversized| {
+            let mut map = BTreeMap::new();
+            map.insert(
+                "entityType",
+                jsonrpc_core::to_value(oversized.entity_type).unwrap(),
+            );
+            map.insert("count", jsonrpc_core::to_value(oversized.count).unwrap());
+            map.insert(
+                "longest",
+                jsonrpc_core::to_value(oversized.longest).unwrap(),
+            );
+            map
+        })
+        .collect();
+
+    let mut map = BTreeMap::new();
+    map.insert("maxLength", jsonrpc_core::to_value(max_length).unwrap());
+    map.insert("entityTypes", jsonrpc_core::to_value(entity_types).unwrap());
+    jsonrpc_core::to_value(map).unwrap()
+}
+
+/// The deployments that were moved by a rebalance
+fn assignment_moves(moves: Vec<AssignmentMove>) -> Value {
+    let moves: Vec<_> = moves
+        .into_iter()
+        .map(|mv| {
+            let mut map = BTreeMap::new();
+            map.insert("deployment", mv.deployment.to_string());
+            map.insert("from", mv.from.to_string());
+            map.insert("to", mv.to.to_string());
+            map
+        })
+        .collect();
+    jsonrpc_core::to_value(moves).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::mock::MockSubgraphRegistrar;
+    use std::sync::Mutex;
+
+    struct NoSimulator;
+
+    impl HandlerSimulator for NoSimulator {
+        fn simulate(
+            &self,
+            _: HandlerSimulation,
+        ) -> Box<dyn Future<Item = SimulationResult, Error = Error> + Send> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn admin_operations_are_recorded_with_their_actor() {
+        // This is the only test that reads the admin tokens, so setting
+        // them here takes effect before they are first loaded
+        env::set_var("GRAPH_ADMIN_TOKENS", "alice:secret1,bob:secret2");
+
+        let recorded = Arc::new(Mutex::new(vec![]));
+        let mut registrar = MockSubgraphRegistrar::new();
+        let log = recorded.clone();
+        registrar.expect_record_admin_operation().returning(
+            move |operation, actor, parameters, error| {
+                log.lock()
+                    .unwrap()
+                    .push((operation, actor, parameters, error));
+                Box::new(future::ok(()))
+            },
+        );
+        let server = JsonRpcServer {
+            registrar: Arc::new(registrar),
+            simulator: Arc::new(NoSimulator),
+            http_port: 8000,
+            ws_port: 8001,
+            node_id: NodeId::new("test").unwrap(),
+            logger: Logger::root(slog::Discard, o!()),
+        };
+        let params =
+            || serde_json::from_value::<Params>(serde_json::json!({ "name": "example" })).unwrap();
+
+        let result = server
+            .audited(
+                "subgraph_create",
+                params(),
+                admin_meta(Some("Bearer secret1")),
+                |_| future::ok(Value::Null),
+            )
+            .wait();
+        assert_eq!(Ok(Value::Null), result);
+
+        let result = server
+            .audited(
+                "subgraph_remove",
+                params(),
+                admin_meta(Some("Bearer secret2")),
+                |_| {
+                    future::err(json_rpc_error(
+                        JSON_RPC_REMOVE_ERROR,
+                        "subgraph not found".to_owned(),
+                    ))
+                },
+            )
+            .wait();
+        assert!(result.is_err());
+
+        // Requests without a valid token are rejected before they run, and
+        // are not recorded
+        let result = server
+            .audited(
+                "subgraph_remove",
+                params(),
+                admin_meta(Some("Bearer alice")),
+                |_| -> future::FutureResult<Value, jsonrpc_core::Error> {
+                    panic!("unauthorized request was run")
+                },
+            )
+            .wait();
+        assert_eq!(
+            Some(jsonrpc_core::ErrorCode::ServerError(
+                JSON_RPC_UNAUTHORIZED_ERROR
+            )),
+            result.err().map(|e| e.code)
+        );
+
+        let parameters = r#"{"name":"example"}"#.to_owned();
+        assert_eq!(
+            vec![
+                (
+                    "subgraph_create".to_owned(),
+                    Some("alice".to_owned()),
+                    parameters.clone(),
+                    None
+                ),
+                (
+                    "subgraph_remove".to_owned(),
+                    Some("bob".to_owned()),
+                    parameters,
+                    Some("subgraph not found".to_owned())
+                ),
+            ],
+            *recorded.lock().unwrap()
+        );
+    }
+}
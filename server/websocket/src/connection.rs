@@ -10,8 +10,10 @@ use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
 use tokio_tungstenite::WebSocketStream;
 use uuid::Uuid;
 
+use graph::components::server::query::GraphQLServerError;
 use graph::prelude::serde_json;
 use graph::prelude::*;
+use graph_server_http::{FailedDeployment, FailedDeployments};
 
 lazy_static! {
     static ref MAX_OPERATIONS_PER_CONNECTION: Option<usize> =
@@ -156,19 +158,47 @@ impl Drop for Operations {
     }
 }
 
+/// The deployment a connection queries, and how to find out whether it
+/// has failed
+struct Deployment<St> {
+    id: SubgraphDeploymentId,
+    store: Arc<St>,
+    failed_deployments: Arc<FailedDeployments>,
+}
+
+impl<St: Store> Deployment<St> {
+    fn check(&self) -> Result<Option<FailedDeployment>, GraphQLServerError> {
+        self.failed_deployments.check(self.store.as_ref(), &self.id)
+    }
+
+    /// The message for `result` of operation `id`; results of a failed
+    /// deployment say that their data is not current
+    fn result_message(&self, id: String, mut result: QueryResult) -> OutgoingMessage {
+        match self.check() {
+            Ok(failed) => {
+                result.extensions = failed.as_ref().map(FailedDeployment::extensions);
+                OutgoingMessage::from_query_result(id, result)
+            }
+            Err(e) => OutgoingMessage::from_error_string(id, e.to_string()),
+        }
+    }
+}
+
 /// A WebSocket connection implementing the GraphQL over WebSocket protocol.
-pub struct GraphQlConnection<Q, S> {
+pub struct GraphQlConnection<Q, S, St> {
     id: String,
     logger: Logger,
     graphql_runner: Arc<Q>,
     stream: WebSocketStream<S>,
     schema: Arc<Schema>,
+    deployment: Arc<Deployment<St>>,
 }
 
-impl<Q, S> GraphQlConnection<Q, S>
+impl<Q, S, St> GraphQlConnection<Q, S, St>
 where
     Q: GraphQlRunner,
     S: tokio_io::AsyncRead + tokio_io::AsyncWrite + Send + 'static,
+    St: Store,
 {
     /// Creates a new GraphQL subscription service.
     pub(crate) fn new(
@@ -176,6 +206,9 @@ where
         schema: Arc<Schema>,
         stream: WebSocketStream<S>,
         graphql_runner: Arc<Q>,
+        deployment: SubgraphDeploymentId,
+        store: Arc<St>,
+        failed_deployments: Arc<FailedDeployments>,
     ) -> Self {
         GraphQlConnection {
             id: Uuid::new_v4().to_string(),
@@ -183,6 +216,11 @@ where
             graphql_runner,
             stream,
             schema,
+            deployment: Arc::new(Deployment {
+                id: deployment,
+                store,
+                failed_deployments,
+            }),
         }
     }
 
@@ -193,6 +231,7 @@ where
         connection_id: String,
         schema: Arc<Schema>,
         graphql_runner: Arc<Q>,
+        deployment: Arc<Deployment<St>>,
     ) -> impl Future<Item = (), Error = WsError> {
         let mut operations = Operations::new(msg_sink.clone());
 
@@ -251,6 +290,12 @@ where
                         }
                     }
 
+                    // Respond with a GQL_ERROR if the deployment has failed and
+                    // queries to failed deployments are rejected
+                    if let Err(e) = deployment.check() {
+                        return send_error_string(&msg_sink, id.clone(), e.to_string());
+                    }
+
                     // Parse the GraphQL query document; respond with a GQL_ERROR if
                     // the query is invalid
                     let query = match parse_query(&payload.query) {
@@ -309,6 +354,7 @@ where
                     let err_id = id.clone();
                     let err_connection_id = connection_id.clone();
                     let err_logger = logger.clone();
+                    let result_deployment = deployment.clone();
                     let run_subscription = graphql_runner
                         .run_subscription(subscription)
                         .map_err(move |e| {
@@ -331,7 +377,7 @@ where
                             // Send results back to the client as GQL_DATA
                             result_stream
                                 .map(move |result| {
-                                    OutgoingMessage::from_query_result(result_id.clone(), result)
+                                    result_deployment.result_message(result_id.clone(), result)
                                 })
                                 .map(WsMessage::from)
                                 .forward(result_sink.sink_map_err(|_| ()))
@@ -358,10 +404,11 @@ where
     }
 }
 
-impl<Q, S> IntoFuture for GraphQlConnection<Q, S>
+impl<Q, S, St> IntoFuture for GraphQlConnection<Q, S, St>
 where
     Q: GraphQlRunner,
     S: tokio_io::AsyncRead + tokio_io::AsyncWrite + Send + 'static,
+    St: Store,
 {
     type Future = Box<dyn Future<Item = Self::Item, Error = Self::Error> + Send>;
     type Item = ();
@@ -384,6 +431,7 @@ where
             self.id.clone(),
             self.schema.clone(),
             self.graphql_runner.clone(),
+            self.deployment.clone(),
         );
 
         // Send outgoing messages asynchronously
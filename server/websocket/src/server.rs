@@ -1,5 +1,6 @@
 use graph::data::subgraph::schema::SUBGRAPHS_ID;
 use graph::prelude::{SubscriptionServer as SubscriptionServerTrait, *};
+use graph_server_http::FailedDeployments;
 use http::Uri;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Mutex;
@@ -14,6 +15,7 @@ pub struct SubscriptionServer<Q, S> {
     logger: Logger,
     graphql_runner: Arc<Q>,
     store: Arc<S>,
+    failed_deployments: Arc<FailedDeployments>,
 }
 
 impl<Q, S> SubscriptionServer<Q, S>
@@ -26,6 +28,7 @@ where
             logger: logger.new(o!("component" => "SubscriptionServer")),
             graphql_runner,
             store,
+            failed_deployments: Arc::new(FailedDeployments::default()),
         }
     }
 
@@ -91,6 +94,7 @@ where
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
         let graphql_runner = self.graphql_runner.clone();
         let store = self.store.clone();
+        let failed_deployments = self.failed_deployments.clone();
 
         let socket = TcpListener::bind(&addr).expect("Failed to bind WebSocket port");
 
@@ -105,6 +109,7 @@ where
                 let graphql_runner = graphql_runner.clone();
                 let store = store.clone();
                 let store2 = store.clone();
+                let failed_deployments = failed_deployments.clone();
 
                 // Subgraph that the request is resolved to (if any)
                 let subgraph_id = Arc::new(Mutex::new(None));
@@ -189,6 +194,9 @@ where
                                 schema,
                                 ws_stream,
                                 graphql_runner.clone(),
+                                subgraph_id,
+                                store2.clone(),
+                                failed_deployments.clone(),
                             );
 
                             // Blocking due to store interactions. Won't be blocking after #905.
@@ -0,0 +1,6 @@
+-- Compressed blocks can not be converted back in SQL; since this is only a
+-- cache, it is safe to drop them
+delete from ethereum_blocks where data is null;
+alter table ethereum_blocks drop constraint ethereum_blocks_data_check;
+alter table ethereum_blocks drop column compressed_data;
+alter table ethereum_blocks alter column data set not null;
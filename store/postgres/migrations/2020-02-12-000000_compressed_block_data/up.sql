@@ -0,0 +1,7 @@
+-- Blocks can be stored either as JSONB in `data` or as compressed CBOR in
+-- `compressed_data`
+alter table ethereum_blocks alter column data drop not null;
+alter table ethereum_blocks add column compressed_data bytea;
+alter table ethereum_blocks
+  add constraint ethereum_blocks_data_check
+  check (data is not null or compressed_data is not null);
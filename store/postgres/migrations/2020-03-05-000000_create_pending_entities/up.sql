@@ -0,0 +1,12 @@
+-- The changes that the pending block on top of a deployment's latest
+-- block makes to the deployment's entities. They are kept apart from the
+-- entities so that they never become part of the deployment's history.
+-- `data` is null for entities that the pending block removes
+create table pending_entities (
+  subgraph     varchar not null,
+  entity       varchar not null,
+  entity_id    varchar not null,
+  data         jsonb,
+  block_number bigint not null,
+  primary key (subgraph, entity, entity_id)
+);
@@ -0,0 +1 @@
+drop table proof_of_indexing;
@@ -0,0 +1,11 @@
+-- The proof of indexing of each block that a deployment processed: a
+-- digest of all the changes the deployment made to its entities up to and
+-- including the block. Each digest is computed from the digest of the
+-- deployment's previous block and the changes of the block
+create table proof_of_indexing (
+  subgraph     varchar not null,
+  block_number bigint not null,
+  block_hash   varchar not null,
+  digest       bytea not null,
+  primary key (subgraph, block_number)
+);
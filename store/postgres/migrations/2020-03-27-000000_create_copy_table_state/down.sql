@@ -0,0 +1 @@
+drop table copy_table_state;
@@ -0,0 +1,14 @@
+-- How far copying the data of the base of a grafted deployment into the
+-- deployment has come. There is one row for each table that is copied;
+-- tables are copied in batches of versions ordered by their `vid` in the
+-- base, and `next_vid` is the first `vid` that has not been copied yet.
+-- The rows of a deployment are removed once all its tables are copied
+create table copy_table_state (
+  subgraph     varchar not null,
+  entity_type  varchar not null,
+  base         varchar not null,
+  block_number bigint not null,
+  next_vid     bigint not null,
+  finished     boolean not null,
+  primary key (subgraph, entity_type)
+);
@@ -0,0 +1,6 @@
+alter table deployment_schemas
+  drop column migration_requested,
+  drop column migration_block_hash,
+  drop column migration_start_block,
+  drop column migration_entity_type,
+  drop column migration_last_id;
@@ -0,0 +1,10 @@
+-- Whether an operator asked for the deployment to be migrated from JSONB
+-- storage to relational storage, and how far the migration has come. The
+-- migration is performed by the node that the deployment is assigned to;
+-- see store/postgres/src/entities.rs
+alter table deployment_schemas
+  add column migration_requested boolean not null default false,
+  add column migration_block_hash varchar,
+  add column migration_start_block integer,
+  add column migration_entity_type varchar,
+  add column migration_last_id varchar;
@@ -0,0 +1 @@
+drop table entity_type_update;
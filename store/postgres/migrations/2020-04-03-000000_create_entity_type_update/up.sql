@@ -0,0 +1,10 @@
+-- The blocks in which a deployment changed entities of each of its types
+create table entity_type_update (
+  subgraph     varchar not null,
+  entity_type  varchar not null,
+  block_number bigint not null,
+  primary key (subgraph, entity_type, block_number)
+);
+
+-- These blocks used to be kept in metadata entities
+delete from subgraphs.entities where entity = 'SubgraphEntityTypeUpdate';
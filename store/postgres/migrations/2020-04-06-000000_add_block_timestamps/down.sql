@@ -0,0 +1,2 @@
+alter table ethereum_blocks
+  drop column "timestamp";
@@ -0,0 +1,6 @@
+-- The timestamp of each block in seconds since the epoch, so that it can
+-- be looked up without decoding the block. Blocks that were cached before
+-- this column existed get their timestamp the first time it is looked up;
+-- see `block_timestamps` in store/postgres/src/store.rs
+alter table ethereum_blocks
+  add column "timestamp" bigint;
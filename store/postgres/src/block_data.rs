@@ -0,0 +1,92 @@
+//! Encoding of the block data in the `ethereum_blocks` table. Blocks are
+//! either stored as JSONB in the `data` column, or as zstd-compressed CBOR
+//! in the `compressed_data` column. Which format is used for writing is
+//! controlled by `GRAPH_CHAIN_STORE_BLOCK_FORMAT`; blocks in either format
+//! can always be read, so that switching formats does not require
+//! rewriting the blocks that are already cached.
+use lazy_static::lazy_static;
+
+use graph::prelude::{format_err, serde_json, Error, EthereumBlock};
+
+/// The zstd compression level. Levels above 3 cost noticeably more CPU
+/// without making blocks much smaller
+const COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BlockFormat {
+    Json,
+    Compressed,
+}
+
+lazy_static! {
+    /// The format in which to write blocks to the chain store
+    static ref BLOCK_FORMAT: BlockFormat =
+        match std::env::var("GRAPH_CHAIN_STORE_BLOCK_FORMAT")
+            .unwrap_or("json".into())
+            .as_str()
+        {
+            "json" => BlockFormat::Json,
+            "compressed" => BlockFormat::Compressed,
+            format => panic!(
+                "invalid GRAPH_CHAIN_STORE_BLOCK_FORMAT `{}`, \
+                 must be either `json` or `compressed`",
+                format
+            ),
+        };
+}
+
+/// The values for the `data` and `compressed_data` columns of a block.
+/// Exactly one of them is set.
+pub(crate) type BlockData = (Option<serde_json::Value>, Option<Vec<u8>>);
+
+pub(crate) fn encode(block: &EthereumBlock) -> Result<BlockData, Error> {
+    encode_as(*BLOCK_FORMAT, block)
+}
+
+fn encode_as(format: BlockFormat, block: &EthereumBlock) -> Result<BlockData, Error> {
+    match format {
+        BlockFormat::Json => Ok((Some(serde_json::to_value(block)?), None)),
+        BlockFormat::Compressed => {
+            let cbor = serde_cbor::to_vec(block)?;
+            Ok((
+                None,
+                Some(zstd::encode_all(cbor.as_slice(), COMPRESSION_LEVEL)?),
+            ))
+        }
+    }
+}
+
+pub(crate) fn decode(data: BlockData) -> Result<EthereumBlock, Error> {
+    match data {
+        (_, Some(compressed)) => {
+            let cbor = zstd::decode_all(compressed.as_slice())?;
+            Ok(serde_cbor::from_slice(&cbor)?)
+        }
+        (Some(json), None) => Ok(serde_json::from_value(json)?),
+        (None, None) => Err(format_err!("block without data in the chain store")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::prelude::{web3::types::H256, LightEthereumBlock};
+
+    #[test]
+    fn roundtrip() {
+        let block = EthereumBlock {
+            block: LightEthereumBlock {
+                hash: Some(H256::from_low_u64_be(7)),
+                ..Default::default()
+            },
+            transaction_receipts: vec![],
+        };
+
+        for format in &[BlockFormat::Json, BlockFormat::Compressed] {
+            let data = encode_as(*format, &block).unwrap();
+            assert_eq!(data.0.is_some(), *format == BlockFormat::Json);
+            assert_eq!(data.1.is_some(), *format == BlockFormat::Compressed);
+            assert_eq!(block, decode(data).unwrap());
+        }
+    }
+}
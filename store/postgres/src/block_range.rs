@@ -10,6 +10,7 @@ use std::ops::{Bound, RangeBounds, RangeFrom};
 use graph::prelude::BlockNumber;
 
 use crate::history_event::HistoryEvent;
+use crate::relational::Table;
 
 /// The name of the column in which we store the block range
 pub(crate) const BLOCK_RANGE_COLUMN: &str = "block_range";
@@ -21,6 +22,10 @@ pub(crate) const BLOCK_RANGE_COLUMN: &str = "block_range";
 /// in the range
 pub(crate) const BLOCK_RANGE_CURRENT: &str = "block_range @> 2147483647";
 
+/// The SQL expression for the block at which an entity version ended,
+/// where i32::MAX stands for versions that are current
+pub(crate) const BLOCK_RANGE_UPPER: &str = "coalesce(upper(block_range), 2147483647)";
+
 /// The range of blocks for which an entity is valid. We need this struct
 /// to bind ranges into Diesel queries.
 #[derive(Clone, Debug)]
@@ -74,9 +79,12 @@ impl ToSql<Range<Integer>, Pg> for BlockRange {
 }
 
 /// Generate the clause that checks whether `block` is in the block range
-/// of an entity
+/// of an entity. If `table` is partitioned by block range, the clause also
+/// spells out the restriction on `lower(block_range)` that Postgres needs
+/// to prune partitions that can not contain matching versions
 #[derive(Constructor)]
 pub struct BlockRangeContainsClause<'a> {
+    table: &'a Table,
     table_prefix: &'a str,
     block: BlockNumber,
 }
@@ -88,6 +96,14 @@ impl<'a> QueryFragment<Pg> for BlockRangeContainsClause<'a> {
         out.push_sql(self.table_prefix);
         out.push_identifier(BLOCK_RANGE_COLUMN)?;
         out.push_sql(" @> ");
-        out.push_bind_param::<Integer, _>(&self.block)
+        out.push_bind_param::<Integer, _>(&self.block)?;
+        if self.table.is_partitioned_by_block() {
+            out.push_sql(" and lower(");
+            out.push_sql(self.table_prefix);
+            out.push_identifier(BLOCK_RANGE_COLUMN)?;
+            out.push_sql(") <= ");
+            out.push_bind_param::<Integer, _>(&self.block)?;
+        }
+        Ok(())
     }
 }
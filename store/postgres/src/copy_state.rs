@@ -0,0 +1,128 @@
+//! Copying the data of the graft base of a deployment can take hours for
+//! large bases. Tables are therefore copied in batches, each in its own
+//! transaction, and `copy_table_state` records for every table how far
+//! copying it has come, so that a copy that was interrupted resumes with
+//! the first batch that was not copied yet instead of starting over.
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{delete, insert_into, update};
+
+use graph::prelude::{BlockNumber, StoreError, SubgraphDeploymentId};
+
+use crate::db_schema::copy_table_state as c;
+
+/// Record that the tables for `entity_types` of `subgraph` are copied from
+/// `base` at `block`, unless that was recorded before, and return the
+/// entity types whose tables still need to be copied. Fails if an earlier
+/// copy of `subgraph` used a different base or block
+pub(crate) fn start(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    base: &SubgraphDeploymentId,
+    block: BlockNumber,
+    entity_types: &[String],
+) -> Result<Vec<String>, StoreError> {
+    let earlier = c::table
+        .filter(c::subgraph.eq(subgraph.as_str()))
+        .filter(
+            c::base
+                .ne(base.as_str())
+                .or(c::block_number.ne(block as i64)),
+        )
+        .select((c::base, c::block_number))
+        .first::<(String, i64)>(conn)
+        .optional()?;
+    if let Some((earlier_base, earlier_block)) = earlier {
+        return Err(StoreError::QueryExecutionError(format!(
+            "deployment {} can not copy data from {} at block {} since it \
+             started copying data from {} at block {}",
+            subgraph, base, block, earlier_base, earlier_block
+        )));
+    }
+
+    let rows = entity_types
+        .iter()
+        .map(|entity_type| {
+            (
+                c::subgraph.eq(subgraph.as_str()),
+                c::entity_type.eq(entity_type.as_str()),
+                c::base.eq(base.as_str()),
+                c::block_number.eq(block as i64),
+                c::next_vid.eq(0),
+                c::finished.eq(false),
+            )
+        })
+        .collect::<Vec<_>>();
+    if !rows.is_empty() {
+        insert_into(c::table)
+            .values(rows)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+    }
+
+    unfinished(conn, subgraph)
+}
+
+/// The first `vid` of the table for `entity_type` of `subgraph` that has
+/// not been copied yet, or `None` if the table has been copied completely
+pub(crate) fn next_vid(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    entity_type: &str,
+) -> Result<Option<i64>, StoreError> {
+    let (next_vid, finished) = c::table
+        .filter(c::subgraph.eq(subgraph.as_str()))
+        .filter(c::entity_type.eq(entity_type))
+        .select((c::next_vid, c::finished))
+        .first::<(i64, bool)>(conn)
+        .optional()?
+        .ok_or_else(|| {
+            StoreError::QueryExecutionError(format!(
+                "deployment {} is not copying the table for {}",
+                subgraph, entity_type
+            ))
+        })?;
+    Ok(if finished { None } else { Some(next_vid) })
+}
+
+/// Record that all versions before `next_vid` of the table for
+/// `entity_type` of `subgraph` have been copied, or, if `next_vid` is
+/// `None`, that the table has been copied completely
+pub(crate) fn advance(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    entity_type: &str,
+    next_vid: Option<i64>,
+) -> Result<(), StoreError> {
+    let row = c::table
+        .filter(c::subgraph.eq(subgraph.as_str()))
+        .filter(c::entity_type.eq(entity_type));
+    match next_vid {
+        Some(next_vid) => update(row).set(c::next_vid.eq(next_vid)).execute(conn)?,
+        None => update(row).set(c::finished.eq(true)).execute(conn)?,
+    };
+    Ok(())
+}
+
+/// The entity types whose tables of `subgraph` have not been copied
+/// completely yet
+pub(crate) fn unfinished(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<Vec<String>, StoreError> {
+    Ok(c::table
+        .filter(c::subgraph.eq(subgraph.as_str()))
+        .filter(c::finished.eq(false))
+        .order(c::entity_type)
+        .select(c::entity_type)
+        .load::<String>(conn)?)
+}
+
+/// Forget how copying the tables of `subgraph` went
+pub(crate) fn clear(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<(), StoreError> {
+    delete(c::table.filter(c::subgraph.eq(subgraph.as_str()))).execute(conn)?;
+    Ok(())
+}
@@ -14,7 +14,9 @@ table! {
         number -> BigInt,
         parent_hash -> Nullable<Varchar>,
         network_name -> Varchar, // REFERENCES ethereum_networks (name),
-        data -> Jsonb,
+        data -> Nullable<Jsonb>,
+        compressed_data -> Nullable<Bytea>,
+        timestamp -> Nullable<BigInt>,
     }
 }
 
@@ -53,3 +55,46 @@ table! {
 
 joinable!(eth_call_cache -> eth_call_meta (contract_address));
 allow_tables_to_appear_in_same_query!(eth_call_cache, eth_call_meta);
+
+table! {
+    /// The proof of indexing of each block that a deployment processed
+    proof_of_indexing (subgraph, block_number) {
+        subgraph -> Varchar,
+        block_number -> BigInt,
+        block_hash -> Varchar,
+        digest -> Bytea,
+    }
+}
+
+table! {
+    /// The changes of the pending block of a deployment; `data` is null
+    /// for removed entities
+    pending_entities (subgraph, entity, entity_id) {
+        subgraph -> Varchar,
+        entity -> Varchar,
+        entity_id -> Varchar,
+        data -> Nullable<Jsonb>,
+        block_number -> BigInt,
+    }
+}
+
+table! {
+    /// The blocks in which a deployment changed entities of each type
+    entity_type_update (subgraph, entity_type, block_number) {
+        subgraph -> Varchar,
+        entity_type -> Varchar,
+        block_number -> BigInt,
+    }
+}
+
+table! {
+    /// How far copying each table of the graft base of a deployment has come
+    copy_table_state (subgraph, entity_type) {
+        subgraph -> Varchar,
+        entity_type -> Varchar,
+        base -> Varchar,
+        block_number -> BigInt,
+        next_vid -> BigInt,
+        finished -> Bool,
+    }
+}
@@ -25,7 +25,7 @@ use diesel::deserialize::QueryableByName;
 use diesel::dsl::any;
 use diesel::pg::{Pg, PgConnection};
 use diesel::r2d2::{ConnectionManager, PooledConnection};
-use diesel::sql_types::{Integer, Jsonb, Nullable, Text};
+use diesel::sql_types::{Array, BigInt, Integer, Jsonb, Nullable, Text};
 use diesel::BoolExpressionMethods;
 use diesel::Connection as _;
 use diesel::ExpressionMethods;
@@ -33,28 +33,33 @@ use diesel::{OptionalExtension, QueryDsl, RunQueryDsl};
 use inflector::cases::snakecase::to_snake_case;
 use lazy_static::lazy_static;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryInto;
 use std::hash::{Hash, Hasher};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 use graph::data::schema::Schema as SubgraphSchema;
-use graph::data::subgraph::schema::SUBGRAPHS_ID;
+use graph::data::subgraph::schema::{SubgraphDeploymentEntity, TypedEntity, SUBGRAPHS_ID};
 use graph::prelude::{
-    debug, format_err, info, serde_json, warn, AttributeIndexDefinition, BlockNumber, Entity,
-    EntityChange, EntityChangeOperation, EntityCollection, EntityFilter, EntityKey,
-    EntityModification, EntityOrder, EntityRange, Error, EthereumBlockPointer, Logger,
-    QueryExecutionError, StoreError, StoreEvent, SubgraphDeploymentId, SubgraphDeploymentStore,
-    ValueType, BLOCK_NUMBER_MAX,
+    debug, format_err, info, serde_json, warn, web3::types::H256, AttributeIndexDefinition,
+    BlockEntityChange, BlockNumber, Entity, EntityAggregation, EntityChange, EntityChangeOperation,
+    EntityCollection, EntityDiff, EntityFilter, EntityKey, EntityModification, EntityOrder,
+    EntityRange, Error, EthereumBlockPointer, IndexCreation, Logger, NullsOrder,
+    OversizedEntityIds, ProofOfIndexing, QueryExecutionError, StoreError, StoreEvent,
+    SubgraphDeploymentId, SubgraphDeploymentStore, Value, ValueType, BLOCK_NUMBER_MAX,
 };
 
 use crate::block_range::block_number;
+use crate::copy_state;
+use crate::entity_type_update;
 use crate::history_event::HistoryEvent;
 use crate::jsonb::PgJsonbExpressionMethods as _;
 use crate::jsonb_queries::FilterQuery;
 use crate::notification_listener::JsonNotification;
-use crate::relational::{IdType, Layout};
+use crate::pending::{self, PendingEntities};
+use crate::proof_of_indexing;
+use crate::relational::{IdType, Layout, Partitioning};
 use crate::store::Store;
 
 lazy_static! {
@@ -74,6 +79,16 @@ lazy_static! {
                  either `relational` or `json`")
         }
     };
+
+    /// For how many blocks before the block a subgraph has processed its
+    /// history is kept when it is migrated from JSONB storage to relational
+    /// storage. This should be at least `ETHEREUM_REORG_THRESHOLD` so that
+    /// the subgraph can still be reverted after the migration
+    static ref STORAGE_MIGRATION_HISTORY: BlockNumber =
+        std::env::var("GRAPH_STORAGE_MIGRATION_HISTORY")
+            .unwrap_or("50".into())
+            .parse::<BlockNumber>()
+            .expect("invalid GRAPH_STORAGE_MIGRATION_HISTORY");
 }
 
 /// The size of string prefixes that we index. This is chosen so that we
@@ -178,6 +193,19 @@ mod public {
             migrating -> Bool,
             /// Track which step of a subgraph migration has been done
             state -> crate::entities::public::DeploymentSchemaStateMapping,
+            /// Whether an operator asked for this subgraph to be migrated
+            /// from JSONB storage to relational storage
+            migration_requested -> Bool,
+            /// The block the subgraph had processed when its tables for
+            /// relational storage were created
+            migration_block_hash -> Nullable<Text>,
+            /// The earliest block whose entities are copied during the
+            /// migration to relational storage
+            migration_start_block -> Nullable<Integer>,
+            /// The entity type and id of the last entity that was copied
+            /// during the migration to relational storage
+            migration_entity_type -> Nullable<Text>,
+            migration_last_id -> Nullable<Text>,
         }
     }
 }
@@ -275,6 +303,15 @@ struct Schema {
     /// locks. When the data is in place, the migration updates `version` to
     /// the new version we migrated to, and sets the state to `Ready`
     state: public::DeploymentSchemaState,
+    /// True if an operator asked for the subgraph to be migrated from
+    /// JSONB storage to relational storage with `request_migration`
+    migration_requested: bool,
+    /// How far the migration to relational storage has come; see
+    /// `Connection::migrate_storage_step`
+    migration_block_hash: Option<String>,
+    migration_start_block: Option<i32>,
+    migration_entity_type: Option<String>,
+    migration_last_id: Option<String>,
 }
 
 /// Storage using JSONB for entities. All entities are stored in one table
@@ -291,6 +328,8 @@ pub(crate) struct JsonStorage {
     event_source: EntityColumn<diesel::sql_types::Text>,
     // The query to count all entities
     count_query: String,
+    /// Whether the subgraph should be migrated to relational storage
+    migration_requested: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -306,6 +345,14 @@ impl Storage {
             Storage::Relational(layout) => &layout.subgraph,
         }
     }
+
+    /// The name of the database schema that holds the subgraph's tables
+    fn schema(&self) -> &str {
+        match self {
+            Storage::Json(json) => &json.schema,
+            Storage::Relational(layout) => &layout.schema,
+        }
+    }
 }
 
 /// Helper struct to support a custom query for entity history
@@ -343,6 +390,26 @@ pub(crate) fn make_storage_cache() -> StorageCache {
     Mutex::new(HashMap::new())
 }
 
+/// Remove the storage of subgraphs from `cache` whose deployment changed
+/// in `event`, since their storage might have changed, too
+pub(crate) fn invalidate_storage_cache(cache: &StorageCache, event: &StoreEvent) {
+    let ids = event
+        .changes
+        .iter()
+        .filter(|change| {
+            change.subgraph_id == *SUBGRAPHS_ID
+                && change.entity_type == SubgraphDeploymentEntity::TYPENAME
+        })
+        .filter_map(|change| SubgraphDeploymentId::new(change.entity_id.clone()).ok())
+        .collect::<Vec<_>>();
+    if !ids.is_empty() {
+        let mut cache = cache.lock().unwrap();
+        for id in ids {
+            cache.remove(&id);
+        }
+    }
+}
+
 /// A connection into the database to handle entities. The connection is
 /// specific to one subgraph, and can only handle entities from that subgraph
 /// or from the metadata subgraph. Attempts to access other subgraphs will
@@ -451,7 +518,7 @@ impl Connection {
         logger: &Logger,
         collection: EntityCollection,
         filter: Option<EntityFilter>,
-        order: Option<(String, ValueType, EntityOrder)>,
+        order: Option<(String, ValueType, EntityOrder, NullsOrder)>,
         range: EntityRange,
         block: BlockNumber,
     ) -> Result<Vec<Entity>, QueryExecutionError> {
@@ -475,6 +542,39 @@ impl Connection {
         }
     }
 
+    pub(crate) fn aggregate(
+        &self,
+        aggregation: EntityAggregation,
+    ) -> Result<Vec<Entity>, QueryExecutionError> {
+        match &*self.storage {
+            Storage::Json(_) => Err(QueryExecutionError::NotSupported(
+                "This subgraph uses JSONB storage, which does not \
+                 support aggregations. Redeploy a new version of this \
+                 subgraph to enable this feature."
+                    .to_owned(),
+            )),
+            Storage::Relational(layout) => layout.aggregate(&self.conn, aggregation),
+        }
+    }
+
+    /// Compute the groups of `aggregation` from `entities` without
+    /// looking at the database
+    pub(crate) fn aggregate_entities(
+        &self,
+        aggregation: &EntityAggregation,
+        entities: Vec<Entity>,
+    ) -> Result<Vec<Entity>, QueryExecutionError> {
+        match &*self.storage {
+            Storage::Json(_) => Err(QueryExecutionError::NotSupported(
+                "This subgraph uses JSONB storage, which does not \
+                 support aggregations. Redeploy a new version of this \
+                 subgraph to enable this feature."
+                    .to_owned(),
+            )),
+            Storage::Relational(layout) => layout.aggregate_entities(aggregation, entities),
+        }
+    }
+
     pub(crate) fn conflicting_entity(
         &self,
         entity_id: &String,
@@ -571,6 +671,394 @@ impl Connection {
         }
     }
 
+    /// Create the indexes that the connection's subgraph is missing. For
+    /// JSONB storage, these are the ones in `indexes`; for relational
+    /// storage, they are the indexes that its layout calls for
+    pub(crate) fn create_missing_indexes(
+        &self,
+        indexes: Vec<AttributeIndexDefinition>,
+    ) -> Result<Vec<IndexCreation>, StoreError> {
+        match &*self.storage {
+            Storage::Json(json) => json.create_missing_indexes(&self.conn, indexes),
+            Storage::Relational(layout) => layout.create_missing_indexes(&self.conn),
+        }
+    }
+
+    /// Report the entity types of the connection's subgraph that have
+    /// entities with an ID longer than `max_length` bytes
+    pub(crate) fn oversized_entity_ids(
+        &self,
+        max_length: usize,
+    ) -> Result<Vec<OversizedEntityIds>, StoreError> {
+        match &*self.storage {
+            Storage::Json(json) => json.oversized_entity_ids(&self.conn, max_length),
+            Storage::Relational(layout) => layout.oversized_entity_ids(&self.conn, max_length),
+        }
+    }
+
+    /// Create any partitions that entity tables need to hold the data for
+    /// `block_ptr` when the subgraph advances from `from`
+    pub(crate) fn ensure_partitions(
+        &self,
+        from: Option<&EthereumBlockPointer>,
+        block_ptr: &EthereumBlockPointer,
+    ) -> Result<(), StoreError> {
+        match &*self.storage {
+            Storage::Json(_) => Ok(()),
+            Storage::Relational(layout) => {
+                let from = from.map(ptr_block_number).transpose()?;
+                let block = ptr_block_number(block_ptr)?;
+                layout.ensure_partitions(&self.conn, from, block)
+            }
+        }
+    }
+
+    /// Return the changes that `block` made to the entities of the
+    /// connection's subgraph
+    pub(crate) fn entity_changes_in_block(
+        &self,
+        block: BlockNumber,
+    ) -> Result<Vec<BlockEntityChange>, StoreError> {
+        self.entity_changes_since(block - 1, block)
+    }
+
+    /// Return the changes that the blocks after `since` up to and
+    /// including `to` made to the entities of the connection's subgraph
+    pub(crate) fn entity_changes_since(
+        &self,
+        since: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<BlockEntityChange>, StoreError> {
+        match &*self.storage {
+            Storage::Json(_) => Err(StoreError::QueryExecutionError(
+                "This subgraph uses JSONB storage, which does not keep the \
+                 block ranges needed to reconstruct entity changes. Redeploy \
+                 a new version of this subgraph to enable this feature."
+                    .to_owned(),
+            )),
+            Storage::Relational(layout) => layout.entity_changes_since(&self.conn, since, to),
+        }
+    }
+
+    /// Return how the entities of type `entity_type` of the connection's
+    /// subgraph differ between blocks `from` and `to`
+    pub(crate) fn entity_diff(
+        &self,
+        entity_type: &str,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<EntityDiff>, StoreError> {
+        match &*self.storage {
+            Storage::Json(_) => Err(StoreError::QueryExecutionError(
+                "This subgraph uses JSONB storage, which does not keep the \
+                 block ranges needed to compare entities between blocks. \
+                 Redeploy a new version of this subgraph to enable this feature."
+                    .to_owned(),
+            )),
+            Storage::Relational(layout) => layout.entity_diff(&self.conn, entity_type, from, to),
+        }
+    }
+
+    /// Return the numbers of the blocks after `since` up to and including
+    /// `to` that changed entities of the connection's subgraph, but at
+    /// most `limit` of them
+    pub(crate) fn blocks_with_entity_changes(
+        &self,
+        since: BlockNumber,
+        to: BlockNumber,
+        limit: usize,
+    ) -> Result<Vec<BlockNumber>, StoreError> {
+        match &*self.storage {
+            Storage::Json(_) => Err(StoreError::QueryExecutionError(
+                "This subgraph uses JSONB storage, which does not keep the \
+                 block ranges needed to find the blocks that changed entities. \
+                 Redeploy a new version of this subgraph to enable this feature."
+                    .to_owned(),
+            )),
+            Storage::Relational(layout) => layout.blocks_with_changes(&self.conn, since, to, limit),
+        }
+    }
+
+    /// The layouts of the connection's subgraph and of `base` if both use
+    /// relational storage, which grafting requires
+    fn graft_layouts<'a>(
+        &'a self,
+        base: &'a Storage,
+    ) -> Result<(&'a Layout, &'a Layout), StoreError> {
+        match (&*self.storage, base) {
+            (Storage::Relational(layout), Storage::Relational(base)) => Ok((layout, base)),
+            _ => Err(StoreError::QueryExecutionError(
+                "Grafting is only possible when both subgraphs use relational \
+                 storage. Redeploy a new version of the base subgraph to graft \
+                 onto it."
+                    .to_owned(),
+            )),
+        }
+    }
+
+    /// Start copying the entities that the subgraph with storage `base`
+    /// had at `block` into the connection's subgraph, or resume a copy that
+    /// was interrupted, and return the entity types whose tables still need
+    /// to be copied with `copy_deployment_batch`
+    pub(crate) fn start_copy_deployment(
+        &self,
+        base: &Storage,
+        block: BlockNumber,
+    ) -> Result<Vec<String>, StoreError> {
+        let (layout, base_layout) = self.graft_layouts(base)?;
+        let entity_types = layout.copied_entity_types(base_layout)?;
+        copy_state::start(
+            &self.conn,
+            self.storage.subgraph(),
+            base.subgraph(),
+            block,
+            &entity_types,
+        )
+    }
+
+    /// Copy the next batch of at most `batch_size` versions of
+    /// `entity_type` from `base` and record how far copying the table has
+    /// come. Returns `true` once the table has been copied completely
+    pub(crate) fn copy_deployment_batch(
+        &self,
+        base: &Storage,
+        entity_type: &str,
+        block: BlockNumber,
+        batch_size: i64,
+    ) -> Result<bool, StoreError> {
+        let (layout, base_layout) = self.graft_layouts(base)?;
+        let subgraph = self.storage.subgraph();
+        let from_vid = match copy_state::next_vid(&self.conn, subgraph, entity_type)? {
+            Some(from_vid) => from_vid,
+            None => return Ok(true),
+        };
+        let next_vid = layout.copy_table_from(
+            &self.conn,
+            base_layout,
+            entity_type,
+            block,
+            from_vid,
+            batch_size,
+        )?;
+        copy_state::advance(&self.conn, subgraph, entity_type, next_vid)?;
+        Ok(next_vid.is_none())
+    }
+
+    /// Finish copying the entities of `base` once all tables have been
+    /// copied: count the copied entities and forget how copying the tables
+    /// went
+    pub(crate) fn finish_copy_deployment(&self, base: &Storage) -> Result<(), StoreError> {
+        let (layout, _) = self.graft_layouts(base)?;
+        let subgraph = self.storage.subgraph();
+        let unfinished = copy_state::unfinished(&self.conn, subgraph)?;
+        if !unfinished.is_empty() {
+            return Err(StoreError::QueryExecutionError(format!(
+                "deployment {} has not finished copying the tables for {}",
+                subgraph,
+                unfinished.join(", ")
+            )));
+        }
+        let count = layout.count_current_entities(&self.conn)?;
+        self.update_entity_count(count as i32)?;
+        copy_state::clear(&self.conn, subgraph)
+    }
+
+    /// Return the disk space used by all tables of the connection's
+    /// subgraph, including their indexes and TOAST data, in bytes
+    pub(crate) fn deployment_size(&self) -> Result<u64, StoreError> {
+        #[derive(QueryableByName)]
+        struct Size {
+            #[sql_type = "BigInt"]
+            size: i64,
+        }
+
+        let query = "select coalesce(sum(pg_total_relation_size(c.oid)), 0)::int8 as size
+                       from pg_class c, pg_namespace n
+                      where c.relnamespace = n.oid
+                        and n.nspname = $1
+                        and c.relkind = 'r'";
+        let size = diesel::sql_query(query)
+            .bind::<Text, _>(self.storage.schema())
+            .get_result::<Size>(&self.conn)?
+            .size;
+        Ok(size as u64)
+    }
+
+    /// Revert all changes that the blocks after `block` made to the
+    /// entities of the connection's subgraph
+    pub(crate) fn rewind(&self, block: BlockNumber) -> Result<(StoreEvent, i32), StoreError> {
+        match &*self.storage {
+            Storage::Json(_) => Err(StoreError::QueryExecutionError(
+                "This subgraph uses JSONB storage, which can only revert one \
+                 block at a time. Redeploy a new version of this subgraph to \
+                 enable this feature."
+                    .to_owned(),
+            )),
+            Storage::Relational(layout) => {
+                self.check_retained(block)?;
+                layout.revert_block(&self.conn, block + 1)
+            }
+        }
+    }
+
+    /// The changes of the pending block of the connection's subgraph
+    pub(crate) fn pending_entities(&self) -> Result<PendingEntities, StoreError> {
+        pending::load(&self.conn, self.storage.subgraph())
+    }
+
+    /// Sort `strings` the way the database orders strings, which depends
+    /// on its collation
+    pub(crate) fn sort_strings(&self, strings: Vec<String>) -> Result<Vec<String>, StoreError> {
+        pending::sort_strings(&self.conn, strings)
+    }
+
+    /// Replace the changes of the pending block of the connection's
+    /// subgraph with `mods`
+    pub(crate) fn replace_pending_entities(
+        &self,
+        block: EthereumBlockPointer,
+        mods: Vec<EntityModification>,
+    ) -> Result<(), StoreError> {
+        match &*self.storage {
+            Storage::Json(_) => Err(StoreError::QueryExecutionError(
+                "This subgraph uses JSONB storage, which does not support \
+                 indexing pending blocks. Redeploy a new version of this \
+                 subgraph to enable this feature."
+                    .to_owned(),
+            )),
+            Storage::Relational(_) => {
+                pending::replace(&self.conn, self.storage.subgraph(), block, mods)
+            }
+        }
+    }
+
+    /// Discard the changes of the pending block of the connection's subgraph
+    pub(crate) fn clear_pending_entities(&self) -> Result<(), StoreError> {
+        pending::clear(&self.conn, self.storage.subgraph())
+    }
+
+    /// Store the proof of indexing of the connection's subgraph for `block`,
+    /// which made the changes `mods`
+    pub(crate) fn record_proof_of_indexing(
+        &self,
+        block: &EthereumBlockPointer,
+        mods: &[EntityModification],
+    ) -> Result<(), StoreError> {
+        proof_of_indexing::record(&self.conn, self.storage.subgraph(), block, mods)
+    }
+
+    /// Remove the proofs of indexing of the connection's subgraph for
+    /// `block` and all later blocks
+    pub(crate) fn revert_proof_of_indexing(&self, block: BlockNumber) -> Result<(), StoreError> {
+        proof_of_indexing::revert(&self.conn, self.storage.subgraph(), block)
+    }
+
+    /// Whether the connection's subgraph can be rewound to `block` without
+    /// breaking the chain of its proofs of indexing
+    pub(crate) fn can_rewind_proof_of_indexing(
+        &self,
+        block: BlockNumber,
+    ) -> Result<bool, StoreError> {
+        proof_of_indexing::can_continue_from(&self.conn, self.storage.subgraph(), block)
+    }
+
+    /// Copy the proofs of indexing that `base` has up to `block` to the
+    /// connection's subgraph
+    pub(crate) fn copy_proof_of_indexing(
+        &self,
+        base: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<(), StoreError> {
+        proof_of_indexing::copy(&self.conn, base, self.storage.subgraph(), block)
+    }
+
+    /// The proof of indexing of the connection's subgraph for `block`
+    pub(crate) fn proof_of_indexing(
+        &self,
+        block: BlockNumber,
+    ) -> Result<Option<ProofOfIndexing>, StoreError> {
+        proof_of_indexing::find(&self.conn, self.storage.subgraph(), block)
+    }
+
+    /// The proofs of indexing of the connection's subgraph for the blocks
+    /// from `from` to `to`, inclusive
+    pub(crate) fn proofs_of_indexing(
+        &self,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<ProofOfIndexing>, StoreError> {
+        proof_of_indexing::find_range(&self.conn, self.storage.subgraph(), from, to)
+    }
+
+    /// Record that the connection's subgraph changed entities of
+    /// `entity_types` in `block`
+    pub(crate) fn record_entity_type_updates<'a>(
+        &self,
+        block: BlockNumber,
+        entity_types: impl Iterator<Item = &'a str>,
+    ) -> Result<(), StoreError> {
+        entity_type_update::record(&self.conn, self.storage.subgraph(), block, entity_types)
+    }
+
+    /// Copy the blocks in which `base` changed each entity type up to
+    /// `block` to the connection's subgraph
+    pub(crate) fn copy_entity_type_updates(
+        &self,
+        base: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<(), StoreError> {
+        entity_type_update::copy(&self.conn, base, self.storage.subgraph(), block)
+    }
+
+    /// For each entity type of the connection's subgraph, the latest block
+    /// up to `block` in which entities of the type changed
+    pub(crate) fn entity_type_updates(
+        &self,
+        block: Option<BlockNumber>,
+    ) -> Result<Vec<(String, BlockNumber)>, StoreError> {
+        entity_type_update::latest(&self.conn, self.storage.subgraph(), block)
+    }
+
+    /// Write the changes `mods` to annotation entities of the connection's
+    /// subgraph. Annotations are not part of the subgraph's history: every
+    /// change replaces all previous versions of the entity, and the new
+    /// version is visible at every block
+    pub(crate) fn write_annotations(
+        &self,
+        mods: Vec<EntityModification>,
+    ) -> Result<(), StoreError> {
+        let layout = match &*self.storage {
+            Storage::Json(_) => {
+                return Err(StoreError::QueryExecutionError(
+                    "This subgraph uses JSONB storage, which does not support \
+                     annotations. Redeploy a new version of this subgraph to \
+                     enable this feature."
+                        .to_owned(),
+                ))
+            }
+            Storage::Relational(layout) => layout,
+        };
+
+        let mut count = 0;
+        for modification in mods {
+            use EntityModification::*;
+
+            count += match modification {
+                Insert { key, data } | Overwrite { key, data } => {
+                    match layout.overwrite_unversioned(&self.conn, &key, Some(&data))? {
+                        0 => 1,
+                        _ => 0,
+                    }
+                }
+                Remove { key } => match layout.overwrite_unversioned(&self.conn, &key, None)? {
+                    0 => 0,
+                    _ => -1,
+                },
+            };
+        }
+        self.update_entity_count(count)
+    }
+
     pub(crate) fn revert_block(
         &self,
         block_ptr: &EthereumBlockPointer,
@@ -582,7 +1070,8 @@ impl Connection {
         let (event, count) = match &*self.storage {
             Storage::Json(json) => json.revert_block(&self.conn, block_ptr.hash_hex())?,
             Storage::Relational(layout) => {
-                let block = block_ptr.number.try_into().unwrap();
+                let block = ptr_block_number(block_ptr)?;
+                self.check_retained(block - 1)?;
                 layout.revert_block(&self.conn, block)?
             }
         };
@@ -611,6 +1100,32 @@ impl Connection {
         self.storage.update_entity_count(&self.conn, count)
     }
 
+    /// Add `writes` to the `entityWriteCount` property of the
+    /// `SubgraphDeployment` for this connection. Unlike `entityCount`, it
+    /// counts every entity that was written, including changes and
+    /// removals, and is not lowered when blocks are reverted
+    pub(crate) fn update_entity_write_count(&self, writes: i32) -> Result<(), StoreError> {
+        if writes == 0 {
+            return Ok(());
+        }
+
+        let query = "
+            update subgraphs.entities
+            set data = data || (format('{\"entityWriteCount\":
+                                  { \"data\": \"%s\",
+                                    \"type\": \"BigInt\"}}',
+                                  coalesce((data->'entityWriteCount'->>'data')::numeric, 0)
+                                    + $1))::jsonb
+            where entity='SubgraphDeployment'
+              and id = $2
+            ";
+        Ok(diesel::sql_query(query)
+            .bind::<Integer, _>(writes)
+            .bind::<Text, _>(self.storage.subgraph().to_string())
+            .execute(&self.conn)
+            .map(|_| ())?)
+    }
+
     pub(crate) fn create_history_event(
         &self,
         block_ptr: EthereumBlockPointer,
@@ -642,6 +1157,30 @@ impl Connection {
         }
     }
 
+    /// Ask for the connection's subgraph to be migrated from JSONB storage
+    /// to relational storage. The migration is performed in the background
+    /// by the node the subgraph is assigned to; see `migrate_storage_step`
+    pub(crate) fn request_migration(&self) -> Result<(), StoreError> {
+        use public::deployment_schemas as dsl;
+
+        let subgraph = self.storage.subgraph();
+        match &*self.storage {
+            Storage::Json(_) if *subgraph == *SUBGRAPHS_ID => Err(StoreError::QueryExecutionError(
+                "the subgraph of subgraphs can not be migrated".to_owned(),
+            )),
+            Storage::Json(_) => {
+                diesel::update(dsl::table.filter(dsl::subgraph.eq(subgraph.to_string())))
+                    .set(dsl::migration_requested.eq(true))
+                    .execute(&self.conn)?;
+                Ok(())
+            }
+            Storage::Relational(_) => Err(StoreError::QueryExecutionError(format!(
+                "deployment {} already uses relational storage",
+                subgraph
+            ))),
+        }
+    }
+
     /// Check if the schema for `subgraph` needs to be migrated, and if so
     /// if now (indicated by the block pointer) is the right time to do so.
     /// We try to spread the actual database work associated with checking
@@ -793,6 +1332,391 @@ impl Connection {
         })
     }
 
+    /// Perform the next step of migrating the subgraph from JSONB storage
+    /// to relational storage and return `true` once the subgraph has been
+    /// switched over. Each step is performed in its own transaction and
+    /// records how far the migration has come, so that a migration that was
+    /// interrupted continues where it left off. The subgraph must not
+    /// process blocks while it is migrated; `head` is the block it
+    /// processed last. If that changes between steps, the migration starts
+    /// over.
+    ///
+    /// The first step creates the relational tables in a separate database
+    /// schema. Each of the following steps copies all versions of at most
+    /// `batch_size` entities into them, and checks that their current
+    /// versions have the same proof of indexing as the entities they were
+    /// copied from. The last step checks that the new tables have as many
+    /// entities of each type as the old ones, drops the old database
+    /// schema, and puts the new one in its place. Queries keep using the
+    /// old database schema until then
+    pub(crate) fn migrate_storage_step(
+        &self,
+        logger: &Logger,
+        subgraph_schema: &SubgraphSchema,
+        head: &EthereumBlockPointer,
+        batch_size: i64,
+    ) -> Result<bool, StoreError> {
+        use self::public::deployment_schemas as dsl;
+        use self::public::DeploymentSchemaState as State;
+
+        let json = match &*self.storage {
+            Storage::Json(json) => json,
+            Storage::Relational(_) => return Ok(true),
+        };
+        let subgraph = &json.subgraph;
+
+        self.conn.transaction(|| -> Result<bool, StoreError> {
+            let schema = find_schema(&self.conn, subgraph)?.ok_or_else(|| {
+                StoreError::QueryExecutionError(format!(
+                    "subgraph {} has no entry in deployment_schemas and can not be migrated",
+                    subgraph
+                ))
+            })?;
+            if !schema.migration_requested {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "no migration to relational storage was requested for subgraph {}",
+                    subgraph
+                )));
+            }
+
+            debug!(
+                logger,
+                "migration step";
+                "name" => &schema.name,
+                "subgraph" => subgraph.to_string(),
+                "state" => format!("{:?}", schema.state),
+                "entity_type" => schema.migration_entity_type.as_ref().map(String::as_str),
+                "last_id" => schema.migration_last_id.as_ref().map(String::as_str),
+            );
+            let start = match (schema.state, schema.migration_start_block) {
+                (State::Tables, Some(start))
+                    if schema.migration_block_hash == Some(head.hash_hex()) =>
+                {
+                    start
+                }
+                (State::Tables, _) => {
+                    info!(logger, "Subgraph processed blocks since its migration started, starting over";
+                          "subgraph" => subgraph.to_string(),
+                          "block_number" => head.number);
+                    diesel::update(dsl::table.filter(dsl::subgraph.eq(subgraph.to_string())))
+                        .set(dsl::state.eq(State::Ready))
+                        .execute(&self.conn)?;
+                    return Ok(false);
+                }
+                (State::Ready, _) => {
+                    self.create_migration_tables(logger, json, subgraph_schema, head)?;
+                    return Ok(false);
+                }
+            };
+
+            let layout = Layout::new(
+                &subgraph_schema.document,
+                IdType::String,
+                subgraph.clone(),
+                json.migration_schema(),
+            )?;
+            let window = MigrationWindow::load(&self.conn, json, head, start)?;
+            if window.start != start {
+                info!(logger, "Blocks of the subgraph were removed from the block cache since its migration started, starting over";
+                      "subgraph" => subgraph.to_string(),
+                      "start_block" => start);
+                diesel::update(dsl::table.filter(dsl::subgraph.eq(subgraph.to_string())))
+                    .set(dsl::state.eq(State::Ready))
+                    .execute(&self.conn)?;
+                return Ok(false);
+            }
+            let position = match (schema.migration_entity_type, schema.migration_last_id) {
+                (Some(entity_type), Some(last_id)) => Some((entity_type, last_id)),
+                _ => None,
+            };
+            if self.copy_migration_batch(json, &layout, &window, head, position, batch_size)? {
+                return Ok(false);
+            }
+            self.finish_migration(json, &layout, &window)?;
+            Ok(true)
+        })
+    }
+
+    /// Create the relational tables for the subgraph in the database
+    /// schema that it is migrated into, and record that the migration
+    /// copies the subgraph as of `head`
+    fn create_migration_tables(
+        &self,
+        logger: &Logger,
+        json: &JsonStorage,
+        subgraph_schema: &SubgraphSchema,
+        head: &EthereumBlockPointer,
+    ) -> Result<(), StoreError> {
+        use self::public::deployment_schemas as dsl;
+        use self::public::DeploymentSchemaState as State;
+
+        let head_number = ptr_block_number(head)?;
+        let wanted = (head_number - *STORAGE_MIGRATION_HISTORY).max(0);
+        let window = MigrationWindow::load(&self.conn, json, head, wanted)?;
+        if window.start > wanted {
+            warn!(logger, "Not all blocks of the subgraph are in the block cache, keeping less history";
+                  "subgraph" => json.subgraph.to_string(),
+                  "wanted_start_block" => wanted,
+                  "start_block" => window.start);
+        }
+
+        let name = json.migration_schema();
+        let query = format!(
+            "drop schema if exists {name} cascade; create schema {name}",
+            name = name
+        );
+        self.conn.batch_execute(&query)?;
+        let layout = Layout::create_relational_schema(
+            &self.conn,
+            &name,
+            json.subgraph.clone(),
+            &subgraph_schema.document,
+        )?;
+        layout.ensure_partitions(&self.conn, None, window.start)?;
+        for block in window.start + 1..=head_number {
+            layout.ensure_partitions(&self.conn, Some(block - 1), block)?;
+        }
+
+        diesel::update(dsl::table.filter(dsl::subgraph.eq(json.subgraph.to_string())))
+            .set((
+                dsl::state.eq(State::Tables),
+                dsl::migration_block_hash.eq(Some(head.hash_hex())),
+                dsl::migration_start_block.eq(Some(window.start)),
+                dsl::migration_entity_type.eq(None::<String>),
+                dsl::migration_last_id.eq(None::<String>),
+            ))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// Copy all versions of the next batch of entities from `json` into
+    /// `layout`, starting after `position`, the entity type and id of the
+    /// last entity that was copied. Entity types are copied in the order of
+    /// their names, and entities in the order of their ids. Return `false`
+    /// if all entities have been copied already
+    fn copy_migration_batch(
+        &self,
+        json: &JsonStorage,
+        layout: &Layout,
+        window: &MigrationWindow,
+        head: &EthereumBlockPointer,
+        position: Option<(String, String)>,
+        batch_size: i64,
+    ) -> Result<bool, StoreError> {
+        use self::public::deployment_schemas as dsl;
+
+        let mut entity_types = layout.tables.keys().collect::<Vec<_>>();
+        entity_types.sort();
+        for entity_type in entity_types {
+            let after = match &position {
+                Some((copied_type, _)) if copied_type > entity_type => continue,
+                Some((copied_type, last_id)) if copied_type == entity_type => {
+                    Some(last_id.as_str())
+                }
+                _ => None,
+            };
+            let ids = json.migration_ids(
+                &self.conn,
+                entity_type,
+                after,
+                window.first_event,
+                batch_size,
+            )?;
+            let last_id = match ids.last() {
+                Some(last_id) => last_id.clone(),
+                None => continue,
+            };
+
+            let current = json.find_by_ids(&self.conn, entity_type, &ids)?;
+            let mut history = match window.first_event {
+                Some(first_event) => {
+                    json.history_since(&self.conn, entity_type, &ids, first_event)?
+                }
+                None => HashMap::new(),
+            };
+            for id in &ids {
+                let key = EntityKey {
+                    subgraph_id: json.subgraph.clone(),
+                    entity_type: entity_type.clone(),
+                    entity_id: id.clone(),
+                };
+                let versions = window.versions(current.get(id), history.remove(id));
+                let mut exists = false;
+                for (block, entity) in versions {
+                    match (&entity, exists) {
+                        (Some(entity), false) => layout.insert(&self.conn, &key, entity, block)?,
+                        (Some(entity), true) => layout.update(&self.conn, &key, entity, block)?,
+                        (None, true) => {
+                            layout.delete(&self.conn, &key, block)?;
+                        }
+                        (None, false) => (),
+                    }
+                    exists = entity.is_some();
+                }
+            }
+            self.check_migration_batch(json, layout, entity_type, &ids, current, head)?;
+
+            diesel::update(dsl::table.filter(dsl::subgraph.eq(json.subgraph.to_string())))
+                .set((
+                    dsl::migration_entity_type.eq(Some(entity_type.as_str())),
+                    dsl::migration_last_id.eq(Some(last_id)),
+                ))
+                .execute(&self.conn)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Check that the versions of the entities `ids` of `entity_type` that
+    /// `layout` has at `head` have the same proof of indexing as the
+    /// entities `current` that `json` has
+    fn check_migration_batch(
+        &self,
+        json: &JsonStorage,
+        layout: &Layout,
+        entity_type: &String,
+        ids: &[String],
+        current: BTreeMap<String, Entity>,
+        head: &EthereumBlockPointer,
+    ) -> Result<(), StoreError> {
+        let digest = |entities: Vec<Entity>| -> Result<H256, StoreError> {
+            let mods = entities
+                .into_iter()
+                .map(|mut data| {
+                    // Relational storage does not store attributes that are
+                    // null
+                    data.retain(|_, value| *value != Value::Null);
+                    let key = EntityKey {
+                        subgraph_id: json.subgraph.clone(),
+                        entity_type: entity_type.clone(),
+                        entity_id: data.id()?,
+                    };
+                    Ok(EntityModification::Insert { key, data })
+                })
+                .collect::<Result<Vec<_>, StoreError>>()?;
+            ProofOfIndexing::digest(None, head, &mods)
+        };
+
+        let mut ids_for_type = BTreeMap::new();
+        ids_for_type.insert(
+            entity_type.as_str(),
+            ids.iter().map(String::as_str).collect(),
+        );
+        let copied = layout
+            .find_many(&self.conn, ids_for_type, BLOCK_NUMBER_MAX)?
+            .remove(entity_type)
+            .unwrap_or_default();
+        let expected = digest(current.into_iter().map(|(_, entity)| entity).collect())?;
+        if digest(copied)? != expected {
+            return Err(StoreError::QueryExecutionError(format!(
+                "copying the {} entities of subgraph {} with ids from {} to {} went wrong: \
+                 the copies have a different proof of indexing",
+                entity_type,
+                json.subgraph,
+                ids.first().map(String::as_str).unwrap_or(""),
+                ids.last().map(String::as_str).unwrap_or(""),
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that `layout` has the same entities as `json`, and switch the
+    /// subgraph over to `layout`. Since history before the start of
+    /// `window` was not copied, the subgraph can not be queried or
+    /// reverted before that block any more
+    fn finish_migration(
+        &self,
+        json: &JsonStorage,
+        layout: &Layout,
+        window: &MigrationWindow,
+    ) -> Result<(), StoreError> {
+        use self::public::deployment_schemas as dsl;
+        use self::public::DeploymentSchemaState as State;
+        use self::public::DeploymentSchemaVersion as V;
+
+        let expected = json.count_entities_by_type(&self.conn)?;
+        let copied = layout
+            .count_current_entities_by_type(&self.conn)?
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .collect::<BTreeMap<_, _>>();
+        if expected != copied {
+            return Err(StoreError::QueryExecutionError(format!(
+                "copying the entities of subgraph {} went wrong: expected {:?} \
+                 entities but copied {:?}",
+                json.subgraph, expected, copied
+            )));
+        }
+
+        let query = format!(
+            "drop schema {old} cascade; alter schema {new} rename to {old}",
+            old = json.schema,
+            new = layout.schema
+        );
+        self.conn.batch_execute(&query)?;
+        diesel::update(dsl::table.filter(dsl::subgraph.eq(json.subgraph.to_string())))
+            .set((
+                dsl::version.eq(V::Relational),
+                dsl::state.eq(State::Ready),
+                dsl::migration_requested.eq(false),
+                dsl::migration_block_hash.eq(None::<String>),
+                dsl::migration_start_block.eq(None::<i32>),
+                dsl::migration_entity_type.eq(None::<String>),
+                dsl::migration_last_id.eq(None::<String>),
+            ))
+            .execute(&self.conn)?;
+
+        let query = "
+            update subgraphs.entities
+            set data = data || (format('{\"earliestRetainedBlockNumber\":
+                                  { \"data\": \"%s\",
+                                    \"type\": \"BigInt\"}}', $1))::jsonb
+            where entity='SubgraphDeployment'
+              and id = $2
+            ";
+        diesel::sql_query(query)
+            .bind::<Integer, _>(window.start)
+            .bind::<Text, _>(json.subgraph.to_string())
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// Return an error if the state of the subgraph at `block` can not be
+    /// restored since the subgraph does not keep history for it
+    fn check_retained(&self, block: BlockNumber) -> Result<(), StoreError> {
+        match self.earliest_retained_block()? {
+            Some(earliest) if block < earliest => Err(StoreError::QueryExecutionError(format!(
+                "subgraph {} can not be reverted to block {} since it only keeps \
+                 history from block {} on",
+                self.storage.subgraph(),
+                block,
+                earliest
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The earliest block that the subgraph keeps history for, if it does
+    /// not keep all of it because it was migrated from JSONB storage
+    fn earliest_retained_block(&self) -> Result<Option<BlockNumber>, StoreError> {
+        #[derive(QueryableByName)]
+        struct Earliest {
+            #[sql_type = "Nullable<Integer>"]
+            block: Option<i32>,
+        }
+
+        let query = "
+            select (data->'earliestRetainedBlockNumber'->>'data')::int as block
+              from subgraphs.entities
+             where entity='SubgraphDeployment'
+               and id = $1";
+        Ok(diesel::sql_query(query)
+            .bind::<Text, _>(self.storage.subgraph().to_string())
+            .get_result::<Earliest>(&self.conn)
+            .optional()?
+            .and_then(|earliest| earliest.block))
+    }
+
     pub(crate) fn send_store_event(&self, event: &StoreEvent) -> Result<(), StoreError> {
         let v = serde_json::to_value(event)?;
         JsonNotification::send("store_events", &v, &*self.conn)
@@ -830,6 +1754,24 @@ impl Connection {
             return Ok(());
         }
 
+        // Only relational storage supports partitioning; rather than
+        // ignoring the request, refuse to create the deployment
+        if let v::Split = *GRAPH_STORAGE_SCHEME {
+            if let Some(entity_type) = Partitioning::requested_by(&schema.document) {
+                return Err(StoreError::MalformedDirective(format!(
+                    "@partition on type {}: partitioning requires relational storage, \
+                     but GRAPH_STORAGE_SCHEME is `json`",
+                    entity_type
+                )));
+            }
+        }
+
+        // Proofs, entity type updates and copy progress left behind by an
+        // earlier deployment with the same id do not apply to this one
+        proof_of_indexing::remove(&self.conn, &schema.id)?;
+        entity_type_update::remove(&self.conn, &schema.id)?;
+        copy_state::clear(&self.conn, &schema.id)?;
+
         // Create a schema for the deployment.
         let schemas: Vec<String> = diesel::insert_into(deployment_schemas::table)
             .values((
@@ -857,6 +1799,33 @@ impl Connection {
         }
     }
 
+    /// Create tables and indexes in `tablespace` for the rest of the
+    /// current transaction
+    pub(crate) fn use_tablespace(&self, tablespace: &str) -> Result<(), StoreError> {
+        #[derive(QueryableByName)]
+        struct Tablespace {
+            #[sql_type = "Text"]
+            #[allow(dead_code)]
+            spcname: String,
+        }
+
+        let found = diesel::sql_query("select spcname from pg_tablespace where spcname = $1")
+            .bind::<Text, _>(tablespace)
+            .get_result::<Tablespace>(&self.conn)
+            .optional()?;
+        if found.is_none() {
+            return Err(StoreError::Unknown(format_err!(
+                "tablespace `{}` does not exist",
+                tablespace
+            )));
+        }
+        let query = format!(
+            "set local default_tablespace = \"{}\"",
+            tablespace.replace('"', "\"\"")
+        );
+        Ok(self.conn.batch_execute(&query)?)
+    }
+
     pub(crate) fn uses_relational_schema(&self) -> bool {
         match &*self.storage {
             Storage::Json(_) => false,
@@ -877,6 +1846,34 @@ fn find_schema(
         .optional()?)
 }
 
+/// The deployments that should be migrated from JSONB storage to relational
+/// storage and that have not finished migrating yet
+pub(crate) fn storage_migrations_requested(
+    conn: &PgConnection,
+) -> Result<Vec<SubgraphDeploymentId>, StoreError> {
+    deployment_schemas::table
+        .select(deployment_schemas::subgraph)
+        .filter(deployment_schemas::migration_requested.eq(true))
+        .load::<String>(conn)?
+        .into_iter()
+        .map(|subgraph| {
+            SubgraphDeploymentId::new(subgraph.clone())
+                .map_err(|_| StoreError::Unknown(format_err!("illegal subgraph {}", subgraph)))
+        })
+        .collect()
+}
+
+// The number of the block `ptr` points to as a `BlockNumber`, or an error
+// if it is too large to be one
+fn ptr_block_number(ptr: &EthereumBlockPointer) -> Result<BlockNumber, StoreError> {
+    ptr.number.try_into().map_err(|_| {
+        StoreError::QueryExecutionError(format!(
+            "block number {} is too large to store",
+            ptr.number
+        ))
+    })
+}
+
 fn entity_to_json(key: &EntityKey, entity: &Entity) -> Result<serde_json::Value, Error> {
     serde_json::to_value(entity).map_err(|e| {
         format_err!(
@@ -896,6 +1893,189 @@ fn entity_from_json(json: serde_json::Value, entity: &str) -> Result<Entity, Sto
 }
 
 impl JsonStorage {
+    /// The name of the database schema that the subgraph's entities are
+    /// copied into when it is migrated to relational storage
+    fn migration_schema(&self) -> String {
+        format!("{}_relational", self.schema)
+    }
+
+    /// Return the ids of at most `limit` entities of type `entity` that
+    /// come after `after`, ordered by id. These are the entities that exist
+    /// now, together with the ones that were changed by events from
+    /// `first_event` on. If `after` is `None`, start with the first entity
+    fn migration_ids(
+        &self,
+        conn: &PgConnection,
+        entity: &str,
+        after: Option<&str>,
+        first_event: Option<i32>,
+        limit: i64,
+    ) -> Result<Vec<String>, StoreError> {
+        #[derive(QueryableByName)]
+        struct Id {
+            #[sql_type = "Text"]
+            id: String,
+        }
+
+        let query = format!(
+            "select id from (
+               (select id from {schema}.entities
+                 where entity = $1 and ($2::text is null or id > $2)
+                 order by id limit $4)
+               union
+               (select entity_id as id from {schema}.entity_history
+                 where entity = $1 and event_id >= $3
+                   and ($2::text is null or entity_id > $2))) ids
+             order by id limit $4",
+            schema = self.schema
+        );
+        Ok(diesel::sql_query(query)
+            .bind::<Text, _>(entity)
+            .bind::<Nullable<Text>, _>(after)
+            .bind::<Nullable<Integer>, _>(first_event)
+            .bind::<BigInt, _>(limit)
+            .load::<Id>(conn)?
+            .into_iter()
+            .map(|row| row.id)
+            .collect())
+    }
+
+    /// The entities of type `entity` with one of the `ids`, keyed by id
+    fn find_by_ids(
+        &self,
+        conn: &PgConnection,
+        entity: &str,
+        ids: &[String],
+    ) -> Result<BTreeMap<String, Entity>, StoreError> {
+        #[derive(QueryableByName)]
+        struct EntityData {
+            #[sql_type = "Text"]
+            id: String,
+            #[sql_type = "Jsonb"]
+            data: serde_json::Value,
+        }
+
+        let query = format!(
+            "select id, data from {}.entities where entity = $1 and id = any($2)",
+            self.schema
+        );
+        diesel::sql_query(query)
+            .bind::<Text, _>(entity)
+            .bind::<Array<Text>, _>(ids)
+            .load::<EntityData>(conn)?
+            .into_iter()
+            .map(|row| Ok((row.id, entity_from_json(row.data, entity)?)))
+            .collect()
+    }
+
+    /// The history of the entities of type `entity` with one of the `ids`
+    /// from event `first_event` on, keyed by id. For each change of an
+    /// entity, in the order in which they were made, this has the event
+    /// that made the change and the entity before the change, or `None` if
+    /// the entity did not exist before
+    fn history_since(
+        &self,
+        conn: &PgConnection,
+        entity: &str,
+        ids: &[String],
+        first_event: i32,
+    ) -> Result<HashMap<String, Vec<(i32, Option<Entity>)>>, StoreError> {
+        #[derive(QueryableByName)]
+        struct History {
+            #[sql_type = "Text"]
+            entity_id: String,
+            #[sql_type = "Integer"]
+            event_id: i32,
+            #[sql_type = "Nullable<Jsonb>"]
+            data_before: Option<serde_json::Value>,
+        }
+
+        let query = format!(
+            "select h.entity_id, h.event_id, h.data_before
+               from {}.entity_history h
+              where h.entity = $1 and h.entity_id = any($2) and h.event_id >= $3
+              order by h.id",
+            self.schema
+        );
+        let rows = diesel::sql_query(query)
+            .bind::<Text, _>(entity)
+            .bind::<Array<Text>, _>(ids)
+            .bind::<Integer, _>(first_event)
+            .load::<History>(conn)?;
+
+        let mut history = HashMap::new();
+        for row in rows {
+            let before = row
+                .data_before
+                .map(|data| entity_from_json(data, entity))
+                .transpose()?;
+            history
+                .entry(row.entity_id)
+                .or_insert_with(Vec::new)
+                .push((row.event_id, before));
+        }
+        Ok(history)
+    }
+
+    /// The number of entities of each entity type, keyed by entity type
+    fn count_entities_by_type(
+        &self,
+        conn: &PgConnection,
+    ) -> Result<BTreeMap<String, usize>, StoreError> {
+        #[derive(QueryableByName)]
+        struct Count {
+            #[sql_type = "Text"]
+            entity: String,
+            #[sql_type = "BigInt"]
+            count: i64,
+        }
+
+        let query = format!(
+            "select entity, count(*) as count from {}.entities group by entity",
+            self.schema
+        );
+        Ok(diesel::sql_query(query)
+            .load::<Count>(conn)?
+            .into_iter()
+            .map(|row| (row.entity, row.count as usize))
+            .collect())
+    }
+
+    /// Report the entity types that have entities with an ID longer than
+    /// `max_length` bytes
+    fn oversized_entity_ids(
+        &self,
+        conn: &PgConnection,
+        max_length: usize,
+    ) -> Result<Vec<OversizedEntityIds>, StoreError> {
+        #[derive(QueryableByName)]
+        struct Oversized {
+            #[sql_type = "Text"]
+            entity: String,
+            #[sql_type = "BigInt"]
+            count: i64,
+            #[sql_type = "Integer"]
+            longest: i32,
+        }
+
+        let query = format!(
+            "select entity, count(*) as count, max(octet_length(id)) as longest \
+               from {}.entities where octet_length(id) > $1 \
+              group by entity order by entity",
+            self.schema
+        );
+        Ok(diesel::sql_query(query)
+            .bind::<Integer, _>(max_length.min(std::i32::MAX as usize) as i32)
+            .load::<Oversized>(conn)?
+            .into_iter()
+            .map(|row| OversizedEntityIds {
+                entity_type: row.entity,
+                count: row.count as u64,
+                longest: row.longest as u64,
+            })
+            .collect())
+    }
+
     fn find(
         &self,
         conn: &PgConnection,
@@ -919,9 +2099,10 @@ impl JsonStorage {
         conn: &PgConnection,
         collection: EntityCollection,
         filter: Option<EntityFilter>,
-        order: Option<(String, ValueType, EntityOrder)>,
+        order: Option<(String, ValueType, EntityOrder, NullsOrder)>,
         range: EntityRange,
     ) -> Result<Vec<Entity>, QueryExecutionError> {
+        let from_end = range.from_end;
         let query = FilterQuery::new(&self.table, collection, filter, order, range)?;
 
         let query_debug_info = debug_query(&query).to_string();
@@ -934,12 +2115,17 @@ impl JsonStorage {
                     e, query_debug_info
                 ))
             })?;
-        values
+        let mut entities = values
             .into_iter()
             .map(|(_, value, entity_type)| {
                 entity_from_json(value, &entity_type).map_err(QueryExecutionError::from)
             })
-            .collect()
+            .collect::<Result<Vec<_>, _>>()?;
+        // The last entities are found in reverse order
+        if from_end {
+            entities.reverse();
+        }
+        Ok(entities)
     }
 
     fn insert(
@@ -1200,6 +2386,55 @@ impl JsonStorage {
             .map(|(changes, count)| (StoreEvent::new(changes), count))
     }
 
+    fn attribute_index_name(index: &AttributeIndexDefinition) -> String {
+        format!(
+            "attr_{}_{}_{}_{}",
+            index.entity_number,
+            index.attribute_number,
+            to_snake_case(&index.entity_name),
+            to_snake_case(&index.attribute_name)
+        )
+    }
+
+    /// Build the indexes in `indexes` that do not exist yet
+    fn create_missing_indexes(
+        &self,
+        conn: &PgConnection,
+        indexes: Vec<AttributeIndexDefinition>,
+    ) -> Result<Vec<IndexCreation>, StoreError> {
+        #[derive(QueryableByName)]
+        struct ExistingIndex {
+            #[sql_type = "Text"]
+            name: String,
+        }
+
+        let existing = diesel::sql_query(
+            "select indexname::text as name from pg_indexes where schemaname = $1",
+        )
+        .bind::<Text, _>(&self.schema)
+        .load::<ExistingIndex>(conn)?
+        .into_iter()
+        .map(|index| index.name)
+        .collect::<HashSet<_>>();
+
+        Ok(indexes
+            .iter()
+            .filter_map(|index| {
+                let name = Self::attribute_index_name(index);
+                if existing.contains(&name[..name.len().min(63)]) {
+                    return None;
+                }
+                Some(IndexCreation {
+                    error: self
+                        .build_attribute_index(conn, index)
+                        .err()
+                        .map(|e| e.to_string()),
+                    name,
+                })
+            })
+            .collect())
+    }
+
     fn build_attribute_index(
         &self,
         conn: &PgConnection,
@@ -1243,13 +2478,7 @@ impl JsonStorage {
         // because of that we include the `entity_number` and
         // `attribute_number` to ensure that a 63 character prefix
         // of the name is guaranteed to be unique
-        let name = format!(
-            "attr_{}_{}_{}_{}",
-            index.entity_number,
-            index.attribute_number,
-            to_snake_case(&index.entity_name),
-            to_snake_case(&index.attribute_name)
-        );
+        let name = Self::attribute_index_name(index);
         let query = match index.field_value_type {
             ValueType::String => format!(
                 "create index if not exists {name}
@@ -1352,6 +2581,142 @@ impl JsonStorage {
     }
 }
 
+/// The blocks whose history is kept when a subgraph is migrated from JSONB
+/// storage to relational storage. JSONB storage records the changes each
+/// block made as events in `event_meta_data` that refer to the block by its
+/// hash, including the changes of blocks that were reverted since. We
+/// therefore follow the chain from the subgraph's head back to `start`
+/// through the block cache to find the events of the blocks that are still
+/// part of it
+struct MigrationWindow {
+    /// Entities are copied with the state they had at this block, and
+    /// the changes of later blocks are copied as separate versions
+    start: BlockNumber,
+    /// The earliest event of a block after `start`, or `None` if no block
+    /// after `start` changed any entities
+    first_event: Option<i32>,
+    /// The last event of each block after `start` that changed entities,
+    /// mapped to the number of the block
+    last_events: HashMap<i32, BlockNumber>,
+}
+
+impl MigrationWindow {
+    /// Load the events of the blocks from `start` to `head`. If some of
+    /// these blocks are not in the block cache any more, the window starts
+    /// later than `start`
+    fn load(
+        conn: &PgConnection,
+        json: &JsonStorage,
+        head: &EthereumBlockPointer,
+        start: BlockNumber,
+    ) -> Result<Self, StoreError> {
+        #[derive(QueryableByName)]
+        struct Block {
+            #[sql_type = "Text"]
+            hash: String,
+            #[sql_type = "BigInt"]
+            number: i64,
+        }
+
+        #[derive(QueryableByName)]
+        struct Events {
+            #[sql_type = "Text"]
+            source: String,
+            #[sql_type = "Integer"]
+            first_event: i32,
+            #[sql_type = "Integer"]
+            last_event: i32,
+        }
+
+        let head_number = ptr_block_number(head)?;
+        let chain = diesel::sql_query(
+            "with recursive chain(hash, number, parent_hash) as (
+               select hash, number, parent_hash from ethereum_blocks where hash = $1
+               union all
+               select b.hash, b.number, b.parent_hash
+                 from chain c, ethereum_blocks b
+                where b.hash = c.parent_hash
+                  and c.number > $2)
+             select hash, number from chain",
+        )
+        .bind::<Text, _>(head.hash_hex())
+        .bind::<BigInt, _>(start as i64)
+        .load::<Block>(conn)?;
+
+        // We can only tell which events belong to blocks whose hash we know
+        let start = match chain.iter().map(|block| block.number).min() {
+            Some(earliest) => start.max(earliest as BlockNumber - 1),
+            None => head_number,
+        };
+        let numbers = chain
+            .into_iter()
+            .filter(|block| block.number > start as i64)
+            .map(|block| (block.hash, block.number as BlockNumber))
+            .collect::<HashMap<_, _>>();
+        let hashes = numbers.keys().cloned().collect::<Vec<_>>();
+
+        let query = format!(
+            "select m.source, min(m.id) as first_event, max(m.id) as last_event
+               from event_meta_data m
+              where m.source = any($1)
+                and exists (select 1 from {}.entity_history h where h.event_id = m.id)
+              group by m.source",
+            json.schema
+        );
+        let events = diesel::sql_query(query)
+            .bind::<Array<Text>, _>(&hashes)
+            .load::<Events>(conn)?;
+
+        Ok(MigrationWindow {
+            start,
+            first_event: events.iter().map(|events| events.first_event).min(),
+            last_events: events
+                .into_iter()
+                .filter_map(|events| {
+                    numbers
+                        .get(&events.source)
+                        .map(|number| (events.last_event, *number))
+                })
+                .collect(),
+        })
+    }
+
+    /// The versions of an entity that exists as `current` now and that was
+    /// changed as described by `history` from `first_event` on; see
+    /// `JsonStorage::history_since`. Each version is the block from which
+    /// on the entity had a new state, and that state, or `None` if the
+    /// entity did not exist from that block on
+    fn versions(
+        &self,
+        current: Option<&Entity>,
+        history: Option<Vec<(i32, Option<Entity>)>>,
+    ) -> Vec<(BlockNumber, Option<Entity>)> {
+        let history = history.unwrap_or_default();
+        let mut versions = Vec::new();
+
+        // Each change records the entity as it was before the change, and
+        // the state of the entity after a change is therefore recorded by
+        // the change that followed it
+        let mut states = history
+            .iter()
+            .map(|(_, before)| before.clone())
+            .chain(std::iter::once(current.cloned()));
+        versions.push((self.start, states.next().unwrap_or(None)));
+        for (i, ((event, _), after)) in history.iter().zip(states).enumerate() {
+            let last_in_event = history
+                .get(i + 1)
+                .map_or(true, |(next_event, _)| next_event != event);
+            if !last_in_event {
+                continue;
+            }
+            if let Some(block) = self.last_events.get(event) {
+                versions.push((*block, after));
+            }
+        }
+        versions
+    }
+}
+
 impl Storage {
     /// The version for newly created subgraph schemas. Changing this most
     /// likely also requires changing `create_schema`
@@ -1390,6 +2755,7 @@ impl Storage {
                     data,
                     event_source,
                     count_query,
+                    migration_requested: schema.migration_requested,
                 })
             }
             V::Relational => {
@@ -1407,9 +2773,17 @@ impl Storage {
     }
 
     /// Return `true` if it is safe to cache this storage instance across
-    /// transactions
+    /// transactions. Storage for a subgraph that is being migrated to
+    /// relational storage changes when the migration finishes. Requesting
+    /// a migration sends a store event for the subgraph's deployment that
+    /// removes its storage from the cache on every node; see
+    /// `invalidate_storage_cache`
     pub(crate) fn is_cacheable(&self) -> bool {
-        !self.needs_migrating()
+        let migration_requested = match self {
+            Storage::Json(json) => json.migration_requested,
+            Storage::Relational(_) => false,
+        };
+        !self.needs_migrating() && !migration_requested
     }
 
     /// Adjust the `entityCount` property of the `SubgraphDeployment` for
@@ -1574,6 +2948,9 @@ fn drop_schema(
     if let Some(schema) = info {
         let query = format!("drop schema if exists {} cascade", schema.name);
         conn.batch_execute(&*query)?;
+        proof_of_indexing::remove(conn, subgraph)?;
+        entity_type_update::remove(conn, subgraph)?;
+        copy_state::clear(conn, subgraph)?;
         Ok(diesel::delete(deployment_schemas::table)
             .filter(deployment_schemas::subgraph.eq(schema.subgraph))
             .execute(conn)?)
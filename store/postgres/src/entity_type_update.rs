@@ -0,0 +1,126 @@
+//! The blocks in which a deployment changed entities of each of its types,
+//! stored in `entity_type_update`. Clients use the latest of these blocks
+//! to decide whether results they cached for a type are still current.
+//!
+//! Recording them adds one statement per block, however many types the
+//! block changed. Since clients compare them with the blocks they cached
+//! results at, they never go backwards: reverting a block keeps the blocks
+//! that were recorded for it, because reverting changes the entities of
+//! those types again.
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Nullable, Text};
+use diesel::{delete, insert_into};
+
+use graph::prelude::{BlockNumber, StoreError, SubgraphDeploymentId};
+
+use crate::db_schema::entity_type_update as u;
+
+#[derive(QueryableByName)]
+struct EntityTypeUpdate {
+    #[sql_type = "Text"]
+    entity_type: String,
+    #[sql_type = "Nullable<BigInt>"]
+    block_number: Option<i64>,
+}
+
+/// Record that `subgraph` changed entities of `entity_types` in `block`
+pub(crate) fn record<'a>(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    block: BlockNumber,
+    entity_types: impl Iterator<Item = &'a str>,
+) -> Result<(), StoreError> {
+    let rows = entity_types
+        .map(|entity_type| {
+            (
+                u::subgraph.eq(subgraph.as_str()),
+                u::entity_type.eq(entity_type),
+                u::block_number.eq(block as i64),
+            )
+        })
+        .collect::<Vec<_>>();
+    if !rows.is_empty() {
+        insert_into(u::table)
+            .values(rows)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// For each entity type of `subgraph`, the latest block up to and
+/// including `block` in which entities of the type changed, ordered by
+/// entity type. Without `block`, all recorded blocks are considered.
+///
+/// There is a row for every block that changed a type, and the query
+/// therefore walks the primary key from one entity type to the next rather
+/// than reading all rows of the deployment
+pub(crate) fn latest(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    block: Option<BlockNumber>,
+) -> Result<Vec<(String, BlockNumber)>, StoreError> {
+    Ok(diesel::sql_query(
+        "with recursive types(entity_type) as (
+           select min(entity_type) from entity_type_update where subgraph = $1
+           union all
+           select (select min(u.entity_type)
+                     from entity_type_update u
+                    where u.subgraph = $1
+                      and u.entity_type > t.entity_type)
+             from types t
+            where t.entity_type is not null)
+         select t.entity_type,
+                (select max(u.block_number)
+                   from entity_type_update u
+                  where u.subgraph = $1
+                    and u.entity_type = t.entity_type
+                    and u.block_number <= $2) as block_number
+           from types t
+          where t.entity_type is not null
+          order by t.entity_type",
+    )
+    .bind::<Text, _>(subgraph.as_str())
+    .bind::<BigInt, _>(block.map_or(std::i64::MAX, |block| block as i64))
+    .load::<EntityTypeUpdate>(conn)?
+    .into_iter()
+    .filter_map(|update| {
+        update
+            .block_number
+            .map(|block_number| (update.entity_type, block_number as BlockNumber))
+    })
+    .collect())
+}
+
+/// Give `subgraph` the blocks that `base` recorded up to `block`, for a
+/// deployment that starts from the data of `base`
+pub(crate) fn copy(
+    conn: &PgConnection,
+    base: &SubgraphDeploymentId,
+    subgraph: &SubgraphDeploymentId,
+    block: BlockNumber,
+) -> Result<(), StoreError> {
+    diesel::sql_query(
+        "insert into entity_type_update(subgraph, entity_type, block_number)
+         select $1, entity_type, block_number
+           from entity_type_update
+          where subgraph = $2
+            and block_number <= $3
+         on conflict do nothing",
+    )
+    .bind::<Text, _>(subgraph.as_str())
+    .bind::<Text, _>(base.as_str())
+    .bind::<BigInt, _>(block as i64)
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Remove everything that was recorded for `subgraph`
+pub(crate) fn remove(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<(), StoreError> {
+    delete(u::table.filter(u::subgraph.eq(subgraph.as_str()))).execute(conn)?;
+    Ok(())
+}
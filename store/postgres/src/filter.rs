@@ -217,6 +217,23 @@ where
                 .map(|filter_expr| Box::new(p.or(filter_expr)) as FilterExpression<QS>)
         }),
 
+        // Entities for which the filter is null do not pass it, and
+        // therefore pass its negation
+        Negation(filter) => {
+            let unknown = build_filter(*filter.clone())?;
+            build_filter(*filter).map(|filter_expr| {
+                Box::new(dsl::not(filter_expr).or(unknown.is_null())) as FilterExpression<QS>
+            })
+        }
+
+        TypeCondition(entity_type, filter) => build_filter(*filter).map(|filter_expr| {
+            Box::new(
+                sql("c.entity != ")
+                    .bind::<Text, _>(entity_type)
+                    .or(filter_expr),
+            ) as FilterExpression<QS>
+        }),
+
         Contains(..) | NotContains(..) => {
             let (attribute, contains, op, value) = match filter {
                 EntityFilter::Contains(attribute, value) => (attribute, true, " LIKE ", value),
@@ -261,6 +278,16 @@ where
             }
         }
 
+        // A list contains any of the values if it contains one of them
+        ContainsAny(attribute, Value::List(values)) => build_filter(Or(values
+            .into_iter()
+            .map(|value| Contains(attribute.clone(), Value::List(vec![value])))
+            .collect())),
+        ContainsAny(_, value) => Err(UnsupportedFilter {
+            filter: "contains_any".to_owned(),
+            value,
+        }),
+
         Equal(..) | Not(..) => {
             let (attribute, op, is_negated, value) = match filter {
                 Equal(attribute, value) => (attribute, " = ", false, value),
@@ -439,5 +466,11 @@ where
                 }
             }
         }
+
+        // Deployments that store entities as JSONB can not join entities
+        Child(child) => Err(UnsupportedFilter {
+            filter: format!("{}_", child.attribute),
+            value: Value::Null,
+        }),
     }
 }
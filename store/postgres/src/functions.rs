@@ -11,10 +11,6 @@ sql_function! {
     fn attempt_chain_head_update(net_name: Text, ancestor_count: BigInt) -> Array<Text>
 }
 
-sql_function! {
-    fn lookup_ancestor_block(start_block_hash: Text, ancestor_count: BigInt) -> Nullable<Jsonb>
-}
-
 sql_function! {
     fn pg_notify(channel: Text, msg: Text)
 }
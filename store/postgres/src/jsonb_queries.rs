@@ -8,7 +8,7 @@ use diesel::query_dsl::RunQueryDsl;
 use diesel::result::QueryResult;
 use diesel::sql_types::{Array, Bool, Jsonb, Text};
 use graph::prelude::{
-    EntityCollection, EntityFilter, EntityLink, EntityOrder, EntityRange, EntityWindow,
+    EntityCollection, EntityFilter, EntityLink, EntityOrder, EntityRange, EntityWindow, NullsOrder,
     QueryExecutionError, ValueType, WindowAttribute,
 };
 
@@ -21,6 +21,7 @@ pub struct OrderDetails {
     cast: &'static str,
     prefix_only: bool,
     direction: EntityOrder,
+    nulls: NullsOrder,
 }
 
 pub struct FilterQuery<'a> {
@@ -29,6 +30,9 @@ pub struct FilterQuery<'a> {
     filter: Option<Box<dyn BoxableExpression<EntityTable, Pg, SqlType = Bool>>>,
     order: Option<OrderDetails>,
     range: EntityRange,
+    /// The direction of `id`, and of `entity` for queries for several
+    /// entity types, when they break ties
+    tiebreak: EntityOrder,
 }
 
 impl<'a> FilterQuery<'a> {
@@ -36,10 +40,10 @@ impl<'a> FilterQuery<'a> {
         table: &'a EntityTable,
         collection: EntityCollection,
         filter: Option<EntityFilter>,
-        order: Option<(String, ValueType, EntityOrder)>,
+        order: Option<(String, ValueType, EntityOrder, NullsOrder)>,
         range: EntityRange,
     ) -> Result<Self, QueryExecutionError> {
-        let order = if let Some((attribute, value_type, direction)) = order {
+        let order = if let Some((attribute, value_type, direction, nulls)) = order {
             let cast = match value_type {
                 ValueType::BigInt | ValueType::BigDecimal => "::numeric",
                 ValueType::Boolean => "::boolean",
@@ -55,15 +59,28 @@ impl<'a> FilterQuery<'a> {
             };
 
             let prefix_only = &attribute != PRIMARY_KEY_COLUMN && value_type == ValueType::String;
+            // Take the last entities as the first ones in the reversed
+            // order; they are flipped back once they are loaded
+            let (direction, nulls) = if range.from_end {
+                (direction.reverse(), nulls.reverse())
+            } else {
+                (direction, nulls)
+            };
             Some(OrderDetails {
                 attribute,
                 cast,
                 prefix_only,
                 direction,
+                nulls,
             })
         } else {
             None
         };
+        let tiebreak = if range.from_end {
+            EntityOrder::Descending
+        } else {
+            EntityOrder::Ascending
+        };
         let filter = if let Some(filter) = filter {
             Some(build_filter(filter).map_err(|e| {
                 QueryExecutionError::FilterNotSupportedError(format!("{}", e.value), e.filter)
@@ -77,9 +94,20 @@ impl<'a> FilterQuery<'a> {
             filter,
             order,
             range,
+            tiebreak,
         })
     }
 
+    /// Whether the query is for entities of more than one type
+    fn has_several_types(&self) -> bool {
+        match &self.collection {
+            EntityCollection::All(entities) => entities.len() > 1,
+            EntityCollection::Window(windows) => windows
+                .iter()
+                .any(|window| window.child_type != windows[0].child_type),
+        }
+    }
+
     fn entities_clause(&self, entities: &Vec<String>, out: &mut AstPass<Pg>) -> QueryResult<()> {
         if entities.len() == 1 {
             // If there is only one entity_type, which is the case in all
@@ -119,9 +147,19 @@ impl<'a> FilterQuery<'a> {
                 out.push_sql(" ");
             }
             out.push_sql(order.direction.to_sql());
-            out.push_sql(" nulls last, ");
+            out.push_sql(" ");
+            out.push_sql(order.nulls.to_sql());
+            out.push_sql(", ");
         }
-        out.push_identifier(PRIMARY_KEY_COLUMN)
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" ");
+        out.push_sql(self.tiebreak.to_sql());
+        // Entities of different types can have the same id
+        if self.has_several_types() {
+            out.push_sql(", entity ");
+            out.push_sql(self.tiebreak.to_sql());
+        }
+        Ok(())
     }
 
     fn limit(&self, out: &mut AstPass<Pg>) {
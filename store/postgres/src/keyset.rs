@@ -0,0 +1,407 @@
+//! Keyset pagination for queries with `after` and `before` cursors. Rather
+//! than skipping over the entities in front of the cursor, which gets
+//! slower the further into the result a query goes and skips or repeats
+//! entities when entities in front of the cursor are added or removed, the
+//! query only selects entities whose sort key comes after or before the
+//! sort key of the cursor. Queries are ordered by an attribute, then by
+//! `id`, and then by entity type, and the sort key of an entity is the
+//! triple of its value for that attribute, its id, and its type.
+use graph::prelude::{
+    EntityCollection, EntityCursor, EntityFilter, EntityOrder, EntityQuery, NullsOrder,
+    QueryExecutionError, Value,
+};
+
+/// Turn the `after` and `before` cursors of `query` into a filter on the
+/// sort key of the query. Entities of different types are ordered the way
+/// `sort_strings` orders their type names, which must be the order of the
+/// database. A query with a `before` cursor returns the entities right in
+/// front of the cursor, and therefore counts its range from the end
+pub(crate) fn restrict<S>(
+    mut query: EntityQuery,
+    sort_strings: S,
+) -> Result<EntityQuery, QueryExecutionError>
+where
+    S: FnOnce(Vec<String>) -> Result<Vec<String>, QueryExecutionError>,
+{
+    if query.after.is_none() && query.before.is_none() {
+        return Ok(query);
+    }
+
+    let attribute = query
+        .order_by
+        .as_ref()
+        .map(|(attribute, _)| attribute.clone());
+    let direction = query.order_direction.unwrap_or(EntityOrder::Ascending);
+    let nulls = query.order_nulls.unwrap_or(NullsOrder::Last);
+
+    // Ties between entities with the same id only need to be broken if the
+    // query is for several entity types
+    let mut entity_types = match &query.collection {
+        EntityCollection::All(entity_types) => entity_types.clone(),
+        EntityCollection::Window(windows) => windows
+            .iter()
+            .map(|window| window.child_type.clone())
+            .collect(),
+    };
+    entity_types.sort();
+    entity_types.dedup();
+    let entity_types = if entity_types.len() > 1 {
+        entity_types.extend(
+            query
+                .after
+                .iter()
+                .chain(query.before.iter())
+                .map(|cursor| cursor.entity_type.clone()),
+        );
+        entity_types.sort();
+        entity_types.dedup();
+        sort_strings(entity_types)?
+    } else {
+        vec![]
+    };
+
+    let mut filters = Vec::new();
+    if let Some(cursor) = query.after.take() {
+        filters.push(beyond(
+            cursor,
+            attribute.as_ref(),
+            direction,
+            nulls,
+            EntityOrder::Ascending,
+            &entity_types,
+        ));
+    }
+    // The entities before the cursor are the ones after it when the order
+    // of the query is reversed
+    if let Some(cursor) = query.before.take() {
+        filters.push(beyond(
+            cursor,
+            attribute.as_ref(),
+            direction.reverse(),
+            nulls.reverse(),
+            EntityOrder::Descending,
+            &entity_types,
+        ));
+        query.range.from_end = true;
+    }
+
+    for filter in filters {
+        query.filter = Some(filter.and_maybe(query.filter.take()));
+    }
+    Ok(query)
+}
+
+/// The filter for the entities that come after `value` for `attribute`
+/// when entities are ordered by `attribute` in `direction`
+fn past(attribute: &str, value: Value, direction: EntityOrder) -> EntityFilter {
+    match direction {
+        EntityOrder::Ascending => EntityFilter::GreaterThan(attribute.to_owned(), value),
+        EntityOrder::Descending => EntityFilter::LessThan(attribute.to_owned(), value),
+    }
+}
+
+/// Like `past`, but also for the entities whose value is `value`
+fn at_or_past(attribute: &str, value: Value, direction: EntityOrder) -> EntityFilter {
+    match direction {
+        EntityOrder::Ascending => EntityFilter::GreaterOrEqual(attribute.to_owned(), value),
+        EntityOrder::Descending => EntityFilter::LessOrEqual(attribute.to_owned(), value),
+    }
+}
+
+/// The filter for the entities that come after `cursor` when entities are
+/// ordered by `id` in `direction` and then by type in `tiebreak`. The
+/// `entity_types` are in the order of the database and contain the types
+/// of the query and of `cursor`, or are empty if the query is only for one
+/// type and there are no ties to break
+fn past_id(
+    cursor: &EntityCursor,
+    direction: EntityOrder,
+    tiebreak: EntityOrder,
+    entity_types: &[String],
+) -> EntityFilter {
+    let id = Value::String(cursor.id.clone());
+    if entity_types.is_empty() {
+        return past("id", id, direction);
+    }
+    let cursor_pos = entity_types
+        .iter()
+        .position(|entity_type| entity_type == &cursor.entity_type);
+    let filters = entity_types
+        .iter()
+        .enumerate()
+        .map(|(pos, entity_type)| {
+            // Entities of this type that have the same id as the cursor
+            // come after it if their type comes after the cursor's type
+            let type_is_past = match tiebreak {
+                EntityOrder::Ascending => Some(pos) > cursor_pos,
+                EntityOrder::Descending => Some(pos) < cursor_pos,
+            };
+            let filter = if type_is_past {
+                at_or_past("id", id.clone(), direction)
+            } else {
+                past("id", id.clone(), direction)
+            };
+            EntityFilter::TypeCondition(entity_type.clone(), Box::new(filter))
+        })
+        .collect();
+    EntityFilter::And(filters)
+}
+
+/// The filter for the entities that come strictly after `cursor` when
+/// entities are ordered by `attribute` in `direction` with nulls placed
+/// according to `nulls`, and then by `id` and type in `tiebreak`
+fn beyond(
+    cursor: EntityCursor,
+    attribute: Option<&String>,
+    direction: EntityOrder,
+    nulls: NullsOrder,
+    tiebreak: EntityOrder,
+    entity_types: &[String],
+) -> EntityFilter {
+    let attribute = match attribute {
+        // Queries without an order attribute are ordered by `id`
+        None => return past_id(&cursor, tiebreak, tiebreak, entity_types),
+        Some(attribute) if attribute == "id" => {
+            return past_id(&cursor, direction, tiebreak, entity_types)
+        }
+        Some(attribute) => attribute,
+    };
+
+    let is_null = EntityFilter::Equal(attribute.clone(), Value::Null);
+    let mut filters = Vec::new();
+    if cursor.value == Value::Null {
+        filters.push(EntityFilter::And(vec![
+            is_null,
+            past_id(&cursor, tiebreak, tiebreak, entity_types),
+        ]));
+        if nulls == NullsOrder::First {
+            filters.push(EntityFilter::Not(attribute.clone(), Value::Null));
+        }
+    } else {
+        filters.push(past(attribute, cursor.value.clone(), direction));
+        filters.push(EntityFilter::And(vec![
+            EntityFilter::Equal(attribute.clone(), cursor.value.clone()),
+            past_id(&cursor, tiebreak, tiebreak, entity_types),
+        ]));
+        if nulls == NullsOrder::Last {
+            filters.push(is_null);
+        }
+    }
+    EntityFilter::Or(filters)
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::prelude::{
+        Entity, EntityCollection, SubgraphDeploymentId, ValueType, BLOCK_NUMBER_MAX,
+    };
+
+    use super::*;
+
+    fn users() -> Vec<Entity> {
+        vec![
+            ("1", Some("b")),
+            ("2", Some("a")),
+            ("3", Some("b")),
+            ("4", None),
+        ]
+        .into_iter()
+        .map(|(id, name)| {
+            let mut user = Entity::new();
+            user.set("__typename", "User");
+            user.set("id", id);
+            if let Some(name) = name {
+                user.set("name", name);
+            }
+            user
+        })
+        .collect()
+    }
+
+    fn query(direction: EntityOrder, nulls: NullsOrder) -> EntityQuery {
+        EntityQuery::new(
+            SubgraphDeploymentId::new("keyset").unwrap(),
+            BLOCK_NUMBER_MAX,
+            EntityCollection::All(vec!["User".to_owned()]),
+        )
+        .order_by("name", ValueType::String, direction)
+        .order_nulls(nulls)
+    }
+
+    fn cursor(name: Option<&str>, id: &str) -> EntityCursor {
+        typed_cursor(name, id, "User")
+    }
+
+    fn typed_cursor(name: Option<&str>, id: &str, entity_type: &str) -> EntityCursor {
+        let cursor = EntityCursor {
+            value: name.map_or(Value::Null, Value::from),
+            id: id.to_owned(),
+            entity_type: entity_type.to_owned(),
+        };
+        // Cursors survive the trip through the client
+        assert_eq!(cursor, EntityCursor::decode(&cursor.encode()).unwrap());
+        cursor
+    }
+
+    /// The entities that pass the filter of `query` after its cursors have
+    /// been turned into filters
+    fn restricted(query: EntityQuery, entities: Vec<Entity>) -> Vec<Entity> {
+        let query = restrict(query, |mut strings| {
+            strings.sort();
+            Ok(strings)
+        })
+        .unwrap();
+        assert!(query.after.is_none() && query.before.is_none());
+        entities
+            .into_iter()
+            .filter(|entity| {
+                query
+                    .filter
+                    .as_ref()
+                    .map_or(true, |f| f.matches(entity).unwrap())
+            })
+            .collect()
+    }
+
+    /// The ids of the users that pass the filter of `query` after its
+    /// cursors have been turned into filters
+    fn matching(query: EntityQuery) -> Vec<String> {
+        restricted(query, users())
+            .into_iter()
+            .map(|user| user.id().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ascending_nulls_last() {
+        // The order is 2, 1, 3, 4
+        let query = || query(EntityOrder::Ascending, NullsOrder::Last);
+        assert_eq!(
+            vec!["3", "4"],
+            matching(query().after(cursor(Some("b"), "1")))
+        );
+        assert_eq!(
+            vec!["1", "2"],
+            matching(query().before(cursor(Some("b"), "3")))
+        );
+        assert_eq!(
+            Vec::<String>::new(),
+            matching(query().after(cursor(None, "4")))
+        );
+        assert_eq!(
+            vec!["1", "3"],
+            matching(
+                query()
+                    .after(cursor(Some("a"), "2"))
+                    .before(cursor(None, "4"))
+            )
+        );
+    }
+
+    #[test]
+    fn descending_nulls_first() {
+        // The order is 4, 1, 3, 2
+        let query = || query(EntityOrder::Descending, NullsOrder::First);
+        assert_eq!(
+            vec!["2", "3"],
+            matching(query().after(cursor(Some("b"), "1")))
+        );
+        assert_eq!(
+            vec!["1", "4"],
+            matching(query().before(cursor(Some("b"), "3")))
+        );
+        assert_eq!(
+            vec!["1", "2", "3"],
+            matching(query().after(cursor(None, "4")))
+        );
+    }
+
+    #[test]
+    fn order_by_id() {
+        let query = EntityQuery::new(
+            SubgraphDeploymentId::new("keyset").unwrap(),
+            BLOCK_NUMBER_MAX,
+            EntityCollection::All(vec!["User".to_owned()]),
+        );
+        assert_eq!(vec!["3", "4"], matching(query.after(cursor(None, "2"))));
+    }
+
+    #[test]
+    fn before_counts_from_the_end() {
+        let query = query(EntityOrder::Ascending, NullsOrder::Last);
+        let after = restrict(query.clone().after(cursor(Some("a"), "2")), Ok).unwrap();
+        assert!(!after.range.from_end);
+        let before = restrict(query.before(cursor(Some("b"), "3")), Ok).unwrap();
+        assert!(before.range.from_end);
+    }
+
+    #[test]
+    fn ties_between_types_are_broken_by_type() {
+        // Bands and musicians can have the same id; the order is
+        // Band 1, Musician 1, Band 2, Musician 2
+        let entities = vec![
+            ("Band", "1"),
+            ("Musician", "1"),
+            ("Band", "2"),
+            ("Musician", "2"),
+        ]
+        .into_iter()
+        .map(|(entity_type, id)| {
+            let mut entity = Entity::new();
+            entity.set("__typename", entity_type);
+            entity.set("id", id);
+            entity
+        })
+        .collect::<Vec<_>>();
+        let query = || {
+            EntityQuery::new(
+                SubgraphDeploymentId::new("keyset").unwrap(),
+                BLOCK_NUMBER_MAX,
+                EntityCollection::All(vec!["Musician".to_owned(), "Band".to_owned()]),
+            )
+        };
+        let matching = |query: EntityQuery| {
+            restricted(query, entities.clone())
+                .into_iter()
+                .map(|entity| {
+                    format!(
+                        "{} {}",
+                        entity.get("__typename").unwrap(),
+                        entity.id().unwrap()
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec!["Musician 1", "Band 2", "Musician 2"],
+            matching(query().after(typed_cursor(None, "1", "Band")))
+        );
+        assert_eq!(
+            vec!["Band 1", "Musician 1"],
+            matching(query().before(typed_cursor(None, "2", "Band")))
+        );
+        assert_eq!(
+            vec!["Band 2"],
+            matching(
+                query()
+                    .after(typed_cursor(None, "1", "Musician"))
+                    .before(typed_cursor(None, "2", "Musician"))
+            )
+        );
+        // Ordered by `id` explicitly, the type still breaks ties
+        assert_eq!(
+            vec!["Band 1", "Musician 1", "Musician 2"],
+            matching(
+                query()
+                    .order_by("id", ValueType::ID, EntityOrder::Descending)
+                    .after(typed_cursor(None, "2", "Band"))
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_cursor() {
+        assert!(EntityCursor::decode("not a cursor").is_err());
+    }
+}
@@ -18,19 +18,29 @@ extern crate lazy_static;
 extern crate lru_time_cache;
 extern crate postgres;
 extern crate serde;
+extern crate serde_cbor;
 extern crate uuid;
+extern crate zstd;
 
+mod block_data;
 mod block_range;
 mod chain_head_listener;
 pub mod connection_pool;
+mod copy_state;
 mod db_schema;
 mod entities;
+mod entity_type_update;
 mod filter;
 mod functions;
 mod history_event;
 mod jsonb;
 mod jsonb_queries;
+mod keyset;
+mod maintenance;
+mod negative_cache;
 mod notification_listener;
+mod pending;
+mod proof_of_indexing;
 pub mod relational;
 mod relational_queries;
 mod sql_value;
@@ -48,7 +58,9 @@ pub mod layout_for_tests {
     pub use crate::block_range::*;
     pub use crate::entities::STRING_PREFIX_SIZE;
     pub use crate::relational::*;
+    pub use crate::relational_queries::WindowStrategy;
 }
 
 pub use self::chain_head_listener::ChainHeadUpdateListener;
+pub use self::maintenance::TableMaintenance;
 pub use self::store::{Store, StoreConfig};
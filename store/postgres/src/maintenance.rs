@@ -0,0 +1,278 @@
+//! Tune autovacuum for entity tables according to how much they churn.
+//! Postgres' default autovacuum settings only vacuum a table once a fifth
+//! of its rows are dead, which lets tables whose entities are updated or
+//! removed all the time bloat badly. This module periodically looks at the
+//! statistics Postgres keeps for each table in the subgraph schemas, lowers
+//! the autovacuum thresholds for tables with a lot of updates and deletes,
+//! and analyzes tables right away after large bulk changes so that the
+//! query planner does not work with stale statistics until autovacuum gets
+//! around to them.
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::sql_types::{BigInt, Double, Nullable, Text};
+use diesel::RunQueryDsl;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use graph::prelude::{futures03, info, o, tokio, warn, Error, Logger};
+
+lazy_static! {
+    /// How often to look at the churn of entity tables, in seconds. Setting
+    /// this to 0 turns table maintenance off
+    static ref TABLE_MAINTENANCE_INTERVAL: u64 = env::var("GRAPH_TABLE_MAINTENANCE_INTERVAL")
+        .unwrap_or("600".into())
+        .parse::<u64>()
+        .expect("invalid GRAPH_TABLE_MAINTENANCE_INTERVAL");
+}
+
+/// Tables with fewer rows than this are left to the default settings;
+/// vacuuming them is cheap no matter how they are tuned
+const MIN_LIVE_ROWS: i64 = 10_000;
+
+/// A table is analyzed right away if at least this many rows, and at least
+/// half of its rows, were changed since it was last analyzed
+const ANALYZE_MIN_CHANGES: i64 = 100_000;
+
+/// The autovacuum settings for a table, given as the fraction of its rows
+/// that have to be dead (for vacuum) or changed (for analyze) before
+/// autovacuum processes it
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ScaleFactors {
+    vacuum: f64,
+    analyze: f64,
+}
+
+impl ScaleFactors {
+    /// Postgres' own defaults
+    const DEFAULT: ScaleFactors = ScaleFactors {
+        vacuum: 0.2,
+        analyze: 0.1,
+    };
+
+    /// Choose settings from the number of rows updated or deleted during
+    /// the last interval, relative to the number of rows in the table. The
+    /// more a table churns, the sooner autovacuum needs to clean up after it
+    fn for_churn(churn: f64) -> ScaleFactors {
+        if churn >= 0.5 {
+            ScaleFactors {
+                vacuum: 0.01,
+                analyze: 0.005,
+            }
+        } else if churn >= 0.05 {
+            ScaleFactors {
+                vacuum: 0.05,
+                analyze: 0.02,
+            }
+        } else {
+            ScaleFactors::DEFAULT
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct TableStats {
+    #[sql_type = "Text"]
+    schema: String,
+    #[sql_type = "Text"]
+    table: String,
+    #[sql_type = "BigInt"]
+    live_rows: i64,
+    #[sql_type = "BigInt"]
+    churned_rows: i64,
+    #[sql_type = "BigInt"]
+    changed_since_analyze: i64,
+    #[sql_type = "Nullable<Double>"]
+    vacuum_scale_factor: Option<f64>,
+    #[sql_type = "Nullable<Double>"]
+    analyze_scale_factor: Option<f64>,
+}
+
+impl TableStats {
+    fn qualified_name(&self) -> String {
+        format!("\"{}\".\"{}\"", self.schema, self.table)
+    }
+
+    /// The settings the table has in the database, or `None` if only one
+    /// of them is set and the table needs to be changed either way
+    fn factors(&self) -> Option<ScaleFactors> {
+        match (self.vacuum_scale_factor, self.analyze_scale_factor) {
+            (None, None) => Some(ScaleFactors::DEFAULT),
+            (Some(vacuum), Some(analyze)) => Some(ScaleFactors { vacuum, analyze }),
+            _ => None,
+        }
+    }
+}
+
+/// What we remember about a table between runs
+struct TableState {
+    churned_rows: i64,
+    /// The settings the table has, or `None` if they need to be changed no
+    /// matter what. They are read from the database when we first see the
+    /// table, so that tables are not changed again every time the node
+    /// starts
+    factors: Option<ScaleFactors>,
+}
+
+/// Periodically adjusts the autovacuum settings of the tables in all
+/// subgraph schemas, and analyzes tables after large changes. Every change
+/// is logged; the settings currently in effect for a table can be seen in
+/// the `reloptions` column of `pg_class`.
+pub struct TableMaintenance {
+    logger: Logger,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    tables: Mutex<HashMap<String, TableState>>,
+}
+
+impl TableMaintenance {
+    pub fn new(logger: &Logger, pool: Pool<ConnectionManager<PgConnection>>) -> Self {
+        TableMaintenance {
+            logger: logger.new(o!("component" => "TableMaintenance")),
+            pool,
+            tables: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start running table maintenance in the background, unless it has
+    /// been turned off
+    pub fn start(self) {
+        use futures03::stream::StreamExt;
+
+        if *TABLE_MAINTENANCE_INTERVAL == 0 {
+            return;
+        }
+
+        // Blocking due to database interactions
+        graph::spawn_blocking(
+            tokio::time::interval(Duration::from_secs(*TABLE_MAINTENANCE_INTERVAL)).for_each(
+                move |_| {
+                    if let Err(e) = self.run() {
+                        warn!(self.logger, "Table maintenance failed"; "error" => e.to_string());
+                    }
+                    futures03::future::ready(())
+                },
+            ),
+        );
+    }
+
+    fn run(&self) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+
+        // Per-table statistics are kept for the tables in all subgraph
+        // schemas, including the `entities` table of JSONB storage. The
+        // autovacuum settings of a table are in its `reloptions`
+        let query = "select s.schemaname::text as schema,
+                            s.relname::text as table,
+                            s.n_live_tup as live_rows,
+                            (s.n_tup_upd + s.n_tup_del) as churned_rows,
+                            s.n_mod_since_analyze as changed_since_analyze,
+                            (select o.option_value::float8
+                               from pg_options_to_table(c.reloptions) o
+                              where o.option_name = 'autovacuum_vacuum_scale_factor')
+                              as vacuum_scale_factor,
+                            (select o.option_value::float8
+                               from pg_options_to_table(c.reloptions) o
+                              where o.option_name = 'autovacuum_analyze_scale_factor')
+                              as analyze_scale_factor
+                       from pg_stat_user_tables s
+                       join pg_class c on c.oid = s.relid
+                      where s.schemaname like 'sgd%'";
+        let stats = diesel::sql_query(query).load::<TableStats>(&*conn)?;
+
+        let mut tables = self.tables.lock().unwrap();
+        for stats in stats {
+            let name = stats.qualified_name();
+
+            if stats.changed_since_analyze >= ANALYZE_MIN_CHANGES
+                && stats.changed_since_analyze * 2 >= stats.live_rows
+            {
+                info!(self.logger, "Analyzing table after large changes";
+                      "table" => &name,
+                      "changed_rows" => stats.changed_since_analyze);
+                diesel::sql_query(format!("analyze {}", name)).execute(&*conn)?;
+            }
+
+            // The statistics counters are cumulative, and we can only tell
+            // how much a table churns once we have seen it before. If they
+            // went down, the statistics were reset
+            let seen = tables.get(&name).map_or(false, |previous| {
+                previous.churned_rows <= stats.churned_rows
+            });
+            if !seen {
+                tables.insert(
+                    name,
+                    TableState {
+                        churned_rows: stats.churned_rows,
+                        factors: stats.factors(),
+                    },
+                );
+                continue;
+            }
+            let previous = tables.get_mut(&name).unwrap();
+
+            let churn = if stats.live_rows < MIN_LIVE_ROWS {
+                0.0
+            } else {
+                (stats.churned_rows - previous.churned_rows) as f64 / stats.live_rows as f64
+            };
+            previous.churned_rows = stats.churned_rows;
+
+            let factors = ScaleFactors::for_churn(churn);
+            if Some(factors) != previous.factors {
+                info!(self.logger, "Changing autovacuum settings";
+                      "table" => &name,
+                      "churn" => churn,
+                      "vacuum_scale_factor" => factors.vacuum,
+                      "analyze_scale_factor" => factors.analyze);
+                let sql = if factors == ScaleFactors::DEFAULT {
+                    format!(
+                        "alter table {} reset (autovacuum_vacuum_scale_factor, \
+                         autovacuum_analyze_scale_factor)",
+                        name
+                    )
+                } else {
+                    format!(
+                        "alter table {} set (autovacuum_vacuum_scale_factor = {}, \
+                         autovacuum_analyze_scale_factor = {})",
+                        name, factors.vacuum, factors.analyze
+                    )
+                };
+                diesel::sql_query(sql).execute(&*conn)?;
+                previous.factors = Some(factors);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn scale_factors_for_churn() {
+    assert_eq!(ScaleFactors::DEFAULT, ScaleFactors::for_churn(0.0));
+    assert_eq!(ScaleFactors::DEFAULT, ScaleFactors::for_churn(0.049));
+    assert_eq!(0.05, ScaleFactors::for_churn(0.05).vacuum);
+    assert_eq!(0.01, ScaleFactors::for_churn(3.0).vacuum);
+}
+
+#[test]
+fn factors_from_reloptions() {
+    let stats = |vacuum_scale_factor, analyze_scale_factor| TableStats {
+        schema: "sgd1".to_owned(),
+        table: "thing".to_owned(),
+        live_rows: 0,
+        churned_rows: 0,
+        changed_since_analyze: 0,
+        vacuum_scale_factor,
+        analyze_scale_factor,
+    };
+    assert_eq!(Some(ScaleFactors::DEFAULT), stats(None, None).factors());
+    assert_eq!(
+        Some(ScaleFactors {
+            vacuum: 0.05,
+            analyze: 0.02
+        }),
+        stats(Some(0.05), Some(0.02)).factors()
+    );
+    assert_eq!(None, stats(Some(0.05), None).factors());
+}
@@ -0,0 +1,219 @@
+//! Remember which entities `Store::get` did not find, so that handlers that
+//! check whether an entity exists before creating it do not have to go to
+//! the database every time
+use lazy_static::lazy_static;
+use lru_time_cache::LruCache;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use graph::data::subgraph::schema::SUBGRAPHS_ID;
+use graph::prelude::{
+    CounterVec, Entity, EntityKey, MetricsRegistry, StoreEvent, SubgraphDeploymentId,
+};
+
+lazy_static! {
+    /// How many missing entities to remember for each deployment; 0 turns
+    /// the cache off
+    static ref NEGATIVE_CACHE_SIZE: usize = std::env::var("GRAPH_STORE_NEGATIVE_CACHE_SIZE")
+        .map(|s| s.parse::<usize>().expect("invalid GRAPH_STORE_NEGATIVE_CACHE_SIZE"))
+        .unwrap_or(10_000);
+}
+
+/// The missing entities of one deployment
+struct Missing {
+    /// Changed whenever entities of the deployment are written, so that a
+    /// lookup that raced with a write does not remember a stale result
+    generation: u64,
+    keys: LruCache<(String, String), ()>,
+}
+
+impl Missing {
+    fn new() -> Self {
+        Missing {
+            generation: 0,
+            keys: LruCache::with_capacity(*NEGATIVE_CACHE_SIZE),
+        }
+    }
+}
+
+pub(crate) struct NegativeCache {
+    deployments: Mutex<HashMap<SubgraphDeploymentId, Missing>>,
+    lookups: Box<CounterVec>,
+}
+
+impl NegativeCache {
+    pub fn new(registry: &dyn MetricsRegistry) -> Self {
+        let lookups = registry
+            .new_counter_vec(
+                String::from("store_get_lookups"),
+                String::from(
+                    "Counts entity lookups by whether the entity was found in the \
+                     database, was not found there, or was known to be missing",
+                ),
+                HashMap::new(),
+                vec![String::from("result")],
+            )
+            .expect("failed to create `store_get_lookups` counter");
+        NegativeCache {
+            deployments: Mutex::new(HashMap::new()),
+            lookups,
+        }
+    }
+
+    /// Look up the entity `key` with `find`, unless it is known to be
+    /// missing. Metadata entities are written by every node and are
+    /// therefore never cached
+    pub fn get<E>(
+        &self,
+        key: &EntityKey,
+        find: impl FnOnce() -> Result<Option<Entity>, E>,
+    ) -> Result<Option<Entity>, E> {
+        if *NEGATIVE_CACHE_SIZE == 0 || key.subgraph_id == *SUBGRAPHS_ID {
+            return find();
+        }
+
+        let cache_key = (key.entity_type.clone(), key.entity_id.clone());
+        let generation = {
+            let mut deployments = self.deployments.lock().unwrap();
+            let missing = deployments
+                .entry(key.subgraph_id.clone())
+                .or_insert_with(Missing::new);
+            if missing.keys.get(&cache_key).is_some() {
+                self.lookups.with_label_values(&["cached_not_found"]).inc();
+                return Ok(None);
+            }
+            missing.generation
+        };
+
+        let entity = find()?;
+        if entity.is_some() {
+            self.lookups.with_label_values(&["found"]).inc();
+        } else {
+            self.lookups.with_label_values(&["not_found"]).inc();
+            let mut deployments = self.deployments.lock().unwrap();
+            if let Some(missing) = deployments.get_mut(&key.subgraph_id) {
+                if missing.generation == generation {
+                    missing.keys.insert(cache_key, ());
+                }
+            }
+        }
+        Ok(entity)
+    }
+
+    /// Forget that the entities changed by `event` are missing. This must
+    /// be called for every write, whether it was made by this node or
+    /// another one
+    pub fn invalidate(&self, event: &StoreEvent) {
+        let mut deployments = self.deployments.lock().unwrap();
+        for change in &event.changes {
+            if let Some(missing) = deployments.get_mut(&change.subgraph_id) {
+                missing.generation += 1;
+                missing
+                    .keys
+                    .remove(&(change.entity_type.clone(), change.entity_id.clone()));
+            }
+        }
+    }
+
+    /// Forget everything about `subgraph`, for writes that do not report
+    /// the entities they change
+    pub fn clear(&self, subgraph: &SubgraphDeploymentId) {
+        self.deployments.lock().unwrap().remove(subgraph);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::prelude::{EntityChange, EntityChangeOperation};
+    use graph_mock::MockMetricsRegistry;
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn key(subgraph: &SubgraphDeploymentId, id: &str) -> EntityKey {
+        EntityKey {
+            subgraph_id: subgraph.clone(),
+            entity_type: "Thing".to_owned(),
+            entity_id: id.to_owned(),
+        }
+    }
+
+    fn changed(key: &EntityKey) -> StoreEvent {
+        StoreEvent::new(vec![EntityChange::from_key(
+            key.clone(),
+            EntityChangeOperation::Set,
+        )])
+    }
+
+    /// Look up `key` and return how often `find` had to be called
+    fn lookups(cache: &NegativeCache, key: &EntityKey, times: usize) -> usize {
+        let finds = Cell::new(0);
+        for _ in 0..times {
+            cache
+                .get(key, || -> Result<_, ()> {
+                    finds.set(finds.get() + 1);
+                    Ok(None)
+                })
+                .unwrap();
+        }
+        finds.get()
+    }
+
+    #[test]
+    fn remembers_missing_entities() {
+        let cache = NegativeCache::new(&MockMetricsRegistry::new());
+        let subgraph = SubgraphDeploymentId::new("QmNegative").unwrap();
+        let missing = key(&subgraph, "1");
+
+        assert_eq!(1, lookups(&cache, &missing, 3));
+
+        // Entities that exist are looked up every time
+        let found = key(&subgraph, "2");
+        let finds = Cell::new(0);
+        for _ in 0..2 {
+            cache
+                .get(&found, || -> Result<_, ()> {
+                    finds.set(finds.get() + 1);
+                    Ok(Some(Entity::new()))
+                })
+                .unwrap();
+        }
+        assert_eq!(2, finds.get());
+
+        // Metadata is never cached
+        assert_eq!(2, lookups(&cache, &key(&*SUBGRAPHS_ID, "1"), 2));
+    }
+
+    #[test]
+    fn forgets_changed_entities() {
+        let cache = NegativeCache::new(&MockMetricsRegistry::new());
+        let subgraph = SubgraphDeploymentId::new("QmNegative").unwrap();
+        let missing = key(&subgraph, "1");
+        let other = key(&subgraph, "2");
+
+        assert_eq!(1, lookups(&cache, &missing, 1));
+        assert_eq!(1, lookups(&cache, &other, 1));
+        cache.invalidate(&changed(&missing));
+        assert_eq!(1, lookups(&cache, &missing, 2));
+        assert_eq!(0, lookups(&cache, &other, 1));
+
+        cache.clear(&subgraph);
+        assert_eq!(1, lookups(&cache, &other, 1));
+    }
+
+    #[test]
+    fn does_not_remember_lookups_that_raced_with_a_write() {
+        let cache = NegativeCache::new(&MockMetricsRegistry::new());
+        let subgraph = SubgraphDeploymentId::new("QmNegative").unwrap();
+        let missing = key(&subgraph, "1");
+
+        // The entity is written while it is being looked up
+        cache
+            .get(&missing, || -> Result<_, ()> {
+                cache.invalidate(&changed(&missing));
+                Ok(None)
+            })
+            .unwrap();
+        assert_eq!(1, lookups(&cache, &missing, 1));
+    }
+}
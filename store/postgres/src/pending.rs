@@ -0,0 +1,751 @@
+//! The changes that the pending block on top of a deployment's latest block
+//! makes to the deployment's entities. They are stored in
+//! `pending_entities`, apart from the entities themselves, so that they
+//! never become part of the deployment's history, and so that all query
+//! nodes see them. The changes are replaced whenever the deployment indexes
+//! a new pending block, and removed whenever a block is written to or
+//! reverted from the deployment.
+//!
+//! Queries at `BLOCK_NUMBER_PENDING` run against the latest block, and the
+//! pending changes are then merged into their result in memory.
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Array, Text};
+use diesel::{delete, insert_into, sql_query};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use graph::prelude::{
+    serde_json, ChildFilter, Entity, EntityAggregation, EntityCollection, EntityFilter, EntityLink,
+    EntityModification, EntityOrder, EntityQuery, EntityRange, EthereumBlockPointer, NullsOrder,
+    QueryExecutionError, StoreError, SubgraphDeploymentId, Value, BLOCK_NUMBER_MAX,
+    BLOCK_NUMBER_PENDING,
+};
+
+use crate::db_schema::pending_entities as p;
+
+/// The attribute that holds the parent id in the results of queries for a
+/// windowed collection
+const PARENT_ID: &str = "g$parent_id";
+
+/// The pending changes of a deployment by entity type and id; the entity
+/// is `None` if the pending block removes it
+pub(crate) struct PendingEntities(HashMap<(String, String), Option<Entity>>);
+
+impl PendingEntities {
+    /// Whether the pending block changes any entity of type `entity_type`
+    pub(crate) fn changes(&self, entity_type: &str) -> bool {
+        self.0.keys().any(|(changed, _)| changed == entity_type)
+    }
+}
+
+pub(crate) fn load(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<PendingEntities, StoreError> {
+    let rows = p::table
+        .filter(p::subgraph.eq(subgraph.as_str()))
+        .select((p::entity, p::entity_id, p::data))
+        .load::<(String, String, Option<serde_json::Value>)>(conn)?;
+
+    rows.into_iter()
+        .map(|(entity, id, data)| -> Result<_, StoreError> {
+            let data = match data {
+                Some(data) => Some(serde_json::from_value::<Entity>(data)?),
+                None => None,
+            };
+            Ok(((entity, id), data))
+        })
+        .collect::<Result<_, _>>()
+        .map(PendingEntities)
+}
+
+pub(crate) fn clear(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<(), StoreError> {
+    delete(p::table.filter(p::subgraph.eq(subgraph.as_str()))).execute(conn)?;
+    Ok(())
+}
+
+/// Replace the pending changes of `subgraph` with `mods`
+pub(crate) fn replace(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    block: EthereumBlockPointer,
+    mods: Vec<EntityModification>,
+) -> Result<(), StoreError> {
+    clear(conn, subgraph)?;
+
+    let rows = mods
+        .into_iter()
+        .map(|modification| -> Result<_, StoreError> {
+            use EntityModification::*;
+
+            let (key, data) = match modification {
+                Insert { key, data } | Overwrite { key, data } => {
+                    (key, Some(serde_json::to_value(data)?))
+                }
+                Remove { key } => (key, None),
+            };
+            Ok((
+                p::subgraph.eq(subgraph.to_string()),
+                p::entity.eq(key.entity_type),
+                p::entity_id.eq(key.entity_id),
+                p::data.eq(data),
+                p::block_number.eq(block.number as i64),
+            ))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if !rows.is_empty() {
+        insert_into(p::table).values(rows).execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Sort `strings` with the collation of the database, which need not be
+/// the byte order Rust uses
+pub(crate) fn sort_strings(
+    conn: &PgConnection,
+    strings: Vec<String>,
+) -> Result<Vec<String>, StoreError> {
+    #[derive(QueryableByName)]
+    struct Sorted {
+        #[sql_type = "Text"]
+        s: String,
+    }
+
+    Ok(
+        sql_query("select s from unnest($1::text[]) as s order by s")
+            .bind::<Array<Text>, _>(strings)
+            .load::<Sorted>(conn)?
+            .into_iter()
+            .map(|sorted| sorted.s)
+            .collect(),
+    )
+}
+
+/// Run `query`, which is for `BLOCK_NUMBER_PENDING`, with `execute` at the
+/// latest block and merge the pending changes into the result. The filter,
+/// order and range of the query are applied to the pending versions of the
+/// entities the same way the store applies them; strings are ordered with
+/// `sort_strings` so that they end up in the order the database puts them
+/// in. Entities that the pending block creates can only be placed in
+/// windows whose children store the parent id; entities that are only
+/// linked from their parents show up in windows once they are no longer
+/// pending
+pub(crate) fn query<F, S>(
+    pending: &PendingEntities,
+    mut query: EntityQuery,
+    execute: F,
+    sort_strings: S,
+) -> Result<Vec<Entity>, QueryExecutionError>
+where
+    F: Fn(EntityQuery) -> Result<Vec<Entity>, QueryExecutionError>,
+    S: Fn(Vec<String>) -> Result<Vec<String>, QueryExecutionError>,
+{
+    query.block = BLOCK_NUMBER_MAX;
+    if pending.0.is_empty() {
+        return execute(query);
+    }
+    if let Some(filter) = query.filter.take() {
+        query.filter = Some(resolve_child_filters(
+            pending,
+            &query.subgraph_id,
+            filter,
+            &execute,
+            &sort_strings,
+        )?);
+    }
+
+    let range = query.range.clone();
+    let collection = query.collection.clone();
+    let filter = query.filter.clone();
+    let order = query.order_by.as_ref().map(|(attribute, _)| {
+        (
+            attribute.clone(),
+            query.order_direction.unwrap_or(EntityOrder::Ascending),
+            query.order_nulls.unwrap_or(NullsOrder::Last),
+        )
+    });
+
+    // Entities that the pending block changed are dropped from the result
+    // and replaced by their pending version if it still matches. Fetch
+    // enough extra entities to fill the requested range nonetheless
+    query.range = EntityRange {
+        first: range
+            .first
+            .map(|first| first + range.skip + pending.0.len() as u32),
+        skip: 0,
+        from_end: range.from_end,
+    };
+
+    let mut parents: HashMap<(String, String), Vec<Value>> = HashMap::new();
+    let mut entities = vec![];
+    for entity in execute(query)? {
+        let key = (string(&entity, "__typename"), string(&entity, "id"));
+        if pending.0.contains_key(&key) {
+            if let Some(parent_id) = entity.get(PARENT_ID) {
+                parents.entry(key).or_default().push(parent_id.clone());
+            }
+        } else {
+            entities.push(entity);
+        }
+    }
+
+    for (key, data) in &pending.0 {
+        let (entity_type, _) = key;
+        let data = match data {
+            Some(data) => data,
+            None => continue,
+        };
+
+        // Make the pending version look like an entity from the store
+        let mut entity = Entity::new();
+        entity.insert("__typename".to_owned(), Value::from(entity_type));
+        for (attribute, value) in data.iter() {
+            if *value != Value::Null {
+                entity.insert(attribute.clone(), value.clone());
+            }
+        }
+
+        if let Some(filter) = &filter {
+            if !filter.matches(&entity)? {
+                continue;
+            }
+        }
+
+        match &collection {
+            EntityCollection::All(entity_types) => {
+                if entity_types.contains(entity_type) {
+                    entities.push(entity);
+                }
+            }
+            EntityCollection::Window(windows) => {
+                let mut parent_ids: Vec<Value> = vec![];
+                for window in windows.iter().filter(|w| &w.child_type == entity_type) {
+                    let candidates = match &window.link {
+                        EntityLink::Direct(attribute) => match entity.get(attribute.name()) {
+                            Some(Value::List(values)) => values.clone(),
+                            Some(value) => vec![value.clone()],
+                            None => vec![],
+                        },
+                        EntityLink::Parent(_) => parents.get(key).cloned().unwrap_or_default(),
+                    };
+                    for parent_id in candidates {
+                        let in_window = match &parent_id {
+                            Value::String(id) => window.ids.contains(id),
+                            _ => false,
+                        };
+                        if in_window && !parent_ids.contains(&parent_id) {
+                            parent_ids.push(parent_id);
+                        }
+                    }
+                }
+                for parent_id in parent_ids {
+                    let mut entity = entity.clone();
+                    entity.insert(PARENT_ID.to_owned(), parent_id);
+                    entities.push(entity);
+                }
+            }
+        }
+    }
+
+    // Rank every string the entities are sorted by in the order of the
+    // database
+    let mut strings = HashSet::new();
+    for entity in &entities {
+        let attributes = order
+            .as_ref()
+            .map(|(attribute, _, _)| attribute.as_str())
+            .into_iter()
+            .chain(vec![PARENT_ID, "id", "__typename"]);
+        for attribute in attributes {
+            if let Some(Value::String(s)) = entity.get(attribute) {
+                strings.insert(s.clone());
+            }
+        }
+    }
+    let ranks = rank_strings(strings, sort_strings)?;
+    let compare = |a: &Value, b: &Value| compare_values(&ranks, a, b);
+
+    let get = |entity: &Entity, attribute: &str| -> Value {
+        entity.get(attribute).cloned().unwrap_or(Value::Null)
+    };
+    entities.sort_by(|a, b| {
+        compare(&get(a, PARENT_ID), &get(b, PARENT_ID))
+            .then_with(|| match &order {
+                Some((attribute, direction, nulls)) => {
+                    compare_attribute(a, b, attribute, *direction, *nulls, &compare)
+                }
+                None => Ordering::Equal,
+            })
+            .then_with(|| compare(&get(a, "id"), &get(b, "id")))
+            .then_with(|| compare(&get(a, "__typename"), &get(b, "__typename")))
+    });
+    // Counting from the end of each window is counting from the start of
+    // the reversed windows
+    if range.from_end {
+        entities.reverse();
+    }
+
+    // Apply the range to each window; without windows, all entities are
+    // in the same window
+    let mut result = vec![];
+    let mut window = None;
+    let mut pos = 0;
+    for entity in entities {
+        let parent_id = entity.get(PARENT_ID).cloned();
+        if parent_id != window {
+            window = parent_id;
+            pos = 0;
+        }
+        if pos >= range.skip && range.first.map_or(true, |first| pos < range.skip + first) {
+            result.push(entity);
+        }
+        pos += 1;
+    }
+    if range.from_end {
+        result.reverse();
+    }
+    Ok(result)
+}
+
+/// Replace the filters on linked entities in `filter` with filters on the
+/// ids of the linked entities that pass them at the pending block. The
+/// linked entities may themselves be pending, which the database can not
+/// see, and the pending versions of entities can only be checked against
+/// filters that do not need the entities they link to
+fn resolve_child_filters(
+    pending: &PendingEntities,
+    subgraph_id: &SubgraphDeploymentId,
+    filter: EntityFilter,
+    execute: &dyn Fn(EntityQuery) -> Result<Vec<Entity>, QueryExecutionError>,
+    sort_strings: &dyn Fn(Vec<String>) -> Result<Vec<String>, QueryExecutionError>,
+) -> Result<EntityFilter, QueryExecutionError> {
+    use EntityFilter as f;
+
+    let resolve =
+        |filter| resolve_child_filters(pending, subgraph_id, filter, execute, sort_strings);
+    Ok(match filter {
+        f::And(filters) => f::And(filters.into_iter().map(resolve).collect::<Result<_, _>>()?),
+        f::Or(filters) => f::Or(filters.into_iter().map(resolve).collect::<Result<_, _>>()?),
+        f::Negation(filter) => f::Negation(Box::new(resolve(*filter)?)),
+        f::TypeCondition(entity_type, filter) => {
+            f::TypeCondition(entity_type, Box::new(resolve(*filter)?))
+        }
+        f::Child(ChildFilter {
+            attribute,
+            entity_types,
+            derived,
+            list,
+            filter,
+        }) => {
+            let children = EntityQuery::new(
+                subgraph_id.clone(),
+                BLOCK_NUMBER_PENDING,
+                EntityCollection::All(entity_types),
+            )
+            .filter(*filter)
+            .range(EntityRange {
+                first: None,
+                skip: 0,
+                from_end: false,
+            });
+            let children = query(pending, children, execute, sort_strings)?;
+
+            // Either the children hold the ids of their parents, or the
+            // parents hold the ids of their children
+            let (attribute, ids) = if derived {
+                let mut ids = vec![];
+                for child in &children {
+                    match child.get(&attribute) {
+                        Some(Value::List(values)) => ids.extend(values.iter().cloned()),
+                        Some(Value::Null) | None => (),
+                        Some(value) => ids.push(value.clone()),
+                    }
+                }
+                ("id".to_owned(), ids)
+            } else {
+                let ids = children
+                    .iter()
+                    .filter_map(|child| child.get("id").cloned())
+                    .collect::<Vec<_>>();
+                (attribute, ids)
+            };
+            if ids.is_empty() {
+                f::Or(vec![])
+            } else if list && !derived {
+                f::ContainsAny(attribute, Value::List(ids))
+            } else {
+                f::In(attribute, ids)
+            }
+        }
+        filter => filter,
+    })
+}
+
+/// Run `aggregation`, which is for `BLOCK_NUMBER_PENDING`, over the
+/// pending versions of the entities it looks at. The entities are found
+/// with `query`, and `aggregate_entities` forms the groups from them,
+/// which are then ordered and cut to the range of `aggregation` the way
+/// the store does it
+pub(crate) fn aggregate<F, A, S>(
+    pending: &PendingEntities,
+    aggregation: EntityAggregation,
+    execute: F,
+    aggregate_entities: A,
+    sort_strings: S,
+) -> Result<Vec<Entity>, QueryExecutionError>
+where
+    F: Fn(EntityQuery) -> Result<Vec<Entity>, QueryExecutionError>,
+    A: FnOnce(&EntityAggregation, Vec<Entity>) -> Result<Vec<Entity>, QueryExecutionError>,
+    S: Fn(Vec<String>) -> Result<Vec<String>, QueryExecutionError>,
+{
+    let mut entity_query = EntityQuery::new(
+        aggregation.subgraph_id.clone(),
+        aggregation.block,
+        EntityCollection::All(vec![aggregation.entity_type.clone()]),
+    )
+    .range(EntityRange {
+        first: Some(aggregation.max_entities.saturating_add(1)),
+        skip: 0,
+        from_end: false,
+    });
+    if let Some(filter) = &aggregation.filter {
+        entity_query = entity_query.filter(filter.clone());
+    }
+    let entities = query(pending, entity_query, execute, &sort_strings)?;
+    if entities.len() > aggregation.max_entities as usize {
+        return Err(QueryExecutionError::TooManyEntitiesToAggregate(
+            aggregation.entity_type.clone(),
+            aggregation.max_entities,
+        ));
+    }
+    let mut groups = aggregate_entities(&aggregation, entities)?;
+
+    // Groups are ordered by their `group_by` values, with nulls last
+    let mut strings = HashSet::new();
+    for group in &groups {
+        for attribute in &aggregation.group_by {
+            if let Some(Value::String(s)) = group.get(attribute) {
+                strings.insert(s.clone());
+            }
+        }
+    }
+    let ranks = rank_strings(strings, sort_strings)?;
+    let compare = |a: &Value, b: &Value| compare_values(&ranks, a, b);
+    groups.sort_by(|a, b| {
+        aggregation
+            .group_by
+            .iter()
+            .fold(Ordering::Equal, |ordering, attribute| {
+                ordering.then_with(|| {
+                    compare_attribute(
+                        a,
+                        b,
+                        attribute,
+                        EntityOrder::Ascending,
+                        NullsOrder::Last,
+                        &compare,
+                    )
+                })
+            })
+    });
+
+    let range = aggregation.range;
+    Ok(groups
+        .into_iter()
+        .skip(range.skip as usize)
+        .take(range.first.map_or(usize::MAX, |first| first as usize))
+        .collect())
+}
+
+/// The position of each of `strings` in the order of the database
+fn rank_strings<S>(
+    strings: HashSet<String>,
+    sort_strings: S,
+) -> Result<HashMap<String, usize>, QueryExecutionError>
+where
+    S: FnOnce(Vec<String>) -> Result<Vec<String>, QueryExecutionError>,
+{
+    Ok(sort_strings(strings.into_iter().collect())?
+        .into_iter()
+        .enumerate()
+        .map(|(rank, s)| (s, rank))
+        .collect())
+}
+
+/// Compare values the way the database does, using `ranks` for strings
+fn compare_values(ranks: &HashMap<String, usize>, a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => ranks.get(a).cmp(&ranks.get(b)),
+        (a, b) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+    }
+}
+
+fn string(entity: &Entity, attribute: &str) -> String {
+    match entity.get(attribute) {
+        Some(Value::String(s)) => s.clone(),
+        _ => String::new(),
+    }
+}
+
+fn compare_attribute(
+    a: &Entity,
+    b: &Entity,
+    attribute: &str,
+    direction: EntityOrder,
+    nulls: NullsOrder,
+    compare: &dyn Fn(&Value, &Value) -> Ordering,
+) -> Ordering {
+    let get = |entity: &Entity| entity.get(attribute).filter(|value| **value != Value::Null);
+    let null_order = match nulls {
+        NullsOrder::First => Ordering::Less,
+        NullsOrder::Last => Ordering::Greater,
+    };
+    match (get(a), get(b)) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => null_order,
+        (Some(_), None) => null_order.reverse(),
+        (Some(a), Some(b)) => {
+            let ordering = compare(a, b);
+            match direction {
+                EntityOrder::Ascending => ordering,
+                EntityOrder::Descending => ordering.reverse(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::prelude::ValueType;
+
+    fn user(id: &str, name: Option<&str>) -> Entity {
+        let mut entity = Entity::new();
+        entity.set("__typename", "User");
+        entity.set("id", id);
+        if let Some(name) = name {
+            entity.set("name", name);
+        }
+        entity
+    }
+
+    fn ids(entities: Vec<Entity>) -> Vec<String> {
+        entities.iter().map(|entity| string(entity, "id")).collect()
+    }
+
+    #[test]
+    fn pending_entities_are_merged_in_database_order() {
+        let mut changes = HashMap::new();
+        let mut pending_b = user("b", Some("bob"));
+        pending_b.remove("__typename");
+        changes.insert(("User".to_owned(), "b".to_owned()), Some(pending_b));
+        changes.insert(("User".to_owned(), "c".to_owned()), None);
+        let pending = PendingEntities(changes);
+
+        let subgraph = SubgraphDeploymentId::new("pending").unwrap();
+        let query = EntityQuery::new(
+            subgraph,
+            BLOCK_NUMBER_PENDING,
+            EntityCollection::All(vec!["User".to_owned()]),
+        )
+        .filter(EntityFilter::Not("name".to_owned(), "carol".into()))
+        .order_by("name", ValueType::String, EntityOrder::Ascending);
+
+        // The database compares strings without regard to case here
+        let execute = |_| {
+            Ok(vec![
+                user("a", Some("Alice")),
+                user("c", Some("Carl")),
+                user("d", Some("Dave")),
+            ])
+        };
+        let sort_strings = |mut strings: Vec<String>| {
+            strings.sort_by_key(|s| s.to_lowercase());
+            Ok(strings)
+        };
+
+        // `c` is removed by the pending block, and `b` is put between
+        // `Alice` and `Dave` although `bob` comes after both in byte order
+        let entities = super::query(&pending, query, execute, sort_strings).unwrap();
+        assert_eq!(vec!["a", "b", "d"], ids(entities));
+    }
+
+    #[test]
+    fn nested_filters_see_pending_entities() {
+        let friend = |id: &str, name: Option<&str>, friend: Option<&str>| {
+            let mut entity = user(id, name);
+            if let Some(friend) = friend {
+                entity.set("friend", friend);
+            }
+            entity
+        };
+
+        // `b` is renamed to `bob` and `d` is no longer `bob`; `e` is new
+        let mut changes = HashMap::new();
+        let pending_user = |id, name, friend_id| {
+            let mut entity = friend(id, name, friend_id);
+            entity.remove("__typename");
+            Some(entity)
+        };
+        changes.insert(
+            ("User".to_owned(), "b".to_owned()),
+            pending_user("b", Some("bob"), None),
+        );
+        changes.insert(
+            ("User".to_owned(), "d".to_owned()),
+            pending_user("d", Some("dan"), None),
+        );
+        changes.insert(
+            ("User".to_owned(), "e".to_owned()),
+            pending_user("e", None, Some("b")),
+        );
+        let pending = PendingEntities(changes);
+
+        // The database checks filters like the entities would
+        let latest = vec![
+            friend("a", None, Some("b")),
+            friend("b", Some("bert"), None),
+            friend("c", None, Some("d")),
+            friend("d", Some("bob"), None),
+        ];
+        let execute = |query: EntityQuery| -> Result<Vec<Entity>, QueryExecutionError> {
+            let mut entities = vec![];
+            for entity in &latest {
+                if query
+                    .filter
+                    .as_ref()
+                    .map_or(Ok(true), |f| f.matches(entity))?
+                {
+                    entities.push(entity.clone());
+                }
+            }
+            Ok(entities)
+        };
+
+        let subgraph = SubgraphDeploymentId::new("pending").unwrap();
+        let query = EntityQuery::new(
+            subgraph,
+            BLOCK_NUMBER_PENDING,
+            EntityCollection::All(vec!["User".to_owned()]),
+        )
+        .filter(EntityFilter::Child(ChildFilter {
+            attribute: "friend".to_owned(),
+            entity_types: vec!["User".to_owned()],
+            derived: false,
+            list: false,
+            filter: Box::new(EntityFilter::Equal("name".to_owned(), "bob".into())),
+        }));
+
+        let sort_strings = |mut strings: Vec<String>| {
+            strings.sort();
+            Ok(strings)
+        };
+        let entities = super::query(&pending, query, execute, sort_strings).unwrap();
+        assert_eq!(vec!["a", "e"], ids(entities));
+    }
+
+    #[test]
+    fn pending_entities_are_aggregated() {
+        let player = |id: &str, team: &str| {
+            let mut entity = Entity::new();
+            entity.set("__typename", "Player");
+            entity.set("id", id);
+            entity.set("team", team);
+            entity
+        };
+
+        let mut changes = HashMap::new();
+        let mut pending_b = player("b", "blue");
+        pending_b.remove("__typename");
+        changes.insert(("Player".to_owned(), "b".to_owned()), Some(pending_b));
+        changes.insert(("Player".to_owned(), "c".to_owned()), None);
+        let pending = PendingEntities(changes);
+        assert!(pending.changes("Player"));
+        assert!(!pending.changes("Team"));
+
+        let aggregation = |max_entities| EntityAggregation {
+            subgraph_id: SubgraphDeploymentId::new("pending").unwrap(),
+            block: BLOCK_NUMBER_PENDING,
+            entity_type: "Player".to_owned(),
+            filter: None,
+            group_by: vec!["team".to_owned()],
+            range: EntityRange::first(100),
+            max_entities,
+        };
+        let execute = |_| {
+            Ok(vec![
+                player("a", "Red"),
+                player("c", "blue"),
+                player("d", "Red"),
+            ])
+        };
+        // Count the players of each team in the order they come in
+        let aggregate_entities = |_: &EntityAggregation, entities: Vec<Entity>| {
+            let mut groups: Vec<Entity> = vec![];
+            for entity in entities {
+                let team = entity.get("team").cloned().unwrap();
+                match groups
+                    .iter_mut()
+                    .find(|group| group.get("team") == Some(&team))
+                {
+                    Some(group) => {
+                        let count = group.get("count").cloned().unwrap().as_int().unwrap();
+                        group.set("count", count + 1);
+                    }
+                    None => {
+                        let mut group = Entity::new();
+                        group.set("team", team);
+                        group.set("count", 1);
+                        groups.push(group);
+                    }
+                }
+            }
+            Ok(groups)
+        };
+        let sort_strings = |mut strings: Vec<String>| {
+            strings.sort_by_key(|s| s.to_lowercase());
+            Ok(strings)
+        };
+
+        // `c` is removed and `b` moves to `blue`, which the database puts
+        // before `Red`
+        let groups = aggregate(
+            &pending,
+            aggregation(3),
+            execute,
+            aggregate_entities,
+            sort_strings,
+        )
+        .unwrap();
+        let groups = groups
+            .iter()
+            .map(|group| (string(group, "team"), group.get("count").cloned()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("blue".to_owned(), Some(Value::Int(1))),
+                ("Red".to_owned(), Some(Value::Int(2)))
+            ],
+            groups
+        );
+
+        // The pending block leaves three players to aggregate
+        match aggregate(
+            &pending,
+            aggregation(2),
+            execute,
+            aggregate_entities,
+            sort_strings,
+        ) {
+            Err(QueryExecutionError::TooManyEntitiesToAggregate(entity_type, 2)) => {
+                assert_eq!("Player", entity_type)
+            }
+            result => panic!("expected too many entities, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! The proof of indexing of a deployment for a block is a digest of all the
+//! changes the deployment made to its entities up to and including that
+//! block. It is computed from the proof of the previous block the
+//! deployment processed, the hash of the block, and the changes of the
+//! block, and stored in `proof_of_indexing`. Since handlers are
+//! deterministic, indexers that agree on the proof for a block agree on the
+//! data of the deployment at that block, and the first block for which two
+//! indexers disagree is the first one where their data diverged.
+//!
+//! Changes to metadata are not part of the proof since they record how the
+//! block was processed, for example how long handlers took, which differs
+//! from indexer to indexer.
+//!
+//! The proofs of recent blocks are all kept. Of older blocks, only the
+//! proofs of every `GRAPH_PROOF_OF_INDEXING_INTERVAL`th block are kept, so
+//! that the first block for which indexers disagree can still be narrowed
+//! down without keeping a row for every block forever. A deployment can
+//! therefore only be rewound to a block whose proof is still kept, and a
+//! deployment that is grafted onto a block whose proof is no longer kept
+//! starts a new chain of proofs.
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+use diesel::{delete, insert_into};
+use lazy_static::lazy_static;
+use std::str::FromStr;
+
+use graph::prelude::{
+    web3::types::H256, BlockNumber, EntityModification, EthereumBlockPointer, ProofOfIndexing,
+    StoreError, SubgraphDeploymentId,
+};
+
+use crate::db_schema::proof_of_indexing as p;
+
+lazy_static! {
+    /// For how many blocks before the latest one the proofs of all blocks
+    /// are kept
+    static ref PROOF_OF_INDEXING_HISTORY: i64 =
+        std::env::var("GRAPH_PROOF_OF_INDEXING_HISTORY")
+            .map(|s| s.parse::<i64>().expect("invalid GRAPH_PROOF_OF_INDEXING_HISTORY"))
+            .unwrap_or(10_000);
+
+    /// Of older blocks, the proofs of blocks whose number is a multiple of
+    /// this are kept
+    static ref PROOF_OF_INDEXING_INTERVAL: i64 =
+        std::env::var("GRAPH_PROOF_OF_INDEXING_INTERVAL")
+            .map(|s| s.parse::<i64>().expect("invalid GRAPH_PROOF_OF_INDEXING_INTERVAL"))
+            .unwrap_or(1_000);
+}
+
+/// A digest as it is stored in `proof_of_indexing`
+fn to_h256(bytes: &[u8]) -> Result<H256, StoreError> {
+    if bytes.len() != 32 {
+        return Err(StoreError::QueryExecutionError(format!(
+            "invalid proof of indexing of length {}",
+            bytes.len()
+        )));
+    }
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(bytes);
+    Ok(H256::from(digest))
+}
+
+/// Compute the proof of `subgraph` for `block` from the changes `mods` of
+/// the block and store it
+pub(crate) fn record(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    block: &EthereumBlockPointer,
+    mods: &[EntityModification],
+) -> Result<(), StoreError> {
+    let previous = p::table
+        .filter(p::subgraph.eq(subgraph.as_str()))
+        .filter(p::block_number.lt(block.number as i64))
+        .order(p::block_number.desc())
+        .select(p::digest)
+        .first::<Vec<u8>>(conn)
+        .optional()?
+        .map(|digest| to_h256(&digest))
+        .transpose()?;
+    let digest = ProofOfIndexing::digest(previous, block, mods)?;
+
+    insert_into(p::table)
+        .values((
+            p::subgraph.eq(subgraph.as_str()),
+            p::block_number.eq(block.number as i64),
+            p::block_hash.eq(block.hash_hex()),
+            p::digest.eq(digest.as_ref()),
+        ))
+        .execute(conn)?;
+    prune(conn, subgraph, block.number as i64)
+}
+
+/// Remove the proofs of `subgraph` that are no longer kept now that it
+/// processed block `latest`
+fn prune(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    latest: i64,
+) -> Result<(), StoreError> {
+    let before = latest - *PROOF_OF_INDEXING_HISTORY;
+    if before <= 0 {
+        return Ok(());
+    }
+    diesel::sql_query(
+        "delete from proof_of_indexing
+          where subgraph = $1
+            and block_number < $2
+            and block_number % $3 <> 0",
+    )
+    .bind::<Text, _>(subgraph.as_str())
+    .bind::<BigInt, _>(before)
+    .bind::<BigInt, _>((*PROOF_OF_INDEXING_INTERVAL).max(1))
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Whether the proofs of `subgraph` can continue from `block`: either its
+/// proof for `block` is kept, or it has no proofs up to `block` at all
+pub(crate) fn can_continue_from(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    block: BlockNumber,
+) -> Result<bool, StoreError> {
+    let latest = p::table
+        .filter(p::subgraph.eq(subgraph.as_str()))
+        .filter(p::block_number.le(block as i64))
+        .select(diesel::dsl::max(p::block_number))
+        .first::<Option<i64>>(conn)?;
+    Ok(latest.map_or(true, |latest| latest == block as i64))
+}
+
+/// Remove all proofs of `subgraph`
+pub(crate) fn remove(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<(), StoreError> {
+    delete(p::table.filter(p::subgraph.eq(subgraph.as_str()))).execute(conn)?;
+    Ok(())
+}
+
+/// Remove the proofs of `subgraph` for `block` and all later blocks
+pub(crate) fn revert(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    block: BlockNumber,
+) -> Result<(), StoreError> {
+    delete(
+        p::table
+            .filter(p::subgraph.eq(subgraph.as_str()))
+            .filter(p::block_number.ge(block as i64)),
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Give `subgraph` the proofs that `base` has for `block` and earlier
+/// blocks, for a deployment that starts from the data of `base`
+pub(crate) fn copy(
+    conn: &PgConnection,
+    base: &SubgraphDeploymentId,
+    subgraph: &SubgraphDeploymentId,
+    block: BlockNumber,
+) -> Result<(), StoreError> {
+    if !can_continue_from(conn, base, block)? {
+        return Ok(());
+    }
+    let rows = p::table
+        .filter(p::subgraph.eq(base.as_str()))
+        .filter(p::block_number.le(block as i64))
+        .select((p::block_number, p::block_hash, p::digest))
+        .load::<(i64, String, Vec<u8>)>(conn)?
+        .into_iter()
+        .map(|(block_number, block_hash, digest)| {
+            (
+                p::subgraph.eq(subgraph.to_string()),
+                p::block_number.eq(block_number),
+                p::block_hash.eq(block_hash),
+                p::digest.eq(digest),
+            )
+        })
+        .collect::<Vec<_>>();
+    if !rows.is_empty() {
+        insert_into(p::table).values(rows).execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Turn a row of `proof_of_indexing` into a proof
+fn to_proof(block: i64, hash: String, digest: Vec<u8>) -> Result<ProofOfIndexing, StoreError> {
+    let hash = H256::from_str(&hash).map_err(|e| {
+        StoreError::QueryExecutionError(format!("invalid block hash `{}`: {}", hash, e))
+    })?;
+    Ok(ProofOfIndexing {
+        block: EthereumBlockPointer {
+            hash,
+            number: block as u64,
+        },
+        digest: to_h256(&digest)?,
+    })
+}
+
+/// The proof of `subgraph` for `block`, if it processed that block
+pub(crate) fn find(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    block: BlockNumber,
+) -> Result<Option<ProofOfIndexing>, StoreError> {
+    p::table
+        .filter(p::subgraph.eq(subgraph.as_str()))
+        .filter(p::block_number.eq(block as i64))
+        .select((p::block_number, p::block_hash, p::digest))
+        .first::<(i64, String, Vec<u8>)>(conn)
+        .optional()?
+        .map(|(block, hash, digest)| to_proof(block, hash, digest))
+        .transpose()
+}
+
+/// The proofs of `subgraph` for the blocks from `from` to `to`, inclusive,
+/// that it processed, ordered by block number
+pub(crate) fn find_range(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    from: BlockNumber,
+    to: BlockNumber,
+) -> Result<Vec<ProofOfIndexing>, StoreError> {
+    p::table
+        .filter(p::subgraph.eq(subgraph.as_str()))
+        .filter(p::block_number.ge(from as i64))
+        .filter(p::block_number.le(to as i64))
+        .order(p::block_number)
+        .select((p::block_number, p::block_hash, p::digest))
+        .load::<(i64, String, Vec<u8>)>(conn)?
+        .into_iter()
+        .map(|(block, hash, digest)| to_proof(block, hash, digest))
+        .collect()
+}
@@ -7,30 +7,84 @@
 //! The pivotal struct in this module is the `Layout` which handles all the
 //! information about mapping a GraphQL schema to database tables
 use diesel::connection::SimpleConnection;
+use diesel::sql_types::{BigInt, Bool, Double, Integer, Nullable, Text};
 use diesel::{debug_query, OptionalExtension, PgConnection, RunQueryDsl};
 use graphql_parser::query as q;
 use graphql_parser::schema as s;
 use inflector::Inflector;
+use lazy_static::lazy_static;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
 use std::fmt::{self, Write};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
 use crate::relational_queries::{
-    ClampRangeQuery, ConflictingEntityQuery, EntityData, FilterQuery, FindManyQuery, FindQuery,
-    InsertQuery, RevertClampQuery, RevertRemoveQuery,
+    AggregateQuery, BlockRangeBound, ClampRangeQuery, ConflictingEntityQuery, EntityData,
+    FilterQuery, FindManyQuery, FindQuery, InsertQuery, RemoveVersionsQuery, RevertClampQuery,
+    RevertRemoveQuery, VersionsChangedQuery, WindowStrategy,
+};
+use graph::data::schema::{
+    FulltextAlgorithm, FulltextDefinition, FulltextLanguage, Schema, PARTITION_DIRECTIVE,
+    SCHEMA_TYPE_NAME,
 };
-use graph::data::schema::SCHEMA_TYPE_NAME;
 use graph::prelude::{
-    format_err, trace, BlockNumber, Entity, EntityChange, EntityChangeOperation, EntityCollection,
-    EntityFilter, EntityKey, EntityOrder, EntityRange, Logger, QueryExecutionError, StoreError,
-    StoreEvent, SubgraphDeploymentId, ValueType,
+    format_err, trace, BlockEntityChange, BlockEntityChangeKind, BlockNumber, Entity,
+    EntityAggregation, EntityChange, EntityChangeOperation, EntityCollection, EntityDiff,
+    EntityFilter, EntityKey, EntityOrder, EntityRange, IndexCreation, Logger, NullsOrder,
+    OversizedEntityIds, QueryExecutionError, StoreError, StoreEvent, SubgraphDeploymentId,
+    ValueType,
 };
 
-use crate::block_range::BLOCK_RANGE_COLUMN;
+use crate::block_range::{BLOCK_RANGE_COLUMN, BLOCK_RANGE_UPPER};
 use crate::entities::STRING_PREFIX_SIZE;
 
+lazy_static! {
+    /// Deployments whose queries for the children of several parents always
+    /// use the given strategy instead of one chosen from table statistics
+    static ref FORCED_WINDOW_STRATEGIES: HashMap<SubgraphDeploymentId, WindowStrategy> =
+        env::var("GRAPH_QUERY_WINDOW_STRATEGY")
+            .ok()
+            .map(|strategies| {
+                parse_window_strategies(&strategies).expect("invalid GRAPH_QUERY_WINDOW_STRATEGY")
+            })
+            .unwrap_or_default();
+}
+
+/// Look up the children of each parent separately when parents have at
+/// least this many children on average
+const LATERAL_MIN_CHILDREN_PER_PARENT: f64 = 100.0;
+
+/// How long an estimate of the number of children per parent is used
+/// before it is made again from current statistics
+const CHILDREN_PER_PARENT_TTL: Duration = Duration::from_secs(300);
+
+/// Parse a list of window strategies of the form `deployment:strategy,...`
+fn parse_window_strategies(
+    strategies: &str,
+) -> Result<HashMap<SubgraphDeploymentId, WindowStrategy>, String> {
+    strategies
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(deployment), Some(strategy)) => {
+                    let deployment = SubgraphDeploymentId::new(deployment)
+                        .map_err(|()| format!("invalid deployment ID `{}`", deployment))?;
+                    Ok((deployment, strategy.parse()?))
+                }
+                _ => Err(format!(
+                    "window strategy `{}` must have the form `deployment:strategy`",
+                    entry
+                )),
+            }
+        })
+        .collect()
+}
+
 /// A string we use as a SQL name for a table or column. The important thing
 /// is that SQL names are snake cased. Using this type makes it easier to
 /// spot cases where we use a GraphQL name like 'bigThing' when we should
@@ -126,6 +180,11 @@ pub enum IdType {
 
 type EnumMap = BTreeMap<String, Vec<String>>;
 
+/// Estimates of the number of children per parent, keyed by the name of the
+/// child table and the column that links children to their parent, with
+/// the time they were made
+type ChildrenPerParent = RwLock<HashMap<(SqlName, SqlName), (Instant, Option<f64>)>>;
+
 #[derive(Debug, Clone)]
 pub struct Layout {
     /// The SQL type for columns with GraphQL type `ID`
@@ -145,6 +204,13 @@ pub struct Layout {
     pub enums: EnumMap,
     /// The query to count all entities
     pub count_query: String,
+    /// What we know about the tables of this layout that helps choose how
+    /// to query the children of several parents. It is kept per layout so
+    /// that queries for different subgraphs do not contend for it
+    children_per_parent: Arc<ChildrenPerParent>,
+    /// The strategy to use for all queries for the children of several
+    /// parents instead of one chosen from table statistics
+    forced_window_strategy: Option<WindowStrategy>,
 }
 
 impl Layout {
@@ -172,6 +238,7 @@ impl Layout {
         let mut interfaces: HashMap<String, Vec<SqlName>> = HashMap::new();
         let mut tables = Vec::new();
         let mut enums = EnumMap::new();
+        let fulltext = Schema::fulltext_definitions(document);
 
         for defn in &document.definitions {
             match defn {
@@ -183,6 +250,7 @@ impl Layout {
                         &schema,
                         &mut interfaces,
                         &enums,
+                        &fulltext,
                         id_type,
                         tables.len() as u32,
                     )?;
@@ -192,6 +260,10 @@ impl Layout {
                     SqlName::check_valid_identifier(&interface_type.name, "interface")?;
                     interfaces.insert(interface_type.name.clone(), vec![]);
                 }
+                // Unions are queried through the tables of their members
+                TypeDefinition(Union(union_type)) => {
+                    SqlName::check_valid_identifier(&union_type.name, "union")?;
+                }
                 TypeDefinition(Enum(enum_type)) => {
                     SqlName::check_valid_identifier(&enum_type.name, "enum")?;
                     let values: Vec<_> = enum_type
@@ -251,15 +323,26 @@ impl Layout {
 
         Ok(Layout {
             id_type,
-            subgraph,
             schema,
             tables,
             interfaces,
             enums,
             count_query,
+            children_per_parent: Arc::new(RwLock::new(HashMap::new())),
+            forced_window_strategy: FORCED_WINDOW_STRATEGIES.get(&subgraph).cloned(),
+            subgraph,
         })
     }
 
+    /// Use `strategy` for all queries for the children of several parents,
+    /// no matter what the table statistics say
+    pub fn with_window_strategy(self, strategy: WindowStrategy) -> Self {
+        Layout {
+            forced_window_strategy: Some(strategy),
+            ..self
+        }
+    }
+
     pub fn create_relational_schema(
         conn: &PgConnection,
         schema_name: &str,
@@ -400,7 +483,7 @@ impl Layout {
         conn: &PgConnection,
         collection: EntityCollection,
         filter: Option<EntityFilter>,
-        order: Option<(String, ValueType, EntityOrder)>,
+        order: Option<(String, ValueType, EntityOrder, NullsOrder)>,
         range: EntityRange,
         block: BlockNumber,
     ) -> Result<Vec<Entity>, QueryExecutionError> {
@@ -422,7 +505,15 @@ impl Layout {
                 "time_ms" => elapsed.as_millis()
             );
         }
+        let from_end = range.from_end;
         let query = FilterQuery::new(&self, collection, filter.as_ref(), order, range, block)?;
+        let query = match query.lateral_candidate() {
+            Some((table, column)) => {
+                let strategy = self.window_strategy(logger, conn, table, column);
+                query.with_window_strategy(strategy)
+            }
+            None => query,
+        };
         let query_clone = query.clone();
 
         let start = Instant::now();
@@ -434,12 +525,168 @@ impl Layout {
             ))
         })?;
         log_query_timing(logger, &query_clone, start.elapsed());
-        values
+        let mut entities = values
             .into_iter()
             .map(|entity_data| entity_data.to_entity(self).map_err(|e| e.into()))
+            .collect::<Result<Vec<_>, QueryExecutionError>>()?;
+        // The last entities are found in reverse order
+        if from_end {
+            entities.reverse();
+        }
+        Ok(entities)
+    }
+
+    pub fn aggregate(
+        &self,
+        conn: &PgConnection,
+        aggregation: EntityAggregation,
+    ) -> Result<Vec<Entity>, QueryExecutionError> {
+        let table = self.table_for_entity(&aggregation.entity_type)?;
+        let query = AggregateQuery::new(
+            &self,
+            table.as_ref(),
+            aggregation.filter.as_ref(),
+            &aggregation.group_by,
+            aggregation.range,
+            aggregation.block,
+            aggregation.max_entities,
+        )?;
+        let attributes = query.attributes();
+        let query_clone = query.clone();
+        let values = query.load::<EntityData>(conn).map_err(|e| {
+            QueryExecutionError::ResolveEntitiesError(format!(
+                "{}, query = {:?}",
+                e,
+                debug_query(&query_clone).to_string()
+            ))
+        })?;
+        if values.first().map_or(false, |data| {
+            data.aggregated_entities() > aggregation.max_entities as u64
+        }) {
+            return Err(QueryExecutionError::TooManyEntitiesToAggregate(
+                aggregation.entity_type.clone(),
+                aggregation.max_entities,
+            ));
+        }
+        values
+            .into_iter()
+            .map(|entity_data| entity_data.to_aggregate(&attributes).map_err(|e| e.into()))
             .collect()
     }
 
+    /// Compute the groups of `aggregation` from `entities` in memory; the
+    /// groups are neither ordered nor restricted to the range of
+    /// `aggregation`. This is used for aggregations that have to see
+    /// entities that are not in the database yet
+    pub fn aggregate_entities(
+        &self,
+        aggregation: &EntityAggregation,
+        entities: Vec<Entity>,
+    ) -> Result<Vec<Entity>, QueryExecutionError> {
+        let table = self.table_for_entity(&aggregation.entity_type)?;
+        let query = AggregateQuery::new(
+            &self,
+            table.as_ref(),
+            aggregation.filter.as_ref(),
+            &aggregation.group_by,
+            aggregation.range.clone(),
+            aggregation.block,
+            aggregation.max_entities,
+        )?;
+        Ok(query.aggregate_entities(entities))
+    }
+
+    /// Choose how to query the children of several parents from `table`
+    /// that link to their parent through `column`, unless the strategy
+    /// for this deployment is forced
+    fn window_strategy(
+        &self,
+        logger: &Logger,
+        conn: &PgConnection,
+        table: &Table,
+        column: &Column,
+    ) -> WindowStrategy {
+        if let Some(strategy) = self.forced_window_strategy {
+            return strategy;
+        }
+        match self.children_per_parent(conn, table, column) {
+            Ok(Some(children)) if children >= LATERAL_MIN_CHILDREN_PER_PARENT => {
+                WindowStrategy::Lateral
+            }
+            Ok(_) => WindowStrategy::Rank,
+            Err(e) => {
+                // Statistics only help choose a strategy, and every
+                // strategy produces the same result
+                trace!(logger, "Failed to get statistics for window query";
+                       "table" => table.qualified_name.as_str(),
+                       "error" => e.to_string());
+                WindowStrategy::Rank
+            }
+        }
+    }
+
+    /// Estimate the average number of children per parent from the number
+    /// of rows in `table` and the number of distinct values in `column`
+    /// that Postgres keeps in its statistics. Returns `None` if the table
+    /// has not been analyzed yet
+    fn children_per_parent(
+        &self,
+        conn: &PgConnection,
+        table: &Table,
+        column: &Column,
+    ) -> Result<Option<f64>, StoreError> {
+        #[derive(QueryableByName)]
+        struct ColumnStats {
+            #[sql_type = "Double"]
+            rows: f64,
+            #[sql_type = "Nullable<Double>"]
+            n_distinct: Option<f64>,
+        }
+
+        let key = (table.name.clone(), column.name.clone());
+        if let Some((made_at, estimate)) = self.children_per_parent.read().unwrap().get(&key) {
+            if made_at.elapsed() < CHILDREN_PER_PARENT_TTL {
+                return Ok(*estimate);
+            }
+        }
+
+        let query = "select c.reltuples::float8 as rows,
+                            s.n_distinct::float8 as n_distinct
+                       from pg_class c
+                       join pg_namespace n on n.oid = c.relnamespace
+                       left join pg_stats s
+                         on s.schemaname = n.nspname
+                        and s.tablename = c.relname
+                        and s.attname = $3
+                      where n.nspname = $1
+                        and c.relname = $2";
+        let stats = diesel::sql_query(query)
+            .bind::<Text, _>(&self.schema)
+            .bind::<Text, _>(table.name.as_str())
+            .bind::<Text, _>(column.name.as_str())
+            .get_result::<ColumnStats>(conn)
+            .optional()?;
+        let estimate = stats.and_then(|stats| {
+            // A negative `n_distinct` is the number of distinct values as
+            // a fraction of the number of rows
+            let distinct = match stats.n_distinct? {
+                n_distinct if n_distinct < 0.0 => -n_distinct * stats.rows,
+                n_distinct => n_distinct,
+            };
+            if distinct > 0.0 {
+                Some(stats.rows / distinct)
+            } else {
+                None
+            }
+        });
+
+        self.children_per_parent
+            .write()
+            .unwrap()
+            .insert(key, (Instant::now(), estimate));
+        Ok(estimate)
+    }
+
     pub fn update(
         &self,
         conn: &PgConnection,
@@ -464,6 +711,42 @@ impl Layout {
         Ok(ClampRangeQuery::new(table, key, block).execute(conn)?)
     }
 
+    /// Replace all versions of the entity `key` with a single version that
+    /// holds `entity` and is visible at every block, or remove the entity
+    /// entirely if `entity` is `None`. Since such a version starts at block
+    /// 0, reverting blocks never touches it. Return the number of versions
+    /// that were removed
+    pub fn overwrite_unversioned(
+        &self,
+        conn: &PgConnection,
+        key: &EntityKey,
+        entity: Option<&Entity>,
+    ) -> Result<usize, StoreError> {
+        let table = self.table_for_entity(&key.entity_type)?;
+        let removed = RemoveVersionsQuery::new(table, key).execute(conn)?;
+        if let Some(entity) = entity {
+            table.ensure_partition(conn, &self.schema, None, 0)?;
+            InsertQuery::new(table, key, entity, 0)?.execute(conn)?;
+        }
+        Ok(removed)
+    }
+
+    /// Make sure that tables that are partitioned by block have a
+    /// partition for the data we are about to write at `block` when the
+    /// subgraph advances from block `from`, which is `None` if the subgraph
+    /// has not processed any blocks yet
+    pub fn ensure_partitions(
+        &self,
+        conn: &PgConnection,
+        from: Option<BlockNumber>,
+        block: BlockNumber,
+    ) -> Result<(), StoreError> {
+        for table in self.tables.values() {
+            table.ensure_partition(conn, &self.schema, from, block)?;
+        }
+        Ok(())
+    }
+
     pub fn revert_block(
         &self,
         conn: &PgConnection,
@@ -518,6 +801,499 @@ impl Layout {
         }
         Ok((StoreEvent::new(changes), count))
     }
+
+    /// Reconstruct the changes that `block` made to entities from the
+    /// block ranges of their versions
+    pub fn entity_changes_in_block(
+        &self,
+        conn: &PgConnection,
+        block: BlockNumber,
+    ) -> Result<Vec<BlockEntityChange>, StoreError> {
+        self.entity_changes_since(conn, block - 1, block)
+    }
+
+    /// Reconstruct the changes that the blocks after `since` up to and
+    /// including `to` made to entities. Applying them to the entities as
+    /// of `since` produces the entities as of `to`. A version that was
+    /// written after `since` and is current at `to` is the new state of
+    /// an entity; if the version that was current at `since` has ended by
+    /// `to`, the entity was updated, otherwise it was created. A version
+    /// that was current at `since` and ended by `to` without a successor
+    /// means the entity was deleted
+    pub fn entity_changes_since(
+        &self,
+        conn: &PgConnection,
+        since: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<BlockEntityChange>, StoreError> {
+        let mut changes = Vec::new();
+        for table in self.tables.values() {
+            let written = self.versions_changed(conn, table, BlockRangeBound::Lower, since, to)?;
+            let mut ended =
+                self.versions_changed(conn, table, BlockRangeBound::Upper, since, to)?;
+
+            for (id, entity) in written {
+                let kind = match ended.remove(&id) {
+                    Some(_) => BlockEntityChangeKind::Updated,
+                    None => BlockEntityChangeKind::Created,
+                };
+                changes.push(BlockEntityChange {
+                    entity_type: table.object.clone(),
+                    entity_id: id,
+                    kind,
+                    data: entity,
+                });
+            }
+            // Whatever is left in `ended` was deleted by `to`
+            changes.extend(ended.into_iter().map(|(id, entity)| BlockEntityChange {
+                entity_type: table.object.clone(),
+                entity_id: id,
+                kind: BlockEntityChangeKind::Deleted,
+                data: entity,
+            }));
+        }
+        changes.sort_by(|a, b| {
+            a.entity_type
+                .cmp(&b.entity_type)
+                .then_with(|| a.entity_id.cmp(&b.entity_id))
+        });
+        Ok(changes)
+    }
+
+    /// Compare the entities of type `entity_type` as of block `from` with
+    /// those as of block `to`. The versions that were current at `from`
+    /// and ended by `to` are the previous state of the entities that
+    /// changed, and the versions written after `from` that are current at
+    /// `to` are their new state
+    pub fn entity_diff(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<EntityDiff>, StoreError> {
+        if from > to {
+            return Ok(self
+                .entity_diff(conn, entity_type, to, from)?
+                .into_iter()
+                .map(EntityDiff::inverse)
+                .collect());
+        }
+
+        let table = self.table_for_entity(entity_type)?;
+        let written = self.versions_changed(conn, table, BlockRangeBound::Lower, from, to)?;
+        let mut ended = self.versions_changed(conn, table, BlockRangeBound::Upper, from, to)?;
+
+        let mut diffs = Vec::new();
+        for (id, entity) in written {
+            let previous = ended.remove(&id);
+            diffs.extend(EntityDiff::between(id, previous.as_ref(), Some(&entity)));
+        }
+        diffs.extend(
+            ended
+                .into_iter()
+                .filter_map(|(id, entity)| EntityDiff::between(id, Some(&entity), None)),
+        );
+        diffs.sort_by(|a, b| a.entity_id.cmp(&b.entity_id));
+        Ok(diffs)
+    }
+
+    /// Create the attribute indexes that `as_ddl` would create for this
+    /// layout, but that are missing in the database, e.g., because the
+    /// subgraph was deployed by an older version of graph-node. Indexes
+    /// that a failed `create index concurrently` left behind as invalid
+    /// are built again. Unpartitioned tables are indexed concurrently so
+    /// that indexing does not block writes, which means this must not be
+    /// called inside a transaction
+    pub fn create_missing_indexes(
+        &self,
+        conn: &PgConnection,
+    ) -> Result<Vec<IndexCreation>, StoreError> {
+        #[derive(QueryableByName)]
+        struct ExistingIndex {
+            #[sql_type = "Text"]
+            name: String,
+            #[sql_type = "Bool"]
+            valid: bool,
+        }
+
+        let query = "select c.relname::text as name, i.indisvalid as valid
+                       from pg_index i
+                       join pg_class c on c.oid = i.indexrelid
+                       join pg_namespace n on n.oid = c.relnamespace
+                      where n.nspname = $1";
+        let existing = diesel::sql_query(query)
+            .bind::<Text, _>(&self.schema)
+            .load::<ExistingIndex>(conn)?
+            .into_iter()
+            .map(|index| (index.name, index.valid))
+            .collect::<HashMap<_, _>>();
+
+        let mut created = Vec::new();
+        for table in self.tables.values() {
+            let concurrently = if table.partitioning.is_none() {
+                "concurrently "
+            } else {
+                ""
+            };
+            for index in table.attribute_indexes() {
+                // Concurrent index operations can not be combined with
+                // other statements, so the drop is executed on its own
+                let mut statements = Vec::new();
+                match existing.get(index.pg_name()) {
+                    Some(true) => continue,
+                    Some(false) => statements.push(format!(
+                        "drop index {}{}.\"{}\"",
+                        concurrently,
+                        self.schema,
+                        index.pg_name()
+                    )),
+                    None => (),
+                }
+                statements.push(format!(
+                    "create index {}{} on {}.\"{}\" using {}({})",
+                    concurrently, index.name, self.schema, table.name, index.method, index.expr
+                ));
+                let error = statements
+                    .iter()
+                    .map(|sql| conn.batch_execute(sql))
+                    .find_map(Result::err)
+                    .map(|e| e.to_string());
+                created.push(IndexCreation {
+                    name: index.name,
+                    error,
+                });
+            }
+        }
+        Ok(created)
+    }
+
+    /// Report the entity types that have entities with an ID longer than
+    /// `max_length` bytes, counting each such entity once no matter how
+    /// many versions of it there are. IDs are measured the way `store.set`
+    /// measures them, which for `bytea` IDs is as a `0x`-prefixed hex
+    /// string
+    pub fn oversized_entity_ids(
+        &self,
+        conn: &PgConnection,
+        max_length: usize,
+    ) -> Result<Vec<OversizedEntityIds>, StoreError> {
+        #[derive(QueryableByName)]
+        struct Oversized {
+            #[sql_type = "BigInt"]
+            count: i64,
+            #[sql_type = "Nullable<Integer>"]
+            longest: Option<i32>,
+        }
+
+        let length = match self.id_type {
+            IdType::String => format!("octet_length({})", PRIMARY_KEY_COLUMN),
+            IdType::Bytes => format!("2 + 2 * octet_length({})", PRIMARY_KEY_COLUMN),
+        };
+        let mut oversized = Vec::new();
+        for table in self.tables.values() {
+            let query = format!(
+                "select count(distinct {id}) as count, max({length}) as longest \
+                   from {table} where {length} > $1",
+                id = PRIMARY_KEY_COLUMN,
+                length = length,
+                table = table.qualified_name,
+            );
+            let row = diesel::sql_query(query)
+                .bind::<Integer, _>(max_length.min(std::i32::MAX as usize) as i32)
+                .get_result::<Oversized>(conn)?;
+            if let Some(longest) = row.longest {
+                oversized.push(OversizedEntityIds {
+                    entity_type: table.object.clone(),
+                    count: row.count as u64,
+                    longest: longest as u64,
+                });
+            }
+        }
+        oversized.sort_by(|a, b| a.entity_type.cmp(&b.entity_type));
+        Ok(oversized)
+    }
+
+    /// Return the numbers of the blocks after `since` up to and including
+    /// `to` that wrote or ended a version of any entity, in ascending
+    /// order, but at most `limit` of them
+    pub fn blocks_with_changes(
+        &self,
+        conn: &PgConnection,
+        since: BlockNumber,
+        to: BlockNumber,
+        limit: usize,
+    ) -> Result<Vec<BlockNumber>, StoreError> {
+        #[derive(QueryableByName)]
+        struct ChangedBlock {
+            #[sql_type = "Integer"]
+            block: i32,
+        }
+
+        if self.tables.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // Both bounds are written the way the BRIN index on them is
+        // defined so that the index can be used
+        let lower = format!("lower({})", BLOCK_RANGE_COLUMN);
+        let mut changed = Vec::new();
+        for table in self.tables.values() {
+            for bound in &[lower.as_str(), BLOCK_RANGE_UPPER] {
+                changed.push(format!(
+                    "select {bound} as block from {table} \
+                      where {bound} > $1 and {bound} <= $2",
+                    bound = bound,
+                    table = table.qualified_name,
+                ));
+            }
+        }
+        let query = format!(
+            "select distinct block from ({}) changed order by block limit $3",
+            changed.join(" union all ")
+        );
+        Ok(diesel::sql_query(query)
+            .bind::<Integer, _>(since)
+            .bind::<Integer, _>(to)
+            .bind::<BigInt, _>(limit as i64)
+            .load::<ChangedBlock>(conn)?
+            .into_iter()
+            .map(|changed| changed.block)
+            .collect())
+    }
+
+    /// Check that the data of `base` can be copied into this layout. Every
+    /// attribute that both layouts have must have the same type, and
+    /// attributes that `base` does not have, or that it allows to be null,
+    /// must be nullable here. Entity types that this layout does not have
+    /// are fine; their data is not copied
+    fn check_copy_from(&self, base: &Layout) -> Result<(), StoreError> {
+        let mut errors = vec![];
+        for table in self.tables.values() {
+            let base_table = match base.tables.get(&table.object) {
+                Some(base_table) => base_table,
+                None => continue,
+            };
+            // Fulltext searches are computed from the copied fields
+            for column in table.columns.iter().filter(|column| !column.is_fulltext()) {
+                match base_table.columns.iter().find(|c| c.name == column.name) {
+                    Some(base_column) => {
+                        let same_type = column.is_list() == base_column.is_list()
+                            && (column.column_type == base_column.column_type
+                                || (column.is_enum() && base_column.is_enum()));
+                        if !same_type {
+                            errors.push(format!(
+                                "`{}.{}` has a different type",
+                                table.object, column.field
+                            ));
+                        } else if !column.is_nullable() && base_column.is_nullable() {
+                            errors.push(format!(
+                                "`{}.{}` is required, but is optional in the base",
+                                table.object, column.field
+                            ));
+                        }
+                    }
+                    None if !column.is_nullable() => errors.push(format!(
+                        "`{}.{}` is required, but does not exist in the base",
+                        table.object, column.field
+                    )),
+                    None => (),
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(StoreError::Unknown(format_err!(
+                "the schema of {} is not compatible with the schema of {}: {}",
+                self.subgraph,
+                base.subgraph,
+                errors.join(", ")
+            )))
+        }
+    }
+
+    /// Copy the entity versions that `base` had at `block` into the tables
+    /// of this layout, which must be empty. Versions that were current at
+    /// `block` are current here, too, so that indexing can continue with
+    /// the block after `block`. Returns the number of current entities
+    pub fn copy_from(
+        &self,
+        conn: &PgConnection,
+        base: &Layout,
+        block: BlockNumber,
+    ) -> Result<usize, StoreError> {
+        for entity_type in self.copied_entity_types(base)? {
+            self.copy_table_from(conn, base, &entity_type, block, 0, std::i64::MAX)?;
+        }
+        self.count_current_entities(conn)
+    }
+
+    /// The entity types whose versions `copy_table_from` copies from
+    /// `base`, which are the ones that both layouts have. Fails if the
+    /// schemas of the two layouts are not compatible
+    pub fn copied_entity_types(&self, base: &Layout) -> Result<Vec<String>, StoreError> {
+        self.check_copy_from(base)?;
+        Ok(self
+            .tables
+            .values()
+            .filter(|table| base.tables.contains_key(&table.object))
+            .map(|table| table.object.clone())
+            .collect())
+    }
+
+    /// Copy the versions of `entity_type` that `base` had at `block` and
+    /// whose `vid` in `base` is at least `from_vid` and less than
+    /// `from_vid + batch_size` into this layout. Versions that were current
+    /// at `block` are current here, too. Copying a table in batches, each
+    /// in its own transaction, starts with `from_vid` 0 and continues with
+    /// the `vid` that this returns until it returns `None`
+    pub fn copy_table_from(
+        &self,
+        conn: &PgConnection,
+        base: &Layout,
+        entity_type: &str,
+        block: BlockNumber,
+        from_vid: i64,
+        batch_size: i64,
+    ) -> Result<Option<i64>, StoreError> {
+        #[derive(QueryableByName)]
+        struct MaxVid {
+            #[sql_type = "Nullable<BigInt>"]
+            max_vid: Option<i64>,
+        }
+
+        let table = self.table_for_entity(entity_type)?;
+        let base_table = base.table_for_entity(entity_type)?;
+
+        // Copied versions can have been written at any block up to
+        // `block`, and each of them needs its partition
+        if from_vid == 0 {
+            if let Some(Partitioning::BlockRange { size }) = table.partitioning {
+                let mut start = 0;
+                while start <= block {
+                    table.ensure_partition(conn, &self.schema, None, start)?;
+                    start += size;
+                }
+            }
+        }
+
+        let mut columns = vec![];
+        let mut values = vec![];
+        for column in &table.columns {
+            // The base might not have the fulltext search or build it
+            // differently; compute it from the fields we copy instead
+            if let ColumnType::TSVector(config) = &column.column_type {
+                columns.push(column.name.quoted());
+                values.push(config.to_tsvector(base_table));
+                continue;
+            }
+            let base_column = match base_table.columns.iter().find(|c| c.name == column.name) {
+                Some(base_column) => base_column,
+                None => continue,
+            };
+            columns.push(column.name.quoted());
+            // Enums are types in the subgraph's database schema, and
+            // values have to be converted between the two schemas
+            if column.is_enum() {
+                let list = if column.is_list() { "[]" } else { "" };
+                values.push(format!(
+                    "{}::text{}::{}{}",
+                    base_column.name.quoted(),
+                    list,
+                    column.sql_type(),
+                    list
+                ));
+            } else {
+                values.push(base_column.name.quoted());
+            }
+        }
+
+        // Versions that ended after `block` are still current at
+        // `block`, and therefore get an open block range
+        let to_vid = from_vid.saturating_add(batch_size);
+        let query = format!(
+            "insert into {table}({columns}, {block_range}) \
+             select {values}, \
+                    int4range(lower({block_range}), \
+                              case when upper({block_range}) <= $1 \
+                                   then upper({block_range}) end) \
+               from {base_table} \
+              where lower({block_range}) <= $1 \
+                and {vid} >= $2 and {vid} < $3",
+            table = table.qualified_name,
+            columns = columns.join(", "),
+            values = values.join(", "),
+            block_range = BLOCK_RANGE_COLUMN,
+            base_table = base_table.qualified_name,
+            vid = VID_COLUMN,
+        );
+        diesel::sql_query(query)
+            .bind::<Integer, _>(block)
+            .bind::<BigInt, _>(from_vid)
+            .bind::<BigInt, _>(to_vid)
+            .execute(conn)?;
+
+        let query = format!(
+            "select max({}) as max_vid from {}",
+            VID_COLUMN, base_table.qualified_name
+        );
+        let max_vid = diesel::sql_query(query)
+            .get_result::<MaxVid>(conn)?
+            .max_vid
+            .unwrap_or(-1);
+        Ok(if to_vid > max_vid { None } else { Some(to_vid) })
+    }
+
+    /// The number of entities in this layout that are current
+    pub fn count_current_entities(&self, conn: &PgConnection) -> Result<usize, StoreError> {
+        Ok(self.count_current_entities_by_type(conn)?.values().sum())
+    }
+
+    /// The number of entities of each entity type in this layout that are
+    /// current, keyed by entity type
+    pub fn count_current_entities_by_type(
+        &self,
+        conn: &PgConnection,
+    ) -> Result<BTreeMap<String, usize>, StoreError> {
+        #[derive(QueryableByName)]
+        struct Count {
+            #[sql_type = "BigInt"]
+            count: i64,
+        }
+
+        let mut counts = BTreeMap::new();
+        for table in self.tables.values() {
+            let query = format!(
+                "select count(*) as count from {} where upper_inf({})",
+                table.qualified_name, BLOCK_RANGE_COLUMN
+            );
+            let count = diesel::sql_query(query).get_result::<Count>(conn)?.count;
+            counts.insert(table.object.clone(), count as usize);
+        }
+        Ok(counts)
+    }
+
+    /// The versions in `table` that were written (for `BlockRangeBound::Lower`)
+    /// or ended (for `BlockRangeBound::Upper`) after `since` up to and
+    /// including `to`, keyed by entity id
+    fn versions_changed(
+        &self,
+        conn: &PgConnection,
+        table: &Table,
+        bound: BlockRangeBound,
+        since: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<BTreeMap<String, Entity>, StoreError> {
+        VersionsChangedQuery::new(table, bound, since, to)
+            .load::<EntityData>(conn)?
+            .into_iter()
+            .map(|data| {
+                let entity = data.to_entity(self)?;
+                Ok((entity.id()?, entity))
+            })
+            .collect()
+    }
 }
 
 /// This is almost the same as graph::data::store::ValueType, but without
@@ -534,6 +1310,39 @@ pub enum ColumnType {
     /// A user-defined enum. The string contains the name of the Postgres
     /// enum we created for it, fully qualified with the schema
     Enum(SqlName),
+    /// The `tsvector` for a fulltext search over other columns of the table
+    TSVector(FulltextConfig),
+}
+
+/// How the `tsvector` for a fulltext search is built from the fields of an
+/// entity, and how matches are ranked
+#[derive(Clone, Debug, PartialEq)]
+pub struct FulltextConfig {
+    pub language: FulltextLanguage,
+    pub algorithm: FulltextAlgorithm,
+    /// The GraphQL names of the `String` fields that are searched
+    pub fields: Vec<String>,
+}
+
+impl FulltextConfig {
+    /// The SQL expression that builds the `tsvector` from the columns of
+    /// `table` that hold the searched fields
+    fn to_tsvector(&self, table: &Table) -> String {
+        let columns = self
+            .fields
+            .iter()
+            .filter_map(|field| table.column_for_field(field).ok())
+            .map(|column| column.name.quoted())
+            .collect::<Vec<_>>();
+        if columns.is_empty() {
+            return "null".to_owned();
+        }
+        format!(
+            "to_tsvector('{}', concat_ws(' ', {}))",
+            self.language.as_sql(),
+            columns.join(", ")
+        )
+    }
 }
 
 impl From<IdType> for ColumnType {
@@ -592,6 +1401,7 @@ impl ColumnType {
             ColumnType::Int => "integer",
             ColumnType::String => "text",
             ColumnType::Enum(name) => name.as_str(),
+            ColumnType::TSVector(_) => "tsvector",
         }
     }
 }
@@ -623,6 +1433,24 @@ impl Column {
         })
     }
 
+    /// The column for the fulltext search `definition`. Mappings never set
+    /// it; it is computed from the searched fields whenever an entity is
+    /// written
+    fn new_fulltext(definition: &FulltextDefinition) -> Result<Column, StoreError> {
+        SqlName::check_valid_identifier(&definition.name, "fulltext search")?;
+
+        Ok(Column {
+            name: SqlName::from(definition.name.as_str()),
+            field: definition.name.clone(),
+            field_type: q::Type::NamedType("tsvector".to_owned()),
+            column_type: ColumnType::TSVector(FulltextConfig {
+                language: definition.language,
+                algorithm: definition.algorithm,
+                fields: definition.fields.clone(),
+            }),
+        })
+    }
+
     fn sql_type(&self) -> &str {
         self.column_type.sql_type()
     }
@@ -658,6 +1486,14 @@ impl Column {
         }
     }
 
+    pub fn is_fulltext(&self) -> bool {
+        if let ColumnType::TSVector(_) = self.column_type {
+            true
+        } else {
+            false
+        }
+    }
+
     /// Return `true` if this column stores user-supplied text. Such
     /// columns may contain very large values and need to be handled
     /// specially for indexing
@@ -690,6 +1526,102 @@ pub(crate) const PRIMARY_KEY_COLUMN: &str = "id";
 /// synthetic primary key. This is the name of the column we use.
 pub(crate) const VID_COLUMN: &str = "vid";
 
+/// How the table for an entity type is partitioned. Partitioning requires
+/// declarative partitioning, and therefore Postgres 11 or later. Postgres
+/// can only enforce the exclusion constraint that keeps the block ranges
+/// of the versions of an entity from overlapping within each partition
+#[derive(Clone, Debug, PartialEq)]
+pub enum Partitioning {
+    /// Partition by `lower(block_range)`, i.e., the block at which an
+    /// entity version was created. Each partition covers `size` blocks;
+    /// partitions are created as indexing reaches the blocks they cover.
+    /// Versions written at the same block end up in the same partition,
+    /// and every other version is clamped before the next one is written
+    BlockRange { size: BlockNumber },
+    /// Partition by the hash of `column` into `count` partitions, all of
+    /// which are created together with the table. The column is always
+    /// `id`, which never changes, so that all versions of an entity are in
+    /// the same partition
+    Hash { column: SqlName, count: u32 },
+}
+
+impl Partitioning {
+    /// Return the name of the first entity type in `document` that has a
+    /// `@partition` directive, if there is one
+    pub fn requested_by(document: &s::Document) -> Option<&str> {
+        document
+            .definitions
+            .iter()
+            .filter_map(|defn| match defn {
+                s::Definition::TypeDefinition(s::TypeDefinition::Object(obj)) => Some(obj),
+                _ => None,
+            })
+            .find(|obj| {
+                obj.directives
+                    .iter()
+                    .any(|dir| dir.name == PARTITION_DIRECTIVE)
+            })
+            .map(|obj| obj.name.as_str())
+    }
+
+    /// Parse the `@partition` directive on `defn`, if there is one. The
+    /// directive has the form `@partition(by: "block", size: <blocks>)` or
+    /// `@partition(by: "hash", field: "id", count: <partitions>)`
+    fn from_directives(defn: &s::ObjectType) -> Result<Option<Partitioning>, StoreError> {
+        let dir = match defn
+            .directives
+            .iter()
+            .find(|dir| dir.name == PARTITION_DIRECTIVE)
+        {
+            None => return Ok(None),
+            Some(dir) => dir,
+        };
+
+        let malformed = |msg: &str| {
+            StoreError::MalformedDirective(format!(
+                "@{} on type {}: {}",
+                PARTITION_DIRECTIVE, defn.name, msg
+            ))
+        };
+        let arg = |name: &str| {
+            dir.arguments
+                .iter()
+                .find(|(arg, _)| arg == name)
+                .map(|(_, value)| value)
+        };
+        let positive_int = |name: &str| match arg(name) {
+            Some(s::Value::Int(n)) => match n.as_i64() {
+                Some(n) if n > 0 && n <= std::i32::MAX as i64 => Ok(n),
+                _ => Err(malformed(&format!("`{}` must be a positive integer", name))),
+            },
+            _ => Err(malformed(&format!("`{}` must be a positive integer", name))),
+        };
+
+        match arg("by") {
+            Some(s::Value::String(by)) if by == "block" => Ok(Some(Partitioning::BlockRange {
+                size: positive_int("size")? as BlockNumber,
+            })),
+            Some(s::Value::String(by)) if by == "hash" => {
+                let field = match arg("field") {
+                    Some(s::Value::String(field)) => field,
+                    _ => return Err(malformed("`field` must be the name of a field")),
+                };
+                // Entities can change any attribute but their `id`; hashing
+                // anything else would spread the versions of an entity over
+                // several partitions
+                if field != PRIMARY_KEY_COLUMN {
+                    return Err(malformed("tables can only be hash partitioned by `id`"));
+                }
+                Ok(Some(Partitioning::Hash {
+                    column: SqlName::from(PRIMARY_KEY_COLUMN),
+                    count: positive_int("count")? as u32,
+                }))
+            }
+            _ => Err(malformed("`by` must be either \"block\" or \"hash\"")),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Table {
     /// The name of the GraphQL object type ('Thing')
@@ -707,6 +1639,8 @@ pub struct Table {
     /// is really only needed for the tests to make the names of indexes
     /// predictable
     position: u32,
+    /// How the table is partitioned, if at all
+    pub partitioning: Option<Partitioning>,
 }
 
 impl Table {
@@ -715,6 +1649,7 @@ impl Table {
         schema: &str,
         interfaces: &mut HashMap<String, Vec<SqlName>>,
         enums: &EnumMap,
+        fulltext: &[FulltextDefinition],
         id_type: IdType,
         position: u32,
     ) -> Result<Table, StoreError> {
@@ -726,13 +1661,21 @@ impl Table {
             .iter()
             .filter(|field| !derived_column(field))
             .map(|field| Column::new(field, schema, enums, id_type))
+            .chain(
+                fulltext
+                    .iter()
+                    .filter(|definition| definition.entity == defn.name)
+                    .map(Column::new_fulltext),
+            )
             .collect::<Result<Vec<_>, _>>()?;
+        let partitioning = Partitioning::from_directives(defn)?;
         let table = Table {
             object: defn.name.clone(),
             name: table_name.clone(),
             qualified_name: SqlName::qualified_name(schema, &table_name),
             columns,
             position,
+            partitioning,
         };
         for interface_name in &defn.implements_interfaces {
             match interfaces.get_mut(interface_name) {
@@ -765,6 +1708,65 @@ impl Table {
             .ok_or_else(|| StoreError::UnknownField(field.to_string()))
     }
 
+    /// Return `true` if this table is partitioned by the block at which
+    /// entity versions were created
+    pub fn is_partitioned_by_block(&self) -> bool {
+        match self.partitioning {
+            Some(Partitioning::BlockRange { .. }) => true,
+            _ => false,
+        }
+    }
+
+    /// Return `true` if this table is hash partitioned on `column`
+    pub fn is_partition_key(&self, column: &Column) -> bool {
+        match &self.partitioning {
+            Some(Partitioning::Hash { column: key, .. }) => key == &column.name,
+            _ => false,
+        }
+    }
+
+    /// The name of the partition of this table that holds entity versions
+    /// created at blocks in `[number * size, (number + 1) * size)` for
+    /// tables partitioned by block, or whose partition key hashes to
+    /// `number` for hash partitioned tables
+    fn partition_name(&self, number: i64) -> SqlName {
+        SqlName(format!("{}_p{}", self.name, number))
+    }
+
+    /// Make sure that the partition that holds entity versions created at
+    /// block `block` exists when the subgraph moves from block `from` to
+    /// `block`. This is a noop for tables that are not partitioned by block
+    fn ensure_partition(
+        &self,
+        conn: &PgConnection,
+        schema: &str,
+        from: Option<BlockNumber>,
+        block: BlockNumber,
+    ) -> Result<(), StoreError> {
+        if let Some(Partitioning::BlockRange { size }) = self.partitioning {
+            // The partition for `block` must already exist if we wrote
+            // data for `from` and both blocks fall into the same partition
+            if from.map_or(false, |from| from >= 0 && from / size == block / size) {
+                return Ok(());
+            }
+            let number = (block / size) as i64;
+            let sql = format!(
+                "create table if not exists {schema}.{partition} partition of {schema}.{table}\n    \
+                 (primary key({vid}), exclude using gist (id with =, {block_range} with &&))\n    \
+                 for values from ({from}) to ({to});",
+                schema = schema,
+                partition = self.partition_name(number).quoted(),
+                table = self.name.quoted(),
+                vid = VID_COLUMN,
+                block_range = BLOCK_RANGE_COLUMN,
+                from = number * size as i64,
+                to = (number + 1) * size as i64
+            );
+            conn.batch_execute(&sql)?;
+        }
+        Ok(())
+    }
+
     /// Generate the DDL for one table, i.e. one `create table` statement
     /// and all `create index` statements for the table's columns
     ///
@@ -782,51 +1784,143 @@ impl Table {
             column.as_ddl(out)?;
             write!(out, ",\n")?;
         }
-        // Add block_range column and constraint
-        write!(
-            out,
-            "\n        {vid}                  bigserial primary key,\
-             \n        {block_range}          int4range not null,
+        // Add block_range column and constraint. Postgres does not allow
+        // primary keys or exclusion constraints on a partitioned table
+        // that do not include the partition key; we therefore put them on
+        // each partition
+        match &self.partitioning {
+            None => write!(
+                out,
+                "\n        {vid}                  bigserial primary key,\
+                 \n        {block_range}          int4range not null,
         exclude using gist   (id with =, {block_range} with &&)\n);\n",
-            vid = VID_COLUMN,
-            block_range = BLOCK_RANGE_COLUMN
-        )?;
-
-        // Create indexes. Skip columns whose type is an array of enum,
-        // since there is no good way to index them with Postgres 9.6.
-        // Once we move to Postgres 11, we can enable that
-        // (tracked in graph-node issue #1330)
-        for (i, column) in self
-            .columns
-            .iter()
-            .filter(|col| !(col.is_list() && col.is_enum()))
-            .enumerate()
-        {
-            // Attributes that are plain strings are indexed with a BTree; but
-            // they can be too large for Postgres' limit on values that can go
-            // into a BTree. For those attributes, only index the first
-            // STRING_PREFIX_SIZE characters
-            let index_expr = if column.is_text() {
-                format!("left({}, {})", column.name.quoted(), STRING_PREFIX_SIZE)
-            } else {
-                column.name.quoted()
-            };
+                vid = VID_COLUMN,
+                block_range = BLOCK_RANGE_COLUMN
+            )?,
+            Some(Partitioning::BlockRange { .. }) => write!(
+                out,
+                "\n        {vid}                  bigserial not null,\
+                 \n        {block_range}          int4range not null\n\
+                 ) partition by range (lower({block_range}));\n",
+                vid = VID_COLUMN,
+                block_range = BLOCK_RANGE_COLUMN
+            )?,
+            Some(Partitioning::Hash { column, count }) => {
+                write!(
+                    out,
+                    "\n        {vid}                  bigserial not null,\
+                     \n        {block_range}          int4range not null\n\
+                     ) partition by hash ({column});\n",
+                    vid = VID_COLUMN,
+                    block_range = BLOCK_RANGE_COLUMN,
+                    column = column.quoted()
+                )?;
+                for i in 0..*count {
+                    write!(
+                        out,
+                        "create table {schema}.{partition} partition of {schema}.{table}\n    \
+                         (primary key({vid}), exclude using gist (id with =, {block_range} with &&))\n    \
+                         for values with (modulus {count}, remainder {i});\n",
+                        schema = layout.schema,
+                        partition = self.partition_name(i as i64).quoted(),
+                        table = self.name.quoted(),
+                        vid = VID_COLUMN,
+                        block_range = BLOCK_RANGE_COLUMN,
+                        count = count,
+                        i = i
+                    )?;
+                }
+            }
+        }
 
-            let method = if column.is_list() { "gin" } else { "btree" };
+        for index in self.attribute_indexes() {
             write!(
                 out,
-                "create index attr_{table_index}_{column_index}_{table_name}_{column_name}\n    on {schema_name}.\"{table_name}\" using {method}({index_expr});\n",
-                table_index = self.position,
+                "create index {name}\n    on {schema_name}.\"{table_name}\" using {method}({index_expr});\n",
+                name = index.name,
                 table_name = self.name,
-                column_index = i,
-                column_name = column.name,
                 schema_name = layout.schema,
-                method = method,
-                index_expr = index_expr,
+                method = index.method,
+                index_expr = index.expr,
             )?;
         }
         write!(out, "\n")
     }
+
+    /// The indexes on the attributes of this table, followed by a BRIN
+    /// index on the bounds of the block range that lets us find the
+    /// versions a range of blocks wrote or ended without scanning the
+    /// whole table
+    fn attribute_indexes(&self) -> Vec<AttributeIndex> {
+        // Skip columns whose type is an array of enum, since there is no
+        // good way to index them with Postgres 9.6. Once we move to
+        // Postgres 11, we can enable that (tracked in graph-node issue #1330)
+        let mut indexes: Vec<_> = self
+            .columns
+            .iter()
+            .filter(|col| !(col.is_list() && col.is_enum()))
+            .enumerate()
+            .map(|(i, column)| {
+                // Attributes that are plain strings are indexed with a BTree;
+                // but they can be too large for Postgres' limit on values
+                // that can go into a BTree. For those attributes, only index
+                // the first STRING_PREFIX_SIZE characters. The column a table
+                // is hash partitioned on is indexed in full so that queries
+                // that compare it to a value can be pruned to one partition
+                let expr = if column.is_text() && !self.is_partition_key(column) {
+                    format!("left({}, {})", column.name.quoted(), STRING_PREFIX_SIZE)
+                } else {
+                    column.name.quoted()
+                };
+
+                AttributeIndex {
+                    name: format!(
+                        "attr_{table_index}_{column_index}_{table_name}_{column_name}",
+                        table_index = self.position,
+                        column_index = i,
+                        table_name = self.name,
+                        column_name = column.name,
+                    ),
+                    method: if column.is_list() || column.is_fulltext() {
+                        "gin"
+                    } else {
+                        "btree"
+                    },
+                    expr,
+                }
+            })
+            .collect();
+        indexes.push(AttributeIndex {
+            name: format!(
+                "brin_{table_index}_{table_name}",
+                table_index = self.position,
+                table_name = self.name,
+            ),
+            method: "brin",
+            expr: format!(
+                "lower({block_range}), {upper}, {vid}",
+                block_range = BLOCK_RANGE_COLUMN,
+                upper = BLOCK_RANGE_UPPER,
+                vid = VID_COLUMN
+            ),
+        });
+        indexes
+    }
+}
+
+/// An index on one attribute of an entity table
+struct AttributeIndex {
+    name: String,
+    method: &'static str,
+    expr: String,
+}
+
+impl AttributeIndex {
+    /// The name Postgres uses for the index, which is the first 63 bytes
+    /// of its name. Index names only contain ASCII characters
+    fn pg_name(&self) -> &str {
+        &self.name[..self.name.len().min(63)]
+    }
 }
 
 /// Return the enclosed named type for a field type, i.e., the type after
@@ -885,6 +1979,66 @@ mod tests {
         assert!(table.column(&bad_sql_name).is_err());
     }
 
+    #[test]
+    fn window_strategies() {
+        use graph::prelude::{EntityLink, EntityWindow, WindowAttribute};
+
+        let strategies = parse_window_strategies("QmA:lateral, QmB:rank").unwrap();
+        assert_eq!(
+            Some(&WindowStrategy::Lateral),
+            strategies.get(&SubgraphDeploymentId::new("QmA").unwrap())
+        );
+        assert_eq!(
+            Some(&WindowStrategy::Rank),
+            strategies.get(&SubgraphDeploymentId::new("QmB").unwrap())
+        );
+        assert!(parse_window_strategies("QmA").is_err());
+        assert!(parse_window_strategies("QmA:nested").is_err());
+
+        // The songs written by some musicians can be looked up for each
+        // musician separately, but the musicians of a band can not
+        let layout = test_layout(MUSIC_GQL);
+        let window = |child_type: &str, link| {
+            EntityCollection::Window(vec![EntityWindow {
+                child_type: child_type.to_owned(),
+                ids: vec!["m1".to_owned(), "m2".to_owned()],
+                link: EntityLink::Direct(link),
+            }])
+        };
+        let order = Some((
+            "title".to_owned(),
+            ValueType::String,
+            EntityOrder::Ascending,
+            NullsOrder::Last,
+        ));
+        let songs = FilterQuery::new(
+            &layout,
+            window("Song", WindowAttribute::Scalar("writtenBy".to_owned())),
+            None,
+            order,
+            EntityRange::first(5),
+            1,
+        )
+        .unwrap();
+        let (table, column) = songs.lateral_candidate().unwrap();
+        assert_eq!("song", table.name.as_str());
+        assert_eq!("written_by", column.name.as_str());
+        let sql = debug_query(&songs.with_window_strategy(WindowStrategy::Lateral)).to_string();
+        assert!(sql.contains("cross join lateral"));
+        assert!(sql.contains("c.\"written_by\" = p.id"));
+
+        let musicians = FilterQuery::new(
+            &layout,
+            window("Musician", WindowAttribute::List("bands".to_owned())),
+            None,
+            None,
+            EntityRange::first(5),
+            1,
+        )
+        .unwrap();
+        assert!(musicians.lateral_candidate().is_none());
+    }
+
     #[test]
     fn generate_ddl() {
         let layout = test_layout(THING_GQL);
@@ -900,6 +2054,93 @@ mod tests {
         assert_eq!(FOREST_DDL, sql);
     }
 
+    #[test]
+    fn generate_partitioned_ddl() {
+        let layout = test_layout(BLOCK_PARTITION_GQL);
+        let sql = layout.as_ddl().expect("Failed to generate DDL");
+        assert_eq!(BLOCK_PARTITION_DDL, sql);
+        let table = layout.table(&"transfer".into()).unwrap();
+        assert!(table.is_partitioned_by_block());
+
+        let layout = test_layout(HASH_PARTITION_GQL);
+        let sql = layout.as_ddl().expect("Failed to generate DDL");
+        assert_eq!(HASH_PARTITION_DDL, sql);
+        let table = layout.table(&"balance".into()).unwrap();
+        assert!(!table.is_partitioned_by_block());
+        assert!(table.is_partition_key(table.column(&"id".into()).unwrap()));
+        assert!(!table.is_partition_key(table.column(&"account".into()).unwrap()));
+    }
+
+    #[test]
+    fn partition_on_deploy() {
+        use graph::data::schema::{PartitionSpec, SchemaValidationError};
+
+        let gql = "type Transfer @entity { id: ID!, from: String!, value: BigInt! }";
+        let document = parse_schema(gql).expect("Test schema invalid");
+        let subgraph = SubgraphDeploymentId::new("subgraph").unwrap();
+        let mut schema = Schema::new(subgraph.clone(), document);
+        assert_eq!(None, Partitioning::requested_by(&schema.document));
+
+        let mut partitions = BTreeMap::new();
+        partitions.insert("Transfer".to_owned(), PartitionSpec::Block { size: 100000 });
+        schema.set_partitioning(&partitions).unwrap();
+        assert_eq!(
+            Some("Transfer"),
+            Partitioning::requested_by(&schema.document)
+        );
+        let layout = Layout::new(&schema.document, IdType::String, subgraph, "rel")
+            .expect("Failed to construct Layout");
+        let sql = layout.as_ddl().expect("Failed to generate DDL");
+        assert_eq!(BLOCK_PARTITION_DDL, sql);
+
+        partitions.insert("Nothing".to_owned(), PartitionSpec::Block { size: 100000 });
+        assert_eq!(
+            Err(SchemaValidationError::PartitionedTypeUndefined(
+                "Nothing".to_owned()
+            )),
+            schema.set_partitioning(&partitions)
+        );
+    }
+
+    #[test]
+    fn generate_fulltext_ddl() {
+        let layout = test_layout(FULLTEXT_GQL);
+        let sql = layout.as_ddl().expect("Failed to generate DDL");
+        assert_eq!(FULLTEXT_DDL, sql);
+        let table = layout.table(&"band".into()).unwrap();
+        let column = table.column_for_field("bandSearch").unwrap();
+        assert!(column.is_fulltext());
+        assert!(!column.is_text());
+    }
+
+    #[test]
+    fn malformed_partition_directive() {
+        fn layout(gql: &str) -> Result<Layout, StoreError> {
+            let schema = parse_schema(gql).expect("Test schema invalid");
+            let subgraph = SubgraphDeploymentId::new("subgraph").unwrap();
+            Layout::new(&schema, IdType::String, subgraph, "rel")
+        }
+
+        let bad = vec![
+            "type Thing @entity @partition(by: \"color\") { id: ID! }",
+            "type Thing @entity @partition(by: \"block\") { id: ID! }",
+            "type Thing @entity @partition(by: \"block\", size: 0) { id: ID! }",
+            "type Thing @entity @partition(by: \"hash\", field: \"name\", count: 4) { id: ID! }",
+            "type Thing @entity @partition(by: \"hash\", field: \"tags\", count: 4) \
+             { id: ID!, tags: [String!]! }",
+            "type Thing @entity @partition(by: \"hash\", field: \"id\") { id: ID! }",
+            "type Thing @entity @partition(by: \"hash\", field: \"name\", count: 4) \
+             { id: ID!, name: String! }",
+        ];
+        for gql in bad {
+            match layout(gql) {
+                Err(StoreError::MalformedDirective(_)) => (),
+                Err(e) => panic!("unexpected error {} for {}", e, gql),
+                Ok(_) => panic!("expected an error for {}", gql),
+            }
+        }
+    }
+
     const THING_GQL: &str = "
         type Thing @entity {
             id: ID!
@@ -937,6 +2178,8 @@ create index attr_0_0_thing_id
     on rel.\"thing\" using btree(\"id\");
 create index attr_0_1_thing_big_thing
     on rel.\"thing\" using btree(\"big_thing\");
+create index brin_0_thing
+    on rel.\"thing\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
 
 create table rel.\"scalar\" (
         \"id\"                 text not null,
@@ -968,6 +2211,8 @@ create index attr_1_6_scalar_big_int
     on rel.\"scalar\" using btree(\"big_int\");
 create index attr_1_7_scalar_color
     on rel.\"scalar\" using btree(\"color\");
+create index brin_1_scalar
+    on rel.\"scalar\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
 
 ";
 
@@ -1016,6 +2261,8 @@ create index attr_0_2_musician_main_band
     on rel.\"musician\" using btree(\"main_band\");
 create index attr_0_3_musician_bands
     on rel.\"musician\" using gin(\"bands\");
+create index brin_0_musician
+    on rel.\"musician\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
 
 create table rel.\"band\" (
         \"id\"                 text not null,
@@ -1032,6 +2279,8 @@ create index attr_1_1_band_name
     on rel.\"band\" using btree(left(\"name\", 256));
 create index attr_1_2_band_original_songs
     on rel.\"band\" using gin(\"original_songs\");
+create index brin_1_band
+    on rel.\"band\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
 
 create table rel.\"song\" (
         \"id\"                 text not null,
@@ -1048,6 +2297,8 @@ create index attr_2_1_song_title
     on rel.\"song\" using btree(left(\"title\", 256));
 create index attr_2_2_song_written_by
     on rel.\"song\" using btree(\"written_by\");
+create index brin_2_song
+    on rel.\"song\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
 
 create table rel.\"song_stat\" (
         \"id\"                 text not null,
@@ -1061,6 +2312,8 @@ create index attr_3_0_song_stat_id
     on rel.\"song_stat\" using btree(\"id\");
 create index attr_3_1_song_stat_played
     on rel.\"song_stat\" using btree(\"played\");
+create index brin_3_song_stat
+    on rel.\"song_stat\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
 
 ";
 
@@ -1097,6 +2350,8 @@ create index attr_0_0_animal_id
     on rel.\"animal\" using btree(\"id\");
 create index attr_0_1_animal_forest
     on rel.\"animal\" using btree(\"forest\");
+create index brin_0_animal
+    on rel.\"animal\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
 
 create table rel.\"forest\" (
         \"id\"                 text not null,
@@ -1107,6 +2362,8 @@ create table rel.\"forest\" (
 );
 create index attr_1_0_forest_id
     on rel.\"forest\" using btree(\"id\");
+create index brin_1_forest
+    on rel.\"forest\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
 
 create table rel.\"habitat\" (
         \"id\"                 text not null,
@@ -1123,6 +2380,103 @@ create index attr_2_1_habitat_most_common
     on rel.\"habitat\" using btree(\"most_common\");
 create index attr_2_2_habitat_dwellers
     on rel.\"habitat\" using gin(\"dwellers\");
+create index brin_2_habitat
+    on rel.\"habitat\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
+
+";
+
+    const BLOCK_PARTITION_GQL: &str = "
+        type Transfer @entity @partition(by: \"block\", size: 100000) {
+            id: ID!
+            from: String!
+            value: BigInt!
+        }";
+
+    const BLOCK_PARTITION_DDL: &str = "create table rel.\"transfer\" (
+        \"id\"                 text not null,
+        \"from\"               text not null,
+        \"value\"              numeric not null,
+
+        vid                  bigserial not null,
+        block_range          int4range not null
+) partition by range (lower(block_range));
+create index attr_0_0_transfer_id
+    on rel.\"transfer\" using btree(\"id\");
+create index attr_0_1_transfer_from
+    on rel.\"transfer\" using btree(left(\"from\", 256));
+create index attr_0_2_transfer_value
+    on rel.\"transfer\" using btree(\"value\");
+create index brin_0_transfer
+    on rel.\"transfer\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
+
+";
+
+    const FULLTEXT_GQL: &str = "
+        type _Schema_ @fulltext(
+            name: \"bandSearch\",
+            language: en,
+            algorithm: rank,
+            include: [{ entity: \"Band\", fields: [{ name: \"name\" }, { name: \"bio\" }] }]
+        )
+
+        type Band @entity {
+            id: ID!
+            name: String!
+            bio: String
+        }";
+
+    const FULLTEXT_DDL: &str = "create table rel.\"band\" (
+        \"id\"                 text not null,
+        \"name\"               text not null,
+        \"bio\"                text,
+        \"band_search\"        tsvector,
+
+        vid                  bigserial primary key,
+        block_range          int4range not null,
+        exclude using gist   (id with =, block_range with &&)
+);
+create index attr_0_0_band_id
+    on rel.\"band\" using btree(\"id\");
+create index attr_0_1_band_name
+    on rel.\"band\" using btree(left(\"name\", 256));
+create index attr_0_2_band_bio
+    on rel.\"band\" using btree(left(\"bio\", 256));
+create index attr_0_3_band_band_search
+    on rel.\"band\" using gin(\"band_search\");
+create index brin_0_band
+    on rel.\"band\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
+
+";
+
+    const HASH_PARTITION_GQL: &str = "
+        type Balance @entity @partition(by: \"hash\", field: \"id\", count: 2) {
+            id: ID!
+            account: String!
+            amount: BigInt!
+        }";
+
+    const HASH_PARTITION_DDL: &str = "create table rel.\"balance\" (
+        \"id\"                 text not null,
+        \"account\"            text not null,
+        \"amount\"             numeric not null,
+
+        vid                  bigserial not null,
+        block_range          int4range not null
+) partition by hash (\"id\");
+create table rel.\"balance_p0\" partition of rel.\"balance\"
+    (primary key(vid), exclude using gist (id with =, block_range with &&))
+    for values with (modulus 2, remainder 0);
+create table rel.\"balance_p1\" partition of rel.\"balance\"
+    (primary key(vid), exclude using gist (id with =, block_range with &&))
+    for values with (modulus 2, remainder 1);
+create index attr_0_0_balance_id
+    on rel.\"balance\" using btree(\"id\");
+create index attr_0_1_balance_account
+    on rel.\"balance\" using btree(left(\"account\", 256));
+create index attr_0_2_balance_amount
+    on rel.\"balance\" using btree(\"amount\");
+create index brin_0_balance
+    on rel.\"balance\" using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
 
 ";
 }
@@ -17,9 +17,9 @@ use std::str::FromStr;
 
 use graph::data::store::scalar;
 use graph::prelude::{
-    format_err, serde_json, Attribute, BlockNumber, Entity, EntityCollection, EntityFilter,
-    EntityKey, EntityLink, EntityOrder, EntityRange, EntityWindow, QueryExecutionError, StoreError,
-    Value, ValueType,
+    format_err, serde_json, AggregateFunction, Attribute, BlockNumber, ChildFilter, Entity,
+    EntityCollection, EntityFilter, EntityKey, EntityLink, EntityOrder, EntityRange, EntityWindow,
+    NullsOrder, QueryExecutionError, StoreError, Value, ValueType, AGGREGATE_COUNT_ATTRIBUTE,
 };
 
 use crate::block_range::{
@@ -27,7 +27,9 @@ use crate::block_range::{
 };
 use crate::entities::STRING_PREFIX_SIZE;
 use crate::filter::UnsupportedFilter;
-use crate::relational::{Column, ColumnType, Layout, SqlName, Table, PRIMARY_KEY_COLUMN};
+use crate::relational::{
+    Column, ColumnType, FulltextConfig, Layout, SqlName, Table, PRIMARY_KEY_COLUMN,
+};
 use crate::sql_value::SqlValue;
 
 /// Helper struct for retrieving entities from the database. With diesel, we
@@ -145,7 +147,12 @@ impl EntityData {
                     if key == "g$parent_id" {
                         let value = Self::value_from_json(&ColumnType::String, json)?;
                         entity.insert("g$parent_id".to_owned(), value);
-                    } else if let Some(column) = table.column(&SqlName::from_snake_case(key)).ok() {
+                    } else if let Some(column) = table
+                        .column(&SqlName::from_snake_case(key))
+                        .ok()
+                        .filter(|column| !column.is_fulltext())
+                    {
+                        // Fulltext searches are not part of the entity
                         let value = Self::value_from_json(&column.column_type, json)?;
                         if value != Value::Null {
                             entity.insert(column.field.clone(), value);
@@ -161,6 +168,38 @@ impl EntityData {
     }
 }
 
+impl EntityData {
+    /// The number of entities, across all groups, that the
+    /// `AggregateQuery` that produced this `EntityData` looked at
+    pub fn aggregated_entities(&self) -> u64 {
+        self.data
+            .get(AGGREGATED_ENTITIES)
+            .and_then(|entities| entities.as_u64())
+            .unwrap_or(0)
+    }
+
+    /// Map the `EntityData` for one group of an `AggregateQuery` to an
+    /// entity; `attributes` are the attributes of the aggregation with the
+    /// types of their values
+    pub fn to_aggregate(self, attributes: &[(String, ColumnType)]) -> Result<Entity, StoreError> {
+        use serde_json::Value as j;
+        match self.data {
+            j::Object(mut map) => {
+                let mut entity = Entity::new();
+                for (attribute, column_type) in attributes {
+                    let json = map.remove(attribute).unwrap_or(j::Null);
+                    let value = Self::value_from_json(column_type, json)?;
+                    entity.insert(attribute.clone(), value);
+                }
+                Ok(entity)
+            }
+            _ => unreachable!(
+                "we use `to_json` in our queries, and will therefore always get an object back"
+            ),
+        }
+    }
+}
+
 /// A `QueryValue` makes it possible to bind a `Value` into a SQL query
 /// using the metadata from Column
 struct QueryValue<'a>(&'a Value, &'a ColumnType);
@@ -213,6 +252,9 @@ impl<'a> QueryFragment<Pg> for QueryValue<'a> {
                         out.push_sql("[]");
                         Ok(())
                     }
+                    ColumnType::TSVector(_) => {
+                        unreachable!("fulltext searches are never compared with lists")
+                    }
                 }
             }
             Value::Null => {
@@ -387,31 +429,70 @@ impl<'a> QueryFragment<Pg> for PrefixComparison<'a> {
 /// the `where` clause of a SQL query. The attributes mentioned in
 /// the `filter` must all come from the given `table`, which is used to
 /// map GraphQL names to column names, and to determine the type of the
-/// column an attribute refers to
+/// column an attribute refers to. Filters on the children of an entity
+/// look up the tables of the children in `layout` and check the versions
+/// of the children that were current at `block`
 #[derive(Debug, Clone)]
 pub struct QueryFilter<'a> {
     filter: &'a EntityFilter,
     table: &'a Table,
+    layout: &'a Layout,
+    block: BlockNumber,
 }
 
 impl<'a> QueryFilter<'a> {
-    pub fn new(filter: &'a EntityFilter, table: &'a Table) -> Result<Self, StoreError> {
-        Self::valid_attributes(filter, table)?;
-        Ok(QueryFilter { filter, table })
+    pub fn new(
+        filter: &'a EntityFilter,
+        table: &'a Table,
+        layout: &'a Layout,
+        block: BlockNumber,
+    ) -> Result<Self, StoreError> {
+        Self::valid_attributes(filter, table, layout)?;
+        Ok(QueryFilter {
+            filter,
+            table,
+            layout,
+            block,
+        })
     }
 
-    fn valid_attributes(filter: &'a EntityFilter, table: &'a Table) -> Result<(), StoreError> {
+    fn valid_attributes(
+        filter: &'a EntityFilter,
+        table: &'a Table,
+        layout: &'a Layout,
+    ) -> Result<(), StoreError> {
         use EntityFilter::*;
         match filter {
             And(filters) | Or(filters) => {
                 for filter in filters {
-                    Self::valid_attributes(filter, table)?;
+                    Self::valid_attributes(filter, table, layout)?;
+                }
+            }
+            Negation(filter) => Self::valid_attributes(filter, table, layout)?,
+            TypeCondition(entity_type, filter) => {
+                if &table.object == entity_type {
+                    Self::valid_attributes(filter, table, layout)?;
+                }
+            }
+            Child(child) => {
+                if !child.derived {
+                    table.column_for_field(&child.attribute)?;
+                }
+                for entity_type in &child.entity_types {
+                    let child_table = layout.table_for_entity(entity_type)?;
+                    if child.derived {
+                        child_table.column_for_field(&child.attribute)?;
+                    }
+                    Self::valid_attributes(&child.filter, child_table, layout)?;
                 }
             }
 
+            Equal(attr, _) => {
+                table.column_for_field(attr)?;
+            }
             Contains(attr, _)
             | NotContains(attr, _)
-            | Equal(attr, _)
+            | ContainsAny(attr, _)
             | Not(attr, _)
             | GreaterThan(attr, _)
             | LessThan(attr, _)
@@ -423,7 +504,12 @@ impl<'a> QueryFilter<'a> {
             | NotStartsWith(attr, _)
             | EndsWith(attr, _)
             | NotEndsWith(attr, _) => {
-                table.column_for_field(attr)?;
+                if table.column_for_field(attr)?.is_fulltext() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the fulltext search `{}` can only be used to search for text",
+                        attr
+                    )));
+                }
             }
         }
         Ok(())
@@ -433,6 +519,8 @@ impl<'a> QueryFilter<'a> {
         QueryFilter {
             filter,
             table: self.table,
+            layout: self.layout,
+            block: self.block,
         }
     }
 
@@ -442,6 +530,14 @@ impl<'a> QueryFilter<'a> {
             .expect("the constructor already checked that all attribute names are valid")
     }
 
+    /// Return `true` if comparisons with `column` should go through the
+    /// index on the prefix of the column. The column a table is hash
+    /// partitioned on is indexed in full, and Postgres can only prune
+    /// partitions if it sees a comparison with the entire column
+    fn use_prefix(&self, column: &Column) -> bool {
+        column.is_text() && !self.table.is_partition_key(column)
+    }
+
     fn binary_op(
         &self,
         filters: &Vec<EntityFilter>,
@@ -500,6 +596,9 @@ impl<'a> QueryFilter<'a> {
                 }
             }
             Value::List(_) => {
+                if negated {
+                    out.push_sql("not ");
+                }
                 out.push_identifier(column.name.as_str())?;
                 out.push_sql(" @> ");
                 QueryValue(value, &column.column_type).walk_ast(out)?;
@@ -523,6 +622,30 @@ impl<'a> QueryFilter<'a> {
         Ok(())
     }
 
+    /// Generate `column && value`, which uses the GIN index on the column
+    fn contains_any(
+        &self,
+        attribute: &Attribute,
+        value: &Value,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let column = self.column(attribute);
+
+        match value {
+            Value::List(_) => {
+                out.push_identifier(column.name.as_str())?;
+                out.push_sql(" && ");
+                QueryValue(value, &column.column_type).walk_ast(out)?;
+                Ok(())
+            }
+            _ => Err(UnsupportedFilter {
+                filter: "contains_any".to_owned(),
+                value: value.clone(),
+            }
+            .into()),
+        }
+    }
+
     fn equals(
         &self,
         attribute: &Attribute,
@@ -532,7 +655,9 @@ impl<'a> QueryFilter<'a> {
     ) -> QueryResult<()> {
         let column = self.column(attribute);
 
-        if column.is_text() && value.is_string() {
+        if let ColumnType::TSVector(config) = &column.column_type {
+            self.fulltext_match(column, config, value, out)?;
+        } else if self.use_prefix(column) && value.is_string() {
             PrefixComparison::new(op, column, value).walk_ast(out.reborrow())?;
         } else {
             out.push_identifier(column.name.as_str())?;
@@ -561,6 +686,34 @@ impl<'a> QueryFilter<'a> {
         Ok(())
     }
 
+    /// Generate `column @@ plainto_tsquery(language, text)`, which uses the
+    /// GIN index on the fulltext search `column`. Any text is a valid
+    /// search; it matches entities that contain all of its words
+    fn fulltext_match(
+        &self,
+        column: &Column,
+        config: &FulltextConfig,
+        value: &Value,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        match value {
+            Value::String(text) => {
+                out.push_identifier(column.name.as_str())?;
+                out.push_sql(" @@ plainto_tsquery('");
+                out.push_sql(config.language.as_sql());
+                out.push_sql("', ");
+                out.push_bind_param::<Text, _>(text)?;
+                out.push_sql(")");
+                Ok(())
+            }
+            _ => Err(UnsupportedFilter {
+                filter: "fulltext".to_owned(),
+                value: value.clone(),
+            }
+            .into()),
+        }
+    }
+
     fn compare(
         &self,
         attribute: &Attribute,
@@ -570,16 +723,21 @@ impl<'a> QueryFilter<'a> {
     ) -> QueryResult<()> {
         let column = self.column(attribute);
 
-        if column.is_text() && value.is_string() {
+        if self.use_prefix(column) && value.is_string() {
             PrefixComparison::new(op, column, value).walk_ast(out.reborrow())?;
         } else {
             out.push_identifier(column.name.as_str())?;
             out.push_sql(op.as_str());
             match value {
-                Value::BigInt(_) | Value::BigDecimal(_) | Value::Int(_) | Value::String(_) => {
-                    QueryValue(value, &column.column_type).walk_ast(out)?
-                }
-                Value::Bool(_) | Value::Bytes(_) | Value::List(_) | Value::Null => {
+                // Comparing booleans and bytes is only needed to continue
+                // queries ordered by them after a cursor
+                Value::BigInt(_)
+                | Value::BigDecimal(_)
+                | Value::Int(_)
+                | Value::String(_)
+                | Value::Bool(_)
+                | Value::Bytes(_) => QueryValue(value, &column.column_type).walk_ast(out)?,
+                Value::List(_) | Value::Null => {
                     return Err(UnsupportedFilter {
                         filter: op.as_str().to_owned(),
                         value: value.clone(),
@@ -632,7 +790,7 @@ impl<'a> QueryFilter<'a> {
         }
 
         if have_non_nulls {
-            if column.is_text()
+            if self.use_prefix(column)
                 && values.iter().all(|v| match v {
                     Value::String(s) => s.len() <= STRING_PREFIX_SIZE - 1,
                     _ => false,
@@ -672,6 +830,73 @@ impl<'a> QueryFilter<'a> {
         Ok(())
     }
 
+    /// Generate a condition that checks whether any of the children that
+    /// `child` links to passes the filter of `child`
+    fn child(&self, child: &'a ChildFilter, mut out: AstPass<Pg>) -> QueryResult<()> {
+        // Generate
+        //   column in (select i.id from child_table i
+        //               where <i is current at block> and <child filter>
+        //              union all ...)
+        // when the parent stores the ids of its children, and
+        //   id in (select i.child_column from child_table i where ...)
+        // when the link is derived. The children's columns in the child
+        // filter are not qualified and therefore refer to `i`. Lists of ids
+        // are checked with `&&` on the parent side, and are unnested on
+        // the child side
+        if child.derived {
+            out.push_identifier(PRIMARY_KEY_COLUMN)?;
+            out.push_sql(" in (");
+        } else {
+            let column = self.column(&child.attribute);
+            out.push_identifier(column.name.as_str())?;
+            if column.is_list() {
+                out.push_sql(" && array(");
+            } else {
+                out.push_sql(" in (");
+            }
+        }
+        for (i, entity_type) in child.entity_types.iter().enumerate() {
+            let table = self
+                .layout
+                .table_for_entity(entity_type)
+                .expect("the constructor already checked that all child types have tables");
+            if i > 0 {
+                out.push_sql(" union all ");
+            }
+            out.push_sql("select ");
+            if child.derived {
+                let column = table
+                    .column_for_field(&child.attribute)
+                    .expect("the constructor already checked that all attribute names are valid");
+                if column.is_list() {
+                    out.push_sql("unnest(i.");
+                    out.push_identifier(column.name.as_str())?;
+                    out.push_sql(")");
+                } else {
+                    out.push_sql("i.");
+                    out.push_identifier(column.name.as_str())?;
+                }
+            } else {
+                out.push_sql("i.");
+                out.push_identifier(PRIMARY_KEY_COLUMN)?;
+            }
+            out.push_sql(" from ");
+            out.push_sql(table.qualified_name.as_str());
+            out.push_sql(" i where ");
+            BlockRangeContainsClause::new(table, "i.", self.block).walk_ast(out.reborrow())?;
+            out.push_sql(" and ");
+            QueryFilter {
+                filter: &child.filter,
+                table,
+                layout: self.layout,
+                block: self.block,
+            }
+            .walk_ast(out.reborrow())?;
+        }
+        out.push_sql(")");
+        Ok(())
+    }
+
     fn starts_or_ends_with(
         &self,
         attribute: &Attribute,
@@ -720,9 +945,28 @@ impl<'a> QueryFragment<Pg> for QueryFilter<'a> {
         match &self.filter {
             And(filters) => self.binary_op(filters, " and ", " true ", out)?,
             Or(filters) => self.binary_op(filters, " or ", " false ", out)?,
+            Negation(filter) => {
+                // Entities for which the filter is null, e.g., because it
+                // compares an attribute that is null, do not pass it, and
+                // therefore pass its negation
+                out.push_sql("not coalesce((");
+                self.with(filter).walk_ast(out.reborrow())?;
+                out.push_sql("), false)");
+            }
+            TypeCondition(entity_type, filter) => {
+                if &self.table.object == entity_type {
+                    out.push_sql("(");
+                    self.with(filter).walk_ast(out.reborrow())?;
+                    out.push_sql(")");
+                } else {
+                    out.push_sql(" true ");
+                }
+            }
+            Child(child) => self.child(child, out)?,
 
             Contains(attr, value) => self.contains(attr, value, false, out)?,
             NotContains(attr, value) => self.contains(attr, value, true, out)?,
+            ContainsAny(attr, value) => self.contains_any(attr, value, out)?,
 
             Equal(attr, value) => self.equals(attr, value, c::Equal, out)?,
             Not(attr, value) => self.equals(attr, value, c::NotEqual, out)?,
@@ -774,7 +1018,7 @@ impl<'a> QueryFragment<Pg> for FindQuery<'a> {
         out.push_sql(" = ");
         out.push_bind_param::<Text, _>(&self.id)?;
         out.push_sql(" and ");
-        BlockRangeContainsClause::new("e.", self.block).walk_ast(out)
+        BlockRangeContainsClause::new(self.table, "e.", self.block).walk_ast(out)
     }
 }
 
@@ -828,7 +1072,7 @@ impl<'a> QueryFragment<Pg> for FindManyQuery<'a> {
             out.push_sql(" = any(");
             out.push_bind_param::<Array<Text>, _>(&self.ids_for_type[table.object.as_str()])?;
             out.push_sql(") and ");
-            BlockRangeContainsClause::new("e.", self.block).walk_ast(out.reborrow())?;
+            BlockRangeContainsClause::new(table, "e.", self.block).walk_ast(out.reborrow())?;
         }
         Ok(())
     }
@@ -896,7 +1140,7 @@ impl<'a> QueryFragment<Pg> for InsertQuery<'a> {
 
         out.push_sql("(");
         for column in self.table.columns.iter() {
-            if self.entity.contains_key(&column.field) {
+            if self.entity.contains_key(&column.field) || column.is_fulltext() {
                 out.push_identifier(column.name.as_str())?;
                 out.push_sql(", ");
             }
@@ -905,7 +1149,24 @@ impl<'a> QueryFragment<Pg> for InsertQuery<'a> {
 
         out.push_sql(")\nvalues(");
         for column in self.table.columns.iter() {
-            if let Some(value) = self.entity.get(&column.field) {
+            if let ColumnType::TSVector(config) = &column.column_type {
+                // Fulltext searches are computed from the searched fields,
+                // never set by the mappings
+                let text = config
+                    .fields
+                    .iter()
+                    .filter_map(|field| match self.entity.get(field) {
+                        Some(Value::String(s)) => Some(s.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                out.push_sql("to_tsvector('");
+                out.push_sql(config.language.as_sql());
+                out.push_sql("', ");
+                out.push_bind_param::<Text, _>(&text)?;
+                out.push_sql("), ");
+            } else if let Some(value) = self.entity.get(&column.field) {
                 QueryValue(value, &column.column_type).walk_ast(out.reborrow())?;
                 out.push_sql(", ");
             }
@@ -1049,6 +1310,7 @@ impl<'a> FilterWindow<'a> {
         layout: &'a Layout,
         window: EntityWindow,
         query_filter: Option<&'a EntityFilter>,
+        block: BlockNumber,
     ) -> Result<Self, QueryExecutionError> {
         let EntityWindow {
             child_type,
@@ -1057,7 +1319,7 @@ impl<'a> FilterWindow<'a> {
         } = window;
         let table = layout.table_for_entity(&child_type).map(|rc| rc.as_ref())?;
         let query_filter = query_filter
-            .map(|filter| QueryFilter::new(filter, table))
+            .map(|filter| QueryFilter::new(filter, table, layout, block))
             .transpose()?;
         let link = TableLink::new(layout, table, link)?;
         Ok(FilterWindow {
@@ -1110,15 +1372,16 @@ impl<'a> FilterWindow<'a> {
                 out.push_sql(" = any(");
                 out.push_bind_param::<Array<Text>, _>(&self.ids)?;
                 out.push_sql(")\n   and ");
-                BlockRangeContainsClause::new("c.", block).walk_ast(out.reborrow())?;
+                BlockRangeContainsClause::new(self.table, "c.", block).walk_ast(out.reborrow())?;
             }
-            TableLink::Parent(_) => {
+            TableLink::Parent(parent) => {
                 out.push_sql("p.id = any(");
                 out.push_bind_param::<Array<Text>, _>(&self.ids)?;
                 out.push_sql(")\n   and ");
-                BlockRangeContainsClause::new("c.", block).walk_ast(out.reborrow())?;
+                BlockRangeContainsClause::new(self.table, "c.", block).walk_ast(out.reborrow())?;
                 out.push_sql("\n   and ");
-                BlockRangeContainsClause::new("p.", block).walk_ast(out.reborrow())?;
+                BlockRangeContainsClause::new(parent.table, "p.", block)
+                    .walk_ast(out.reborrow())?;
             }
         }
         Ok(())
@@ -1157,6 +1420,15 @@ impl<'a> FilterWindow<'a> {
         Ok(())
     }
 
+    /// The column in the child table that holds the id of its parent, if
+    /// the children of each parent can be looked up separately through it
+    fn parent_column(&self) -> Option<&'a Column> {
+        match self.link {
+            TableLink::Direct(column) if !column.is_list() => Some(column),
+            TableLink::Direct(_) | TableLink::Parent(_) => None,
+        }
+    }
+
     /// Select a basic subset of columns from the child table for use in
     /// the `matches` CTE of queries that need to retrieve entities of
     /// different types or entities that link differently to their parents
@@ -1198,6 +1470,7 @@ impl<'a> FilterCollection<'a> {
         layout: &'a Layout,
         collection: EntityCollection,
         filter: Option<&'a EntityFilter>,
+        block: BlockNumber,
     ) -> Result<Self, QueryExecutionError> {
         match collection {
             EntityCollection::All(entities) => {
@@ -1213,7 +1486,7 @@ impl<'a> FilterCollection<'a> {
                             .map(|rc| rc.as_ref())
                             .and_then(|table| {
                                 filter
-                                    .map(|filter| QueryFilter::new(filter, table))
+                                    .map(|filter| QueryFilter::new(filter, table, layout, block))
                                     .transpose()
                                     .map(|filter| (table, filter))
                             })
@@ -1224,7 +1497,7 @@ impl<'a> FilterCollection<'a> {
             EntityCollection::Window(windows) => {
                 let windows = windows
                     .into_iter()
-                    .map(|window| FilterWindow::new(layout, window, filter))
+                    .map(|window| FilterWindow::new(layout, window, filter, block))
                     .collect::<Result<_, _>>()?;
                 Ok(FilterCollection::Window(windows))
             }
@@ -1246,12 +1519,50 @@ impl<'a> FilterCollection<'a> {
     }
 }
 
+/// How to query the children of a set of parents, e.g., the positions of
+/// a list of owners, when only the first few children of each parent in
+/// some order are needed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowStrategy {
+    /// Rank all matching children of all parents, and then keep the
+    /// requested range of each parent's children. Works well when parents
+    /// have few children
+    Rank,
+    /// Look up the children of each parent separately, sorted and limited
+    /// to the requested range. This lets Postgres walk an index on the
+    /// parent and sort columns instead of sorting all children of all
+    /// parents, which is much faster when parents have many children
+    Lateral,
+}
+
+impl FromStr for WindowStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rank" => Ok(WindowStrategy::Rank),
+            "lateral" => Ok(WindowStrategy::Lateral),
+            _ => Err(format!(
+                "unknown window strategy `{}`, must be `rank` or `lateral`",
+                s
+            )),
+        }
+    }
+}
+
 /// Convenience to pass the name of the column to order by around. If `name`
 /// is `None`, the sort key should be ignored
 #[derive(Debug, Clone)]
 pub struct SortKey {
     name: Option<SqlName>,
     direction: EntityOrder,
+    nulls: NullsOrder,
+    /// When ordering by a fulltext search, the function that ranks
+    /// matches, the text search configuration, and the search text
+    rank: Option<(&'static str, &'static str, String)>,
+    /// The direction of `id`, and of `entity` for queries that combine
+    /// several tables, when they break ties
+    tiebreak: EntityOrder,
 }
 
 impl SortKey {
@@ -1266,24 +1577,79 @@ impl SortKey {
         Ok(())
     }
 
+    /// Reverse the order completely, including how ties are broken, so
+    /// that the first entities in the reversed order are the last ones in
+    /// the original order
+    fn reverse(self) -> Self {
+        SortKey {
+            direction: self.direction.reverse(),
+            nulls: self.nulls.reverse(),
+            tiebreak: self.tiebreak.reverse(),
+            ..self
+        }
+    }
+
     /// Generate
-    ///   order by [name direction,] id
+    ///   order by [name direction nulls,] id tiebreak
+    /// or, for a fulltext search,
+    ///   order by rank(name, plainto_tsquery(language, text)) direction nulls, id tiebreak
     fn order_by(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
         out.push_sql("order by ");
         if let Some(name) = &self.name {
-            out.push_identifier(name.as_str())?;
+            if let Some((rank, language, text)) = &self.rank {
+                out.push_sql(rank);
+                out.push_sql("(");
+                out.push_identifier(name.as_str())?;
+                out.push_sql(", plainto_tsquery('");
+                out.push_sql(language);
+                out.push_sql("', ");
+                out.push_bind_param::<Text, _>(text)?;
+                out.push_sql("))");
+            } else {
+                out.push_identifier(name.as_str())?;
+            }
             out.push_sql(" ");
             out.push_sql(self.direction.to_sql());
-            out.push_sql(" nulls last");
+            out.push_sql(" ");
+            out.push_sql(self.nulls.to_sql());
             if name.as_str() != PRIMARY_KEY_COLUMN {
                 out.push_sql(", ");
                 out.push_identifier(PRIMARY_KEY_COLUMN)?;
+                out.push_sql(" ");
+                out.push_sql(self.tiebreak.to_sql());
             }
             Ok(())
         } else {
-            out.push_identifier(PRIMARY_KEY_COLUMN)
+            out.push_identifier(PRIMARY_KEY_COLUMN)?;
+            out.push_sql(" ");
+            out.push_sql(self.tiebreak.to_sql());
+            Ok(())
         }
     }
+
+    /// Generate
+    ///   order by [name direction nulls,] id tiebreak, entity tiebreak
+    /// for queries that combine the rows of several tables. Entities of
+    /// different types can have the same id, for example the members of a
+    /// union, and ordering by their type, too, keeps their order stable so
+    /// that paging through the results neither skips nor repeats entities
+    fn order_by_with_entity(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        self.order_by(out)?;
+        out.push_sql(", entity ");
+        out.push_sql(self.tiebreak.to_sql());
+        Ok(())
+    }
+}
+
+/// The text that `filter` searches for with the fulltext search `attribute`
+fn fulltext_search_text<'a>(filter: &'a EntityFilter, attribute: &str) -> Option<&'a str> {
+    match filter {
+        EntityFilter::Equal(attr, Value::String(text)) if attr == attribute => Some(text.as_str()),
+        EntityFilter::And(filters) => filters
+            .iter()
+            .find_map(|filter| fulltext_search_text(filter, attribute)),
+        _ => None,
+    }
 }
 
 /// The parallel to `EntityQuery`.
@@ -1296,6 +1662,7 @@ pub struct FilterQuery<'a> {
     sort_key: SortKey,
     range: EntityRange,
     block: BlockNumber,
+    window_strategy: WindowStrategy,
 }
 
 impl<'a> FilterQuery<'a> {
@@ -1303,41 +1670,99 @@ impl<'a> FilterQuery<'a> {
         layout: &'a Layout,
         collection: EntityCollection,
         filter: Option<&'a EntityFilter>,
-        order: Option<(String, ValueType, EntityOrder)>,
+        order: Option<(String, ValueType, EntityOrder, NullsOrder)>,
         range: EntityRange,
         block: BlockNumber,
     ) -> Result<Self, QueryExecutionError> {
-        let collection = FilterCollection::new(layout, collection, filter)?;
+        let collection = FilterCollection::new(layout, collection, filter, block)?;
 
         // Get the name of the column we order by; if there is more than one
-        // table, we are querying an interface, and the order is on an attribute
-        // in that interface so that all tables have a column for that. It is
-        // therefore enough to just look at the first table to get the name
+        // table, we are querying an interface or a union, and the order is on
+        // an attribute that all its types share so that all tables have a
+        // column for that. It is therefore enough to just look at the first
+        // table to get the name
         let first_table = collection
             .first_table()
             .expect("an entity query always contains at least one entity type/table");
         let sort_key = match order {
-            Some((ref attribute, _, direction)) => {
+            Some((ref attribute, _, direction, nulls)) => {
                 let column = first_table.column_for_field(&attribute)?;
+                // Matches of a fulltext search are ranked against the text
+                // that the filter searches for
+                let rank = match &column.column_type {
+                    ColumnType::TSVector(config) => {
+                        let text = filter
+                            .and_then(|filter| fulltext_search_text(filter, attribute))
+                            .ok_or_else(|| {
+                                QueryExecutionError::OrderByNotSupportedError(
+                                    first_table.object.clone(),
+                                    attribute.clone(),
+                                )
+                            })?;
+                        Some((
+                            config.algorithm.rank_function(),
+                            config.language.as_sql(),
+                            text.to_owned(),
+                        ))
+                    }
+                    _ => None,
+                };
                 SortKey {
                     name: Some(column.name.clone()),
                     direction,
+                    nulls,
+                    rank,
+                    tiebreak: EntityOrder::Ascending,
                 }
             }
             None => SortKey {
                 name: None,
                 direction: EntityOrder::Ascending,
+                nulls: NullsOrder::Last,
+                rank: None,
+                tiebreak: EntityOrder::Ascending,
             },
         };
+        // The last entities are the first ones in the reversed order; they
+        // are flipped back into the order of the query once they are loaded
+        let sort_key = if range.from_end {
+            sort_key.reverse()
+        } else {
+            sort_key
+        };
 
         Ok(FilterQuery {
             collection,
             sort_key,
             range,
             block,
+            window_strategy: WindowStrategy::Rank,
         })
     }
 
+    /// The child table and the column linking it to its parents if this
+    /// query could use `WindowStrategy::Lateral`: it is for the first few
+    /// children of each parent, all of the same type, and children hold
+    /// the id of a single parent
+    pub fn lateral_candidate(&self) -> Option<(&'a Table, &'a Column)> {
+        match &self.collection {
+            FilterCollection::Window(windows)
+                if windows.len() == 1 && self.range.first.is_some() =>
+            {
+                let window = &windows[0];
+                window.parent_column().map(|column| (window.table, column))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn with_window_strategy(self, window_strategy: WindowStrategy) -> Self {
+        FilterQuery {
+            window_strategy,
+            ..self
+        }
+    }
+
     /// Generate `[limit {first}] [offset {skip}]
     fn limit(&self, out: &mut AstPass<Pg>) {
         if let Some(first) = &self.range.first {
@@ -1387,7 +1812,7 @@ impl<'a> FilterQuery<'a> {
         out.push_sql(table.qualified_name.as_str());
         out.push_sql(" c");
         out.push_sql("\n where ");
-        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        BlockRangeContainsClause::new(table, "c.", self.block).walk_ast(out.reborrow())?;
         if let Some(filter) = table_filter {
             out.push_sql(" and ");
             filter.walk_ast(out.reborrow())?;
@@ -1456,6 +1881,77 @@ impl<'a> FilterQuery<'a> {
         Ok(())
     }
 
+    /// Only one table/filter pair, and a window in which children link
+    /// directly to a single parent; look up the children of each parent
+    /// separately
+    ///
+    /// Generate a query
+    ///   select '..' as entity, to_jsonb(c.*) as data
+    ///     from unnest($parent_ids) as p(id)
+    ///          cross join lateral
+    ///          (select c.*, p.id as g$parent_id,
+    ///                  rank() over (order by ..) as g$pos
+    ///             from table c
+    ///            where c.{parent_column} = p.id
+    ///              and block_range @> $block
+    ///              and filter
+    ///            order by ..
+    ///            limit first offset skip) c
+    ///    order by c.g$parent_id, c.g$pos
+    fn query_window_lateral(
+        &self,
+        window: &FilterWindow,
+        column: &Column,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        Self::select_entity_and_data(&window.table, &mut out);
+        out.push_sql(
+            "
+  from unnest(",
+        );
+        out.push_bind_param::<Array<Text>, _>(&window.ids)?;
+        out.push_sql(
+            ") as p(id)
+       cross join lateral
+       (select c.*, ",
+        );
+        out.push_sql("p.id as g$parent_id, rank() over (");
+        self.sort_key.order_by(&mut out)?;
+        out.push_sql(
+            ") as g$pos
+  from ",
+        );
+        out.push_sql(window.table.qualified_name.as_str());
+        out.push_sql(
+            " c
+ where c.",
+        );
+        out.push_identifier(column.name.as_str())?;
+        out.push_sql(
+            " = p.id
+   and ",
+        );
+        BlockRangeContainsClause::new(window.table, "c.", self.block).walk_ast(out.reborrow())?;
+        if let Some(filter) = &window.query_filter {
+            out.push_sql(
+                "
+   and ",
+            );
+            filter.walk_ast(out.reborrow())?;
+        }
+        out.push_sql(
+            "
+ ",
+        );
+        self.sort_key.order_by(&mut out)?;
+        self.limit(&mut out);
+        out.push_sql(
+            ") c
+ order by c.g$parent_id, c.g$pos",
+        );
+        Ok(())
+    }
+
     /// No windowing, but multiple entity types
     fn query_no_window(
         &self,
@@ -1463,7 +1959,8 @@ impl<'a> FilterQuery<'a> {
         mut out: AstPass<Pg>,
     ) -> QueryResult<()> {
         // We have multiple tables which might have different schemas since
-        // the entity_types come from implementing the same interface. We
+        // the entity_types come from implementing the same interface or
+        // belong to the same union. We
         // need to do the query in two steps: first we build a CTE with the
         // id's of entities matching the filter and order/limit. As a second
         // step, we get matching rows from the underlying tables and convert
@@ -1477,14 +1974,14 @@ impl<'a> FilterQuery<'a> {
         //    where {query_filter}
         //    union all
         //    ...
-        //    order by {sort_key}
+        //    order by {sort_key}, entity
         //    limit n offset m)
         // select m.entity, to_jsonb(c.*) as data, c.id, c.{sort_key}
         //   from {table} c, matches m
         //  where c.vid = m.vid and m.entity = '...'
         //  union all
         //  ...
-        //  order by c.{sort_key}
+        //  order by c.{sort_key}, entity
 
         // Step 1: build matches CTE
         out.push_sql("with matches as (");
@@ -1503,7 +2000,7 @@ impl<'a> FilterQuery<'a> {
             self.filtered_rows(table, filter, out.reborrow())?;
         }
         out.push_sql("\n ");
-        self.sort_key.order_by(&mut out)?;
+        self.sort_key.order_by_with_entity(&mut out)?;
         self.limit(&mut out);
 
         out.push_sql(")\n");
@@ -1523,7 +2020,7 @@ impl<'a> FilterQuery<'a> {
             out.push_bind_param::<Text, _>(&table.object)?;
         }
         out.push_sql("\n ");
-        self.sort_key.order_by(&mut out)?;
+        self.sort_key.order_by_with_entity(&mut out)?;
         Ok(())
     }
 
@@ -1561,7 +2058,7 @@ impl<'a> FilterQuery<'a> {
         out.push_sql("select c.* from (");
 
         out.push_sql("select c.*, rank() over (partition by c.g$parent_id ");
-        self.sort_key.order_by(&mut out)?;
+        self.sort_key.order_by_with_entity(&mut out)?;
         out.push_sql(") as g$pos");
 
         out.push_sql("\n from (");
@@ -1640,7 +2137,12 @@ impl<'a> QueryFragment<Pg> for FilterQuery<'a> {
                     let window = windows
                         .first()
                         .expect("a query always uses at least one table");
-                    self.query_window_one_entity(window, out)
+                    match (self.window_strategy, window.parent_column()) {
+                        (WindowStrategy::Lateral, Some(column)) => {
+                            self.query_window_lateral(window, column, out)
+                        }
+                        _ => self.query_window_one_entity(window, out),
+                    }
                 } else {
                     self.query_window(windows, out)
                 }
@@ -1663,6 +2165,285 @@ impl<'a> LoadQuery<PgConnection, EntityData> for FilterQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for FilterQuery<'a> {}
 
+/// The attribute of the results of an `AggregateQuery` that holds the
+/// number of entities it looked at
+const AGGREGATED_ENTITIES: &str = "g$entities";
+
+/// Count the entities in a table that match a filter, grouped by the values
+/// of some of their columns, and compute the sum, minimum, maximum, and
+/// average of each numeric column for every group. At most one more than
+/// `max_entities` entities are looked at, so that callers can tell from
+/// `EntityData::aggregated_entities` whether there were too many
+#[derive(Debug, Clone)]
+pub struct AggregateQuery<'a> {
+    table: &'a Table,
+    filter: Option<QueryFilter<'a>>,
+    group_by: Vec<&'a Column>,
+    numeric: Vec<&'a Column>,
+    range: EntityRange,
+    block: BlockNumber,
+    max_entities: u32,
+}
+
+impl<'a> AggregateQuery<'a> {
+    pub fn new(
+        layout: &'a Layout,
+        table: &'a Table,
+        filter: Option<&'a EntityFilter>,
+        group_by: &[String],
+        range: EntityRange,
+        block: BlockNumber,
+        max_entities: u32,
+    ) -> Result<Self, QueryExecutionError> {
+        let filter = filter
+            .map(|filter| QueryFilter::new(filter, table, layout, block))
+            .transpose()?;
+        let numeric = table
+            .columns
+            .iter()
+            .filter(|column| Self::is_numeric(column))
+            .collect::<Vec<_>>();
+        let group_by = group_by
+            .iter()
+            .map(|attribute| {
+                let column = table.column_for_field(attribute)?;
+                let clashes = attribute == AGGREGATE_COUNT_ATTRIBUTE
+                    || numeric.iter().any(|numeric| {
+                        AggregateFunction::ALL
+                            .iter()
+                            .any(|function| &function.attribute(&numeric.field) == attribute)
+                    });
+                if column.is_list() || column.is_fulltext() || clashes {
+                    return Err(QueryExecutionError::NotSupported(format!(
+                        "entities of type `{}` can not be grouped by `{}`",
+                        table.object, attribute
+                    )));
+                }
+                Ok(column)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AggregateQuery {
+            table,
+            filter,
+            group_by,
+            numeric,
+            range,
+            block,
+            max_entities,
+        })
+    }
+
+    fn is_numeric(column: &Column) -> bool {
+        match column.column_type {
+            ColumnType::Int | ColumnType::BigInt | ColumnType::BigDecimal => !column.is_list(),
+            _ => false,
+        }
+    }
+
+    /// The type of the values that `function` computes over `column`
+    fn aggregate_type(function: AggregateFunction, column: &Column) -> ColumnType {
+        match (function, &column.column_type) {
+            (AggregateFunction::Avg, _) => ColumnType::BigDecimal,
+            // Postgres sums `int4` into a `bigint`
+            (AggregateFunction::Sum, ColumnType::Int) => ColumnType::BigInt,
+            (_, column_type) => column_type.clone(),
+        }
+    }
+
+    /// The attributes of the entities that this query produces, together
+    /// with the types of their values
+    pub fn attributes(&self) -> Vec<(String, ColumnType)> {
+        let mut attributes = vec![(AGGREGATE_COUNT_ATTRIBUTE.to_owned(), ColumnType::Int)];
+        for column in &self.group_by {
+            attributes.push((column.field.clone(), column.column_type.clone()));
+        }
+        for column in &self.numeric {
+            for function in AggregateFunction::ALL.iter() {
+                attributes.push((
+                    function.attribute(&column.field),
+                    Self::aggregate_type(*function, column),
+                ));
+            }
+        }
+        attributes
+    }
+
+    /// Aggregate `entities`, which must be the entities that this query
+    /// would look at, in memory. The result has the same groups as running
+    /// the query, but they are not ordered and the range is not applied
+    pub fn aggregate_entities(&self, entities: Vec<Entity>) -> Vec<Entity> {
+        let mut groups: Vec<(Vec<Value>, Vec<Entity>)> = vec![];
+        for entity in entities {
+            let key = self
+                .group_by
+                .iter()
+                .map(|column| entity.get(&column.field).cloned().unwrap_or(Value::Null))
+                .collect::<Vec<_>>();
+            match groups.iter_mut().find(|(group_key, _)| *group_key == key) {
+                Some((_, members)) => members.push(entity),
+                None => groups.push((key, vec![entity])),
+            }
+        }
+
+        groups
+            .into_iter()
+            .map(|(key, members)| {
+                let mut group = Entity::new();
+                group.insert(
+                    AGGREGATE_COUNT_ATTRIBUTE.to_owned(),
+                    Value::Int(members.len() as i32),
+                );
+                for (column, value) in self.group_by.iter().zip(key) {
+                    group.insert(column.field.clone(), value);
+                }
+                for column in &self.numeric {
+                    // Like in SQL, aggregates ignore nulls
+                    let values = members
+                        .iter()
+                        .filter_map(|member| member.get(&column.field))
+                        .filter(|value| **value != Value::Null)
+                        .collect::<Vec<_>>();
+                    for function in AggregateFunction::ALL.iter() {
+                        group.insert(
+                            function.attribute(&column.field),
+                            Self::compute(*function, &values),
+                        );
+                    }
+                }
+                group
+            })
+            .collect()
+    }
+
+    /// Compute `function` over `values`, which are all of the same numeric
+    /// type, with the type that `aggregate_type` gives for it
+    fn compute(function: AggregateFunction, values: &[&Value]) -> Value {
+        use std::cmp::Ordering;
+
+        let compare = |a: &&&Value, b: &&&Value| a.partial_cmp(b).unwrap_or(Ordering::Equal);
+        let sum = || match values.first() {
+            Some(Value::BigDecimal(_)) => Value::BigDecimal(values.iter().fold(
+                scalar::BigDecimal::new(0.into(), 0),
+                |sum, value| match value {
+                    Value::BigDecimal(d) => sum + d.clone(),
+                    _ => sum,
+                },
+            )),
+            _ => Value::BigInt(values.iter().fold(
+                scalar::BigInt::from(0),
+                |sum, value| match value {
+                    Value::Int(i) => sum + scalar::BigInt::from(*i),
+                    Value::BigInt(n) => sum + n.clone(),
+                    _ => sum,
+                },
+            )),
+        };
+
+        if values.is_empty() {
+            return Value::Null;
+        }
+        match function {
+            AggregateFunction::Sum => sum(),
+            AggregateFunction::Min => (*values.iter().min_by(compare).unwrap()).clone(),
+            AggregateFunction::Max => (*values.iter().max_by(compare).unwrap()).clone(),
+            AggregateFunction::Avg => {
+                let sum = match sum() {
+                    Value::BigDecimal(d) => d,
+                    Value::BigInt(n) => n.to_big_decimal(scalar::BigInt::from(0)),
+                    _ => unreachable!("sums are always numbers"),
+                };
+                let count = scalar::BigDecimal::new((values.len() as i64).into(), 0);
+                Value::BigDecimal(sum / count)
+            }
+        }
+    }
+}
+
+impl<'a> QueryFragment<Pg> for AggregateQuery<'a> {
+    /// Generate
+    ///   select '..' as entity, to_jsonb(a.*) as data
+    ///     from (select count(*)::int as "count", c.group_col as "groupCol",
+    ///                  sum(c.num_col) as "numCol_sum", ..,
+    ///                  sum(count(*)) over () as "g$entities"
+    ///             from (select * from table c
+    ///                    where block_range @> $block
+    ///                      and filter
+    ///                    limit $max_entities + 1) c
+    ///            group by c.group_col, ..
+    ///            order by c.group_col, ..
+    ///            limit .. offset ..) a
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        out.push_sql("select '");
+        out.push_sql(&self.table.object);
+        out.push_sql("' as entity, to_jsonb(a.*) as data");
+        out.push_sql("\n  from (select count(*)::int as ");
+        out.push_identifier(AGGREGATE_COUNT_ATTRIBUTE)?;
+        for column in &self.group_by {
+            out.push_sql(", c.");
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql(" as ");
+            out.push_identifier(&column.field)?;
+        }
+        for column in &self.numeric {
+            for function in AggregateFunction::ALL.iter() {
+                out.push_sql(", ");
+                out.push_sql(function.as_str());
+                out.push_sql("(c.");
+                out.push_identifier(column.name.as_str())?;
+                out.push_sql(") as ");
+                out.push_identifier(&function.attribute(&column.field))?;
+            }
+        }
+        out.push_sql(", sum(count(*)) over () as ");
+        out.push_identifier(AGGREGATED_ENTITIES)?;
+        out.push_sql("\n  from (select * from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c");
+        out.push_sql("\n where ");
+        BlockRangeContainsClause::new(self.table, "c.", self.block).walk_ast(out.reborrow())?;
+        if let Some(filter) = &self.filter {
+            out.push_sql(" and ");
+            filter.walk_ast(out.reborrow())?;
+        }
+        out.push_sql("\n limit ");
+        out.push_sql(&(self.max_entities as u64 + 1).to_string());
+        out.push_sql(") c");
+        for clause in &["\n group by ", "\n order by "] {
+            for (i, column) in self.group_by.iter().enumerate() {
+                out.push_sql(if i == 0 { *clause } else { ", " });
+                out.push_sql("c.");
+                out.push_identifier(column.name.as_str())?;
+            }
+        }
+        if let Some(first) = &self.range.first {
+            out.push_sql("\n limit ");
+            out.push_sql(&first.to_string());
+        }
+        if self.range.skip > 0 {
+            out.push_sql("\noffset ");
+            out.push_sql(&self.range.skip.to_string());
+        }
+        out.push_sql(") a");
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for AggregateQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityData> for AggregateQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for AggregateQuery<'a> {}
+
 /// Reduce the upper bound of the current entry's block range to `block` as
 /// long as that does not result in an empty block range
 #[derive(Debug, Clone, Constructor)]
@@ -1706,6 +2487,39 @@ impl<'a> QueryId for ClampRangeQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for ClampRangeQuery<'a> {}
 
+/// A query that removes all versions of an entity, regardless of their
+/// block range
+#[derive(Debug, Clone, Constructor)]
+pub struct RemoveVersionsQuery<'a> {
+    table: &'a Table,
+    key: &'a EntityKey,
+}
+
+impl<'a> QueryFragment<Pg> for RemoveVersionsQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        // delete from table
+        //  where id = $id
+        out.unsafe_to_cache_prepared();
+        out.push_sql("delete from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(
+            "
+ where ",
+        );
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = ");
+        out.push_bind_param::<Text, _>(&self.key.entity_id)
+    }
+}
+
+impl<'a> QueryId for RemoveVersionsQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for RemoveVersionsQuery<'a> {}
+
 /// Helper struct for returning the id's touched by the RevertRemove and
 /// RevertExtend queries
 #[derive(QueryableByName, PartialEq, Eq, Hash)]
@@ -1779,10 +2593,8 @@ impl<'a> QueryFragment<Pg> for RevertClampQuery<'a> {
         out.push_identifier(BLOCK_RANGE_COLUMN)?;
         out.push_sql(" = int4range(lower(");
         out.push_identifier(BLOCK_RANGE_COLUMN)?;
-        out.push_sql("), null)\n where");
-        out.push_identifier(BLOCK_RANGE_COLUMN)?;
-        out.push_sql(" @> ");
-        out.push_bind_param::<Integer, _>(&self.block)?;
+        out.push_sql("), null)\n where ");
+        BlockRangeContainsClause::new(self.table, "", self.block).walk_ast(out.reborrow())?;
         out.push_sql(" and not ");
         out.push_sql(BLOCK_RANGE_CURRENT);
         out.push_sql("\nreturning ");
@@ -1803,3 +2615,95 @@ impl<'a> LoadQuery<PgConnection, RevertEntityData> for RevertClampQuery<'a> {
 }
 
 impl<'a, Conn> RunQueryDsl<Conn> for RevertClampQuery<'a> {}
+
+/// The end of a version's block range that `VersionsChangedQuery` matches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockRangeBound {
+    /// Versions that were written after `since` and are still current
+    /// at `to`
+    Lower,
+    /// Versions that were current at `since` and were superseded or
+    /// removed by `to`
+    Upper,
+}
+
+/// A query that returns the versions in `table` that were written or
+/// ended between the blocks `since` (exclusive) and `to` (inclusive)
+#[derive(Debug, Clone, Constructor)]
+pub struct VersionsChangedQuery<'a> {
+    table: &'a Table,
+    bound: BlockRangeBound,
+    since: BlockNumber,
+    to: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for VersionsChangedQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Construct a query
+        //   select '..' as entity, to_jsonb(e.*) as data
+        //     from table e
+        //    where e.block_range && int4range($since, $to, '(]')
+        //      and lower(e.block_range) > $since
+        //      and e.block_range @> $to
+        //    order by e.id
+        // or, for `BlockRangeBound::Upper`
+        //    where e.block_range && int4range($since, $to, '[)')
+        //      and e.block_range @> $since
+        //      and upper(e.block_range) <= $to
+        //
+        // The overlap with the blocks in question lets Postgres use the
+        // range index instead of scanning the whole table
+        out.push_sql("select ");
+        out.push_bind_param::<Text, _>(&self.table.object)?;
+        out.push_sql(" as entity, to_jsonb(e.*) as data\n");
+        out.push_sql("  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" e\n where e.");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(" && int4range(");
+        out.push_bind_param::<Integer, _>(&self.since)?;
+        out.push_sql(", ");
+        out.push_bind_param::<Integer, _>(&self.to)?;
+        out.push_sql(match self.bound {
+            BlockRangeBound::Lower => ", '(]')\n   and ",
+            BlockRangeBound::Upper => ", '[)')\n   and ",
+        });
+        match self.bound {
+            BlockRangeBound::Lower => {
+                out.push_sql("lower(e.");
+                out.push_identifier(BLOCK_RANGE_COLUMN)?;
+                out.push_sql(") > ");
+                out.push_bind_param::<Integer, _>(&self.since)?;
+                out.push_sql("\n   and ");
+                BlockRangeContainsClause::new(self.table, "e.", self.to)
+                    .walk_ast(out.reborrow())?;
+            }
+            BlockRangeBound::Upper => {
+                BlockRangeContainsClause::new(self.table, "e.", self.since)
+                    .walk_ast(out.reborrow())?;
+                out.push_sql("\n   and upper(e.");
+                out.push_identifier(BLOCK_RANGE_COLUMN)?;
+                out.push_sql(") <= ");
+                out.push_bind_param::<Integer, _>(&self.to)?;
+            }
+        }
+        out.push_sql("\n order by e.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)
+    }
+}
+
+impl<'a> QueryId for VersionsChangedQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityData> for VersionsChangedQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for VersionsChangedQuery<'a> {}
@@ -4,8 +4,9 @@ use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
 use diesel::{insert_into, select, update};
 use futures::sync::mpsc::{channel, Sender};
+use graphql_parser::schema as s;
 use lru_time_cache::LruCache;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::{TryFrom, TryInto};
 use std::iter::FromIterator;
 use std::sync::{Arc, Mutex, RwLock};
@@ -14,27 +15,36 @@ use uuid::Uuid;
 
 use graph::components::store::Store as StoreTrait;
 use graph::data::subgraph::schema::{
-    SubgraphDeploymentEntity, SubgraphManifestEntity, TypedEntity as _, SUBGRAPHS_ID,
+    attribute_index_definitions, DynamicEthereumContractDataSourceEntity,
+    SubgraphAdminOperationEntity, SubgraphDeploymentEntity, SubgraphErrorEntity,
+    SubgraphHandlerExecutionEntity, SubgraphManifestEntity, SubgraphSkippedBlockEntity,
+    TypedEntity, SUBGRAPHS_ID,
 };
 use graph::prelude::{
     bail, debug, ethabi, format_err, futures03, info, o, serde_json, stream, tiny_keccak, tokio,
-    trace, warn, web3, AttributeIndexDefinition, BigInt, BlockNumber, ChainHeadUpdateListener as _,
-    ChainHeadUpdateStream, ChainStore, Entity, EntityKey, EntityModification, EntityOrder,
-    EntityQuery, EntityRange, Error, EthereumBlock, EthereumBlockPointer, EthereumCallCache,
-    EthereumNetworkIdentifier, EventProducer as _, Future, Future01CompatExt, LightEthereumBlock,
-    Logger, MetadataOperation, MetricsRegistry, QueryExecutionError, Schema, Sink as _,
-    StopwatchMetrics, StoreError, StoreEvent, StoreEventStream, StoreEventStreamBox, Stream,
-    SubgraphAssignmentProviderError, SubgraphDeploymentId, SubgraphDeploymentStore,
-    SubgraphEntityPair, TransactionAbortError, Value, BLOCK_NUMBER_MAX,
+    trace, warn, web3, AttributeIndexDefinition, BigInt, BlockEntityChange, BlockNumber,
+    ChainHeadUpdateListener as _, ChainHeadUpdateStream, ChainStore, Entity, EntityAggregation,
+    EntityChange, EntityChangeOperation, EntityCollection, EntityDiff, EntityFilter, EntityKey,
+    EntityModification, EntityOrder, EntityQuery, EntityRange, Error, EthereumBlock,
+    EthereumBlockPointer, EthereumCallCache, EthereumNetworkIdentifier, EventProducer as _, Future,
+    Future01CompatExt, IndexCreation, LightEthereumBlock, Logger, MetadataOperation,
+    MetricsRegistry, NullsOrder, OversizedEntityIds, ProofOfIndexing, QueryExecutionError, Schema,
+    Sink as _, StopwatchMetrics, StoreError, StoreEvent, StoreEventStream, StoreEventStreamBox,
+    Stream, SubgraphAssignmentProviderError, SubgraphDeploymentId, SubgraphDeploymentStore,
+    SubgraphEntityPair, TransactionAbortError, Value, BLOCK_NUMBER_MAX, BLOCK_NUMBER_PENDING,
 };
 use graph_chain_ethereum::BlockIngestorMetrics;
-use graph_graphql::prelude::api_schema;
+use graph_graphql::prelude::{api_schema, api_schema_without_aggregations};
 use web3::types::H256;
 
+use crate::block_data;
 use crate::chain_head_listener::ChainHeadUpdateListener;
 use crate::entities as e;
-use crate::functions::{attempt_chain_head_update, lookup_ancestor_block};
+use crate::functions::attempt_chain_head_update;
 use crate::history_event::HistoryEvent;
+use crate::keyset;
+use crate::negative_cache::NegativeCache;
+use crate::pending;
 use crate::store_events::StoreEventListener;
 
 embed_migrations!("./migrations");
@@ -139,7 +149,10 @@ pub struct Store {
     /// A cache for the storage metadata for subgraphs. The Store just
     /// hosts this because it lives long enough, but it is managed from
     /// the entities module
-    pub(crate) storage_cache: e::StorageCache,
+    pub(crate) storage_cache: Arc<e::StorageCache>,
+
+    /// The entities that `get` did not find
+    negative_cache: Arc<NegativeCache>,
 
     registry: Arc<dyn MetricsRegistry>,
 }
@@ -180,7 +193,8 @@ impl Store {
             genesis_block_ptr: (net_identifiers.genesis_block_hash, 0 as u64).into(),
             conn: pool,
             schema_cache: Mutex::new(LruCache::with_capacity(100)),
-            storage_cache: e::make_storage_cache(),
+            storage_cache: Arc::new(e::make_storage_cache()),
+            negative_cache: Arc::new(NegativeCache::new(registry.as_ref())),
             registry,
         };
 
@@ -276,10 +290,18 @@ impl Store {
     ) {
         let logger = self.logger.clone();
         let subscriptions = self.subscriptions.clone();
+        let negative_cache = self.negative_cache.clone();
+        let storage_cache = self.storage_cache.clone();
 
         graph::spawn(
             store_events
                 .for_each(move |event| {
+                    // Other nodes might have written entities that this
+                    // node knows to be missing
+                    negative_cache.invalidate(&event);
+                    // or changed how deployments are stored
+                    e::invalidate_storage_cache(&storage_cache, &event);
+
                     let senders = subscriptions.read().unwrap().clone();
                     let logger = logger.clone();
                     let subscriptions = subscriptions.clone();
@@ -365,11 +387,29 @@ impl Store {
         conn: &e::Connection,
         query: EntityQuery,
     ) -> Result<Vec<Entity>, QueryExecutionError> {
+        let query = keyset::restrict(query, |strings| {
+            conn.sort_strings(strings)
+                .map_err(QueryExecutionError::from)
+        })?;
+        if query.block == BLOCK_NUMBER_PENDING {
+            let pending = conn.pending_entities()?;
+            return pending::query(
+                &pending,
+                query,
+                |query| self.execute_query(conn, query),
+                |strings| {
+                    conn.sort_strings(strings)
+                        .map_err(QueryExecutionError::from)
+                },
+            );
+        }
+
         // Add order by filters to query
         let order = match query.order_by {
             Some((attribute, value_type)) => {
                 let direction = query.order_direction.unwrap_or(EntityOrder::Ascending);
-                Some((attribute, value_type, direction))
+                let nulls = query.order_nulls.unwrap_or(NullsOrder::Last);
+                Some((attribute, value_type, direction, nulls))
             }
             None => None,
         };
@@ -566,6 +606,7 @@ impl Store {
         stopwatch: StopwatchMetrics,
     ) -> Result<(), StoreError> {
         let mut count = 0;
+        let mut writes = 0;
 
         for modification in mods {
             use EntityModification::*;
@@ -605,9 +646,11 @@ impl Store {
             }?;
             if do_count {
                 count += n;
+                writes += 1;
             }
         }
         conn.update_entity_count(count)?;
+        conn.update_entity_write_count(writes)?;
         Ok(())
     }
 
@@ -626,6 +669,47 @@ impl Store {
         Ok(event)
     }
 
+    /// Operations that remove the metadata blocks after `block` recorded
+    /// for `subgraph`: the dynamic data sources they created, and their
+    /// handler executions, skipped blocks and errors. Reverting a single
+    /// block removes these through the history of the block, but rewinding
+    /// does not keep track of history
+    fn remove_metadata_after_operations(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+        block: u64,
+    ) -> Result<Vec<MetadataOperation>, StoreError> {
+        let entities = vec![
+            (
+                DynamicEthereumContractDataSourceEntity::TYPENAME,
+                "ethereumBlockNumber",
+            ),
+            (SubgraphHandlerExecutionEntity::TYPENAME, "blockNumber"),
+            (SubgraphSkippedBlockEntity::TYPENAME, "blockNumber"),
+            (SubgraphErrorEntity::TYPENAME, "blockNumber"),
+        ];
+
+        let mut ops = vec![];
+        for (entity, block_attribute) in entities {
+            let query = EntityQuery::new(
+                SUBGRAPHS_ID.clone(),
+                BLOCK_NUMBER_MAX,
+                EntityCollection::All(vec![entity.to_owned()]),
+            )
+            .filter(EntityFilter::And(vec![
+                EntityFilter::new_equal("deployment", subgraph.to_string()),
+                EntityFilter::GreaterThan(block_attribute.to_owned(), block.into()),
+            ]));
+            for found in self.find(query)? {
+                ops.push(MetadataOperation::Remove {
+                    entity: entity.to_owned(),
+                    id: found.id()?,
+                });
+            }
+        }
+        Ok(ops)
+    }
+
     /// Build a partial Postgres index on a Subgraph-Entity-Attribute
     fn build_entity_attribute_index_with_conn(
         &self,
@@ -736,9 +820,26 @@ impl Store {
         let input_schema = Schema::parse(&input_schema, subgraph_id.clone())?;
         let mut schema = input_schema.clone();
 
+        // The audit log of admin operations is only available to admins
+        // through the index node, and must not be queryable through the
+        // public API of the subgraph of subgraphs
+        if *subgraph_id == *SUBGRAPHS_ID {
+            schema.document.definitions.retain(|def| match def {
+                s::Definition::TypeDefinition(s::TypeDefinition::Object(t)) => {
+                    t.name != SubgraphAdminOperationEntity::TYPENAME
+                }
+                _ => true,
+            });
+        }
+
         // Generate an API schema for the subgraph and make sure all types in the
-        // API schema have a @subgraphId directive as well
-        schema.document = api_schema(&schema.document)?;
+        // API schema have a @subgraphId directive as well. The subgraph of
+        // subgraphs uses JSONB storage, which can not aggregate entities
+        schema.document = if *subgraph_id == *SUBGRAPHS_ID {
+            api_schema_without_aggregations(&schema.document)?
+        } else {
+            api_schema(&schema.document)?
+        };
         schema.add_subgraph_id_directives(subgraph_id.clone());
 
         let pair = SchemaPair {
@@ -807,10 +908,12 @@ impl StoreTrait for Store {
     }
 
     fn get(&self, key: EntityKey) -> Result<Option<Entity>, QueryExecutionError> {
-        let conn = self
-            .get_entity_conn(&key.subgraph_id)
-            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
-        self.get_entity(&conn, &key.subgraph_id, &key.entity_type, &key.entity_id)
+        self.negative_cache.get(&key, || {
+            let conn = self
+                .get_entity_conn(&key.subgraph_id)
+                .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+            self.get_entity(&conn, &key.subgraph_id, &key.entity_type, &key.entity_id)
+        })
     }
 
     fn get_many(
@@ -848,6 +951,31 @@ impl StoreTrait for Store {
         }
     }
 
+    fn aggregate(&self, mut query: EntityAggregation) -> Result<Vec<Entity>, QueryExecutionError> {
+        let conn = self
+            .get_entity_conn(&query.subgraph_id)
+            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+        if query.block == BLOCK_NUMBER_PENDING {
+            // Only aggregate in memory when the pending block changes the
+            // entities; otherwise, the latest block has the same groups
+            let pending = conn.pending_entities()?;
+            if pending.changes(&query.entity_type) {
+                return pending::aggregate(
+                    &pending,
+                    query,
+                    |query| self.execute_query(&conn, query),
+                    |aggregation, entities| conn.aggregate_entities(aggregation, entities),
+                    |strings| {
+                        conn.sort_strings(strings)
+                            .map_err(QueryExecutionError::from)
+                    },
+                );
+            }
+            query.block = BLOCK_NUMBER_MAX;
+        }
+        conn.aggregate(query)
+    }
+
     fn find_ens_name(&self, hash: &str) -> Result<Option<String>, QueryExecutionError> {
         use crate::db_schema::ens_names as dsl;
 
@@ -869,6 +997,15 @@ impl StoreTrait for Store {
             })
     }
 
+    fn entity_type_updates(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block: Option<BlockNumber>,
+    ) -> Result<Vec<(String, BlockNumber)>, StoreError> {
+        self.get_entity_conn(subgraph_id)?
+            .entity_type_updates(block)
+    }
+
     fn transact_block_operations(
         &self,
         subgraph_id: SubgraphDeploymentId,
@@ -898,17 +1035,37 @@ impl StoreTrait for Store {
                     assert!(block_ptr_from.number < block_ptr_to.number);
                 }
 
+                // Tables that are partitioned by block might need a new
+                // partition for the changes in this block
+                econn.ensure_partitions(block_ptr_from.as_ref(), &block_ptr_to)?;
+
                 // Ensure the history event exists in the database
                 let history_event = econn.create_history_event(block_ptr_to, &mods)?;
 
                 let should_migrate = econn.should_migrate(&subgraph_id, &block_ptr_to)?;
 
+                // The pending block is either this block or was replaced by it
+                econn.clear_pending_entities()?;
+
                 // Emit a store event for the changes we are about to make. We
                 // wait with sending it until we have done all our other work
                 // so that we do not hold a lock on the notification queue
                 // for longer than we have to
                 let event: StoreEvent = mods.iter().collect();
 
+                // The proof of indexing covers the changes of this block
+                econn.record_proof_of_indexing(&block_ptr_to, &mods)?;
+                econn.record_entity_type_updates(
+                    block_ptr_to.number.try_into().unwrap(),
+                    event
+                        .changes
+                        .iter()
+                        .filter(|change| change.subgraph_id == subgraph_id)
+                        .map(|change| change.entity_type.as_str())
+                        .collect::<BTreeSet<_>>()
+                        .into_iter(),
+                )?;
+
                 // Make the changes
                 let section = stopwatch.start_section("apply_entity_modifications");
                 self.apply_entity_modifications(&econn, mods, Some(&history_event), stopwatch)?;
@@ -925,6 +1082,7 @@ impl StoreTrait for Store {
                     self.apply_metadata_operations_with_conn(&econn, block_ptr_ops)?;
                 Ok((event, metadata_event, should_migrate))
             })?;
+        self.negative_cache.invalidate(&event);
 
         // Send the events separately, because NOTIFY uses a global DB lock.
         econn.transaction(|| {
@@ -983,8 +1141,11 @@ impl StoreTrait for Store {
 
             let (event, count) = econn.revert_block(&block_ptr_from)?;
             econn.update_entity_count(count)?;
+            econn.revert_proof_of_indexing(block_ptr_from.number.try_into().unwrap())?;
+            econn.clear_pending_entities()?;
             Ok((event, metadata_event))
         })?;
+        self.negative_cache.invalidate(&event);
 
         // Send the events separately, because NOTIFY uses a global DB lock.
         econn.transaction(|| {
@@ -1030,6 +1191,21 @@ impl StoreTrait for Store {
         })
     }
 
+    fn create_subgraph_deployment_in_tablespace(
+        &self,
+        schema: &Schema,
+        ops: Vec<MetadataOperation>,
+        tablespace: String,
+    ) -> Result<(), StoreError> {
+        let econn = self.get_entity_conn(&*SUBGRAPHS_ID)?;
+        econn.transaction(|| -> Result<(), StoreError> {
+            let event = self.apply_metadata_operations_with_conn(&econn, ops.clone())?;
+            econn.use_tablespace(&tablespace)?;
+            econn.create_schema(schema)?;
+            econn.send_store_event(&event)
+        })
+    }
+
     fn start_subgraph_deployment(
         &self,
         subgraph_id: &SubgraphDeploymentId,
@@ -1037,11 +1213,13 @@ impl StoreTrait for Store {
     ) -> Result<(), StoreError> {
         let econn = self.get_entity_conn(subgraph_id)?;
 
-        econn.transaction(|| {
+        econn.transaction(|| -> Result<(), StoreError> {
             let event = self.apply_metadata_operations_with_conn(&econn, ops)?;
             econn.start_subgraph()?;
             econn.send_store_event(&event)
-        })
+        })?;
+        self.negative_cache.clear(subgraph_id);
+        Ok(())
     }
 
     fn migrate_subgraph_deployment(
@@ -1114,6 +1292,288 @@ impl SubgraphDeploymentStore for Store {
         self.get_entity_conn(subgraph)
             .map(|econn| econn.uses_relational_schema())
     }
+
+    fn entity_changes_in_block(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<Vec<BlockEntityChange>, StoreError> {
+        self.get_entity_conn(subgraph)?
+            .entity_changes_in_block(block)
+    }
+
+    fn entity_changes_since(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+        since: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<BlockEntityChange>, StoreError> {
+        self.get_entity_conn(subgraph)?
+            .entity_changes_since(since, to)
+    }
+
+    fn entity_diff(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+        entity_type: &str,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<EntityDiff>, StoreError> {
+        self.get_entity_conn(subgraph)?
+            .entity_diff(entity_type, from, to)
+    }
+
+    fn create_missing_indexes(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+    ) -> Result<Vec<IndexCreation>, StoreError> {
+        let indexes = attribute_index_definitions(
+            subgraph.clone(),
+            self.input_schema(subgraph)?.document.clone(),
+        );
+        // Not in a transaction since indexes are created concurrently
+        self.get_entity_conn(subgraph)?
+            .create_missing_indexes(indexes)
+    }
+
+    fn oversized_entity_ids(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+        max_length: usize,
+    ) -> Result<Vec<OversizedEntityIds>, StoreError> {
+        self.get_entity_conn(subgraph)?
+            .oversized_entity_ids(max_length)
+    }
+
+    fn blocks_with_entity_changes(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+        since: BlockNumber,
+        to: BlockNumber,
+        limit: usize,
+    ) -> Result<Vec<BlockNumber>, StoreError> {
+        self.get_entity_conn(subgraph)?
+            .blocks_with_entity_changes(since, to, limit)
+    }
+
+    fn deployment_size(&self, subgraph: &SubgraphDeploymentId) -> Result<u64, StoreError> {
+        self.get_entity_conn(subgraph)?.deployment_size()
+    }
+
+    fn request_storage_migration(&self, subgraph: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        let econn = self.get_entity_conn(subgraph)?;
+        econn.request_migration()?;
+        // Nodes must not keep using the storage they cached for the
+        // deployment, since it changes when the migration finishes
+        econn.send_store_event(&deployment_changed(subgraph))
+    }
+
+    fn storage_migrations_requested(&self) -> Result<Vec<SubgraphDeploymentId>, StoreError> {
+        e::storage_migrations_requested(&*self.get_conn()?)
+    }
+
+    fn migrate_storage_batch(
+        &self,
+        logger: &Logger,
+        subgraph: &SubgraphDeploymentId,
+        batch_size: i64,
+    ) -> Result<bool, StoreError> {
+        let head = self.block_ptr(subgraph.clone())?.ok_or_else(|| {
+            StoreError::QueryExecutionError(format!(
+                "subgraph {} has not processed any blocks and can not be migrated",
+                subgraph
+            ))
+        })?;
+        let schema = self.input_schema(subgraph)?;
+        let econn = self.get_entity_conn(subgraph)?;
+        let done = econn.migrate_storage_step(logger, &schema, &head, batch_size)?;
+        if done {
+            self.storage_cache.lock().unwrap().remove(subgraph);
+            econn.send_store_event(&deployment_changed(subgraph))?;
+        }
+        Ok(done)
+    }
+
+    fn start_copy_deployment(
+        &self,
+        base: &SubgraphDeploymentId,
+        subgraph: &SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+    ) -> Result<Vec<String>, StoreError> {
+        let base_storage = self.storage(&*self.get_conn()?, base)?;
+        let econn = self.get_entity_conn(subgraph)?;
+        econn.transaction(|| -> Result<Vec<String>, StoreError> {
+            if let Some(block_ptr) = self.block_ptr_with_conn(subgraph.clone(), &econn)? {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "deployment {} can not copy data from {} since it has \
+                     already processed block {}",
+                    subgraph, base, block_ptr.number
+                )));
+            }
+            econn.start_copy_deployment(&base_storage, block.number.try_into().unwrap())
+        })
+    }
+
+    fn copy_deployment_batch(
+        &self,
+        base: &SubgraphDeploymentId,
+        subgraph: &SubgraphDeploymentId,
+        entity_type: &str,
+        block: EthereumBlockPointer,
+        batch_size: i64,
+    ) -> Result<bool, StoreError> {
+        let base_storage = self.storage(&*self.get_conn()?, base)?;
+        let econn = self.get_entity_conn(subgraph)?;
+        econn.transaction(|| {
+            econn.copy_deployment_batch(
+                &base_storage,
+                entity_type,
+                block.number.try_into().unwrap(),
+                batch_size,
+            )
+        })
+    }
+
+    fn finish_copy_deployment(
+        &self,
+        base: &SubgraphDeploymentId,
+        subgraph: &SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+        mut ops: Vec<MetadataOperation>,
+    ) -> Result<(), StoreError> {
+        let base_storage = self.storage(&*self.get_conn()?, base)?;
+        let econn = self.get_entity_conn(subgraph)?;
+        econn.transaction(|| -> Result<(), StoreError> {
+            econn.finish_copy_deployment(&base_storage)?;
+            econn.copy_proof_of_indexing(base, block.number.try_into().unwrap())?;
+            econn.copy_entity_type_updates(base, block.number.try_into().unwrap())?;
+            ops.extend(
+                SubgraphDeploymentEntity::update_ethereum_block_pointer_operations(subgraph, block),
+            );
+            let event = self.apply_metadata_operations_with_conn(&econn, ops)?;
+            econn.send_store_event(&event)
+        })?;
+        self.negative_cache.clear(subgraph);
+        Ok(())
+    }
+
+    fn transact_pending_block_operations(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+        mods: Vec<EntityModification>,
+    ) -> Result<(), StoreError> {
+        let econn = self.get_entity_conn(subgraph_id)?;
+        econn.transaction(|| -> Result<(), StoreError> {
+            // The deployment might have moved on since the pending block was
+            // processed; the changes are then stale and are dropped
+            let latest = self.block_ptr_with_conn(subgraph_id.clone(), &econn)?;
+            if latest.map(|ptr| ptr.number + 1) != Some(block.number) {
+                return econn.clear_pending_entities();
+            }
+            econn.replace_pending_entities(block, mods)
+        })
+    }
+
+    fn write_annotations(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        mods: Vec<EntityModification>,
+    ) -> Result<(), StoreError> {
+        let econn = self.get_entity_conn(subgraph_id)?;
+        let event: StoreEvent = mods.iter().collect();
+        econn.transaction(|| -> Result<(), StoreError> {
+            for modification in &mods {
+                if let EntityModification::Remove { .. } = modification {
+                    continue;
+                }
+                self.check_interface_entity_uniqueness(&econn, modification.entity_key())?;
+            }
+            econn.write_annotations(mods)?;
+            econn.send_store_event(&event)
+        })?;
+        self.negative_cache.invalidate(&event);
+        Ok(())
+    }
+
+    fn proof_of_indexing(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<Option<ProofOfIndexing>, StoreError> {
+        self.get_entity_conn(subgraph)?.proof_of_indexing(block)
+    }
+
+    fn proofs_of_indexing(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<ProofOfIndexing>, StoreError> {
+        self.get_entity_conn(subgraph)?.proofs_of_indexing(from, to)
+    }
+
+    fn rewind_deployment(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+    ) -> Result<(), StoreError> {
+        let econn = self.get_entity_conn(subgraph)?;
+        let (event, metadata_event) = econn.transaction(|| -> Result<_, StoreError> {
+            let current = self
+                .block_ptr_with_conn(subgraph.clone(), &econn)?
+                .ok_or_else(|| {
+                    StoreError::QueryExecutionError(format!(
+                        "deployment {} has not processed any blocks yet",
+                        subgraph
+                    ))
+                })?;
+            if block.number >= current.number {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "deployment {} can only be rewound to a block before its \
+                     current block {}",
+                    subgraph, current.number
+                )));
+            }
+
+            // Make sure that `block` is on the chain the deployment indexed
+            let ancestor = self.ancestor_block(current, current.number - block.number)?;
+            if ancestor.and_then(|ancestor| ancestor.block.hash) != Some(block.hash) {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "block {} with hash {:?} is not an ancestor of block {} of \
+                     deployment {} in the block cache",
+                    block.number, block.hash, current.number, subgraph
+                )));
+            }
+
+            if !econn.can_rewind_proof_of_indexing(block.number.try_into().unwrap())? {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "deployment {} can not be rewound to block {} since its proof of \
+                     indexing for that block is no longer kept",
+                    subgraph, block.number
+                )));
+            }
+
+            let mut ops = self.remove_metadata_after_operations(subgraph, block.number)?;
+            ops.extend(
+                SubgraphDeploymentEntity::update_ethereum_block_pointer_operations(subgraph, block),
+            );
+            let metadata_event = self.apply_metadata_operations_with_conn(&econn, ops)?;
+
+            let (event, count) = econn.rewind(block.number.try_into().unwrap())?;
+            econn.update_entity_count(count)?;
+            econn.revert_proof_of_indexing((block.number + 1).try_into().unwrap())?;
+            econn.clear_pending_entities()?;
+            Ok((event, metadata_event))
+        })?;
+        self.negative_cache.invalidate(&event);
+
+        // Send the events separately, because NOTIFY uses a global DB lock.
+        econn.transaction(|| {
+            econn.send_store_event(&metadata_event)?;
+            econn.send_store_event(&event)
+        })
+    }
 }
 
 impl ChainStore for Store {
@@ -1134,13 +1594,16 @@ impl ChainStore for Store {
         let conn = self.conn.clone();
         let net_name = self.network_name.clone();
         Box::new(blocks.for_each(move |block| {
-            let json_blob = serde_json::to_value(&block).expect("Failed to serialize block");
+            let (json_blob, compressed_blob) =
+                block_data::encode(&block).expect("Failed to serialize block");
             let values = (
                 hash.eq(format!("{:x}", block.block.hash.unwrap())),
                 number.eq(block.block.number.unwrap().as_u64() as i64),
                 parent_hash.eq(format!("{:x}", block.block.parent_hash)),
                 network_name.eq(&net_name),
                 data.eq(json_blob),
+                compressed_data.eq(compressed_blob),
+                timestamp.eq(block.block.timestamp.low_u64() as i64),
             );
 
             // Insert blocks.
@@ -1168,7 +1631,8 @@ impl ChainStore for Store {
             let block_hash = format!("{:x}", block.hash.unwrap());
             let p_hash = format!("{:x}", block.parent_hash);
             let block_number = block.number.unwrap().as_u64();
-            let json_blob = serde_json::to_value(&EthereumBlock {
+            let block_timestamp = block.timestamp.low_u64();
+            let (json_blob, compressed_blob) = block_data::encode(&EthereumBlock {
                 block,
                 transaction_receipts: Vec::new(),
             })
@@ -1179,6 +1643,8 @@ impl ChainStore for Store {
                 parent_hash.eq(p_hash),
                 network_name.eq(&net_name),
                 data.eq(json_blob),
+                compressed_data.eq(compressed_blob),
+                timestamp.eq(block_timestamp as i64),
             );
 
             // Insert blocks. On conflict do nothing, we don't want to erase transaction receipts.
@@ -1240,38 +1706,119 @@ impl ChainStore for Store {
     fn blocks(&self, hashes: Vec<H256>) -> Result<Vec<LightEthereumBlock>, Error> {
         use crate::db_schema::ethereum_blocks::dsl::*;
         use diesel::dsl::{any, sql};
-        use diesel::sql_types::Jsonb;
+        use diesel::sql_types::{Jsonb, Nullable};
 
+        // For blocks stored as JSON, only load the block itself and not
+        // the transaction receipts
         ethereum_blocks
-            .select(sql::<Jsonb>("data -> 'block'"))
+            .select((
+                sql::<Nullable<Jsonb>>(
+                    "case when data is null then null \
+                     else jsonb_build_object('block', data -> 'block', \
+                                             'transaction_receipts', '[]'::jsonb) end",
+                ),
+                compressed_data,
+            ))
             .filter(network_name.eq(&self.network_name))
             .filter(hash.eq(any(Vec::from_iter(
                 hashes.into_iter().map(|h| format!("{:x}", h)),
             ))))
-            .load::<serde_json::Value>(&*self.get_conn()?)?
+            .load::<(Option<serde_json::Value>, Option<Vec<u8>>)>(&*self.get_conn()?)?
             .into_iter()
-            .map(|block| serde_json::from_value(block).map_err(Into::into))
+            .map(|block| block_data::decode(block).map(|block| block.block))
             .collect()
     }
 
+    fn block_timestamps(&self, hashes: Vec<H256>) -> Result<HashMap<H256, u64>, Error> {
+        use crate::db_schema::ethereum_blocks::dsl::*;
+        use diesel::dsl::{any, sql};
+        use diesel::sql_types::{Nullable, Text};
+
+        let conn = self.get_conn()?;
+        let blocks = ethereum_blocks
+            .select((hash, timestamp))
+            .filter(hash.eq(any(Vec::from_iter(
+                hashes.into_iter().map(|h| format!("{:x}", h)),
+            ))))
+            .load::<(String, Option<i64>)>(&*conn)?;
+
+        let mut timestamps = HashMap::new();
+        for (block_hash, block_timestamp) in blocks {
+            let block_timestamp = match block_timestamp {
+                Some(block_timestamp) => block_timestamp as u64,
+                None => {
+                    // The block was cached before timestamps were recorded.
+                    // Take the timestamp from the block once; for blocks
+                    // stored as JSON, only the timestamp is loaded
+                    let (json_timestamp, compressed) = ethereum_blocks
+                        .select((
+                            sql::<Nullable<Text>>("data -> 'block' ->> 'timestamp'"),
+                            compressed_data,
+                        ))
+                        .filter(hash.eq(&block_hash))
+                        .first::<(Option<String>, Option<Vec<u8>>)>(&*conn)?;
+                    let block_timestamp = match json_timestamp {
+                        Some(hex) => u64::from_str_radix(hex.trim_start_matches("0x"), 16)?,
+                        None => block_data::decode((None, compressed))?
+                            .block
+                            .timestamp
+                            .low_u64(),
+                    };
+                    update(ethereum_blocks.filter(hash.eq(&block_hash)))
+                        .set(timestamp.eq(block_timestamp as i64))
+                        .execute(&*conn)?;
+                    block_timestamp
+                }
+            };
+            timestamps.insert(block_hash.parse::<H256>()?, block_timestamp);
+        }
+        Ok(timestamps)
+    }
+
     fn ancestor_block(
         &self,
         block_ptr: EthereumBlockPointer,
         offset: u64,
     ) -> Result<Option<EthereumBlock>, Error> {
+        use diesel::sql_types::{Binary, Integer, Jsonb, Nullable, Text};
+
         if block_ptr.number < offset {
             bail!("block offset points to before genesis block");
         }
 
-        select(lookup_ancestor_block(block_ptr.hash_hex(), offset as i64))
-            .first::<Option<serde_json::Value>>(&*self.get_conn()?)
-            .map(|val_opt| {
-                val_opt.map(|val| {
-                    serde_json::from_value::<EthereumBlock>(val)
-                        .expect("Failed to deserialize block from database")
-                })
-            })
-            .map_err(Error::from)
+        #[derive(QueryableByName)]
+        struct AncestorBlock {
+            #[sql_type = "Nullable<Jsonb>"]
+            data: Option<serde_json::Value>,
+            #[sql_type = "Nullable<Binary>"]
+            compressed_data: Option<Vec<u8>>,
+        };
+
+        // Follow parent hashes back the necessary number of steps to find
+        // the ancestor; this is the same as the `lookup_ancestor_block`
+        // database function, except that it also returns compressed blocks
+        let query = "
+            with recursive ancestors(block_hash, block_offset) as (
+                values ($1::varchar, 0)
+                union all
+                select b.parent_hash, a.block_offset + 1
+                  from ancestors a, ethereum_blocks b
+                 where a.block_hash = b.hash
+                   and a.block_offset < $2
+            )
+            select b.data, b.compressed_data
+              from ancestors a, ethereum_blocks b
+             where a.block_offset = $2
+               and b.hash = a.block_hash";
+
+        diesel::sql_query(query)
+            .bind::<Text, _>(block_ptr.hash_hex())
+            .bind::<Integer, _>(offset as i32)
+            .get_result::<AncestorBlock>(&*self.get_conn()?)
+            .optional()
+            .map_err(Error::from)?
+            .map(|block| block_data::decode((block.data, block.compressed_data)))
+            .transpose()
     }
 
     fn cleanup_cached_blocks(&self, ancestor_count: u64) -> Result<(BlockNumber, usize), Error> {
@@ -1431,6 +1978,16 @@ impl EthereumCallCache for Store {
     }
 }
 
+/// A store event that says that the deployment `subgraph` changed
+fn deployment_changed(subgraph: &SubgraphDeploymentId) -> StoreEvent {
+    StoreEvent::new(vec![EntityChange {
+        subgraph_id: SUBGRAPHS_ID.clone(),
+        entity_type: SubgraphDeploymentEntity::TYPENAME.to_owned(),
+        entity_id: subgraph.to_string(),
+        operation: EntityChangeOperation::Set,
+    }])
+}
+
 /// The id is the hashed contract_address + encoded_call + block hash. This uniquely identifies the
 /// call. Use 128 bits of output to save some bytes in the DB.
 fn contract_call_id(
@@ -1,13 +1,18 @@
 //! Test ChainStore implementation of Store, in particular, how
 //! the chain head pointer gets updated in various situations
 
+use diesel::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
 use futures::future::IntoFuture;
 use std::fmt::Debug;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use graph::components::store::{ChainStore, Store as _};
-use graph::prelude::{Future01CompatExt, SubgraphDeploymentId};
-use graph_store_postgres::Store as DieselStore;
+use graph::prelude::{
+    serde_json, web3::types::H256, EthereumBlock, EthereumBlockPointer, Future01CompatExt,
+    LightEthereumBlock, SubgraphDeploymentId,
+};
+use graph_store_postgres::{db_schema_for_tests as db_schema, Store as DieselStore};
 
 use test_store::block_store::{
     Chain, FakeBlock, BLOCK_FIVE, BLOCK_FOUR, BLOCK_ONE, BLOCK_ONE_NO_PARENT, BLOCK_ONE_SIBLING,
@@ -220,3 +225,99 @@ fn block_hashes_by_number() {
         Ok(())
     })
 }
+
+#[test]
+fn block_timestamps() {
+    let chain = vec![&*GENESIS_BLOCK];
+    run_test(chain, move |store| -> Result<(), ()> {
+        let block = LightEthereumBlock {
+            hash: Some(BLOCK_ONE.block_hash()),
+            parent_hash: GENESIS_BLOCK.block_hash(),
+            number: Some(1.into()),
+            timestamp: 1_000.into(),
+            ..Default::default()
+        };
+        store.upsert_light_blocks(vec![block]).unwrap();
+
+        // Blocks that are not in the store are left out
+        let hashes = vec![BLOCK_ONE.block_hash(), BLOCK_TWO.block_hash()];
+        let timestamps = store.block_timestamps(hashes.clone()).unwrap();
+        assert_eq!(1, timestamps.len());
+        assert_eq!(Some(&1_000), timestamps.get(&BLOCK_ONE.block_hash()));
+
+        // Blocks cached before timestamps were recorded get their
+        // timestamp from the block data
+        {
+            use db_schema::ethereum_blocks as b;
+
+            let conn = PgConnection::establish(postgres_test_url().as_str())
+                .expect("Failed to connect to Postgres");
+            diesel::update(b::table)
+                .set(b::timestamp.eq(None::<i64>))
+                .execute(&conn)
+                .expect("Failed to clear block timestamps");
+        }
+        let timestamps = store.block_timestamps(hashes).unwrap();
+        assert_eq!(Some(&1_000), timestamps.get(&BLOCK_ONE.block_hash()));
+        Ok(())
+    })
+}
+
+#[test]
+fn compressed_blocks() {
+    let chain = vec![&*GENESIS_BLOCK];
+    run_test(chain, move |store| -> Result<(), ()> {
+        let light_block = |block: &FakeBlock| LightEthereumBlock {
+            hash: Some(block.block_hash()),
+            parent_hash: H256::from_str(&block.parent_hash).unwrap(),
+            number: Some(block.number.into()),
+            ..Default::default()
+        };
+        let block = |block: &FakeBlock| EthereumBlock {
+            block: light_block(block),
+            transaction_receipts: vec![],
+        };
+        let blocks = vec![&*BLOCK_ONE, &*BLOCK_TWO, &*BLOCK_THREE];
+        store
+            .upsert_light_blocks(blocks.iter().map(|b| light_block(*b)).collect())
+            .unwrap();
+
+        // Rewrite blocks one and two the way they are stored with
+        // `GRAPH_CHAIN_STORE_BLOCK_FORMAT=compressed`, and leave block
+        // three as JSON, so that ancestor lookups from block three have
+        // to follow parent hashes across both formats
+        {
+            use db_schema::ethereum_blocks as b;
+
+            let conn = PgConnection::establish(postgres_test_url().as_str())
+                .expect("Failed to connect to Postgres");
+            for fake in vec![&*BLOCK_ONE, &*BLOCK_TWO] {
+                let cbor = serde_cbor::to_vec(&block(fake)).unwrap();
+                let compressed = zstd::encode_all(cbor.as_slice(), 3).unwrap();
+                diesel::update(b::table.filter(b::hash.eq(&fake.hash)))
+                    .set((
+                        b::data.eq(None::<serde_json::Value>),
+                        b::compressed_data.eq(Some(compressed)),
+                    ))
+                    .execute(&conn)
+                    .expect("Failed to compress block");
+            }
+        }
+
+        let head = EthereumBlockPointer::from((BLOCK_THREE.block_hash(), BLOCK_THREE.number));
+        for (offset, expected) in blocks.iter().rev().enumerate() {
+            let ancestor = store.ancestor_block(head, offset as u64).unwrap();
+            assert_eq!(Some(block(*expected)), ancestor);
+        }
+
+        let mut light_blocks = store
+            .blocks(blocks.iter().map(|b| b.block_hash()).collect())
+            .unwrap();
+        light_blocks.sort_by_key(|b| b.number);
+        assert_eq!(
+            blocks.iter().map(|b| light_block(*b)).collect::<Vec<_>>(),
+            light_blocks
+        );
+        Ok(())
+    })
+}
@@ -10,15 +10,24 @@ use std::str::FromStr;
 
 use graph::data::store::scalar::{BigDecimal, BigInt, Bytes};
 use graph::prelude::{
-    bigdecimal::One, web3::types::H256, Entity, EntityCollection, EntityFilter, EntityKey,
-    EntityOrder, EntityQuery, EntityRange, Future01CompatExt, Schema, SubgraphDeploymentId, Value,
-    ValueType, BLOCK_NUMBER_MAX,
+    bigdecimal::One, web3::types::H256, BlockEntityChangeKind, Entity, EntityAggregation,
+    EntityCollection, EntityFilter, EntityKey, EntityLink, EntityOrder, EntityQuery, EntityRange,
+    EntityWindow, Future01CompatExt, IndexCreation, NullsOrder, OversizedEntityIds,
+    QueryExecutionError, Schema, SubgraphDeploymentId, Value, ValueType, WindowAttribute,
+    BLOCK_NUMBER_MAX,
 };
-use graph_store_postgres::layout_for_tests::{Layout, STRING_PREFIX_SIZE};
+use graph_store_postgres::layout_for_tests::{Layout, WindowStrategy, STRING_PREFIX_SIZE};
 
 use test_store::*;
 
 const THINGS_GQL: &str = "
+    type _Schema_ @fulltext(
+        name: \"bandSearch\",
+        language: en,
+        algorithm: rank,
+        include: [{ entity: \"Band\", fields: [{ name: \"name\" }, { name: \"bio\" }] }]
+    )
+
     type Thing @entity {
         id: ID!
         bigThing: Thing!
@@ -71,6 +80,12 @@ const THINGS_GQL: &str = "
         favorite_color: Color,
         drinks: [String!]
     }
+
+    type Band @entity {
+        id: ID!,
+        name: String!,
+        bio: String
+    }
 ";
 
 const SCHEMA_NAME: &str = "layout";
@@ -428,6 +443,7 @@ fn count_scalar_entities(conn: &PgConnection, layout: &Layout) -> usize {
             EntityRange {
                 first: None,
                 skip: 0,
+                from_end: false,
             },
             BLOCK_NUMBER_MAX,
         )
@@ -462,6 +478,409 @@ fn delete() {
     });
 }
 
+#[test]
+fn entity_changes_in_block() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for id in &["one", "two", "three"] {
+            let mut entity = SCALAR_ENTITY.clone();
+            entity.set("id", *id);
+            insert_entity(&conn, &layout, "Scalar", entity);
+        }
+
+        let key = |id: &str| EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Scalar".to_owned(),
+            entity_id: id.to_owned(),
+        };
+
+        // In block 1, update 'one', delete 'two' and create 'four'
+        let mut one = SCALAR_ENTITY.clone();
+        one.set("string", "updated");
+        layout
+            .update(&conn, &key("one"), &one, 1)
+            .expect("Failed to update");
+        layout
+            .delete(&conn, &key("two"), 1)
+            .expect("Failed to delete");
+        let mut four = SCALAR_ENTITY.clone();
+        four.set("id", "four");
+        layout
+            .insert(&conn, &key("four"), &four, 1)
+            .expect("Failed to insert");
+
+        let summary = |block| {
+            layout
+                .entity_changes_in_block(&conn, block)
+                .expect("Failed to get entity changes")
+                .into_iter()
+                .map(|change| (change.entity_id, change.kind))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec![
+                ("one".to_owned(), BlockEntityChangeKind::Created),
+                ("three".to_owned(), BlockEntityChangeKind::Created),
+                ("two".to_owned(), BlockEntityChangeKind::Created),
+            ],
+            summary(0)
+        );
+        assert_eq!(
+            vec![
+                ("four".to_owned(), BlockEntityChangeKind::Created),
+                ("one".to_owned(), BlockEntityChangeKind::Updated),
+                ("two".to_owned(), BlockEntityChangeKind::Deleted),
+            ],
+            summary(1)
+        );
+
+        let changes = layout.entity_changes_in_block(&conn, 1).unwrap();
+        assert_eq!(Some(&Value::from("updated")), changes[1].data.get("string"));
+        Ok(())
+    });
+}
+
+#[test]
+fn entity_changes_since() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for id in &["one", "two", "three"] {
+            let mut entity = SCALAR_ENTITY.clone();
+            entity.set("id", *id);
+            insert_entity(&conn, &layout, "Scalar", entity);
+        }
+
+        let key = |id: &str| EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Scalar".to_owned(),
+            entity_id: id.to_owned(),
+        };
+
+        // In block 1, update 'one', delete 'two' and create 'four'; in
+        // block 2, update 'one' again and delete 'four'
+        let mut one = SCALAR_ENTITY.clone();
+        one.set("string", "updated");
+        layout
+            .update(&conn, &key("one"), &one, 1)
+            .expect("Failed to update");
+        layout
+            .delete(&conn, &key("two"), 1)
+            .expect("Failed to delete");
+        let mut four = SCALAR_ENTITY.clone();
+        four.set("id", "four");
+        layout
+            .insert(&conn, &key("four"), &four, 1)
+            .expect("Failed to insert");
+        one.set("string", "updated again");
+        layout
+            .update(&conn, &key("one"), &one, 2)
+            .expect("Failed to update");
+        layout
+            .delete(&conn, &key("four"), 2)
+            .expect("Failed to delete");
+
+        let changes = layout
+            .entity_changes_since(&conn, 0, 2)
+            .expect("Failed to get entity changes");
+        assert_eq!(
+            vec![
+                ("one".to_owned(), BlockEntityChangeKind::Updated),
+                ("two".to_owned(), BlockEntityChangeKind::Deleted),
+            ],
+            changes
+                .iter()
+                .map(|change| (change.entity_id.clone(), change.kind))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(&Value::from("updated again")),
+            changes[0].data.get("string")
+        );
+
+        // Nothing changed after the latest block
+        assert!(layout.entity_changes_since(&conn, 2, 2).unwrap().is_empty());
+        Ok(())
+    });
+}
+
+#[test]
+fn entity_diff() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for id in &["one", "two", "three"] {
+            let mut entity = SCALAR_ENTITY.clone();
+            entity.set("id", *id);
+            insert_entity(&conn, &layout, "Scalar", entity);
+        }
+
+        let key = |id: &str| EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Scalar".to_owned(),
+            entity_id: id.to_owned(),
+        };
+
+        // In block 1, update 'one', delete 'two' and create 'four'; in
+        // block 2, write 'three' again without changing it
+        let mut one = SCALAR_ENTITY.clone();
+        one.set("string", "updated");
+        one.set("int", 17);
+        layout
+            .update(&conn, &key("one"), &one, 1)
+            .expect("Failed to update");
+        layout
+            .delete(&conn, &key("two"), 1)
+            .expect("Failed to delete");
+        let mut four = SCALAR_ENTITY.clone();
+        four.set("id", "four");
+        layout
+            .insert(&conn, &key("four"), &four, 1)
+            .expect("Failed to insert");
+        let mut three = SCALAR_ENTITY.clone();
+        three.set("id", "three");
+        layout
+            .update(&conn, &key("three"), &three, 2)
+            .expect("Failed to update");
+
+        let diff = |from, to| {
+            layout
+                .entity_diff(&conn, "Scalar", from, to)
+                .expect("Failed to diff entities")
+                .into_iter()
+                .map(|diff| (diff.entity_id, diff.kind, diff.changed_fields))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec![
+                ("four".to_owned(), BlockEntityChangeKind::Created, vec![]),
+                (
+                    "one".to_owned(),
+                    BlockEntityChangeKind::Updated,
+                    vec!["int".to_owned(), "string".to_owned()]
+                ),
+                ("two".to_owned(), BlockEntityChangeKind::Deleted, vec![]),
+            ],
+            diff(0, 2)
+        );
+        assert_eq!(
+            vec![
+                ("four".to_owned(), BlockEntityChangeKind::Deleted, vec![]),
+                (
+                    "one".to_owned(),
+                    BlockEntityChangeKind::Updated,
+                    vec!["int".to_owned(), "string".to_owned()]
+                ),
+                ("two".to_owned(), BlockEntityChangeKind::Created, vec![]),
+            ],
+            diff(2, 0)
+        );
+        assert!(diff(1, 2).is_empty());
+        Ok(())
+    });
+}
+
+#[test]
+fn blocks_with_changes() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let key = |id: &str| EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Scalar".to_owned(),
+            entity_id: id.to_owned(),
+        };
+
+        // Create 'one' in block 0, update it in block 3, and create 'two'
+        // in block 5 and delete it in block 7
+        insert_entity(&conn, &layout, "Scalar", SCALAR_ENTITY.clone());
+        let mut one = SCALAR_ENTITY.clone();
+        one.set("string", "updated");
+        layout
+            .update(&conn, &key("one"), &one, 3)
+            .expect("Failed to update");
+        let mut two = SCALAR_ENTITY.clone();
+        two.set("id", "two");
+        layout
+            .insert(&conn, &key("two"), &two, 5)
+            .expect("Failed to insert");
+        layout
+            .delete(&conn, &key("two"), 7)
+            .expect("Failed to delete");
+
+        let blocks = |since, to, limit| {
+            layout
+                .blocks_with_changes(&conn, since, to, limit)
+                .expect("Failed to find blocks with changes")
+        };
+
+        assert_eq!(vec![0, 3, 5, 7], blocks(-1, 10, 10));
+        assert_eq!(vec![0, 3], blocks(-1, 10, 2));
+        assert_eq!(vec![5, 7], blocks(3, 10, 10));
+        assert_eq!(vec![3, 5], blocks(0, 6, 10));
+        assert!(blocks(7, 10, 10).is_empty());
+        Ok(())
+    });
+}
+
+#[test]
+fn copy_from() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let key = |id: &str| EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Scalar".to_owned(),
+            entity_id: id.to_owned(),
+        };
+        let create_layout = |name: &str, gql: &str| {
+            let id = SubgraphDeploymentId::new(name).unwrap();
+            let schema = Schema::parse(gql, id.clone()).unwrap();
+            conn.batch_execute(&format!(
+                "drop schema if exists {name} cascade; create schema {name}",
+                name = name
+            ))
+            .expect("Failed to create schema");
+            Layout::create_relational_schema(&conn, name, id, &schema.document)
+                .expect("Failed to create relational schema")
+        };
+
+        // Create 'one' in block 0 and update it in block 6, create 'two'
+        // in block 2 and delete it in block 3, and create 'three' in
+        // block 5
+        insert_entity(&conn, &layout, "Scalar", SCALAR_ENTITY.clone());
+        let mut one = SCALAR_ENTITY.clone();
+        one.set("string", "updated");
+        layout
+            .update(&conn, &key("one"), &one, 6)
+            .expect("Failed to update");
+        let mut two = SCALAR_ENTITY.clone();
+        two.set("id", "two");
+        layout
+            .insert(&conn, &key("two"), &two, 2)
+            .expect("Failed to insert");
+        layout
+            .delete(&conn, &key("two"), 3)
+            .expect("Failed to delete");
+        let mut three = SCALAR_ENTITY.clone();
+        three.set("id", "three");
+        layout
+            .insert(&conn, &key("three"), &three, 5)
+            .expect("Failed to insert");
+
+        // Copy what the layout had at block 4
+        let copy = create_layout("copy", THINGS_GQL);
+        let count = copy.copy_from(&conn, &layout, 4).expect("Failed to copy");
+        assert_eq!(1, count);
+
+        let find = |id: &str, block| {
+            copy.find(&conn, "Scalar", id, block)
+                .expect("Failed to read copied entity")
+        };
+        assert_entity_eq!(
+            scrub(&*SCALAR_ENTITY),
+            find("one", BLOCK_NUMBER_MAX).unwrap()
+        );
+        assert!(find("two", BLOCK_NUMBER_MAX).is_none());
+        assert!(find("two", 2).is_some());
+        assert!(find("three", BLOCK_NUMBER_MAX).is_none());
+
+        // Attributes that the base does not have must be optional
+        let incompatible = create_layout(
+            "incompatible",
+            &THINGS_GQL.replace("color: Color,", "color: Color,\n        extra: String!,"),
+        );
+        let err = incompatible
+            .copy_from(&conn, &layout, 4)
+            .expect_err("Copying into an incompatible layout must fail");
+        assert!(err
+            .to_string()
+            .contains("`Scalar.extra` is required, but does not exist in the base"));
+
+        // Copying one version at a time copies the same entities
+        let batched = create_layout("batched", THINGS_GQL);
+        let mut batches = 0;
+        let mut from_vid = Some(0);
+        while let Some(vid) = from_vid {
+            from_vid = batched
+                .copy_table_from(&conn, &layout, "Scalar", 4, vid, 1)
+                .expect("Failed to copy batch");
+            batches += 1;
+        }
+        assert!(batches > 1);
+        assert_eq!(
+            1,
+            batched
+                .count_current_entities(&conn)
+                .expect("Failed to count")
+        );
+        assert_entity_eq!(
+            scrub(&*SCALAR_ENTITY),
+            batched
+                .find(&conn, "Scalar", "one", BLOCK_NUMBER_MAX)
+                .expect("Failed to read copied entity")
+                .unwrap()
+        );
+        assert!(batched
+            .find(&conn, "Scalar", "two", 2)
+            .expect("Failed to read copied entity")
+            .is_some());
+
+        conn.batch_execute(
+            "drop schema copy cascade; drop schema incompatible cascade; \
+             drop schema batched cascade",
+        )
+        .expect("Failed to drop schemas");
+        Ok(())
+    });
+}
+
+#[test]
+fn create_missing_indexes() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        assert!(layout
+            .create_missing_indexes(&conn)
+            .expect("Failed to create indexes")
+            .is_empty());
+
+        let query = format!("drop index {}.attr_1_4_scalar_string", SCHEMA_NAME);
+        conn.batch_execute(&query).expect("Failed to drop index");
+        assert_eq!(
+            vec![IndexCreation {
+                name: "attr_1_4_scalar_string".to_owned(),
+                error: None,
+            }],
+            layout
+                .create_missing_indexes(&conn)
+                .expect("Failed to create indexes")
+        );
+        assert!(layout
+            .create_missing_indexes(&conn)
+            .expect("Failed to create indexes")
+            .is_empty());
+        Ok(())
+    });
+}
+
+#[test]
+fn oversized_entity_ids() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_pets(&conn, &layout);
+
+        let oversized = |entity_type: &str, count, longest| OversizedEntityIds {
+            entity_type: entity_type.to_owned(),
+            count,
+            longest,
+        };
+        assert_eq!(
+            vec![oversized("Cat", 1, 8)],
+            layout
+                .oversized_entity_ids(&conn, 5)
+                .expect("Failed to find oversized IDs")
+        );
+        assert_eq!(
+            vec![oversized("Cat", 1, 8), oversized("Dog", 1, 5)],
+            layout
+                .oversized_entity_ids(&conn, 4)
+                .expect("Failed to find oversized IDs")
+        );
+        Ok(())
+    });
+}
+
 #[test]
 fn conflicting_entity() {
     run_test(|conn, layout| -> Result<(), ()> {
@@ -496,6 +915,169 @@ fn conflicting_entity() {
     })
 }
 
+#[test]
+fn aggregate() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        let aggregation = |group_by: Vec<&str>, filter: Option<EntityFilter>| EntityAggregation {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            block: BLOCK_NUMBER_MAX,
+            entity_type: "User".to_owned(),
+            filter,
+            group_by: group_by.into_iter().map(|attr| attr.to_owned()).collect(),
+            range: EntityRange::first(100),
+            max_entities: 3,
+        };
+
+        let groups = layout
+            .aggregate(conn, aggregation(vec!["coffee"], None))
+            .expect("aggregating users succeeds");
+        assert_eq!(2, groups.len());
+        assert_eq!(Some(&Value::Bool(false)), groups[0].get("coffee"));
+        assert_eq!(Some(&Value::Int(2)), groups[0].get("count"));
+        assert_eq!(
+            Some(&Value::BigInt(BigInt::from(95))),
+            groups[0].get("age_sum")
+        );
+        assert_eq!(Some(&Value::Int(28)), groups[0].get("age_min"));
+        assert_eq!(Some(&Value::Int(67)), groups[0].get("age_max"));
+        assert_eq!(Some(&Value::Bool(true)), groups[1].get("coffee"));
+        assert_eq!(Some(&Value::Int(1)), groups[1].get("count"));
+
+        // Aggregating the entities in memory forms the same groups
+        let users = layout
+            .query(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                None,
+                None,
+                EntityRange {
+                    first: None,
+                    skip: 0,
+                    from_end: false,
+                },
+                BLOCK_NUMBER_MAX,
+            )
+            .expect("querying users succeeds");
+        let mut in_memory = layout
+            .aggregate_entities(&aggregation(vec!["coffee"], None), users)
+            .expect("aggregating users in memory succeeds");
+        in_memory.sort_by_key(|group| group.get("coffee") == Some(&Value::Bool(true)));
+        assert_eq!(groups.len(), in_memory.len());
+        for (group, in_memory) in groups.iter().zip(in_memory.iter()) {
+            for attribute in &[
+                "coffee", "count", "age_sum", "age_min", "age_max", "age_avg",
+            ] {
+                assert_eq!(group.get(attribute), in_memory.get(attribute));
+            }
+        }
+
+        let groups = layout
+            .aggregate(
+                conn,
+                aggregation(vec![], Some(EntityFilter::new_equal("coffee", false))),
+            )
+            .expect("aggregating filtered users succeeds");
+        assert_eq!(1, groups.len());
+        assert_eq!(Some(&Value::Int(2)), groups[0].get("count"));
+        assert_eq!(None, groups[0].get("coffee"));
+
+        let result = layout.aggregate(conn, aggregation(vec!["drinks"], None));
+        assert!(result.is_err());
+
+        // Aggregations can not look at more than `max_entities` entities
+        let too_many = EntityAggregation {
+            max_entities: 2,
+            ..aggregation(vec!["coffee"], None)
+        };
+        match layout.aggregate(conn, too_many) {
+            Err(QueryExecutionError::TooManyEntitiesToAggregate(entity_type, 2)) => {
+                assert_eq!("User", entity_type)
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        let few_enough = EntityAggregation {
+            max_entities: 2,
+            ..aggregation(vec![], Some(EntityFilter::new_equal("coffee", false)))
+        };
+        assert!(layout.aggregate(conn, few_enough).is_ok());
+        Ok(())
+    })
+}
+
+#[test]
+fn window_strategies_agree() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // Thing `a` has children `a1` to `a4`, and `b` has `b1` and `b2`;
+        // each parent is also its own child
+        for (id, parent) in &[
+            ("a", "a"),
+            ("a1", "a"),
+            ("a2", "a"),
+            ("a3", "a"),
+            ("a4", "a"),
+            ("b", "b"),
+            ("b1", "b"),
+            ("b2", "b"),
+            ("c1", "c"),
+        ] {
+            let mut thing = Entity::new();
+            thing.set("id", *id);
+            thing.set("bigThing", *parent);
+            insert_entity(conn, layout, "Thing", thing);
+        }
+
+        let children = |strategy: WindowStrategy, range: EntityRange| {
+            let collection = EntityCollection::Window(vec![EntityWindow {
+                child_type: "Thing".to_owned(),
+                ids: vec!["a".to_owned(), "b".to_owned()],
+                link: EntityLink::Direct(WindowAttribute::Scalar("bigThing".to_owned())),
+            }]);
+            let order = Some((
+                "id".to_owned(),
+                ValueType::String,
+                EntityOrder::Descending,
+                NullsOrder::Last,
+            ));
+            let mut ids = layout
+                .clone()
+                .with_window_strategy(strategy)
+                .query(
+                    &*LOGGER,
+                    conn,
+                    collection,
+                    None,
+                    order,
+                    range,
+                    BLOCK_NUMBER_MAX,
+                )
+                .expect("window query failed")
+                .into_iter()
+                .map(|entity| entity.id().unwrap())
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+
+        let first = EntityRange::first(2);
+        let skipped = EntityRange {
+            first: Some(2),
+            skip: 1,
+            from_end: false,
+        };
+        for (range, expected) in vec![
+            (first, vec!["a3", "a4", "b1", "b2"]),
+            (skipped, vec!["a2", "a3", "b", "b1"]),
+        ] {
+            assert_eq!(expected, children(WindowStrategy::Rank, range.clone()));
+            assert_eq!(expected, children(WindowStrategy::Lateral, range));
+        }
+        Ok(())
+    });
+}
+
 fn test_find(expected_entity_ids: Vec<&str>, query: EntityQuery) {
     let expected_entity_ids: Vec<String> =
         expected_entity_ids.into_iter().map(str::to_owned).collect();
@@ -507,7 +1089,8 @@ fn test_find(expected_entity_ids: Vec<&str>, query: EntityQuery) {
         let order = match query.order_by {
             Some((attribute, value_type)) => {
                 let direction = query.order_direction.unwrap_or(EntityOrder::Ascending);
-                Some((attribute, value_type, direction))
+                let nulls = query.order_nulls.unwrap_or(NullsOrder::Last);
+                Some((attribute, value_type, direction, nulls))
             }
             None => None,
         };
@@ -551,6 +1134,71 @@ fn user_query() -> EntityQuery {
     query(vec!["User"])
 }
 
+fn insert_band(conn: &PgConnection, layout: &Layout, id: &str, name: &str, bio: &str) {
+    let mut band = Entity::new();
+    band.set("id", id);
+    band.set("name", name);
+    band.set("bio", bio);
+    insert_entity(conn, layout, "Band", band);
+}
+
+#[test]
+fn fulltext_search() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_band(conn, layout, "mogwai", "Mogwai", "Post rock from Glasgow");
+        insert_band(
+            conn,
+            layout,
+            "sigur-ros",
+            "Sigur Ros",
+            "Post rock from Iceland. Their post rock is the slowest post rock",
+        );
+        insert_band(conn, layout, "metallica", "Metallica", "Heavy metal");
+
+        let search = |text: &str| {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["Band".to_owned()]),
+                    Some(EntityFilter::Equal(
+                        "bandSearch".to_owned(),
+                        Value::from(text),
+                    )),
+                    Some((
+                        "bandSearch".to_owned(),
+                        ValueType::String,
+                        EntityOrder::Descending,
+                        NullsOrder::Last,
+                    )),
+                    EntityRange::first(100),
+                    BLOCK_NUMBER_MAX,
+                )
+                .expect("fulltext search failed")
+                .into_iter()
+                .map(|band| band.id().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // Plain text with several words, punctuation and stop words is a
+        // valid search, and bands that mention it more often rank higher
+        assert_eq!(vec!["sigur-ros", "mogwai"], search("post rock"));
+        assert_eq!(vec!["sigur-ros", "mogwai"], search("The post, rock!"));
+        // Searches cover all fields, and are stemmed
+        assert_eq!(vec!["mogwai"], search("mogwai glasgow"));
+        assert_eq!(vec!["metallica"], search("metals"));
+        assert!(search("post metal").is_empty());
+
+        // The search is not part of the entity
+        let band = layout
+            .find(conn, "Band", "mogwai", BLOCK_NUMBER_MAX)
+            .expect("Failed to read Band[mogwai]")
+            .unwrap();
+        assert!(band.get("bandSearch").is_none());
+        Ok(())
+    })
+}
+
 #[test]
 fn find_interface() {
     test_find(vec!["garfield", "pluto"], query(vec!["Cat", "Dog"]));
@@ -579,6 +1227,52 @@ fn find_interface() {
     );
 }
 
+#[test]
+fn find_interface_type_condition() {
+    // The condition only restricts cats; dogs always pass it
+    test_find(
+        vec!["pluto"],
+        query(vec!["Cat", "Dog"]).filter(EntityFilter::TypeCondition(
+            "Cat".to_owned(),
+            Box::new(EntityFilter::Equal("name".into(), "Tom".into())),
+        )),
+    );
+
+    test_find(
+        vec!["garfield", "pluto"],
+        query(vec!["Cat", "Dog"]).filter(EntityFilter::TypeCondition(
+            "Cat".to_owned(),
+            Box::new(EntityFilter::StartsWith("name".into(), "Gar".into())),
+        )),
+    );
+
+    test_find(
+        vec!["garfield"],
+        query(vec!["Cat", "Dog"]).filter(EntityFilter::And(vec![
+            EntityFilter::TypeCondition(
+                "Cat".to_owned(),
+                Box::new(EntityFilter::Equal("name".into(), "Garfield".into())),
+            ),
+            EntityFilter::TypeCondition(
+                "Dog".to_owned(),
+                Box::new(EntityFilter::Equal("name".into(), "Garfield".into())),
+            ),
+        ])),
+    );
+
+    // Conditions for other types do not need the queried type to have
+    // the attribute they filter by
+    test_find(
+        vec!["1", "2", "3"],
+        user_query()
+            .filter(EntityFilter::TypeCondition(
+                "Cat".to_owned(),
+                Box::new(EntityFilter::Equal("name".into(), "Garfield".into())),
+            ))
+            .order_by("id", ValueType::String, EntityOrder::Ascending),
+    );
+}
+
 #[test]
 fn find_string_contains() {
     test_find(
@@ -602,6 +1296,30 @@ fn find_list_contains() {
     test_find(vec![], query(vec!["beer", "wine", "water"]));
 }
 
+#[test]
+fn find_list_not_contains() {
+    fn query(v: Vec<&str>) -> EntityQuery {
+        let drinks: Option<Value> = Some(v.into());
+        user_query().filter(EntityFilter::NotContains("drinks".into(), drinks.into()))
+    }
+
+    test_find(vec!["3"], query(vec!["beer"]));
+    test_find(vec!["2", "3"], query(vec!["beer", "tea"]));
+}
+
+#[test]
+fn find_list_contains_any() {
+    fn query(v: Vec<&str>) -> EntityQuery {
+        let drinks: Option<Value> = Some(v.into());
+        user_query().filter(EntityFilter::ContainsAny("drinks".into(), drinks.into()))
+    }
+
+    test_find(vec!["2"], query(vec!["wine"]));
+    test_find(vec!["2", "3"], query(vec!["beer", "tea"]));
+    test_find(vec![], query(vec!["water"]));
+    test_find(vec![], query(vec![]));
+}
+
 #[test]
 fn find_string_equal() {
     test_find(
@@ -668,6 +1386,35 @@ fn find_string_less_than_range() {
     )
 }
 
+#[test]
+fn find_last_entities() {
+    let last = |first, skip| EntityRange {
+        first: Some(first),
+        skip,
+        from_end: true,
+    };
+    // The last entities are still returned in the order of the query
+    test_find(
+        vec!["1", "3"],
+        user_query()
+            .order_by("name", ValueType::String, EntityOrder::Ascending)
+            .range(last(2, 0)),
+    );
+    test_find(
+        vec!["2", "1"],
+        user_query()
+            .order_by("name", ValueType::String, EntityOrder::Ascending)
+            .range(last(2, 1)),
+    );
+    test_find(vec!["2", "3"], user_query().range(last(2, 0)));
+    test_find(
+        vec!["pluto"],
+        query(vec!["Cat", "Dog"])
+            .order_by("name", ValueType::String, EntityOrder::Ascending)
+            .range(last(1, 0)),
+    );
+}
+
 #[test]
 fn find_string_multiple_and() {
     test_find(
@@ -727,6 +1474,52 @@ fn find_string_not_in() {
     )
 }
 
+#[test]
+fn find_enum_in() {
+    test_find(
+        vec!["1"],
+        user_query()
+            .filter(EntityFilter::In(
+                "favorite_color".to_owned(),
+                vec!["yellow".into(), "BLUE".into()],
+            ))
+            .order_by("id", ValueType::String, EntityOrder::Ascending),
+    );
+
+    test_find(
+        vec!["2", "3"],
+        user_query()
+            .filter(EntityFilter::In(
+                "favorite_color".to_owned(),
+                vec!["red".into(), Value::Null],
+            ))
+            .order_by("id", ValueType::String, EntityOrder::Ascending),
+    );
+}
+
+#[test]
+fn find_enum_not_in() {
+    test_find(
+        vec!["2"],
+        user_query()
+            .filter(EntityFilter::NotIn(
+                "favorite_color".to_owned(),
+                vec!["yellow".into(), "BLUE".into()],
+            ))
+            .order_by("id", ValueType::String, EntityOrder::Ascending),
+    );
+
+    test_find(
+        vec!["1"],
+        user_query()
+            .filter(EntityFilter::NotIn(
+                "favorite_color".to_owned(),
+                vec!["red".into(), Value::Null],
+            ))
+            .order_by("id", ValueType::String, EntityOrder::Ascending),
+    );
+}
+
 #[test]
 fn find_float_equal() {
     test_find(
@@ -1131,6 +1924,43 @@ fn find_where_nested_and_or() {
     )
 }
 
+#[test]
+fn find_where_not() {
+    // User 3 has no favorite color, so `favorite_color = 'red'` is null
+    // for it, and it passes the negation
+    test_find(
+        vec!["1", "3"],
+        user_query()
+            .filter(EntityFilter::Negation(Box::new(EntityFilter::Equal(
+                "favorite_color".to_owned(),
+                Value::from("red"),
+            ))))
+            .order_by("id", ValueType::String, EntityOrder::Ascending),
+    );
+
+    test_find(
+        vec!["3"],
+        user_query()
+            .filter(EntityFilter::Negation(Box::new(EntityFilter::In(
+                "favorite_color".to_owned(),
+                vec!["red".into(), "yellow".into()],
+            ))))
+            .order_by("id", ValueType::String, EntityOrder::Ascending),
+    );
+
+    test_find(
+        vec!["2"],
+        user_query()
+            .filter(EntityFilter::Negation(Box::new(EntityFilter::Negation(
+                Box::new(EntityFilter::Equal(
+                    "favorite_color".to_owned(),
+                    Value::from("red"),
+                )),
+            ))))
+            .order_by("id", ValueType::String, EntityOrder::Ascending),
+    );
+}
+
 #[test]
 fn find_enum_equal() {
     test_find(
@@ -160,6 +160,8 @@ fn insert_test_data(store: Arc<DieselStore>) {
         schema: TEST_SUBGRAPH_SCHEMA.clone(),
         data_sources: vec![],
         templates: vec![],
+        graft: None,
+        features: vec![],
     };
 
     // Create SubgraphDeploymentEntity
@@ -1338,6 +1340,37 @@ fn revert_block_with_delete() {
     })
 }
 
+#[test]
+fn revert_before_earliest_retained_block_is_refused() {
+    run_test(|store| {
+        // A deployment that was migrated from JSONB storage only keeps
+        // history from the block the migration started at
+        let op = MetadataOperation::Set {
+            entity: SubgraphDeploymentEntity::TYPENAME.to_owned(),
+            id: TEST_SUBGRAPH_ID.to_string(),
+            data: Entity::from(vec![(
+                "earliestRetainedBlockNumber",
+                Value::BigInt(BigInt::from(3)),
+            )]),
+        };
+        store
+            .apply_metadata_operations(vec![op])
+            .expect("Failed to set earliestRetainedBlockNumber");
+
+        let count = get_entity_count(store.clone(), &TEST_SUBGRAPH_ID);
+        assert!(store
+            .revert_block_operations(
+                TEST_SUBGRAPH_ID.clone(),
+                *TEST_BLOCK_2_PTR,
+                *TEST_BLOCK_1_PTR,
+            )
+            .is_err());
+        assert_eq!(count, get_entity_count(store.clone(), &TEST_SUBGRAPH_ID));
+
+        Ok(())
+    })
+}
+
 #[test]
 fn revert_block_with_partial_update() {
     run_test(|store| {
@@ -1606,6 +1639,8 @@ fn entity_changes_are_fired_and_forwarded_to_subscriptions() {
             schema: schema.clone(),
             data_sources: vec![],
             templates: vec![],
+            graft: None,
+            features: vec![],
         };
 
         // Create SubgraphDeploymentEntity
@@ -2207,3 +2242,447 @@ fn find_at_block() {
         shaqueeena_at_block(7000, "teeko@email.com");
     }
 }
+
+#[test]
+fn admin_operations_are_not_in_public_api() {
+    run_test(|store| -> Result<(), ()> {
+        let schema = store.api_schema(&*SUBGRAPHS_ID).unwrap();
+        let types: Vec<_> = schema
+            .document
+            .definitions
+            .iter()
+            .filter_map(|def| match def {
+                s::Definition::TypeDefinition(s::TypeDefinition::Object(t)) => Some(&t.name),
+                _ => None,
+            })
+            .collect();
+
+        assert!(types.iter().any(|name| *name == "SubgraphDeployment"));
+        assert!(!types
+            .iter()
+            .any(|name| *name == SubgraphAdminOperationEntity::TYPENAME));
+        Ok(())
+    })
+}
+
+#[test]
+fn subgraph_of_subgraphs_has_no_aggregations() {
+    run_test(|store| -> Result<(), ()> {
+        // JSONB storage can not aggregate entities
+        let schema = store.api_schema(&*SUBGRAPHS_ID).unwrap();
+        let types: Vec<_> = schema
+            .document
+            .definitions
+            .iter()
+            .filter_map(|def| match def {
+                s::Definition::TypeDefinition(s::TypeDefinition::Object(t)) => Some(t),
+                _ => None,
+            })
+            .collect();
+        assert!(!types.iter().any(|t| t.name.ends_with("_aggregate")));
+        let query = types
+            .iter()
+            .find(|t| t.name == "Query")
+            .expect("the API schema has a Query type");
+        assert!(query
+            .fields
+            .iter()
+            .any(|field| field.name == "subgraphDeployments"));
+        assert!(!query
+            .fields
+            .iter()
+            .any(|field| field.name.ends_with("Aggregates")));
+
+        // Subgraphs that use relational storage have them
+        let schema = store.api_schema(&TEST_SUBGRAPH_ID).unwrap();
+        assert!(schema.document.definitions.iter().any(|def| match def {
+            s::Definition::TypeDefinition(s::TypeDefinition::Object(t)) => {
+                t.name == "User_aggregate"
+            }
+            _ => false,
+        }));
+        Ok(())
+    })
+}
+
+#[test]
+fn create_subgraph_deployment_in_tablespace() {
+    run_test(|store| -> Result<(), ()> {
+        let create = |name: &str, tablespace: &str| {
+            let subgraph_id = SubgraphDeploymentId::new(name).unwrap();
+            let schema = Schema::parse(USER_GQL, subgraph_id.clone()).unwrap();
+            let manifest = SubgraphManifest {
+                id: subgraph_id.clone(),
+                location: "/ipfs/test".to_owned(),
+                spec_version: "1".to_owned(),
+                description: None,
+                repository: None,
+                schema: schema.clone(),
+                data_sources: vec![],
+                templates: vec![],
+                graft: None,
+                features: vec![],
+            };
+            let ops = SubgraphDeploymentEntity::new(&manifest, false, false, None, None)
+                .create_operations(&subgraph_id);
+            let result =
+                store.create_subgraph_deployment_in_tablespace(&schema, ops, tablespace.to_owned());
+            let exists = store
+                .get(SubgraphDeploymentEntity::key(subgraph_id))
+                .unwrap()
+                .is_some();
+            (result, exists)
+        };
+
+        let (result, exists) = create("TablespaceSubgraph", "pg_default");
+        assert!(result.is_ok());
+        assert!(exists);
+
+        // Nothing is created if the tablespace does not exist
+        let (result, exists) = create("NoTablespaceSubgraph", "no_such_tablespace");
+        assert!(result.is_err());
+        assert!(!exists);
+        Ok(())
+    })
+}
+
+#[test]
+fn revert_block_with_subgraph_error() {
+    run_test(|store| -> Result<(), ()> {
+        let error = SubgraphErrorEntity::new(
+            TEST_SUBGRAPH_ID.clone(),
+            *TEST_BLOCK_3_PTR,
+            "handler failed".to_owned(),
+        );
+        let id = error.id();
+        transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            *TEST_BLOCK_3_PTR,
+            error.write_entity_operations(&id),
+        )
+        .unwrap();
+
+        let key = EntityKey {
+            subgraph_id: SUBGRAPHS_ID.clone(),
+            entity_type: SubgraphErrorEntity::TYPENAME.to_owned(),
+            entity_id: id,
+        };
+        assert!(store.get(key.clone()).unwrap().is_some());
+
+        // A reorg removes the errors of the blocks it reverts
+        store
+            .revert_block_operations(
+                TEST_SUBGRAPH_ID.clone(),
+                *TEST_BLOCK_3_PTR,
+                *TEST_BLOCK_2_PTR,
+            )
+            .unwrap();
+        assert!(store.get(key).unwrap().is_none());
+        Ok(())
+    })
+}
+
+#[test]
+fn entity_type_updates() {
+    run_test(|store| -> Result<(), ()> {
+        let updates = |block| store.entity_type_updates(&TEST_SUBGRAPH_ID, block).unwrap();
+        assert_eq!(vec![(USER.to_owned(), 2)], updates(None));
+        assert_eq!(vec![(USER.to_owned(), 1)], updates(Some(1)));
+        assert_eq!(vec![(USER.to_owned(), 0)], updates(Some(0)));
+
+        // Reverting a block changes the entities it changed again, and
+        // the latest update therefore does not go backwards
+        store
+            .revert_block_operations(
+                TEST_SUBGRAPH_ID.clone(),
+                *TEST_BLOCK_2_PTR,
+                *TEST_BLOCK_1_PTR,
+            )
+            .unwrap();
+        assert_eq!(vec![(USER.to_owned(), 2)], updates(None));
+        assert_eq!(vec![(USER.to_owned(), 1)], updates(Some(1)));
+        Ok(())
+    })
+}
+
+#[test]
+fn copy_deployment_resumes_and_is_removed() {
+    run_test(|store| -> Result<(), ()> {
+        let create = |name: &str| {
+            let subgraph_id = SubgraphDeploymentId::new(name).unwrap();
+            let schema = Schema::parse(USER_GQL, subgraph_id.clone()).unwrap();
+            let manifest = SubgraphManifest {
+                id: subgraph_id.clone(),
+                location: "/ipfs/test".to_owned(),
+                spec_version: "1".to_owned(),
+                description: None,
+                repository: None,
+                schema: schema.clone(),
+                data_sources: vec![],
+                templates: vec![],
+                graft: Some(Graft {
+                    base: TEST_SUBGRAPH_ID.clone(),
+                    block: 2,
+                }),
+                features: vec![],
+            };
+            let ops = SubgraphDeploymentEntity::new(&manifest, false, false, None, None)
+                .create_operations(&subgraph_id);
+            store.create_subgraph_deployment(&schema, ops).unwrap();
+            subgraph_id
+        };
+        let base = &*TEST_SUBGRAPH_ID;
+        let block = *TEST_BLOCK_2_PTR;
+
+        let grafted = create("GraftedSubgraph");
+        let entity_types = store
+            .start_copy_deployment(base, &grafted, block)
+            .expect("Failed to start copying");
+        assert!(entity_types.contains(&USER.to_owned()));
+
+        // Copy part of the users, as if copying was interrupted after that
+        store
+            .copy_deployment_batch(base, &grafted, USER, block, 1)
+            .expect("Failed to copy batch");
+
+        // Resuming from another block of the base is refused
+        assert!(store
+            .start_copy_deployment(base, &grafted, *TEST_BLOCK_1_PTR)
+            .is_err());
+
+        // Resuming continues with the tables that are not finished
+        let entity_types = store
+            .start_copy_deployment(base, &grafted, block)
+            .expect("Failed to resume copying");
+        assert!(entity_types.contains(&USER.to_owned()));
+        for entity_type in &entity_types {
+            while !store
+                .copy_deployment_batch(base, &grafted, entity_type, block, 1)
+                .expect("Failed to copy batch")
+            {}
+        }
+        store
+            .finish_copy_deployment(base, &grafted, block, vec![])
+            .expect("Failed to finish copying");
+
+        // Every version was copied exactly once
+        assert_eq!(3, get_entity_count(store.clone(), &grafted));
+        let user = |id: &str| {
+            store
+                .get(EntityKey {
+                    subgraph_id: grafted.clone(),
+                    entity_type: USER.to_owned(),
+                    entity_id: id.to_owned(),
+                })
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(
+            Some(&Value::from("teeko@email.com")),
+            user("3").get("email")
+        );
+        assert_eq!(Some(&Value::from("Cindini")), user("2").get("name"));
+
+        // Removing a deployment forgets how far copying its tables came
+        let abandoned = create("AbandonedGraftSubgraph");
+        store
+            .start_copy_deployment(base, &abandoned, block)
+            .expect("Failed to start copying");
+        let copy_states = || {
+            let conn = PgConnection::establish(postgres_test_url().as_str())
+                .expect("Failed to connect to Postgres");
+            diesel::select(dsl::sql::<sql_types::BigInt>(
+                "(select count(*) from copy_table_state)",
+            ))
+            .get_result::<i64>(&conn)
+            .expect("Failed to count copy states")
+        };
+        assert!(copy_states() > 0);
+        remove_test_data(store.clone());
+        assert_eq!(0, copy_states());
+        Ok(())
+    })
+}
+
+#[test]
+fn entity_id_limits_are_recorded_on_deployments() {
+    run_test(|store| -> Result<(), ()> {
+        let deployment = store
+            .get(SubgraphDeploymentEntity::key(TEST_SUBGRAPH_ID.clone()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            graph::data::store::EntityIdLimits::configured(),
+            SubgraphDeploymentEntity::entity_id_limits(&deployment).unwrap()
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn oversized_entity_ids_in_json_storage() {
+    run_test(|store| -> Result<(), ()> {
+        // The subgraph of subgraphs uses JSONB storage. The test data has
+        // one deployment, `testsubgraph`, with the manifest
+        // `testsubgraph-manifest`
+        let oversized = |max_length| {
+            store
+                .oversized_entity_ids(&*SUBGRAPHS_ID, max_length)
+                .expect("Failed to find oversized IDs")
+        };
+        let entity_type = |oversized: &Vec<OversizedEntityIds>, entity_type: &str| {
+            oversized
+                .iter()
+                .find(|oversized| oversized.entity_type == entity_type)
+                .cloned()
+        };
+
+        let ids = oversized(11);
+        assert_eq!(
+            Some(OversizedEntityIds {
+                entity_type: "SubgraphDeployment".to_owned(),
+                count: 1,
+                longest: 12,
+            }),
+            entity_type(&ids, "SubgraphDeployment")
+        );
+        assert_eq!(
+            Some(OversizedEntityIds {
+                entity_type: "SubgraphManifest".to_owned(),
+                count: 1,
+                longest: 21,
+            }),
+            entity_type(&ids, "SubgraphManifest")
+        );
+
+        let ids = oversized(12);
+        assert_eq!(None, entity_type(&ids, "SubgraphDeployment"));
+        assert!(entity_type(&ids, "SubgraphManifest").is_some());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_where_not_in_json_storage() {
+    run_test(|store| -> Result<(), ()> {
+        // The subgraph of subgraphs uses JSONB storage. Removal `b` has no
+        // `atBlock`, so `atBlock = 5` is null for it, and it passes the
+        // negation
+        let removal = |id: &str, at_block: Option<u64>| {
+            let mut data = Entity::new();
+            data.set("id", id);
+            data.set("atBlock", at_block);
+            MetadataOperation::Set {
+                entity: "SubgraphDeploymentRemoval".to_owned(),
+                id: id.to_owned(),
+                data,
+            }
+        };
+        store
+            .apply_metadata_operations(vec![removal("a", Some(5)), removal("b", None)])
+            .expect("Failed to insert removals");
+
+        let find = |filter: EntityFilter| -> Vec<String> {
+            let query = EntityQuery::new(
+                SUBGRAPHS_ID.clone(),
+                BLOCK_NUMBER_MAX,
+                EntityCollection::All(vec!["SubgraphDeploymentRemoval".to_owned()]),
+            )
+            .filter(filter)
+            .order_by("id", ValueType::String, EntityOrder::Ascending);
+            store
+                .find(query)
+                .expect("Failed to find removals")
+                .iter()
+                .map(|entity| entity.id().unwrap())
+                .collect()
+        };
+        let at_block = |block: u64| Value::from(block);
+
+        assert_eq!(
+            vec!["b"],
+            find(EntityFilter::Negation(Box::new(EntityFilter::Equal(
+                "atBlock".to_owned(),
+                at_block(5),
+            ))))
+        );
+        assert_eq!(
+            vec!["a", "b"],
+            find(EntityFilter::Negation(Box::new(EntityFilter::GreaterThan(
+                "atBlock".to_owned(),
+                at_block(10),
+            ))))
+        );
+        assert_eq!(
+            vec!["a"],
+            find(EntityFilter::Negation(Box::new(EntityFilter::Negation(
+                Box::new(EntityFilter::Equal("atBlock".to_owned(), at_block(5))),
+            ))))
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn setting_a_disk_quota_keeps_the_measured_size() {
+    run_test(|store| -> Result<(), ()> {
+        let id = TEST_SUBGRAPH_ID.clone();
+        let quota = || {
+            store
+                .get(SubgraphDeploymentQuotaEntity::key(id.clone()))
+                .expect("Failed to get quota")
+                .expect("Quota not found")
+        };
+        let size = |quota: &Entity| {
+            quota
+                .get("size")
+                .cloned()
+                .and_then(Value::as_bigint)
+                .map(|size| size.to_u64())
+        };
+        let exceeded = |quota: &Entity| {
+            (
+                quota.get("softLimitExceeded").cloned(),
+                quota.get("hardLimitExceeded").cloned(),
+            )
+        };
+
+        // A new quota has not been measured yet
+        store
+            .apply_metadata_operations(
+                SubgraphDeploymentQuotaEntity::new(Some(100), Some(200))
+                    .write_operations(&id, None),
+            )
+            .expect("Failed to set quota");
+        let q = quota();
+        assert_eq!(None, size(&q));
+        assert_eq!(
+            (Some(Value::Bool(false)), Some(Value::Bool(false))),
+            exceeded(&q)
+        );
+
+        store
+            .apply_metadata_operations(SubgraphDeploymentQuotaEntity::update_usage_operations(
+                &id, 150, true, false,
+            ))
+            .expect("Failed to record usage");
+
+        // Changing the limits keeps the size and checks the new limits
+        // against it
+        store
+            .apply_metadata_operations(
+                SubgraphDeploymentQuotaEntity::new(None, Some(120))
+                    .write_operations(&id, size(&quota())),
+            )
+            .expect("Failed to change quota");
+        let q = quota();
+        assert_eq!(Some(150), size(&q));
+        assert_eq!(None, q.get("softLimit"));
+        assert_eq!(
+            (Some(Value::Bool(false)), Some(Value::Bool(true))),
+            exceeded(&q)
+        );
+        Ok(())
+    })
+}
@@ -88,6 +88,8 @@ pub fn create_test_subgraph(subgraph_id: &str, schema: &str) {
         schema: schema.clone(),
         data_sources: vec![],
         templates: vec![],
+        graft: None,
+        features: vec![],
     };
 
     let ops = SubgraphDeploymentEntity::new(&manifest, false, false, None, None)